@@ -0,0 +1,356 @@
+//! This module contains the audit log of a server: an append-only record of the
+//! administrative and destructive operations it was asked to perform, such as creating and
+//! releasing databases, changing their rules, deleting data and managing tokens.
+//!
+//! Each event records when the operation was requested, by whom, the database it concerns and
+//! the SHA-256 hash of the request that was sent, along with the error if the operation
+//! failed. Events are appended to a local file as lines of JSON, or written to object storage
+//! as one object each under a prefix, so that no event is ever rewritten. The most recent
+//! events are also kept in memory, to be queried from the `system.audit_log` table.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::stream::TryStreamExt;
+use object_store::ObjectStore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{ResultExt, Snafu};
+
+/// The number of events kept in memory for the `system.audit_log` table
+pub const MAX_RECENT_EVENTS: usize = 1000;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error opening audit log {:?}: {}", path, source))]
+    OpeningFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error writing to audit log {:?}: {}", path, source))]
+    WritingFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Invalid event on line {} of audit log {:?}: {}", line, path, source))]
+    InvalidEvent {
+        path: PathBuf,
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Error accessing audit log in object storage: {}", source))]
+    ObjectStore { source: object_store::Error },
+
+    #[snafu(display("Invalid event at {} of audit log: {}", location, source))]
+    InvalidObject {
+        location: String,
+        source: serde_json::Error,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An operation the server was asked to perform
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// When the operation was requested
+    pub time: DateTime<Utc>,
+    /// Who requested the operation, such as `Token admin` or `User alice`
+    pub actor: String,
+    /// The name of the operation, such as `CreateDatabase`
+    pub operation: String,
+    /// The database the operation concerns, if any
+    pub db_name: Option<String>,
+    /// The SHA-256 hash of the request, as hexadecimal
+    pub payload_sha256: String,
+    /// Why the operation failed, if it did
+    pub error: Option<String>,
+}
+
+impl AuditEvent {
+    /// Creates the event of the operation `operation`, requested now by `actor` with the
+    /// encoded request `payload`
+    pub fn new(
+        actor: impl Into<String>,
+        operation: impl Into<String>,
+        db_name: Option<String>,
+        payload: &[u8],
+        error: Option<String>,
+    ) -> Self {
+        Self {
+            time: Utc::now(),
+            actor: actor.into(),
+            operation: operation.into(),
+            db_name,
+            payload_sha256: hex::encode(Sha256::digest(payload)),
+            error,
+        }
+    }
+}
+
+/// Where the events are written
+#[derive(Debug)]
+enum Sink {
+    File {
+        path: PathBuf,
+        file: Mutex<File>,
+    },
+    ObjectStore {
+        store: Arc<ObjectStore>,
+        prefix: String,
+        /// Distinguishes the objects of the events recorded in the same nanosecond
+        sequence: AtomicU64,
+    },
+}
+
+/// The audit log of a server
+#[derive(Debug)]
+pub struct AuditLog {
+    sink: Sink,
+    recent: Mutex<VecDeque<AuditEvent>>,
+}
+
+impl AuditLog {
+    /// Appends the events to the file at `path`, whose last events are loaded
+    pub fn open_file(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)
+            .context(OpeningFile { path: &path })?;
+
+        let mut recent = VecDeque::new();
+        let reader = BufReader::new(File::open(&path).context(OpeningFile { path: &path })?);
+        for (index, line) in reader.lines().enumerate() {
+            let line = line.context(OpeningFile { path: &path })?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = serde_json::from_str(&line).context(InvalidEvent {
+                path: &path,
+                line: index + 1,
+            })?;
+            push_recent(&mut recent, event);
+        }
+
+        Ok(Self {
+            sink: Sink::File {
+                path,
+                file: Mutex::new(file),
+            },
+            recent: Mutex::new(recent),
+        })
+    }
+
+    /// Writes the events to `store` under the directory `prefix`, from which the last events
+    /// are loaded
+    pub async fn open_object_store(
+        store: Arc<ObjectStore>,
+        prefix: impl Into<String>,
+    ) -> Result<Self> {
+        let prefix = prefix.into();
+        let mut locations: Vec<_> = store
+            .list(Some(&prefix))
+            .await
+            .context(ObjectStore)?
+            .try_concat()
+            .await
+            .context(ObjectStore)?;
+        // the names of the objects sort in the order the events were recorded
+        locations.sort();
+        let skip = locations.len().saturating_sub(MAX_RECENT_EVENTS);
+
+        let mut recent = VecDeque::new();
+        for location in locations.into_iter().skip(skip) {
+            let data = store
+                .get(&location)
+                .await
+                .context(ObjectStore)?
+                .map_ok(|b| bytes::BytesMut::from(&b[..]))
+                .try_concat()
+                .await
+                .context(ObjectStore)?;
+            let event = serde_json::from_slice(&data).context(InvalidObject {
+                location: &location,
+            })?;
+            push_recent(&mut recent, event);
+        }
+
+        Ok(Self {
+            sink: Sink::ObjectStore {
+                store,
+                prefix,
+                sequence: AtomicU64::new(0),
+            },
+            recent: Mutex::new(recent),
+        })
+    }
+
+    /// Where the events are written, for logging
+    pub fn location(&self) -> String {
+        match &self.sink {
+            Sink::File { path, .. } => path.display().to_string(),
+            Sink::ObjectStore { prefix, .. } => format!("object storage under {}", prefix),
+        }
+    }
+
+    /// Records `event`, which is kept in memory even if it can't be written
+    pub async fn record(&self, event: AuditEvent) -> Result<()> {
+        let mut data = serde_json::to_vec(&event).expect("events can be serialized to JSON");
+        push_recent(
+            &mut self.recent.lock().expect("mutex poisoned"),
+            event.clone(),
+        );
+
+        match &self.sink {
+            Sink::File { path, file } => {
+                // the whole line is written and flushed at once so that the events of
+                // concurrent operations don't interleave
+                data.push(b'\n');
+                let mut file = file.lock().expect("mutex poisoned");
+                file.write_all(&data)
+                    .and_then(|_| file.flush())
+                    .context(WritingFile { path })
+            }
+            Sink::ObjectStore {
+                store,
+                prefix,
+                sequence,
+            } => {
+                let name = format!(
+                    "{:020}-{:010}.json",
+                    event.time.timestamp_nanos(),
+                    sequence.fetch_add(1, Ordering::Relaxed)
+                );
+                let location = if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", prefix, name)
+                };
+                let len = data.len();
+                let data = std::io::Result::Ok(Bytes::from(data));
+                store
+                    .put(&location, futures::stream::once(async move { data }), len)
+                    .await
+                    .context(ObjectStore)
+            }
+        }
+    }
+
+    /// Returns the recent events, oldest first
+    pub fn events(&self) -> Vec<AuditEvent> {
+        self.recent
+            .lock()
+            .expect("mutex poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+fn push_recent(recent: &mut VecDeque<AuditEvent>, event: AuditEvent) {
+    recent.push_back(event);
+    while recent.len() > MAX_RECENT_EVENTS {
+        recent.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::InMemory;
+
+    fn event(operation: &str, db_name: Option<&str>) -> AuditEvent {
+        AuditEvent::new(
+            "Token admin",
+            operation,
+            db_name.map(ToString::to_string),
+            operation.as_bytes(),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        let log = AuditLog::open_file(&path).unwrap();
+        log.record(event("CreateDatabase", Some("mydb")))
+            .await
+            .unwrap();
+        let mut failed = event("DeleteToken", None);
+        failed.error = Some("Token not found: nope".to_string());
+        log.record(failed.clone()).await.unwrap();
+        drop(log);
+
+        // the events are appended, and the previous ones loaded
+        let log = AuditLog::open_file(&path).unwrap();
+        log.record(event("ReleaseDatabase", Some("mydb")))
+            .await
+            .unwrap();
+        let events = log.events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].operation, "CreateDatabase");
+        assert_eq!(events[1], failed);
+        assert_eq!(events[2].db_name.as_deref(), Some("mydb"));
+        assert_eq!(
+            events[0].payload_sha256,
+            hex::encode(Sha256::digest(b"CreateDatabase"))
+        );
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn object_store() {
+        let store = Arc::new(ObjectStore::new_in_memory(InMemory::new()));
+
+        let log = AuditLog::open_object_store(Arc::clone(&store), "audit")
+            .await
+            .unwrap();
+        log.record(event("CreateDatabase", Some("mydb")))
+            .await
+            .unwrap();
+        log.record(event("Delete", Some("mydb"))).await.unwrap();
+
+        let objects: Vec<_> = store
+            .list(Some("audit"))
+            .await
+            .unwrap()
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(objects.len(), 2);
+
+        let log = AuditLog::open_object_store(store, "audit").await.unwrap();
+        let operations: Vec<_> = log.events().into_iter().map(|e| e.operation).collect();
+        assert_eq!(operations, vec!["CreateDatabase", "Delete"]);
+    }
+
+    #[test]
+    fn recent_events_are_bounded() {
+        let mut recent = VecDeque::new();
+        for i in 0..MAX_RECENT_EVENTS + 10 {
+            push_recent(&mut recent, event(&i.to_string(), None));
+        }
+        assert_eq!(recent.len(), MAX_RECENT_EVENTS);
+        assert_eq!(recent.front().unwrap().operation, "10");
+    }
+}
@@ -0,0 +1,488 @@
+//! This module contains the catalog of the chunks of a database that are persisted to object
+//! storage as Parquet files. The catalog is stored with the configuration of the server, so
+//! that the persisted chunks are known again once the configuration is loaded.
+//!
+//! Chunks are added to the catalog by bulk imports, and when the chunks of the write buffer are
+//! persisted as the server shuts down. The catalog also holds the tombstones of the deletes
+//! applied to the persisted chunks, until the rows they delete are purged from the files.
+//!
+//! Rows written late, to a partition whose chunks were already persisted, are persisted in a
+//! new chunk flagged as overlapping when its time range overlaps that of an earlier chunk of
+//! the same table, as it may then hold points also in the earlier chunks. Queries merge the
+//! rows of the same point across the chunks of such a partition, and compaction folds the
+//! overlapping chunks into one.
+
+use std::collections::BTreeMap;
+
+use data_types::chunk::{ColumnPredicate, ColumnStatistics, ColumnSummary};
+use generated_types::management;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compaction::PersistedFile,
+    integrity::PartChecksums,
+    tombstone::{DeletePredicate, Tombstone},
+};
+
+/// The chunks of a database that are persisted to object storage
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Catalog {
+    chunks: Vec<PersistedChunk>,
+    next_chunk_id: u32,
+    #[serde(default)]
+    tombstones: Vec<Tombstone>,
+}
+
+/// A table of a chunk, persisted as a Parquet file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistedChunk {
+    pub partition_key: String,
+    /// The id of the chunk, unique among the persisted chunks of the database
+    pub id: u32,
+    pub table_name: String,
+    /// The location of the Parquet file in object storage
+    pub location: String,
+    pub row_count: usize,
+    pub size_bytes: usize,
+    /// The time range of the rows of the chunk, in nanoseconds since the epoch
+    pub min_time: Option<i64>,
+    pub max_time: Option<i64>,
+    /// The columns the rows of the Parquet file are sorted by, most significant first. Empty
+    /// if the rows are in no particular order.
+    #[serde(default)]
+    pub sort_key: Vec<String>,
+    /// The SHA-256 checksum of the Parquet file, as hexadecimal. Not set for the chunks
+    /// persisted before checksums were recorded.
+    #[serde(default)]
+    pub checksum: Option<String>,
+    /// The checksums of the footer and column chunks of the Parquet file, which queries check
+    /// as they fetch them. Not set for the chunks persisted before they were recorded.
+    #[serde(default)]
+    pub part_checksums: Option<PartChecksums>,
+    /// The statistics of each column of the chunk. Empty for the chunks persisted before
+    /// column statistics were recorded.
+    #[serde(default)]
+    pub columns: Vec<ColumnStatistics>,
+    /// Whether the chunk was persisted after an earlier chunk of its partition and table
+    /// whose time range overlaps its own, so that they may hold rows of the same points
+    #[serde(default)]
+    pub overlaps: bool,
+}
+
+impl Catalog {
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Returns the id to persist the next chunk with. Ids are never reused, even if the chunk
+    /// they were handed out for is never added.
+    pub fn next_chunk_id(&mut self) -> u32 {
+        let id = self.next_chunk_id;
+        self.next_chunk_id += 1;
+        id
+    }
+
+    /// Records that `chunk` was persisted
+    pub fn add_chunk(&mut self, chunk: PersistedChunk) {
+        self.chunks.push(chunk);
+    }
+
+    /// Whether `chunk` overlaps one of the chunks persisted so far, as described on
+    /// `PersistedChunk::overlaps`
+    pub fn overlaps(&self, chunk: &PersistedChunk) -> bool {
+        self.chunks
+            .iter()
+            .any(|other| other.may_share_points(chunk))
+    }
+
+    /// Replaces the chunks with those rebuilt from the files in object storage. Ids keep
+    /// increasing past those of the rebuilt chunks, and the chunks overlapping earlier ones
+    /// are flagged again.
+    pub fn rebuild(&mut self, mut chunks: Vec<PersistedChunk>) {
+        let next_chunk_id = chunks.iter().map(|chunk| chunk.id + 1).max().unwrap_or(0);
+        self.next_chunk_id = self.next_chunk_id.max(next_chunk_id);
+
+        chunks.sort_by_key(|chunk| chunk.id);
+        self.chunks = vec![];
+        for mut chunk in chunks {
+            chunk.overlaps = self.overlaps(&chunk);
+            self.chunks.push(chunk);
+        }
+    }
+
+    /// Moves the file of each chunk to the location `location` returns for it
+    pub fn relocate(&mut self, location: impl Fn(&PersistedChunk) -> String) {
+        for chunk in &mut self.chunks {
+            chunk.location = location(chunk);
+        }
+    }
+
+    /// The persisted chunks, ordered by partition key and id
+    pub fn chunks(&self) -> Vec<PersistedChunk> {
+        let mut chunks = self.chunks.clone();
+        chunks.sort_by(|a, b| (&a.partition_key, a.id).cmp(&(&b.partition_key, b.id)));
+        chunks
+    }
+
+    /// The chunks whose rows are all older than `boundary`, a timestamp in nanoseconds, such
+    /// as the chunks past the retention period of the database
+    pub fn expired_chunks(&self, boundary: i64) -> Vec<PersistedChunk> {
+        self.chunks()
+            .into_iter()
+            .filter(|chunk| chunk.max_time.map_or(false, |max| max < boundary))
+            .collect()
+    }
+
+    /// Replaces the chunk `id` with `replacement`, or removes it if there is no replacement
+    pub fn replace_chunk(&mut self, id: u32, replacement: Option<PersistedChunk>) {
+        self.chunks.retain(|chunk| chunk.id != id);
+        self.chunks.extend(replacement);
+    }
+
+    /// The chunks of each partition and table that has an overlapping chunk, by id, which
+    /// compaction folds into one chunk
+    pub fn overlapping_chunks(&self) -> Vec<Vec<PersistedChunk>> {
+        let mut groups: BTreeMap<(&str, &str), Vec<PersistedChunk>> = BTreeMap::new();
+        for chunk in self.chunks.iter().filter(|chunk| chunk.overlaps) {
+            groups.insert(
+                (chunk.partition_key.as_str(), chunk.table_name.as_str()),
+                vec![],
+            );
+        }
+        for chunk in &self.chunks {
+            if let Some(group) =
+                groups.get_mut(&(chunk.partition_key.as_str(), chunk.table_name.as_str()))
+            {
+                group.push(chunk.clone());
+            }
+        }
+
+        groups
+            .into_iter()
+            .map(|(_, mut group)| {
+                group.sort_by_key(|chunk| chunk.id);
+                group
+            })
+            .collect()
+    }
+
+    /// Whether the chunks of table `table_name` in partition `partition_key` include an
+    /// overlapping chunk
+    pub fn has_overlaps(&self, partition_key: &str, table_name: &str) -> bool {
+        self.chunks.iter().any(|chunk| {
+            chunk.overlaps && chunk.partition_key == partition_key && chunk.table_name == table_name
+        })
+    }
+
+    /// Replaces the chunks `ids` with `compacted`, which holds their rows, or removes them
+    /// if there is no row left
+    pub fn compact_chunks(&mut self, ids: &[u32], compacted: Option<PersistedChunk>) {
+        self.chunks.retain(|chunk| !ids.contains(&chunk.id));
+        self.chunks.extend(compacted);
+    }
+
+    /// Records a delete of the rows matching `predicate` from the chunks persisted so far
+    pub fn add_tombstone(&mut self, predicate: DeletePredicate) -> Tombstone {
+        let tombstone = Tombstone {
+            predicate,
+            before_chunk_id: self.next_chunk_id,
+        };
+        self.tombstones.push(tombstone.clone());
+        tombstone
+    }
+
+    /// The deletes recorded, oldest first
+    pub fn tombstones(&self) -> &[Tombstone] {
+        &self.tombstones
+    }
+
+    /// The deletes that may apply to the rows of `chunk`
+    pub fn deletes(&self, chunk: &PersistedChunk) -> Vec<DeletePredicate> {
+        self.tombstones
+            .iter()
+            .filter(|t| chunk.id < t.before_chunk_id && t.predicate.may_match(chunk))
+            .map(|t| t.predicate.clone())
+            .collect()
+    }
+
+    /// Drops the tombstones that no longer apply to any chunk, once the chunks they applied to
+    /// have been rewritten without the deleted rows. Returns the number of tombstones dropped.
+    pub fn drop_obsolete_tombstones(&mut self) -> usize {
+        let before = self.tombstones.len();
+        let chunks = &self.chunks;
+        self.tombstones.retain(|t| {
+            chunks
+                .iter()
+                .any(|chunk| chunk.id < t.before_chunk_id && t.predicate.may_match(chunk))
+        });
+        before - self.tombstones.len()
+    }
+
+    /// The files of the persisted chunks, to plan compactions with
+    pub fn files(&self) -> Vec<PersistedFile> {
+        self.chunks
+            .iter()
+            .map(|chunk| PersistedFile {
+                partition_key: chunk.partition_key.clone(),
+                table_name: chunk.table_name.clone(),
+                chunk_id: chunk.id,
+                location: chunk.location.clone(),
+                size_bytes: chunk.size_bytes,
+                sort_key: chunk.sort_key.clone(),
+            })
+            .collect()
+    }
+}
+
+impl PersistedChunk {
+    /// Whether the chunk and `other` hold rows of the same table and partition that may have
+    /// the same time, judging by their time ranges
+    fn may_share_points(&self, other: &Self) -> bool {
+        if self.partition_key != other.partition_key || self.table_name != other.table_name {
+            return false;
+        }
+        match (self.min_time, self.max_time, other.min_time, other.max_time) {
+            (Some(min), Some(max), Some(other_min), Some(other_max)) => {
+                min <= other_max && other_min <= max
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns false if no row of the chunk can satisfy all of `predicates`, judging by the
+    /// statistics of its columns. Chunks without statistics may always match.
+    pub fn could_match(&self, predicates: &[ColumnPredicate]) -> bool {
+        if self.columns.is_empty() {
+            return true;
+        }
+
+        predicates.iter().all(|predicate| {
+            self.columns
+                .iter()
+                .find(|column| column.column_name == predicate.column_name)
+                // the rows of a chunk without the column all have it null
+                .map_or(false, |column| column.could_match(predicate))
+        })
+    }
+
+    /// The statistics of the columns of the chunk, as listed by `system.columns`
+    pub fn column_summaries(&self) -> Vec<ColumnSummary> {
+        self.columns
+            .iter()
+            .map(|column| ColumnSummary {
+                partition_key: self.partition_key.clone(),
+                chunk_id: self.id,
+                table_name: self.table_name.clone(),
+                column_name: column.column_name.clone(),
+                column_type: column.column_type.clone(),
+                encoding: "parquet".to_string(),
+                count: column.count,
+                null_count: column.null_count,
+                distinct_count: column.distinct_count,
+                min_value: column.min_value.clone(),
+                max_value: column.max_value.clone(),
+                // the columns are in object storage rather than in memory
+                estimated_bytes: 0,
+            })
+            .collect()
+    }
+}
+
+impl From<PersistedChunk> for management::PersistedChunk {
+    fn from(chunk: PersistedChunk) -> Self {
+        Self {
+            partition_key: chunk.partition_key,
+            id: chunk.id,
+            table_name: chunk.table_name,
+            location: chunk.location,
+            row_count: chunk.row_count as u64,
+            size_bytes: chunk.size_bytes as u64,
+            sort_key: chunk.sort_key,
+            checksum: chunk.checksum.unwrap_or_default(),
+            overlaps: chunk.overlaps,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_types::chunk::{Comparison, Literal};
+
+    fn chunk(partition_key: &str, id: u32) -> PersistedChunk {
+        PersistedChunk {
+            partition_key: partition_key.to_string(),
+            id,
+            table_name: "cpu".to_string(),
+            location: format!("1/db/data/{}/{}/cpu.parquet", partition_key, id),
+            row_count: 10,
+            size_bytes: 100,
+            min_time: Some(1),
+            max_time: Some(2),
+            sort_key: vec!["host".to_string(), "time".to_string()],
+            checksum: Some("abc".to_string()),
+            part_checksums: None,
+            columns: vec![],
+            overlaps: false,
+        }
+    }
+
+    #[test]
+    fn chunks() {
+        let mut catalog = Catalog::default();
+        assert!(catalog.is_empty());
+
+        let first = catalog.next_chunk_id();
+        let second = catalog.next_chunk_id();
+        assert_eq!((first, second), (0, 1));
+
+        catalog.add_chunk(chunk("b", second));
+        catalog.add_chunk(chunk("a", first));
+        assert!(!catalog.is_empty());
+        assert_eq!(catalog.chunks(), vec![chunk("a", 0), chunk("b", 1)]);
+        assert_eq!(catalog.files()[0].location, "1/db/data/b/1/cpu.parquet");
+        assert_eq!(catalog.files()[0].sort_key, vec!["host", "time"]);
+
+        let mut relocated = catalog.clone();
+        relocated.relocate(|chunk| format!("{}.parquet", chunk.id));
+        assert_eq!(relocated.files()[0].location, "1.parquet");
+
+        let mut rebuilt = catalog.clone();
+        rebuilt.rebuild(vec![chunk("c", 5)]);
+        assert_eq!(rebuilt.chunks(), vec![chunk("c", 5)]);
+        assert_eq!(rebuilt.next_chunk_id(), 6);
+
+        let json = serde_json::to_string(&catalog).unwrap();
+        let mut restored: Catalog = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, catalog);
+        assert_eq!(restored.next_chunk_id(), 2);
+    }
+
+    #[test]
+    fn tombstones() {
+        let mut catalog = Catalog::default();
+        catalog.next_chunk_id();
+        catalog.add_chunk(chunk("a", 0));
+
+        let predicate = DeletePredicate {
+            table_name: "cpu".to_string(),
+            start: 0,
+            end: 10,
+            tags: Default::default(),
+        };
+        let tombstone = catalog.add_tombstone(predicate.clone());
+        assert_eq!(tombstone.before_chunk_id, 1);
+
+        // the delete doesn't apply to the chunks persisted after it
+        let id = catalog.next_chunk_id();
+        catalog.add_chunk(chunk("a", id));
+        assert_eq!(catalog.deletes(&chunk("a", 0)), vec![predicate.clone()]);
+        assert!(catalog.deletes(&chunk("a", 1)).is_empty());
+
+        let json = serde_json::to_string(&catalog).unwrap();
+        let restored: Catalog = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.tombstones(), &[tombstone]);
+
+        // the tombstone is kept until the chunk it applies to is rewritten
+        assert_eq!(catalog.drop_obsolete_tombstones(), 0);
+        assert_eq!(catalog.tombstones().len(), 1);
+        let id = catalog.next_chunk_id();
+        catalog.replace_chunk(0, Some(chunk("a", id)));
+        assert_eq!(catalog.drop_obsolete_tombstones(), 1);
+        assert!(catalog.tombstones().is_empty());
+        assert_eq!(catalog.chunks(), vec![chunk("a", 1), chunk("a", 2)]);
+    }
+
+    #[test]
+    fn overlapping_chunks() {
+        let mut catalog = Catalog::default();
+        catalog.add_chunk(chunk("a", 0));
+        catalog.add_chunk(chunk("b", 1));
+
+        // a chunk of another table, or of later rows, doesn't overlap
+        let mut other_table = chunk("a", 2);
+        other_table.table_name = "mem".to_string();
+        let mut later = chunk("a", 3);
+        later.min_time = Some(3);
+        later.max_time = Some(4);
+        assert!(!catalog.overlaps(&other_table));
+        assert!(!catalog.overlaps(&later));
+        catalog.add_chunk(other_table);
+        catalog.add_chunk(later);
+        assert!(catalog.overlapping_chunks().is_empty());
+
+        let mut late = chunk("a", 4);
+        late.max_time = Some(3);
+        late.overlaps = catalog.overlaps(&late);
+        assert!(late.overlaps);
+        catalog.add_chunk(late.clone());
+        assert!(catalog.has_overlaps("a", "cpu"));
+        assert!(!catalog.has_overlaps("a", "mem"));
+        assert!(!catalog.has_overlaps("b", "cpu"));
+
+        let groups = catalog.overlapping_chunks();
+        assert_eq!(groups.len(), 1);
+        let ids: Vec<_> = groups[0].iter().map(|chunk| chunk.id).collect();
+        assert_eq!(ids, vec![0, 3, 4]);
+
+        // the flags are found again when the catalog is rebuilt
+        let mut rebuilt = Catalog::default();
+        let mut chunks = catalog.chunks();
+        for chunk in &mut chunks {
+            chunk.overlaps = false;
+        }
+        rebuilt.rebuild(chunks);
+        assert_eq!(rebuilt.chunks(), catalog.chunks());
+
+        let mut compacted = chunk("a", 5);
+        compacted.max_time = Some(4);
+        catalog.compact_chunks(&ids, Some(compacted.clone()));
+        assert!(catalog.overlapping_chunks().is_empty());
+        assert_eq!(catalog.chunks().len(), 3);
+    }
+
+    #[test]
+    fn chunks_without_sort_key() {
+        // catalogs stored before chunks had sort keys
+        let json = r#"{"chunks":[{"partition_key":"a","id":0,"table_name":"cpu","location":"l","row_count":1,"size_bytes":1,"min_time":null,"max_time":null}],"next_chunk_id":1}"#;
+        let catalog: Catalog = serde_json::from_str(json).unwrap();
+        assert!(catalog.chunks()[0].sort_key.is_empty());
+        assert!(catalog.chunks()[0].checksum.is_none());
+        assert!(catalog.chunks()[0].part_checksums.is_none());
+        assert!(catalog.chunks()[0].columns.is_empty());
+    }
+
+    #[test]
+    fn prunes_chunks() {
+        let mut chunk = chunk("a", 0);
+        let host = |op, value: &str| ColumnPredicate {
+            column_name: "host".to_string(),
+            op,
+            value: Literal::String(value.to_string()),
+        };
+        // no statistics
+        assert!(chunk.could_match(&[host(Comparison::Eq, "z")]));
+
+        chunk.columns = vec![ColumnStatistics {
+            column_name: "host".to_string(),
+            column_type: "tag".to_string(),
+            count: 10,
+            null_count: 0,
+            distinct_count: 2,
+            min_value: "a".to_string(),
+            max_value: "b".to_string(),
+        }];
+        assert!(chunk.could_match(&[]));
+        assert!(chunk.could_match(&[host(Comparison::Eq, "b")]));
+        assert!(!chunk.could_match(&[host(Comparison::Eq, "b"), host(Comparison::Gt, "b")]));
+        assert!(!chunk.could_match(&[ColumnPredicate {
+            column_name: "region".to_string(),
+            op: Comparison::Eq,
+            value: Literal::String("west".to_string()),
+        }]));
+
+        let summaries = chunk.column_summaries();
+        assert_eq!(summaries[0].column_name, "host");
+        assert_eq!(summaries[0].distinct_count, 2);
+    }
+}
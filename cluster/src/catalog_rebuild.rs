@@ -0,0 +1,254 @@
+//! This module rebuilds the catalog of a database from the Parquet files of its persisted
+//! chunks, for when the stored configuration holding the catalog is lost or corrupted.
+//!
+//! Each Parquet file carries what the catalog records about its chunk in the key-value metadata
+//! of its footer, under `METADATA_KEY`. Listing the files under `<writer id>/<db>/data/` and
+//! reading their footers gives back the chunks of the catalog. The files written before the
+//! footer was added are still recovered from their location, without their time range and
+//! sort key.
+//!
+//! The footer of a file rewritten by purging deleted rows, or compacted from overlapping chunks,
+//! also records the chunks it replaces, so that the files of the replaced chunks, which are only
+//! deleted once the catalog is stored, are left out if they are still around.
+
+use arrow_deps::parquet::{
+    errors::ParquetError,
+    file::{
+        reader::{FileReader, SerializedFileReader},
+        serialized_reader::SliceableCursor,
+    },
+};
+use data_types::chunk::ColumnStatistics;
+use serde::{Deserialize, Deserializer, Serialize};
+use snafu::{OptionExt, ResultExt, Snafu};
+
+use crate::{catalog::PersistedChunk, integrity};
+
+/// The key of the key-value metadata of Parquet files holding their `ChunkMetadata`
+pub const METADATA_KEY: &str = "iox.chunk";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("not the location of a persisted chunk"))]
+    UnexpectedLocation,
+
+    #[snafu(display("error reading the Parquet footer: {}", source))]
+    ReadingFooter { source: ParquetError },
+
+    #[snafu(display("invalid chunk metadata: {}", source))]
+    InvalidMetadata { source: serde_json::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What the catalog records about a persisted chunk, apart from what the file itself tells
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkMetadata {
+    pub partition_key: String,
+    pub chunk_id: u32,
+    pub table_name: String,
+    pub min_time: Option<i64>,
+    pub max_time: Option<i64>,
+    pub sort_key: Vec<String>,
+    /// The ids of the chunks this chunk was rewritten or compacted from, if any. The files
+    /// written before compactions record a single id, or none.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub replaces: Vec<u32>,
+    #[serde(default)]
+    pub columns: Vec<ColumnStatistics>,
+}
+
+/// The outcome of rebuilding the catalog of a database
+#[derive(Debug, Default)]
+pub struct RebuiltCatalog {
+    /// The chunks recovered, which the catalog now holds
+    pub chunks: Vec<PersistedChunk>,
+    /// The files that could not be recovered, with the reason
+    pub skipped: Vec<(String, Error)>,
+}
+
+impl ChunkMetadata {
+    /// The key-value metadata to write to the footer of the Parquet file of the chunk
+    pub fn to_key_value(&self) -> (String, String) {
+        let value = serde_json::to_string(self).expect("chunk metadata is always serializable");
+        (METADATA_KEY.to_string(), value)
+    }
+}
+
+/// The prefix of the locations of the Parquet files of database `db_name`
+pub fn data_prefix(id: u32, db_name: &str) -> String {
+    format!("{}/{}/data/", id, db_name)
+}
+
+/// Recovers the catalog entry of the chunk persisted at `location`, of contents `data`, along
+/// with the ids of the chunks it replaces. Whether the chunk overlaps others is left for the
+/// catalog to find out again.
+pub fn recover_chunk(location: &str, data: &[u8]) -> Result<(PersistedChunk, Vec<u32>)> {
+    let reader =
+        SerializedFileReader::new(SliceableCursor::new(data.to_vec())).context(ReadingFooter)?;
+    let file_metadata = reader.metadata().file_metadata();
+
+    let stored = file_metadata
+        .key_value_metadata()
+        .as_ref()
+        .and_then(|key_values| key_values.iter().find(|kv| kv.key == METADATA_KEY))
+        .and_then(|kv| kv.value.as_ref());
+    let metadata = match stored {
+        Some(value) => serde_json::from_str(value).context(InvalidMetadata)?,
+        None => parse_location(location).context(UnexpectedLocation)?,
+    };
+
+    let chunk = PersistedChunk {
+        partition_key: metadata.partition_key,
+        id: metadata.chunk_id,
+        table_name: metadata.table_name,
+        location: location.to_string(),
+        row_count: file_metadata.num_rows() as usize,
+        size_bytes: data.len(),
+        min_time: metadata.min_time,
+        max_time: metadata.max_time,
+        sort_key: metadata.sort_key,
+        checksum: Some(integrity::checksum(data)),
+        part_checksums: Some(integrity::part_checksums(data).context(ReadingFooter)?),
+        columns: metadata.columns,
+        overlaps: false,
+    };
+    Ok((chunk, metadata.replaces))
+}
+
+/// Leaves out of the recovered chunks those replaced by another recovered chunk
+pub fn drop_replaced(recovered: Vec<(PersistedChunk, Vec<u32>)>) -> Vec<PersistedChunk> {
+    let replaced: Vec<_> = recovered
+        .iter()
+        .flat_map(|(_, replaces)| replaces.iter().copied())
+        .collect();
+    recovered
+        .into_iter()
+        .map(|(chunk, _)| chunk)
+        .filter(|chunk| !replaced.contains(&chunk.id))
+        .collect()
+}
+
+/// The partition key, chunk id and table of a location of the form
+/// `<writer id>/<db>/data/<partition key>/<chunk id>/<table>.parquet`
+fn parse_location(location: &str) -> Option<ChunkMetadata> {
+    let mut parts = location.rsplitn(3, '/');
+    let file_name = parts.next()?;
+    let chunk_id = parts.next()?.parse().ok()?;
+    let rest = parts.next()?;
+
+    if !file_name.ends_with(".parquet") {
+        return None;
+    }
+    let table_name = &file_name[..file_name.len() - ".parquet".len()];
+    let partition_key = rest.splitn(4, '/').nth(3)?;
+
+    Some(ChunkMetadata {
+        partition_key: partition_key.to_string(),
+        chunk_id,
+        table_name: table_name.to_string(),
+        min_time: None,
+        max_time: None,
+        sort_key: vec![],
+        replaces: vec![],
+        columns: vec![],
+    })
+}
+
+/// Reads the ids of the replaced chunks from a list, or from the single id, possibly null, of
+/// the files written before compactions
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Replaces {
+        One(Option<u32>),
+        Many(Vec<u32>),
+    }
+
+    Ok(match Replaces::deserialize(deserializer)? {
+        Replaces::One(id) => id.into_iter().collect(),
+        Replaces::Many(ids) => ids,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_locations() {
+        let metadata = parse_location("1/mydb/data/2020-11-01T00/7/cpu.parquet").unwrap();
+        assert_eq!(metadata.partition_key, "2020-11-01T00");
+        assert_eq!(metadata.chunk_id, 7);
+        assert_eq!(metadata.table_name, "cpu");
+
+        assert!(parse_location("1/mydb/data/p/x/cpu.parquet").is_none());
+        assert!(parse_location("1/mydb/data/p/7/cpu.json").is_none());
+        assert!(parse_location("1/mydb/7/cpu.parquet").is_none());
+    }
+
+    #[test]
+    fn drops_replaced_chunks() {
+        let chunk = |id| PersistedChunk {
+            partition_key: "p".to_string(),
+            id,
+            table_name: "cpu".to_string(),
+            location: format!("1/db/data/p/{}/cpu.parquet", id),
+            row_count: 1,
+            size_bytes: 1,
+            min_time: None,
+            max_time: None,
+            sort_key: vec![],
+            checksum: None,
+            part_checksums: None,
+            columns: vec![],
+            overlaps: false,
+        };
+
+        let chunks = drop_replaced(vec![
+            (chunk(0), vec![]),
+            (chunk(1), vec![]),
+            (chunk(2), vec![0]),
+            (chunk(3), vec![]),
+            (chunk(4), vec![]),
+            (chunk(5), vec![3, 4]),
+        ]);
+        let ids: Vec<_> = chunks.iter().map(|chunk| chunk.id).collect();
+        assert_eq!(ids, vec![1, 2, 5]);
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        let metadata = ChunkMetadata {
+            partition_key: "p".to_string(),
+            chunk_id: 3,
+            table_name: "cpu".to_string(),
+            min_time: Some(10),
+            max_time: Some(20),
+            sort_key: vec!["host".to_string(), "time".to_string()],
+            replaces: vec![1, 2],
+            columns: vec![ColumnStatistics {
+                column_name: "host".to_string(),
+                column_type: "tag".to_string(),
+                count: 2,
+                null_count: 1,
+                distinct_count: 2,
+                min_value: "a".to_string(),
+                max_value: "b".to_string(),
+            }],
+        };
+        let (key, value) = metadata.to_key_value();
+        assert_eq!(key, METADATA_KEY);
+        assert_eq!(
+            serde_json::from_str::<ChunkMetadata>(&value).unwrap(),
+            metadata
+        );
+        // the files written before compactions replace a single chunk
+        let single = value.replace("[1,2]", "1");
+        let metadata = serde_json::from_str::<ChunkMetadata>(&single).unwrap();
+        assert_eq!(metadata.replaces, vec![1]);
+        let none = value.replace("[1,2]", "null");
+        let metadata = serde_json::from_str::<ChunkMetadata>(&none).unwrap();
+        assert!(metadata.replaces.is_empty());
+    }
+}
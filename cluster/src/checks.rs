@@ -0,0 +1,621 @@
+//! This module contains threshold checks: tasks that periodically run a query against a
+//! database and compare its result to thresholds, such as alerting when the CPU usage of a
+//! host stays above 90%, and notify endpoints when the level of the check changes.
+//!
+//! The value of a check is the first numeric value of the first row of its query, which
+//! should aggregate the recent rows, as in
+//! `SELECT max(usage) FROM cpu WHERE time >= $start AND time < $end`, where `$start` and `$end`
+//! are replaced by the bounds of the window of `every` before the evaluation, as for tasks.
+//! The check is `Crit` when the value is past its critical threshold, `Warn` when it is past its
+//! warning threshold, and `Ok` otherwise, where past means above or below depending on the
+//! direction of the check. To keep a value hovering around a threshold from flapping, a check
+//! only returns to a lower level once the value is past the threshold by the hysteresis.
+//!
+//! Checks are part of the configuration of the server, and their endpoints are notified of
+//! each change of level. Their current state is kept in memory, and evaluations that fail or
+//! return no value leave it as it is.
+
+use std::{collections::BTreeMap, fmt, sync::Mutex, time::Duration};
+
+use arrow_deps::arrow::{
+    array::{Array, Float64Array, Int64Array, UInt64Array},
+    datatypes::DataType,
+    record_batch::RecordBatch,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt};
+use tracing::{info, info_span, warn};
+use tracing_futures::Instrument;
+
+use crate::{
+    CheckAlreadyExists, CheckNotFound, ConnectionManager, DatabaseNotFound, InvalidCheck, Result,
+    Server,
+};
+
+/// The level of a check, ordered by severity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Ok,
+    Warn,
+    Crit,
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        Self::Ok
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::Warn => write!(f, "warn"),
+            Self::Crit => write!(f, "crit"),
+        }
+    }
+}
+
+/// Whether values above or below the thresholds of a check are past them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Above,
+    Below,
+}
+
+impl Default for Direction {
+    fn default() -> Self {
+        Self::Above
+    }
+}
+
+/// Where the changes of level of a check are sent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Endpoint {
+    /// POSTs the notification as JSON to the URL
+    Webhook { url: String },
+    /// Posts a message to the Slack incoming webhook URL
+    Slack { url: String },
+    /// Triggers and resolves incidents of the PagerDuty service with the integration key
+    PagerDuty { routing_key: String },
+}
+
+/// A threshold check
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Check {
+    pub name: String,
+    /// The database the query runs against
+    pub db_name: String,
+    /// The SQL query, in which `$start` and `$end` are replaced by the bounds of the window
+    /// before the evaluation
+    pub query: String,
+    /// How often the check is evaluated, which is also the width of the windows
+    pub every: Duration,
+    #[serde(default)]
+    pub direction: Direction,
+    /// The value past which the check is `Warn`, if any
+    #[serde(default)]
+    pub warn: Option<f64>,
+    /// The value past which the check is `Crit`, if any
+    #[serde(default)]
+    pub crit: Option<f64>,
+    /// How far back past a threshold the value must be for the check to return to a lower
+    /// level
+    #[serde(default)]
+    pub hysteresis: f64,
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+}
+
+// the thresholds of checks are validated to be finite when they are created
+impl Eq for Check {}
+
+impl Check {
+    /// Returns the window an evaluation at `now` covers, as nanoseconds since the epoch, the
+    /// end excluded
+    pub fn window(&self, now: DateTime<Utc>) -> (i64, i64) {
+        let every = self.every.as_nanos().min(i64::MAX as u128).max(1) as i64;
+        let end = now.timestamp_nanos().div_euclid(every) * every;
+        (end.saturating_sub(every), end)
+    }
+
+    /// Returns the query of the evaluation covering `window`
+    pub fn query_for(&self, (start, end): (i64, i64)) -> String {
+        self.query
+            .replace("$start", &start.to_string())
+            .replace("$end", &end.to_string())
+    }
+
+    /// Returns the level of the check for `value`, when it was at level `previous`
+    pub fn level(&self, value: f64, previous: Level) -> Level {
+        let past = |threshold: Option<f64>, level: Level| {
+            threshold.map_or(false, |threshold| {
+                // the check stays at a level it reached until the value is back past the
+                // threshold by the hysteresis
+                let margin = if previous >= level {
+                    self.hysteresis
+                } else {
+                    0.0
+                };
+                match self.direction {
+                    Direction::Above => value >= threshold - margin,
+                    Direction::Below => value <= threshold + margin,
+                }
+            })
+        };
+
+        if past(self.crit, Level::Crit) {
+            Level::Crit
+        } else if past(self.warn, Level::Warn) {
+            Level::Warn
+        } else {
+            Level::Ok
+        }
+    }
+}
+
+/// The state of a check
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CheckState {
+    pub level: Level,
+    /// The value of the last evaluation that returned one
+    pub value: Option<f64>,
+    /// When the check was last evaluated
+    pub evaluated_at: Option<DateTime<Utc>>,
+    /// The error the last evaluation failed with, if any
+    pub error: Option<String>,
+}
+
+/// A change of level of a check, sent to its endpoints
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub check: String,
+    pub db_name: String,
+    pub previous: Level,
+    pub level: Level,
+    pub value: f64,
+    pub time: DateTime<Utc>,
+    pub endpoints: Vec<Endpoint>,
+}
+
+/// The states of the checks of a server, and the last window each check covered
+#[derive(Debug, Default)]
+pub struct CheckHistory {
+    states: Mutex<BTreeMap<String, CheckState>>,
+    last_windows: Mutex<BTreeMap<String, i64>>,
+}
+
+impl CheckHistory {
+    /// Returns true if `check` hasn't covered the window ending at `end` yet
+    pub fn is_due(&self, check: &str, end: i64) -> bool {
+        self.last_windows
+            .lock()
+            .expect("mutex poisoned")
+            .get(check)
+            .map_or(true, |&last| last < end)
+    }
+
+    /// Records an evaluation of `check` covering the window ending at `end`, returning the
+    /// previous and new levels of the check
+    pub fn record(
+        &self,
+        check: &Check,
+        end: i64,
+        now: DateTime<Utc>,
+        result: Result<Option<f64>, String>,
+    ) -> (Level, Level) {
+        self.last_windows
+            .lock()
+            .expect("mutex poisoned")
+            .insert(check.name.clone(), end);
+
+        let mut states = self.states.lock().expect("mutex poisoned");
+        let state = states.entry(check.name.clone()).or_default();
+        let previous = state.level;
+        state.evaluated_at = Some(now);
+        match result {
+            Ok(Some(value)) => {
+                state.level = check.level(value, previous);
+                state.value = Some(value);
+                state.error = None;
+            }
+            Ok(None) => state.error = None,
+            Err(e) => state.error = Some(e),
+        }
+        (previous, state.level)
+    }
+
+    /// Returns the state of `check`
+    pub fn state(&self, check: &str) -> CheckState {
+        self.states
+            .lock()
+            .expect("mutex poisoned")
+            .get(check)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Forgets the state of a deleted check
+    pub fn remove(&self, check: &str) {
+        self.last_windows
+            .lock()
+            .expect("mutex poisoned")
+            .remove(check);
+        self.states.lock().expect("mutex poisoned").remove(check);
+    }
+}
+
+/// Returns the first numeric value of the first row of `batches`, if any
+pub fn first_value(batches: &[RecordBatch]) -> Option<f64> {
+    let batch = batches.iter().find(|batch| batch.num_rows() > 0)?;
+    batch.columns().iter().find_map(|column| {
+        if column.is_null(0) {
+            return None;
+        }
+        let any = column.as_any();
+        match column.data_type() {
+            DataType::Float64 => any.downcast_ref::<Float64Array>().map(|a| a.value(0)),
+            DataType::Int64 => any.downcast_ref::<Int64Array>().map(|a| a.value(0) as f64),
+            DataType::UInt64 => any.downcast_ref::<UInt64Array>().map(|a| a.value(0) as f64),
+            _ => None,
+        }
+    })
+}
+
+impl<M: ConnectionManager> Server<M> {
+    /// Adds a threshold check, see `checks`
+    pub fn create_check(&mut self, check: Check) -> Result<()> {
+        self.require_id()?;
+
+        let invalid = |reason: &'static str| InvalidCheck {
+            name: &check.name,
+            reason,
+        };
+        ensure!(!check.name.is_empty(), invalid("the name is required"));
+        ensure!(!check.query.is_empty(), invalid("the query is required"));
+        ensure!(
+            check.every > Duration::from_secs(0),
+            invalid("checks must be evaluated every period longer than zero")
+        );
+        ensure!(
+            check.warn.is_some() || check.crit.is_some(),
+            invalid("a warning or critical threshold is required")
+        );
+        ensure!(
+            check.warn.iter().chain(&check.crit).all(|t| t.is_finite()),
+            invalid("the thresholds must be finite numbers")
+        );
+        ensure!(
+            check.hysteresis.is_finite() && check.hysteresis >= 0.0,
+            invalid("the hysteresis must be a positive number")
+        );
+        ensure!(
+            check.endpoints.iter().all(|endpoint| match endpoint {
+                Endpoint::Webhook { url } | Endpoint::Slack { url } => {
+                    url.starts_with("http://") || url.starts_with("https://")
+                }
+                Endpoint::PagerDuty { routing_key } => !routing_key.is_empty(),
+            }),
+            invalid("endpoints require an HTTP URL or a PagerDuty integration key")
+        );
+        ensure!(
+            !self.config.checks.contains_key(&check.name),
+            CheckAlreadyExists { name: &check.name }
+        );
+        ensure!(
+            self.config.databases.contains_key(&check.db_name),
+            DatabaseNotFound { db: &check.db_name }
+        );
+
+        self.config.checks.insert(check.name.clone(), check);
+        Ok(())
+    }
+
+    /// Removes a check, and its state
+    pub fn delete_check(&mut self, name: &str) -> Result<Check> {
+        self.require_id()?;
+
+        let check = self
+            .config
+            .checks
+            .remove(name)
+            .context(CheckNotFound { name })?;
+        self.check_history.remove(name);
+        Ok(check)
+    }
+
+    /// Returns the checks of the server, ordered by name, with their state
+    pub fn checks(&self) -> Vec<(Check, CheckState)> {
+        self.config
+            .checks
+            .values()
+            .map(|check| (check.clone(), self.check_history.state(&check.name)))
+            .collect()
+    }
+
+    /// Evaluates each check that hasn't covered the latest window before `now`, and returns
+    /// the changes of level to notify the endpoints of
+    pub async fn run_due_checks(&self, now: DateTime<Utc>) -> Vec<Notification> {
+        let mut notifications = vec![];
+        for check in self.config.checks.values() {
+            let window = check.window(now);
+            if !self.check_history.is_due(&check.name, window.1) {
+                continue;
+            }
+
+            let result = self
+                .query_local(&check.db_name, &check.query_for(window))
+                .instrument(info_span!("check", name = check.name.as_str()))
+                .await
+                .map(|batches| first_value(&batches))
+                .map_err(|e| e.to_string());
+            let status = match &result {
+                Ok(_) => "success",
+                Err(e) => {
+                    warn!(check = check.name.as_str(), error = %e, "check failed");
+                    "failure"
+                }
+            };
+            metrics::registry()
+                .counter(
+                    "cluster_check_evaluations_total",
+                    "Evaluations of threshold checks",
+                    &[("status", status)],
+                )
+                .inc();
+
+            let value = result.as_ref().ok().copied().flatten();
+            let (previous, level) = self.check_history.record(check, window.1, now, result);
+            if let (true, Some(value)) = (previous != level, value) {
+                info!(
+                    "check {} changed from {} to {} with value {}",
+                    check.name, previous, level, value
+                );
+                notifications.push(Notification {
+                    check: check.name.clone(),
+                    db_name: check.db_name.clone(),
+                    previous,
+                    level,
+                    value,
+                    time: now,
+                    endpoints: check.endpoints.clone(),
+                });
+            }
+        }
+        notifications
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{parsed_lines, Result, TestConnectionManager},
+        Error,
+    };
+    use arrow_deps::arrow::{
+        array::{ArrayRef, StringArray},
+        datatypes::{Field, Schema},
+    };
+    use data_types::database_rules::DatabaseRules;
+    use object_store::{InMemory, ObjectStore, ObjectStoreIntegration};
+    use std::sync::Arc;
+
+    fn check(direction: Direction, warn: f64, crit: f64) -> Check {
+        Check {
+            name: "cpu_high".to_string(),
+            db_name: "foo".to_string(),
+            query: "select max(usage) from cpu where time >= $start and time < $end".to_string(),
+            every: Duration::from_secs(60),
+            direction,
+            warn: Some(warn),
+            crit: Some(crit),
+            hysteresis: 5.0,
+            endpoints: vec![],
+        }
+    }
+
+    #[test]
+    fn levels_with_hysteresis() {
+        let check = check(Direction::Above, 80.0, 90.0);
+        assert_eq!(check.level(50.0, Level::Ok), Level::Ok);
+        assert_eq!(check.level(80.0, Level::Ok), Level::Warn);
+        assert_eq!(check.level(95.0, Level::Warn), Level::Crit);
+        // the check stays critical until the value is 5 below the threshold
+        assert_eq!(check.level(87.0, Level::Crit), Level::Crit);
+        assert_eq!(check.level(84.0, Level::Crit), Level::Warn);
+        assert_eq!(check.level(76.0, Level::Warn), Level::Warn);
+        assert_eq!(check.level(74.0, Level::Warn), Level::Ok);
+        assert_eq!(check.level(77.0, Level::Ok), Level::Ok);
+
+        let check = check(Direction::Below, 20.0, 10.0);
+        assert_eq!(check.level(30.0, Level::Ok), Level::Ok);
+        assert_eq!(check.level(5.0, Level::Ok), Level::Crit);
+        assert_eq!(check.level(14.0, Level::Crit), Level::Crit);
+        assert_eq!(check.level(16.0, Level::Crit), Level::Warn);
+        assert_eq!(check.level(26.0, Level::Warn), Level::Ok);
+    }
+
+    #[test]
+    fn records_states() {
+        let history = CheckHistory::default();
+        let check = check(Direction::Above, 80.0, 90.0);
+        let now = Utc::now();
+        let (_, end) = check.window(now);
+        assert!(history.is_due(&check.name, end));
+
+        assert_eq!(
+            history.record(&check, end, now, Ok(Some(95.0))),
+            (Level::Ok, Level::Crit)
+        );
+        assert!(!history.is_due(&check.name, end));
+
+        // failed evaluations and evaluations without a value keep the level
+        assert_eq!(
+            history.record(&check, end + 1, now, Err("boom".to_string())),
+            (Level::Crit, Level::Crit)
+        );
+        let state = history.state(&check.name);
+        assert_eq!(state.value, Some(95.0));
+        assert_eq!(state.error.as_deref(), Some("boom"));
+        assert_eq!(
+            history.record(&check, end + 2, now, Ok(None)),
+            (Level::Crit, Level::Crit)
+        );
+        assert_eq!(history.state(&check.name).error, None);
+
+        history.remove(&check.name);
+        assert_eq!(history.state(&check.name), CheckState::default());
+    }
+
+    #[test]
+    fn finds_first_value() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("host", DataType::Utf8, false),
+            Field::new("usage", DataType::Float64, true),
+            Field::new("count", DataType::Int64, false),
+        ]));
+        let batch = |usage: Vec<Option<f64>>| {
+            let rows = usage.len();
+            RecordBatch::try_new(
+                Arc::clone(&schema),
+                vec![
+                    Arc::new(StringArray::from(vec!["a"; rows])) as ArrayRef,
+                    Arc::new(Float64Array::from(usage)),
+                    Arc::new(Int64Array::from(vec![3; rows])),
+                ],
+            )
+            .unwrap()
+        };
+        // null values are skipped
+        assert_eq!(first_value(&[batch(vec![None, Some(0.5)])]), Some(3.0));
+        assert_eq!(
+            first_value(&[batch(vec![]), batch(vec![Some(0.5)])]),
+            Some(0.5)
+        );
+        assert_eq!(first_value(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn threshold_checks() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        // 12:01 and 12:04, within the window of the evaluation at 12:05:30
+        let lines = parsed_lines(
+            "cpu,host=a usage=85 1604232060000000000
+             cpu,host=b usage=95 1604232240000000000",
+        );
+        server.write_lines("foo", &lines).await?;
+
+        let check = Check {
+            name: "cpu_high".to_string(),
+            db_name: "foo".to_string(),
+            query: "select max(usage) from cpu where time >= $start and time < $end".to_string(),
+            every: Duration::from_secs(300),
+            direction: Direction::Above,
+            warn: Some(80.0),
+            crit: Some(90.0),
+            hysteresis: 5.0,
+            endpoints: vec![Endpoint::Slack {
+                url: "https://hooks.slack.com/services/T0/B0/x".to_string(),
+            }],
+        };
+        server.create_check(check.clone())?;
+
+        let err = server.create_check(check.clone()).unwrap_err();
+        assert!(matches!(err, Error::CheckAlreadyExists { .. }), "{}", err);
+        let err = server
+            .create_check(Check {
+                name: "other".to_string(),
+                warn: None,
+                crit: None,
+                ..check.clone()
+            })
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidCheck { .. }), "{}", err);
+        let err = server
+            .create_check(Check {
+                name: "other".to_string(),
+                endpoints: vec![Endpoint::Webhook {
+                    url: "localhost".to_string(),
+                }],
+                ..check.clone()
+            })
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidCheck { .. }), "{}", err);
+
+        let now = DateTime::parse_from_rfc3339("2020-11-01T12:05:30Z")?.with_timezone(&Utc);
+        let notifications = server.run_due_checks(now).await;
+        assert_eq!(
+            notifications,
+            vec![Notification {
+                check: "cpu_high".to_string(),
+                db_name: "foo".to_string(),
+                previous: Level::Ok,
+                level: Level::Crit,
+                value: 95.0,
+                time: now,
+                endpoints: check.endpoints.clone(),
+            }]
+        );
+        // the window was evaluated already
+        assert!(server.run_due_checks(now).await.is_empty());
+
+        // 87 is within the hysteresis of the critical threshold
+        let later = now + chrono::Duration::minutes(5);
+        server
+            .write_lines(
+                "foo",
+                &parsed_lines("cpu,host=a usage=87 1604232360000000000"),
+            )
+            .await?;
+        assert!(server.run_due_checks(later).await.is_empty());
+        let later = later + chrono::Duration::minutes(5);
+        server
+            .write_lines(
+                "foo",
+                &parsed_lines("cpu,host=a usage=50 1604232660000000000"),
+            )
+            .await?;
+        let notifications = server.run_due_checks(later).await;
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].previous, Level::Crit);
+        assert_eq!(notifications[0].level, Level::Ok);
+
+        let (_, state) = &server.checks()[0];
+        assert_eq!(state.level, Level::Ok);
+        assert_eq!(state.value, Some(50.0));
+
+        // the checks are part of the configuration
+        server.store_configuration().await?;
+        let store = match &server.store.0 {
+            ObjectStoreIntegration::InMemory(in_mem) => in_mem.clone().await,
+            _ => panic!("wrong type"),
+        };
+        let mut restarted = Server::new(
+            TestConnectionManager::new(),
+            ObjectStore::new_in_memory(store),
+        );
+        restarted.load_configuration(1).await?;
+        assert_eq!(restarted.checks()[0].0, check);
+
+        restarted.delete_check("cpu_high")?;
+        assert!(restarted.checks().is_empty());
+        let err = restarted.delete_check("cpu_high").unwrap_err();
+        assert!(matches!(err, Error::CheckNotFound { .. }), "{}", err);
+
+        Ok(())
+    }
+}
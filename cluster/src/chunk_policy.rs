@@ -0,0 +1,210 @@
+//! This module contains the policies operators give the chunks of the read buffer of a
+//! database, to keep the chunks queried by hot dashboards in memory while bulk history ages
+//! out quickly. A policy applies to one chunk of a partition, or to every chunk of the
+//! partition, and either:
+//!
+//! * pins the chunks, which are then never dropped from the read buffer to free memory, or
+//! * gives the chunks a TTL, after which they are dropped from the read buffer even if the
+//!   retention period of the database keeps the rest of its data longer.
+//!
+//! The policy of a chunk takes precedence over the policy of its partition. Policies are part
+//! of the configuration of the server.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The policy of a chunk, or of every chunk of a partition
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkPolicy {
+    pub partition_key: String,
+    /// The chunk of the partition the policy applies to, or every chunk of the partition if
+    /// none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_id: Option<u32>,
+    /// Whether the chunks are kept in the read buffer when memory runs low
+    #[serde(default)]
+    pub pinned: bool,
+    /// How long the chunks stay in the read buffer once moved there, if limited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<Duration>,
+}
+
+impl ChunkPolicy {
+    /// Returns why the policy is invalid, if it is
+    pub fn validate(&self) -> Result<(), String> {
+        if self.partition_key.is_empty() {
+            return Err("the partition key is required".to_string());
+        }
+        match (self.pinned, self.ttl) {
+            (true, Some(_)) => Err("pinned chunks can't have a TTL".to_string()),
+            (false, None) => Err("the policy must pin the chunks or give them a TTL".to_string()),
+            (false, Some(ttl)) if ttl == Duration::from_secs(0) => {
+                Err("the TTL must be positive".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The chunk policies of a database, ordered by partition key, with the policy of a whole
+/// partition before the policies of its chunks
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ChunkPolicies {
+    policies: Vec<ChunkPolicy>,
+}
+
+impl ChunkPolicies {
+    /// Sets `policy`, replacing the policy of the same chunk or partition if there is one
+    pub fn set(&mut self, policy: ChunkPolicy) {
+        match self
+            .policies
+            .binary_search_by(|p| key(p).cmp(&key(&policy)))
+        {
+            Ok(index) => self.policies[index] = policy,
+            Err(index) => self.policies.insert(index, policy),
+        }
+    }
+
+    /// Removes the policy of the chunk `chunk_id` of the partition, or of the whole partition if
+    /// none, returning it if there was one
+    pub fn remove(&mut self, partition_key: &str, chunk_id: Option<u32>) -> Option<ChunkPolicy> {
+        let index = self
+            .policies
+            .iter()
+            .position(|p| p.partition_key == partition_key && p.chunk_id == chunk_id)?;
+        Some(self.policies.remove(index))
+    }
+
+    pub fn policies(&self) -> &[ChunkPolicy] {
+        &self.policies
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    /// Returns the policy the chunk `chunk_id` of the partition follows: its own policy, or
+    /// else the policy of the partition
+    pub fn get(&self, partition_key: &str, chunk_id: u32) -> Option<&ChunkPolicy> {
+        let mut partition_policy = None;
+        for policy in self
+            .policies
+            .iter()
+            .filter(|p| p.partition_key == partition_key)
+        {
+            match policy.chunk_id {
+                Some(id) if id == chunk_id => return Some(policy),
+                None => partition_policy = Some(policy),
+                _ => {}
+            }
+        }
+        partition_policy
+    }
+
+    /// Returns true if the chunk is pinned in the read buffer
+    pub fn is_pinned(&self, partition_key: &str, chunk_id: u32) -> bool {
+        self.get(partition_key, chunk_id)
+            .map_or(false, |policy| policy.pinned)
+    }
+
+    /// Returns true if the TTL of the chunk, moved to the read buffer at `loaded_at`, has
+    /// elapsed at `now`
+    pub fn is_expired(
+        &self,
+        partition_key: &str,
+        chunk_id: u32,
+        loaded_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let ttl = match self.get(partition_key, chunk_id).and_then(|p| p.ttl) {
+            Some(ttl) => ttl,
+            None => return false,
+        };
+        match chrono::Duration::from_std(ttl) {
+            Ok(ttl) => loaded_at + ttl <= now,
+            // a TTL too long to represent never elapses
+            Err(_) => false,
+        }
+    }
+}
+
+/// Sorts the policy of a whole partition before the policies of its chunks
+fn key(policy: &ChunkPolicy) -> (&str, Option<u32>) {
+    (&policy.partition_key, policy.chunk_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(partition_key: &str, chunk_id: Option<u32>, ttl: Option<u64>) -> ChunkPolicy {
+        ChunkPolicy {
+            partition_key: partition_key.to_string(),
+            chunk_id,
+            pinned: ttl.is_none(),
+            ttl: ttl.map(Duration::from_secs),
+        }
+    }
+
+    #[test]
+    fn chunk_policies_take_precedence() {
+        let mut policies = ChunkPolicies::default();
+        policies.set(policy("2020-11-02", Some(1), None));
+        policies.set(policy("2020-11-02", None, Some(60)));
+        policies.set(policy("2020-11-01", None, None));
+        assert_eq!(
+            policies
+                .policies()
+                .iter()
+                .map(|p| (p.partition_key.as_str(), p.chunk_id))
+                .collect::<Vec<_>>(),
+            vec![
+                ("2020-11-01", None),
+                ("2020-11-02", None),
+                ("2020-11-02", Some(1))
+            ]
+        );
+
+        assert!(policies.is_pinned("2020-11-01", 7));
+        assert!(policies.is_pinned("2020-11-02", 1));
+        assert!(!policies.is_pinned("2020-11-02", 2));
+        assert!(!policies.is_pinned("2020-11-03", 1));
+
+        let loaded_at = Utc::now();
+        let later = loaded_at + chrono::Duration::seconds(60);
+        assert!(policies.is_expired("2020-11-02", 2, loaded_at, later));
+        assert!(!policies.is_expired("2020-11-02", 2, loaded_at, loaded_at));
+        assert!(!policies.is_expired("2020-11-02", 1, loaded_at, later));
+
+        // setting a policy again replaces it
+        policies.set(policy("2020-11-02", Some(1), Some(10)));
+        assert_eq!(policies.policies().len(), 3);
+        assert!(policies.is_expired("2020-11-02", 1, loaded_at, later));
+
+        assert!(policies.remove("2020-11-02", None).is_some());
+        assert!(policies.remove("2020-11-02", None).is_none());
+        assert!(!policies.is_expired("2020-11-02", 2, loaded_at, later));
+    }
+
+    #[test]
+    fn validates_policies() {
+        assert!(policy("2020-11-01", None, None).validate().is_ok());
+        assert!(policy("2020-11-01", Some(1), Some(60)).validate().is_ok());
+        assert!(policy("", None, None).validate().is_err());
+        assert!(policy("2020-11-01", None, Some(0)).validate().is_err());
+
+        let pinned_with_ttl = ChunkPolicy {
+            pinned: true,
+            ..policy("2020-11-01", None, Some(60))
+        };
+        assert!(pinned_with_ttl.validate().is_err());
+        let neither = ChunkPolicy {
+            pinned: false,
+            ..policy("2020-11-01", None, None)
+        };
+        assert!(neither.validate().is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! This module contains the planning of the compaction of the Parquet files persisted for a
+//! partition. Each persist of a chunk writes a new file, so a partition that is persisted often
+//! ends up with many small files, which are slow to query. Planning groups the small files of
+//! each table of a partition into batches that, once merged, come close to a target file size.
+//!
+//! Only files sorted by the same sort key are merged together, so that the merged file can keep
+//! the sort order of its inputs.
+//!
+//! The server executes the plans, see `Server::compact_small_chunks`: it merges the chunks of
+//! each plan into one, swaps them in the catalog and deletes their files once the catalog is
+//! stored.
+
+use std::collections::BTreeMap;
+
+/// A Parquet file persisted to object storage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistedFile {
+    /// The partition the data in the file belongs to
+    pub partition_key: String,
+    /// The table whose rows the file holds
+    pub table_name: String,
+    /// The id of the chunk persisted in the file
+    pub chunk_id: u32,
+    /// The location of the file in object storage
+    pub location: String,
+    /// The size of the file, in bytes
+    pub size_bytes: usize,
+    /// The columns the rows of the file are sorted by, most significant first
+    pub sort_key: Vec<String>,
+}
+
+/// Controls which files get compacted together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionConfig {
+    /// The size compacted files should not exceed. Files at least this large are never
+    /// compacted.
+    pub target_file_size: usize,
+    /// The minimum number of files worth merging in one compaction
+    pub min_files: usize,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            target_file_size: 100 * 1024 * 1024,
+            min_files: 2,
+        }
+    }
+}
+
+/// A set of files of one table of a partition to merge into a single file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionPlan {
+    pub partition_key: String,
+    pub table_name: String,
+    /// The sort key shared by the input files, which the merged file keeps
+    pub sort_key: Vec<String>,
+    /// The locations of the files to merge, smallest first
+    pub inputs: Vec<String>,
+    /// The ids of the chunks of the files to merge, in the order of `inputs`
+    pub chunk_ids: Vec<u32>,
+    /// The combined size of the input files, in bytes
+    pub total_bytes: usize,
+}
+
+/// Groups the small files of each table of a partition into compaction plans, keeping files with
+/// different sort keys apart. Files are packed smallest first, so that each plan merges as many
+/// files as possible without its combined size exceeding the target file size. Groups with
+/// fewer than `config.min_files` files are not worth compacting and are left out.
+pub fn plan_compactions(files: &[PersistedFile], config: &CompactionConfig) -> Vec<CompactionPlan> {
+    let mut by_partition: BTreeMap<(&str, &str, &[String]), Vec<&PersistedFile>> = BTreeMap::new();
+    for file in files {
+        if file.size_bytes < config.target_file_size {
+            by_partition
+                .entry((
+                    file.partition_key.as_str(),
+                    file.table_name.as_str(),
+                    file.sort_key.as_slice(),
+                ))
+                .or_default()
+                .push(file);
+        }
+    }
+
+    let mut plans = vec![];
+    for ((partition_key, table_name, sort_key), mut files) in by_partition {
+        files.sort_by(|a, b| {
+            a.size_bytes
+                .cmp(&b.size_bytes)
+                .then_with(|| a.location.cmp(&b.location))
+        });
+
+        let new_plan = || CompactionPlan {
+            partition_key: partition_key.to_string(),
+            table_name: table_name.to_string(),
+            sort_key: sort_key.to_vec(),
+            inputs: vec![],
+            chunk_ids: vec![],
+            total_bytes: 0,
+        };
+        let mut current = new_plan();
+
+        for file in files {
+            if current.total_bytes + file.size_bytes > config.target_file_size {
+                let full = std::mem::replace(&mut current, new_plan());
+                if full.inputs.len() >= config.min_files {
+                    plans.push(full);
+                }
+            }
+
+            current.inputs.push(file.location.clone());
+            current.chunk_ids.push(file.chunk_id);
+            current.total_bytes += file.size_bytes;
+        }
+
+        if current.inputs.len() >= config.min_files {
+            plans.push(current);
+        }
+    }
+
+    plans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(partition_key: &str, location: &str, size_bytes: usize) -> PersistedFile {
+        PersistedFile {
+            partition_key: partition_key.to_string(),
+            table_name: "cpu".to_string(),
+            chunk_id: size_bytes as u32,
+            location: location.to_string(),
+            size_bytes,
+            sort_key: vec!["host".to_string(), "time".to_string()],
+        }
+    }
+
+    #[test]
+    fn groups_small_files_per_partition() {
+        let config = CompactionConfig {
+            target_file_size: 100,
+            min_files: 2,
+        };
+
+        let files = vec![
+            file("p1", "p1/a.parquet", 40),
+            file("p1", "p1/b.parquet", 10),
+            file("p1", "p1/c.parquet", 30),
+            file("p1", "p1/d.parquet", 50),
+            file("p1", "p1/big.parquet", 150),
+            file("p2", "p2/a.parquet", 20),
+            file("p3", "p3/a.parquet", 20),
+            file("p3", "p3/b.parquet", 20),
+        ];
+
+        let plans = plan_compactions(&files, &config);
+
+        assert_eq!(
+            plans,
+            vec![
+                CompactionPlan {
+                    partition_key: "p1".to_string(),
+                    table_name: "cpu".to_string(),
+                    sort_key: vec!["host".to_string(), "time".to_string()],
+                    inputs: vec![
+                        "p1/b.parquet".to_string(),
+                        "p1/c.parquet".to_string(),
+                        "p1/a.parquet".to_string(),
+                    ],
+                    chunk_ids: vec![10, 30, 40],
+                    total_bytes: 80,
+                },
+                CompactionPlan {
+                    partition_key: "p3".to_string(),
+                    table_name: "cpu".to_string(),
+                    sort_key: vec!["host".to_string(), "time".to_string()],
+                    inputs: vec!["p3/a.parquet".to_string(), "p3/b.parquet".to_string()],
+                    chunk_ids: vec![20, 20],
+                    total_bytes: 40,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_to_compact() {
+        let config = CompactionConfig::default();
+        assert!(plan_compactions(&[], &config).is_empty());
+
+        let files = vec![file("p1", "p1/a.parquet", 1)];
+        assert!(plan_compactions(&files, &config).is_empty());
+
+        // files sorted differently can't be merged into one sorted file
+        let files = vec![
+            file("p1", "p1/a.parquet", 1),
+            PersistedFile {
+                sort_key: vec!["time".to_string()],
+                ..file("p1", "p1/b.parquet", 1)
+            },
+        ];
+        assert!(plan_compactions(&files, &config).is_empty());
+
+        // nor can the files of different tables
+        let files = vec![
+            file("p1", "p1/cpu.parquet", 1),
+            PersistedFile {
+                table_name: "mem".to_string(),
+                ..file("p1", "p1/mem.parquet", 1)
+            },
+        ];
+        assert!(plan_compactions(&files, &config).is_empty());
+    }
+}
@@ -0,0 +1,254 @@
+//! This module contains the `storage::DatabaseStore` of the databases of a `Server`, so that
+//! the protocols written against that trait, such as the HTTP API, the InfluxDB v1 API, the
+//! UDP and unix socket listeners and the storage gRPC service, share the databases of the
+//! server with its gRPC and other APIs rather than keeping their own.
+//!
+//! Writes go through `Server::write_lines`, so they are checked against the schema and
+//! timestamp rules of their database, deduplicated, stored in the local buffer and its WAL,
+//! and replicated as the rules say. SQL queries go through `Server::query_local`, which scans
+//! every tier of the database. The plans of the storage gRPC service are built over the local
+//! buffer.
+
+use std::{sync::Arc, time::Duration};
+
+use arrow_deps::arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use data_types::{data::ReplicatedWrite, database_rules::DatabaseRules};
+use influxdb_line_protocol::ParsedLine;
+use snafu::OptionExt;
+use storage::{
+    access::RowAccess,
+    autocomplete::{TagValues, TagValuesQuery},
+    exec::{FieldListPlan, GroupedSeriesSetPlans, SeriesSetPlans, StringSetPlan},
+    predicate::Predicate,
+    validate::LineDiagnostic,
+    Database, DatabaseStore,
+};
+use tokio::sync::RwLock;
+use tracing::warn;
+use write_buffer::Db as WriteBufferDb;
+
+use crate::{
+    ConnectionManager, DatabaseNotFound, Error, NoLocalBuffer, Result, Server,
+    INGEST_PAUSED_RETRY_AFTER,
+};
+
+/// The databases of a `Server`. Databases that don't exist are created with `rules` when
+/// written to, if set, and writes to them fail with `DatabaseNotFound` otherwise, such as on
+/// a router, which only routes the writes of the databases whose rules say where to.
+#[derive(Debug)]
+pub struct ServerDatabases<M: ConnectionManager> {
+    server: Arc<RwLock<Server<M>>>,
+    rules: Option<DatabaseRules>,
+}
+
+impl<M: ConnectionManager> ServerDatabases<M> {
+    pub fn new(server: Arc<RwLock<Server<M>>>, rules: Option<DatabaseRules>) -> Self {
+        Self { server, rules }
+    }
+
+    pub fn server(&self) -> &Arc<RwLock<Server<M>>> {
+        &self.server
+    }
+
+    fn database(&self, server: &Server<M>, name: &str) -> Option<Arc<ServerDatabase<M>>> {
+        let buffer = server.config.databases.get(name)?.buffer.clone();
+        Some(Arc::new(ServerDatabase {
+            server: Arc::clone(&self.server),
+            name: name.to_string(),
+            buffer,
+        }))
+    }
+}
+
+#[async_trait]
+impl<M> DatabaseStore for ServerDatabases<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    type Database = ServerDatabase<M>;
+    type Error = Error;
+
+    async fn db_names_sorted(&self) -> Vec<String> {
+        // the databases are kept in a BTreeMap, so the names are sorted already
+        self.server.read().await.db_names()
+    }
+
+    async fn db(&self, name: &str) -> Option<Arc<Self::Database>> {
+        let server = self.server.read().await;
+        self.database(&server, name)
+    }
+
+    async fn db_or_create(&self, name: &str) -> Result<Arc<Self::Database>> {
+        if let Some(db) = self.db(name).await {
+            return Ok(db);
+        }
+        let rules = self.rules.as_ref().context(DatabaseNotFound { db: name })?;
+
+        let mut server = self.server.write().await;
+        // another write may have created the database while the lock was released
+        if !server.config.databases.contains_key(name) {
+            server.create_database(name, rules.clone()).await?;
+            // the database is still created from its WAL if the server restarts before the
+            // configuration is stored
+            if let Err(e) = server.store_configuration().await {
+                warn!(db = name, error = %e, "configuration not stored after creating database");
+            }
+        }
+        Ok(self.database(&server, name).expect("database just created"))
+    }
+}
+
+/// A database of a `Server`, along with its local buffer if it stores locally
+#[derive(Debug)]
+pub struct ServerDatabase<M: ConnectionManager> {
+    server: Arc<RwLock<Server<M>>>,
+    name: String,
+    buffer: Option<Arc<WriteBufferDb>>,
+}
+
+impl<M: ConnectionManager> ServerDatabase<M> {
+    fn buffer(&self) -> Result<&WriteBufferDb> {
+        self.buffer
+            .as_deref()
+            .context(NoLocalBuffer { db: &self.name })
+    }
+}
+
+fn buffer_error(e: write_buffer::Error) -> Error {
+    Error::UnknownDatabaseError {
+        source: Box::new(e),
+    }
+}
+
+#[async_trait]
+impl<M> Database for ServerDatabase<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    type Error = Error;
+
+    async fn write_lines(&self, lines: &[ParsedLine<'_>]) -> Result<()> {
+        self.server
+            .read()
+            .await
+            .write_lines(&self.name, lines)
+            .await
+    }
+
+    async fn validate_lines(&self, lines: &[ParsedLine<'_>]) -> Result<Vec<LineDiagnostic>> {
+        self.server
+            .read()
+            .await
+            .validate_lines(&self.name, lines)
+            .await
+    }
+
+    async fn store_replicated_write(&self, write: &ReplicatedWrite) -> Result<()> {
+        self.buffer()?
+            .store_replicated_write(write)
+            .await
+            .map_err(buffer_error)
+    }
+
+    fn data_version(&self) -> Option<u64> {
+        self.buffer.as_ref()?.data_version()
+    }
+
+    fn write_retry_after(&self, error: &Error) -> Option<Duration> {
+        match error {
+            Error::WriteThrottled { source, .. } => source.retry_after(),
+            Error::IngestPaused { .. } => Some(INGEST_PAUSED_RETRY_AFTER),
+            _ => None,
+        }
+    }
+
+    fn write_rejected(&self, error: &Error) -> bool {
+        matches!(
+            error,
+            Error::SchemaViolations { .. }
+                | Error::TimestampViolations { .. }
+                | Error::ReadOnlyReplica { .. }
+        )
+    }
+
+    async fn query(&self, query: &str) -> Result<Vec<RecordBatch>> {
+        self.server
+            .read()
+            .await
+            .query_local(&self.name, query)
+            .await
+    }
+
+    async fn query_with_access(&self, query: &str, access: &RowAccess) -> Result<Vec<RecordBatch>> {
+        self.server
+            .read()
+            .await
+            .query_local_with_access(&self.name, query, access)
+            .await
+    }
+
+    async fn table_names(&self, predicate: Predicate) -> Result<StringSetPlan> {
+        self.buffer()?
+            .table_names(predicate)
+            .await
+            .map_err(buffer_error)
+    }
+
+    async fn tag_column_names(&self, predicate: Predicate) -> Result<StringSetPlan> {
+        self.buffer()?
+            .tag_column_names(predicate)
+            .await
+            .map_err(buffer_error)
+    }
+
+    async fn field_columns(&self, predicate: Predicate) -> Result<FieldListPlan> {
+        self.buffer()?
+            .field_columns(predicate)
+            .await
+            .map_err(buffer_error)
+    }
+
+    async fn column_values(
+        &self,
+        column_name: &str,
+        predicate: Predicate,
+    ) -> Result<StringSetPlan> {
+        self.buffer()?
+            .column_values(column_name, predicate)
+            .await
+            .map_err(buffer_error)
+    }
+
+    async fn query_series(&self, predicate: Predicate) -> Result<SeriesSetPlans> {
+        self.buffer()?
+            .query_series(predicate)
+            .await
+            .map_err(buffer_error)
+    }
+
+    async fn query_groups(
+        &self,
+        predicate: Predicate,
+        group_columns: Vec<String>,
+    ) -> Result<GroupedSeriesSetPlans> {
+        self.buffer()?
+            .query_groups(predicate, group_columns)
+            .await
+            .map_err(buffer_error)
+    }
+
+    async fn tag_values_with_prefix(&self, query: &TagValuesQuery) -> Result<TagValues> {
+        self.buffer()?
+            .tag_values_with_prefix(query)
+            .await
+            .map_err(buffer_error)
+    }
+
+    async fn table_to_arrow(&self, table_name: &str, columns: &[&str]) -> Result<Vec<RecordBatch>> {
+        self.buffer()?
+            .table_to_arrow(table_name, columns)
+            .await
+            .map_err(buffer_error)
+    }
+}
@@ -0,0 +1,476 @@
+//! This module contains the write deduplication window of databases, which drops the points
+//! that were already written recently. Upstream queues that deliver writes at least once
+//! redeliver whole batches after a failure, and their points are identical to the points
+//! written the first time.
+//!
+//! Points are identical when they have the same series key, timestamp and fields, which are
+//! remembered for as long as the window of the database, whether the points are written as
+//! lines or as the rows of entries. Points without a timestamp are stamped with the time of
+//! the write, so they are never duplicates.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use data_types::entry::{ColumnValues, Entry, LogicalColumnType};
+use influxdb_line_protocol::{FieldValue, ParsedLine};
+
+/// The most points remembered per database. The oldest points are forgotten first, before
+/// the end of the window, when more points are written within a window.
+pub const MAX_POINTS: usize = 10_000_000;
+
+/// The series key, timestamp and fields of a point, which are the same for identical points.
+/// Tags and fields are in the order of their names, as their order in a line doesn't matter.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PointKey(Arc<[u8]>);
+
+/// A field value of a point, as it is compared
+#[derive(Debug, Clone, Copy)]
+enum Value<'a> {
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    String(&'a str),
+}
+
+impl PointKey {
+    fn new(
+        measurement: &str,
+        mut tags: Vec<(&str, &str)>,
+        timestamp: i64,
+        mut fields: Vec<(&str, Value<'_>)>,
+    ) -> Self {
+        tags.sort_unstable();
+        fields.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        // each string is prefixed with its length, so that no two points share a key
+        fn push_str(key: &mut Vec<u8>, s: &str) {
+            key.extend_from_slice(&(s.len() as u64).to_le_bytes());
+            key.extend_from_slice(s.as_bytes());
+        }
+
+        let mut key = vec![];
+        push_str(&mut key, measurement);
+        key.extend_from_slice(&(tags.len() as u64).to_le_bytes());
+        for (name, value) in tags {
+            push_str(&mut key, name);
+            push_str(&mut key, value);
+        }
+        key.extend_from_slice(&timestamp.to_le_bytes());
+        for (name, value) in fields {
+            push_str(&mut key, name);
+            match value {
+                Value::I64(v) => {
+                    key.push(0);
+                    key.extend_from_slice(&v.to_le_bytes());
+                }
+                Value::F64(v) => {
+                    key.push(1);
+                    key.extend_from_slice(&v.to_bits().to_le_bytes());
+                }
+                Value::Bool(v) => key.extend_from_slice(&[2, v as u8]),
+                Value::String(v) => {
+                    key.push(3);
+                    push_str(&mut key, v);
+                }
+            }
+        }
+
+        Self(key.into())
+    }
+}
+
+/// Returns the key of the point of `line`, if it has a timestamp
+pub fn line_key(line: &ParsedLine<'_>) -> Option<PointKey> {
+    let timestamp = line.timestamp?;
+    let tags = line
+        .series
+        .tag_set
+        .iter()
+        .flatten()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    let fields = line
+        .field_set
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                FieldValue::I64(v) => Value::I64(*v),
+                FieldValue::F64(v) => Value::F64(*v),
+                FieldValue::String(v) => Value::String(v.as_str()),
+                FieldValue::Boolean(v) => Value::Bool(*v),
+            };
+            (name.as_str(), value)
+        })
+        .collect();
+
+    Some(PointKey::new(
+        line.series.measurement.as_str(),
+        tags,
+        timestamp,
+        fields,
+    ))
+}
+
+/// Returns the keys of the points of the rows of `entry`, in the order of its rows. The rows
+/// of an entry all have a timestamp, and their key is the key of the line they were converted
+/// from.
+pub fn entry_keys(entry: &Entry) -> Vec<Option<PointKey>> {
+    let mut keys = vec![];
+    for write in entry.partition_writes() {
+        for batch in write.table_batches() {
+            let columns: Vec<_> = batch
+                .columns()
+                .into_iter()
+                .map(|column| (column.name(), column.logical_type(), column.values()))
+                .collect();
+            for row in 0..batch.row_count() {
+                let mut tags = vec![];
+                let mut fields = vec![];
+                let mut timestamp = None;
+                for (name, logical_type, values) in &columns {
+                    let value = match values {
+                        ColumnValues::I64(v) => v[row].map(Value::I64),
+                        ColumnValues::F64(v) => v[row].map(Value::F64),
+                        ColumnValues::Bool(v) => v[row].map(Value::Bool),
+                        ColumnValues::String(v) => v[row].map(Value::String),
+                    };
+                    match (*logical_type, value) {
+                        (LogicalColumnType::Time, Some(Value::I64(v))) => timestamp = Some(v),
+                        (LogicalColumnType::Tag, Some(Value::String(v))) => tags.push((*name, v)),
+                        (LogicalColumnType::Field, Some(value)) => fields.push((*name, value)),
+                        _ => {}
+                    }
+                }
+                keys.push(
+                    timestamp.map(|timestamp| PointKey::new(batch.name(), tags, timestamp, fields)),
+                );
+            }
+        }
+    }
+    keys
+}
+
+/// Whether a point is being written or was written, and when
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Seen {
+    Reserved,
+    Written(DateTime<Utc>),
+}
+
+/// The points written to a database recently
+#[derive(Debug, Default)]
+pub struct DedupWindow {
+    /// The points written or being written, by their key
+    seen: HashMap<PointKey, Seen>,
+    /// The keys of the points written, in the order they were written
+    order: VecDeque<(DateTime<Utc>, PointKey)>,
+}
+
+impl DedupWindow {
+    /// Returns which of the points with `keys` to write at `now`: those that are not identical
+    /// to a point written within `window`, or being written, or to a previous point of `keys`.
+    /// The points without a key are always written. The points to write are reserved until
+    /// the returned reservation is recorded, once they are written, or dropped, so that
+    /// concurrent writes of the same points only write them once while the retries of failed
+    /// writes aren't dropped.
+    pub fn reserve<'a>(
+        dedup: &'a Mutex<Self>,
+        keys: Vec<Option<PointKey>>,
+        window: Duration,
+        now: DateTime<Utc>,
+    ) -> (Vec<bool>, Reservation<'a>) {
+        let mut this = dedup.lock().expect("mutex poisoned");
+        this.expire(window, now);
+
+        let mut batch = HashSet::new();
+        let mut reserved = vec![];
+        let keep = keys
+            .into_iter()
+            .map(|key| match key {
+                Some(key) => {
+                    if this.seen.contains_key(&key) || !batch.insert(key.clone()) {
+                        return false;
+                    }
+                    this.seen.insert(key.clone(), Seen::Reserved);
+                    reserved.push(key);
+                    true
+                }
+                None => true,
+            })
+            .collect();
+        (
+            keep,
+            Reservation {
+                dedup,
+                keys: reserved,
+            },
+        )
+    }
+
+    /// The number of points remembered
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// Whether no point is remembered
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Remembers the points with `keys`, written at `now`
+    fn record(&mut self, keys: Vec<PointKey>, now: DateTime<Utc>) {
+        for key in keys {
+            self.seen.insert(key.clone(), Seen::Written(now));
+            self.order.push_back((now, key));
+        }
+        while self.order.len() > MAX_POINTS {
+            self.pop_oldest();
+        }
+    }
+
+    /// Forgets the reservation of the points with `keys`, which weren't written
+    fn release(&mut self, keys: &[PointKey]) {
+        for key in keys {
+            if self.seen.get(key) == Some(&Seen::Reserved) {
+                self.seen.remove(key);
+            }
+        }
+    }
+
+    /// Forgets the points written more than `window` before `now`
+    fn expire(&mut self, window: Duration, now: DateTime<Utc>) {
+        let window =
+            chrono::Duration::from_std(window).unwrap_or_else(|_| chrono::Duration::max_value());
+        let cutoff = now.checked_sub_signed(window);
+        while let Some((time, _)) = self.order.front() {
+            if cutoff.map_or(true, |cutoff| *time > cutoff) {
+                break;
+            }
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((time, key)) = self.order.pop_front() {
+            // the point may have been written again since, and is then remembered from then
+            if self.seen.get(&key) == Some(&Seen::Written(time)) {
+                self.seen.remove(&key);
+            }
+        }
+    }
+}
+
+/// The points of a write reserved in the deduplication window of its database, which are
+/// released unless they are recorded as written
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    dedup: &'a Mutex<DedupWindow>,
+    keys: Vec<PointKey>,
+}
+
+impl<'a> Reservation<'a> {
+    /// Remembers the reserved points as written at `now`
+    pub fn record(mut self, now: DateTime<Utc>) {
+        let keys = std::mem::take(&mut self.keys);
+        if !keys.is_empty() {
+            self.dedup.lock().expect("mutex poisoned").record(keys, now);
+        }
+    }
+}
+
+impl<'a> Drop for Reservation<'a> {
+    fn drop(&mut self) {
+        if !self.keys.is_empty() {
+            self.dedup
+                .lock()
+                .expect("mutex poisoned")
+                .release(&self.keys);
+        }
+    }
+}
+
+/// Leaves out the lines that are duplicates of the points written to a database within
+/// `window` before `now`, as `DedupWindow::reserve` finds them in `dedup`, the window of the
+/// database. Returns the lines to write along with their reservation, or `None` if all the
+/// lines are duplicates.
+pub fn deduplicate_lines<'a, 'l>(
+    dedup: &'a Mutex<DedupWindow>,
+    lines: &'a [ParsedLine<'l>],
+    window: Duration,
+    now: DateTime<Utc>,
+) -> Option<(Cow<'a, [ParsedLine<'l>]>, Reservation<'a>)> {
+    let keys = lines.iter().map(line_key).collect();
+    let (keep, reservation) = DedupWindow::reserve(dedup, keys, window, now);
+    let duplicates = keep.iter().filter(|keep| !**keep).count();
+    if duplicates == 0 {
+        return Some((Cow::Borrowed(lines), reservation));
+    }
+
+    metrics::registry()
+        .counter(
+            "cluster_lines_deduplicated_total",
+            "Lines of line protocol dropped as duplicates of recent writes",
+            &[],
+        )
+        .add(duplicates as u64);
+    if duplicates == keep.len() {
+        return None;
+    }
+    let lines = lines
+        .iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(line, _)| line.clone())
+        .collect();
+    Some((Cow::Owned(lines), reservation))
+}
+
+/// Leaves out the rows of `entry` that are duplicates of recent points, like
+/// `deduplicate_lines`. Returns the entry to write along with the reservation of its rows, or
+/// `None` if all its rows are duplicates.
+pub fn deduplicate_entry(
+    dedup: &Mutex<DedupWindow>,
+    entry: Entry,
+    window: Duration,
+    now: DateTime<Utc>,
+) -> Option<(Entry, Reservation<'_>)> {
+    let keys = entry_keys(&entry);
+    let (keep, reservation) = DedupWindow::reserve(dedup, keys, window, now);
+    let duplicates = keep.iter().filter(|keep| !**keep).count();
+    if duplicates == 0 {
+        return Some((entry, reservation));
+    }
+
+    metrics::registry()
+        .counter(
+            "cluster_entry_rows_deduplicated_total",
+            "Rows of entries dropped as duplicates of recent writes",
+            &[],
+        )
+        .add(duplicates as u64);
+    if duplicates == keep.len() {
+        return None;
+    }
+    Some((entry.retain_rows(&keep), reservation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{parsed_lines, to_csv, TestConnectionManager},
+        Server,
+    };
+    use chrono::TimeZone;
+    use data_types::{database_rules::DatabaseRules, entry::lines_to_entry};
+    use influxdb_line_protocol::parse_lines;
+    use object_store::{InMemory, ObjectStore};
+
+    fn lines(lp: &str) -> Vec<ParsedLine<'_>> {
+        parse_lines(lp).map(Result::unwrap).collect()
+    }
+
+    fn keys(lp: &str) -> Vec<Option<PointKey>> {
+        lines(lp).iter().map(line_key).collect()
+    }
+
+    #[test]
+    fn identical_points() {
+        let a = keys("cpu,host=a,region=west usage=1,idle=2i 10");
+        let b = keys("cpu,region=west,host=a idle=2i,usage=1 10");
+        assert_eq!(a, b);
+
+        for other in &[
+            "cpu,host=b,region=west usage=1,idle=2i 10",
+            "cpu,host=a,region=west usage=1,idle=2i 11",
+            "cpu,host=a,region=west usage=1,idle=3i 10",
+            "cpu,host=a,region=west usage=1,idle=2 10",
+            "mem,host=a,region=west usage=1,idle=2i 10",
+        ] {
+            assert_ne!(a, keys(other));
+        }
+
+        assert_eq!(keys("cpu usage=1"), vec![None]);
+    }
+
+    #[test]
+    fn entry_points() {
+        let lp = "cpu,host=a usage=1,idle=2i 10\ncpu,host=b up=true 20\nmem,host=a msg=\"hi\" 30";
+        let entry = lines_to_entry(1, 1, &lines(lp), &DatabaseRules::default()).unwrap();
+        assert_eq!(entry_keys(&entry), keys(lp));
+    }
+
+    #[test]
+    fn window() {
+        let window = Duration::from_secs(60);
+        let start = Utc.timestamp(1_000, 0);
+        let dedup = Mutex::new(DedupWindow::default());
+        let len = || dedup.lock().unwrap().len();
+
+        let first = "cpu usage=1 10\ncpu usage=2 20\ncpu usage=1 10\ncpu usage=3";
+        let (keep, reservation) = DedupWindow::reserve(&dedup, keys(first), window, start);
+        assert_eq!(keep, vec![true, true, false, true]);
+        // the points being written are dropped from concurrent writes
+        let (keep, concurrent) = DedupWindow::reserve(&dedup, keys(first), window, start);
+        assert_eq!(keep, vec![false, false, false, true]);
+        drop(concurrent);
+        reservation.record(start);
+        assert_eq!(len(), 2);
+
+        let redelivered = "cpu usage=1 10\ncpu usage=4 20\ncpu usage=3";
+        let later = start + chrono::Duration::seconds(30);
+        let (keep, reservation) = DedupWindow::reserve(&dedup, keys(redelivered), window, later);
+        assert_eq!(keep, vec![false, true, true]);
+        reservation.record(later);
+
+        // the first points are forgotten once the window has passed
+        let (keep, _) = DedupWindow::reserve(
+            &dedup,
+            keys(first),
+            window,
+            start + chrono::Duration::seconds(61),
+        );
+        assert_eq!(keep, vec![true, true, false, true]);
+        // and their reservation is released as they weren't written
+        assert_eq!(len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dedup_window() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            dedup_window: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let lines = parsed_lines("cpu,host=a bar=1 10\ncpu,host=a bar=2 20");
+        server.write_lines("foo", &lines).await?;
+        // a redelivered write only adds its new points
+        let lines = parsed_lines("cpu,host=a bar=1 10\ncpu,host=a bar=2 20\ncpu,host=a bar=3 30");
+        server.write_lines("foo", &lines).await?;
+        server.write_lines("foo", &lines).await?;
+
+        // as are the rows of entries that were written as lines
+        let lines = parsed_lines("cpu,host=a bar=3 30\ncpu,host=a bar=4 40");
+        let entry = lines_to_entry(2, 1, &lines, &DatabaseRules::default())?;
+        server.write_entry("foo", entry.data().to_vec()).await?;
+        server.write_entry("foo", entry.into_data()).await?;
+
+        let results = server
+            .query_local("foo", "select bar from cpu order by time")
+            .await?;
+        assert_eq!(to_csv(&results), "bar\n1\n2\n3\n4\n");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+//! This module contains how the server deletes rows from the chunks of its databases persisted
+//! to object storage: a delete records a tombstone in the catalog of the database, see
+//! `tombstone`, and purging rewrites the chunks with deleted rows without them.
+
+use std::sync::Arc;
+
+use snafu::{OptionExt, ResultExt};
+
+use crate::{
+    catalog::PersistedChunk,
+    persistence::packers_from_batches,
+    query_chunk::{ParquetChunk, QueryChunk},
+    tombstone::{DeletePredicate, Tombstone},
+    ConnectionManager, DatabaseNotFound, Result, ScanningChunks, Server, StoreError,
+};
+
+impl<M: ConnectionManager> Server<M> {
+    /// Deletes the rows of the table `predicate.table_name` matching `predicate` from the
+    /// chunks of the database persisted to object storage, by recording a tombstone in its
+    /// catalog. Queries leave out the rows right away, and `purge_deleted_rows` eventually
+    /// removes them from the files. The configuration, which holds the catalog, is stored
+    /// again, so that the delete survives restarts. Rows still buffered in memory are not
+    /// deleted.
+    pub async fn delete(&self, db_name: &str, predicate: DeletePredicate) -> Result<Tombstone> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        db.ensure_writable(db_name)?;
+
+        let tombstone = db
+            .catalog
+            .lock()
+            .expect("mutex poisoned")
+            .add_tombstone(predicate);
+        self.store_configuration().await?;
+
+        Ok(tombstone)
+    }
+
+    /// Returns the deletes recorded for the persisted chunks of the database whose rows are
+    /// not purged yet
+    pub fn tombstones(&self, db_name: &str) -> Result<Vec<Tombstone>> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        Ok(db
+            .catalog
+            .lock()
+            .expect("mutex poisoned")
+            .tombstones()
+            .to_vec())
+    }
+
+    /// Rewrites the persisted chunks of every database with rows deleted by a tombstone
+    /// without those rows, and drops the tombstones that no longer apply to any chunk. A
+    /// rewritten chunk gets a new id, which the tombstones don't apply to, and keeps the sort
+    /// key of the chunk it replaces, as removing rows keeps them in order; a chunk left without
+    /// rows is removed. The files of the replaced chunks are deleted once the configuration,
+    /// which holds the catalog, is stored again. Returns the database name of each chunk
+    /// purged, with the chunk replacing it, if any. The chunks of tables with overlapping
+    /// chunks are left for `compact_overlapping_chunks`.
+    pub async fn purge_deleted_rows(
+        &self,
+    ) -> Result<Vec<(String, PersistedChunk, Option<PersistedChunk>)>> {
+        let mut purged = vec![];
+        let mut dropped_tombstones = 0;
+
+        // the writer the replicas follow purges their chunks
+        let owned = self
+            .config
+            .databases
+            .iter()
+            .filter(|(_, db)| db.replica_of.is_none());
+        for (db_name, db) in owned {
+            let to_purge: Vec<_> = {
+                let catalog = db.catalog.lock().expect("mutex poisoned");
+                catalog
+                    .chunks()
+                    .into_iter()
+                    // compacting overlapping chunks purges their deleted rows, and keeps the
+                    // rows of each point in the order of the ids of the chunks
+                    .filter(|chunk| !catalog.has_overlaps(&chunk.partition_key, &chunk.table_name))
+                    .map(|chunk| {
+                        let deletes = catalog.deletes(&chunk);
+                        (chunk, deletes)
+                    })
+                    .filter(|(_, deletes)| !deletes.is_empty())
+                    .collect()
+            };
+
+            for (chunk, deletes) in to_purge {
+                let batches = ParquetChunk::new(Arc::clone(&self.store), chunk.clone(), deletes)
+                    .table_to_arrow(&chunk.table_name, &[])
+                    .await
+                    .context(ScanningChunks)?;
+
+                let replacement = if batches.is_empty() {
+                    None
+                } else {
+                    let (schema, columns) = packers_from_batches(&chunk.table_name, &batches)?;
+                    let sort_key = chunk.sort_key.clone();
+                    Some(
+                        self.write_chunk(
+                            db_name,
+                            db,
+                            &chunk.partition_key,
+                            &schema,
+                            &columns,
+                            sort_key,
+                            vec![chunk.id],
+                        )
+                        .await?,
+                    )
+                };
+
+                db.catalog
+                    .lock()
+                    .expect("mutex poisoned")
+                    .replace_chunk(chunk.id, replacement.clone());
+                purged.push((db_name.clone(), chunk, replacement));
+            }
+
+            dropped_tombstones += db
+                .catalog
+                .lock()
+                .expect("mutex poisoned")
+                .drop_obsolete_tombstones();
+        }
+
+        if !purged.is_empty() || dropped_tombstones > 0 {
+            self.store_configuration().await?;
+        }
+        for (_, chunk, _) in &purged {
+            self.store
+                .delete(&chunk.location)
+                .await
+                .context(StoreError)?;
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{Result, TestConnectionManager};
+    use data_types::{database_rules::DatabaseRules, table_schema::DataType};
+    use futures::TryStreamExt;
+    use ingest::import::{FileFormat, SchemaMapping, TimeUnit};
+    use object_store::{InMemory, ObjectStore};
+
+    #[tokio::test]
+    async fn delete_and_purge_rows() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let mapping = SchemaMapping {
+            table: "cpu".to_string(),
+            tags: vec!["host".to_string()],
+            fields: vec![("usage".to_string(), DataType::Float)],
+            time_column: "time".to_string(),
+            time_unit: TimeUnit::Seconds,
+        };
+        let data = b"host,usage,time\na,0.1,10\nb,0.2,20\na,0.3,30\n".to_vec();
+        let table = ingest::import::convert(FileFormat::Csv, data, &mapping)?;
+        let chunk = server.import_table("foo", "p", table).await?;
+
+        let predicate = DeletePredicate {
+            table_name: "cpu".to_string(),
+            start: 0,
+            end: 25_000_000_000,
+            tags: vec![("host".to_string(), "a".to_string())]
+                .into_iter()
+                .collect(),
+        };
+        server.delete("foo", predicate).await?;
+
+        // rows persisted after the delete are kept
+        let data = b"host,usage,time\na,0.4,15\n".to_vec();
+        let table = ingest::import::convert(FileFormat::Csv, data, &mapping)?;
+        server.import_table("foo", "p", table).await?;
+
+        let query = "select host, usage from cpu order by usage";
+        let expected = vec![
+            "+------+-------+",
+            "| host | usage |",
+            "+------+-------+",
+            "| b    | 0.2   |",
+            "| a    | 0.3   |",
+            "| a    | 0.4   |",
+            "+------+-------+",
+        ];
+        let results = server.query_local("foo", query).await?;
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected.join("\n")
+        );
+
+        // the tombstone is stored with the configuration
+        let config = server.store.get("1/config.json").await?;
+        let config = config
+            .map_ok(|b| bytes::BytesMut::from(&b[..]))
+            .try_concat()
+            .await?;
+        let manager = TestConnectionManager::new();
+        let mut restored = Server::new(manager, ObjectStore::new_in_memory(InMemory::new()));
+        restored.config = serde_json::from_slice(&config)?;
+        assert_eq!(restored.tombstones("foo")?, server.tombstones("foo")?);
+        assert_eq!(server.tombstones("foo")?.len(), 1);
+
+        // purging rewrites the first chunk without the deleted rows
+        let purged = server.purge_deleted_rows().await?;
+        assert_eq!(purged.len(), 1);
+        let (db_name, old, new) = &purged[0];
+        assert_eq!((db_name.as_str(), old), ("foo", &chunk));
+        let new = new.as_ref().unwrap();
+        assert_eq!(new.row_count, 2);
+        assert_eq!(new.sort_key, vec!["host", "time"]);
+        assert_eq!(
+            (new.min_time, new.max_time),
+            (Some(20_000_000_000), Some(30_000_000_000))
+        );
+        assert!(server.store.get(&chunk.location).await.is_err());
+        assert!(server.tombstones("foo")?.is_empty());
+
+        let results = server.query_local("foo", query).await?;
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected.join("\n")
+        );
+
+        // there is nothing left to purge
+        assert!(server.purge_deleted_rows().await?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! This module contains the dimension tables of databases: small tables uploaded as CSV files,
+//! such as the owner and datacenter of each host, that queries join with the measurements of
+//! their database. The types of the columns of a table are inferred from its values.
+//!
+//! The file of a table is stored as is at `<writer id>/<db>/dimensions/<table>.csv`, and its
+//! rows are loaded into memory the first time a query reads them after a restart.
+
+use std::io::Cursor;
+
+use arrow_deps::arrow::{csv, record_batch::RecordBatch};
+use bytes::Bytes;
+use futures::stream::TryStreamExt;
+use object_store::ObjectStore;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+
+use crate::{
+    DimensionTableEmpty, DimensionTableTooLarge, InvalidDimensionTable, InvalidDimensionTableName,
+    Result, StoreError,
+};
+
+/// The most rows a dimension table may have, as its rows are kept in memory and joined with
+/// every query that names it
+pub const MAX_ROWS: usize = 100_000;
+
+/// A dimension table of a database
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DimensionTable {
+    /// The location of the CSV file of the table in object storage
+    pub location: String,
+    pub row_count: usize,
+    /// The rows of the table, once loaded
+    #[serde(skip)]
+    pub rows: Option<RecordBatch>,
+}
+
+/// The location of the CSV file of the dimension table `table_name` of database `db_name`
+pub fn location(id: u32, db_name: &str, table_name: &str) -> String {
+    format!("{}/{}/dimensions/{}.csv", id, db_name, table_name)
+}
+
+/// Returns `InvalidDimensionTableName` unless `table_name` is a plain SQL identifier, which
+/// queries can name without quoting it
+pub fn validate_name(table_name: &str) -> Result<()> {
+    ensure!(
+        table_name
+            .chars()
+            .next()
+            .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+            && table_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        InvalidDimensionTableName { table: table_name }
+    );
+    Ok(())
+}
+
+/// Converts the CSV file `data`, whose first line names the columns, to the rows of the
+/// dimension table `table_name`
+pub fn parse(table_name: &str, data: &[u8]) -> Result<RecordBatch> {
+    let reader = csv::ReaderBuilder::new()
+        .has_header(true)
+        .infer_schema(Some(MAX_ROWS))
+        .with_batch_size(MAX_ROWS + 1)
+        .build(Cursor::new(data))
+        .context(InvalidDimensionTable { table: table_name })?;
+    let mut batches = reader
+        .collect::<Result<Vec<_>, _>>()
+        .context(InvalidDimensionTable { table: table_name })?;
+
+    ensure!(
+        batches.len() <= 1 && batches.iter().all(|batch| batch.num_rows() <= MAX_ROWS),
+        DimensionTableTooLarge { table: table_name }
+    );
+    batches
+        .pop()
+        .context(DimensionTableEmpty { table: table_name })
+}
+
+/// Stores the CSV file `data` of a dimension table at `location`
+pub async fn store(store: &ObjectStore, location: &str, data: Vec<u8>) -> Result<()> {
+    let data = Bytes::from(data);
+    let len = data.len();
+    store
+        .put(
+            location,
+            futures::stream::once(async move { std::io::Result::Ok(data) }),
+            len,
+        )
+        .await
+        .context(StoreError)
+}
+
+/// Loads the rows of the dimension table `table_name` from its CSV file at `location`
+pub async fn load(store: &ObjectStore, table_name: &str, location: &str) -> Result<RecordBatch> {
+    let data = store
+        .get(location)
+        .await
+        .context(StoreError)?
+        .map_ok(|b| bytes::BytesMut::from(&b[..]))
+        .try_concat()
+        .await
+        .context(StoreError)?;
+    parse(table_name, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use arrow_deps::arrow::datatypes::DataType;
+    use object_store::InMemory;
+
+    #[tokio::test]
+    async fn dimension_tables() -> Result<(), Box<dyn std::error::Error>> {
+        let data = b"host,datacenter,cores\na,us-east,8\nb,eu-west,16\n".to_vec();
+        let rows = parse("hosts", &data)?;
+        assert_eq!(rows.num_rows(), 2);
+        let schema = rows.schema();
+        assert_eq!(schema.field(1).data_type(), &DataType::Utf8);
+        assert_eq!(schema.field(2).data_type(), &DataType::Int64);
+
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let location = location(1, "foo", "hosts");
+        assert_eq!(location, "1/foo/dimensions/hosts.csv");
+        self::store(&store, &location, data).await?;
+        let loaded = load(&store, "hosts", &location).await?;
+        assert_eq!(loaded.schema(), rows.schema());
+        assert_eq!(loaded.num_rows(), 2);
+
+        let err = parse("hosts", b"host,datacenter\n").unwrap_err();
+        assert!(matches!(err, Error::DimensionTableEmpty { .. }), "{}", err);
+        let mut data = b"n\n".to_vec();
+        for n in 0..=MAX_ROWS {
+            data.extend(format!("{}\n", n).bytes());
+        }
+        let err = parse("numbers", &data).unwrap_err();
+        assert!(
+            matches!(err, Error::DimensionTableTooLarge { .. }),
+            "{}",
+            err
+        );
+
+        assert!(validate_name("hosts_2").is_ok());
+        for name in &["", "2hosts", "../config", "hosts.csv"] {
+            assert!(validate_name(name).is_err(), "{}", name);
+        }
+
+        Ok(())
+    }
+}
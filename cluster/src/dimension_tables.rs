@@ -0,0 +1,219 @@
+//! This module contains how the server loads, drops and reads the dimension tables of its
+//! databases, which are described in `dimension`.
+
+use arrow_deps::arrow::record_batch::RecordBatch;
+use snafu::{OptionExt, ResultExt};
+
+use crate::{
+    dimension::{self, DimensionTable},
+    ConnectionManager, DatabaseNotFound, Db, DimensionTableNotFound, Result, Server, StoreError,
+};
+
+impl<M: ConnectionManager> Server<M> {
+    /// Replaces the dimension table `table_name` of the database with the rows of the CSV file
+    /// `data`, as described in `dimension`, returning the number of rows. Queries of the
+    /// database can join its measurements with the table, which hides the measurement of the
+    /// same name, if any.
+    pub async fn load_dimension_table(
+        &self,
+        db_name: &str,
+        table_name: &str,
+        data: Vec<u8>,
+    ) -> Result<usize> {
+        let id = self.require_id()?;
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        db.ensure_writable(db_name)?;
+        dimension::validate_name(table_name)?;
+
+        let rows = dimension::parse(table_name, &data)?;
+        let location = dimension::location(id, db_name, table_name);
+        dimension::store(&self.store, &location, data).await?;
+
+        let row_count = rows.num_rows();
+        db.dimension_tables.lock().expect("mutex poisoned").insert(
+            table_name.to_string(),
+            DimensionTable {
+                location,
+                row_count,
+                rows: Some(rows),
+            },
+        );
+        self.store_configuration().await?;
+
+        Ok(row_count)
+    }
+
+    /// Drops the dimension table `table_name` of the database, and deletes its file
+    pub async fn drop_dimension_table(&self, db_name: &str, table_name: &str) -> Result<()> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        db.ensure_writable(db_name)?;
+
+        let table = db
+            .dimension_tables
+            .lock()
+            .expect("mutex poisoned")
+            .remove(table_name)
+            .context(DimensionTableNotFound {
+                db: db_name,
+                table: table_name,
+            })?;
+        self.store_configuration().await?;
+        self.store
+            .delete(&table.location)
+            .await
+            .context(StoreError)?;
+
+        Ok(())
+    }
+
+    /// Returns the names of the dimension tables of the database, with their number of rows
+    pub fn dimension_tables(&self, db_name: &str) -> Result<Vec<(String, usize)>> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        Ok(db
+            .dimension_tables
+            .lock()
+            .expect("mutex poisoned")
+            .iter()
+            .map(|(name, table)| (name.clone(), table.row_count))
+            .collect())
+    }
+
+    /// Returns the rows of the dimension table `table_name` of `db`, if it has one, loading
+    /// them from object storage if they aren't loaded yet
+    pub(crate) async fn dimension_rows(
+        &self,
+        db: &Db,
+        table_name: &str,
+    ) -> Result<Option<RecordBatch>> {
+        let location = match db
+            .dimension_tables
+            .lock()
+            .expect("mutex poisoned")
+            .get(table_name)
+        {
+            Some(DimensionTable {
+                rows: Some(rows), ..
+            }) => return Ok(Some(rows.clone())),
+            Some(table) => table.location.clone(),
+            None => return Ok(None),
+        };
+
+        let rows = dimension::load(&self.store, table_name, &location).await?;
+        if let Some(table) = db
+            .dimension_tables
+            .lock()
+            .expect("mutex poisoned")
+            .get_mut(table_name)
+        {
+            table.rows = Some(rows.clone());
+        }
+        Ok(Some(rows))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{Result, TestConnectionManager},
+        Config, Error,
+    };
+    use data_types::database_rules::DatabaseRules;
+    use futures::TryStreamExt;
+    use influxdb_line_protocol::parse_lines;
+    use object_store::{InMemory, ObjectStore};
+
+    #[tokio::test]
+    async fn dimension_tables() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        server
+            .create_database("foo", DatabaseRules::default())
+            .await?;
+
+        let lines: Vec<_> = parse_lines("cpu,host=a usage=0.5 10\ncpu,host=b usage=0.7 20")
+            .map(|l| l.unwrap())
+            .collect();
+        server.write_lines("foo", &lines).await?;
+
+        let data = b"host,datacenter\na,us-east\nb,eu-west\n".to_vec();
+        assert_eq!(server.load_dimension_table("foo", "hosts", data).await?, 2);
+        assert_eq!(
+            server.dimension_tables("foo")?,
+            vec![("hosts".to_string(), 2)]
+        );
+
+        let query = "select cpu.host, datacenter, usage from cpu join hosts \
+                     on cpu.host = hosts.host order by usage";
+        let expected = vec![
+            "+------+------------+-------+",
+            "| host | datacenter | usage |",
+            "+------+------------+-------+",
+            "| a    | us-east    | 0.5   |",
+            "| b    | eu-west    | 0.7   |",
+            "+------+------------+-------+",
+        ];
+        let results = server.query_local("foo", query).await?;
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected.join("\n")
+        );
+
+        // the rows are loaded from object storage after a restart
+        let config = server
+            .store
+            .get("1/config.json")
+            .await?
+            .map_ok(|b| bytes::BytesMut::from(&b[..]))
+            .try_concat()
+            .await?;
+        let restored: Config = serde_json::from_slice(&config)?;
+        let table = &restored.databases["foo"].dimension_tables;
+        let table = &table.lock().expect("mutex poisoned")["hosts"];
+        assert_eq!(table.location, "1/foo/dimensions/hosts.csv");
+        assert!(table.rows.is_none());
+        server.config.databases["foo"]
+            .dimension_tables
+            .lock()
+            .expect("mutex poisoned")
+            .get_mut("hosts")
+            .unwrap()
+            .rows = None;
+        let results = server.query_local("foo", query).await?;
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected.join("\n")
+        );
+
+        let err = server
+            .load_dimension_table("foo", "../hosts", b"host\na\n".to_vec())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidDimensionTableName { .. }));
+
+        server.drop_dimension_table("foo", "hosts").await?;
+        assert!(server.dimension_tables("foo")?.is_empty());
+        let err = server
+            .drop_dimension_table("foo", "hosts")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::DimensionTableNotFound { .. }));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+//! This module contains the tracking of the activity of databases, so that a server hosting
+//! many databases with little traffic keeps a bounded footprint. A database whose lifecycle
+//! rules set `hibernate_after` hibernates once it has received no writes or queries for that
+//! long: the chunks of its buffers are persisted to object storage and dropped from memory,
+//! see `Server::hibernate_idle_databases`.
+//!
+//! Nothing is reloaded when a hibernating database is accessed again. Its next write or query
+//! wakes it: queries read its persisted chunks from object storage, and writes start new
+//! chunks of the mutable buffer.
+
+use std::{
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use snafu::{OptionExt, ResultExt};
+
+use crate::{
+    catalog::PersistedChunk, dedup::DedupWindow, persistence::packers_from_batches,
+    query_chunk::QueryChunk, ConnectionManager, DatabaseNotFound, Db, Result, ScanningChunks,
+    Server,
+};
+
+/// When a database was last accessed, and whether it is hibernating
+#[derive(Debug)]
+pub struct Activity {
+    /// When the database was last written or queried, in nanoseconds since the epoch
+    last_access: AtomicI64,
+    hibernating: AtomicBool,
+}
+
+impl Default for Activity {
+    /// A database is active from when it is created or loaded
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+
+impl Activity {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            last_access: AtomicI64::new(now.timestamp_nanos()),
+            hibernating: AtomicBool::new(false),
+        }
+    }
+
+    /// Records that the database was accessed at `now`, returning true if the access wakes it
+    pub fn record_access(&self, now: DateTime<Utc>) -> bool {
+        self.last_access
+            .store(now.timestamp_nanos(), Ordering::SeqCst);
+        self.hibernating.swap(false, Ordering::SeqCst)
+    }
+
+    /// Returns when the database was last accessed, if it is awake and has been idle for at
+    /// least `period` at `now`
+    pub fn idle_since(&self, period: Duration, now: DateTime<Utc>) -> Option<i64> {
+        if self.is_hibernating() {
+            return None;
+        }
+
+        let last_access = self.last_access.load(Ordering::SeqCst);
+        let period = i64::try_from(period.as_nanos()).unwrap_or(i64::MAX);
+        let idle = now.timestamp_nanos().saturating_sub(last_access);
+        Some(last_access).filter(|_| idle >= period)
+    }
+
+    /// Marks the database as hibernating, unless it was accessed after `last_access`, as
+    /// returned by `idle_since`. Returns true if it was marked.
+    pub fn hibernate(&self, last_access: i64) -> bool {
+        if self.last_access.load(Ordering::SeqCst) != last_access {
+            return false;
+        }
+        self.hibernating.store(true, Ordering::SeqCst);
+        true
+    }
+
+    pub fn is_hibernating(&self) -> bool {
+        self.hibernating.load(Ordering::SeqCst)
+    }
+}
+
+impl<M: ConnectionManager> Server<M> {
+    /// Hibernates the databases that received no writes or queries for the `hibernate_after`
+    /// of their lifecycle rules at `now`: the chunks of their mutable buffer and the chunks of
+    /// their read buffer that aren't pinned are persisted to object storage and dropped from
+    /// memory, along with the points of their deduplication window. Their next write or
+    /// query wakes them. Returns the name of each database hibernated, with the chunks
+    /// persisted.
+    pub async fn hibernate_idle_databases(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(String, Vec<PersistedChunk>)>> {
+        let mut hibernated = vec![];
+        let mut any_persisted = false;
+
+        for (db_name, db) in &self.config.databases {
+            let buff = match &db.buffer {
+                Some(buff) if db.replica_of.is_none() => buff,
+                _ => continue,
+            };
+            let last_access = match db.rules.lifecycle_rules.hibernate_after {
+                Some(period) => match db.activity.idle_since(period, now) {
+                    Some(last_access) => last_access,
+                    None => continue,
+                },
+                None => continue,
+            };
+
+            let mut persisted = self.persist_chunks(db_name, db, buff, None).await?;
+            persisted.extend(self.persist_read_buffer(db_name, db).await?);
+            any_persisted |= !persisted.is_empty();
+            *db.dedup.lock().expect("mutex poisoned") = DedupWindow::default();
+            db.reloaded.lock().expect("mutex poisoned").clear();
+
+            // a write or query meanwhile keeps the database awake, with its new chunks
+            if db.activity.hibernate(last_access) {
+                metrics::registry()
+                    .counter(
+                        "cluster_databases_hibernated_total",
+                        "Databases hibernated after being idle",
+                        &[],
+                    )
+                    .inc();
+                hibernated.push((db_name.clone(), persisted));
+            }
+        }
+
+        if any_persisted {
+            self.store_configuration().await?;
+        }
+        Ok(hibernated)
+    }
+
+    /// Returns true if the database is hibernating
+    pub fn is_hibernating(&self, db_name: &str) -> Result<bool> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        Ok(db.activity.is_hibernating())
+    }
+
+    /// Persists the chunks of the read buffer of the database that aren't pinned, one chunk
+    /// per table, and drops them from the read buffer. The configuration, which holds the
+    /// catalog, is left for the caller to store.
+    async fn persist_read_buffer(&self, db_name: &str, db: &Db) -> Result<Vec<PersistedChunk>> {
+        let chunks: Vec<_> = {
+            let policies = db.chunk_policies.lock().expect("mutex poisoned");
+            db.read_buffer
+                .lock()
+                .expect("mutex poisoned")
+                .iter()
+                .filter(|chunk| !policies.is_pinned(chunk.partition_key(), chunk.id()))
+                .cloned()
+                .collect()
+        };
+        let mut persisted = vec![];
+
+        for chunk in chunks {
+            for table_name in chunk.table_names().await.context(ScanningChunks)? {
+                let batches = chunk
+                    .table_to_arrow(&table_name, &[])
+                    .await
+                    .context(ScanningChunks)?;
+                let (schema, mut columns) = packers_from_batches(&table_name, &batches)?;
+                let persisted_chunk = self
+                    .persist_table(db_name, db, chunk.partition_key(), &schema, &mut columns)
+                    .await?;
+                persisted.push(persisted_chunk);
+            }
+
+            db.read_buffer
+                .lock()
+                .expect("mutex poisoned")
+                .retain(|c| !Arc::ptr_eq(c, &chunk));
+        }
+
+        Ok(persisted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{parsed_lines, Result, TestConnectionManager};
+    use chrono::TimeZone;
+    use data_types::database_rules::{DatabaseRules, LifecycleRules};
+    use object_store::{InMemory, ObjectStore};
+
+    #[test]
+    fn hibernates_idle_databases() {
+        let at = |secs| Utc.timestamp(secs, 0);
+        let period = Duration::from_secs(60);
+        let activity = Activity::new(at(100));
+
+        assert_eq!(activity.idle_since(period, at(159)), None);
+        let last_access = activity.idle_since(period, at(160)).unwrap();
+        assert_eq!(last_access, at(100).timestamp_nanos());
+
+        // an access while the database is being hibernated keeps it awake
+        assert!(!activity.record_access(at(170)));
+        assert!(!activity.hibernate(last_access));
+        assert!(!activity.is_hibernating());
+
+        let last_access = activity.idle_since(period, at(230)).unwrap();
+        assert!(activity.hibernate(last_access));
+        assert!(activity.is_hibernating());
+        assert_eq!(activity.idle_since(period, at(1000)), None);
+
+        // the next access wakes it
+        assert!(activity.record_access(at(1000)));
+        assert!(!activity.is_hibernating());
+        assert!(!activity.record_access(at(1001)));
+    }
+
+    #[tokio::test]
+    async fn hibernate_idle_databases() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            lifecycle_rules: LifecycleRules {
+                hibernate_after: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+        server
+            .create_database(
+                "bar",
+                DatabaseRules {
+                    store_locally: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        // one chunk in the read buffer, and one open in the mutable buffer
+        server
+            .write_lines("foo", &parsed_lines("cpu,host=a usage=0.5 10"))
+            .await?;
+        let partition_key = server.chunk_summaries("foo").await?[0]
+            .partition_key
+            .clone();
+        let closed = server.close_chunk("foo", &partition_key).await?;
+        server.move_chunk("foo", &partition_key, closed.id).await?;
+        server
+            .write_lines("foo", &parsed_lines("cpu,host=b usage=0.7 20"))
+            .await?;
+        server
+            .write_lines("bar", &parsed_lines("cpu,host=a usage=0.1 10"))
+            .await?;
+
+        // the databases were just written
+        assert!(server
+            .hibernate_idle_databases(Utc::now())
+            .await?
+            .is_empty());
+
+        let later = Utc::now() + chrono::Duration::seconds(120);
+        let hibernated = server.hibernate_idle_databases(later).await?;
+        assert_eq!(hibernated.len(), 1);
+        assert_eq!(hibernated[0].0, "foo");
+        assert_eq!(hibernated[0].1.len(), 2);
+        assert!(server.is_hibernating("foo")?);
+        assert!(!server.is_hibernating("bar")?);
+        assert!(server.chunk_summaries("foo").await?.is_empty());
+        assert_eq!(server.memory_usage("foo").await?.total(), 0);
+        assert_eq!(server.chunk_summaries("bar").await?.len(), 1);
+
+        // hibernating databases are left alone
+        assert!(server.hibernate_idle_databases(later).await?.is_empty());
+
+        // the next query wakes the database, and reads its rows from object storage
+        let results = server
+            .query_local("foo", "select host, usage from cpu order by host")
+            .await?;
+        let expected = vec![
+            "+------+-------+",
+            "| host | usage |",
+            "+------+-------+",
+            "| a    | 0.5   |",
+            "| b    | 0.7   |",
+            "+------+-------+",
+        ];
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected.join("\n")
+        );
+        assert!(!server.is_hibernating("foo")?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+//! This module contains the integrity checks of the Parquet files of persisted chunks. The
+//! SHA-256 checksum of each file is recorded in the catalog when the file is written, and
+//! checked whenever the whole file is fetched, such as to be copied to a snapshot. Queries
+//! only fetch the footer and the column chunks they scan, so the checksums of these parts of
+//! the file are recorded too, and each part is checked as it is fetched. The chunks persisted
+//! before the checksums of their parts were recorded are fetched whole by queries, to check
+//! the checksum of their file. The files of a whole catalog can be verified at once, to find
+//! the missing and corrupt files before they are queried.
+
+use std::fmt;
+
+use arrow_deps::parquet::{
+    errors::ParquetError,
+    file::{
+        reader::{FileReader, SerializedFileReader},
+        serialized_reader::SliceableCursor,
+    },
+};
+use object_store::ObjectStore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::catalog::PersistedChunk;
+
+/// The size of the end of a Parquet file that follows its metadata: the length of the metadata
+/// and the magic number
+const FOOTER_SIZE: usize = 8;
+
+/// The checksums of the parts of a Parquet file that queries fetch on their own
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartChecksums {
+    /// The checksum of the footer of the file: its metadata, their length and the magic number
+    pub footer: String,
+    /// The checksum of each column chunk of the file, by row group then column
+    pub column_chunks: Vec<Vec<String>>,
+}
+
+/// The checksum recorded for the contents of a file: its SHA-256 hash, as hexadecimal
+pub fn checksum(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// The outcome of verifying the file of a persisted chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileStatus {
+    /// The file matches the size and checksum recorded in the catalog
+    Ok,
+    /// The file has the recorded size, but no checksum was recorded for it, as it was
+    /// persisted before checksums were
+    Unverified,
+    /// The file could not be fetched from object storage
+    Missing { error: String },
+    /// The file does not have the size recorded in the catalog
+    SizeMismatch { expected: usize, actual: usize },
+    /// The file does not have the checksum recorded in the catalog
+    ChecksumMismatch { expected: String, actual: String },
+}
+
+impl FileStatus {
+    /// Whether the file is missing or corrupt
+    pub fn is_problem(&self) -> bool {
+        !matches!(self, Self::Ok | Self::Unverified)
+    }
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::Unverified => write!(f, "no checksum recorded"),
+            Self::Missing { error } => write!(f, "missing: {}", error),
+            Self::SizeMismatch { expected, actual } => {
+                write!(f, "corrupt: {} bytes instead of {}", actual, expected)
+            }
+            Self::ChecksumMismatch { expected, actual } => {
+                write!(f, "corrupt: checksum {} instead of {}", actual, expected)
+            }
+        }
+    }
+}
+
+/// Checks the contents of the file of `chunk` against the size and checksum recorded for it
+pub fn check(chunk: &PersistedChunk, data: &[u8]) -> FileStatus {
+    if data.len() != chunk.size_bytes {
+        return FileStatus::SizeMismatch {
+            expected: chunk.size_bytes,
+            actual: data.len(),
+        };
+    }
+
+    match &chunk.checksum {
+        Some(expected) => {
+            let actual = checksum(data);
+            if &actual == expected {
+                FileStatus::Ok
+            } else {
+                FileStatus::ChecksumMismatch {
+                    expected: expected.clone(),
+                    actual,
+                }
+            }
+        }
+        None => FileStatus::Unverified,
+    }
+}
+
+/// Returns the checksums of the parts of the Parquet file `data`
+pub fn part_checksums(data: &[u8]) -> Result<PartChecksums, ParquetError> {
+    let reader = SerializedFileReader::new(SliceableCursor::new(data.to_vec()))?;
+
+    // the file was read, so it ends with the length of its metadata and the magic number
+    let end = &data[data.len() - FOOTER_SIZE..];
+    let metadata_size = u32::from_le_bytes([end[0], end[1], end[2], end[3]]) as usize;
+    let footer = checksum(&data[data.len() - FOOTER_SIZE - metadata_size..]);
+
+    let column_chunks = reader
+        .metadata()
+        .row_groups()
+        .iter()
+        .map(|row_group| {
+            row_group
+                .columns()
+                .iter()
+                .map(|column| {
+                    let (start, length) = column.byte_range();
+                    checksum(&data[start as usize..(start + length) as usize])
+                })
+                .collect()
+        })
+        .collect();
+
+    Ok(PartChecksums {
+        footer,
+        column_chunks,
+    })
+}
+
+/// Checks the contents of a part of a file against `expected`, the checksum recorded for it
+pub fn check_part(expected: &str, data: &[u8]) -> FileStatus {
+    let actual = checksum(data);
+    if actual == expected {
+        FileStatus::Ok
+    } else {
+        FileStatus::ChecksumMismatch {
+            expected: expected.to_string(),
+            actual,
+        }
+    }
+}
+
+/// Fetches the file of `chunk` from `store` and checks it
+pub async fn verify(store: &ObjectStore, chunk: &PersistedChunk) -> FileStatus {
+    match fetch(store, &chunk.location).await {
+        Ok(data) => check(chunk, &data),
+        Err(e) => FileStatus::Missing {
+            error: e.to_string(),
+        },
+    }
+}
+
+/// Fetches the whole file at `location`
+pub async fn fetch(store: &ObjectStore, location: &str) -> object_store::Result<Vec<u8>> {
+    use futures::stream::TryStreamExt;
+
+    let data = store
+        .get(location)
+        .await?
+        .map_ok(|b| bytes::BytesMut::from(&b[..]))
+        .try_concat()
+        .await?;
+    Ok(data.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::InMemory;
+
+    fn chunk(data: &[u8], checksum: Option<String>) -> PersistedChunk {
+        PersistedChunk {
+            partition_key: "p".to_string(),
+            id: 0,
+            table_name: "cpu".to_string(),
+            location: "1/db/data/p/0/cpu.parquet".to_string(),
+            row_count: 1,
+            size_bytes: data.len(),
+            min_time: None,
+            max_time: None,
+            sort_key: vec![],
+            checksum,
+            part_checksums: None,
+            columns: vec![],
+            overlaps: false,
+        }
+    }
+
+    #[test]
+    fn checks_contents() {
+        assert_eq!(
+            checksum(b"hello"),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+
+        let data = b"parquet";
+        let good = chunk(data, Some(checksum(data)));
+        assert_eq!(check(&good, data), FileStatus::Ok);
+        assert_eq!(check(&chunk(data, None), data), FileStatus::Unverified);
+        assert!(!FileStatus::Unverified.is_problem());
+
+        let status = check(&good, b"parquey");
+        assert!(matches!(status, FileStatus::ChecksumMismatch { .. }));
+        assert!(status.is_problem());
+        assert_eq!(
+            check(&good, b"parq"),
+            FileStatus::SizeMismatch {
+                expected: 7,
+                actual: 4
+            }
+        );
+
+        assert_eq!(check_part(&checksum(b"par"), b"par"), FileStatus::Ok);
+        assert!(check_part(&checksum(b"par"), b"pay").is_problem());
+    }
+
+    #[tokio::test]
+    async fn verifies_stored_files() {
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let data = b"parquet";
+        let chunk = chunk(data, Some(checksum(data)));
+
+        let status = verify(&store, &chunk).await;
+        assert!(matches!(status, FileStatus::Missing { .. }), "{}", status);
+
+        let bytes = bytes::Bytes::from(&data[..]);
+        store
+            .put(
+                &chunk.location,
+                futures::stream::once(async move { std::io::Result::Ok(bytes) }),
+                data.len(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(verify(&store, &chunk).await, FileStatus::Ok);
+    }
+}
@@ -0,0 +1,298 @@
+//! This module contains how the server holds the ownership leases of the databases it owns,
+//! which are described in `ownership`. The leases are claimed when databases are created or
+//! loaded and must be renewed before they expire. Writes are only accepted while the server
+//! holds the lease of their database, and the lease is verified against the object store
+//! before the chunks or the configuration of the database are written.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use snafu::{ensure, OptionExt};
+use tracing::info;
+
+use crate::{
+    ownership::{self, Lease},
+    ConnectionManager, DatabaseNotFound, Db, Error, LeasesDisabled, NotOwner, Result, Server,
+};
+
+/// How the server claims the databases it owns, see `ownership`
+#[derive(Debug, Clone)]
+pub(crate) struct LeaseSettings {
+    /// The name the server holds leases under, unique to this process
+    holder: String,
+    /// How long a lease lasts unless renewed
+    duration: Duration,
+}
+
+impl<M: ConnectionManager> Server<M> {
+    /// makes the server claim an ownership lease lasting `duration` on each database it owns,
+    /// under the name `holder`, which must be unique to this process. Writes are rejected to
+    /// the databases it doesn't hold the lease of, and the leases must be renewed with
+    /// `renew_leases` well before they expire. Returns `InvalidLeaseDuration` if `duration` is
+    /// zero or longer than `ownership::MAX_DURATION`.
+    pub fn enable_ownership_leases(
+        &mut self,
+        holder: impl Into<String>,
+        duration: Duration,
+    ) -> Result<()> {
+        ownership::validate_duration(duration)?;
+        self.leases = Some(LeaseSettings {
+            holder: holder.into(),
+            duration,
+        });
+        Ok(())
+    }
+
+    /// Returns the lease of the database, once verified that the server still holds it and
+    /// that none of `entries`, the stored entries of the database, was written under a newer
+    /// lease or by another holder
+    pub(crate) async fn fence(
+        &self,
+        id: u32,
+        db_name: &str,
+        db: &Db,
+        entries: &[ownership::StoredEntry],
+    ) -> Result<Lease> {
+        self.verify_lease(id, db_name, db).await?;
+        let lease = db
+            .lease
+            .lock()
+            .expect("mutex poisoned")
+            .clone()
+            .context(NotOwner { db: db_name })?;
+
+        let result = ownership::fence(db_name, &lease, entries);
+        if result.is_err() {
+            *db.lease.lock().expect("mutex poisoned") = None;
+        }
+        result.map(|_| lease)
+    }
+
+    /// Claims or renews the ownership lease of each database this server owns, if it claims
+    /// leases. Returns the databases whose lease couldn't be claimed, along with why: writes
+    /// to them are rejected until a later renewal claims the lease.
+    pub async fn renew_leases(&self) -> Vec<(String, Error)> {
+        let id = match self.config.id {
+            Some(id) if self.leases.is_some() => id,
+            _ => return vec![],
+        };
+
+        let mut failed = vec![];
+        for (db_name, db) in &self.config.databases {
+            if db.replica_of.is_some() {
+                continue;
+            }
+            match self.claim_lease(id, db_name, false).await {
+                Ok(lease) => *db.lease.lock().expect("mutex poisoned") = lease,
+                Err(e) => {
+                    // the lease runs out on its own if the store can't be reached
+                    if let Error::DatabaseOwned { .. } = e {
+                        *db.lease.lock().expect("mutex poisoned") = None;
+                    }
+                    failed.push((db_name.clone(), e));
+                }
+            }
+        }
+        failed
+    }
+
+    /// Takes over the ownership lease of database `db_name` even if another server holds it,
+    /// for failovers from a server that is gone before its lease expired. The other server
+    /// stops writing to the object store once it notices. Returns the fencing token of the
+    /// new lease.
+    pub async fn force_claim_database(&self, db_name: &str) -> Result<u64> {
+        let id = self.require_id()?;
+        ensure!(self.leases.is_some(), LeasesDisabled);
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        db.ensure_writable(db_name)?;
+
+        let lease = self
+            .claim_lease(id, db_name, true)
+            .await?
+            .expect("leases are enabled");
+        let token = lease.token;
+        *db.lease.lock().expect("mutex poisoned") = Some(lease);
+
+        info!(db = db_name, token, "force-claimed database");
+        Ok(token)
+    }
+
+    /// Claims the lease of database `db_name`, if the server claims leases
+    pub(crate) async fn claim_lease(
+        &self,
+        id: u32,
+        db_name: &str,
+        force: bool,
+    ) -> Result<Option<Lease>> {
+        let settings = match &self.leases {
+            Some(settings) => settings,
+            None => return Ok(None),
+        };
+        ownership::claim(
+            &self.store,
+            id,
+            db_name,
+            &settings.holder,
+            settings.duration,
+            Utc::now(),
+            force,
+        )
+        .await
+        .map(Some)
+    }
+
+    /// Returns `NotOwner` if the server claims leases but has no unexpired lease on the
+    /// database. This doesn't look at the object store, see `verify_lease`.
+    pub(crate) fn check_lease(&self, db_name: &str, db: &Db) -> Result<()> {
+        if self.leases.is_none() || db.replica_of.is_some() {
+            return Ok(());
+        }
+        let held = db
+            .lease
+            .lock()
+            .expect("mutex poisoned")
+            .as_ref()
+            .map_or(false, |lease| lease.expires > Utc::now());
+        ensure!(held, NotOwner { db: db_name });
+        Ok(())
+    }
+
+    /// Returns `OwnershipLost` if another server took over the lease of the database, or
+    /// `NotOwner` if the server has no lease on it, so that the chunks and catalog written by
+    /// its owner aren't overwritten
+    pub(crate) async fn verify_lease(&self, id: u32, db_name: &str, db: &Db) -> Result<()> {
+        if self.leases.is_none() || db.replica_of.is_some() {
+            return Ok(());
+        }
+        let lease = db
+            .lease
+            .lock()
+            .expect("mutex poisoned")
+            .clone()
+            .context(NotOwner { db: db_name })?;
+
+        let result = ownership::verify(&self.store, id, db_name, &lease).await;
+        if let Err(Error::OwnershipLost { .. }) = result {
+            *db.lease.lock().expect("mutex poisoned") = None;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        load_config, read_config,
+        tests::{parsed_lines, Result, TestConnectionManager},
+    };
+    use data_types::database_rules::DatabaseRules;
+    use object_store::ObjectStore;
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn ownership_leases() -> Result {
+        let dir = tempfile::tempdir()?;
+        let shared_store = || ObjectStore::new_file(object_store::File::new(dir.path()));
+        let lease_duration = Duration::from_secs(60);
+
+        let mut first = Server::new(TestConnectionManager::new(), shared_store());
+        first.set_id(1);
+        let err = first
+            .enable_ownership_leases("first", Duration::from_secs(0))
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidLeaseDuration { .. }), "{}", err);
+        first.enable_ownership_leases("first", lease_duration)?;
+        first
+            .create_database("foo", DatabaseRules::default())
+            .await?;
+        first.store_configuration().await?;
+
+        // a second server with the same id can't claim the database while the lease lasts
+        let mut second = Server::new(TestConnectionManager::new(), shared_store());
+        second.set_id(1);
+        second.enable_ownership_leases("second", lease_duration)?;
+        second.load_configuration(1).await?;
+        let err = second
+            .write_lines("foo", &parsed_lines("cpu usage=1 10"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotOwner { .. }), "{}", err);
+        let failed = second.renew_leases().await;
+        assert_eq!(failed.len(), 1);
+        assert!(matches!(failed[0].1, Error::DatabaseOwned { .. }));
+        assert!(first.renew_leases().await.is_empty());
+
+        // until it takes the database over, which fences off the first server
+        assert_eq!(second.force_claim_database("foo").await?, 2);
+        second.store_configuration().await?;
+        second
+            .write_lines("foo", &parsed_lines("cpu usage=1 10"))
+            .await?;
+
+        // the first server still stores its other databases, but not the catalog of the
+        // database it lost, whose latest entry was written under the lease of the second server
+        let latest = |entries: &BTreeMap<String, Vec<ownership::StoredEntry>>, db_name: &str| {
+            let entry = entries[db_name].last().unwrap();
+            (entry.token, entry.holder.clone())
+        };
+        first
+            .create_database("bar", DatabaseRules::default())
+            .await?;
+        let err = first.store_configuration().await.unwrap_err();
+        assert!(
+            matches!(err, Error::OwnershipLost { token: 1, .. }),
+            "{}",
+            err
+        );
+        let entries = ownership::stored_entries(&first.store, 1).await?;
+        assert_eq!(latest(&entries, "foo"), (2, "second".to_string()));
+        assert_eq!(latest(&entries, "bar"), (1, "first".to_string()));
+        let stored: serde_json::Value =
+            serde_json::from_slice(&read_config(&first.store, 1).await?)?;
+        assert_eq!(stored["databases"], serde_json::json!({}));
+        let loaded = load_config(&first.store, 1).await?;
+        assert_eq!(
+            loaded.databases.keys().collect::<Vec<_>>(),
+            vec!["bar", "foo"]
+        );
+
+        // nor does it drop the databases only the second server has
+        second
+            .create_database("baz", DatabaseRules::default())
+            .await?;
+        second.store_configuration().await?;
+        first.store_configuration().await.unwrap_err();
+        let entries = ownership::stored_entries(&first.store, 1).await?;
+        assert_eq!(latest(&entries, "baz"), (1, "second".to_string()));
+        let err = first
+            .write_lines("foo", &parsed_lines("cpu usage=1 10"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotOwner { .. }), "{}", err);
+
+        // releasing the database gives up its lease, whose token the next owner increases, and
+        // deletes its entries
+        second.release_database("foo").await?;
+        let released = ownership::load(&second.store, 1, "foo").await?.unwrap();
+        assert_eq!(released.token, 2);
+        assert!(released.expires <= Utc::now());
+        let entries = ownership::stored_entries(&first.store, 1).await?;
+        assert!(!entries.contains_key("foo"));
+        assert!(first.renew_leases().await.is_empty());
+        first.store_configuration().await?;
+        first.store_configuration().await?;
+        let entries = ownership::stored_entries(&first.store, 1).await?;
+        let generations: Vec<_> = entries["foo"]
+            .iter()
+            .map(|entry| (entry.token, entry.generation))
+            .collect();
+        assert_eq!(generations, vec![(3, 1), (3, 2)]);
+
+        Ok(())
+    }
+}
@@ -27,7 +27,7 @@
 //!                                    ┌ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─
 //!            ┌────────┐  ┌────────┐   Step 1:                 │
 //!            │Router 1│  │Router 2│  │  Parse LP
-//!            │        │  │        │     Create ReplicatedWrite│
+//!            │        │  │        │     Create Entry          │
 //!            └───┬─┬──┘  └────────┘  └ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─
 //!                │ │
 //!                │ │                     ┌ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─ ─
@@ -63,29 +63,97 @@
     clippy::use_self
 )]
 
+pub mod audit;
+pub mod catalog;
+pub mod catalog_rebuild;
+pub mod checks;
+pub mod chunk_policy;
+pub mod compaction;
+pub mod database_store;
+pub mod dedup;
+pub mod dimension;
+pub mod hibernation;
+pub mod integrity;
+pub mod memory;
+pub mod ownership;
+pub mod query_chunk;
+pub mod rules_history;
+pub mod snapshot;
+pub mod system_tables;
+pub mod tail;
+pub mod tasks;
+pub mod tiering;
+pub mod timestamps;
+pub mod tombstone;
+pub mod tracker;
+pub mod trigram;
+pub mod wal_replay;
+pub mod write_stats;
+
+mod deletes;
+mod dimension_tables;
+mod leases;
+mod persistence;
+mod replicas;
+mod retention;
+mod subscriptions;
+mod templates;
+
+pub use replicas::ReplicaRefresh;
+
 use std::{
+    borrow::Cow,
     collections::BTreeMap,
+    convert::TryFrom,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
+    time::Duration,
 };
 
 use arrow_deps::arrow::record_batch::RecordBatch;
+use audit::{AuditEvent, AuditLog};
+use catalog::{Catalog, PersistedChunk};
+use catalog_rebuild::RebuiltCatalog;
+use checks::{Check, CheckHistory};
+use chrono::{DateTime, Utc};
+use chunk_policy::{ChunkPolicies, ChunkPolicy};
 use data_types::{
-    data::{lines_to_replicated_write, ReplicatedWrite},
-    database_rules::{DatabaseRules, HostGroup, HostGroupId, MatchTables},
+    chunk::{ChunkStorage, ChunkSummary},
+    database_rules::{DatabaseRules, HostGroup, HostGroupId, SchemaViolation, TimestampViolation},
+    entry::{self, lines_to_entry, Entry},
+    TIME_COLUMN_NAME,
 };
+use dedup::DedupWindow;
+use dimension::DimensionTable;
+use hibernation::Activity;
 use influxdb_line_protocol::ParsedLine;
+use ingest::import::ImportedTable;
+use leases::LeaseSettings;
+use memory::{MemoryUsage, QueryMemory};
 use object_store::ObjectStore;
-use storage::Database;
-use write_buffer::Db as WriteBufferDb;
+use ownership::Lease;
+use persistence::{encode_parquet, packers_from_batches, sort_for_persistence};
+use query_chunk::{MutableBufferChunk, ParquetChunk, QueryChunk, ReadBufferChunk};
+use snapshot::Snapshot;
+use storage::{access::RowAccess, predicate::TimestampRange, validate::LineDiagnostic, Database};
+use tasks::{Task, TaskHistory};
+use tombstone::DeletePredicate;
+use tracker::{Tracker, TrackerRegistry};
+use wal_replay::WalReplay;
+use write_buffer::{Db as WriteBufferDb, WriteLimits};
+use write_stats::WriteStats;
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::stream::TryStreamExt;
 use serde::{Deserialize, Serialize};
-use snafu::{OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, info_span, warn};
+use tracing_futures::Instrument;
 
 type DatabaseError = Box<dyn std::error::Error + Send + Sync + 'static>;
 
@@ -95,10 +163,128 @@ pub enum Error {
     ServerError { source: std::io::Error },
     #[snafu(display("database not found: {}", db))]
     DatabaseNotFound { db: String },
+    #[snafu(display("database already exists: {}", db))]
+    DatabaseAlreadyExists { db: String },
     #[snafu(display("database error: {}", source))]
     UnknownDatabaseError { source: DatabaseError },
     #[snafu(display("no local buffer for database: {}", db))]
     NoLocalBuffer { db: String },
+    #[snafu(display("no open chunk for partition {} in database: {}", partition_key, db))]
+    OpenChunkNotFound { db: String, partition_key: String },
+    #[snafu(display(
+        "no closed chunk {} for partition {} in database: {}",
+        chunk_id,
+        partition_key,
+        db
+    ))]
+    ClosedChunkNotFound {
+        db: String,
+        partition_key: String,
+        chunk_id: u32,
+    },
+    #[snafu(display(
+        "write does not conform to the schema of database {}: {}",
+        db,
+        violations
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))]
+    SchemaViolations {
+        db: String,
+        violations: Vec<SchemaViolation>,
+    },
+    #[snafu(display(
+        "write has timestamps out of the bounds of database {}: {}",
+        db,
+        violations
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))]
+    TimestampViolations {
+        db: String,
+        violations: Vec<TimestampViolation>,
+    },
+    #[snafu(display(
+        "database {} has a strict schema, which entries can't be checked against: write line protocol instead",
+        db
+    ))]
+    EntryWithStrictSchema { db: String },
+    #[snafu(display("invalid entry: {}", source))]
+    InvalidEntry { source: entry::Error },
+    #[snafu(display("error converting lines to an entry: {}", source))]
+    BuildingEntry { source: entry::Error },
+    #[snafu(display("error building system tables: {}", source))]
+    SystemTablesError {
+        source: arrow_deps::arrow::error::ArrowError,
+    },
+    #[snafu(display("table {} of database {} is not allowed", table, db))]
+    TableNotAllowed { db: String, table: String },
+    #[snafu(display("database {} is not allowed", db))]
+    DatabaseNotAllowed { db: String },
+    #[snafu(display("error filtering the allowed rows of database {}: {}", db, source))]
+    FilteringRows {
+        db: String,
+        source: arrow_deps::arrow::error::ArrowError,
+    },
+    #[snafu(display("error converting the timestamps of table {}: {}", table, source))]
+    ConvertingTimestamps {
+        table: String,
+        source: arrow_deps::arrow::error::ArrowError,
+    },
+    #[snafu(display(
+        "database {} uses {} bytes of memory, over its limit of {} bytes",
+        db,
+        used,
+        limit
+    ))]
+    BufferFull {
+        db: String,
+        used: usize,
+        limit: usize,
+    },
+    #[snafu(display("writes to database {} are throttled: {}", db, source))]
+    WriteThrottled {
+        db: String,
+        source: write_buffer::Error,
+    },
+    #[snafu(display("error opening the local buffer of database {}: {}", db, source))]
+    OpeningBuffer {
+        db: String,
+        source: write_buffer::Error,
+    },
+    #[snafu(display(
+        "writes to database {} are rejected until the replay of its WAL gets past entry {}",
+        db,
+        sequence_number
+    ))]
+    WalReplayFailed { db: String, sequence_number: u64 },
+    #[snafu(display("the replay of the WAL of database {} didn't fail", db))]
+    WalReplayNotFailed { db: String },
+    #[snafu(display(
+        "can't skip the replay of the WAL of database {} from entry {} to entry {}",
+        db,
+        from,
+        to
+    ))]
+    InvalidWalSkip { db: String, from: u64, to: u64 },
+    #[snafu(display("error listing the WALs in {:?}: {}", dir, source))]
+    ListingWals {
+        dir: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("error removing the WAL of database {}: {}", db, source))]
+    RemovingWal { db: String, source: std::io::Error },
+    #[snafu(display("error syncing the WAL of database {}: {}", db, source))]
+    SyncingWal {
+        db: String,
+        source: write_buffer::Error,
+    },
+    #[snafu(display("error scanning chunks: {}", source))]
+    ScanningChunks { source: query_chunk::Error },
     #[snafu(display("host group not found: {}", id))]
     HostGroupNotFound { id: HostGroupId },
     #[snafu(display("no hosts in group: {}", id))]
@@ -118,6 +304,118 @@ pub enum Error {
     ErrorDeserializing { source: serde_json::Error },
     #[snafu(display("store error: {}", source))]
     StoreError { source: object_store::Error },
+    #[snafu(display("error decoding rules stored at {}: {}", location, source))]
+    ErrorDecodingRules {
+        location: String,
+        source: prost::DecodeError,
+    },
+    #[snafu(display("invalid rules stored at {}: {}", location, source))]
+    InvalidStoredRules {
+        location: String,
+        source: data_types::database_rules::Error,
+    },
+    #[snafu(display("no generation {} of the rules of database: {}", generation, db))]
+    RulesGenerationNotFound { db: String, generation: u64 },
+    #[snafu(display("error encoding table {} as Parquet: {}", table, message))]
+    ParquetEncoding { table: String, message: String },
+    #[snafu(display("{} is corrupt: {}", location, status))]
+    CorruptFile {
+        location: String,
+        status: integrity::FileStatus,
+    },
+    #[snafu(display("error merging the points of table {}: {}", table, message))]
+    MergingPoints { table: String, message: String },
+    #[snafu(display("error sorting table {}: {}", table, source))]
+    SortingTable {
+        table: String,
+        source: packers::sorter::Error,
+    },
+    #[snafu(display("database {} is a read-only replica of writer {}", db, owner))]
+    ReadOnlyReplica { db: String, owner: u32 },
+    #[snafu(display("writes to database {} are paused", db))]
+    IngestPaused { db: String },
+    #[snafu(display("server {} can't be a replica of itself", id))]
+    ReplicaOfSelf { id: u32 },
+    #[snafu(display("database {} is owned by server {} until {}", db, holder, expires))]
+    DatabaseOwned {
+        db: String,
+        holder: String,
+        expires: DateTime<Utc>,
+    },
+    #[snafu(display(
+        "lost the ownership of database {} (lease token {}) to another server",
+        db,
+        token
+    ))]
+    OwnershipLost { db: String, token: u64 },
+    #[snafu(display("this server doesn't hold the ownership lease of database {}", db))]
+    NotOwner { db: String },
+    #[snafu(display(
+        "invalid lease duration {:?}: leases last more than zero seconds and at most a year",
+        duration
+    ))]
+    InvalidLeaseDuration { duration: Duration },
+    #[snafu(display("ownership leases are not enabled on this server"))]
+    LeasesDisabled,
+    #[snafu(display("task already exists: {}", name))]
+    TaskAlreadyExists { name: String },
+    #[snafu(display("task not found: {}", name))]
+    TaskNotFound { name: String },
+    #[snafu(display("invalid task {}: {}", name, reason))]
+    InvalidTask { name: String, reason: String },
+    #[snafu(display("check already exists: {}", name))]
+    CheckAlreadyExists { name: String },
+    #[snafu(display("check not found: {}", name))]
+    CheckNotFound { name: String },
+    #[snafu(display("invalid check {}: {}", name, reason))]
+    InvalidCheck { name: String, reason: String },
+    #[snafu(display("rules template already exists: {}", name))]
+    TemplateAlreadyExists { name: String },
+    #[snafu(display("rules template not found: {}", name))]
+    TemplateNotFound { name: String },
+    #[snafu(display("invalid rules template {}: {}", name, reason))]
+    InvalidTemplate { name: String, reason: String },
+    #[snafu(display("rules template {} is used by database {}", name, db))]
+    TemplateInUse { name: String, db: String },
+    #[snafu(display("error applying rules template {}: {}", name, source))]
+    ApplyingTemplate {
+        name: String,
+        source: data_types::database_rules::Error,
+    },
+    #[snafu(display("error parsing the results of task {}: {}", name, source))]
+    ParsingTaskResults {
+        name: String,
+        source: influxdb_line_protocol::Error,
+    },
+    #[snafu(display("invalid dimension table name {:?}", table))]
+    InvalidDimensionTableName { table: String },
+    #[snafu(display("invalid CSV file for dimension table {}: {}", table, source))]
+    InvalidDimensionTable {
+        table: String,
+        source: arrow_deps::arrow::error::ArrowError,
+    },
+    #[snafu(display("dimension table {} has no rows", table))]
+    DimensionTableEmpty { table: String },
+    #[snafu(display("dimension table {} has more than {} rows", table, dimension::MAX_ROWS))]
+    DimensionTableTooLarge { table: String },
+    #[snafu(display("dimension table {} of database {} not found", table, db))]
+    DimensionTableNotFound { db: String, table: String },
+    #[snafu(display("invalid policy for partition {}: {}", partition_key, reason))]
+    InvalidChunkPolicy {
+        partition_key: String,
+        reason: String,
+    },
+    #[snafu(display(
+        "no policy for chunk {:?} of partition {} of database {}",
+        chunk_id,
+        partition_key,
+        db
+    ))]
+    ChunkPolicyNotFound {
+        db: String,
+        partition_key: String,
+        chunk_id: Option<u32>,
+    },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -129,7 +427,18 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub struct Server<M: ConnectionManager> {
     config: Config,
     connection_manager: M,
-    store: ObjectStore,
+    store: Arc<ObjectStore>,
+    jobs: Arc<TrackerRegistry>,
+    query_parallelism: usize,
+    row_group_fetches: usize,
+    task_history: TaskHistory,
+    check_history: CheckHistory,
+    audit_log: Option<Arc<AuditLog>>,
+    leases: Option<LeaseSettings>,
+    /// The directory the local buffers keep their WAL in, see `set_wal_dir`
+    wal_dir: Option<PathBuf>,
+    /// The limits over which the local buffers throttle writes, see `set_write_limits`
+    write_limits: WriteLimits,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
@@ -138,74 +447,368 @@ struct Config {
     id: Option<u32>,
     databases: BTreeMap<String, Db>,
     host_groups: BTreeMap<HostGroupId, HostGroup>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tasks: BTreeMap<String, Task>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    checks: BTreeMap<String, Check>,
+    /// The rules templates databases can be created from, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    templates: BTreeMap<String, DatabaseRules>,
 }
 
 impl<M: ConnectionManager> Server<M> {
     pub fn new(connection_manager: M, store: ObjectStore) -> Self {
         Self {
             config: Config::default(),
-            store,
+            store: Arc::new(store),
             connection_manager,
+            jobs: Arc::new(TrackerRegistry::new()),
+            query_parallelism: DEFAULT_QUERY_PARALLELISM,
+            row_group_fetches: DEFAULT_ROW_GROUP_FETCHES,
+            task_history: TaskHistory::default(),
+            check_history: CheckHistory::default(),
+            audit_log: None,
+            leases: None,
+            wal_dir: None,
+            write_limits: WriteLimits::default(),
+        }
+    }
+
+    /// Returns the registry of the background jobs running on this server
+    pub fn jobs(&self) -> &Arc<TrackerRegistry> {
+        &self.jobs
+    }
+
+    /// sets the number of chunks a query scans at the same time, which is also the number of
+    /// workers that execute the scans of a query. Defaults to `DEFAULT_QUERY_PARALLELISM`.
+    pub fn set_query_parallelism(&mut self, parallelism: usize) {
+        self.query_parallelism = parallelism.max(1);
+    }
+
+    /// Returns the number of chunks a query scans at the same time
+    pub fn query_parallelism(&self) -> usize {
+        self.query_parallelism
+    }
+
+    /// sets the number of row groups of Parquet files a query fetches from object storage at
+    /// the same time, from all the files it scans. Defaults to `DEFAULT_ROW_GROUP_FETCHES`.
+    pub fn set_row_group_fetches(&mut self, fetches: usize) {
+        self.row_group_fetches = fetches.max(1);
+    }
+
+    /// sets the directory the local buffers of the databases keep their write ahead log in, in
+    /// a subdirectory named after their database. The buffers opened from then on replay the
+    /// entries their WAL already holds, and append the entries they store to it. Without a WAL
+    /// directory, the buffers are only kept in memory.
+    pub fn set_wal_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.wal_dir = Some(dir.into());
+    }
+
+    /// sets the limits over which the local buffers throttle writes, including the buffers
+    /// already open. The partition limit of the lifecycle rules of a database takes precedence.
+    pub fn set_write_limits(&mut self, limits: WriteLimits) {
+        self.write_limits = limits;
+        for db in self.config.databases.values() {
+            db.configure_buffer(limits);
         }
     }
 
+    /// sets the audit log that administrative and destructive operations are recorded to
+    pub fn set_audit_log(&mut self, audit_log: AuditLog) {
+        self.audit_log = Some(Arc::new(audit_log));
+    }
+
+    /// returns the audit log of the server, if it has one
+    pub fn audit_log(&self) -> Option<&Arc<AuditLog>> {
+        self.audit_log.as_ref()
+    }
+
+    /// returns the object store the server persists its data to
+    pub fn store(&self) -> &Arc<ObjectStore> {
+        &self.store
+    }
+
     /// sets the id of the server, which is used for replication and the base path in object storage
     pub fn set_id(&mut self, id: u32) {
         self.config.id = Some(id);
     }
 
+    /// returns the id of the server, if it has been set
+    pub fn id(&self) -> Option<u32> {
+        self.config.id
+    }
+
     fn require_id(&self) -> Result<u32> {
         Ok(self.config.id.context(IdNotSet)?)
     }
 
-    /// Tells the server the set of rules for a database. Currently, this is not persisted and
-    /// is for in-memory processing rules only.
+    /// Tells the server the set of rules for a database. If the rules name a template, they
+    /// are those of the template apart from the fields they override. The rules are written
+    /// to the store as a new generation of the rules of the database, see `rules_history`. A
+    /// database that stores locally replays the WAL it has from an earlier incarnation, and
+    /// rejects writes if the replay fails, see `skip_wal_replay`.
     pub async fn create_database(
         &mut self,
         db_name: impl Into<String>,
         rules: DatabaseRules,
     ) -> Result<()> {
-        self.require_id()?;
+        let id = self.require_id()?;
 
         let db_name = db_name.into();
+        ensure!(
+            !self.config.databases.contains_key(&db_name),
+            DatabaseAlreadyExists { db: db_name }
+        );
+        let rules = self.inherit_template(rules)?;
+        let lease = self.claim_lease(id, &db_name, false).await?;
+        self.store_rules_version(id, &db_name, &rules).await?;
 
-        let buffer = if rules.store_locally {
-            Some(WriteBufferDb::new(&db_name))
-        } else {
-            None
-        };
+        let (buffer, replay) =
+            open_buffer(&self.jobs, self.wal_dir.as_deref(), &db_name, &rules, &[]).await?;
+        let mut db = Db::new(rules, buffer, self.write_limits);
+        db.wal_replay = replay;
+        db.resume_sequence(id).await;
+        *db.lease.lock().expect("mutex poisoned") = lease;
+        self.config.databases.insert(db_name, db);
 
-        let sequence = AtomicU64::new(STARTING_SEQUENCE);
-        let db = Db {
-            rules,
-            buffer,
-            sequence,
+        Ok(())
+    }
+
+    /// Replaces the rules of an existing database, which inherit from their template as in
+    /// `create_database`. Data already buffered locally is kept. A local buffer is created if
+    /// the new rules turn on `store_locally`, and dropped if they turn it off. The rules are
+    /// written to the store as a new generation, which is returned.
+    pub async fn update_database_rules(
+        &mut self,
+        db_name: &str,
+        rules: DatabaseRules,
+    ) -> Result<u64> {
+        let id = self.require_id()?;
+
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        db.ensure_writable(db_name)?;
+        let rules = self.inherit_template(rules)?;
+        let generation = self.store_rules_version(id, db_name, &rules).await?;
+        let (buffer, replay) = match &db.buffer {
+            Some(_) => (None, None),
+            None => {
+                let wal_dir = self.wal_dir.as_deref();
+                open_buffer(&self.jobs, wal_dir, db_name, &rules, &db.wal_skips).await?
+            }
         };
 
-        self.config.databases.insert(db_name, db);
+        let limits = self.write_limits;
+        let db = self
+            .config
+            .databases
+            .get_mut(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        db.apply_rules(rules, buffer, limits);
+        if replay.is_some() {
+            db.wal_replay = replay;
+        }
+        db.resume_sequence(id).await;
 
-        Ok(())
+        Ok(generation)
     }
 
-    /// Creates a host group with a set of connection strings to hosts. These host connection
-    /// strings should be something that the connection manager can use to return a remote server
-    /// to work with.
-    pub async fn create_host_group(&mut self, id: HostGroupId, hosts: Vec<String>) -> Result<()> {
-        self.require_id()?;
+    /// Returns every stored version of the rules of the named database, oldest first
+    pub async fn database_rules_versions(
+        &self,
+        db_name: &str,
+    ) -> Result<Vec<rules_history::RulesVersion>> {
+        let id = self.require_id()?;
+        ensure!(
+            self.config.databases.contains_key(db_name),
+            DatabaseNotFound { db: db_name }
+        );
 
-        self.config
-            .host_groups
-            .insert(id.clone(), HostGroup { id, hosts });
+        rules_history::list(&self.store, id, db_name).await
+    }
+
+    /// Sets the rules of the named database back to the stored generation `generation`. The
+    /// rules are written again as a new generation, which is returned, so that the rollback
+    /// can itself be rolled back.
+    pub async fn rollback_database_rules(&mut self, db_name: &str, generation: u64) -> Result<u64> {
+        let id = self.require_id()?;
+        ensure!(
+            self.config.databases.contains_key(db_name),
+            DatabaseNotFound { db: db_name }
+        );
+
+        let generations = rules_history::generations(&self.store, id, db_name).await?;
+        ensure!(
+            generations.contains(&generation),
+            RulesGenerationNotFound {
+                db: db_name,
+                generation
+            }
+        );
+        let rules = rules_history::load(&self.store, id, db_name, generation).await?;
+
+        self.update_database_rules(db_name, rules).await
+    }
+
+    /// Applies the latest stored generation of the rules of each database this server owns
+    /// that differs from the rules in use, such as rules stored by another process or
+    /// restored from a backup, without restarting the server. The rules are applied as
+    /// `update_database_rules` applies them. Returns the databases whose rules changed,
+    /// along with the generation applied.
+    pub async fn reload_database_rules(&mut self) -> Result<Vec<(String, u64)>> {
+        let id = self.require_id()?;
+
+        let mut reloaded = vec![];
+        for (db_name, db) in &mut self.config.databases {
+            if db.replica_of.is_some() {
+                continue;
+            }
+            let latest = rules_history::generations(&self.store, id, db_name)
+                .await?
+                .last()
+                .copied();
+            if let Some(generation) = latest {
+                let rules = rules_history::load(&self.store, id, db_name, generation).await?;
+                if rules != db.rules {
+                    info!(db = db_name.as_str(), generation, "reloading stored rules");
+                    let (buffer, replay) = match &db.buffer {
+                        Some(_) => (None, None),
+                        None => {
+                            let wal_dir = self.wal_dir.as_deref();
+                            open_buffer(&self.jobs, wal_dir, db_name, &rules, &db.wal_skips).await?
+                        }
+                    };
+                    db.apply_rules(rules, buffer, self.write_limits);
+                    if replay.is_some() {
+                        db.wal_replay = replay;
+                    }
+                    db.resume_sequence(id).await;
+                    reloaded.push((db_name.clone(), generation));
+                }
+            }
+        }
+
+        Ok(reloaded)
+    }
+
+    /// Writes `rules` as the generation after the latest stored generation of the rules of
+    /// the database and returns it. A database that was released and created again continues
+    /// from the generations of its earlier incarnation.
+    async fn store_rules_version(
+        &self,
+        id: u32,
+        db_name: &str,
+        rules: &DatabaseRules,
+    ) -> Result<u64> {
+        let latest = rules_history::generations(&self.store, id, db_name)
+            .await?
+            .last()
+            .copied()
+            .unwrap_or_default();
+        let generation = latest + 1;
+        rules_history::store(&self.store, id, db_name, generation, rules).await?;
+
+        Ok(generation)
+    }
+
+    /// Removes the database from this server, dropping any data buffered for it locally along
+    /// with its WAL.
+    pub async fn release_database(&mut self, db_name: &str) -> Result<()> {
+        let id = self.require_id()?;
+
+        let db = self
+            .config
+            .databases
+            .remove(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        let Db { lease, buffer, .. } = db;
+        drop(buffer);
+        // the WAL would bring the data back once the server restarts
+        if let Some(wal_dir) = &self.wal_dir {
+            let dir = wal_dir.join(db_name);
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir).context(RemovingWal { db: db_name })?;
+            }
+        }
+
+        if let Some(lease) = lease.into_inner().expect("mutex poisoned") {
+            ownership::release(&self.store, id, db_name, &lease, Utc::now()).await?;
+        }
 
         Ok(())
     }
 
+    /// Returns the names of all databases configured on this server
+    pub fn db_names(&self) -> Vec<String> {
+        self.config.databases.keys().cloned().collect()
+    }
+
+    /// Returns the rules of the named database, if it exists
+    pub fn db_rules(&self, db_name: &str) -> Option<&DatabaseRules> {
+        self.config.databases.get(db_name).map(|db| &db.rules)
+    }
+
+    /// Returns the recent audit events of the database `db_name` and of the server as a
+    /// whole, oldest first
+    pub fn audit_events(&self, db_name: &str) -> Vec<AuditEvent> {
+        self.audit_log
+            .as_ref()
+            .map(|log| {
+                log.events()
+                    .into_iter()
+                    .filter(|e| e.db_name.as_deref().map_or(true, |name| name == db_name))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     /// Saves the configuration of database rules and host groups to a single JSON file in
     /// the configured store under a directory /<writer ID/config.json
+    ///
+    /// The configuration holds the catalogs of the databases, which must not overwrite those
+    /// written by another server with the same id that took a database over. When the server
+    /// claims leases, the configuration of each database it holds the lease of is stored on its
+    /// own instead, as the next entry under the lease, see the `ownership` module, and the JSON
+    /// file only holds the replicas. A database is left as stored if the server lost its lease,
+    /// or if an entry was written under a newer lease, and the first database found lost is
+    /// returned as an error once the others are stored, so that losing one database doesn't
+    /// keep the others from being stored. When the server doesn't claim leases, all databases
+    /// are stored in the JSON file and the entries are deleted.
     pub async fn store_configuration(&self) -> Result<()> {
         let id = self.require_id()?;
+        let mut config = serde_json::to_value(&self.config).context(ErrorSerializing)?;
+        let mut lost = None;
+        if self.leases.is_some() {
+            let mut entries = ownership::stored_entries(&self.store, id).await?;
+            let databases = match config.get_mut("databases") {
+                Some(serde_json::Value::Object(databases)) => databases,
+                _ => unreachable!("the databases are serialized to a JSON object"),
+            };
+
+            for (db_name, db) in &self.config.databases {
+                if db.replica_of.is_some() {
+                    continue;
+                }
+                databases.remove(db_name);
+                let db_entries = entries.remove(db_name).unwrap_or_default();
+                match self.fence(id, db_name, db, &db_entries).await {
+                    Ok(lease) => {
+                        let data = serde_json::to_vec(db).context(ErrorSerializing)?;
+                        ownership::store_entry(&self.store, id, db_name, &lease, &db_entries, data)
+                            .await?;
+                    }
+                    Err(e) => {
+                        warn!(db = db_name.as_str(), error = %e, "database configuration not stored");
+                        lost.get_or_insert(e);
+                    }
+                }
+            }
+        }
 
-        let data = Bytes::from(serde_json::to_vec(&self.config).context(ErrorSerializing)?);
+        let data = Bytes::from(serde_json::to_vec(&config).context(ErrorSerializing)?);
         let len = data.len();
         let location = config_location(id);
 
@@ -219,31 +822,129 @@ impl<M: ConnectionManager> Server<M> {
             .await
             .context(StoreError)?;
 
-        Ok(())
+        if self.leases.is_none() {
+            ownership::delete_entries(&self.store, id).await?;
+        }
+
+        lost.map_or(Ok(()), Err)
     }
 
-    /// Loads the configuration for this server from the configured store. This replaces
-    /// any in-memory configuration that might already be set.
+    /// Loads the configuration for this server from the configured store, or starts from an
+    /// empty configuration if the server never stored one. This replaces any in-memory
+    /// configuration that might already be set. The databases that store locally replay their
+    /// WAL, see `set_wal_dir`. A database whose replay fails is loaded nonetheless, without a
+    /// local buffer, and rejects writes until the replay is skipped past the entry it failed
+    /// at, see `skip_wal_replay`.
     pub async fn load_configuration(&mut self, id: u32) -> Result<()> {
-        let location = config_location(id);
+        let mut config = if config_stored(&self.store, id).await? {
+            load_config(&self.store, id).await?
+        } else {
+            // the entries of the databases are stored before the JSON file
+            let mut config = Config {
+                id: Some(id),
+                ..Default::default()
+            };
+            load_entries(&self.store, id, &mut config).await?;
+            config
+        };
 
-        let read_data = self
-            .store
-            .get(&location)
-            .await
-            .context(StoreError)?
-            .map_ok(|b| bytes::BytesMut::from(&b[..]))
-            .try_concat()
-            .await
-            .context(StoreError)?;
+        // The configuration is stored after the rules, so a crash in between leaves newer rules
+        // in the history than in the configuration.
+        for (db_name, db) in &mut config.databases {
+            let latest = rules_history::generations(&self.store, id, db_name)
+                .await?
+                .last()
+                .copied();
+            if let Some(generation) = latest {
+                let rules = rules_history::load(&self.store, id, db_name, generation).await?;
+                if rules != db.rules {
+                    info!(
+                        db = db_name.as_str(),
+                        generation, "applying newer stored rules"
+                    );
+                    db.rules = rules;
+                }
+            }
+        }
+
+        for (db_name, db) in &config.databases {
+            if db.replica_of.is_some() {
+                continue;
+            }
+            match self.claim_lease(id, db_name, false).await {
+                Ok(lease) => *db.lease.lock().expect("mutex poisoned") = lease,
+                Err(e) => warn!(
+                    db = db_name.as_str(),
+                    error = %e,
+                    "database loaded without its ownership lease, writes are rejected"
+                ),
+            }
+        }
 
-        let config: Config = serde_json::from_slice(&read_data).context(ErrorDeserializing)?;
+        for (db_name, db) in &mut config.databases {
+            if db.replica_of.is_some() {
+                // queries are planned against the local buffer, which stays empty
+                db.buffer = Some(Arc::new(WriteBufferDb::new(db_name.as_str())));
+            } else {
+                let wal_dir = self.wal_dir.as_deref();
+                let (buffer, replay) =
+                    open_buffer(&self.jobs, wal_dir, db_name, &db.rules, &db.wal_skips).await?;
+                db.buffer = buffer.map(Arc::new);
+                db.wal_replay = replay;
+            }
+            db.configure_buffer(self.write_limits);
+            db.resume_sequence(id).await;
+        }
         self.config = config;
 
         Ok(())
     }
 
-    /// `write_lines` takes in raw line protocol and converts it to a `ReplicatedWrite`, which
+    /// Creates a database with `rules` for each WAL in the WAL directory whose database the
+    /// configuration doesn't have, replaying it: those are the databases created implicitly
+    /// by a write before the configuration was stored. Returns the names of the databases
+    /// created.
+    pub async fn replay_wals(&mut self, rules: &DatabaseRules) -> Result<Vec<String>> {
+        let wal_dir = match &self.wal_dir {
+            Some(wal_dir) if wal_dir.exists() => wal_dir.clone(),
+            _ => return Ok(vec![]),
+        };
+
+        let mut names = vec![];
+        let entries = std::fs::read_dir(&wal_dir).context(ListingWals { dir: &wal_dir })?;
+        for entry in entries {
+            let entry = entry.context(ListingWals { dir: &wal_dir })?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // hidden directories hold the files of other components, such as the TLS keys
+            if !entry.path().is_dir() || name.starts_with('.') {
+                continue;
+            }
+            if !self.config.databases.contains_key(&name) {
+                names.push(name);
+            }
+        }
+        names.sort();
+
+        for name in &names {
+            self.create_database(name.as_str(), rules.clone()).await?;
+        }
+        Ok(names)
+    }
+
+    /// Flushes the WAL of each database to disk, for the entries buffered by the WAL writer
+    pub async fn sync_wals(&self) -> Result<()> {
+        for (db_name, db) in &self.config.databases {
+            if let Some(buffer) = &db.buffer {
+                buffer
+                    .sync_wal()
+                    .await
+                    .context(SyncingWal { db: db_name })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `write_lines` takes in raw line protocol and converts it to an `Entry`, which
     /// is then replicated to other servers based on the configuration of the `db`.
     /// This is step #1 from the above diagram.
     pub async fn write_lines(&self, db_name: &str, lines: &[ParsedLine<'_>]) -> Result<()> {
@@ -255,190 +956,3591 @@ impl<M: ConnectionManager> Server<M> {
             .get(db_name)
             .context(DatabaseNotFound { db: db_name })?;
 
+        let violations = db.rules.check_schema(lines);
+        if !violations.is_empty() {
+            db.write_stats.record_rejected_lines(db_name, lines);
+            return SchemaViolations {
+                db: db_name,
+                violations,
+            }
+            .fail();
+        }
+
+        let now = Utc::now();
+        let violations = db.rules.check_timestamps(lines, now);
+        if !violations.is_empty() {
+            db.write_stats.record_rejected_lines(db_name, lines);
+            return TimestampViolations {
+                db: db_name,
+                violations,
+            }
+            .fail();
+        }
+
+        // the points are only remembered once they are written, so that the retries of
+        // failed writes aren't dropped
+        let (lines, reservation) = match db.rules.dedup_window {
+            Some(window) => match dedup::deduplicate_lines(&db.dedup, lines, window, now) {
+                Some((lines, reservation)) => (lines, Some(reservation)),
+                None => return Ok(()),
+            },
+            None => (Cow::Borrowed(lines), None),
+        };
+
+        metrics::registry()
+            .counter(
+                "cluster_lines_written_total",
+                "Lines of line protocol accepted for writing",
+                &[],
+            )
+            .add(lines.len() as u64);
+
         let sequence = db.next_sequence();
-        let write = lines_to_replicated_write(id, sequence, lines, &db.rules);
+        let entry = lines_to_entry(id, sequence, &lines, &db.rules).context(BuildingEntry)?;
+
+        self.handle_write(db_name, db, entry)
+            .instrument(info_span!("write", db_name, lines = lines.len()))
+            .await?;
 
-        self.handle_replicated_write(db_name, db, write).await?;
+        if let Some(reservation) = reservation {
+            reservation.record(now);
+        }
 
         Ok(())
     }
 
-    /// Executes a query against the local write buffer database, if one exists.
-    pub async fn query_local(&self, db_name: &str, query: &str) -> Result<Vec<RecordBatch>> {
+    /// Checks `lines` against the database without writing them, returning the problems found
+    /// with each line: lines that break the strict schema or the timestamp bounds of its
+    /// rules, and values whose type differs from the type of their column in the local buffer
+    /// or in an earlier line
+    pub async fn validate_lines(
+        &self,
+        db_name: &str,
+        lines: &[ParsedLine<'_>],
+    ) -> Result<Vec<LineDiagnostic>> {
         let db = self
             .config
             .databases
             .get(db_name)
             .context(DatabaseNotFound { db: db_name })?;
 
-        let buff = db.buffer.as_ref().context(NoLocalBuffer { db: db_name })?;
-        buff.query(query)
+        // the violations are displayed with their line number, which diagnostics hold apart
+        fn diagnostic(line_number: usize, violation: impl ToString) -> LineDiagnostic {
+            let prefix = format!("line {}: ", line_number);
+            LineDiagnostic {
+                line_number,
+                message: violation
+                    .to_string()
+                    .trim_start_matches(prefix.as_str())
+                    .to_string(),
+            }
+        }
+        let mut diagnostics: Vec<_> = db
+            .rules
+            .check_schema(lines)
+            .into_iter()
+            .map(|v| diagnostic(v.line_number, v))
+            .collect();
+        diagnostics.extend(
+            db.rules
+                .check_timestamps(lines, Utc::now())
+                .into_iter()
+                .map(|v| diagnostic(v.line_number, v)),
+        );
+
+        let column_types = match &db.buffer {
+            Some(buffer) => buffer.column_types().await,
+            None => BTreeMap::new(),
+        };
+        diagnostics.extend(storage::validate::check_column_types(column_types, lines));
+
+        diagnostics.sort_by_key(|d| d.line_number);
+        Ok(diagnostics)
+    }
+
+    /// `write_entry` takes in the bytes of an `Entry` that was converted from line protocol
+    /// by another server, such as a router, and stores and replicates it as `write_lines`
+    /// would, leaving out the rows that are duplicates of recent writes. Entries can't be
+    /// checked against the strict schema of a database, so they are rejected by databases that
+    /// have one.
+    pub async fn write_entry(&self, db_name: &str, data: Vec<u8>) -> Result<()> {
+        self.require_id()?;
+
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        ensure!(
+            db.rules.strict_schema.is_none(),
+            EntryWithStrictSchema { db: db_name }
+        );
+        let entry = Entry::try_from(data).context(InvalidEntry)?;
+
+        // the rows are deduplicated as the lines of `write_lines` are
+        let now = Utc::now();
+        let (entry, reservation) = match db.rules.dedup_window {
+            Some(window) => match dedup::deduplicate_entry(&db.dedup, entry, window, now) {
+                Some((entry, reservation)) => (entry, Some(reservation)),
+                None => return Ok(()),
+            },
+            None => (entry, None),
+        };
+
+        metrics::registry()
+            .counter(
+                "cluster_entries_written_total",
+                "Entries accepted for writing",
+                &[],
+            )
+            .inc();
+
+        let bytes = entry.data().len();
+        self.handle_write(db_name, db, entry)
+            .instrument(info_span!("write_entry", db_name, bytes))
+            .await?;
+
+        if let Some(reservation) = reservation {
+            reservation.record(now);
+        }
+
+        Ok(())
+    }
+
+    /// Executes a query against the local data of the database, if it has a local write
+    /// buffer. The query scans the chunks of every tier: the chunks of the mutable buffer,
+    /// the chunks moved to the read buffer and the chunks persisted to object storage. The
+    /// tables of the `system` schema can be queried alongside the tables of the database, as
+    /// can the tables of the other databases, named `<database>.<table>`.
+    pub async fn query_local(&self, db_name: &str, query: &str) -> Result<Vec<RecordBatch>> {
+        self.query_local_across(db_name, query, |_| Some(RowAccess::default()))
             .await
-            .map_err(|e| Box::new(e) as DatabaseError)
-            .context(UnknownDatabaseError {})
     }
 
-    pub async fn handle_replicated_write(
+    /// Executes a query like `query_local`, over only the rows of the database `access`
+    /// allows. The query can't read the tables of other databases.
+    pub async fn query_local_with_access(
         &self,
         db_name: &str,
-        db: &Db,
-        write: ReplicatedWrite,
-    ) -> Result<()> {
-        if let Some(buf) = &db.buffer {
-            buf.store_replicated_write(&write)
-                .await
-                .map_err(|e| Box::new(e) as DatabaseError)
-                .context(UnknownDatabaseError {})?;
+        query: &str,
+        access: &RowAccess,
+    ) -> Result<Vec<RecordBatch>> {
+        self.query_local_across(db_name, query, |name| {
+            Some(access.clone()).filter(|_| name == db_name)
+        })
+        .await
+    }
+
+    /// Executes a query like `query_local`, where `access` returns the rows of each database
+    /// the query reads that it may access, or `None` if it may not read the database. Each
+    /// database qualifying a table, as in `SELECT ... FROM other_db.cpu`, needs a local write
+    /// buffer.
+    pub async fn query_local_across<F>(
+        &self,
+        db_name: &str,
+        query: &str,
+        access: F,
+    ) -> Result<Vec<RecordBatch>>
+    where
+        F: Fn(&str) -> Option<RowAccess>,
+    {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        let buff = db
+            .buffer
+            .as_deref()
+            .context(NoLocalBuffer { db: db_name })?;
+        let db_access = access(db_name).context(DatabaseNotAllowed { db: db_name })?;
+        db.record_access(db_name);
+
+        let mut column_summaries = buff.column_summaries().await;
+        column_summaries.extend(
+            db.catalog
+                .lock()
+                .expect("mutex poisoned")
+                .chunks()
+                .iter()
+                .flat_map(PersistedChunk::column_summaries),
+        );
+        let mut tables: BTreeMap<_, _> = system_tables::build(
+            &self.chunk_summaries(db_name).await?,
+            &column_summaries,
+            &self.jobs.list(),
+            &self.tasks(),
+            &self.task_runs(),
+            &self.audit_events(db_name),
+            &db.write_stats.tables(),
+            &db.write_stats.partitions(),
+            &self.wal_replays(),
+        )
+        .context(SystemTablesError)?
+        .into_iter()
+        .map(|(name, batches)| (name, vec![batches]))
+        .collect();
+
+        // the tables qualified with the name of a database are read from that database
+        let table_names = write_buffer::query_table_names(query)
+            .map_err(|e| Box::new(e) as DatabaseError)
+            .context(UnknownDatabaseError {})?;
+        let mut db_tables = vec![];
+        let mut other_db_tables: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for name in &table_names {
+            match self.qualified_table(name) {
+                Some((other_db, table)) => other_db_tables.entry(other_db).or_default().push(table),
+                None => db_tables.push(name.as_str()),
+            }
         }
 
-        for host_group_id in &db.rules.replication {
-            self.replicate_to_host_group(host_group_id, db_name, &write)
-                .await?;
+        if let Some(table) = db_tables
+            .iter()
+            .find(|name| !db_access.allows_measurement(name))
+        {
+            return TableNotAllowed {
+                db: db_name,
+                table: *table,
+            }
+            .fail();
         }
+        let mut sort_keys = BTreeMap::new();
+        let fetches = Arc::new(Semaphore::new(self.row_group_fetches));
+        self.scan_tables(
+            db_name,
+            query,
+            "",
+            &db_tables,
+            &db_access,
+            &fetches,
+            &mut tables,
+            &mut sort_keys,
+        )
+        .await?;
 
-        for subscription in &db.rules.subscriptions {
-            match subscription.matcher.tables {
-                MatchTables::All => {
-                    self.replicate_to_host_group(&subscription.host_group_id, db_name, &write)
-                        .await?
+        // the rows of the other databases are restricted here, as the query is planned with
+        // the access to its own database
+        let mut other_tables = BTreeMap::new();
+        for (other_name, names) in other_db_tables {
+            let other_access = access(other_name).context(DatabaseNotAllowed { db: other_name })?;
+            if let Some(table) = names
+                .iter()
+                .find(|name| !other_access.allows_measurement(name))
+            {
+                return TableNotAllowed {
+                    db: other_name,
+                    table: *table,
                 }
-                MatchTables::Table(_) => unimplemented!(),
-                MatchTables::Regex(_) => unimplemented!(),
+                .fail();
+            }
+            self.config.databases[other_name].record_access(other_name);
+
+            let mut scanned = BTreeMap::new();
+            self.scan_tables(
+                other_name,
+                query,
+                &format!("{}.", other_name),
+                &names,
+                &other_access,
+                &fetches,
+                &mut scanned,
+                &mut sort_keys,
+            )
+            .await?;
+            for (name, partitions) in scanned {
+                // the tables are keyed by their name in the query, qualified with the database
+                let table_name = &name[other_name.len() + 1..];
+                let partitions = partitions
+                    .iter()
+                    .map(|batches| {
+                        batches
+                            .iter()
+                            .map(|batch| other_access.filter_batch(table_name, batch))
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .context(FilteringRows { db: other_name })?;
+                other_tables.insert(name, partitions);
             }
         }
 
-        Ok(())
+        let _reservation = db.query_memory.reserve(memory::batches_size(
+            tables
+                .values()
+                .chain(other_tables.values())
+                .flatten()
+                .flatten(),
+        ));
+        buff.query_allowed(
+            query,
+            &tables,
+            &other_tables,
+            &sort_keys,
+            self.query_parallelism,
+            &db_access,
+        )
+        .await
+        .map_err(|e| Box::new(e) as DatabaseError)
+        .context(UnknownDatabaseError {})
+    }
+
+    /// Returns the database and the name in it of the table `name`, if `name` is qualified
+    /// with the name of a database of the server, as in `other_db.cpu`
+    fn qualified_table<'a>(&self, name: &'a str) -> Option<(&'a str, &'a str)> {
+        let dot = name.find('.')?;
+        let (db_name, table) = (&name[..dot], &name[dot + 1..]);
+        if db_name == system_tables::SCHEMA || !self.config.databases.contains_key(db_name) {
+            return None;
+        }
+        Some((db_name, table))
     }
 
-    async fn replicate_to_host_group(
+    /// Scans the rows of the tables `table_names` of the database `db_name` that `query` reads, in the chunks of
+    /// every tier of the database, into `tables`, partitioned by the chunks they were read
+    /// from, with the columns their partitions are sorted on in `sort_keys`. The tables are
+    /// keyed by their name in `query`, which is `prefix` followed by their name in the
+    /// database. Dimension tables are read whole, and tables without rows are left out.
+    #[allow(clippy::too_many_arguments)]
+    async fn scan_tables(
         &self,
-        host_group_id: &str,
         db_name: &str,
-        write: &ReplicatedWrite,
+        query: &str,
+        prefix: &str,
+        table_names: &[&str],
+        access: &RowAccess,
+        fetches: &Arc<Semaphore>,
+        tables: &mut BTreeMap<String, Vec<Vec<RecordBatch>>>,
+        sort_keys: &mut BTreeMap<String, Vec<String>>,
     ) -> Result<()> {
-        let group = self
+        let db = self
             .config
-            .host_groups
-            .get(host_group_id)
-            .context(HostGroupNotFound { id: host_group_id })?;
-
-        let host = group
-            .hosts
-            .get(0)
-            .context(NoHostInGroup { id: host_group_id })?;
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        let buff = db.buffer.as_ref().context(NoLocalBuffer { db: db_name })?;
+        let read_buffer = db.read_buffer.lock().expect("mutex poisoned").clone();
+        let mut chunks: Vec<Arc<dyn QueryChunk>> = vec![];
+        for chunk in MutableBufferChunk::all(buff).await {
+            chunks.push(Arc::new(chunk));
+        }
+        for chunk in read_buffer {
+            chunks.push(chunk);
+        }
+        let persisted: Vec<_> = {
+            let catalog = db.catalog.lock().expect("mutex poisoned");
+            catalog
+                .chunks()
+                .into_iter()
+                .map(|chunk| {
+                    let deletes = catalog.deletes(&chunk);
+                    (chunk, deletes)
+                })
+                .collect()
+        };
+        // the persisted chunks are reloaded into the read buffer only if the database has a
+        // buffer size to evict them by
+        let rules = &db.rules.lifecycle_rules;
+        let reload = rules.buffer_size_soft.or(rules.buffer_size_hard).is_some();
+        if reload {
+            let catalog_chunks: Vec<_> = persisted.iter().map(|(chunk, _)| chunk.clone()).collect();
+            db.reloaded
+                .lock()
+                .expect("mutex poisoned")
+                .retain(&catalog_chunks);
+        }
+        for (chunk, deletes) in persisted {
+            let read = table_names.contains(&chunk.table_name.as_str());
+            let reloaded = if reload && read {
+                Some(self.reload_chunk(db_name, db, &chunk, fetches).await?)
+            } else {
+                None
+            };
+            let mut parquet_chunk = ParquetChunk::new(Arc::clone(&self.store), chunk, deletes)
+                .with_fetches(Arc::clone(fetches));
+            if let Some(rows) = reloaded {
+                parquet_chunk = parquet_chunk.with_reloaded(rows);
+            }
+            chunks.push(Arc::new(parquet_chunk));
+        }
+        if reload {
+            self.evict_reloaded_chunks(db_name, db).await?;
+        }
+        query_chunk::sort_chunks(&mut chunks);
 
-        let connection = self
-            .connection_manager
-            .remote_server(host)
-            .await
+        // the columns and predicates of the query name the time column as the rules do,
+        // which the chunks store as `time`
+        let timestamp_rules = &db.rules.timestamps;
+        let predicates: BTreeMap<_, _> = write_buffer::query_column_predicates(query)
             .map_err(|e| Box::new(e) as DatabaseError)
-            .context(UnableToGetConnection { server: host })?;
-
-        connection
-            .replicate(db_name, write)
-            .await
+            .context(UnknownDatabaseError {})?
+            .into_iter()
+            .map(|(table, predicates)| {
+                let predicates = timestamps::stored_predicates(timestamp_rules, &predicates);
+                (table, predicates)
+            })
+            .collect();
+        // only the columns the query reads are scanned, with the tags the rows it may access
+        // are told apart by
+        let mut columns: BTreeMap<_, _> = write_buffer::query_columns(query)
             .map_err(|e| Box::new(e) as DatabaseError)
-            .context(ErrorReplicating {})?;
+            .context(UnknownDatabaseError {})?
+            .into_iter()
+            .map(|(table, columns)| (table, timestamps::stored_columns(timestamp_rules, &columns)))
+            .collect();
+        // the rows past the retention period are left out by their timestamp
+        let boundary = retention::boundary(&db.rules, Utc::now());
+        for table_columns in columns.values_mut() {
+            table_columns.extend(access.tag_columns().into_iter().map(ToString::to_string));
+            if boundary.is_some() {
+                table_columns.insert(TIME_COLUMN_NAME.to_string());
+            }
+        }
 
-        Ok(())
-    }
-}
+        for &table_name in table_names {
+            let query_name = format!("{}{}", prefix, table_name);
+            if tables.contains_key(&query_name) {
+                continue;
+            }
+            if let Some(rows) = self.dimension_rows(db, table_name).await? {
+                tables.insert(query_name, vec![vec![rows]]);
+                continue;
+            }
 
-/// The `Server` will ask the `ConnectionManager` for connections to a specific remote server.
-/// These connections can be used to communicate with other servers.
-/// This is implemented as a trait for dependency injection in testing.
-#[async_trait]
-pub trait ConnectionManager {
-    type Error: std::error::Error + Send + Sync + 'static;
+            let table_columns: Vec<_> = columns
+                .get(&query_name)
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .collect();
+            let partitions = query_chunk::scan_table(
+                &chunks,
+                table_name,
+                &table_columns,
+                predicates.get(&query_name).map_or(&[][..], Vec::as_slice),
+                self.query_parallelism,
+            )
+            .await
+            .context(ScanningChunks)?;
+            let partitions = match boundary {
+                Some(boundary) => tombstone::expire_rows(partitions, boundary)
+                    .context(FilteringRows { db: db_name })?,
+                None => partitions,
+            };
+            if partitions.is_empty() {
+                continue;
+            }
+            let partitions = partitions
+                .iter()
+                .map(|batches| {
+                    batches
+                        .iter()
+                        .map(|batch| timestamps::query_batch(timestamp_rules, batch))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .context(ConvertingTimestamps { table: table_name })?;
+            let sort_key: Vec<_> = query_chunk::table_sort_key(&chunks, table_name)
+                .await
+                .context(ScanningChunks)?
+                .iter()
+                .map(|column| timestamps::query_name(timestamp_rules, column).to_string())
+                .collect();
+            if !sort_key.is_empty() {
+                sort_keys.insert(query_name.clone(), sort_key);
+            }
+            tables.insert(query_name, partitions);
+        }
+        Ok(())
+    }
 
-    type RemoteServer: RemoteServer;
+    /// Returns the rows of the persisted chunk `chunk` of the database from its read buffer,
+    /// reloading them from the Parquet file of the chunk if they aren't there
+    async fn reload_chunk(
+        &self,
+        db_name: &str,
+        db: &Db,
+        chunk: &PersistedChunk,
+        fetches: &Arc<Semaphore>,
+    ) -> Result<Arc<ReadBufferChunk>> {
+        let now = Utc::now().timestamp_nanos();
+        if let Some(rows) = db.reloaded.lock().expect("mutex poisoned").get(chunk, now) {
+            return Ok(rows);
+        }
 
-    async fn remote_server(&self, connect: &str) -> Result<Arc<Self::RemoteServer>, Self::Error>;
-}
+        // the deletes are applied as the rows are scanned, as more may be recorded later
+        let batches = ParquetChunk::new(Arc::clone(&self.store), chunk.clone(), vec![])
+            .with_fetches(Arc::clone(fetches))
+            .table_to_arrow(&chunk.table_name, &[])
+            .await
+            .context(ScanningChunks)?;
+        let mut tables = BTreeMap::new();
+        tables.insert(chunk.table_name.clone(), batches);
+        let rows = Arc::new(ReadBufferChunk::new(
+            &chunk.partition_key,
+            chunk.id,
+            tables,
+            &[],
+        ));
 
-/// The `RemoteServer` represents the API for replicating, subscribing, and querying other servers.
-#[async_trait]
-pub trait RemoteServer {
-    type Error: std::error::Error + Send + Sync + 'static;
+        db.reloaded
+            .lock()
+            .expect("mutex poisoned")
+            .insert(chunk, Arc::clone(&rows), now);
+        tiering::record_reload(db_name);
+        debug!(
+            "reloaded chunk {} of partition {} in database {} into the read buffer",
+            chunk.id, chunk.partition_key, db_name
+        );
+        Ok(rows)
+    }
+
+    /// Returns a summary of the chunks held in the local write buffer of the database, in its
+    /// mutable buffer and in its read buffer
+    pub async fn chunk_summaries(&self, db_name: &str) -> Result<Vec<ChunkSummary>> {
+        let buff = self.local_buffer(db_name)?;
+        let db = &self.config.databases[db_name];
+
+        let mut summaries = buff.chunk_summaries().await;
+        summaries.extend(
+            db.read_buffer
+                .lock()
+                .expect("mutex poisoned")
+                .iter()
+                .map(|chunk| chunk.summary()),
+        );
+        Ok(summaries)
+    }
+
+    /// Returns the memory used by the buffers and the running queries of the database
+    pub async fn memory_usage(&self, db_name: &str) -> Result<MemoryUsage> {
+        let buff = self.local_buffer(db_name)?;
+        let db = &self.config.databases[db_name];
+
+        let mutable_buffer = buff
+            .chunk_summaries()
+            .await
+            .iter()
+            .map(|c| c.estimated_bytes)
+            .sum();
+        let read_buffer = db
+            .read_buffer
+            .lock()
+            .expect("mutex poisoned")
+            .iter()
+            .map(|c| c.summary().estimated_bytes)
+            .sum::<usize>()
+            + db.reloaded.lock().expect("mutex poisoned").bytes();
+
+        Ok(MemoryUsage {
+            mutable_buffer,
+            read_buffer,
+            queries: db.query_memory.bytes(),
+        })
+    }
 
-    /// Sends a replicated write to a remote server. This is step #2 from the diagram.
-    async fn replicate(
+    /// Moves the closed chunk `chunk_id` of partition `partition_key` from the mutable buffer
+    /// of the database to its read buffer, returning the summary of the read buffer chunk.
+    /// Queries see the rows of the chunk throughout the move.
+    pub async fn move_chunk(
         &self,
-        db: &str,
-        replicated_write: &ReplicatedWrite,
-    ) -> Result<(), Self::Error>;
-}
+        db_name: &str,
+        partition_key: &str,
+        chunk_id: u32,
+    ) -> Result<ChunkSummary> {
+        let buff = self.local_buffer(db_name)?;
+        let db = &self.config.databases[db_name];
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Db {
-    #[serde(flatten)]
-    pub rules: DatabaseRules,
-    #[serde(skip)]
-    pub buffer: Option<WriteBufferDb>,
-    #[serde(skip)]
-    sequence: AtomicU64,
-}
+        let chunk = MutableBufferChunk::all(buff)
+            .await
+            .into_iter()
+            .find(|c| {
+                c.partition_key() == partition_key
+                    && c.id() == chunk_id
+                    && c.storage() == ChunkStorage::ClosedMutableBuffer
+            })
+            .context(ClosedChunkNotFound {
+                db: db_name,
+                partition_key,
+                chunk_id,
+            })?;
+
+        let chunk = Arc::new(
+            ReadBufferChunk::load(&chunk, &db.rules.trigram_indexes)
+                .await
+                .context(ScanningChunks)?,
+        );
+        let summary = chunk.summary();
+        db.read_buffer
+            .lock()
+            .expect("mutex poisoned")
+            .push(Arc::clone(&chunk));
+
+        buff.drop_chunk(partition_key, chunk_id)
+            .await
+            .map_err(|e| Box::new(e) as DatabaseError)
+            .context(UnknownDatabaseError {})?;
+
+        Ok(summary)
+    }
+
+    /// Closes the open chunk for `partition_key` in the local write buffer of the database,
+    /// returning its summary
+    pub async fn close_chunk(&self, db_name: &str, partition_key: &str) -> Result<ChunkSummary> {
+        let buff = self.local_buffer(db_name)?;
+
+        let has_open_chunk = buff.chunk_summaries().await.iter().any(|c| {
+            c.partition_key == partition_key && c.storage == ChunkStorage::OpenMutableBuffer
+        });
+        ensure!(
+            has_open_chunk,
+            OpenChunkNotFound {
+                db: db_name,
+                partition_key
+            }
+        );
+
+        buff.close_chunk(partition_key)
+            .await
+            .map_err(|e| Box::new(e) as DatabaseError)
+            .context(UnknownDatabaseError {})
+    }
+
+    /// Starts a job exporting the data of the database's local write buffer to `store` as
+    /// Parquet, one file per table of each chunk at
+    /// `<prefix>/<db>/<partition key>/<chunk id>/<table>.parquet`. Chunks that have not been
+    /// persisted yet are exported from memory. Only the table `table`, the partition
+    /// `partition_key` and the rows in `range` are exported, when set.
+    pub async fn export_database(
+        &self,
+        db_name: &str,
+        table: Option<&str>,
+        partition_key: Option<&str>,
+        range: Option<TimestampRange>,
+        store: Arc<ObjectStore>,
+        prefix: &str,
+    ) -> Result<Tracker> {
+        let buff = self.local_buffer(db_name)?;
+        let settings = self
+            .config
+            .databases
+            .get(db_name)
+            .map(|db| db.rules.parquet.clone())
+            .unwrap_or_default();
+
+        // the rows are copied out under the lock, so that writes arriving while the job runs
+        // are left out of the export
+        let tables = buff
+            .export(table, partition_key, range)
+            .await
+            .map_err(|e| Box::new(e) as DatabaseError)
+            .context(UnknownDatabaseError {})?;
+
+        let db_prefix = if prefix.is_empty() {
+            db_name.to_string()
+        } else {
+            format!("{}/{}", prefix.trim_end_matches('/'), db_name)
+        };
+
+        Ok(self.jobs.spawn(
+            format!("export database {}", db_name),
+            |progress| async move {
+                progress.set_total(tables.len());
+                for table in tables {
+                    let location = format!(
+                        "{}/{}/{}/{}.parquet",
+                        db_prefix,
+                        table.partition_key,
+                        table.chunk_id,
+                        table.schema.measurement()
+                    );
+                    let data = Bytes::from(encode_parquet(
+                        &table.schema,
+                        &table.columns,
+                        vec![],
+                        &settings,
+                    )?);
+                    let len = data.len();
+
+                    store
+                        .put(
+                            &location,
+                            futures::stream::once(async move { std::io::Result::Ok(data) }),
+                            len,
+                        )
+                        .await
+                        .context(StoreError)?;
+                    progress.inc_completed(1);
+                }
+                Ok::<_, Error>(())
+            },
+        ))
+    }
+
+    /// Takes a snapshot of database `db_name` to `prefix` in `store`, see `snapshot`: copies
+    /// the Parquet files of the chunks persisted to object storage in a background job, then
+    /// writes the manifest holding the rules and the catalog as of when the snapshot was taken.
+    pub async fn snapshot_database(
+        &self,
+        db_name: &str,
+        store: Arc<ObjectStore>,
+        prefix: &str,
+    ) -> Result<Tracker> {
+        let writer_id = self.require_id()?;
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        // the catalog is copied at once, so that the snapshot is consistent even if chunks are
+        // persisted while the files are copied
+        let mut catalog = db.catalog.lock().expect("mutex poisoned").clone();
+        let chunks = catalog.chunks();
+        catalog.relocate(snapshot::relative_location);
+        let manifest = Snapshot {
+            db_name: db_name.to_string(),
+            writer_id,
+            rules: db.rules.clone(),
+            catalog,
+        };
+
+        let source = Arc::clone(&self.store);
+        let prefix = snapshot::snapshot_prefix(prefix, db_name);
+        Ok(self.jobs.spawn(
+            format!("snapshot database {}", db_name),
+            |progress| async move {
+                progress.set_total(chunks.len());
+                for chunk in &chunks {
+                    let to = snapshot::join(&prefix, &snapshot::relative_location(chunk));
+                    snapshot::copy_file(&source, &chunk.location, &store, &to, chunk).await?;
+                    progress.inc_completed(1);
+                }
+                snapshot::write_manifest(&store, &prefix, &manifest).await
+            },
+        ))
+    }
+
+    /// Copies the Parquet files of the snapshot under `prefix` in `store` to the store of this
+    /// server, where they are restored as database `db_name`, checking them against their
+    /// sizes and checksums. Returns the snapshot, with the chunks of its catalog at their new
+    /// locations, to pass to `restore_database` once the files are copied.
+    pub async fn copy_snapshot(
+        &self,
+        db_name: &str,
+        store: &ObjectStore,
+        prefix: &str,
+    ) -> Result<Snapshot> {
+        let id = self.require_id()?;
+        ensure!(
+            !self.config.databases.contains_key(db_name),
+            DatabaseAlreadyExists { db: db_name }
+        );
+
+        let mut snapshot = snapshot::read_manifest(store, prefix).await?;
+        let db_prefix = format!("{}/{}", id, db_name);
+        for chunk in snapshot.catalog.chunks() {
+            let from = snapshot::join(prefix, &chunk.location);
+            let to = snapshot::join(&db_prefix, &chunk.location);
+            snapshot::copy_file(store, &from, &self.store, &to, &chunk).await?;
+        }
+        snapshot
+            .catalog
+            .relocate(|chunk| snapshot::join(&db_prefix, &chunk.location));
+
+        Ok(snapshot)
+    }
+
+    /// Creates database `db_name` with the rules and catalog of `snapshot`, whose files
+    /// `copy_snapshot` copied, and stores the configuration
+    pub async fn restore_database(&mut self, db_name: &str, snapshot: Snapshot) -> Result<()> {
+        self.create_database(db_name, snapshot.rules).await?;
+        *self.config.databases[db_name]
+            .catalog
+            .lock()
+            .expect("mutex poisoned") = snapshot.catalog;
+        self.store_configuration().await?;
+
+        info!(
+            "restored database {} from the snapshot of database {} of writer {}",
+            db_name, snapshot.db_name, snapshot.writer_id
+        );
+        Ok(())
+    }
+
+    /// Persists the rows of a bulk import as a new chunk of partition `partition_key`, without
+    /// going through the write buffer, and registers the chunk in the catalog of the database.
+    /// The rows are written to `<writer id>/<db>/data/<partition key>/<chunk id>/<table>.parquet`
+    /// and the configuration, which holds the catalog, is stored again. The rows are sorted by
+    /// their tags and time first, the rows of the same point are merged, and the sort key is
+    /// recorded with the chunk.
+    pub async fn import_table(
+        &self,
+        db_name: &str,
+        partition_key: &str,
+        mut table: ImportedTable,
+    ) -> Result<PersistedChunk> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        db.ensure_writable(db_name)?;
+
+        let chunk = self
+            .persist_table(
+                db_name,
+                db,
+                partition_key,
+                &table.schema,
+                &mut table.columns,
+            )
+            .await?;
+        self.store_configuration().await?;
+
+        Ok(chunk)
+    }
+
+    /// Persists the rows of a bulk import like `import_table`, as a new chunk of each
+    /// partition the partition template of the database puts them in, as it would if they
+    /// were written as line protocol. The chunks are returned in the order of their partition
+    /// keys.
+    pub async fn import_partitioned_table(
+        &self,
+        db_name: &str,
+        table: ImportedTable,
+    ) -> Result<Vec<PersistedChunk>> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        db.ensure_writable(db_name)?;
+
+        // every imported row has a timestamp, so the default time is never used
+        let now = Utc::now();
+        let template = &db.rules.partition_template;
+        let table_name = table.schema.measurement().to_string();
+        let partitions = table.partition(|table, row| {
+            template.row_partition_key(
+                &table_name,
+                |column| table.value(row, column),
+                Some(table.time(row)),
+                &now,
+            )
+        });
+
+        let mut chunks = Vec::with_capacity(partitions.len());
+        for (partition_key, mut table) in partitions {
+            let chunk = self
+                .persist_table(
+                    db_name,
+                    db,
+                    &partition_key,
+                    &table.schema,
+                    &mut table.columns,
+                )
+                .await?;
+            chunks.push(chunk);
+        }
+        self.store_configuration().await?;
+
+        Ok(chunks)
+    }
+
+    /// Sets the policy of a chunk of the read buffer of the database, or of every chunk of a
+    /// partition, as described in `chunk_policy`, replacing its previous policy if any
+    pub async fn set_chunk_policy(&self, db_name: &str, policy: ChunkPolicy) -> Result<()> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        if let Err(reason) = policy.validate() {
+            return InvalidChunkPolicy {
+                partition_key: policy.partition_key,
+                reason,
+            }
+            .fail();
+        }
+
+        db.chunk_policies
+            .lock()
+            .expect("mutex poisoned")
+            .set(policy);
+        self.store_configuration().await
+    }
+
+    /// Removes the policy of the chunk `chunk_id` of the partition, or of the whole partition
+    /// if none
+    pub async fn remove_chunk_policy(
+        &self,
+        db_name: &str,
+        partition_key: &str,
+        chunk_id: Option<u32>,
+    ) -> Result<()> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        db.chunk_policies
+            .lock()
+            .expect("mutex poisoned")
+            .remove(partition_key, chunk_id)
+            .context(ChunkPolicyNotFound {
+                db: db_name,
+                partition_key,
+                chunk_id,
+            })?;
+        self.store_configuration().await
+    }
+
+    /// Returns the chunk policies of the database, ordered by partition key
+    pub fn chunk_policies(&self, db_name: &str) -> Result<Vec<ChunkPolicy>> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        Ok(db
+            .chunk_policies
+            .lock()
+            .expect("mutex poisoned")
+            .policies()
+            .to_vec())
+    }
+
+    /// Folds the overlapping chunks of every database into the chunks persisted before them:
+    /// the chunks of each table of a partition with an overlapping chunk are merged into one
+    /// chunk, keeping the last value written to each column of each point like queries do, and
+    /// leaving out the rows deleted by tombstones. The files of the compacted chunks are
+    /// deleted once the configuration, which holds the catalog, is stored again. Returns the
+    /// database name of each set of chunks compacted, with the chunk replacing them, if any
+    /// row is left.
+    pub async fn compact_overlapping_chunks(
+        &self,
+    ) -> Result<Vec<(String, Vec<PersistedChunk>, Option<PersistedChunk>)>> {
+        let mut compacted = vec![];
+        let mut dropped_tombstones = 0;
+
+        // the writer the replicas follow compacts their chunks
+        let owned = self
+            .config
+            .databases
+            .iter()
+            .filter(|(_, db)| db.replica_of.is_none());
+        for (db_name, db) in owned {
+            let groups: Vec<Vec<_>> = {
+                let catalog = db.catalog.lock().expect("mutex poisoned");
+                catalog
+                    .overlapping_chunks()
+                    .into_iter()
+                    .map(|group| {
+                        group
+                            .into_iter()
+                            .map(|chunk| {
+                                let deletes = catalog.deletes(&chunk);
+                                (chunk, deletes)
+                            })
+                            .collect()
+                    })
+                    .collect()
+            };
+
+            for group in groups {
+                if group.is_empty() {
+                    continue;
+                }
+                let (inputs, replacement) = self.merge_persisted_chunks(db_name, db, group).await?;
+                compacted.push((db_name.clone(), inputs, replacement));
+            }
+
+            dropped_tombstones += db
+                .catalog
+                .lock()
+                .expect("mutex poisoned")
+                .drop_obsolete_tombstones();
+        }
+
+        if !compacted.is_empty() || dropped_tombstones > 0 {
+            self.store_configuration().await?;
+        }
+        for (_, chunks, _) in &compacted {
+            for chunk in chunks {
+                self.store
+                    .delete(&chunk.location)
+                    .await
+                    .context(StoreError)?;
+            }
+        }
+        Ok(compacted)
+    }
+
+    /// Merges the small persisted chunks of every database whose lifecycle rules set a
+    /// `compaction_target_file_size`: the chunks of each table of a partition smaller than the
+    /// target are grouped as planned by `compaction::plan_compactions`, and each group is
+    /// merged into one chunk, sorted and with one row per point, leaving out the rows deleted
+    /// by tombstones. The files of the compacted chunks are deleted once the configuration,
+    /// which holds the catalog, is stored again. Returns the database name of each set of
+    /// chunks compacted, with the chunk replacing them, if any row is left. The chunks of
+    /// tables with overlapping chunks are left for `compact_overlapping_chunks`.
+    pub async fn compact_small_chunks(
+        &self,
+    ) -> Result<Vec<(String, Vec<PersistedChunk>, Option<PersistedChunk>)>> {
+        let mut compacted = vec![];
+        let mut dropped_tombstones = 0;
+
+        // the writer the replicas follow compacts their chunks
+        let owned = self
+            .config
+            .databases
+            .iter()
+            .filter(|(_, db)| db.replica_of.is_none());
+        for (db_name, db) in owned {
+            let config = match db.rules.lifecycle_rules.compaction_target_file_size {
+                Some(target_file_size) => compaction::CompactionConfig {
+                    target_file_size,
+                    ..Default::default()
+                },
+                None => continue,
+            };
+            let groups: Vec<Vec<_>> = {
+                let catalog = db.catalog.lock().expect("mutex poisoned");
+                let files: Vec<_> = catalog
+                    .files()
+                    .into_iter()
+                    .filter(|file| !catalog.has_overlaps(&file.partition_key, &file.table_name))
+                    .collect();
+                let chunks = catalog.chunks();
+                compaction::plan_compactions(&files, &config)
+                    .into_iter()
+                    .map(|plan| {
+                        chunks
+                            .iter()
+                            .filter(|chunk| plan.chunk_ids.contains(&chunk.id))
+                            .map(|chunk| (chunk.clone(), catalog.deletes(chunk)))
+                            .collect()
+                    })
+                    .collect()
+            };
+
+            for group in groups {
+                if group.is_empty() {
+                    continue;
+                }
+                let (inputs, replacement) = self.merge_persisted_chunks(db_name, db, group).await?;
+                compacted.push((db_name.clone(), inputs, replacement));
+            }
+
+            dropped_tombstones += db
+                .catalog
+                .lock()
+                .expect("mutex poisoned")
+                .drop_obsolete_tombstones();
+        }
+
+        if !compacted.is_empty() || dropped_tombstones > 0 {
+            self.store_configuration().await?;
+        }
+        for (_, chunks, _) in &compacted {
+            for chunk in chunks {
+                self.store
+                    .delete(&chunk.location)
+                    .await
+                    .context(StoreError)?;
+            }
+        }
+        Ok(compacted)
+    }
+
+    /// Merges `group`, persisted chunks of the same table and partition with the deletes that
+    /// apply to them, into one chunk, keeping the last value written to each column of each
+    /// point like queries do, and replaces them with it in the catalog. Returns the chunks
+    /// compacted, whose files are left for the caller to delete once the catalog is stored,
+    /// with the chunk replacing them, if any row is left.
+    async fn merge_persisted_chunks(
+        &self,
+        db_name: &str,
+        db: &Db,
+        group: Vec<(PersistedChunk, Vec<DeletePredicate>)>,
+    ) -> Result<(Vec<PersistedChunk>, Option<PersistedChunk>)> {
+        let (partition_key, table_name) = (
+            group[0].0.partition_key.clone(),
+            group[0].0.table_name.clone(),
+        );
+        let ids: Vec<_> = group.iter().map(|(chunk, _)| chunk.id).collect();
+        let mut chunks: Vec<Arc<dyn QueryChunk>> = vec![];
+        for (chunk, deletes) in &group {
+            chunks.push(Arc::new(ParquetChunk::new(
+                Arc::clone(&self.store),
+                chunk.clone(),
+                deletes.clone(),
+            )));
+        }
+        let batches = query_chunk::merge_table(&chunks, &table_name, &[])
+            .await
+            .context(ScanningChunks)?;
+
+        let replacement = if batches.iter().all(|batch| batch.num_rows() == 0) {
+            None
+        } else {
+            let (schema, mut columns) = packers_from_batches(&table_name, &batches)?;
+            let sort_key = sort_for_persistence(&schema, &mut columns)?;
+            Some(
+                self.write_chunk(
+                    db_name,
+                    db,
+                    &partition_key,
+                    &schema,
+                    &columns,
+                    sort_key,
+                    ids.clone(),
+                )
+                .await?,
+            )
+        };
+
+        db.catalog
+            .lock()
+            .expect("mutex poisoned")
+            .compact_chunks(&ids, replacement.clone());
+        let inputs = group.into_iter().map(|(chunk, _)| chunk).collect();
+        Ok((inputs, replacement))
+    }
+
+    /// Rebuilds the catalog of database `db_name` from the Parquet files under its prefix in
+    /// object storage, for when the catalog stored with the configuration is lost or corrupt.
+    /// The database must exist: if the whole configuration was lost, create it again first.
+    /// Files that can't be read are skipped. The tombstones of the catalog are kept, as the
+    /// recovered chunks keep their ids, and the configuration is stored again.
+    pub async fn rebuild_catalog(&self, db_name: &str) -> Result<RebuiltCatalog> {
+        let id = self.require_id()?;
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        let prefix = catalog_rebuild::data_prefix(id, db_name);
+        let locations = self
+            .store
+            .list(Some(&prefix))
+            .await
+            .context(StoreError)?
+            .try_concat()
+            .await
+            .context(StoreError)?;
+
+        let mut recovered = vec![];
+        let mut skipped = vec![];
+        for location in locations {
+            let data = integrity::fetch(&self.store, &location)
+                .await
+                .context(StoreError)?;
+            match catalog_rebuild::recover_chunk(&location, &data) {
+                Ok(chunk) => recovered.push(chunk),
+                Err(e) => {
+                    warn!(
+                        "skipping {} rebuilding the catalog of {}: {}",
+                        location, db_name, e
+                    );
+                    skipped.push((location, e));
+                }
+            }
+        }
+        let chunks = catalog_rebuild::drop_replaced(recovered);
+
+        db.catalog
+            .lock()
+            .expect("mutex poisoned")
+            .rebuild(chunks.clone());
+        self.store_configuration().await?;
+
+        info!(
+            "rebuilt the catalog of {} with {} chunks, skipping {} files",
+            db_name,
+            chunks.len(),
+            skipped.len()
+        );
+        Ok(RebuiltCatalog { chunks, skipped })
+    }
+
+    /// Returns the chunks of the database persisted to object storage
+    pub fn persisted_chunks(&self, db_name: &str) -> Result<Vec<PersistedChunk>> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        Ok(db.catalog.lock().expect("mutex poisoned").chunks())
+    }
+
+    /// Fetches the file of each chunk persisted to object storage, of the database `db_name`
+    /// or of every database if not set, and checks it against the size and checksum recorded
+    /// in the catalog. Returns the database name and status of each chunk, in the order of the
+    /// databases and of the chunks of each.
+    pub async fn verify_persisted_chunks(
+        &self,
+        db_name: Option<&str>,
+    ) -> Result<Vec<(String, PersistedChunk, integrity::FileStatus)>> {
+        let databases: Vec<_> = match db_name {
+            Some(db_name) => {
+                let db = self
+                    .config
+                    .databases
+                    .get(db_name)
+                    .context(DatabaseNotFound { db: db_name })?;
+                vec![(db_name, db)]
+            }
+            None => self
+                .config
+                .databases
+                .iter()
+                .map(|(db_name, db)| (db_name.as_str(), db))
+                .collect(),
+        };
+
+        let mut checked = vec![];
+        for (db_name, db) in databases {
+            let chunks = db.catalog.lock().expect("mutex poisoned").chunks();
+            for chunk in chunks {
+                let status = integrity::verify(&self.store, &chunk).await;
+                checked.push((db_name.to_string(), chunk, status));
+            }
+        }
+        Ok(checked)
+    }
+
+    /// Merges the chunks of each partition of the read buffers whose number reaches the
+    /// `read_buffer_merge_chunks` lifecycle rule of their database into one chunk, see
+    /// `ReadBufferChunk::merge`. The partitions with a chunk that has a policy of its own are
+    /// left alone, as the merged chunk can't follow it. Returns the database name of each set
+    /// of chunks merged, with the summaries of the chunks and of the chunk replacing them.
+    pub async fn merge_read_buffer_chunks(
+        &self,
+    ) -> Result<Vec<(String, Vec<ChunkSummary>, ChunkSummary)>> {
+        let mut merged = vec![];
+
+        for (db_name, db) in &self.config.databases {
+            let min_chunks = match db.rules.lifecycle_rules.read_buffer_merge_chunks {
+                Some(min_chunks) => min_chunks.max(2),
+                None => continue,
+            };
+
+            let partitions: Vec<Vec<Arc<ReadBufferChunk>>> = {
+                let policies = db.chunk_policies.lock().expect("mutex poisoned");
+                let read_buffer = db.read_buffer.lock().expect("mutex poisoned");
+                let mut partitions: BTreeMap<&str, Vec<_>> = BTreeMap::new();
+                for chunk in read_buffer.iter() {
+                    partitions
+                        .entry(chunk.partition_key())
+                        .or_default()
+                        .push(Arc::clone(chunk));
+                }
+                let mut mergeable = vec![];
+                for chunks in partitions.values() {
+                    let own_policy = chunks.iter().any(|chunk| {
+                        policies
+                            .get(chunk.partition_key(), chunk.id())
+                            .map_or(false, |policy| policy.chunk_id.is_some())
+                    });
+                    if chunks.len() >= min_chunks && !own_policy {
+                        mergeable.push(chunks.clone());
+                    }
+                }
+                mergeable
+            };
+
+            for chunks in partitions {
+                let chunk = Arc::new(
+                    ReadBufferChunk::merge(&chunks, &db.rules.trigram_indexes)
+                        .context(ScanningChunks)?,
+                );
+
+                // the chunks may have been dropped while they were merged, and their rows
+                // must not come back
+                let mut read_buffer = db.read_buffer.lock().expect("mutex poisoned");
+                let positions: Vec<_> = chunks
+                    .iter()
+                    .filter_map(|input| read_buffer.iter().position(|c| Arc::ptr_eq(c, input)))
+                    .collect();
+                if positions.len() != chunks.len() {
+                    continue;
+                }
+                // the chunks are in the order of the read buffer, and the merged chunk takes
+                // the place of the oldest
+                read_buffer[positions[0]] = Arc::clone(&chunk);
+                for position in positions[1..].iter().rev() {
+                    read_buffer.remove(*position);
+                }
+
+                let summaries = chunks.iter().map(|c| c.summary()).collect();
+                merged.push((db_name.clone(), summaries, chunk.summary()));
+            }
+        }
+
+        Ok(merged)
+    }
+
+    /// Pauses the writes to a database, which are rejected with `IngestPaused` until they are
+    /// resumed, or resumes them if `paused` is false. Writers are told to retry after
+    /// `INGEST_PAUSED_RETRY_AFTER`, so that they hold on to their writes meanwhile.
+    pub fn set_ingest_paused(&mut self, db_name: &str, paused: bool) -> Result<()> {
+        self.require_id()?;
+
+        let db = self
+            .config
+            .databases
+            .get_mut(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        db.ensure_writable(db_name)?;
+        db.ingest_paused = paused;
+        Ok(())
+    }
+
+    /// Returns true if the writes to the database are paused
+    pub fn is_ingest_paused(&self, db_name: &str) -> Result<bool> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        Ok(db.ingest_paused)
+    }
+
+    /// Returns how the last replay of the WAL of the database went, or `None` if its local
+    /// buffer wasn't restored from a WAL
+    pub fn wal_replay(&self, db_name: &str) -> Result<Option<WalReplay>> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        Ok(db.wal_replay.clone())
+    }
+
+    /// Returns how the last replay of the WAL of each database went, for the databases whose
+    /// local buffer was restored from a WAL
+    pub fn wal_replays(&self) -> Vec<(String, WalReplay)> {
+        self.config
+            .databases
+            .iter()
+            .filter_map(|(db_name, db)| Some((db_name.clone(), db.wal_replay.clone()?)))
+            .collect()
+    }
+
+    /// Skips the replay of the WAL of a database whose replay failed to the entry with
+    /// sequence number `to`, and replays the WAL again. The entries from the one the replay
+    /// failed at up to but not including `to` are left out of this and later replays, so they
+    /// are lost. Returns how the new replay went, which fails again if an entry after those
+    /// can't be restored either.
+    pub async fn skip_wal_replay(&mut self, db_name: &str, to: u64) -> Result<WalReplay> {
+        let id = self.require_id()?;
+
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        let from = match &db.wal_replay {
+            Some(replay) if replay.failed() => replay.progress.next_sequence_number,
+            _ => return WalReplayNotFailed { db: db_name }.fail(),
+        };
+        ensure!(
+            to > from,
+            InvalidWalSkip {
+                db: db_name,
+                from,
+                to
+            }
+        );
+        let mut skips = db.wal_skips.clone();
+        skips.push(wal_replay::Skip { from, to });
+        let wal_dir = self.wal_dir.as_deref();
+        let (buffer, replay) = open_buffer(&self.jobs, wal_dir, db_name, &db.rules, &skips).await?;
+        // the WAL is gone if it was removed meanwhile, and the buffer starts empty
+        let replay = replay.unwrap_or_default();
+        info!(db = db_name, from, to, "skipped the replay of the WAL");
+
+        let limits = self.write_limits;
+        let db = self
+            .config
+            .databases
+            .get_mut(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        db.wal_skips = skips;
+        db.buffer = buffer.map(Arc::new);
+        db.wal_replay = Some(replay.clone());
+        db.configure_buffer(limits);
+        db.resume_sequence(id).await;
+
+        Ok(replay)
+    }
+
+    fn local_buffer(&self, db_name: &str) -> Result<&Arc<WriteBufferDb>> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+
+        db.buffer.as_ref().context(NoLocalBuffer { db: db_name })
+    }
+
+    pub async fn handle_write(&self, db_name: &str, db: &Db, entry: Entry) -> Result<()> {
+        db.record_access(db_name);
+        match self.store_and_replicate(db_name, db, &entry).await {
+            Ok(()) => {
+                db.write_stats.record(db_name, &entry, Utc::now());
+                db.tails.publish(db_name, &entry);
+                Ok(())
+            }
+            Err(e) => {
+                db.write_stats.record_failed_entry(db_name, &entry);
+                Err(e)
+            }
+        }
+    }
+
+    async fn store_and_replicate(&self, db_name: &str, db: &Db, entry: &Entry) -> Result<()> {
+        db.ensure_writable(db_name)?;
+        db.ensure_ingesting(db_name)?;
+        self.check_lease(db_name, db)?;
+
+        if let Some(buf) = &db.buffer {
+            self.enforce_memory_budget(db_name, db).await?;
+            buf.store_entry(entry).await.map_err(|e| {
+                if e.retry_after().is_some() {
+                    Error::WriteThrottled {
+                        db: db_name.to_string(),
+                        source: e,
+                    }
+                } else {
+                    Error::UnknownDatabaseError {
+                        source: Box::new(e) as DatabaseError,
+                    }
+                }
+            })?;
+        }
+
+        self.replicate(db_name, db, entry).await
+    }
+
+    /// Applies the lifecycle rules of the database when it uses more memory than its soft
+    /// limit, or its hard limit if it has no soft limit: the persisted chunks reloaded into the
+    /// read buffer are evicted first, then the chunks of the mutable buffer are closed and
+    /// moved to the read buffer, which holds them more compactly, and if that is not enough
+    /// and the rules allow dropping data that isn't persisted, the oldest chunks of the read
+    /// buffer that aren't pinned are dropped. Returns `BufferFull` if the database still uses
+    /// more than its hard limit, so that the write is rejected.
+    async fn enforce_memory_budget(&self, db_name: &str, db: &Db) -> Result<()> {
+        let rules = &db.rules.lifecycle_rules;
+        let threshold = match rules.buffer_size_soft.or(rules.buffer_size_hard) {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
+        let buff = self.local_buffer(db_name)?;
+
+        self.evict_reloaded_chunks(db_name, db).await?;
+
+        if self.memory_usage(db_name).await?.total() > threshold {
+            // move the closed chunks first, as they hold the oldest data
+            let mut chunks = buff.chunk_summaries().await;
+            chunks.sort_by_key(|c| c.storage == ChunkStorage::OpenMutableBuffer);
+
+            for chunk in chunks {
+                if self.memory_usage(db_name).await?.total() <= threshold {
+                    break;
+                }
+                if chunk.storage == ChunkStorage::OpenMutableBuffer {
+                    // a concurrent write may have closed it already
+                    match buff.close_chunk(&chunk.partition_key).await {
+                        Ok(_) | Err(write_buffer::Error::OpenChunkNotFound { .. }) => {}
+                        Err(e) => {
+                            return Err(Error::UnknownDatabaseError {
+                                source: Box::new(e),
+                            })
+                        }
+                    }
+                }
+                match self
+                    .move_chunk(db_name, &chunk.partition_key, chunk.id)
+                    .await
+                {
+                    Ok(_) | Err(Error::ClosedChunkNotFound { .. }) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        if rules.drop_non_persisted {
+            while self.memory_usage(db_name).await?.total() > threshold {
+                let policies = db.chunk_policies.lock().expect("mutex poisoned");
+                let mut read_buffer = db.read_buffer.lock().expect("mutex poisoned");
+                let oldest_unpinned = read_buffer
+                    .iter()
+                    .position(|chunk| !policies.is_pinned(chunk.partition_key(), chunk.id()));
+                let dropped = match oldest_unpinned {
+                    Some(index) => read_buffer.remove(index),
+                    None => break,
+                };
+                info!(
+                    "dropped chunk {} of partition {} in database {} to free memory",
+                    dropped.id(),
+                    dropped.partition_key(),
+                    db_name
+                );
+            }
+        }
+
+        if let Some(limit) = rules.buffer_size_hard {
+            let used = self.memory_usage(db_name).await?.total();
+            ensure!(
+                used <= limit,
+                BufferFull {
+                    db: db_name,
+                    used,
+                    limit
+                }
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Evicts the least recently queried of the persisted chunks reloaded into the read buffer
+    /// of the database while it uses more memory than its soft limit, or its hard limit if it
+    /// has no soft limit, as `tiering::select_victims` chooses them
+    async fn evict_reloaded_chunks(&self, db_name: &str, db: &Db) -> Result<()> {
+        let rules = &db.rules.lifecycle_rules;
+        let threshold = match rules.buffer_size_soft.or(rules.buffer_size_hard) {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
+        let usage = self.memory_usage(db_name).await?;
+        // the read buffer may use what the mutable buffer and the queries leave
+        let max_bytes = threshold.saturating_sub(usage.mutable_buffer + usage.queries);
+
+        let mut resident: Vec<_> = db
+            .read_buffer
+            .lock()
+            .expect("mutex poisoned")
+            .iter()
+            .map(|chunk| tiering::ResidentChunk {
+                partition_key: chunk.partition_key().to_string(),
+                id: chunk.id(),
+                size_bytes: chunk.summary().estimated_bytes,
+                last_access: chunk.loaded_at().timestamp_nanos(),
+                persisted: false,
+            })
+            .collect();
+        let mut reloaded = db.reloaded.lock().expect("mutex poisoned");
+        resident.extend(reloaded.resident());
+
+        for victim in tiering::select_victims(&resident, max_bytes) {
+            reloaded.remove(victim.id);
+            tiering::record_eviction(db_name, victim);
+            debug!(
+                "evicted chunk {} of partition {} in database {} from the read buffer",
+                victim.id, victim.partition_key, db_name
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The `Server` will ask the `ConnectionManager` for connections to a specific remote server.
+/// These connections can be used to communicate with other servers.
+/// This is implemented as a trait for dependency injection in testing.
+#[async_trait]
+pub trait ConnectionManager {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    type RemoteServer: RemoteServer;
+
+    async fn remote_server(&self, connect: &str) -> Result<Arc<Self::RemoteServer>, Self::Error>;
+}
+
+/// The `RemoteServer` represents the API for replicating, subscribing, and querying other servers.
+#[async_trait]
+pub trait RemoteServer {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Sends an entry to a remote server. This is step #2 from the diagram.
+    async fn replicate(&self, db: &str, entry: &Entry) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Db {
+    #[serde(flatten)]
+    pub rules: DatabaseRules,
+    #[serde(skip)]
+    pub buffer: Option<Arc<WriteBufferDb>>,
+    #[serde(skip)]
+    sequence: AtomicU64,
+    /// The chunks persisted to object storage
+    #[serde(default, skip_serializing_if = "catalog_is_empty")]
+    catalog: Mutex<Catalog>,
+    /// The chunks moved from the mutable buffer to the read buffer, oldest first
+    #[serde(skip)]
+    read_buffer: Mutex<Vec<Arc<ReadBufferChunk>>>,
+    /// The persisted chunks reloaded into the read buffer by queries, which are evicted first
+    /// when the database uses too much memory
+    #[serde(skip)]
+    reloaded: Mutex<tiering::ReloadedChunks>,
+    #[serde(skip)]
+    query_memory: QueryMemory,
+    /// The points written recently, if the database has a deduplication window
+    #[serde(skip)]
+    dedup: Mutex<DedupWindow>,
+    /// The writer this database is a read-only replica of, if it isn't owned by this server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    replica_of: Option<u32>,
+    /// The ownership lease the server holds on the database, if it claims leases
+    #[serde(skip)]
+    lease: Mutex<Option<Lease>>,
+    /// The dimension tables of the database, by name
+    #[serde(default, skip_serializing_if = "no_dimension_tables")]
+    dimension_tables: Mutex<BTreeMap<String, DimensionTable>>,
+    /// The subscriptions to the rows written to the database
+    #[serde(skip)]
+    tails: tail::Subscriptions,
+    /// The rows written to each table since the server started
+    #[serde(skip)]
+    write_stats: WriteStats,
+    /// The policies pinning chunks in the read buffer or limiting how long they stay there
+    #[serde(default, skip_serializing_if = "no_chunk_policies")]
+    chunk_policies: Mutex<ChunkPolicies>,
+    /// Whether writes to the database are rejected until they are resumed, such as while an
+    /// operator deals with writes that break ingest
+    #[serde(default, skip_serializing_if = "is_false")]
+    ingest_paused: bool,
+    /// The entries of the WAL that replays leave out, skipped past by an operator because
+    /// they couldn't be restored
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    wal_skips: Vec<wal_replay::Skip>,
+    /// How the last replay of the WAL went, if the local buffer was restored from one
+    #[serde(skip)]
+    wal_replay: Option<WalReplay>,
+    /// When the database was last accessed, to hibernate it once it is idle
+    #[serde(skip)]
+    activity: Activity,
+}
+
+fn catalog_is_empty(catalog: &Mutex<Catalog>) -> bool {
+    catalog.lock().expect("mutex poisoned").is_empty()
+}
+
+fn no_dimension_tables(tables: &Mutex<BTreeMap<String, DimensionTable>>) -> bool {
+    tables.lock().expect("mutex poisoned").is_empty()
+}
+
+fn no_chunk_policies(policies: &Mutex<ChunkPolicies>) -> bool {
+    policies.lock().expect("mutex poisoned").is_empty()
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+impl PartialEq for Db {
+    fn eq(&self, other: &Self) -> bool {
+        self.rules == other.rules
+    }
+}
+impl Eq for Db {}
+
+const STARTING_SEQUENCE: u64 = 1;
+
+/// The default number of chunks a query scans at the same time
+pub const DEFAULT_QUERY_PARALLELISM: usize = 4;
+
+/// The default number of row groups of Parquet files a query fetches at the same time
+pub const DEFAULT_ROW_GROUP_FETCHES: usize = 16;
+
+/// How long the writers to a database whose writes are paused are told to wait before
+/// retrying
+pub const INGEST_PAUSED_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+impl Db {
+    /// Creates a database with `buffer` as its local buffer, see `open_buffer`, which is
+    /// configured from `rules` and throttles writes over `limits`
+    fn new(rules: DatabaseRules, buffer: Option<WriteBufferDb>, limits: WriteLimits) -> Self {
+        let db = Self {
+            rules,
+            buffer: buffer.map(Arc::new),
+            sequence: AtomicU64::new(STARTING_SEQUENCE),
+            catalog: Mutex::default(),
+            read_buffer: Mutex::default(),
+            reloaded: Mutex::default(),
+            query_memory: QueryMemory::default(),
+            dedup: Mutex::default(),
+            replica_of: None,
+            lease: Mutex::default(),
+            dimension_tables: Mutex::default(),
+            tails: tail::Subscriptions::default(),
+            write_stats: WriteStats::default(),
+            chunk_policies: Mutex::default(),
+            ingest_paused: false,
+            wal_skips: vec![],
+            wal_replay: None,
+            activity: Activity::default(),
+        };
+        db.configure_buffer(limits);
+        db
+    }
+
+    /// Returns `ReadOnlyReplica` if the database is a replica, which can't be changed
+    fn ensure_writable(&self, db_name: &str) -> Result<()> {
+        match self.replica_of {
+            Some(owner) => ReadOnlyReplica { db: db_name, owner }.fail(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns `IngestPaused` if writes to the database are paused, counting the rejected
+    /// write, or `WalReplayFailed` if the replay of its WAL failed
+    fn ensure_ingesting(&self, db_name: &str) -> Result<()> {
+        if let Some(replay) = self.wal_replay.as_ref().filter(|replay| replay.failed()) {
+            return WalReplayFailed {
+                db: db_name,
+                sequence_number: replay.progress.next_sequence_number,
+            }
+            .fail();
+        }
+        if self.ingest_paused {
+            metrics::registry()
+                .counter(
+                    "cluster_paused_writes_total",
+                    "Writes rejected because the writes to their database are paused",
+                    &[("db_name", db_name)],
+                )
+                .inc();
+            return IngestPaused { db: db_name }.fail();
+        }
+        Ok(())
+    }
+
+    /// The name of the rules template the rules of the database come from, if any
+    fn template_name(&self) -> Option<&str> {
+        self.rules
+            .template
+            .as_ref()
+            .map(|template| template.name.as_str())
+    }
+
+    fn next_sequence(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Records a write or query of the database, which wakes it if it is hibernating
+    fn record_access(&self, db_name: &str) {
+        if self.activity.record_access(Utc::now()) {
+            info!("database {} woke from hibernation", db_name);
+            metrics::registry()
+                .counter(
+                    "cluster_databases_woken_total",
+                    "Databases woken from hibernation by a write or query",
+                    &[],
+                )
+                .inc();
+        }
+    }
+
+    /// Replaces the rules of the database, installing `buffer` if the rules now store
+    /// locally or dropping the local buffer if they no longer do
+    fn apply_rules(
+        &mut self,
+        rules: DatabaseRules,
+        buffer: Option<WriteBufferDb>,
+        limits: WriteLimits,
+    ) {
+        if !rules.store_locally {
+            self.buffer = None;
+            self.wal_replay = None;
+            self.read_buffer.lock().expect("mutex poisoned").clear();
+            self.reloaded.lock().expect("mutex poisoned").clear();
+        } else if self.buffer.is_none() {
+            self.buffer = buffer.map(Arc::new);
+        }
+        if rules.dedup_window.is_none() {
+            *self.dedup.lock().expect("mutex poisoned") = DedupWindow::default();
+        }
+        self.rules = rules;
+        self.configure_buffer(limits);
+    }
+
+    /// Configures the local buffer from the rules of the database
+    fn configure_buffer(&self, limits: WriteLimits) {
+        if let Some(buffer) = &self.buffer {
+            buffer.set_retention_period(self.rules.retention_period);
+            buffer.set_write_limits(write_limits(&self.rules, limits));
+            buffer.set_rollup_rules(&self.rules.rollups);
+        }
+    }
+
+    /// Continues the sequence of the entries server `id` writes after the last one the local
+    /// buffer replayed from its WAL, which would otherwise skip the new entries as applied
+    async fn resume_sequence(&self, id: u32) {
+        let buffer = match &self.buffer {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        let last = buffer
+            .sequences()
+            .await
+            .iter()
+            .filter(|(_, producer_id, _)| *producer_id == id)
+            .map(|(_, _, mark)| mark)
+            .max();
+        if let Some(last) = last {
+            if self.sequence.load(Ordering::SeqCst) <= last {
+                self.sequence.store(last + 1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// The limits over which the local buffer throttles writes, those of the server unless the
+/// rules set the partition limit. The database wide hard limit is enforced by
+/// `Server::enforce_memory_budget` instead, after the soft limit had its chance.
+fn write_limits(rules: &DatabaseRules, defaults: WriteLimits) -> WriteLimits {
+    WriteLimits {
+        partition_size: rules
+            .lifecycle_rules
+            .partition_size_hard
+            .or(defaults.partition_size),
+        buffer_size: defaults.buffer_size,
+    }
+}
+
+/// Opens the local buffer of database `db_name`, if `rules` store locally: from its WAL in
+/// `wal_dir` if it has one, or a new WAL otherwise. Without a WAL directory, the buffer is
+/// only kept in memory. The WAL is replayed leaving out the entries in `skips`, see
+/// `wal_replay::replay`, and how the replay went is returned along with the buffer, which is
+/// `None` if the replay failed.
+async fn open_buffer(
+    jobs: &TrackerRegistry,
+    wal_dir: Option<&Path>,
+    db_name: &str,
+    rules: &DatabaseRules,
+    skips: &[wal_replay::Skip],
+) -> Result<(Option<WriteBufferDb>, Option<WalReplay>)> {
+    if !rules.store_locally {
+        return Ok((None, None));
+    }
+    let wal_dir = match wal_dir {
+        Some(wal_dir) => wal_dir,
+        None => return Ok((Some(WriteBufferDb::new(db_name)), None)),
+    };
+
+    let dir = wal_dir.join(db_name);
+    if !dir.exists() {
+        let mut dir = wal_dir.to_path_buf();
+        let buffer = WriteBufferDb::try_with_wal(db_name, &mut dir)
+            .await
+            .context(OpeningBuffer { db: db_name })?;
+        return Ok((Some(buffer), None));
+    }
+
+    let (buffer, replay) = wal_replay::replay(jobs, dir, db_name, skips).await;
+    if let Some(error) = &replay.error {
+        warn!(
+            db = db_name,
+            sequence_number = replay.progress.next_sequence_number,
+            error = %error,
+            "replay of the WAL failed, writes are rejected until it is skipped past the entry"
+        );
+    }
+    Ok((buffer, Some(replay)))
+}
+
+// location in the store for the configuration file
+fn config_location(id: u32) -> String {
+    format!("{}/config.json", id)
+}
+
+/// Returns whether the server with id `id` stored its configuration
+async fn config_stored(store: &ObjectStore, id: u32) -> Result<bool> {
+    let location = config_location(id);
+    let stored = store
+        .list(Some(&location))
+        .await
+        .context(StoreError)?
+        .try_concat()
+        .await
+        .context(StoreError)?
+        .contains(&location);
+    Ok(stored)
+}
+
+/// Loads the configuration stored by the server with id `id`
+async fn load_config(store: &ObjectStore, id: u32) -> Result<Config> {
+    let read_data = read_config(store, id).await?;
+    let mut config = serde_json::from_slice(&read_data).context(ErrorDeserializing)?;
+    load_entries(store, id, &mut config).await?;
+    Ok(config)
+}
+
+/// Replaces the databases of `config` with those the server with id `id` stored in entries
+/// under their leases, see `Server::store_configuration`
+async fn load_entries(store: &ObjectStore, id: u32, config: &mut Config) -> Result<()> {
+    for (db_name, data) in ownership::load_entries(store, id).await? {
+        let db = serde_json::from_slice(&data).context(ErrorDeserializing)?;
+        config.databases.insert(db_name, db);
+    }
+    Ok(())
+}
+
+async fn read_config(store: &ObjectStore, id: u32) -> Result<bytes::BytesMut> {
+    store
+        .get(&config_location(id))
+        .await
+        .context(StoreError)?
+        .map_ok(|b| bytes::BytesMut::from(&b[..]))
+        .try_concat()
+        .await
+        .context(StoreError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_deps::arrow::{
+        array::{Array, Int64Array},
+        csv,
+        util::string_writer::StringWriter,
+    };
+    use async_trait::async_trait;
+    use data_types::database_rules::{
+        FieldType, LifecycleRules, MeasurementSchema, ParquetSettings, PartitionTemplate,
+        StrictSchema, TemplatePart, TimestampRules, WriteBounds,
+    };
+    use data_types::table_schema::DataType;
+    use futures::TryStreamExt;
+    use influxdb_line_protocol::parse_lines;
+    use ingest::import::{FileFormat, SchemaMapping, TimeUnit};
+    use object_store::{InMemory, ObjectStoreIntegration};
+    use snafu::Snafu;
+    use std::{sync::Mutex, time::Duration};
+    use storage::DatabaseStore;
+
+    pub(crate) type TestError = Box<dyn std::error::Error + Send + Sync + 'static>;
+    pub(crate) type Result<T = (), E = TestError> = std::result::Result<T, E>;
+
+    #[tokio::test]
+    async fn server_api_calls_return_error_with_no_id_set() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+
+        let rules = DatabaseRules::default();
+        let resp = server.create_database("foo", rules).await.unwrap_err();
+        assert!(matches!(resp, Error::IdNotSet));
+
+        let lines = parsed_lines("cpu foo=1 10");
+        let resp = server.write_lines("foo", &lines).await.unwrap_err();
+        assert!(matches!(resp, Error::IdNotSet));
+
+        let resp = server
+            .create_host_group("group1".to_string(), vec!["serverA".to_string()])
+            .await
+            .unwrap_err();
+        assert!(matches!(resp, Error::IdNotSet));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_local() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let line = "cpu bar=1 10";
+        let lines: Vec<_> = parse_lines(line).map(|l| l.unwrap()).collect();
+        server.write_lines("foo", &lines).await.unwrap();
+
+        let results = server
+            .query_local("foo", "select * from cpu")
+            .await
+            .unwrap();
+
+        assert_eq!(to_csv(&results), "bar,time\n1,10\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serves_the_database_store() -> Result {
+        let mut server = Server::new(
+            TestConnectionManager::new(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        server.set_id(1);
+        let server = Arc::new(tokio::sync::RwLock::new(server));
+        let rules = DatabaseRules {
+            store_locally: true,
+            strict_schema: Some(Default::default()),
+            ..Default::default()
+        };
+        let databases = database_store::ServerDatabases::new(Arc::clone(&server), Some(rules));
+        assert!(databases.db("foo").await.is_none());
+
+        // writes create the database in the server, with the rules of the store
+        let db = databases.db_or_create("foo").await?;
+        let err = db
+            .write_lines(&parsed_lines("cpu bar=1 10"))
+            .await
+            .unwrap_err();
+        assert!(db.write_rejected(&err), "{}", err);
+
+        server
+            .write()
+            .await
+            .update_database_rules(
+                "foo",
+                DatabaseRules {
+                    store_locally: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        db.write_lines(&parsed_lines("cpu bar=1 10")).await?;
+        assert_eq!(databases.db_names_sorted().await, vec!["foo"]);
+
+        let results = db.query("select * from cpu").await?;
+        assert_eq!(to_csv(&results), "bar,time\n1,10\n");
+        let results = server
+            .read()
+            .await
+            .query_local("foo", "select * from cpu")
+            .await?;
+        assert_eq!(to_csv(&results), "bar,time\n1,10\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn routes_the_writes_to_the_database_store() -> Result {
+        let mut manager = TestConnectionManager::new();
+        let remote = Arc::new(TestRemoteServer::default());
+        manager
+            .remotes
+            .insert("serverA".to_string(), Arc::clone(&remote));
+        let mut server = Server::new(manager, ObjectStore::new_in_memory(InMemory::new()));
+        server.set_id(1);
+        server
+            .create_host_group("az1".to_string(), vec!["serverA".to_string()])
+            .await?;
+        let rules = DatabaseRules {
+            replication: vec!["az1".to_string()],
+            replication_count: 1,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+        let server = Arc::new(tokio::sync::RwLock::new(server));
+
+        // without rules to create them with, the databases written to must exist
+        let databases = database_store::ServerDatabases::new(Arc::clone(&server), None);
+        let err = databases.db_or_create("bar").await.unwrap_err();
+        assert!(matches!(err, Error::DatabaseNotFound { .. }), "{}", err);
+
+        let db = databases.db_or_create("foo").await?;
+        db.write_lines(&parsed_lines("cpu bar=1 10")).await?;
+        let writes = remote.writes.lock().unwrap().get("foo").unwrap().clone();
+        assert_eq!(writes.len(), 1);
+        let err = db.query("select * from cpu").await.unwrap_err();
+        assert!(matches!(err, Error::NoLocalBuffer { .. }), "{}", err);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replays_the_wals_of_the_databases() -> Result {
+        let dir = tempfile::tempdir()?;
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+
+        let mut server = Server::new(
+            TestConnectionManager::new(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        server.set_wal_dir(dir.path());
+        server.set_id(1);
+        server.create_database("foo", rules.clone()).await?;
+        server
+            .write_lines("foo", &parsed_lines("cpu bar=1 10"))
+            .await?;
+        server.sync_wals().await?;
+        drop(server);
+
+        // the configuration was never stored, so the database is created from its WAL
+        let mut restarted = Server::new(
+            TestConnectionManager::new(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        restarted.set_wal_dir(dir.path());
+        restarted.load_configuration(1).await?;
+        assert_eq!(restarted.replay_wals(&rules).await?, vec!["foo"]);
+        assert!(restarted.replay_wals(&rules).await?.is_empty());
+        let replays = restarted.jobs().list();
+        assert_eq!(replays.len(), 1);
+        assert_eq!(replays[0].progress().completed(), 1);
+
+        // the entries written after the replay continue its sequence, rather than being
+        // skipped as already applied
+        restarted
+            .write_lines("foo", &parsed_lines("cpu bar=2 20"))
+            .await?;
+        let results = restarted
+            .query_local("foo", "select * from cpu order by time")
+            .await?;
+        assert_eq!(to_csv(&results), "bar,time\n1,10\n2,20\n");
+
+        // releasing the database drops its WAL, so that it isn't created again
+        restarted.release_database("foo").await?;
+        assert!(!dir.path().join("foo").exists());
+        assert!(restarted.replay_wals(&rules).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_the_replay_of_wal_entries() -> Result {
+        let dir = tempfile::tempdir()?;
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        // the metrics are labelled with the name of the database
+        let db_name = "unreplayable";
+
+        let mut server = Server::new(
+            TestConnectionManager::new(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        server.set_wal_dir(dir.path());
+        server.set_id(1);
+        server.create_database(db_name, rules.clone()).await?;
+        server
+            .write_lines(db_name, &parsed_lines("cpu bar=1 10"))
+            .await?;
+        server.sync_wals().await?;
+        server.store_configuration().await?;
+        let store = match &server.store.0 {
+            ObjectStoreIntegration::InMemory(in_mem) => in_mem.clone().await,
+            _ => panic!("wrong type"),
+        };
+        drop(server);
+
+        // an entry cut short, which is still marked as one, followed by a valid entry
+        let mut wal = wal::WalBuilder::new(dir.path().join(db_name)).wal()?;
+        let mut poisoned = lines_to_entry(1, 2, &parsed_lines("cpu bar=2 20"), &rules)?.into_data();
+        poisoned.truncate(8);
+        wal.append(wal::WritePayload::new(poisoned)?)?;
+        let entry = lines_to_entry(1, 3, &parsed_lines("cpu bar=3 30"), &rules)?;
+        wal.append(wal::WritePayload::new(entry.into_data())?)?;
+        wal.sync_all()?;
+
+        let wal_dir = &dir.path().to_path_buf();
+        let restart = move |store| async move {
+            let mut restarted = Server::new(
+                TestConnectionManager::new(),
+                ObjectStore::new_in_memory(store),
+            );
+            restarted.set_wal_dir(wal_dir);
+            restarted.load_configuration(1).await?;
+            Ok::<_, TestError>(restarted)
+        };
+
+        // the replay stops at the entry cut short, and the database rejects writes
+        let mut restarted = restart(store).await?;
+        let replay = restarted.wal_replay(db_name)?.expect("WAL replayed");
+        assert!(replay.failed());
+        assert_eq!(replay.progress.replayed, 1);
+        assert_eq!(replay.progress.next_sequence_number, 1);
+        assert_eq!(replay.progress.lag(), 2);
+        let lag = metrics::registry()
+            .gauge(
+                "cluster_wal_replay_lag_entries",
+                "",
+                &[("db_name", db_name)],
+            )
+            .get();
+        assert_eq!(lag, 2);
+        let lines = parsed_lines("cpu bar=4 40");
+        let err = restarted.write_lines(db_name, &lines).await.unwrap_err();
+        assert!(
+            matches!(err, Error::WalReplayFailed { sequence_number: 1, .. }),
+            "{}",
+            err
+        );
+
+        // the database without a buffer is followed from the others
+        restarted.create_database("other", rules.clone()).await?;
+        let results = restarted
+            .query_local(
+                "other",
+                "select db_name, replayed, next_sequence_number, lag from system.wal_replay \
+                 where error is not null",
+            )
+            .await?;
+        assert_eq!(
+            to_csv(&results),
+            "db_name,replayed,next_sequence_number,lag\nunreplayable,1,1,2\n"
+        );
+
+        let err = restarted.skip_wal_replay(db_name, 1).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidWalSkip { .. }), "{}", err);
+        let replay = restarted.skip_wal_replay(db_name, 2).await?;
+        assert!(!replay.failed());
+        assert_eq!(replay.progress.replayed, 2);
+        assert_eq!(replay.progress.skipped, 1);
+        assert_eq!(replay.progress.lag(), 0);
+        let err = restarted.skip_wal_replay(db_name, 3).await.unwrap_err();
+        assert!(matches!(err, Error::WalReplayNotFailed { .. }), "{}", err);
+
+        restarted.write_lines(db_name, &lines).await?;
+        let results = restarted
+            .query_local(db_name, "select * from cpu order by time")
+            .await?;
+        assert_eq!(to_csv(&results), "bar,time\n1,10\n3,30\n4,40\n");
+
+        // the entries skipped stay skipped after a restart
+        restarted.sync_wals().await?;
+        restarted.store_configuration().await?;
+        let store = match &restarted.store.0 {
+            ObjectStoreIntegration::InMemory(in_mem) => in_mem.clone().await,
+            _ => panic!("wrong type"),
+        };
+        drop(restarted);
+        let restarted = restart(store).await?;
+        let replay = restarted.wal_replay(db_name)?.expect("WAL replayed");
+        assert!(!replay.failed());
+        assert_eq!(replay.progress.skipped, 1);
+        let results = restarted
+            .query_local(db_name, "select * from cpu order by time")
+            .await?;
+        assert_eq!(to_csv(&results), "bar,time\n1,10\n3,30\n4,40\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_entries() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules.clone()).await?;
+
+        let lines = parsed_lines("cpu bar=1 10");
+        let entry = lines_to_entry(2, 1, &lines, &rules)?;
+        server.write_entry("foo", entry.data().to_vec()).await?;
+
+        let results = server.query_local("foo", "select * from cpu").await?;
+        assert_eq!(to_csv(&results), "bar,time\n1,10\n");
+
+        let err = server
+            .write_entry("bar", entry.data().to_vec())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::DatabaseNotFound { .. }), "{}", err);
+
+        let strict = DatabaseRules {
+            strict_schema: Some(Default::default()),
+            ..rules
+        };
+        server.create_database("strict", strict).await?;
+        let err = server
+            .write_entry("strict", entry.into_data())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::EntryWithStrictSchema { .. }),
+            "{}",
+            err
+        );
+
+        let err = server.write_entry("foo", vec![]).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidEntry { .. }), "{}", err);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn store_and_load_configuration() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let host_group_id = "az1".to_string();
+        let remote_id = "serverA";
+        let rules = DatabaseRules {
+            replication: vec![host_group_id.clone()],
+            replication_count: 1,
+            ..Default::default()
+        };
+        server
+            .create_host_group(host_group_id.clone(), vec![remote_id.to_string()])
+            .await
+            .unwrap();
+        let db_name = "foo";
+        server.create_database(db_name, rules).await.unwrap();
+
+        server.store_configuration().await.unwrap();
+
+        let location = "1/config.json";
+        let read_data = server
+            .store
+            .get(location)
+            .await
+            .unwrap()
+            .map_ok(|b| bytes::BytesMut::from(&b[..]))
+            .try_concat()
+            .await
+            .unwrap();
+
+        let config = r#"{"id":1,"databases":{"foo":{"partition_template":{"parts":[]},"store_locally":false,"replication":["az1"],"replication_count":1,"replication_queue_max_size":0,"subscriptions":[],"query_local":false,"primary_query_group":null,"secondary_query_groups":[],"read_only_partitions":[]}},"host_groups":{"az1":{"id":"az1","hosts":["serverA"]}}}"#;
+        let read_data = std::str::from_utf8(&*read_data).unwrap();
+        assert_eq!(read_data, config);
+
+        let manager = TestConnectionManager::new();
+        let store = match server.store.0 {
+            ObjectStoreIntegration::InMemory(in_mem) => in_mem.clone().await,
+            _ => panic!("wrong type"),
+        };
+        let store = ObjectStore::new_in_memory(store);
+
+        let mut recovered_server = Server::new(manager, store);
+        assert_ne!(server.config, recovered_server.config);
+        recovered_server.load_configuration(1).await.unwrap();
+        assert_eq!(server.config, recovered_server.config);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn manage_databases() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        assert_eq!(server.id(), None);
+        server.set_id(1);
+        assert_eq!(server.id(), Some(1));
+
+        server
+            .create_database("foo", DatabaseRules::default())
+            .await?;
+        server
+            .create_database("bar", DatabaseRules::default())
+            .await?;
+        assert_eq!(server.db_names(), vec!["bar", "foo"]);
+
+        let err = server
+            .create_database("foo", DatabaseRules::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::DatabaseAlreadyExists { .. }));
+
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.update_database_rules("foo", rules.clone()).await?;
+        assert_eq!(server.db_rules("foo"), Some(&rules));
+
+        let lines = parsed_lines("cpu bar=1 10");
+        server.write_lines("foo", &lines).await?;
+        let results = server.query_local("foo", "select * from cpu").await?;
+        assert!(!results.is_empty());
+
+        let err = server
+            .update_database_rules("baz", DatabaseRules::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::DatabaseNotFound { .. }));
+
+        server.release_database("foo").await?;
+        assert_eq!(server.db_names(), vec!["bar"]);
+        assert_eq!(server.db_rules("foo"), None);
+
+        let err = server.release_database("foo").await.unwrap_err();
+        assert!(matches!(err, Error::DatabaseNotFound { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn version_and_roll_back_rules() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+
+        let first = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        let second = DatabaseRules {
+            query_local: true,
+            ..Default::default()
+        };
+        server.create_database("foo", first.clone()).await?;
+        assert_eq!(
+            server.update_database_rules("foo", second.clone()).await?,
+            2
+        );
+
+        let versions = server.database_rules_versions("foo").await?;
+        let generations: Vec<_> = versions.iter().map(|v| v.generation).collect();
+        assert_eq!(generations, vec![1, 2]);
+        assert_eq!(versions[0].rules, first);
+        assert_eq!(versions[1].rules, second);
+
+        assert_eq!(server.rollback_database_rules("foo", 1).await?, 3);
+        assert_eq!(server.db_rules("foo"), Some(&first));
+        assert!(server.chunk_summaries("foo").await?.is_empty());
+
+        let err = server.rollback_database_rules("foo", 4).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::RulesGenerationNotFound { generation: 4, .. }
+        ));
+
+        // rules stored after the configuration are applied when it is loaded
+        server.store_configuration().await?;
+        server.update_database_rules("foo", second.clone()).await?;
+
+        let store = match server.store.0 {
+            ObjectStoreIntegration::InMemory(in_mem) => in_mem.clone().await,
+            _ => panic!("wrong type"),
+        };
+        let mut recovered_server = Server::new(
+            TestConnectionManager::new(),
+            ObjectStore::new_in_memory(store),
+        );
+        recovered_server.load_configuration(1).await?;
+        assert_eq!(recovered_server.db_rules("foo"), Some(&second));
+
+        // a database created again continues from the earlier generations
+        server.release_database("foo").await?;
+        server.create_database("foo", first).await?;
+        assert_eq!(server.database_rules_versions("foo").await?.len(), 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_rules() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules.clone()).await?;
+        server.create_database("bar", rules.clone()).await?;
+        assert!(server.reload_database_rules().await?.is_empty());
+
+        // rules stored by someone else are applied to the running database
+        let reloaded_rules = DatabaseRules {
+            lifecycle_rules: LifecycleRules {
+                partition_size_hard: Some(1),
+                ..Default::default()
+            },
+            ..rules
+        };
+        rules_history::store(&server.store, 1, "foo", 2, &reloaded_rules).await?;
+        assert_eq!(
+            server.reload_database_rules().await?,
+            vec![("foo".to_string(), 2)]
+        );
+        assert_eq!(server.db_rules("foo"), Some(&reloaded_rules));
+        assert!(server.reload_database_rules().await?.is_empty());
+
+        // the new partition limit throttles writes
+        let lines = parsed_lines("cpu bar=1 10");
+        server.write_lines("foo", &lines).await?;
+        let err = server.write_lines("foo", &lines).await.unwrap_err();
+        assert!(matches!(err, Error::WriteThrottled { .. }), "{}", err);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn close_chunks() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        server
+            .create_database("foo", DatabaseRules::default())
+            .await?;
+
+        let err = server.chunk_summaries("foo").await.unwrap_err();
+        assert!(matches!(err, Error::NoLocalBuffer { .. }));
+
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("bar", rules).await?;
+
+        let lines = parsed_lines("cpu bar=1 10");
+        server.write_lines("bar", &lines).await?;
+
+        let chunks = server.chunk_summaries("bar").await?;
+        assert_eq!(chunks.len(), 1);
+        let partition_key = chunks[0].partition_key.clone();
+
+        let chunk = server.close_chunk("bar", &partition_key).await?;
+        assert_eq!(chunk.storage, ChunkStorage::ClosedMutableBuffer);
+
+        let err = server.close_chunk("bar", &partition_key).await.unwrap_err();
+        assert!(matches!(err, Error::OpenChunkNotFound { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_writes_violating_strict_schema() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+
+        let cpu = MeasurementSchema {
+            tags: vec!["host".to_string()].into_iter().collect(),
+            fields: vec![("bar".to_string(), FieldType::Float)]
+                .into_iter()
+                .collect(),
+        };
+        let rules = DatabaseRules {
+            store_locally: true,
+            strict_schema: Some(StrictSchema {
+                measurements: vec![("cpu".to_string(), cpu)].into_iter().collect(),
+            }),
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let lines = parsed_lines("cpu,host=a bar=1 10\ncpu,region=west bar=2i 20");
+        let err = server.write_lines("foo", &lines).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "write does not conform to the schema of database foo: \
+             line 2: tag region is not declared for measurement cpu, \
+             line 2: field bar of measurement cpu is declared as float but was written as integer"
+        );
+        // nothing of a rejected write is stored
+        assert!(server.chunk_summaries("foo").await?.is_empty());
+
+        let lines = parsed_lines("cpu,host=a bar=1 10\ncpu bar=2 20");
+        server.write_lines("foo", &lines).await?;
+        assert_eq!(server.chunk_summaries("foo").await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_writes_out_of_bounds() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+
+        let rules = DatabaseRules {
+            store_locally: true,
+            write_bounds: Some(WriteBounds {
+                max_future: Some(Duration::from_secs(3600)),
+                max_past: Some(Duration::from_secs(3600)),
+            }),
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let now = Utc::now().timestamp_nanos();
+        let lp = format!("cpu bar=1 {}\ncpu bar=2 10", now);
+        let lines = parsed_lines(&lp);
+        let err = server.write_lines("foo", &lines).await.unwrap_err();
+        assert!(matches!(err, Error::TimestampViolations { .. }), "{}", err);
+        assert!(
+            err.to_string().ends_with(
+                "line 2: timestamp 1970-01-01T00:00:00.000000010+00:00 \
+                 of measurement cpu is more than 3600s in the past"
+            ),
+            "{}",
+            err
+        );
+        // nothing of a rejected write is stored
+        assert!(server.chunk_summaries("foo").await?.is_empty());
+
+        let lp = format!("cpu bar=1 {}\ncpu bar=2", now);
+        let lines = parsed_lines(&lp);
+        server.write_lines("foo", &lines).await?;
+        assert_eq!(server.chunk_summaries("foo").await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_system_tables() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let lines = parsed_lines("cpu bar=1 10\ncpu bar=2 20");
+        server.write_lines("foo", &lines).await?;
+
+        let job = server
+            .jobs()
+            .spawn("dummy", |_| async { Ok::<_, String>(()) });
+        job.join().await;
+
+        let results = server
+            .query_local("foo", "select id, storage, row_count from system.chunks")
+            .await?;
+        assert_eq!(
+            to_csv(&results),
+            "id,storage,row_count\n0,OpenMutableBuffer,2\n"
+        );
+
+        let results = server
+            .query_local(
+                "foo",
+                "select table_name, column_name, column_type, encoding, count, min_value, \
+                 max_value from system.columns",
+            )
+            .await?;
+        assert_eq!(
+            to_csv(&results),
+            "table_name,column_name,column_type,encoding,count,min_value,max_value\n\
+             cpu,bar,f64,plain,2,1,2\n\
+             cpu,time,i64,plain,2,10,20\n"
+        );
+
+        let results = server
+            .query_local("foo", "select description, status from system.operations")
+            .await?;
+        assert_eq!(to_csv(&results), "description,status\ndummy,Success\n");
+
+        let results = server
+            .query_local(
+                "foo",
+                "select table_name, rows_written, bytes_written, write_errors from system.tables",
+            )
+            .await?;
+        assert_eq!(
+            to_csv(&results),
+            "table_name,rows_written,bytes_written,write_errors\ncpu,2,32,0\n"
+        );
+        let results = server
+            .query_local(
+                "foo",
+                "select table_name, rows_written from system.partitions where last_write is not null",
+            )
+            .await?;
+        assert_eq!(to_csv(&results), "table_name,rows_written\ncpu,2\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_with_restricted_access() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let lines = parsed_lines(
+            "cpu,tenant=acme bar=1 10\ncpu,tenant=beta bar=2 20\nmem,tenant=acme used=3 10",
+        );
+        server.write_lines("foo", &lines).await?;
+
+        let mut tags = BTreeMap::new();
+        tags.insert("tenant".to_string(), "acme".to_string());
+        let access = RowAccess::new(&["cpu".to_string()], &tags);
+
+        let results = server
+            .query_local_with_access("foo", "select tenant, bar from cpu", &access)
+            .await?;
+        assert_eq!(to_csv(&results), "tenant,bar\nacme,1\n");
+
+        // aggregates only see the allowed rows too
+        let results = server
+            .query_local_with_access("foo", "select count(*) as n from cpu", &access)
+            .await?;
+        assert_eq!(to_csv(&results), "n\n1\n");
+
+        for query in &["select * from mem", "select * from system.chunks"] {
+            let err = server
+                .query_local_with_access("foo", query, &access)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::TableNotAllowed { .. }), "{}", err);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_across_databases() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        for db_name in &["foo", "bar"] {
+            let rules = DatabaseRules {
+                store_locally: true,
+                ..Default::default()
+            };
+            server.create_database(*db_name, rules).await?;
+        }
+        server
+            .write_lines("foo", &parsed_lines("cpu,host=a usage=0.5 10"))
+            .await?;
+        server
+            .write_lines(
+                "bar",
+                &parsed_lines("mem,tenant=acme,host=a used=1 10\nmem,tenant=beta,host=b used=2 10"),
+            )
+            .await?;
+
+        let query = "select host, used from bar.mem order by host";
+        let results = server.query_local("foo", query).await?;
+        assert_eq!(to_csv(&results), "host,used\na,1\nb,2\n");
+
+        let query = "select count(*) as n from cpu join bar.mem m on cpu.host = m.host";
+        let results = server.query_local("foo", query).await?;
+        assert_eq!(to_csv(&results), "n\n1\n");
+
+        // the rows of each database are restricted by the access to it
+        let mut tags = BTreeMap::new();
+        tags.insert("tenant".to_string(), "acme".to_string());
+        let query = "select host, used from bar.mem order by host";
+        let results = server
+            .query_local_across("foo", query, |name| match name {
+                "bar" => Some(RowAccess::new(&[], &tags)),
+                _ => Some(RowAccess::default()),
+            })
+            .await?;
+        assert_eq!(to_csv(&results), "host,used\na,1\n");
+
+        let err = server
+            .query_local_with_access("foo", query, &RowAccess::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::DatabaseNotAllowed { .. }), "{}", err);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_database() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let lines = parsed_lines("cpu bar=1 10\ncpu bar=2 20\nmem used=3 10");
+        server.write_lines("foo", &lines).await?;
+
+        let export_store = Arc::new(ObjectStore::new_in_memory(InMemory::new()));
+        let job = server
+            .export_database(
+                "foo",
+                Some("cpu"),
+                None,
+                Some(TimestampRange::new(15, 25)),
+                Arc::clone(&export_store),
+                "lake/",
+            )
+            .await?;
+        job.join().await;
+        assert_eq!(
+            job.status(),
+            tracker::TrackerStatus::Success,
+            "{:?}",
+            job.error()
+        );
+        assert_eq!(job.progress().completed(), 1);
+
+        let files: Vec<_> = export_store.list(None).await?.try_concat().await?;
+        // the default rules put every row into the partition with an empty key
+        assert_eq!(files, vec!["lake/foo//0/cpu.parquet"]);
+
+        let data = export_store
+            .get("lake/foo//0/cpu.parquet")
+            .await?
+            .map_ok(|b| bytes::BytesMut::from(&b[..]))
+            .try_concat()
+            .await?;
+        assert_eq!(&data[..4], b"PAR1");
+
+        let err = server
+            .export_database("bar", None, None, None, export_store, "")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::DatabaseNotFound { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_table() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        server
+            .create_database("foo", DatabaseRules::default())
+            .await?;
+
+        let mapping = SchemaMapping {
+            table: "cpu".to_string(),
+            tags: vec!["host".to_string()],
+            fields: vec![("usage".to_string(), DataType::Float)],
+            time_column: "time".to_string(),
+            time_unit: TimeUnit::Seconds,
+        };
+        let data = b"host,usage,time\na,0.5,10\nb,0.7,20\n".to_vec();
+        let table = ingest::import::convert(FileFormat::Csv, data, &mapping)?;
+
+        let chunk = server.import_table("foo", "2020-11-01", table).await?;
+        assert_eq!(chunk.id, 0);
+        assert_eq!(chunk.location, "1/foo/data/2020-11-01/0/cpu.parquet");
+        assert_eq!(chunk.row_count, 2);
+        assert_eq!(chunk.min_time, Some(10_000_000_000));
+        assert_eq!(chunk.max_time, Some(20_000_000_000));
+        assert_eq!(chunk.sort_key, vec!["host", "time"]);
+
+        let data = server
+            .store
+            .get(&chunk.location)
+            .await?
+            .map_ok(|b| bytes::BytesMut::from(&b[..]))
+            .try_concat()
+            .await?;
+        assert_eq!(data.len(), chunk.size_bytes);
+
+        // the catalog is stored with the configuration
+        let config = server.store.get("1/config.json").await?;
+        let config = config
+            .map_ok(|b| bytes::BytesMut::from(&b[..]))
+            .try_concat()
+            .await?;
+        let manager = TestConnectionManager::new();
+        let mut restored = Server::new(manager, ObjectStore::new_in_memory(InMemory::new()));
+        restored.config = serde_json::from_slice(&config)?;
+        assert_eq!(restored.persisted_chunks("foo")?, vec![chunk]);
+
+        let err = server.persisted_chunks("bar").unwrap_err();
+        assert!(matches!(err, Error::DatabaseNotFound { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_partitioned_table() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            partition_template: PartitionTemplate {
+                parts: vec![
+                    TemplatePart::Column("host".to_string()),
+                    TemplatePart::TimeFormat("%Y-%m-%d".to_string()),
+                ],
+            },
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let mapping = SchemaMapping {
+            table: "cpu".to_string(),
+            tags: vec!["host".to_string()],
+            fields: vec![("usage".to_string(), DataType::Float)],
+            time_column: "time".to_string(),
+            time_unit: TimeUnit::Seconds,
+        };
+        let data = b"host,usage,time\na,0.5,10\nb,0.7,20\na,0.9,86410\na,0.1,30\n".to_vec();
+        let table = ingest::import::convert(FileFormat::Csv, data, &mapping)?;
+
+        let chunks = server.import_partitioned_table("foo", table).await?;
+        let summary: Vec<_> = chunks
+            .iter()
+            .map(|chunk| (chunk.partition_key.as_str(), chunk.row_count))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("host_a-1970-01-01", 2),
+                ("host_a-1970-01-02", 1),
+                ("host_b-1970-01-01", 1),
+            ]
+        );
+        assert_eq!(server.persisted_chunks("foo")?, chunks);
+
+        let table =
+            ingest::import::convert(FileFormat::Csv, b"host,usage,time\n".to_vec(), &mapping)?;
+        assert!(server
+            .import_partitioned_table("foo", table)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prune_persisted_chunks() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let mapping = SchemaMapping {
+            table: "cpu".to_string(),
+            tags: vec!["host".to_string()],
+            fields: vec![("usage".to_string(), DataType::Float)],
+            time_column: "time".to_string(),
+            time_unit: TimeUnit::Seconds,
+        };
+        let import =
+            |data: &[u8]| ingest::import::convert(FileFormat::Csv, data.to_vec(), &mapping);
+        let first = server
+            .import_table(
+                "foo",
+                "p",
+                import(
+                    b"host,usage,time
+a,0.5,10
+b,,20
+",
+                )?,
+            )
+            .await?;
+        let second = server
+            .import_table(
+                "foo",
+                "p",
+                import(
+                    b"host,usage,time
+c,2.5,30
+",
+                )?,
+            )
+            .await?;
+
+        let usage = first
+            .columns
+            .iter()
+            .find(|column| column.column_name == "usage")
+            .unwrap();
+        assert_eq!(usage.column_type, "f64");
+        assert_eq!((usage.count, usage.null_count), (1, 1));
+        assert_eq!(
+            (usage.min_value.as_str(), usage.max_value.as_str()),
+            ("0.5", "0.5")
+        );
+
+        let results = server
+            .query_local(
+                "foo",
+                "select chunk_id, encoding, count, null_count, distinct_count, min_value, \
+                 max_value from system.columns where column_name = 'host' order by chunk_id",
+            )
+            .await?;
+        assert_eq!(
+            to_csv(&results),
+            "chunk_id,encoding,count,null_count,distinct_count,min_value,max_value\n\
+             0,parquet,2,0,2,a,b\n\
+             1,parquet,1,0,1,c,c\n"
+        );
+
+        // the file of a chunk ruled out by its statistics is not fetched
+        server.store.delete(&second.location).await?;
+        let results = server
+            .query_local("foo", "select host from cpu where host < 'c'")
+            .await?;
+        assert_eq!(to_csv(&results), "host\na\nb\n");
+        server
+            .query_local("foo", "select host from cpu where host = 'c'")
+            .await
+            .unwrap_err();
+
+        // a query ruling out every chunk selects no rows
+        let results = server
+            .query_local("foo", "select host from cpu where host = 'z'")
+            .await?;
+        assert_eq!(results.iter().map(RecordBatch::num_rows).sum::<usize>(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_persisted_chunks() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let mapping = SchemaMapping {
+            table: "cpu".to_string(),
+            tags: vec!["host".to_string()],
+            fields: vec![("usage".to_string(), DataType::Float)],
+            time_column: "time".to_string(),
+            time_unit: TimeUnit::Seconds,
+        };
+        let data = b"host,usage,time\na,0.5,10\n".to_vec();
+        let table = ingest::import::convert(FileFormat::Csv, data, &mapping)?;
+        let chunk = server.import_table("foo", "p", table).await?;
+        assert_eq!(
+            chunk.checksum.as_deref(),
+            Some(
+                integrity::checksum(&integrity::fetch(&server.store, &chunk.location).await?)
+                    .as_str()
+            )
+        );
+
+        let checked = server.verify_persisted_chunks(None).await?;
+        assert_eq!(
+            checked,
+            vec![("foo".to_string(), chunk.clone(), integrity::FileStatus::Ok)]
+        );
+
+        // a column chunk corrupted in object storage fails the queries fetching it, while the
+        // footer is intact
+        let mut data = integrity::fetch(&server.store, &chunk.location).await?;
+        // the first column chunk follows the magic number that starts the file
+        data[4] ^= 0xff;
+        let corrupt = Bytes::from(data);
+        server
+            .store
+            .put(
+                &chunk.location,
+                futures::stream::once(async move { std::io::Result::Ok(corrupt) }),
+                chunk.size_bytes,
+            )
+            .await?;
+        let err = server
+            .query_local("foo", "select * from cpu")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("corrupt: checksum"), "{}", err);
+
+        // as does a whole file corrupted
+        let corrupt = Bytes::from(vec![0; chunk.size_bytes]);
+        server
+            .store
+            .put(
+                &chunk.location,
+                futures::stream::once(async move { std::io::Result::Ok(corrupt) }),
+                chunk.size_bytes,
+            )
+            .await?;
+        let checked = server.verify_persisted_chunks(Some("foo")).await?;
+        assert!(matches!(
+            checked[0].2,
+            integrity::FileStatus::ChecksumMismatch { .. }
+        ));
+        let err = server
+            .query_local("foo", "select * from cpu")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("is corrupt"), "{}", err);
+
+        server.store.delete(&chunk.location).await?;
+        let checked = server.verify_persisted_chunks(Some("foo")).await?;
+        assert!(matches!(
+            checked[0].2,
+            integrity::FileStatus::Missing { .. }
+        ));
+
+        let err = server
+            .verify_persisted_chunks(Some("bar"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::DatabaseNotFound { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rebuild_catalog() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let mapping = SchemaMapping {
+            table: "cpu".to_string(),
+            tags: vec!["host".to_string()],
+            fields: vec![("usage".to_string(), DataType::Float)],
+            time_column: "time".to_string(),
+            time_unit: TimeUnit::Seconds,
+        };
+        let mut chunks = vec![];
+        for (partition_key, data) in &[
+            ("p1", "host,usage,time\nb,0.5,20\na,0.7,10\n"),
+            ("p2", "host,usage,time\nc,0.1,30\n"),
+        ] {
+            let table =
+                ingest::import::convert(FileFormat::Csv, data.as_bytes().to_vec(), &mapping)?;
+            chunks.push(server.import_table("foo", partition_key, table).await?);
+        }
+
+        // a file that isn't a persisted chunk is skipped
+        let junk = Bytes::from(&b"not parquet"[..]);
+        server
+            .store
+            .put(
+                "1/foo/data/p1/9/cpu.parquet",
+                futures::stream::once(async move { std::io::Result::Ok(junk) }),
+                11,
+            )
+            .await?;
+
+        // lose the catalog
+        server.config.databases["foo"]
+            .catalog
+            .lock()
+            .expect("mutex poisoned")
+            .rebuild(vec![]);
+        assert!(server.persisted_chunks("foo")?.is_empty());
+
+        let rebuilt = server.rebuild_catalog("foo").await?;
+        assert_eq!(rebuilt.skipped.len(), 1);
+        assert_eq!(rebuilt.skipped[0].0, "1/foo/data/p1/9/cpu.parquet");
+        assert_eq!(server.persisted_chunks("foo")?, chunks);
+        assert_eq!(chunks[0].sort_key, vec!["host", "time"]);
+        assert_eq!(chunks[0].min_time, Some(10_000_000_000));
+
+        let batches = server
+            .query_local("foo", "select host from cpu order by host")
+            .await?;
+        assert_eq!(to_csv(&batches), "host\na\nb\nc\n");
+
+        let err = server.rebuild_catalog("bar").await.unwrap_err();
+        assert!(matches!(err, Error::DatabaseNotFound { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_and_restore_database() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules.clone()).await?;
+
+        let mapping = SchemaMapping {
+            table: "cpu".to_string(),
+            tags: vec!["host".to_string()],
+            fields: vec![("usage".to_string(), DataType::Float)],
+            time_column: "time".to_string(),
+            time_unit: TimeUnit::Seconds,
+        };
+        let data = b"host,usage,time\na,0.5,10\nb,0.7,20\n".to_vec();
+        let table = ingest::import::convert(FileFormat::Csv, data, &mapping)?;
+        let chunk = server.import_table("foo", "p", table).await?;
+
+        let backup_store = Arc::new(ObjectStore::new_in_memory(InMemory::new()));
+        let job = server
+            .snapshot_database("foo", Arc::clone(&backup_store), "backups/")
+            .await?;
+        job.join().await;
+        assert_eq!(
+            job.status(),
+            tracker::TrackerStatus::Success,
+            "{:?}",
+            job.error()
+        );
+        let mut files: Vec<_> = backup_store.list(None).await?.try_concat().await?;
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "backups/foo/data/p/0/cpu.parquet",
+                "backups/foo/snapshot.json"
+            ]
+        );
+
+        // restore the snapshot on another server, under another name
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut other = Server::new(manager, store);
+        other.set_id(2);
+        let snapshot = other
+            .copy_snapshot("bar", &backup_store, "backups/foo")
+            .await?;
+        assert_eq!(snapshot.db_name, "foo");
+        assert_eq!(snapshot.writer_id, 1);
+        other.restore_database("bar", snapshot).await?;
+
+        let restored = other.persisted_chunks("bar")?;
+        assert_eq!(
+            restored,
+            vec![PersistedChunk {
+                location: "2/bar/data/p/0/cpu.parquet".to_string(),
+                ..chunk.clone()
+            }]
+        );
+        assert_eq!(other.db_rules("bar"), Some(&rules));
+        let batches = other
+            .query_local("bar", "select host from cpu order by host")
+            .await?;
+        assert_eq!(to_csv(&batches), "host\na\nb\n");
+
+        let err = other
+            .copy_snapshot("bar", &backup_store, "backups/foo")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::DatabaseAlreadyExists { .. }));
+
+        // a file corrupted in the backup is not restored
+        let corrupt = Bytes::from(vec![0; chunk.size_bytes]);
+        backup_store
+            .put(
+                "backups/foo/data/p/0/cpu.parquet",
+                futures::stream::once(async move { std::io::Result::Ok(corrupt) }),
+                chunk.size_bytes,
+            )
+            .await?;
+        let err = other
+            .copy_snapshot("baz", &backup_store, "backups/foo")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::CorruptFile { .. }), "{}", err);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_row_groups() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        server.set_row_group_fetches(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            parquet: ParquetSettings {
+                row_group_size: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+        server
+            .write_lines(
+                "foo",
+                &parsed_lines(
+                    "cpu,host=a usage=1,idle=9 10\n\
+                     cpu,host=b usage=2,idle=8 20\n\
+                     cpu,host=c usage=3,idle=7 30\n\
+                     cpu,host=d usage=4,idle=6 40\n\
+                     cpu,host=e usage=5,idle=5 50",
+                ),
+            )
+            .await?;
+        server.persist_buffers().await?;
+
+        // each row group is fetched and decoded on its own, one at a time
+        let chunk = server.persisted_chunks("foo")?.remove(0);
+        let batches = ParquetChunk::new(Arc::clone(&server.store), chunk, vec![])
+            .with_fetches(Arc::new(Semaphore::new(1)))
+            .table_to_arrow("cpu", &["usage"])
+            .await?;
+        let rows: Vec<_> = batches.iter().map(RecordBatch::num_rows).collect();
+        assert_eq!(rows, vec![2, 2, 1]);
+        assert_eq!(batches[0].num_columns(), 1);
+
+        let results = server
+            .query_local(
+                "foo",
+                "select host, usage from cpu where usage > 1 order by time",
+            )
+            .await?;
+        let expected = vec![
+            "+------+-------+",
+            "| host | usage |",
+            "+------+-------+",
+            "| b    | 2     |",
+            "| c    | 3     |",
+            "| d    | 4     |",
+            "| e    | 5     |",
+            "+------+-------+",
+        ];
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected.join("\n")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_small_chunks() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            lifecycle_rules: LifecycleRules {
+                compaction_target_file_size: Some(1024 * 1024),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+        server
+            .create_database(
+                "bar",
+                DatabaseRules {
+                    store_locally: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        // each persist writes a small file
+        let writes = [
+            "cpu,host=b usage=1.5 10",
+            "cpu,host=a usage=2.5 20",
+            "mem free=3 30",
+        ];
+        for lines in &writes {
+            for db_name in &["foo", "bar"] {
+                server.write_lines(db_name, &parsed_lines(lines)).await?;
+            }
+            server.persist_buffers().await?;
+        }
+        assert_eq!(server.persisted_chunks("foo")?.len(), 3);
 
-impl PartialEq for Db {
-    fn eq(&self, other: &Self) -> bool {
-        self.rules == other.rules
+        // the files of each table are merged into one sorted file, in the databases that
+        // compact their chunks
+        let compacted = server.compact_small_chunks().await?;
+        assert_eq!(compacted.len(), 1);
+        let (db_name, inputs, replacement) = &compacted[0];
+        assert_eq!(db_name, "foo");
+        assert_eq!(inputs.len(), 2);
+        let replacement = replacement.as_ref().unwrap();
+        assert_eq!(replacement.row_count, 2);
+        assert_eq!(replacement.table_name, "cpu");
+        assert!(!replacement.sort_key.is_empty());
+        assert_eq!(server.persisted_chunks("foo")?.len(), 2);
+        assert_eq!(server.persisted_chunks("bar")?.len(), 3);
+        assert!(server.store.get(&inputs[0].location).await.is_err());
+        let results = server
+            .query_local("foo", "select host, usage from cpu order by host")
+            .await?;
+        assert_eq!(to_csv(&results), "host,usage\na,2.5\nb,1.5\n");
+        assert!(server.compact_small_chunks().await?.is_empty());
+
+        Ok(())
     }
-}
-impl Eq for Db {}
 
-const STARTING_SEQUENCE: u64 = 1;
+    #[tokio::test]
+    async fn reloads_persisted_chunks_into_read_buffer() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = |buffer_size_soft| DatabaseRules {
+            store_locally: true,
+            lifecycle_rules: LifecycleRules {
+                buffer_size_soft: Some(buffer_size_soft),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // the metrics are labelled with the name of the database
+        let db_name = "tiered";
+        server.create_database(db_name, rules(1024 * 1024)).await?;
+        server
+            .write_lines(
+                db_name,
+                &parsed_lines("cpu,host=a usage=1.5 10\ncpu,host=b usage=2.5 20"),
+            )
+            .await?;
+        server.persist_buffers().await?;
+        assert_eq!(server.memory_usage(db_name).await?.read_buffer, 0);
 
-impl Db {
-    fn next_sequence(&self) -> u64 {
-        self.sequence.fetch_add(1, Ordering::SeqCst)
-    }
-}
+        let labels = [("db_name", db_name)];
+        let counter = |name| metrics::registry().counter(name, "", &labels).get();
+        let query = "select host, usage from cpu order by host";
 
-// location in the store for the configuration file
-fn config_location(id: u32) -> String {
-    format!("{}/config.json", id)
-}
+        // the chunk is reloaded by the first query only
+        for _ in 0..2 {
+            let results = server.query_local(db_name, query).await?;
+            assert_eq!(to_csv(&results), "host,usage\na,1.5\nb,2.5\n");
+        }
+        assert!(server.memory_usage(db_name).await?.read_buffer > 0);
+        assert_eq!(counter("cluster_read_buffer_reloaded_chunks_total"), 1);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use arrow_deps::arrow::{csv, util::string_writer::StringWriter};
-    use async_trait::async_trait;
-    use data_types::database_rules::{MatchTables, Matcher, Subscription};
-    use futures::TryStreamExt;
-    use influxdb_line_protocol::parse_lines;
-    use object_store::{InMemory, ObjectStoreIntegration};
-    use snafu::Snafu;
-    use std::sync::Mutex;
+        // the deletes recorded after the reload still apply
+        let predicate = DeletePredicate {
+            table_name: "cpu".to_string(),
+            start: 0,
+            end: 15,
+            tags: vec![("host".to_string(), "a".to_string())]
+                .into_iter()
+                .collect(),
+        };
+        server.delete(db_name, predicate).await?;
+        let results = server.query_local(db_name, query).await?;
+        assert_eq!(to_csv(&results), "host,usage\nb,2.5\n");
+
+        // once the database is over its buffer size, the chunk is evicted after each query
+        server.update_database_rules(db_name, rules(1)).await?;
+        let results = server.query_local(db_name, query).await?;
+        assert_eq!(to_csv(&results), "host,usage\nb,2.5\n");
+        assert_eq!(server.memory_usage(db_name).await?.read_buffer, 0);
+        assert_eq!(counter("cluster_read_buffer_reloaded_chunks_total"), 2);
+        assert_eq!(counter("cluster_read_buffer_evicted_chunks_total"), 1);
+        assert!(counter("cluster_read_buffer_evicted_bytes_total") > 0);
 
-    type TestError = Box<dyn std::error::Error + Send + Sync + 'static>;
-    type Result<T = (), E = TestError> = std::result::Result<T, E>;
+        Ok(())
+    }
 
     #[tokio::test]
-    async fn server_api_calls_return_error_with_no_id_set() -> Result {
+    async fn scans_only_queried_columns() -> Result {
         let manager = TestConnectionManager::new();
         let store = ObjectStore::new_in_memory(InMemory::new());
         let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+        server
+            .write_lines(
+                "foo",
+                &parsed_lines(
+                    "cpu,host=a,region=west usage=1,idle=2 10\n\
+                     cpu,host=b,region=east usage=3,idle=4 20",
+                ),
+            )
+            .await?;
+        server.persist_buffers().await?;
 
-        let rules = DatabaseRules::default();
-        let resp = server.create_database("foo", rules).await.unwrap_err();
-        assert!(matches!(resp, Error::IdNotSet));
-
-        let lines = parsed_lines("cpu foo=1 10");
-        let resp = server.write_lines("foo", &lines).await.unwrap_err();
-        assert!(matches!(resp, Error::IdNotSet));
+        // the columns the deletes match rows on are read, but not returned
+        let chunk = server.persisted_chunks("foo")?.remove(0);
+        let deletes = vec![DeletePredicate {
+            table_name: "cpu".to_string(),
+            start: 0,
+            end: 15,
+            tags: vec![("host".to_string(), "a".to_string())]
+                .into_iter()
+                .collect(),
+        }];
+        let batches = ParquetChunk::new(Arc::clone(&server.store), chunk, deletes)
+            .table_to_arrow("cpu", &["usage"])
+            .await?;
+        assert_eq!(batches.len(), 1);
+        let schema = batches[0].schema();
+        let names: Vec<_> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, vec!["usage"]);
+        assert_eq!(batches[0].num_rows(), 1);
 
-        let resp = server
-            .create_host_group("group1".to_string(), vec!["serverA".to_string()])
-            .await
-            .unwrap_err();
-        assert!(matches!(resp, Error::IdNotSet));
+        server
+            .write_lines("foo", &parsed_lines("cpu,host=c,region=west usage=5 30"))
+            .await?;
+        let access = RowAccess::new(
+            &[],
+            &vec![("region".to_string(), "west".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        let results = server
+            .query_local_with_access(
+                "foo",
+                "select usage from cpu where host != 'b' order by time",
+                &access,
+            )
+            .await?;
+        let expected = vec![
+            "+-------+",
+            "| usage |",
+            "+-------+",
+            "| 1     |",
+            "| 5     |",
+            "+-------+",
+        ];
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected.join("\n")
+        );
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn writes_local() -> Result {
+    async fn query_across_tiers() -> Result {
         let manager = TestConnectionManager::new();
         let store = ObjectStore::new_in_memory(InMemory::new());
         let mut server = Server::new(manager, store);
@@ -449,216 +4551,517 @@ mod tests {
         };
         server.create_database("foo", rules).await?;
 
-        let line = "cpu bar=1 10";
-        let lines: Vec<_> = parse_lines(line).map(|l| l.unwrap()).collect();
-        server.write_lines("foo", &lines).await.unwrap();
+        // a chunk moved to the read buffer
+        server
+            .write_lines("foo", &parsed_lines("cpu,host=a usage=0.1 10"))
+            .await?;
+        let partition_key = server.chunk_summaries("foo").await?[0]
+            .partition_key
+            .clone();
+        let closed = server.close_chunk("foo", &partition_key).await?;
+        let moved = server.move_chunk("foo", &partition_key, closed.id).await?;
+        assert_eq!(moved.storage, ChunkStorage::ReadBuffer);
+        assert_eq!(moved.row_count, 1);
 
-        let results = server
-            .query_local("foo", "select * from cpu")
+        let err = server
+            .move_chunk("foo", &partition_key, closed.id)
             .await
-            .unwrap();
+            .unwrap_err();
+        assert!(matches!(err, Error::ClosedChunkNotFound { .. }));
 
-        let mut sw = StringWriter::new();
-        {
-            let mut writer = csv::Writer::new(&mut sw);
-            for r in results {
-                writer.write(&r).unwrap();
-            }
-        }
-        assert_eq!(&sw.to_string(), "bar,time\n1,10\n");
+        // an open chunk with a column the other chunks don't have
+        server
+            .write_lines("foo", &parsed_lines("cpu,host=b,region=west usage=0.2 20"))
+            .await?;
+
+        // a chunk persisted to object storage
+        let mapping = SchemaMapping {
+            table: "cpu".to_string(),
+            tags: vec!["host".to_string()],
+            fields: vec![("usage".to_string(), DataType::Float)],
+            time_column: "time".to_string(),
+            time_unit: TimeUnit::Seconds,
+        };
+        let data = b"host,usage,time\nc,0.3,30\n".to_vec();
+        let table = ingest::import::convert(FileFormat::Csv, data, &mapping)?;
+        server.import_table("foo", &partition_key, table).await?;
+
+        let storage: Vec<_> = server
+            .chunk_summaries("foo")
+            .await?
+            .into_iter()
+            .map(|c| c.storage)
+            .collect();
+        assert_eq!(
+            storage,
+            vec![ChunkStorage::OpenMutableBuffer, ChunkStorage::ReadBuffer]
+        );
+
+        // a table only persisted to object storage, whose rows are sorted when persisted
+        let mapping = SchemaMapping {
+            table: "disk".to_string(),
+            ..mapping
+        };
+        let data = b"host,usage,time\nb,0.5,10\na,0.6,20\n".to_vec();
+        let table = ingest::import::convert(FileFormat::Csv, data, &mapping)?;
+        server.import_table("foo", &partition_key, table).await?;
+
+        let results = server
+            .query_local("foo", "select host, usage from disk order by host")
+            .await?;
+        let expected = vec![
+            "+------+-------+",
+            "| host | usage |",
+            "+------+-------+",
+            "| a    | 0.6   |",
+            "| b    | 0.5   |",
+            "+------+-------+",
+        ];
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected.join("\n")
+        );
+
+        let results = server
+            .query_local("foo", "select host, region, usage from cpu order by usage")
+            .await?;
+        let expected = vec![
+            "+------+--------+-------+",
+            "| host | region | usage |",
+            "+------+--------+-------+",
+            "| a    |        | 0.1   |",
+            "| b    | west   | 0.2   |",
+            "| c    |        | 0.3   |",
+            "+------+--------+-------+",
+        ];
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected.join("\n")
+        );
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn replicate_to_single_group() -> Result {
-        let mut manager = TestConnectionManager::new();
-        let remote = Arc::new(TestRemoteServer::default());
-        let remote_id = "serverA";
-        manager
-            .remotes
-            .insert(remote_id.to_string(), remote.clone());
-
+    async fn partition_write_limit() -> Result {
+        let manager = TestConnectionManager::new();
         let store = ObjectStore::new_in_memory(InMemory::new());
-
         let mut server = Server::new(manager, store);
         server.set_id(1);
-        let host_group_id = "az1".to_string();
-        let rules = DatabaseRules {
-            replication: vec![host_group_id.clone()],
-            replication_count: 1,
+        let mut rules = DatabaseRules {
+            store_locally: true,
+            lifecycle_rules: LifecycleRules {
+                partition_size_hard: Some(1),
+                ..Default::default()
+            },
             ..Default::default()
         };
-        server
-            .create_host_group(host_group_id.clone(), vec![remote_id.to_string()])
-            .await
-            .unwrap();
-        let db_name = "foo";
-        server.create_database(db_name, rules).await.unwrap();
+        server.create_database("foo", rules.clone()).await?;
 
+        // the partition is empty before the first write
         let lines = parsed_lines("cpu bar=1 10");
-        server.write_lines("foo", &lines).await.unwrap();
+        server.write_lines("foo", &lines).await?;
 
-        let writes = remote.writes.lock().unwrap().get(db_name).unwrap().clone();
+        let err = server.write_lines("foo", &lines).await.unwrap_err();
+        assert!(matches!(err, Error::WriteThrottled { .. }), "{}", err);
+        assert!(err.to_string().contains("throttled"), "{}", err);
 
-        let write_text = r#"
-writer:1, sequence:1, checksum:226387645
-partition_key:
-  table:cpu
-    bar:1 time:10
-"#;
+        // raising the limit lets writes through again
+        rules.lifecycle_rules.partition_size_hard = None;
+        server.update_database_rules("foo", rules).await?;
+        server.write_lines("foo", &lines).await?;
 
-        assert_eq!(write_text, writes[0].to_string());
+        Ok(())
+    }
 
-        // ensure sequence number goes up
-        let lines = parsed_lines("mem,server=A,region=west user=232 12");
-        server.write_lines("foo", &lines).await.unwrap();
+    #[tokio::test]
+    async fn pause_ingest() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        // the metrics are labelled with the name of the database
+        let db_name = "paused";
+        server.create_database(db_name, rules).await?;
+        let lines = parsed_lines("cpu,host=a usage=1.5 10");
 
-        let writes = remote.writes.lock().unwrap().get(db_name).unwrap().clone();
-        assert_eq!(2, writes.len());
+        server.set_ingest_paused(db_name, true)?;
+        assert!(server.is_ingest_paused(db_name)?);
+        let err = server.write_lines(db_name, &lines).await.unwrap_err();
+        assert!(matches!(err, Error::IngestPaused { .. }), "{}", err);
+        let rejected = metrics::registry()
+            .counter("cluster_paused_writes_total", "", &[("db_name", db_name)])
+            .get();
+        assert_eq!(rejected, 1);
 
-        let write_text = r#"
-writer:1, sequence:2, checksum:3759030699
-partition_key:
-  table:mem
-    server:A region:west user:232 time:12
-"#;
+        // the writes stay paused after a restart
+        server.store_configuration().await?;
+        let store = match &server.store.0 {
+            ObjectStoreIntegration::InMemory(in_mem) => in_mem.clone().await,
+            _ => panic!("wrong type"),
+        };
+        let mut restarted = Server::new(
+            TestConnectionManager::new(),
+            ObjectStore::new_in_memory(store),
+        );
+        restarted.load_configuration(1).await?;
+        assert!(restarted.is_ingest_paused(db_name)?);
 
-        assert_eq!(write_text, writes[1].to_string());
+        restarted.set_ingest_paused(db_name, false)?;
+        restarted.write_lines(db_name, &lines).await?;
+        let results = restarted
+            .query_local(db_name, "select host, usage from cpu")
+            .await?;
+        assert_eq!(to_csv(&results), "host,usage\na,1.5\n");
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn sends_all_to_subscriber() -> Result {
-        let mut manager = TestConnectionManager::new();
-        let remote = Arc::new(TestRemoteServer::default());
-        let remote_id = "serverA";
-        manager
-            .remotes
-            .insert(remote_id.to_string(), remote.clone());
-
+    async fn audit_log_system_table() -> Result {
+        let manager = TestConnectionManager::new();
         let store = ObjectStore::new_in_memory(InMemory::new());
-
         let mut server = Server::new(manager, store);
         server.set_id(1);
-        let host_group_id = "az1".to_string();
         let rules = DatabaseRules {
-            subscriptions: vec![Subscription {
-                name: "query_server_1".to_string(),
-                host_group_id: host_group_id.clone(),
-                matcher: Matcher {
-                    tables: MatchTables::All,
-                    predicate: None,
-                },
-            }],
+            store_locally: true,
             ..Default::default()
         };
-        server
-            .create_host_group(host_group_id.clone(), vec![remote_id.to_string()])
-            .await
-            .unwrap();
-        let db_name = "foo";
-        server.create_database(db_name, rules).await.unwrap();
-
-        let lines = parsed_lines("cpu bar=1 10");
-        server.write_lines("foo", &lines).await.unwrap();
+        server.create_database("foo", rules).await?;
 
-        let writes = remote.writes.lock().unwrap().get(db_name).unwrap().clone();
+        let dir = tempfile::tempdir()?;
+        server.set_audit_log(AuditLog::open_file(dir.path().join("audit.jsonl"))?);
+        let log = server.audit_log().unwrap();
+        for (operation, db_name) in &[
+            ("CreateDatabase", Some("foo")),
+            ("CreateDatabase", Some("bar")),
+            ("CreateToken", None),
+        ] {
+            let event = AuditEvent::new(
+                "Token admin",
+                *operation,
+                db_name.map(ToString::to_string),
+                b"request",
+                None,
+            );
+            log.record(event).await?;
+        }
 
-        let write_text = r#"
-writer:1, sequence:1, checksum:226387645
-partition_key:
-  table:cpu
-    bar:1 time:10
-"#;
+        // the events of other databases aren't visible
+        let results = server
+            .query_local(
+                "foo",
+                "select actor, operation, db_name from system.audit_log",
+            )
+            .await?;
+        assert_eq!(
+            to_csv(&results),
+            "actor,operation,db_name\n\
+             Token admin,CreateDatabase,foo\n\
+             Token admin,CreateToken,\n"
+        );
 
-        assert_eq!(write_text, writes[0].to_string());
+        Ok(())
+    }
 
-        // ensure sequence number goes up
-        let lines = parsed_lines("mem,server=A,region=west user=232 12");
-        server.write_lines("foo", &lines).await.unwrap();
+    #[tokio::test]
+    async fn memory_budget() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let mut rules = DatabaseRules {
+            store_locally: true,
+            lifecycle_rules: LifecycleRules {
+                buffer_size_hard: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        server.create_database("foo", rules.clone()).await?;
 
-        let writes = remote.writes.lock().unwrap().get(db_name).unwrap().clone();
-        assert_eq!(2, writes.len());
+        let lines = parsed_lines("cpu bar=1 10");
+        server.write_lines("foo", &lines).await?;
+        let usage = server.memory_usage("foo").await?;
+        assert!(usage.mutable_buffer > 1);
+        assert_eq!(usage.read_buffer, 0);
 
-        let write_text = r#"
-writer:1, sequence:2, checksum:3759030699
-partition_key:
-  table:mem
-    server:A region:west user:232 time:12
-"#;
+        // the chunk is moved to the read buffer, which is still over the limit
+        let err = server.write_lines("foo", &lines).await.unwrap_err();
+        assert!(matches!(err, Error::BufferFull { limit: 1, .. }), "{}", err);
+        let chunks = server.chunk_summaries("foo").await?;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].storage, ChunkStorage::ReadBuffer);
+        let usage = server.memory_usage("foo").await?;
+        assert_eq!(usage.mutable_buffer, 0);
+        assert_eq!(usage.read_buffer, chunks[0].estimated_bytes);
 
-        assert_eq!(write_text, writes[1].to_string());
+        // dropping the chunk makes room for the write
+        rules.lifecycle_rules.drop_non_persisted = true;
+        server.update_database_rules("foo", rules).await?;
+        server.write_lines("foo", &lines).await?;
+        let chunks = server.chunk_summaries("foo").await?;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].storage, ChunkStorage::OpenMutableBuffer);
+        assert_eq!(server.memory_usage("foo").await?.queries, 0);
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn store_and_load_configuration() -> Result {
+    async fn chunk_policies() -> Result {
         let manager = TestConnectionManager::new();
         let store = ObjectStore::new_in_memory(InMemory::new());
-
         let mut server = Server::new(manager, store);
         server.set_id(1);
-        let host_group_id = "az1".to_string();
-        let remote_id = "serverA";
         let rules = DatabaseRules {
-            replication: vec![host_group_id.clone()],
-            replication_count: 1,
+            store_locally: true,
+            lifecycle_rules: LifecycleRules {
+                buffer_size_hard: Some(1),
+                drop_non_persisted: true,
+                ..Default::default()
+            },
             ..Default::default()
         };
-        server
-            .create_host_group(host_group_id.clone(), vec![remote_id.to_string()])
-            .await
-            .unwrap();
-        let db_name = "foo";
-        server.create_database(db_name, rules).await.unwrap();
+        server.create_database("foo", rules).await?;
 
-        server.store_configuration().await.unwrap();
+        let lines = parsed_lines("cpu bar=1 10");
+        server.write_lines("foo", &lines).await?;
+        let partition_key = server.chunk_summaries("foo").await?[0]
+            .partition_key
+            .clone();
+        let pin = ChunkPolicy {
+            partition_key: partition_key.clone(),
+            chunk_id: None,
+            pinned: true,
+            ttl: None,
+        };
+        server.set_chunk_policy("foo", pin.clone()).await?;
+        assert_eq!(server.chunk_policies("foo")?, vec![pin]);
 
-        let location = "1/config.json";
-        let read_data = server
+        // the pinned chunk is moved to the read buffer but kept there
+        let err = server.write_lines("foo", &lines).await.unwrap_err();
+        assert!(matches!(err, Error::BufferFull { .. }), "{}", err);
+        let chunks = server.chunk_summaries("foo").await?;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].storage, ChunkStorage::ReadBuffer);
+
+        // the policy of the chunk takes precedence over the policy of its partition
+        let ttl = ChunkPolicy {
+            partition_key: partition_key.clone(),
+            chunk_id: Some(chunks[0].id),
+            pinned: false,
+            ttl: Some(Duration::from_nanos(1)),
+        };
+        server.set_chunk_policy("foo", ttl.clone()).await?;
+        let dropped = server.drop_expired_chunks().await;
+        assert_eq!(dropped, vec![("foo".to_string(), chunks[0].clone())]);
+        assert!(server.chunk_summaries("foo").await?.is_empty());
+
+        // the policies are part of the configuration
+        let config = server
             .store
-            .get(location)
-            .await
-            .unwrap()
+            .get("1/config.json")
+            .await?
             .map_ok(|b| bytes::BytesMut::from(&b[..]))
             .try_concat()
+            .await?;
+        let restored: Config = serde_json::from_slice(&config)?;
+        assert_eq!(
+            restored.databases["foo"]
+                .chunk_policies
+                .lock()
+                .expect("mutex poisoned")
+                .policies()
+                .len(),
+            2
+        );
+
+        let err = server
+            .set_chunk_policy(
+                "foo",
+                ChunkPolicy {
+                    pinned: false,
+                    ttl: None,
+                    ..ttl
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidChunkPolicy { .. }), "{}", err);
+
+        server
+            .remove_chunk_policy("foo", &partition_key, None)
+            .await?;
+        let err = server
+            .remove_chunk_policy("foo", &partition_key, None)
             .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ChunkPolicyNotFound { .. }), "{}", err);
+        assert_eq!(server.chunk_policies("foo")?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn timestamp_rules() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            timestamps: TimestampRules {
+                time_column: "occurred_at".to_string(),
+                timestamp_fields: vec!["ingested_at".to_string()],
+            },
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        let lines = parsed_lines(
+            "events,kind=click ingested_at=1600000000000000020i 10\n\
+             events,kind=view ingested_at=1600000000000000030i 20",
+        );
+        server.write_lines("foo", &lines).await?;
+
+        let results = server
+            .query_local(
+                "foo",
+                "select kind, ingested_at, occurred_at from events where occurred_at > 15",
+            )
+            .await?;
+        let batch = &results[0];
+        let schema = batch.schema();
+        let names: Vec<_> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, vec!["kind", "ingested_at", "occurred_at"]);
+        assert_eq!(
+            schema.field(1).data_type(),
+            &arrow_deps::arrow::datatypes::DataType::Timestamp(
+                arrow_deps::arrow::datatypes::TimeUnit::Nanosecond,
+                None
+            )
+        );
+        assert_eq!(batch.num_rows(), 1);
+        let ingested_at = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<arrow_deps::arrow::array::TimestampNanosecondArray>()
+            .unwrap();
+        assert_eq!(ingested_at.value(0), 1_600_000_000_000_000_030);
+        let occurred_at = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<Int64Array>()
             .unwrap();
+        assert_eq!(occurred_at.value(0), 20);
 
-        let config = r#"{"id":1,"databases":{"foo":{"partition_template":{"parts":[]},"store_locally":false,"replication":["az1"],"replication_count":1,"replication_queue_max_size":0,"subscriptions":[],"query_local":false,"primary_query_group":null,"secondary_query_groups":[],"read_only_partitions":[]}},"host_groups":{"az1":{"id":"az1","hosts":["serverA"]}}}"#;
-        let read_data = std::str::from_utf8(&*read_data).unwrap();
-        assert_eq!(read_data, config);
+        // the timestamp fields must be integers, and no column can take the name of the time
+        // column
+        for lp in &[
+            "events,kind=click ingested_at=1.5 30",
+            "events,occurred_at=x value=1 30",
+        ] {
+            let err = server
+                .write_lines("foo", &parsed_lines(lp))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::SchemaViolations { .. }), "{}", err);
+        }
+
+        Ok(())
+    }
 
+    #[tokio::test]
+    async fn merge_read_buffer_chunks() -> Result {
         let manager = TestConnectionManager::new();
-        let store = match server.store.0 {
-            ObjectStoreIntegration::InMemory(in_mem) => in_mem.clone().await,
-            _ => panic!("wrong type"),
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            lifecycle_rules: LifecycleRules {
+                read_buffer_merge_chunks: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
         };
-        let store = ObjectStore::new_in_memory(store);
+        server.create_database("foo", rules).await?;
 
-        let mut recovered_server = Server::new(manager, store);
-        assert_ne!(server.config, recovered_server.config);
-        recovered_server.load_configuration(1).await.unwrap();
-        assert_eq!(server.config, recovered_server.config);
+        let mut moved = vec![];
+        for lp in &[
+            "cpu,host=a usage=1 10",
+            "cpu,host=b usage=3 10\ncpu,host=a usage=2 10",
+        ] {
+            server.write_lines("foo", &parsed_lines(lp)).await?;
+            let partition_key = server.chunk_summaries("foo").await?[0]
+                .partition_key
+                .clone();
+            let closed = server.close_chunk("foo", &partition_key).await?;
+            moved.push(server.move_chunk("foo", &partition_key, closed.id).await?);
+
+            // a single chunk is left alone
+            if moved.len() == 1 {
+                assert!(server.merge_read_buffer_chunks().await?.is_empty());
+            }
+        }
+        let partition_key = moved[0].partition_key.clone();
+
+        let merged = server.merge_read_buffer_chunks().await?;
+        assert_eq!(merged.len(), 1);
+        let (db_name, chunks, chunk) = &merged[0];
+        assert_eq!(db_name, "foo");
+        assert_eq!(chunks, &moved);
+        assert_eq!(chunk.id, moved[1].id);
+        assert_eq!(chunk.row_count, 2);
+        assert_eq!(server.chunk_summaries("foo").await?, vec![chunk.clone()]);
+
+        let results = server
+            .query_local("foo", "select host, usage from cpu order by host")
+            .await?;
+        assert_eq!(to_csv(&results), "host,usage\na,2\nb,3\n");
+
+        // a chunk with a policy of its own keeps its partition from being merged
+        server
+            .write_lines("foo", &parsed_lines("cpu,host=c usage=4 10"))
+            .await?;
+        let closed = server.close_chunk("foo", &partition_key).await?;
+        server.move_chunk("foo", &partition_key, closed.id).await?;
+        let pin = ChunkPolicy {
+            partition_key: partition_key.clone(),
+            chunk_id: Some(closed.id),
+            pinned: true,
+            ttl: None,
+        };
+        server.set_chunk_policy("foo", pin).await?;
+        assert!(server.merge_read_buffer_chunks().await?.is_empty());
+        assert_eq!(server.chunk_summaries("foo").await?.len(), 2);
+
+        server
+            .remove_chunk_policy("foo", &partition_key, Some(closed.id))
+            .await?;
+        let merged = server.merge_read_buffer_chunks().await?;
+        assert_eq!(merged[0].2.row_count, 3);
 
         Ok(())
     }
 
     #[derive(Snafu, Debug, Clone)]
-    enum TestClusterError {
+    pub(crate) enum TestClusterError {
         #[snafu(display("Test cluster error:  {}", message))]
         General { message: String },
     }
 
-    struct TestConnectionManager {
-        remotes: BTreeMap<String, Arc<TestRemoteServer>>,
+    pub(crate) struct TestConnectionManager {
+        pub(crate) remotes: BTreeMap<String, Arc<TestRemoteServer>>,
     }
 
     impl TestConnectionManager {
-        fn new() -> Self {
+        pub(crate) fn new() -> Self {
             Self {
                 remotes: BTreeMap::new(),
             }
@@ -676,28 +5079,35 @@ partition_key:
     }
 
     #[derive(Default)]
-    struct TestRemoteServer {
-        writes: Mutex<BTreeMap<String, Vec<ReplicatedWrite>>>,
+    pub(crate) struct TestRemoteServer {
+        pub(crate) writes: Mutex<BTreeMap<String, Vec<Entry>>>,
     }
 
     #[async_trait]
     impl RemoteServer for TestRemoteServer {
         type Error = TestClusterError;
 
-        async fn replicate(
-            &self,
-            db: &str,
-            replicated_write: &ReplicatedWrite,
-        ) -> Result<(), Self::Error> {
+        async fn replicate(&self, db: &str, entry: &Entry) -> Result<(), Self::Error> {
             let mut writes = self.writes.lock().unwrap();
             let entries = writes.entry(db.to_string()).or_insert_with(Vec::new);
-            entries.push(replicated_write.clone());
+            entries.push(entry.clone());
 
             Ok(())
         }
     }
 
-    fn parsed_lines(lp: &str) -> Vec<ParsedLine<'_>> {
+    pub(crate) fn to_csv(batches: &[RecordBatch]) -> String {
+        let mut sw = StringWriter::new();
+        {
+            let mut writer = csv::Writer::new(&mut sw);
+            for batch in batches {
+                writer.write(batch).unwrap();
+            }
+        }
+        sw.to_string()
+    }
+
+    pub(crate) fn parsed_lines(lp: &str) -> Vec<ParsedLine<'_>> {
         parse_lines(lp).map(|l| l.unwrap()).collect()
     }
 }
@@ -0,0 +1,111 @@
+//! This module contains the memory accounting of databases. The memory of a database is held
+//! by the chunks of its mutable buffer, the chunks of its read buffer and the tables its
+//! running queries have materialised. The buffers are measured when needed, while queries
+//! reserve the memory they hold for as long as they run, so that the total can be checked
+//! against the budget set in the lifecycle rules of the database.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use arrow_deps::arrow::{array::Array, record_batch::RecordBatch};
+
+/// The memory used by a database, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub mutable_buffer: usize,
+    pub read_buffer: usize,
+    pub queries: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.mutable_buffer + self.read_buffer + self.queries
+    }
+}
+
+/// Tracks the memory held by the running queries of a database
+#[derive(Debug, Default)]
+pub struct QueryMemory {
+    bytes: AtomicUsize,
+}
+
+impl QueryMemory {
+    /// Records that a query holds `bytes` more, until the returned reservation is dropped
+    pub fn reserve(&self, bytes: usize) -> Reservation<'_> {
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+        Reservation {
+            memory: self,
+            bytes,
+        }
+    }
+
+    /// The memory held by running queries, in bytes
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::SeqCst)
+    }
+}
+
+/// Memory reserved by a query, which is released when the reservation is dropped
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    memory: &'a QueryMemory,
+    bytes: usize,
+}
+
+impl<'a> Drop for Reservation<'a> {
+    fn drop(&mut self) {
+        self.memory.bytes.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+/// The memory used by the arrays of `batches`, in bytes
+pub fn batches_size<'a>(batches: impl IntoIterator<Item = &'a RecordBatch>) -> usize {
+    batches
+        .into_iter()
+        .flat_map(|batch| batch.columns())
+        .map(|column| column.get_array_memory_size())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_deps::arrow::{
+        array::Int64Array,
+        datatypes::{DataType, Field, Schema},
+    };
+    use std::sync::Arc;
+
+    #[test]
+    fn reservations() {
+        let memory = QueryMemory::default();
+        let first = memory.reserve(10);
+        {
+            let _second = memory.reserve(5);
+            assert_eq!(memory.bytes(), 15);
+        }
+        assert_eq!(memory.bytes(), 10);
+        drop(first);
+        assert_eq!(memory.bytes(), 0);
+
+        let usage = MemoryUsage {
+            mutable_buffer: 1,
+            read_buffer: 2,
+            queries: 3,
+        };
+        assert_eq!(usage.total(), 6);
+    }
+
+    #[test]
+    fn sizes_batches() {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let batch =
+            RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from(vec![1; 1000]))]).unwrap();
+
+        assert_eq!(batches_size(Vec::new()), 0);
+        assert!(batches_size(&[batch.clone()]) >= 8000);
+        assert_eq!(
+            batches_size(&[batch.clone(), batch.clone()]),
+            2 * batches_size(&[batch])
+        );
+    }
+}
@@ -0,0 +1,497 @@
+//! This module contains the ownership leases of databases, which keep two servers configured
+//! with the same writer id from both writing the chunks and catalog of a database, which would
+//! corrupt it.
+//!
+//! The lease of a database is an object at `<writer id>/<db>/owner.json` naming the server
+//! holding it, until when, and its fencing token. Servers are told apart by a holder name that
+//! is unique to each process, not by their writer id. The token increases by one each time the
+//! lease changes hands, and the server checks the lease still has the token it claimed before
+//! writing to the object store, so that a server that lost its lease, because it paused for
+//! longer than the lease or because another server force-claimed it, stops writing instead of
+//! overwriting the catalog of the new owner. Releasing a lease expires it rather than deleting
+//! it, so that tokens keep increasing across owners.
+//!
+//! Object stores can't compare and swap, so two servers claiming an expired lease at the same
+//! time may both write it. Claims read the lease back after writing it, and only the server
+//! whose lease was written last keeps it. For the same reason a server may still write once
+//! it lost its lease, so the servers claiming leases don't store the configuration of their
+//! databases, which holds their catalog, in the shared configuration of the writer id. Each
+//! database is stored on its own as immutable entries at
+//! `<writer id>/databases/<db>/<token>-<generation>-<holder>.json`, named after the lease
+//! they were written under and numbered in the order they were written, see
+//! `Server::store_configuration`. No entry is ever overwritten: the latest entry of the latest
+//! token is the configuration of the database, that of the holder of the lease if another
+//! server wrote under the same token, and a server doesn't write another entry once one was
+//! written under a newer token.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::stream::TryStreamExt;
+use object_store::ObjectStore;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+
+use crate::{
+    DatabaseOwned, ErrorDeserializing, InvalidLeaseDuration, OwnershipLost, Result, StoreError,
+};
+
+/// The longest lease a server may claim
+pub const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// The ownership of a database by a server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    /// The server holding the lease
+    pub holder: String,
+    /// Increases by one each time the lease changes hands, starting from 1
+    pub token: u64,
+    /// When the lease expires unless renewed
+    pub expires: DateTime<Utc>,
+}
+
+/// Returns a holder name unique to this process, from its id and start time
+pub fn holder_name() -> String {
+    format!("{}-{}", std::process::id(), Utc::now().timestamp_nanos())
+}
+
+fn location(id: u32, db_name: &str) -> String {
+    format!("{}/{}/owner.json", id, db_name)
+}
+
+/// Returns `InvalidLeaseDuration` unless `duration` is more than zero and at most
+/// `MAX_DURATION`
+pub fn validate_duration(duration: Duration) -> Result<chrono::Duration> {
+    ensure!(
+        duration > Duration::from_secs(0) && duration <= MAX_DURATION,
+        InvalidLeaseDuration { duration }
+    );
+    chrono::Duration::from_std(duration)
+        .ok()
+        .context(InvalidLeaseDuration { duration })
+}
+
+/// Reads the lease of database `db_name`, if it has one
+pub async fn load(store: &ObjectStore, id: u32, db_name: &str) -> Result<Option<Lease>> {
+    let location = location(id, db_name);
+    let exists = store
+        .list(Some(&location))
+        .await
+        .context(StoreError)?
+        .try_concat()
+        .await
+        .context(StoreError)?
+        .contains(&location);
+    if !exists {
+        return Ok(None);
+    }
+
+    let data = store
+        .get(&location)
+        .await
+        .context(StoreError)?
+        .map_ok(|b| bytes::BytesMut::from(&b[..]))
+        .try_concat()
+        .await
+        .context(StoreError)?;
+    serde_json::from_slice(&data)
+        .map(Some)
+        .context(ErrorDeserializing)
+}
+
+/// Claims database `db_name` for `holder` until `duration` after `now`, or renews the lease if
+/// `holder` already has it. Returns `DatabaseOwned` if another server holds a lease that hasn't
+/// expired yet, unless `force` is set, which takes the lease over regardless for failovers, and
+/// `InvalidLeaseDuration` if the lease would last too long.
+pub async fn claim(
+    store: &ObjectStore,
+    id: u32,
+    db_name: &str,
+    holder: &str,
+    duration: Duration,
+    now: DateTime<Utc>,
+    force: bool,
+) -> Result<Lease> {
+    let expires = validate_duration(duration)
+        .ok()
+        .and_then(|duration| now.checked_add_signed(duration))
+        .context(InvalidLeaseDuration { duration })?;
+    let token = match load(store, id, db_name).await? {
+        Some(current) if current.holder == holder => current.token,
+        Some(current) => {
+            ensure!(
+                force || current.expires <= now,
+                DatabaseOwned {
+                    db: db_name,
+                    holder: current.holder,
+                    expires: current.expires,
+                }
+            );
+            current.token + 1
+        }
+        None => 1,
+    };
+
+    let lease = Lease {
+        holder: holder.to_string(),
+        token,
+        expires,
+    };
+    put(store, id, db_name, &lease).await?;
+
+    // another server may have claimed the lease at the same time
+    verify(store, id, db_name, &lease).await?;
+    Ok(lease)
+}
+
+async fn put(store: &ObjectStore, id: u32, db_name: &str, lease: &Lease) -> Result<()> {
+    let data = Bytes::from(serde_json::to_vec(lease).expect("leases can be serialized to JSON"));
+    let len = data.len();
+    store
+        .put(
+            &location(id, db_name),
+            futures::stream::once(async move { std::io::Result::Ok(data) }),
+            len,
+        )
+        .await
+        .context(StoreError)
+}
+
+/// Returns `OwnershipLost` unless the lease of database `db_name` is still `lease`, with the
+/// same holder and token
+pub async fn verify(store: &ObjectStore, id: u32, db_name: &str, lease: &Lease) -> Result<()> {
+    let current = load(store, id, db_name).await?;
+    ensure!(
+        current.map_or(false, |current| current.holder == lease.holder
+            && current.token == lease.token),
+        OwnershipLost {
+            db: db_name,
+            token: lease.token,
+        }
+    );
+    Ok(())
+}
+
+/// Gives up the lease of database `db_name`, if `lease` is still its lease, so that another
+/// server can claim it right away. The stored entries of the database are deleted, so that it
+/// isn't loaded again. The lease expires at `now` and keeps its token, so that the next claim
+/// gets a newer one.
+pub async fn release(
+    store: &ObjectStore,
+    id: u32,
+    db_name: &str,
+    lease: &Lease,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    if verify(store, id, db_name, lease).await.is_ok() {
+        let entries = stored_entries(store, id).await?;
+        for entry in entries.get(db_name).into_iter().flatten() {
+            if entry.token <= lease.token {
+                store.delete(&entry.location).await.context(StoreError)?;
+            }
+        }
+
+        let expired = Lease {
+            expires: now.min(lease.expires),
+            ..lease.clone()
+        };
+        put(store, id, db_name, &expired).await?;
+    }
+    Ok(())
+}
+
+/// An entry of the configuration of a database, written under a lease
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredEntry {
+    /// The token of the lease the entry was written under
+    pub token: u64,
+    /// Increases by one with each entry written under the same lease, starting from 1
+    pub generation: u64,
+    /// The holder of the lease the entry was written under
+    pub holder: String,
+    location: String,
+}
+
+impl StoredEntry {
+    /// The location of the entry of database `db_name`. The numbers are zero padded so that
+    /// the locations sort in the order the entries were written, and the holder is encoded as
+    /// hexadecimal, as it may hold any character.
+    fn new(id: u32, db_name: &str, lease: &Lease, generation: u64) -> Self {
+        let location = format!(
+            "{}{}/{:020}-{:020}-{}.json",
+            entries_prefix(id),
+            db_name,
+            lease.token,
+            generation,
+            hex::encode(&lease.holder)
+        );
+        Self {
+            token: lease.token,
+            generation,
+            holder: lease.holder.clone(),
+            location,
+        }
+    }
+
+    /// The database and entry stored at `location`, if it is the location of an entry
+    fn parse(id: u32, location: &str) -> Option<(String, Self)> {
+        let path = location.strip_prefix(entries_prefix(id).as_str())?;
+        let (db_name, name) = path.split_at(path.rfind('/')?);
+        let mut parts = name[1..].strip_suffix(".json")?.splitn(3, '-');
+        let token = parts.next()?.parse().ok()?;
+        let generation = parts.next()?.parse().ok()?;
+        let holder = String::from_utf8(hex::decode(parts.next()?).ok()?).ok()?;
+        let entry = Self {
+            token,
+            generation,
+            holder,
+            location: location.to_string(),
+        };
+        Some((db_name.to_string(), entry))
+    }
+
+    /// Whether the entry was written under `lease`
+    fn written_under(&self, lease: &Lease) -> bool {
+        self.token == lease.token && self.holder == lease.holder
+    }
+}
+
+fn entries_prefix(id: u32) -> String {
+    format!("{}/databases/", id)
+}
+
+/// Returns the stored entries of the databases of writer `id`, by database, in the order they
+/// were written
+pub async fn stored_entries(
+    store: &ObjectStore,
+    id: u32,
+) -> Result<BTreeMap<String, Vec<StoredEntry>>> {
+    let locations: Vec<String> = store
+        .list(Some(&entries_prefix(id)))
+        .await
+        .context(StoreError)?
+        .try_concat()
+        .await
+        .context(StoreError)?;
+
+    let mut entries: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for (db_name, entry) in locations
+        .iter()
+        .filter_map(|location| StoredEntry::parse(id, location))
+    {
+        entries.entry(db_name).or_default().push(entry);
+    }
+    for db_entries in entries.values_mut() {
+        db_entries.sort_by_key(|entry| (entry.token, entry.generation));
+    }
+    Ok(entries)
+}
+
+/// Returns `OwnershipLost` if one of `entries`, the stored entries of database `db_name`, was
+/// written under a newer lease than `lease`. The entries another server wrote under the token
+/// of `lease` don't fence it, as only one of them can still hold the lease, see `verify`.
+pub fn fence(db_name: &str, lease: &Lease, entries: &[StoredEntry]) -> Result<()> {
+    let lost = entries.iter().any(|entry| entry.token > lease.token);
+    ensure!(
+        !lost,
+        OwnershipLost {
+            db: db_name,
+            token: lease.token,
+        }
+    );
+    Ok(())
+}
+
+/// Writes `data` as the next entry of database `db_name` under `lease`, after `entries`, its
+/// stored entries, which must have been fenced. The earlier entries are deleted, but for the
+/// latest one written under `lease`, which another server may be loading.
+pub async fn store_entry(
+    store: &ObjectStore,
+    id: u32,
+    db_name: &str,
+    lease: &Lease,
+    entries: &[StoredEntry],
+    data: Vec<u8>,
+) -> Result<()> {
+    let generation = entries
+        .iter()
+        .filter(|entry| entry.written_under(lease))
+        .map(|entry| entry.generation + 1)
+        .max()
+        .unwrap_or(1);
+    let entry = StoredEntry::new(id, db_name, lease, generation);
+
+    let len = data.len();
+    let data = Bytes::from(data);
+    store
+        .put(
+            &entry.location,
+            futures::stream::once(async move { std::io::Result::Ok(data) }),
+            len,
+        )
+        .await
+        .context(StoreError)?;
+
+    let kept = entries
+        .iter()
+        .rev()
+        .find(|entry| entry.written_under(lease))
+        .or_else(|| entries.last());
+    for entry in entries {
+        if Some(entry) != kept {
+            store.delete(&entry.location).await.context(StoreError)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the configuration of each database of writer `id` stored in entries: its latest entry
+/// of the latest token, written by the holder of the lease of the database if two holders
+/// wrote under that token
+pub async fn load_entries(store: &ObjectStore, id: u32) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut loaded = BTreeMap::new();
+    for (db_name, entries) in stored_entries(store, id).await? {
+        let lease = load(store, id, &db_name).await?;
+        let latest = entries.iter().max_by_key(|entry| {
+            let holds = lease
+                .as_ref()
+                .map_or(false, |lease| entry.written_under(lease));
+            (entry.token, holds, entry.generation)
+        });
+        if let Some(entry) = latest {
+            let data = store
+                .get(&entry.location)
+                .await
+                .context(StoreError)?
+                .map_ok(|b| bytes::BytesMut::from(&b[..]))
+                .try_concat()
+                .await
+                .context(StoreError)?;
+            loaded.insert(db_name, data.to_vec());
+        }
+    }
+    Ok(loaded)
+}
+
+/// Deletes the stored entries of all databases of writer `id`
+pub async fn delete_entries(store: &ObjectStore, id: u32) -> Result<()> {
+    for entry in stored_entries(store, id).await?.values().flatten() {
+        store.delete(&entry.location).await.context(StoreError)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use object_store::InMemory;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[tokio::test]
+    async fn claims() -> Result<(), Box<dyn std::error::Error>> {
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let start = Utc::now();
+        assert_eq!(load(&store, 1, "foo").await?, None);
+
+        let first = claim(&store, 1, "foo", "a", MINUTE, start, false).await?;
+        assert_eq!(first.token, 1);
+        assert_eq!(load(&store, 1, "foo").await?, Some(first.clone()));
+
+        // the holder renews its lease, which others can't claim until it expires
+        let later = start + chrono::Duration::seconds(30);
+        let renewed = claim(&store, 1, "foo", "a", MINUTE, later, false).await?;
+        assert_eq!(renewed.token, 1);
+        let err = claim(&store, 1, "foo", "b", MINUTE, later, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::DatabaseOwned { .. }), "{}", err);
+
+        let expired = later + chrono::Duration::seconds(61);
+        let second = claim(&store, 1, "foo", "b", MINUTE, expired, false).await?;
+        assert_eq!(second.token, 2);
+        let err = verify(&store, 1, "foo", &renewed).await.unwrap_err();
+        assert!(
+            matches!(err, Error::OwnershipLost { token: 1, .. }),
+            "{}",
+            err
+        );
+
+        // a forced claim takes over a lease that hasn't expired
+        let third = claim(&store, 1, "foo", "a", MINUTE, expired, true).await?;
+        assert_eq!(third.token, 3);
+        assert!(verify(&store, 1, "foo", &second).await.is_err());
+
+        // only the holder releases the lease, which keeps its token
+        release(&store, 1, "foo", &second, expired).await?;
+        assert_eq!(load(&store, 1, "foo").await?, Some(third.clone()));
+        release(&store, 1, "foo", &third, expired).await?;
+        let released = load(&store, 1, "foo").await?.unwrap();
+        assert_eq!(released.token, 3);
+        assert_eq!(released.expires, expired);
+        let fourth = claim(&store, 1, "foo", "b", MINUTE, expired, false).await?;
+        assert_eq!(fourth.token, 4);
+
+        // leases must last a while, but not forever
+        for duration in &[Duration::from_secs(0), Duration::from_secs(u64::MAX)] {
+            let err = claim(&store, 1, "foo", "b", *duration, expired, false)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::InvalidLeaseDuration { .. }), "{}", err);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn entries() -> Result<(), Box<dyn std::error::Error>> {
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let start = Utc::now();
+        let a = claim(&store, 1, "foo", "a", MINUTE, start, false).await?;
+        store_entry(&store, 1, "foo", &a, &[], b"1".to_vec()).await?;
+        let stored = stored_entries(&store, 1).await?;
+        fence("foo", &a, &stored["foo"])?;
+        store_entry(&store, 1, "foo", &a, &stored["foo"], b"2".to_vec()).await?;
+        let stored = stored_entries(&store, 1).await?;
+        let generations: Vec<_> = stored["foo"].iter().map(|e| e.generation).collect();
+        assert_eq!(generations, vec![1, 2]);
+        assert_eq!(load_entries(&store, 1).await?["foo"], b"2".to_vec());
+
+        // a server that wrote its lease at the same time as the holder doesn't overwrite
+        // the entries of the holder, which replaces them
+        let b = Lease {
+            holder: "b/c-d".to_string(),
+            ..a.clone()
+        };
+        store_entry(&store, 1, "foo", &b, &[], b"3".to_vec()).await?;
+        assert_eq!(load_entries(&store, 1).await?["foo"], b"2".to_vec());
+        let stored = stored_entries(&store, 1).await?;
+        assert_eq!(stored["foo"].len(), 3);
+        fence("foo", &a, &stored["foo"])?;
+        store_entry(&store, 1, "foo", &a, &stored["foo"], b"5".to_vec()).await?;
+        let stored = stored_entries(&store, 1).await?;
+        let holders: Vec<_> = stored["foo"].iter().map(|e| e.holder.as_str()).collect();
+        assert_eq!(holders, vec!["a", "a"]);
+
+        // the server whose lease was taken over stops once the new holder wrote an entry
+        let c = claim(&store, 1, "foo", "c", MINUTE, start, true).await?;
+        store_entry(&store, 1, "foo", &c, &stored["foo"], b"4".to_vec()).await?;
+        assert_eq!(load_entries(&store, 1).await?["foo"], b"4".to_vec());
+        let stored = stored_entries(&store, 1).await?;
+        assert_eq!(stored["foo"].len(), 2);
+        let err = fence("foo", &a, &stored["foo"]).unwrap_err();
+        assert!(
+            matches!(err, Error::OwnershipLost { token: 1, .. }),
+            "{}",
+            err
+        );
+
+        // releasing the lease deletes the entries
+        release(&store, 1, "foo", &c, start).await?;
+        assert!(stored_entries(&store, 1).await?.is_empty());
+
+        Ok(())
+    }
+}
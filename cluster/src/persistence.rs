@@ -0,0 +1,1349 @@
+//! This module contains how the server persists the chunks of its databases to object
+//! storage: the chunks of the mutable buffer once the lifecycle rules of their database say
+//! they are due, or when the server shuts down, and increments of the open chunks. Each table
+//! of a chunk is written as a Parquet file, with its rows sorted by tags and time and the rows
+//! of each point merged, and registered in the catalog of the database.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    convert::TryFrom,
+    hash::Hash,
+    ops::Range,
+};
+
+use arrow_deps::arrow::{
+    compute::kernels::cast::cast, datatypes::DataType as ArrowDataType, record_batch::RecordBatch,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use data_types::{
+    chunk::{ChunkStorage, ColumnStatistics},
+    database_rules::ParquetSettings,
+    table_schema::{DataType, Schema, SchemaBuilder},
+};
+use ingest::parquet::writer::{CompressionLevel, IOxParquetTableWriter, MemWriter};
+use packers::{IOxTableWriter, Packer, Packers};
+use snafu::{ensure, ResultExt};
+use write_buffer::Db as WriteBufferDb;
+
+use crate::{
+    catalog::PersistedChunk, catalog_rebuild::ChunkMetadata, integrity, ClosedChunkNotFound,
+    ConnectionManager, DatabaseError, Db, Error, Result, Server, SortingTable, StoreError,
+    UnknownDatabaseError,
+};
+
+impl<M: ConnectionManager> Server<M> {
+    /// Persists the closed chunk `chunk_id` of partition `partition_key` from the mutable
+    /// buffer of the database to object storage, one chunk per table, and drops it from the
+    /// buffer, returning the persisted chunks
+    pub async fn persist_chunk(
+        &self,
+        db_name: &str,
+        partition_key: &str,
+        chunk_id: u32,
+    ) -> Result<Vec<PersistedChunk>> {
+        let buff = self.local_buffer(db_name)?;
+        let db = &self.config.databases[db_name];
+        db.ensure_writable(db_name)?;
+
+        let closed = buff.chunk_summaries().await.iter().any(|c| {
+            c.partition_key == partition_key
+                && c.id == chunk_id
+                && c.storage == ChunkStorage::ClosedMutableBuffer
+        });
+        ensure!(
+            closed,
+            ClosedChunkNotFound {
+                db: db_name,
+                partition_key,
+                chunk_id,
+            }
+        );
+
+        let chunk = std::iter::once((partition_key.to_string(), chunk_id)).collect();
+        let persisted = self.persist_closed_chunks(db_name, db, buff, chunk).await?;
+        self.store_configuration().await?;
+        Ok(persisted)
+    }
+
+    /// Persists the open and closed chunks of the mutable buffer of every database with a
+    /// local buffer to object storage, like bulk imports, and drops them from the buffer once
+    /// all are persisted. Open chunks are closed first, so that the rows written meanwhile
+    /// are kept in the buffer. This is how the data buffered in memory survives a shutdown.
+    /// Chunks moved to the read buffer are not persisted yet. Returns the name of the
+    /// database of each chunk persisted.
+    pub async fn persist_buffers(&self) -> Result<Vec<(String, PersistedChunk)>> {
+        let mut persisted = vec![];
+
+        for (db_name, db) in &self.config.databases {
+            if let Some(buff) = &db.buffer {
+                for chunk in self.persist_chunks(db_name, db, buff, None).await? {
+                    persisted.push((db_name.clone(), chunk));
+                }
+            }
+        }
+
+        if !persisted.is_empty() {
+            self.store_configuration().await?;
+        }
+        Ok(persisted)
+    }
+
+    /// Persists the chunks of the mutable buffer that the lifecycle rules of their database
+    /// say are due at `now`, like `persist_buffers`: the chunks whose newest row is older
+    /// than `persist_row_age`, and all the chunks of a database once they hold more than
+    /// `persist_buffer_size` bytes. The open chunks holding at least `persist_increment_rows`
+    /// rows not persisted yet are persisted as increments, and stay open. Returns the name of
+    /// the database of each chunk persisted.
+    pub async fn persist_due(&self, now: DateTime<Utc>) -> Result<Vec<(String, PersistedChunk)>> {
+        let mut persisted = vec![];
+
+        for (db_name, db) in &self.config.databases {
+            let buff = match &db.buffer {
+                Some(buff) if db.replica_of.is_none() => buff,
+                _ => continue,
+            };
+            let rules = &db.rules.lifecycle_rules;
+
+            let buffered: usize = buff
+                .chunk_summaries()
+                .await
+                .iter()
+                .map(|c| c.estimated_bytes)
+                .sum();
+            let partitions = match (rules.persist_buffer_size, rules.persist_row_age) {
+                (Some(limit), _) if buffered > limit => Some(None),
+                (_, Some(age)) => {
+                    let age = i64::try_from(age.as_nanos()).unwrap_or(i64::MAX);
+                    let boundary = now.timestamp_nanos().saturating_sub(age);
+                    let due: BTreeSet<_> = buff
+                        .chunks_older_than(boundary)
+                        .await
+                        .into_iter()
+                        .map(|c| c.partition_key)
+                        .collect();
+                    if due.is_empty() {
+                        None
+                    } else {
+                        Some(Some(due))
+                    }
+                }
+                _ => None,
+            };
+
+            if let Some(partitions) = partitions {
+                for chunk in self
+                    .persist_chunks(db_name, db, buff, partitions.as_ref())
+                    .await?
+                {
+                    persisted.push((db_name.clone(), chunk));
+                }
+            }
+
+            // the chunks persisted above were closed, so only the other open chunks are left
+            if let Some(min_rows) = rules.persist_increment_rows {
+                for chunk in self.persist_increments(db_name, db, buff, min_rows).await? {
+                    persisted.push((db_name.clone(), chunk));
+                }
+            }
+        }
+
+        if !persisted.is_empty() {
+            self.store_configuration().await?;
+        }
+        Ok(persisted)
+    }
+
+    /// Closes the open chunks of the mutable buffer `buff` of the database, persists its
+    /// closed chunks and drops them from the buffer, only for the partitions in `partitions`
+    /// if given. The configuration, which holds the catalog, is left for the caller to store.
+    pub(crate) async fn persist_chunks(
+        &self,
+        db_name: &str,
+        db: &Db,
+        buff: &WriteBufferDb,
+        partitions: Option<&BTreeSet<String>>,
+    ) -> Result<Vec<PersistedChunk>> {
+        let selected =
+            |partition_key: &String| partitions.map_or(true, |p| p.contains(partition_key));
+
+        for chunk in buff.chunk_summaries().await {
+            if chunk.storage == ChunkStorage::OpenMutableBuffer && selected(&chunk.partition_key) {
+                buff.close_chunk(&chunk.partition_key)
+                    .await
+                    .map_err(|e| Box::new(e) as DatabaseError)
+                    .context(UnknownDatabaseError {})?;
+            }
+        }
+        let closed: BTreeSet<_> = buff
+            .chunk_summaries()
+            .await
+            .into_iter()
+            .filter(|c| {
+                c.storage == ChunkStorage::ClosedMutableBuffer && selected(&c.partition_key)
+            })
+            .map(|c| (c.partition_key, c.id))
+            .collect();
+
+        self.persist_closed_chunks(db_name, db, buff, closed).await
+    }
+
+    /// Persists the closed chunks `closed`, by partition key and chunk id, of the mutable
+    /// buffer `buff` of the database and drops them from the buffer. The configuration, which
+    /// holds the catalog, is left for the caller to store.
+    async fn persist_closed_chunks(
+        &self,
+        db_name: &str,
+        db: &Db,
+        buff: &WriteBufferDb,
+        closed: BTreeSet<(String, u32)>,
+    ) -> Result<Vec<PersistedChunk>> {
+        let mut persisted = vec![];
+
+        let tables = buff
+            .export(None, None, None)
+            .await
+            .map_err(|e| Box::new(e) as DatabaseError)
+            .context(UnknownDatabaseError {})?;
+        for mut table in tables {
+            if !closed.contains(&(table.partition_key.clone(), table.chunk_id)) {
+                continue;
+            }
+            let chunk = self
+                .persist_table(
+                    db_name,
+                    db,
+                    &table.partition_key,
+                    &table.schema,
+                    &mut table.columns,
+                )
+                .await?;
+            persisted.push(chunk);
+        }
+
+        for (partition_key, chunk_id) in closed {
+            buff.drop_chunk(&partition_key, chunk_id)
+                .await
+                .map_err(|e| Box::new(e) as DatabaseError)
+                .context(UnknownDatabaseError {})?;
+        }
+
+        Ok(persisted)
+    }
+
+    /// Persists the rows of the open chunks of the mutable buffer `buff` of the database that
+    /// weren't persisted yet, as increments of the chunks, for the chunks with at least
+    /// `min_rows` of them. The chunks stay open, and queries read the persisted rows from
+    /// object storage rather than from the buffer. The configuration, which holds the catalog,
+    /// is left for the caller to store.
+    async fn persist_increments(
+        &self,
+        db_name: &str,
+        db: &Db,
+        buff: &WriteBufferDb,
+        min_rows: usize,
+    ) -> Result<Vec<PersistedChunk>> {
+        let mut persisted = vec![];
+
+        for chunk in buff.chunk_summaries().await {
+            if chunk.storage != ChunkStorage::OpenMutableBuffer {
+                continue;
+            }
+            // a concurrent write may have closed it already
+            let increments = match buff.export_increment(&chunk.partition_key, min_rows).await {
+                Ok(increments) => increments,
+                Err(write_buffer::Error::OpenChunkNotFound { .. }) => continue,
+                Err(e) => {
+                    return Err(Error::UnknownDatabaseError {
+                        source: Box::new(e),
+                    })
+                }
+            };
+
+            for mut increment in increments {
+                let table = &mut increment.table;
+                let chunk = self
+                    .persist_table(
+                        db_name,
+                        db,
+                        &table.partition_key,
+                        &table.schema,
+                        &mut table.columns,
+                    )
+                    .await?;
+                buff.mark_persisted(&increment)
+                    .await
+                    .map_err(|e| Box::new(e) as DatabaseError)
+                    .context(UnknownDatabaseError {})?;
+                persisted.push(chunk);
+            }
+        }
+
+        Ok(persisted)
+    }
+
+    /// Writes the rows of a table to object storage as a new chunk of partition
+    /// `partition_key`, sorted and deduplicated for persistence, and registers the chunk in the
+    /// catalog of the database. The configuration, which holds the catalog, is left for the
+    /// caller to store.
+    pub(crate) async fn persist_table(
+        &self,
+        db_name: &str,
+        db: &Db,
+        partition_key: &str,
+        schema: &Schema,
+        columns: &mut Vec<Packers>,
+    ) -> Result<PersistedChunk> {
+        let sort_key = sort_for_persistence(schema, columns)?;
+        let mut chunk = self
+            .write_chunk(
+                db_name,
+                db,
+                partition_key,
+                schema,
+                columns,
+                sort_key,
+                vec![],
+            )
+            .await?;
+        // the chunk may hold rows written late, to points of chunks persisted earlier
+        let mut catalog = db.catalog.lock().expect("mutex poisoned");
+        chunk.overlaps = catalog.overlaps(&chunk);
+        catalog.add_chunk(chunk.clone());
+
+        Ok(chunk)
+    }
+
+    /// Writes the rows of a table, sorted by `sort_key`, to object storage as a new chunk of
+    /// partition `partition_key`, without registering it in the catalog of the database. If
+    /// the chunk is a rewrite of the chunks `replaces`, its file records them.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn write_chunk(
+        &self,
+        db_name: &str,
+        db: &Db,
+        partition_key: &str,
+        schema: &Schema,
+        columns: &[Packers],
+        sort_key: Vec<String>,
+        replaces: Vec<u32>,
+    ) -> Result<PersistedChunk> {
+        let id = self.require_id()?;
+        self.verify_lease(id, db_name, db).await?;
+
+        let chunk_id = db.catalog.lock().expect("mutex poisoned").next_chunk_id();
+        let table_name = schema.measurement().to_string();
+        let location = format!(
+            "{}/{}/data/{}/{}/{}.parquet",
+            id, db_name, partition_key, chunk_id, table_name
+        );
+
+        let (min_time, max_time) = time_range(schema, columns);
+        let column_stats = column_statistics(schema, columns);
+        // The footer of the file records the chunk, to rebuild the catalog from if it is lost
+        let metadata = ChunkMetadata {
+            partition_key: partition_key.to_string(),
+            chunk_id,
+            table_name: table_name.clone(),
+            min_time,
+            max_time,
+            sort_key: sort_key.clone(),
+            replaces,
+            columns: column_stats.clone(),
+        };
+        let data = Bytes::from(encode_parquet(
+            schema,
+            columns,
+            vec![metadata.to_key_value()],
+            &db.rules.parquet,
+        )?);
+        let size_bytes = data.len();
+        let checksum = integrity::checksum(&data);
+        let part_checksums =
+            integrity::part_checksums(&data).map_err(|e| Error::ParquetEncoding {
+                table: table_name.clone(),
+                message: e.to_string(),
+            })?;
+        self.store
+            .put(
+                &location,
+                futures::stream::once(async move { std::io::Result::Ok(data) }),
+                size_bytes,
+            )
+            .await
+            .context(StoreError)?;
+
+        Ok(PersistedChunk {
+            partition_key: partition_key.to_string(),
+            id: chunk_id,
+            table_name,
+            location,
+            row_count: columns.first().map_or(0, Packers::num_rows),
+            size_bytes,
+            min_time,
+            max_time,
+            sort_key,
+            checksum: Some(checksum),
+            part_checksums: Some(part_checksums),
+            columns: column_stats,
+            overlaps: false,
+        })
+    }
+}
+
+/// Sorts the rows of a table by its tags, in name order, and then by time, so that queries
+/// ordering by them don't have to sort the rows again, and merges the rows of the same point,
+/// which have the same tags and time. Returns the sort key of the rows: the sorted columns up to
+/// the first one with nulls, as nulls sort last here but first in queries.
+pub(crate) fn sort_for_persistence(
+    schema: &Schema,
+    columns: &mut Vec<Packers>,
+) -> Result<Vec<String>> {
+    let col_defs = schema.get_col_defs();
+    let mut sort_columns: Vec<_> = col_defs.iter().filter(|col| schema.is_tag(col)).collect();
+    sort_columns.sort_by(|a, b| a.name.cmp(&b.name));
+    let time_column = col_defs.iter().find(|col| col.name == *schema.timestamp());
+    sort_columns.extend(time_column);
+    let sort_by: Vec<_> = sort_columns.iter().map(|col| col.index as usize).collect();
+
+    let rows = columns.first().map(Packers::num_rows).unwrap_or(0);
+    if rows > 1 {
+        // The sort isn't stable, so the row numbers are sorted by last to keep the rows of
+        // each point in the order they were written
+        columns.push(Packers::from((0..rows as i64).collect::<Vec<_>>()));
+        let mut with_order = sort_by.clone();
+        with_order.push(columns.len() - 1);
+        let sorted = packers::sorter::sort(columns, &with_order);
+        columns.pop();
+        sorted.context(SortingTable {
+            table: schema.measurement(),
+        })?;
+
+        // Without a time for every row, rows with the same tags may be different points
+        if time_column.map_or(false, |col| columns[col.index as usize].null_count() == 0) {
+            merge_duplicate_rows(columns, &sort_by);
+        }
+    }
+
+    Ok(sort_columns
+        .iter()
+        .take_while(|col| columns[col.index as usize].null_count() == 0)
+        .map(|col| col.name.clone())
+        .collect())
+}
+
+/// Merges the consecutive rows of `columns` that have the same values in `key_columns`, nulls
+/// included, into one row whose columns have the last value written to them. The rows must be
+/// in the order they were written.
+fn merge_duplicate_rows(columns: &mut [Packers], key_columns: &[usize]) {
+    let rows = columns.first().map(Packers::num_rows).unwrap_or(0);
+    let mut groups: Vec<Range<usize>> = Vec::with_capacity(rows);
+    for row in 0..rows {
+        match groups.last_mut() {
+            Some(group)
+                if key_columns
+                    .iter()
+                    .all(|&index| same_value(&columns[index], group.start, row)) =>
+            {
+                group.end = row + 1
+            }
+            _ => groups.push(row..row + 1),
+        }
+    }
+    if groups.len() == rows {
+        return;
+    }
+
+    for column in columns.iter_mut() {
+        *column = match column {
+            Packers::Float(p) => Packers::Float(merge_rows(p, &groups)),
+            Packers::Integer(p) => Packers::Integer(merge_rows(p, &groups)),
+            Packers::String(p) => Packers::String(merge_rows(p, &groups)),
+            Packers::Boolean(p) => Packers::Boolean(merge_rows(p, &groups)),
+        };
+    }
+}
+
+/// Whether rows `a` and `b` of `column` have the same value, or are both null
+fn same_value(column: &Packers, a: usize, b: usize) -> bool {
+    match column {
+        Packers::Float(p) => p.get(a) == p.get(b),
+        Packers::Integer(p) => p.get(a) == p.get(b),
+        Packers::String(p) => p.get(a) == p.get(b),
+        Packers::Boolean(p) => p.get(a) == p.get(b),
+    }
+}
+
+/// Returns one row for each group of rows of `packer`, with the last value of the group
+fn merge_rows<T>(packer: &Packer<T>, groups: &[Range<usize>]) -> Packer<T>
+where
+    T: Default + Clone + std::fmt::Debug,
+{
+    let mut merged = Packer::with_capacity(groups.len());
+    for group in groups {
+        merged.push_option(group.clone().rev().find_map(|row| packer.get(row)).cloned());
+    }
+    merged
+}
+
+/// Returns the smallest and largest timestamp of the rows of a table
+fn time_range(schema: &Schema, columns: &[Packers]) -> (Option<i64>, Option<i64>) {
+    let times = schema
+        .get_col_defs()
+        .iter()
+        .find(|col| col.name == *schema.timestamp())
+        .and_then(|col| match &columns[col.index as usize] {
+            Packers::Integer(packer) => Some(packer.some_values()),
+            _ => None,
+        })
+        .unwrap_or_default();
+    (times.iter().min().copied(), times.iter().max().copied())
+}
+
+/// Returns the statistics of each column of a table
+fn column_statistics(schema: &Schema, columns: &[Packers]) -> Vec<ColumnStatistics> {
+    schema
+        .get_col_defs()
+        .iter()
+        .map(|col| {
+            let packers = &columns[col.index as usize];
+            let (column_type, (count, distinct_count, min_value, max_value)) = match packers {
+                Packers::Float(p) => ("f64", summarize(p.some_values(), |v| v.to_bits())),
+                Packers::Integer(p) => ("i64", summarize(p.some_values(), |v| *v)),
+                Packers::Boolean(p) => ("bool", summarize(p.some_values(), |v| *v)),
+                Packers::String(p) => {
+                    let values: Vec<String> = p
+                        .some_values()
+                        .iter()
+                        .map(|v| v.as_utf8().unwrap_or_default().to_string())
+                        .collect();
+                    let column_type = if schema.is_tag(col) { "tag" } else { "String" };
+                    (column_type, summarize(values, String::clone))
+                }
+            };
+
+            ColumnStatistics {
+                column_name: col.name.clone(),
+                column_type: column_type.to_string(),
+                count,
+                null_count: packers.num_rows() as u32 - count,
+                distinct_count,
+                min_value,
+                max_value,
+            }
+        })
+        .collect()
+}
+
+/// Returns the number of `values`, the number of distinct values, which are told apart by
+/// `key`, and the smallest and largest values formatted as strings
+fn summarize<T, K>(values: Vec<T>, key: impl Fn(&T) -> K) -> (u32, u32, String, String)
+where
+    T: PartialOrd + ToString,
+    K: Eq + Hash,
+{
+    let distinct = values.iter().map(key).collect::<HashSet<_>>().len();
+    let cmp = |a: &&T, b: &&T| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal);
+    let format = |v: Option<&T>| v.map(ToString::to_string).unwrap_or_default();
+
+    (
+        values.len() as u32,
+        distinct as u32,
+        format(values.iter().min_by(cmp)),
+        format(values.iter().max_by(cmp)),
+    )
+}
+
+/// Encodes the rows of a table into a Parquet file, with `metadata` in its footer, as tuned by
+/// the `settings` of the database
+pub(crate) fn encode_parquet(
+    schema: &Schema,
+    columns: &[Packers],
+    metadata: Vec<(String, String)>,
+    settings: &ParquetSettings,
+) -> Result<Vec<u8>> {
+    let encoding_error = |e: packers::Error| Error::ParquetEncoding {
+        table: schema.measurement().to_string(),
+        message: e.to_string(),
+    };
+    let buffer = MemWriter::default();
+
+    let mut writer = IOxParquetTableWriter::new_with_settings(
+        schema,
+        CompressionLevel::Compatibility,
+        buffer.clone(),
+        metadata,
+        settings,
+    )
+    .map_err(|e| encoding_error(e.into()))?;
+    writer.write_batch(columns).map_err(encoding_error)?;
+    writer.close().map_err(encoding_error)?;
+
+    Ok(buffer.take_data())
+}
+
+/// Converts the rows of a table read back from a Parquet file written by `encode_parquet` into
+/// the schema and columns to encode them again. `encode_parquet` writes the tags first, so the
+/// leading string columns are taken as tags, and the `time` column holds the timestamps.
+pub(crate) fn packers_from_batches(
+    table_name: &str,
+    batches: &[RecordBatch],
+) -> Result<(Schema, Vec<Packers>)> {
+    let encoding_error = |message: String| Error::ParquetEncoding {
+        table: table_name.to_string(),
+        message,
+    };
+    let arrow_schema = match batches.first() {
+        Some(batch) => batch.schema(),
+        None => return Ok((SchemaBuilder::new(table_name).build(), vec![])),
+    };
+
+    let mut builder = SchemaBuilder::new(table_name);
+    let mut in_tags = true;
+    for field in arrow_schema.fields() {
+        let data_type = match field.data_type() {
+            _ if field.name() == "time" => continue,
+            ArrowDataType::Utf8 if in_tags => {
+                builder = builder.tag(field.name());
+                continue;
+            }
+            ArrowDataType::Utf8 => DataType::String,
+            ArrowDataType::Float64 => DataType::Float,
+            ArrowDataType::Int64 | ArrowDataType::UInt64 => DataType::Integer,
+            ArrowDataType::Boolean => DataType::Boolean,
+            other => {
+                return Err(encoding_error(format!(
+                    "column {} has unsupported type {:?}",
+                    field.name(),
+                    other
+                )))
+            }
+        };
+        in_tags = false;
+        builder = builder.field(field.name(), data_type);
+    }
+    let schema = builder.build();
+
+    let mut columns = vec![];
+    for col in schema.get_col_defs() {
+        let index = arrow_schema
+            .index_of(&col.name)
+            .map_err(|e| encoding_error(e.to_string()))?;
+        let mut packers = match col.data_type {
+            DataType::String => Packers::String(Packer::new()),
+            DataType::Float => Packers::Float(Packer::new()),
+            DataType::Integer | DataType::Timestamp => Packers::Integer(Packer::new()),
+            DataType::Boolean => Packers::Boolean(Packer::new()),
+        };
+
+        for batch in batches {
+            packers
+                .extend_from_arrow(batch.column(index).as_ref())
+                .map_err(|e| encoding_error(format!("column {}: {}", col.name, e)))?;
+        }
+        columns.push(packers);
+    }
+
+    Ok((schema, columns))
+}
+
+/// Merges the rows of the same point in `batches` of the table `table_name`, which share a
+/// schema and are in the order the rows were written, keeping the last value written to each
+/// column like persisting does. The merged rows are sorted by tags and time, in the schema of
+/// the batches.
+pub(crate) fn deduplicate_batches(
+    table_name: &str,
+    batches: &[RecordBatch],
+) -> Result<Vec<RecordBatch>> {
+    let merging_error = |message: String| Error::MergingPoints {
+        table: table_name.to_string(),
+        message,
+    };
+    let arrow_schema = match batches.first() {
+        Some(batch) => batch.schema(),
+        None => return Ok(vec![]),
+    };
+
+    let (schema, mut columns) = packers_from_batches(table_name, batches)?;
+    sort_for_persistence(&schema, &mut columns)?;
+
+    let col_defs = schema.get_col_defs();
+    let mut arrays = vec![];
+    for field in arrow_schema.fields() {
+        let col = col_defs
+            .iter()
+            .find(|col| col.name == *field.name())
+            .ok_or_else(|| merging_error(format!("column {} was not merged", field.name())))?;
+        let array = columns[col.index as usize]
+            .to_arrow()
+            .map_err(|e| merging_error(e.to_string()))?;
+        let array = if array.data_type() == field.data_type() {
+            array
+        } else {
+            cast(&array, field.data_type()).map_err(|e| merging_error(e.to_string()))?
+        };
+        arrays.push(array);
+    }
+
+    let batch =
+        RecordBatch::try_new(arrow_schema, arrays).map_err(|e| merging_error(e.to_string()))?;
+    Ok(vec![batch])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        load_config,
+        tests::{parsed_lines, to_csv, Result, TestConnectionManager},
+    };
+    use arrow_deps::arrow::array::{
+        Array, ArrayRef, BooleanArray, Float64Array, Int64Array, StringArray,
+    };
+    use data_types::database_rules::{DatabaseRules, LifecycleRules};
+    use object_store::{InMemory, ObjectStore};
+    use proptest::{
+        collection::{btree_set, vec},
+        num,
+        prelude::*,
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn persist_buffers() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+        server
+            .create_database("bar", DatabaseRules::default())
+            .await?;
+
+        server
+            .write_lines(
+                "foo",
+                &parsed_lines(
+                    "cpu,host=b usage=0.2 20\n\
+                     cpu,host=a usage=0.5,idle=0.4 10\n\
+                     cpu,host=a usage=0.1 10\n\
+                     mem used=3 30",
+                ),
+            )
+            .await?;
+
+        let persisted = server.persist_buffers().await?;
+        let tables: Vec<_> = persisted
+            .iter()
+            .map(|(db_name, chunk)| (db_name.as_str(), chunk.table_name.as_str()))
+            .collect();
+        assert_eq!(tables, vec![("foo", "cpu"), ("foo", "mem")]);
+        let cpu = &persisted[0].1;
+        assert_eq!(cpu.row_count, 2);
+        assert_eq!((cpu.min_time, cpu.max_time), (Some(10), Some(20)));
+        assert_eq!(cpu.sort_key, vec!["host", "time"]);
+
+        // the persisted chunks are dropped from the buffer, but can still be queried
+        assert!(server.chunk_summaries("foo").await?.is_empty());
+        let results = server
+            .query_local("foo", "select host, usage, idle from cpu order by host")
+            .await?;
+        // the rows of the same point are merged, keeping the last value of each field
+        let expected = vec![
+            "+------+-------+------+",
+            "| host | usage | idle |",
+            "+------+-------+------+",
+            "| a    | 0.1   | 0.4  |",
+            "| b    | 0.2   |      |",
+            "+------+-------+------+",
+        ];
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected.join("\n")
+        );
+        assert!(server.store.get("1/config.json").await.is_ok());
+
+        // there is nothing left to persist
+        assert!(server.persist_buffers().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn persist_chunk() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        server
+            .write_lines(
+                "foo",
+                &parsed_lines(
+                    "cpu,host=a usage=0.1 10
+mem used=3 10",
+                ),
+            )
+            .await?;
+        let partition_key = server.chunk_summaries("foo").await?[0]
+            .partition_key
+            .clone();
+
+        // only closed chunks are persisted
+        let err = server
+            .persist_chunk("foo", &partition_key, 0)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ClosedChunkNotFound { .. }), "{}", err);
+
+        let closed = server.close_chunk("foo", &partition_key).await?;
+        server
+            .write_lines("foo", &parsed_lines("cpu,host=b usage=0.2 20"))
+            .await?;
+        let persisted = server
+            .persist_chunk("foo", &partition_key, closed.id)
+            .await?;
+        let tables: Vec<_> = persisted.iter().map(|c| c.table_name.as_str()).collect();
+        assert_eq!(tables, vec!["cpu", "mem"]);
+
+        // the chunk written to meanwhile stays in the buffer
+        let chunks = server.chunk_summaries("foo").await?;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].storage, ChunkStorage::OpenMutableBuffer);
+        assert_eq!(server.persisted_chunks("foo")?.len(), 2);
+        let stored = load_config(&server.store, 1).await?;
+        assert_eq!(
+            stored.databases["foo"]
+                .catalog
+                .lock()
+                .unwrap()
+                .chunks()
+                .len(),
+            2
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn persist_due() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let mut rules = DatabaseRules {
+            store_locally: true,
+            lifecycle_rules: LifecycleRules {
+                persist_row_age: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        server.create_database("foo", rules.clone()).await?;
+        let now = Utc::now();
+
+        // the newest row of the chunk is late, long past the age
+        server
+            .write_lines("foo", &parsed_lines("cpu usage=1 10\ncpu usage=2 20"))
+            .await?;
+        let persisted = server.persist_due(now).await?;
+        assert_eq!(persisted.len(), 1);
+        assert_eq!(persisted[0].1.row_count, 2);
+        assert!(server.chunk_summaries("foo").await?.is_empty());
+
+        // recent rows stay buffered until they are old enough
+        let lp = format!("cpu usage=3 {}", now.timestamp_nanos());
+        server.write_lines("foo", &parsed_lines(&lp)).await?;
+        assert!(server.persist_due(now).await?.is_empty());
+        let later = now + chrono::Duration::seconds(61);
+        assert_eq!(server.persist_due(later).await?.len(), 1);
+
+        // or until the buffer holds too much unpersisted data
+        rules.lifecycle_rules.persist_row_age = None;
+        rules.lifecycle_rules.persist_buffer_size = Some(1);
+        server.update_database_rules("foo", rules).await?;
+        server.write_lines("foo", &parsed_lines(&lp)).await?;
+        assert_eq!(server.persist_due(now).await?.len(), 1);
+        assert!(server.persist_due(now).await?.is_empty());
+
+        Ok(())
+    }
+
+    fn strings(values: &[Option<&str>]) -> Packers {
+        Packers::from(
+            values
+                .iter()
+                .map(|value| value.map(|value| value.as_bytes().to_vec()))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn sorts_and_merges_rows_for_persistence() {
+        let schema = SchemaBuilder::new("cpu")
+            .tag("region")
+            .tag("host")
+            .field("usage", DataType::Float)
+            .field("idle", DataType::Float)
+            .build();
+        let mut columns = vec![
+            strings(&[
+                Some("west"),
+                None,
+                Some("west"),
+                Some("west"),
+                None,
+                Some("west"),
+            ]),
+            strings(&[
+                Some("a"),
+                Some("a"),
+                Some("a"),
+                Some("b"),
+                Some("a"),
+                Some("a"),
+            ]),
+            Packers::from(vec![
+                Some(1.0),
+                Some(2.0),
+                None,
+                Some(4.0),
+                Some(5.0),
+                Some(3.0),
+            ]),
+            Packers::from(vec![Some(10.0), None, Some(11.0), None, None, None]),
+            Packers::from(vec![20_i64, 10, 20, 10, 10, 20]),
+        ];
+
+        let sort_key = sort_for_persistence(&schema, &mut columns).unwrap();
+        // the rows are sorted by host, region and time, with the rows without a region after
+        // the others. Each field of a point has the last value written to it, the rows
+        // without a region included.
+        assert_eq!(
+            columns,
+            vec![
+                strings(&[Some("west"), None, Some("west")]),
+                strings(&[Some("a"), Some("a"), Some("b")]),
+                Packers::from(vec![Some(3.0), Some(5.0), Some(4.0)]),
+                Packers::from(vec![Some(11.0), None, None]),
+                Packers::from(vec![20_i64, 10, 10]),
+            ]
+        );
+        // the region has nulls, which queries sort first
+        assert_eq!(sort_key, vec!["host"]);
+    }
+
+    #[test]
+    fn keeps_rows_without_time_for_persistence() {
+        let schema = SchemaBuilder::new("cpu")
+            .tag("host")
+            .field("usage", DataType::Float)
+            .build();
+        let mut columns = vec![
+            strings(&[Some("b"), Some("a"), Some("a")]),
+            Packers::from(vec![Some(1.0), Some(2.0), Some(3.0)]),
+            Packers::from(vec![None::<i64>, None, None]),
+        ];
+
+        let sort_key = sort_for_persistence(&schema, &mut columns).unwrap();
+        assert_eq!(
+            columns,
+            vec![
+                strings(&[Some("a"), Some("a"), Some("b")]),
+                Packers::from(vec![Some(2.0), Some(3.0), Some(1.0)]),
+                Packers::from(vec![None::<i64>, None, None]),
+            ]
+        );
+        assert_eq!(sort_key, vec!["host"]);
+    }
+
+    #[test]
+    fn merges_duplicate_rows() {
+        let mut columns = vec![
+            strings(&[Some("a"), None, None, Some("b")]),
+            Packers::from(vec![Some(1_i64), Some(2), None, Some(4)]),
+            Packers::from(vec![None, Some(true), Some(false), None]),
+        ];
+
+        // the rows without a value in a key column are the same row
+        merge_duplicate_rows(&mut columns, &[0]);
+        assert_eq!(
+            columns,
+            vec![
+                strings(&[Some("a"), None, Some("b")]),
+                Packers::from(vec![Some(1_i64), Some(2), Some(4)]),
+                Packers::from(vec![None, Some(false), None]),
+            ]
+        );
+
+        // rows without duplicates are left alone
+        let unmerged = columns.clone();
+        merge_duplicate_rows(&mut columns, &[0, 1]);
+        assert_eq!(columns, unmerged);
+    }
+
+    #[tokio::test]
+    async fn persist_increments() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            lifecycle_rules: LifecycleRules {
+                persist_increment_rows: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+        let now = Utc::now();
+        let query = "select usage from cpu order by usage";
+
+        server
+            .write_lines("foo", &parsed_lines("cpu usage=0.1 10\ncpu usage=0.2 20"))
+            .await?;
+        let persisted = server.persist_due(now).await?;
+        assert_eq!(persisted.len(), 1);
+        assert_eq!(persisted[0].1.row_count, 2);
+
+        // the chunk stays open, and its persisted rows are queried once
+        server
+            .write_lines("foo", &parsed_lines("cpu usage=0.3 30"))
+            .await?;
+        let chunks = server.chunk_summaries("foo").await?;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].storage, ChunkStorage::OpenMutableBuffer);
+        assert_eq!(chunks[0].row_count, 3);
+        assert!(server.persist_due(now).await?.is_empty());
+        let results = server.query_local("foo", query).await?;
+        assert_eq!(to_csv(&results), "usage\n0.1\n0.2\n0.3\n");
+
+        // only the rows written since the increment are persisted with the chunk
+        let persisted = server.persist_buffers().await?;
+        assert_eq!(persisted.len(), 1);
+        assert_eq!(persisted[0].1.row_count, 1);
+        assert_eq!(server.persisted_chunks("foo")?.len(), 2);
+        let results = server.query_local("foo", query).await?;
+        assert_eq!(to_csv(&results), "usage\n0.1\n0.2\n0.3\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn late_writes() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+        server
+            .write_lines(
+                "foo",
+                &parsed_lines("cpu,host=a usage=1,idle=2 10\ncpu,host=b usage=1 20"),
+            )
+            .await?;
+        let persisted = server.persist_buffers().await?;
+        assert!(!persisted[0].1.overlaps);
+
+        // a point written again, after its partition was persisted, and a late point
+        server
+            .write_lines(
+                "foo",
+                &parsed_lines("cpu,host=a usage=5 10\ncpu,host=c usage=3 15"),
+            )
+            .await?;
+        let expected = vec![
+            "+------+-------+------+",
+            "| host | usage | idle |",
+            "+------+-------+------+",
+            "| a    | 5     | 2    |",
+            "| b    | 1     |      |",
+            "| c    | 3     |      |",
+            "+------+-------+------+",
+        ]
+        .join("\n");
+        let query = "select host, usage, idle from cpu order by host";
+        let results = server.query_local("foo", query).await?;
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected
+        );
+
+        // the late rows are persisted in an overlapping chunk, whose points are still merged
+        let persisted = server.persist_buffers().await?;
+        assert!(persisted[0].1.overlaps);
+        let results = server.query_local("foo", query).await?;
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected
+        );
+
+        // compaction folds the overlapping chunk into the chunk persisted first
+        let compacted = server.compact_overlapping_chunks().await?;
+        assert_eq!(compacted.len(), 1);
+        let (_, inputs, replacement) = &compacted[0];
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(replacement.as_ref().unwrap().row_count, 3);
+        let chunks = server.persisted_chunks("foo")?;
+        assert_eq!(chunks.len(), 1);
+        assert!(!chunks[0].overlaps);
+        assert!(server.store.get(&inputs[0].location).await.is_err());
+        let results = server.query_local("foo", query).await?;
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected
+        );
+        assert!(server.compact_overlapping_chunks().await?.is_empty());
+
+        Ok(())
+    }
+
+    /// The tag keys of the lines of the round trip test, which need escaping
+    const ROUND_TRIP_TAGS: &[&str] = &["host", "data center", "a,b=c"];
+    const ROUND_TRIP_FLOAT: &str = "usage=total";
+    const ROUND_TRIP_INTEGER: &str = "bytes,in";
+    const ROUND_TRIP_BOOLEAN: &str = "up down";
+    const ROUND_TRIP_STRING: &str = "message\\text";
+
+    /// A line of the measurement `m`, with a value, or none, for each of the tags and fields
+    /// of the round trip test
+    #[derive(Debug, Clone)]
+    struct RoundTripRow {
+        tags: Vec<Option<String>>,
+        float: Option<f64>,
+        integer: Option<i64>,
+        boolean: Option<bool>,
+        string: Option<String>,
+        time: i64,
+    }
+
+    impl RoundTripRow {
+        fn to_line(&self) -> String {
+            let escape = |s: &str| {
+                let mut escaped = String::new();
+                for c in s.chars() {
+                    if matches!(c, '\\' | ',' | '=' | ' ') {
+                        escaped.push('\\');
+                    }
+                    escaped.push(c);
+                }
+                escaped
+            };
+
+            let mut series = "m".to_string();
+            for (key, value) in ROUND_TRIP_TAGS.iter().zip(&self.tags) {
+                if let Some(value) = value {
+                    series.push_str(&format!(",{}={}", escape(key), escape(value)));
+                }
+            }
+            let mut fields = vec![];
+            if let Some(v) = self.float {
+                fields.push(format!("{}={}", escape(ROUND_TRIP_FLOAT), v));
+            }
+            if let Some(v) = self.integer {
+                fields.push(format!("{}={}i", escape(ROUND_TRIP_INTEGER), v));
+            }
+            if let Some(v) = self.boolean {
+                fields.push(format!("{}={}", escape(ROUND_TRIP_BOOLEAN), v));
+            }
+            if let Some(v) = &self.string {
+                let v = v.replace('\\', r"\\").replace('"', r#"\""#);
+                fields.push(format!(r#"{}="{}""#, escape(ROUND_TRIP_STRING), v));
+            }
+            format!("{} {} {}", series, fields.join(","), self.time)
+        }
+    }
+
+    /// The tags and fields of a row, with at least one field
+    #[allow(clippy::type_complexity)]
+    fn round_trip_row() -> impl Strategy<
+        Value = (
+            Vec<Option<String>>,
+            (Option<f64>, Option<i64>, Option<bool>, Option<String>),
+        ),
+    > {
+        // the values of tags can't be empty or end with a backslash, even escaped
+        let tag = proptest::option::of(r#"[a-z ,=\\"é日]{0,6}[a-z]"#);
+        let float = prop_oneof![
+            Just(f64::MIN),
+            Just(f64::MAX),
+            Just(f64::MIN_POSITIVE),
+            num::f64::NORMAL | num::f64::SUBNORMAL | num::f64::ZERO,
+        ];
+        let integer = prop_oneof![Just(i64::MIN), Just(i64::MAX), Just(0), any::<i64>()];
+        let fields = (
+            proptest::option::of(float),
+            proptest::option::of(integer),
+            proptest::option::of(any::<bool>()),
+            proptest::option::of(r#"[a-z ,=\\"é日]{0,8}"#),
+        )
+            .prop_filter("a line needs a field", |(f, i, b, s)| {
+                f.is_some() || i.is_some() || b.is_some() || s.is_some()
+            });
+        (vec(tag, ROUND_TRIP_TAGS.len()), fields)
+    }
+
+    /// Rows with distinct timestamps, in the order of their timestamps, so that none of them
+    /// are merged as points of the same series
+    fn round_trip_rows() -> impl Strategy<Value = Vec<RoundTripRow>> {
+        btree_set(
+            -1_000_000_000_000_000_000i64..4_000_000_000_000_000_000,
+            1..20,
+        )
+        .prop_flat_map(|times| {
+            let len = times.len();
+            (Just(times), vec(round_trip_row(), len))
+        })
+        .prop_map(|(times, rows)| {
+            times
+                .into_iter()
+                .zip(rows)
+                .map(
+                    |(time, (tags, (float, integer, boolean, string)))| RoundTripRow {
+                        tags,
+                        float,
+                        integer,
+                        boolean,
+                        string,
+                        time,
+                    },
+                )
+                .collect()
+        })
+    }
+
+    /// Returns the values of the column `name` of `batches`, cast to `data_type` and read by
+    /// `value`, or `None` if the batches don't have the column
+    fn column_values<T>(
+        batches: &[RecordBatch],
+        name: &str,
+        data_type: ArrowDataType,
+        value: impl Fn(&ArrayRef, usize) -> T,
+    ) -> Option<Vec<Option<T>>> {
+        let mut values = vec![];
+        for batch in batches {
+            let index = batch.schema().index_of(name).ok()?;
+            let array = cast(batch.column(index), &data_type).unwrap();
+            values.extend((0..array.len()).map(|i| {
+                if array.is_null(i) {
+                    None
+                } else {
+                    Some(value(&array, i))
+                }
+            }));
+        }
+        Some(values)
+    }
+
+    /// Asserts that the column `name` holds `expected`. A column without values may be left
+    /// out of the results.
+    fn assert_column<T: std::fmt::Debug + PartialEq>(
+        name: &str,
+        actual: Option<Vec<Option<T>>>,
+        expected: Vec<Option<T>>,
+    ) {
+        match actual {
+            Some(actual) => assert_eq!(actual, expected, "column {}", name),
+            None => assert!(
+                expected.iter().all(Option::is_none),
+                "column {} is missing",
+                name
+            ),
+        }
+    }
+
+    /// Asserts that `batches` hold the values of `rows`, in order
+    fn assert_round_trip(rows: &[RoundTripRow], batches: &[RecordBatch]) {
+        let int = |a: &ArrayRef, i| a.as_any().downcast_ref::<Int64Array>().unwrap().value(i);
+        let float = |a: &ArrayRef, i| a.as_any().downcast_ref::<Float64Array>().unwrap().value(i);
+        let boolean = |a: &ArrayRef, i| a.as_any().downcast_ref::<BooleanArray>().unwrap().value(i);
+        let string = |a: &ArrayRef, i| {
+            a.as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap()
+                .value(i)
+                .to_string()
+        };
+
+        assert_column(
+            "time",
+            column_values(batches, "time", ArrowDataType::Int64, int),
+            rows.iter().map(|row| Some(row.time)).collect(),
+        );
+        for (i, &key) in ROUND_TRIP_TAGS.iter().enumerate() {
+            assert_column(
+                key,
+                column_values(batches, key, ArrowDataType::Utf8, string),
+                rows.iter().map(|row| row.tags[i].clone()).collect(),
+            );
+        }
+        assert_column(
+            ROUND_TRIP_FLOAT,
+            column_values(batches, ROUND_TRIP_FLOAT, ArrowDataType::Float64, float),
+            rows.iter().map(|row| row.float).collect(),
+        );
+        assert_column(
+            ROUND_TRIP_INTEGER,
+            column_values(batches, ROUND_TRIP_INTEGER, ArrowDataType::Int64, int),
+            rows.iter().map(|row| row.integer).collect(),
+        );
+        assert_column(
+            ROUND_TRIP_BOOLEAN,
+            column_values(batches, ROUND_TRIP_BOOLEAN, ArrowDataType::Boolean, boolean),
+            rows.iter().map(|row| row.boolean).collect(),
+        );
+        assert_column(
+            ROUND_TRIP_STRING,
+            column_values(batches, ROUND_TRIP_STRING, ArrowDataType::Utf8, string),
+            rows.iter().map(|row| row.string.clone()).collect(),
+        );
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        /// The values and timestamps of the lines written are read back unchanged by queries,
+        /// both from the mutable buffer and from the Parquet files it is persisted to
+        #[test]
+        fn round_trip(rows in round_trip_rows()) {
+            let lp: Vec<_> = rows.iter().map(RoundTripRow::to_line).collect();
+            let lp = lp.join("\n");
+            let query = "select * from m order by time";
+
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let manager = TestConnectionManager::new();
+                let store = ObjectStore::new_in_memory(InMemory::new());
+                let mut server = Server::new(manager, store);
+                server.set_id(1);
+                let rules = DatabaseRules {
+                    store_locally: true,
+                    ..Default::default()
+                };
+                server.create_database("foo", rules).await.unwrap();
+                server.write_lines("foo", &parsed_lines(&lp)).await.unwrap();
+
+                let buffered = server.query_local("foo", query).await.unwrap();
+                assert_round_trip(&rows, &buffered);
+
+                server.persist_buffers().await.unwrap();
+                assert!(server.chunk_summaries("foo").await.unwrap().is_empty());
+                let persisted = server.query_local("foo", query).await.unwrap();
+                assert_round_trip(&rows, &persisted);
+            });
+        }
+    }
+}
@@ -0,0 +1,1704 @@
+//! This module contains the chunks that queries scan. The data of a partition can be spread
+//! over chunks in several tiers: open and closed chunks of the mutable buffer, chunks moved to
+//! the read buffer, and chunks persisted to object storage as Parquet files. `QueryChunk` gives
+//! them a common interface, so that a query scans the chunks of every tier, and `merge_table`
+//! combines the rows of a table from all of them into batches of one schema.
+//!
+//! The chunks of a partition that rows were written to after some of its chunks were persisted
+//! may hold rows of the same points. The rows of such a partition are merged into one row per
+//! point, keeping the last value written to each column, as the chunks are ordered the way
+//! their rows were written.
+//!
+//! Scans can be limited to the columns a query reads, and each tier then only converts those
+//! columns: the mutable buffer only converts their values to Arrow, the read buffer only clones
+//! their arrays, and only their column chunks are fetched and decoded from Parquet files.
+//!
+//! Parquet files are read with range reads rather than fetched whole: the footer first, then
+//! the column chunks of the row groups, several row groups at a time, each decoded on the
+//! query pool while the next ones are fetched. A query shares a limit on the row groups it
+//! fetches at a time between all the files it scans, so that a cold query reading many files
+//! doesn't flood object storage with requests.
+//!
+//! The read buffer splits the tables of its chunks with indexed columns into row groups, and
+//! leaves out of scans the row groups whose trigram indexes show they have no matching rows.
+//! The small chunks of a partition in the read buffer can be merged into one, with one row per
+//! point, which saves the overhead of scanning each of them.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::Cursor,
+    ops::Range,
+    rc::Rc,
+    sync::Arc,
+    time::Instant,
+};
+
+use arrow_deps::{
+    arrow::{
+        array::{Array, ArrayRef, Int64Array, UInt64Array},
+        compute::kernels::cast::cast,
+        datatypes::{DataType, Field, Schema},
+        error::ArrowError,
+        record_batch::RecordBatch,
+    },
+    parquet::{
+        arrow::arrow_reader::{ArrowReader, ParquetFileArrowReader},
+        errors::ParquetError,
+        file::reader::{ChunkReader, FileReader, Length, SerializedFileReader},
+    },
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use data_types::{
+    chunk::{ChunkStorage, ChunkSummary, ColumnPredicate},
+    TIME_COLUMN_NAME,
+};
+use futures::{
+    future,
+    stream::{self, StreamExt, TryStreamExt},
+};
+use object_store::ObjectStore;
+use snafu::{ensure, ResultExt, Snafu};
+use storage::exec::pool;
+use tokio::sync::Semaphore;
+use write_buffer::Db as WriteBufferDb;
+
+use crate::{
+    catalog::PersistedChunk,
+    integrity,
+    memory::batches_size,
+    tombstone::{self, DeletePredicate},
+    trigram::RowGroupIndexes,
+    DEFAULT_ROW_GROUP_FETCHES,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("error scanning mutable buffer chunk: {}", source))]
+    MutableBuffer { source: write_buffer::Error },
+
+    #[snafu(display("error fetching {} from object storage: {}", location, source))]
+    FetchingParquet {
+        location: String,
+        source: object_store::Error,
+    },
+
+    #[snafu(display("{} is corrupt: no Parquet footer", location))]
+    MissingFooter { location: String },
+
+    #[snafu(display("{} is corrupt: {}", location, status))]
+    CorruptParquet {
+        location: String,
+        status: integrity::FileStatus,
+    },
+
+    #[snafu(display("error reading {}: {}", location, source))]
+    ReadingParquet {
+        location: String,
+        source: ParquetError,
+    },
+
+    #[snafu(display("error converting {} to Arrow: {}", location, source))]
+    ConvertingParquet {
+        location: String,
+        source: ArrowError,
+    },
+
+    #[snafu(display("error decoding a row group of {}: {}", location, source))]
+    DecodingRowGroup {
+        location: String,
+        source: tokio::task::JoinError,
+    },
+
+    #[snafu(display("error scanning a chunk of table {}: {}", table, source))]
+    ScanningChunk {
+        table: String,
+        source: tokio::task::JoinError,
+    },
+
+    #[snafu(display("error applying the deletes of {}: {}", location, source))]
+    ApplyingDeletes {
+        location: String,
+        source: ArrowError,
+    },
+
+    #[snafu(display("error selecting the columns of table {}: {}", table, source))]
+    Projecting { table: String, source: ArrowError },
+
+    #[snafu(display("error merging the chunks of table {}: {}", table, source))]
+    MergingChunks { table: String, source: ArrowError },
+
+    #[snafu(display("error merging the rows of the points of table {}: {}", table, source))]
+    DeduplicatingRows {
+        table: String,
+        source: Box<crate::Error>,
+    },
+
+    #[snafu(display(
+        "only the chunks of one partition can be merged, not of {:?}",
+        partition_keys
+    ))]
+    MergingPartitions { partition_keys: Vec<String> },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A chunk of a partition that queries can scan, whichever tier holds it
+#[async_trait]
+pub trait QueryChunk: fmt::Debug + Send + Sync {
+    fn partition_key(&self) -> &str;
+
+    fn id(&self) -> u32;
+
+    fn storage(&self) -> ChunkStorage;
+
+    /// The columns the rows of each table of the chunk are sorted by, most significant first.
+    /// Empty if the rows are in no particular order.
+    fn sort_key(&self) -> &[String] {
+        &[]
+    }
+
+    /// Returns false if the chunk has rows of the table `table_name` but none of them can
+    /// satisfy all of `predicates`, so that scanning the table can be skipped
+    fn could_match(&self, _table_name: &str, _predicates: &[ColumnPredicate]) -> bool {
+        true
+    }
+
+    /// Whether the chunk was persisted after earlier chunks of its partition that may hold
+    /// rows of the same points
+    fn overlaps(&self) -> bool {
+        false
+    }
+
+    /// The names of the tables with rows in the chunk
+    async fn table_names(&self) -> Result<Vec<String>>;
+
+    /// The rows of the table `table_name`, which are empty if the chunk has no such table.
+    /// Only the columns named in `columns` that the table has are returned, or all of them if
+    /// `columns` is empty.
+    async fn table_to_arrow(&self, table_name: &str, columns: &[&str]) -> Result<Vec<RecordBatch>>;
+
+    /// The rows of the table `table_name` like `table_to_arrow`, leaving out the parts of the
+    /// chunk whose indexes show that none of their rows can satisfy all of `predicates`
+    async fn matching_table_to_arrow(
+        &self,
+        table_name: &str,
+        columns: &[&str],
+        _predicates: &[ColumnPredicate],
+    ) -> Result<Vec<RecordBatch>> {
+        self.table_to_arrow(table_name, columns).await
+    }
+}
+
+#[async_trait]
+impl<C: QueryChunk> QueryChunk for Arc<C> {
+    fn partition_key(&self) -> &str {
+        self.as_ref().partition_key()
+    }
+
+    fn id(&self) -> u32 {
+        self.as_ref().id()
+    }
+
+    fn storage(&self) -> ChunkStorage {
+        self.as_ref().storage()
+    }
+
+    fn sort_key(&self) -> &[String] {
+        self.as_ref().sort_key()
+    }
+
+    fn could_match(&self, table_name: &str, predicates: &[ColumnPredicate]) -> bool {
+        self.as_ref().could_match(table_name, predicates)
+    }
+
+    fn overlaps(&self) -> bool {
+        self.as_ref().overlaps()
+    }
+
+    async fn table_names(&self) -> Result<Vec<String>> {
+        self.as_ref().table_names().await
+    }
+
+    async fn table_to_arrow(&self, table_name: &str, columns: &[&str]) -> Result<Vec<RecordBatch>> {
+        self.as_ref().table_to_arrow(table_name, columns).await
+    }
+
+    async fn matching_table_to_arrow(
+        &self,
+        table_name: &str,
+        columns: &[&str],
+        predicates: &[ColumnPredicate],
+    ) -> Result<Vec<RecordBatch>> {
+        self.as_ref()
+            .matching_table_to_arrow(table_name, columns, predicates)
+            .await
+    }
+}
+
+/// An open or closed chunk of the mutable buffer, which is a partition of the write buffer
+#[derive(Debug)]
+pub struct MutableBufferChunk {
+    buffer: Arc<WriteBufferDb>,
+    summary: ChunkSummary,
+}
+
+impl MutableBufferChunk {
+    /// Returns the chunks of the mutable buffer `buffer`
+    pub async fn all(buffer: &Arc<WriteBufferDb>) -> Vec<Self> {
+        buffer
+            .chunk_summaries()
+            .await
+            .into_iter()
+            .map(|summary| Self {
+                buffer: Arc::clone(buffer),
+                summary,
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl QueryChunk for MutableBufferChunk {
+    fn partition_key(&self) -> &str {
+        &self.summary.partition_key
+    }
+
+    fn id(&self) -> u32 {
+        self.summary.id
+    }
+
+    fn storage(&self) -> ChunkStorage {
+        self.summary.storage
+    }
+
+    async fn table_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .buffer
+            .chunk_table_names(self.partition_key(), self.id())
+            .await)
+    }
+
+    async fn table_to_arrow(&self, table_name: &str, columns: &[&str]) -> Result<Vec<RecordBatch>> {
+        let batch = self
+            .buffer
+            .chunk_table_to_arrow(self.partition_key(), self.id(), table_name, columns)
+            .await
+            .context(MutableBuffer)?;
+        Ok(batch.into_iter().collect())
+    }
+}
+
+/// The number of rows of the row groups the read buffer splits tables with indexed columns into
+const ROW_GROUP_SIZE: usize = 8 * 1024;
+
+/// A chunk of the read buffer, holding the rows of a closed chunk of the mutable buffer as
+/// Arrow record batches
+#[derive(Debug)]
+pub struct ReadBufferChunk {
+    partition_key: String,
+    id: u32,
+    /// The memory used by the record batches of the chunk, in bytes
+    estimated_bytes: usize,
+    tables: BTreeMap<String, Vec<RecordBatch>>,
+    /// The indexes of each record batch of each table with indexed columns, in the order of
+    /// the batches
+    indexes: BTreeMap<String, Vec<RowGroupIndexes>>,
+    /// The memory used by the indexes, in bytes
+    index_bytes: usize,
+    /// How long building the indexes took, in microseconds
+    index_build_micros: u64,
+    /// When the chunk was moved to the read buffer
+    loaded_at: DateTime<Utc>,
+    /// The largest timestamp of the rows of the chunk, in nanoseconds since the epoch
+    max_time: Option<i64>,
+}
+
+impl ReadBufferChunk {
+    /// Creates a chunk holding the record batches of `tables`, keyed by table name. The
+    /// tables with string columns named in `indexed_columns` are split into row groups,
+    /// and those columns are indexed by trigram.
+    pub fn new(
+        partition_key: impl Into<String>,
+        id: u32,
+        mut tables: BTreeMap<String, Vec<RecordBatch>>,
+        indexed_columns: &[String],
+    ) -> Self {
+        let estimated_bytes = batches_size(tables.values().flatten());
+        let max_time = tables.values().flatten().filter_map(max_time).max();
+
+        let start = Instant::now();
+        let mut indexes = BTreeMap::new();
+        if !indexed_columns.is_empty() {
+            for (table_name, batches) in tables.iter_mut() {
+                let groups: Vec<_> = batches.iter().flat_map(row_groups).collect();
+                let table_indexes: Vec<_> = groups
+                    .iter()
+                    .map(|row_group| RowGroupIndexes::build(row_group, indexed_columns))
+                    .collect();
+                if table_indexes.iter().any(|indexes| !indexes.is_empty()) {
+                    *batches = groups;
+                    indexes.insert(table_name.clone(), table_indexes);
+                }
+            }
+        }
+        let index_build_micros = start.elapsed().as_micros() as u64;
+        let index_bytes = indexes.values().flatten().map(RowGroupIndexes::size).sum();
+
+        Self {
+            partition_key: partition_key.into(),
+            id,
+            estimated_bytes,
+            tables,
+            indexes,
+            index_bytes,
+            index_build_micros,
+            loaded_at: Utc::now(),
+            max_time,
+        }
+    }
+
+    /// Copies the rows of a closed chunk of the mutable buffer into a new read buffer chunk
+    /// with the same partition key and id, indexing the string columns named in
+    /// `indexed_columns`
+    pub async fn load(chunk: &MutableBufferChunk, indexed_columns: &[String]) -> Result<Self> {
+        let mut tables = BTreeMap::new();
+        for table_name in chunk.table_names().await? {
+            let batches = chunk.table_to_arrow(&table_name, &[]).await?;
+            tables.insert(table_name, batches);
+        }
+
+        Ok(Self::new(
+            chunk.partition_key(),
+            chunk.id(),
+            tables,
+            indexed_columns,
+        ))
+    }
+
+    /// Merges `chunks`, all of the same partition and in the order their rows were written,
+    /// into one chunk with one row per point, keeping the last value written to each column
+    /// like queries do. The rows of each table are sorted by tags and time. The merged chunk
+    /// takes the id of the newest of `chunks`, and the time the oldest was moved to the read
+    /// buffer, so that their TTL isn't extended.
+    pub fn merge(chunks: &[Arc<Self>], indexed_columns: &[String]) -> Result<Self> {
+        let partition_keys: BTreeSet<_> = chunks.iter().map(|c| c.partition_key()).collect();
+        ensure!(
+            partition_keys.len() == 1,
+            MergingPartitions {
+                partition_keys: partition_keys
+                    .into_iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>(),
+            }
+        );
+
+        let mut table_batches: BTreeMap<&str, Vec<RecordBatch>> = BTreeMap::new();
+        for chunk in chunks {
+            for (table_name, batches) in &chunk.tables {
+                table_batches
+                    .entry(table_name.as_str())
+                    .or_default()
+                    .extend(batches.iter().cloned());
+            }
+        }
+
+        let mut tables = BTreeMap::new();
+        for (table_name, batches) in table_batches {
+            let aligned = align_batches(&batches).context(MergingChunks { table: table_name })?;
+            let merged = crate::persistence::deduplicate_batches(table_name, &aligned)
+                .map_err(Box::new)
+                .context(DeduplicatingRows { table: table_name })?;
+            tables.insert(table_name.to_string(), merged);
+        }
+
+        let id = chunks.iter().map(|c| c.id).max().unwrap_or_default();
+        let mut merged = Self::new(chunks[0].partition_key(), id, tables, indexed_columns);
+        if let Some(loaded_at) = chunks.iter().map(|c| c.loaded_at).min() {
+            merged.loaded_at = loaded_at;
+        }
+        Ok(merged)
+    }
+
+    pub fn loaded_at(&self) -> DateTime<Utc> {
+        self.loaded_at
+    }
+
+    /// The largest timestamp of the rows of the chunk, if it has any
+    pub fn max_time(&self) -> Option<i64> {
+        self.max_time
+    }
+
+    pub fn summary(&self) -> ChunkSummary {
+        ChunkSummary {
+            partition_key: self.partition_key.clone(),
+            id: self.id,
+            storage: ChunkStorage::ReadBuffer,
+            estimated_bytes: self.estimated_bytes,
+            row_count: self
+                .tables
+                .values()
+                .flatten()
+                .map(RecordBatch::num_rows)
+                .sum(),
+            index_bytes: self.index_bytes,
+            index_build_micros: self.index_build_micros,
+        }
+    }
+}
+
+/// Splits `batch` into slices of up to `ROW_GROUP_SIZE` rows, which share its arrays
+fn row_groups(batch: &RecordBatch) -> Vec<RecordBatch> {
+    if batch.num_rows() <= ROW_GROUP_SIZE {
+        return vec![batch.clone()];
+    }
+
+    (0..batch.num_rows())
+        .step_by(ROW_GROUP_SIZE)
+        .map(|offset| {
+            let len = ROW_GROUP_SIZE.min(batch.num_rows() - offset);
+            let columns = batch
+                .columns()
+                .iter()
+                .map(|column| column.slice(offset, len))
+                .collect();
+            RecordBatch::try_new(batch.schema(), columns).expect("slices of a valid batch")
+        })
+        .collect()
+}
+
+#[async_trait]
+impl QueryChunk for ReadBufferChunk {
+    fn partition_key(&self) -> &str {
+        &self.partition_key
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn storage(&self) -> ChunkStorage {
+        ChunkStorage::ReadBuffer
+    }
+
+    fn could_match(&self, table_name: &str, predicates: &[ColumnPredicate]) -> bool {
+        self.indexes.get(table_name).map_or(true, |indexes| {
+            indexes
+                .iter()
+                .any(|row_group| row_group.could_match(predicates))
+        })
+    }
+
+    async fn table_names(&self) -> Result<Vec<String>> {
+        Ok(self.tables.keys().cloned().collect())
+    }
+
+    async fn table_to_arrow(&self, table_name: &str, columns: &[&str]) -> Result<Vec<RecordBatch>> {
+        self.matching_table_to_arrow(table_name, columns, &[]).await
+    }
+
+    async fn matching_table_to_arrow(
+        &self,
+        table_name: &str,
+        columns: &[&str],
+        predicates: &[ColumnPredicate],
+    ) -> Result<Vec<RecordBatch>> {
+        let batches = match self.tables.get(table_name) {
+            Some(batches) => batches,
+            None => return Ok(vec![]),
+        };
+        let indexes = self.indexes.get(table_name);
+        batches
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| {
+                indexes.map_or(true, |indexes| indexes[*index].could_match(predicates))
+            })
+            .map(|(_, batch)| project(batch, columns))
+            .collect::<Result<_, _>>()
+            .context(Projecting { table: table_name })
+    }
+}
+
+/// The largest timestamp of the rows of `batch`, if it has a time column with any
+fn max_time(batch: &RecordBatch) -> Option<i64> {
+    let index = batch.schema().index_of(TIME_COLUMN_NAME).ok()?;
+    let times = cast(batch.column(index), &DataType::Int64).ok()?;
+    let times = times.as_any().downcast_ref::<Int64Array>()?;
+    (0..times.len())
+        .filter(|&row| !times.is_null(row))
+        .map(|row| times.value(row))
+        .max()
+}
+
+/// The number of rows read from a Parquet file at a time
+const PARQUET_BATCH_SIZE: usize = 64 * 1024;
+
+/// The number of bytes fetched from the end of a Parquet file to read its metadata. The rest
+/// of the metadata is fetched if it is larger.
+const FOOTER_FETCH_SIZE: usize = 64 * 1024;
+
+/// The length of the metadata and the magic number ending a Parquet file
+const FOOTER_SIZE: usize = 8;
+
+const PARQUET_MAGIC: &[u8] = b"PAR1";
+
+/// A table of a chunk persisted to object storage, whose Parquet file is read when the chunk
+/// is scanned: its footer first, then the column chunks of its row groups, up to the limit of
+/// `fetches` row groups at a time. Each part of the file is checked against the checksum
+/// recorded for it as it is fetched, and each row group is decoded on the query pool as soon
+/// as it is fetched. The files of the chunks persisted before the checksums of their parts
+/// were recorded are fetched whole instead, and checked against the checksum of the file. The
+/// rows matching the deletes recorded for the chunk are left out.
+#[derive(Debug)]
+pub struct ParquetChunk {
+    store: Arc<ObjectStore>,
+    chunk: PersistedChunk,
+    deletes: Vec<DeletePredicate>,
+    fetches: Arc<Semaphore>,
+    /// The rows of the chunk reloaded into the read buffer, if they were
+    reloaded: Option<Arc<ReadBufferChunk>>,
+}
+
+impl ParquetChunk {
+    pub fn new(
+        store: Arc<ObjectStore>,
+        chunk: PersistedChunk,
+        deletes: Vec<DeletePredicate>,
+    ) -> Self {
+        Self {
+            store,
+            chunk,
+            deletes,
+            fetches: Arc::new(Semaphore::new(DEFAULT_ROW_GROUP_FETCHES)),
+            reloaded: None,
+        }
+    }
+
+    /// Fetches row groups within the limit of `fetches`, shared with the other chunks a query
+    /// scans, rather than a limit of its own
+    pub fn with_fetches(mut self, fetches: Arc<Semaphore>) -> Self {
+        self.fetches = fetches;
+        self
+    }
+
+    /// Reads the rows of the chunk from `rows`, a copy of all of them reloaded into the read
+    /// buffer, rather than from its Parquet file. The deletes of the chunk still apply.
+    pub fn with_reloaded(mut self, rows: Arc<ReadBufferChunk>) -> Self {
+        self.reloaded = Some(rows);
+        self
+    }
+
+    /// Fetches the end of the Parquet file of the chunk, up to the start of its metadata,
+    /// returning the offset of the data fetched with it
+    async fn fetch_footer(&self) -> Result<(usize, Bytes)> {
+        let location = &self.chunk.location;
+        let size = self.chunk.size_bytes;
+        ensure!(
+            size >= FOOTER_SIZE + PARQUET_MAGIC.len(),
+            MissingFooter { location }
+        );
+
+        let _permit = self.fetches.acquire().await;
+        let start = size - FOOTER_FETCH_SIZE.min(size);
+        let data = self
+            .store
+            .get_range(location, start..size)
+            .await
+            .context(FetchingParquet { location })?;
+        let footer = &data[data.len() - FOOTER_SIZE..];
+        ensure!(&footer[4..] == PARQUET_MAGIC, MissingFooter { location });
+        let metadata_size = u32::from_le_bytes([footer[0], footer[1], footer[2], footer[3]]);
+        let footer_size = FOOTER_SIZE + metadata_size as usize;
+        ensure!(footer_size <= size, MissingFooter { location });
+        let (start, data) = if footer_size <= data.len() {
+            (start, data)
+        } else {
+            let start = size - footer_size;
+            let data = self
+                .store
+                .get_range(location, start..size)
+                .await
+                .context(FetchingParquet { location })?;
+            (start, data)
+        };
+
+        let expected = self
+            .chunk
+            .part_checksums
+            .as_ref()
+            .map(|c| c.footer.as_str());
+        self.check_part(expected, &data[data.len() - footer_size..])?;
+        Ok((start, data))
+    }
+
+    /// Fetches the whole Parquet file of the chunk and checks it against the checksum recorded
+    /// for it
+    async fn fetch_file(&self) -> Result<FetchedRanges> {
+        let location = &self.chunk.location;
+        let data = {
+            let _permit = self.fetches.acquire().await;
+            integrity::fetch(&self.store, location)
+                .await
+                .context(FetchingParquet { location })?
+        };
+        let status = integrity::check(&self.chunk, &data);
+        ensure!(!status.is_problem(), CorruptParquet { location, status });
+
+        Ok(FetchedRanges {
+            size: self.chunk.size_bytes,
+            ranges: vec![(0, Bytes::from(data))],
+        })
+    }
+
+    /// Checks a part of the Parquet file of the chunk against `expected`, its checksum, if it
+    /// was recorded
+    fn check_part(&self, expected: Option<&str>, data: &[u8]) -> Result<()> {
+        if let Some(expected) = expected {
+            let status = integrity::check_part(expected, data);
+            ensure!(
+                !status.is_problem(),
+                CorruptParquet {
+                    location: &self.chunk.location,
+                    status
+                }
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the column chunks of the Parquet file to fetch for each of its row groups, those
+    /// of the columns of `indices`, or of all columns if not set, with the index of their column
+    /// and their range in the file
+    fn row_group_ranges(
+        &self,
+        footer: &FetchedRanges,
+        indices: Option<&[usize]>,
+    ) -> Result<Vec<Vec<(usize, Range<usize>)>>> {
+        let location = &self.chunk.location;
+        let file_reader =
+            SerializedFileReader::new(footer.clone()).context(ReadingParquet { location })?;
+        Ok(file_reader
+            .metadata()
+            .row_groups()
+            .iter()
+            .map(|row_group| {
+                let columns: Vec<_> = match indices {
+                    Some(indices) => indices.to_vec(),
+                    None => (0..row_group.num_columns()).collect(),
+                };
+                columns
+                    .into_iter()
+                    .map(|index| {
+                        let (start, length) = row_group.column(index).byte_range();
+                        (index, start as usize..(start + length) as usize)
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Returns the indices of the columns of the Parquet file of the chunk to decode to read
+    /// `columns`, or None to decode all of them
+    fn column_indices(
+        &self,
+        footer: &FetchedRanges,
+        columns: &[&str],
+    ) -> Result<Option<Vec<usize>>> {
+        if columns.is_empty() {
+            return Ok(None);
+        }
+
+        // the columns the deletes match rows on are read too, and left out after applying them
+        let mut needed: BTreeSet<&str> = columns.iter().copied().collect();
+        for delete in &self.deletes {
+            needed.insert(TIME_COLUMN_NAME);
+            needed.extend(delete.tags.keys().map(String::as_str));
+        }
+
+        let location = &self.chunk.location;
+        let file_reader =
+            SerializedFileReader::new(footer.clone()).context(ReadingParquet { location })?;
+        let schema = ParquetFileArrowReader::new(Rc::new(file_reader))
+            .get_schema()
+            .context(ReadingParquet { location })?;
+        Ok(Some(
+            schema
+                .fields()
+                .iter()
+                .enumerate()
+                .filter(|(_, field)| needed.contains(field.name().as_str()))
+                .map(|(index, _)| index)
+                .collect(),
+        ))
+    }
+
+    /// Fetches the column chunks `columns` of the row group `row_group` of the Parquet file of
+    /// the chunk that aren't in `file` yet, at the same time, once the query may fetch another
+    /// row group. The column chunks stored one after the other are fetched with a single read,
+    /// and each is checked against its checksum.
+    async fn fetch_row_group(
+        &self,
+        file: &FetchedRanges,
+        row_group: usize,
+        columns: Vec<(usize, Range<usize>)>,
+    ) -> Result<Vec<(usize, Bytes)>> {
+        let location = &self.chunk.location;
+        let ranges: Vec<_> =
+            coalesce_ranges(columns.iter().map(|(_, range)| range.clone()).collect())
+                .into_iter()
+                .filter(|range| file.slice(range).is_none())
+                .collect();
+        if ranges.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let fetched = {
+            let _permit = self.fetches.acquire().await;
+            future::try_join_all(ranges.into_iter().map(|range| async move {
+                let start = range.start;
+                self.store
+                    .get_range(location, range)
+                    .await
+                    .map(|data| (start, data))
+                    .context(FetchingParquet { location })
+            }))
+            .await?
+        };
+        let fetched = FetchedRanges {
+            size: file.size,
+            ranges: fetched,
+        };
+
+        for (column, range) in &columns {
+            if let Some(data) = fetched.slice(range) {
+                let expected = self
+                    .chunk
+                    .part_checksums
+                    .as_ref()
+                    .and_then(|c| c.column_chunks.get(row_group)?.get(*column))
+                    .map(String::as_str);
+                self.check_part(expected, &data)?;
+            }
+        }
+        Ok(fetched.ranges)
+    }
+
+    /// Fetches and decodes the row groups of the Parquet file of the chunk, with the columns
+    /// `columns` and those the deletes of the chunk read, or all columns if `columns` is empty
+    async fn fetch_table(&self, columns: &[&str]) -> Result<Vec<RecordBatch>> {
+        let location = &self.chunk.location;
+        let file = match (&self.chunk.part_checksums, &self.chunk.checksum) {
+            (None, Some(_)) => self.fetch_file().await?,
+            _ => FetchedRanges {
+                size: self.chunk.size_bytes,
+                ranges: vec![self.fetch_footer().await?],
+            },
+        };
+        let indices = self.column_indices(&file, columns)?;
+        let row_groups = self.row_group_ranges(&file, indices.as_deref())?;
+
+        // all the row groups are started at once, as the limit of the fetches decides how many
+        // are fetched at a time
+        let count = row_groups.len().max(1);
+        let decoded: Vec<Vec<RecordBatch>> = stream::iter(row_groups.into_iter().enumerate())
+            .map(|(row_group, columns)| {
+                let mut file = file.clone();
+                let indices = indices.clone();
+                async move {
+                    let fetched = self.fetch_row_group(&file, row_group, columns).await?;
+                    file.ranges.extend(fetched);
+                    let owned_location = location.clone();
+                    pool::spawn(async move {
+                        decode_row_group(file, row_group, indices, &owned_location)
+                    })
+                    .await
+                    .context(DecodingRowGroup { location })?
+                }
+            })
+            .buffered(count)
+            .try_collect()
+            .await?;
+        Ok(decoded.into_iter().flatten().collect())
+    }
+}
+
+#[async_trait]
+impl QueryChunk for ParquetChunk {
+    fn partition_key(&self) -> &str {
+        &self.chunk.partition_key
+    }
+
+    fn id(&self) -> u32 {
+        self.chunk.id
+    }
+
+    fn storage(&self) -> ChunkStorage {
+        ChunkStorage::ObjectStore
+    }
+
+    fn sort_key(&self) -> &[String] {
+        &self.chunk.sort_key
+    }
+
+    fn could_match(&self, table_name: &str, predicates: &[ColumnPredicate]) -> bool {
+        table_name != self.chunk.table_name || self.chunk.could_match(predicates)
+    }
+
+    fn overlaps(&self) -> bool {
+        self.chunk.overlaps
+    }
+
+    async fn table_names(&self) -> Result<Vec<String>> {
+        Ok(vec![self.chunk.table_name.clone()])
+    }
+
+    async fn table_to_arrow(&self, table_name: &str, columns: &[&str]) -> Result<Vec<RecordBatch>> {
+        if table_name != self.chunk.table_name {
+            return Ok(vec![]);
+        }
+
+        // the columns the deletes read are projected out after they are applied
+        let batches = match &self.reloaded {
+            Some(rows) => rows.table_to_arrow(table_name, &[]).await?,
+            None => self.fetch_table(columns).await?,
+        };
+
+        let location = &self.chunk.location;
+        tombstone::delete_rows(batches, &self.deletes)
+            .and_then(|batches| {
+                batches
+                    .iter()
+                    .map(|batch| project(batch, columns))
+                    .collect()
+            })
+            .context(ApplyingDeletes { location })
+    }
+}
+
+/// The ranges of a Parquet file fetched from object storage, which its metadata and row groups
+/// are decoded from
+#[derive(Debug, Clone)]
+struct FetchedRanges {
+    size: usize,
+    /// The data fetched, with its offset in the file
+    ranges: Vec<(usize, Bytes)>,
+}
+
+impl Length for FetchedRanges {
+    fn len(&self) -> u64 {
+        self.size as u64
+    }
+}
+
+impl FetchedRanges {
+    /// The data of `range` of the file, if it was fetched
+    fn slice(&self, range: &Range<usize>) -> Option<Bytes> {
+        self.ranges
+            .iter()
+            .find(|(offset, data)| *offset <= range.start && range.end <= offset + data.len())
+            .map(|(offset, data)| data.slice(range.start - offset..range.end - offset))
+    }
+}
+
+impl ChunkReader for FetchedRanges {
+    type T = Cursor<Bytes>;
+
+    fn get_read(&self, start: u64, length: usize) -> Result<Self::T, ParquetError> {
+        let start = start as usize;
+        self.slice(&(start..start + length))
+            .map(Cursor::new)
+            .ok_or_else(|| {
+                ParquetError::General(format!(
+                    "bytes {} to {} were not fetched",
+                    start,
+                    start + length
+                ))
+            })
+    }
+}
+
+/// Merges the ranges that are next to or overlap each other, so that the column chunks stored
+/// one after the other are fetched with a single read
+fn coalesce_ranges(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut coalesced: Vec<Range<usize>> = vec![];
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => coalesced.push(range),
+        }
+    }
+    coalesced
+}
+
+/// Decodes the row group `row_group` of the Parquet file at `location` from the ranges fetched
+/// for it, with only the columns of `indices` if set
+fn decode_row_group(
+    file: FetchedRanges,
+    row_group: usize,
+    indices: Option<Vec<usize>>,
+    location: &str,
+) -> Result<Vec<RecordBatch>> {
+    let mut file_reader = SerializedFileReader::new(file).context(ReadingParquet { location })?;
+    file_reader.filter_row_groups(&|_, index| index == row_group);
+    let mut arrow_reader = ParquetFileArrowReader::new(Rc::new(file_reader));
+    let record_reader = match indices {
+        Some(indices) => arrow_reader.get_record_reader_by_columns(indices, PARQUET_BATCH_SIZE),
+        None => arrow_reader.get_record_reader(PARQUET_BATCH_SIZE),
+    };
+    record_reader
+        .context(ReadingParquet { location })?
+        .map(|batch| batch.and_then(signed_integers))
+        .collect::<Result<Vec<_>, _>>()
+        .context(ConvertingParquet { location })
+}
+
+/// Orders chunks the way queries scan them: by partition key, then from the oldest tier to
+/// the newest, then by id. Scanning chunks in a stable order keeps the order of the rows of
+/// unordered queries the same from one query to the next.
+pub fn sort_chunks(chunks: &mut [Arc<dyn QueryChunk>]) {
+    fn tier(storage: ChunkStorage) -> u8 {
+        match storage {
+            ChunkStorage::ObjectStore => 0,
+            ChunkStorage::ReadBuffer => 1,
+            ChunkStorage::ClosedMutableBuffer => 2,
+            ChunkStorage::OpenMutableBuffer => 3,
+        }
+    }
+
+    chunks.sort_by(|a, b| {
+        (a.partition_key(), tier(a.storage()), a.id()).cmp(&(
+            b.partition_key(),
+            tier(b.storage()),
+            b.id(),
+        ))
+    });
+}
+
+/// Returns the names of the tables of `chunks`, each once
+pub async fn table_names(chunks: &[Arc<dyn QueryChunk>]) -> Result<Vec<String>> {
+    let mut names = vec![];
+    for chunk in chunks {
+        names.extend(chunk.table_names().await?);
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Returns the sort key of the rows `merge_table` returns for the table `table_name`. The rows
+/// are only known to be sorted when they all come from a single sorted chunk, as the rows of
+/// several chunks are concatenated.
+pub async fn table_sort_key(
+    chunks: &[Arc<dyn QueryChunk>],
+    table_name: &str,
+) -> Result<Vec<String>> {
+    let mut sort_key = None;
+    for chunk in chunks {
+        if chunk.table_names().await?.iter().any(|t| t == table_name) {
+            if sort_key.is_some() {
+                return Ok(vec![]);
+            }
+            sort_key = Some(chunk.sort_key().to_vec());
+        }
+    }
+    Ok(sort_key.unwrap_or_default())
+}
+
+/// Scans the columns `columns` of the table `table_name`, or all of them if empty, of each of
+/// `chunks`, in order, and converts the batches to a common schema. Returns no batches if none
+/// of the chunks has rows of the table.
+pub async fn merge_table(
+    chunks: &[Arc<dyn QueryChunk>],
+    table_name: &str,
+    columns: &[&str],
+) -> Result<Vec<RecordBatch>> {
+    Ok(scan_table(chunks, table_name, columns, &[], 1)
+        .await?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+/// Scans the table `table_name` of `chunks` like `merge_table`, with up to `parallelism` chunks
+/// scanned at a time, each by a task of its own on the query pool so that they run on different
+/// workers, and keeps the batches of each chunk apart. The query engine executes each of the
+/// returned partitions on its own worker. Chunks without rows of the table have no
+/// partition, and the partitions are in the order of `chunks`. The chunks whose statistics show
+/// that none of their rows can satisfy `predicates` are not scanned, nor the parts of the
+/// chunks whose indexes show it.
+///
+/// The rows of the chunks of a partition that may hold rows of the same points, as found by
+/// `overlapping_partitions`, are merged into a single partition with one row per point. All
+/// these chunks are scanned, as the rows of a point can't be merged without its latest values,
+/// and all their columns too, as points are told apart by all their tags. The merged rows are
+/// then limited to `columns`.
+pub async fn scan_table(
+    chunks: &[Arc<dyn QueryChunk>],
+    table_name: &str,
+    columns: &[&str],
+    predicates: &[ColumnPredicate],
+    parallelism: usize,
+) -> Result<Vec<Vec<RecordBatch>>> {
+    let overlapping = overlapping_partitions(chunks);
+    let mut scanned: Vec<Vec<RecordBatch>> = stream::iter(chunks)
+        .map(|chunk| {
+            let chunk = Arc::clone(chunk);
+            let overlaps = overlapping.contains(chunk.partition_key());
+            let table = table_name.to_string();
+            let columns: Vec<_> = columns.iter().map(ToString::to_string).collect();
+            let predicates = predicates.to_vec();
+            let scan = tokio::spawn(async move {
+                let columns: Vec<_> = columns.iter().map(String::as_str).collect();
+                if overlaps {
+                    chunk.table_to_arrow(&table, &[]).await
+                } else if chunk.could_match(&table, &predicates) {
+                    chunk
+                        .matching_table_to_arrow(&table, &columns, &predicates)
+                        .await
+                } else {
+                    Ok(vec![])
+                }
+            });
+            async move { scan.await.context(ScanningChunk { table: table_name })? }
+        })
+        .buffered(parallelism.max(1))
+        .try_collect()
+        .await?;
+
+    // if every chunk with rows of the table was ruled out, one is scanned anyway, so that the
+    // table keeps its columns and the query returns no rows rather than failing
+    if scanned.iter().all(Vec::is_empty) {
+        if let Some(index) = chunks
+            .iter()
+            .position(|chunk| !chunk.could_match(table_name, predicates))
+        {
+            scanned[index] = chunks[index].table_to_arrow(table_name, columns).await?;
+        }
+    }
+
+    let counts: Vec<_> = scanned.iter().map(Vec::len).collect();
+    let batches: Vec<_> = scanned.into_iter().flatten().collect();
+    let mut batches = align_batches(&batches)
+        .context(MergingChunks { table: table_name })?
+        .into_iter();
+
+    // the partitions of the chunks that overlap, by partition key, with their number of chunks
+    let mut merged: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    let mut partitions: Vec<Vec<RecordBatch>> = vec![];
+    for (chunk, count) in chunks.iter().zip(counts) {
+        if count == 0 {
+            continue;
+        }
+        let chunk_batches = batches.by_ref().take(count);
+
+        let key = chunk.partition_key();
+        if overlapping.contains(key) {
+            if let Some((index, merged_chunks)) = merged.get_mut(key) {
+                partitions[*index].extend(chunk_batches);
+                *merged_chunks += 1;
+                continue;
+            }
+            merged.insert(key, (partitions.len(), 1));
+        }
+        partitions.push(chunk_batches.collect());
+    }
+
+    for (index, merged_chunks) in merged.values() {
+        if *merged_chunks > 1 {
+            partitions[*index] =
+                crate::persistence::deduplicate_batches(table_name, &partitions[*index])
+                    .map_err(Box::new)
+                    .context(DeduplicatingRows { table: table_name })?;
+        }
+        partitions[*index] = partitions[*index]
+            .iter()
+            .map(|batch| project(batch, columns))
+            .collect::<Result<_, _>>()
+            .context(MergingChunks { table: table_name })?;
+    }
+
+    Ok(partitions)
+}
+
+/// Returns the partition keys of the chunks that may hold rows of the same points: those of
+/// the partitions with a persisted chunk that overlaps earlier ones, and of the partitions
+/// with persisted chunks that rows were written to since, which are in chunks of the other
+/// tiers.
+pub fn overlapping_partitions(chunks: &[Arc<dyn QueryChunk>]) -> BTreeSet<String> {
+    let persisted: BTreeSet<_> = chunks
+        .iter()
+        .filter(|chunk| chunk.storage() == ChunkStorage::ObjectStore)
+        .map(|chunk| chunk.partition_key())
+        .collect();
+
+    chunks
+        .iter()
+        .filter(|chunk| {
+            chunk.overlaps()
+                || (chunk.storage() != ChunkStorage::ObjectStore
+                    && persisted.contains(chunk.partition_key()))
+        })
+        .map(|chunk| chunk.partition_key().to_string())
+        .collect()
+}
+
+/// Returns the columns of `batch` named in `columns`, in the order of the schema of `batch`,
+/// or all of them if `columns` is empty
+fn project(batch: &RecordBatch, columns: &[&str]) -> Result<RecordBatch, ArrowError> {
+    if columns.is_empty() {
+        return Ok(batch.clone());
+    }
+
+    let schema = batch.schema();
+    let indices: Vec<_> = schema
+        .fields()
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| columns.contains(&field.name().as_str()))
+        .map(|(index, _)| index)
+        .collect();
+    if indices.len() == schema.fields().len() {
+        return Ok(batch.clone());
+    }
+
+    let fields = indices
+        .iter()
+        .map(|&index| schema.field(index).clone())
+        .collect();
+    let arrays = indices
+        .iter()
+        .map(|&index| Arc::clone(batch.column(index)))
+        .collect();
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)
+}
+
+/// Converts `batches` to their merged schema. The columns of the merged schema are the
+/// columns of all the batches, in the order they first appear. A column gets the type it has
+/// in the first batch it appears in, and is cast to that type in the other batches. Batches
+/// without a column get nulls for it.
+fn align_batches(batches: &[RecordBatch]) -> Result<Vec<RecordBatch>, ArrowError> {
+    let mut fields: Vec<Field> = vec![];
+    for batch in batches {
+        for field in batch.schema().fields() {
+            if !fields.iter().any(|f| f.name() == field.name()) {
+                fields.push(Field::new(field.name(), field.data_type().clone(), true));
+            }
+        }
+    }
+    let schema = Arc::new(Schema::new(fields));
+
+    batches
+        .iter()
+        .map(|batch| {
+            let batch_schema = batch.schema();
+            let columns = schema
+                .fields()
+                .iter()
+                .map(|field| match batch_schema.index_of(field.name()) {
+                    Ok(index) => {
+                        let column = batch.column(index);
+                        if column.data_type() == field.data_type() {
+                            Ok(Arc::clone(column))
+                        } else {
+                            cast(column, field.data_type())
+                        }
+                    }
+                    Err(_) => null_column(field.data_type(), batch.num_rows()),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            RecordBatch::try_new(Arc::clone(&schema), columns)
+        })
+        .collect()
+}
+
+/// Returns `batch` with its unsigned integer columns read as the signed integers they hold.
+/// The Parquet files written before integer columns were marked as signed mark them as
+/// unsigned, which would turn negative values into huge ones.
+fn signed_integers(batch: RecordBatch) -> Result<RecordBatch, ArrowError> {
+    let schema = batch.schema();
+    if !schema
+        .fields()
+        .iter()
+        .any(|field| field.data_type() == &DataType::UInt64)
+    {
+        return Ok(batch);
+    }
+
+    let fields = schema
+        .fields()
+        .iter()
+        .map(|field| match field.data_type() {
+            DataType::UInt64 => Field::new(field.name(), DataType::Int64, field.is_nullable()),
+            _ => field.clone(),
+        })
+        .collect();
+    let columns = batch
+        .columns()
+        .iter()
+        .map(
+            |column| match column.as_any().downcast_ref::<UInt64Array>() {
+                Some(values) => {
+                    let values: Vec<_> = (0..values.len())
+                        .map(|i| Some(values.value(i) as i64).filter(|_| values.is_valid(i)))
+                        .collect();
+                    Arc::new(Int64Array::from(values)) as ArrayRef
+                }
+                None => Arc::clone(column),
+            },
+        )
+        .collect();
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+}
+
+/// A column of `len` nulls of type `data_type`, cast from nulls of an integer column, which
+/// converts to every type the chunks hold
+fn null_column(data_type: &DataType, len: usize) -> Result<ArrayRef, ArrowError> {
+    let nulls: ArrayRef = Arc::new(Int64Array::from(vec![None; len]));
+    if data_type == &DataType::Int64 {
+        Ok(nulls)
+    } else {
+        cast(&nulls, data_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_deps::arrow::{
+        array::{Float64Array, StringArray},
+        util::pretty::pretty_format_batches,
+    };
+    use data_types::chunk::{Comparison, Literal};
+    use std::{io::Read, sync::Mutex, time::Duration};
+
+    fn batch(columns: Vec<(&str, ArrayRef)>) -> RecordBatch {
+        let fields = columns
+            .iter()
+            .map(|(name, column)| Field::new(name, column.data_type().clone(), true))
+            .collect();
+        RecordBatch::try_new(
+            Arc::new(Schema::new(fields)),
+            columns.into_iter().map(|(_, column)| column).collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn coalesce_column_chunk_ranges() {
+        assert_eq!(
+            coalesce_ranges(vec![30..40, 4..10, 10..20, 15..18, 50..60]),
+            vec![4..20, 30..40, 50..60]
+        );
+        assert!(coalesce_ranges(vec![]).is_empty());
+    }
+
+    #[test]
+    fn read_fetched_ranges() {
+        let file = FetchedRanges {
+            size: 100,
+            ranges: vec![
+                (10, Bytes::from(&b"0123456789"[..])),
+                (90, Bytes::from(&b"abcdefghij"[..])),
+            ],
+        };
+        assert_eq!(file.len(), 100);
+
+        let mut data = vec![];
+        file.get_read(12, 3)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"234");
+        data.clear();
+        file.get_read(95, 5)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"fghij");
+        assert!(file.get_read(18, 5).is_err());
+        assert!(file.get_read(50, 1).is_err());
+    }
+
+    #[test]
+    fn aligns_schemas() {
+        let batches = vec![
+            batch(vec![
+                ("host", Arc::new(StringArray::from(vec!["a"]))),
+                ("usage", Arc::new(Float64Array::from(vec![0.5]))),
+                ("time", Arc::new(Int64Array::from(vec![10]))),
+            ]),
+            // another chunk has another column, an integer field of the same name, and the
+            // columns in another order
+            batch(vec![
+                ("time", Arc::new(Int64Array::from(vec![20]))),
+                ("region", Arc::new(StringArray::from(vec!["west"]))),
+                ("usage", Arc::new(Int64Array::from(vec![1]))),
+            ]),
+        ];
+
+        let aligned = align_batches(&batches).unwrap();
+        assert_eq!(aligned[0].schema(), aligned[1].schema());
+
+        let expected = vec![
+            "+------+-------+------+--------+",
+            "| host | usage | time | region |",
+            "+------+-------+------+--------+",
+            "| a    | 0.5   | 10   |        |",
+            "|      | 1     | 20   | west   |",
+            "+------+-------+------+--------+",
+        ];
+        assert_eq!(
+            pretty_format_batches(&aligned).unwrap().trim(),
+            expected.join("\n")
+        );
+    }
+
+    #[test]
+    fn reads_unsigned_integers_as_signed() {
+        let unsigned = batch(vec![
+            (
+                "bytes",
+                Arc::new(UInt64Array::from(vec![Some(u64::MAX), None])),
+            ),
+            ("time", Arc::new(Int64Array::from(vec![10, 20]))),
+        ]);
+
+        let signed = signed_integers(unsigned).unwrap();
+        assert_eq!(signed.schema().field(0).data_type(), &DataType::Int64);
+        let bytes = signed
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(bytes.value(0), -1);
+        assert!(bytes.is_null(1));
+    }
+
+    #[tokio::test]
+    async fn scans_chunks_in_order() {
+        let read_buffer = ReadBufferChunk::new(
+            "a",
+            1,
+            vec![(
+                "cpu".to_string(),
+                vec![batch(vec![("time", Arc::new(Int64Array::from(vec![1])))])],
+            )]
+            .into_iter()
+            .collect(),
+            &[],
+        );
+        let buffer = Arc::new(WriteBufferDb::new("foo"));
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines("cpu usage=1 2\nmem used=2 3")
+            .map(|l| l.unwrap())
+            .collect();
+        storage::Database::write_lines(&*buffer, &lines)
+            .await
+            .unwrap();
+
+        let mut chunks: Vec<Arc<dyn QueryChunk>> = vec![];
+        for chunk in MutableBufferChunk::all(&buffer).await {
+            chunks.push(Arc::new(chunk));
+        }
+        chunks.push(Arc::new(read_buffer));
+        sort_chunks(&mut chunks);
+        assert_eq!(chunks[0].storage(), ChunkStorage::OpenMutableBuffer);
+        assert_eq!(chunks[1].storage(), ChunkStorage::ReadBuffer);
+
+        assert_eq!(table_names(&chunks).await.unwrap(), vec!["cpu", "mem"]);
+
+        let merged = merge_table(&chunks, "cpu", &[]).await.unwrap();
+        let expected = vec![
+            "+-------+------+",
+            "| usage | time |",
+            "+-------+------+",
+            "| 1     | 2    |",
+            "|       | 1    |",
+            "+-------+------+",
+        ];
+        assert_eq!(
+            pretty_format_batches(&merged).unwrap().trim(),
+            expected.join("\n")
+        );
+        assert!(merge_table(&chunks, "disk", &[]).await.unwrap().is_empty());
+
+        let partitions = scan_table(&chunks, "cpu", &[], &[], 4).await.unwrap();
+        assert_eq!(partitions.len(), 2);
+        let flattened: Vec<_> = partitions.iter().flatten().cloned().collect();
+        assert_eq!(
+            pretty_format_batches(&flattened).unwrap(),
+            pretty_format_batches(&merged).unwrap()
+        );
+        assert_eq!(partitions[1][0].num_rows(), 1);
+        assert_eq!(
+            scan_table(&chunks, "mem", &[], &[], 4).await.unwrap().len(),
+            1
+        );
+    }
+
+    /// A chunk with a row of `cpu` at the time `id`, that takes `delay` to scan. It counts
+    /// the chunks being scanned, and the most scanned at the same time, in `scans`.
+    #[derive(Debug)]
+    struct SlowChunk {
+        id: u32,
+        delay: Duration,
+        scans: Arc<Mutex<(usize, usize)>>,
+    }
+
+    #[async_trait]
+    impl QueryChunk for SlowChunk {
+        fn partition_key(&self) -> &str {
+            "a"
+        }
+
+        fn id(&self) -> u32 {
+            self.id
+        }
+
+        fn storage(&self) -> ChunkStorage {
+            ChunkStorage::ReadBuffer
+        }
+
+        async fn table_names(&self) -> Result<Vec<String>> {
+            Ok(vec!["cpu".to_string()])
+        }
+
+        async fn table_to_arrow(
+            &self,
+            table_name: &str,
+            _columns: &[&str],
+        ) -> Result<Vec<RecordBatch>> {
+            if table_name != "cpu" {
+                return Ok(vec![]);
+            }
+
+            {
+                let mut scans = self.scans.lock().unwrap();
+                scans.0 += 1;
+                scans.1 = scans.1.max(scans.0);
+            }
+            tokio::time::delay_for(self.delay).await;
+            self.scans.lock().unwrap().0 -= 1;
+
+            let time = Int64Array::from(vec![i64::from(self.id)]);
+            Ok(vec![batch(vec![("time", Arc::new(time))])])
+        }
+    }
+
+    #[tokio::test]
+    async fn scans_chunks_in_parallel() {
+        let scans = Arc::new(Mutex::new((0, 0)));
+        // the later chunks are scanned faster, so that they are done before the earlier ones
+        let chunks: Vec<Arc<dyn QueryChunk>> = (0..8)
+            .map(|id| {
+                Arc::new(SlowChunk {
+                    id,
+                    delay: Duration::from_millis(10 * u64::from(8 - id)),
+                    scans: Arc::clone(&scans),
+                }) as _
+            })
+            .collect();
+        let times = |partitions: &[Vec<RecordBatch>]| -> Vec<i64> {
+            partitions
+                .iter()
+                .map(|batches| {
+                    assert_eq!(batches.len(), 1);
+                    let times = batches[0]
+                        .column(0)
+                        .as_any()
+                        .downcast_ref::<Int64Array>()
+                        .unwrap();
+                    times.value(0)
+                })
+                .collect()
+        };
+
+        let partitions = scan_table(&chunks, "cpu", &[], &[], 3).await.unwrap();
+        assert_eq!(times(&partitions), (0..8).collect::<Vec<_>>());
+        assert_eq!(*scans.lock().unwrap(), (0, 3));
+
+        *scans.lock().unwrap() = (0, 0);
+        let partitions = scan_table(&chunks, "cpu", &[], &[], 1).await.unwrap();
+        assert_eq!(times(&partitions), (0..8).collect::<Vec<_>>());
+        assert_eq!(*scans.lock().unwrap(), (0, 1));
+
+        assert!(scan_table(&chunks, "mem", &[], &[], 3)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn scans_only_needed_columns() {
+        let read_buffer = ReadBufferChunk::new(
+            "a",
+            1,
+            vec![(
+                "cpu".to_string(),
+                vec![batch(vec![
+                    ("host", Arc::new(StringArray::from(vec!["a"]))),
+                    ("usage", Arc::new(Float64Array::from(vec![0.5]))),
+                    ("idle", Arc::new(Float64Array::from(vec![0.2]))),
+                    ("time", Arc::new(Int64Array::from(vec![1]))),
+                ])],
+            )]
+            .into_iter()
+            .collect(),
+            &[],
+        );
+        let buffer = Arc::new(WriteBufferDb::new("foo"));
+        let lines: Vec<_> =
+            influxdb_line_protocol::parse_lines("cpu,host=b,region=west usage=1,idle=3 2")
+                .map(|l| l.unwrap())
+                .collect();
+        storage::Database::write_lines(&*buffer, &lines)
+            .await
+            .unwrap();
+
+        let mut chunks: Vec<Arc<dyn QueryChunk>> = vec![];
+        for chunk in MutableBufferChunk::all(&buffer).await {
+            chunks.push(Arc::new(chunk));
+        }
+        chunks.push(Arc::new(read_buffer));
+        sort_chunks(&mut chunks);
+
+        // columns the table doesn't have are ignored
+        let columns = ["time", "usage", "host", "missing"];
+        for chunk in &chunks {
+            for batch in chunk.table_to_arrow("cpu", &columns).await.unwrap() {
+                let schema = batch.schema();
+                let names: Vec<_> = schema.fields().iter().map(Field::name).collect();
+                assert_eq!(names, vec!["host", "usage", "time"], "{:?}", chunk);
+            }
+        }
+
+        let merged = merge_table(&chunks, "cpu", &columns).await.unwrap();
+        let expected = vec![
+            "+------+-------+------+",
+            "| host | usage | time |",
+            "+------+-------+------+",
+            "| b    | 1     | 2    |",
+            "| a    | 0.5   | 1    |",
+            "+------+-------+------+",
+        ];
+        assert_eq!(
+            pretty_format_batches(&merged).unwrap().trim(),
+            expected.join("\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_row_groups_by_index() {
+        let logs = |messages: Vec<&str>, times: Vec<i64>| {
+            batch(vec![
+                ("message", Arc::new(StringArray::from(messages))),
+                ("time", Arc::new(Int64Array::from(times))),
+            ])
+        };
+        let tables: BTreeMap<_, _> = vec![(
+            "logs".to_string(),
+            vec![
+                logs(vec!["connection refused", "disk full"], vec![1, 2]),
+                logs(vec!["request timeout after 30s"], vec![3]),
+            ],
+        )]
+        .into_iter()
+        .collect();
+        let read_buffer = ReadBufferChunk::new("a", 1, tables.clone(), &["message".to_string()]);
+        let summary = read_buffer.summary();
+        assert!(summary.index_bytes > 0);
+        assert_eq!(summary.row_count, 3);
+
+        let like = |pattern: &str| ColumnPredicate {
+            column_name: "message".to_string(),
+            op: Comparison::Like,
+            value: Literal::String(pattern.to_string()),
+        };
+        assert!(read_buffer.could_match("logs", &[like("%timeout%")]));
+        assert!(!read_buffer.could_match("logs", &[like("%unreachable%")]));
+
+        let batches = read_buffer
+            .matching_table_to_arrow("logs", &["time"], &[like("%timeout%")])
+            .await
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 1);
+        assert_eq!(
+            read_buffer.table_to_arrow("logs", &[]).await.unwrap().len(),
+            2
+        );
+
+        // without indexed columns nothing is ruled out
+        let unindexed = ReadBufferChunk::new("a", 2, tables, &[]);
+        assert_eq!(unindexed.summary().index_bytes, 0);
+        assert!(unindexed.could_match("logs", &[like("%unreachable%")]));
+    }
+
+    #[tokio::test]
+    async fn merges_read_buffer_chunks() {
+        let cpu = |hosts: Vec<&str>, usage: Vec<f64>, times: Vec<i64>| {
+            batch(vec![
+                ("host", Arc::new(StringArray::from(hosts))),
+                ("usage", Arc::new(Float64Array::from(usage))),
+                ("time", Arc::new(Int64Array::from(times))),
+            ])
+        };
+        let chunk = |id, tables: Vec<(&str, RecordBatch)>| {
+            let tables = tables
+                .into_iter()
+                .map(|(name, batch)| (name.to_string(), vec![batch]))
+                .collect();
+            Arc::new(ReadBufferChunk::new("a", id, tables, &[]))
+        };
+        let chunks = vec![
+            chunk(
+                1,
+                vec![("cpu", cpu(vec!["b", "a"], vec![1.0, 2.0], vec![10, 10]))],
+            ),
+            // overwrites a point of the first chunk, and has another table
+            chunk(
+                3,
+                vec![
+                    ("cpu", cpu(vec!["a", "a"], vec![3.0, 4.0], vec![10, 5])),
+                    (
+                        "mem",
+                        batch(vec![("time", Arc::new(Int64Array::from(vec![7])))]),
+                    ),
+                ],
+            ),
+        ];
+
+        let merged = ReadBufferChunk::merge(&chunks, &["host".to_string()]).unwrap();
+        assert_eq!(merged.partition_key(), "a");
+        assert_eq!(merged.id(), 3);
+        assert_eq!(merged.loaded_at(), chunks[0].loaded_at());
+        assert_eq!(merged.table_names().await.unwrap(), vec!["cpu", "mem"]);
+        let summary = merged.summary();
+        assert_eq!(summary.row_count, 4);
+        assert!(summary.index_bytes > 0);
+
+        let expected = vec![
+            "+------+-------+------+",
+            "| host | usage | time |",
+            "+------+-------+------+",
+            "| a    | 4     | 5    |",
+            "| a    | 3     | 10   |",
+            "| b    | 1     | 10   |",
+            "+------+-------+------+",
+        ];
+        let batches = merged.table_to_arrow("cpu", &[]).await.unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().trim(),
+            expected.join("\n")
+        );
+
+        let other = Arc::new(ReadBufferChunk::new("b", 4, BTreeMap::new(), &[]));
+        let err = ReadBufferChunk::merge(&[Arc::clone(&chunks[0]), other], &[]).unwrap_err();
+        assert!(matches!(err, Error::MergingPartitions { .. }), "{}", err);
+        let err = ReadBufferChunk::merge(&[], &[]).unwrap_err();
+        assert!(matches!(err, Error::MergingPartitions { .. }), "{}", err);
+    }
+}
@@ -0,0 +1,161 @@
+//! This module contains the read-only replicas a server serves of the databases of another
+//! writer, from the configuration and the chunks the writer stored in the object store they
+//! share. Replicas answer queries, but reject writes and deletes.
+
+use std::sync::Mutex;
+
+use snafu::ensure;
+use tracing::warn;
+use write_buffer::Db as WriteBufferDb;
+
+use crate::{load_config, ConnectionManager, Db, ReplicaOfSelf, Result, Server};
+
+/// What a refresh of the replicas of the databases of a writer changed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReplicaRefresh {
+    /// The databases the writer created since the last refresh
+    pub added: Vec<String>,
+    /// The databases the writer released since the last refresh
+    pub removed: Vec<String>,
+    /// The number of persisted chunks the replicas serve
+    pub chunks: usize,
+}
+
+impl<M: ConnectionManager> Server<M> {
+    /// Makes this server a read-only replica of the databases of writer `owner_id`, from the
+    /// configuration the writer stored: queries are served over the chunks the catalogs of the
+    /// writer list, read from the object store this server shares with the writer. Databases
+    /// the writer created since the last refresh are added, those it released are removed, and
+    /// the rules and catalogs of the others replaced, so this is meant to be called
+    /// periodically. Databases this server owns are left alone, even if the writer has a
+    /// database of the same name. Writes and deletes to the replicas return `ReadOnlyReplica`.
+    pub async fn refresh_replicas(&mut self, owner_id: u32) -> Result<ReplicaRefresh> {
+        ensure!(
+            self.config.id != Some(owner_id),
+            ReplicaOfSelf { id: owner_id }
+        );
+        let owner = load_config(&self.store, owner_id).await?;
+        let mut refresh = ReplicaRefresh::default();
+
+        let released: Vec<_> = self
+            .config
+            .databases
+            .iter()
+            .filter(|(db_name, db)| {
+                db.replica_of == Some(owner_id) && !owner.databases.contains_key(*db_name)
+            })
+            .map(|(db_name, _)| db_name.clone())
+            .collect();
+        for db_name in released {
+            self.config.databases.remove(&db_name);
+            refresh.removed.push(db_name);
+        }
+
+        for (db_name, owned) in owner.databases {
+            let mut rules = owned.rules;
+            // queries are planned against the local buffer, which stays empty
+            rules.store_locally = true;
+            let catalog = owned.catalog.into_inner().expect("mutex poisoned");
+            refresh.chunks += catalog.chunks().len();
+
+            match self.config.databases.get_mut(&db_name) {
+                Some(db) if db.replica_of == Some(owner_id) => {
+                    if db.rules != rules {
+                        let buffer = WriteBufferDb::new(db_name.as_str());
+                        db.apply_rules(rules, Some(buffer), self.write_limits);
+                    }
+                    *db.catalog.lock().expect("mutex poisoned") = catalog;
+                }
+                Some(_) => warn!(
+                    db = db_name.as_str(),
+                    owner_id, "not replicating a database this server already has"
+                ),
+                None => {
+                    let buffer = WriteBufferDb::new(db_name.as_str());
+                    let mut db = Db::new(rules, Some(buffer), self.write_limits);
+                    db.catalog = Mutex::new(catalog);
+                    db.replica_of = Some(owner_id);
+                    self.config.databases.insert(db_name.clone(), db);
+                    refresh.added.push(db_name);
+                }
+            }
+        }
+
+        Ok(refresh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{parsed_lines, to_csv, Result, TestConnectionManager},
+        Error,
+    };
+    use data_types::database_rules::DatabaseRules;
+    use object_store::ObjectStore;
+
+    #[tokio::test]
+    async fn read_only_replicas() -> Result {
+        let dir = tempfile::tempdir()?;
+        let shared_store = || ObjectStore::new_file(object_store::File::new(dir.path()));
+        let mut writer = Server::new(TestConnectionManager::new(), shared_store());
+        writer.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        writer.create_database("foo", rules).await?;
+        writer
+            .write_lines("foo", &parsed_lines("cpu,host=a usage=0.1 10"))
+            .await?;
+        writer.persist_buffers().await?;
+
+        let mut replica = Server::new(TestConnectionManager::new(), shared_store());
+        replica.set_id(2);
+        let refresh = replica.refresh_replicas(1).await?;
+        assert_eq!(
+            refresh,
+            ReplicaRefresh {
+                added: vec!["foo".to_string()],
+                removed: vec![],
+                chunks: 1,
+            }
+        );
+        let query = "select host, usage from cpu order by host";
+        let results = replica.query_local("foo", query).await?;
+        assert_eq!(to_csv(&results), "host,usage\na,0.1\n");
+
+        let err = replica
+            .write_lines("foo", &parsed_lines("cpu,host=b usage=0.2 20"))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::ReadOnlyReplica { owner: 1, .. }),
+            "{}",
+            err
+        );
+
+        // the replica follows the chunks the writer persists, and the databases it releases
+        writer
+            .write_lines("foo", &parsed_lines("cpu,host=b usage=0.2 20"))
+            .await?;
+        writer.persist_buffers().await?;
+        let refresh = replica.refresh_replicas(1).await?;
+        assert!(refresh.added.is_empty());
+        assert_eq!(refresh.chunks, 2);
+        let results = replica.query_local("foo", query).await?;
+        assert_eq!(to_csv(&results), "host,usage\na,0.1\nb,0.2\n");
+
+        writer.release_database("foo").await?;
+        writer.store_configuration().await?;
+        let refresh = replica.refresh_replicas(1).await?;
+        assert_eq!(refresh.removed, vec!["foo"]);
+        assert!(replica.db_names().is_empty());
+
+        let err = replica.refresh_replicas(2).await.unwrap_err();
+        assert!(matches!(err, Error::ReplicaOfSelf { id: 2 }), "{}", err);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+//! This module contains how the server drops the chunks of its databases that only hold rows
+//! past their retention period, from every tier: the chunks of the mutable and read buffers,
+//! and the chunks persisted to object storage. Queries leave out the rows past the retention
+//! period before their chunks are dropped, see `boundary`.
+
+use std::convert::TryFrom;
+
+use chrono::{DateTime, Utc};
+use data_types::{chunk::ChunkSummary, database_rules::DatabaseRules};
+use snafu::ResultExt;
+
+use crate::{catalog::PersistedChunk, ConnectionManager, Result, Server, StoreError};
+
+impl<M: ConnectionManager> Server<M> {
+    /// Drops the persisted chunks of every database that only contain data older than the
+    /// database's retention period at `now`. The files of the chunks are deleted once the
+    /// configuration, which holds the catalog, is stored again. Returns the database name of
+    /// each chunk dropped.
+    pub async fn drop_expired_persisted_chunks(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(String, PersistedChunk)>> {
+        let mut dropped = vec![];
+
+        // the writer the replicas follow drops their chunks
+        let owned = self
+            .config
+            .databases
+            .iter()
+            .filter(|(_, db)| db.replica_of.is_none());
+        for (db_name, db) in owned {
+            let boundary = match boundary(&db.rules, now) {
+                Some(boundary) => boundary,
+                None => continue,
+            };
+            let expired = db
+                .catalog
+                .lock()
+                .expect("mutex poisoned")
+                .expired_chunks(boundary);
+            if expired.is_empty() {
+                continue;
+            }
+            let id = self.require_id()?;
+            self.verify_lease(id, db_name, db).await?;
+
+            let mut catalog = db.catalog.lock().expect("mutex poisoned");
+            for chunk in expired {
+                catalog.replace_chunk(chunk.id, None);
+                dropped.push((db_name.clone(), chunk));
+            }
+            catalog.drop_obsolete_tombstones();
+        }
+
+        if dropped.is_empty() {
+            return Ok(dropped);
+        }
+        self.store_configuration().await?;
+        for (_, chunk) in &dropped {
+            self.store
+                .delete(&chunk.location)
+                .await
+                .context(StoreError)?;
+        }
+        Ok(dropped)
+    }
+
+    /// Drops the chunks of the mutable and read buffers of every database that only contain
+    /// data older than the database's retention period, and the chunks of the read buffers
+    /// whose TTL elapsed, returning the database name and summary of each dropped chunk. The
+    /// persisted chunks are dropped by `drop_expired_persisted_chunks`.
+    pub async fn drop_expired_chunks(&self) -> Vec<(String, ChunkSummary)> {
+        let mut dropped = vec![];
+        let now = Utc::now();
+
+        for (db_name, db) in &self.config.databases {
+            if let Some(buffer) = &db.buffer {
+                for chunk in buffer.drop_expired_chunks().await {
+                    dropped.push((db_name.clone(), chunk));
+                }
+            }
+
+            let boundary = boundary(&db.rules, now);
+            let policies = db.chunk_policies.lock().expect("mutex poisoned");
+            if policies.is_empty() && boundary.is_none() {
+                continue;
+            }
+            db.read_buffer
+                .lock()
+                .expect("mutex poisoned")
+                .retain(|chunk| {
+                    let past_retention = match (chunk.max_time(), boundary) {
+                        (Some(max_time), Some(boundary)) => max_time < boundary,
+                        _ => false,
+                    };
+                    let expired = past_retention
+                        || policies.is_expired(
+                            chunk.partition_key(),
+                            chunk.id(),
+                            chunk.loaded_at(),
+                            now,
+                        );
+                    if expired {
+                        dropped.push((db_name.clone(), chunk.summary()));
+                    }
+                    !expired
+                });
+        }
+
+        dropped
+    }
+}
+
+/// Returns the timestamp, in nanoseconds, before which the data of a database with `rules` is
+/// past its retention period at `now`, if it has one
+pub(crate) fn boundary(rules: &DatabaseRules, now: DateTime<Utc>) -> Option<i64> {
+    let retention_period = rules.retention_period?;
+    let retention_nanos = i64::try_from(retention_period.as_nanos()).unwrap_or(i64::MAX);
+    Some(now.timestamp_nanos().saturating_sub(retention_nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{parsed_lines, Result, TestConnectionManager};
+    use arrow_deps::arrow::record_batch::RecordBatch;
+    use data_types::table_schema::DataType;
+    use ingest::import::{FileFormat, SchemaMapping, TimeUnit};
+    use object_store::{InMemory, ObjectStore};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn retention_across_tiers() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            retention_period: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+        let now = Utc::now();
+        let old = now - chrono::Duration::hours(2);
+
+        // a chunk of old rows moved to the read buffer
+        let lp = format!("cpu,host=a usage=0.1 {}", old.timestamp_nanos());
+        server.write_lines("foo", &parsed_lines(&lp)).await?;
+        let partition_key = server.chunk_summaries("foo").await?[0]
+            .partition_key
+            .clone();
+        let closed = server.close_chunk("foo", &partition_key).await?;
+        let moved = server.move_chunk("foo", &partition_key, closed.id).await?;
+
+        // recent rows in the mutable buffer
+        let lp = format!("cpu,host=b usage=0.2 {}", now.timestamp_nanos());
+        server.write_lines("foo", &parsed_lines(&lp)).await?;
+
+        // persisted chunks of old and recent rows, and of old rows only
+        let mapping = SchemaMapping {
+            table: "cpu".to_string(),
+            tags: vec!["host".to_string()],
+            fields: vec![("usage".to_string(), DataType::Float)],
+            time_column: "time".to_string(),
+            time_unit: TimeUnit::Seconds,
+        };
+        let data = format!(
+            "host,usage,time\nc,0.3,{}\nd,0.4,{}\n",
+            old.timestamp(),
+            now.timestamp()
+        );
+        let table = ingest::import::convert(FileFormat::Csv, data.into_bytes(), &mapping)?;
+        server.import_table("foo", &partition_key, table).await?;
+        let mapping = SchemaMapping {
+            table: "disk".to_string(),
+            ..mapping
+        };
+        let data = format!("host,usage,time\ne,0.5,{}\n", old.timestamp());
+        let table = ingest::import::convert(FileFormat::Csv, data.into_bytes(), &mapping)?;
+        server.import_table("foo", &partition_key, table).await?;
+
+        // queries leave out the old rows of every tier before their chunks are dropped
+        let results = server
+            .query_local("foo", "select host, usage from cpu order by host")
+            .await?;
+        let expected = vec![
+            "+------+-------+",
+            "| host | usage |",
+            "+------+-------+",
+            "| b    | 0.2   |",
+            "| d    | 0.4   |",
+            "+------+-------+",
+        ];
+        assert_eq!(
+            arrow_deps::arrow::util::pretty::pretty_format_batches(&results)?.trim(),
+            expected.join("\n")
+        );
+        let results = server.query_local("foo", "select host from disk").await?;
+        assert_eq!(results.iter().map(RecordBatch::num_rows).sum::<usize>(), 0);
+
+        // the chunks of the buffers and of object storage holding only old rows are dropped
+        let dropped = server.drop_expired_chunks().await;
+        assert_eq!(dropped, vec![("foo".to_string(), moved)]);
+        let dropped = server.drop_expired_persisted_chunks(Utc::now()).await?;
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].1.table_name, "disk");
+        let chunks = server.config.databases["foo"]
+            .catalog
+            .lock()
+            .expect("mutex poisoned")
+            .chunks();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].table_name, "cpu");
+        assert!(server.store.get(&dropped[0].1.location).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+//! This module keeps every version of the rules of a database in object storage. Each time the
+//! rules of a database are set, they are written as a new generation: an immutable object at
+//! `<writer id>/<db>/rules/<generation>.pb` holding the protobuf encoding of the rules. Earlier
+//! generations are never overwritten, so that a database can be rolled back to rules that
+//! worked after a bad update.
+//!
+//! A generation is written before the server applies the rules and stores its configuration,
+//! so the latest generation is the most recent rules of the database even if the server
+//! crashed before storing its configuration.
+
+use std::convert::TryInto;
+
+use bytes::Bytes;
+use data_types::database_rules::DatabaseRules;
+use futures::stream::TryStreamExt;
+use generated_types::management;
+use object_store::ObjectStore;
+use prost::Message;
+use snafu::ResultExt;
+
+use crate::{ErrorDecodingRules, InvalidStoredRules, Result, StoreError};
+
+/// A version of the rules of a database
+#[derive(Debug, Clone, PartialEq)]
+pub struct RulesVersion {
+    /// Increases by one with each version, starting from 1
+    pub generation: u64,
+    pub rules: DatabaseRules,
+}
+
+/// The location of generation `generation` of the rules of database `db_name`. The generation
+/// is zero padded so that the locations sort in the order of the generations.
+fn location(id: u32, db_name: &str, generation: u64) -> String {
+    format!("{}/{}/rules/{:020}.pb", id, db_name, generation)
+}
+
+/// The generation of the rules stored at `location`, if it is a location of rules
+fn parse_generation(location: &str) -> Option<u64> {
+    let name = location.rsplit('/').next()?;
+    if !name.ends_with(".pb") {
+        return None;
+    }
+    name[..name.len() - ".pb".len()].parse().ok()
+}
+
+/// Writes `rules` as generation `generation` of the rules of database `db_name`
+pub async fn store(
+    store: &ObjectStore,
+    id: u32,
+    db_name: &str,
+    generation: u64,
+    rules: &DatabaseRules,
+) -> Result<()> {
+    let mut proto: management::DatabaseRules = rules.clone().into();
+    proto.name = db_name.to_string();
+
+    let mut data = Vec::with_capacity(proto.encoded_len());
+    proto
+        .encode(&mut data)
+        .expect("encoding into a Vec never runs out of space");
+    let len = data.len();
+    let data = Bytes::from(data);
+
+    store
+        .put(
+            &location(id, db_name, generation),
+            futures::stream::once(async move { std::io::Result::Ok(data) }),
+            len,
+        )
+        .await
+        .context(StoreError)
+}
+
+/// Returns the generations of the rules of database `db_name` stored so far, oldest first
+pub async fn generations(store: &ObjectStore, id: u32, db_name: &str) -> Result<Vec<u64>> {
+    let prefix = format!("{}/{}/rules/", id, db_name);
+    let mut generations: Vec<_> = store
+        .list(Some(&prefix))
+        .await
+        .context(StoreError)?
+        .try_concat()
+        .await
+        .context(StoreError)?
+        .iter()
+        .filter_map(|location| parse_generation(location))
+        .collect();
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+/// Reads generation `generation` of the rules of database `db_name`
+pub async fn load(
+    store: &ObjectStore,
+    id: u32,
+    db_name: &str,
+    generation: u64,
+) -> Result<DatabaseRules> {
+    let location = location(id, db_name, generation);
+    let data = store
+        .get(&location)
+        .await
+        .context(StoreError)?
+        .map_ok(|b| bytes::BytesMut::from(&b[..]))
+        .try_concat()
+        .await
+        .context(StoreError)?;
+
+    let proto = management::DatabaseRules::decode(&data[..]).context(ErrorDecodingRules {
+        location: &location,
+    })?;
+    proto.try_into().context(InvalidStoredRules {
+        location: &location,
+    })
+}
+
+/// Reads every stored version of the rules of database `db_name`, oldest first
+pub async fn list(store: &ObjectStore, id: u32, db_name: &str) -> Result<Vec<RulesVersion>> {
+    let mut versions = vec![];
+    for generation in generations(store, id, db_name).await? {
+        let rules = load(store, id, db_name, generation).await?;
+        versions.push(RulesVersion { generation, rules });
+    }
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::InMemory;
+
+    #[test]
+    fn locations_sort_by_generation() {
+        assert_eq!(location(1, "foo", 2), "1/foo/rules/00000000000000000002.pb");
+        assert!(location(1, "foo", 9) < location(1, "foo", 10));
+        assert_eq!(parse_generation(&location(1, "foo", 10)), Some(10));
+        assert_eq!(parse_generation("1/foo/rules/latest.pb"), None);
+    }
+
+    #[tokio::test]
+    async fn store_and_list() -> Result<(), Box<dyn std::error::Error>> {
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let first = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        let second = DatabaseRules {
+            replication_count: 2,
+            ..Default::default()
+        };
+        self::store(&store, 1, "foo", 1, &first).await?;
+        self::store(&store, 1, "foo", 2, &second).await?;
+        self::store(&store, 1, "foobar", 1, &second).await?;
+
+        assert_eq!(generations(&store, 1, "foo").await?, vec![1, 2]);
+        assert_eq!(load(&store, 1, "foo", 1).await?, first);
+        assert_eq!(
+            list(&store, 1, "foo").await?,
+            vec![
+                RulesVersion {
+                    generation: 1,
+                    rules: first
+                },
+                RulesVersion {
+                    generation: 2,
+                    rules: second
+                },
+            ]
+        );
+        assert!(generations(&store, 2, "foo").await?.is_empty());
+
+        Ok(())
+    }
+}
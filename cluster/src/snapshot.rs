@@ -0,0 +1,150 @@
+//! This module contains the snapshots of databases, used to back a database up to another
+//! object store and to restore it on another server, such as in another region or environment.
+//!
+//! A snapshot of database `<db>` taken to prefix `<prefix>` is laid out as:
+//!
+//! ```text
+//! <prefix>/<db>/snapshot.json
+//! <prefix>/<db>/data/<partition key>/<chunk id>/<table>.parquet
+//! ```
+//!
+//! The manifest, `snapshot.json`, holds the rules of the database and its catalog as of when the
+//! snapshot was taken, with the locations of the chunks relative to the snapshot. It is written
+//! after all the Parquet files are copied, so that a snapshot interrupted halfway can't be
+//! restored. Restoring copies the files back under the writer id of the restoring server,
+//! checking them against their recorded sizes and checksums, and keeps the chunk ids and
+//! tombstones of the catalog.
+//!
+//! Only the chunks persisted to object storage are part of a snapshot: rows still buffered in
+//! memory are not.
+
+use bytes::Bytes;
+use data_types::database_rules::DatabaseRules;
+use object_store::ObjectStore;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt};
+
+use crate::{
+    catalog::{Catalog, PersistedChunk},
+    integrity, CorruptFile, ErrorDeserializing, ErrorSerializing, Result, StoreError,
+};
+
+/// The name of the manifest of a snapshot
+pub const MANIFEST: &str = "snapshot.json";
+
+/// The manifest of a snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The name of the database the snapshot was taken of
+    pub db_name: String,
+    /// The writer id of the server the snapshot was taken on
+    pub writer_id: u32,
+    pub rules: DatabaseRules,
+    /// The catalog of the database. The locations of the chunks are relative to the snapshot
+    /// in a manifest, and to the store of the server once restored.
+    pub catalog: Catalog,
+}
+
+/// The prefix of the snapshot of database `db_name` taken to `prefix`
+pub fn snapshot_prefix(prefix: &str, db_name: &str) -> String {
+    join(prefix, db_name)
+}
+
+/// The location of the file of `chunk` relative to a snapshot, or to the data directory of
+/// its database
+pub fn relative_location(chunk: &PersistedChunk) -> String {
+    format!(
+        "data/{}/{}/{}.parquet",
+        chunk.partition_key, chunk.id, chunk.table_name
+    )
+}
+
+/// `location` under `prefix`, if any
+pub fn join(prefix: &str, location: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        location.to_string()
+    } else {
+        format!("{}/{}", prefix, location)
+    }
+}
+
+/// Copies the file of `chunk` from `from` in `source` to `to` in `destination`, checking it
+/// against the size and checksum recorded for it on the way
+pub async fn copy_file(
+    source: &ObjectStore,
+    from: &str,
+    destination: &ObjectStore,
+    to: &str,
+    chunk: &PersistedChunk,
+) -> Result<()> {
+    let data = integrity::fetch(source, from).await.context(StoreError)?;
+    let status = integrity::check(chunk, &data);
+    ensure!(
+        !status.is_problem(),
+        CorruptFile {
+            location: from,
+            status
+        }
+    );
+
+    put(destination, to, Bytes::from(data)).await
+}
+
+/// Writes the manifest of a snapshot, under `prefix` in `store`
+pub async fn write_manifest(store: &ObjectStore, prefix: &str, snapshot: &Snapshot) -> Result<()> {
+    let data = serde_json::to_vec_pretty(snapshot).context(ErrorSerializing)?;
+    put(store, &join(prefix, MANIFEST), Bytes::from(data)).await
+}
+
+/// Reads the manifest of the snapshot under `prefix` in `store`
+pub async fn read_manifest(store: &ObjectStore, prefix: &str) -> Result<Snapshot> {
+    let data = integrity::fetch(store, &join(prefix, MANIFEST))
+        .await
+        .context(StoreError)?;
+    serde_json::from_slice(&data).context(ErrorDeserializing)
+}
+
+async fn put(store: &ObjectStore, location: &str, data: Bytes) -> Result<()> {
+    let len = data.len();
+    store
+        .put(
+            location,
+            futures::stream::once(async move { std::io::Result::Ok(data) }),
+            len,
+        )
+        .await
+        .context(StoreError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locations() {
+        let chunk = PersistedChunk {
+            partition_key: "2020-11-01T00".to_string(),
+            id: 4,
+            table_name: "cpu".to_string(),
+            location: "1/mydb/data/2020-11-01T00/4/cpu.parquet".to_string(),
+            row_count: 1,
+            size_bytes: 1,
+            min_time: None,
+            max_time: None,
+            sort_key: vec![],
+            checksum: None,
+            part_checksums: None,
+            columns: vec![],
+            overlaps: false,
+        };
+
+        assert_eq!(
+            relative_location(&chunk),
+            "data/2020-11-01T00/4/cpu.parquet"
+        );
+        assert_eq!(snapshot_prefix("backups/", "mydb"), "backups/mydb");
+        assert_eq!(snapshot_prefix("", "mydb"), "mydb");
+        assert_eq!(join("backups/mydb", MANIFEST), "backups/mydb/snapshot.json");
+    }
+}
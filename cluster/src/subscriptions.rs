@@ -0,0 +1,293 @@
+//! This module contains how the server forwards the writes to its databases to other servers:
+//! to the host groups of the replication rules of each database, and to those of its
+//! subscriptions. Clients subscribe to the rows written to a database by tailing it, see
+//! `tail`.
+
+use data_types::{
+    database_rules::{HostGroup, HostGroupId, MatchTables},
+    entry::Entry,
+};
+use snafu::{ensure, OptionExt, ResultExt};
+
+use crate::{
+    tail, ConnectionManager, DatabaseError, DatabaseNotFound, Db, ErrorReplicating,
+    HostGroupNotFound, NoHostInGroup, RemoteServer, Result, Server, TableNotAllowed,
+    UnableToGetConnection,
+};
+
+impl<M: ConnectionManager> Server<M> {
+    /// Creates a host group with a set of connection strings to hosts. These host connection
+    /// strings should be something that the connection manager can use to return a remote server
+    /// to work with.
+    pub async fn create_host_group(&mut self, id: HostGroupId, hosts: Vec<String>) -> Result<()> {
+        self.require_id()?;
+
+        self.config
+            .host_groups
+            .insert(id.clone(), HostGroup { id, hosts });
+
+        Ok(())
+    }
+
+    /// Subscribes to the rows written to the database from now on that match `filter`, as
+    /// described in `tail`. The subscription buffers up to `buffer_size` batches of rows before
+    /// it is evicted.
+    pub fn tail(
+        &self,
+        db_name: &str,
+        filter: tail::Filter,
+        buffer_size: usize,
+    ) -> Result<tail::Subscription> {
+        let db = self
+            .config
+            .databases
+            .get(db_name)
+            .context(DatabaseNotFound { db: db_name })?;
+        ensure!(
+            filter.access.allows_measurement(&filter.table_name),
+            TableNotAllowed {
+                db: db_name,
+                table: &filter.table_name,
+            }
+        );
+
+        Ok(db.tails.subscribe(filter, buffer_size))
+    }
+
+    async fn replicate_to_host_group(
+        &self,
+        host_group_id: &str,
+        db_name: &str,
+        entry: &Entry,
+    ) -> Result<()> {
+        let group = self
+            .config
+            .host_groups
+            .get(host_group_id)
+            .context(HostGroupNotFound { id: host_group_id })?;
+
+        let host = group
+            .hosts
+            .get(0)
+            .context(NoHostInGroup { id: host_group_id })?;
+
+        let connection = self
+            .connection_manager
+            .remote_server(host)
+            .await
+            .map_err(|e| Box::new(e) as DatabaseError)
+            .context(UnableToGetConnection { server: host })?;
+
+        connection
+            .replicate(db_name, entry)
+            .await
+            .map_err(|e| Box::new(e) as DatabaseError)
+            .context(ErrorReplicating {})?;
+
+        Ok(())
+    }
+
+    /// Replicates `entry`, written to the database, to the host groups of its replication
+    /// rules and of its subscriptions
+    pub(crate) async fn replicate(&self, db_name: &str, db: &Db, entry: &Entry) -> Result<()> {
+        for host_group_id in &db.rules.replication {
+            self.replicate_to_host_group(host_group_id, db_name, entry)
+                .await?;
+        }
+
+        for subscription in &db.rules.subscriptions {
+            match subscription.matcher.tables {
+                MatchTables::All => {
+                    self.replicate_to_host_group(&subscription.host_group_id, db_name, entry)
+                        .await?
+                }
+                MatchTables::Table(_) => unimplemented!(),
+                MatchTables::Regex(_) => unimplemented!(),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{parsed_lines, Result, TestConnectionManager, TestRemoteServer},
+        Error,
+    };
+    use data_types::database_rules::{DatabaseRules, Matcher, Subscription};
+    use influxdb_line_protocol::parse_lines;
+    use object_store::{InMemory, ObjectStore};
+    use std::{collections::BTreeMap, sync::Arc};
+    use storage::access::RowAccess;
+
+    #[tokio::test]
+    async fn replicate_to_single_group() -> Result {
+        let mut manager = TestConnectionManager::new();
+        let remote = Arc::new(TestRemoteServer::default());
+        let remote_id = "serverA";
+        manager
+            .remotes
+            .insert(remote_id.to_string(), remote.clone());
+
+        let store = ObjectStore::new_in_memory(InMemory::new());
+
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let host_group_id = "az1".to_string();
+        let rules = DatabaseRules {
+            replication: vec![host_group_id.clone()],
+            replication_count: 1,
+            ..Default::default()
+        };
+        server
+            .create_host_group(host_group_id.clone(), vec![remote_id.to_string()])
+            .await
+            .unwrap();
+        let db_name = "foo";
+        server.create_database(db_name, rules).await.unwrap();
+
+        let lines = parsed_lines("cpu bar=1 10");
+        server.write_lines("foo", &lines).await.unwrap();
+
+        let writes = remote.writes.lock().unwrap().get(db_name).unwrap().clone();
+
+        let write_text = r#"
+producer:1, sequence:1
+partition_key:
+  table:cpu
+    bar:1 time:10
+"#;
+
+        assert_eq!(write_text, writes[0].to_string());
+
+        // ensure sequence number goes up
+        let lines = parsed_lines("mem,server=A,region=west user=232 12");
+        server.write_lines("foo", &lines).await.unwrap();
+
+        let writes = remote.writes.lock().unwrap().get(db_name).unwrap().clone();
+        assert_eq!(2, writes.len());
+
+        let write_text = r#"
+producer:1, sequence:2
+partition_key:
+  table:mem
+    region:west server:A time:12 user:232
+"#;
+
+        assert_eq!(write_text, writes[1].to_string());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sends_all_to_subscriber() -> Result {
+        let mut manager = TestConnectionManager::new();
+        let remote = Arc::new(TestRemoteServer::default());
+        let remote_id = "serverA";
+        manager
+            .remotes
+            .insert(remote_id.to_string(), remote.clone());
+
+        let store = ObjectStore::new_in_memory(InMemory::new());
+
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let host_group_id = "az1".to_string();
+        let rules = DatabaseRules {
+            subscriptions: vec![Subscription {
+                name: "query_server_1".to_string(),
+                host_group_id: host_group_id.clone(),
+                matcher: Matcher {
+                    tables: MatchTables::All,
+                    predicate: None,
+                },
+            }],
+            ..Default::default()
+        };
+        server
+            .create_host_group(host_group_id.clone(), vec![remote_id.to_string()])
+            .await
+            .unwrap();
+        let db_name = "foo";
+        server.create_database(db_name, rules).await.unwrap();
+
+        let lines = parsed_lines("cpu bar=1 10");
+        server.write_lines("foo", &lines).await.unwrap();
+
+        let writes = remote.writes.lock().unwrap().get(db_name).unwrap().clone();
+
+        let write_text = r#"
+producer:1, sequence:1
+partition_key:
+  table:cpu
+    bar:1 time:10
+"#;
+
+        assert_eq!(write_text, writes[0].to_string());
+
+        // ensure sequence number goes up
+        let lines = parsed_lines("mem,server=A,region=west user=232 12");
+        server.write_lines("foo", &lines).await.unwrap();
+
+        let writes = remote.writes.lock().unwrap().get(db_name).unwrap().clone();
+        assert_eq!(2, writes.len());
+
+        let write_text = r#"
+producer:1, sequence:2
+partition_key:
+  table:mem
+    region:west server:A time:12 user:232
+"#;
+
+        assert_eq!(write_text, writes[1].to_string());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tails_new_rows() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        server
+            .create_database("foo", DatabaseRules::default())
+            .await?;
+
+        let filter = tail::Filter {
+            table_name: "cpu".to_string(),
+            tags: vec![("host".to_string(), "a".to_string())]
+                .into_iter()
+                .collect(),
+            access: RowAccess::default(),
+        };
+        let mut subscription = server.tail("foo", filter, 10)?;
+
+        let lines: Vec<_> = parse_lines("cpu,host=a usage=0.5 10\ncpu,host=b usage=0.7 20")
+            .map(|l| l.unwrap())
+            .collect();
+        server.write_lines("foo", &lines).await?;
+
+        let points = subscription.points.recv().await.unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].time, 10);
+        assert_eq!(
+            points[0].fields.get("usage"),
+            Some(&tail::FieldValue::F64(0.5))
+        );
+
+        let access = RowAccess::new(&["mem".to_string()], &BTreeMap::new());
+        let filter = tail::Filter {
+            table_name: "cpu".to_string(),
+            access,
+            ..Default::default()
+        };
+        let err = server.tail("foo", filter, 10).unwrap_err();
+        assert!(matches!(err, Error::TableNotAllowed { .. }));
+
+        Ok(())
+    }
+}
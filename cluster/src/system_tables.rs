@@ -0,0 +1,506 @@
+//! This module contains the virtual tables of the `system` schema, which describe the state of
+//! a database and of the server it lives on. They are built on demand and can be queried with
+//! SQL like any other table, for example `SELECT * FROM system.chunks`.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use arrow_deps::arrow::{
+    array::{ArrayRef, BooleanArray, Int64Array, StringArray, UInt32Array, UInt64Array},
+    datatypes::{DataType, Field, Schema},
+    error::Result,
+    record_batch::RecordBatch,
+};
+use data_types::chunk::{ChunkStorage, ChunkSummary, ColumnSummary};
+
+use crate::{
+    audit::AuditEvent,
+    tasks::{Task, TaskRun},
+    tracker::{Tracker, TrackerStatus},
+    wal_replay::WalReplay,
+    write_stats::TableWrites,
+};
+
+/// The schema the system tables are named in, which takes precedence over a database of the
+/// same name in the tables of queries
+pub const SCHEMA: &str = "system";
+
+/// The chunks of the database, with their storage tier and size
+pub const CHUNKS: &str = "system.chunks";
+/// The columns of each table in each chunk of the database, with their statistics and size
+pub const COLUMNS: &str = "system.columns";
+/// The background operations of the server
+pub const OPERATIONS: &str = "system.operations";
+/// The continuous query tasks of the server
+pub const TASKS: &str = "system.tasks";
+/// The recent runs of the tasks of the server, with their outcome
+pub const TASK_RUNS: &str = "system.task_runs";
+/// The recent audit events of the database and of the server as a whole
+pub const AUDIT_LOG: &str = "system.audit_log";
+/// The rows and bytes written to each table of the database since the server started
+pub const TABLES: &str = "system.tables";
+/// The rows and bytes written to each table of each partition of the database since the
+/// server started
+pub const PARTITIONS: &str = "system.partitions";
+/// How far the last replay of the WAL of each database of the server got, and how many
+/// entries it is behind by
+pub const WAL_REPLAY: &str = "system.wal_replay";
+
+/// Builds all of the system tables, keyed by table name
+#[allow(clippy::too_many_arguments)]
+pub fn build(
+    chunks: &[ChunkSummary],
+    columns: &[ColumnSummary],
+    operations: &[Tracker],
+    tasks: &[Task],
+    runs: &[TaskRun],
+    audit_events: &[AuditEvent],
+    table_writes: &[(String, TableWrites)],
+    partition_writes: &[(String, String, TableWrites)],
+    wal_replays: &[(String, WalReplay)],
+) -> Result<BTreeMap<String, Vec<RecordBatch>>> {
+    let mut tables = BTreeMap::new();
+    tables.insert(CHUNKS.to_string(), vec![chunks_batch(chunks)?]);
+    tables.insert(COLUMNS.to_string(), vec![columns_batch(columns)?]);
+    tables.insert(OPERATIONS.to_string(), vec![operations_batch(operations)?]);
+    tables.insert(TASKS.to_string(), vec![tasks_batch(tasks)?]);
+    tables.insert(TASK_RUNS.to_string(), vec![task_runs_batch(runs)?]);
+    tables.insert(AUDIT_LOG.to_string(), vec![audit_log_batch(audit_events)?]);
+    tables.insert(TABLES.to_string(), vec![tables_batch(table_writes)?]);
+    tables.insert(
+        PARTITIONS.to_string(),
+        vec![partitions_batch(partition_writes)?],
+    );
+    tables.insert(WAL_REPLAY.to_string(), vec![wal_replay_batch(wal_replays)?]);
+    Ok(tables)
+}
+
+fn chunks_batch(chunks: &[ChunkSummary]) -> Result<RecordBatch> {
+    let schema = Schema::new(vec![
+        Field::new("partition_key", DataType::Utf8, false),
+        Field::new("id", DataType::UInt32, false),
+        Field::new("storage", DataType::Utf8, false),
+        Field::new("estimated_bytes", DataType::UInt64, false),
+        Field::new("row_count", DataType::UInt64, false),
+        Field::new("index_bytes", DataType::UInt64, false),
+        Field::new("index_build_micros", DataType::UInt64, false),
+    ]);
+
+    let partition_key = StringArray::from(
+        chunks
+            .iter()
+            .map(|c| c.partition_key.as_str())
+            .collect::<Vec<_>>(),
+    );
+    let id = UInt32Array::from(chunks.iter().map(|c| c.id).collect::<Vec<_>>());
+    let storage = StringArray::from(
+        chunks
+            .iter()
+            .map(|c| storage_name(c.storage))
+            .collect::<Vec<_>>(),
+    );
+    let estimated_bytes = UInt64Array::from(
+        chunks
+            .iter()
+            .map(|c| c.estimated_bytes as u64)
+            .collect::<Vec<_>>(),
+    );
+    let row_count = UInt64Array::from(
+        chunks
+            .iter()
+            .map(|c| c.row_count as u64)
+            .collect::<Vec<_>>(),
+    );
+    let index_bytes = UInt64Array::from(
+        chunks
+            .iter()
+            .map(|c| c.index_bytes as u64)
+            .collect::<Vec<_>>(),
+    );
+    let index_build_micros = UInt64Array::from(
+        chunks
+            .iter()
+            .map(|c| c.index_build_micros)
+            .collect::<Vec<_>>(),
+    );
+
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            Arc::new(partition_key) as ArrayRef,
+            Arc::new(id),
+            Arc::new(storage),
+            Arc::new(estimated_bytes),
+            Arc::new(row_count),
+            Arc::new(index_bytes),
+            Arc::new(index_build_micros),
+        ],
+    )
+}
+
+fn columns_batch(columns: &[ColumnSummary]) -> Result<RecordBatch> {
+    let schema = Schema::new(vec![
+        Field::new("partition_key", DataType::Utf8, false),
+        Field::new("chunk_id", DataType::UInt32, false),
+        Field::new("table_name", DataType::Utf8, false),
+        Field::new("column_name", DataType::Utf8, false),
+        Field::new("column_type", DataType::Utf8, false),
+        Field::new("encoding", DataType::Utf8, false),
+        Field::new("count", DataType::UInt64, false),
+        Field::new("null_count", DataType::UInt64, false),
+        Field::new("distinct_count", DataType::UInt64, false),
+        Field::new("min_value", DataType::Utf8, false),
+        Field::new("max_value", DataType::Utf8, false),
+        Field::new("estimated_bytes", DataType::UInt64, false),
+    ]);
+
+    let strings = |f: fn(&ColumnSummary) -> &str| {
+        StringArray::from(columns.iter().map(f).collect::<Vec<_>>())
+    };
+
+    let partition_key = strings(|c| c.partition_key.as_str());
+    let chunk_id = UInt32Array::from(columns.iter().map(|c| c.chunk_id).collect::<Vec<_>>());
+    let table_name = strings(|c| c.table_name.as_str());
+    let column_name = strings(|c| c.column_name.as_str());
+    let column_type = strings(|c| c.column_type.as_str());
+    let encoding = strings(|c| c.encoding.as_str());
+    let counts = |f: fn(&ColumnSummary) -> u32| {
+        UInt64Array::from(columns.iter().map(|c| u64::from(f(c))).collect::<Vec<_>>())
+    };
+
+    let count = counts(|c| c.count);
+    let null_count = counts(|c| c.null_count);
+    let distinct_count = counts(|c| c.distinct_count);
+    let min_value = strings(|c| c.min_value.as_str());
+    let max_value = strings(|c| c.max_value.as_str());
+    let estimated_bytes = UInt64Array::from(
+        columns
+            .iter()
+            .map(|c| c.estimated_bytes as u64)
+            .collect::<Vec<_>>(),
+    );
+
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            Arc::new(partition_key) as ArrayRef,
+            Arc::new(chunk_id),
+            Arc::new(table_name),
+            Arc::new(column_name),
+            Arc::new(column_type),
+            Arc::new(encoding),
+            Arc::new(count),
+            Arc::new(null_count),
+            Arc::new(distinct_count),
+            Arc::new(min_value),
+            Arc::new(max_value),
+            Arc::new(estimated_bytes),
+        ],
+    )
+}
+
+fn operations_batch(operations: &[Tracker]) -> Result<RecordBatch> {
+    let schema = Schema::new(vec![
+        Field::new("id", DataType::UInt64, false),
+        Field::new("description", DataType::Utf8, false),
+        Field::new("status", DataType::Utf8, false),
+        Field::new("error", DataType::Utf8, true),
+        Field::new("completed", DataType::UInt64, false),
+        Field::new("total", DataType::UInt64, false),
+    ]);
+
+    let errors: Vec<_> = operations.iter().map(Tracker::error).collect();
+
+    let id = UInt64Array::from(operations.iter().map(Tracker::id).collect::<Vec<_>>());
+    let description = StringArray::from(
+        operations
+            .iter()
+            .map(Tracker::description)
+            .collect::<Vec<_>>(),
+    );
+    let status = StringArray::from(
+        operations
+            .iter()
+            .map(|t| status_name(t.status()))
+            .collect::<Vec<_>>(),
+    );
+    let error = StringArray::from(errors.iter().map(|e| e.as_deref()).collect::<Vec<_>>());
+    let completed = UInt64Array::from(
+        operations
+            .iter()
+            .map(|t| t.progress().completed() as u64)
+            .collect::<Vec<_>>(),
+    );
+    let total = UInt64Array::from(
+        operations
+            .iter()
+            .map(|t| t.progress().total() as u64)
+            .collect::<Vec<_>>(),
+    );
+
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            Arc::new(id) as ArrayRef,
+            Arc::new(description),
+            Arc::new(status),
+            Arc::new(error),
+            Arc::new(completed),
+            Arc::new(total),
+        ],
+    )
+}
+
+fn tasks_batch(tasks: &[Task]) -> Result<RecordBatch> {
+    let schema = Schema::new(vec![
+        Field::new("name", DataType::Utf8, false),
+        Field::new("db_name", DataType::Utf8, false),
+        Field::new("query", DataType::Utf8, false),
+        Field::new("every_ns", DataType::UInt64, false),
+        Field::new("destination_db", DataType::Utf8, false),
+        Field::new("destination_table", DataType::Utf8, false),
+        Field::new("paused", DataType::Boolean, false),
+    ]);
+
+    let strings = |f: fn(&Task) -> &str| StringArray::from(tasks.iter().map(f).collect::<Vec<_>>());
+
+    let name = strings(|t| t.name.as_str());
+    let db_name = strings(|t| t.db_name.as_str());
+    let query = strings(|t| t.query.as_str());
+    let every_ns = UInt64Array::from(
+        tasks
+            .iter()
+            .map(|t| t.every.as_nanos() as u64)
+            .collect::<Vec<_>>(),
+    );
+    let destination_db = strings(|t| t.destination_db.as_str());
+    let destination_table = strings(|t| t.destination_table.as_str());
+    let paused = BooleanArray::from(tasks.iter().map(|t| t.paused).collect::<Vec<_>>());
+
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            Arc::new(name) as ArrayRef,
+            Arc::new(db_name),
+            Arc::new(query),
+            Arc::new(every_ns),
+            Arc::new(destination_db),
+            Arc::new(destination_table),
+            Arc::new(paused),
+        ],
+    )
+}
+
+fn task_runs_batch(runs: &[TaskRun]) -> Result<RecordBatch> {
+    let schema = Schema::new(vec![
+        Field::new("task", DataType::Utf8, false),
+        Field::new("window_start", DataType::Int64, false),
+        Field::new("window_end", DataType::Int64, false),
+        Field::new("started_at", DataType::Utf8, false),
+        Field::new("duration_ns", DataType::UInt64, false),
+        Field::new("rows", DataType::UInt64, false),
+        Field::new("error", DataType::Utf8, true),
+    ]);
+
+    let started_at: Vec<_> = runs.iter().map(|r| r.started_at.to_rfc3339()).collect();
+
+    let task = StringArray::from(runs.iter().map(|r| r.task.as_str()).collect::<Vec<_>>());
+    let window_start = Int64Array::from(runs.iter().map(|r| r.window.0).collect::<Vec<_>>());
+    let window_end = Int64Array::from(runs.iter().map(|r| r.window.1).collect::<Vec<_>>());
+    let started_at = StringArray::from(started_at.iter().map(String::as_str).collect::<Vec<_>>());
+    let duration_ns = UInt64Array::from(
+        runs.iter()
+            .map(|r| r.duration.as_nanos() as u64)
+            .collect::<Vec<_>>(),
+    );
+    let rows = UInt64Array::from(runs.iter().map(|r| r.rows as u64).collect::<Vec<_>>());
+    let error = StringArray::from(runs.iter().map(|r| r.error.as_deref()).collect::<Vec<_>>());
+
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            Arc::new(task) as ArrayRef,
+            Arc::new(window_start),
+            Arc::new(window_end),
+            Arc::new(started_at),
+            Arc::new(duration_ns),
+            Arc::new(rows),
+            Arc::new(error),
+        ],
+    )
+}
+
+fn audit_log_batch(events: &[AuditEvent]) -> Result<RecordBatch> {
+    let schema = Schema::new(vec![
+        Field::new("time", DataType::Utf8, false),
+        Field::new("actor", DataType::Utf8, false),
+        Field::new("operation", DataType::Utf8, false),
+        Field::new("db_name", DataType::Utf8, true),
+        Field::new("payload_sha256", DataType::Utf8, false),
+        Field::new("error", DataType::Utf8, true),
+    ]);
+
+    let times: Vec<_> = events.iter().map(|e| e.time.to_rfc3339()).collect();
+
+    let strings =
+        |f: fn(&AuditEvent) -> &str| StringArray::from(events.iter().map(f).collect::<Vec<_>>());
+    let optional_strings = |f: fn(&AuditEvent) -> Option<&str>| {
+        StringArray::from(events.iter().map(f).collect::<Vec<_>>())
+    };
+
+    let time = StringArray::from(times.iter().map(String::as_str).collect::<Vec<_>>());
+    let actor = strings(|e| e.actor.as_str());
+    let operation = strings(|e| e.operation.as_str());
+    let db_name = optional_strings(|e| e.db_name.as_deref());
+    let payload_sha256 = strings(|e| e.payload_sha256.as_str());
+    let error = optional_strings(|e| e.error.as_deref());
+
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            Arc::new(time) as ArrayRef,
+            Arc::new(actor),
+            Arc::new(operation),
+            Arc::new(db_name),
+            Arc::new(payload_sha256),
+            Arc::new(error),
+        ],
+    )
+}
+
+fn tables_batch(tables: &[(String, TableWrites)]) -> Result<RecordBatch> {
+    let (names, writes): (Vec<_>, Vec<_>) = tables
+        .iter()
+        .map(|(table_name, writes)| (table_name.as_str(), writes))
+        .unzip();
+    let mut fields = vec![Field::new("table_name", DataType::Utf8, false)];
+    fields.extend(writes_fields());
+    let mut columns = vec![Arc::new(StringArray::from(names)) as ArrayRef];
+    columns.extend(writes_columns(&writes));
+
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+}
+
+fn partitions_batch(partitions: &[(String, String, TableWrites)]) -> Result<RecordBatch> {
+    let partition_key = StringArray::from(
+        partitions
+            .iter()
+            .map(|(partition_key, _, _)| partition_key.as_str())
+            .collect::<Vec<_>>(),
+    );
+    let table_name = StringArray::from(
+        partitions
+            .iter()
+            .map(|(_, table_name, _)| table_name.as_str())
+            .collect::<Vec<_>>(),
+    );
+    let writes: Vec<_> = partitions.iter().map(|(_, _, writes)| writes).collect();
+
+    let mut fields = vec![
+        Field::new("partition_key", DataType::Utf8, false),
+        Field::new("table_name", DataType::Utf8, false),
+    ];
+    fields.extend(writes_fields());
+    let mut columns = vec![Arc::new(partition_key) as ArrayRef, Arc::new(table_name)];
+    columns.extend(writes_columns(&writes));
+
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+}
+
+fn wal_replay_batch(replays: &[(String, WalReplay)]) -> Result<RecordBatch> {
+    let schema = Schema::new(vec![
+        Field::new("db_name", DataType::Utf8, false),
+        Field::new("replayed", DataType::UInt64, false),
+        Field::new("skipped", DataType::UInt64, false),
+        Field::new("next_sequence_number", DataType::UInt64, false),
+        Field::new("end_sequence_number", DataType::UInt64, false),
+        Field::new("lag", DataType::UInt64, false),
+        Field::new("duration_ns", DataType::UInt64, false),
+        Field::new("error", DataType::Utf8, true),
+    ]);
+
+    let counts = |f: fn(&WalReplay) -> u64| {
+        UInt64Array::from(replays.iter().map(|(_, r)| f(r)).collect::<Vec<_>>())
+    };
+
+    let db_name = StringArray::from(
+        replays
+            .iter()
+            .map(|(db_name, _)| db_name.as_str())
+            .collect::<Vec<_>>(),
+    );
+    let replayed = counts(|r| r.progress.replayed as u64);
+    let skipped = counts(|r| r.progress.skipped as u64);
+    let next_sequence_number = counts(|r| r.progress.next_sequence_number);
+    let end_sequence_number = counts(|r| r.progress.end_sequence_number);
+    let lag = counts(|r| r.progress.lag());
+    let duration_ns = counts(|r| r.duration.as_nanos() as u64);
+    let error = StringArray::from(
+        replays
+            .iter()
+            .map(|(_, r)| r.error.as_deref())
+            .collect::<Vec<_>>(),
+    );
+
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            Arc::new(db_name) as ArrayRef,
+            Arc::new(replayed),
+            Arc::new(skipped),
+            Arc::new(next_sequence_number),
+            Arc::new(end_sequence_number),
+            Arc::new(lag),
+            Arc::new(duration_ns),
+            Arc::new(error),
+        ],
+    )
+}
+
+/// The fields of `system.tables` and `system.partitions` that describe the writes to a table
+fn writes_fields() -> Vec<Field> {
+    vec![
+        Field::new("rows_written", DataType::UInt64, false),
+        Field::new("bytes_written", DataType::UInt64, false),
+        Field::new("last_write", DataType::Utf8, true),
+        Field::new("write_errors", DataType::UInt64, false),
+    ]
+}
+
+fn writes_columns(writes: &[&TableWrites]) -> Vec<ArrayRef> {
+    let last_writes: Vec<_> = writes
+        .iter()
+        .map(|w| w.last_write.map(|time| time.to_rfc3339()))
+        .collect();
+    let counts = |f: fn(&TableWrites) -> u64| {
+        Arc::new(UInt64Array::from(
+            writes.iter().map(|w| f(w)).collect::<Vec<_>>(),
+        )) as ArrayRef
+    };
+
+    vec![
+        counts(|w| w.rows),
+        counts(|w| w.bytes),
+        Arc::new(StringArray::from(
+            last_writes.iter().map(Option::as_deref).collect::<Vec<_>>(),
+        )),
+        counts(|w| w.errors),
+    ]
+}
+
+fn storage_name(storage: ChunkStorage) -> &'static str {
+    match storage {
+        ChunkStorage::OpenMutableBuffer => "OpenMutableBuffer",
+        ChunkStorage::ClosedMutableBuffer => "ClosedMutableBuffer",
+        ChunkStorage::ReadBuffer => "ReadBuffer",
+        ChunkStorage::ObjectStore => "ObjectStore",
+    }
+}
+
+fn status_name(status: TrackerStatus) -> &'static str {
+    match status {
+        TrackerStatus::Running => "Running",
+        TrackerStatus::Success => "Success",
+        TrackerStatus::Failure => "Failure",
+        TrackerStatus::Cancelled => "Cancelled",
+    }
+}
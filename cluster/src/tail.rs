@@ -0,0 +1,306 @@
+//! This module contains the subscriptions to the rows written to a database, which stream the
+//! new rows of a measurement with some tag values to clients as they are written, for alerting
+//! and live dashboards.
+//!
+//! The rows of each write are matched against the filter of every subscription of the database
+//! once the write is stored, and sent to the subscription as one batch. A subscription buffers
+//! a bounded number of batches: a subscriber that doesn't keep up is evicted once its buffer is
+//! full, rather than holding up writes or buffering without bound, and can tell it was evicted
+//! from the end of its stream.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use data_types::entry::{ColumnValues, Entry, LogicalColumnType};
+use storage::access::RowAccess;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::info;
+
+/// The batches of rows a subscription buffers, unless it asks otherwise
+pub const DEFAULT_BUFFER_SIZE: usize = 1024;
+
+/// The most batches of rows a subscription may buffer
+pub const MAX_BUFFER_SIZE: usize = 65_536;
+
+/// The value of a field of a row
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    String(String),
+}
+
+/// A row written to a database
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub table_name: String,
+    /// The timestamp of the row, in nanoseconds since the epoch
+    pub time: i64,
+    pub tags: BTreeMap<String, String>,
+    pub fields: BTreeMap<String, FieldValue>,
+}
+
+/// The rows a subscription receives
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub table_name: String,
+    /// The rows must have all of these tag values
+    pub tags: BTreeMap<String, String>,
+    /// The rows the subscriber may access
+    pub access: RowAccess,
+}
+
+/// The receiving end of a subscription
+#[derive(Debug)]
+pub struct Subscription {
+    /// The batches of rows matching the filter of the subscription, which end once the
+    /// subscription is evicted
+    pub points: mpsc::Receiver<Vec<Point>>,
+    /// Set once the subscription is evicted for not keeping up
+    pub evicted: Arc<AtomicBool>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    filter: Filter,
+    sender: mpsc::Sender<Vec<Point>>,
+    evicted: Arc<AtomicBool>,
+}
+
+/// The subscriptions to the rows written to a database
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl Subscriptions {
+    /// Subscribes to the rows matching `filter`, buffering up to `buffer_size` batches of rows
+    /// before the subscription is evicted
+    pub fn subscribe(&self, filter: Filter, buffer_size: usize) -> Subscription {
+        let (sender, points) = mpsc::channel(buffer_size.max(1).min(MAX_BUFFER_SIZE));
+        let evicted = Arc::new(AtomicBool::new(false));
+        self.subscribers
+            .lock()
+            .expect("mutex poisoned")
+            .push(Subscriber {
+                filter,
+                sender,
+                evicted: Arc::clone(&evicted),
+            });
+        Subscription { points, evicted }
+    }
+
+    /// The number of active subscriptions
+    pub fn len(&self) -> usize {
+        self.subscribers.lock().expect("mutex poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sends the rows of `entry` to the subscriptions they match. Subscriptions whose buffer
+    /// is full are evicted, and those whose subscriber is gone are dropped.
+    pub fn publish(&self, db_name: &str, entry: &Entry) {
+        let mut subscribers = self.subscribers.lock().expect("mutex poisoned");
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let mut kept = Vec::with_capacity(subscribers.len());
+        for mut subscriber in subscribers.drain(..) {
+            let points = matching_points(entry, &subscriber.filter);
+            if points.is_empty() {
+                kept.push(subscriber);
+                continue;
+            }
+            match subscriber.sender.try_send(points) {
+                Ok(()) => kept.push(subscriber),
+                Err(TrySendError::Full(_)) => {
+                    info!(
+                        "evicting subscriber to {} of database {} that is not keeping up",
+                        subscriber.filter.table_name, db_name
+                    );
+                    subscriber.evicted.store(true, Ordering::SeqCst);
+                    metrics::registry()
+                        .counter(
+                            "cluster_tail_subscribers_evicted_total",
+                            "Subscribers to new rows evicted because their buffer was full",
+                            &[],
+                        )
+                        .inc();
+                }
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+        *subscribers = kept;
+    }
+}
+
+/// The rows of `entry` that match `filter`
+fn matching_points(entry: &Entry, filter: &Filter) -> Vec<Point> {
+    if !filter.access.allows_measurement(&filter.table_name) {
+        return vec![];
+    }
+
+    let mut points = vec![];
+    for write in entry.partition_writes() {
+        for batch in write.table_batches() {
+            if batch.name() != filter.table_name {
+                continue;
+            }
+
+            let columns: Vec<_> = batch
+                .columns()
+                .into_iter()
+                .map(|column| (column.name(), column.logical_type(), column.values()))
+                .collect();
+            for row in 0..batch.row_count() {
+                let mut point = Point {
+                    table_name: filter.table_name.clone(),
+                    time: 0,
+                    tags: BTreeMap::new(),
+                    fields: BTreeMap::new(),
+                };
+                for (name, logical_type, values) in &columns {
+                    match (*logical_type, values) {
+                        (LogicalColumnType::Time, ColumnValues::I64(values)) => {
+                            point.time = values[row].unwrap_or_default()
+                        }
+                        (LogicalColumnType::Tag, ColumnValues::String(values)) => {
+                            if let Some(value) = values[row] {
+                                point.tags.insert(name.to_string(), value.to_string());
+                            }
+                        }
+                        (LogicalColumnType::Field, values) => {
+                            if let Some(value) = field_value(values, row) {
+                                point.fields.insert(name.to_string(), value);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+
+                let matches = filter
+                    .tags
+                    .iter()
+                    .all(|(key, value)| point.tags.get(key) == Some(value));
+                if matches && filter.access.allows_point(&point.table_name, &point.tags) {
+                    points.push(point);
+                }
+            }
+        }
+    }
+    points
+}
+
+fn field_value(values: &ColumnValues<'_>, row: usize) -> Option<FieldValue> {
+    match values {
+        ColumnValues::I64(values) => values[row].map(FieldValue::I64),
+        ColumnValues::F64(values) => values[row].map(FieldValue::F64),
+        ColumnValues::Bool(values) => values[row].map(FieldValue::Bool),
+        ColumnValues::String(values) => values[row].map(|value| FieldValue::String(value.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_types::{database_rules::DatabaseRules, entry::lines_to_entry};
+    use influxdb_line_protocol::parse_lines;
+
+    fn entry(lines: &str) -> Entry {
+        let lines: Vec<_> = parse_lines(lines).map(|l| l.unwrap()).collect();
+        lines_to_entry(1, 1, &lines, &DatabaseRules::default()).unwrap()
+    }
+
+    fn filter(table_name: &str, tags: &[(&str, &str)]) -> Filter {
+        Filter {
+            table_name: table_name.to_string(),
+            tags: tags
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            access: RowAccess::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_matching_points() {
+        let subscriptions = Subscriptions::default();
+        let mut cpu_a = subscriptions.subscribe(filter("cpu", &[("host", "a")]), 10);
+        let mut mem = subscriptions.subscribe(filter("mem", &[]), 10);
+
+        subscriptions.publish(
+            "foo",
+            &entry("cpu,host=a usage=0.5,cores=4i 10\ncpu,host=b usage=0.7 20\nmem free=3 30"),
+        );
+
+        let points = cpu_a.points.recv().await.unwrap();
+        let expected = Point {
+            table_name: "cpu".to_string(),
+            time: 10,
+            tags: vec![("host".to_string(), "a".to_string())]
+                .into_iter()
+                .collect(),
+            fields: vec![
+                ("cores".to_string(), FieldValue::I64(4)),
+                ("usage".to_string(), FieldValue::F64(0.5)),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        assert_eq!(points, vec![expected]);
+        assert_eq!(mem.points.recv().await.unwrap()[0].time, 30);
+
+        // subscriptions whose subscriber is gone are dropped
+        drop(mem);
+        subscriptions.publish("foo", &entry("mem free=4 40"));
+        assert_eq!(subscriptions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn evicts_slow_subscribers() {
+        let subscriptions = Subscriptions::default();
+        let mut slow = subscriptions.subscribe(filter("cpu", &[]), 2);
+        for time in 0..3 {
+            subscriptions.publish("foo", &entry(&format!("cpu usage=0.5 {}", time)));
+        }
+
+        assert!(subscriptions.is_empty());
+        assert!(slow.evicted.load(Ordering::SeqCst));
+        // the buffered rows are still received before the end of the stream
+        assert_eq!(slow.points.recv().await.unwrap()[0].time, 0);
+        assert_eq!(slow.points.recv().await.unwrap()[0].time, 1);
+        assert!(slow.points.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn respects_access() {
+        let subscriptions = Subscriptions::default();
+        let tags = vec![("tenant".to_string(), "acme".to_string())]
+            .into_iter()
+            .collect();
+        let mut tenant = subscriptions.subscribe(
+            Filter {
+                access: RowAccess::new(&[], &tags),
+                ..filter("cpu", &[])
+            },
+            10,
+        );
+
+        subscriptions.publish(
+            "foo",
+            &entry("cpu,tenant=other usage=0.1 10\ncpu,tenant=acme usage=0.2 20"),
+        );
+        let points = tenant.points.recv().await.unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].time, 20);
+    }
+}
@@ -0,0 +1,564 @@
+//! This module contains continuous queries: tasks that periodically run a query against a
+//! database and write its results into a table, such as to downsample raw 10s points into 5m
+//! rollups that are kept for longer.
+//!
+//! Each run covers the window of `every` before the time it runs at, aligned to the epoch,
+//! whose bounds in nanoseconds replace `$start` and `$end` in the query, as in
+//! `... WHERE time >= $start AND time < $end`. The result rows are written as line protocol:
+//! string columns become tags, the `time` column the timestamp and the other columns fields.
+//!
+//! The tasks are part of the configuration of the server. Their recent runs are kept in
+//! memory, and can be queried from the `system.tasks` and `system.task_runs` tables.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use arrow_deps::arrow::{
+    array::{Array, ArrayRef, BooleanArray, Float64Array, Int64Array, StringArray, UInt64Array},
+    datatypes::DataType,
+    record_batch::RecordBatch,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+use tracing::{info_span, warn};
+use tracing_futures::Instrument;
+
+use crate::{
+    ConnectionManager, DatabaseNotFound, InvalidTask, ParsingTaskResults, Result, Server,
+    TaskAlreadyExists, TaskNotFound,
+};
+
+/// The number of runs kept in the history of the tasks of a server
+pub const MAX_RUNS: usize = 1000;
+
+/// A continuous query
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Task {
+    pub name: String,
+    /// The database the query runs against
+    pub db_name: String,
+    /// The SQL query, in which `$start` and `$end` are replaced by the bounds of the window
+    /// of the run
+    pub query: String,
+    /// How often the task runs, which is also the width of the windows
+    pub every: Duration,
+    /// The database the results are written to
+    pub destination_db: String,
+    /// The table the results are written to
+    pub destination_table: String,
+    /// Paused tasks don't run until they are resumed
+    #[serde(default)]
+    pub paused: bool,
+}
+
+impl Task {
+    /// Returns the window a run at `now` covers, as nanoseconds since the epoch, the end
+    /// excluded
+    pub fn window(&self, now: DateTime<Utc>) -> (i64, i64) {
+        let every = self.every.as_nanos().min(i64::MAX as u128).max(1) as i64;
+        let end = now.timestamp_nanos().div_euclid(every) * every;
+        (end.saturating_sub(every), end)
+    }
+
+    /// Returns the query of the run covering `window`
+    pub fn query_for(&self, (start, end): (i64, i64)) -> String {
+        self.query
+            .replace("$start", &start.to_string())
+            .replace("$end", &end.to_string())
+    }
+}
+
+/// A run of a task
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskRun {
+    pub task: String,
+    /// The window the run covered
+    pub window: (i64, i64),
+    pub started_at: DateTime<Utc>,
+    pub duration: Duration,
+    /// The number of rows written
+    pub rows: usize,
+    /// The error the run failed with, if any
+    pub error: Option<String>,
+}
+
+/// The recent runs of the tasks of a server, and the last window each task covered
+#[derive(Debug, Default)]
+pub struct TaskHistory {
+    runs: Mutex<VecDeque<TaskRun>>,
+    last_windows: Mutex<BTreeMap<String, i64>>,
+}
+
+impl TaskHistory {
+    /// Returns true if `task` hasn't covered the window ending at `end` yet
+    pub fn is_due(&self, task: &str, end: i64) -> bool {
+        self.last_windows
+            .lock()
+            .expect("mutex poisoned")
+            .get(task)
+            .map_or(true, |&last| last < end)
+    }
+
+    /// Records a run, dropping the oldest runs past `MAX_RUNS`
+    pub fn record(&self, run: TaskRun) {
+        self.last_windows
+            .lock()
+            .expect("mutex poisoned")
+            .insert(run.task.clone(), run.window.1);
+
+        let mut runs = self.runs.lock().expect("mutex poisoned");
+        runs.push_back(run);
+        while runs.len() > MAX_RUNS {
+            runs.pop_front();
+        }
+    }
+
+    /// Forgets the runs of a deleted task
+    pub fn remove(&self, task: &str) {
+        self.last_windows
+            .lock()
+            .expect("mutex poisoned")
+            .remove(task);
+        self.runs
+            .lock()
+            .expect("mutex poisoned")
+            .retain(|run| run.task != task);
+    }
+
+    /// Returns the recorded runs, oldest first
+    pub fn runs(&self) -> Vec<TaskRun> {
+        self.runs
+            .lock()
+            .expect("mutex poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Converts the rows of `batches` to line protocol for `table`. Rows without any field value
+/// are skipped.
+pub fn to_line_protocol(table: &str, batches: &[RecordBatch]) -> String {
+    let mut lines = String::new();
+    for batch in batches {
+        let schema = batch.schema();
+        for row in 0..batch.num_rows() {
+            let mut tags = String::new();
+            let mut fields = String::new();
+            let mut time = None;
+
+            for (field, column) in schema.fields().iter().zip(batch.columns()) {
+                if column.is_null(row) {
+                    continue;
+                }
+                let name = escape(field.name(), &[',', '=', ' ']);
+                match column.data_type() {
+                    DataType::Int64 if field.name() == "time" => {
+                        time = downcast::<Int64Array>(column).map(|a| a.value(row));
+                    }
+                    DataType::Utf8 => {
+                        if let Some(value) = downcast::<StringArray>(column) {
+                            let value = escape(value.value(row), &[',', '=', ' ']);
+                            write!(tags, ",{}={}", name, value).expect("writing to a string");
+                        }
+                    }
+                    data_type => {
+                        if let Some(value) = field_value(column, data_type, row) {
+                            let separator = if fields.is_empty() { "" } else { "," };
+                            write!(fields, "{}{}={}", separator, name, value)
+                                .expect("writing to a string");
+                        }
+                    }
+                }
+            }
+
+            if fields.is_empty() {
+                continue;
+            }
+            lines.push_str(&escape(table, &[',', ' ']));
+            lines.push_str(&tags);
+            lines.push(' ');
+            lines.push_str(&fields);
+            if let Some(time) = time {
+                write!(lines, " {}", time).expect("writing to a string");
+            }
+            lines.push('\n');
+        }
+    }
+    lines
+}
+
+fn downcast<T: 'static>(column: &ArrayRef) -> Option<&T> {
+    column.as_any().downcast_ref::<T>()
+}
+
+/// Returns the value of `row` of a field column as line protocol
+fn field_value(column: &ArrayRef, data_type: &DataType, row: usize) -> Option<String> {
+    match data_type {
+        DataType::Float64 => downcast::<Float64Array>(column)
+            .map(|a| a.value(row))
+            .filter(|v| v.is_finite())
+            .map(|v| v.to_string()),
+        DataType::Int64 => downcast::<Int64Array>(column).map(|a| format!("{}i", a.value(row))),
+        // line protocol has no unsigned integers
+        DataType::UInt64 => downcast::<UInt64Array>(column)
+            .map(|a| a.value(row))
+            .filter(|&v| v <= i64::MAX as u64)
+            .map(|v| format!("{}i", v)),
+        DataType::Boolean => downcast::<BooleanArray>(column).map(|a| a.value(row).to_string()),
+        _ => None,
+    }
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl<M: ConnectionManager> Server<M> {
+    /// Adds a continuous query, see `tasks`. The results are written to the database of the
+    /// query if the task has no destination database.
+    pub fn create_task(&mut self, mut task: Task) -> Result<()> {
+        self.require_id()?;
+
+        let invalid = |reason: &'static str| InvalidTask {
+            name: &task.name,
+            reason,
+        };
+        ensure!(!task.name.is_empty(), invalid("the name is required"));
+        ensure!(!task.query.is_empty(), invalid("the query is required"));
+        ensure!(
+            !task.destination_table.is_empty(),
+            invalid("the destination table is required")
+        );
+        ensure!(
+            task.every > Duration::from_secs(0),
+            invalid("tasks must run every period longer than zero")
+        );
+        ensure!(
+            !self.config.tasks.contains_key(&task.name),
+            TaskAlreadyExists { name: &task.name }
+        );
+
+        if task.destination_db.is_empty() {
+            task.destination_db = task.db_name.clone();
+        }
+        for db_name in &[&task.db_name, &task.destination_db] {
+            ensure!(
+                self.config.databases.contains_key(db_name.as_str()),
+                DatabaseNotFound {
+                    db: db_name.as_str()
+                }
+            );
+        }
+
+        self.config.tasks.insert(task.name.clone(), task);
+        Ok(())
+    }
+
+    /// Pauses or resumes a task. A resumed task runs for the latest window only, not for the
+    /// windows that passed while it was paused.
+    pub fn set_task_paused(&mut self, name: &str, paused: bool) -> Result<()> {
+        self.require_id()?;
+
+        let task = self
+            .config
+            .tasks
+            .get_mut(name)
+            .context(TaskNotFound { name })?;
+        task.paused = paused;
+        Ok(())
+    }
+
+    /// Removes a task, and the history of its runs
+    pub fn delete_task(&mut self, name: &str) -> Result<Task> {
+        self.require_id()?;
+
+        let task = self
+            .config
+            .tasks
+            .remove(name)
+            .context(TaskNotFound { name })?;
+        self.task_history.remove(name);
+        Ok(task)
+    }
+
+    /// Returns the tasks of the server, ordered by name
+    pub fn tasks(&self) -> Vec<Task> {
+        self.config.tasks.values().cloned().collect()
+    }
+
+    /// Returns the recent runs of the tasks, oldest first
+    pub fn task_runs(&self) -> Vec<TaskRun> {
+        self.task_history.runs()
+    }
+
+    /// Runs each task that isn't paused and hasn't covered the latest window before `now`,
+    /// and returns the runs. A run that fails is recorded with its error but not retried: the
+    /// next run covers the next window.
+    pub async fn run_due_tasks(&self, now: DateTime<Utc>) -> Vec<TaskRun> {
+        let mut runs = vec![];
+        for task in self.config.tasks.values().filter(|task| !task.paused) {
+            let window = task.window(now);
+            if !self.task_history.is_due(&task.name, window.1) {
+                continue;
+            }
+
+            let started = Instant::now();
+            let result = self
+                .run_task(task, window)
+                .instrument(info_span!("task", name = task.name.as_str()))
+                .await;
+            let run = TaskRun {
+                task: task.name.clone(),
+                window,
+                started_at: now,
+                duration: started.elapsed(),
+                rows: *result.as_ref().unwrap_or(&0),
+                error: result.err().map(|e| e.to_string()),
+            };
+
+            let status = if run.error.is_some() {
+                warn!(task = task.name.as_str(), error = ?run.error, "task failed");
+                "failure"
+            } else {
+                "success"
+            };
+            metrics::registry()
+                .counter(
+                    "cluster_task_runs_total",
+                    "Runs of continuous query tasks",
+                    &[("status", status)],
+                )
+                .inc();
+
+            self.task_history.record(run.clone());
+            runs.push(run);
+        }
+        runs
+    }
+
+    /// Runs the query of `task` for `window` and writes its results, returning the number of
+    /// rows written
+    async fn run_task(&self, task: &Task, window: (i64, i64)) -> Result<usize> {
+        let batches = self
+            .query_local(&task.db_name, &task.query_for(window))
+            .await?;
+        let lines = to_line_protocol(&task.destination_table, &batches);
+        let lines = influxdb_line_protocol::parse_lines(&lines)
+            .collect::<Result<Vec<_>, _>>()
+            .context(ParsingTaskResults { name: &task.name })?;
+        if !lines.is_empty() {
+            self.write_lines(&task.destination_db, &lines).await?;
+        }
+        Ok(lines.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{parsed_lines, to_csv, Result, TestConnectionManager},
+        Error,
+    };
+    use arrow_deps::arrow::datatypes::{Field, Schema};
+    use data_types::database_rules::DatabaseRules;
+    use object_store::{InMemory, ObjectStore, ObjectStoreIntegration};
+    use std::sync::Arc;
+
+    #[test]
+    fn windows() {
+        let task = Task {
+            name: "rollup".to_string(),
+            db_name: "foo".to_string(),
+            query: "SELECT * FROM cpu WHERE time >= $start AND time < $end".to_string(),
+            every: Duration::from_secs(300),
+            destination_db: "foo".to_string(),
+            destination_table: "cpu_5m".to_string(),
+            paused: false,
+        };
+
+        let now = DateTime::parse_from_rfc3339("2020-11-01T12:07:30Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let window = task.window(now);
+        assert_eq!(
+            window,
+            (1_604_232_000_000_000_000, 1_604_232_300_000_000_000)
+        );
+        assert_eq!(
+            task.query_for(window),
+            "SELECT * FROM cpu WHERE time >= 1604232000000000000 AND time < 1604232300000000000"
+        );
+
+        let history = TaskHistory::default();
+        assert!(history.is_due("rollup", window.1));
+        history.record(TaskRun {
+            task: "rollup".to_string(),
+            window,
+            started_at: now,
+            duration: Duration::from_millis(1),
+            rows: 0,
+            error: None,
+        });
+        assert!(!history.is_due("rollup", window.1));
+        assert!(history.is_due("rollup", window.1 + 1));
+        assert_eq!(history.runs().len(), 1);
+
+        history.remove("rollup");
+        assert!(history.runs().is_empty());
+    }
+
+    #[test]
+    fn line_protocol() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("host", DataType::Utf8, true),
+            Field::new("mean usage", DataType::Float64, true),
+            Field::new("count", DataType::Int64, true),
+            Field::new("time", DataType::Int64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec![Some("a,b"), None, Some("c")])),
+                Arc::new(Float64Array::from(vec![Some(0.5), Some(2.0), None])),
+                Arc::new(Int64Array::from(vec![Some(3), Some(4), None])),
+                Arc::new(Int64Array::from(vec![10, 20, 30])),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            to_line_protocol("cpu 5m", &[batch]),
+            "cpu\\ 5m,host=a\\,b mean\\ usage=0.5,count=3i 10\n\
+             cpu\\ 5m mean\\ usage=2,count=4i 20\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn continuous_queries() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        server.create_database("foo", rules).await?;
+
+        // 12:01, 12:02 and 12:03, then 12:06 past the window of the run at 12:07:30
+        let lines = parsed_lines(
+            "cpu,host=a usage=1 1604232060000000000
+             cpu,host=a usage=2 1604232120000000000
+             cpu,host=b usage=4 1604232180000000000
+             cpu,host=b usage=8 1604232360000000000",
+        );
+        server.write_lines("foo", &lines).await?;
+
+        let task = Task {
+            name: "rollup".to_string(),
+            db_name: "foo".to_string(),
+            query: "select date_bin(time, '5m', '', '') as time, host, count(usage) as samples \
+                    from cpu where time >= $start and time < $end \
+                    group by date_bin(time, '5m', '', ''), host"
+                .to_string(),
+            every: Duration::from_secs(300),
+            destination_db: "".to_string(),
+            destination_table: "cpu_5m".to_string(),
+            paused: false,
+        };
+        server.create_task(task.clone())?;
+        server.create_task(Task {
+            name: "broken".to_string(),
+            query: "not sql".to_string(),
+            ..task.clone()
+        })?;
+
+        let err = server.create_task(task.clone()).unwrap_err();
+        assert!(matches!(err, Error::TaskAlreadyExists { .. }), "{}", err);
+        let err = server
+            .create_task(Task {
+                name: "other".to_string(),
+                destination_db: "bar".to_string(),
+                ..task.clone()
+            })
+            .unwrap_err();
+        assert!(matches!(err, Error::DatabaseNotFound { .. }), "{}", err);
+        let err = server
+            .create_task(Task {
+                name: "other".to_string(),
+                every: Duration::from_secs(0),
+                ..task.clone()
+            })
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidTask { .. }), "{}", err);
+
+        let now = DateTime::parse_from_rfc3339("2020-11-01T12:07:30Z")?.with_timezone(&Utc);
+        let runs = server.run_due_tasks(now).await;
+        assert_eq!(runs.len(), 2);
+        assert!(runs[0].error.is_some());
+        assert_eq!(runs[1].task, "rollup");
+        assert_eq!(runs[1].rows, 2);
+        assert_eq!(runs[1].error, None);
+
+        let results = server
+            .query_local(
+                "foo",
+                "select host, samples, time from cpu_5m order by host",
+            )
+            .await?;
+        assert_eq!(
+            to_csv(&results),
+            "host,samples,time\n\
+             a,2,1604232000000000000\n\
+             b,1,1604232000000000000\n"
+        );
+
+        // the window was covered already, and paused tasks don't run
+        assert!(server.run_due_tasks(now).await.is_empty());
+        server.set_task_paused("rollup", true)?;
+        server.delete_task("broken")?;
+        let later = now + chrono::Duration::minutes(5);
+        assert!(server.run_due_tasks(later).await.is_empty());
+
+        let results = server
+            .query_local("foo", "select task, rows from system.task_runs")
+            .await?;
+        assert_eq!(to_csv(&results), "task,rows\nrollup,2\n");
+
+        // the tasks are part of the configuration
+        server.store_configuration().await?;
+        let store = match &server.store.0 {
+            ObjectStoreIntegration::InMemory(in_mem) => in_mem.clone().await,
+            _ => panic!("wrong type"),
+        };
+        let mut restarted = Server::new(
+            TestConnectionManager::new(),
+            ObjectStore::new_in_memory(store),
+        );
+        restarted.load_configuration(1).await?;
+        assert_eq!(
+            restarted.tasks(),
+            vec![Task {
+                destination_db: "foo".to_string(),
+                paused: true,
+                ..task
+            }]
+        );
+
+        Ok(())
+    }
+}
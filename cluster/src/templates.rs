@@ -0,0 +1,272 @@
+//! This module contains the rules templates of the server: named rules that the rules of
+//! databases can take the fields they don't override from, see `DatabaseRules::template`.
+//! Changes to a template apply to the databases whose rules name it.
+
+use data_types::database_rules::DatabaseRules;
+use snafu::{ensure, OptionExt, ResultExt};
+
+use crate::{
+    ApplyingTemplate, ConnectionManager, InvalidTemplate, Result, Server, TemplateAlreadyExists,
+    TemplateInUse, TemplateNotFound,
+};
+
+impl<M: ConnectionManager> Server<M> {
+    /// Adds a rules template, which the rules of databases can name to take their fields from
+    /// it, see `DatabaseRules::template`
+    pub fn create_rules_template(&mut self, name: &str, rules: DatabaseRules) -> Result<()> {
+        self.require_id()?;
+
+        ensure!(
+            !name.is_empty(),
+            InvalidTemplate {
+                name,
+                reason: "the name is required"
+            }
+        );
+        ensure!(
+            rules.template.is_none(),
+            InvalidTemplate {
+                name,
+                reason: "templates can't inherit from other templates"
+            }
+        );
+        ensure!(
+            !self.config.templates.contains_key(name),
+            TemplateAlreadyExists { name }
+        );
+
+        self.config.templates.insert(name.to_string(), rules);
+        Ok(())
+    }
+
+    /// Replaces the rules of a template, and applies them to each database whose rules
+    /// name it, apart from the fields they override, as `update_database_rules` does. The
+    /// read-only replicas follow the rules of the writer instead. Returns the databases whose
+    /// rules changed, along with their new generation.
+    pub async fn update_rules_template(
+        &mut self,
+        name: &str,
+        rules: DatabaseRules,
+    ) -> Result<Vec<(String, u64)>> {
+        self.require_id()?;
+
+        ensure!(
+            rules.template.is_none(),
+            InvalidTemplate {
+                name,
+                reason: "templates can't inherit from other templates"
+            }
+        );
+        let template = self
+            .config
+            .templates
+            .get_mut(name)
+            .context(TemplateNotFound { name })?;
+        *template = rules;
+
+        let inheriting: Vec<_> = self
+            .config
+            .databases
+            .iter()
+            .filter(|(_, db)| db.replica_of.is_none() && db.template_name() == Some(name))
+            .map(|(db_name, db)| (db_name.clone(), db.rules.clone()))
+            .collect();
+
+        let mut updated = vec![];
+        for (db_name, db_rules) in inheriting {
+            let rules = self.inherit_template(db_rules.clone())?;
+            if rules != db_rules {
+                let generation = self.update_database_rules(&db_name, rules).await?;
+                updated.push((db_name, generation));
+            }
+        }
+        Ok(updated)
+    }
+
+    /// Removes a template that no database's rules name
+    pub fn delete_rules_template(&mut self, name: &str) -> Result<DatabaseRules> {
+        self.require_id()?;
+
+        ensure!(
+            self.config.templates.contains_key(name),
+            TemplateNotFound { name }
+        );
+        if let Some((db_name, _)) = self
+            .config
+            .databases
+            .iter()
+            .find(|(_, db)| db.template_name() == Some(name))
+        {
+            return TemplateInUse { name, db: db_name }.fail();
+        }
+
+        Ok(self
+            .config
+            .templates
+            .remove(name)
+            .expect("the template exists"))
+    }
+
+    /// Returns the rules templates of the server, ordered by name
+    pub fn rules_templates(&self) -> Vec<(String, DatabaseRules)> {
+        self.config
+            .templates
+            .iter()
+            .map(|(name, rules)| (name.clone(), rules.clone()))
+            .collect()
+    }
+
+    /// Returns `rules` with the fields they don't override taken from the template they name,
+    /// if any
+    pub(crate) fn inherit_template(&self, rules: DatabaseRules) -> Result<DatabaseRules> {
+        let name = match &rules.template {
+            Some(template) => &template.name,
+            None => return Ok(rules),
+        };
+        let template = self
+            .config
+            .templates
+            .get(name)
+            .context(TemplateNotFound { name })?;
+
+        rules.inherit(template).context(ApplyingTemplate { name })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{Result, TestConnectionManager},
+        Error,
+    };
+    use data_types::database_rules::TemplateRef;
+    use object_store::{InMemory, ObjectStore, ObjectStoreIntegration};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn rules_templates() -> Result {
+        let manager = TestConnectionManager::new();
+        let store = ObjectStore::new_in_memory(InMemory::new());
+        let mut server = Server::new(manager, store);
+        server.set_id(1);
+
+        let template = DatabaseRules {
+            store_locally: true,
+            retention_period: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        server.create_rules_template("tenant", template.clone())?;
+        let err = server
+            .create_rules_template("tenant", template.clone())
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::TemplateAlreadyExists { .. }),
+            "{}",
+            err
+        );
+
+        // the databases take the fields they don't override from the template
+        let template_ref = TemplateRef {
+            name: "tenant".to_string(),
+            overrides: vec!["retention_period".to_string()],
+        };
+        let rules = DatabaseRules {
+            retention_period: Some(Duration::from_secs(60)),
+            template: Some(template_ref.clone()),
+            ..Default::default()
+        };
+        server.create_database("foo", rules.clone()).await?;
+        server
+            .create_database(
+                "bar",
+                DatabaseRules {
+                    template: Some(TemplateRef {
+                        name: "tenant".to_string(),
+                        overrides: vec![],
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        server
+            .create_database("other", DatabaseRules::default())
+            .await?;
+        let foo_rules = DatabaseRules {
+            retention_period: Some(Duration::from_secs(60)),
+            template: Some(template_ref.clone()),
+            ..template.clone()
+        };
+        assert_eq!(server.db_rules("foo"), Some(&foo_rules));
+        assert_eq!(
+            server.db_rules("bar").unwrap().retention_period,
+            template.retention_period
+        );
+
+        // the changes of the template apply to the databases created from it
+        let template = DatabaseRules {
+            dedup_window: Some(Duration::from_secs(300)),
+            ..template
+        };
+        let updated = server
+            .update_rules_template("tenant", template.clone())
+            .await?;
+        assert_eq!(
+            updated,
+            vec![("bar".to_string(), 2), ("foo".to_string(), 2)]
+        );
+        assert_eq!(
+            server.db_rules("foo"),
+            Some(&DatabaseRules {
+                dedup_window: Some(Duration::from_secs(300)),
+                ..foo_rules
+            })
+        );
+        assert_eq!(server.db_rules("other"), Some(&DatabaseRules::default()));
+        assert!(server
+            .update_rules_template("tenant", template.clone())
+            .await?
+            .is_empty());
+        assert_eq!(
+            server.rules_templates(),
+            vec![("tenant".to_string(), template)]
+        );
+
+        // templates are stored with the configuration
+        server.store_configuration().await?;
+        let store = match server.store.0 {
+            ObjectStoreIntegration::InMemory(in_mem) => in_mem.clone().await,
+            _ => panic!("wrong type"),
+        };
+        let mut recovered_server = Server::new(
+            TestConnectionManager::new(),
+            ObjectStore::new_in_memory(store),
+        );
+        recovered_server.load_configuration(1).await?;
+        assert_eq!(recovered_server.rules_templates(), server.rules_templates());
+
+        let err = server
+            .create_database(
+                "baz",
+                DatabaseRules {
+                    template: Some(TemplateRef {
+                        name: "missing".to_string(),
+                        overrides: vec![],
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::TemplateNotFound { .. }), "{}", err);
+
+        let err = server.delete_rules_template("tenant").unwrap_err();
+        assert!(matches!(err, Error::TemplateInUse { .. }), "{}", err);
+        server.release_database("foo").await?;
+        server.release_database("bar").await?;
+        server.delete_rules_template("tenant")?;
+        assert!(server.rules_templates().is_empty());
+
+        Ok(())
+    }
+}
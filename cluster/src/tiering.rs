@@ -0,0 +1,210 @@
+//! This module contains the policy deciding which chunks to evict from the read buffer when a
+//! database uses more memory than its buffer size. The persisted chunks that queries read are
+//! reloaded into the read buffer from their Parquet files, and only those can be evicted, as
+//! they can be reloaded again when next queried. The least recently accessed chunks are
+//! evicted first.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{catalog::PersistedChunk, query_chunk::ReadBufferChunk};
+
+/// What the policy needs to know about a chunk held in the read buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResidentChunk {
+    pub partition_key: String,
+    pub id: u32,
+    /// The memory used by the chunk in the read buffer, in bytes
+    pub size_bytes: usize,
+    /// When the chunk was last queried, in nanoseconds since the epoch
+    pub last_access: i64,
+    /// Whether the chunk has been persisted to object storage
+    pub persisted: bool,
+}
+
+/// Chooses the chunks to evict so that the read buffer uses at most `max_bytes`. Only persisted
+/// chunks are candidates, least recently accessed first. If evicting every persisted chunk is
+/// not enough, all of them are returned.
+pub fn select_victims(chunks: &[ResidentChunk], max_bytes: usize) -> Vec<&ResidentChunk> {
+    let mut used: usize = chunks.iter().map(|c| c.size_bytes).sum();
+    if used <= max_bytes {
+        return vec![];
+    }
+
+    let mut candidates: Vec<_> = chunks.iter().filter(|c| c.persisted).collect();
+    candidates.sort_by_key(|c| c.last_access);
+
+    let mut victims = vec![];
+    for chunk in candidates {
+        if used <= max_bytes {
+            break;
+        }
+        used -= chunk.size_bytes;
+        victims.push(chunk);
+    }
+
+    victims
+}
+
+/// The persisted chunks of a database reloaded into its read buffer, by id
+#[derive(Debug, Default)]
+pub struct ReloadedChunks {
+    chunks: BTreeMap<u32, ReloadedChunk>,
+}
+
+#[derive(Debug)]
+struct ReloadedChunk {
+    /// The location of the Parquet file the rows were reloaded from
+    location: String,
+    rows: Arc<ReadBufferChunk>,
+    /// When the chunk was last queried, in nanoseconds since the epoch
+    last_access: i64,
+}
+
+impl ReloadedChunks {
+    /// Returns the rows of `chunk` if they were reloaded, recording that they were accessed
+    /// at `now`
+    pub fn get(&mut self, chunk: &PersistedChunk, now: i64) -> Option<Arc<ReadBufferChunk>> {
+        let reloaded = self.chunks.get_mut(&chunk.id)?;
+        if reloaded.location != chunk.location {
+            return None;
+        }
+        reloaded.last_access = now;
+        Some(Arc::clone(&reloaded.rows))
+    }
+
+    /// Records that the rows of `chunk` were reloaded at `now`
+    pub fn insert(&mut self, chunk: &PersistedChunk, rows: Arc<ReadBufferChunk>, now: i64) {
+        self.chunks.insert(
+            chunk.id,
+            ReloadedChunk {
+                location: chunk.location.clone(),
+                rows,
+                last_access: now,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.chunks.remove(&id);
+    }
+
+    /// Drops the rows of the chunks that are no longer among the persisted chunks `chunks`,
+    /// such as those compacted or past the retention period
+    pub fn retain(&mut self, chunks: &[PersistedChunk]) {
+        self.chunks.retain(|id, reloaded| {
+            chunks
+                .iter()
+                .any(|chunk| chunk.id == *id && chunk.location == reloaded.location)
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+    }
+
+    /// The memory used by the reloaded rows, in bytes
+    pub fn bytes(&self) -> usize {
+        self.chunks
+            .values()
+            .map(|reloaded| reloaded.rows.summary().estimated_bytes)
+            .sum()
+    }
+
+    /// The reloaded chunks, as candidates for eviction
+    pub fn resident(&self) -> Vec<ResidentChunk> {
+        self.chunks
+            .iter()
+            .map(|(id, reloaded)| {
+                let summary = reloaded.rows.summary();
+                ResidentChunk {
+                    partition_key: summary.partition_key,
+                    id: *id,
+                    size_bytes: summary.estimated_bytes,
+                    last_access: reloaded.last_access,
+                    persisted: true,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Records that `chunk` was evicted from the read buffer of database `db_name`
+pub fn record_eviction(db_name: &str, chunk: &ResidentChunk) {
+    let labels = [("db_name", db_name)];
+    metrics::registry()
+        .counter(
+            "cluster_read_buffer_evicted_chunks_total",
+            "Persisted chunks evicted from the read buffer",
+            &labels,
+        )
+        .inc();
+    metrics::registry()
+        .counter(
+            "cluster_read_buffer_evicted_bytes_total",
+            "Bytes of the persisted chunks evicted from the read buffer",
+            &labels,
+        )
+        .add(chunk.size_bytes as u64);
+}
+
+/// Records that a persisted chunk of database `db_name` was reloaded into the read buffer from
+/// its Parquet file to answer a query
+pub fn record_reload(db_name: &str) {
+    metrics::registry()
+        .counter(
+            "cluster_read_buffer_reloaded_chunks_total",
+            "Persisted chunks reloaded into the read buffer from their Parquet files",
+            &[("db_name", db_name)],
+        )
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: u32, size_bytes: usize, last_access: i64, persisted: bool) -> ResidentChunk {
+        ResidentChunk {
+            partition_key: "p1".to_string(),
+            id,
+            size_bytes,
+            last_access,
+            persisted,
+        }
+    }
+
+    fn ids(victims: Vec<&ResidentChunk>) -> Vec<u32> {
+        victims.into_iter().map(|c| c.id).collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_accessed_persisted_chunks() {
+        let chunks = vec![
+            chunk(0, 100, 10, true),
+            chunk(1, 100, 5, false),
+            chunk(2, 100, 20, true),
+            chunk(3, 100, 30, true),
+        ];
+
+        assert!(select_victims(&chunks, 400).is_empty());
+        assert_eq!(ids(select_victims(&chunks, 300)), vec![0]);
+        assert_eq!(ids(select_victims(&chunks, 150)), vec![0, 2, 3]);
+        // unpersisted chunks are never evicted, even if over the limit
+        assert_eq!(ids(select_victims(&chunks, 0)), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn metrics() {
+        let db_name = "tiering_metrics";
+        record_eviction(db_name, &chunk(0, 100, 10, true));
+        record_eviction(db_name, &chunk(1, 50, 10, true));
+        record_reload(db_name);
+
+        let labels = [("db_name", db_name)];
+        let registry = metrics::registry();
+        let counter = |name| registry.counter(name, "", &labels).get();
+        assert_eq!(counter("cluster_read_buffer_evicted_chunks_total"), 2);
+        assert_eq!(counter("cluster_read_buffer_evicted_bytes_total"), 150);
+        assert_eq!(counter("cluster_read_buffer_reloaded_chunks_total"), 1);
+    }
+}
@@ -0,0 +1,169 @@
+//! This module contains the translation between the timestamps of the chunks and those of the
+//! queries of a database with `TimestampRules`. The chunks store the timestamp of each row in
+//! the `time` column, and the timestamp fields as integers of nanoseconds since the epoch.
+//! Queries see the time column under the name given by the rules, and the timestamp fields as
+//! timestamps, so the columns and predicates of a query are translated to the names the chunks
+//! use before they are scanned, and the scanned rows to the schema queries see.
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use arrow_deps::arrow::{
+    compute::kernels::cast::cast,
+    datatypes::{DataType, Field, Schema, TimeUnit},
+    error::ArrowError,
+    record_batch::RecordBatch,
+};
+use data_types::{chunk::ColumnPredicate, database_rules::TimestampRules, TIME_COLUMN_NAME};
+
+/// The type queries read the timestamp fields as
+const TIMESTAMP_TYPE: DataType = DataType::Timestamp(TimeUnit::Nanosecond, None);
+
+/// Returns the name of the column the chunks store the column queried as `name` under
+pub fn stored_name<'a>(rules: &TimestampRules, name: &'a str) -> &'a str {
+    if name == rules.time_column() {
+        TIME_COLUMN_NAME
+    } else {
+        name
+    }
+}
+
+/// Returns the names of the columns the chunks store the columns queried as `columns` under
+pub fn stored_columns(rules: &TimestampRules, columns: &BTreeSet<String>) -> BTreeSet<String> {
+    columns
+        .iter()
+        .map(|name| stored_name(rules, name).to_string())
+        .collect()
+}
+
+/// Returns `predicates` comparing the columns the chunks store
+pub fn stored_predicates(
+    rules: &TimestampRules,
+    predicates: &[ColumnPredicate],
+) -> Vec<ColumnPredicate> {
+    predicates
+        .iter()
+        .map(|predicate| ColumnPredicate {
+            column_name: stored_name(rules, &predicate.column_name).to_string(),
+            ..predicate.clone()
+        })
+        .collect()
+}
+
+/// Returns the name queries give the column the chunks store as `name`
+pub fn query_name<'a>(rules: &'a TimestampRules, name: &'a str) -> &'a str {
+    if name == TIME_COLUMN_NAME {
+        rules.time_column()
+    } else {
+        name
+    }
+}
+
+/// Converts a batch scanned from the chunks to the schema queries see: the time column is
+/// renamed, and the integer timestamp fields are read as timestamps
+pub fn query_batch(rules: &TimestampRules, batch: &RecordBatch) -> Result<RecordBatch, ArrowError> {
+    if rules == &TimestampRules::default() {
+        return Ok(batch.clone());
+    }
+
+    let schema = batch.schema();
+    let mut fields = vec![];
+    let mut columns = vec![];
+    for (field, column) in schema.fields().iter().zip(batch.columns()) {
+        let name = query_name(rules, field.name());
+        if rules.is_timestamp_field(name) && field.data_type() == &DataType::Int64 {
+            fields.push(Field::new(name, TIMESTAMP_TYPE, field.is_nullable()));
+            columns.push(cast(column, &TIMESTAMP_TYPE)?);
+        } else {
+            fields.push(Field::new(
+                name,
+                field.data_type().clone(),
+                field.is_nullable(),
+            ));
+            columns.push(Arc::clone(column));
+        }
+    }
+
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_deps::arrow::array::{
+        Array, ArrayRef, Float64Array, Int64Array, TimestampNanosecondArray,
+    };
+    use data_types::chunk::{Comparison, Literal};
+
+    fn rules() -> TimestampRules {
+        TimestampRules {
+            time_column: "occurred_at".to_string(),
+            timestamp_fields: vec!["ingested_at".to_string()],
+        }
+    }
+
+    #[test]
+    fn translates_names() {
+        let rules = rules();
+        assert_eq!(stored_name(&rules, "occurred_at"), "time");
+        assert_eq!(stored_name(&rules, "ingested_at"), "ingested_at");
+        assert_eq!(query_name(&rules, "time"), "occurred_at");
+
+        let columns = vec!["occurred_at".to_string(), "value".to_string()]
+            .into_iter()
+            .collect();
+        let stored: Vec<_> = stored_columns(&rules, &columns).into_iter().collect();
+        assert_eq!(stored, vec!["time", "value"]);
+
+        let predicate = ColumnPredicate {
+            column_name: "occurred_at".to_string(),
+            op: Comparison::Gt,
+            value: Literal::Number("10".to_string()),
+        };
+        let stored = stored_predicates(&rules, &[predicate]);
+        assert_eq!(stored[0].column_name, "time");
+        assert_eq!(stored[0].op, Comparison::Gt);
+
+        // without rules, the names are kept
+        let rules = TimestampRules::default();
+        assert_eq!(stored_name(&rules, "time"), "time");
+        assert_eq!(query_name(&rules, "time"), "time");
+    }
+
+    #[test]
+    fn converts_batches() {
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("ingested_at", DataType::Int64, true),
+                Field::new("value", DataType::Float64, true),
+                Field::new("time", DataType::Int64, false),
+            ])),
+            vec![
+                Arc::new(Int64Array::from(vec![
+                    Some(1_600_000_000_000_000_000),
+                    None,
+                ])) as ArrayRef,
+                Arc::new(Float64Array::from(vec![1.0, 2.0])),
+                Arc::new(Int64Array::from(vec![10, 20])),
+            ],
+        )
+        .unwrap();
+
+        let converted = query_batch(&rules(), &batch).unwrap();
+        let schema = converted.schema();
+        let names: Vec<_> = schema.fields().iter().map(Field::name).collect();
+        assert_eq!(names, vec!["ingested_at", "value", "occurred_at"]);
+        assert_eq!(schema.field(0).data_type(), &TIMESTAMP_TYPE);
+        assert_eq!(schema.field(2).data_type(), &DataType::Int64);
+
+        let ingested_at = converted
+            .column(0)
+            .as_any()
+            .downcast_ref::<TimestampNanosecondArray>()
+            .unwrap();
+        assert_eq!(ingested_at.value(0), 1_600_000_000_000_000_000);
+        assert!(ingested_at.is_null(1));
+
+        let unchanged = query_batch(&TimestampRules::default(), &batch).unwrap();
+        assert_eq!(unchanged.schema(), batch.schema());
+    }
+}
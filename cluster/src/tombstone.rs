@@ -0,0 +1,285 @@
+//! This module contains the deletes applied to the chunks of a database persisted to object
+//! storage. Parquet files are immutable, so a delete is recorded in the catalog as a tombstone
+//! instead of rewriting the files right away. Scans of the persisted chunks leave out the rows
+//! of their tombstones, and purging rewrites the files of the chunks without those rows, after
+//! which the tombstones are dropped.
+//!
+//! A tombstone only applies to the chunks persisted before it was recorded, so that rows
+//! matching the delete that are written afterwards are kept.
+//!
+//! The rows past the retention period of their database are left out of scans the same way,
+//! until the chunks holding them expire as a whole and are dropped.
+
+use std::collections::BTreeMap;
+
+use arrow_deps::arrow::{
+    array::{Array, BooleanArray, Int64Array, StringArray},
+    compute::kernels::{cast::cast, filter::filter_record_batch},
+    datatypes::DataType,
+    error::ArrowError,
+    record_batch::RecordBatch,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::catalog::PersistedChunk;
+
+/// The rows of a table to delete: those with a timestamp in a range and the given tag values
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeletePredicate {
+    pub table_name: String,
+    /// The inclusive lower bound of the timestamps of the rows, in nanoseconds since the epoch
+    pub start: i64,
+    /// The exclusive upper bound of the timestamps of the rows
+    pub end: i64,
+    /// The value each of these tags must have. Rows without one of the tags are kept.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// A delete recorded in the catalog
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub predicate: DeletePredicate,
+    /// The delete applies to the persisted chunks with an id below this one, which are the
+    /// chunks persisted before it was recorded
+    pub before_chunk_id: u32,
+}
+
+impl DeletePredicate {
+    /// Whether the chunk may have rows to delete, judging by its table and time range
+    pub fn may_match(&self, chunk: &PersistedChunk) -> bool {
+        chunk.table_name == self.table_name
+            && chunk.min_time.map_or(true, |min| min < self.end)
+            && chunk.max_time.map_or(true, |max| max >= self.start)
+    }
+
+    /// Which rows of `batch` match the predicate
+    fn matching_rows(&self, batch: &RecordBatch) -> Result<Vec<bool>, ArrowError> {
+        let schema = batch.schema();
+        let times = match schema.index_of("time") {
+            Ok(index) => cast(batch.column(index), &DataType::Int64)?,
+            Err(_) => return Ok(vec![false; batch.num_rows()]),
+        };
+        let times = times
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .expect("cast to Int64");
+
+        let mut tags = vec![];
+        for (name, value) in &self.tags {
+            let column = match schema.index_of(name) {
+                Ok(index) => batch.column(index),
+                Err(_) => return Ok(vec![false; batch.num_rows()]),
+            };
+            match column.as_any().downcast_ref::<StringArray>() {
+                Some(column) => tags.push((column, value.as_str())),
+                None => return Ok(vec![false; batch.num_rows()]),
+            }
+        }
+
+        Ok((0..batch.num_rows())
+            .map(|row| {
+                !times.is_null(row)
+                    && self.start <= times.value(row)
+                    && times.value(row) < self.end
+                    && tags
+                        .iter()
+                        .all(|(column, value)| !column.is_null(row) && column.value(row) == *value)
+            })
+            .collect())
+    }
+}
+
+/// Leaves out of `batches` the rows matching any of `predicates`
+pub fn delete_rows(
+    batches: Vec<RecordBatch>,
+    predicates: &[DeletePredicate],
+) -> Result<Vec<RecordBatch>, ArrowError> {
+    if predicates.is_empty() {
+        return Ok(batches);
+    }
+
+    let mut kept = vec![];
+    for batch in batches {
+        let mut keep = vec![true; batch.num_rows()];
+        for predicate in predicates {
+            for (keep, matches) in keep.iter_mut().zip(predicate.matching_rows(&batch)?) {
+                *keep &= !matches;
+            }
+        }
+
+        if keep.iter().all(|&keep| keep) {
+            kept.push(batch);
+        } else if keep.iter().any(|&keep| keep) {
+            kept.push(filter_record_batch(&batch, &BooleanArray::from(keep))?);
+        }
+    }
+    Ok(kept)
+}
+
+/// Leaves out of the `partitions` of a table the rows older than `boundary`, a timestamp in
+/// nanoseconds, such as those past the retention period of their database. The partitions
+/// left without rows are left out too, except for an empty batch if no row is left at all, so
+/// that the table keeps its columns.
+pub fn expire_rows(
+    partitions: Vec<Vec<RecordBatch>>,
+    boundary: i64,
+) -> Result<Vec<Vec<RecordBatch>>, ArrowError> {
+    let expired = DeletePredicate {
+        table_name: String::new(),
+        start: i64::MIN,
+        end: boundary,
+        tags: BTreeMap::new(),
+    };
+    let empty = match partitions.iter().flatten().next() {
+        Some(batch) => Some(filter_record_batch(
+            batch,
+            &BooleanArray::from(vec![false; batch.num_rows()]),
+        )?),
+        None => None,
+    };
+
+    let mut kept = vec![];
+    for batches in partitions {
+        let batches = delete_rows(batches, std::slice::from_ref(&expired))?;
+        if !batches.is_empty() {
+            kept.push(batches);
+        }
+    }
+    if kept.is_empty() {
+        kept.extend(empty.map(|batch| vec![batch]));
+    }
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_deps::arrow::datatypes::{Field, Schema};
+    use std::sync::Arc;
+
+    fn test_batch(hosts: &[&str], times: &[i64]) -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("host", DataType::Utf8, true),
+            Field::new("time", DataType::Int64, true),
+        ]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(StringArray::from(hosts.to_vec())),
+                Arc::new(Int64Array::from(times.to_vec())),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn predicate(start: i64, end: i64, tags: &[(&str, &str)]) -> DeletePredicate {
+        DeletePredicate {
+            table_name: "cpu".to_string(),
+            start,
+            end,
+            tags: tags
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    fn rows(batches: &[RecordBatch]) -> Vec<(String, i64)> {
+        let mut rows = vec![];
+        for batch in batches {
+            let hosts = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap();
+            let times = batch
+                .column(1)
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap();
+            for row in 0..batch.num_rows() {
+                rows.push((hosts.value(row).to_string(), times.value(row)));
+            }
+        }
+        rows
+    }
+
+    #[test]
+    fn deletes_matching_rows() {
+        let batches = vec![
+            test_batch(&["a", "b", "a"], &[10, 20, 30]),
+            test_batch(&["a"], &[40]),
+        ];
+
+        // the range excludes its end, and the tag must match
+        let kept = delete_rows(batches.clone(), &[predicate(10, 40, &[("host", "a")])]).unwrap();
+        assert_eq!(
+            rows(&kept),
+            vec![("b".to_string(), 20), ("a".to_string(), 40)]
+        );
+
+        // rows matching any of the predicates are deleted, and emptied batches dropped
+        let kept = delete_rows(
+            batches.clone(),
+            &[predicate(0, 25, &[]), predicate(25, 100, &[("host", "a")])],
+        )
+        .unwrap();
+        assert!(kept.is_empty());
+
+        // predicates on a tag the table doesn't have match no rows
+        let kept = delete_rows(batches, &[predicate(0, 100, &[("region", "west")])]).unwrap();
+        assert_eq!(rows(&kept).len(), 4);
+    }
+
+    #[test]
+    fn expires_old_rows() {
+        let partitions = vec![
+            vec![test_batch(&["a", "b"], &[10, 20])],
+            vec![test_batch(&["a"], &[30])],
+        ];
+
+        let kept = expire_rows(partitions.clone(), 20).unwrap();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(rows(&kept[0]), vec![("b".to_string(), 20)]);
+
+        // the expired partitions are dropped, but the table keeps its columns
+        let kept = expire_rows(partitions.clone(), 25).unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(rows(&kept[0]), vec![("a".to_string(), 30)]);
+        let kept = expire_rows(partitions, 100).unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0][0].num_rows(), 0);
+        assert_eq!(kept[0][0].num_columns(), 2);
+        assert!(expire_rows(vec![], 100).unwrap().is_empty());
+    }
+
+    #[test]
+    fn matches_chunks_by_table_and_time() {
+        let chunk = PersistedChunk {
+            partition_key: "p".to_string(),
+            id: 0,
+            table_name: "cpu".to_string(),
+            location: "l".to_string(),
+            row_count: 1,
+            size_bytes: 1,
+            min_time: Some(10),
+            max_time: Some(20),
+            sort_key: vec![],
+            checksum: None,
+            part_checksums: None,
+            columns: vec![],
+            overlaps: false,
+        };
+
+        assert!(predicate(20, 30, &[]).may_match(&chunk));
+        assert!(predicate(0, 11, &[]).may_match(&chunk));
+        assert!(!predicate(0, 10, &[]).may_match(&chunk));
+        assert!(!predicate(21, 30, &[]).may_match(&chunk));
+        assert!(!DeletePredicate {
+            table_name: "mem".to_string(),
+            ..predicate(0, 30, &[])
+        }
+        .may_match(&chunk));
+    }
+}
@@ -0,0 +1,281 @@
+//! This module contains a registry of the long running background jobs of a server, such as
+//! moving, persisting or compacting chunks. Each job is spawned onto the tokio runtime through
+//! the registry, which keeps a `Tracker` for it that can be used to observe its status and
+//! progress, cancel it, or wait for it to complete.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::{
+    future::{AbortHandle, Abortable, BoxFuture, Shared},
+    Future, FutureExt,
+};
+
+/// The identifier of a job, unique within a `TrackerRegistry`
+pub type TrackerId = u64;
+
+/// The state a tracked job is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerStatus {
+    /// The job is still running
+    Running,
+    /// The job ran to completion without error
+    Success,
+    /// The job returned an error
+    Failure,
+    /// The job was cancelled before it completed
+    Cancelled,
+}
+
+impl TrackerStatus {
+    /// Returns true if the job is no longer running
+    pub fn is_complete(self) -> bool {
+        self != Self::Running
+    }
+}
+
+/// Counters a job can use to report how much of its work is done
+#[derive(Debug, Default)]
+pub struct Progress {
+    completed: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl Progress {
+    /// Sets the total number of units of work the job will perform
+    pub fn set_total(&self, total: usize) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Records that `count` more units of work have been completed
+    pub fn inc_completed(&self, count: usize) {
+        self.completed.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Returns the number of units of work completed so far
+    pub fn completed(&self) -> usize {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of units of work, zero if not known
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+struct TrackerState {
+    id: TrackerId,
+    description: String,
+    status: Mutex<(TrackerStatus, Option<String>)>,
+    progress: Arc<Progress>,
+    abort_handle: AbortHandle,
+}
+
+/// A handle to a job spawned through a `TrackerRegistry`
+#[derive(Clone)]
+pub struct Tracker {
+    state: Arc<TrackerState>,
+    complete: Shared<BoxFuture<'static, ()>>,
+}
+
+impl std::fmt::Debug for Tracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tracker")
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl Tracker {
+    pub fn id(&self) -> TrackerId {
+        self.state.id
+    }
+
+    pub fn description(&self) -> &str {
+        &self.state.description
+    }
+
+    pub fn status(&self) -> TrackerStatus {
+        self.state.status.lock().expect("mutex poisoned").0
+    }
+
+    /// Returns the error message of the job if it failed
+    pub fn error(&self) -> Option<String> {
+        self.state.status.lock().expect("mutex poisoned").1.clone()
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.state.progress
+    }
+
+    /// Requests that the job is cancelled. The job stops the next time it yields, which is
+    /// reflected in its status once it has.
+    pub fn cancel(&self) {
+        self.state.abort_handle.abort();
+    }
+
+    /// Resolves once the job is no longer running
+    pub async fn join(&self) {
+        self.complete.clone().await
+    }
+
+    fn set_status(&self, status: TrackerStatus, error: Option<String>) {
+        *self.state.status.lock().expect("mutex poisoned") = (status, error);
+    }
+}
+
+/// Keeps track of the jobs spawned through it
+#[derive(Debug, Default)]
+pub struct TrackerRegistry {
+    next_id: AtomicU64,
+    trackers: Mutex<BTreeMap<TrackerId, Tracker>>,
+}
+
+impl TrackerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawns the job created by `f` onto the tokio runtime and starts tracking it. `f` is
+    /// given the `Progress` of the job to report on how far along it is.
+    pub fn spawn<F, Fut, E>(&self, description: impl Into<String>, f: F) -> Tracker
+    where
+        F: FnOnce(Arc<Progress>) -> Fut,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: Display,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let progress = Arc::new(Progress::default());
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        let (tx, rx) = futures::channel::oneshot::channel::<()>();
+
+        let tracker = Tracker {
+            state: Arc::new(TrackerState {
+                id,
+                description: description.into(),
+                status: Mutex::new((TrackerStatus::Running, None)),
+                progress: Arc::clone(&progress),
+                abort_handle,
+            }),
+            // the sender is dropped once the job has finished and its status is set
+            complete: rx.map(|_| ()).boxed().shared(),
+        };
+
+        let job = Abortable::new(f(progress), abort_registration);
+        let job_tracker = tracker.clone();
+        tokio::spawn(async move {
+            match job.await {
+                Ok(Ok(())) => job_tracker.set_status(TrackerStatus::Success, None),
+                Ok(Err(e)) => job_tracker.set_status(TrackerStatus::Failure, Some(e.to_string())),
+                Err(_) => job_tracker.set_status(TrackerStatus::Cancelled, None),
+            }
+            drop(tx);
+        });
+
+        self.trackers
+            .lock()
+            .expect("mutex poisoned")
+            .insert(id, tracker.clone());
+
+        tracker
+    }
+
+    /// Returns the tracker of the job with the given id, if it is still registered
+    pub fn get(&self, id: TrackerId) -> Option<Tracker> {
+        self.trackers
+            .lock()
+            .expect("mutex poisoned")
+            .get(&id)
+            .cloned()
+    }
+
+    /// Returns the trackers of all registered jobs, ordered by id
+    pub fn list(&self) -> Vec<Tracker> {
+        self.trackers
+            .lock()
+            .expect("mutex poisoned")
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Stops tracking the jobs that have completed, returning their trackers
+    pub fn reclaim(&self) -> Vec<Tracker> {
+        let mut trackers = self.trackers.lock().expect("mutex poisoned");
+
+        let completed: Vec<_> = trackers
+            .values()
+            .filter(|t| t.status().is_complete())
+            .map(|t| t.id())
+            .collect();
+
+        completed
+            .into_iter()
+            .filter_map(|id| trackers.remove(&id))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn track_success_and_failure() {
+        let registry = TrackerRegistry::new();
+
+        let success = registry.spawn("success", |progress| async move {
+            progress.set_total(2);
+            progress.inc_completed(2);
+            Ok::<_, String>(())
+        });
+        let failure = registry.spawn("failure", |_| async move { Err("oh no".to_string()) });
+
+        success.join().await;
+        failure.join().await;
+
+        assert_eq!(success.status(), TrackerStatus::Success);
+        assert_eq!(success.progress().completed(), 2);
+        assert_eq!(success.progress().total(), 2);
+        assert_eq!(success.error(), None);
+
+        assert_eq!(failure.status(), TrackerStatus::Failure);
+        assert_eq!(failure.error(), Some("oh no".to_string()));
+
+        let ids: Vec<_> = registry.list().iter().map(Tracker::id).collect();
+        assert_eq!(ids, vec![success.id(), failure.id()]);
+        assert_eq!(registry.get(failure.id()).unwrap().description(), "failure");
+    }
+
+    #[tokio::test]
+    async fn cancel_and_reclaim() {
+        let registry = TrackerRegistry::new();
+
+        let quick = registry.spawn("quick", |_| async { Ok::<_, String>(()) });
+        let slow = registry.spawn("slow", |_| async {
+            tokio::time::delay_for(Duration::from_secs(60)).await;
+            Ok::<_, String>(())
+        });
+
+        quick.join().await;
+        assert_eq!(slow.status(), TrackerStatus::Running);
+
+        let reclaimed: Vec<_> = registry.reclaim().iter().map(Tracker::id).collect();
+        assert_eq!(reclaimed, vec![quick.id()]);
+        assert!(registry.get(quick.id()).is_none());
+
+        slow.cancel();
+        slow.join().await;
+        assert_eq!(slow.status(), TrackerStatus::Cancelled);
+        assert_eq!(registry.reclaim().len(), 1);
+        assert!(registry.list().is_empty());
+    }
+}
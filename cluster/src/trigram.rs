@@ -0,0 +1,182 @@
+//! This module contains the trigram indexes of the string columns of read buffer chunks,
+//! which let queries of log-like data such as `SELECT * FROM logs WHERE message LIKE
+//! '%timeout%'` skip the row groups of a chunk without a match.
+//!
+//! The index of a column of a row group holds every sequence of three bytes found in its
+//! values. A value containing `timeout` contains the trigrams `tim`, `ime`, `meo`, `eou` and
+//! `out`, so a row group missing any of them has no match. Patterns and values shorter than
+//! three bytes rule nothing out. The columns to index are named by the `trigram_indexes` rule
+//! of the database, and the indexes are built when a chunk is moved to the read buffer.
+
+use std::{collections::BTreeMap, mem};
+
+use arrow_deps::arrow::{
+    array::{Array, StringArray},
+    datatypes::DataType,
+    record_batch::RecordBatch,
+};
+use data_types::chunk::{ColumnPredicate, Comparison, Literal};
+
+type Trigram = [u8; 3];
+
+/// The characters of a `LIKE` pattern that aren't matched literally: the wildcards, and the
+/// characters escapes and regular expressions give a meaning to
+const PATTERN_SPECIAL_CHARS: &str = "%_\\.^$*+?()[]{}|";
+
+/// The trigrams of the values of a string column of a row group
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrigramIndex {
+    /// Sorted and deduplicated
+    trigrams: Vec<Trigram>,
+}
+
+impl TrigramIndex {
+    pub fn build(values: &StringArray) -> Self {
+        let mut trigrams = vec![];
+        for i in 0..values.len() {
+            if !values.is_null(i) {
+                trigrams.extend(trigrams_of(values.value(i)));
+            }
+        }
+        trigrams.sort_unstable();
+        trigrams.dedup();
+        Self { trigrams }
+    }
+
+    /// The memory used by the index, in bytes
+    pub fn size(&self) -> usize {
+        self.trigrams.len() * mem::size_of::<Trigram>()
+    }
+
+    /// Returns false if no value of the column contains `s`
+    pub fn could_contain(&self, s: &str) -> bool {
+        trigrams_of(s).all(|trigram| self.trigrams.binary_search(&trigram).is_ok())
+    }
+}
+
+fn trigrams_of(s: &str) -> impl Iterator<Item = Trigram> + '_ {
+    s.as_bytes().windows(3).map(|w| [w[0], w[1], w[2]])
+}
+
+/// The trigram indexes of the columns of a row group
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RowGroupIndexes {
+    /// Keyed by column name
+    columns: BTreeMap<String, TrigramIndex>,
+}
+
+impl RowGroupIndexes {
+    /// Indexes the string columns of `batch` named in `columns`
+    pub fn build(batch: &RecordBatch, columns: &[String]) -> Self {
+        let schema = batch.schema();
+        let columns = schema
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| {
+                field.data_type() == &DataType::Utf8 && columns.contains(field.name())
+            })
+            .filter_map(|(index, field)| {
+                let values = batch.column(index).as_any().downcast_ref::<StringArray>()?;
+                Some((field.name().clone(), TrigramIndex::build(values)))
+            })
+            .collect();
+        Self { columns }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// The memory used by the indexes, in bytes
+    pub fn size(&self) -> usize {
+        self.columns.values().map(TrigramIndex::size).sum()
+    }
+
+    /// Returns false if none of the rows of the row group can satisfy all of `predicates`,
+    /// judging by the indexes of the columns they compare. True does not mean any row does.
+    pub fn could_match(&self, predicates: &[ColumnPredicate]) -> bool {
+        predicates.iter().all(|predicate| {
+            let index = match self.columns.get(&predicate.column_name) {
+                Some(index) => index,
+                None => return true,
+            };
+            match (predicate.op, &predicate.value) {
+                (Comparison::Eq, Literal::String(value)) => index.could_contain(value),
+                (Comparison::Like, Literal::String(pattern)) => {
+                    like_fragments(pattern).all(|fragment| index.could_contain(fragment))
+                }
+                _ => true,
+            }
+        })
+    }
+}
+
+/// Returns the fragments of the `LIKE` pattern that every value matching it contains
+fn like_fragments(pattern: &str) -> impl Iterator<Item = &str> {
+    pattern
+        .split(move |c: char| PATTERN_SPECIAL_CHARS.contains(c))
+        .filter(|fragment| fragment.len() >= 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_deps::arrow::{
+        array::{ArrayRef, Int64Array},
+        datatypes::{Field, Schema},
+    };
+    use std::sync::Arc;
+
+    #[test]
+    fn finds_like_fragments() {
+        let fragments = |pattern| like_fragments(pattern).collect::<Vec<_>>();
+        assert_eq!(fragments("%timeout%"), vec!["timeout"]);
+        assert_eq!(
+            fragments("conn_refused%on port%"),
+            vec!["conn", "refused", "on port"]
+        );
+        assert_eq!(fragments("a.b*%ab%"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn rules_out_row_groups() {
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("message", DataType::Utf8, true),
+                Field::new("level", DataType::Utf8, true),
+                Field::new("time", DataType::Int64, false),
+            ])),
+            vec![
+                Arc::new(StringArray::from(vec![
+                    Some("request timeout after 30s"),
+                    None,
+                ])) as ArrayRef,
+                Arc::new(StringArray::from(vec![Some("error"), Some("info")])),
+                Arc::new(Int64Array::from(vec![1, 2])),
+            ],
+        )
+        .unwrap();
+        let indexes = RowGroupIndexes::build(&batch, &["message".to_string(), "time".to_string()]);
+        assert!(!indexes.is_empty());
+        assert!(indexes.size() > 0);
+
+        let predicate = |column_name: &str, op, value: &str| ColumnPredicate {
+            column_name: column_name.to_string(),
+            op,
+            value: Literal::String(value.to_string()),
+        };
+        let like = |pattern| predicate("message", Comparison::Like, pattern);
+        assert!(indexes.could_match(&[like("%timeout%")]));
+        assert!(indexes.could_match(&[like("request%30s")]));
+        assert!(!indexes.could_match(&[like("%refused%")]));
+        assert!(!indexes.could_match(&[like("%timeout%"), like("%refused%")]));
+        // too short to rule anything out
+        assert!(indexes.could_match(&[like("%zz%")]));
+
+        assert!(!indexes.could_match(&[predicate("message", Comparison::Eq, "disk full")]));
+        assert!(indexes.could_match(&[predicate("message", Comparison::Gt, "disk full")]));
+        // the level column isn't indexed
+        assert!(indexes.could_match(&[predicate("level", Comparison::Eq, "warning")]));
+    }
+}
@@ -0,0 +1,133 @@
+//! This module contains the replay of the WAL of a database that stores locally, which
+//! restores its local buffer when the server starts. A replay reports how many entries of the
+//! WAL it is behind by, its lag, which `system.wal_replay` and the metrics of the server show.
+//!
+//! A replay that fails stops at the entry it couldn't restore, such as an entry that was cut
+//! short, and the database rejects writes until the replay gets past that entry: an operator
+//! skips the replay to a later entry with `Server::skip_wal_replay`, which loses the entries
+//! skipped.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use write_buffer::{Db as WriteBufferDb, ReplayProgress};
+
+use crate::tracker::TrackerRegistry;
+
+/// The entries of the WAL of a database its replays leave out, from the entry with sequence
+/// number `from` up to but not including the entry `to`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Skip {
+    pub from: u64,
+    pub to: u64,
+}
+
+impl Skip {
+    fn contains(&self, sequence_number: u64) -> bool {
+        (self.from..self.to).contains(&sequence_number)
+    }
+}
+
+/// How the last replay of the WAL of a database went
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalReplay {
+    /// How far the replay got, which is the entry it failed at if it failed
+    pub progress: ReplayProgress,
+    pub duration: Duration,
+    /// Why the replay failed, if it did
+    pub error: Option<String>,
+}
+
+impl WalReplay {
+    /// Returns true if the replay failed, in which case the database has no local buffer and
+    /// rejects writes
+    pub fn failed(&self) -> bool {
+        self.error.is_some()
+    }
+}
+
+/// Replays the WAL in `dir` of database `db_name`, leaving out the entries in `skips`, by a
+/// job of `jobs` so that its progress can be followed. Returns the restored buffer, unless
+/// the replay failed, along with how the replay went.
+pub(crate) async fn replay(
+    jobs: &TrackerRegistry,
+    dir: PathBuf,
+    db_name: &str,
+    skips: &[Skip],
+) -> (Option<WriteBufferDb>, WalReplay) {
+    // how far the replay got, and how long the database has been catching up with its WAL
+    let labels = [("db_name", db_name)];
+    let replayed_entries = metrics::registry().gauge(
+        "cluster_wal_replay_entries",
+        "Entries of its WAL the database replayed, by the running or last replay",
+        &labels,
+    );
+    let lag_entries = metrics::registry().gauge(
+        "cluster_wal_replay_lag_entries",
+        "Entries of its WAL the running or last replay of the WAL of the database is behind by",
+        &labels,
+    );
+    let replay_seconds = metrics::registry().gauge(
+        "cluster_wal_replay_seconds",
+        "How long the running or last replay of the WAL of the database took",
+        &labels,
+    );
+    replayed_entries.set(0);
+    lag_entries.set(0);
+    replay_seconds.set(0);
+
+    let skips = skips.to_vec();
+    let last_progress = Arc::new(Mutex::new(ReplayProgress::default()));
+    let job_progress = Arc::clone(&last_progress);
+    let started = Instant::now();
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    let tracker = jobs.spawn(format!("Replay WAL {}", dir.display()), |progress| {
+        async move {
+            let buffer = WriteBufferDb::restore_from_wal_skipping(
+                dir,
+                |sequence_number| skips.iter().any(|skip| skip.contains(sequence_number)),
+                |replay| {
+                    let done = replay.replayed + replay.skipped;
+                    progress.set_total(done + replay.lag() as usize);
+                    progress.inc_completed(done - progress.completed());
+                    replayed_entries.set(replay.replayed as i64);
+                    lag_entries.set(replay.lag() as i64);
+                    replay_seconds.set(started.elapsed().as_secs() as i64);
+                    *job_progress.lock().expect("mutex poisoned") = *replay;
+                },
+            )
+            .await;
+            replay_seconds.set(started.elapsed().as_secs() as i64);
+            let buffer = buffer?;
+            // the receiver only goes away if the replay was abandoned
+            let _ = sender.send(buffer);
+            Ok::<_, write_buffer::Error>(())
+        }
+    });
+    tracker.join().await;
+
+    let replay = WalReplay {
+        progress: *last_progress.lock().expect("mutex poisoned"),
+        duration: started.elapsed(),
+        error: None,
+    };
+    match receiver.await {
+        Ok(buffer) => (Some(buffer), replay),
+        Err(_) => {
+            let error = tracker
+                .error()
+                .unwrap_or_else(|| "the replay was cancelled".to_string());
+            (
+                None,
+                WalReplay {
+                    error: Some(error),
+                    ..replay
+                },
+            )
+        }
+    }
+}
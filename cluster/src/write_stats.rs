@@ -0,0 +1,194 @@
+//! This module contains the write statistics of a database: the rows and bytes written to
+//! each table and to each partition of a table, when they were last written and how many
+//! writes to them failed, so that operators can find the measurements driving the growth of a
+//! database. They are kept in memory since the server started, and can be queried in the
+//! `system.tables` and `system.partitions` tables.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use data_types::entry::Entry;
+use influxdb_line_protocol::ParsedLine;
+
+/// The writes to a table, or to a table in a partition
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableWrites {
+    pub rows: u64,
+    /// The size of the values written, as `TableBatch::size`
+    pub bytes: u64,
+    pub last_write: Option<DateTime<Utc>>,
+    /// The writes rejected or that failed to be stored
+    pub errors: u64,
+}
+
+/// The writes to a database, by table and by partition
+#[derive(Debug, Default)]
+pub struct WriteStats {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Keyed by table name
+    tables: BTreeMap<String, TableWrites>,
+    /// Keyed by partition key and table name
+    partitions: BTreeMap<(String, String), TableWrites>,
+}
+
+impl WriteStats {
+    /// Records the rows of `entry`, stored at `now`
+    pub fn record(&self, db_name: &str, entry: &Entry, now: DateTime<Utc>) {
+        let mut inner = self.inner.lock().expect("mutex poisoned");
+        for write in entry.partition_writes() {
+            for batch in write.table_batches() {
+                let rows = batch.row_count() as u64;
+                let bytes = batch.size() as u64;
+                for writes in inner.writes(write.key(), batch.name()).iter_mut() {
+                    writes.rows += rows;
+                    writes.bytes += bytes;
+                    writes.last_write = Some(now);
+                }
+
+                let labels = [("db_name", db_name), ("table", batch.name())];
+                let registry = metrics::registry();
+                registry
+                    .counter(
+                        "cluster_table_rows_written_total",
+                        "Rows written to each table",
+                        &labels,
+                    )
+                    .add(rows);
+                registry
+                    .counter(
+                        "cluster_table_bytes_written_total",
+                        "Bytes of values written to each table",
+                        &labels,
+                    )
+                    .add(bytes);
+            }
+        }
+    }
+
+    /// Records that the rows of `entry` failed to be stored
+    pub fn record_failed_entry(&self, db_name: &str, entry: &Entry) {
+        let mut inner = self.inner.lock().expect("mutex poisoned");
+        for write in entry.partition_writes() {
+            for batch in write.table_batches() {
+                for writes in inner.writes(write.key(), batch.name()).iter_mut() {
+                    writes.errors += 1;
+                }
+                write_error_metric(db_name, batch.name());
+            }
+        }
+    }
+
+    /// Records that `lines` were rejected before they were split into partitions
+    pub fn record_rejected_lines(&self, db_name: &str, lines: &[ParsedLine<'_>]) {
+        let mut tables: Vec<_> = lines
+            .iter()
+            .map(|line| line.series.measurement.as_str())
+            .collect();
+        tables.sort_unstable();
+        tables.dedup();
+
+        let mut inner = self.inner.lock().expect("mutex poisoned");
+        for table in tables {
+            inner.tables.entry(table.to_string()).or_default().errors += 1;
+            write_error_metric(db_name, table);
+        }
+    }
+
+    /// Returns the writes to each table, ordered by table name
+    pub fn tables(&self) -> Vec<(String, TableWrites)> {
+        let inner = self.inner.lock().expect("mutex poisoned");
+        inner
+            .tables
+            .iter()
+            .map(|(table, writes)| (table.clone(), writes.clone()))
+            .collect()
+    }
+
+    /// Returns the writes to each table of each partition, ordered by partition key and table
+    /// name
+    pub fn partitions(&self) -> Vec<(String, String, TableWrites)> {
+        let inner = self.inner.lock().expect("mutex poisoned");
+        inner
+            .partitions
+            .iter()
+            .map(|((partition_key, table), writes)| {
+                (partition_key.clone(), table.clone(), writes.clone())
+            })
+            .collect()
+    }
+}
+
+impl Inner {
+    /// Returns the writes to `table` and to `table` in the partition `partition_key`
+    fn writes(&mut self, partition_key: &str, table: &str) -> [&mut TableWrites; 2] {
+        let Self { tables, partitions } = self;
+        [
+            tables.entry(table.to_string()).or_default(),
+            partitions
+                .entry((partition_key.to_string(), table.to_string()))
+                .or_default(),
+        ]
+    }
+}
+
+fn write_error_metric(db_name: &str, table: &str) {
+    metrics::registry()
+        .counter(
+            "cluster_table_write_errors_total",
+            "Writes to each table that were rejected or failed",
+            &[("db_name", db_name), ("table", table)],
+        )
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_types::{database_rules::DatabaseRules, entry::lines_to_entry};
+    use influxdb_line_protocol::parse_lines;
+
+    fn lines(lines: &str) -> Vec<ParsedLine<'_>> {
+        parse_lines(lines).map(|l| l.unwrap()).collect()
+    }
+
+    #[test]
+    fn records_writes() {
+        let stats = WriteStats::default();
+        let now = Utc::now();
+        let rules = DatabaseRules::default();
+
+        let written = lines("cpu,host=a usage=0.5 10\ncpu,host=b usage=0.7 20\nmem free=3i 30");
+        let entry = lines_to_entry(1, 1, &written, &rules).unwrap();
+        stats.record("foo", &entry, now);
+        stats.record_failed_entry("foo", &entry);
+        stats.record_rejected_lines("foo", &lines("cpu usage=1 40\ncpu usage=2 50"));
+
+        let tables = stats.tables();
+        assert_eq!(tables.len(), 2);
+        assert_eq!(
+            tables[0],
+            (
+                "cpu".to_string(),
+                TableWrites {
+                    rows: 2,
+                    bytes: 2 + 16 + 16,
+                    last_write: Some(now),
+                    errors: 2,
+                }
+            )
+        );
+        assert_eq!(tables[1].1.rows, 1);
+        assert_eq!(tables[1].1.errors, 1);
+
+        // the rejected lines have no partition
+        let partitions = stats.partitions();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].1, "cpu");
+        assert_eq!(partitions[0].2.rows, 2);
+        assert_eq!(partitions[0].2.errors, 1);
+    }
+}
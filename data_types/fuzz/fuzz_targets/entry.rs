@@ -0,0 +1,23 @@
+//! Decodes arbitrary data as an entry, as received from other servers or read from the WAL,
+//! and reads all of it if it is accepted. Run with `cargo +nightly fuzz run entry` from the
+//! `data_types` directory.
+
+#![no_main]
+
+use std::convert::TryFrom;
+
+use data_types::entry::Entry;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(entry) = Entry::try_from(data.to_vec()) {
+        entry.to_string();
+        for write in entry.partition_writes() {
+            for batch in write.table_batches() {
+                for column in batch.columns() {
+                    column.values();
+                }
+            }
+        }
+    }
+});
@@ -0,0 +1,319 @@
+//! This module contains structs that describe the chunks of data held by a database. A chunk
+//! is a unit of data within a partition that moves through the storage tiers as a whole.
+
+use std::{cmp::Ordering, convert::TryFrom};
+
+use generated_types::management;
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unknown chunk storage: {}", storage))]
+    UnknownStorage { storage: i32 },
+}
+
+/// Which storage tier a chunk currently lives in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChunkStorage {
+    /// The chunk is still accepting writes in the mutable buffer
+    OpenMutableBuffer,
+    /// The chunk no longer accepts writes but still lives in the mutable buffer
+    ClosedMutableBuffer,
+    /// The chunk has been converted into the read buffer
+    ReadBuffer,
+    /// The chunk has been persisted to object storage
+    ObjectStore,
+}
+
+/// Describes the location, size and contents of a chunk
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSummary {
+    /// The partition key of the partition this chunk belongs to
+    pub partition_key: String,
+    /// The id of the chunk, unique within its partition
+    pub id: u32,
+    /// Where the chunk currently lives
+    pub storage: ChunkStorage,
+    /// An estimate of the memory (or storage) used by the chunk, in bytes
+    pub estimated_bytes: usize,
+    /// The total number of rows across all tables in the chunk
+    pub row_count: usize,
+    /// The memory used by the indexes of the chunk, in bytes
+    #[serde(default)]
+    pub index_bytes: usize,
+    /// How long building the indexes of the chunk took, in microseconds
+    #[serde(default)]
+    pub index_build_micros: u64,
+}
+
+/// Describes the statistics and size of a column of a table in a chunk
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColumnSummary {
+    pub partition_key: String,
+    pub chunk_id: u32,
+    pub table_name: String,
+    pub column_name: String,
+    /// The type of the values stored in the column, such as `f64` or `tag`
+    pub column_type: String,
+    /// How the values of the column are encoded in memory, such as `dictionary` or
+    /// `I64BitPacked`
+    pub encoding: String,
+    /// The number of non-null values in the column
+    pub count: u32,
+    /// The number of null values in the column
+    pub null_count: u32,
+    /// The number of distinct non-null values in the column
+    pub distinct_count: u32,
+    pub min_value: String,
+    pub max_value: String,
+    /// An estimate of the memory used by the values of the column, in bytes
+    pub estimated_bytes: usize,
+}
+
+/// The statistics of a column of a persisted chunk, recorded when the chunk is written
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColumnStatistics {
+    pub column_name: String,
+    /// The type of the values of the column, named like in `ColumnSummary`
+    pub column_type: String,
+    /// The number of non-null values in the column
+    pub count: u32,
+    pub null_count: u32,
+    pub distinct_count: u32,
+    /// The minimum and maximum values of the column, formatted as strings. Empty if the column
+    /// only holds nulls.
+    pub min_value: String,
+    pub max_value: String,
+}
+
+/// A comparison of a column with a constant that rows must satisfy, such as `host = 'a'`,
+/// taken from the `WHERE` clause of a query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnPredicate {
+    pub column_name: String,
+    pub op: Comparison,
+    pub value: Literal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    /// The column matches the SQL `LIKE` pattern, where `%` matches any string and `_` any
+    /// character
+    Like,
+}
+
+/// A constant of a `ColumnPredicate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Literal {
+    /// A number, as written in the query
+    Number(String),
+    String(String),
+}
+
+impl Comparison {
+    /// The comparison with the operands swapped, so that `1 < x` becomes `x > 1`. `LIKE`
+    /// only takes the pattern on its right, and is left as it is.
+    pub fn flip(self) -> Self {
+        match self {
+            Self::Eq => Self::Eq,
+            Self::Lt => Self::Gt,
+            Self::LtEq => Self::GtEq,
+            Self::Gt => Self::Lt,
+            Self::GtEq => Self::LtEq,
+            Self::Like => Self::Like,
+        }
+    }
+}
+
+impl ColumnStatistics {
+    /// Returns false if no value of the column can satisfy `predicate`, judging by the
+    /// minimum and maximum values of the column. True does not mean any value does.
+    pub fn could_match(&self, predicate: &ColumnPredicate) -> bool {
+        if self.count == 0 {
+            // a comparison with null is never true
+            return false;
+        }
+
+        let min = compare(&self.column_type, &self.min_value, &predicate.value);
+        let max = compare(&self.column_type, &self.max_value, &predicate.value);
+        match (predicate.op, min, max) {
+            (Comparison::Eq, Some(min), Some(max)) => {
+                min != Ordering::Greater && max != Ordering::Less
+            }
+            (Comparison::Lt, Some(min), _) => min == Ordering::Less,
+            (Comparison::LtEq, Some(min), _) => min != Ordering::Greater,
+            (Comparison::Gt, _, Some(max)) => max == Ordering::Greater,
+            (Comparison::GtEq, _, Some(max)) => max != Ordering::Less,
+            // the values can't be compared with the constant here, or are matched against a
+            // pattern, so they may match
+            _ => true,
+        }
+    }
+}
+
+/// Compares `value`, a value of a column of type `column_type` formatted as a string, with
+/// `literal`. Returns `None` if they are not of comparable types.
+fn compare(column_type: &str, value: &str, literal: &Literal) -> Option<Ordering> {
+    match (column_type, literal) {
+        ("tag", Literal::String(s)) | ("String", Literal::String(s)) => Some(value.cmp(s)),
+        ("i64", Literal::Number(n)) => match (value.parse::<i64>(), n.parse::<i64>()) {
+            (Ok(value), Ok(n)) => Some(value.cmp(&n)),
+            _ => value
+                .parse::<f64>()
+                .ok()?
+                .partial_cmp(&n.parse::<f64>().ok()?),
+        },
+        ("f64", Literal::Number(n)) => value.parse::<f64>().ok()?.partial_cmp(&n.parse().ok()?),
+        _ => None,
+    }
+}
+
+impl From<ChunkStorage> for management::ChunkStorage {
+    fn from(storage: ChunkStorage) -> Self {
+        match storage {
+            ChunkStorage::OpenMutableBuffer => Self::OpenMutableBuffer,
+            ChunkStorage::ClosedMutableBuffer => Self::ClosedMutableBuffer,
+            ChunkStorage::ReadBuffer => Self::ReadBuffer,
+            ChunkStorage::ObjectStore => Self::ObjectStore,
+        }
+    }
+}
+
+impl From<management::ChunkStorage> for ChunkStorage {
+    fn from(storage: management::ChunkStorage) -> Self {
+        match storage {
+            management::ChunkStorage::OpenMutableBuffer => Self::OpenMutableBuffer,
+            management::ChunkStorage::ClosedMutableBuffer => Self::ClosedMutableBuffer,
+            management::ChunkStorage::ReadBuffer => Self::ReadBuffer,
+            management::ChunkStorage::ObjectStore => Self::ObjectStore,
+        }
+    }
+}
+
+impl From<ChunkSummary> for management::Chunk {
+    fn from(summary: ChunkSummary) -> Self {
+        let storage: management::ChunkStorage = summary.storage.into();
+
+        Self {
+            partition_key: summary.partition_key,
+            id: summary.id,
+            storage: storage as i32,
+            estimated_bytes: summary.estimated_bytes as u64,
+            row_count: summary.row_count as u64,
+            index_bytes: summary.index_bytes as u64,
+            index_build_micros: summary.index_build_micros,
+        }
+    }
+}
+
+impl TryFrom<management::Chunk> for ChunkSummary {
+    type Error = Error;
+
+    fn try_from(proto: management::Chunk) -> Result<Self, Self::Error> {
+        let storage =
+            management::ChunkStorage::from_i32(proto.storage).context(UnknownStorage {
+                storage: proto.storage,
+            })?;
+
+        Ok(Self {
+            partition_key: proto.partition_key,
+            id: proto.id,
+            storage: storage.into(),
+            estimated_bytes: proto.estimated_bytes as usize,
+            row_count: proto.row_count as usize,
+            index_bytes: proto.index_bytes as usize,
+            index_build_micros: proto.index_build_micros,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_summary_to_protobuf() {
+        let summary = ChunkSummary {
+            partition_key: "2020-10-10".to_string(),
+            id: 2,
+            storage: ChunkStorage::ClosedMutableBuffer,
+            estimated_bytes: 1024,
+            row_count: 10,
+            index_bytes: 64,
+            index_build_micros: 12,
+        };
+
+        let chunk: management::Chunk = summary.clone().into();
+        assert_eq!(chunk.partition_key, "2020-10-10");
+        assert_eq!(chunk.id, 2);
+        assert_eq!(
+            chunk.storage,
+            management::ChunkStorage::ClosedMutableBuffer as i32
+        );
+        assert_eq!(chunk.estimated_bytes, 1024);
+        assert_eq!(chunk.row_count, 10);
+        assert_eq!(chunk.index_bytes, 64);
+
+        assert_eq!(ChunkSummary::try_from(chunk).unwrap(), summary);
+    }
+
+    #[test]
+    fn column_statistics_rule_out_values() {
+        let stats = |column_type: &str, min: &str, max: &str| ColumnStatistics {
+            column_name: "c".to_string(),
+            column_type: column_type.to_string(),
+            count: 2,
+            null_count: 0,
+            distinct_count: 2,
+            min_value: min.to_string(),
+            max_value: max.to_string(),
+        };
+        let predicate = |op, value| ColumnPredicate {
+            column_name: "c".to_string(),
+            op,
+            value,
+        };
+        let string = |s: &str| Literal::String(s.to_string());
+        let number = |n: &str| Literal::Number(n.to_string());
+
+        let host = stats("tag", "b", "d");
+        assert!(host.could_match(&predicate(Comparison::Eq, string("c"))));
+        assert!(!host.could_match(&predicate(Comparison::Eq, string("a"))));
+        assert!(!host.could_match(&predicate(Comparison::Eq, string("e"))));
+        assert!(!host.could_match(&predicate(Comparison::Lt, string("b"))));
+        assert!(host.could_match(&predicate(Comparison::LtEq, string("b"))));
+        assert!(!host.could_match(&predicate(Comparison::Gt, string("d"))));
+        assert!(host.could_match(&predicate(Comparison::GtEq, string("d"))));
+        // not comparable
+        assert!(host.could_match(&predicate(Comparison::Eq, number("1"))));
+
+        let time = stats("i64", "9", "20");
+        assert!(time.could_match(&predicate(Comparison::Eq, number("10"))));
+        assert!(!time.could_match(&predicate(Comparison::Gt, number("20"))));
+        assert!(!time.could_match(&predicate(Comparison::Lt, number("8.5"))));
+
+        let usage = stats("f64", "0.5", "1.5");
+        assert!(!usage.could_match(&predicate(Comparison::GtEq, number("2"))));
+        assert!(usage.could_match(&predicate(Comparison::Eq, number("1"))));
+
+        let mut nulls = stats("tag", "", "");
+        nulls.count = 0;
+        assert!(!nulls.could_match(&predicate(Comparison::Eq, string(""))));
+    }
+
+    #[test]
+    fn chunk_summary_from_invalid_protobuf() {
+        let chunk = management::Chunk {
+            storage: 42,
+            ..Default::default()
+        };
+        let err = ChunkSummary::try_from(chunk).unwrap_err();
+        assert!(matches!(err, Error::UnknownStorage { storage: 42 }));
+    }
+}
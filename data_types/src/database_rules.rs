@@ -1,8 +1,18 @@
-use influxdb_line_protocol::ParsedLine;
+use generated_types::management;
+use influxdb_line_protocol::{FieldValue, ParsedLine};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::{TryFrom, TryInto},
+    fmt,
+    time::Duration,
+};
 
 use chrono::{DateTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
-use snafu::Snafu;
+use snafu::{ensure, OptionExt, Snafu};
+
+use crate::TIME_COLUMN_NAME;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -11,13 +21,32 @@ pub enum Error {
         source_module: &'static str,
         source: Box<dyn std::error::Error + Send + Sync + 'static>,
     },
+
+    #[snafu(display("Required field missing in database rules: {}", field))]
+    MissingField { field: &'static str },
+
+    #[snafu(display(
+        "Replication count {} is larger than the maximum of {}",
+        count,
+        u8::MAX
+    ))]
+    ReplicationCountTooLarge { count: u32 },
+
+    #[snafu(display("Invalid type for field {} of measurement {}", field, measurement))]
+    InvalidFieldType { measurement: String, field: String },
+
+    #[snafu(display("Invalid rollup of table {}: {}", table, reason))]
+    InvalidRollupRule { table: String, reason: &'static str },
+
+    #[snafu(display("Unknown field {} of the database rules", field))]
+    UnknownRulesField { field: String },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 /// DatabaseRules contains the rules for replicating data, sending data to subscribers, and
 /// querying data for a single database.
-#[derive(Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq)]
 pub struct DatabaseRules {
     /// Template that generates a partition key for each row inserted into the db
     pub partition_template: PartitionTemplate,
@@ -68,6 +97,63 @@ pub struct DatabaseRules {
     /// queries by pointing it at a collection of partitions and then telling it to also pull
     /// data from the replication servers (writes that haven't been snapshotted into a partition).
     pub read_only_partitions: Vec<PartitionId>,
+
+    /// How long data is kept for, based on its timestamps. Chunks that only contain data older
+    /// than the retention period are dropped, and older rows are excluded from query results.
+    /// If not set, data is kept forever.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_period: Option<Duration>,
+
+    /// If set, writes must conform to the measurements, tag keys and field types declared
+    /// in the schema. Writes that would add a column or change the type of a field are
+    /// rejected rather than widening the schema of the table.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict_schema: Option<StrictSchema>,
+
+    /// How much memory the buffers of the database may use, and what is done when they use
+    /// more
+    #[serde(default)]
+    pub lifecycle_rules: LifecycleRules,
+
+    /// If set, writes with timestamps too far in the future or in the past are rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_bounds: Option<WriteBounds>,
+
+    /// Aggregates of tables maintained by the write buffer as the rows are written, which
+    /// answer the queries of dashboards over long time ranges without scanning the rows
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rollups: Vec<RollupRule>,
+
+    /// How the metrics exported to the database by OpenTelemetry SDKs and collectors are
+    /// written
+    #[serde(default)]
+    pub otlp: OtlpRules,
+
+    /// If set, points identical in series key, timestamp and fields to a point written within
+    /// this long are dropped, so that the writes redelivered by upstream queues that deliver
+    /// at least once are only stored once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_window: Option<Duration>,
+
+    /// How the chunks of the database are encoded when they are persisted to Parquet files
+    #[serde(default)]
+    pub parquet: ParquetSettings,
+
+    /// The string columns whose values the read buffer indexes by trigram, in every table with
+    /// such a column, so that queries filtering them with `LIKE '%timeout%'` or `=` skip the
+    /// row groups without a match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trigram_indexes: Vec<String>,
+
+    /// The name queries give the time column of the tables, and the fields holding
+    /// timestamps too
+    #[serde(default)]
+    pub timestamps: TimestampRules,
+
+    /// If set, the rules are those of the template, apart from the fields it overrides, and
+    /// follow the changes of the template
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<TemplateRef>,
 }
 
 impl DatabaseRules {
@@ -78,6 +164,406 @@ impl DatabaseRules {
     ) -> Result<String> {
         self.partition_template.partition_key(line, default_time)
     }
+
+    /// Checks `lines` against the strict schema of the database, if it has one, and against
+    /// its timestamp rules, returning a violation for every offending column in the order of
+    /// the lines.
+    pub fn check_schema(&self, lines: &[ParsedLine<'_>]) -> Vec<SchemaViolation> {
+        let mut violations = match &self.strict_schema {
+            Some(schema) => schema.check_lines(lines),
+            None => vec![],
+        };
+        violations.extend(self.timestamps.check_lines(lines));
+        violations.sort_by_key(|v| v.line_number);
+        violations
+    }
+
+    /// Checks the timestamps of `lines` against the write bounds of the database, if it has
+    /// some, returning a violation for every offending line. Lines without a timestamp are
+    /// stamped with `now`, so they are always accepted.
+    /// Returns the rules of `template` with the fields `self.template` overrides taken from
+    /// `self`, or `self` if it doesn't name a template
+    pub fn inherit(&self, template: &Self) -> Result<Self> {
+        let template_ref = match &self.template {
+            Some(template_ref) => template_ref,
+            None => return Ok(self.clone()),
+        };
+
+        let mut rules = template.clone();
+        for field in &template_ref.overrides {
+            match field.as_str() {
+                "partition_template" => rules.partition_template = self.partition_template.clone(),
+                "store_locally" => rules.store_locally = self.store_locally,
+                "replication" => rules.replication = self.replication.clone(),
+                "replication_count" => rules.replication_count = self.replication_count,
+                "replication_queue_max_size" => {
+                    rules.replication_queue_max_size = self.replication_queue_max_size
+                }
+                "subscriptions" => rules.subscriptions = self.subscriptions.clone(),
+                "query_local" => rules.query_local = self.query_local,
+                "primary_query_group" => {
+                    rules.primary_query_group = self.primary_query_group.clone()
+                }
+                "secondary_query_groups" => {
+                    rules.secondary_query_groups = self.secondary_query_groups.clone()
+                }
+                "read_only_partitions" => {
+                    rules.read_only_partitions = self.read_only_partitions.clone()
+                }
+                "retention_period" => rules.retention_period = self.retention_period,
+                "strict_schema" => rules.strict_schema = self.strict_schema.clone(),
+                "lifecycle_rules" => rules.lifecycle_rules = self.lifecycle_rules.clone(),
+                "write_bounds" => rules.write_bounds = self.write_bounds.clone(),
+                "rollups" => rules.rollups = self.rollups.clone(),
+                "otlp" => rules.otlp = self.otlp.clone(),
+                "dedup_window" => rules.dedup_window = self.dedup_window,
+                "parquet" => rules.parquet = self.parquet.clone(),
+                "trigram_indexes" => rules.trigram_indexes = self.trigram_indexes.clone(),
+                "timestamps" => rules.timestamps = self.timestamps.clone(),
+                _ => return UnknownRulesField { field }.fail(),
+            }
+        }
+        rules.template = Some(template_ref.clone());
+
+        Ok(rules)
+    }
+
+    pub fn check_timestamps(
+        &self,
+        lines: &[ParsedLine<'_>],
+        now: DateTime<Utc>,
+    ) -> Vec<TimestampViolation> {
+        match &self.write_bounds {
+            Some(bounds) => bounds.check_lines(lines, now, self.retention_period),
+            None => vec![],
+        }
+    }
+}
+
+/// `WriteBounds` reject points with timestamps implausibly far from the time of the write,
+/// such as the points of a device with a bogus clock, which would otherwise create
+/// partitions that are never written to again.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct WriteBounds {
+    /// Points more than this far in the future are rejected
+    pub max_future: Option<Duration>,
+    /// Points older than this are rejected. If not set, points older than the retention
+    /// period of the database are rejected, if it has one.
+    pub max_past: Option<Duration>,
+}
+
+impl WriteBounds {
+    /// Checks the timestamp of each of `lines`. Line numbers in the returned violations start
+    /// at 1.
+    pub fn check_lines(
+        &self,
+        lines: &[ParsedLine<'_>],
+        now: DateTime<Utc>,
+        retention_period: Option<Duration>,
+    ) -> Vec<TimestampViolation> {
+        let nanos = |d: Duration| i64::try_from(d.as_nanos()).unwrap_or(i64::MAX);
+        let now = now.timestamp_nanos();
+        let max_past = self.max_past.or(retention_period);
+
+        lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                let timestamp = line.timestamp?;
+                let kind = match (self.max_future, max_past) {
+                    (Some(future), _) if timestamp > now.saturating_add(nanos(future)) => {
+                        TimestampViolationKind::TooFarInFuture { max_future: future }
+                    }
+                    (_, Some(past)) if timestamp < now.saturating_sub(nanos(past)) => {
+                        TimestampViolationKind::TooOld { max_past: past }
+                    }
+                    _ => return None,
+                };
+
+                Some(TimestampViolation {
+                    line_number: i + 1,
+                    measurement: line.series.measurement.to_string(),
+                    timestamp,
+                    kind,
+                })
+            })
+            .collect()
+    }
+}
+
+/// `TemplateRef` names the rules template the rules of a database come from, so that the
+/// databases of a fleet, such as those of each tenant, share their configuration. A change of
+/// the template applies to each database created from it.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct TemplateRef {
+    /// The name of the template
+    pub name: String,
+    /// The fields of the rules, named as in `DatabaseRules`, that the database sets itself
+    /// rather than taking them from the template
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<String>,
+}
+
+/// `TimestampRules` let the tables of a database hold more than one timestamp, such as event
+/// data recording when each event occurred and when it was ingested. The timestamp of a line
+/// is written to the time column, which queries can give another name than `time`, and the
+/// timestamp fields are written as integers of nanoseconds since the epoch, which queries
+/// read as timestamps.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct TimestampRules {
+    /// The name queries give the time column, `time` if empty
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub time_column: String,
+    /// The integer fields holding timestamps, in every table with such a field
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub timestamp_fields: Vec<String>,
+}
+
+impl TimestampRules {
+    /// The name queries give the time column
+    pub fn time_column(&self) -> &str {
+        if self.time_column.is_empty() {
+            TIME_COLUMN_NAME
+        } else {
+            &self.time_column
+        }
+    }
+
+    /// Returns true if the field `name` holds timestamps
+    pub fn is_timestamp_field(&self, name: &str) -> bool {
+        self.timestamp_fields.iter().any(|f| f == name)
+    }
+
+    /// Checks that the timestamp fields of `lines` are written as integers, and that no tag
+    /// or field takes the name of the time column, which queries couldn't tell apart. Line
+    /// numbers in the returned violations start at 1.
+    pub fn check_lines(&self, lines: &[ParsedLine<'_>]) -> Vec<SchemaViolation> {
+        let mut violations = vec![];
+        if self.time_column.is_empty() && self.timestamp_fields.is_empty() {
+            return violations;
+        }
+
+        for (i, line) in lines.iter().enumerate() {
+            let violation = |kind| SchemaViolation {
+                line_number: i + 1,
+                measurement: line.series.measurement.to_string(),
+                kind,
+            };
+
+            let tag_keys = line.series.tag_set.iter().flatten().map(|(key, _)| key);
+            let field_keys = line.field_set.iter().map(|(key, _)| key);
+            for key in tag_keys.chain(field_keys) {
+                if !self.time_column.is_empty() && key.to_string() == self.time_column {
+                    violations.push(violation(SchemaViolationKind::TimeColumnName {
+                        column: key.to_string(),
+                    }));
+                }
+            }
+
+            for (key, value) in &line.field_set {
+                let field = key.to_string();
+                let actual = FieldType::of(value);
+                if actual != FieldType::Integer && self.is_timestamp_field(&field) {
+                    violations.push(violation(SchemaViolationKind::TimestampFieldType {
+                        field,
+                        actual,
+                    }));
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+/// A written line with a timestamp outside of the `WriteBounds` of the database
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TimestampViolation {
+    /// The position of the offending line in the write, starting at 1
+    pub line_number: usize,
+    pub measurement: String,
+    /// The timestamp of the line, in nanoseconds since the epoch
+    pub timestamp: i64,
+    pub kind: TimestampViolationKind,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TimestampViolationKind {
+    TooFarInFuture { max_future: Duration },
+    TooOld { max_past: Duration },
+}
+
+impl fmt::Display for TimestampViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}: timestamp {} of measurement {} is ",
+            self.line_number,
+            Utc.timestamp_nanos(self.timestamp).to_rfc3339(),
+            self.measurement
+        )?;
+        match self.kind {
+            TimestampViolationKind::TooFarInFuture { max_future } => {
+                write!(f, "more than {}s in the future", max_future.as_secs())
+            }
+            TimestampViolationKind::TooOld { max_past } => {
+                write!(f, "more than {}s in the past", max_past.as_secs())
+            }
+        }
+    }
+}
+
+/// A `RollupRule` maintains the count, sum, min and max of the numeric fields of `table` for
+/// each window of `every` and series, updated as rows are written. They are queried as the
+/// table `destination_table`, with a row per window of each series, whose `time` is the start
+/// of the window, and the columns `<field>_count`, `<field>_sum`, `<field>_min` and
+/// `<field>_max`.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct RollupRule {
+    /// The table whose rows are aggregated
+    pub table: String,
+    /// The name the aggregates are queried as
+    pub destination_table: String,
+    /// The width of the windows
+    pub every: Duration,
+    /// The fields aggregated. All numeric fields if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+}
+
+impl RollupRule {
+    /// Returns the start of the window containing `time`, in nanoseconds since the epoch
+    pub fn window_start(&self, time: i64) -> i64 {
+        let every = i64::try_from(self.every.as_nanos())
+            .unwrap_or(i64::MAX)
+            .max(1);
+        time.div_euclid(every) * every
+    }
+
+    /// Returns true if the rule aggregates the field `name`
+    pub fn aggregates(&self, name: &str) -> bool {
+        self.fields.is_empty() || self.fields.iter().any(|f| f == name)
+    }
+}
+
+/// `OtlpRules` configure how OpenTelemetry metrics become rows: each data point is written to
+/// the table named after its metric, with the attributes of its resource and its own
+/// attributes as tags.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct OtlpRules {
+    /// The resource attributes written as tags. All of them if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub resource_attributes: Vec<String>,
+    /// Prepended to the names of the metrics to name their tables
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub table_prefix: String,
+}
+
+impl OtlpRules {
+    /// Returns true if the resource attribute `key` is written as a tag
+    pub fn keeps_resource_attribute(&self, key: &str) -> bool {
+        self.resource_attributes.is_empty() || self.resource_attributes.iter().any(|a| a == key)
+    }
+}
+
+/// `LifecycleRules` bound the memory used by the mutable buffer, the read buffer and the
+/// running queries of a database, so that a database receiving more data than fits in memory
+/// rejects writes instead of bringing the process down.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct LifecycleRules {
+    /// Once the database uses more memory than this, in bytes, the chunks of the mutable
+    /// buffer are moved to the read buffer, and the oldest chunks of the read buffer are
+    /// dropped if `drop_non_persisted` is set, until it uses less
+    pub buffer_size_soft: Option<usize>,
+    /// Writes are rejected while the database uses more memory than this, in bytes, even
+    /// after the actions of the soft limit
+    pub buffer_size_hard: Option<usize>,
+    /// Allows the soft limit to drop chunks of the read buffer, whose data is lost unless it
+    /// was also written somewhere else
+    pub drop_non_persisted: bool,
+    /// Writes to a partition are throttled while its mutable buffer uses more memory than
+    /// this, in bytes, so that clients back off until it is moved out
+    pub partition_size_hard: Option<usize>,
+    /// The chunks of the mutable buffer whose newest row is older than this are persisted to
+    /// object storage. Rows written late, with a time long past, are then persisted soon
+    /// after they are written, rather than staying in memory as long as the open chunk.
+    #[serde(default)]
+    pub persist_row_age: Option<Duration>,
+    /// Once the chunks of the mutable buffer hold more unpersisted data than this, in bytes,
+    /// they are all persisted to object storage, which bounds the data lost in a crash
+    #[serde(default)]
+    pub persist_buffer_size: Option<usize>,
+    /// Once the open chunk of a partition holds at least this many rows not persisted yet,
+    /// they are persisted to object storage as an increment of the chunk, which stays open.
+    /// Large partitions that fill slowly then reach durability long before their chunk is
+    /// closed, and only the rows written since the last increment are persisted when it is.
+    #[serde(default)]
+    pub persist_increment_rows: Option<usize>,
+    /// Once a partition has at least this many chunks in the read buffer, they are merged
+    /// into one chunk with one row per point, which saves the memory and scanning overhead of
+    /// each chunk. The chunks with a policy of their own are not merged.
+    #[serde(default)]
+    pub read_buffer_merge_chunks: Option<usize>,
+    /// Once the database has received no writes or queries for this long, the chunks of its
+    /// mutable buffer and read buffer are persisted to object storage and dropped from memory,
+    /// until it is accessed again. The chunks pinned in the read buffer are kept.
+    #[serde(default)]
+    pub hibernate_after: Option<Duration>,
+    /// The persisted chunks of a table of a partition smaller than this, in bytes, are merged
+    /// into chunks of up to this size, sorted and with one row per point, as a partition
+    /// persisted often ends up with many small files, which are slow to query
+    #[serde(default)]
+    pub compaction_target_file_size: Option<usize>,
+}
+
+/// `ParquetSettings` tune how the chunks of a database are encoded when they are persisted to
+/// Parquet files, as the defaults write files poorly sized for some workloads: tables with
+/// many rows are better read in several row groups, and high-cardinality string columns
+/// spend more on their dictionary than they save. The unset settings keep the defaults.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct ParquetSettings {
+    /// The most rows in a row group. Tables with more rows are written in several row
+    /// groups, rather than all in one.
+    pub row_group_size: Option<usize>,
+    /// The codec the pages are compressed with, GZIP by default
+    pub compression: Option<ParquetCompression>,
+    /// The size, in bytes, of the dictionary of a column chunk over which its following
+    /// values are encoded without the dictionary
+    pub dictionary_page_size: Option<usize>,
+    /// Which statistics are written, those of each column chunk by default
+    pub statistics: Option<ParquetStatistics>,
+}
+
+/// The codec the pages of a Parquet file are compressed with
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+pub enum ParquetCompression {
+    Uncompressed,
+    Snappy,
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+impl fmt::Display for ParquetCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Uncompressed => "uncompressed",
+            Self::Snappy => "snappy",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Which statistics are written to a Parquet file
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+pub enum ParquetStatistics {
+    /// No statistics are written
+    None,
+    /// The statistics of each column chunk are written
+    Chunk,
 }
 
 /// `PartitionTemplate` is used to compute the partition key of each row that gets written. It
@@ -87,7 +573,7 @@ impl DatabaseRules {
 ///
 /// The key is constructed in order of the template parts; thus ordering changes what partition
 /// key is generated.
-#[derive(Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq)]
 pub struct PartitionTemplate {
     parts: Vec<TemplatePart>,
 }
@@ -98,19 +584,37 @@ impl PartitionTemplate {
         line: &ParsedLine<'_>,
         default_time: &DateTime<Utc>,
     ) -> Result<String> {
+        Ok(self.row_partition_key(
+            line.series.measurement.as_str(),
+            |column| match line.tag_value(column) {
+                Some(v) => Some(v.to_string()),
+                None => line.field_value(column).map(ToString::to_string),
+            },
+            line.timestamp,
+            default_time,
+        ))
+    }
+
+    /// Returns the partition key of a row of table `table_name` that is not line protocol,
+    /// such as a row of a bulk import. `value` returns the value of a column of the row,
+    /// formatted as in line protocol, if the row has one.
+    pub fn row_partition_key(
+        &self,
+        table_name: &str,
+        value: impl Fn(&str) -> Option<String>,
+        time: Option<i64>,
+        default_time: &DateTime<Utc>,
+    ) -> String {
         let parts: Vec<_> = self
             .parts
             .iter()
             .map(|p| match p {
-                TemplatePart::Table => line.series.measurement.to_string(),
-                TemplatePart::Column(column) => match line.tag_value(&column) {
+                TemplatePart::Table => table_name.to_string(),
+                TemplatePart::Column(column) => match value(&column) {
                     Some(v) => format!("{}_{}", column, v),
-                    None => match line.field_value(&column) {
-                        Some(v) => format!("{}_{}", column, v),
-                        None => "".to_string(),
-                    },
+                    None => "".to_string(),
                 },
-                TemplatePart::TimeFormat(format) => match line.timestamp {
+                TemplatePart::TimeFormat(format) => match time {
                     Some(t) => Utc.timestamp_nanos(t).format(&format).to_string(),
                     None => default_time.format(&format).to_string(),
                 },
@@ -118,12 +622,12 @@ impl PartitionTemplate {
             })
             .collect();
 
-        Ok(parts.join("-"))
+        parts.join("-")
     }
 }
 
 /// `TemplatePart` specifies what part of a row should be used to compute this part of a partition key.
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub enum TemplatePart {
     Table,
     Column(String),
@@ -133,7 +637,7 @@ pub enum TemplatePart {
 }
 
 /// `RegexCapture` is for pulling parts of a string column into the partition key.
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct RegexCapture {
     column: String,
     regex: String,
@@ -141,7 +645,7 @@ pub struct RegexCapture {
 
 /// `StrftimeColumn` can be used to create a time based partition key off some column other than
 /// the builtin `time` column.
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct StrftimeColumn {
     column: String,
     format: String,
@@ -162,7 +666,7 @@ pub type WriterId = String;
 ///
 /// For pull based subscriptions, the requester will send a matcher, which the receiver
 /// will execute against its in-memory WAL.
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct Subscription {
     pub name: String,
     pub host_group_id: HostGroupId,
@@ -171,7 +675,7 @@ pub struct Subscription {
 
 /// `Matcher` specifies the rule against the table name and/or a predicate
 /// against the row to determine if it matches the write rule.
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct Matcher {
     #[serde(flatten)]
     pub tables: MatchTables,
@@ -182,7 +686,7 @@ pub struct Matcher {
 
 /// `MatchTables` looks at the table name of a row to determine if it should
 /// match the rule.
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum MatchTables {
     #[serde(rename = "*")]
@@ -193,13 +697,727 @@ pub enum MatchTables {
 
 pub type HostGroupId = String;
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct HostGroup {
     pub id: HostGroupId,
     /// `hosts` is a vector of connection strings for remote hosts.
     pub hosts: Vec<String>,
 }
 
+/// `StrictSchema` declares up front the measurements a database accepts, and the tag keys
+/// and fields (with their types) of each of them. The timestamp column is always allowed.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct StrictSchema {
+    pub measurements: BTreeMap<String, MeasurementSchema>,
+}
+
+/// The tag keys and fields declared for a measurement of a `StrictSchema`
+#[derive(Debug, Clone, Serialize, Deserialize, Default, Eq, PartialEq)]
+pub struct MeasurementSchema {
+    pub tags: BTreeSet<String>,
+    pub fields: BTreeMap<String, FieldType>,
+}
+
+/// The type of a field declared in a `StrictSchema`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum FieldType {
+    Float,
+    Integer,
+    String,
+    Boolean,
+}
+
+impl FieldType {
+    fn of(value: &FieldValue<'_>) -> Self {
+        match value {
+            FieldValue::F64(_) => Self::Float,
+            FieldValue::I64(_) => Self::Integer,
+            FieldValue::String(_) => Self::String,
+            FieldValue::Boolean(_) => Self::Boolean,
+        }
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Float => "float",
+            Self::Integer => "integer",
+            Self::String => "string",
+            Self::Boolean => "boolean",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl StrictSchema {
+    /// Checks each of `lines` against the schema. Line numbers in the returned violations
+    /// start at 1.
+    pub fn check_lines(&self, lines: &[ParsedLine<'_>]) -> Vec<SchemaViolation> {
+        let mut violations = vec![];
+
+        for (i, line) in lines.iter().enumerate() {
+            let line_number = i + 1;
+            let measurement = line.series.measurement.to_string();
+
+            let schema = match self.measurements.get(&measurement) {
+                Some(schema) => schema,
+                None => {
+                    violations.push(SchemaViolation {
+                        line_number,
+                        measurement,
+                        kind: SchemaViolationKind::UnknownMeasurement,
+                    });
+                    continue;
+                }
+            };
+
+            if let Some(tag_set) = &line.series.tag_set {
+                for (key, _) in tag_set {
+                    let key = key.to_string();
+                    if !schema.tags.contains(&key) {
+                        violations.push(SchemaViolation {
+                            line_number,
+                            measurement: measurement.clone(),
+                            kind: SchemaViolationKind::UnknownTag { tag: key },
+                        });
+                    }
+                }
+            }
+
+            for (key, value) in &line.field_set {
+                let field = key.to_string();
+                let actual = FieldType::of(value);
+                let kind = match schema.fields.get(&field) {
+                    None => SchemaViolationKind::UnknownField { field },
+                    Some(&expected) if expected != actual => {
+                        SchemaViolationKind::FieldTypeMismatch {
+                            field,
+                            expected,
+                            actual,
+                        }
+                    }
+                    Some(_) => continue,
+                };
+
+                violations.push(SchemaViolation {
+                    line_number,
+                    measurement: measurement.clone(),
+                    kind,
+                });
+            }
+        }
+
+        violations
+    }
+}
+
+/// A column of a written line that doesn't conform to a `StrictSchema`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SchemaViolation {
+    /// The position of the offending line in the write, starting at 1
+    pub line_number: usize,
+    pub measurement: String,
+    pub kind: SchemaViolationKind,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SchemaViolationKind {
+    UnknownMeasurement,
+    UnknownTag {
+        tag: String,
+    },
+    UnknownField {
+        field: String,
+    },
+    FieldTypeMismatch {
+        field: String,
+        expected: FieldType,
+        actual: FieldType,
+    },
+    TimeColumnName {
+        column: String,
+    },
+    TimestampFieldType {
+        field: String,
+        actual: FieldType,
+    },
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line_number)?;
+        match &self.kind {
+            SchemaViolationKind::UnknownMeasurement => {
+                write!(f, "measurement {} is not declared", self.measurement)
+            }
+            SchemaViolationKind::UnknownTag { tag } => write!(
+                f,
+                "tag {} is not declared for measurement {}",
+                tag, self.measurement
+            ),
+            SchemaViolationKind::UnknownField { field } => write!(
+                f,
+                "field {} is not declared for measurement {}",
+                field, self.measurement
+            ),
+            SchemaViolationKind::FieldTypeMismatch {
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "field {} of measurement {} is declared as {} but was written as {}",
+                field, self.measurement, expected, actual
+            ),
+            SchemaViolationKind::TimeColumnName { column } => write!(
+                f,
+                "column {} of measurement {} has the name of the time column",
+                column, self.measurement
+            ),
+            SchemaViolationKind::TimestampFieldType { field, actual } => write!(
+                f,
+                "field {} of measurement {} holds timestamps but was written as {}, not integer",
+                field, self.measurement, actual
+            ),
+        }
+    }
+}
+
+/// Converts the rules into their protobuf representation. The database name
+/// is not part of `DatabaseRules` and must be set by the caller.
+impl From<DatabaseRules> for management::DatabaseRules {
+    fn from(rules: DatabaseRules) -> Self {
+        Self {
+            name: String::new(),
+            partition_template: Some(rules.partition_template.into()),
+            store_locally: rules.store_locally,
+            replication: rules.replication,
+            replication_count: rules.replication_count.into(),
+            replication_queue_max_size: rules.replication_queue_max_size as u64,
+            subscriptions: rules.subscriptions.into_iter().map(Into::into).collect(),
+            query_local: rules.query_local,
+            primary_query_group: rules.primary_query_group.unwrap_or_default(),
+            secondary_query_groups: rules.secondary_query_groups,
+            read_only_partitions: rules.read_only_partitions,
+            retention_period_seconds: rules
+                .retention_period
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            strict_schema: rules.strict_schema.map(Into::into),
+            lifecycle_rules: Some(rules.lifecycle_rules.into()),
+            write_bounds: rules.write_bounds.map(Into::into),
+            rollups: rules.rollups.into_iter().map(Into::into).collect(),
+            otlp: Some(rules.otlp.into()),
+            dedup_window_seconds: rules.dedup_window.map(|d| d.as_secs()).unwrap_or_default(),
+            parquet: Some(rules.parquet.into()),
+            trigram_indexes: rules.trigram_indexes,
+            timestamps: Some(rules.timestamps.into()),
+            template: rules.template.map(Into::into),
+        }
+    }
+}
+
+impl TryFrom<management::DatabaseRules> for DatabaseRules {
+    type Error = Error;
+
+    fn try_from(proto: management::DatabaseRules) -> Result<Self, Self::Error> {
+        let partition_template = proto
+            .partition_template
+            .map(TryInto::try_into)
+            .transpose()?
+            .unwrap_or_default();
+
+        let replication_count =
+            u8::try_from(proto.replication_count)
+                .ok()
+                .context(ReplicationCountTooLarge {
+                    count: proto.replication_count,
+                })?;
+
+        let subscriptions = proto
+            .subscriptions
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<Result<Vec<_>>>()?;
+
+        let primary_query_group = Some(proto.primary_query_group).filter(|g| !g.is_empty());
+
+        let retention_period = Some(proto.retention_period_seconds)
+            .filter(|s| *s != 0)
+            .map(Duration::from_secs);
+
+        let strict_schema = proto.strict_schema.map(TryInto::try_into).transpose()?;
+
+        let lifecycle_rules = proto.lifecycle_rules.map(Into::into).unwrap_or_default();
+
+        let write_bounds = proto.write_bounds.map(Into::into);
+
+        let rollups = proto
+            .rollups
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<Result<Vec<_>>>()?;
+
+        let otlp = proto.otlp.map(Into::into).unwrap_or_default();
+
+        let dedup_window = Some(proto.dedup_window_seconds)
+            .filter(|s| *s != 0)
+            .map(Duration::from_secs);
+
+        let parquet = proto.parquet.map(Into::into).unwrap_or_default();
+
+        let timestamps = proto.timestamps.map(Into::into).unwrap_or_default();
+
+        let template = proto
+            .template
+            .filter(|template| !template.name.is_empty())
+            .map(Into::into);
+
+        Ok(Self {
+            partition_template,
+            store_locally: proto.store_locally,
+            replication: proto.replication,
+            replication_count,
+            replication_queue_max_size: proto.replication_queue_max_size as usize,
+            subscriptions,
+            query_local: proto.query_local,
+            primary_query_group,
+            secondary_query_groups: proto.secondary_query_groups,
+            read_only_partitions: proto.read_only_partitions,
+            retention_period,
+            strict_schema,
+            lifecycle_rules,
+            write_bounds,
+            rollups,
+            otlp,
+            dedup_window,
+            parquet,
+            trigram_indexes: proto.trigram_indexes,
+            timestamps,
+            template,
+        })
+    }
+}
+
+impl From<WriteBounds> for management::WriteBounds {
+    fn from(bounds: WriteBounds) -> Self {
+        let seconds = |d: Option<Duration>| d.map(|d| d.as_secs()).unwrap_or_default();
+        Self {
+            max_future_seconds: seconds(bounds.max_future),
+            max_past_seconds: seconds(bounds.max_past),
+        }
+    }
+}
+
+impl From<management::WriteBounds> for WriteBounds {
+    fn from(proto: management::WriteBounds) -> Self {
+        let duration = |s: u64| Some(s).filter(|s| *s != 0).map(Duration::from_secs);
+        Self {
+            max_future: duration(proto.max_future_seconds),
+            max_past: duration(proto.max_past_seconds),
+        }
+    }
+}
+
+impl From<RollupRule> for management::RollupRule {
+    fn from(rule: RollupRule) -> Self {
+        Self {
+            table: rule.table,
+            destination_table: rule.destination_table,
+            every_seconds: rule.every.as_secs(),
+            fields: rule.fields,
+        }
+    }
+}
+
+impl TryFrom<management::RollupRule> for RollupRule {
+    type Error = Error;
+
+    fn try_from(proto: management::RollupRule) -> Result<Self, Self::Error> {
+        let invalid = |reason: &'static str| InvalidRollupRule {
+            table: &proto.table,
+            reason,
+        };
+        ensure!(!proto.table.is_empty(), invalid("the table is required"));
+        ensure!(
+            !proto.destination_table.is_empty() && proto.destination_table != proto.table,
+            invalid("the destination table must be another table")
+        );
+        ensure!(
+            proto.every_seconds > 0,
+            invalid("the windows must be longer than zero")
+        );
+
+        Ok(Self {
+            every: Duration::from_secs(proto.every_seconds),
+            table: proto.table,
+            destination_table: proto.destination_table,
+            fields: proto.fields,
+        })
+    }
+}
+
+impl From<OtlpRules> for management::OtlpRules {
+    fn from(rules: OtlpRules) -> Self {
+        Self {
+            resource_attributes: rules.resource_attributes,
+            table_prefix: rules.table_prefix,
+        }
+    }
+}
+
+impl From<management::OtlpRules> for OtlpRules {
+    fn from(proto: management::OtlpRules) -> Self {
+        Self {
+            resource_attributes: proto.resource_attributes,
+            table_prefix: proto.table_prefix,
+        }
+    }
+}
+
+impl From<TimestampRules> for management::TimestampRules {
+    fn from(rules: TimestampRules) -> Self {
+        Self {
+            time_column: rules.time_column,
+            timestamp_fields: rules.timestamp_fields,
+        }
+    }
+}
+
+impl From<management::TimestampRules> for TimestampRules {
+    fn from(proto: management::TimestampRules) -> Self {
+        Self {
+            time_column: proto.time_column,
+            timestamp_fields: proto.timestamp_fields,
+        }
+    }
+}
+
+impl From<TemplateRef> for management::TemplateRef {
+    fn from(template: TemplateRef) -> Self {
+        Self {
+            name: template.name,
+            overrides: template.overrides,
+        }
+    }
+}
+
+impl From<management::TemplateRef> for TemplateRef {
+    fn from(proto: management::TemplateRef) -> Self {
+        Self {
+            name: proto.name,
+            overrides: proto.overrides,
+        }
+    }
+}
+
+impl From<LifecycleRules> for management::LifecycleRules {
+    fn from(rules: LifecycleRules) -> Self {
+        Self {
+            buffer_size_soft: rules.buffer_size_soft.unwrap_or_default() as u64,
+            buffer_size_hard: rules.buffer_size_hard.unwrap_or_default() as u64,
+            drop_non_persisted: rules.drop_non_persisted,
+            partition_size_hard: rules.partition_size_hard.unwrap_or_default() as u64,
+            persist_row_age_seconds: rules
+                .persist_row_age
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            persist_buffer_size: rules.persist_buffer_size.unwrap_or_default() as u64,
+            persist_increment_rows: rules.persist_increment_rows.unwrap_or_default() as u64,
+            read_buffer_merge_chunks: rules.read_buffer_merge_chunks.unwrap_or_default() as u64,
+            hibernate_after_seconds: rules
+                .hibernate_after
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            compaction_target_file_size: rules.compaction_target_file_size.unwrap_or_default()
+                as u64,
+        }
+    }
+}
+
+impl From<management::LifecycleRules> for LifecycleRules {
+    fn from(proto: management::LifecycleRules) -> Self {
+        let limit = |bytes: u64| Some(bytes as usize).filter(|b| *b != 0);
+        Self {
+            buffer_size_soft: limit(proto.buffer_size_soft),
+            buffer_size_hard: limit(proto.buffer_size_hard),
+            drop_non_persisted: proto.drop_non_persisted,
+            partition_size_hard: limit(proto.partition_size_hard),
+            persist_row_age: Some(proto.persist_row_age_seconds)
+                .filter(|s| *s != 0)
+                .map(Duration::from_secs),
+            persist_buffer_size: limit(proto.persist_buffer_size),
+            persist_increment_rows: limit(proto.persist_increment_rows),
+            read_buffer_merge_chunks: limit(proto.read_buffer_merge_chunks),
+            hibernate_after: Some(proto.hibernate_after_seconds)
+                .filter(|s| *s != 0)
+                .map(Duration::from_secs),
+            compaction_target_file_size: limit(proto.compaction_target_file_size),
+        }
+    }
+}
+
+impl From<ParquetSettings> for management::ParquetSettings {
+    fn from(settings: ParquetSettings) -> Self {
+        use management::parquet_settings::{Compression, Statistics};
+
+        let compression = match settings.compression {
+            None => Compression::Unspecified,
+            Some(ParquetCompression::Uncompressed) => Compression::Uncompressed,
+            Some(ParquetCompression::Snappy) => Compression::Snappy,
+            Some(ParquetCompression::Gzip) => Compression::Gzip,
+            Some(ParquetCompression::Zstd) => Compression::Zstd,
+            Some(ParquetCompression::Lz4) => Compression::Lz4,
+        };
+        let statistics = match settings.statistics {
+            None => Statistics::Unspecified,
+            Some(ParquetStatistics::None) => Statistics::None,
+            Some(ParquetStatistics::Chunk) => Statistics::Chunk,
+        };
+
+        Self {
+            row_group_size: settings.row_group_size.unwrap_or_default() as u64,
+            compression: compression as i32,
+            dictionary_page_size: settings.dictionary_page_size.unwrap_or_default() as u64,
+            statistics: statistics as i32,
+        }
+    }
+}
+
+impl From<management::ParquetSettings> for ParquetSettings {
+    fn from(proto: management::ParquetSettings) -> Self {
+        use management::parquet_settings::{Compression, Statistics};
+
+        let size = |size: u64| Some(size as usize).filter(|s| *s != 0);
+        let compression = match Compression::from_i32(proto.compression) {
+            Some(Compression::Uncompressed) => Some(ParquetCompression::Uncompressed),
+            Some(Compression::Snappy) => Some(ParquetCompression::Snappy),
+            Some(Compression::Gzip) => Some(ParquetCompression::Gzip),
+            Some(Compression::Zstd) => Some(ParquetCompression::Zstd),
+            Some(Compression::Lz4) => Some(ParquetCompression::Lz4),
+            Some(Compression::Unspecified) | None => None,
+        };
+        let statistics = match Statistics::from_i32(proto.statistics) {
+            Some(Statistics::None) => Some(ParquetStatistics::None),
+            Some(Statistics::Chunk) => Some(ParquetStatistics::Chunk),
+            Some(Statistics::Unspecified) | None => None,
+        };
+
+        Self {
+            row_group_size: size(proto.row_group_size),
+            compression,
+            dictionary_page_size: size(proto.dictionary_page_size),
+            statistics,
+        }
+    }
+}
+
+impl From<PartitionTemplate> for management::PartitionTemplate {
+    fn from(template: PartitionTemplate) -> Self {
+        Self {
+            parts: template.parts.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl TryFrom<management::PartitionTemplate> for PartitionTemplate {
+    type Error = Error;
+
+    fn try_from(proto: management::PartitionTemplate) -> Result<Self, Self::Error> {
+        let parts = proto
+            .parts
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { parts })
+    }
+}
+
+impl From<TemplatePart> for management::partition_template::Part {
+    fn from(part: TemplatePart) -> Self {
+        use management::partition_template::part::{ColumnFormat, Part};
+
+        let part = match part {
+            TemplatePart::Table => Part::Table(()),
+            TemplatePart::Column(column) => Part::Column(column),
+            TemplatePart::TimeFormat(format) => Part::Time(format),
+            TemplatePart::RegexCapture(RegexCapture { column, regex }) => {
+                Part::Regex(ColumnFormat {
+                    column,
+                    format: regex,
+                })
+            }
+            TemplatePart::StrftimeColumn(StrftimeColumn { column, format }) => {
+                Part::StrfTime(ColumnFormat { column, format })
+            }
+        };
+
+        Self { part: Some(part) }
+    }
+}
+
+impl TryFrom<management::partition_template::Part> for TemplatePart {
+    type Error = Error;
+
+    fn try_from(proto: management::partition_template::Part) -> Result<Self, Self::Error> {
+        use management::partition_template::part::{ColumnFormat, Part};
+
+        let part = proto.part.context(MissingField {
+            field: "partition_template.parts.part",
+        })?;
+
+        Ok(match part {
+            Part::Table(_) => Self::Table,
+            Part::Column(column) => Self::Column(column),
+            Part::Time(format) => Self::TimeFormat(format),
+            Part::Regex(ColumnFormat { column, format }) => Self::RegexCapture(RegexCapture {
+                column,
+                regex: format,
+            }),
+            Part::StrfTime(ColumnFormat { column, format }) => {
+                Self::StrftimeColumn(StrftimeColumn { column, format })
+            }
+        })
+    }
+}
+
+impl From<Subscription> for management::Subscription {
+    fn from(subscription: Subscription) -> Self {
+        Self {
+            name: subscription.name,
+            host_group_id: subscription.host_group_id,
+            matcher: Some(subscription.matcher.into()),
+        }
+    }
+}
+
+impl TryFrom<management::Subscription> for Subscription {
+    type Error = Error;
+
+    fn try_from(proto: management::Subscription) -> Result<Self, Self::Error> {
+        let matcher = proto
+            .matcher
+            .context(MissingField {
+                field: "subscriptions.matcher",
+            })?
+            .try_into()?;
+
+        Ok(Self {
+            name: proto.name,
+            host_group_id: proto.host_group_id,
+            matcher,
+        })
+    }
+}
+
+impl From<Matcher> for management::Matcher {
+    fn from(matcher: Matcher) -> Self {
+        use management::matcher::TableMatcher;
+
+        let table_matcher = match matcher.tables {
+            MatchTables::All => TableMatcher::All(()),
+            MatchTables::Table(table) => TableMatcher::Table(table),
+            MatchTables::Regex(regex) => TableMatcher::Regex(regex),
+        };
+
+        Self {
+            table_matcher: Some(table_matcher),
+            predicate: matcher.predicate.unwrap_or_default(),
+        }
+    }
+}
+
+impl TryFrom<management::Matcher> for Matcher {
+    type Error = Error;
+
+    fn try_from(proto: management::Matcher) -> Result<Self, Self::Error> {
+        use management::matcher::TableMatcher;
+
+        let tables = match proto.table_matcher.context(MissingField {
+            field: "subscriptions.matcher.table_matcher",
+        })? {
+            TableMatcher::All(_) => MatchTables::All,
+            TableMatcher::Table(table) => MatchTables::Table(table),
+            TableMatcher::Regex(regex) => MatchTables::Regex(regex),
+        };
+
+        let predicate = Some(proto.predicate).filter(|p| !p.is_empty());
+
+        Ok(Self { tables, predicate })
+    }
+}
+
+impl From<StrictSchema> for management::StrictSchema {
+    fn from(schema: StrictSchema) -> Self {
+        let measurements = schema
+            .measurements
+            .into_iter()
+            .map(|(name, measurement)| management::MeasurementSchema {
+                name,
+                tags: measurement.tags.into_iter().collect(),
+                fields: measurement
+                    .fields
+                    .into_iter()
+                    .map(|(name, field_type)| management::FieldSchema {
+                        name,
+                        r#type: management::FieldType::from(field_type) as i32,
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Self { measurements }
+    }
+}
+
+impl TryFrom<management::StrictSchema> for StrictSchema {
+    type Error = Error;
+
+    fn try_from(proto: management::StrictSchema) -> Result<Self, Self::Error> {
+        let mut measurements = BTreeMap::new();
+
+        for measurement in proto.measurements {
+            let mut fields = BTreeMap::new();
+            for field in measurement.fields {
+                let field_type = match management::FieldType::from_i32(field.r#type) {
+                    Some(management::FieldType::Float) => Some(FieldType::Float),
+                    Some(management::FieldType::Integer) => Some(FieldType::Integer),
+                    Some(management::FieldType::String) => Some(FieldType::String),
+                    Some(management::FieldType::Boolean) => Some(FieldType::Boolean),
+                    Some(management::FieldType::Unspecified) | None => None,
+                }
+                .context(InvalidFieldType {
+                    measurement: &measurement.name,
+                    field: &field.name,
+                })?;
+                fields.insert(field.name, field_type);
+            }
+
+            let schema = MeasurementSchema {
+                tags: measurement.tags.into_iter().collect(),
+                fields,
+            };
+            measurements.insert(measurement.name, schema);
+        }
+
+        Ok(Self { measurements })
+    }
+}
+
+impl From<FieldType> for management::FieldType {
+    fn from(field_type: FieldType) -> Self {
+        match field_type {
+            FieldType::Float => Self::Float,
+            FieldType::Integer => Self::Integer,
+            FieldType::String => Self::String,
+            FieldType::Boolean => Self::Boolean,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -360,6 +1578,386 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn row_partition_key() -> Result {
+        let template = PartitionTemplate {
+            parts: vec![
+                TemplatePart::Table,
+                TemplatePart::Column("region".to_string()),
+                TemplatePart::Column("host".to_string()),
+                TemplatePart::TimeFormat("%Y-%m-%d".to_string()),
+            ],
+        };
+
+        let key = template.row_partition_key(
+            "cpu",
+            |column| match column {
+                "region" => Some("west".to_string()),
+                _ => None,
+            },
+            Some(1602338097000000000),
+            &Utc::now(),
+        );
+        assert_eq!("cpu-region_west--2020-10-10", key);
+
+        Ok(())
+    }
+
+    #[test]
+    fn database_rules_protobuf_round_trip() -> Result {
+        let rules = DatabaseRules {
+            partition_template: PartitionTemplate {
+                parts: vec![
+                    TemplatePart::Table,
+                    TemplatePart::Column("region".to_string()),
+                    TemplatePart::TimeFormat("%Y-%m-%d".to_string()),
+                    TemplatePart::RegexCapture(RegexCapture {
+                        column: "host".to_string(),
+                        regex: "(.*)-prod".to_string(),
+                    }),
+                    TemplatePart::StrftimeColumn(StrftimeColumn {
+                        column: "event_time".to_string(),
+                        format: "%H".to_string(),
+                    }),
+                ],
+            },
+            store_locally: true,
+            replication: vec!["az1".to_string(), "az2".to_string()],
+            replication_count: 2,
+            replication_queue_max_size: 100,
+            subscriptions: vec![
+                Subscription {
+                    name: "all".to_string(),
+                    host_group_id: "query".to_string(),
+                    matcher: Matcher {
+                        tables: MatchTables::All,
+                        predicate: None,
+                    },
+                },
+                Subscription {
+                    name: "cpu".to_string(),
+                    host_group_id: "query".to_string(),
+                    matcher: Matcher {
+                        tables: MatchTables::Table("cpu".to_string()),
+                        predicate: Some("host = 'a'".to_string()),
+                    },
+                },
+            ],
+            query_local: true,
+            primary_query_group: Some("az1".to_string()),
+            secondary_query_groups: vec!["az2".to_string()],
+            read_only_partitions: vec!["1/foo/2020-10-10".to_string()],
+            retention_period: Some(Duration::from_secs(3600)),
+            strict_schema: Some(cpu_schema()),
+            lifecycle_rules: LifecycleRules {
+                buffer_size_soft: Some(1024),
+                buffer_size_hard: Some(2048),
+                drop_non_persisted: true,
+                partition_size_hard: Some(512),
+                persist_row_age: Some(Duration::from_secs(600)),
+                persist_buffer_size: Some(4096),
+                persist_increment_rows: Some(10_000),
+                read_buffer_merge_chunks: Some(8),
+                hibernate_after: Some(Duration::from_secs(86_400)),
+                compaction_target_file_size: Some(64 * 1024 * 1024),
+            },
+            write_bounds: Some(WriteBounds {
+                max_future: Some(Duration::from_secs(60)),
+                max_past: None,
+            }),
+            rollups: vec![RollupRule {
+                table: "cpu".to_string(),
+                destination_table: "cpu_1m".to_string(),
+                every: Duration::from_secs(60),
+                fields: vec!["usage".to_string()],
+            }],
+            otlp: OtlpRules {
+                resource_attributes: vec!["service.name".to_string()],
+                table_prefix: "otel_".to_string(),
+            },
+            dedup_window: Some(Duration::from_secs(300)),
+            parquet: ParquetSettings {
+                row_group_size: Some(100_000),
+                compression: Some(ParquetCompression::Zstd),
+                dictionary_page_size: None,
+                statistics: Some(ParquetStatistics::None),
+            },
+            trigram_indexes: vec!["message".to_string()],
+            timestamps: TimestampRules {
+                time_column: "occurred_at".to_string(),
+                timestamp_fields: vec!["ingested_at".to_string()],
+            },
+            template: Some(TemplateRef {
+                name: "tenant".to_string(),
+                overrides: vec!["retention_period".to_string()],
+            }),
+        };
+
+        let protobuf: management::DatabaseRules = rules.clone().into();
+        assert_eq!(protobuf.primary_query_group, "az1");
+
+        let back = DatabaseRules::try_from(protobuf)?;
+        assert_eq!(rules, back);
+
+        Ok(())
+    }
+
+    #[test]
+    fn database_rules_protobuf_defaults() -> Result {
+        let protobuf = management::DatabaseRules {
+            name: "foo".to_string(),
+            ..Default::default()
+        };
+
+        let rules = DatabaseRules::try_from(protobuf)?;
+        assert_eq!(rules, DatabaseRules::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn database_rules_protobuf_errors() {
+        let protobuf = management::DatabaseRules {
+            replication_count: 300,
+            ..Default::default()
+        };
+        let err = DatabaseRules::try_from(protobuf).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Replication count 300 is larger than the maximum of 255"
+        );
+
+        let protobuf = management::DatabaseRules {
+            rollups: vec![management::RollupRule {
+                table: "cpu".to_string(),
+                destination_table: "cpu_1m".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let err = DatabaseRules::try_from(protobuf).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid rollup of table cpu: the windows must be longer than zero"
+        );
+
+        let protobuf = management::DatabaseRules {
+            partition_template: Some(management::PartitionTemplate {
+                parts: vec![management::partition_template::Part { part: None }],
+            }),
+            ..Default::default()
+        };
+        let err = DatabaseRules::try_from(protobuf).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Required field missing in database rules: partition_template.parts.part"
+        );
+    }
+
+    #[test]
+    fn strict_schema_protobuf_errors() {
+        let protobuf = management::DatabaseRules {
+            strict_schema: Some(management::StrictSchema {
+                measurements: vec![management::MeasurementSchema {
+                    name: "cpu".to_string(),
+                    tags: vec![],
+                    fields: vec![management::FieldSchema {
+                        name: "usage".to_string(),
+                        r#type: management::FieldType::Unspecified as i32,
+                    }],
+                }],
+            }),
+            ..Default::default()
+        };
+        let err = DatabaseRules::try_from(protobuf).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid type for field usage of measurement cpu"
+        );
+    }
+
+    #[test]
+    fn check_schema() {
+        let rules = DatabaseRules::default();
+        let lines = parsed_lines("mem,host=a free=1i 10");
+        assert!(rules.check_schema(&lines).is_empty());
+
+        let rules = DatabaseRules {
+            strict_schema: Some(cpu_schema()),
+            ..Default::default()
+        };
+
+        let lines = parsed_lines(
+            "cpu,host=a,region=west usage=1.0 10\n\
+             cpu,host=a usage=2.0,idle=1.0 20\n\
+             mem,host=a free=1i 30\n\
+             cpu,host=b usage=3i,up=true 40\n\
+             cpu,zone=z usage=4.0 50",
+        );
+        let violations: Vec<_> = rules
+            .check_schema(&lines)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            violations,
+            vec![
+                "line 2: field idle is not declared for measurement cpu",
+                "line 3: measurement mem is not declared",
+                "line 4: field usage of measurement cpu is declared as float but was written as integer",
+                "line 5: tag zone is not declared for measurement cpu",
+            ]
+        );
+    }
+
+    #[test]
+    fn inherit_template() {
+        let template = DatabaseRules {
+            store_locally: true,
+            retention_period: Some(Duration::from_secs(3600)),
+            lifecycle_rules: LifecycleRules {
+                buffer_size_hard: Some(2048),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // without a template, the rules are kept
+        let rules = DatabaseRules {
+            retention_period: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert_eq!(rules.inherit(&template).unwrap(), rules);
+
+        let template_ref = TemplateRef {
+            name: "tenant".to_string(),
+            overrides: vec!["retention_period".to_string()],
+        };
+        let rules = DatabaseRules {
+            retention_period: Some(Duration::from_secs(60)),
+            dedup_window: Some(Duration::from_secs(60)),
+            template: Some(template_ref.clone()),
+            ..Default::default()
+        };
+        let inherited = rules.inherit(&template).unwrap();
+        assert_eq!(
+            inherited,
+            DatabaseRules {
+                retention_period: Some(Duration::from_secs(60)),
+                template: Some(template_ref),
+                ..template.clone()
+            }
+        );
+
+        let rules = DatabaseRules {
+            template: Some(TemplateRef {
+                name: "tenant".to_string(),
+                overrides: vec!["retention".to_string()],
+            }),
+            ..Default::default()
+        };
+        let err = rules.inherit(&template).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown field retention of the database rules"
+        );
+    }
+
+    #[test]
+    fn check_timestamp_fields() {
+        let rules = DatabaseRules {
+            timestamps: TimestampRules {
+                time_column: "occurred_at".to_string(),
+                timestamp_fields: vec!["ingested_at".to_string()],
+            },
+            ..Default::default()
+        };
+        assert_eq!(rules.timestamps.time_column(), "occurred_at");
+        assert_eq!(TimestampRules::default().time_column(), "time");
+
+        let lines = parsed_lines(
+            "events,kind=click ingested_at=1600000000000000000i 10\n\
+             events,kind=click ingested_at=1.5 20\n\
+             events,occurred_at=x value=1 30\n\
+             events occurred_at=1i,time=2i 40",
+        );
+        let violations: Vec<_> = rules
+            .check_schema(&lines)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            violations,
+            vec![
+                "line 2: field ingested_at of measurement events holds timestamps but was written as float, not integer",
+                "line 3: column occurred_at of measurement events has the name of the time column",
+                "line 4: column occurred_at of measurement events has the name of the time column",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_timestamps() {
+        let now = Utc.timestamp_nanos(1_000_000_000_000);
+        let lines = parsed_lines(
+            "cpu usage=1.0 999000000000\n\
+             cpu usage=2.0 1060000000001\n\
+             mem free=1i 939999999999\n\
+             cpu usage=3.0",
+        );
+
+        let rules = DatabaseRules::default();
+        assert!(rules.check_timestamps(&lines, now).is_empty());
+
+        let mut rules = DatabaseRules {
+            retention_period: Some(Duration::from_secs(60)),
+            write_bounds: Some(WriteBounds {
+                max_future: Some(Duration::from_secs(60)),
+                max_past: None,
+            }),
+            ..Default::default()
+        };
+        let violations: Vec<_> = rules
+            .check_timestamps(&lines, now)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                "line 2: timestamp 1970-01-01T00:17:40.000000001+00:00 of measurement cpu is more than 60s in the future",
+                "line 3: timestamp 1970-01-01T00:15:39.999999999+00:00 of measurement mem is more than 60s in the past",
+            ]
+        );
+
+        // the explicit bound in the past takes precedence over the retention period
+        rules.write_bounds = Some(WriteBounds {
+            max_future: None,
+            max_past: Some(Duration::from_secs(3600)),
+        });
+        assert!(rules.check_timestamps(&lines, now).is_empty());
+    }
+
+    fn cpu_schema() -> StrictSchema {
+        let cpu = MeasurementSchema {
+            tags: vec!["host".to_string(), "region".to_string()]
+                .into_iter()
+                .collect(),
+            fields: vec![
+                ("usage".to_string(), FieldType::Float),
+                ("up".to_string(), FieldType::Boolean),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        StrictSchema {
+            measurements: vec![("cpu".to_string(), cpu)].into_iter().collect(),
+        }
+    }
+
     fn parsed_lines(lp: &str) -> Vec<ParsedLine<'_>> {
         parse_lines(lp).map(|l| l.unwrap()).collect()
     }
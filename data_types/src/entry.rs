@@ -0,0 +1,1112 @@
+//! This module contains the `Entry` format, in which writes are appended to the WAL, applied
+//! to the write buffer and sent to replicas. An entry carries the rows of a write split by
+//! partition and table and stored column by column, along with the producer and sequence
+//! number of the write.
+
+use crate::database_rules::{self, DatabaseRules};
+use crate::verifier::{self, Table, Verifier};
+use crate::TIME_COLUMN_NAME;
+use generated_types::entry as eb;
+use influxdb_line_protocol::{FieldValue, ParsedLine};
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    fmt,
+};
+
+use chrono::Utc;
+use flatbuffers::{FlatBufferBuilder, WIPOffset};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+pub use eb::LogicalColumnType;
+
+/// The version of the format written by this server. Entries of a later version are rejected.
+pub const ENTRY_VERSION: u32 = 1;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Data is not an entry"))]
+    NotAnEntry,
+
+    #[snafu(display("Malformed entry: {}", source))]
+    Malformed { source: verifier::Error },
+
+    #[snafu(display(
+        "Unsupported entry version {}, the latest supported is {}",
+        version,
+        ENTRY_VERSION
+    ))]
+    UnsupportedVersion { version: u32 },
+
+    #[snafu(display("Entry is missing {}", field))]
+    MissingField { field: &'static str },
+
+    #[snafu(display(
+        "Column {} of table {} has {} values, but {} rows are present",
+        column,
+        table,
+        values,
+        rows
+    ))]
+    ColumnLengthMismatch {
+        table: String,
+        column: String,
+        values: usize,
+        rows: usize,
+    },
+
+    #[snafu(display(
+        "Column {} of table {} has a null mask of {} bytes for {} rows",
+        column,
+        table,
+        bytes,
+        rows
+    ))]
+    NullMaskLengthMismatch {
+        table: String,
+        column: String,
+        bytes: usize,
+        rows: usize,
+    },
+
+    #[snafu(display("Column {} appears more than once in table {}", column, table))]
+    DuplicateColumn { table: String, column: String },
+
+    #[snafu(display(
+        "Column {} of table {} is written with conflicting types",
+        column,
+        table
+    ))]
+    ColumnTypeConflict { table: String, column: String },
+
+    #[snafu(display("Table {} must have a non-null i64 time column", table))]
+    InvalidTimeColumn { table: String },
+
+    #[snafu(display("Error computing partition key: {}", source))]
+    ComputingPartitionKey { source: database_rules::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A write, in the `Entry` flatbuffers format. Entries are checked against the schema of the
+/// format when they are created, so their accessors don't fail: the layout of the buffer is
+/// verified first, and then the consistency of its batches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    data: Vec<u8>,
+}
+
+impl Entry {
+    /// Returns true if `data` is marked as an entry, without checking it further
+    pub fn is_entry(data: &[u8]) -> bool {
+        data.len() >= 8 && eb::entry_buffer_has_identifier(data)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn version(&self) -> u32 {
+        self.fb().version()
+    }
+
+    pub fn producer_id(&self) -> u32 {
+        self.fb().producer_id()
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.fb().sequence_number()
+    }
+
+    pub fn partition_writes(&self) -> Vec<PartitionWrite<'_>> {
+        self.fb()
+            .partition_writes()
+            .map(|writes| writes.into_iter().map(|fb| PartitionWrite { fb }).collect())
+            .unwrap_or_default()
+    }
+
+    /// The number of rows written by the entry, across all of its partitions and tables
+    pub fn row_count(&self) -> usize {
+        self.partition_writes()
+            .iter()
+            .flat_map(|write| write.table_batches())
+            .map(|batch| batch.row_count())
+            .sum()
+    }
+
+    /// Returns an entry of the same producer and sequence number with the rows of this entry
+    /// for which `keep` is true, given for each row in the order of the partitions of the
+    /// entry, then of their tables. The rows past the end of `keep` are kept. The tables and
+    /// partitions left without rows are left out.
+    pub fn retain_rows(&self, keep: &[bool]) -> Self {
+        let mut keep = keep.iter().copied();
+        let mut fbb = FlatBufferBuilder::new_with_capacity(self.data.len());
+
+        let mut partition_writes = vec![];
+        for write in self.partition_writes() {
+            let mut table_batches = vec![];
+            for batch in write.table_batches() {
+                let rows: Vec<_> = (0..batch.row_count())
+                    .filter(|_| keep.next().unwrap_or(true))
+                    .collect();
+                if rows.is_empty() {
+                    continue;
+                }
+                let columns: BTreeMap<_, _> = batch
+                    .columns()
+                    .iter()
+                    .filter_map(|column| Some((column.name(), retain_values(column, &rows)?)))
+                    .collect();
+                table_batches.push(add_table_batch(
+                    &mut fbb,
+                    batch.name(),
+                    rows.len(),
+                    &columns,
+                ));
+            }
+            if !table_batches.is_empty() {
+                partition_writes.push(add_partition_write(&mut fbb, write.key(), &table_batches));
+            }
+        }
+
+        finish_entry(
+            fbb,
+            self.version(),
+            self.producer_id(),
+            self.sequence_number(),
+            &partition_writes,
+        )
+    }
+
+    fn fb(&self) -> eb::Entry<'_> {
+        eb::get_root_as_entry(&self.data)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let entry = self.fb();
+        ensure!(
+            entry.version() <= ENTRY_VERSION,
+            UnsupportedVersion {
+                version: entry.version()
+            }
+        );
+
+        for write in entry.partition_writes().into_iter().flatten() {
+            write.key().context(MissingField {
+                field: "partition key",
+            })?;
+
+            for batch in write.table_batches().into_iter().flatten() {
+                let table = batch.name().context(MissingField {
+                    field: "table name",
+                })?;
+                validate_table_batch(table, &batch)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl TryFrom<Vec<u8>> for Entry {
+    type Error = Error;
+
+    fn try_from(data: Vec<u8>) -> Result<Self> {
+        ensure!(Self::is_entry(&data), NotAnEntry);
+
+        verify(&data).context(Malformed)?;
+        let entry = Self { data };
+        entry.validate()?;
+        Ok(entry)
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "\nproducer:{}, sequence:{}\n",
+            self.producer_id(),
+            self.sequence_number()
+        )?;
+
+        for write in self.partition_writes() {
+            writeln!(f, "partition_key:{}", write.key())?;
+
+            for batch in write.table_batches() {
+                writeln!(f, "  table:{}", batch.name())?;
+
+                let columns: Vec<_> = batch
+                    .columns()
+                    .into_iter()
+                    .map(|c| (c.name(), c.values()))
+                    .collect();
+                for row in 0..batch.row_count() {
+                    write!(f, "   ")?;
+                    for (name, values) in &columns {
+                        if let Some(value) = values.value_string(row) {
+                            write!(f, " {}:{}", name, value)?;
+                        }
+                    }
+                    writeln!(f)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that `data` is laid out following the schema of entries, so that the accessors of
+/// the generated code can read it
+fn verify(data: &[u8]) -> verifier::Result<()> {
+    let mut verifier = Verifier::new(data);
+    let entry = verifier.root()?;
+    verifier.scalar(&entry, 0, 4)?; // version
+    verifier.scalar(&entry, 1, 4)?; // producer_id
+    verifier.scalar(&entry, 2, 8)?; // sequence_number
+
+    if let Some(writes) = verifier.vector(&entry, 3, 4)? {
+        for i in 0..writes.len() {
+            let write = verifier.vector_table(&writes, i)?;
+            verifier.string(&write, 0)?; // key
+
+            if let Some(batches) = verifier.vector(&write, 1, 4)? {
+                for j in 0..batches.len() {
+                    let batch = verifier.vector_table(&batches, j)?;
+                    verify_table_batch(&mut verifier, &batch)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn verify_table_batch(verifier: &mut Verifier<'_>, batch: &Table) -> verifier::Result<()> {
+    verifier.string(batch, 0)?; // name
+    verifier.scalar(batch, 1, 4)?; // row_count
+
+    if let Some(columns) = verifier.vector(batch, 2, 4)? {
+        for i in 0..columns.len() {
+            let column = verifier.vector_table(&columns, i)?;
+            verify_column(verifier, &column)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn verify_column(verifier: &mut Verifier<'_>, column: &Table) -> verifier::Result<()> {
+    const I64: u8 = eb::ColumnValues::I64Values as u8;
+    const F64: u8 = eb::ColumnValues::F64Values as u8;
+    const BOOL: u8 = eb::ColumnValues::BoolValues as u8;
+    const STRING: u8 = eb::ColumnValues::StringValues as u8;
+
+    verifier.string(column, 0)?; // name
+    let max_type = LogicalColumnType::Time as u8;
+    verifier.enum_value(column, 1, "logical column type", max_type)?;
+    let values_type = verifier.enum_value(column, 2, "column values type", STRING)?;
+
+    // the values of each type are a table holding a vector of them
+    if let Some(values) = verifier.table(column, 3)? {
+        match values_type {
+            Some(I64) | Some(F64) => {
+                verifier.vector(&values, 0, 8)?;
+            }
+            Some(BOOL) => {
+                if let Some(vector) = verifier.vector(&values, 0, 1)? {
+                    verifier.bools(&vector)?;
+                }
+            }
+            Some(STRING) => {
+                if let Some(vector) = verifier.vector(&values, 0, 4)? {
+                    for i in 0..vector.len() {
+                        verifier.vector_string(&vector, i)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    verifier.vector(column, 4, 1)?; // null_mask
+    Ok(())
+}
+
+fn validate_table_batch(table: &str, batch: &eb::TableWriteBatch<'_>) -> Result<()> {
+    let rows = batch.row_count() as usize;
+    let mut names = BTreeSet::new();
+    let mut has_time = false;
+
+    for column in batch.columns().into_iter().flatten() {
+        let name = column.name().context(MissingField {
+            field: "column name",
+        })?;
+        ensure!(
+            names.insert(name),
+            DuplicateColumn {
+                table,
+                column: name
+            }
+        );
+
+        let present = match column.null_mask() {
+            Some(mask) => {
+                ensure!(
+                    mask.len() == (rows + 7) / 8,
+                    NullMaskLengthMismatch {
+                        table,
+                        column: name,
+                        bytes: mask.len(),
+                        rows,
+                    }
+                );
+                (0..rows).filter(|&row| is_present(mask, row)).count()
+            }
+            None => rows,
+        };
+
+        let values = values_len(&column).context(MissingField {
+            field: "column values",
+        })?;
+        ensure!(
+            values == present,
+            ColumnLengthMismatch {
+                table,
+                column: name,
+                values,
+                rows: present,
+            }
+        );
+
+        if name == TIME_COLUMN_NAME {
+            ensure!(
+                column.logical_column_type() == LogicalColumnType::Time
+                    && column.values_type() == eb::ColumnValues::I64Values
+                    && present == rows,
+                InvalidTimeColumn { table }
+            );
+            has_time = true;
+        }
+    }
+
+    ensure!(has_time || rows == 0, InvalidTimeColumn { table });
+
+    Ok(())
+}
+
+fn values_len(column: &eb::Column<'_>) -> Option<usize> {
+    use eb::ColumnValues::*;
+
+    match column.values_type() {
+        I64Values => column.values_as_i64values()?.values().map(|v| v.len()),
+        F64Values => column.values_as_f64values()?.values().map(|v| v.len()),
+        BoolValues => column.values_as_bool_values()?.values().map(|v| v.len()),
+        StringValues => column.values_as_string_values()?.values().map(|v| v.len()),
+        NONE => None,
+    }
+}
+
+fn is_present(mask: &[u8], row: usize) -> bool {
+    mask[row / 8] & (1 << (row % 8)) != 0
+}
+
+/// The rows of an entry that go to one partition
+#[derive(Debug)]
+pub struct PartitionWrite<'a> {
+    fb: eb::PartitionWrite<'a>,
+}
+
+impl<'a> PartitionWrite<'a> {
+    pub fn key(&self) -> &'a str {
+        self.fb.key().expect("validated partition key")
+    }
+
+    pub fn table_batches(&self) -> Vec<TableBatch<'a>> {
+        self.fb
+            .table_batches()
+            .map(|batches| batches.into_iter().map(|fb| TableBatch { fb }).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// The rows of an entry that go to one table of a partition
+#[derive(Debug)]
+pub struct TableBatch<'a> {
+    fb: eb::TableWriteBatch<'a>,
+}
+
+impl<'a> TableBatch<'a> {
+    pub fn name(&self) -> &'a str {
+        self.fb.name().expect("validated table name")
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.fb.row_count() as usize
+    }
+
+    /// The size of the values of the batch, in bytes: 8 bytes per integer or float, 1 byte
+    /// per boolean and the length of each string
+    pub fn size(&self) -> usize {
+        self.columns().iter().map(Column::size).sum()
+    }
+
+    pub fn columns(&self) -> Vec<Column<'a>> {
+        let row_count = self.row_count();
+        self.fb
+            .columns()
+            .map(|columns| {
+                columns
+                    .into_iter()
+                    .map(|fb| Column { fb, row_count })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// A column of a table batch
+#[derive(Debug)]
+pub struct Column<'a> {
+    fb: eb::Column<'a>,
+    row_count: usize,
+}
+
+impl<'a> Column<'a> {
+    pub fn name(&self) -> &'a str {
+        self.fb.name().expect("validated column name")
+    }
+
+    pub fn logical_type(&self) -> LogicalColumnType {
+        self.fb.logical_column_type()
+    }
+
+    /// The size of the non-null values of the column, in bytes
+    pub fn size(&self) -> usize {
+        match self.fb.values_type() {
+            eb::ColumnValues::StringValues => self
+                .fb
+                .values_as_string_values()
+                .and_then(|v| v.values())
+                .map_or(0, |values| values.iter().map(str::len).sum()),
+            eb::ColumnValues::BoolValues => values_len(&self.fb).unwrap_or_default(),
+            _ => values_len(&self.fb).unwrap_or_default() * 8,
+        }
+    }
+
+    /// The value of the column for each row of its batch, `None` where the row is null
+    pub fn values(&self) -> ColumnValues<'a> {
+        let mask = self.fb.null_mask();
+        let rows = self.row_count;
+
+        match self.fb.values_type() {
+            eb::ColumnValues::I64Values => {
+                let values = self.fb.values_as_i64values().and_then(|v| v.values());
+                ColumnValues::I64(expand(values.into_iter().flatten(), mask, rows))
+            }
+            eb::ColumnValues::F64Values => {
+                let values = self.fb.values_as_f64values().and_then(|v| v.values());
+                ColumnValues::F64(expand(values.into_iter().flatten(), mask, rows))
+            }
+            eb::ColumnValues::BoolValues => {
+                let values = self.fb.values_as_bool_values().and_then(|v| v.values());
+                ColumnValues::Bool(expand(values.into_iter().flatten().copied(), mask, rows))
+            }
+            eb::ColumnValues::StringValues => {
+                let values = self.fb.values_as_string_values().and_then(|v| v.values());
+                ColumnValues::String(expand(values.into_iter().flatten(), mask, rows))
+            }
+            eb::ColumnValues::NONE => unreachable!("validated column values"),
+        }
+    }
+}
+
+fn expand<T>(
+    mut values: impl Iterator<Item = T>,
+    mask: Option<&[u8]>,
+    rows: usize,
+) -> Vec<Option<T>> {
+    (0..rows)
+        .map(|row| match mask {
+            Some(mask) if !is_present(mask, row) => None,
+            _ => values.next(),
+        })
+        .collect()
+}
+
+/// The values of a column, with a `None` for every null row
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnValues<'a> {
+    I64(Vec<Option<i64>>),
+    F64(Vec<Option<f64>>),
+    Bool(Vec<Option<bool>>),
+    String(Vec<Option<&'a str>>),
+}
+
+impl<'a> ColumnValues<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            Self::I64(v) => v.len(),
+            Self::F64(v) => v.len(),
+            Self::Bool(v) => v.len(),
+            Self::String(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The value of `row` formatted as a string, `None` if it is null
+    pub fn value_string(&self, row: usize) -> Option<String> {
+        match self {
+            Self::I64(v) => v[row].map(|v| v.to_string()),
+            Self::F64(v) => v[row].map(|v| v.to_string()),
+            Self::Bool(v) => v[row].map(|v| v.to_string()),
+            Self::String(v) => v[row].map(|v| v.to_string()),
+        }
+    }
+}
+
+/// Converts `lines` into an entry of the producer `producer_id`, splitting them into
+/// partitions using `rules`. Lines without a timestamp are given the current time.
+pub fn lines_to_entry(
+    producer_id: u32,
+    sequence_number: u64,
+    lines: &[ParsedLine<'_>],
+    rules: &DatabaseRules,
+) -> Result<Entry> {
+    let default_time = Utc::now();
+
+    let mut partitions: BTreeMap<String, BTreeMap<&str, Vec<&ParsedLine<'_>>>> = BTreeMap::new();
+    for line in lines {
+        let key = rules
+            .partition_key(line, &default_time)
+            .context(ComputingPartitionKey)?;
+        partitions
+            .entry(key)
+            .or_default()
+            .entry(line.series.measurement.as_str())
+            .or_default()
+            .push(line);
+    }
+
+    let mut fbb = FlatBufferBuilder::new_with_capacity(1024);
+    let default_time = default_time.timestamp_nanos();
+
+    let mut partition_writes = Vec::with_capacity(partitions.len());
+    for (key, tables) in &partitions {
+        let mut table_batches = Vec::with_capacity(tables.len());
+        for (table, lines) in tables {
+            let columns = table_columns(table, lines, default_time)?;
+            table_batches.push(add_table_batch(&mut fbb, table, lines.len(), &columns));
+        }
+
+        partition_writes.push(add_partition_write(&mut fbb, key, &table_batches));
+    }
+
+    Ok(finish_entry(
+        fbb,
+        ENTRY_VERSION,
+        producer_id,
+        sequence_number,
+        &partition_writes,
+    ))
+}
+
+fn add_partition_write<'a>(
+    fbb: &mut FlatBufferBuilder<'a>,
+    key: &str,
+    table_batches: &[WIPOffset<eb::TableWriteBatch<'a>>],
+) -> WIPOffset<eb::PartitionWrite<'a>> {
+    let key = fbb.create_string(key);
+    let table_batches = fbb.create_vector(table_batches);
+    eb::PartitionWrite::create(
+        fbb,
+        &eb::PartitionWriteArgs {
+            key: Some(key),
+            table_batches: Some(table_batches),
+        },
+    )
+}
+
+fn finish_entry<'a>(
+    mut fbb: FlatBufferBuilder<'a>,
+    version: u32,
+    producer_id: u32,
+    sequence_number: u64,
+    partition_writes: &[WIPOffset<eb::PartitionWrite<'a>>],
+) -> Entry {
+    let partition_writes = fbb.create_vector(partition_writes);
+    let entry = eb::Entry::create(
+        &mut fbb,
+        &eb::EntryArgs {
+            version,
+            producer_id,
+            sequence_number,
+            partition_writes: Some(partition_writes),
+        },
+    );
+    eb::finish_entry_buffer(&mut fbb, entry);
+
+    let (mut data, idx) = fbb.collapse();
+    Entry {
+        data: data.split_off(idx),
+    }
+}
+
+/// A column of a table being built, holding the values of the rows that are present
+#[derive(Debug)]
+struct ColumnBuilder<'a> {
+    logical_type: LogicalColumnType,
+    values: ValuesBuilder<'a>,
+    present: Vec<bool>,
+}
+
+#[derive(Debug)]
+enum ValuesBuilder<'a> {
+    I64(Vec<i64>),
+    F64(Vec<f64>),
+    Bool(Vec<bool>),
+    String(Vec<&'a str>),
+}
+
+impl<'a> ColumnBuilder<'a> {
+    fn new(logical_type: LogicalColumnType, value: &ValuesBuilder<'_>) -> Self {
+        let values = match value {
+            ValuesBuilder::I64(_) => ValuesBuilder::I64(vec![]),
+            ValuesBuilder::F64(_) => ValuesBuilder::F64(vec![]),
+            ValuesBuilder::Bool(_) => ValuesBuilder::Bool(vec![]),
+            ValuesBuilder::String(_) => ValuesBuilder::String(vec![]),
+        };
+
+        Self {
+            logical_type,
+            values,
+            present: vec![],
+        }
+    }
+
+    /// Sets the value of `row`, which is given as a single element builder. Returns false if
+    /// the value doesn't have the type of the column.
+    fn push(
+        &mut self,
+        row: usize,
+        logical_type: LogicalColumnType,
+        value: ValuesBuilder<'a>,
+    ) -> bool {
+        if logical_type != self.logical_type {
+            return false;
+        }
+
+        match (&mut self.values, value) {
+            (ValuesBuilder::I64(values), ValuesBuilder::I64(v)) => values.extend(v),
+            (ValuesBuilder::F64(values), ValuesBuilder::F64(v)) => values.extend(v),
+            (ValuesBuilder::Bool(values), ValuesBuilder::Bool(v)) => values.extend(v),
+            (ValuesBuilder::String(values), ValuesBuilder::String(v)) => values.extend(v),
+            _ => return false,
+        }
+
+        self.present.resize(row, false);
+        self.present.push(true);
+        true
+    }
+}
+
+fn table_columns<'a>(
+    table: &str,
+    lines: &[&'a ParsedLine<'_>],
+    default_time: i64,
+) -> Result<BTreeMap<&'a str, ColumnBuilder<'a>>> {
+    let mut columns: BTreeMap<&str, ColumnBuilder<'_>> = BTreeMap::new();
+
+    let mut push = |row: usize,
+                    name: &'a str,
+                    logical_type: LogicalColumnType,
+                    value: ValuesBuilder<'a>|
+     -> Result<()> {
+        let column = columns
+            .entry(name)
+            .or_insert_with(|| ColumnBuilder::new(logical_type, &value));
+        ensure!(
+            column.push(row, logical_type, value),
+            ColumnTypeConflict {
+                table,
+                column: name
+            }
+        );
+        Ok(())
+    };
+
+    for (row, line) in lines.iter().enumerate() {
+        for (name, value) in line.series.tag_set.iter().flatten() {
+            let value = ValuesBuilder::String(vec![value.as_str()]);
+            push(row, name.as_str(), LogicalColumnType::Tag, value)?;
+        }
+
+        for (name, value) in &line.field_set {
+            let value = match value {
+                FieldValue::I64(v) => ValuesBuilder::I64(vec![*v]),
+                FieldValue::F64(v) => ValuesBuilder::F64(vec![*v]),
+                FieldValue::Boolean(v) => ValuesBuilder::Bool(vec![*v]),
+                FieldValue::String(v) => ValuesBuilder::String(vec![v.as_str()]),
+            };
+            push(row, name.as_str(), LogicalColumnType::Field, value)?;
+        }
+
+        let time = ValuesBuilder::I64(vec![line.timestamp.unwrap_or(default_time)]);
+        push(row, TIME_COLUMN_NAME, LogicalColumnType::Time, time)?;
+    }
+
+    Ok(columns)
+}
+
+/// Returns the values of `column` for `rows`, none if they are all null
+fn retain_values<'a>(column: &Column<'a>, rows: &[usize]) -> Option<ColumnBuilder<'a>> {
+    let logical_type = column.logical_type();
+    let values = column.values();
+    let mut builder: Option<ColumnBuilder<'_>> = None;
+
+    for (row, &source) in rows.iter().enumerate() {
+        let value = match &values {
+            ColumnValues::I64(v) => v[source].map(|v| ValuesBuilder::I64(vec![v])),
+            ColumnValues::F64(v) => v[source].map(|v| ValuesBuilder::F64(vec![v])),
+            ColumnValues::Bool(v) => v[source].map(|v| ValuesBuilder::Bool(vec![v])),
+            ColumnValues::String(v) => v[source].map(|v| ValuesBuilder::String(vec![v])),
+        };
+        if let Some(value) = value {
+            let pushed = builder
+                .get_or_insert_with(|| ColumnBuilder::new(logical_type, &value))
+                .push(row, logical_type, value);
+            debug_assert!(pushed, "the values of a column have its type");
+        }
+    }
+
+    builder
+}
+
+fn add_table_batch<'a>(
+    fbb: &mut FlatBufferBuilder<'a>,
+    table: &str,
+    rows: usize,
+    columns: &BTreeMap<&str, ColumnBuilder<'_>>,
+) -> WIPOffset<eb::TableWriteBatch<'a>> {
+    let columns = columns
+        .iter()
+        .map(|(name, column)| add_column(fbb, name, rows, column))
+        .collect::<Vec<_>>();
+
+    let name = fbb.create_string(table);
+    let columns = fbb.create_vector(&columns);
+    eb::TableWriteBatch::create(
+        fbb,
+        &eb::TableWriteBatchArgs {
+            name: Some(name),
+            row_count: rows as u32,
+            columns: Some(columns),
+        },
+    )
+}
+
+fn add_column<'a>(
+    fbb: &mut FlatBufferBuilder<'a>,
+    name: &str,
+    rows: usize,
+    column: &ColumnBuilder<'_>,
+) -> WIPOffset<eb::Column<'a>> {
+    let name = fbb.create_string(name);
+
+    let (values_type, values) = match &column.values {
+        ValuesBuilder::I64(v) => {
+            let values = Some(fbb.create_vector(v));
+            let values = eb::I64Values::create(fbb, &eb::I64ValuesArgs { values });
+            (eb::ColumnValues::I64Values, values.as_union_value())
+        }
+        ValuesBuilder::F64(v) => {
+            let values = Some(fbb.create_vector(v));
+            let values = eb::F64Values::create(fbb, &eb::F64ValuesArgs { values });
+            (eb::ColumnValues::F64Values, values.as_union_value())
+        }
+        ValuesBuilder::Bool(v) => {
+            let values = Some(fbb.create_vector(v));
+            let values = eb::BoolValues::create(fbb, &eb::BoolValuesArgs { values });
+            (eb::ColumnValues::BoolValues, values.as_union_value())
+        }
+        ValuesBuilder::String(v) => {
+            let values = Some(fbb.create_vector_of_strings(v));
+            let values = eb::StringValues::create(fbb, &eb::StringValuesArgs { values });
+            (eb::ColumnValues::StringValues, values.as_union_value())
+        }
+    };
+
+    let null_mask = if column.present.len() == rows && column.present.iter().all(|p| *p) {
+        None
+    } else {
+        let mut mask = vec![0u8; (rows + 7) / 8];
+        for (row, _) in column.present.iter().enumerate().filter(|(_, p)| **p) {
+            mask[row / 8] |= 1 << (row % 8);
+        }
+        Some(fbb.create_vector(&mask))
+    };
+
+    eb::Column::create(
+        fbb,
+        &eb::ColumnArgs {
+            name: Some(name),
+            logical_column_type: column.logical_type,
+            values_type,
+            values: Some(values),
+            null_mask,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb_line_protocol::parse_lines;
+
+    type TestError = Box<dyn std::error::Error + Send + Sync + 'static>;
+    type TestResult<T = (), E = TestError> = std::result::Result<T, E>;
+
+    fn parse(lp: &str) -> Vec<ParsedLine<'_>> {
+        parse_lines(lp).map(|l| l.unwrap()).collect()
+    }
+
+    #[test]
+    fn lines_roundtrip_through_entry() -> TestResult {
+        let lp = "cpu,host=a usage=1.5,count=1i 10\n\
+                  cpu,host=b usage=2.5,up=true 20\n\
+                  cpu usage=3.5,msg=\"hi\" 30\n\
+                  mem free=7i 40";
+        let rules = DatabaseRules::default();
+        let entry = lines_to_entry(7, 42, &parse(lp), &rules)?;
+
+        let entry = Entry::try_from(entry.into_data())?;
+        assert_eq!(entry.version(), ENTRY_VERSION);
+        assert_eq!(entry.producer_id(), 7);
+        assert_eq!(entry.sequence_number(), 42);
+        assert_eq!(entry.row_count(), 4);
+
+        let writes = entry.partition_writes();
+        assert_eq!(writes.len(), 1);
+        let batches = writes[0].table_batches();
+        let names: Vec<_> = batches.iter().map(|b| b.name()).collect();
+        assert_eq!(names, vec!["cpu", "mem"]);
+        assert_eq!(batches[0].row_count(), 3);
+
+        let columns: BTreeMap<_, _> = batches[0]
+            .columns()
+            .into_iter()
+            .map(|c| (c.name(), (c.logical_type(), c.values())))
+            .collect();
+        assert_eq!(
+            columns["host"],
+            (
+                LogicalColumnType::Tag,
+                ColumnValues::String(vec![Some("a"), Some("b"), None])
+            )
+        );
+        assert_eq!(
+            columns["usage"],
+            (
+                LogicalColumnType::Field,
+                ColumnValues::F64(vec![Some(1.5), Some(2.5), Some(3.5)])
+            )
+        );
+        assert_eq!(
+            columns["count"].1,
+            ColumnValues::I64(vec![Some(1), None, None])
+        );
+        assert_eq!(
+            columns["up"].1,
+            ColumnValues::Bool(vec![None, Some(true), None])
+        );
+        assert_eq!(
+            columns["msg"].1,
+            ColumnValues::String(vec![None, None, Some("hi")])
+        );
+        assert_eq!(
+            columns["time"],
+            (
+                LogicalColumnType::Time,
+                ColumnValues::I64(vec![Some(10), Some(20), Some(30)])
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn retain_rows() -> TestResult {
+        let lp = "cpu,host=a usage=1.5 10\n\
+                  cpu,host=b usage=2.5,up=true 20\n\
+                  mem free=7i 40";
+        let entry = lines_to_entry(7, 42, &parse(lp), &DatabaseRules::default())?;
+
+        let retained = Entry::try_from(entry.retain_rows(&[false, true, false]).into_data())?;
+        assert_eq!(retained.producer_id(), 7);
+        assert_eq!(retained.sequence_number(), 42);
+        assert_eq!(retained.row_count(), 1);
+
+        let batches = retained.partition_writes()[0].table_batches();
+        assert_eq!(batches.len(), 1);
+        let columns: BTreeMap<_, _> = batches[0]
+            .columns()
+            .into_iter()
+            .map(|c| (c.name(), c.values()))
+            .collect();
+        assert_eq!(columns["host"], ColumnValues::String(vec![Some("b")]));
+        assert_eq!(columns["up"], ColumnValues::Bool(vec![Some(true)]));
+        assert_eq!(columns["time"], ColumnValues::I64(vec![Some(20)]));
+
+        // the columns without values in the rows kept are left out
+        let retained = entry.retain_rows(&[true, false]);
+        assert_eq!(retained.row_count(), 2);
+        let batches = retained.partition_writes()[0].table_batches();
+        let names: Vec<_> = batches[0].columns().iter().map(Column::name).collect();
+        assert_eq!(names, vec!["host", "time", "usage"]);
+
+        assert!(entry
+            .retain_rows(&[false, false, false])
+            .partition_writes()
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn table_batch_size() -> TestResult {
+        let lines: Vec<_> =
+            parse_lines("cpu,host=a usage=0.5,up=true 10\ncpu,host=bb usage=0.7 20")
+                .collect::<Result<_, _>>()?;
+        let entry = lines_to_entry(1, 1, &lines, &DatabaseRules::default())?;
+
+        let batches = entry.partition_writes()[0].table_batches();
+        // 3 of host, 2 floats and 2 timestamps, and 1 boolean
+        assert_eq!(batches[0].size(), 3 + 16 + 16 + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn conflicting_types_are_rejected() {
+        let lines = parse("cpu usage=1.5 10\ncpu usage=2i 20");
+        let err = lines_to_entry(1, 1, &lines, &DatabaseRules::default()).unwrap_err();
+        assert!(matches!(err, Error::ColumnTypeConflict { .. }));
+
+        let lines = parse("cpu,usage=a x=1 10\ncpu usage=\"a\" 20");
+        let err = lines_to_entry(1, 1, &lines, &DatabaseRules::default()).unwrap_err();
+        assert!(matches!(err, Error::ColumnTypeConflict { .. }));
+    }
+
+    /// Reads all of the entry in `data`, if it is accepted
+    fn read(data: Vec<u8>) {
+        if let Ok(entry) = Entry::try_from(data) {
+            entry.to_string();
+            for write in entry.partition_writes() {
+                for batch in write.table_batches() {
+                    batch.size();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_entries_are_rejected() -> TestResult {
+        let lines = parse("cpu,host=a usage=1.5,up=true 10\ncpu,host=b count=2i,msg=\"hi\" 20");
+        let data = lines_to_entry(1, 1, &lines, &DatabaseRules::default())?.into_data();
+
+        // every truncation and corrupted byte is rejected or read without panicking
+        for len in 0..data.len() {
+            read(data[..len].to_vec());
+        }
+        for pos in 0..data.len() {
+            for &byte in &[0x00, 0x01, 0x02, 0x7f, 0x80, 0xff] {
+                let mut corrupted = data.clone();
+                corrupted[pos] = byte;
+                read(corrupted);
+            }
+        }
+
+        let mut truncated = data.clone();
+        truncated.truncate(data.len() / 2);
+        let err = Entry::try_from(truncated).unwrap_err();
+        assert!(matches!(err, Error::Malformed { .. }), "{}", err);
+
+        Ok(())
+    }
+
+    fn entry_with_version(version: u32) -> Vec<u8> {
+        let mut fbb = FlatBufferBuilder::new();
+        let entry = eb::Entry::create(
+            &mut fbb,
+            &eb::EntryArgs {
+                version,
+                producer_id: 1,
+                sequence_number: 1,
+                partition_writes: None,
+            },
+        );
+        eb::finish_entry_buffer(&mut fbb, entry);
+        fbb.finished_data().to_vec()
+    }
+
+    #[test]
+    fn invalid_entries_are_rejected() {
+        assert!(Entry::try_from(entry_with_version(ENTRY_VERSION)).is_ok());
+
+        let err = Entry::try_from(entry_with_version(ENTRY_VERSION + 1)).unwrap_err();
+        assert!(
+            matches!(err, Error::UnsupportedVersion { version } if version == ENTRY_VERSION + 1)
+        );
+
+        let err = Entry::try_from(b"not an entry".to_vec()).unwrap_err();
+        assert!(matches!(err, Error::NotAnEntry));
+
+        // a column with fewer values than rows
+        let mut fbb = FlatBufferBuilder::new();
+        let column = ColumnBuilder {
+            logical_type: LogicalColumnType::Time,
+            values: ValuesBuilder::I64(vec![1]),
+            present: vec![true, true],
+        };
+        let mut columns = BTreeMap::new();
+        columns.insert(TIME_COLUMN_NAME, column);
+        let batch = add_table_batch(&mut fbb, "cpu", 2, &columns);
+        let key = fbb.create_string("key");
+        let batches = fbb.create_vector(&[batch]);
+        let write = eb::PartitionWrite::create(
+            &mut fbb,
+            &eb::PartitionWriteArgs {
+                key: Some(key),
+                table_batches: Some(batches),
+            },
+        );
+        let writes = fbb.create_vector(&[write]);
+        let entry = eb::Entry::create(
+            &mut fbb,
+            &eb::EntryArgs {
+                version: ENTRY_VERSION,
+                producer_id: 1,
+                sequence_number: 1,
+                partition_writes: Some(writes),
+            },
+        );
+        eb::finish_entry_buffer(&mut fbb, entry);
+
+        let err = Entry::try_from(fbb.finished_data().to_vec()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ColumnLengthMismatch {
+                values: 1,
+                rows: 2,
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+//! This module contains the representation of histogram values, such as the latency
+//! histograms of Prometheus and OpenTelemetry. A histogram is stored as a string field
+//! listing the cumulative count of each of its buckets by upper bound, such as
+//! `0.1:3,0.5:10,+Inf:12`, alongside its `count` and `sum` fields.
+//!
+//! A dense histogram lists all of its buckets. A sparse histogram leaves out the buckets
+//! between other buckets with the same count, which the quantiles don't depend on, so that
+//! histograms with many buckets, most of them empty, take less space.
+
+use std::{fmt, str::FromStr};
+
+use snafu::{ensure, OptionExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Invalid histogram bucket '{}', expected <upper bound>:<count>",
+        bucket
+    ))]
+    InvalidBucket { bucket: String },
+
+    #[snafu(display("Unknown histogram layout '{}', expected dense or sparse", layout))]
+    UnknownLayout { layout: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Whether the buckets that the quantiles don't depend on are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistogramLayout {
+    Dense,
+    Sparse,
+}
+
+impl Default for HistogramLayout {
+    fn default() -> Self {
+        Self::Dense
+    }
+}
+
+impl FromStr for HistogramLayout {
+    type Err = Error;
+
+    fn from_str(layout: &str) -> Result<Self> {
+        match layout {
+            "dense" => Ok(Self::Dense),
+            "sparse" => Ok(Self::Sparse),
+            _ => UnknownLayout { layout }.fail(),
+        }
+    }
+}
+
+/// The cumulative counts of the buckets of a histogram, by upper bound
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    buckets: Vec<(f64, f64)>,
+}
+
+impl Histogram {
+    /// Creates a histogram from its `(upper bound, cumulative count)` buckets, in any order
+    pub fn new(mut buckets: Vec<(f64, f64)>) -> Self {
+        buckets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        Self { buckets }
+    }
+
+    pub fn buckets(&self) -> &[(f64, f64)] {
+        &self.buckets
+    }
+
+    /// Returns the histogram in `layout`
+    pub fn with_layout(self, layout: HistogramLayout) -> Self {
+        match layout {
+            HistogramLayout::Dense => self,
+            HistogramLayout::Sparse => {
+                let counts: Vec<_> = self.buckets.iter().map(|(_, count)| *count).collect();
+                let buckets = self
+                    .buckets
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, _)| {
+                        let same_as_previous = i > 0 && counts[i - 1] == counts[i];
+                        let same_as_next = i + 1 < counts.len() && counts[i + 1] == counts[i];
+                        !(same_as_previous && same_as_next)
+                    })
+                    .map(|(_, bucket)| *bucket)
+                    .collect();
+                Self { buckets }
+            }
+        }
+    }
+
+    /// Estimates the `q` quantile of the observations, interpolating linearly within the
+    /// bucket it falls in, as Prometheus' `histogram_quantile` does. Returns `None` if there
+    /// are no observations, or the histogram has no `+Inf` bucket or no other bucket.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if q.is_nan() {
+            return None;
+        }
+        if q < 0.0 {
+            return Some(f64::NEG_INFINITY);
+        }
+        if q > 1.0 {
+            return Some(f64::INFINITY);
+        }
+
+        let buckets = &self.buckets;
+        let (last_bound, observations) = *buckets.last()?;
+        if buckets.len() < 2 || last_bound != f64::INFINITY || observations <= 0.0 {
+            return None;
+        }
+
+        // counts that decrease, such as after a reset of some of the buckets, count as flat
+        let mut counts = Vec::with_capacity(buckets.len());
+        for (_, count) in buckets {
+            let previous = counts.last().copied().unwrap_or(0.0);
+            counts.push(f64::max(*count, previous));
+        }
+        let observations = counts[counts.len() - 1];
+
+        // the quantile falls in the first non-empty bucket its rank is within
+        let mut rank = q * observations;
+        let b = counts
+            .iter()
+            .position(|&count| count >= rank && count > 0.0)
+            .unwrap_or(counts.len() - 1);
+
+        if b == buckets.len() - 1 {
+            return Some(buckets[b - 1].0);
+        }
+        if b == 0 && buckets[0].0 <= 0.0 {
+            return Some(buckets[0].0);
+        }
+
+        let (mut start, end) = (0.0, buckets[b].0);
+        let mut count = counts[b];
+        if b > 0 {
+            start = buckets[b - 1].0;
+            count -= counts[b - 1];
+            rank -= counts[b - 1];
+        }
+        Some(start + (end - start) * (rank / count))
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (bound, count)) in self.buckets.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            if *bound == f64::INFINITY {
+                write!(f, "+Inf:{}", count)?;
+            } else {
+                write!(f, "{}:{}", bound, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Histogram {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let buckets = s
+            .split(',')
+            .filter(|bucket| !bucket.trim().is_empty())
+            .map(|bucket| {
+                let invalid = || InvalidBucket { bucket };
+                let mut parts = bucket.trim().splitn(2, ':');
+                let bound = parts.next().context(invalid())?;
+                let count = parts.next().context(invalid())?;
+
+                let bound = match bound {
+                    "+Inf" | "Inf" => Some(f64::INFINITY),
+                    bound => bound.parse().ok(),
+                };
+                let count = count.parse::<f64>().ok();
+                match (bound, count) {
+                    (Some(bound), Some(count)) => {
+                        ensure!(!bound.is_nan() && count >= 0.0, invalid());
+                        Ok((bound, count))
+                    }
+                    _ => invalid().fail(),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self::new(buckets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        let histogram: Histogram = "0.5:10, 0.1:3,+Inf:12".parse().unwrap();
+        assert_eq!(
+            histogram.buckets(),
+            &[(0.1, 3.0), (0.5, 10.0), (f64::INFINITY, 12.0)]
+        );
+        assert_eq!(histogram.to_string(), "0.1:3,0.5:10,+Inf:12");
+
+        for invalid in &["0.1", "0.1:x", "NaN:1", "0.1:-1"] {
+            assert!(invalid.parse::<Histogram>().is_err(), "{}", invalid);
+        }
+        assert_eq!(
+            "sideways"
+                .parse::<HistogramLayout>()
+                .unwrap_err()
+                .to_string(),
+            "Unknown histogram layout 'sideways', expected dense or sparse"
+        );
+    }
+
+    #[test]
+    fn quantiles() {
+        let histogram: Histogram = "0.1:0,0.2:0,0.3:0,0.5:10,1:20,+Inf:20".parse().unwrap();
+        assert_eq!(histogram.quantile(0.5), Some(0.5));
+        assert_eq!(histogram.quantile(0.75), Some(0.75));
+        assert_eq!(histogram.quantile(0.25), Some(0.4));
+        assert_eq!(histogram.quantile(0.0), Some(0.3));
+        assert_eq!(histogram.quantile(-1.0), Some(f64::NEG_INFINITY));
+        assert_eq!(histogram.quantile(f64::NAN), None);
+
+        // the quantiles of the sparse layout are the same
+        let sparse = histogram.clone().with_layout(HistogramLayout::Sparse);
+        assert_eq!(sparse.to_string(), "0.1:0,0.3:0,0.5:10,1:20,+Inf:20");
+        for q in &[0.25, 0.5, 0.75, 0.99] {
+            assert_eq!(sparse.quantile(*q), histogram.quantile(*q));
+        }
+
+        // observations in the +Inf bucket are at the highest finite bound
+        let histogram: Histogram = "1:1,+Inf:4".parse().unwrap();
+        assert_eq!(histogram.quantile(0.9), Some(1.0));
+
+        assert_eq!("1:1,2:4".parse::<Histogram>().unwrap().quantile(0.5), None);
+        assert_eq!(
+            "1:0,+Inf:0".parse::<Histogram>().unwrap().quantile(0.5),
+            None
+        );
+    }
+}
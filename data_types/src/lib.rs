@@ -12,7 +12,11 @@
 
 pub const TIME_COLUMN_NAME: &str = "time";
 
+pub mod chunk;
 pub mod data;
 pub mod database_rules;
+pub mod entry;
+pub mod histogram;
 pub mod partition_metadata;
 pub mod table_schema;
+pub mod verifier;
@@ -42,7 +42,7 @@ use std::convert::From;
 use tracing::warn;
 
 /// Represents a specific Line Protocol Tag name
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Tag {
     pub name: String,
     index: u32,
@@ -76,7 +76,7 @@ pub enum DataType {
 }
 
 /// Represents a specific Line Protocol Field name
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Field {
     pub name: String,
     pub data_type: DataType,
@@ -115,7 +115,7 @@ impl ColumnDefinition {
 /// Represents the overall "schema" of line protocol data. See the
 /// module definition for more details and example of how to construct
 /// and access a `Schema` object.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Schema {
     measurement: String,
     tags: BTreeMap<String, Tag>,
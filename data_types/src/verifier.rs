@@ -0,0 +1,404 @@
+//! This module contains a verifier of the layout of flatbuffers. The accessors generated for
+//! a schema trust the buffer: they panic on offsets pointing out of it, and read enums,
+//! booleans and strings without checking their values. Buffers received from other servers or
+//! read from disk are walked with a `Verifier` following their schema before being accessed.
+//!
+//! The verifier also bounds the work of walking a buffer: the tables and vectors it visits
+//! may not add up to more bytes than the buffer has, so that offsets pointing many times to
+//! the same data can't make a small buffer expensive to check or to read.
+
+use std::convert::TryInto;
+
+use snafu::{ensure, OptionExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{} at offset {} is out of the buffer of {} bytes", what, offset, len))]
+    OutOfBounds {
+        what: &'static str,
+        offset: usize,
+        len: usize,
+    },
+
+    #[snafu(display("{} at offset {} is not aligned to {} bytes", what, offset, alignment))]
+    Unaligned {
+        what: &'static str,
+        offset: usize,
+        alignment: usize,
+    },
+
+    #[snafu(display("Invalid vtable for the table at offset {}", offset))]
+    InvalidVtable { offset: usize },
+
+    #[snafu(display(
+        "Field {} of the table at offset {} is out of the table",
+        field,
+        offset
+    ))]
+    FieldOutOfTable { field: usize, offset: usize },
+
+    #[snafu(display("String at offset {} is not null terminated valid UTF-8", offset))]
+    InvalidString { offset: usize },
+
+    #[snafu(display("Invalid {} {} at offset {}", what, value, offset))]
+    InvalidValue {
+        what: &'static str,
+        value: u8,
+        offset: usize,
+    },
+
+    #[snafu(display("Buffer refers to more data than its {} bytes", len))]
+    ExceedsBuffer { len: usize },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A table of the buffer, whose vtable has been checked
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pos: usize,
+    vtable: usize,
+    vtable_len: usize,
+    object_len: usize,
+}
+
+/// A vector of the buffer, whose elements are in the buffer
+#[derive(Debug, Clone, Copy)]
+pub struct Vector {
+    /// The position of the first element
+    pos: usize,
+    len: usize,
+}
+
+impl Vector {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Walks a flatbuffer, checking each table, vector and string it is asked to
+#[derive(Debug)]
+pub struct Verifier<'a> {
+    buf: &'a [u8],
+    /// The bytes the tables and vectors still to be visited may add up to
+    budget: usize,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            budget: buf.len(),
+        }
+    }
+
+    /// Returns the root table of the buffer
+    pub fn root(&mut self) -> Result<Table> {
+        let pos = self.follow(0, "root offset")?;
+        self.table_at(pos)
+    }
+
+    /// Checks the scalar field `id` of `size` bytes, if present
+    pub fn scalar(&self, table: &Table, id: usize, size: usize) -> Result<()> {
+        self.field(table, id, size).map(|_| ())
+    }
+
+    /// Returns the value of the enum field `id` of a byte, if present, failing if it is above
+    /// `max`. The type of a union is such a field.
+    pub fn enum_value(
+        &self,
+        table: &Table,
+        id: usize,
+        what: &'static str,
+        max: u8,
+    ) -> Result<Option<u8>> {
+        match self.field(table, id, 1)? {
+            Some(pos) => {
+                let value = self.buf[pos];
+                ensure!(
+                    value <= max,
+                    InvalidValue {
+                        what,
+                        value,
+                        offset: pos
+                    }
+                );
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the table of the field `id`, if present
+    pub fn table(&mut self, table: &Table, id: usize) -> Result<Option<Table>> {
+        match self.field(table, id, 4)? {
+            Some(pos) => {
+                let pos = self.follow(pos, "table offset")?;
+                self.table_at(pos).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Checks the string of the field `id`, if present
+    pub fn string(&mut self, table: &Table, id: usize) -> Result<()> {
+        if let Some(pos) = self.field(table, id, 4)? {
+            let pos = self.follow(pos, "string offset")?;
+            self.string_at(pos)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the vector of elements of `elem_size` bytes of the field `id`, if present. The
+    /// elements of a vector of tables or strings are their offsets, of 4 bytes.
+    pub fn vector(&mut self, table: &Table, id: usize, elem_size: usize) -> Result<Option<Vector>> {
+        match self.field(table, id, 4)? {
+            Some(pos) => {
+                let pos = self.follow(pos, "vector offset")?;
+                self.vector_at(pos, elem_size).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the table at `index` of a vector of tables
+    pub fn vector_table(&mut self, vector: &Vector, index: usize) -> Result<Table> {
+        let pos = self.follow(vector.pos + 4 * index, "table offset")?;
+        self.table_at(pos)
+    }
+
+    /// Checks the string at `index` of a vector of strings
+    pub fn vector_string(&mut self, vector: &Vector, index: usize) -> Result<()> {
+        let pos = self.follow(vector.pos + 4 * index, "string offset")?;
+        self.string_at(pos)
+    }
+
+    /// Checks that each element of a vector of booleans is 0 or 1
+    pub fn bools(&self, vector: &Vector) -> Result<()> {
+        let values = &self.buf[vector.pos..vector.pos + vector.len];
+        match values.iter().position(|&value| value > 1) {
+            Some(index) => InvalidValue {
+                what: "bool",
+                value: values[index],
+                offset: vector.pos + index,
+            }
+            .fail(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the position of the field `id` of `size` bytes, which is also its alignment, if
+    /// the table has it
+    fn field(&self, table: &Table, id: usize, size: usize) -> Result<Option<usize>> {
+        let slot = 4 + 2 * id;
+        if slot + 2 > table.vtable_len {
+            return Ok(None);
+        }
+
+        let offset = self.u16_at(table.vtable + slot, "vtable")? as usize;
+        if offset == 0 {
+            return Ok(None);
+        }
+        ensure!(
+            offset >= 4 && offset + size <= table.object_len,
+            FieldOutOfTable {
+                field: id,
+                offset: table.pos
+            }
+        );
+
+        let pos = table.pos + offset;
+        aligned("field", pos, size)?;
+        Ok(Some(pos))
+    }
+
+    fn table_at(&mut self, pos: usize) -> Result<Table> {
+        self.spend(4)?;
+        aligned("table", pos, 4)?;
+        let soffset = self.u32_at(pos, "table")? as i32;
+
+        let vtable = pos as i64 - i64::from(soffset);
+        ensure!(
+            vtable >= 0 && vtable as usize + 4 <= self.buf.len(),
+            InvalidVtable { offset: pos }
+        );
+        let vtable = vtable as usize;
+        aligned("vtable", vtable, 2)?;
+
+        let vtable_len = self.u16_at(vtable, "vtable")? as usize;
+        let object_len = self.u16_at(vtable + 2, "vtable")? as usize;
+        ensure!(
+            vtable_len >= 4 && vtable_len % 2 == 0 && object_len >= 4,
+            InvalidVtable { offset: pos }
+        );
+        self.range("vtable", vtable, vtable_len)?;
+        self.range("table", pos, object_len)?;
+
+        Ok(Table {
+            pos,
+            vtable,
+            vtable_len,
+            object_len,
+        })
+    }
+
+    fn vector_at(&mut self, pos: usize, elem_size: usize) -> Result<Vector> {
+        aligned("vector", pos, 4)?;
+        let len = self.u32_at(pos, "vector")? as usize;
+        let size = len.checked_mul(elem_size).context(ExceedsBuffer {
+            len: self.buf.len(),
+        })?;
+        self.spend(4 + size)?;
+
+        let elements = pos + 4;
+        aligned("vector", elements, elem_size.min(8))?;
+        self.range("vector", elements, size)?;
+
+        Ok(Vector { pos: elements, len })
+    }
+
+    fn string_at(&mut self, pos: usize) -> Result<()> {
+        let vector = self.vector_at(pos, 1)?;
+        let terminator = vector.pos + vector.len;
+        let terminated = self.range("string", terminator, 1)?[0] == 0;
+        let valid = std::str::from_utf8(&self.buf[vector.pos..terminator]).is_ok();
+        ensure!(terminated && valid, InvalidString { offset: pos });
+        Ok(())
+    }
+
+    /// Returns the position `pos` plus the offset stored at `pos`
+    fn follow(&self, pos: usize, what: &'static str) -> Result<usize> {
+        let offset = self.u32_at(pos, what)? as usize;
+        Ok(pos + offset)
+    }
+
+    fn spend(&mut self, bytes: usize) -> Result<()> {
+        self.budget = self.budget.checked_sub(bytes).context(ExceedsBuffer {
+            len: self.buf.len(),
+        })?;
+        Ok(())
+    }
+
+    fn range(&self, what: &'static str, offset: usize, size: usize) -> Result<&'a [u8]> {
+        offset
+            .checked_add(size)
+            .and_then(|end| self.buf.get(offset..end))
+            .context(OutOfBounds {
+                what,
+                offset,
+                len: self.buf.len(),
+            })
+    }
+
+    fn u16_at(&self, offset: usize, what: &'static str) -> Result<u16> {
+        let bytes = self.range(what, offset, 2)?;
+        Ok(u16::from_le_bytes(bytes.try_into().expect("2 bytes")))
+    }
+
+    fn u32_at(&self, offset: usize, what: &'static str) -> Result<u32> {
+        let bytes = self.range(what, offset, 4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().expect("4 bytes")))
+    }
+}
+
+fn aligned(what: &'static str, offset: usize, alignment: usize) -> Result<()> {
+    ensure!(
+        offset % alignment == 0,
+        Unaligned {
+            what,
+            offset,
+            alignment
+        }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flatbuffers::{FlatBufferBuilder, WIPOffset};
+
+    /// Returns a buffer whose root table has a vector of booleans as field 0, a string as
+    /// field 1 and a u64 as field 2, along with the positions of the vector and the string
+    fn buffer(bools: &[u8], string: &str) -> (Vec<u8>, usize, usize) {
+        let mut fbb = FlatBufferBuilder::new();
+        let vector = fbb.create_vector(bools);
+        let string = fbb.create_string(string);
+        let start = fbb.start_table();
+        fbb.push_slot::<u64>(8, 7, 0);
+        fbb.push_slot_always::<WIPOffset<_>>(4, vector);
+        fbb.push_slot_always::<WIPOffset<_>>(6, string);
+        let root = fbb.end_table(start);
+        fbb.finish_minimal(root);
+
+        let data = fbb.finished_data().to_vec();
+        let vector = data.len() - vector.value() as usize;
+        let string = data.len() - string.value() as usize;
+        (data, vector, string)
+    }
+
+    fn verify(data: &[u8]) -> Result<()> {
+        let mut verifier = Verifier::new(data);
+        let root = verifier.root()?;
+        if let Some(vector) = verifier.vector(&root, 0, 1)? {
+            verifier.bools(&vector)?;
+        }
+        verifier.string(&root, 1)?;
+        verifier.scalar(&root, 2, 8)
+    }
+
+    #[test]
+    fn verify_buffers() {
+        let (data, ..) = buffer(&[0, 1, 1], "hello");
+        verify(&data).unwrap();
+        for len in 0..data.len() {
+            // must not panic
+            let _ = verify(&data[..len]);
+        }
+
+        let (mut data, ..) = buffer(&[0, 1, 1], "hello");
+        data[0..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        assert!(matches!(verify(&data), Err(Error::OutOfBounds { .. })));
+
+        // a vector claiming more elements than the buffer holds
+        let (mut data, vector, _) = buffer(&[0, 1, 1], "hello");
+        data[vector..vector + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(verify(&data), Err(Error::ExceedsBuffer { .. })));
+
+        let (data, ..) = buffer(&[0, 2], "hello");
+        assert!(matches!(
+            verify(&data),
+            Err(Error::InvalidValue { value: 2, .. })
+        ));
+
+        let (mut data, _, string) = buffer(&[0], "hello");
+        data[string + 4] = 0xff;
+        assert!(matches!(verify(&data), Err(Error::InvalidString { .. })));
+        let (mut data, _, string) = buffer(&[0], "hello");
+        data[string + 4 + 5] = b'!';
+        assert!(matches!(verify(&data), Err(Error::InvalidString { .. })));
+    }
+
+    #[test]
+    fn offsets_to_the_same_data_exceed_the_buffer() {
+        let mut fbb = FlatBufferBuilder::new();
+        let string = fbb.create_string(&"x".repeat(100));
+        let strings = fbb.create_vector(&vec![string; 100]);
+        let start = fbb.start_table();
+        fbb.push_slot_always::<WIPOffset<_>>(4, strings);
+        let root = fbb.end_table(start);
+        fbb.finish_minimal(root);
+
+        let mut verifier = Verifier::new(fbb.finished_data());
+        let root = verifier.root().unwrap();
+        let strings = verifier.vector(&root, 0, 4).unwrap().unwrap();
+        let err = (0..strings.len())
+            .try_for_each(|i| verifier.vector_string(&strings, i))
+            .unwrap_err();
+        assert!(matches!(err, Error::ExceedsBuffer { .. }));
+    }
+}
@@ -15,30 +15,76 @@ fn main() -> Result<()> {
     let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
 
     generate_grpc_types(&root)?;
-    generate_wal_types(&root)?;
+    generate_flatbuffers_types(&root)?;
 
     Ok(())
 }
 
 /// Schema used with gRPC requests
 ///
-/// Creates `influxdata.platform.storage.rs`
+/// Creates `influxdata.platform.storage.rs`,
+/// `influxdata.iox.management.v1.rs`, `influxdata.iox.query.v1.rs`,
+/// `influxdata.iox.write.v1.rs`, the `opentelemetry.proto.*.rs` files of
+/// the OpenTelemetry metrics service, the `arrow.flight.protocol*.rs` files
+/// of the Arrow Flight SQL service and the `grpc.*.rs` files of the health and
+/// reflection services. Also creates `proto_descriptor.bin`, the descriptors
+/// of every file, served by the reflection service.
 fn generate_grpc_types(root: &Path) -> Result<()> {
-    let proto_file = root.join("influxdb_iox.proto");
+    let otel = root.join("opentelemetry").join("proto");
+    let flight = root.join("arrow").join("flight").join("protocol");
+    let grpc = root.join("grpc");
+    let proto_files = vec![
+        root.join("influxdb_iox.proto"),
+        root.join("management.proto"),
+        root.join("query.proto"),
+        root.join("write.proto"),
+        otel.join("common/v1/common.proto"),
+        otel.join("resource/v1/resource.proto"),
+        otel.join("metrics/v1/metrics.proto"),
+        otel.join("collector/metrics/v1/metrics_service.proto"),
+        flight.join("Flight.proto"),
+        flight.join("FlightSql.proto"),
+        grpc.join("health/v1/health.proto"),
+        grpc.join("reflection/v1alpha/reflection.proto"),
+    ];
 
-    println!("cargo:rerun-if-changed={}", proto_file.display());
-    tonic_build::compile_protos(proto_file)?;
+    for proto_file in &proto_files {
+        println!("cargo:rerun-if-changed={}", proto_file.display());
+    }
+
+    tonic_build::configure().compile(&proto_files, &[root.to_path_buf()])?;
+
+    let out_dir =
+        PathBuf::from(std::env::var_os("OUT_DIR").expect("Could not determine `OUT_DIR`"));
+    let status = Command::new(prost_build::protoc())
+        .arg("--include_imports")
+        .arg(format!(
+            "--descriptor_set_out={}",
+            out_dir.join("proto_descriptor.bin").display()
+        ))
+        .arg(format!("--proto_path={}", root.display()))
+        .arg(format!(
+            "--proto_path={}",
+            prost_build::protoc_include().display()
+        ))
+        .args(&proto_files)
+        .status()?;
+    if !status.success() {
+        return Err("`protoc` failed to write the file descriptor set".into());
+    }
 
     Ok(())
 }
 
-/// Schema used in the WAL
+/// Schema used in the WAL and for write entries
 ///
-/// Creates `wal_generated.rs`
-fn generate_wal_types(root: &Path) -> Result<()> {
-    let wal_file = root.join("wal.fbs");
+/// Creates `wal_generated.rs` and `entry_generated.rs`
+fn generate_flatbuffers_types(root: &Path) -> Result<()> {
+    let fbs_files = vec![root.join("wal.fbs"), root.join("entry.fbs")];
 
-    println!("cargo:rerun-if-changed={}", wal_file.display());
+    for fbs_file in &fbs_files {
+        println!("cargo:rerun-if-changed={}", fbs_file.display());
+    }
     let out_dir: PathBuf = std::env::var_os("OUT_DIR")
         .expect("Could not determine `OUT_DIR`")
         .into();
@@ -47,7 +93,7 @@ fn generate_wal_types(root: &Path) -> Result<()> {
         .arg("--rust")
         .arg("-o")
         .arg(&out_dir)
-        .arg(wal_file)
+        .args(&fbs_files)
         .status();
 
     match status {
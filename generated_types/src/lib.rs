@@ -9,6 +9,99 @@
 include!(concat!(env!("OUT_DIR"), "/influxdata.platform.storage.rs"));
 include!(concat!(env!("OUT_DIR"), "/wal_generated.rs"));
 
+// The generated code of each schema has its own imports, which would clash at the root
+mod entry_generated {
+    include!(concat!(env!("OUT_DIR"), "/entry_generated.rs"));
+}
+
+/// The columnar write entries that writes are stored, logged and replicated as
+pub use entry_generated::entry;
+
+/// Types and services of the management API, used to configure the
+/// databases served by an IOx server
+pub mod management {
+    include!(concat!(env!("OUT_DIR"), "/influxdata.iox.management.v1.rs"));
+}
+
+/// Types and services of the query API, used to run SQL queries against the
+/// databases served by an IOx server
+pub mod query {
+    include!(concat!(env!("OUT_DIR"), "/influxdata.iox.query.v1.rs"));
+}
+
+/// Types and services of the write API, used to write entries that were
+/// already converted from line protocol
+pub mod write {
+    include!(concat!(env!("OUT_DIR"), "/influxdata.iox.write.v1.rs"));
+}
+
+/// Types and services of the OpenTelemetry protocol (OTLP), used to receive the metrics
+/// exported by OpenTelemetry SDKs and collectors
+pub mod opentelemetry {
+    pub mod proto {
+        pub mod common {
+            pub mod v1 {
+                include!(concat!(env!("OUT_DIR"), "/opentelemetry.proto.common.v1.rs"));
+            }
+        }
+
+        pub mod resource {
+            pub mod v1 {
+                include!(concat!(env!("OUT_DIR"), "/opentelemetry.proto.resource.v1.rs"));
+            }
+        }
+
+        pub mod metrics {
+            pub mod v1 {
+                include!(concat!(env!("OUT_DIR"), "/opentelemetry.proto.metrics.v1.rs"));
+            }
+        }
+
+        pub mod collector {
+            pub mod metrics {
+                pub mod v1 {
+                    include!(concat!(
+                        env!("OUT_DIR"),
+                        "/opentelemetry.proto.collector.metrics.v1.rs"
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Types and services of the Arrow Flight protocol and of Flight SQL, used by JDBC and ODBC
+/// drivers to run SQL queries and list the tables of a server
+pub mod arrow_flight {
+    pub mod protocol {
+        include!(concat!(env!("OUT_DIR"), "/arrow.flight.protocol.rs"));
+
+        pub mod sql {
+            include!(concat!(env!("OUT_DIR"), "/arrow.flight.protocol.sql.rs"));
+        }
+    }
+}
+
+/// Types and services of the standard gRPC health checking and server reflection protocols
+pub mod grpc {
+    pub mod health {
+        pub mod v1 {
+            include!(concat!(env!("OUT_DIR"), "/grpc.health.v1.rs"));
+        }
+    }
+
+    pub mod reflection {
+        pub mod v1alpha {
+            include!(concat!(env!("OUT_DIR"), "/grpc.reflection.v1alpha.rs"));
+        }
+    }
+}
+
+/// The encoded `google.protobuf.FileDescriptorSet` of every protobuf file of the gRPC services
+/// and of the files they import, served by the reflection service
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/proto_descriptor.bin"));
+
 // Can't implement `Default` because `prost::Message` implements `Default`
 impl TimestampRange {
     pub fn max() -> Self {
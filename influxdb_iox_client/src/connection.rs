@@ -0,0 +1,174 @@
+//! Connections to the gRPC API of an IOx server, which all clients share.
+
+use std::time::Duration;
+
+use snafu::ResultExt;
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    transport::{Channel, Endpoint},
+    Request,
+};
+
+use crate::error::{Connecting, Error, Result};
+
+/// The header that carries the token requests authenticate with
+const AUTHORIZATION_HEADER: &str = "authorization";
+
+/// Configures and opens a [`Connection`].
+///
+/// ```no_run
+/// # async fn example() -> Result<(), influxdb_iox_client::Error> {
+/// use std::time::Duration;
+///
+/// let connection = influxdb_iox_client::Builder::default()
+///     .token("some-token")
+///     .timeout(Duration::from_secs(30))
+///     .build("http://127.0.0.1:8082")
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct Builder {
+    token: Option<String>,
+    timeout: Option<Duration>,
+    concurrency_limit: Option<usize>,
+}
+
+impl Builder {
+    /// Authenticates the requests of the connection with the secret of a token.
+    pub fn token(self, token: impl Into<String>) -> Self {
+        Self {
+            token: Some(token.into()),
+            ..self
+        }
+    }
+
+    /// Fails requests that take longer than `timeout`. Requests don't time out by default.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Limits the number of requests that are in flight at once.
+    pub fn concurrency_limit(self, limit: usize) -> Self {
+        Self {
+            concurrency_limit: Some(limit),
+            ..self
+        }
+    }
+
+    /// Connects to the gRPC API of the server at `url`, such as `http://127.0.0.1:8082`.
+    pub async fn build(self, url: impl Into<String>) -> Result<Connection> {
+        let url = url.into();
+        let authorization = self.authorization()?;
+        let channel = self
+            .endpoint(&url)?
+            .connect()
+            .await
+            .context(Connecting { url })?;
+
+        Ok(Connection {
+            channel,
+            authorization,
+        })
+    }
+
+    /// Creates a connection to the server at `url` that is only opened by the first request,
+    /// so that the server does not have to be up yet.
+    pub fn build_lazy(self, url: impl Into<String>) -> Result<Connection> {
+        let url = url.into();
+        let authorization = self.authorization()?;
+        let channel = self
+            .endpoint(&url)?
+            .connect_lazy()
+            .context(Connecting { url })?;
+
+        Ok(Connection {
+            channel,
+            authorization,
+        })
+    }
+
+    fn endpoint(&self, url: &str) -> Result<Endpoint> {
+        let mut endpoint = Endpoint::from_shared(url.to_string())
+            .map_err(|_| Error::InvalidUrl { url: url.into() })?;
+        if let Some(timeout) = self.timeout {
+            endpoint = endpoint.timeout(timeout);
+        }
+        if let Some(limit) = self.concurrency_limit {
+            endpoint = endpoint.concurrency_limit(limit);
+        }
+        Ok(endpoint)
+    }
+
+    fn authorization(&self) -> Result<Option<MetadataValue<Ascii>>> {
+        self.token
+            .as_ref()
+            .map(|token| {
+                MetadataValue::from_str(&format!("Token {}", token))
+                    .map_err(|_| Error::InvalidToken)
+            })
+            .transpose()
+    }
+}
+
+/// A connection to an IOx server. Connections are cheap to clone, and the clones share the
+/// underlying HTTP/2 connection, so one connection can serve all clients of a process.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    channel: Channel,
+    authorization: Option<MetadataValue<Ascii>>,
+}
+
+impl Connection {
+    /// The channel the clients send their requests over
+    pub(crate) fn channel(&self) -> Channel {
+        self.channel.clone()
+    }
+
+    /// Wraps `message` into a request carrying the token of the connection
+    pub(crate) fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert(AUTHORIZATION_HEADER, authorization.clone());
+        }
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tokens() {
+        let connection = Builder::default()
+            .build_lazy("http://127.0.0.1:8082")
+            .unwrap();
+        assert!(connection.request(()).metadata().is_empty());
+
+        let connection = Builder::default()
+            .token("secret")
+            .build_lazy("http://127.0.0.1:8082")
+            .unwrap();
+        let request = connection.request(());
+        assert_eq!(
+            request.metadata().get(AUTHORIZATION_HEADER).unwrap(),
+            "Token secret"
+        );
+
+        let err = Builder::default()
+            .token("new\nline")
+            .build_lazy("http://127.0.0.1:8082")
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidToken));
+
+        let err = Builder::default().build_lazy("not a url").unwrap_err();
+        assert!(matches!(err, Error::InvalidUrl { .. }));
+    }
+}
@@ -0,0 +1,126 @@
+//! The errors returned by the clients.
+
+use arrow_deps::arrow::error::ArrowError;
+use snafu::Snafu;
+use tonic::{Code, Status};
+
+/// Errors that occur while connecting to an IOx server, or while making requests to it.
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    /// The URL of the server is not valid.
+    #[snafu(display("Invalid server URL: {}", url))]
+    InvalidUrl {
+        /// The URL that was given.
+        url: String,
+    },
+
+    /// The server could not be reached.
+    #[snafu(display("Error connecting to {}: {}", url, source))]
+    Connecting {
+        /// The URL of the server.
+        url: String,
+        /// The underlying error from `tonic`.
+        source: tonic::transport::Error,
+    },
+
+    /// The token contains characters that can't be sent in a header.
+    #[snafu(display("Invalid token: it can only contain visible ASCII characters"))]
+    InvalidToken,
+
+    /// The request did not carry a valid token.
+    #[snafu(display("Unauthenticated: {}", message))]
+    Unauthenticated {
+        /// The message of the server.
+        message: String,
+    },
+
+    /// The token of the request does not grant the permission the request requires.
+    #[snafu(display("Permission denied: {}", message))]
+    PermissionDenied {
+        /// The message of the server.
+        message: String,
+    },
+
+    /// A database, partition, chunk or operation the request refers to does not exist.
+    #[snafu(display("Not found: {}", message))]
+    NotFound {
+        /// The message of the server.
+        message: String,
+    },
+
+    /// The database or token the request creates already exists.
+    #[snafu(display("Already exists: {}", message))]
+    AlreadyExists {
+        /// The message of the server.
+        message: String,
+    },
+
+    /// The server rejected the arguments of the request.
+    #[snafu(display("Invalid argument: {}", message))]
+    InvalidArgument {
+        /// The message of the server.
+        message: String,
+    },
+
+    /// The server can't handle the request in its current state, for example because its
+    /// writer id is not set.
+    #[snafu(display("Failed precondition: {}", message))]
+    FailedPrecondition {
+        /// The message of the server.
+        message: String,
+    },
+
+    /// The server returned another error.
+    #[snafu(display("Server error: {}", source))]
+    ServerError {
+        /// The status returned by the server.
+        source: Status,
+    },
+
+    /// The response of the server lacks a field that is always set.
+    #[snafu(display("The server did not return the {}", field))]
+    EmptyResponse {
+        /// The name of the missing field.
+        field: &'static str,
+    },
+
+    /// The results of a query could not be decoded.
+    #[snafu(display("Error decoding the results: {}", source))]
+    DecodingResults {
+        /// The underlying error from `arrow`.
+        source: ArrowError,
+    },
+}
+
+impl From<Status> for Error {
+    fn from(status: Status) -> Self {
+        let message = status.message().to_string();
+        match status.code() {
+            Code::Unauthenticated => Self::Unauthenticated { message },
+            Code::PermissionDenied => Self::PermissionDenied { message },
+            Code::NotFound => Self::NotFound { message },
+            Code::AlreadyExists => Self::AlreadyExists { message },
+            Code::InvalidArgument => Self::InvalidArgument { message },
+            Code::FailedPrecondition => Self::FailedPrecondition { message },
+            _ => Self::ServerError { source: status },
+        }
+    }
+}
+
+/// A specialized `Result` for the errors of the clients.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses() {
+        let err = Error::from(Status::not_found("Database foo not found"));
+        assert_eq!(err.to_string(), "Not found: Database foo not found");
+
+        let err = Error::from(Status::internal("boom"));
+        assert!(matches!(err, Error::ServerError { .. }));
+    }
+}
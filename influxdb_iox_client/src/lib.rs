@@ -0,0 +1,63 @@
+#![deny(rust_2018_idioms)]
+#![warn(
+    missing_copy_implementations,
+    missing_debug_implementations,
+    missing_docs,
+    clippy::explicit_iter_loop,
+    clippy::use_self
+)]
+
+//! # influxdb_iox_client
+//!
+//! This is a Rust client for the gRPC APIs of InfluxDB IOx: the management, operations, query
+//! and write APIs each have a client. The clients share a [`Connection`], which is opened with
+//! a [`Builder`] and carries the token requests authenticate with.
+//!
+//! ## Quick start
+//!
+//! This example connects to an IOx server running at `http://127.0.0.1:8082`, creates the
+//! database "mydb" and queries it.
+//!
+//! ```no_run
+//! async fn example() -> Result<(), influxdb_iox_client::Error> {
+//!     use influxdb_iox_client::{generated_types::management::DatabaseRules, *};
+//!
+//!     let connection = Builder::default()
+//!         .token("some-token")
+//!         .build("http://127.0.0.1:8082")
+//!         .await?;
+//!
+//!     let rules = DatabaseRules {
+//!         name: "mydb".to_string(),
+//!         store_locally: true,
+//!         query_local: true,
+//!         ..Default::default()
+//!     };
+//!     ManagementClient::new(connection.clone())
+//!         .create_database(rules)
+//!         .await?;
+//!
+//!     let batches = QueryClient::new(connection)
+//!         .query("mydb", "select * from cpu")
+//!         .await?;
+//!     println!("{} batches", batches.len());
+//!     Ok(())
+//! }
+//! ```
+
+pub mod connection;
+pub mod error;
+pub mod management;
+pub mod operations;
+pub mod query;
+pub mod write;
+
+pub use connection::{Builder, Connection};
+pub use error::{Error, Result};
+pub use management::ManagementClient;
+pub use operations::OperationsClient;
+pub use query::QueryClient;
+pub use write::WriteClient;
+
+/// The protobuf types the clients send and return
+pub use generated_types;
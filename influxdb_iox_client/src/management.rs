@@ -0,0 +1,580 @@
+//! A client for the management API of IOx, which configures the server and its databases.
+
+use generated_types::management::{
+    chunk_policy::ChunkId, management_service_client::ManagementServiceClient, Check, Chunk,
+    ChunkPolicy, CloseChunkRequest, CreateCheckRequest, CreateDatabaseRequest,
+    CreateDummyJobRequest, CreateRulesTemplateRequest, CreateTaskRequest, CreateTokenRequest,
+    DatabaseRules, DatabaseRulesUpdate, DatabaseRulesVersion, DeleteCheckRequest,
+    DeleteChunkPolicyRequest, DeleteRequest, DeleteRulesTemplateRequest, DeleteTaskRequest,
+    DeleteTokenRequest, DropDimensionTableRequest, ExportDatabaseRequest,
+    ForceClaimDatabaseRequest, GetDatabaseRequest, GetWriterIdRequest, ImportDataRequest,
+    ListChecksRequest, ListChecksResponse, ListChunkPoliciesRequest, ListChunksRequest,
+    ListDatabaseRulesVersionsRequest, ListDatabasesRequest, ListRulesTemplatesRequest,
+    ListTasksRequest, ListTasksResponse, ListTokensRequest, LoadDimensionTableRequest,
+    MoveChunkRequest, Operation, PauseIngestRequest, PauseTaskRequest, PersistChunkRequest,
+    PersistedChunk, RebuildCatalogRequest, RebuildCatalogResponse, ReleaseDatabaseRequest,
+    RestoreDatabaseRequest, RollbackDatabaseRulesRequest, SetChunkPolicyRequest,
+    SkipWalReplayRequest, SnapshotDatabaseRequest, Task, Token, UpdateDatabaseRulesRequest,
+    UpdateRulesTemplateRequest, UpdateWriterIdRequest, VerifiedFile, VerifyCatalogRequest,
+    WalReplay,
+};
+use snafu::OptionExt;
+use tonic::transport::Channel;
+
+use crate::{
+    error::{EmptyResponse, Result},
+    Connection,
+};
+
+/// A client for the management API of an IOx server.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), influxdb_iox_client::Error> {
+/// use influxdb_iox_client::{Builder, ManagementClient};
+///
+/// let connection = Builder::default().build("http://127.0.0.1:8082").await?;
+/// let mut client = ManagementClient::new(connection);
+/// for name in client.list_databases().await? {
+///     println!("{}", name);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManagementClient {
+    inner: ManagementServiceClient<Channel>,
+    connection: Connection,
+}
+
+impl ManagementClient {
+    /// Creates a client that sends its requests over `connection`.
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            inner: ManagementServiceClient::new(connection.channel()),
+            connection,
+        }
+    }
+
+    /// Returns the writer id of the server, which is 0 if it is not set yet.
+    pub async fn get_writer_id(&mut self) -> Result<u32> {
+        let request = self.connection.request(GetWriterIdRequest {});
+        Ok(self.inner.get_writer_id(request).await?.into_inner().id)
+    }
+
+    /// Sets the writer id of the server, which it requires before it accepts writes.
+    pub async fn update_writer_id(&mut self, id: u32) -> Result<()> {
+        let request = self.connection.request(UpdateWriterIdRequest { id });
+        self.inner.update_writer_id(request).await?;
+        Ok(())
+    }
+
+    /// Returns the names of the databases of the server.
+    pub async fn list_databases(&mut self) -> Result<Vec<String>> {
+        let request = self.connection.request(ListDatabasesRequest {});
+        Ok(self.inner.list_databases(request).await?.into_inner().names)
+    }
+
+    /// Returns the rules of the database `name`.
+    pub async fn get_database(&mut self, name: impl Into<String>) -> Result<DatabaseRules> {
+        let request = self
+            .connection
+            .request(GetDatabaseRequest { name: name.into() });
+        self.inner
+            .get_database(request)
+            .await?
+            .into_inner()
+            .rules
+            .context(EmptyResponse { field: "rules" })
+    }
+
+    /// Creates a database with `rules`, whose name is the name of the database.
+    pub async fn create_database(&mut self, rules: DatabaseRules) -> Result<()> {
+        let request = self
+            .connection
+            .request(CreateDatabaseRequest { rules: Some(rules) });
+        self.inner.create_database(request).await?;
+        Ok(())
+    }
+
+    /// Replaces the rules of the database named by `rules`, returning the generation they were
+    /// stored as.
+    pub async fn update_database_rules(&mut self, rules: DatabaseRules) -> Result<u64> {
+        let request = self
+            .connection
+            .request(UpdateDatabaseRulesRequest { rules: Some(rules) });
+        Ok(self
+            .inner
+            .update_database_rules(request)
+            .await?
+            .into_inner()
+            .generation)
+    }
+
+    /// Returns every stored version of the rules of the database `db_name`, oldest first.
+    pub async fn list_database_rules_versions(
+        &mut self,
+        db_name: impl Into<String>,
+    ) -> Result<Vec<DatabaseRulesVersion>> {
+        let request = self.connection.request(ListDatabaseRulesVersionsRequest {
+            db_name: db_name.into(),
+        });
+        Ok(self
+            .inner
+            .list_database_rules_versions(request)
+            .await?
+            .into_inner()
+            .versions)
+    }
+
+    /// Sets the rules of the database `db_name` back to generation `generation`, returning the
+    /// generation they were stored as again.
+    pub async fn rollback_database_rules(
+        &mut self,
+        db_name: impl Into<String>,
+        generation: u64,
+    ) -> Result<u64> {
+        let request = self.connection.request(RollbackDatabaseRulesRequest {
+            db_name: db_name.into(),
+            generation,
+        });
+        Ok(self
+            .inner
+            .rollback_database_rules(request)
+            .await?
+            .into_inner()
+            .generation)
+    }
+
+    /// Releases the database `name`, so that another server can take it over.
+    pub async fn release_database(&mut self, name: impl Into<String>) -> Result<()> {
+        let request = self
+            .connection
+            .request(ReleaseDatabaseRequest { name: name.into() });
+        self.inner.release_database(request).await?;
+        Ok(())
+    }
+
+    /// Pauses the writes to the database `db_name`, or resumes them if `paused` is false.
+    pub async fn pause_ingest(&mut self, db_name: impl Into<String>, paused: bool) -> Result<()> {
+        let request = self.connection.request(PauseIngestRequest {
+            db_name: db_name.into(),
+            paused,
+        });
+        self.inner.pause_ingest(request).await?;
+        Ok(())
+    }
+
+    /// Skips the replay of the WAL of the database `db_name`, which failed, to the entry
+    /// `sequence_number` and replays the WAL again. Returns how the new replay went.
+    pub async fn skip_wal_replay(
+        &mut self,
+        db_name: impl Into<String>,
+        sequence_number: u64,
+    ) -> Result<WalReplay> {
+        let request = self.connection.request(SkipWalReplayRequest {
+            db_name: db_name.into(),
+            sequence_number,
+        });
+        let response = self.inner.skip_wal_replay(request).await?;
+        response.into_inner().wal_replay.context(EmptyResponse {
+            field: "wal_replay",
+        })
+    }
+
+    /// Returns the chunks of the database `db_name`.
+    pub async fn list_chunks(&mut self, db_name: impl Into<String>) -> Result<Vec<Chunk>> {
+        let request = self.connection.request(ListChunksRequest {
+            db_name: db_name.into(),
+        });
+        Ok(self.inner.list_chunks(request).await?.into_inner().chunks)
+    }
+
+    /// Closes the open chunk of a partition, returning the closed chunk.
+    pub async fn close_chunk(
+        &mut self,
+        db_name: impl Into<String>,
+        partition_key: impl Into<String>,
+    ) -> Result<Chunk> {
+        let request = self.connection.request(CloseChunkRequest {
+            db_name: db_name.into(),
+            partition_key: partition_key.into(),
+        });
+        self.inner
+            .close_chunk(request)
+            .await?
+            .into_inner()
+            .chunk
+            .context(EmptyResponse { field: "chunk" })
+    }
+
+    /// Moves a closed chunk of a partition to the read buffer, returning the chunk in the
+    /// read buffer.
+    pub async fn move_chunk(
+        &mut self,
+        db_name: impl Into<String>,
+        partition_key: impl Into<String>,
+        chunk_id: u32,
+    ) -> Result<Chunk> {
+        let request = self.connection.request(MoveChunkRequest {
+            db_name: db_name.into(),
+            partition_key: partition_key.into(),
+            chunk_id,
+        });
+        self.inner
+            .move_chunk(request)
+            .await?
+            .into_inner()
+            .chunk
+            .context(EmptyResponse { field: "chunk" })
+    }
+
+    /// Writes a closed chunk of a partition out to object storage.
+    pub async fn persist_chunk(
+        &mut self,
+        db_name: impl Into<String>,
+        partition_key: impl Into<String>,
+        chunk_id: u32,
+    ) -> Result<()> {
+        let request = self.connection.request(PersistChunkRequest {
+            db_name: db_name.into(),
+            partition_key: partition_key.into(),
+            chunk_id,
+        });
+        self.inner.persist_chunk(request).await?;
+        Ok(())
+    }
+
+    /// Sets the policy of a chunk of the read buffer of database `db_name`, or of every chunk
+    /// of a partition, replacing its previous policy if any
+    pub async fn set_chunk_policy(
+        &mut self,
+        db_name: impl Into<String>,
+        policy: ChunkPolicy,
+    ) -> Result<()> {
+        let request = self.connection.request(SetChunkPolicyRequest {
+            db_name: db_name.into(),
+            policy: Some(policy),
+        });
+        self.inner.set_chunk_policy(request).await?;
+        Ok(())
+    }
+
+    /// Lists the chunk policies of database `db_name`
+    pub async fn list_chunk_policies(
+        &mut self,
+        db_name: impl Into<String>,
+    ) -> Result<Vec<ChunkPolicy>> {
+        let request = self.connection.request(ListChunkPoliciesRequest {
+            db_name: db_name.into(),
+        });
+        Ok(self
+            .inner
+            .list_chunk_policies(request)
+            .await?
+            .into_inner()
+            .policies)
+    }
+
+    /// Removes the policy of the chunk `chunk_id` of a partition of database `db_name`, or of
+    /// the whole partition if none
+    pub async fn delete_chunk_policy(
+        &mut self,
+        db_name: impl Into<String>,
+        partition_key: impl Into<String>,
+        chunk_id: Option<u32>,
+    ) -> Result<()> {
+        let request = self.connection.request(DeleteChunkPolicyRequest {
+            db_name: db_name.into(),
+            partition_key: partition_key.into(),
+            chunk_id: chunk_id.map(|id| ChunkId { id }),
+        });
+        self.inner.delete_chunk_policy(request).await?;
+        Ok(())
+    }
+
+    /// Starts exporting the data selected by `request`, returning the operation that tracks
+    /// the export. See [`OperationsClient`](crate::OperationsClient) to wait for it.
+    pub async fn export_database(&mut self, request: ExportDatabaseRequest) -> Result<Operation> {
+        let request = self.connection.request(request);
+        self.inner
+            .export_database(request)
+            .await?
+            .into_inner()
+            .operation
+            .context(EmptyResponse { field: "operation" })
+    }
+
+    /// Starts taking a snapshot of database `db_name`, with its rules, its catalog and the
+    /// Parquet files of its persisted chunks, to `output`: `s3://bucket/prefix`,
+    /// `gs://bucket/prefix` or a directory of the server. Returns the operation copying the
+    /// files.
+    pub async fn snapshot_database(
+        &mut self,
+        db_name: impl Into<String>,
+        output: impl Into<String>,
+    ) -> Result<Operation> {
+        let request = self.connection.request(SnapshotDatabaseRequest {
+            db_name: db_name.into(),
+            output: output.into(),
+        });
+        self.inner
+            .snapshot_database(request)
+            .await?
+            .into_inner()
+            .operation
+            .context(EmptyResponse { field: "operation" })
+    }
+
+    /// Creates database `db_name` from the snapshot at `input`, which is the output a snapshot
+    /// was taken to followed by the name of the database snapshotted. Returns the chunks of the
+    /// restored catalog.
+    pub async fn restore_database(
+        &mut self,
+        db_name: impl Into<String>,
+        input: impl Into<String>,
+    ) -> Result<Vec<PersistedChunk>> {
+        let request = self.connection.request(RestoreDatabaseRequest {
+            db_name: db_name.into(),
+            input: input.into(),
+        });
+        Ok(self
+            .inner
+            .restore_database(request)
+            .await?
+            .into_inner()
+            .chunks)
+    }
+
+    /// Imports a CSV or Parquet file into new persisted chunks, which are returned.
+    pub async fn import_data(&mut self, request: ImportDataRequest) -> Result<Vec<PersistedChunk>> {
+        let request = self.connection.request(request);
+        Ok(self.inner.import_data(request).await?.into_inner().chunks)
+    }
+
+    /// Deletes the rows selected by `request` from the persisted chunks of a database
+    pub async fn delete(&mut self, request: DeleteRequest) -> Result<()> {
+        let request = self.connection.request(request);
+        self.inner.delete(request).await?;
+        Ok(())
+    }
+
+    /// Loads the CSV file `data` into database `db_name` as dimension table `table_name`,
+    /// returning its number of rows.
+    pub async fn load_dimension_table(
+        &mut self,
+        db_name: impl Into<String>,
+        table_name: impl Into<String>,
+        data: Vec<u8>,
+    ) -> Result<u64> {
+        let request = self.connection.request(LoadDimensionTableRequest {
+            db_name: db_name.into(),
+            table_name: table_name.into(),
+            data,
+        });
+        Ok(self
+            .inner
+            .load_dimension_table(request)
+            .await?
+            .into_inner()
+            .row_count)
+    }
+
+    /// Drops dimension table `table_name` of database `db_name`
+    pub async fn drop_dimension_table(
+        &mut self,
+        db_name: impl Into<String>,
+        table_name: impl Into<String>,
+    ) -> Result<()> {
+        let request = self.connection.request(DropDimensionTableRequest {
+            db_name: db_name.into(),
+            table_name: table_name.into(),
+        });
+        self.inner.drop_dimension_table(request).await?;
+        Ok(())
+    }
+
+    /// Checks the Parquet files of the persisted chunks of database `db_name`, or of every
+    /// database if empty, against the sizes and checksums recorded in their catalogs.
+    pub async fn verify_catalog(
+        &mut self,
+        db_name: impl Into<String>,
+    ) -> Result<Vec<VerifiedFile>> {
+        let request = self.connection.request(VerifyCatalogRequest {
+            db_name: db_name.into(),
+        });
+        Ok(self.inner.verify_catalog(request).await?.into_inner().files)
+    }
+
+    /// Rebuilds the catalog of database `db_name` from the Parquet files persisted under its
+    /// prefix in object storage, returning the chunks recovered and the files skipped.
+    pub async fn rebuild_catalog(
+        &mut self,
+        db_name: impl Into<String>,
+    ) -> Result<RebuildCatalogResponse> {
+        let request = self.connection.request(RebuildCatalogRequest {
+            db_name: db_name.into(),
+        });
+        Ok(self.inner.rebuild_catalog(request).await?.into_inner())
+    }
+
+    /// Takes over the ownership lease of database `db_name` even if another server holds it,
+    /// returning the fencing token of the new lease.
+    pub async fn force_claim_database(&mut self, db_name: impl Into<String>) -> Result<u64> {
+        let request = self.connection.request(ForceClaimDatabaseRequest {
+            db_name: db_name.into(),
+        });
+        Ok(self
+            .inner
+            .force_claim_database(request)
+            .await?
+            .into_inner()
+            .token)
+    }
+
+    /// Starts an operation that sleeps for each of `durations` in turn, to test the
+    /// operations API with.
+    pub async fn create_dummy_job(
+        &mut self,
+        durations: impl IntoIterator<Item = std::time::Duration>,
+    ) -> Result<Operation> {
+        let request = self.connection.request(CreateDummyJobRequest {
+            nanos: durations
+                .into_iter()
+                .map(|duration| duration.as_nanos() as u64)
+                .collect(),
+        });
+        self.inner
+            .create_dummy_job(request)
+            .await?
+            .into_inner()
+            .operation
+            .context(EmptyResponse { field: "operation" })
+    }
+
+    /// Creates `token`, returning its secret, which can not be retrieved later.
+    pub async fn create_token(&mut self, token: Token) -> Result<String> {
+        let request = self
+            .connection
+            .request(CreateTokenRequest { token: Some(token) });
+        Ok(self.inner.create_token(request).await?.into_inner().secret)
+    }
+
+    /// Returns the tokens of the server, without their secrets.
+    pub async fn list_tokens(&mut self) -> Result<Vec<Token>> {
+        let request = self.connection.request(ListTokensRequest {});
+        Ok(self.inner.list_tokens(request).await?.into_inner().tokens)
+    }
+
+    /// Deletes the token with the id `id`.
+    pub async fn delete_token(&mut self, id: impl Into<String>) -> Result<()> {
+        let request = self
+            .connection
+            .request(DeleteTokenRequest { id: id.into() });
+        self.inner.delete_token(request).await?;
+        Ok(())
+    }
+
+    /// Creates the continuous query `task`.
+    pub async fn create_task(&mut self, task: Task) -> Result<()> {
+        let request = self
+            .connection
+            .request(CreateTaskRequest { task: Some(task) });
+        self.inner.create_task(request).await?;
+        Ok(())
+    }
+
+    /// Lists the continuous queries of the server and their recent runs.
+    pub async fn list_tasks(&mut self) -> Result<ListTasksResponse> {
+        let request = self.connection.request(ListTasksRequest {});
+        Ok(self.inner.list_tasks(request).await?.into_inner())
+    }
+
+    /// Pauses the continuous query `name`, or resumes it if `paused` is false.
+    pub async fn pause_task(&mut self, name: impl Into<String>, paused: bool) -> Result<()> {
+        let request = self.connection.request(PauseTaskRequest {
+            name: name.into(),
+            paused,
+        });
+        self.inner.pause_task(request).await?;
+        Ok(())
+    }
+
+    /// Deletes the continuous query `name`.
+    pub async fn delete_task(&mut self, name: impl Into<String>) -> Result<()> {
+        let request = self
+            .connection
+            .request(DeleteTaskRequest { name: name.into() });
+        self.inner.delete_task(request).await?;
+        Ok(())
+    }
+
+    /// Creates the threshold check `check`.
+    pub async fn create_check(&mut self, check: Check) -> Result<()> {
+        let request = self
+            .connection
+            .request(CreateCheckRequest { check: Some(check) });
+        self.inner.create_check(request).await?;
+        Ok(())
+    }
+
+    /// Lists the threshold checks of the server and their current state.
+    pub async fn list_checks(&mut self) -> Result<ListChecksResponse> {
+        let request = self.connection.request(ListChecksRequest {});
+        Ok(self.inner.list_checks(request).await?.into_inner())
+    }
+
+    /// Deletes the threshold check `name`.
+    pub async fn delete_check(&mut self, name: impl Into<String>) -> Result<()> {
+        let request = self
+            .connection
+            .request(DeleteCheckRequest { name: name.into() });
+        self.inner.delete_check(request).await?;
+        Ok(())
+    }
+
+    /// Creates a rules template with `rules`, whose name is the name of the template.
+    pub async fn create_rules_template(&mut self, rules: DatabaseRules) -> Result<()> {
+        let request = self
+            .connection
+            .request(CreateRulesTemplateRequest { rules: Some(rules) });
+        self.inner.create_rules_template(request).await?;
+        Ok(())
+    }
+
+    /// Replaces the rules of the template named by `rules`, returning the databases whose
+    /// rules changed along with the generation they were stored as.
+    pub async fn update_rules_template(
+        &mut self,
+        rules: DatabaseRules,
+    ) -> Result<Vec<DatabaseRulesUpdate>> {
+        let request = self
+            .connection
+            .request(UpdateRulesTemplateRequest { rules: Some(rules) });
+        Ok(self
+            .inner
+            .update_rules_template(request)
+            .await?
+            .into_inner()
+            .updated)
+    }
+
+    /// Lists the rules templates of the server, each named by the name of its rules.
+    pub async fn list_rules_templates(&mut self) -> Result<Vec<DatabaseRules>> {
+        let request = self.connection.request(ListRulesTemplatesRequest {});
+        Ok(self
+            .inner
+            .list_rules_templates(request)
+            .await?
+            .into_inner()
+            .templates)
+    }
+
+    /// Deletes the rules template `name`.
+    pub async fn delete_rules_template(&mut self, name: impl Into<String>) -> Result<()> {
+        let request = self
+            .connection
+            .request(DeleteRulesTemplateRequest { name: name.into() });
+        self.inner.delete_rules_template(request).await?;
+        Ok(())
+    }
+}
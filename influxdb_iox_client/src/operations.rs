@@ -0,0 +1,100 @@
+//! A client for the operations API of IOx, which tracks long running jobs of the server, such
+//! as exports.
+
+use std::time::Duration;
+
+use generated_types::management::{
+    operations_service_client::OperationsServiceClient, CancelOperationRequest,
+    GetOperationRequest, ListOperationsRequest, Operation, OperationStatus, WaitOperationRequest,
+};
+use snafu::OptionExt;
+use tonic::transport::Channel;
+
+use crate::{
+    error::{EmptyResponse, Result},
+    Connection,
+};
+
+/// A client for the operations API of an IOx server.
+#[derive(Debug, Clone)]
+pub struct OperationsClient {
+    inner: OperationsServiceClient<Channel>,
+    connection: Connection,
+}
+
+impl OperationsClient {
+    /// Creates a client that sends its requests over `connection`.
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            inner: OperationsServiceClient::new(connection.channel()),
+            connection,
+        }
+    }
+
+    /// Returns the operations of the server, both running and completed.
+    pub async fn list_operations(&mut self) -> Result<Vec<Operation>> {
+        let request = self.connection.request(ListOperationsRequest {});
+        Ok(self
+            .inner
+            .list_operations(request)
+            .await?
+            .into_inner()
+            .operations)
+    }
+
+    /// Returns the operation with the id `id`.
+    pub async fn get_operation(&mut self, id: u64) -> Result<Operation> {
+        let request = self.connection.request(GetOperationRequest { id });
+        self.inner
+            .get_operation(request)
+            .await?
+            .into_inner()
+            .operation
+            .context(EmptyResponse { field: "operation" })
+    }
+
+    /// Cancels the operation with the id `id`, if it is still running.
+    pub async fn cancel_operation(&mut self, id: u64) -> Result<()> {
+        let request = self.connection.request(CancelOperationRequest { id });
+        self.inner.cancel_operation(request).await?;
+        Ok(())
+    }
+
+    /// Waits until the operation with the id `id` completes, or at most `timeout` if given,
+    /// returning the operation as it is then.
+    pub async fn wait_operation(
+        &mut self,
+        id: u64,
+        timeout: Option<Duration>,
+    ) -> Result<Operation> {
+        let request = self.connection.request(WaitOperationRequest {
+            id,
+            timeout_nanos: timeout.map_or(0, |timeout| timeout.as_nanos() as u64),
+        });
+        self.inner
+            .wait_operation(request)
+            .await?
+            .into_inner()
+            .operation
+            .context(EmptyResponse { field: "operation" })
+    }
+}
+
+/// Returns true if `operation` completed, whether it succeeded, failed or was cancelled.
+pub fn is_complete(operation: &Operation) -> bool {
+    operation.status != OperationStatus::Running as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completion() {
+        let mut operation = Operation::default();
+        assert!(!is_complete(&operation));
+
+        operation.status = OperationStatus::Cancelled as i32;
+        assert!(is_complete(&operation));
+    }
+}
@@ -0,0 +1,94 @@
+//! A client for the query API of IOx, which runs SQL queries against the databases of the
+//! server.
+
+use std::io::Cursor;
+
+use arrow_deps::arrow::{ipc::reader::StreamReader, record_batch::RecordBatch};
+use generated_types::query::{
+    query_service_client::QueryServiceClient, QueryRequest, TailRequest, TailResponse,
+};
+use snafu::ResultExt;
+use tonic::{transport::Channel, Streaming};
+
+use crate::{
+    error::{DecodingResults, Result},
+    Connection,
+};
+
+/// A client for the query API of an IOx server.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), influxdb_iox_client::Error> {
+/// use influxdb_iox_client::{Builder, QueryClient};
+///
+/// let connection = Builder::default().build("http://127.0.0.1:8082").await?;
+/// let batches = QueryClient::new(connection)
+///     .query("mydb", "select * from cpu")
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct QueryClient {
+    inner: QueryServiceClient<Channel>,
+    connection: Connection,
+}
+
+impl QueryClient {
+    /// Creates a client that sends its requests over `connection`.
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            inner: QueryServiceClient::new(connection.channel()),
+            connection,
+        }
+    }
+
+    /// Runs `sql` against the database `db_name`, returning the results as record batches.
+    /// Queries without results return no batches.
+    pub async fn query(
+        &mut self,
+        db_name: impl Into<String>,
+        sql: impl Into<String>,
+    ) -> Result<Vec<RecordBatch>> {
+        let request = self.connection.request(QueryRequest {
+            db_name: db_name.into(),
+            sql: sql.into(),
+        });
+        let arrow_ipc = self.inner.query(request).await?.into_inner().arrow_ipc;
+        decode(arrow_ipc)
+    }
+
+    /// Subscribes to the rows of a table written to a database from now on, as selected by
+    /// `request`. The stream ends with a `ResourceExhausted` status if the client is evicted
+    /// for not keeping up.
+    pub async fn tail(&mut self, request: TailRequest) -> Result<Streaming<TailResponse>> {
+        let request = self.connection.request(request);
+        Ok(self.inner.tail(request).await?.into_inner())
+    }
+}
+
+/// Decodes the Arrow IPC stream of a query response
+fn decode(arrow_ipc: Vec<u8>) -> Result<Vec<RecordBatch>> {
+    if arrow_ipc.is_empty() {
+        return Ok(vec![]);
+    }
+
+    StreamReader::try_new(Cursor::new(arrow_ipc))
+        .context(DecodingResults)?
+        .collect::<Result<Vec<_>, _>>()
+        .context(DecodingResults)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn decoding() {
+        assert!(decode(vec![]).unwrap().is_empty());
+
+        let err = decode(b"not arrow".to_vec()).unwrap_err();
+        assert!(matches!(err, Error::DecodingResults { .. }));
+    }
+}
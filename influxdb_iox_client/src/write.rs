@@ -0,0 +1,56 @@
+//! A client for the write API of IOx, which accepts entries that were already converted from
+//! line protocol.
+
+use generated_types::write::{
+    write_service_client::WriteServiceClient, ValidateLinesRequest, ValidateLinesResponse,
+    WriteEntryRequest,
+};
+use tonic::transport::Channel;
+
+use crate::{error::Result, Connection};
+
+/// A client for the write API of an IOx server.
+#[derive(Debug, Clone)]
+pub struct WriteClient {
+    inner: WriteServiceClient<Channel>,
+    connection: Connection,
+}
+
+impl WriteClient {
+    /// Creates a client that sends its requests over `connection`.
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            inner: WriteServiceClient::new(connection.channel()),
+            connection,
+        }
+    }
+
+    /// Writes `entry`, an `Entry` flatbuffer, to the database `db_name`. The server
+    /// stores and replicates it according to the rules of the database.
+    pub async fn write_entry(
+        &mut self,
+        db_name: impl Into<String>,
+        entry: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        let request = self.connection.request(WriteEntryRequest {
+            db_name: db_name.into(),
+            entry: entry.into(),
+        });
+        self.inner.write_entry(request).await?;
+        Ok(())
+    }
+
+    /// Checks the line protocol `lp_data` against the rules and the columns of the database
+    /// `db_name` without writing it. The lines are valid if the response has no diagnostics.
+    pub async fn validate_lines(
+        &mut self,
+        db_name: impl Into<String>,
+        lp_data: impl Into<String>,
+    ) -> Result<ValidateLinesResponse> {
+        let request = self.connection.request(ValidateLinesRequest {
+            db_name: db_name.into(),
+            lp_data: lp_data.into(),
+        });
+        Ok(self.inner.validate_lines(request).await?.into_inner())
+    }
+}
@@ -1,4 +1,21 @@
-use snafu::{OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+/// The layout of the series keys in a TSM index, which differs between the InfluxDB versions
+/// that wrote the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// InfluxDB 1.x: `<measurement>,<tag_key>=<tag_value>,...#!~#<field_key>`
+    V1,
+    /// InfluxDB 2.x: the org and bucket ids, followed by the measurement and field key stored
+    /// as the special tags `\x00` and `\xff`
+    V2,
+}
+
+impl Default for KeyFormat {
+    fn default() -> Self {
+        Self::V2
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct ParsedTSMKey {
@@ -23,6 +40,9 @@ pub enum DataError {
     #[snafu(display(r#"No field key (expected to find in tag field \xff)"#))]
     NoFieldKey {},
 
+    #[snafu(display(r#"No field key (expected to find after #!~#)"#))]
+    NoFieldSeparator {},
+
     #[snafu(display(
         r#"Found new measurement '{}' after the first '{}'"#,
         new_measurement,
@@ -153,6 +173,98 @@ fn parse_tsm_key_internal(key: &[u8]) -> Result<ParsedTSMKey, DataError> {
     })
 }
 
+/// Parses the measurement, field key and tag set from a TSM index key written by
+/// InfluxDB 1.x, which is the series key followed by the field key:
+///
+/// <measurement>,<tag_keys_str>#!~#<field_key_str>
+///
+/// For example:
+/// cpu,host=server01,region=us-west#!~#usage_idle
+///
+///    measurement = "cpu"
+///    tags = [("host", "server01"), ("region", "us-west")]
+///    field = "usage_idle"
+pub fn parse_v1_tsm_key(key: &[u8]) -> Result<ParsedTSMKey, Error> {
+    parse_v1_tsm_key_internal(key).context(ParsingTSMKey {
+        key: String::from_utf8_lossy(key),
+    })
+}
+
+fn parse_v1_tsm_key_internal(key: &[u8]) -> Result<ParsedTSMKey, DataError> {
+    let separator = key
+        .windows(4)
+        .position(|window| window == b"#!~#")
+        .context(NoFieldSeparator)?;
+
+    // as in 2.x keys, the field key after the delimiter is not escaped
+    let field_key = String::from_utf8_lossy(&key[separator + 4..]).into_owned();
+    ensure!(
+        !field_key.is_empty(),
+        ParsingFieldKey {
+            details: "field key too short",
+        }
+    );
+
+    let mut parts = split_unescaped(&key[..separator], b',').into_iter();
+    let measurement = unescape(parts.next().unwrap_or_default());
+    ensure!(!measurement.is_empty(), NoMeasurement);
+
+    let mut tagset = Vec::with_capacity(parts.len());
+    for part in parts {
+        match split_unescaped(part, b'=')[..] {
+            [tag_key, tag_value] if !tag_key.is_empty() => {
+                tagset.push((unescape(tag_key), unescape(tag_value)))
+            }
+            _ => {
+                return ParsingTSMTagKey {
+                    description: format!("invalid tag '{}'", String::from_utf8_lossy(part)),
+                }
+                .fail()
+            }
+        }
+    }
+
+    Ok(ParsedTSMKey {
+        measurement,
+        tagset,
+        field_key,
+    })
+}
+
+/// Splits `bytes` on each `separator` that is not escaped with a backslash
+fn split_unescaped(bytes: &[u8], separator: u8) -> Vec<&[u8]> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (i, &byte) in bytes.iter().enumerate() {
+        if escaped {
+            escaped = false;
+        } else if byte == b'\\' {
+            escaped = true;
+        } else if byte == separator {
+            parts.push(&bytes[start..i]);
+            start = i + 1;
+        }
+    }
+    parts.push(&bytes[start..]);
+    parts
+}
+
+/// Removes the backslashes escaping commas, equals signs and spaces in series keys
+fn unescape(bytes: &[u8]) -> String {
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter().copied().peekable();
+
+    while let Some(byte) = iter.next() {
+        match (byte, iter.peek()) {
+            (b'\\', Some(&b',')) | (b'\\', Some(&b'=')) | (b'\\', Some(&b' ')) => {}
+            _ => unescaped.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
 /// Parses the field value stored in a TSM field key into a field name.
 /// fields are stored on the series keys in TSM indexes as follows:
 ///
@@ -653,6 +765,49 @@ mod tests {
         assert_eq!(parsed_key.field_key, String::from("f"));
     }
 
+    #[test]
+    fn parse_v1_tsm_key_good() {
+        let parsed_key = parse_v1_tsm_key(b"cpu,host=a,region=us-west#!~#usage_idle").unwrap();
+        assert_eq!(parsed_key.measurement, "cpu");
+        assert_eq!(
+            parsed_key.tagset,
+            vec![
+                (String::from("host"), String::from("a")),
+                (String::from("region"), String::from("us-west")),
+            ]
+        );
+        assert_eq!(parsed_key.field_key, "usage_idle");
+
+        // escaped separators are part of the names
+        let parsed_key = parse_v1_tsm_key(br"disk\ io,mount\=point=\,x#!~#Code Cache").unwrap();
+        assert_eq!(parsed_key.measurement, "disk io");
+        assert_eq!(
+            parsed_key.tagset,
+            vec![(String::from("mount=point"), String::from(",x"))]
+        );
+        assert_eq!(parsed_key.field_key, "Code Cache");
+
+        let parsed_key = parse_v1_tsm_key(b"mem#!~#free").unwrap();
+        assert_eq!(parsed_key.measurement, "mem");
+        assert!(parsed_key.tagset.is_empty());
+    }
+
+    #[test]
+    fn parse_v1_tsm_key_bad() {
+        for (key, expected) in &[
+            (
+                &b"cpu,host=a"[..],
+                "No field key (expected to find after #!~#)",
+            ),
+            (&b"cpu,host=a#!~#"[..], "field key too short"),
+            (&b",host=a#!~#f"[..], "No measurement found"),
+            (&b"cpu,host#!~#f"[..], "invalid tag 'host'"),
+        ] {
+            let err = parse_v1_tsm_key(key).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}", err);
+        }
+    }
+
     #[test]
     fn parse_tsm_error_has_key() {
         //<org_id bucket_id>,\x00=<measurement>,<tag_keys_str>
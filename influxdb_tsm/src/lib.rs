@@ -16,7 +16,7 @@ use std::error;
 use std::fmt;
 use std::io;
 
-pub use key::ParsedTSMKey;
+pub use key::{KeyFormat, ParsedTSMKey};
 
 #[derive(Clone, Debug, Copy, PartialEq)]
 pub enum BlockType {
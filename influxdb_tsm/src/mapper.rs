@@ -1,7 +1,7 @@
 ///! Types for mapping and converting series data from TSM indexes produced by
-///! InfluxDB >= 2.x
+///! InfluxDB >= 2.x, or by InfluxDB 1.x when mapping with `KeyFormat::V1`
 use crate::reader::{BlockData, BlockDecoder, TSMIndexReader, ValuePair};
-use crate::{Block, BlockType, TSMError};
+use crate::{Block, BlockType, KeyFormat, TSMError};
 
 use tracing::warn;
 
@@ -25,6 +25,7 @@ where
 {
     iter: Peekable<TSMIndexReader<R>>,
     reader_idx: usize,
+    key_format: KeyFormat,
 }
 
 impl<R> TSMMeasurementMapper<R>
@@ -32,7 +33,17 @@ where
     R: Read + Seek,
 {
     pub fn new(iter: Peekable<TSMIndexReader<R>>, reader_idx: usize) -> Self {
-        Self { iter, reader_idx }
+        Self {
+            iter,
+            reader_idx,
+            key_format: KeyFormat::default(),
+        }
+    }
+
+    /// Parses the keys of the index as written in `key_format`, rather than by InfluxDB 2.x
+    pub fn with_key_format(mut self, key_format: KeyFormat) -> Self {
+        self.key_format = key_format;
+        self
     }
 }
 
@@ -53,7 +64,7 @@ impl<R: Read + Seek> Iterator for TSMMeasurementMapper<R> {
         // `None` indicates the end of index iteration.
         let entry = try_or_some!(self.iter.next()?);
 
-        let parsed_key = try_or_some!(entry.parse_key());
+        let parsed_key = try_or_some!(entry.parse_key_with(self.key_format));
         let mut measurement: MeasurementTable =
             MeasurementTable::new(parsed_key.measurement, self.reader_idx);
         try_or_some!(measurement.add_series_data(
@@ -69,7 +80,7 @@ impl<R: Read + Seek> Iterator for TSMMeasurementMapper<R> {
         while let Some(res) = self.iter.peek() {
             match res {
                 Ok(entry) => {
-                    let parsed_key = try_or_some!(entry.parse_key());
+                    let parsed_key = try_or_some!(entry.parse_key_with(self.key_format));
                     if measurement.name != parsed_key.measurement {
                         // Next entry is for a different measurement.
                         return Some(Ok(measurement));
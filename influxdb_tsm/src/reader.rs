@@ -222,7 +222,16 @@ impl IndexEntry {
     }
 
     pub fn parse_key(&self) -> Result<ParsedTSMKey, TSMError> {
-        key::parse_tsm_key(&self.key).map_err(|e| TSMError {
+        self.parse_key_with(KeyFormat::V2)
+    }
+
+    /// Parses the key of this entry, written in `format`
+    pub fn parse_key_with(&self, format: KeyFormat) -> Result<ParsedTSMKey, TSMError> {
+        let parsed = match format {
+            KeyFormat::V1 => key::parse_v1_tsm_key(&self.key),
+            KeyFormat::V2 => key::parse_tsm_key(&self.key),
+        };
+        parsed.map_err(|e| TSMError {
             description: e.to_string(),
         })
     }
@@ -0,0 +1,682 @@
+//! This module contains the code to convert CSV and Parquet files into the columns of a table,
+//! for bulk imports that bypass line protocol.
+//!
+//! A `SchemaMapping` declares which columns of a file hold the tags, the fields (and their
+//! types) and the timestamp of each row. Other columns of the file are ignored. Values are
+//! converted to the declared types, so that, for example, the integers of a Parquet file can
+//! be imported as a float field.
+use arrow_deps::parquet::{
+    errors::ParquetError,
+    file::{
+        reader::{FileReader, SerializedFileReader},
+        serialized_reader::SliceableCursor,
+    },
+    record::Field,
+};
+use data_types::table_schema::{DataType, Schema, SchemaBuilder};
+use packers::{ByteArray, Packers};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::{collections::BTreeMap, str::FromStr};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unknown file format '{}', expected csv or parquet", format))]
+    UnknownFormat { format: String },
+
+    #[snafu(display("Unknown time unit '{}', expected s, ms, us or ns", unit))]
+    UnknownTimeUnit { unit: String },
+
+    #[snafu(display("Error reading CSV: {}", source))]
+    ReadingCsv { source: csv::Error },
+
+    #[snafu(display("Error reading Parquet: {}", source))]
+    ReadingParquet { source: ParquetError },
+
+    #[snafu(display("Column {} not found in the file", column))]
+    ColumnNotFound { column: String },
+
+    #[snafu(display(
+        "Row {}: invalid {:?} value '{}' in column {}",
+        row,
+        data_type,
+        value,
+        column
+    ))]
+    InvalidValue {
+        row: usize,
+        column: String,
+        value: String,
+        data_type: DataType,
+    },
+
+    #[snafu(display("Row {}: missing timestamp in column {}", row, column))]
+    MissingTimestamp { row: usize, column: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The formats files can be imported from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileFormat {
+    /// Comma separated values, with a header row naming the columns
+    Csv,
+    Parquet,
+}
+
+impl FromStr for FileFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format.to_ascii_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "parquet" => Ok(Self::Parquet),
+            _ => UnknownFormat { format }.fail(),
+        }
+    }
+}
+
+/// The unit of the integer timestamps of a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeUnit {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    Nanoseconds,
+}
+
+impl TimeUnit {
+    fn nanos(self) -> i64 {
+        match self {
+            Self::Seconds => 1_000_000_000,
+            Self::Milliseconds => 1_000_000,
+            Self::Microseconds => 1_000,
+            Self::Nanoseconds => 1,
+        }
+    }
+}
+
+impl Default for TimeUnit {
+    fn default() -> Self {
+        Self::Nanoseconds
+    }
+}
+
+impl FromStr for TimeUnit {
+    type Err = Error;
+
+    fn from_str(unit: &str) -> Result<Self> {
+        match unit {
+            "s" => Ok(Self::Seconds),
+            "ms" => Ok(Self::Milliseconds),
+            "us" => Ok(Self::Microseconds),
+            "ns" => Ok(Self::Nanoseconds),
+            _ => UnknownTimeUnit { unit }.fail(),
+        }
+    }
+}
+
+/// Declares how the columns of a file map to the table they are imported into
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaMapping {
+    /// The name of the table
+    pub table: String,
+    /// The columns holding tag values
+    pub tags: Vec<String>,
+    /// The columns holding field values, and the type of each field
+    pub fields: Vec<(String, DataType)>,
+    /// The column holding the timestamps. Timestamps that are not Parquet timestamps are
+    /// integers in `time_unit`.
+    pub time_column: String,
+    pub time_unit: TimeUnit,
+}
+
+impl SchemaMapping {
+    /// The schema of the imported table. Its timestamp column is always named `time`.
+    pub fn schema(&self) -> Schema {
+        let builder = self
+            .tags
+            .iter()
+            .fold(SchemaBuilder::new(&self.table), |builder, tag| {
+                builder.tag(tag)
+            });
+        self.fields
+            .iter()
+            .fold(builder, |builder, (name, data_type)| {
+                builder.field(name, *data_type)
+            })
+            .build()
+    }
+
+    /// The names of the mapped columns of the file, in the order of the columns of `schema`
+    fn source_columns(&self) -> Vec<&str> {
+        self.tags
+            .iter()
+            .map(String::as_str)
+            .chain(self.fields.iter().map(|(name, _)| name.as_str()))
+            .chain(std::iter::once(self.time_column.as_str()))
+            .collect()
+    }
+}
+
+/// The rows of an imported file
+#[derive(Debug)]
+pub struct ImportedTable {
+    pub schema: Schema,
+    /// The values of each column of `schema`, in order
+    pub columns: Vec<Packers>,
+    pub rows: usize,
+    pub min_time: Option<i64>,
+    pub max_time: Option<i64>,
+}
+
+impl ImportedTable {
+    /// Returns the value of column `column` in row `row`, formatted as in line protocol, if
+    /// the table has the column and the row has a value for it
+    pub fn value(&self, row: usize, column: &str) -> Option<String> {
+        let index = self
+            .schema
+            .get_col_defs()
+            .iter()
+            .position(|col| col.name == column)?;
+
+        match &self.columns[index] {
+            Packers::Float(p) => p.get(row).map(ToString::to_string),
+            Packers::Integer(p) => p.get(row).map(|v| format!("{}i", v)),
+            Packers::String(p) => p
+                .get(row)
+                .map(|v| v.as_utf8().unwrap_or_default().to_string()),
+            Packers::Boolean(p) => p.get(row).map(ToString::to_string),
+        }
+    }
+
+    /// Returns the time of row `row`, in nanoseconds. Every imported row has one.
+    pub fn time(&self, row: usize) -> i64 {
+        *self
+            .columns
+            .last()
+            .expect("the time column is always mapped")
+            .i64_packer()
+            .get(row)
+            .expect("imported rows always have a timestamp")
+    }
+
+    /// Splits the rows of the table by the partition key `partition_key` returns for each
+    /// of them, returning the rows of each partition in the order of their keys
+    pub fn partition(
+        self,
+        mut partition_key: impl FnMut(&Self, usize) -> String,
+    ) -> Vec<(String, Self)> {
+        let mut partitions: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for row in 0..self.rows {
+            partitions
+                .entry(partition_key(&self, row))
+                .or_default()
+                .push(row);
+        }
+
+        // avoid copying the rows of a table that falls in a single partition
+        if partitions.len() == 1 {
+            let key = partitions.into_iter().next().expect("one partition").0;
+            return vec![(key, self)];
+        }
+
+        partitions
+            .into_iter()
+            .map(|(key, rows)| {
+                let columns = self
+                    .columns
+                    .iter()
+                    .map(|column| column.take_rows(&rows))
+                    .collect();
+                let times = rows.iter().map(|&row| self.time(row));
+                let table = Self {
+                    schema: self.schema.clone(),
+                    columns,
+                    rows: rows.len(),
+                    min_time: times.clone().min(),
+                    max_time: times.max(),
+                };
+                (key, table)
+            })
+            .collect()
+    }
+}
+
+/// Converts the rows of the file `data`, in `format`, according to `mapping`
+pub fn convert(
+    format: FileFormat,
+    data: Vec<u8>,
+    mapping: &SchemaMapping,
+) -> Result<ImportedTable> {
+    let mut table = TableBuilder::new(mapping);
+    match format {
+        FileFormat::Csv => convert_csv(&data, mapping, &mut table)?,
+        FileFormat::Parquet => convert_parquet(data, mapping, &mut table)?,
+    }
+    Ok(table.finish())
+}
+
+fn convert_csv(data: &[u8], mapping: &SchemaMapping, table: &mut TableBuilder<'_>) -> Result<()> {
+    let mut reader = csv::Reader::from_reader(data);
+    let headers = reader.headers().context(ReadingCsv)?.clone();
+    let indexes = column_indexes(mapping, headers.iter())?;
+
+    let mut values = Vec::with_capacity(indexes.len());
+    for record in reader.records() {
+        let record = record.context(ReadingCsv)?;
+        values.clear();
+        values.extend(indexes.iter().map(|&index| match record.get(index) {
+            Some("") | None => Value::Null,
+            Some(value) => Value::String(value.to_string()),
+        }));
+        table.push_row(&values)?;
+    }
+    Ok(())
+}
+
+fn convert_parquet(
+    data: Vec<u8>,
+    mapping: &SchemaMapping,
+    table: &mut TableBuilder<'_>,
+) -> Result<()> {
+    let reader = SerializedFileReader::new(SliceableCursor::new(data)).context(ReadingParquet)?;
+    let names: Vec<_> = reader
+        .metadata()
+        .file_metadata()
+        .schema()
+        .get_fields()
+        .iter()
+        .map(|field| field.name().to_string())
+        .collect();
+    let indexes = column_indexes(mapping, names.iter().map(String::as_str))?;
+
+    let mut values = Vec::with_capacity(indexes.len());
+    for row in reader.get_row_iter(None).context(ReadingParquet)? {
+        let fields: Vec<_> = row.get_column_iter().map(|(_, field)| field).collect();
+        values.clear();
+        values.extend(indexes.iter().map(|&index| Value::from(fields[index])));
+        table.push_row(&values)?;
+    }
+    Ok(())
+}
+
+/// Finds the index of each mapped column among the `columns` of a file
+fn column_indexes<'a>(
+    mapping: &SchemaMapping,
+    columns: impl Iterator<Item = &'a str>,
+) -> Result<Vec<usize>> {
+    let columns: Vec<_> = columns.collect();
+    mapping
+        .source_columns()
+        .into_iter()
+        .map(|name| {
+            columns
+                .iter()
+                .position(|column| *column == name)
+                .context(ColumnNotFound { column: name })
+        })
+        .collect()
+}
+
+/// A value read from a file, before it is converted to the type of its column
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    /// A timestamp in nanoseconds, which is not scaled by the time unit of the mapping
+    Time(i64),
+}
+
+impl From<&Field> for Value {
+    fn from(field: &Field) -> Self {
+        match field {
+            Field::Null => Self::Null,
+            Field::Bool(v) => Self::Boolean(*v),
+            Field::Byte(v) => Self::Integer(i64::from(*v)),
+            Field::Short(v) => Self::Integer(i64::from(*v)),
+            Field::Int(v) => Self::Integer(i64::from(*v)),
+            Field::Long(v) => Self::Integer(*v),
+            Field::UByte(v) => Self::Integer(i64::from(*v)),
+            Field::UShort(v) => Self::Integer(i64::from(*v)),
+            Field::UInt(v) => Self::Integer(i64::from(*v)),
+            Field::Float(v) => Self::Float(f64::from(*v)),
+            Field::Double(v) => Self::Float(*v),
+            Field::Str(v) => Self::String(v.clone()),
+            Field::TimestampMillis(v) => Self::Time(*v as i64 * 1_000_000),
+            Field::TimestampMicros(v) => Self::Time(*v as i64 * 1_000),
+            other => Self::String(other.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Null => Ok(()),
+            Self::Boolean(v) => write!(f, "{}", v),
+            Self::Integer(v) | Self::Time(v) => write!(f, "{}", v),
+            Self::Float(v) => write!(f, "{}", v),
+            Self::String(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Packs the converted values of each row
+#[derive(Debug)]
+struct TableBuilder<'a> {
+    mapping: &'a SchemaMapping,
+    schema: Schema,
+    columns: Vec<Packers>,
+    rows: usize,
+    min_time: Option<i64>,
+    max_time: Option<i64>,
+}
+
+impl<'a> TableBuilder<'a> {
+    fn new(mapping: &'a SchemaMapping) -> Self {
+        let schema = mapping.schema();
+        let columns = schema
+            .get_col_defs()
+            .iter()
+            .map(|col| Packers::from(col.data_type))
+            .collect();
+
+        Self {
+            mapping,
+            schema,
+            columns,
+            rows: 0,
+            min_time: None,
+            max_time: None,
+        }
+    }
+
+    /// Appends a row, given the values of the columns of the schema in order
+    fn push_row(&mut self, values: &[Value]) -> Result<()> {
+        let row = self.rows + 1;
+        let col_defs = self.schema.get_col_defs();
+        let (time, values) = values
+            .split_last()
+            .expect("the time column is always mapped");
+
+        let time = match time {
+            Value::Time(nanos) => Some(*nanos),
+            Value::Integer(time) => time.checked_mul(self.mapping.time_unit.nanos()),
+            Value::String(time) => time
+                .parse::<i64>()
+                .ok()
+                .and_then(|time| time.checked_mul(self.mapping.time_unit.nanos())),
+            Value::Null => {
+                return MissingTimestamp {
+                    row,
+                    column: &self.mapping.time_column,
+                }
+                .fail()
+            }
+            Value::Boolean(_) | Value::Float(_) => None,
+        }
+        .with_context(|| InvalidValue {
+            row,
+            column: &self.mapping.time_column,
+            value: time.to_string(),
+            data_type: DataType::Timestamp,
+        })?;
+
+        // convert the whole row before packing it, so that an invalid value leaves the
+        // columns with the same number of rows
+        let mut converted = Vec::with_capacity(values.len());
+        for (value, col) in values.iter().zip(&col_defs) {
+            let packed = convert_value(value, col.data_type);
+            ensure!(
+                packed.is_some() || *value == Value::Null,
+                InvalidValue {
+                    row,
+                    column: &col.name,
+                    value: value.to_string(),
+                    data_type: col.data_type,
+                }
+            );
+            converted.push(packed.unwrap_or(Packed::Null));
+        }
+
+        for (packed, column) in converted.into_iter().zip(&mut self.columns) {
+            match (packed, column) {
+                (Packed::Float(v), Packers::Float(p)) => p.push(v),
+                (Packed::Integer(v), Packers::Integer(p)) => p.push(v),
+                (Packed::String(v), Packers::String(p)) => p.push(ByteArray::from(v.as_str())),
+                (Packed::Boolean(v), Packers::Boolean(p)) => p.push(v),
+                (_, column) => column.push_none(),
+            }
+        }
+        self.columns
+            .last_mut()
+            .expect("the time column is always mapped")
+            .i64_packer_mut()
+            .push(time);
+
+        self.rows += 1;
+        self.min_time = Some(self.min_time.map_or(time, |min| min.min(time)));
+        self.max_time = Some(self.max_time.map_or(time, |max| max.max(time)));
+        Ok(())
+    }
+
+    fn finish(self) -> ImportedTable {
+        ImportedTable {
+            schema: self.schema,
+            columns: self.columns,
+            rows: self.rows,
+            min_time: self.min_time,
+            max_time: self.max_time,
+        }
+    }
+}
+
+/// A value converted to the type of its column
+#[derive(Debug)]
+enum Packed {
+    Null,
+    Float(f64),
+    Integer(i64),
+    String(String),
+    Boolean(bool),
+}
+
+/// Converts `value` to `data_type`, returning `None` if it can't be
+fn convert_value(value: &Value, data_type: DataType) -> Option<Packed> {
+    match (data_type, value) {
+        (_, Value::Null) => None,
+        (DataType::String, value) => Some(Packed::String(value.to_string())),
+        (DataType::Float, Value::Float(v)) => Some(Packed::Float(*v)),
+        (DataType::Float, Value::Integer(v)) => Some(Packed::Float(*v as f64)),
+        (DataType::Float, Value::String(v)) => v.parse().ok().map(Packed::Float),
+        (DataType::Integer, Value::Integer(v)) | (DataType::Integer, Value::Time(v)) => {
+            Some(Packed::Integer(*v))
+        }
+        (DataType::Integer, Value::String(v)) => v.parse().ok().map(Packed::Integer),
+        (DataType::Boolean, Value::Boolean(v)) => Some(Packed::Boolean(*v)),
+        (DataType::Boolean, Value::String(v)) => {
+            v.to_ascii_lowercase().parse().ok().map(Packed::Boolean)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parquet::writer::{CompressionLevel, IOxParquetTableWriter, MemWriter};
+    use packers::IOxTableWriter;
+
+    fn mapping() -> SchemaMapping {
+        SchemaMapping {
+            table: "weather".to_string(),
+            tags: vec!["city".to_string()],
+            fields: vec![
+                ("temp".to_string(), DataType::Float),
+                ("sunny".to_string(), DataType::Boolean),
+            ],
+            time_column: "ts".to_string(),
+            time_unit: TimeUnit::Seconds,
+        }
+    }
+
+    #[test]
+    fn csv() {
+        let data = "ts,city,temp,ignored,sunny\n\
+                    10,Boston,71.5,x,true\n\
+                    20,,72,y,\n\
+                    5,Paris,,z,FALSE\n";
+
+        let table = convert(FileFormat::Csv, data.as_bytes().to_vec(), &mapping()).unwrap();
+        assert_eq!(table.schema.measurement(), "weather");
+        let names: Vec<_> = table
+            .schema
+            .get_col_defs()
+            .into_iter()
+            .map(|col| col.name)
+            .collect();
+        assert_eq!(names, vec!["city", "temp", "sunny", "time"]);
+
+        assert_eq!(table.rows, 3);
+        assert_eq!(table.min_time, Some(5_000_000_000));
+        assert_eq!(table.max_time, Some(20_000_000_000));
+        assert_eq!(
+            table.columns[0].str_packer().to_vec(),
+            &[
+                Some(ByteArray::from("Boston")),
+                None,
+                Some(ByteArray::from("Paris"))
+            ]
+        );
+        assert_eq!(
+            table.columns[1].f64_packer().to_vec(),
+            &[Some(71.5), Some(72.0), None]
+        );
+        assert_eq!(
+            table.columns[2].bool_packer().to_vec(),
+            &[Some(true), None, Some(false)]
+        );
+        assert_eq!(
+            table.columns[3].i64_packer().to_vec(),
+            &[
+                Some(10_000_000_000),
+                Some(20_000_000_000),
+                Some(5_000_000_000)
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_errors() {
+        let err = convert(FileFormat::Csv, b"ts,city,temp\n".to_vec(), &mapping()).unwrap_err();
+        assert!(
+            matches!(&err, Error::ColumnNotFound { column } if column == "sunny"),
+            "{}",
+            err
+        );
+
+        let data = b"ts,city,temp,sunny\n10,Boston,warm,true\n".to_vec();
+        let err = convert(FileFormat::Csv, data, &mapping()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Row 1: invalid Float value 'warm' in column temp"
+        );
+
+        let data = b"ts,city,temp,sunny\n10,Boston,1,true\n,Boston,1,true\n".to_vec();
+        let err = convert(FileFormat::Csv, data, &mapping()).unwrap_err();
+        assert_eq!(err.to_string(), "Row 2: missing timestamp in column ts");
+    }
+
+    #[test]
+    fn parquet() {
+        let source_schema = SchemaBuilder::new("source")
+            .tag("city")
+            .field("temp", DataType::Integer)
+            .field("sunny", DataType::Boolean)
+            .build();
+        let mut columns: Vec<_> = source_schema
+            .get_col_defs()
+            .iter()
+            .map(|col| Packers::from(col.data_type))
+            .collect();
+        columns[0].str_packer_mut().push(ByteArray::from("Boston"));
+        columns[1].i64_packer_mut().push(71);
+        columns[2].bool_packer_mut().push_option(None);
+        columns[3].i64_packer_mut().push(10);
+
+        let buffer = MemWriter::default();
+        let mut writer = IOxParquetTableWriter::new(
+            &source_schema,
+            CompressionLevel::Compatibility,
+            buffer.clone(),
+        )
+        .unwrap();
+        writer.write_batch(&columns).unwrap();
+        writer.close().unwrap();
+
+        let mapping = SchemaMapping {
+            time_column: "time".to_string(),
+            time_unit: TimeUnit::Nanoseconds,
+            ..mapping()
+        };
+        let table = convert(FileFormat::Parquet, buffer.take_data(), &mapping).unwrap();
+        assert_eq!(table.rows, 1);
+        assert_eq!(table.columns[1].f64_packer().to_vec(), &[Some(71.0)]);
+        assert_eq!(table.columns[2].bool_packer().to_vec(), &[None]);
+        assert_eq!(table.columns[3].i64_packer().to_vec(), &[Some(10)]);
+    }
+
+    #[test]
+    fn partition() {
+        let data = "ts,city,temp,sunny\n\
+                    10,Boston,71.5,true\n\
+                    20,Paris,72,\n\
+                    5,Boston,,false\n";
+        let table = convert(FileFormat::Csv, data.as_bytes().to_vec(), &mapping()).unwrap();
+        assert_eq!(table.value(0, "city").as_deref(), Some("Boston"));
+        assert_eq!(table.value(1, "temp").as_deref(), Some("72"));
+        assert_eq!(table.value(1, "sunny"), None);
+        assert_eq!(table.value(0, "missing"), None);
+        assert_eq!(table.time(2), 5_000_000_000);
+
+        let partitions = table.partition(|table, row| table.value(row, "city").unwrap());
+        let summary: Vec<_> = partitions
+            .iter()
+            .map(|(key, table)| (key.as_str(), table.rows, table.min_time, table.max_time))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("Boston", 2, Some(5_000_000_000), Some(10_000_000_000)),
+                ("Paris", 1, Some(20_000_000_000), Some(20_000_000_000)),
+            ]
+        );
+        assert_eq!(
+            partitions[0].1.columns[2].bool_packer().to_vec(),
+            &[Some(true), Some(false)]
+        );
+
+        // a table in a single partition is kept as is
+        let data = b"ts,city,temp,sunny\n10,Boston,71.5,true\n".to_vec();
+        let table = convert(FileFormat::Csv, data, &mapping()).unwrap();
+        let partitions = table.partition(|_, _| "p".to_string());
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].1.rows, 1);
+    }
+
+    #[test]
+    fn formats_and_units() {
+        assert_eq!("CSV".parse::<FileFormat>().unwrap(), FileFormat::Csv);
+        assert_eq!(
+            "parquet".parse::<FileFormat>().unwrap(),
+            FileFormat::Parquet
+        );
+        assert!("json".parse::<FileFormat>().is_err());
+
+        assert_eq!("ms".parse::<TimeUnit>().unwrap(), TimeUnit::Milliseconds);
+        assert!("minutes".parse::<TimeUnit>().is_err());
+    }
+}
@@ -0,0 +1,215 @@
+//! This module contains the translation of JSON documents, such as the messages published by
+//! IoT devices, into line protocol, as described by a `JsonMapping`.
+//!
+//! A document is either an object, which becomes a line, or an array of objects, each of
+//! which becomes a line. The mapping names the measurement of the lines and, for each of their
+//! tags and fields, the path of the value in the object, its keys separated by dots, such as
+//! `readings.temperature`. Numbers become float fields, booleans boolean fields and strings
+//! string fields.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use snafu::{ResultExt, Snafu};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use crate::prometheus::{escape, write_series};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error reading JSON mapping {:?}: {}", path, source))]
+    ReadingMapping {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error parsing JSON mapping {:?}: {}", path, source))]
+    ParsingMapping {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Error parsing JSON document: {}", source))]
+    ParsingDocument { source: serde_json::Error },
+
+    #[snafu(display("Expected a JSON object or an array of objects"))]
+    NotAnObject,
+
+    #[snafu(display("Invalid timestamp at {}: expected an integer", path))]
+    InvalidTimestamp { path: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// How the values of a JSON object become a line
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JsonMapping {
+    /// The measurement of the lines
+    pub measurement: String,
+    /// The paths of the tag values, by tag name
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// The paths of the field values, by field name
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Written as a tag with this name, the topic or source of the document
+    #[serde(default)]
+    pub source_tag: Option<String>,
+    /// The path of the timestamp, if any. Lines without one get the time of the write
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    /// The unit of the timestamp: s, ms, us or ns. Defaults to ns
+    #[serde(default)]
+    pub timestamp_unit: Option<String>,
+}
+
+impl JsonMapping {
+    /// Reads a mapping from the JSON file at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).context(ReadingMapping { path })?;
+        serde_json::from_reader(file).context(ParsingMapping { path })
+    }
+
+    /// Translates `document` into line protocol, tagging the lines with `source` if the
+    /// mapping has a `source_tag`. Objects without any of the mapped fields are skipped.
+    pub fn to_line_protocol(&self, source: &str, document: &[u8]) -> Result<String> {
+        let document: Value = serde_json::from_slice(document).context(ParsingDocument)?;
+        let objects: Vec<&Value> = match &document {
+            Value::Array(objects) => objects.iter().collect(),
+            object => vec![object],
+        };
+
+        let multiplier = match self.timestamp_unit.as_deref() {
+            Some("s") => 1_000_000_000,
+            Some("ms") => 1_000_000,
+            Some("us") => 1_000,
+            _ => 1,
+        };
+
+        let mut lines = String::new();
+        for object in objects {
+            if !object.is_object() {
+                return NotAnObject.fail();
+            }
+
+            let mut tags = BTreeMap::new();
+            if let Some(tag) = &self.source_tag {
+                if !source.is_empty() {
+                    tags.insert(tag.clone(), source.to_string());
+                }
+            }
+            for (tag, path) in &self.tags {
+                let value = match lookup(object, path) {
+                    Some(Value::String(value)) => value.clone(),
+                    Some(Value::Number(value)) => value.to_string(),
+                    Some(Value::Bool(value)) => value.to_string(),
+                    _ => continue,
+                };
+                if !value.is_empty() {
+                    tags.insert(tag.clone(), value);
+                }
+            }
+
+            let mut fields = vec![];
+            for (field, path) in &self.fields {
+                let value = match lookup(object, path) {
+                    Some(Value::Number(value)) => match value.as_f64() {
+                        Some(value) if value.is_finite() => value.to_string(),
+                        _ => continue,
+                    },
+                    Some(Value::Bool(value)) => value.to_string(),
+                    Some(Value::String(value)) => {
+                        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+                    }
+                    _ => continue,
+                };
+                fields.push(format!("{}={}", escape(field, &[',', '=', ' ']), value));
+            }
+            if fields.is_empty() {
+                continue;
+            }
+
+            write_series(&mut lines, &self.measurement, &tags);
+            lines.push(' ');
+            lines.push_str(&fields.join(","));
+            if let Some(path) = &self.timestamp {
+                if let Some(value) = lookup(object, path) {
+                    let timestamp = value
+                        .as_i64()
+                        .and_then(|t| t.checked_mul(multiplier))
+                        .ok_or_else(|| Error::InvalidTimestamp { path: path.clone() })?;
+                    write!(lines, " {}", timestamp).expect("writing to a string");
+                }
+            }
+            lines.push('\n');
+        }
+
+        Ok(lines)
+    }
+}
+
+/// Returns the value at the dot separated `path` of `object`
+fn lookup<'a>(object: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(object, |value, key| value.as_object()?.get(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate() {
+        let mapping: JsonMapping = serde_json::from_str(
+            r#"{
+                "measurement": "sensors",
+                "tags": {"device": "id", "room": "location.room"},
+                "fields": {"temperature": "readings.temp", "on": "on", "status": "status"},
+                "source_tag": "topic",
+                "timestamp": "ts",
+                "timestamp_unit": "ms"
+            }"#,
+        )
+        .unwrap();
+
+        let lines = mapping
+            .to_line_protocol(
+                "home/kitchen",
+                br#"[
+                    {"id": 7, "location": {"room": "kitchen east"}, "readings": {"temp": 21.5},
+                     "on": true, "status": "say \"hi\"", "ts": 1000},
+                    {"id": "a", "readings": {"temp": 20}},
+                    {"id": "b", "other": 1}
+                ]"#,
+            )
+            .unwrap();
+        assert_eq!(
+            lines,
+            "sensors,device=7,room=kitchen\\ east,topic=home/kitchen \
+             on=true,status=\"say \\\"hi\\\"\",temperature=21.5 1000000000\n\
+             sensors,device=a,topic=home/kitchen temperature=20\n"
+        );
+        let parsed = influxdb_line_protocol::parse_lines(&lines)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(parsed.len(), 2);
+
+        let err = mapping.to_line_protocol("", b"[1]").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected a JSON object or an array of objects"
+        );
+        let err = mapping
+            .to_line_protocol("", br#"{"on": false, "ts": "yesterday"}"#)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid timestamp at ts: expected an integer"
+        );
+        assert!(mapping.to_line_protocol("", b"{").is_err());
+    }
+}
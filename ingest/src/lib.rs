@@ -15,7 +15,7 @@ use influxdb_line_protocol::{FieldValue, ParsedLine};
 use influxdb_tsm::{
     mapper::{ColumnData, MeasurementTable, TSMMeasurementMapper},
     reader::{BlockDecoder, TSMBlockReader, TSMIndexReader},
-    BlockType, TSMError,
+    BlockType, KeyFormat, TSMError,
 };
 use packers::{
     ByteArray, Error as TableError, IOxTableWriter, IOxTableWriterSource, Packer, Packers,
@@ -27,7 +27,12 @@ use std::{
 };
 use tracing::debug;
 
+pub mod import;
+pub mod json;
+pub mod otlp;
 pub mod parquet;
+pub mod prometheus;
+pub mod tsm_import;
 
 #[derive(Debug, Clone, Copy)]
 pub struct ConversionSettings {
@@ -510,167 +515,38 @@ fn pack_lines<'a>(schema: &Schema, lines: &[ParsedLine<'a>]) -> Vec<Packers> {
     packers
 }
 
-// use arrow::array;
-// use arrow::datatypes;
-// use arrow::ipc::writer;
-// use arrow::record_batch;
-// use std::fs::File;
-// use std::sync::Arc;
-
-// fn arrow_datatype(datatype: DataType) -> datatypes::DataType {
-//     match datatype {
-//         DataType::Float => datatypes::DataType::Float64,
-//         DataType::Integer => datatypes::DataType::Int64,
-//         DataType::String => datatypes::DataType::Utf8,
-//         // DataType::String => datatypes::DataType::Dictionary(
-//         //     std::boxed::Box::new(datatypes::DataType::Int16),
-//         //     std::boxed::Box::new(datatypes::DataType::Utf8),
-//         // ),
-//         DataType::Boolean => datatypes::DataType::Boolean,
-//         DataType::Timestamp => datatypes::DataType::Int64,
-//     }
-// }
-
-// fn write_arrow_file(parquet_schema: Schema, packers: Vec<Packers>) -> Result<(), Error> {
-//     let file = File::create("/tmp/http_api_requests_total.arrow").unwrap();
-
-//     let mut record_batch_fields: Vec<datatypes::Field> = vec![];
-//     // no default() on Field...
-//     record_batch_fields.resize(
-//         parquet_schema.get_col_defs().len(),
-//         datatypes::Field::new("foo", datatypes::DataType::Int64, false),
-//     );
-
-//     for col_def in parquet_schema.get_col_defs() {
-//         let nullable = col_def.data_type != DataType::Timestamp;
-//         // if col_def.data_type == DataType::Timestamp {
-//         //     nullable = false;
-//         // } else {
-//         //     nullable = true;
-//         // }
-
-//         record_batch_fields[col_def.index as usize] = datatypes::Field::new(
-//             col_def.name.as_str(),
-//             arrow_datatype(col_def.data_type),
-//             nullable,
-//         );
-//     }
-//     println!("{:?}", record_batch_fields);
-//     println!("{:?}", parquet_schema.get_col_defs());
-//     let schema = datatypes::Schema::new(record_batch_fields);
-
-//     let mut writer = writer::StreamWriter::try_new(file, &schema).unwrap();
-
-//     // let num_rows = packers[0].num_rows();
-//     let batch_size = 60_000;
-
-//     let mut packer_chunkers: Vec<PackerChunker<'_>> = vec![];
-//     for packer in &packers {
-//         packer_chunkers.push(packer.chunk_values(batch_size));
-//     }
-
-//     loop {
-//         let mut chunked_packers: Vec<Packers> = Vec::with_capacity(packers.len());
-//         for chunker in &mut packer_chunkers {
-//             match chunker {
-//                 PackerChunker::Float(c) => {
-//                     if let Some(chunk) = c.next() {
-//                         chunked_packers.push(Packers::Float(Packer::from(chunk)));
-//                     }
-//                 }
-//                 PackerChunker::Integer(c) => {
-//                     if let Some(chunk) = c.next() {
-//                         chunked_packers.push(Packers::Integer(Packer::from(chunk)));
-//                     }
-//                 }
-//                 PackerChunker::String(c) => {
-//                     if let Some(chunk) = c.next() {
-//                         chunked_packers.push(Packers::String(Packer::from(chunk)));
-//                     }
-//                 }
-//                 PackerChunker::Boolean(c) => {
-//                     if let Some(chunk) = c.next() {
-//                         chunked_packers.push(Packers::Boolean(Packer::from(chunk)));
-//                     }
-//                 }
-//             }
-//         }
-
-//         if chunked_packers.is_empty() {
-//             break;
-//         }
-
-//         // let sort = [0, 7, 6, 12];
-//         // let sort = [8, 4, 9, 0, 1, 7, 10, 6, 5, 2, 3, 12];
-//         let sort = [3, 2, 5, 6, 10, 7, 1, 0, 9, 4, 8, 12];
-//         packers::sorter::sort(&mut chunked_packers, &sort).unwrap();
-
-//         println!(
-//             "Writing {:?} packers with size: {:?}",
-//             chunked_packers.len(),
-//             chunked_packers[0].num_rows()
-//         );
-//         write_arrow_batch(&mut writer, Arc::new(schema.clone()), chunked_packers);
-//     }
-
-//     writer.finish().unwrap();
-//     Ok(())
-// }
-
-// fn write_arrow_batch(
-//     w: &mut writer::StreamWriter<File>,
-//     schema: Arc<datatypes::Schema>,
-//     packers: Vec<Packers>,
-// ) {
-//     let mut record_batch_arrays: Vec<array::ArrayRef> = vec![];
-
-//     for packer in packers {
-//         match packer {
-//             Packers::Float(p) => {
-//                 record_batch_arrays.push(Arc::new(array::Float64Array::from(p.values().to_vec())));
-//             }
-//             Packers::Integer(p) => {
-//                 record_batch_arrays.push(Arc::new(array::Int64Array::from(p.values().to_vec())));
-//             }
-//             Packers::String(p) => {
-//                 let mut builder = array::StringBuilder::new(p.num_rows());
-//                 for v in p.values() {
-//                     match v {
-//                         Some(v) => {
-//                             builder.append_value(v.as_utf8().unwrap()).unwrap();
-//                         }
-//                         None => {
-//                             builder.append_null().unwrap();
-//                         }
-//                     }
-//                 }
-//                 let array = builder.finish();
-//                 record_batch_arrays.push(Arc::new(array));
-//             }
-//             Packers::Boolean(p) => {
-//                 let array = array::BooleanArray::from(p.values().to_vec());
-//                 record_batch_arrays.push(Arc::new(array));
-//             }
-//         }
-//     }
-
-//     let record_batch = record_batch::RecordBatch::try_new(schema, record_batch_arrays).unwrap();
-//     w.write(&record_batch).unwrap();
-// }
-
 /// Converts one or more TSM files into the packers internal columnar
 /// data format and then passes that converted data to a `IOxTableWriter`.
 pub struct TSMFileConverter {
     table_writer_source: Box<dyn IOxTableWriterSource>,
+    key_format: KeyFormat,
+    batch_rows: usize,
 }
 
 impl TSMFileConverter {
     pub fn new(table_writer_source: Box<dyn IOxTableWriterSource>) -> Self {
         Self {
             table_writer_source,
+            key_format: KeyFormat::default(),
+            batch_rows: usize::MAX,
         }
     }
 
+    /// Writes the rows of each measurement in batches of at least `batch_rows` rows, one
+    /// block of series at a time, instead of holding all the rows of the measurement in
+    /// memory. A batch only ends between the blocks of two series, so it may hold more rows.
+    pub fn with_batch_rows(mut self, batch_rows: usize) -> Self {
+        self.batch_rows = batch_rows;
+        self
+    }
+
+    /// Converts TSM files whose keys are written in `key_format`, such as the files of
+    /// InfluxDB 1.x shards
+    pub fn with_key_format(mut self, key_format: KeyFormat) -> Self {
+        self.key_format = key_format;
+        self
+    }
+
     /// Given one or more sets of readers, converts the underlying TSM data into
     /// a set of Parquet files segmented by measurement name.
     ///
@@ -703,7 +579,10 @@ impl TSMFileConverter {
 
         for (i, (reader, size)) in index_readers.into_iter().enumerate() {
             let index_reader = TSMIndexReader::try_new(reader, size).context(TSMProcessing)?;
-            mappers.push(TSMMeasurementMapper::new(index_reader.peekable(), i));
+            mappers.push(
+                TSMMeasurementMapper::new(index_reader.peekable(), i)
+                    .with_key_format(self.key_format),
+            );
         }
 
         // track all the block readers for each file, so that the correct reader
@@ -722,17 +601,27 @@ impl TSMFileConverter {
             match next_measurement {
                 Some(mut table) => {
                     // convert (potentially merged) measurement..
-                    let (schema, packed_columns) =
-                        Self::process_measurement_table(&mut block_reader, &mut table)?;
-                    let mut table_writer = self
-                        .table_writer_source
-                        .next_writer(&schema)
-                        .context(WriterCreation)?;
-
-                    table_writer
-                        .write_batch(&packed_columns)
-                        .context(WriterCreation)?;
-                    table_writer.close().context(WriterCreation)?;
+                    let table_writer_source = &mut self.table_writer_source;
+                    let mut table_writer = None;
+                    Self::process_measurement_table(
+                        &mut block_reader,
+                        &mut table,
+                        self.batch_rows,
+                        |schema, packed_columns| {
+                            let mut writer = match table_writer.take() {
+                                Some(writer) => writer,
+                                None => table_writer_source
+                                    .next_writer(schema)
+                                    .context(WriterCreation)?,
+                            };
+                            writer.write_batch(packed_columns).context(WriterCreation)?;
+                            table_writer = Some(writer);
+                            Ok(())
+                        },
+                    )?;
+                    if let Some(mut table_writer) = table_writer {
+                        table_writer.close().context(WriterCreation)?;
+                    }
                 }
                 None => break,
             }
@@ -806,11 +695,14 @@ impl TSMFileConverter {
     }
 
     // Given a measurement table `process_measurement_table` produces an
-    // appropriate schema and set of Packers.
+    // appropriate schema and passes sets of Packers of at least `batch_rows`
+    // rows to `write_batch`, which is called at least once.
     fn process_measurement_table(
         mut block_reader: impl BlockDecoder,
         m: &mut MeasurementTable,
-    ) -> Result<(Schema, Vec<Packers>), Error> {
+        batch_rows: usize,
+        mut write_batch: impl FnMut(&Schema, &[Packers]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
         let mut builder = SchemaBuilder::new(&m.name);
         let mut packed_columns: Vec<Packers> = Vec::new();
 
@@ -854,21 +746,16 @@ impl TSMFileConverter {
         //    columns, including any NULL entries.
         //  - Materialise NULL values for any field columns that the emitted
         //    section does not have any data for.
+        //  - Pass the packers to `write_batch` and clear them once they hold
+        //    at least `batch_rows` rows.
         //
+        let mut written = false;
         m.process(
             &mut block_reader,
             |section: influxdb_tsm::mapper::TableSection| -> Result<(), TSMError> {
                 // number of rows in each column in this table section.
                 let col_len = section.len();
 
-                // if this is the first section of the table then we can avoid
-                // extending slices and just move the slice over to the packer
-                // vector.
-                //
-                // TODO(edd): will the compiler just figure this out for us w.r.t
-                // `extend_from_slice`??
-                let first_table_section = section.is_first();
-
                 // Process the timestamp column.
                 let ts_idx = name_packer
                     .get(schema.timestamp())
@@ -877,7 +764,10 @@ impl TSMFileConverter {
                         description: e.to_string(),
                     })?;
 
-                if section.is_first() {
+                // if this is the first section of the batch then we can avoid
+                // copying the timestamps and just move them over to the packer
+                // vector.
+                if packed_columns[*ts_idx].num_rows() == 0 {
                     packed_columns[*ts_idx] = Packers::from(section.ts);
                 } else {
                     packed_columns[*ts_idx]
@@ -887,7 +777,8 @@ impl TSMFileConverter {
 
                 // Process any tag columns that this section has values for.
                 // We have to materialise the values for the column, which are
-                // guaranteed to be the same.
+                // guaranteed to be the same, so they all share the buffer of a
+                // single value.
                 for (tag_key, tag_value) in &section.tag_cols {
                     let idx = name_packer
                         .get(tag_key)
@@ -896,14 +787,9 @@ impl TSMFileConverter {
                             description: e.to_string(),
                         })?;
 
-                    // this will create a column of repeated values.
-                    if first_table_section {
-                        packed_columns[*idx] = Packers::from_elem_str(tag_value, col_len);
-                    } else {
-                        packed_columns[*idx]
-                            .str_packer_mut()
-                            .extend_from_slice(&vec![ByteArray::from(tag_value.as_ref()); col_len]);
-                    }
+                    packed_columns[*idx]
+                        .str_packer_mut()
+                        .fill_with(ByteArray::from(tag_value.as_ref()), col_len);
                 }
 
                 // Not all tag columns may be present in the section. For those
@@ -926,21 +812,11 @@ impl TSMFileConverter {
                             description: e.to_string(),
                         })?;
 
-                    if first_table_section {
-                        // creates a column of repeated None values.
-                        let col: Vec<Option<Vec<u8>>> = vec![None; col_len];
-                        packed_columns[*idx] = Packers::from(col);
-                    } else {
-                        // pad out column with None values because we don't have a
-                        // value for it.
-                        packed_columns[*idx]
-                            .str_packer_mut()
-                            .fill_with_null(col_len);
-                    }
+                    packed_columns[*idx].fill_with_null(col_len);
                 }
 
-                // Next we will write out all of the field columns for this
-                // section.
+                // Next we will append all of the field columns for this
+                // section, moving the values over from the section.
                 let mut got_field_cols = Vec::new();
                 for (field_key, field_values) in section.field_cols {
                     let idx = name_packer
@@ -950,51 +826,24 @@ impl TSMFileConverter {
                             description: e.to_string(),
                         })?;
 
-                    if first_table_section {
-                        match field_values {
-                            ColumnData::Float(v) => packed_columns[*idx] = Packers::from(v),
-                            ColumnData::Integer(v) => packed_columns[*idx] = Packers::from(v),
-                            ColumnData::Str(v) => packed_columns[*idx] = Packers::from(v),
-                            ColumnData::Bool(v) => packed_columns[*idx] = Packers::from(v),
-                            ColumnData::Unsigned(v) => packed_columns[*idx] = Packers::from(v),
-                        }
-                    } else {
-                        match field_values {
-                            ColumnData::Float(v) => packed_columns[*idx]
-                                .f64_packer_mut()
-                                .extend_from_option_slice(&v),
-                            ColumnData::Integer(v) => packed_columns[*idx]
-                                .i64_packer_mut()
-                                .extend_from_option_slice(&v),
-                            ColumnData::Str(values) => {
-                                let col = packed_columns[*idx].str_packer_mut();
-                                for value in values {
-                                    match value {
-                                        Some(v) => col.push(ByteArray::from(v)),
-                                        None => col.push_option(None),
-                                    }
-                                }
-                            }
-                            ColumnData::Bool(v) => packed_columns[*idx]
-                                .bool_packer_mut()
-                                .extend_from_option_slice(&v),
-                            ColumnData::Unsigned(values) => {
-                                let col = packed_columns[*idx].i64_packer_mut();
-                                for value in values {
-                                    match value {
-                                        Some(v) => col.push(v as i64),
-                                        None => col.push_option(None),
-                                    }
-                                }
-                            }
-                        }
+                    let col = &mut packed_columns[*idx];
+                    match field_values {
+                        ColumnData::Float(v) => col.f64_packer_mut().extend_from_options(v),
+                        ColumnData::Integer(v) => col.i64_packer_mut().extend_from_options(v),
+                        ColumnData::Str(v) => col
+                            .str_packer_mut()
+                            .extend_from_options(v.into_iter().map(|v| v.map(ByteArray::from))),
+                        ColumnData::Bool(v) => col.bool_packer_mut().extend_from_options(v),
+                        ColumnData::Unsigned(v) => col
+                            .i64_packer_mut()
+                            .extend_from_options(v.into_iter().map(|v| v.map(|v| v as i64))),
                     }
                     got_field_cols.push(field_key);
                 }
 
                 // Finally, materialise NULL values for all of the field columns
                 // that this section does not have any values for
-                for (key, field_type) in &fks {
+                for (key, _) in &fks {
                     if got_field_cols.contains(key) {
                         continue;
                     }
@@ -1006,65 +855,27 @@ impl TSMFileConverter {
                             description: e.to_string(),
                         })?;
 
-                    // this will create a column of repeated None values.
-                    if first_table_section {
-                        match field_type {
-                            BlockType::Float => {
-                                let col: Vec<Option<f64>> = vec![None; col_len];
-                                packed_columns[*idx] = Packers::from(col);
-                            }
-                            BlockType::Integer => {
-                                let col: Vec<Option<i64>> = vec![None; col_len];
-                                packed_columns[*idx] = Packers::from(col);
-                            }
-                            BlockType::Bool => {
-                                let col: Vec<Option<bool>> = vec![None; col_len];
-                                packed_columns[*idx] = Packers::from(col);
-                            }
-                            BlockType::Str => {
-                                let col: Vec<Option<Vec<u8>>> = vec![None; col_len];
-                                packed_columns[*idx] = Packers::from(col);
-                            }
-                            BlockType::Unsigned => {
-                                let col: Vec<Option<u64>> = vec![None; col_len];
-                                packed_columns[*idx] = Packers::from(col);
-                            }
-                        }
-                    } else {
-                        match field_type {
-                            BlockType::Float => {
-                                packed_columns[*idx]
-                                    .f64_packer_mut()
-                                    .fill_with_null(col_len);
-                            }
-                            BlockType::Integer => {
-                                packed_columns[*idx]
-                                    .i64_packer_mut()
-                                    .fill_with_null(col_len);
-                            }
-                            BlockType::Bool => {
-                                packed_columns[*idx]
-                                    .bool_packer_mut()
-                                    .fill_with_null(col_len);
-                            }
-                            BlockType::Str => {
-                                packed_columns[*idx]
-                                    .str_packer_mut()
-                                    .fill_with_null(col_len);
-                            }
-                            BlockType::Unsigned => {
-                                packed_columns[*idx]
-                                    .i64_packer_mut()
-                                    .fill_with_null(col_len);
-                            }
-                        }
+                    packed_columns[*idx].fill_with_null(col_len);
+                }
+
+                if packed_columns[*ts_idx].num_rows() >= batch_rows {
+                    write_batch(&schema, &packed_columns).map_err(|e| TSMError {
+                        description: e.to_string(),
+                    })?;
+                    written = true;
+                    for packer in &mut packed_columns {
+                        packer.clear();
                     }
                 }
                 Ok(())
             },
         )
         .context(TSMProcessing)?;
-        Ok((schema, packed_columns))
+
+        if !written || packed_columns[0].num_rows() > 0 {
+            write_batch(&schema, &packed_columns)?;
+        }
+        Ok(())
     }
 }
 
@@ -1082,6 +893,7 @@ impl std::fmt::Debug for TSMFileConverter {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("TSMFileConverter")
             .field("table_writer_source", &"DYNAMIC")
+            .field("key_format", &self.key_format)
             .finish()
     }
 }
@@ -1790,102 +1602,19 @@ mod tests {
         // | NULL |  west  |    a   | 99.5  |  NULL   |   NULL  | 3000 |
         // | NULL |  west  |    a   | 100.3 |  NULL   |   NULL  | 4000 |
 
-        let mut table = MeasurementTable::new("cpu".to_string(), 0);
-        // cpu region=east temp=<all the block data for this key>
-        table.add_series_data(
-            vec![("region".to_string(), "east".to_string())],
-            "temp".to_string(),
-            Block {
-                min_time: 0,
-                max_time: 0,
-                offset: 0,
-                size: 0,
-                typ: BlockType::Float,
-                reader_idx: 0,
-            },
-        )?;
-
-        // cpu region=east voltage=<all the block data for this key>
-        table.add_series_data(
-            vec![("region".to_string(), "east".to_string())],
-            "voltage".to_string(),
-            Block {
-                min_time: 1,
-                max_time: 0,
-                offset: 0,
-                size: 0,
-                typ: BlockType::Float,
-                reader_idx: 0,
-            },
-        )?;
-
-        // cpu region=west,server=a temp=<all the block data for this key>
-        table.add_series_data(
-            vec![
-                ("region".to_string(), "west".to_string()),
-                ("server".to_string(), "a".to_string()),
-            ],
-            "temp".to_string(),
-            Block {
-                min_time: 2,
-                max_time: 0,
-                offset: 0,
-                size: 0,
-                typ: BlockType::Float,
-                reader_idx: 0,
-            },
-        )?;
-
-        // cpu az=b watts=<all the block data for this key>
-        table.add_series_data(
-            vec![("az".to_string(), "b".to_string())],
-            "watts".to_string(),
-            Block {
-                min_time: 3,
-                max_time: 0,
-                offset: 0,
-                size: 0,
-                typ: BlockType::Unsigned,
-                reader_idx: 0,
+        let (mut table, decoder) = cpu_table()?;
+        let mut batches = vec![];
+        TSMFileConverter::process_measurement_table(
+            decoder,
+            &mut table,
+            usize::MAX,
+            |schema, packers| {
+                batches.push((schema.clone(), packers.to_vec()));
+                Ok(())
             },
         )?;
-
-        let mut block_map = BTreeMap::new();
-        block_map.insert(
-            0,
-            BlockData::Float {
-                i: 0,
-                ts: vec![0, 1000, 2000],
-                values: vec![1.2, 1.2, 1.4],
-            },
-        );
-        block_map.insert(
-            1,
-            BlockData::Float {
-                i: 0,
-                ts: vec![0, 1000, 2000],
-                values: vec![10.2, 10.2, 10.4],
-            },
-        );
-        block_map.insert(
-            2,
-            BlockData::Float {
-                i: 0,
-                ts: vec![2000, 3000, 4000],
-                values: vec![100.2, 99.5, 100.3],
-            },
-        );
-        block_map.insert(
-            3,
-            BlockData::Unsigned {
-                i: 0,
-                ts: vec![3000, 4000, 5000],
-                values: vec![1000, 2000, 3000],
-            },
-        );
-
-        let decoder = MockBlockDecoder::new(block_map);
-        let (schema, packers) = TSMFileConverter::process_measurement_table(decoder, &mut table)?;
+        assert_eq!(batches.len(), 1);
+        let (schema, packers) = batches.remove(0);
 
         let expected_defs = vec![
             ColumnDefinition::new("az", 0, DataType::String),
@@ -2007,6 +1736,140 @@ mod tests {
         Ok(())
     }
 
+    // The `cpu` measurement table of the input data of `process_measurement_table`, and a
+    // decoder of its blocks
+    fn cpu_table() -> Result<(MeasurementTable, MockBlockDecoder), Box<dyn std::error::Error>> {
+        let mut table = MeasurementTable::new("cpu".to_string(), 0);
+        // cpu region=east temp=<all the block data for this key>
+        table.add_series_data(
+            vec![("region".to_string(), "east".to_string())],
+            "temp".to_string(),
+            Block {
+                min_time: 0,
+                max_time: 0,
+                offset: 0,
+                size: 0,
+                typ: BlockType::Float,
+                reader_idx: 0,
+            },
+        )?;
+
+        // cpu region=east voltage=<all the block data for this key>
+        table.add_series_data(
+            vec![("region".to_string(), "east".to_string())],
+            "voltage".to_string(),
+            Block {
+                min_time: 1,
+                max_time: 0,
+                offset: 0,
+                size: 0,
+                typ: BlockType::Float,
+                reader_idx: 0,
+            },
+        )?;
+
+        // cpu region=west,server=a temp=<all the block data for this key>
+        table.add_series_data(
+            vec![
+                ("region".to_string(), "west".to_string()),
+                ("server".to_string(), "a".to_string()),
+            ],
+            "temp".to_string(),
+            Block {
+                min_time: 2,
+                max_time: 0,
+                offset: 0,
+                size: 0,
+                typ: BlockType::Float,
+                reader_idx: 0,
+            },
+        )?;
+
+        // cpu az=b watts=<all the block data for this key>
+        table.add_series_data(
+            vec![("az".to_string(), "b".to_string())],
+            "watts".to_string(),
+            Block {
+                min_time: 3,
+                max_time: 0,
+                offset: 0,
+                size: 0,
+                typ: BlockType::Unsigned,
+                reader_idx: 0,
+            },
+        )?;
+
+        let mut block_map = BTreeMap::new();
+        block_map.insert(
+            0,
+            BlockData::Float {
+                i: 0,
+                ts: vec![0, 1000, 2000],
+                values: vec![1.2, 1.2, 1.4],
+            },
+        );
+        block_map.insert(
+            1,
+            BlockData::Float {
+                i: 0,
+                ts: vec![0, 1000, 2000],
+                values: vec![10.2, 10.2, 10.4],
+            },
+        );
+        block_map.insert(
+            2,
+            BlockData::Float {
+                i: 0,
+                ts: vec![2000, 3000, 4000],
+                values: vec![100.2, 99.5, 100.3],
+            },
+        );
+        block_map.insert(
+            3,
+            BlockData::Unsigned {
+                i: 0,
+                ts: vec![3000, 4000, 5000],
+                values: vec![1000, 2000, 3000],
+            },
+        );
+
+        Ok((table, MockBlockDecoder::new(block_map)))
+    }
+
+    #[test]
+    fn process_measurement_table_in_batches() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut table, decoder) = cpu_table()?;
+        let mut batches = vec![];
+        TSMFileConverter::process_measurement_table(decoder, &mut table, 3, |_, packers| {
+            batches.push(packers.to_vec());
+            Ok(())
+        })?;
+
+        // each series is a section of three rows, so each one is a batch
+        let times: Vec<_> = batches
+            .iter()
+            .map(|packers| packers[6].i64_packer().to_vec())
+            .collect();
+        assert_eq!(
+            times,
+            vec![
+                vec![Some(3), Some(4), Some(5)],
+                vec![Some(0), Some(1), Some(2)],
+                vec![Some(2), Some(3), Some(4)],
+            ]
+        );
+        assert_eq!(
+            batches[1][1],
+            Packers::String(Packer::from(vec![
+                Some(ByteArray::from("east")),
+                Some(ByteArray::from("east")),
+                Some(ByteArray::from("east")),
+            ]))
+        );
+
+        Ok(())
+    }
+
     fn empty_block() -> Block {
         Block {
             min_time: 0,
@@ -0,0 +1,290 @@
+//! This module contains the translation of the metrics exported by OpenTelemetry SDKs and
+//! collectors over OTLP into line protocol.
+//!
+//! Each data point becomes a line of the table named after its metric, prefixed with the
+//! `table_prefix` of the `OtlpRules` of the database. The kept attributes of its resource
+//! and its own attributes are its tags. Gauges and sums have a `value` field, histograms the
+//! fields `count`, `sum` and `buckets`, as described in `data_types::histogram`, and
+//! summaries the fields `count`, `sum` and `quantile_<q>` for each of their quantiles.
+use std::{collections::BTreeMap, convert::TryFrom, fmt::Write};
+
+use data_types::{database_rules::OtlpRules, histogram::Histogram};
+use generated_types::opentelemetry::proto::{
+    collector::metrics::v1::ExportMetricsServiceRequest,
+    common::v1::{any_value, KeyValue},
+    metrics::v1::{metric, number_data_point},
+};
+
+use crate::prometheus::{escape, write_series};
+
+/// Translates the metrics of `request` into line protocol. Data points without a time get
+/// the time of the write. Values that are not finite are skipped, as line protocol can't
+/// represent them.
+pub fn to_line_protocol(request: &ExportMetricsServiceRequest, rules: &OtlpRules) -> String {
+    let mut lines = String::new();
+
+    for resource_metrics in &request.resource_metrics {
+        let mut resource_tags = BTreeMap::new();
+        if let Some(resource) = &resource_metrics.resource {
+            let kept = resource
+                .attributes
+                .iter()
+                .filter(|a| rules.keeps_resource_attribute(&a.key));
+            add_tags(&mut resource_tags, kept);
+        }
+
+        let metrics = resource_metrics
+            .scope_metrics
+            .iter()
+            .flat_map(|scope| &scope.metrics)
+            .filter(|metric| !metric.name.is_empty());
+
+        for metric in metrics {
+            let table = format!("{}{}", rules.table_prefix, metric.name);
+            let mut write_point = |attributes: &[KeyValue], fields: &[String], time: u64| {
+                if fields.is_empty() {
+                    return;
+                }
+                let mut tags = resource_tags.clone();
+                add_tags(&mut tags, attributes);
+
+                write_series(&mut lines, &table, &tags);
+                lines.push(' ');
+                lines.push_str(&fields.join(","));
+                if let Some(time) = i64::try_from(time).ok().filter(|time| *time > 0) {
+                    write!(lines, " {}", time).expect("writing to a string");
+                }
+                lines.push('\n');
+            };
+
+            match &metric.data {
+                Some(metric::Data::Gauge(gauge)) => {
+                    for point in &gauge.data_points {
+                        let fields: Vec<_> =
+                            number_field(point.value.as_ref()).into_iter().collect();
+                        write_point(&point.attributes, &fields, point.time_unix_nano);
+                    }
+                }
+                Some(metric::Data::Sum(sum)) => {
+                    for point in &sum.data_points {
+                        let fields: Vec<_> =
+                            number_field(point.value.as_ref()).into_iter().collect();
+                        write_point(&point.attributes, &fields, point.time_unix_nano);
+                    }
+                }
+                Some(metric::Data::Histogram(histogram)) => {
+                    for point in &histogram.data_points {
+                        let mut fields = vec![format!("count={}", point.count)];
+                        if point.sum.is_finite() {
+                            fields.push(format!("sum={}", point.sum));
+                        }
+                        // there is a count for each explicit bound and the +Inf bucket
+                        if !point.bucket_counts.is_empty()
+                            && point.bucket_counts.len() == point.explicit_bounds.len() + 1
+                        {
+                            let bounds = point
+                                .explicit_bounds
+                                .iter()
+                                .copied()
+                                .chain(std::iter::once(f64::INFINITY));
+                            let mut cumulative = 0u64;
+                            let buckets = bounds
+                                .zip(&point.bucket_counts)
+                                .map(|(bound, count)| {
+                                    cumulative += *count;
+                                    (bound, cumulative as f64)
+                                })
+                                .collect();
+                            fields.push(format!("buckets=\"{}\"", Histogram::new(buckets)));
+                        }
+                        write_point(&point.attributes, &fields, point.time_unix_nano);
+                    }
+                }
+                Some(metric::Data::Summary(summary)) => {
+                    for point in &summary.data_points {
+                        let mut fields = vec![format!("count={}", point.count)];
+                        if point.sum.is_finite() {
+                            fields.push(format!("sum={}", point.sum));
+                        }
+                        for quantile in &point.quantile_values {
+                            if quantile.value.is_finite() {
+                                let name = format!("quantile_{}", quantile.quantile);
+                                fields.push(format!(
+                                    "{}={}",
+                                    escape(&name, &[',', '=', ' ']),
+                                    quantile.value
+                                ));
+                            }
+                        }
+                        write_point(&point.attributes, &fields, point.time_unix_nano);
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
+    lines
+}
+
+/// Returns the `value` field of a gauge or sum data point, if it has a finite value
+fn number_field(value: Option<&number_data_point::Value>) -> Option<String> {
+    match value? {
+        number_data_point::Value::AsInt(value) => Some(format!("value={}i", value)),
+        number_data_point::Value::AsDouble(value) if value.is_finite() => {
+            Some(format!("value={}", value))
+        }
+        number_data_point::Value::AsDouble(_) => None,
+    }
+}
+
+/// Adds the attributes with scalar, non-empty values to `tags`, replacing the tags with the
+/// same keys
+fn add_tags<'a>(
+    tags: &mut BTreeMap<String, String>,
+    attributes: impl IntoIterator<Item = &'a KeyValue>,
+) {
+    for attribute in attributes {
+        let value = match attribute.value.as_ref().and_then(|v| v.value.as_ref()) {
+            Some(any_value::Value::StringValue(value)) => value.clone(),
+            Some(any_value::Value::BoolValue(value)) => value.to_string(),
+            Some(any_value::Value::IntValue(value)) => value.to_string(),
+            Some(any_value::Value::DoubleValue(value)) => value.to_string(),
+            _ => continue,
+        };
+        if !attribute.key.is_empty() && !value.is_empty() {
+            tags.insert(attribute.key.clone(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generated_types::opentelemetry::proto::{
+        common::v1::AnyValue,
+        metrics::v1::{
+            summary_data_point::ValueAtQuantile, Gauge, Histogram as HistogramData,
+            HistogramDataPoint, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics, Summary,
+            SummaryDataPoint,
+        },
+        resource::v1::Resource,
+    };
+
+    fn attribute(key: &str, value: any_value::Value) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue { value: Some(value) }),
+        }
+    }
+
+    fn request(metrics: Vec<Metric>) -> ExportMetricsServiceRequest {
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![
+                        attribute(
+                            "service.name",
+                            any_value::Value::StringValue("checkout".to_string()),
+                        ),
+                        attribute("process.pid", any_value::Value::IntValue(42)),
+                    ],
+                    dropped_attributes_count: 0,
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: None,
+                    metrics,
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    fn metric(name: &str, data: metric::Data) -> Metric {
+        Metric {
+            name: name.to_string(),
+            description: String::new(),
+            unit: String::new(),
+            data: Some(data),
+        }
+    }
+
+    #[test]
+    fn translate() {
+        let request = request(vec![
+            metric(
+                "queue_size",
+                metric::Data::Gauge(Gauge {
+                    data_points: vec![
+                        NumberDataPoint {
+                            attributes: vec![attribute(
+                                "queue",
+                                any_value::Value::StringValue("a b".to_string()),
+                            )],
+                            time_unix_nano: 1000,
+                            value: Some(number_data_point::Value::AsInt(3)),
+                            ..Default::default()
+                        },
+                        NumberDataPoint {
+                            time_unix_nano: 2000,
+                            value: Some(number_data_point::Value::AsDouble(f64::NAN)),
+                            ..Default::default()
+                        },
+                    ],
+                }),
+            ),
+            metric(
+                "latency",
+                metric::Data::Histogram(HistogramData {
+                    data_points: vec![HistogramDataPoint {
+                        time_unix_nano: 1000,
+                        count: 6,
+                        sum: 2.5,
+                        bucket_counts: vec![1, 0, 5],
+                        explicit_bounds: vec![0.1, 0.5],
+                        ..Default::default()
+                    }],
+                    aggregation_temporality: 2,
+                }),
+            ),
+            metric(
+                "rpc_duration",
+                metric::Data::Summary(Summary {
+                    data_points: vec![SummaryDataPoint {
+                        count: 4,
+                        sum: 2.0,
+                        quantile_values: vec![ValueAtQuantile {
+                            quantile: 0.99,
+                            value: 1.5,
+                        }],
+                        ..Default::default()
+                    }],
+                }),
+            ),
+        ]);
+
+        let lines = to_line_protocol(&request, &OtlpRules::default());
+        assert_eq!(
+            lines,
+            "queue_size,process.pid=42,queue=a\\ b,service.name=checkout value=3i 1000\n\
+             latency,process.pid=42,service.name=checkout count=6,sum=2.5,\
+             buckets=\"0.1:1,0.5:1,+Inf:6\" 1000\n\
+             rpc_duration,process.pid=42,service.name=checkout count=4,sum=2,quantile_0.99=1.5\n"
+        );
+
+        // the lines are valid line protocol
+        let parsed = influxdb_line_protocol::parse_lines(&lines)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(parsed.len(), 3);
+
+        let rules = OtlpRules {
+            resource_attributes: vec!["service.name".to_string()],
+            table_prefix: "otel_".to_string(),
+        };
+        let lines = to_line_protocol(&request, &rules);
+        assert!(lines.starts_with("otel_queue_size,queue=a\\ b,service.name=checkout value=3i"));
+        assert!(!lines.contains("process.pid"));
+    }
+}
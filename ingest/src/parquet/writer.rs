@@ -4,23 +4,28 @@ use arrow_deps::parquet::{
     basic::{Compression, Encoding, LogicalType, Repetition, Type as PhysicalType},
     errors::ParquetError,
     file::{
+        metadata::KeyValue,
         properties::{WriterProperties, WriterPropertiesBuilder},
         writer::{FileWriter, SerializedFileWriter, TryClone},
     },
     schema::types::{ColumnPath, Type},
 };
+use data_types::database_rules::{ParquetCompression, ParquetSettings, ParquetStatistics};
 use parquet::file::writer::ParquetWriter;
 use snafu::{OptionExt, ResultExt, Snafu};
 use std::{
+    borrow::Cow,
     fmt,
-    io::{Seek, Write},
+    io::{self, Cursor, Seek, SeekFrom, Write},
+    ops::Range,
     rc::Rc,
     str::FromStr,
+    sync::{Arc, Mutex},
 };
 use tracing::debug;
 
 use super::metadata::parquet_schema_as_string;
-use packers::{Error as TableError, IOxTableWriter, Packers};
+use packers::{Error as TableError, IOxTableWriter, Packer, Packers};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -82,6 +87,8 @@ where
 {
     parquet_schema: Rc<parquet::schema::types::Type>,
     file_writer: SerializedFileWriter<W>,
+    /// The most rows written in a row group, if limited
+    row_group_size: Option<usize>,
 }
 
 impl<W: 'static> IOxParquetTableWriter<W>
@@ -139,7 +146,38 @@ where
         compression_level: CompressionLevel,
         writer: W,
     ) -> Result<Self, Error> {
-        let writer_props = create_writer_props(&schema, compression_level);
+        Self::new_with_metadata(schema, compression_level, writer, vec![])
+    }
+
+    /// Create a new TableWriter like `new`, that also stores the
+    /// `(key, value)` pairs of `metadata` in the key-value metadata
+    /// of the footer of the parquet file
+    pub fn new_with_metadata(
+        schema: &data_types::table_schema::Schema,
+        compression_level: CompressionLevel,
+        writer: W,
+        metadata: Vec<(String, String)>,
+    ) -> Result<Self, Error> {
+        Self::new_with_settings(
+            schema,
+            compression_level,
+            writer,
+            metadata,
+            &ParquetSettings::default(),
+        )
+    }
+
+    /// Create a new TableWriter like `new_with_metadata`, that also
+    /// applies the row group size, codec, dictionary size and statistics
+    /// of `settings`
+    pub fn new_with_settings(
+        schema: &data_types::table_schema::Schema,
+        compression_level: CompressionLevel,
+        writer: W,
+        metadata: Vec<(String, String)>,
+        settings: &ParquetSettings,
+    ) -> Result<Self, Error> {
+        let writer_props = create_writer_props(&schema, compression_level, metadata, settings);
         let parquet_schema = convert_to_parquet_schema(&schema)?;
 
         let file_writer = SerializedFileWriter::new(writer, parquet_schema.clone(), writer_props)
@@ -150,6 +188,7 @@ where
         let parquet_writer = Self {
             parquet_schema,
             file_writer,
+            row_group_size: settings.row_group_size,
         };
         debug!(
             "ParqutWriter created for schema: {}",
@@ -163,10 +202,43 @@ where
     W: Write + Seek + TryClone,
 {
     /// Writes a batch of packed data to the output file in a single
-    /// column chunk
-    ///
-    /// TODO: better control of column chunks
+    /// row group, or in several if the batch has more rows than the
+    /// row group size
     fn write_batch(&mut self, packers: &[Packers]) -> Result<(), TableError> {
+        let num_rows = packers.first().map_or(0, Packers::num_rows);
+        let row_group_size = self.row_group_size.unwrap_or(num_rows).max(1);
+
+        let mut start = 0;
+        loop {
+            let end = num_rows.min(start + row_group_size);
+            self.write_row_group(packers, start..end)?;
+            start = end;
+            if start >= num_rows {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Closes this writer, and finalizes the underlying parquet file
+    fn close(&mut self) -> Result<(), TableError> {
+        self.file_writer.close().context(ParquetLibraryError {
+            message: String::from("Can't close file writer"),
+        })?;
+        Ok(())
+    }
+}
+
+impl<W: 'static> IOxParquetTableWriter<W>
+where
+    W: Write + Seek + TryClone,
+{
+    /// Writes the rows `rows` of a batch of packed data to the output
+    /// file in a single row group
+    fn write_row_group(
+        &mut self,
+        packers: &[Packers],
+        rows: Range<usize>,
+    ) -> Result<(), TableError> {
         // now write out the data
         let mut row_group_writer =
             self.file_writer
@@ -198,22 +270,22 @@ where
             // I think this match could be so much shorter but not sure how yet.
             match col_writer {
                 BoolColumnWriter(ref mut w) => {
-                    let p = packer.bool_packer();
-                    let n = w
-                        .write_batch(&p.some_values(), Some(&p.def_levels()), None)
-                        .context(ParquetLibraryError {
+                    let (values, def_levels) = column_chunk(packer.bool_packer(), rows.clone());
+                    let n = w.write_batch(&values, Some(&def_levels), None).context(
+                        ParquetLibraryError {
                             message: String::from("Can't write_batch with bool values"),
-                        })?;
+                        },
+                    )?;
                     debug!("Wrote {} rows of bool data", n);
                 }
                 Int32ColumnWriter(_) => unreachable!("ParquetWriter does not support INT32 data"),
                 Int64ColumnWriter(ref mut w) => {
-                    let p = packer.i64_packer();
-                    let n = w
-                        .write_batch(&p.some_values(), Some(&p.def_levels()), None)
-                        .context(ParquetLibraryError {
+                    let (values, def_levels) = column_chunk(packer.i64_packer(), rows.clone());
+                    let n = w.write_batch(&values, Some(&def_levels), None).context(
+                        ParquetLibraryError {
                             message: String::from("Can't write_batch with int64 values"),
-                        })?;
+                        },
+                    )?;
                     debug!("Wrote {} rows of int64 data", n);
                 }
                 Int96ColumnWriter(_) => unreachable!("ParquetWriter does not support INT96 data"),
@@ -221,21 +293,21 @@ where
                     unreachable!("ParquetWriter does not support FLOAT (32-bit float) data")
                 }
                 DoubleColumnWriter(ref mut w) => {
-                    let p = packer.f64_packer();
-                    let n = w
-                        .write_batch(&p.some_values(), Some(&p.def_levels()), None)
-                        .context(ParquetLibraryError {
+                    let (values, def_levels) = column_chunk(packer.f64_packer(), rows.clone());
+                    let n = w.write_batch(&values, Some(&def_levels), None).context(
+                        ParquetLibraryError {
                             message: String::from("Can't write_batch with f64 values"),
-                        })?;
+                        },
+                    )?;
                     debug!("Wrote {} rows of f64 data", n);
                 }
                 ByteArrayColumnWriter(ref mut w) => {
-                    let p = packer.str_packer();
-                    let n = w
-                        .write_batch(&p.some_values(), Some(&p.def_levels()), None)
-                        .context(ParquetLibraryError {
+                    let (values, def_levels) = column_chunk(packer.str_packer(), rows.clone());
+                    let n = w.write_batch(&values, Some(&def_levels), None).context(
+                        ParquetLibraryError {
                             message: String::from("Can't write_batch with byte array values"),
-                        })?;
+                        },
+                    )?;
                     debug!("Wrote {} rows of byte data", n);
                 }
                 FixedLenByteArrayColumnWriter(_) => {
@@ -257,14 +329,30 @@ where
             })?;
         Ok(())
     }
+}
 
-    /// Closes this writer, and finalizes the underlying parquet file
-    fn close(&mut self) -> Result<(), TableError> {
-        self.file_writer.close().context(ParquetLibraryError {
-            message: String::from("Can't close file writer"),
-        })?;
-        Ok(())
+/// Returns the non-null values and the definition levels of the rows
+/// `rows` of `packer`
+fn column_chunk<T>(packer: &Packer<T>, rows: Range<usize>) -> (Cow<'_, [T]>, Vec<i16>)
+where
+    T: Default + Clone + std::fmt::Debug,
+{
+    if rows.start == 0 && rows.end == packer.num_rows() {
+        return (packer.non_null_values(), packer.def_levels());
     }
+
+    let mut values = Vec::with_capacity(rows.len());
+    let mut def_levels = Vec::with_capacity(rows.len());
+    for value in packer.iter().skip(rows.start).take(rows.len()) {
+        match value {
+            Some(value) => {
+                values.push(value.clone());
+                def_levels.push(1);
+            }
+            None => def_levels.push(0),
+        }
+    }
+    (Cow::Owned(values), def_levels)
 }
 
 impl<W> fmt::Debug for IOxParquetTableWriter<W>
@@ -292,7 +380,7 @@ fn convert_to_parquet_schema(
             data_types::table_schema::DataType::Boolean => (PhysicalType::BOOLEAN, None),
             data_types::table_schema::DataType::Float => (PhysicalType::DOUBLE, None),
             data_types::table_schema::DataType::Integer => {
-                (PhysicalType::INT64, Some(LogicalType::UINT_64))
+                (PhysicalType::INT64, Some(LogicalType::INT_64))
             }
             data_types::table_schema::DataType::String => {
                 (PhysicalType::BYTE_ARRAY, Some(LogicalType::UTF8))
@@ -380,13 +468,37 @@ fn set_integer_encoding(
 fn create_writer_props(
     schema: &data_types::table_schema::Schema,
     compression_level: CompressionLevel,
+    metadata: Vec<(String, String)>,
+    settings: &ParquetSettings,
 ) -> Rc<WriterProperties> {
     let mut builder = WriterProperties::builder();
 
-    // TODO: Maybe tweak more of these settings for maximum performance.
+    if !metadata.is_empty() {
+        builder = builder.set_key_value_metadata(Some(
+            metadata
+                .into_iter()
+                .map(|(key, value)| KeyValue {
+                    key,
+                    value: Some(value),
+                })
+                .collect(),
+        ));
+    }
 
-    // start off with GZIP for maximum compression ratio (at expense of CPU performance...)
-    builder = builder.set_compression(Compression::GZIP);
+    // start off with GZIP for maximum compression ratio (at expense of CPU performance...),
+    // unless the database asks for another codec
+    let compression = match settings.compression {
+        Some(ParquetCompression::Uncompressed) => Compression::UNCOMPRESSED,
+        Some(ParquetCompression::Snappy) => Compression::SNAPPY,
+        Some(ParquetCompression::Gzip) | None => Compression::GZIP,
+        Some(ParquetCompression::Zstd) => Compression::ZSTD,
+        Some(ParquetCompression::Lz4) => Compression::LZ4,
+    };
+    builder = builder.set_compression(compression);
+
+    if let Some(size) = settings.dictionary_page_size {
+        builder = builder.set_dictionary_pagesize_limit(size);
+    }
 
     // Setup encoding as defined in
     // https://github.com/influxdata/influxdb_iox/blob/alamb/encoding_thoughts/docs/encoding_thoughts.md
@@ -451,13 +563,50 @@ fn create_writer_props(
     //
     // This is due to the fact that the underlying rust parquet
     // library does not support statistics generation at this time.
+    let statistics_enabled = settings.statistics != Some(ParquetStatistics::None);
     let props = builder
-        .set_statistics_enabled(true)
+        .set_statistics_enabled(statistics_enabled)
         .set_created_by("InfluxDB IOx".to_string())
         .build();
     Rc::new(props)
 }
 
+/// An in-memory file to write Parquet data to. The Parquet writer writes through clones of
+/// its sink, which, like clones of a `File`, share the position of the original.
+#[derive(Debug, Clone, Default)]
+pub struct MemWriter(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+impl MemWriter {
+    /// Takes the data written so far, leaving the file empty
+    pub fn take_data(&self) -> Vec<u8> {
+        let mut cursor = self.0.lock().expect("mutex poisoned");
+        cursor.set_position(0);
+        std::mem::take(cursor.get_mut())
+    }
+}
+
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().expect("mutex poisoned").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.lock().expect("mutex poisoned").seek(pos)
+    }
+}
+
+impl TryClone for MemWriter {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -500,7 +649,7 @@ mod tests {
             OPTIONAL BYTE_ARRAY tag1 (UTF8);
             OPTIONAL BYTE_ARRAY string_field (UTF8);
             OPTIONAL DOUBLE float_field;
-            OPTIONAL INT64 int_field (UINT_64);
+            OPTIONAL INT64 int_field (INT_64);
             OPTIONAL BOOLEAN bool_field;
             OPTIONAL INT64 time (TIMESTAMP_MICROS);
 }"#,
@@ -529,9 +678,31 @@ mod tests {
         do_test_create_writer_props(CompressionLevel::Compatibility);
     }
 
+    #[test]
+    fn test_create_writer_props_metadata() {
+        let schema = make_test_schema();
+        let metadata = vec![("key".to_string(), "value".to_string())];
+        let writer_props = create_writer_props(
+            &schema,
+            CompressionLevel::Maximum,
+            metadata,
+            &ParquetSettings::default(),
+        );
+
+        let key_values = writer_props.key_value_metadata().as_ref().unwrap();
+        assert_eq!(key_values.len(), 1);
+        assert_eq!(key_values[0].key, "key");
+        assert_eq!(key_values[0].value.as_deref(), Some("value"));
+    }
+
     fn do_test_create_writer_props(compression_level: CompressionLevel) {
         let schema = make_test_schema();
-        let writer_props = create_writer_props(&schema, compression_level);
+        let writer_props = create_writer_props(
+            &schema,
+            compression_level,
+            vec![],
+            &ParquetSettings::default(),
+        );
 
         let tag1_colpath = ColumnPath::from("tag1");
         assert_eq!(writer_props.encoding(&tag1_colpath), None);
@@ -636,6 +807,81 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_create_writer_props_settings() {
+        let schema = make_test_schema();
+        let settings = ParquetSettings {
+            row_group_size: Some(1000),
+            compression: Some(ParquetCompression::Zstd),
+            dictionary_page_size: Some(4096),
+            statistics: Some(ParquetStatistics::None),
+        };
+        let writer_props =
+            create_writer_props(&schema, CompressionLevel::Compatibility, vec![], &settings);
+
+        let tag1_colpath = ColumnPath::from("tag1");
+        assert_eq!(writer_props.compression(&tag1_colpath), Compression::ZSTD);
+        assert_eq!(writer_props.dictionary_pagesize_limit(), 4096);
+        assert_eq!(writer_props.statistics_enabled(&tag1_colpath), false);
+    }
+
+    #[test]
+    fn row_groups() {
+        use arrow_deps::parquet::file::{
+            reader::{FileReader, SerializedFileReader},
+            serialized_reader::SliceableCursor,
+        };
+
+        let schema = data_types::table_schema::SchemaBuilder::new("measurement_name")
+            .tag("tag1")
+            .field("float_field", data_types::table_schema::DataType::Float)
+            .build();
+        let packers = vec![
+            Packers::from(vec![
+                Some(b"a".to_vec()),
+                None,
+                Some(b"b".to_vec()),
+                None,
+                None,
+            ]),
+            Packers::from(vec![Some(1.0), Some(2.0), None, Some(4.0), Some(5.0)]),
+            Packers::from(vec![1_i64, 2, 3, 4, 5]),
+        ];
+
+        let row_groups = |settings: ParquetSettings| {
+            let buffer = MemWriter::default();
+            let mut writer = IOxParquetTableWriter::new_with_settings(
+                &schema,
+                CompressionLevel::Compatibility,
+                buffer.clone(),
+                vec![],
+                &settings,
+            )
+            .unwrap();
+            writer.write_batch(&packers).unwrap();
+            writer.close().unwrap();
+
+            let reader =
+                SerializedFileReader::new(SliceableCursor::new(buffer.take_data())).unwrap();
+            let metadata = reader.metadata();
+            assert_eq!(metadata.file_metadata().num_rows(), 5);
+            metadata
+                .row_groups()
+                .iter()
+                .map(|group| group.num_rows())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(row_groups(ParquetSettings::default()), vec![5]);
+        assert_eq!(
+            row_groups(ParquetSettings {
+                row_group_size: Some(2),
+                ..Default::default()
+            }),
+            vec![2, 2, 1]
+        );
+    }
+
     #[test]
     fn compression_level() {
         assert_eq!(
@@ -0,0 +1,374 @@
+//! This module contains the translation of metrics in the Prometheus text exposition format,
+//! as scraped from or pushed by instrumented services, into line protocol.
+//!
+//! Each sample becomes a line of the table named after its metric, with its labels as tags
+//! and its value as the `value` field. The `_bucket`, `_sum` and `_count` samples of a
+//! histogram declared by a `# TYPE <name> histogram` comment are gathered into one line of
+//! the table `<name>` per label set, with the fields `count`, `sum` and `buckets`, as
+//! described in `data_types::histogram`.
+use data_types::histogram::{Histogram, HistogramLayout};
+use snafu::{OptionExt, Snafu};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Line {}: invalid sample '{}': {}", line_number, line, reason))]
+    InvalidSample {
+        line_number: usize,
+        line: String,
+        reason: &'static str,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A histogram being gathered from its samples
+#[derive(Debug, Default)]
+struct PendingHistogram {
+    buckets: Vec<(f64, f64)>,
+    sum: Option<f64>,
+    count: Option<f64>,
+}
+
+/// The output of the translation, a line per sample or histogram, in the order they were
+/// first seen
+enum Output {
+    Sample(String),
+    Histogram(usize),
+}
+
+/// Translates the metrics of `text` into line protocol, writing histograms in `layout`.
+/// Samples with a timestamp, in milliseconds, get it in nanoseconds; the others get the time
+/// of the write. Samples whose values are not finite are skipped, as line protocol can't
+/// represent them.
+pub fn to_line_protocol(text: &str, layout: HistogramLayout) -> Result<String> {
+    let mut histogram_names = vec![];
+    let mut outputs = vec![];
+    // the histograms by series and timestamp
+    let mut histograms: Vec<(String, Option<i64>, PendingHistogram)> = vec![];
+    let mut histogram_ids: HashMap<(String, Option<i64>), usize> = HashMap::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('#') {
+            let mut words = line[1..].split_whitespace();
+            if let (Some("TYPE"), Some(name), Some("histogram")) =
+                (words.next(), words.next(), words.next())
+            {
+                histogram_names.push(name.to_string());
+            }
+            continue;
+        }
+
+        let sample = parse_sample(line).map_err(|reason| Error::InvalidSample {
+            line_number: i + 1,
+            line: line.to_string(),
+            reason,
+        })?;
+
+        let histogram = histogram_names.iter().find_map(|name| {
+            if !sample.name.starts_with(name.as_str()) {
+                return None;
+            }
+            let suffix = &sample.name[name.len()..];
+            match suffix {
+                "_bucket" | "_sum" | "_count" => Some((name, suffix)),
+                _ => None,
+            }
+        });
+
+        match histogram {
+            Some((name, suffix)) => {
+                let mut labels = sample.labels.clone();
+                let le = labels.remove("le");
+
+                let mut series = String::new();
+                write_series(&mut series, name, &labels);
+                let key = (series, sample.timestamp);
+
+                let id = match histogram_ids.get(&key) {
+                    Some(id) => *id,
+                    None => {
+                        let id = histograms.len();
+                        histogram_ids.insert(key.clone(), id);
+                        histograms.push((key.0, key.1, PendingHistogram::default()));
+                        outputs.push(Output::Histogram(id));
+                        id
+                    }
+                };
+                let histogram = &mut histograms[id].2;
+                match suffix {
+                    "_bucket" => {
+                        let le = le.context(InvalidSample {
+                            line_number: i + 1,
+                            line,
+                            reason: "histogram bucket without an le label",
+                        })?;
+                        let bound = match le.as_str() {
+                            "+Inf" => Some(f64::INFINITY),
+                            le => le.parse().ok(),
+                        };
+                        let bound = bound.context(InvalidSample {
+                            line_number: i + 1,
+                            line,
+                            reason: "invalid le label",
+                        })?;
+                        histogram.buckets.push((bound, sample.value));
+                    }
+                    "_sum" => histogram.sum = Some(sample.value),
+                    _ => histogram.count = Some(sample.value),
+                }
+            }
+            None => {
+                if !sample.value.is_finite() {
+                    continue;
+                }
+                let mut output = String::new();
+                write_series(&mut output, sample.name, &sample.labels);
+                write!(output, " value={}", sample.value).expect("writing to a string");
+                if let Some(timestamp) = sample.timestamp {
+                    write!(output, " {}", timestamp).expect("writing to a string");
+                }
+                outputs.push(Output::Sample(output));
+            }
+        }
+    }
+
+    let mut lines = String::new();
+    for output in outputs {
+        match output {
+            Output::Sample(line) => lines.push_str(&line),
+            Output::Histogram(id) => {
+                let (series, timestamp, histogram) = &histograms[id];
+
+                let mut fields = vec![];
+                if let Some(count) = histogram.count.filter(|c| c.is_finite()) {
+                    fields.push(format!("count={}", count));
+                }
+                if let Some(sum) = histogram.sum.filter(|s| s.is_finite()) {
+                    fields.push(format!("sum={}", sum));
+                }
+                if !histogram.buckets.is_empty() {
+                    let buckets = Histogram::new(histogram.buckets.clone()).with_layout(layout);
+                    fields.push(format!("buckets=\"{}\"", buckets));
+                }
+                if fields.is_empty() {
+                    continue;
+                }
+
+                lines.push_str(series);
+                lines.push(' ');
+                lines.push_str(&fields.join(","));
+                if let Some(timestamp) = timestamp {
+                    write!(lines, " {}", timestamp).expect("writing to a string");
+                }
+            }
+        }
+        lines.push('\n');
+    }
+
+    Ok(lines)
+}
+
+/// A sample of the text format, with its timestamp in nanoseconds
+#[derive(Debug, PartialEq)]
+struct Sample<'a> {
+    name: &'a str,
+    labels: BTreeMap<String, String>,
+    value: f64,
+    timestamp: Option<i64>,
+}
+
+/// Parses `name{label="value",...} value [timestamp]`
+fn parse_sample(line: &str) -> Result<Sample<'_>, &'static str> {
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .ok_or("missing value")?;
+    let name = &line[..name_end];
+    if name.is_empty() {
+        return Err("missing metric name");
+    }
+
+    let mut labels = BTreeMap::new();
+    let mut rest = &line[name_end..];
+    if rest.starts_with('{') {
+        rest = &rest[1..];
+        loop {
+            rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+            if rest.starts_with('}') {
+                rest = &rest[1..];
+                break;
+            }
+            let eq = rest.find('=').ok_or("unterminated labels")?;
+            let label = rest[..eq].trim();
+            rest = rest[eq + 1..].trim_start();
+            if !rest.starts_with('"') {
+                return Err("label values must be quoted");
+            }
+
+            let mut value = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next().ok_or("unterminated label value")? {
+                    (i, '"') => break i,
+                    (_, '\\') => match chars.next().ok_or("unterminated label value")?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    (_, c) => value.push(c),
+                }
+            };
+            rest = &rest[end + 2..];
+
+            // an empty label value is the same as no label
+            if !value.is_empty() {
+                labels.insert(label.to_string(), value);
+            }
+        }
+    }
+
+    let mut words = rest.split_whitespace();
+    let value = match words.next().ok_or("missing value")? {
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        value => value.parse().map_err(|_| "invalid value")?,
+    };
+    let timestamp = match words.next() {
+        Some(millis) => Some(
+            millis
+                .parse::<i64>()
+                .ok()
+                .and_then(|millis| millis.checked_mul(1_000_000))
+                .ok_or("invalid timestamp")?,
+        ),
+        None => None,
+    };
+
+    Ok(Sample {
+        name,
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+/// Writes the measurement and tags of a line
+pub(crate) fn write_series(out: &mut String, measurement: &str, labels: &BTreeMap<String, String>) {
+    out.push_str(&escape(measurement, &[',', ' ']));
+    for (key, value) in labels {
+        write!(
+            out,
+            ",{}={}",
+            escape(key, &[',', '=', ' ']),
+            escape(value, &[',', '=', ' '])
+        )
+        .expect("writing to a string");
+    }
+}
+
+pub(crate) fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METRICS: &str = r#"
+# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="400",path=""} 3 1395066363000
+
+# A histogram, which has a pretty complex representation in the text format:
+# HELP http_request_duration_seconds A histogram of the request duration.
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 0
+http_request_duration_seconds_bucket{le="0.1"} 0
+http_request_duration_seconds_bucket{le="0.2"} 0
+http_request_duration_seconds_bucket{le="0.5"} 129389
+http_request_duration_seconds_bucket{le="1"} 133988
+http_request_duration_seconds_bucket{le="+Inf"} 144320
+http_request_duration_seconds_sum 53423
+http_request_duration_seconds_count 144320
+
+go_gc_duration_seconds{quantile="0.5", host="a b"} NaN
+temperature{room="kitchen \"east\""} 21.5
+"#;
+
+    #[test]
+    fn translate() {
+        let lines = to_line_protocol(METRICS, HistogramLayout::Dense).unwrap();
+        assert_eq!(
+            lines,
+            "http_requests_total,code=200,method=post value=1027 1395066363000000000\n\
+             http_requests_total,code=400,method=post value=3 1395066363000000000\n\
+             http_request_duration_seconds count=144320,sum=53423,\
+             buckets=\"0.05:0,0.1:0,0.2:0,0.5:129389,1:133988,+Inf:144320\"\n\
+             temperature,room=kitchen\\ \"east\" value=21.5\n"
+        );
+
+        let lines = to_line_protocol(METRICS, HistogramLayout::Sparse).unwrap();
+        assert!(lines.contains("buckets=\"0.05:0,0.2:0,0.5:129389,1:133988,+Inf:144320\""));
+
+        // the lines are valid line protocol
+        let parsed = influxdb_line_protocol::parse_lines(&lines)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(parsed.len(), 4);
+    }
+
+    #[test]
+    fn histograms_per_label_set() {
+        let lines = to_line_protocol(
+            "# TYPE latency histogram\n\
+             latency_bucket{path=\"/a\",le=\"1\"} 1 1000\n\
+             latency_bucket{path=\"/b\",le=\"1\"} 2 1000\n\
+             latency_bucket{path=\"/a\",le=\"+Inf\"} 3 1000\n\
+             latency_bucket{path=\"/b\",le=\"+Inf\"} 4 1000\n",
+            HistogramLayout::Dense,
+        )
+        .unwrap();
+        assert_eq!(
+            lines,
+            "latency,path=/a buckets=\"1:1,+Inf:3\" 1000000000\n\
+             latency,path=/b buckets=\"1:2,+Inf:4\" 1000000000\n"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = to_line_protocol("cpu{host=a} 1", HistogramLayout::Dense).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Line 1: invalid sample 'cpu{host=a} 1': label values must be quoted"
+        );
+
+        for (text, reason) in &[
+            ("cpu", "missing value"),
+            ("cpu x", "invalid value"),
+            ("cpu 1 yesterday", "invalid timestamp"),
+            ("cpu{host=\"a} 1", "unterminated label value"),
+            (
+                "# TYPE latency histogram\nlatency_bucket 1",
+                "histogram bucket without an le label",
+            ),
+        ] {
+            let err = to_line_protocol(text, HistogramLayout::Dense).unwrap_err();
+            assert!(err.to_string().ends_with(reason), "{}: {}", text, err);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! This module contains the code to convert the shards of an InfluxDB 1.x data directory into
+//! the rows of their measurements, to import into a database like the rows of other bulk
+//! imports.
+//!
+//! A 1.x data directory holds the TSM files of each shard at
+//! `<data dir>/<database>/<retention policy>/<shard id>/*.tsm`. The measurement schemas are
+//! reconstructed from the series keys in the TSM indexes, so the TSI `index` directories,
+//! which only hold the same series again, are not read.
+use crate::{import::ImportedTable, TSMFileConverter};
+use data_types::table_schema::Schema;
+use influxdb_tsm::KeyFormat;
+use packers::{Error as TableError, IOxTableWriter, IOxTableWriterSource, Packers};
+use snafu::{ResultExt, Snafu};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::debug;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error reading directory {}: {}", path.display(), source))]
+    ReadingDirectory { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Error opening TSM file {}: {}", path.display(), source))]
+    OpeningFile { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Error converting shard {}: {}", shard, source))]
+    Converting { shard: u64, source: crate::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The number of rows of a measurement converted at a time, so that the memory used to
+/// convert a shard doesn't grow with the size of its measurements
+const BATCH_ROWS: usize = 1_000_000;
+
+/// The TSM files of one shard of a 1.x retention policy
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shard {
+    pub database: String,
+    pub retention_policy: String,
+    pub id: u64,
+    /// The TSM files of the shard, oldest generation first
+    pub tsm_files: Vec<PathBuf>,
+    /// The total size of the TSM files, in bytes
+    pub size: u64,
+}
+
+/// Returns the shards with TSM files in the 1.x data directory `data_dir`, sorted by database,
+/// retention policy and shard id.
+pub fn find_shards(data_dir: impl AsRef<Path>) -> Result<Vec<Shard>> {
+    let mut shards = Vec::new();
+
+    for (database, database_path) in sub_directories(data_dir.as_ref())? {
+        for (retention_policy, rp_path) in sub_directories(&database_path)? {
+            for (shard_dir, shard_path) in sub_directories(&rp_path)? {
+                // skip anything that isn't a shard, such as the series file of the database
+                let id = match shard_dir.parse() {
+                    Ok(id) => id,
+                    Err(_) => continue,
+                };
+
+                let mut tsm_files = Vec::new();
+                let mut size = 0;
+                for entry in read_dir(&shard_path)? {
+                    let path = entry.path();
+                    if path.extension().map_or(false, |ext| ext == "tsm") {
+                        size += entry
+                            .metadata()
+                            .context(ReadingDirectory { path: &shard_path })?
+                            .len();
+                        tsm_files.push(path);
+                    }
+                }
+
+                if tsm_files.is_empty() {
+                    debug!("Skipping shard {} without TSM files", shard_path.display());
+                    continue;
+                }
+
+                // TSM file names start with their generation, so that data in later files
+                // replaces data in earlier ones when they are merged.
+                tsm_files.sort();
+                shards.push(Shard {
+                    database: database.clone(),
+                    retention_policy: retention_policy.clone(),
+                    id,
+                    tsm_files,
+                    size,
+                });
+            }
+        }
+    }
+
+    shards.sort_by(|a, b| {
+        (&a.database, &a.retention_policy, a.id).cmp(&(&b.database, &b.retention_policy, b.id))
+    });
+    Ok(shards)
+}
+
+fn read_dir(path: &Path) -> Result<Vec<fs::DirEntry>> {
+    fs::read_dir(path)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .context(ReadingDirectory { path })
+}
+
+/// Returns the names and paths of the directories in `path`, other than the ones 1.x uses for
+/// its series file and TSI indexes
+fn sub_directories(path: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut dirs = Vec::new();
+    for entry in read_dir(path)? {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if path.is_dir() && name != "_series" && name != "index" {
+            dirs.push((name, path));
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Converts the TSM files of `shard`, passing the rows of each measurement to `sink` as they
+/// are converted, in batches of at most `BATCH_ROWS` rows, measurement after measurement in
+/// the order of their names. The conversion stops at the first error `sink` returns.
+pub fn convert_shard<F>(shard: &Shard, sink: F) -> Result<()>
+where
+    F: FnMut(ImportedTable) -> Result<(), TableError> + Send + 'static,
+{
+    let mut index_readers = Vec::with_capacity(shard.tsm_files.len());
+    let mut block_readers = Vec::with_capacity(shard.tsm_files.len());
+    for path in &shard.tsm_files {
+        let index_file = File::open(path).context(OpeningFile { path })?;
+        let len = index_file.metadata().context(OpeningFile { path })?.len();
+        let block_file = File::open(path).context(OpeningFile { path })?;
+
+        index_readers.push((BufReader::new(index_file), len as usize));
+        block_readers.push(BufReader::new(block_file));
+    }
+
+    let writer_source = BatchWriterSource {
+        sink: Arc::new(Mutex::new(sink)),
+    };
+
+    TSMFileConverter::new(Box::new(writer_source))
+        .with_key_format(KeyFormat::V1)
+        .with_batch_rows(BATCH_ROWS)
+        .convert(index_readers, block_readers)
+        .context(Converting { shard: shard.id })?;
+
+    Ok(())
+}
+
+type Sink = Arc<Mutex<dyn FnMut(ImportedTable) -> Result<(), TableError> + Send>>;
+
+/// Creates writers that pass each batch of a measurement to the sink
+struct BatchWriterSource {
+    sink: Sink,
+}
+
+impl IOxTableWriterSource for BatchWriterSource {
+    fn next_writer(&mut self, schema: &Schema) -> Result<Box<dyn IOxTableWriter>, TableError> {
+        Ok(Box::new(BatchWriter {
+            schema: schema.clone(),
+            sink: Arc::clone(&self.sink),
+        }))
+    }
+}
+
+/// Passes the batches of a measurement to the sink, with their time range
+struct BatchWriter {
+    schema: Schema,
+    sink: Sink,
+}
+
+impl IOxTableWriter for BatchWriter {
+    fn write_batch(&mut self, packers: &[Packers]) -> Result<(), TableError> {
+        let rows = packers.first().map_or(0, |packer| packer.num_rows());
+        if rows == 0 {
+            return Ok(());
+        }
+
+        let mut table = ImportedTable {
+            schema: self.schema.clone(),
+            columns: packers.to_vec(),
+            rows,
+            min_time: None,
+            max_time: None,
+        };
+        // the time is the last column of the schemas of TSM files, as `ImportedTable` expects
+        let times = (0..rows).map(|row| table.time(row));
+        table.min_time = times.clone().min();
+        table.max_time = times.max();
+
+        (&mut *self.sink.lock().expect("mutex poisoned"))(table)
+    }
+
+    fn close(&mut self) -> Result<(), TableError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb_tsm::encoders::{integer, timestamp};
+
+    /// Returns a TSM file holding an integer field for each of `series`
+    fn tsm_file(series: &[(&str, &[i64], &[i64])]) -> Vec<u8> {
+        // header: magic number and version
+        let mut file = vec![0x16, 0xd1, 0x16, 0xd1, 1];
+
+        let mut index = Vec::new();
+        for &(key, times, values) in series {
+            let offset = file.len() as u64;
+
+            let mut ts = Vec::new();
+            timestamp::encode(times, &mut ts).unwrap();
+            let mut vs = Vec::new();
+            integer::encode(values, &mut vs).unwrap();
+
+            // checksum (not checked), block type, timestamps and values
+            file.extend_from_slice(&[0, 0, 0, 0, 1]);
+            let mut len = ts.len() as u64;
+            while len >= 0x80 {
+                file.push(len as u8 | 0x80);
+                len >>= 7;
+            }
+            file.push(len as u8);
+            file.extend_from_slice(&ts);
+            file.extend_from_slice(&vs);
+
+            index.extend_from_slice(&(key.len() as u16).to_be_bytes());
+            index.extend_from_slice(key.as_bytes());
+            index.push(1);
+            index.extend_from_slice(&1u16.to_be_bytes());
+            index.extend_from_slice(&times[0].to_be_bytes());
+            index.extend_from_slice(&times[times.len() - 1].to_be_bytes());
+            index.extend_from_slice(&offset.to_be_bytes());
+            index.extend_from_slice(&((file.len() as u64 - offset) as u32).to_be_bytes());
+        }
+
+        let index_offset = file.len() as u64;
+        file.extend_from_slice(&index);
+        file.extend_from_slice(&index_offset.to_be_bytes());
+        file
+    }
+
+    #[test]
+    fn find_and_convert_shards() {
+        let dir = test_helpers::tmp_dir().unwrap();
+        let shard_dir = dir.path().join("telegraf").join("autogen").join("1");
+        fs::create_dir_all(shard_dir.join("index")).unwrap();
+        fs::create_dir_all(dir.path().join("telegraf").join("_series")).unwrap();
+        fs::create_dir_all(dir.path().join("telegraf").join("weekly").join("2")).unwrap();
+
+        let file = tsm_file(&[
+            ("cpu,host=a#!~#usage", &[10, 20], &[1, 2]),
+            ("cpu,host=b#!~#usage", &[15], &[3]),
+            ("mem,host=a#!~#free", &[5], &[100]),
+        ]);
+        fs::write(shard_dir.join("000000001-000000001.tsm"), &file).unwrap();
+        fs::write(shard_dir.join("fields.idx"), b"").unwrap();
+
+        let shards = find_shards(dir.path()).unwrap();
+        assert_eq!(
+            shards,
+            vec![Shard {
+                database: "telegraf".to_string(),
+                retention_policy: "autogen".to_string(),
+                id: 1,
+                tsm_files: vec![shard_dir.join("000000001-000000001.tsm")],
+                size: file.len() as u64,
+            }]
+        );
+
+        let tables = Arc::new(Mutex::new(vec![]));
+        let sink = Arc::clone(&tables);
+        convert_shard(&shards[0], move |table| {
+            sink.lock().unwrap().push(table);
+            Ok(())
+        })
+        .unwrap();
+        let tables = tables.lock().unwrap();
+        let summary: Vec<_> = tables
+            .iter()
+            .map(|t| (t.schema.measurement(), t.rows, t.min_time, t.max_time))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("cpu", 3, Some(10), Some(20)), ("mem", 1, Some(5), Some(5))]
+        );
+        assert_eq!(tables[0].value(2, "host"), Some("b".to_string()));
+        assert_eq!(tables[0].value(2, "usage"), Some("3i".to_string()));
+
+        // the errors of the sink stop the conversion
+        let err = convert_shard(&shards[0], |_| {
+            Err(TableError::from_other(io::Error::new(
+                io::ErrorKind::Other,
+                "sink closed",
+            )))
+        })
+        .unwrap_err();
+        assert!(matches!(err, Error::Converting { shard: 1, .. }), "{}", err);
+    }
+
+    #[test]
+    fn missing_data_directory() {
+        let err = find_shards("/does/not/exist").unwrap_err();
+        assert!(matches!(err, Error::ReadingDirectory { .. }), "{}", err);
+    }
+}
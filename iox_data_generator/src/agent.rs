@@ -0,0 +1,210 @@
+//! Agents generate the points of a workload, one sample at a time.
+
+use influxdb2_client::DataPoint;
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{field::FieldValues, specification::AgentSpec, tag::TagValues};
+
+/// Generates the samples of an agent, from a start time up to an end time
+#[derive(Debug)]
+pub struct Agent {
+    name: String,
+    name_tag_key: Option<String>,
+    measurements: Vec<MeasurementGenerator>,
+    rng: StdRng,
+    /// The time between samples, in nanoseconds
+    interval: i64,
+    /// The time of the next sample, in nanoseconds since the epoch
+    current_time: i64,
+    end_time: i64,
+}
+
+#[derive(Debug)]
+struct MeasurementGenerator {
+    name: String,
+    series_per_sample: usize,
+    tags: Vec<TagValues>,
+    fields: Vec<FieldValues>,
+}
+
+impl Agent {
+    /// Creates agent number `agent_id` of `spec`, whose random values are generated from
+    /// `seed`. It generates samples from `start_time`, inclusive, to `end_time`, exclusive.
+    pub fn new(
+        spec: &AgentSpec,
+        agent_id: usize,
+        seed: u64,
+        start_time: i64,
+        end_time: i64,
+    ) -> Self {
+        let measurements = spec
+            .measurements
+            .iter()
+            .flat_map(|measurement| {
+                let names: Vec<_> = if measurement.count == 1 {
+                    vec![measurement.name.clone()]
+                } else {
+                    (1..=measurement.count)
+                        .map(|n| format!("{}_{}", measurement.name, n))
+                        .collect()
+                };
+
+                names.into_iter().map(move |name| MeasurementGenerator {
+                    name,
+                    series_per_sample: measurement.series_per_sample,
+                    tags: measurement.tags.iter().map(TagValues::new).collect(),
+                    fields: measurement
+                        .fields
+                        .iter()
+                        .map(|field| FieldValues::new(field, start_time))
+                        .collect(),
+                })
+            })
+            .collect();
+
+        Self {
+            name: format!("{}-{}", spec.name, agent_id),
+            name_tag_key: spec.name_tag_key.clone(),
+            measurements,
+            rng: StdRng::seed_from_u64(seed),
+            interval: spec.sampling_interval.as_nanos() as i64,
+            current_time: start_time,
+            end_time,
+        }
+    }
+
+    /// The name of the agent
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Generates the points of the next sample and returns them with their time, or `None`
+    /// once the end time is reached
+    pub fn next_sample(&mut self) -> Option<(i64, Vec<DataPoint>)> {
+        if self.current_time >= self.end_time {
+            return None;
+        }
+        let time = self.current_time;
+        self.current_time += self.interval;
+
+        let Self {
+            name,
+            name_tag_key,
+            measurements,
+            rng,
+            ..
+        } = self;
+
+        let mut points = vec![];
+        for measurement in measurements {
+            for _ in 0..measurement.series_per_sample {
+                let mut point = DataPoint::builder(measurement.name.as_str());
+                if let Some(key) = name_tag_key {
+                    point = point.tag(key.as_str(), name.as_str());
+                }
+                for tag in &mut measurement.tags {
+                    point = point.tag(tag.name(), tag.sample(rng));
+                }
+                let mut has_fields = false;
+                for field in &mut measurement.fields {
+                    if let Some(value) = field.sample(time, rng) {
+                        point = point.field(field.name(), value);
+                        has_fields = true;
+                    }
+                }
+                // every field of the point can be in a gap
+                if !has_fields {
+                    continue;
+                }
+
+                points.push(
+                    point
+                        .timestamp(time)
+                        .build()
+                        .expect("points with fields are valid"),
+                );
+            }
+        }
+
+        Some((time, points))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{line_protocol, specification::DataSpec};
+
+    const SPEC: &str = r#"
+name = "fleet"
+
+[[agents]]
+name = "host"
+sampling_interval = "1s"
+name_tag_key = "host"
+
+[[agents.measurements]]
+name = "cpu"
+count = 2
+series_per_sample = 2
+tags = [{ name = "core", cardinality = 4, distribution = "sequential" }]
+
+[[agents.measurements.fields]]
+name = "usage"
+type = "integer"
+min = 3
+max = 3
+
+[[agents.measurements.fields]]
+name = "valid"
+type = "bool"
+"#;
+
+    fn agent(seed: u64) -> Agent {
+        let spec = DataSpec::from_toml(SPEC).unwrap();
+        Agent::new(&spec.agents[0], 7, seed, 1_000_000_000, 3_500_000_000)
+    }
+
+    #[test]
+    fn samples() {
+        let mut agent = agent(1);
+        assert_eq!(agent.name(), "host-7");
+
+        let (time, points) = agent.next_sample().unwrap();
+        assert_eq!(time, 1_000_000_000);
+        let lines = String::from_utf8(line_protocol(&points)).unwrap();
+        let lines: Vec<_> = lines
+            .lines()
+            .map(|line| line.split(",valid=").next().unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "cpu_1,core=core-0,host=host-7 usage=3i",
+                "cpu_1,core=core-1,host=host-7 usage=3i",
+                "cpu_2,core=core-0,host=host-7 usage=3i",
+                "cpu_2,core=core-1,host=host-7 usage=3i",
+            ]
+        );
+
+        let times: Vec<_> = std::iter::from_fn(|| agent.next_sample())
+            .map(|(time, _)| time)
+            .collect();
+        assert_eq!(times, vec![2_000_000_000, 3_000_000_000]);
+    }
+
+    #[test]
+    fn seeded_agents_generate_the_same_data() {
+        let generate = |seed| {
+            let mut agent = agent(seed);
+            let mut data = vec![];
+            while let Some((_, points)) = agent.next_sample() {
+                data.extend(line_protocol(&points));
+            }
+            data
+        };
+
+        assert_eq!(generate(1), generate(1));
+        assert_ne!(generate(1), generate(2));
+    }
+}
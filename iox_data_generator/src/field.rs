@@ -0,0 +1,244 @@
+//! Generates the values of fields, at random or according to their profile.
+
+use std::f64::consts::PI;
+
+use influxdb2_client::FieldValue;
+use rand::{
+    distributions::{Alphanumeric, Uniform},
+    seq::SliceRandom,
+    Rng,
+};
+
+use crate::specification::{FieldSpec, FieldValueSpec, ProfileSpec};
+
+/// Generates the values of a field
+#[derive(Debug, Clone)]
+pub struct FieldValues {
+    name: String,
+    value: FieldValueSpec,
+    profile: Option<Profile>,
+}
+
+/// The state of the profile of a field
+#[derive(Debug, Clone)]
+struct Profile {
+    spec: ProfileSpec,
+    /// The time the trend starts from, in nanoseconds since the epoch
+    start_time: i64,
+    /// The end of the ongoing spike, if any
+    spike_until: i64,
+    /// The end of the ongoing gap, if any
+    gap_until: i64,
+}
+
+impl FieldValues {
+    /// Creates a generator of the values of the field specified by `spec`, in samples from
+    /// `start_time`
+    pub fn new(spec: &FieldSpec, start_time: i64) -> Self {
+        Self {
+            name: spec.name.clone(),
+            value: spec.value.clone(),
+            profile: spec.profile.clone().map(|spec| Profile {
+                spec,
+                start_time,
+                spike_until: i64::MIN,
+                gap_until: i64::MIN,
+            }),
+        }
+    }
+
+    /// The key of the field
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Generates the value of the field in the sample at `time`, in nanoseconds since the
+    /// epoch, or `None` if the field is in a gap
+    pub fn sample(&mut self, time: i64, rng: &mut impl Rng) -> Option<FieldValue> {
+        let profile = match &mut self.profile {
+            Some(profile) => profile,
+            None => return Some(random_value(&self.value, rng)),
+        };
+
+        let value = profile.sample(time, rng)?;
+        Some(match self.value {
+            FieldValueSpec::Float { min, max } => value.max(min).min(max).into(),
+            FieldValueSpec::Integer { min, max } => {
+                (value.round().max(min as f64).min(max as f64) as i64).into()
+            }
+            _ => unreachable!("only numeric fields are validated to have profiles"),
+        })
+    }
+}
+
+impl Profile {
+    fn sample(&mut self, time: i64, rng: &mut impl Rng) -> Option<f64> {
+        let spec = &self.spec;
+
+        if time < self.gap_until {
+            return None;
+        }
+        if let Some(gaps) = &spec.gaps {
+            if rng.gen_bool(gaps.probability) {
+                self.gap_until = time.saturating_add(gaps.duration.as_nanos() as i64);
+                return None;
+            }
+        }
+
+        let hours = (time - self.start_time) as f64 / 3_600_000_000_000.0;
+        let mut value = spec.baseline + spec.trend * hours;
+
+        for cycle in &spec.seasonality {
+            let period = cycle.period.as_nanos() as i64;
+            let offset = (time - cycle.phase.as_nanos() as i64).rem_euclid(period);
+            value += cycle.amplitude * (2.0 * PI * offset as f64 / period as f64).sin();
+        }
+
+        if spec.noise > 0.0 {
+            value += rng.gen_range(-spec.noise, spec.noise);
+        }
+
+        if let Some(spikes) = &spec.spikes {
+            if time >= self.spike_until && rng.gen_bool(spikes.probability) {
+                self.spike_until = time.saturating_add(spikes.duration.as_nanos() as i64);
+            }
+            if time < self.spike_until {
+                value += spikes.magnitude;
+            }
+        }
+
+        Some(value)
+    }
+}
+
+/// Picks a value of a field at random
+fn random_value(spec: &FieldValueSpec, rng: &mut impl Rng) -> FieldValue {
+    match spec {
+        FieldValueSpec::Float { min, max } => rng.sample(Uniform::new_inclusive(min, max)).into(),
+        FieldValueSpec::Integer { min, max } => rng.sample(Uniform::new_inclusive(min, max)).into(),
+        FieldValueSpec::Bool => rng.gen::<bool>().into(),
+        FieldValueSpec::String { values, length } => match values.choose(rng) {
+            Some(value) => value.as_str().into(),
+            None => rng
+                .sample_iter(&Alphanumeric)
+                .take(*length)
+                .collect::<String>()
+                .into(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::specification::{GapSpec, SeasonalitySpec, SpikeSpec};
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::time::Duration;
+
+    const MINUTE: i64 = 60_000_000_000;
+    const HOUR: i64 = 60 * MINUTE;
+
+    fn profile() -> ProfileSpec {
+        ProfileSpec {
+            baseline: 50.0,
+            trend: 0.0,
+            noise: 0.0,
+            seasonality: vec![],
+            spikes: None,
+            gaps: None,
+        }
+    }
+
+    fn field(profile: ProfileSpec) -> FieldValues {
+        FieldValues::new(
+            &FieldSpec {
+                name: "usage".to_string(),
+                value: FieldValueSpec::Float {
+                    min: 0.0,
+                    max: 100.0,
+                },
+                profile: Some(profile),
+            },
+            0,
+        )
+    }
+
+    fn float(value: Option<FieldValue>) -> Option<f64> {
+        value.map(|value| match value {
+            FieldValue::F64(value) => value,
+            other => panic!("not a float: {:?}", other),
+        })
+    }
+
+    #[test]
+    fn trend_and_seasonality() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut values = field(ProfileSpec {
+            trend: 2.0,
+            seasonality: vec![SeasonalitySpec {
+                period: Duration::from_secs(24 * 3600),
+                amplitude: 10.0,
+                phase: Duration::from_secs(0),
+            }],
+            ..profile()
+        });
+
+        let mut sample = |time| float(values.sample(time, &mut rng)).unwrap();
+        assert!((sample(0) - 50.0).abs() < 1e-9);
+        // the daily cycle peaks after 6 hours, and is back to the baseline after 12
+        assert!((sample(6 * HOUR) - 72.0).abs() < 1e-9);
+        assert!((sample(12 * HOUR) - 74.0).abs() < 1e-9);
+        assert!((sample(18 * HOUR) - 76.0).abs() < 1e-9);
+        // values are clamped to the bounds of the field
+        assert!((sample(40 * HOUR) - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn spikes_and_gaps() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut values = field(ProfileSpec {
+            spikes: Some(SpikeSpec {
+                probability: 1.0,
+                magnitude: -30.0,
+                duration: Duration::from_secs(120),
+            }),
+            ..profile()
+        });
+        let sampled: Vec<_> = (0..3)
+            .map(|minute| float(values.sample(minute * MINUTE, &mut rng)))
+            .collect();
+        assert_eq!(sampled, vec![Some(20.0), Some(20.0), Some(20.0)]);
+
+        let mut values = field(ProfileSpec {
+            gaps: Some(GapSpec {
+                probability: 0.1,
+                duration: Duration::from_secs(600),
+            }),
+            ..profile()
+        });
+        let sampled: Vec<_> = (0..1000)
+            .map(|minute| float(values.sample(minute * MINUTE, &mut rng)))
+            .collect();
+        let missing = sampled.iter().filter(|value| value.is_none()).count();
+        // a gap starts after about 10 samples, and lasts 10 samples
+        assert!(missing > 300 && missing < 700, "{}", missing);
+        assert!(sampled.iter().flatten().all(|value| *value == 50.0));
+    }
+
+    #[test]
+    fn integers_are_rounded() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut values = FieldValues::new(
+            &FieldSpec {
+                name: "count".to_string(),
+                value: FieldValueSpec::Integer { min: 0, max: 10 },
+                profile: Some(ProfileSpec {
+                    baseline: 4.6,
+                    ..profile()
+                }),
+            },
+            0,
+        );
+        assert_eq!(values.sample(0, &mut rng), Some(FieldValue::I64(5)));
+    }
+}
@@ -0,0 +1,222 @@
+#![deny(rust_2018_idioms)]
+#![warn(
+    missing_copy_implementations,
+    missing_debug_implementations,
+    missing_docs,
+    clippy::explicit_iter_loop,
+    clippy::use_self
+)]
+
+//! # iox_data_generator
+//!
+//! Generates synthetic workloads and drives them against the write API of a server, to test
+//! how much it can take. A workload is described by a TOML [`specification`]: agents, each
+//! writing samples of their measurements at a fixed interval, with tags whose values follow a
+//! distribution and fields of a given type, whose values can follow the trends, cycles, spikes
+//! and gaps of real series.
+//!
+//! Samples older than the present are written as fast as the server accepts them, so a run
+//! can backfill history; later samples are written when their time comes. Queries can be run
+//! at the same time, and the run reports the throughput and latency of both.
+
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::future;
+use influxdb2_client::{DataPoint, WriteDataPoint};
+use snafu::{ResultExt, Snafu};
+use tracing::warn;
+
+pub mod agent;
+pub mod field;
+pub mod query;
+pub mod specification;
+pub mod stats;
+pub mod tag;
+pub mod write;
+
+use agent::Agent;
+use query::QueryLoad;
+use specification::DataSpec;
+use stats::{Stats, Summary};
+use write::PointsWriter;
+
+/// Errors running a workload
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The specification file could not be read
+    #[snafu(display("Error reading specification {}: {}", path.display(), source))]
+    ReadingSpecification {
+        /// The path of the file
+        path: PathBuf,
+        /// The underlying IO error
+        source: std::io::Error,
+    },
+
+    /// The specification is not valid TOML or does not match its format
+    #[snafu(display("Error parsing specification: {}", source))]
+    ParsingSpecification {
+        /// The underlying TOML error
+        source: toml::de::Error,
+    },
+
+    /// The specification can't generate data
+    #[snafu(display("Invalid specification: {}", message))]
+    InvalidSpecification {
+        /// What is wrong with the specification
+        message: String,
+    },
+
+    /// An agent or query task panicked
+    #[snafu(display("Task of the run failed: {}", source))]
+    TaskFailed {
+        /// The error joining the task
+        source: tokio::task::JoinError,
+    },
+}
+
+/// A specialized `Result` for the errors of this crate
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The throughput and latency of a run
+#[derive(Debug, Clone, Copy)]
+pub struct Report {
+    /// The writes of the agents
+    pub writes: Summary,
+    /// The queries run during the writes, if any. Their lines are the rows they returned.
+    pub queries: Option<Summary>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "writes\n{}", self.writes)?;
+        if let Some(queries) = &self.queries {
+            writeln!(f, "\nqueries\n{}", queries)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the workload `spec`, whose agents write samples from `start_time` to `end_time`, in
+/// nanoseconds since the epoch, through `writer`. The queries of `query_load` run until the
+/// agents are done.
+pub async fn run(
+    spec: &DataSpec,
+    writer: PointsWriter,
+    query_load: Option<QueryLoad>,
+    start_time: i64,
+    end_time: i64,
+) -> Result<Report> {
+    let base_seed = spec.base_seed.unwrap_or_else(rand::random);
+    let start = Instant::now();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let queries = query_load.map(|load| tokio::spawn(load.run(Arc::clone(&stop))));
+
+    let mut agents = vec![];
+    for agent_spec in &spec.agents {
+        for agent_id in 0..agent_spec.count {
+            let seed = base_seed.wrapping_add(agents.len() as u64);
+            let agent = Agent::new(agent_spec, agent_id, seed, start_time, end_time);
+            agents.push(tokio::spawn(run_agent(agent, writer.clone())));
+        }
+    }
+
+    let mut write_stats = Stats::default();
+    for result in future::join_all(agents).await {
+        write_stats.merge(result.context(TaskFailed)?);
+    }
+    let writes = write_stats.summary(start.elapsed());
+
+    stop.store(true, std::sync::atomic::Ordering::Relaxed);
+    let queries = match queries {
+        Some(queries) => Some(queries.await.context(TaskFailed)?.summary(start.elapsed())),
+        None => None,
+    };
+
+    Ok(Report { writes, queries })
+}
+
+/// Writes the samples of `agent` as they become due, returning the statistics of the writes
+async fn run_agent(mut agent: Agent, writer: PointsWriter) -> Stats {
+    let mut stats = Stats::default();
+
+    while let Some((time, points)) = agent.next_sample() {
+        let ahead = time - now_ns();
+        if ahead > 0 {
+            tokio::time::delay_for(Duration::from_nanos(ahead as u64)).await;
+        }
+
+        let body = line_protocol(&points);
+        let bytes = body.len();
+        let start = Instant::now();
+        match writer.write(body).await {
+            Ok(()) => stats.record_success(start.elapsed(), points.len(), bytes),
+            Err(e) => {
+                warn!("agent {} failed to write: {}", agent.name(), e);
+                stats.record_error(start.elapsed());
+            }
+        }
+    }
+
+    stats
+}
+
+/// Encodes `points` as line protocol
+pub fn line_protocol(points: &[DataPoint]) -> Vec<u8> {
+    let mut body = vec![];
+    for point in points {
+        point
+            .write_data_point_to(&mut body)
+            .expect("writing to a Vec can't fail");
+    }
+    body
+}
+
+/// The current time, in nanoseconds since the epoch
+pub fn now_ns() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time is after the epoch")
+        .as_nanos() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn run_to_stdout() {
+        let spec = DataSpec::from_toml(
+            r#"
+name = "fleet"
+base_seed = 1
+
+[[agents]]
+name = "host"
+count = 2
+sampling_interval = "1s"
+
+[[agents.measurements]]
+name = "cpu"
+fields = [{ name = "usage", type = "float" }]
+"#,
+        )
+        .unwrap();
+
+        let end_time = now_ns();
+        let start_time = end_time - 10_000_000_000;
+        let report = run(&spec, PointsWriter::Stdout, None, start_time, end_time)
+            .await
+            .unwrap();
+
+        assert_eq!(report.writes.requests, 20);
+        assert_eq!(report.writes.errors, 0);
+        assert_eq!(report.writes.lines, 20);
+        assert!(report.queries.is_none());
+    }
+}
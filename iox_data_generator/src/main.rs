@@ -0,0 +1,177 @@
+//! Command line entrypoint of the data generator
+#![deny(rust_2018_idioms)]
+#![warn(
+    missing_copy_implementations,
+    missing_debug_implementations,
+    clippy::explicit_iter_loop,
+    clippy::use_self
+)]
+
+use std::time::Duration;
+
+use clap::{crate_authors, crate_version, App, Arg, ArgMatches};
+use influxdb_iox_client::{Builder, QueryClient};
+use iox_data_generator::{now_ns, query::QueryLoad, specification::DataSpec, write::PointsWriter};
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+
+    let help = r#"Generates synthetic workloads and writes them to an IOx server
+
+Examples:
+    # Backfills an hour of the workload in fleet.toml into the bucket "metrics" of org "myorg"
+    iox_data_generator fleet.toml --org myorg --bucket metrics --history 1h
+
+    # Writes the workload in real time for 10 minutes while running a query every second
+    iox_data_generator fleet.toml --org myorg --bucket metrics --run-for 10m \
+        --query "SELECT count(*) FROM cpu"
+
+    # Prints 5 minutes of the workload as line protocol
+    iox_data_generator fleet.toml --print --history 5m > fleet.lp
+"#;
+
+    let matches = App::new(help)
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Load generator for InfluxDB IOx")
+        .arg(
+            Arg::with_name("SPECIFICATION")
+                .help("Path to the TOML specification of the workload")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .help("URL of the HTTP API of the server to write to")
+                .takes_value(true)
+                .default_value("http://127.0.0.1:8080"),
+        )
+        .arg(
+            Arg::with_name("grpc-host")
+                .long("grpc-host")
+                .help("URL of the gRPC API of the server to query")
+                .takes_value(true)
+                .default_value("http://127.0.0.1:8082"),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .env("INFLUX_TOKEN")
+                .help("Token to authenticate the writes and queries with")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("org")
+                .long("org")
+                .help("Organization to write to")
+                .takes_value(true)
+                .required_unless("print"),
+        )
+        .arg(
+            Arg::with_name("bucket")
+                .long("bucket")
+                .help("Bucket to write to")
+                .takes_value(true)
+                .required_unless("print"),
+        )
+        .arg(
+            Arg::with_name("print")
+                .long("print")
+                .help("Print the line protocol to standard output instead of writing it"),
+        )
+        .arg(
+            Arg::with_name("history")
+                .long("history")
+                .help("How much data before now to generate, written as fast as possible")
+                .takes_value(true)
+                .default_value("0s"),
+        )
+        .arg(
+            Arg::with_name("run-for")
+                .long("run-for")
+                .help("How long to keep writing data in real time after the history")
+                .takes_value(true)
+                .default_value("0s"),
+        )
+        .arg(
+            Arg::with_name("query")
+                .long("query")
+                .help("SQL query to run repeatedly while writing, can be given more than once")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with("print"),
+        )
+        .arg(
+            Arg::with_name("query-interval")
+                .long("query-interval")
+                .help("Pause between rounds of queries")
+                .takes_value(true)
+                .default_value("1s"),
+        )
+        .get_matches();
+
+    if let Err(e) = generate(&matches).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn generate(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let spec = DataSpec::from_file(matches.value_of("SPECIFICATION").unwrap())?;
+
+    let history = duration(matches, "history")?;
+    let run_for = duration(matches, "run-for")?;
+    if history + run_for == Duration::from_secs(0) {
+        return Err("one of --history and --run-for must be above zero".into());
+    }
+    let now = now_ns();
+    let start_time = now - history.as_nanos() as i64;
+    let end_time = now + run_for.as_nanos() as i64;
+
+    let token = matches.value_of("token").unwrap_or_default();
+    let writer = if matches.is_present("print") {
+        PointsWriter::Stdout
+    } else {
+        PointsWriter::http(
+            matches.value_of("host").unwrap(),
+            token,
+            matches.value_of("org").unwrap(),
+            matches.value_of("bucket").unwrap(),
+        )
+    };
+
+    let query_load = match matches.values_of("query") {
+        Some(queries) => {
+            let connection = Builder::default()
+                .token(token)
+                .build(matches.value_of("grpc-host").unwrap())
+                .await?;
+            // the HTTP write API writes bucket `bucket` of org `org` to database `org_bucket`
+            let db_name = format!(
+                "{}_{}",
+                matches.value_of("org").unwrap(),
+                matches.value_of("bucket").unwrap()
+            );
+            Some(QueryLoad::new(
+                QueryClient::new(connection),
+                db_name,
+                queries.map(str::to_string).collect(),
+                duration(matches, "query-interval")?,
+            ))
+        }
+        None => None,
+    };
+
+    let report = iox_data_generator::run(&spec, writer, query_load, start_time, end_time).await?;
+    eprintln!("{}\n{}", spec.name, report);
+
+    Ok(())
+}
+
+fn duration(matches: &ArgMatches<'_>, name: &str) -> Result<Duration, String> {
+    let value = matches.value_of(name).unwrap();
+    humantime::parse_duration(value).map_err(|e| format!("invalid --{} {}: {}", name, value, e))
+}
@@ -0,0 +1,66 @@
+//! Runs SQL queries against a server while a workload is written, to measure how queries
+//! perform under write load.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use influxdb_iox_client::QueryClient;
+use tracing::warn;
+
+use crate::stats::Stats;
+
+/// Queries to run in a loop
+#[derive(Debug, Clone)]
+pub struct QueryLoad {
+    client: QueryClient,
+    db_name: String,
+    queries: Vec<String>,
+    interval: Duration,
+}
+
+impl QueryLoad {
+    /// Runs each of `queries` against database `db_name` through `client`, pausing for
+    /// `interval` between rounds
+    pub fn new(
+        client: QueryClient,
+        db_name: impl Into<String>,
+        queries: Vec<String>,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            client,
+            db_name: db_name.into(),
+            queries,
+            interval,
+        }
+    }
+
+    /// Runs rounds of queries until `stop` is set, returning their statistics
+    pub async fn run(mut self, stop: Arc<AtomicBool>) -> Stats {
+        let mut stats = Stats::default();
+
+        while !stop.load(Ordering::Relaxed) {
+            for sql in &self.queries {
+                let start = Instant::now();
+                match self.client.query(self.db_name.as_str(), sql.as_str()).await {
+                    Ok(batches) => {
+                        let rows = batches.iter().map(|batch| batch.num_rows()).sum();
+                        stats.record_success(start.elapsed(), rows, 0);
+                    }
+                    Err(e) => {
+                        warn!("query {} failed: {}", sql, e);
+                        stats.record_error(start.elapsed());
+                    }
+                }
+            }
+            tokio::time::delay_for(self.interval).await;
+        }
+
+        stats
+    }
+}
@@ -0,0 +1,580 @@
+//! The specification of a workload, read from a TOML file.
+//!
+//! A workload is made of agents, each writing a sample of its measurements at a fixed interval,
+//! like Telegraf agents running on a fleet of hosts would. For example:
+//!
+//! ```toml
+//! name = "fleet"
+//! base_seed = 42
+//!
+//! [[agents]]
+//! name = "host"
+//! count = 100
+//! sampling_interval = "10s"
+//! name_tag_key = "host"
+//!
+//! [[agents.measurements]]
+//! name = "cpu"
+//! tags = [{ name = "core", cardinality = 8, distribution = "sequential" }]
+//!
+//! [[agents.measurements.fields]]
+//! name = "usage_user"
+//! type = "float"
+//! min = 0.0
+//! max = 100.0
+//! ```
+//!
+//! The values of numeric fields are picked at random between their bounds, unless they have a
+//! profile, which makes them follow a trend and daily or weekly cycles, with spikes and gaps
+//! in the data like real series have:
+//!
+//! ```toml
+//! [agents.measurements.fields.profile]
+//! baseline = 40.0
+//! trend = 0.5
+//! noise = 2.0
+//! seasonality = [{ period = "1d", amplitude = 20.0, phase = "6h" }]
+//! spikes = { probability = 0.001, magnitude = 50.0, duration = "2m" }
+//! gaps = { probability = 0.0005, duration = "10m" }
+//! ```
+
+use std::{path::Path, time::Duration};
+
+use serde::{Deserialize, Deserializer};
+use snafu::{ensure, ResultExt};
+
+use crate::{InvalidSpecification, ParsingSpecification, ReadingSpecification, Result};
+
+/// The specification of a workload
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DataSpec {
+    /// The name of the workload, used in reports
+    pub name: String,
+    /// The seed of the random values. Runs with the same seed generate the same data; runs
+    /// without a seed each generate different data.
+    pub base_seed: Option<u64>,
+    /// The agents writing the data
+    pub agents: Vec<AgentSpec>,
+}
+
+/// The specification of a group of identical agents
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentSpec {
+    /// The name of the agents, which are named `<name>-<n>`
+    pub name: String,
+    /// The number of agents writing concurrently
+    #[serde(default = "one")]
+    pub count: usize,
+    /// The time between the samples of an agent, such as "10s"
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub sampling_interval: Duration,
+    /// If set, the points of each agent have a tag with this key and the name of the agent as
+    /// its value, so that agents write separate series
+    pub name_tag_key: Option<String>,
+    /// The measurements of each sample
+    pub measurements: Vec<MeasurementSpec>,
+}
+
+/// The specification of a measurement written in each sample
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeasurementSpec {
+    /// The name of the measurement
+    pub name: String,
+    /// The number of measurements with this specification. When more than one, they are
+    /// named `<name>_<n>`, starting from 1.
+    #[serde(default = "one")]
+    pub count: usize,
+    /// The number of points of the measurement in each sample, each with its own tag values
+    #[serde(default = "one")]
+    pub series_per_sample: usize,
+    /// The tags of the points
+    #[serde(default)]
+    pub tags: Vec<TagSpec>,
+    /// The fields of the points, of which there must be at least one
+    pub fields: Vec<FieldSpec>,
+}
+
+/// The specification of a tag, whose values are `<name>-<n>` for `n` from 0 to the
+/// cardinality of the tag
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagSpec {
+    /// The key of the tag
+    pub name: String,
+    /// The number of values of the tag
+    pub cardinality: u32,
+    /// How often each value is picked
+    #[serde(default)]
+    pub distribution: Distribution,
+    /// The exponent of the Zipf distribution. The higher, the more the first values are
+    /// picked over the others.
+    #[serde(default = "default_exponent")]
+    pub exponent: f64,
+}
+
+/// How the values of a tag are picked
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Distribution {
+    /// Every value is equally likely
+    Uniform,
+    /// The values are picked in turn
+    Sequential,
+    /// Value `n` is picked with a probability proportional to `1 / (n + 1)^exponent`, so a
+    /// few values are picked most of the time, as with hot hosts or popular endpoints
+    Zipf,
+}
+
+impl Default for Distribution {
+    fn default() -> Self {
+        Self::Uniform
+    }
+}
+
+/// The specification of a field
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FieldSpec {
+    /// The key of the field
+    pub name: String,
+    /// The type and the values of the field
+    #[serde(flatten)]
+    pub value: FieldValueSpec,
+    /// How the values of a float or integer field evolve over time, if they aren't picked at
+    /// random between the bounds of the field
+    #[serde(default)]
+    pub profile: Option<ProfileSpec>,
+}
+
+/// The type of a field and the values it takes
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FieldValueSpec {
+    /// Random floats between `min` and `max`, inclusive
+    Float {
+        /// The smallest value
+        #[serde(default)]
+        min: f64,
+        /// The largest value
+        #[serde(default = "default_max")]
+        max: f64,
+    },
+    /// Random integers between `min` and `max`, inclusive
+    Integer {
+        /// The smallest value
+        #[serde(default)]
+        min: i64,
+        /// The largest value
+        #[serde(default = "default_max_integer")]
+        max: i64,
+    },
+    /// Random booleans
+    Bool,
+    /// Strings picked at random from `values`, or random alphanumeric strings of `length`
+    /// characters if there are no values
+    String {
+        /// The values to pick from
+        #[serde(default)]
+        values: Vec<String>,
+        /// The length of the random strings
+        #[serde(default = "default_length")]
+        length: usize,
+    },
+}
+
+/// How the values of a numeric field evolve over time. The value at a time is the baseline,
+/// plus the trend since the start of the run, the cycles of the seasonality, random noise and
+/// the magnitude of any ongoing spike, clamped to the bounds of the field.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileSpec {
+    /// The value around which the others vary
+    pub baseline: f64,
+    /// How much the baseline changes per hour
+    #[serde(default)]
+    pub trend: f64,
+    /// The largest random deviation of a value, either way
+    #[serde(default)]
+    pub noise: f64,
+    /// The cycles added to the baseline, such as daily and weekly ones
+    #[serde(default)]
+    pub seasonality: Vec<SeasonalitySpec>,
+    /// The spikes of the values, if any
+    pub spikes: Option<SpikeSpec>,
+    /// The gaps in the data, during which the field is not written
+    pub gaps: Option<GapSpec>,
+}
+
+/// A cycle of the values of a field, following a sine wave
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeasonalitySpec {
+    /// The length of a cycle, such as "1d"
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub period: Duration,
+    /// How far the values go above and below the baseline
+    pub amplitude: f64,
+    /// How long after the start of each period, counted from the epoch, the cycle starts
+    /// rising from the baseline. The values peak a quarter of a period later.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub phase: Duration,
+}
+
+/// Spikes that add to the values of a field for a while
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpikeSpec {
+    /// The probability that a spike starts at each sample
+    pub probability: f64,
+    /// What a spike adds to the values, which is negative for dips
+    pub magnitude: f64,
+    /// How long a spike lasts
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub duration: Duration,
+}
+
+/// Gaps in the data of a field, as when a sensor or an agent stops reporting for a while
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GapSpec {
+    /// The probability that a gap starts at each sample
+    pub probability: f64,
+    /// How long a gap lasts
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub duration: Duration,
+}
+
+fn one() -> usize {
+    1
+}
+
+fn default_exponent() -> f64 {
+    1.0
+}
+
+fn default_max() -> f64 {
+    100.0
+}
+
+fn default_max_integer() -> i64 {
+    100
+}
+
+fn default_length() -> usize {
+    8
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+impl DataSpec {
+    /// Reads and validates the specification in the TOML file at `path`
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let toml = std::fs::read_to_string(path).context(ReadingSpecification { path })?;
+        Self::from_toml(&toml)
+    }
+
+    /// Parses and validates the specification `toml`
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        let spec: Self = toml::from_str(toml).context(ParsingSpecification)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    /// Checks the parts of the specification that its types can't
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.agents.is_empty(),
+            InvalidSpecification {
+                message: "there are no agents"
+            }
+        );
+
+        for agent in &self.agents {
+            ensure!(
+                agent.count > 0 && agent.sampling_interval > Duration::from_secs(0),
+                InvalidSpecification {
+                    message: format!(
+                        "agent {} needs a count and a sampling interval above zero",
+                        agent.name
+                    )
+                }
+            );
+            ensure!(
+                !agent.measurements.is_empty(),
+                InvalidSpecification {
+                    message: format!("agent {} has no measurements", agent.name)
+                }
+            );
+
+            for measurement in &agent.measurements {
+                measurement.validate()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl MeasurementSpec {
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            self.count > 0 && self.series_per_sample > 0,
+            InvalidSpecification {
+                message: format!(
+                    "measurement {} needs a count and series per sample above zero",
+                    self.name
+                )
+            }
+        );
+        ensure!(
+            !self.fields.is_empty(),
+            InvalidSpecification {
+                message: format!("measurement {} has no fields", self.name)
+            }
+        );
+
+        for tag in &self.tags {
+            ensure!(
+                tag.cardinality > 0,
+                InvalidSpecification {
+                    message: format!("tag {} of {} has no values", tag.name, self.name)
+                }
+            );
+        }
+
+        for field in &self.fields {
+            let valid = match &field.value {
+                FieldValueSpec::Float { min, max } => min <= max,
+                FieldValueSpec::Integer { min, max } => min <= max,
+                FieldValueSpec::Bool => true,
+                FieldValueSpec::String { values, length } => !values.is_empty() || *length > 0,
+            };
+            ensure!(
+                valid,
+                InvalidSpecification {
+                    message: format!("field {} of {} has no values", field.name, self.name)
+                }
+            );
+
+            if let Some(profile) = &field.profile {
+                let message = match &field.value {
+                    FieldValueSpec::Float { .. } | FieldValueSpec::Integer { .. } => {
+                        profile.problem()
+                    }
+                    _ => Some("only float and integer fields can have a profile"),
+                };
+                if let Some(message) = message {
+                    return InvalidSpecification {
+                        message: format!("field {} of {}: {}", field.name, self.name, message),
+                    }
+                    .fail();
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ProfileSpec {
+    /// Returns what is wrong with the profile, if anything
+    fn problem(&self) -> Option<&'static str> {
+        let zero = Duration::from_secs(0);
+        if self.seasonality.iter().any(|cycle| cycle.period == zero) {
+            return Some("the period of a cycle must be above zero");
+        }
+        let probabilities = self
+            .spikes
+            .iter()
+            .map(|spikes| spikes.probability)
+            .chain(self.gaps.iter().map(|gaps| gaps.probability));
+        for probability in probabilities {
+            if !(0.0..=1.0).contains(&probability) {
+                return Some("probabilities must be between 0 and 1");
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn parse_specification() {
+        let spec = DataSpec::from_toml(
+            r#"
+name = "fleet"
+base_seed = 42
+
+[[agents]]
+name = "host"
+count = 3
+sampling_interval = "10s"
+name_tag_key = "host"
+
+[[agents.measurements]]
+name = "cpu"
+count = 2
+tags = [{ name = "core", cardinality = 8, distribution = "zipf", exponent = 1.5 }]
+
+[[agents.measurements.fields]]
+name = "usage"
+type = "float"
+max = 1.0
+
+[[agents.measurements.fields]]
+name = "status"
+type = "string"
+values = ["ok", "degraded"]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(spec.base_seed, Some(42));
+        let agent = &spec.agents[0];
+        assert_eq!(agent.count, 3);
+        assert_eq!(agent.sampling_interval, Duration::from_secs(10));
+
+        let measurement = &agent.measurements[0];
+        assert_eq!(measurement.count, 2);
+        assert_eq!(measurement.series_per_sample, 1);
+        assert_eq!(
+            measurement.tags,
+            vec![TagSpec {
+                name: "core".to_string(),
+                cardinality: 8,
+                distribution: Distribution::Zipf,
+                exponent: 1.5,
+            }]
+        );
+        assert_eq!(
+            measurement.fields[0].value,
+            FieldValueSpec::Float { min: 0.0, max: 1.0 }
+        );
+        assert_eq!(
+            measurement.fields[1].value,
+            FieldValueSpec::String {
+                values: vec!["ok".to_string(), "degraded".to_string()],
+                length: 8
+            }
+        );
+    }
+
+    #[test]
+    fn parse_profile() {
+        let spec = DataSpec::from_toml(
+            r#"
+name = "sensors"
+
+[[agents]]
+name = "sensor"
+sampling_interval = "1m"
+
+[[agents.measurements]]
+name = "temperature"
+
+[[agents.measurements.fields]]
+name = "celsius"
+type = "float"
+min = -20.0
+max = 50.0
+
+[agents.measurements.fields.profile]
+baseline = 15.0
+trend = 0.01
+seasonality = [{ period = "1d", amplitude = 8.0, phase = "6h" }]
+spikes = { probability = 0.001, magnitude = 10.0, duration = "5m" }
+gaps = { probability = 0.002, duration = "30m" }
+"#,
+        )
+        .unwrap();
+
+        let profile = spec.agents[0].measurements[0].fields[0]
+            .profile
+            .clone()
+            .unwrap();
+        assert_eq!(
+            profile,
+            ProfileSpec {
+                baseline: 15.0,
+                trend: 0.01,
+                noise: 0.0,
+                seasonality: vec![SeasonalitySpec {
+                    period: Duration::from_secs(86_400),
+                    amplitude: 8.0,
+                    phase: Duration::from_secs(6 * 3600),
+                }],
+                spikes: Some(SpikeSpec {
+                    probability: 0.001,
+                    magnitude: 10.0,
+                    duration: Duration::from_secs(300),
+                }),
+                gaps: Some(GapSpec {
+                    probability: 0.002,
+                    duration: Duration::from_secs(1800),
+                }),
+            }
+        );
+
+        let err = DataSpec::from_toml(
+            r#"
+name = "sensors"
+
+[[agents]]
+name = "sensor"
+sampling_interval = "1m"
+
+[[agents.measurements]]
+name = "door"
+fields = [{ name = "open", type = "bool", profile = { baseline = 1.0 } }]
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid specification: field open of door: only float and integer fields can have \
+             a profile"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_specification() {
+        let err = DataSpec::from_toml(
+            r#"
+name = "fleet"
+
+[[agents]]
+name = "host"
+sampling_interval = "10s"
+
+[[agents.measurements]]
+name = "cpu"
+fields = [{ name = "usage", type = "integer", min = 10, max = 1 }]
+"#,
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::InvalidSpecification { .. }));
+        assert_eq!(
+            err.to_string(),
+            "Invalid specification: field usage of cpu has no values"
+        );
+
+        let err = DataSpec::from_toml("name = \"fleet\"\nagents = []").unwrap_err();
+        assert!(matches!(err, Error::InvalidSpecification { .. }));
+
+        let err = DataSpec::from_toml("name = 1").unwrap_err();
+        assert!(matches!(err, Error::ParsingSpecification { .. }));
+    }
+}
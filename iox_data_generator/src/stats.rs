@@ -0,0 +1,168 @@
+//! Throughput and latency statistics of the requests of a run.
+
+use std::{fmt, time::Duration};
+
+/// The requests recorded by a writer or querier
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    latencies: Vec<Duration>,
+    errors: u64,
+    lines: u64,
+    bytes: u64,
+}
+
+impl Stats {
+    /// Records a successful request that took `latency` and sent `lines` lines of `bytes`
+    /// bytes in total
+    pub fn record_success(&mut self, latency: Duration, lines: usize, bytes: usize) {
+        self.latencies.push(latency);
+        self.lines += lines as u64;
+        self.bytes += bytes as u64;
+    }
+
+    /// Records a failed request that took `latency`
+    pub fn record_error(&mut self, latency: Duration) {
+        self.latencies.push(latency);
+        self.errors += 1;
+    }
+
+    /// Adds the requests recorded by `other`
+    pub fn merge(&mut self, other: Self) {
+        self.latencies.extend(other.latencies);
+        self.errors += other.errors;
+        self.lines += other.lines;
+        self.bytes += other.bytes;
+    }
+
+    /// Summarises the requests, which were made over `elapsed`
+    pub fn summary(&self, elapsed: Duration) -> Summary {
+        let mut latencies = self.latencies.clone();
+        latencies.sort_unstable();
+
+        Summary {
+            requests: latencies.len() as u64,
+            errors: self.errors,
+            lines: self.lines,
+            bytes: self.bytes,
+            elapsed,
+            p50: percentile(&latencies, 50.0),
+            p90: percentile(&latencies, 90.0),
+            p99: percentile(&latencies, 99.0),
+            max: latencies.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// The nearest-rank percentile of sorted latencies
+fn percentile(sorted: &[Duration], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1) - 1]
+}
+
+/// A summary of the requests of a run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    /// The number of requests, including failed ones
+    pub requests: u64,
+    /// The number of failed requests
+    pub errors: u64,
+    /// The number of lines written by successful requests
+    pub lines: u64,
+    /// The number of bytes written by successful requests
+    pub bytes: u64,
+    /// The time the requests were made over
+    pub elapsed: Duration,
+    /// The median latency
+    pub p50: Duration,
+    /// The 90th percentile latency
+    pub p90: Duration,
+    /// The 99th percentile latency
+    pub p99: Duration,
+    /// The largest latency
+    pub max: Duration,
+}
+
+impl Summary {
+    /// The number of requests made per second
+    pub fn requests_per_second(&self) -> f64 {
+        per_second(self.requests, self.elapsed)
+    }
+
+    /// The number of lines written per second
+    pub fn lines_per_second(&self) -> f64 {
+        per_second(self.lines, self.elapsed)
+    }
+}
+
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+    let seconds = elapsed.as_secs_f64();
+    if seconds > 0.0 {
+        count as f64 / seconds
+    } else {
+        0.0
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "requests: {} ({} failed) in {:.3}s, {:.1}/s",
+            self.requests,
+            self.errors,
+            self.elapsed.as_secs_f64(),
+            self.requests_per_second()
+        )?;
+        if self.lines > 0 {
+            writeln!(
+                f,
+                "lines:    {} ({} bytes), {:.1}/s",
+                self.lines,
+                self.bytes,
+                self.lines_per_second()
+            )?;
+        }
+        write!(
+            f,
+            "latency:  p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.p50, self.p90, self.p99, self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let mut stats = Stats::default();
+        for ms in (1..=50).rev() {
+            stats.record_success(Duration::from_millis(ms), 10, 100);
+        }
+        let mut other = Stats::default();
+        for ms in 51..=100 {
+            other.record_success(Duration::from_millis(ms), 10, 100);
+        }
+        other.record_error(Duration::from_secs(1));
+        stats.merge(other);
+
+        let summary = stats.summary(Duration::from_secs(2));
+        assert_eq!(summary.requests, 101);
+        assert_eq!(summary.errors, 1);
+        assert_eq!(summary.lines, 1_000);
+        assert_eq!(summary.bytes, 10_000);
+        assert_eq!(summary.p50, Duration::from_millis(51));
+        assert_eq!(summary.p90, Duration::from_millis(91));
+        assert_eq!(summary.p99, Duration::from_millis(100));
+        assert_eq!(summary.max, Duration::from_secs(1));
+        assert!((summary.lines_per_second() - 500.0).abs() < f64::EPSILON);
+
+        let summary = Stats::default().summary(Duration::default());
+        assert_eq!(summary.p99, Duration::default());
+        assert!(summary.requests_per_second().abs() < f64::EPSILON);
+    }
+}
@@ -0,0 +1,154 @@
+//! Picks the values of tags according to their distribution.
+
+use rand::Rng;
+
+use crate::specification::{Distribution, TagSpec};
+
+/// Picks the values of a tag
+#[derive(Debug, Clone)]
+pub struct TagValues {
+    name: String,
+    cardinality: u32,
+    sampler: Sampler,
+}
+
+#[derive(Debug, Clone)]
+enum Sampler {
+    Uniform,
+    Sequential {
+        next: u32,
+    },
+    /// The cumulative probabilities of the values
+    Zipf {
+        cdf: Vec<f64>,
+    },
+}
+
+impl TagValues {
+    /// Creates a picker of the values of the tag specified by `spec`
+    pub fn new(spec: &TagSpec) -> Self {
+        let sampler = match spec.distribution {
+            Distribution::Uniform => Sampler::Uniform,
+            Distribution::Sequential => Sampler::Sequential { next: 0 },
+            Distribution::Zipf => Sampler::Zipf {
+                cdf: zipf_cdf(spec.cardinality, spec.exponent),
+            },
+        };
+
+        Self {
+            name: spec.name.clone(),
+            cardinality: spec.cardinality,
+            sampler,
+        }
+    }
+
+    /// The key of the tag
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Picks the next value of the tag
+    pub fn sample(&mut self, rng: &mut impl Rng) -> String {
+        format!("{}-{}", self.name, self.sample_index(rng))
+    }
+
+    /// Picks the number of the next value of the tag, between 0 and the cardinality
+    fn sample_index(&mut self, rng: &mut impl Rng) -> u32 {
+        match &mut self.sampler {
+            Sampler::Uniform => rng.gen_range(0, self.cardinality),
+            Sampler::Sequential { next } => {
+                let index = *next;
+                *next = (index + 1) % self.cardinality;
+                index
+            }
+            Sampler::Zipf { cdf } => {
+                let p: f64 = rng.gen();
+                let index = match cdf.binary_search_by(|c| c.partial_cmp(&p).expect("no NaN")) {
+                    Ok(index) => index + 1,
+                    Err(index) => index,
+                };
+                // rounding can leave the last cumulative probability just under 1
+                index.min(cdf.len() - 1) as u32
+            }
+        }
+    }
+}
+
+fn zipf_cdf(cardinality: u32, exponent: f64) -> Vec<f64> {
+    let weights: Vec<f64> = (1..=cardinality)
+        .map(|rank| 1.0 / f64::from(rank).powf(exponent))
+        .collect();
+    let total: f64 = weights.iter().sum();
+
+    let mut cumulative = 0.0;
+    weights
+        .into_iter()
+        .map(|weight| {
+            cumulative += weight / total;
+            cumulative
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn tag_values(cardinality: u32, distribution: Distribution) -> TagValues {
+        TagValues::new(&TagSpec {
+            name: "host".to_string(),
+            cardinality,
+            distribution,
+            exponent: 1.0,
+        })
+    }
+
+    #[test]
+    fn sequential() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut values = tag_values(3, Distribution::Sequential);
+
+        let sampled: Vec<_> = (0..4).map(|_| values.sample(&mut rng)).collect();
+        assert_eq!(sampled, vec!["host-0", "host-1", "host-2", "host-0"]);
+    }
+
+    #[test]
+    fn distributions_stay_within_cardinality() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for &distribution in &[
+            Distribution::Uniform,
+            Distribution::Sequential,
+            Distribution::Zipf,
+        ] {
+            let mut values = tag_values(10, distribution);
+            let mut counts = [0; 10];
+            for _ in 0..10_000 {
+                counts[values.sample_index(&mut rng) as usize] += 1;
+            }
+            assert!(
+                counts.iter().all(|&count| count > 0),
+                "{:?}: {:?}",
+                distribution,
+                counts
+            );
+        }
+    }
+
+    #[test]
+    fn zipf_favours_first_values() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut values = tag_values(100, Distribution::Zipf);
+
+        let mut counts = vec![0; 100];
+        for _ in 0..10_000 {
+            counts[values.sample_index(&mut rng) as usize] += 1;
+        }
+
+        // the first value is picked about 19% of the time, the last about 0.2%
+        assert!(counts[0] > 1_500, "{:?}", counts);
+        assert!(counts[0] > counts[1] && counts[1] > counts[9]);
+        assert!(counts[99] < 100, "{:?}", counts);
+    }
+}
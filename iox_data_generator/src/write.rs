@@ -0,0 +1,71 @@
+//! Sends the generated line protocol to a server, or prints it.
+
+use std::io::Write;
+
+use snafu::{ResultExt, Snafu};
+
+/// Errors writing line protocol
+#[derive(Debug, Snafu)]
+pub enum Error {
+    /// The server rejected the write or could not be reached
+    #[snafu(display("Error writing to the server: {}", source))]
+    Http {
+        /// The error of the client
+        source: influxdb2_client::RequestError,
+    },
+
+    /// The line protocol could not be printed
+    #[snafu(display("Error printing line protocol: {}", source))]
+    Printing {
+        /// The underlying IO error
+        source: std::io::Error,
+    },
+}
+
+/// Where the line protocol of a run goes
+#[derive(Debug, Clone)]
+pub enum PointsWriter {
+    /// To the HTTP write API of a server
+    Http {
+        /// The client of the server
+        client: influxdb2_client::Client,
+        /// The organization written to
+        org: String,
+        /// The bucket written to
+        bucket: String,
+    },
+    /// To standard output, to save a workload or inspect it
+    Stdout,
+}
+
+impl PointsWriter {
+    /// Writes to bucket `bucket` of organization `org` through the HTTP write API of the
+    /// server at `host`, authenticating with `token`
+    pub fn http(
+        host: impl Into<String>,
+        token: &str,
+        org: impl Into<String>,
+        bucket: impl Into<String>,
+    ) -> Self {
+        Self::Http {
+            client: influxdb2_client::Client::new(host, token),
+            org: org.into(),
+            bucket: bucket.into(),
+        }
+    }
+
+    /// Writes the line protocol `body`
+    pub async fn write(&self, body: Vec<u8>) -> Result<(), Error> {
+        match self {
+            Self::Http {
+                client,
+                org,
+                bucket,
+            } => client
+                .write_line_protocol(org, bucket, body)
+                .await
+                .context(Http),
+            Self::Stdout => std::io::stdout().lock().write_all(&body).context(Printing),
+        }
+    }
+}
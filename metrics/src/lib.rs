@@ -0,0 +1,446 @@
+#![deny(rust_2018_idioms)]
+#![warn(
+    missing_copy_implementations,
+    missing_debug_implementations,
+    clippy::explicit_iter_loop,
+    clippy::use_self
+)]
+
+//! # metrics
+//!
+//! This crate provides the metrics registry shared by the crates of InfluxDB IOx. Metrics are
+//! registered by name and label values; registering the same metric twice returns the same
+//! instance, so code can look its metrics up where they are recorded. The contents of a
+//! registry can be rendered in the Prometheus text exposition format.
+//!
+//! ```
+//! let registry = metrics::Registry::new();
+//! let lines = registry.counter("ingest_lines_total", "Lines written", &[]);
+//! lines.add(3);
+//!
+//! assert!(registry
+//!     .encode_text()
+//!     .contains("ingest_lines_total 3\n"));
+//! ```
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Bucket boundaries suitable for latencies measured in seconds
+pub const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Bucket boundaries suitable for sizes measured in bytes
+pub const SIZE_BUCKETS: &[f64] = &[
+    1024.0,
+    16.0 * 1024.0,
+    256.0 * 1024.0,
+    1024.0 * 1024.0,
+    16.0 * 1024.0 * 1024.0,
+    256.0 * 1024.0 * 1024.0,
+    1024.0 * 1024.0 * 1024.0,
+];
+
+static GLOBAL: Lazy<Registry> = Lazy::new(Registry::new);
+
+/// Returns the registry shared by the whole process
+pub fn registry() -> &'static Registry {
+    &GLOBAL
+}
+
+/// A value that only goes up, such as a number of requests
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1)
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down, such as the number of open chunks
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, value: i64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts observed values, such as request durations, into buckets
+#[derive(Debug)]
+pub struct Histogram {
+    /// The upper bounds of the buckets, in increasing order
+    bounds: Vec<f64>,
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Debug)]
+struct HistogramState {
+    /// The number of observations that fell into each bucket (not cumulative)
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            state: Mutex::new(HistogramState {
+                buckets: vec![0; bounds.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let mut state = self.state.lock().expect("mutex poisoned");
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            state.buckets[bucket] += 1;
+        }
+        state.sum += value;
+        state.count += 1;
+    }
+
+    /// Returns the number of values observed so far
+    pub fn count(&self) -> u64 {
+        self.state.lock().expect("mutex poisoned").count
+    }
+
+    /// Returns the sum of the values observed so far
+    pub fn sum(&self) -> f64 {
+        self.state.lock().expect("mutex poisoned").sum
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>),
+}
+
+impl Metric {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::Counter(_) => "counter",
+            Self::Gauge(_) => "gauge",
+            Self::Histogram(_) => "histogram",
+        }
+    }
+}
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug)]
+struct Family {
+    help: &'static str,
+    metrics: BTreeMap<Labels, Metric>,
+}
+
+/// A set of metrics, each identified by its name and label values
+#[derive(Debug, Default)]
+pub struct Registry {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the counter `name` with the given labels, registering it if needed
+    pub fn counter(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+    ) -> Arc<Counter> {
+        match self.get_or_register(name, help, labels, || {
+            Metric::Counter(Arc::new(Counter::default()))
+        }) {
+            Metric::Counter(counter) => counter,
+            other => panic!("{} is a {}, not a counter", name, other.type_name()),
+        }
+    }
+
+    /// Returns the gauge `name` with the given labels, registering it if needed
+    pub fn gauge(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+    ) -> Arc<Gauge> {
+        match self.get_or_register(name, help, labels, || {
+            Metric::Gauge(Arc::new(Gauge::default()))
+        }) {
+            Metric::Gauge(gauge) => gauge,
+            other => panic!("{} is a {}, not a gauge", name, other.type_name()),
+        }
+    }
+
+    /// Returns the histogram `name` with the given labels, registering it with `buckets`
+    /// as the upper bounds of its buckets if needed
+    pub fn histogram(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        buckets: &[f64],
+        labels: &[(&str, &str)],
+    ) -> Arc<Histogram> {
+        match self.get_or_register(name, help, labels, || {
+            Metric::Histogram(Arc::new(Histogram::new(buckets)))
+        }) {
+            Metric::Histogram(histogram) => histogram,
+            other => panic!("{} is a {}, not a histogram", name, other.type_name()),
+        }
+    }
+
+    fn get_or_register(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        new: impl FnOnce() -> Metric,
+    ) -> Metric {
+        let labels: Labels = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        let mut families = self.families.lock().expect("mutex poisoned");
+        let family = families.entry(name).or_insert_with(|| Family {
+            help,
+            metrics: BTreeMap::new(),
+        });
+
+        if let Some(metric) = family.metrics.get(&labels) {
+            return metric.clone();
+        }
+
+        // all the metrics of a family must be of the same type, whatever their labels, so a
+        // metric of another type is returned for the caller to reject
+        let metric = new();
+        if let Some(existing) = family.metrics.values().next() {
+            if existing.type_name() != metric.type_name() {
+                return existing.clone();
+            }
+        }
+
+        family.metrics.insert(labels, metric.clone());
+        metric
+    }
+
+    /// Renders all the metrics of the registry in the Prometheus text exposition format
+    pub fn encode_text(&self) -> String {
+        let families = self.families.lock().expect("mutex poisoned");
+        let mut out = String::new();
+
+        for (name, family) in families.iter() {
+            let type_name = match family.metrics.values().next() {
+                Some(metric) => metric.type_name(),
+                None => continue,
+            };
+            writeln!(out, "# HELP {} {}", name, family.help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, type_name).unwrap();
+
+            for (labels, metric) in &family.metrics {
+                match metric {
+                    Metric::Counter(counter) => writeln!(
+                        out,
+                        "{}{} {}",
+                        name,
+                        format_labels(labels, None),
+                        counter.get()
+                    )
+                    .unwrap(),
+                    Metric::Gauge(gauge) => writeln!(
+                        out,
+                        "{}{} {}",
+                        name,
+                        format_labels(labels, None),
+                        gauge.get()
+                    )
+                    .unwrap(),
+                    Metric::Histogram(histogram) => {
+                        let state = histogram.state.lock().expect("mutex poisoned");
+                        let mut cumulative = 0;
+                        for (bound, count) in histogram.bounds.iter().zip(&state.buckets) {
+                            cumulative += count;
+                            let le = bound.to_string();
+                            writeln!(
+                                out,
+                                "{}_bucket{} {}",
+                                name,
+                                format_labels(labels, Some(&le)),
+                                cumulative
+                            )
+                            .unwrap();
+                        }
+                        writeln!(
+                            out,
+                            "{}_bucket{} {}",
+                            name,
+                            format_labels(labels, Some("+Inf")),
+                            state.count
+                        )
+                        .unwrap();
+                        writeln!(
+                            out,
+                            "{}_sum{} {}",
+                            name,
+                            format_labels(labels, None),
+                            state.sum
+                        )
+                        .unwrap();
+                        writeln!(
+                            out,
+                            "{}_count{} {}",
+                            name,
+                            format_labels(labels, None),
+                            state.count
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Formats `labels`, plus the `le` label of a histogram bucket if given, as `{k="v",...}`
+fn format_labels(labels: &[(String, String)], le: Option<&str>) -> String {
+    let mut pairs: Vec<_> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_once() {
+        let registry = Registry::new();
+
+        registry
+            .counter("requests", "help", &[("path", "/a")])
+            .inc();
+        registry
+            .counter("requests", "help", &[("path", "/a")])
+            .inc();
+        registry
+            .counter("requests", "help", &[("path", "/b")])
+            .inc();
+
+        assert_eq!(
+            registry
+                .counter("requests", "help", &[("path", "/a")])
+                .get(),
+            2
+        );
+        assert_eq!(
+            registry
+                .counter("requests", "help", &[("path", "/b")])
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "requests is a counter, not a gauge")]
+    fn kind_mismatch() {
+        let registry = Registry::new();
+        registry.counter("requests", "help", &[("path", "/a")]);
+        registry.gauge("requests", "help", &[("path", "/b")]);
+    }
+
+    #[test]
+    fn encode_text() {
+        let registry = Registry::new();
+
+        registry
+            .counter(
+                "http_requests_total",
+                "Requests handled",
+                &[("path", "/ping")],
+            )
+            .add(2);
+        registry.gauge("open_chunks", "Open chunks", &[]).set(-1);
+        let histogram = registry.histogram("latency", "Latency", &[0.1, 1.0], &[]);
+        histogram.observe(0.0625);
+        histogram.observe(0.5);
+        histogram.observe(4.0);
+
+        assert_eq!(
+            registry.encode_text(),
+            "# HELP http_requests_total Requests handled\n\
+             # TYPE http_requests_total counter\n\
+             http_requests_total{path=\"/ping\"} 2\n\
+             # HELP latency Latency\n\
+             # TYPE latency histogram\n\
+             latency_bucket{le=\"0.1\"} 1\n\
+             latency_bucket{le=\"1\"} 2\n\
+             latency_bucket{le=\"+Inf\"} 3\n\
+             latency_sum 4.5625\n\
+             latency_count 3\n\
+             # HELP open_chunks Open chunks\n\
+             # TYPE open_chunks gauge\n\
+             open_chunks -1\n"
+        );
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(
+            format_labels(&[("q".to_string(), "a \"b\"\n".to_string())], None),
+            "{q=\"a \\\"b\\\"\\n\"}"
+        );
+    }
+}
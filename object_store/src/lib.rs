@@ -20,10 +20,25 @@ use rusoto_core::ByteStream;
 use rusoto_credential::ChainProvider;
 use rusoto_s3::S3;
 use snafu::{ensure, futures::TryStreamExt as _, OptionExt, ResultExt, Snafu};
-use std::{collections::BTreeMap, fmt, io, path::PathBuf};
-use tokio::{fs, sync::RwLock};
+use std::{collections::BTreeMap, fmt, io, ops::Range, path::PathBuf};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::RwLock,
+};
 use tokio_util::codec::{BytesCodec, FramedRead};
 
+/// Counts the requests made to the object store, by operation
+fn record_request(operation: &str) {
+    metrics::registry()
+        .counter(
+            "object_store_requests_total",
+            "Requests made to the object store",
+            &[("operation", operation)],
+        )
+        .inc();
+}
+
 /// Universal interface to multiple object store services.
 #[derive(Debug)]
 pub struct ObjectStore(pub ObjectStoreIntegration);
@@ -49,12 +64,40 @@ impl ObjectStore {
         Self(ObjectStoreIntegration::File(file))
     }
 
+    /// Configure the storage named by `url`, returning it with the prefix of the locations
+    /// under `url`: `s3://bucket/prefix` is an Amazon S3 bucket in the region configured by the
+    /// environment, `gs://bucket/prefix` a Google Cloud Storage bucket, and anything else a
+    /// local directory.
+    pub fn from_url(url: &str) -> (Self, String) {
+        let bucket_and_prefix = |path: &str| {
+            let mut parts = path.splitn(2, '/');
+            let bucket = parts.next().unwrap_or_default().to_string();
+            let prefix = parts.next().unwrap_or_default().trim_end_matches('/');
+            (bucket, prefix.to_string())
+        };
+
+        if url.starts_with("s3://") {
+            let (bucket, prefix) = bucket_and_prefix(&url["s3://".len()..]);
+            let region = rusoto_core::Region::default();
+            (Self::new_amazon_s3(AmazonS3::new(region, bucket)), prefix)
+        } else if url.starts_with("gs://") {
+            let (bucket, prefix) = bucket_and_prefix(&url["gs://".len()..]);
+            (
+                Self::new_google_cloud_storage(GoogleCloudStorage::new(bucket)),
+                prefix,
+            )
+        } else {
+            (Self::new_file(File::new(url)), String::new())
+        }
+    }
+
     /// Save the provided bytes to the specified location.
     pub async fn put<S>(&self, location: &str, bytes: S, length: usize) -> Result<()>
     where
         S: Stream<Item = io::Result<Bytes>> + Send + Sync + 'static,
     {
         use ObjectStoreIntegration::*;
+        record_request("put");
         match &self.0 {
             AmazonS3(s3) => s3.put(location, bytes, length).await?,
             GoogleCloudStorage(gcs) => gcs.put(location, bytes, length).await?,
@@ -68,6 +111,7 @@ impl ObjectStore {
     /// Return the bytes that are stored at the specified location.
     pub async fn get(&self, location: &str) -> Result<impl Stream<Item = Result<Bytes>>> {
         use ObjectStoreIntegration::*;
+        record_request("get");
         Ok(match &self.0 {
             AmazonS3(s3) => s3.get(location).await?.boxed(),
             GoogleCloudStorage(gcs) => gcs.get(location).await?.boxed(),
@@ -77,9 +121,23 @@ impl ObjectStore {
         .err_into())
     }
 
+    /// Return the bytes in `range` of the object stored at the specified location, which
+    /// must be within the object.
+    pub async fn get_range(&self, location: &str, range: Range<usize>) -> Result<Bytes> {
+        use ObjectStoreIntegration::*;
+        record_request("get_range");
+        Ok(match &self.0 {
+            AmazonS3(s3) => s3.get_range(location, range).await?,
+            GoogleCloudStorage(gcs) => gcs.get_range(location, range).await?,
+            InMemory(in_mem) => in_mem.get_range(location, range).await?,
+            File(file) => file.get_range(location, range).await?,
+        })
+    }
+
     /// Delete the object at the specified location.
     pub async fn delete(&self, location: &str) -> Result<()> {
         use ObjectStoreIntegration::*;
+        record_request("delete");
         match &self.0 {
             AmazonS3(s3) => s3.delete(location).await?,
             GoogleCloudStorage(gcs) => gcs.delete(location).await?,
@@ -96,6 +154,7 @@ impl ObjectStore {
         prefix: Option<&'a str>,
     ) -> Result<impl Stream<Item = Result<Vec<String>>> + 'a> {
         use ObjectStoreIntegration::*;
+        record_request("list");
         Ok(match &self.0 {
             AmazonS3(s3) => s3.list(prefix).await?.boxed(),
             GoogleCloudStorage(gcs) => gcs.list(prefix).await?.boxed(),
@@ -119,6 +178,9 @@ pub enum ObjectStoreIntegration {
     File(File),
 }
 
+/// How long the signed URLs that ranges of objects are downloaded from are valid, in seconds
+const GCS_SIGNED_URL_SECONDS: u32 = 60;
+
 /// Configuration for connecting to [Google Cloud Storage](https://cloud.google.com/storage/).
 #[derive(Debug)]
 pub struct GoogleCloudStorage {
@@ -187,6 +249,47 @@ impl GoogleCloudStorage {
         Ok(futures::stream::once(async move { Ok(bytes.into()) }))
     }
 
+    /// Return the bytes in `range` of the object at the specified location. The client only
+    /// downloads whole objects, so the range is downloaded from a signed URL of the object.
+    async fn get_range(&self, location: &str, range: Range<usize>) -> InternalResult<Bytes> {
+        ensure!(range.start < range.end, InvalidRange { range });
+        let location = location.to_string();
+        let bucket_name = self.bucket_name.clone();
+
+        let url = tokio::task::spawn_blocking(move || {
+            cloud_storage::Object::read(&bucket_name, &location)?
+                .download_url(GCS_SIGNED_URL_SECONDS)
+        })
+        .await
+        .context(UnableToGetDataFromGcs)?
+        .context(UnableToGetDataFromGcs2)?;
+
+        let response = reqwest::Client::new()
+            .get(&url)
+            // the end of an HTTP range is inclusive
+            .header(
+                reqwest::header::RANGE,
+                format!("bytes={}-{}", range.start, range.end - 1),
+            )
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .context(UnableToGetRangeFromGcs)?;
+        ensure!(
+            response.status() == reqwest::StatusCode::PARTIAL_CONTENT,
+            InvalidRange { range }
+        );
+        let data = response.bytes().await.context(UnableToGetRangeFromGcs)?;
+        ensure!(
+            data.len() == range.len(),
+            DataDoesNotMatchLength {
+                expected: range.len(),
+                actual: data.len(),
+            }
+        );
+        Ok(data)
+    }
+
     /// Delete the object at the specified location.
     async fn delete(&self, location: &str) -> InternalResult<()> {
         let location = location.to_string();
@@ -296,6 +399,36 @@ impl AmazonS3 {
             .err_into())
     }
 
+    /// Return the bytes in `range` of the object at the specified location
+    async fn get_range(&self, location: &str, range: Range<usize>) -> InternalResult<Bytes> {
+        ensure!(range.start < range.end, InvalidRange { range });
+        let get_request = rusoto_s3::GetObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key: location.to_string(),
+            // the end of an HTTP range is inclusive
+            range: Some(format!("bytes={}-{}", range.start, range.end - 1)),
+            ..Default::default()
+        };
+        let data = self
+            .client
+            .get_object(get_request)
+            .await?
+            .body
+            .context(NoDataFromS3)?
+            .map_ok(|b| bytes::BytesMut::from(&b[..]))
+            .try_concat()
+            .await
+            .context(UnableToGetPieceOfDataFromS3)?;
+        ensure!(
+            data.len() == range.len(),
+            DataDoesNotMatchLength {
+                expected: range.len(),
+                actual: data.len(),
+            }
+        );
+        Ok(data.freeze())
+    }
+
     /// Delete the object at the specified location.
     async fn delete(&self, location: &str) -> InternalResult<()> {
         let delete_request = rusoto_s3::DeleteObjectRequest {
@@ -428,6 +561,18 @@ impl InMemory {
         Ok(futures::stream::once(async move { Ok(data) }))
     }
 
+    /// Return the bytes in `range` of the object at the specified location
+    async fn get_range(&self, location: &str, range: Range<usize>) -> InternalResult<Bytes> {
+        let data = self
+            .storage
+            .read()
+            .await
+            .get(location)
+            .cloned()
+            .context(NoDataInMemory)?;
+        slice_range(data, range)
+    }
+
     /// Delete the object at the specified location.
     async fn delete(&self, location: &str) -> InternalResult<()> {
         self.storage.write().await.remove(location);
@@ -491,6 +636,11 @@ impl File {
         );
 
         let path = self.path(location);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .context(UnableToCreateDirectory { path: parent })?;
+        }
         let mut file = fs::File::create(&path)
             .await
             .context(UnableToCreateFile { path })?;
@@ -518,6 +668,23 @@ impl File {
         Ok(s)
     }
 
+    /// Return the bytes in `range` of the object at the specified location
+    async fn get_range(&self, location: &str, range: Range<usize>) -> InternalResult<Bytes> {
+        let path = self.path(location);
+
+        let mut file = fs::File::open(&path)
+            .await
+            .context(UnableToOpenFile { path: &path })?;
+        let mut data = vec![0; range.len()];
+        file.seek(io::SeekFrom::Start(range.start as u64))
+            .await
+            .context(UnableToReadBytes { path: &path })?;
+        file.read_exact(&mut data)
+            .await
+            .context(UnableToReadBytes { path })?;
+        Ok(data.into())
+    }
+
     /// Delete the object at the specified location.
     async fn delete(&self, location: &str) -> InternalResult<()> {
         let path = self.path(location);
@@ -527,34 +694,56 @@ impl File {
         Ok(())
     }
 
-    /// List all the objects with the given prefix.
+    /// List all the objects with the given prefix, in the subdirectories too. The location of
+    /// a file is its path relative to the root, with `/` separating its directories.
     async fn list<'a>(
         &'a self,
         prefix: Option<&'a str>,
     ) -> InternalResult<impl Stream<Item = InternalResult<Vec<String>>> + 'a> {
-        let dirs = fs::read_dir(&self.root)
-            .await
-            .context(UnableToListDirectory { path: &self.root })?;
-
-        let s = dirs
-            .context(UnableToProcessEntry)
-            .and_then(|entry| {
+        let mut locations = vec![];
+        let mut dirs = vec![(self.root.clone(), String::new())];
+        while let Some((dir, dir_location)) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir)
+                .await
+                .context(UnableToListDirectory { path: &dir })?;
+            while let Some(entry) = entries.next().await {
+                let entry = entry.context(UnableToProcessEntry)?;
                 let name = entry
                     .file_name()
                     .into_string()
                     .ok()
-                    .context(UnableToGetFileName);
-                async move { name }
-            })
-            .try_filter(move |name| {
-                let matches = prefix.map_or(true, |p| name.starts_with(p));
-                async move { matches }
-            })
-            .map_ok(|name| vec![name]);
-        Ok(s)
+                    .context(UnableToGetFileName)?;
+                let location = format!("{}{}", dir_location, name);
+
+                let file_type = entry.file_type().await.context(UnableToProcessEntry)?;
+                if file_type.is_dir() {
+                    let location = format!("{}/", location);
+                    // the directories that can't hold objects with the prefix are skipped
+                    if prefix.map_or(true, |p| {
+                        p.starts_with(&location) || location.starts_with(p)
+                    }) {
+                        dirs.push((entry.path(), location));
+                    }
+                } else if prefix.map_or(true, |p| location.starts_with(p)) {
+                    locations.push(location);
+                }
+            }
+        }
+        locations.sort();
+
+        Ok(stream::once(async move { Ok(locations) }))
     }
 }
 
+/// Returns the bytes in `range` of `data`, which must be within it
+fn slice_range(data: Bytes, range: Range<usize>) -> InternalResult<Bytes> {
+    ensure!(
+        range.start <= range.end && range.end <= data.len(),
+        InvalidRange { range }
+    );
+    Ok(data.slice(range))
+}
+
 /// A specialized `Result` for object store-related errors
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 type InternalResult<T, E = InternalError> = std::result::Result<T, E>;
@@ -594,6 +783,10 @@ enum InternalError {
         expected: usize,
         actual: usize,
     },
+    #[snafu(display("Range {:?} is not within the object", range))]
+    InvalidRange {
+        range: Range<usize>,
+    },
 
     UnableToPutDataToGcs {
         source: tokio::task::JoinError,
@@ -616,6 +809,9 @@ enum InternalError {
     UnableToGetDataFromGcs2 {
         source: cloud_storage::Error,
     },
+    UnableToGetRangeFromGcs {
+        source: reqwest::Error,
+    },
 
     #[snafu(context(false))]
     UnableToPutDataToS3 {
@@ -647,6 +843,11 @@ enum InternalError {
     },
     NoDataInMemory,
 
+    #[snafu(display("Unable to create directory {}: {}", path.display(), source))]
+    UnableToCreateDirectory {
+        source: io::Error,
+        path: PathBuf,
+    },
     #[snafu(display("Unable to create file {}: {}", path.display(), source))]
     UnableToCreateFile {
         source: io::Error,
@@ -750,6 +951,10 @@ mod tests {
             .await?;
         assert_eq!(&*read_data, data);
 
+        let range = storage.get_range(location, 2..7).await?;
+        assert_eq!(range, data.slice(2..7));
+        assert!(storage.get_range(location, 10..20).await.is_err());
+
         storage.delete(location).await?;
 
         let content_list = flatten_list_stream(storage, None).await?;
@@ -758,6 +963,24 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn from_url() {
+        let (store, prefix) = ObjectStore::from_url("gs://bucket/some/prefix/");
+        assert!(
+            matches!(&store.0, ObjectStoreIntegration::GoogleCloudStorage(gcs) if gcs.bucket_name == "bucket")
+        );
+        assert_eq!(prefix, "some/prefix");
+
+        let (_, prefix) = ObjectStore::from_url("gs://bucket");
+        assert_eq!(prefix, "");
+
+        let (store, prefix) = ObjectStore::from_url("/tmp/export");
+        assert!(
+            matches!(&store.0, ObjectStoreIntegration::File(file) if file.root == PathBuf::from("/tmp/export"))
+        );
+        assert_eq!(prefix, "");
+    }
+
     // Tests TODO:
     // GET nonexisting location
     // DELETE nonexisting location
@@ -873,6 +1096,39 @@ mod tests {
             Ok(())
         }
 
+        #[tokio::test]
+        async fn creates_parent_directories() -> Result<()> {
+            let root = TempDir::new()?;
+            let integration = ObjectStore::new_file(File::new(root.path()));
+
+            let data = Bytes::from("arbitrary data");
+            let location = "nested/dir/test_file";
+            let stream_data = std::io::Result::Ok(data.clone());
+            integration
+                .put(
+                    location,
+                    futures::stream::once(async move { stream_data }),
+                    data.len(),
+                )
+                .await?;
+
+            let read_data = integration
+                .get(location)
+                .await?
+                .map_ok(|b| bytes::BytesMut::from(&b[..]))
+                .try_concat()
+                .await?;
+            assert_eq!(&*read_data, data);
+
+            // the objects in subdirectories are listed by their location
+            let content_list = flatten_list_stream(&integration, Some("nested/")).await?;
+            assert_eq!(content_list, &[location]);
+            let content_list = flatten_list_stream(&integration, Some("nested/other")).await?;
+            assert!(content_list.is_empty());
+
+            Ok(())
+        }
+
         #[tokio::test]
         async fn length_mismatch_is_an_error() -> Result<()> {
             let root = TempDir::new()?;
@@ -1,21 +1,33 @@
-//! This module contains code to pack values into a format suitable
-//! for feeding to the parquet writer. It is destined for replacement
-//! by an Apache Arrow based implementation
-
-// Note the maintainability of this code is not likely high (it came
-// from the copy pasta factory) but the plan is to replace it
-// soon... We'll see how long that actually takes...
+//! This module contains code to pack values into columns suitable for
+//! feeding to the parquet writer, or for handing over to Apache Arrow.
+//!
+//! A `Packer` keeps the values of its rows densely in a `Vec`, with a
+//! default value in the NULL rows, next to a validity bitmap with one bit per
+//! row. This is the layout of Arrow arrays, so columns convert to Arrow
+//! without materialising an `Option` per row, and a wide table with many
+//! NULLs takes a fraction of the memory it would as `Vec<Option<T>>`s.
 use core::iter::Iterator;
+use std::borrow::Cow;
+use std::default::Default;
 use std::iter;
-use std::slice::Chunks;
-
+use std::sync::Arc;
+
+use arrow_deps::arrow::{
+    array::{
+        Array, ArrayData, ArrayDataRef, ArrayRef, BooleanArray, BooleanBuilder, Float64Array,
+        Int64Array, StringArray, StringBuilder, UInt64Array,
+    },
+    buffer::Buffer,
+    datatypes::{DataType as ArrowDataType, ToByteSlice},
+};
 use arrow_deps::parquet::data_type::ByteArray;
-use std::default::Default;
+
+use crate::Error;
 
 // NOTE: See https://blog.twitter.com/engineering/en_us/a/2013/dremel-made-simple-with-parquet.html
 // for an explanation of nesting levels
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Packers {
     Float(Packer<f64>),
     Integer(Packer<i64>),
@@ -46,18 +58,11 @@ macro_rules! typed_packer_accessors {
 }
 
 impl<'a> Packers {
-    pub fn chunk_values(&self, chunk_size: usize) -> PackerChunker<'_> {
-        match self {
-            Self::Float(p) => PackerChunker::Float(p.values.chunks(chunk_size)),
-            Self::Integer(p) => PackerChunker::Integer(p.values.chunks(chunk_size)),
-            Self::String(p) => PackerChunker::String(p.values.chunks(chunk_size)),
-            Self::Boolean(p) => PackerChunker::Boolean(p.values.chunks(chunk_size)),
-        }
-    }
-
     /// Create a String Packers with repeated values.
     pub fn from_elem_str(v: &str, n: usize) -> Self {
-        Self::String(Packer::from(vec![ByteArray::from(v); n]))
+        let mut packer = Packer::with_capacity(n);
+        packer.fill_with(ByteArray::from(v), n);
+        Self::String(packer)
     }
 
     /// Reserves the minimum capacity for exactly additional more elements to
@@ -80,6 +85,36 @@ impl<'a> Packers {
         }
     }
 
+    /// Appends `additional` NULL rows
+    pub fn fill_with_null(&mut self, additional: usize) {
+        match self {
+            Self::Float(p) => p.fill_with_null(additional),
+            Self::Integer(p) => p.fill_with_null(additional),
+            Self::String(p) => p.fill_with_null(additional),
+            Self::Boolean(p) => p.fill_with_null(additional),
+        }
+    }
+
+    /// Returns a packer of the same type holding the rows `rows`, in that order
+    pub fn take_rows(&self, rows: &[usize]) -> Self {
+        match self {
+            Self::Float(p) => Self::Float(p.take_rows(rows)),
+            Self::Integer(p) => Self::Integer(p.take_rows(rows)),
+            Self::String(p) => Self::String(p.take_rows(rows)),
+            Self::Boolean(p) => Self::Boolean(p.take_rows(rows)),
+        }
+    }
+
+    /// Removes all the rows, keeping the type of the packer
+    pub fn clear(&mut self) {
+        match self {
+            Self::Float(p) => *p = Packer::new(),
+            Self::Integer(p) => *p = Packer::new(),
+            Self::String(p) => *p = Packer::new(),
+            Self::Boolean(p) => *p = Packer::new(),
+        }
+    }
+
     /// swap two elements within a Packers variant
     pub fn swap(&mut self, a: usize, b: usize) {
         match self {
@@ -100,6 +135,16 @@ impl<'a> Packers {
         }
     }
 
+    /// See description on `Packer::null_count`
+    pub fn null_count(&self) -> usize {
+        match self {
+            Self::Float(p) => p.null_count(),
+            Self::Integer(p) => p.null_count(),
+            Self::String(p) => p.null_count(),
+            Self::Boolean(p) => p.null_count(),
+        }
+    }
+
     /// Determines if the value for `row` is null is null.
     ///
     /// If there is no row then `is_null` returns `true`.
@@ -112,6 +157,108 @@ impl<'a> Packers {
         }
     }
 
+    /// Converts the rows into an Arrow array. The values and the validity
+    /// bitmap of numeric columns are copied over as they are.
+    pub fn to_arrow(&self) -> Result<ArrayRef, Error> {
+        Ok(match self {
+            Self::Float(p) => Arc::new(Float64Array::from(
+                p.primitive_array_data(ArrowDataType::Float64),
+            )),
+            Self::Integer(p) => Arc::new(Int64Array::from(
+                p.primitive_array_data(ArrowDataType::Int64),
+            )),
+            Self::String(p) => {
+                let mut builder = StringBuilder::new(p.num_rows());
+                for value in p.iter() {
+                    match value {
+                        Some(value) => {
+                            builder.append_value(value.as_utf8().map_err(Error::from_other)?)
+                        }
+                        None => builder.append_null(),
+                    }
+                    .map_err(Error::from_other)?;
+                }
+                Arc::new(builder.finish())
+            }
+            Self::Boolean(p) => {
+                let mut builder = BooleanBuilder::new(p.num_rows());
+                for value in p.iter() {
+                    builder
+                        .append_option(value.copied())
+                        .map_err(Error::from_other)?;
+                }
+                Arc::new(builder.finish())
+            }
+        })
+    }
+
+    /// Appends the rows of the Arrow array `array`, which must hold values of
+    /// the type of this column. Integer columns also take unsigned integers.
+    pub fn extend_from_arrow(&mut self, array: &dyn Array) -> Result<(), Error> {
+        let mismatch = |expected: &str| Error::ColumnWithMixedTypes {
+            column_name: None,
+            details: format!("expected {} values, got {:?}", expected, array.data_type()),
+        };
+        let any = array.as_any();
+
+        match self {
+            Self::Float(p) => {
+                let array = any
+                    .downcast_ref::<Float64Array>()
+                    .ok_or_else(|| mismatch("float"))?;
+                if array.null_count() == 0 {
+                    p.extend_from_slice(array.value_slice(0, array.len()));
+                } else {
+                    p.extend_from_options(
+                        (0..array.len())
+                            .map(|i| Some(array.value(i)).filter(|_| array.is_valid(i))),
+                    );
+                }
+            }
+            Self::Integer(p) => {
+                if let Some(array) = any.downcast_ref::<Int64Array>() {
+                    if array.null_count() == 0 {
+                        p.extend_from_slice(array.value_slice(0, array.len()));
+                    } else {
+                        p.extend_from_options(
+                            (0..array.len())
+                                .map(|i| Some(array.value(i)).filter(|_| array.is_valid(i))),
+                        );
+                    }
+                } else {
+                    let array = any
+                        .downcast_ref::<UInt64Array>()
+                        .ok_or_else(|| mismatch("integer"))?;
+                    p.extend_from_options(
+                        (0..array.len())
+                            .map(|i| Some(array.value(i) as i64).filter(|_| array.is_valid(i))),
+                    );
+                }
+            }
+            Self::String(p) => {
+                let array = any
+                    .downcast_ref::<StringArray>()
+                    .ok_or_else(|| mismatch("string"))?;
+                p.extend_from_options((0..array.len()).map(|i| {
+                    if array.is_valid(i) {
+                        Some(ByteArray::from(array.value(i)))
+                    } else {
+                        None
+                    }
+                }));
+            }
+            Self::Boolean(p) => {
+                let array = any
+                    .downcast_ref::<BooleanArray>()
+                    .ok_or_else(|| mismatch("boolean"))?;
+                p.extend_from_options(
+                    (0..array.len()).map(|i| Some(array.value(i)).filter(|_| array.is_valid(i))),
+                );
+            }
+        }
+        Ok(())
+    }
+
     // Implementations of all the accessors for the variants of `Packers`.
     typed_packer_accessors! {
         (f64_packer, f64_packer_mut, f64, Float),
@@ -165,15 +312,9 @@ impl std::convert::From<Vec<Option<bool>>> for Packers {
 
 impl std::convert::From<Vec<Option<u64>>> for Packers {
     fn from(values: Vec<Option<u64>>) -> Self {
-        // TODO(edd): convert this with an iterator?
-        let mut as_i64: Vec<Option<i64>> = Vec::with_capacity(values.len());
-        for v in values {
-            match v {
-                Some(v) => as_i64.push(Some(v as i64)),
-                None => as_i64.push(None),
-            }
-        }
-        Self::Integer(Packer::from(as_i64))
+        let mut packer = Packer::with_capacity(values.len());
+        packer.extend_from_options(values.into_iter().map(|v| v.map(|v| v as i64)));
+        Self::Integer(packer)
     }
 }
 
@@ -203,33 +344,103 @@ impl std::convert::From<influxdb_tsm::BlockType> for Packers {
 
 impl std::convert::From<Vec<Option<Vec<u8>>>> for Packers {
     fn from(values: Vec<Option<Vec<u8>>>) -> Self {
-        // TODO(edd): convert this with an iterator?
-        let mut as_byte_array: Vec<Option<ByteArray>> = Vec::with_capacity(values.len());
-        for v in values {
-            match v {
-                Some(v) => as_byte_array.push(Some(ByteArray::from(v))),
-                None => as_byte_array.push(None),
+        let mut packer = Packer::with_capacity(values.len());
+        packer.extend_from_options(values.into_iter().map(|v| v.map(ByteArray::from)));
+        Self::String(packer)
+    }
+}
+
+/// A bitmap with one bit per row, packed least significant bit first like
+/// the validity buffers of Arrow arrays.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Bitmap {
+    bits: Vec<u8>,
+    len: usize,
+    /// The number of unset bits
+    unset: usize,
+}
+
+impl Bitmap {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            bits: Vec::with_capacity((capacity + 7) / 8),
+            ..Self::default()
+        }
+    }
+
+    fn reserve_exact(&mut self, additional: usize) {
+        let bytes = (self.len + additional + 7) / 8;
+        self.bits
+            .reserve_exact(bytes.saturating_sub(self.bits.len()));
+    }
+
+    fn get(&self, index: usize) -> bool {
+        index < self.len && self.bits[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    fn push(&mut self, bit: bool) {
+        if self.len % 8 == 0 {
+            self.bits.push(0);
+        }
+        if bit {
+            self.bits[self.len / 8] |= 1 << (self.len % 8);
+        } else {
+            self.unset += 1;
+        }
+        self.len += 1;
+    }
+
+    /// Appends `additional` copies of `bit`, a whole byte at a time once the
+    /// last byte is full.
+    fn extend(&mut self, bit: bool, additional: usize) {
+        let mut remaining = additional;
+        while remaining > 0 && self.len % 8 != 0 {
+            self.push(bit);
+            remaining -= 1;
+        }
+
+        let bytes = remaining / 8;
+        let byte = if bit { 0xff } else { 0 };
+        self.bits.extend(iter::repeat(byte).take(bytes));
+        self.len += bytes * 8;
+        if !bit {
+            self.unset += bytes * 8;
+        }
+
+        for _ in 0..remaining % 8 {
+            self.push(bit);
+        }
+    }
+
+    fn extend_from_bitmap(&mut self, other: &Self) {
+        if self.len % 8 == 0 {
+            self.bits.extend_from_slice(&other.bits);
+            self.len += other.len;
+            self.unset += other.unset;
+        } else {
+            for index in 0..other.len {
+                self.push(other.get(index));
             }
         }
-        Self::String(Packer::from(as_byte_array))
     }
-}
 
-/// PackerChunker represents chunkable Packer variants.
-#[derive(Debug)]
-pub enum PackerChunker<'a> {
-    Float(Chunks<'a, Option<f64>>),
-    Integer(Chunks<'a, Option<i64>>),
-    String(Chunks<'a, Option<ByteArray>>),
-    Boolean(Chunks<'a, Option<bool>>),
+    fn swap(&mut self, a: usize, b: usize) {
+        if self.get(a) != self.get(b) {
+            self.bits[a / 8] ^= 1 << (a % 8);
+            self.bits[b / 8] ^= 1 << (b % 8);
+        }
+    }
 }
 
-#[derive(Debug, Default, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Packer<T>
 where
     T: Default + Clone,
 {
-    values: Vec<Option<T>>,
+    /// The values of the rows, with `T::default()` in the NULL rows
+    values: Vec<T>,
+    /// Which rows have a value
+    validity: Bitmap,
 }
 
 impl<T> Packer<T>
@@ -237,13 +448,17 @@ where
     T: Default + Clone + std::fmt::Debug,
 {
     pub fn new() -> Self {
-        Self { values: Vec::new() }
+        Self {
+            values: Vec::new(),
+            validity: Bitmap::default(),
+        }
     }
 
     /// Create a new packer with the specified capacity
     pub fn with_capacity(capacity: usize) -> Self {
         Self {
             values: Vec::with_capacity(capacity),
+            validity: Bitmap::with_capacity(capacity),
         }
     }
 
@@ -251,90 +466,148 @@ where
     /// be inserted to the `Packer<T>` without reallocation.
     pub fn reserve_exact(&mut self, additional: usize) {
         self.values.reserve_exact(additional);
+        self.validity.reserve_exact(additional);
     }
 
     /// Returns the number of logical rows represented in this
-    /// packer. This will be different than self.values.len() when
-    /// NULLs are present because there are no values in self.values
-    /// stored for NULL
+    /// packer, including the NULL rows.
     pub fn num_rows(&self) -> usize {
         self.values.len()
     }
 
+    /// Returns the number of NULL rows in this packer.
+    pub fn null_count(&self) -> usize {
+        self.validity.unset
+    }
+
     /// Get the value of logical row at `index`.
     pub fn get(&self, index: usize) -> Option<&T> {
-        self.values[index].as_ref()
+        let value = &self.values[index];
+        if self.validity.get(index) {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a packer holding the rows `rows`, in that order
+    pub fn take_rows(&self, rows: &[usize]) -> Self {
+        let mut taken = Self::with_capacity(rows.len());
+        taken.extend_from_options(rows.iter().map(|&row| self.get(row).cloned()));
+        taken
     }
 
     pub fn iter(&self) -> PackerIterator<'_, T> {
         PackerIterator::new(&self)
     }
 
-    // TODO(edd): I don't like these getters. They're only needed so we can
-    // write the data into a parquet writer. We should have a method on Packer
-    // that accepts some implementation of a trait that a parquet writer satisfies
-    // and then pass the data through in here.
-    pub fn values(&self) -> &[Option<T>] {
+    /// Returns the values of all rows, which are `T::default()` for the NULL
+    /// rows. Use `is_null` or `iter` to tell the NULL rows apart.
+    pub fn values(&self) -> &[T] {
         &self.values
     }
 
-    /// Returns an iterator that emits `chunk_size` values from the Packer until
-    /// all values are returned.
-    pub fn chunk_values(&self, chunk_size: usize) -> std::slice::Chunks<'_, Option<T>> {
-        self.values.chunks(chunk_size)
+    /// Materialises the logical values of all rows.
+    pub fn to_vec(&self) -> Vec<Option<T>> {
+        self.iter().map(|v| v.cloned()).collect()
     }
 
     /// Returns a binary vector indicating which indexes have null values.
     pub fn def_levels(&self) -> Vec<i16> {
-        self.values
-            .iter()
-            .map(|v| if v.is_some() { 1 } else { 0 })
+        (0..self.num_rows())
+            .map(|index| if self.validity.get(index) { 1 } else { 0 })
             .collect()
     }
 
     /// returns all of the non-null values in the Packer
     pub fn some_values(&self) -> Vec<T> {
-        self.values.iter().filter_map(|x| x.clone()).collect()
+        self.non_null_values().into_owned()
+    }
+
+    /// Returns all of the non-null values in the Packer, without copying them
+    /// when there are no NULL rows.
+    pub fn non_null_values(&self) -> Cow<'_, [T]> {
+        if self.null_count() == 0 {
+            Cow::Borrowed(&self.values)
+        } else {
+            Cow::Owned(self.iter().flatten().cloned().collect())
+        }
     }
 
     pub fn push_option(&mut self, value: Option<T>) {
-        self.values.push(value);
+        match value {
+            Some(value) => self.push(value),
+            None => {
+                self.values.push(T::default());
+                self.validity.push(false);
+            }
+        }
     }
 
     pub fn push(&mut self, value: T) {
-        self.push_option(Some(value));
+        self.values.push(value);
+        self.validity.push(true);
     }
 
     pub fn extend_from_packer(&mut self, other: &Self) {
         self.values.extend_from_slice(&other.values);
+        self.validity.extend_from_bitmap(&other.validity);
     }
 
     pub fn extend_from_slice(&mut self, other: &[T]) {
-        self.values.extend(other.iter().cloned().map(Some));
+        self.values.extend_from_slice(other);
+        self.validity.extend(true, other.len());
     }
 
     pub fn extend_from_option_slice(&mut self, other: &[Option<T>]) {
-        self.values.extend_from_slice(other);
+        self.extend_from_options(other.iter().cloned());
+    }
+
+    /// Appends the values of `other`, taking ownership of them.
+    pub fn extend_from_options(&mut self, other: impl IntoIterator<Item = Option<T>>) {
+        let other = other.into_iter();
+        self.reserve_exact(other.size_hint().0);
+        for value in other {
+            self.push_option(value);
+        }
     }
 
     /// Populate the Packer with additional more values of T.
     pub fn fill_with(&mut self, value: T, additional: usize) {
-        self.values
-            .extend(iter::repeat(Some(value)).take(additional));
+        self.values.extend(iter::repeat(value).take(additional));
+        self.validity.extend(true, additional);
     }
 
     pub fn fill_with_null(&mut self, additional: usize) {
-        self.values.extend(iter::repeat(None).take(additional));
+        self.values
+            .resize(self.values.len() + additional, T::default());
+        self.validity.extend(false, additional);
     }
 
     pub fn swap(&mut self, a: usize, b: usize) {
         self.values.swap(a, b);
+        self.validity.swap(a, b);
     }
 
     /// Return true if the logic value at index is null. Returns true if there
     /// is no row for index.
     pub fn is_null(&self, index: usize) -> bool {
-        self.values.get(index).map_or(true, Option::is_none)
+        !self.validity.get(index)
+    }
+
+    /// Returns the data of an Arrow array of `data_type` holding the rows,
+    /// which shares the layout of the packer.
+    fn primitive_array_data(&self, data_type: ArrowDataType) -> ArrayDataRef
+    where
+        [T]: ToByteSlice,
+    {
+        let mut builder = ArrayData::builder(data_type)
+            .len(self.num_rows())
+            .add_buffer(Buffer::from(self.values.to_byte_slice()));
+        if self.null_count() > 0 {
+            builder = builder.null_bit_buffer(Buffer::from(&self.validity.bits[..]));
+        }
+        builder.build()
     }
 }
 
@@ -369,20 +642,20 @@ where
 
         let curr_iter_i = self.iter_i;
         self.iter_i += 1;
-        Some(self.packer.values[curr_iter_i].as_ref())
+        Some(self.packer.get(curr_iter_i))
     }
 }
 
 // Convert `Vec<T>`, e.g., `Vec<f64>` into the appropriate `Packer<T>` value,
-// e.g., `Packer<f64>`.
+// e.g., `Packer<f64>`. The values are moved over as they are.
 impl<T> std::convert::From<Vec<T>> for Packer<T>
 where
     T: Default + Clone,
 {
-    fn from(v: Vec<T>) -> Self {
-        Self {
-            values: v.into_iter().map(Some).collect(),
-        }
+    fn from(values: Vec<T>) -> Self {
+        let mut validity = Bitmap::with_capacity(values.len());
+        validity.extend(true, values.len());
+        Self { values, validity }
     }
 }
 
@@ -394,9 +667,7 @@ where
 {
     fn from(values: Vec<Option<T>>) -> Self {
         let mut packer = Self::new();
-        for v in values {
-            packer.push_option(v);
-        }
+        packer.extend_from_options(values);
         packer
     }
 }
@@ -409,9 +680,7 @@ where
 {
     fn from(values: &[Option<T>]) -> Self {
         let mut packer = Self::new();
-        for v in values {
-            packer.push_option(v.clone());
-        }
+        packer.extend_from_option_slice(values);
         packer
     }
 }
@@ -424,12 +693,10 @@ mod test {
     // None values in the Packer.
     fn must_materialise_some_values<T>(p: &Packer<T>) -> Vec<T>
     where
-        T: Default + Clone,
+        T: Default + Clone + std::fmt::Debug,
     {
-        p.values
-            .iter()
-            .cloned()
-            .map(|x| x.expect("got None value"))
+        p.iter()
+            .map(|x| x.cloned().expect("got None value"))
             .collect()
     }
 
@@ -465,6 +732,15 @@ mod test {
         assert_eq!(packer_a.def_levels(), &[1; 3]);
     }
 
+    #[test]
+    fn take_rows() {
+        let packer: Packer<i64> = Packer::from(vec![Some(1), None, Some(3), Some(4)]);
+
+        let taken = packer.take_rows(&[3, 1, 0]);
+        assert_eq!(taken.to_vec(), &[Some(4), None, Some(1)]);
+        assert_eq!(taken.def_levels(), &[1, 0, 1]);
+    }
+
     #[test]
     fn pad_with_null() {
         let mut packer: Packer<i64> = Packer::new();
@@ -473,8 +749,10 @@ mod test {
 
         packer.fill_with_null(3);
 
-        assert_eq!(packer.values, &[Some(100), Some(22), None, None, None]);
+        assert_eq!(packer.to_vec(), &[Some(100), Some(22), None, None, None]);
         assert_eq!(packer.def_levels(), &[1, 1, 0, 0, 0]);
+        assert_eq!(packer.values(), &[100, 22, 0, 0, 0]);
+        assert_eq!(packer.null_count(), 3);
     }
 
     #[test]
@@ -492,6 +770,37 @@ mod test {
         assert_eq!(packer.is_null(4), true); // out of bounds
     }
 
+    #[test]
+    fn validity_bitmap() {
+        let mut packer: Packer<i64> = Packer::new();
+        packer.push(1);
+        packer.fill_with_null(20);
+        packer.fill_with(2, 19);
+        packer.push_option(None);
+        assert_eq!(packer.num_rows(), 41);
+        assert_eq!(packer.null_count(), 21);
+        assert_eq!(packer.validity.bits.len(), 6);
+
+        let mut expected = vec![Some(1)];
+        expected.extend(vec![None; 20]);
+        expected.extend(vec![Some(2); 19]);
+        expected.push(None);
+        assert_eq!(packer.to_vec(), expected);
+
+        // appending at an unaligned row copies the bits one by one
+        let other = Packer::from(vec![Some(3), None, Some(4)]);
+        packer.extend_from_packer(&other);
+        expected.extend(vec![Some(3), None, Some(4)]);
+        assert_eq!(packer.to_vec(), expected);
+        assert_eq!(packer.null_count(), 22);
+
+        packer.swap(0, 1);
+        packer.swap(2, 3);
+        expected.swap(0, 1);
+        assert_eq!(packer.to_vec(), expected);
+        assert_eq!(packer.non_null_values().len(), 22);
+    }
+
     #[test]
     fn packers_create() {
         let mut packers: Vec<Packers> = Vec::new();
@@ -511,7 +820,7 @@ mod test {
 
         assert_eq!(packer.num_rows(), 3);
         assert_eq!(
-            packer.values,
+            packer.to_vec(),
             vec![
                 Some(ByteArray::from("foo")),
                 None,
@@ -538,4 +847,41 @@ mod test {
         assert_eq!(values[3], None);
         assert_eq!(values[4], None);
     }
+
+    #[test]
+    fn arrow_round_trip() {
+        let columns = vec![
+            Packers::from(vec![Some(1.5), None, Some(-2.0)]),
+            Packers::from(vec![Some(10_i64), Some(20), None]),
+            Packers::from(vec![None, Some(true), Some(false)]),
+            Packers::from(vec![Some(b"foo".to_vec()), None, Some(b"bar".to_vec())]),
+            Packers::from(vec![1_i64, 2, 3]),
+        ];
+
+        for column in &columns {
+            let array = column.to_arrow().unwrap();
+            assert_eq!(array.len(), 3);
+            assert_eq!(array.null_count(), column.null_count());
+
+            let mut copy = match column {
+                Packers::Float(_) => Packers::Float(Packer::new()),
+                Packers::Integer(_) => Packers::Integer(Packer::new()),
+                Packers::String(_) => Packers::String(Packer::new()),
+                Packers::Boolean(_) => Packers::Boolean(Packer::new()),
+            };
+            copy.extend_from_arrow(array.as_ref()).unwrap();
+            copy.extend_from_arrow(array.as_ref()).unwrap();
+            assert_eq!(copy.num_rows(), 6);
+            for row in 0..6 {
+                assert_eq!(copy.is_null(row), column.is_null(row % 3));
+            }
+        }
+
+        let array = columns[0].to_arrow().unwrap();
+        let array = array.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(array.value(2), -2.0);
+
+        let mut strings = Packers::String(Packer::new());
+        assert!(strings.extend_from_arrow(array).is_err());
+    }
 }
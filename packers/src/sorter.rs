@@ -173,10 +173,9 @@ fn packers_sorted_asc(packers: &[Packers], len: usize, sort_by: &[usize]) -> boo
         for &idx in sort_by {
             match &packers[idx] {
                 Packers::String(p) => {
-                    let vec = p.values();
-                    if vec[i - 1] < vec[i] {
+                    if p.get(i - 1) < p.get(i) {
                         continue 'row_wise;
-                    } else if vec[i - 1] == vec[i] {
+                    } else if p.get(i - 1) == p.get(i) {
                         // try next column
                         continue;
                     } else {
@@ -184,14 +183,11 @@ fn packers_sorted_asc(packers: &[Packers], len: usize, sort_by: &[usize]) -> boo
                         return false;
                     }
                 }
-                Packers::Integer(p) => {
-                    let vec = p.values();
-                    match vec[i - 1].cmp(&vec[i]) {
-                        Ordering::Less => continue 'row_wise,
-                        Ordering::Equal => continue,
-                        Ordering::Greater => return false,
-                    }
-                }
+                Packers::Integer(p) => match p.get(i - 1).cmp(&p.get(i)) {
+                    Ordering::Less => continue 'row_wise,
+                    Ordering::Equal => continue,
+                    Ordering::Greater => return false,
+                },
                 _ => continue, // don't compare on non-string / timestamp cols
             }
         }
@@ -269,14 +265,14 @@ mod test {
 
         if let Packers::Integer(p) = &packers[0] {
             assert_eq!(
-                p.values(),
+                p.to_vec(),
                 vec![Some(200), Some(100), None, None,].as_slice()
             );
         };
 
         if let Packers::String(p) = &packers[1] {
             assert_eq!(
-                p.values(),
+                p.to_vec(),
                 vec![
                     Some(ByteArray::from("a")),
                     Some(ByteArray::from("a")),
@@ -289,7 +285,7 @@ mod test {
 
         if let Packers::String(p) = &packers[2] {
             assert_eq!(
-                p.values(),
+                p.to_vec(),
                 vec![
                     Some(ByteArray::from("cow")),
                     Some(ByteArray::from("cow")),
@@ -302,14 +298,14 @@ mod test {
 
         if let Packers::Float(p) = &packers[3] {
             assert_eq!(
-                p.values(),
+                p.to_vec(),
                 vec![Some(3.22), Some(1.23), Some(45.33), None].as_slice()
             );
         };
 
         if let Packers::Integer(p) = &packers[4] {
             assert_eq!(
-                p.values(),
+                p.to_vec(),
                 vec![Some(99), Some(100), None, Some(105),].as_slice()
             );
         };
@@ -355,7 +351,7 @@ mod test {
 
         sort(&mut packers, &[0]).unwrap();
 
-        let values = packers[0].i64_packer_mut().values();
+        let values = packers[0].i64_packer().to_vec();
 
         let exp: Vec<Option<i64>> = vec![
             Some(1588834100000000),
@@ -393,7 +389,7 @@ mod test {
 
             sort(&mut packers, &[0]).unwrap();
 
-            let values = packers[0].i64_packer_mut().values();
+            let values = packers[0].i64_packer().to_vec();
             let mut prev = values[0];
             for v in values.iter() {
                 assert!(prev <= *v);
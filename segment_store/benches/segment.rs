@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::prelude::*;
+
+use arrow_deps::arrow::array::Array;
+use segment_store::column::{cmp::Operator, AggregateType, Column, Scalar, Value, Values};
+use segment_store::segment::{ColumnType, Predicate, Segment};
+
+const ROWS: [usize; 3] = [1_000, 10_000, 100_000];
+const REGIONS: [&str; 4] = ["east", "north", "south", "west"];
+
+// Compares aggregating the rows that satisfy a predicate by materialising them
+// with `read_filter` with the fused `read_aggregate` path, which aggregates
+// the selected rows directly on the encoded columns.
+fn read_aggregate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("segment_read_aggregate");
+
+    for &num_rows in &ROWS {
+        let mut rng = thread_rng();
+        let time = (0..num_rows as i64).collect::<Vec<_>>();
+        let region = (0..num_rows)
+            .map(|_| *REGIONS.choose(&mut rng).unwrap())
+            .collect::<Vec<_>>();
+        let count = (0..num_rows)
+            .map(|_| rng.gen_range(0, 1_000_u64))
+            .collect::<Vec<_>>();
+
+        let tc = ColumnType::Time(Column::from(time.as_slice()));
+        let rc = ColumnType::Tag(Column::from(region.as_slice()));
+        let fc = ColumnType::Field(Column::from(count.as_slice()));
+        let mut columns = BTreeMap::new();
+        columns.insert("time", &tc);
+        columns.insert("region", &rc);
+        columns.insert("count", &fc);
+        let segment = Segment::new(num_rows as u32, columns);
+
+        // WHERE "region" = 'west' AND time >= 10% AND time < 90% of the rows.
+        let predicates: Vec<Predicate<'_>> = vec![
+            (
+                "time",
+                (
+                    Operator::GTE,
+                    Value::Scalar(Scalar::I64(num_rows as i64 / 10)),
+                ),
+            ),
+            (
+                "time",
+                (
+                    Operator::LT,
+                    Value::Scalar(Scalar::I64(num_rows as i64 / 10 * 9)),
+                ),
+            ),
+            ("region", (Operator::Equal, Value::String("west"))),
+        ];
+
+        group.throughput(Throughput::Elements(num_rows as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("materialise_then_aggregate", num_rows),
+            &predicates,
+            |b, predicates| {
+                b.iter(|| {
+                    let results = segment.read_filter(&["count"], predicates);
+                    match &results[0].1 {
+                        Values::U64(arr) => {
+                            let mut sum = 0;
+                            for i in 0..arr.len() {
+                                sum += arr.value(i);
+                            }
+                            sum
+                        }
+                        _ => unreachable!("count is a u64 column"),
+                    }
+                });
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("fused", num_rows),
+            &predicates,
+            |b, predicates| {
+                b.iter(|| segment.read_aggregate(predicates, &[("count", AggregateType::Sum)]));
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, read_aggregate);
+criterion_main!(benches);
@@ -1,7 +1,9 @@
+pub mod bit_packed;
 pub mod cmp;
 pub mod dictionary;
 pub mod fixed;
 pub mod fixed_null;
+pub mod xor;
 
 use std::collections::BTreeSet;
 use std::convert::TryFrom;
@@ -57,8 +59,28 @@ impl Column {
         }
     }
 
+    /// The size in bytes of the column's encoded values.
     pub fn size(&self) -> u64 {
-        0
+        match &self {
+            Column::String(meta, _) => meta.size,
+            Column::Float(meta, _) => meta.size,
+            Column::Integer(meta, _) => meta.size,
+            Column::Unsigned(meta, _) => meta.size,
+            Column::Bool => todo!(),
+            Column::ByteArray(meta, _) => meta.size,
+        }
+    }
+
+    /// The name of the physical encoding of the column's values, such as
+    /// `I64U8` for 64-bit integers stored as 8-bit unsigned integers.
+    pub fn encoding_name(&self) -> &'static str {
+        match &self {
+            Column::String(_, data) => data.name(),
+            Column::Float(_, data) => data.name(),
+            Column::Integer(_, data) | Column::Unsigned(_, data) => data.name(),
+            Column::Bool => todo!(),
+            Column::ByteArray(_, data) => data.name(),
+        }
     }
 
     /// Returns the (min, max)  values stored in this column
@@ -636,6 +658,13 @@ pub enum StringEncoding {
 /// This implementation is concerned with how to produce string columns with
 /// different encodings.
 impl StringEncoding {
+    /// The name of the encoding.
+    pub fn name(&self) -> &'static str {
+        match &self {
+            Self::RLE(_) => "RLE",
+        }
+    }
+
     /// Determines if the column contains a NULL value.
     pub fn contains_null(&self) -> bool {
         match &self {
@@ -926,6 +955,10 @@ pub enum IntegerEncoding {
     U16U8(fixed::Fixed<u8>),
     U8U8(fixed::Fixed<u8>),
 
+    // Frame-of-reference and bit-packing for i64 values that span a narrow
+    // range but don't fit a narrower physical type, such as timestamps.
+    I64BitPacked(bit_packed::BitPacked),
+
     // TODO - add all the other possible integer combinations.
 
     // Nullable encodings - TODO
@@ -933,6 +966,41 @@ pub enum IntegerEncoding {
 }
 
 impl IntegerEncoding {
+    /// The name of the encoding, which describes the logical and physical
+    /// types of the values.
+    pub fn name(&self) -> &'static str {
+        match &self {
+            Self::I64I64(_) => "I64I64",
+            Self::I64I32(_) => "I64I32",
+            Self::I64U32(_) => "I64U32",
+            Self::I64I16(_) => "I64I16",
+            Self::I64U16(_) => "I64U16",
+            Self::I64I8(_) => "I64I8",
+            Self::I64U8(_) => "I64U8",
+            Self::I32I32(_) => "I32I32",
+            Self::I32I16(_) => "I32I16",
+            Self::I32U16(_) => "I32U16",
+            Self::I32I8(_) => "I32I8",
+            Self::I32U8(_) => "I32U8",
+            Self::I16I16(_) => "I16I16",
+            Self::I16I8(_) => "I16I8",
+            Self::I16U8(_) => "I16U8",
+            Self::I8I8(_) => "I8I8",
+            Self::U64U64(_) => "U64U64",
+            Self::U64U32(_) => "U64U32",
+            Self::U64U16(_) => "U64U16",
+            Self::U64U8(_) => "U64U8",
+            Self::U32U32(_) => "U32U32",
+            Self::U32U16(_) => "U32U16",
+            Self::U32U8(_) => "U32U8",
+            Self::U16U16(_) => "U16U16",
+            Self::U16U8(_) => "U16U8",
+            Self::U8U8(_) => "U8U8",
+            Self::I64BitPacked(_) => "I64BitPacked",
+            Self::I64I64N(_) => "I64I64N",
+        }
+    }
+
     /// Determines if the column contains a NULL value.
     pub fn contains_null(&self) -> bool {
         if let Self::I64I64N(c) = &self {
@@ -949,6 +1017,7 @@ impl IntegerEncoding {
 
             // signed 64-bit variants - logical type is i64 for all these
             Self::I64I64(c) => Value::Scalar(Scalar::I64(c.value(row_id))),
+            Self::I64BitPacked(c) => Value::Scalar(Scalar::I64(c.value(row_id))),
             Self::I64I32(c) => Value::Scalar(Scalar::I64(c.value(row_id))),
             Self::I64U32(c) => Value::Scalar(Scalar::I64(c.value(row_id))),
             Self::I64I16(c) => Value::Scalar(Scalar::I64(c.value(row_id))),
@@ -1004,6 +1073,7 @@ impl IntegerEncoding {
         match &self {
             // signed 64-bit variants - logical type is i64 for all these
             Self::I64I64(c) => Values::I64(Int64Array::from(c.values::<i64>(row_ids, vec![]))),
+            Self::I64BitPacked(c) => Values::I64(Int64Array::from(c.values(row_ids, vec![]))),
             Self::I64I32(c) => Values::I64(Int64Array::from(c.values::<i64>(row_ids, vec![]))),
             Self::I64U32(c) => Values::I64(Int64Array::from(c.values::<i64>(row_ids, vec![]))),
             Self::I64I16(c) => Values::I64(Int64Array::from(c.values::<i64>(row_ids, vec![]))),
@@ -1057,6 +1127,7 @@ impl IntegerEncoding {
         match dst {
             EncodedValues::I64(dst) => match &self {
                 Self::I64I64(data) => EncodedValues::I64(data.values(row_ids, dst)),
+                Self::I64BitPacked(data) => EncodedValues::I64(data.values(row_ids, dst)),
                 Self::I64I32(data) => EncodedValues::I64(data.values(row_ids, dst)),
                 Self::I64U32(data) => EncodedValues::I64(data.values(row_ids, dst)),
                 Self::I64I16(data) => EncodedValues::I64(data.values(row_ids, dst)),
@@ -1078,6 +1149,7 @@ impl IntegerEncoding {
         match dst {
             EncodedValues::I64(dst) => match &self {
                 Self::I64I64(data) => EncodedValues::I64(data.all_values(dst)),
+                Self::I64BitPacked(data) => EncodedValues::I64(data.all_values(dst)),
                 Self::I64I32(data) => EncodedValues::I64(data.all_values(dst)),
                 Self::I64U32(data) => EncodedValues::I64(data.all_values(dst)),
                 Self::I64I16(data) => EncodedValues::I64(data.all_values(dst)),
@@ -1098,6 +1170,7 @@ impl IntegerEncoding {
     pub fn row_ids_filter(&self, op: &cmp::Operator, value: &Scalar, dst: RowIDs) -> RowIDs {
         match &self {
             Self::I64I64(c) => c.row_ids_filter(value.as_i64(), op, dst),
+            Self::I64BitPacked(c) => c.row_ids_filter(value.as_i64(), op, dst),
             Self::I64I32(c) => c.row_ids_filter(value.as_i32(), op, dst),
             Self::I64U32(c) => c.row_ids_filter(value.as_u32(), op, dst),
             Self::I64I16(c) => c.row_ids_filter(value.as_i16(), op, dst),
@@ -1142,6 +1215,9 @@ impl IntegerEncoding {
             Self::I64I64(c) => {
                 c.row_ids_filter_range((low.1.as_i64(), low.0), (high.1.as_i64(), high.0), dst)
             }
+            Self::I64BitPacked(c) => {
+                c.row_ids_filter_range((low.1.as_i64(), low.0), (high.1.as_i64(), high.0), dst)
+            }
             Self::I64I32(c) => {
                 c.row_ids_filter_range((low.1.as_i32(), low.0), (high.1.as_i32(), high.0), dst)
             }
@@ -1224,6 +1300,7 @@ impl IntegerEncoding {
     pub fn min(&self, row_ids: &[u32]) -> Value<'_> {
         match &self {
             IntegerEncoding::I64I64(c) => Value::Scalar(Scalar::I64(c.min(row_ids))),
+            IntegerEncoding::I64BitPacked(c) => Value::Scalar(Scalar::I64(c.min(row_ids))),
             IntegerEncoding::I64I32(c) => Value::Scalar(Scalar::I64(c.min(row_ids))),
             IntegerEncoding::I64U32(c) => Value::Scalar(Scalar::I64(c.min(row_ids))),
             IntegerEncoding::I64I16(c) => Value::Scalar(Scalar::I64(c.min(row_ids))),
@@ -1259,6 +1336,7 @@ impl IntegerEncoding {
     pub fn max(&self, row_ids: &[u32]) -> Value<'_> {
         match &self {
             IntegerEncoding::I64I64(c) => Value::Scalar(Scalar::I64(c.max(row_ids))),
+            IntegerEncoding::I64BitPacked(c) => Value::Scalar(Scalar::I64(c.max(row_ids))),
             IntegerEncoding::I64I32(c) => Value::Scalar(Scalar::I64(c.max(row_ids))),
             IntegerEncoding::I64U32(c) => Value::Scalar(Scalar::I64(c.max(row_ids))),
             IntegerEncoding::I64I16(c) => Value::Scalar(Scalar::I64(c.max(row_ids))),
@@ -1294,6 +1372,7 @@ impl IntegerEncoding {
     pub fn sum(&self, row_ids: &[u32]) -> Value<'_> {
         match &self {
             IntegerEncoding::I64I64(c) => Value::Scalar(Scalar::I64(c.sum(row_ids))),
+            IntegerEncoding::I64BitPacked(c) => Value::Scalar(Scalar::I64(c.sum(row_ids))),
             IntegerEncoding::I64I32(c) => Value::Scalar(Scalar::I64(c.sum(row_ids))),
             IntegerEncoding::I64U32(c) => Value::Scalar(Scalar::I64(c.sum(row_ids))),
             IntegerEncoding::I64I16(c) => Value::Scalar(Scalar::I64(c.sum(row_ids))),
@@ -1329,6 +1408,7 @@ impl IntegerEncoding {
     pub fn count(&self, row_ids: &[u32]) -> u32 {
         match &self {
             IntegerEncoding::I64I64(c) => c.count(row_ids),
+            IntegerEncoding::I64BitPacked(c) => c.count(row_ids),
             IntegerEncoding::I64I32(c) => c.count(row_ids),
             IntegerEncoding::I64U32(c) => c.count(row_ids),
             IntegerEncoding::I64I16(c) => c.count(row_ids),
@@ -1362,10 +1442,21 @@ impl IntegerEncoding {
 pub enum FloatEncoding {
     Fixed64(fixed::Fixed<f64>),
     Fixed32(fixed::Fixed<f32>),
+    // XOR compression for slowly changing f64 values.
+    Xor64(xor::Xor),
     // TODO(edd): encodings for nullable columns
 }
 
 impl FloatEncoding {
+    /// The name of the encoding.
+    pub fn name(&self) -> &'static str {
+        match &self {
+            Self::Fixed64(_) => "F64F64",
+            Self::Fixed32(_) => "F32F32",
+            Self::Xor64(_) => "F64Xor",
+        }
+    }
+
     /// Determines if the column contains a NULL value.
     pub fn contains_null(&self) -> bool {
         // TODO(edd): when adding the nullable columns then ask the nullable
@@ -1380,6 +1471,7 @@ impl FloatEncoding {
             // `c.value` should return.
             Self::Fixed64(c) => Value::Scalar(Scalar::F64(c.value(row_id))),
             Self::Fixed32(c) => Value::Scalar(Scalar::F32(c.value(row_id))),
+            Self::Xor64(c) => Value::Scalar(Scalar::F64(c.value(row_id))),
         }
     }
 
@@ -1390,6 +1482,7 @@ impl FloatEncoding {
         match &self {
             Self::Fixed64(c) => Values::F64(Float64Array::from(c.values::<f64>(row_ids, vec![]))),
             Self::Fixed32(c) => Values::F32(Float32Array::from(c.values::<f32>(row_ids, vec![]))),
+            Self::Xor64(c) => Values::F64(Float64Array::from(c.values(row_ids, vec![]))),
         }
     }
 
@@ -1402,6 +1495,7 @@ impl FloatEncoding {
         match &self {
             FloatEncoding::Fixed64(c) => c.row_ids_filter(value.as_f64(), op, dst),
             FloatEncoding::Fixed32(c) => c.row_ids_filter(value.as_f32(), op, dst),
+            FloatEncoding::Xor64(c) => c.row_ids_filter(value.as_f64(), op, dst),
         }
     }
 
@@ -1423,6 +1517,9 @@ impl FloatEncoding {
             FloatEncoding::Fixed32(c) => {
                 c.row_ids_filter_range((low.1.as_f32(), &low.0), (high.1.as_f32(), &high.0), dst)
             }
+            FloatEncoding::Xor64(c) => {
+                c.row_ids_filter_range((low.1.as_f64(), &low.0), (high.1.as_f64(), &high.0), dst)
+            }
         }
     }
 
@@ -1430,6 +1527,7 @@ impl FloatEncoding {
         match &self {
             FloatEncoding::Fixed64(c) => Value::Scalar(Scalar::F64(c.min(row_ids))),
             FloatEncoding::Fixed32(c) => Value::Scalar(Scalar::F32(c.min(row_ids))),
+            FloatEncoding::Xor64(c) => Value::Scalar(Scalar::F64(c.min(row_ids))),
         }
     }
 
@@ -1437,6 +1535,7 @@ impl FloatEncoding {
         match &self {
             FloatEncoding::Fixed64(c) => Value::Scalar(Scalar::F64(c.max(row_ids))),
             FloatEncoding::Fixed32(c) => Value::Scalar(Scalar::F32(c.max(row_ids))),
+            FloatEncoding::Xor64(c) => Value::Scalar(Scalar::F64(c.max(row_ids))),
         }
     }
 
@@ -1444,6 +1543,7 @@ impl FloatEncoding {
         match &self {
             FloatEncoding::Fixed64(c) => Value::Scalar(Scalar::F64(c.sum(row_ids))),
             FloatEncoding::Fixed32(c) => Value::Scalar(Scalar::F32(c.sum(row_ids))),
+            FloatEncoding::Xor64(c) => Value::Scalar(Scalar::F64(c.sum(row_ids))),
         }
     }
 
@@ -1451,6 +1551,7 @@ impl FloatEncoding {
         match &self {
             FloatEncoding::Fixed64(c) => c.count(row_ids),
             FloatEncoding::Fixed32(c) => c.count(row_ids),
+            FloatEncoding::Xor64(c) => c.count(row_ids),
         }
     }
 }
@@ -1655,7 +1756,8 @@ impl From<&[u8]> for Column {
 }
 
 /// Converts a slice of i64 values into the most compact fixed-width physical
-/// encoding.
+/// encoding, or a bit-packed encoding for values that span a narrow range but
+/// need a wide physical type.
 impl From<&[i64]> for Column {
     fn from(arr: &[i64]) -> Self {
         // determine min and max values.
@@ -1666,6 +1768,21 @@ impl From<&[i64]> for Column {
             max = max.max(v);
         }
 
+        // Values that fit in 32 bits are fastest to read from a narrower
+        // fixed-width type. Otherwise bit-pack the values if that saves at
+        // least a quarter of the space.
+        let fits_32_bits = (min >= 0 && max <= u32::MAX as i64)
+            || (min >= i32::MIN as i64 && max <= i32::MAX as i64);
+        if !fits_32_bits && bit_packed::BitPacked::bit_width(min, max) <= 48 {
+            let data = bit_packed::BitPacked::from(arr);
+            let meta = MetaData {
+                size: data.size(),
+                rows: data.num_rows(),
+                range: Some((min, max)),
+            };
+            return Column::Integer(meta, IntegerEncoding::I64BitPacked(data));
+        }
+
         // This match is carefully ordered. It prioritises smaller physical
         // datatypes that can safely represent the provided logical data type.
         match (min, max) {
@@ -1929,7 +2046,8 @@ impl From<arrow::array::Int64Array> for Column {
     }
 }
 
-/// Converts a slice of `f64` values into a fixed-width column encoding.
+/// Converts a slice of `f64` values into an XOR compressed column encoding
+/// when that is much smaller than a fixed-width encoding.
 impl From<&[f64]> for Column {
     fn from(arr: &[f64]) -> Self {
         // determine min and max values.
@@ -1940,7 +2058,19 @@ impl From<&[f64]> for Column {
             max = max.max(v);
         }
 
+        // Decoding XOR compressed values is slower than reading fixed-width
+        // ones, so it's only worth it if it at least halves the size.
         let data = fixed::Fixed::<f64>::from(arr);
+        let xor_data = xor::Xor::from(arr);
+        if xor_data.size() * 2 <= data.size() {
+            let meta = MetaData {
+                size: xor_data.size(),
+                rows: xor_data.num_rows(),
+                range: Some((min, max)),
+            };
+            return Column::Float(meta, FloatEncoding::Xor64(xor_data));
+        }
+
         let meta = MetaData {
             size: data.size(),
             rows: data.num_rows(),
@@ -1975,6 +2105,7 @@ impl From<&[f32]> for Column {
 
 /// These variants describe supported aggregates that can applied to columnar
 /// data.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AggregateType {
     Count,
     First,
@@ -1990,6 +2121,7 @@ pub enum AggregateType {
 
 /// These variants hold aggregates, which are the results of applying aggregates
 /// to column data.
+#[derive(Debug, PartialEq)]
 pub enum AggregateResult<'a> {
     // Any type of column can have rows counted. NULL values do not contribute
     // to the count. If all rows are NULL then count will be `0`.
@@ -2220,6 +2352,27 @@ impl Values {
         self.len() == 0
     }
 
+    /// Returns `len` NULL values of the same type as these values. Used to
+    /// materialise a column for rows that were written before the column
+    /// existed.
+    pub fn nulls_like(&self, len: usize) -> Self {
+        match &self {
+            Values::String(_) => Values::String(vec![None::<&str>; len].into()),
+            Values::F64(_) => Values::F64(vec![None; len].into()),
+            Values::F32(_) => Values::F32(vec![None; len].into()),
+            Values::I64(_) => Values::I64(vec![None; len].into()),
+            Values::I32(_) => Values::I32(vec![None; len].into()),
+            Values::I16(_) => Values::I16(vec![None; len].into()),
+            Values::I8(_) => Values::I8(vec![None; len].into()),
+            Values::U64(_) => Values::U64(vec![None; len].into()),
+            Values::U32(_) => Values::U32(vec![None; len].into()),
+            Values::U16(_) => Values::U16(vec![None; len].into()),
+            Values::U8(_) => Values::U8(vec![None; len].into()),
+            Values::Bool(_) => Values::Bool(vec![None; len].into()),
+            Values::ByteArray(_) => Values::ByteArray(vec![None::<&[u8]>; len].into()),
+        }
+    }
+
     pub fn value(&self, i: usize) -> Value<'_> {
         match &self {
             Values::String(c) => {
@@ -2627,6 +2780,54 @@ mod test {
         }
     }
 
+    #[test]
+    fn from_i64_slice_bit_packed() {
+        // an hour of nanosecond timestamps at one second intervals.
+        let input = (0..3600)
+            .map(|i| 1_600_000_000_000_000_000 + i * 1_000_000_000)
+            .collect::<Vec<i64>>();
+        let col = Column::from(&input[..]);
+        if let Column::Integer(meta, IntegerEncoding::I64BitPacked(_)) = &col {
+            assert_eq!(meta.rows, 3600);
+            // 42 bits rather than 64 bits per value
+            assert!(meta.size < 3600 * 6);
+        } else {
+            panic!("invalid variant");
+        }
+        assert_eq!(col.encoding_name(), "I64BitPacked");
+        assert_eq!(
+            col.value(3599),
+            Value::Scalar(Scalar::I64(1_600_003_599_000_000_000))
+        );
+        assert_eq!(
+            col.row_ids_filter(
+                &cmp::Operator::GTE,
+                &Value::Scalar(Scalar::I64(1_600_003_598_000_000_000)),
+                RowIDs::new_vector()
+            )
+            .unwrap()
+            .to_vec(),
+            vec![3598, 3599]
+        );
+    }
+
+    #[test]
+    fn from_f64_slice_xor() {
+        let input = (0..1000)
+            .map(|i| (i / 100) as f64 * 0.5)
+            .collect::<Vec<f64>>();
+        let col = Column::from(&input[..]);
+        assert!(matches!(col, Column::Float(_, FloatEncoding::Xor64(_))));
+        assert_eq!(col.encoding_name(), "F64Xor");
+        assert_eq!(col.value(999), Value::Scalar(Scalar::F64(4.5)));
+        assert_eq!(col.max(&[0, 500, 999]), Value::Scalar(Scalar::F64(4.5)));
+
+        // values that change all the time don't compress well.
+        let input = (0..1000).map(|i| i as f64 / 7.0).collect::<Vec<f64>>();
+        let col = Column::from(&input[..]);
+        assert!(matches!(col, Column::Float(_, FloatEncoding::Fixed64(_))));
+    }
+
     #[test]
     fn from_i32_slice() {
         let input = &[-1, i8::MAX as i32];
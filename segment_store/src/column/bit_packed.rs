@@ -0,0 +1,302 @@
+//! An encoding for non-nullable 64-bit integers using frame-of-reference and
+//! bit-packing.
+//!
+//! Each value is stored as its (unsigned) distance from the smallest value in
+//! the column, the "frame of reference", using only as many bits as are needed
+//! to represent the largest distance. Metric and timestamp columns often have
+//! large values that fall within a comparatively small range. For example a
+//! column of nanosecond timestamps covering an hour needs 42 bits per value
+//! rather than 64, and a counter that moves between 1_000_000 and 1_001_000
+//! needs just 10.
+//!
+//! Values are packed contiguously into a vector of words, so any value can be
+//! decoded in constant time.
+use std::mem::size_of;
+
+use crate::column::{cmp, fixed::Fixed, RowIDs};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct BitPacked {
+    // the smallest value in the column, which all others are relative to.
+    base: i64,
+
+    // the number of bits used to store each value. Zero when all values are
+    // equal to `base`.
+    width: u32,
+
+    // the number of values in the column.
+    rows: u32,
+
+    // the packed values, least significant bits first.
+    words: Vec<u64>,
+}
+
+impl std::fmt::Display for BitPacked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[BitPacked] width: {}, rows: {:?}, size: {}",
+            self.width,
+            self.num_rows(),
+            self.size()
+        )
+    }
+}
+
+impl BitPacked {
+    pub fn num_rows(&self) -> u32 {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    /// Returns the total size in bytes of the encoded data. Like `Fixed`, it
+    /// doesn't include the size of the `BitPacked` struct receiver.
+    pub fn size(&self) -> u64 {
+        (size_of::<Vec<u64>>() + size_of::<u64>() * self.words.len() + size_of::<i64>()) as u64
+    }
+
+    /// The number of bits needed to store each of the provided values with
+    /// this encoding.
+    pub fn bit_width(min: i64, max: i64) -> u32 {
+        64 - (max.wrapping_sub(min) as u64).leading_zeros()
+    }
+
+    /// Returns the logical value present at the provided row id.
+    pub fn value(&self, row_id: u32) -> i64 {
+        assert!(row_id < self.rows, "row {} out of bounds", row_id);
+        let delta = read_bits(
+            &self.words,
+            u64::from(row_id) * u64::from(self.width),
+            self.width,
+        );
+        self.base.wrapping_add(delta as i64)
+    }
+
+    /// Returns the logical (decoded) values for the provided row IDs.
+    pub fn values(&self, row_ids: &[u32], mut dst: Vec<i64>) -> Vec<i64> {
+        dst.clear();
+        dst.reserve(row_ids.len());
+        dst.extend(row_ids.iter().map(|&row_id| self.value(row_id)));
+        dst
+    }
+
+    /// Returns the logical (decoded) values for all the rows in the column.
+    pub fn all_values(&self, mut dst: Vec<i64>) -> Vec<i64> {
+        dst.clear();
+        dst.reserve(self.rows as usize);
+        dst.extend((0..self.rows).map(|row_id| self.value(row_id)));
+        dst
+    }
+
+    /// Returns the count of the values for the provided row IDs. This encoding
+    /// cannot have NULL values.
+    pub fn count(&self, row_ids: &[u32]) -> u32 {
+        row_ids.len() as u32
+    }
+
+    /// Returns the summation of the logical values for the provided row IDs.
+    pub fn sum(&self, row_ids: &[u32]) -> i64 {
+        row_ids.iter().map(|&row_id| self.value(row_id)).sum()
+    }
+
+    /// Returns the minimum logical value for the provided row IDs.
+    pub fn min(&self, row_ids: &[u32]) -> i64 {
+        row_ids
+            .iter()
+            .map(|&row_id| self.value(row_id))
+            .min()
+            .expect("no row ids provided")
+    }
+
+    /// Returns the maximum logical value for the provided row IDs.
+    pub fn max(&self, row_ids: &[u32]) -> i64 {
+        row_ids
+            .iter()
+            .map(|&row_id| self.value(row_id))
+            .max()
+            .expect("no row ids provided")
+    }
+
+    /// Returns the set of row ids that satisfy a binary operator on a logical
+    /// value.
+    //
+    // TODO(edd): evaluate predicates on the packed deltas rather than
+    // materialising the column.
+    pub fn row_ids_filter(&self, value: i64, op: &cmp::Operator, dst: RowIDs) -> RowIDs {
+        self.decoded().row_ids_filter(value, op, dst)
+    }
+
+    /// Returns the set of row ids that satisfy a pair of binary operators on
+    /// logical values.
+    pub fn row_ids_filter_range(
+        &self,
+        left: (i64, &cmp::Operator),
+        right: (i64, &cmp::Operator),
+        dst: RowIDs,
+    ) -> RowIDs {
+        self.decoded().row_ids_filter_range(left, right, dst)
+    }
+
+    fn decoded(&self) -> Fixed<i64> {
+        Fixed::from(self.all_values(vec![]).as_slice())
+    }
+}
+
+impl From<&[i64]> for BitPacked {
+    fn from(v: &[i64]) -> Self {
+        let base = v.iter().copied().min().unwrap_or_default();
+        let max = v.iter().copied().max().unwrap_or_default();
+        let width = Self::bit_width(base, max);
+
+        let mut writer = BitWriter::default();
+        for &x in v {
+            writer.write(x.wrapping_sub(base) as u64, width);
+        }
+
+        Self {
+            base,
+            width,
+            rows: v.len() as u32,
+            words: writer.finish(),
+        }
+    }
+}
+
+/// Appends values of arbitrary bit widths to a vector of words, least
+/// significant bits first.
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    words: Vec<u64>,
+    len: u64,
+}
+
+impl BitWriter {
+    /// Appends the low `n` bits of `value`, where `n <= 64`.
+    pub fn write(&mut self, value: u64, n: u32) {
+        if n == 0 {
+            return;
+        }
+        let value = value & mask(n);
+
+        let word = (self.len / 64) as usize;
+        let offset = (self.len % 64) as u32;
+        if word == self.words.len() {
+            self.words.push(0);
+        }
+        self.words[word] |= value << offset;
+        if offset + n > 64 {
+            self.words.push(value >> (64 - offset));
+        }
+
+        self.len += u64::from(n);
+    }
+
+    /// The number of bits written so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn finish(mut self) -> Vec<u64> {
+        self.words.shrink_to_fit();
+        self.words
+    }
+}
+
+/// Reads `n` bits, where `n <= 64`, starting at bit `pos` of words written by a
+/// `BitWriter`.
+pub fn read_bits(words: &[u64], pos: u64, n: u32) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+
+    let word = (pos / 64) as usize;
+    let offset = (pos % 64) as u32;
+    let mut v = words[word] >> offset;
+    if offset + n > 64 {
+        v |= words[word + 1] << (64 - offset);
+    }
+    v & mask(n)
+}
+
+fn mask(n: u32) -> u64 {
+    if n == 64 {
+        u64::MAX
+    } else {
+        (1 << n) - 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::cmp::Operator;
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let cases = vec![
+            vec![
+                1_600_000_000_000_000_000_i64,
+                1_600_000_000_000_000_010,
+                1_600_000_000_000_000_005,
+            ],
+            vec![i64::MIN, i64::MAX, 0, -1],
+            vec![42, 42, 42],
+            vec![-3, 7],
+        ];
+
+        for input in cases {
+            let v = BitPacked::from(input.as_slice());
+            assert_eq!(v.num_rows(), input.len() as u32);
+            assert_eq!(v.all_values(vec![]), input);
+            for (i, &x) in input.iter().enumerate() {
+                assert_eq!(v.value(i as u32), x);
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_narrow_ranges() {
+        let input = (0..1000)
+            .map(|i| 1_600_000_000_000_000_000 + i * 10)
+            .collect::<Vec<i64>>();
+        let v = BitPacked::from(input.as_slice());
+        assert_eq!(v.width, 14);
+        // 1000 14-bit values fit in 219 words
+        assert_eq!(v.words.len(), 219);
+        assert!(v.size() * 4 < Fixed::<i64>::from(input.as_slice()).size());
+
+        // a column of a single repeated value needs no words at all.
+        let v = BitPacked::from(&[7_i64; 100][..]);
+        assert_eq!(v.width, 0);
+        assert!(v.words.is_empty());
+        assert_eq!(v.values(&[0, 99], vec![]), vec![7, 7]);
+    }
+
+    #[test]
+    fn aggregates_and_filters() {
+        let v = BitPacked::from(&[100_i64, 101, 200, 203, 203, 10][..]);
+
+        assert_eq!(v.values(&[0, 2, 5], vec![]), vec![100, 200, 10]);
+        assert_eq!(v.sum(&[0, 1, 5]), 211);
+        assert_eq!(v.min(&[0, 1, 2]), 100);
+        assert_eq!(v.max(&[0, 1, 3]), 203);
+        assert_eq!(v.count(&[0, 1]), 2);
+
+        let row_ids = v.row_ids_filter(203, &Operator::Equal, RowIDs::new_vector());
+        assert_eq!(row_ids.to_vec(), vec![3, 4]);
+
+        let row_ids = v.row_ids_filter_range(
+            (100, &Operator::GT),
+            (203, &Operator::LT),
+            RowIDs::new_vector(),
+        );
+        assert_eq!(row_ids.to_vec(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,297 @@
+//! An encoding for non-nullable 64-bit floats based on the XOR compression
+//! described in Facebook's Gorilla paper.
+//!
+//! Each value is XORed with the value before it. Consecutive values in metrics
+//! data are often equal or close together, so the result usually has long runs
+//! of leading and trailing zeros. An identical value is stored as a single bit
+//! and otherwise only the "meaningful" bits between the zeros are stored.
+//!
+//! Decoding is sequential, so the values are split into blocks that are each
+//! encoded independently. Reading a single value only needs to decode (part
+//! of) one block.
+use std::mem::size_of;
+
+use crate::column::{
+    bit_packed::{read_bits, BitWriter},
+    cmp,
+    fixed::Fixed,
+    RowIDs,
+};
+
+// The number of values in each independently decodable block.
+const BLOCK_SIZE: usize = 128;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Xor {
+    // the bit offset of the start of each block in `words`.
+    block_offsets: Vec<u64>,
+
+    // the number of values in the column.
+    rows: u32,
+
+    // the encoded values.
+    words: Vec<u64>,
+}
+
+impl std::fmt::Display for Xor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[Xor] rows: {:?}, size: {}",
+            self.num_rows(),
+            self.size()
+        )
+    }
+}
+
+impl Xor {
+    pub fn num_rows(&self) -> u32 {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    /// Returns the total size in bytes of the encoded data. Like `Fixed`, it
+    /// doesn't include the size of the `Xor` struct receiver.
+    pub fn size(&self) -> u64 {
+        (size_of::<Vec<u64>>() * 2
+            + size_of::<u64>() * (self.words.len() + self.block_offsets.len())) as u64
+    }
+
+    /// Returns the logical value present at the provided row id.
+    pub fn value(&self, row_id: u32) -> f64 {
+        assert!(row_id < self.rows, "row {} out of bounds", row_id);
+        let row_id = row_id as usize;
+
+        let mut value = 0.0;
+        self.decode_block(row_id / BLOCK_SIZE, row_id % BLOCK_SIZE + 1, |v| value = v);
+        value
+    }
+
+    /// Returns the logical (decoded) values for the provided row IDs.
+    pub fn values(&self, row_ids: &[u32], mut dst: Vec<f64>) -> Vec<f64> {
+        dst.clear();
+        dst.reserve(row_ids.len());
+
+        // row ids are usually ordered, so each block is typically decoded once.
+        let mut block = vec![];
+        let mut current_block = None;
+        for &row_id in row_ids {
+            let row_id = row_id as usize;
+            let block_id = row_id / BLOCK_SIZE;
+            if current_block != Some(block_id) {
+                block.clear();
+                self.decode_block(block_id, BLOCK_SIZE, |v| block.push(v));
+                current_block = Some(block_id);
+            }
+            dst.push(block[row_id % BLOCK_SIZE]);
+        }
+        dst
+    }
+
+    /// Returns the logical (decoded) values for all the rows in the column.
+    pub fn all_values(&self, mut dst: Vec<f64>) -> Vec<f64> {
+        dst.clear();
+        dst.reserve(self.rows as usize);
+        for block_id in 0..self.block_offsets.len() {
+            self.decode_block(block_id, BLOCK_SIZE, |v| dst.push(v));
+        }
+        dst
+    }
+
+    /// Returns the count of the values for the provided row IDs. This encoding
+    /// cannot have NULL values.
+    pub fn count(&self, row_ids: &[u32]) -> u32 {
+        row_ids.len() as u32
+    }
+
+    /// Returns the summation of the logical values for the provided row IDs.
+    pub fn sum(&self, row_ids: &[u32]) -> f64 {
+        self.values(row_ids, vec![]).iter().sum()
+    }
+
+    /// Returns the minimum logical value for the provided row IDs.
+    pub fn min(&self, row_ids: &[u32]) -> f64 {
+        self.values(row_ids, vec![])
+            .into_iter()
+            .fold(f64::NAN, f64::min)
+    }
+
+    /// Returns the maximum logical value for the provided row IDs.
+    pub fn max(&self, row_ids: &[u32]) -> f64 {
+        self.values(row_ids, vec![])
+            .into_iter()
+            .fold(f64::NAN, f64::max)
+    }
+
+    /// Returns the set of row ids that satisfy a binary operator on a logical
+    /// value.
+    pub fn row_ids_filter(&self, value: f64, op: &cmp::Operator, dst: RowIDs) -> RowIDs {
+        self.decoded().row_ids_filter(value, op, dst)
+    }
+
+    /// Returns the set of row ids that satisfy a pair of binary operators on
+    /// logical values.
+    pub fn row_ids_filter_range(
+        &self,
+        left: (f64, &cmp::Operator),
+        right: (f64, &cmp::Operator),
+        dst: RowIDs,
+    ) -> RowIDs {
+        self.decoded().row_ids_filter_range(left, right, dst)
+    }
+
+    fn decoded(&self) -> Fixed<f64> {
+        Fixed::from(self.all_values(vec![]).as_slice())
+    }
+
+    // Decodes up to `n` values of the block, calling `f` with each one.
+    fn decode_block(&self, block_id: usize, n: usize, mut f: impl FnMut(f64)) {
+        let mut pos = self.block_offsets[block_id];
+        let mut read = |bits: u32| {
+            let v = read_bits(&self.words, pos, bits);
+            pos += u64::from(bits);
+            v
+        };
+
+        let rows = (self.rows as usize - block_id * BLOCK_SIZE)
+            .min(BLOCK_SIZE)
+            .min(n);
+        if rows == 0 {
+            return;
+        }
+
+        let mut prev = read(64);
+        f(f64::from_bits(prev));
+
+        let (mut leading, mut trailing) = (0, 0);
+        for _ in 1..rows {
+            if read(1) == 1 {
+                if read(1) == 1 {
+                    leading = read(5) as u32;
+                    let meaningful = match read(6) as u32 {
+                        0 => 64,
+                        n => n,
+                    };
+                    trailing = 64 - leading - meaningful;
+                }
+                prev ^= read(64 - leading - trailing) << trailing;
+            }
+            f(f64::from_bits(prev));
+        }
+    }
+}
+
+impl From<&[f64]> for Xor {
+    fn from(v: &[f64]) -> Self {
+        let mut writer = BitWriter::default();
+        let mut block_offsets = Vec::with_capacity(v.len() / BLOCK_SIZE + 1);
+
+        for block in v.chunks(BLOCK_SIZE) {
+            block_offsets.push(writer.len());
+
+            let mut prev = block[0].to_bits();
+            writer.write(prev, 64);
+
+            // the window of meaningful bits of the previous value; the first
+            // value in a block always describes its own window.
+            let (mut prev_leading, mut prev_trailing) = (u32::MAX, u32::MAX);
+            for x in &block[1..] {
+                let bits = x.to_bits();
+                let xor = bits ^ prev;
+                prev = bits;
+
+                if xor == 0 {
+                    writer.write(0, 1);
+                    continue;
+                }
+                writer.write(1, 1);
+
+                // the leading zero count is stored in 5 bits.
+                let leading = xor.leading_zeros().min(31);
+                let trailing = xor.trailing_zeros();
+                if leading >= prev_leading && trailing >= prev_trailing {
+                    // the meaningful bits fit in the previous window.
+                    writer.write(0, 1);
+                    writer.write(xor >> prev_trailing, 64 - prev_leading - prev_trailing);
+                } else {
+                    let meaningful = 64 - leading - trailing;
+                    writer.write(1, 1);
+                    writer.write(u64::from(leading), 5);
+                    // a meaningful length of 64 is stored as zero.
+                    writer.write(u64::from(meaningful % 64), 6);
+                    writer.write(xor >> trailing, meaningful);
+                    prev_leading = leading;
+                    prev_trailing = trailing;
+                }
+            }
+        }
+
+        Self {
+            block_offsets,
+            rows: v.len() as u32,
+            words: writer.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::cmp::Operator;
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut input = vec![1.0, 1.0, 1.5, -2.25, f64::MAX, f64::MIN, 0.0, -0.0];
+        input.extend((0..300).map(|i| 20.0 + (i % 7) as f64 * 0.5));
+        input.push(f64::INFINITY);
+
+        let v = Xor::from(input.as_slice());
+        assert_eq!(v.num_rows(), input.len() as u32);
+        assert_eq!(v.block_offsets.len(), 3);
+
+        let decoded = v.all_values(vec![]);
+        for (i, (a, b)) in input.iter().zip(decoded.iter()).enumerate() {
+            assert_eq!(a.to_bits(), b.to_bits(), "row {}", i);
+            assert_eq!(a.to_bits(), v.value(i as u32).to_bits(), "row {}", i);
+        }
+
+        assert_eq!(
+            v.values(&[0, 2, 129, 300], vec![]),
+            vec![input[0], input[2], input[129], input[300]]
+        );
+    }
+
+    #[test]
+    fn compresses_slowly_changing_values() {
+        // a gauge that mostly repeats its previous value.
+        let input = (0..1024)
+            .map(|i| 99.5 + (i / 16) as f64)
+            .collect::<Vec<f64>>();
+        let v = Xor::from(input.as_slice());
+        assert_eq!(v.all_values(vec![]), input);
+        assert!(v.size() * 5 < Fixed::<f64>::from(input.as_slice()).size());
+    }
+
+    #[test]
+    fn aggregates_and_filters() {
+        let v = Xor::from(&[100.0, 101.0, 200.0, 203.5, 203.5, 10.0][..]);
+
+        assert!((v.sum(&[0, 1, 5]) - 211.0).abs() < f64::EPSILON);
+        assert!((v.min(&[0, 1, 2]) - 100.0).abs() < f64::EPSILON);
+        assert!((v.max(&[0, 1, 3]) - 203.5).abs() < f64::EPSILON);
+        assert_eq!(v.count(&[0, 1]), 2);
+
+        let row_ids = v.row_ids_filter(203.5, &Operator::Equal, RowIDs::new_vector());
+        assert_eq!(row_ids.to_vec(), vec![3, 4]);
+
+        let row_ids = v.row_ids_filter_range(
+            (100.0, &Operator::GTE),
+            (203.5, &Operator::LT),
+            RowIDs::new_vector(),
+        );
+        assert_eq!(row_ids.to_vec(), vec![0, 1, 2]);
+    }
+}
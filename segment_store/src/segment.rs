@@ -2,7 +2,10 @@ use std::collections::BTreeMap;
 
 use arrow_deps::arrow::datatypes::SchemaRef;
 
-use crate::column::{cmp::Operator, Column, RowIDs, RowIDsOption, Scalar, Value, Values};
+use crate::column::{
+    cmp::Operator, AggregateResult, AggregateType, Column, RowIDs, RowIDsOption, Scalar, Value,
+    Values,
+};
 
 /// The name used for a timestamp column.
 pub const TIME_COLUMN_NAME: &str = data_types::TIME_COLUMN_NAME;
@@ -158,46 +161,169 @@ impl<'a> Segment<'a> {
         &self,
         columns: &[ColumnName<'a>],
         predicates: &[Predicate<'_>],
+    ) -> Vec<(ColumnName<'a>, Values)> {
+        self.read_filter_with_nulls(columns, predicates, &BTreeMap::new())
+    }
+
+    /// Returns a set of materialised column values that satisfy a set of
+    /// predicates, as `read_filter` does. Columns that the segment doesn't
+    /// have, because they were added to the table after the segment was
+    /// created, are materialised as NULLs of the type of their (empty) values
+    /// in `column_types`.
+    pub fn read_filter_with_nulls(
+        &self,
+        columns: &[ColumnName<'a>],
+        predicates: &[Predicate<'_>],
+        column_types: &BTreeMap<String, Values>,
     ) -> Vec<(ColumnName<'a>, Values)> {
         let row_ids = self.row_ids_from_predicates(predicates);
-        self.materialise_rows(columns, row_ids)
+        self.materialise_rows(columns, row_ids, column_types)
     }
 
     fn materialise_rows(
         &self,
         columns: &[ColumnName<'a>],
         row_ids: RowIDsOption,
+        column_types: &BTreeMap<String, Values>,
     ) -> Vec<(ColumnName<'a>, Values)> {
-        let mut results = vec![];
-        match row_ids {
-            RowIDsOption::None(_) => results, // nothing to materialise
-            RowIDsOption::Some(row_ids) => {
-                // TODO(edd): causes an allocation. Implement a way to pass a pooled
-                // buffer to the croaring Bitmap API.
-                let row_ids = row_ids.to_vec();
+        let row_ids = match row_ids {
+            RowIDsOption::None(_) => return vec![], // nothing to materialise
 
-                for col_name in columns {
-                    let col = self.all_columns.get(*col_name).unwrap();
-                    results.push((*col_name, col.values(row_ids.as_slice())));
-                }
-                results
-            }
+            // TODO(edd): causes an allocation. Implement a way to pass a pooled
+            // buffer to the croaring Bitmap API.
+            RowIDsOption::Some(row_ids) => row_ids.to_vec(),
 
-            RowIDsOption::All(_) => {
-                // TODO(edd): Perf - add specialised method to get all
-                // materialised values from a column without having to
-                // materialise a vector of row ids.......
-                let row_ids = (0..self.rows()).collect::<Vec<_>>();
+            // TODO(edd): Perf - add specialised method to get all
+            // materialised values from a column without having to
+            // materialise a vector of row ids.......
+            RowIDsOption::All(_) => (0..self.rows()).collect::<Vec<_>>(),
+        };
 
-                for col_name in columns {
-                    let col = self.all_columns.get(*col_name).unwrap();
-                    results.push((*col_name, col.values(row_ids.as_slice())));
-                }
-                results
+        columns
+            .iter()
+            .map(|col_name| {
+                let values = match self.all_columns.get(*col_name) {
+                    Some(col) => col.values(row_ids.as_slice()),
+                    None => column_types
+                        .get(*col_name)
+                        .unwrap_or_else(|| panic!("column {} not found", col_name))
+                        .nulls_like(row_ids.len()),
+                };
+                (*col_name, values)
+            })
+            .collect()
+    }
+
+    /// Returns the aggregates of the values of columns in the rows that satisfy
+    /// a set of predicates.
+    ///
+    /// The predicates are evaluated into a selection of row ids and each
+    /// aggregate is computed directly on the encoded column for just those
+    /// rows, so the selected rows are never materialised. When every row is
+    /// selected the minimum and maximum come straight from the column's
+    /// metadata.
+    ///
+    /// Right now, predicates are conjunctive (AND).
+    pub fn read_aggregate(
+        &self,
+        predicates: &[Predicate<'_>],
+        aggregates: &[(ColumnName<'a>, AggregateType)],
+    ) -> Vec<(ColumnName<'a>, AggregateResult<'_>)> {
+        let (row_ids, all_rows) = match self.row_ids_from_predicates(predicates) {
+            RowIDsOption::None(_) => (vec![], false),
+            // TODO(edd): aggregate directly over the bitmap rather than a
+            // vector of the row ids it holds.
+            RowIDsOption::Some(row_ids) => (row_ids.to_vec(), false),
+            RowIDsOption::All(_) => ((0..self.rows()).collect::<Vec<_>>(), true),
+        };
+
+        aggregates
+            .iter()
+            .map(|(col_name, agg_type)| {
+                let col = self
+                    .all_columns
+                    .get(*col_name)
+                    .unwrap_or_else(|| panic!("column {} not found", col_name));
+                (
+                    *col_name,
+                    self.aggregate_column(col, agg_type, &row_ids, all_rows),
+                )
+            })
+            .collect()
+    }
+
+    // Aggregates the values of `col` at `row_ids`. `all_rows` is true when
+    // `row_ids` holds every row in the segment.
+    fn aggregate_column(
+        &self,
+        col: &'a Column,
+        agg_type: &AggregateType,
+        row_ids: &[u32],
+        all_rows: bool,
+    ) -> AggregateResult<'_> {
+        if row_ids.is_empty() {
+            return match agg_type {
+                AggregateType::Count => AggregateResult::Count(0),
+                AggregateType::Sum => AggregateResult::Sum(None),
+                AggregateType::Min => AggregateResult::Min(Value::Null),
+                AggregateType::Max => AggregateResult::Max(Value::Null),
+                AggregateType::First => AggregateResult::First(None),
+                AggregateType::Last => AggregateResult::Last(None),
+            };
+        }
+
+        match agg_type {
+            AggregateType::Count => AggregateResult::Count(u64::from(col.count(row_ids))),
+            AggregateType::Sum => AggregateResult::Sum(match col.sum(row_ids) {
+                Value::Scalar(v) => Some(v),
+                _ => None,
+            }),
+            AggregateType::Min if all_rows => {
+                AggregateResult::Min(col.column_range().map_or(Value::Null, |(min, _)| min))
+            }
+            AggregateType::Max if all_rows => {
+                AggregateResult::Max(col.column_range().map_or(Value::Null, |(_, max)| max))
+            }
+            AggregateType::Min => AggregateResult::Min(col.min(row_ids)),
+            AggregateType::Max => AggregateResult::Max(col.max(row_ids)),
+            AggregateType::First => {
+                AggregateResult::First(self.value_at_time(col, row_ids, |t, other| t < other))
+            }
+            AggregateType::Last => {
+                AggregateResult::Last(self.value_at_time(col, row_ids, |t, other| t >= other))
             }
         }
     }
 
+    // Returns the timestamp and value of `col` of the row among `row_ids` whose
+    // timestamp is preferred by `better`.
+    fn value_at_time(
+        &self,
+        col: &'a Column,
+        row_ids: &[u32],
+        better: impl Fn(i64, i64) -> bool,
+    ) -> Option<(i64, Value<'_>)> {
+        let mut best: Option<(i64, u32)> = None;
+        for &row_id in row_ids {
+            let time = match self.time_column.value(row_id) {
+                Value::Scalar(Scalar::I64(time)) => time,
+                _ => continue,
+            };
+            if best.map_or(true, |(best_time, _)| better(time, best_time)) {
+                best = Some((time, row_id));
+            }
+        }
+        best.map(|(time, row_id)| (time, col.value(row_id)))
+    }
+
+    /// The (empty) values of each column of the segment, which carry the
+    /// logical type of the column.
+    pub fn column_types(&self) -> impl Iterator<Item = (ColumnName<'a>, Values)> + '_ {
+        self.all_columns
+            .iter()
+            .map(|(name, col)| (*name, col.values(&[])))
+    }
+
     // Determines the set of row ids that satisfy the time range and all of the
     // optional predicates.
     //
@@ -226,9 +352,9 @@ impl<'a> Segment<'a> {
             dst,
         );
 
-        // TODO(edd): potentially pass this in so we can re-use it once we
-        // have materialised any results.
-        let mut result_row_ids = RowIDs::new_bitmap();
+        // The rows that satisfy all of the predicates evaluated so far, where
+        // `None` means every row does.
+        let mut selection: Option<RowIDs> = None;
 
         match time_row_ids {
             // No matching rows based on time range - return buffer
@@ -241,8 +367,9 @@ impl<'a> Segment<'a> {
 
             // some rows match - continue to apply predicates
             RowIDsOption::Some(row_ids) => {
-                // union empty result set with matching timestamp rows
-                result_row_ids.union(&row_ids);
+                let mut selected = RowIDs::new_bitmap();
+                selected.union(&row_ids);
+                selection = Some(selected);
                 dst = row_ids // hand buffer back
             }
         }
@@ -251,9 +378,12 @@ impl<'a> Segment<'a> {
             if col_name == &TIME_COLUMN_NAME {
                 continue; // we already processed the time column as a special case.
             }
-            // N.B column should always exist because validation of
-            // predicates should happen at the `Table` level.
-            let col = self.all_columns.get(*col_name).unwrap();
+            // A column the segment doesn't have only has NULLs, which don't
+            // satisfy any predicate.
+            let col = match self.all_columns.get(*col_name) {
+                Some(col) => col,
+                None => return RowIDsOption::None(dst),
+            };
 
             // Explanation of how this buffer pattern works here. The idea is
             // that the buffer should be returned to the caller so it can be
@@ -267,10 +397,20 @@ impl<'a> Segment<'a> {
                 // Intersect the row ids found at this column with all those
                 // found on other column predicates.
                 RowIDsOption::Some(row_ids) => {
-                    if result_row_ids.is_empty() {
-                        result_row_ids.union(&row_ids)
+                    match selection.as_mut() {
+                        Some(selected) => selected.intersect(&row_ids),
+                        None => {
+                            let mut selected = RowIDs::new_bitmap();
+                            selected.union(&row_ids);
+                            selection = Some(selected);
+                        }
+                    }
+
+                    // No rows satisfy all of the predicates so far, so none
+                    // will satisfy the remaining ones either.
+                    if selection.as_ref().map_or(false, RowIDs::is_empty) {
+                        return RowIDsOption::None(row_ids);
                     }
-                    result_row_ids.intersect(&row_ids);
                     dst = row_ids; // hand buffer back
                 }
 
@@ -282,11 +422,11 @@ impl<'a> Segment<'a> {
             }
         }
 
-        if result_row_ids.is_empty() {
+        match selection {
+            Some(selected) => RowIDsOption::Some(selected),
             // All rows matched all predicates - return the empty buffer.
-            return RowIDsOption::All(result_row_ids);
+            None => RowIDsOption::All(dst),
         }
-        RowIDsOption::Some(result_row_ids)
     }
 }
 
@@ -553,6 +693,172 @@ west,4";
         assert_eq!(stringify_read_filter_results(results), expected);
     }
 
+    #[test]
+    fn read_filter_disjoint_predicates() {
+        let mut columns = BTreeMap::new();
+        let tc = ColumnType::Time(Column::from(&[1_i64, 2, 3, 4][..]));
+        columns.insert("time", &tc);
+        let rc = ColumnType::Tag(Column::from(&["west", "east", "west", "north"][..]));
+        columns.insert("region", &rc);
+        let mc = ColumnType::Tag(Column::from(&["GET", "GET", "PUT", "PUT"][..]));
+        columns.insert("method", &mc);
+        let segment = Segment::new(4, columns);
+
+        // each predicate matches some rows, but no row matches both.
+        let results = segment.read_filter(
+            &["time"],
+            &build_predicates(
+                1,
+                4,
+                vec![
+                    ("region", (Operator::Equal, Value::String("east"))),
+                    ("method", (Operator::Equal, Value::String("PUT"))),
+                ],
+            ),
+        );
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn read_aggregate() {
+        let mut columns = BTreeMap::new();
+        let tc = ColumnType::Time(Column::from(&[1_i64, 2, 3, 4, 5, 6][..]));
+        columns.insert("time", &tc);
+
+        let rc = ColumnType::Tag(Column::from(
+            &["west", "west", "east", "west", "south", "north"][..],
+        ));
+        columns.insert("region", &rc);
+
+        let fc = ColumnType::Field(Column::from(&[100_u64, 101, 200, 203, 203, 10][..]));
+        columns.insert("count", &fc);
+
+        let segment = Segment::new(6, columns);
+
+        let aggregates = vec![
+            ("count", AggregateType::Count),
+            ("count", AggregateType::Sum),
+            ("count", AggregateType::Min),
+            ("count", AggregateType::Max),
+            ("count", AggregateType::First),
+            ("count", AggregateType::Last),
+        ];
+
+        // every row is selected
+        let results = segment.read_aggregate(&build_predicates(1, 7, vec![]), &aggregates);
+        assert_eq!(
+            results,
+            vec![
+                ("count", AggregateResult::Count(6)),
+                ("count", AggregateResult::Sum(Some(Scalar::U64(817)))),
+                (
+                    "count",
+                    AggregateResult::Min(Value::Scalar(Scalar::U64(10)))
+                ),
+                (
+                    "count",
+                    AggregateResult::Max(Value::Scalar(Scalar::U64(203)))
+                ),
+                (
+                    "count",
+                    AggregateResult::First(Some((1, Value::Scalar(Scalar::U64(100)))))
+                ),
+                (
+                    "count",
+                    AggregateResult::Last(Some((6, Value::Scalar(Scalar::U64(10)))))
+                ),
+            ]
+        );
+
+        // WHERE "region" = "west" AND time >= 2 AND time < 6
+        let results = segment.read_aggregate(
+            &build_predicates(
+                2,
+                6,
+                vec![("region", (Operator::Equal, Value::String("west")))],
+            ),
+            &aggregates,
+        );
+        assert_eq!(
+            results,
+            vec![
+                ("count", AggregateResult::Count(2)),
+                ("count", AggregateResult::Sum(Some(Scalar::U64(304)))),
+                (
+                    "count",
+                    AggregateResult::Min(Value::Scalar(Scalar::U64(101)))
+                ),
+                (
+                    "count",
+                    AggregateResult::Max(Value::Scalar(Scalar::U64(203)))
+                ),
+                (
+                    "count",
+                    AggregateResult::First(Some((2, Value::Scalar(Scalar::U64(101)))))
+                ),
+                (
+                    "count",
+                    AggregateResult::Last(Some((4, Value::Scalar(Scalar::U64(203)))))
+                ),
+            ]
+        );
+
+        // no rows are selected
+        let results = segment.read_aggregate(
+            &build_predicates(
+                1,
+                7,
+                vec![("region", (Operator::Equal, Value::String("mars")))],
+            ),
+            &aggregates[..2],
+        );
+        assert_eq!(
+            results,
+            vec![
+                ("count", AggregateResult::Count(0)),
+                ("count", AggregateResult::Sum(None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_filter_with_nulls() {
+        let mut columns = BTreeMap::new();
+        let tc = ColumnType::Time(Column::from(&[1_i64, 2, 3][..]));
+        columns.insert("time", &tc);
+
+        let rc = ColumnType::Tag(Column::from(&["west", "east", "west"][..]));
+        columns.insert("region", &rc);
+
+        let segment = Segment::new(3, columns);
+
+        // columns added to the table after this segment was created.
+        let count = Column::from(&[1_u64][..]);
+        let host = Column::from(&["a"][..]);
+        let mut column_types = BTreeMap::new();
+        column_types.insert("count".to_string(), count.values(&[]));
+        column_types.insert("host".to_string(), host.values(&[]));
+
+        let results = segment.read_filter_with_nulls(
+            &["count", "host", "region", "time"],
+            &build_predicates(1, 3, vec![]),
+            &column_types,
+        );
+        assert!(matches!(results[0].1, Values::U64(_)));
+        let expected = "count,host,region,time
+NULL,NULL,west,1
+NULL,NULL,east,2";
+        assert_eq!(stringify_read_filter_results(results), expected);
+
+        // NULL doesn't satisfy a predicate on a missing column.
+        let results = segment.read_filter_with_nulls(
+            &["region", "time"],
+            &build_predicates(1, 3, vec![("host", (Operator::Equal, Value::String("a")))]),
+            &column_types,
+        );
+        assert!(results.is_empty());
+    }
+
     #[test]
     fn segment_could_satisfy_predicate() {
         let mut columns = BTreeMap::new();
@@ -16,9 +16,9 @@ use crate::segment::{ColumnName, GroupKey, Predicate, Segment};
 /// Rows within a table's segments can be sorted arbitrarily, therefore it is
 /// possible that time-ranges (for example) can overlap across segments.
 ///
-/// The current write path ensures that a single table emitted for a
-/// measurement within any partition will have the same schema, therefore this
-/// table's schema applies to all of the segments held within it.
+/// The table's schema is the union of the columns of its segments. Columns
+/// can be added to a measurement over time, so an earlier segment may not have
+/// all of the table's columns; those columns are NULL for the segment's rows.
 ///
 /// The total size of a table is tracked and can be increased or reduced by
 /// adding or removing segments.
@@ -44,6 +44,7 @@ impl<'a> Table<'a> {
 
     /// Add a new segment to this table.
     pub fn add_segment(&mut self, segment: Segment<'a>) {
+        self.meta.add_segment(&segment);
         self.segments.push(segment);
     }
 
@@ -118,6 +119,9 @@ impl<'a> Table<'a> {
     /// but can be ranged by time, which should be represented as nanoseconds
     /// since the epoch. Results are included if they satisfy the predicate and
     /// fall with the [min, max) time range domain.
+    ///
+    /// Columns missing from a segment are materialised as NULLs for that
+    /// segment's rows.
     pub fn select(
         &self,
         columns: &[ColumnName<'a>],
@@ -134,7 +138,8 @@ impl<'a> Table<'a> {
         }
 
         for segment in segments {
-            let segment_result = segment.read_filter(columns, predicates);
+            let segment_result =
+                segment.read_filter_with_nulls(columns, predicates, &self.meta.column_types);
             for (i, (col_name, values)) in segment_result.into_iter().enumerate() {
                 assert_eq!(results[i].0, col_name);
                 results[i].1.push(values);
@@ -404,9 +409,9 @@ struct MetaData<'a> {
     // The total number of rows in the table.
     rows: u64,
 
-    // The distinct set of columns for this table (all of these columns will
-    // appear in all of the table's segments) and the range of values for
-    // each of those columns.
+    // The distinct set of columns for this table (a column may not appear in
+    // all of the table's segments) and the range of values for each of those
+    // columns.
     //
     // This can be used to skip the table entirely if a logical predicate can't
     // possibly match based on the range of values a column has.
@@ -418,6 +423,11 @@ struct MetaData<'a> {
     // This can be used to skip the table entirely if the time range for a query
     // falls outside of this range.
     time_range: Option<(i64, i64)>,
+
+    // The (empty) values of each column in the table, which carry the column's
+    // logical type. Used to materialise NULLs for the columns a segment is
+    // missing.
+    column_types: BTreeMap<String, Values>,
 }
 
 impl<'a> MetaData<'a> {
@@ -431,6 +441,10 @@ impl<'a> MetaData<'a> {
                 .map(|(k, v)| (k.to_string(), (v.0.clone(), v.1.clone())))
                 .collect(),
             time_range: Some(segment.time_range()),
+            column_types: segment
+                .column_types()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
         }
     }
 
@@ -439,14 +453,27 @@ impl<'a> MetaData<'a> {
         self.size += segment.size();
         self.rows += u64::from(segment.rows());
 
-        assert_eq!(self.column_ranges.len(), segment.column_ranges().len());
+        self.time_range = Some(match self.time_range {
+            Some((min, max)) => {
+                let (segment_min, segment_max) = segment.time_range();
+                (min.min(segment_min), max.max(segment_max))
+            }
+            None => segment.time_range(),
+        });
+
         for (segment_column_name, (segment_column_range_min, segment_column_range_max)) in
             segment.column_ranges()
         {
-            let mut curr_range = self
+            // the segment may introduce columns the table hasn't seen yet.
+            let curr_range = self
                 .column_ranges
-                .get_mut(&segment_column_name.to_string())
-                .unwrap();
+                .entry(segment_column_name.to_string())
+                .or_insert_with(|| {
+                    (
+                        segment_column_range_min.clone(),
+                        segment_column_range_max.clone(),
+                    )
+                });
             if segment_column_range_min < &curr_range.0 {
                 curr_range.0 = segment_column_range_min.clone();
             }
@@ -455,6 +482,12 @@ impl<'a> MetaData<'a> {
                 curr_range.1 = segment_column_range_max.clone();
             }
         }
+
+        for (column_name, values) in segment.column_types() {
+            if !self.column_types.contains_key(column_name) {
+                self.column_types.insert(column_name.to_string(), values);
+            }
+        }
     }
 
     // invalidate should be called when a segment is removed that impacts the
@@ -615,4 +648,59 @@ mod test {
             stringify_select_results(results)
         );
     }
+
+    #[test]
+    fn select_evolved_schema() {
+        let mut columns = BTreeMap::new();
+        let tc = ColumnType::Time(Column::from(&[1_i64, 2][..]));
+        columns.insert("time", &tc);
+        let rc = ColumnType::Tag(Column::from(&["west", "east"][..]));
+        columns.insert("region", &rc);
+        let segment = Segment::new(2, columns);
+
+        let mut table = Table::new("cpu".to_owned(), segment);
+
+        // A later segment adds a tag and a field.
+        let mut columns = BTreeMap::new();
+        let tc = ColumnType::Time(Column::from(&[10_i64, 20][..]));
+        columns.insert("time", &tc);
+        let rc = ColumnType::Tag(Column::from(&["south", "north"][..]));
+        columns.insert("region", &rc);
+        let hc = ColumnType::Tag(Column::from(&["a", "b"][..]));
+        columns.insert("host", &hc);
+        let fc = ColumnType::Field(Column::from(&[1000_u64, 1002][..]));
+        columns.insert("count", &fc);
+        let segment = Segment::new(2, columns);
+        table.add_segment(segment);
+
+        let results = table.select(
+            &["time", "count", "host", "region"],
+            &build_predicates(1, 31, vec![]),
+        );
+        assert_eq!(
+            "time,count,host,region
+1,NULL,NULL,west
+2,NULL,NULL,east
+
+10,1000,a,south
+20,1002,b,north
+
+",
+            stringify_select_results(results)
+        );
+
+        // Only the later segment can satisfy a predicate on the new tag.
+        let results = table.select(
+            &["time", "host"],
+            &build_predicates(1, 31, vec![("host", (Operator::Equal, Value::String("b")))]),
+        );
+        assert_eq!(
+            "time,host
+20,b
+
+",
+            stringify_select_results(results)
+        );
+        assert_eq!(table.meta.time_range, Some((1, 20)));
+    }
 }
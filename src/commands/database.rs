@@ -0,0 +1,1028 @@
+//! This module contains the `database` commands, which manage the databases of a running
+//! server through its management gRPC API. The commands that describe databases, partitions
+//! and chunks print them either as aligned columns or, for scripts, as JSON.
+
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::DateTime;
+use data_types::{
+    chunk::{ChunkStorage, ChunkSummary},
+    database_rules::{DatabaseRules, ParquetCompression, ParquetStatistics, TemplateRef},
+};
+use generated_types::management::{
+    self, ExportDatabaseRequest, FieldSchema, FieldType, FileFormat, ImportDataRequest, Operation,
+    OperationStatus, SchemaMapping, TimeRange, TimeUnit,
+};
+use influxdb_iox_client::{operations, Builder, ManagementClient, OperationsClient};
+use serde::Serialize;
+use snafu::{OptionExt, ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    Connecting { source: influxdb_iox_client::Error },
+
+    #[snafu(display(
+        "Invalid time '{}': expected an RFC 3339 timestamp or nanoseconds since the epoch",
+        value
+    ))]
+    InvalidTime { value: String },
+
+    #[snafu(display("Error starting the export: {}", source))]
+    StartingExport { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Error waiting for the export: {}", source))]
+    WaitingForExport { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Export failed: {}", error))]
+    ExportFailed { error: String },
+
+    #[snafu(display("Error starting the snapshot: {}", source))]
+    StartingSnapshot { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Error waiting for the snapshot: {}", source))]
+    WaitingForSnapshot { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Snapshot failed: {}", error))]
+    SnapshotFailed { error: String },
+
+    #[snafu(display("Error restoring the database: {}", source))]
+    RestoringDatabase { source: influxdb_iox_client::Error },
+
+    #[snafu(display(
+        "Invalid field '{}': expected <name>:<float|integer|string|boolean>",
+        value
+    ))]
+    InvalidField { value: String },
+
+    #[snafu(display("Invalid time unit '{}': expected s, ms, us or ns", value))]
+    InvalidTimeUnit { value: String },
+
+    #[snafu(display("Unknown format of {:?}: use --format csv or --format parquet", path))]
+    UnknownFormat { path: PathBuf },
+
+    #[snafu(display("Error reading {:?}: {}", path, source))]
+    ReadingFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error importing {:?}: {}", path, source))]
+    Importing {
+        path: PathBuf,
+        source: influxdb_iox_client::Error,
+    },
+
+    #[snafu(display("Error loading the dimension table: {}", source))]
+    LoadingDimensionTable { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Error reading the rules in {:?}: {}", path, source))]
+    ReadingRules {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Invalid rules in {:?}: {}", path, source))]
+    ParsingRules {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Error creating the database: {}", source))]
+    CreatingDatabase { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Error listing the databases: {}", source))]
+    ListingDatabases { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Error getting the database: {}", source))]
+    GettingDatabase { source: influxdb_iox_client::Error },
+
+    #[snafu(display("The server returned invalid rules: {}", source))]
+    InvalidRules {
+        source: data_types::database_rules::Error,
+    },
+
+    #[snafu(display("Error listing the chunks: {}", source))]
+    ListingChunks { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Error closing the chunk: {}", source))]
+    ClosingChunk { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Error persisting the chunk: {}", source))]
+    PersistingChunk { source: influxdb_iox_client::Error },
+
+    #[snafu(display("The server returned an invalid chunk: {}", source))]
+    InvalidChunk { source: data_types::chunk::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// How to reach the management API of the server
+#[derive(Debug)]
+pub struct Connection {
+    /// The URL of the gRPC API, such as `http://127.0.0.1:8082`
+    pub host: String,
+    /// The secret of the token to authenticate with, if any
+    pub token: Option<String>,
+}
+
+/// Describes which data of a database to export, and where to
+#[derive(Debug)]
+pub struct ExportConfig {
+    pub db_name: String,
+    /// If set, only this table is exported
+    pub table: Option<String>,
+    /// If set, only the chunks of this partition are exported
+    pub partition_key: Option<String>,
+    /// If set, only rows at or after this time are exported
+    pub start: Option<String>,
+    /// If set, only rows before this time are exported
+    pub stop: Option<String>,
+    /// `s3://bucket/prefix`, `gs://bucket/prefix` or a directory of the server
+    pub output: String,
+}
+
+/// Describes which files to import into a database, and how their columns map to the table
+#[derive(Debug)]
+pub struct ImportConfig {
+    pub db_name: String,
+    /// The CSV or Parquet files to import, each one into a new chunk
+    pub files: Vec<PathBuf>,
+    /// `csv` or `parquet`. If not set, the format is inferred from the extension of each file
+    pub format: Option<String>,
+    pub table: String,
+    /// The columns to import as tags
+    pub tags: Vec<String>,
+    /// The columns to import as fields, as `<name>:<type>`
+    pub fields: Vec<String>,
+    pub time_column: String,
+    /// `s`, `ms`, `us` or `ns`, the unit of integer timestamps
+    pub time_unit: String,
+    /// The key of the partition the chunks are added to. If not set, the rows are added to
+    /// the partitions the partition template of the database puts them in.
+    pub partition_key: Option<String>,
+}
+
+/// How the commands describing databases, partitions and chunks print them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    /// Aligned columns with a header row
+    Text,
+    /// Pretty printed JSON
+    Json,
+}
+
+/// The partition of a database, summarizing its chunks
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct PartitionSummary {
+    partition_key: String,
+    chunks: usize,
+    estimated_bytes: usize,
+    row_count: usize,
+}
+
+/// How long each request waiting for an operation lasts, before the progress is printed again
+const WAIT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Exports the data of a database as Parquet files, waiting for the server to write them
+pub async fn export(connection: &Connection, config: &ExportConfig) -> Result<()> {
+    let range = if config.start.is_some() || config.stop.is_some() {
+        Some(TimeRange {
+            start: config
+                .start
+                .as_deref()
+                .map(parse_time)
+                .transpose()?
+                .unwrap_or(i64::MIN),
+            end: config
+                .stop
+                .as_deref()
+                .map(parse_time)
+                .transpose()?
+                .unwrap_or(i64::MAX),
+        })
+    } else {
+        None
+    };
+
+    let connection = connect(connection).await?;
+    let request = ExportDatabaseRequest {
+        db_name: config.db_name.clone(),
+        table: config.table.clone().unwrap_or_default(),
+        partition_key: config.partition_key.clone().unwrap_or_default(),
+        range,
+        output: config.output.clone(),
+    };
+    let operation = ManagementClient::new(connection.clone())
+        .export_database(request)
+        .await
+        .context(StartingExport)?;
+
+    let operation = wait_for(connection, operation)
+        .await
+        .context(WaitingForExport)?;
+    if let Some(error) = operation_error(&operation) {
+        return ExportFailed { error }.fail();
+    }
+
+    println!(
+        "Exported {} files to {}",
+        operation.completed, config.output
+    );
+    Ok(())
+}
+
+/// Takes a snapshot of a database, with its rules, its catalog and the Parquet files of its
+/// persisted chunks, waiting for the server to copy the files
+pub async fn snapshot(connection: &Connection, db_name: &str, output: &str) -> Result<()> {
+    let connection = connect(connection).await?;
+    let operation = ManagementClient::new(connection.clone())
+        .snapshot_database(db_name, output)
+        .await
+        .context(StartingSnapshot)?;
+
+    let operation = wait_for(connection, operation)
+        .await
+        .context(WaitingForSnapshot)?;
+    if let Some(error) = operation_error(&operation) {
+        return SnapshotFailed { error }.fail();
+    }
+
+    println!(
+        "Took a snapshot of database {} to {} with {} files",
+        db_name, output, operation.completed
+    );
+    Ok(())
+}
+
+/// Creates database `db_name` from a snapshot taken with `snapshot`, possibly by another server
+pub async fn restore(connection: &Connection, db_name: &str, input: &str) -> Result<()> {
+    let chunks = ManagementClient::new(connect(connection).await?)
+        .restore_database(db_name, input)
+        .await
+        .context(RestoringDatabase)?;
+
+    println!(
+        "Restored database {} from {} with {} chunks",
+        db_name,
+        input,
+        chunks.len()
+    );
+    Ok(())
+}
+
+/// Imports CSV or Parquet files into a database, as persisted chunks of its catalog
+pub async fn import(connection: &Connection, config: &ImportConfig) -> Result<()> {
+    let mapping = SchemaMapping {
+        table: config.table.clone(),
+        tags: config.tags.clone(),
+        fields: config
+            .fields
+            .iter()
+            .map(String::as_str)
+            .map(parse_field)
+            .collect::<Result<_>>()?,
+        time_column: config.time_column.clone(),
+        time_unit: parse_time_unit(&config.time_unit)? as i32,
+    };
+    let formats = config
+        .files
+        .iter()
+        .map(|path| file_format(config.format.as_deref(), path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut client = ManagementClient::new(connect(connection).await?);
+    for (path, format) in config.files.iter().zip(formats) {
+        let data = tokio::fs::read(path).await.context(ReadingFile { path })?;
+        let request = ImportDataRequest {
+            db_name: config.db_name.clone(),
+            partition_key: config.partition_key.clone().unwrap_or_default(),
+            format: format as i32,
+            mapping: Some(mapping.clone()),
+            data,
+        };
+        let chunks = client
+            .import_data(request)
+            .await
+            .context(Importing { path })?;
+
+        for chunk in chunks {
+            println!(
+                "Imported {} rows of {:?} into chunk {} of partition {:?} ({} bytes)",
+                chunk.row_count, path, chunk.id, chunk.partition_key, chunk.size_bytes
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Loads the CSV file `path` into a database as dimension table `table_name`, which queries
+/// can join with the measurements of the database
+pub async fn load_dimension_table(
+    connection: &Connection,
+    db_name: &str,
+    table_name: &str,
+    path: &Path,
+) -> Result<()> {
+    let data = tokio::fs::read(path).await.context(ReadingFile { path })?;
+    let row_count = ManagementClient::new(connect(connection).await?)
+        .load_dimension_table(db_name, table_name, data)
+        .await
+        .context(LoadingDimensionTable)?;
+
+    println!(
+        "Loaded {} rows of {:?} into dimension table {}",
+        row_count, path, table_name
+    );
+    Ok(())
+}
+
+/// Creates a database, with the rules in the JSON file `rules` if given. Otherwise the
+/// database stores writes locally and answers queries from them. With `template`, the rules
+/// are those of the rules template of that name, apart from the fields the rules in the file
+/// list as their overrides.
+pub async fn create(
+    connection: &Connection,
+    db_name: &str,
+    rules: Option<&Path>,
+    template: Option<&str>,
+) -> Result<()> {
+    let mut rules: DatabaseRules = match rules {
+        Some(path) => {
+            let json = tokio::fs::read(path).await.context(ReadingRules { path })?;
+            serde_json::from_slice(&json).context(ParsingRules { path })?
+        }
+        None => DatabaseRules {
+            store_locally: true,
+            query_local: true,
+            ..Default::default()
+        },
+    };
+    if let Some(name) = template {
+        let overrides = rules
+            .template
+            .take()
+            .map(|template| template.overrides)
+            .unwrap_or_default();
+        rules.template = Some(TemplateRef {
+            name: name.to_string(),
+            overrides,
+        });
+    }
+    let rules = management::DatabaseRules {
+        name: db_name.to_string(),
+        ..rules.into()
+    };
+
+    ManagementClient::new(connect(connection).await?)
+        .create_database(rules)
+        .await
+        .context(CreatingDatabase)?;
+
+    println!("Created database {}", db_name);
+    Ok(())
+}
+
+/// Lists the names of the databases of the server
+pub async fn list(connection: &Connection, output: Output) -> Result<()> {
+    let names = ManagementClient::new(connect(connection).await?)
+        .list_databases()
+        .await
+        .context(ListingDatabases)?;
+
+    match output {
+        Output::Text => {
+            for name in names {
+                println!("{}", name);
+            }
+        }
+        Output::Json => println!("{}", to_json(&names)),
+    }
+    Ok(())
+}
+
+/// Prints the rules of a database
+pub async fn get(connection: &Connection, db_name: &str, output: Output) -> Result<()> {
+    let rules = ManagementClient::new(connect(connection).await?)
+        .get_database(db_name)
+        .await
+        .context(GettingDatabase)?;
+    let rules = DatabaseRules::try_from(rules).context(InvalidRules)?;
+
+    match output {
+        Output::Text => println!("{}", format_rules(db_name, &rules)),
+        Output::Json => println!(
+            "{}",
+            to_json(&serde_json::json!({ "name": db_name, "rules": rules }))
+        ),
+    }
+    Ok(())
+}
+
+/// Lists the partitions of a database, with the number of chunks and rows of each
+pub async fn list_partitions(connection: &Connection, db_name: &str, output: Output) -> Result<()> {
+    let partitions = summarize_partitions(&chunk_summaries(connection, db_name).await?);
+
+    match output {
+        Output::Text => {
+            let rows: Vec<_> = partitions
+                .iter()
+                .map(|partition| {
+                    vec![
+                        partition.partition_key.clone(),
+                        partition.chunks.to_string(),
+                        partition.row_count.to_string(),
+                        partition.estimated_bytes.to_string(),
+                    ]
+                })
+                .collect();
+            println!(
+                "{}",
+                format_table(&["PARTITION", "CHUNKS", "ROWS", "BYTES"], &rows)
+            );
+        }
+        Output::Json => println!("{}", to_json(&partitions)),
+    }
+    Ok(())
+}
+
+/// Lists the chunks of a database, or of one of its partitions
+pub async fn list_chunks(
+    connection: &Connection,
+    db_name: &str,
+    partition_key: Option<&str>,
+    output: Output,
+) -> Result<()> {
+    let chunks: Vec<_> = chunk_summaries(connection, db_name)
+        .await?
+        .into_iter()
+        .filter(|chunk| partition_key.map_or(true, |key| key == chunk.partition_key))
+        .collect();
+    print_chunks(&chunks, output);
+    Ok(())
+}
+
+/// Closes the open chunk of a partition, printing the closed chunk
+pub async fn close_chunk(
+    connection: &Connection,
+    db_name: &str,
+    partition_key: &str,
+    output: Output,
+) -> Result<()> {
+    let chunk = ManagementClient::new(connect(connection).await?)
+        .close_chunk(db_name, partition_key)
+        .await
+        .context(ClosingChunk)?;
+
+    print_chunks(
+        &[ChunkSummary::try_from(chunk).context(InvalidChunk)?],
+        output,
+    );
+    Ok(())
+}
+
+/// Writes a closed chunk out to object storage
+pub async fn persist_chunk(
+    connection: &Connection,
+    db_name: &str,
+    partition_key: &str,
+    chunk_id: u32,
+) -> Result<()> {
+    ManagementClient::new(connect(connection).await?)
+        .persist_chunk(db_name, partition_key, chunk_id)
+        .await
+        .context(PersistingChunk)?;
+
+    println!(
+        "Persisted chunk {} of partition {} in database {}",
+        chunk_id, partition_key, db_name
+    );
+    Ok(())
+}
+
+async fn chunk_summaries(connection: &Connection, db_name: &str) -> Result<Vec<ChunkSummary>> {
+    let mut chunks = ManagementClient::new(connect(connection).await?)
+        .list_chunks(db_name)
+        .await
+        .context(ListingChunks)?
+        .into_iter()
+        .map(ChunkSummary::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .context(InvalidChunk)?;
+
+    chunks.sort_by(|a, b| (&a.partition_key, a.id).cmp(&(&b.partition_key, b.id)));
+    Ok(chunks)
+}
+
+fn print_chunks(chunks: &[ChunkSummary], output: Output) {
+    match output {
+        Output::Text => {
+            let rows: Vec<_> = chunks
+                .iter()
+                .map(|chunk| {
+                    vec![
+                        chunk.partition_key.clone(),
+                        chunk.id.to_string(),
+                        storage_name(chunk.storage).to_string(),
+                        chunk.row_count.to_string(),
+                        chunk.estimated_bytes.to_string(),
+                        chunk.index_bytes.to_string(),
+                    ]
+                })
+                .collect();
+            println!(
+                "{}",
+                format_table(
+                    &["PARTITION", "ID", "STORAGE", "ROWS", "BYTES", "INDEX BYTES"],
+                    &rows
+                )
+            );
+        }
+        Output::Json => println!("{}", to_json(&chunks)),
+    }
+}
+
+/// Summarizes the chunks of each partition, ordered by partition key
+fn summarize_partitions(chunks: &[ChunkSummary]) -> Vec<PartitionSummary> {
+    let mut partitions = BTreeMap::new();
+    for chunk in chunks {
+        let partition = partitions
+            .entry(chunk.partition_key.clone())
+            .or_insert_with(|| PartitionSummary {
+                partition_key: chunk.partition_key.clone(),
+                chunks: 0,
+                estimated_bytes: 0,
+                row_count: 0,
+            });
+        partition.chunks += 1;
+        partition.estimated_bytes += chunk.estimated_bytes;
+        partition.row_count += chunk.row_count;
+    }
+    partitions
+        .into_iter()
+        .map(|(_, partition)| partition)
+        .collect()
+}
+
+fn storage_name(storage: ChunkStorage) -> &'static str {
+    match storage {
+        ChunkStorage::OpenMutableBuffer => "open mutable buffer",
+        ChunkStorage::ClosedMutableBuffer => "closed mutable buffer",
+        ChunkStorage::ReadBuffer => "read buffer",
+        ChunkStorage::ObjectStore => "object store",
+    }
+}
+
+fn format_rules(db_name: &str, rules: &DatabaseRules) -> String {
+    let or_none = |values: &[String]| {
+        if values.is_empty() {
+            "none".to_string()
+        } else {
+            values.join(", ")
+        }
+    };
+    let template = rules
+        .partition_template
+        .parts
+        .iter()
+        .map(|part| format!("{:?}", part))
+        .collect::<Vec<_>>();
+    let retention = rules.retention_period.map_or_else(
+        || "forever".to_string(),
+        |period| format!("{}s", period.as_secs()),
+    );
+    let limit = |bytes: Option<usize>| {
+        bytes.map_or_else(|| "none".to_string(), |bytes| format!("{} bytes", bytes))
+    };
+    let lifecycle = &rules.lifecycle_rules;
+    let parquet = &rules.parquet;
+    let bound = |bound: Option<Duration>, default: &str| {
+        bound.map_or_else(
+            || default.to_string(),
+            |bound| format!("{}s", bound.as_secs()),
+        )
+    };
+    // without an explicit bound in the past, the retention period applies
+    let (max_future, max_past) = match rules.write_bounds {
+        Some(bounds) => (
+            bound(bounds.max_future, "none"),
+            bound(bounds.max_past, "retention"),
+        ),
+        None => ("none".to_string(), "none".to_string()),
+    };
+
+    let (rules_template, overrides) = match &rules.template {
+        Some(template) => (template.name.clone(), or_none(&template.overrides)),
+        None => ("none".to_string(), "none".to_string()),
+    };
+
+    let rows = vec![
+        vec!["name".to_string(), db_name.to_string()],
+        vec!["rules template".to_string(), rules_template],
+        vec!["template overrides".to_string(), overrides],
+        vec!["store locally".to_string(), rules.store_locally.to_string()],
+        vec!["query local".to_string(), rules.query_local.to_string()],
+        vec!["partition template".to_string(), or_none(&template)],
+        vec!["replication".to_string(), or_none(&rules.replication)],
+        vec![
+            "replication count".to_string(),
+            rules.replication_count.to_string(),
+        ],
+        vec![
+            "read only partitions".to_string(),
+            or_none(&rules.read_only_partitions),
+        ],
+        vec!["retention".to_string(), retention],
+        vec![
+            "trigram indexes".to_string(),
+            or_none(&rules.trigram_indexes),
+        ],
+        vec![
+            "time column".to_string(),
+            rules.timestamps.time_column().to_string(),
+        ],
+        vec![
+            "timestamp fields".to_string(),
+            or_none(&rules.timestamps.timestamp_fields),
+        ],
+        vec![
+            "buffer size soft".to_string(),
+            limit(lifecycle.buffer_size_soft),
+        ],
+        vec![
+            "buffer size hard".to_string(),
+            limit(lifecycle.buffer_size_hard),
+        ],
+        vec![
+            "partition size hard".to_string(),
+            limit(lifecycle.partition_size_hard),
+        ],
+        vec![
+            "drop non persisted".to_string(),
+            lifecycle.drop_non_persisted.to_string(),
+        ],
+        vec![
+            "persist row age".to_string(),
+            bound(lifecycle.persist_row_age, "none"),
+        ],
+        vec![
+            "persist buffer size".to_string(),
+            limit(lifecycle.persist_buffer_size),
+        ],
+        vec![
+            "persist increment rows".to_string(),
+            lifecycle
+                .persist_increment_rows
+                .map_or_else(|| "none".to_string(), |rows| format!("{} rows", rows)),
+        ],
+        vec![
+            "read buffer merge chunks".to_string(),
+            lifecycle.read_buffer_merge_chunks.map_or_else(
+                || "never".to_string(),
+                |chunks| format!("{} chunks", chunks),
+            ),
+        ],
+        vec![
+            "hibernate after".to_string(),
+            bound(lifecycle.hibernate_after, "never"),
+        ],
+        vec![
+            "compaction target file size".to_string(),
+            lifecycle
+                .compaction_target_file_size
+                .map_or_else(|| "never".to_string(), |bytes| format!("{} bytes", bytes)),
+        ],
+        vec!["max future".to_string(), max_future],
+        vec!["max past".to_string(), max_past],
+        vec![
+            "dedup window".to_string(),
+            bound(rules.dedup_window, "none"),
+        ],
+        vec![
+            "parquet row group size".to_string(),
+            parquet
+                .row_group_size
+                .map_or_else(|| "unlimited".to_string(), |rows| format!("{} rows", rows)),
+        ],
+        vec![
+            "parquet compression".to_string(),
+            parquet
+                .compression
+                .unwrap_or(ParquetCompression::Gzip)
+                .to_string(),
+        ],
+        vec![
+            "parquet dictionary page size".to_string(),
+            parquet
+                .dictionary_page_size
+                .map_or_else(|| "default".to_string(), |bytes| format!("{} bytes", bytes)),
+        ],
+        vec![
+            "parquet statistics".to_string(),
+            (parquet.statistics != Some(ParquetStatistics::None)).to_string(),
+        ],
+    ];
+    format_table(&["RULE", "VALUE"], &rows)
+}
+
+/// Formats `rows` as columns aligned under `headers`
+fn format_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<_> = headers.iter().map(|header| header.len()).collect();
+    for row in rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.len());
+        }
+    }
+
+    let format_row = |values: Vec<&str>| {
+        values
+            .iter()
+            .zip(&widths)
+            .map(|(value, width)| format!("{:width$}", value, width = *width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    let mut lines = vec![format_row(headers.to_vec())];
+    lines.extend(
+        rows.iter()
+            .map(|row| format_row(row.iter().map(String::as_str).collect())),
+    );
+    lines.join("\n")
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string_pretty(value).expect("values are serializable")
+}
+
+/// Connects to the server of `connection`, authenticating with its token if any
+pub(crate) async fn connect(connection: &Connection) -> Result<influxdb_iox_client::Connection> {
+    let builder = match &connection.token {
+        Some(token) => Builder::default().token(token.as_str()),
+        None => Builder::default(),
+    };
+    builder
+        .build(connection.host.as_str())
+        .await
+        .context(Connecting)
+}
+
+/// Parses an RFC 3339 timestamp, or a number of nanoseconds since the epoch
+fn parse_time(value: &str) -> Result<i64> {
+    value
+        .parse()
+        .ok()
+        .or_else(|| {
+            DateTime::parse_from_rfc3339(value)
+                .ok()
+                .map(|time| time.timestamp_nanos())
+        })
+        .context(InvalidTime { value })
+}
+
+/// Parses a field specification such as `usage:float`
+fn parse_field(value: &str) -> Result<FieldSchema> {
+    let mut parts = value.rsplitn(2, ':');
+    let field_type = match parts.next().map(str::to_lowercase).as_deref() {
+        Some("float") => FieldType::Float,
+        Some("integer") => FieldType::Integer,
+        Some("string") => FieldType::String,
+        Some("boolean") => FieldType::Boolean,
+        _ => return InvalidField { value }.fail(),
+    };
+    let name = parts
+        .next()
+        .filter(|name| !name.is_empty())
+        .context(InvalidField { value })?;
+
+    Ok(FieldSchema {
+        name: name.to_string(),
+        r#type: field_type as i32,
+    })
+}
+
+fn parse_time_unit(value: &str) -> Result<TimeUnit> {
+    match value {
+        "s" => Ok(TimeUnit::Seconds),
+        "ms" => Ok(TimeUnit::Milliseconds),
+        "us" => Ok(TimeUnit::Microseconds),
+        "ns" => Ok(TimeUnit::Nanoseconds),
+        _ => InvalidTimeUnit { value }.fail(),
+    }
+}
+
+/// The format of the file at `path`: `format` if set, else the one its extension names
+fn file_format(format: Option<&str>, path: &Path) -> Result<FileFormat> {
+    let format = format
+        .map(str::to_string)
+        .or_else(|| {
+            path.extension()
+                .map(|extension| extension.to_string_lossy().to_string())
+        })
+        .map(|format| format.to_lowercase());
+
+    match format.as_deref() {
+        Some("csv") => Ok(FileFormat::Csv),
+        Some("parquet") => Ok(FileFormat::Parquet),
+        _ => UnknownFormat { path }.fail(),
+    }
+}
+
+/// Waits for `operation` to complete, printing its progress meanwhile
+async fn wait_for(
+    connection: influxdb_iox_client::Connection,
+    mut operation: Operation,
+) -> Result<Operation, influxdb_iox_client::Error> {
+    let mut operations = OperationsClient::new(connection);
+    while !operations::is_complete(&operation) {
+        println!("{}", progress(&operation));
+
+        operation = operations
+            .wait_operation(operation.id, Some(WAIT_INTERVAL))
+            .await?;
+    }
+    Ok(operation)
+}
+
+/// Why the completed `operation` did not succeed, if it didn't
+fn operation_error(operation: &Operation) -> Option<String> {
+    if operation.status == OperationStatus::Success as i32 {
+        None
+    } else if operation.error.is_empty() {
+        Some("the operation was cancelled".to_string())
+    } else {
+        Some(operation.error.clone())
+    }
+}
+
+fn progress(operation: &Operation) -> String {
+    if operation.total == 0 {
+        format!("{}: starting", operation.description)
+    } else {
+        format!(
+            "{}: {}/{} files written",
+            operation.description, operation.completed, operation.total
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_times() {
+        assert_eq!(
+            parse_time("1600000000000000000").unwrap(),
+            1_600_000_000_000_000_000
+        );
+        assert_eq!(parse_time("-10").unwrap(), -10);
+        assert_eq!(
+            parse_time("2020-09-13T12:26:40Z").unwrap(),
+            1_600_000_000_000_000_000
+        );
+        assert_eq!(
+            parse_time("2020-09-13T14:26:40.5+02:00").unwrap(),
+            1_600_000_000_500_000_000
+        );
+
+        let err = parse_time("yesterday").unwrap_err();
+        assert!(matches!(err, Error::InvalidTime { .. }), "{}", err);
+    }
+
+    #[test]
+    fn parse_fields() {
+        let field = parse_field("usage:float").unwrap();
+        assert_eq!(field.name, "usage");
+        assert_eq!(field.r#type, FieldType::Float as i32);
+
+        let field = parse_field("a:b:Boolean").unwrap();
+        assert_eq!(field.name, "a:b");
+        assert_eq!(field.r#type, FieldType::Boolean as i32);
+
+        for value in &["usage", "usage:double", ":float"] {
+            let err = parse_field(value).unwrap_err();
+            assert!(matches!(err, Error::InvalidField { .. }), "{}", err);
+        }
+
+        assert_eq!(parse_time_unit("ms").unwrap(), TimeUnit::Milliseconds);
+        assert!(parse_time_unit("minutes").is_err());
+    }
+
+    #[test]
+    fn file_formats() {
+        let path = Path::new("data/cpu.CSV");
+        assert_eq!(file_format(None, path).unwrap(), FileFormat::Csv);
+        assert_eq!(
+            file_format(Some("parquet"), path).unwrap(),
+            FileFormat::Parquet
+        );
+
+        let err = file_format(None, Path::new("data/cpu")).unwrap_err();
+        assert!(matches!(err, Error::UnknownFormat { .. }), "{}", err);
+    }
+
+    fn chunk(partition_key: &str, id: u32, row_count: usize) -> ChunkSummary {
+        ChunkSummary {
+            partition_key: partition_key.to_string(),
+            id,
+            storage: ChunkStorage::ClosedMutableBuffer,
+            estimated_bytes: 100,
+            row_count,
+            index_bytes: 0,
+            index_build_micros: 0,
+        }
+    }
+
+    #[test]
+    fn partitions() {
+        let chunks = vec![chunk("b", 0, 1), chunk("a", 0, 2), chunk("a", 1, 3)];
+        assert_eq!(
+            summarize_partitions(&chunks),
+            vec![
+                PartitionSummary {
+                    partition_key: "a".to_string(),
+                    chunks: 2,
+                    estimated_bytes: 200,
+                    row_count: 5,
+                },
+                PartitionSummary {
+                    partition_key: "b".to_string(),
+                    chunks: 1,
+                    estimated_bytes: 100,
+                    row_count: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn tables() {
+        let rows = vec![
+            vec!["2020-11-01".to_string(), "0".to_string(), "".to_string()],
+            vec!["b".to_string(), "10".to_string(), "x".to_string()],
+        ];
+        assert_eq!(
+            format_table(&["PARTITION", "ID", "NOTE"], &rows),
+            "PARTITION   ID  NOTE\n2020-11-01  0\nb           10  x"
+        );
+
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        let formatted = format_rules("foo", &rules);
+        // the values are aligned after the longest rule
+        let value = |rule: &str| {
+            formatted
+                .lines()
+                .find(|line| line.starts_with(&format!("{}  ", rule)))
+                .map(|line| line[rule.len()..].trim().to_string())
+        };
+        assert_eq!(
+            value("store locally").as_deref(),
+            Some("true"),
+            "{}",
+            formatted
+        );
+        assert_eq!(
+            value("retention").as_deref(),
+            Some("forever"),
+            "{}",
+            formatted
+        );
+        assert_eq!(
+            value("buffer size hard").as_deref(),
+            Some("none"),
+            "{}",
+            formatted
+        );
+    }
+
+    #[tokio::test]
+    async fn tokens() {
+        // the token is checked before connecting, so no server is needed
+        let connection = Connection {
+            host: "http://127.0.0.1:8082".to_string(),
+            token: Some("new\nline".to_string()),
+        };
+        let err = connect(&connection).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::Connecting {
+                    source: influxdb_iox_client::Error::InvalidToken
+                }
+            ),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! This module contains the `debug` commands, which inspect the state of a running server to
+//! diagnose problems with it, and repair it.
+
+use generated_types::management::{verified_file::Status, VerifiedFile};
+use influxdb_iox_client::ManagementClient;
+use snafu::{ensure, ResultExt, Snafu};
+
+use super::database::{self, Connection};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    Connecting { source: database::Error },
+
+    #[snafu(display("Error verifying the catalog: {}", source))]
+    VerifyingCatalog { source: influxdb_iox_client::Error },
+
+    #[snafu(display("{} of the persisted files are missing or corrupt", problems))]
+    CorruptCatalog { problems: usize },
+
+    #[snafu(display("Error rebuilding the catalog: {}", source))]
+    RebuildingCatalog { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Error claiming the database: {}", source))]
+    ClaimingDatabase { source: influxdb_iox_client::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Checks the Parquet files of the persisted chunks of database `db_name`, or of every database,
+/// against the sizes and checksums recorded in the catalog. Prints the missing and corrupt files,
+/// and fails if there are any.
+pub async fn verify_catalog(connection: &Connection, db_name: Option<&str>) -> Result<()> {
+    let connection = database::connect(connection).await.context(Connecting)?;
+    let files = ManagementClient::new(connection)
+        .verify_catalog(db_name.unwrap_or_default())
+        .await
+        .context(VerifyingCatalog)?;
+
+    for file in &files {
+        if is_problem(file) {
+            println!("{}", describe(file));
+        }
+    }
+    println!("{}", summarize(&files));
+
+    let problems = files.iter().filter(|file| is_problem(file)).count();
+    ensure!(problems == 0, CorruptCatalog { problems });
+    Ok(())
+}
+
+/// Rebuilds the catalog of database `db_name` from the Parquet files the server persisted to
+/// object storage, printing the files that could not be recovered.
+pub async fn rebuild_catalog(connection: &Connection, db_name: &str) -> Result<()> {
+    let connection = database::connect(connection).await.context(Connecting)?;
+    let rebuilt = ManagementClient::new(connection)
+        .rebuild_catalog(db_name)
+        .await
+        .context(RebuildingCatalog)?;
+
+    for file in &rebuilt.skipped {
+        println!("{}: skipped: {}", file.location, file.reason);
+    }
+    println!(
+        "Rebuilt the catalog of database {} with {} chunks, skipping {} files",
+        db_name,
+        rebuilt.chunks.len(),
+        rebuilt.skipped.len()
+    );
+    Ok(())
+}
+
+/// Makes the server take over the ownership lease of database `db_name` from the server that
+/// holds it, for failovers from a server that is gone before its lease expired.
+pub async fn force_claim(connection: &Connection, db_name: &str) -> Result<()> {
+    let connection = database::connect(connection).await.context(Connecting)?;
+    let token = ManagementClient::new(connection)
+        .force_claim_database(db_name)
+        .await
+        .context(ClaimingDatabase)?;
+
+    println!("Claimed database {}, with lease token {}", db_name, token);
+    Ok(())
+}
+
+fn is_problem(file: &VerifiedFile) -> bool {
+    !matches!(
+        Status::from_i32(file.status),
+        Some(Status::Ok) | Some(Status::Unverified)
+    )
+}
+
+fn describe(file: &VerifiedFile) -> String {
+    match &file.chunk {
+        Some(chunk) => format!(
+            "{}: chunk {} of partition {} in database {}: {}",
+            chunk.location, chunk.id, chunk.partition_key, file.db_name, file.description
+        ),
+        None => format!("database {}: {}", file.db_name, file.description),
+    }
+}
+
+fn summarize(files: &[VerifiedFile]) -> String {
+    let count = |status: Status| {
+        files
+            .iter()
+            .filter(|file| file.status == status as i32)
+            .count()
+    };
+    format!(
+        "Verified {} files: {} ok, {} without checksum, {} missing, {} corrupt",
+        files.len(),
+        count(Status::Ok),
+        count(Status::Unverified),
+        count(Status::Missing),
+        count(Status::Corrupt)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generated_types::management::PersistedChunk;
+
+    #[test]
+    fn reports_problems() {
+        let file = |status: Status, description: &str| VerifiedFile {
+            db_name: "mydb".to_string(),
+            chunk: Some(PersistedChunk {
+                partition_key: "p".to_string(),
+                id: 3,
+                location: "1/mydb/data/p/3/cpu.parquet".to_string(),
+                ..Default::default()
+            }),
+            status: status as i32,
+            description: description.to_string(),
+        };
+        let files = vec![
+            file(Status::Ok, "ok"),
+            file(Status::Unverified, "no checksum recorded"),
+            file(Status::Corrupt, "corrupt: 10 bytes instead of 12"),
+        ];
+
+        let problems: Vec<_> = files.iter().filter(|file| is_problem(file)).collect();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(
+            describe(problems[0]),
+            "1/mydb/data/p/3/cpu.parquet: chunk 3 of partition p in database mydb: \
+             corrupt: 10 bytes instead of 12"
+        );
+        assert_eq!(
+            summarize(&files),
+            "Verified 3 files: 1 ok, 1 without checksum, 0 missing, 1 corrupt"
+        );
+    }
+}
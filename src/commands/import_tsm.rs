@@ -0,0 +1,402 @@
+//! This module contains the command that imports the shards of an InfluxDB 1.x data directory
+//! into the object store of an IOx server, as the persisted chunks of its databases.
+//!
+//! Retention policy `rp` of 1.x database `db` is imported into the IOx database named as the
+//! 1.x `/write` API would name it (see `database_name`), which is created with the default
+//! rules if the server doesn't have it. The rows of each measurement are imported like those of
+//! other bulk imports: they are persisted as Parquet files in the partitions the partition
+//! template of the database puts them in, and registered in the catalog of the database, which
+//! the server stores with its configuration. Once all the rows of shard `id` are imported, the
+//! chunks they were persisted in are recorded at
+//! `<writer id>/<database>/tsm_import/<id>.json`, so an interrupted import can be run again
+//! and skips the shards it already imported. The rows of a shard imported again are merged
+//! with those imported before the interruption by queries, as the chunks overlap.
+//!
+//! The import loads and stores the configuration of the server, which must not be running
+//! meanwhile.
+
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use cluster::{catalog::PersistedChunk, Server as AppServer};
+use data_types::database_rules::DatabaseRules;
+use futures::{channel::mpsc, stream, SinkExt, StreamExt, TryStreamExt};
+use ingest::tsm_import::{self, Shard};
+use object_store::ObjectStore;
+use packers::Error as TableError;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+use tracing::info;
+
+use crate::server::{http_routes::v1::database_name, ConnectionManagerImpl};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Error importing into {}: the object store must be a bucket or a directory, without a path",
+        output
+    ))]
+    OutputWithPath { output: String },
+
+    #[snafu(display("Error loading the configuration of the server: {}", source))]
+    LoadingConfiguration { source: cluster::Error },
+
+    #[snafu(display("Error creating database {}: {}", database, source))]
+    CreatingDatabase {
+        database: String,
+        source: cluster::Error,
+    },
+
+    #[snafu(display("Error finding shards to import: {}", source))]
+    FindingShards { source: tsm_import::Error },
+
+    #[snafu(display("Error importing shard {}: {}", shard, source))]
+    ConvertingShard {
+        shard: u64,
+        source: tsm_import::Error,
+    },
+
+    #[snafu(display("Error importing shard {}: conversion panicked: {}", shard, source))]
+    ConversionPanicked {
+        shard: u64,
+        source: tokio::task::JoinError,
+    },
+
+    #[snafu(display("Error importing shard {}: {}", shard, source))]
+    ImportingRows { shard: u64, source: cluster::Error },
+
+    #[snafu(display("Error writing {} to object storage: {}", location, source))]
+    WritingObject {
+        location: String,
+        source: object_store::Error,
+    },
+
+    #[snafu(display("Error reading {} from object storage: {}", location, source))]
+    ReadingObject {
+        location: String,
+        source: object_store::Error,
+    },
+
+    #[snafu(display("Error serializing the chunks of shard {}: {}", shard, source))]
+    SerializingChunks {
+        shard: u64,
+        source: serde_json::Error,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Describes what to import, and where to
+#[derive(Debug)]
+pub struct ImportConfig {
+    /// The 1.x data directory, holding a directory per database
+    pub data_dir: PathBuf,
+    /// The object store of the server to import into: `s3://bucket`, `gs://bucket` or a
+    /// directory
+    pub output: String,
+    /// The writer id of the server to import into
+    pub writer_id: u32,
+    /// If set, only the shards of this 1.x database are imported
+    pub database: Option<String>,
+}
+
+/// The record of an imported shard
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportedShard {
+    pub source_database: String,
+    pub source_retention_policy: String,
+    pub shard_id: u64,
+    /// The chunks the rows of the shard were persisted in
+    pub chunks: Vec<PersistedChunk>,
+}
+
+/// Imports the shards of the 1.x data directory `config.data_dir`, printing the progress
+/// after each shard
+pub async fn import(config: &ImportConfig) -> Result<()> {
+    info!("TSM import starting for {:?}", config);
+    let (store, prefix) = ObjectStore::from_url(&config.output);
+    ensure!(
+        prefix.is_empty(),
+        OutputWithPath {
+            output: &config.output
+        }
+    );
+    let mut server = AppServer::new(ConnectionManagerImpl::new(None), store);
+    server.set_id(config.writer_id);
+    server
+        .load_configuration(config.writer_id)
+        .await
+        .context(LoadingConfiguration)?;
+
+    let shards: Vec<_> = tsm_import::find_shards(&config.data_dir)
+        .context(FindingShards)?
+        .into_iter()
+        .filter(|shard| {
+            config
+                .database
+                .as_ref()
+                .map_or(true, |database| *database == shard.database)
+        })
+        .collect();
+
+    let total_bytes: u64 = shards.iter().map(|shard| shard.size).sum();
+    println!(
+        "Importing {} shards ({} bytes of TSM files)",
+        shards.len(),
+        total_bytes
+    );
+
+    let start = Instant::now();
+    let mut progress = Progress::new(shards.len(), total_bytes);
+    for shard in shards {
+        let database = database_name(&shard.database, Some(&shard.retention_policy));
+        let location = imported_location(config.writer_id, &database, shard.id);
+
+        if exists(server.store(), &location).await? {
+            progress.skipped(&shard);
+            println!(
+                "{}",
+                progress.report(&shard, start.elapsed(), "already imported")
+            );
+            continue;
+        }
+
+        if server.db_rules(&database).is_none() {
+            server
+                .create_database(&database, DatabaseRules::default())
+                .await
+                .context(CreatingDatabase {
+                    database: &database,
+                })?;
+        }
+        let chunks = import_shard(&server, &database, &shard).await?;
+
+        let imported = ImportedShard {
+            source_database: shard.database.clone(),
+            source_retention_policy: shard.retention_policy.clone(),
+            shard_id: shard.id,
+            chunks,
+        };
+        let json =
+            serde_json::to_vec_pretty(&imported).context(SerializingChunks { shard: shard.id })?;
+        put(server.store(), &location, json).await?;
+
+        progress.imported(&shard);
+        println!("{}", progress.report(&shard, start.elapsed(), "imported"));
+    }
+
+    println!(
+        "Imported {} shards in {:.1}s",
+        progress.shards_done,
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+/// Imports the rows of `shard` into database `database`, returning the chunks they were
+/// persisted in. The shard is converted on a blocking thread, one batch of rows ahead of the
+/// import, so that the memory the import uses doesn't grow with the size of the shard.
+async fn import_shard(
+    server: &AppServer<ConnectionManagerImpl>,
+    database: &str,
+    shard: &Shard,
+) -> Result<Vec<PersistedChunk>> {
+    let id = shard.id;
+    let (mut tx, mut rx) = mpsc::channel(1);
+    let converted = {
+        let shard = shard.clone();
+        tokio::task::spawn_blocking(move || {
+            tsm_import::convert_shard(&shard, move |table| {
+                futures::executor::block_on(tx.send(table)).map_err(TableError::from_other)
+            })
+        })
+    };
+
+    let mut chunks = vec![];
+    while let Some(table) = rx.next().await {
+        let imported = server
+            .import_partitioned_table(database, table)
+            .await
+            .context(ImportingRows { shard: id })?;
+        chunks.extend(imported);
+    }
+
+    converted
+        .await
+        .context(ConversionPanicked { shard: id })?
+        .context(ConvertingShard { shard: id })?;
+    Ok(chunks)
+}
+
+fn imported_location(writer_id: u32, database: &str, shard_id: u64) -> String {
+    format!("{}/{}/tsm_import/{}.json", writer_id, database, shard_id)
+}
+
+async fn put(store: &ObjectStore, location: &str, data: Vec<u8>) -> Result<()> {
+    let len = data.len();
+    let data = Bytes::from(data);
+    store
+        .put(
+            location,
+            stream::once(async move { std::io::Result::Ok(data) }),
+            len,
+        )
+        .await
+        .context(WritingObject { location })
+}
+
+async fn exists(store: &ObjectStore, location: &str) -> Result<bool> {
+    let exists = store
+        .list(Some(location))
+        .await
+        .context(ReadingObject { location })?
+        .try_concat()
+        .await
+        .context(ReadingObject { location })?
+        .iter()
+        .any(|listed| listed == location);
+    Ok(exists)
+}
+
+/// Tracks how much of an import is done
+#[derive(Debug)]
+struct Progress {
+    shards_total: usize,
+    shards_done: usize,
+    bytes_total: u64,
+    bytes_done: u64,
+    /// The bytes of the shards converted by this run, to compute the conversion rate with
+    bytes_converted: u64,
+}
+
+impl Progress {
+    fn new(shards_total: usize, bytes_total: u64) -> Self {
+        Self {
+            shards_total,
+            shards_done: 0,
+            bytes_total,
+            bytes_done: 0,
+            bytes_converted: 0,
+        }
+    }
+
+    fn skipped(&mut self, shard: &Shard) {
+        self.shards_done += 1;
+        self.bytes_done += shard.size;
+    }
+
+    fn imported(&mut self, shard: &Shard) {
+        self.skipped(shard);
+        self.bytes_converted += shard.size;
+    }
+
+    fn report(&self, shard: &Shard, elapsed: Duration, status: &str) -> String {
+        let percent = if self.bytes_total == 0 {
+            100.0
+        } else {
+            self.bytes_done as f64 * 100.0 / self.bytes_total as f64
+        };
+        let rate = self.bytes_converted as f64 / 1_000_000.0 / elapsed.as_secs_f64().max(0.001);
+
+        format!(
+            "[{}/{} shards, {:.1}%, {:.1} MB/s] {}/{} shard {} {}",
+            self.shards_done,
+            self.shards_total,
+            percent,
+            rate,
+            shard.database,
+            shard.retention_policy,
+            shard.id,
+            status
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shard(id: u64, size: u64) -> Shard {
+        Shard {
+            database: "telegraf".to_string(),
+            retention_policy: "weekly".to_string(),
+            id,
+            tsm_files: vec![],
+            size,
+        }
+    }
+
+    #[test]
+    fn progress_report() {
+        let mut progress = Progress::new(2, 400);
+
+        progress.skipped(&shard(1, 100));
+        assert_eq!(
+            progress.report(&shard(1, 100), Duration::from_secs(1), "already imported"),
+            "[1/2 shards, 25.0%, 0.0 MB/s] telegraf/weekly shard 1 already imported"
+        );
+
+        progress.imported(&shard(2, 300));
+        assert_eq!(
+            progress.report(&shard(2, 300), Duration::from_secs(1), "imported"),
+            "[2/2 shards, 100.0%, 0.0 MB/s] telegraf/weekly shard 2 imported"
+        );
+    }
+
+    #[test]
+    fn locations() {
+        let database = database_name("telegraf", Some("weekly"));
+        assert_eq!(
+            imported_location(1, &database, 3),
+            "1/telegraf_weekly/tsm_import/3.json"
+        );
+    }
+
+    #[tokio::test]
+    async fn import_skips_imported_shards() {
+        let data_dir = test_helpers::tmp_dir().unwrap();
+        let output_dir = test_helpers::tmp_dir().unwrap();
+        // a shard without valid TSM files fails the import unless it was already imported
+        let shard_dir = data_dir.path().join("telegraf").join("autogen").join("1");
+        std::fs::create_dir_all(&shard_dir).unwrap();
+        std::fs::write(shard_dir.join("000000001-000000001.tsm"), b"not a TSM file").unwrap();
+
+        let config = ImportConfig {
+            data_dir: data_dir.path().into(),
+            output: output_dir.path().to_str().unwrap().to_string(),
+            writer_id: 1,
+            database: None,
+        };
+        let err = import(&config).await.unwrap_err();
+        assert!(
+            matches!(err, Error::ConvertingShard { shard: 1, .. }),
+            "{}",
+            err
+        );
+
+        let (store, _) = ObjectStore::from_url(&config.output);
+        put(&store, &imported_location(1, "telegraf", 1), b"{}".to_vec())
+            .await
+            .unwrap();
+        import(&config).await.unwrap();
+
+        // other databases are left alone
+        std::fs::remove_dir_all(output_dir.path().join("1").join("telegraf")).unwrap();
+        let config = ImportConfig {
+            database: Some("other".to_string()),
+            ..config
+        };
+        import(&config).await.unwrap();
+
+        // the server's object store is a bucket, not a path in one
+        let config = ImportConfig {
+            output: "gs://bucket/iox".to_string(),
+            ..config
+        };
+        let err = import(&config).await.unwrap_err();
+        assert!(matches!(err, Error::OutputWithPath { .. }), "{}", err);
+    }
+}
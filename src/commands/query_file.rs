@@ -0,0 +1,225 @@
+//! This module contains the `query-file` command, which runs a SQL query over local Parquet
+//! and line protocol files without a server, for example to inspect the files of an export.
+//!
+//! The query engine runs in-process: line protocol files are written into an in-memory write
+//! buffer database, in which each measurement is a table, and Parquet files are added as
+//! tables named after the files. The Parquet files of a table can be spread over several
+//! files of the same name, such as the chunks of an export, as long as their schemas match.
+
+use std::{collections::BTreeMap, io::Read, path::Path, rc::Rc};
+
+use arrow_deps::{
+    arrow::{self, record_batch::RecordBatch},
+    parquet::{
+        self,
+        arrow::arrow_reader::{ArrowReader, ParquetFileArrowReader},
+        file::reader::SerializedFileReader,
+    },
+};
+use influxdb_line_protocol::parse_lines;
+use snafu::{ResultExt, Snafu};
+use storage::Database;
+use tracing::debug;
+use write_buffer::Db;
+
+use crate::commands::{
+    input::{FileType, InputPath, InputReader},
+    sql::{self, OutputFormat},
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error opening input {}", source))]
+    OpenInput { source: super::input::Error },
+
+    #[snafu(display("Error reading {}: {}", name, source))]
+    ReadingLineProtocol {
+        name: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error parsing {}: {}", name, source))]
+    ParsingLineProtocol {
+        name: String,
+        source: influxdb_line_protocol::Error,
+    },
+
+    #[snafu(display("Error loading {}: {}", name, source))]
+    LoadingLineProtocol {
+        name: String,
+        source: write_buffer::Error,
+    },
+
+    #[snafu(display("Error reading {}: {}", name, source))]
+    ReadingParquet {
+        name: String,
+        source: parquet::errors::ParquetError,
+    },
+
+    #[snafu(display("Error converting {} to Arrow: {}", name, source))]
+    ConvertingParquet {
+        name: String,
+        source: arrow::error::ArrowError,
+    },
+
+    #[snafu(display("TSM files can't be queried: convert {} to Parquet first", name))]
+    UnsupportedTsm { name: String },
+
+    #[snafu(display("Error running query: {}", source))]
+    Querying { source: write_buffer::Error },
+
+    #[snafu(display("{}", source))]
+    FormattingResults { source: sql::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Describes what to query
+#[derive(Debug)]
+pub struct QueryFileConfig {
+    /// A file, or a directory searched recursively for `.parquet` and `.lp` files, which may be
+    /// gzipped
+    pub input_path: String,
+    pub query: String,
+    pub format: OutputFormat,
+}
+
+/// The number of rows read from Parquet files at a time
+const BATCH_SIZE: usize = 64 * 1024;
+
+/// Runs the query over the files of `config.input_path`, printing the results
+pub async fn query_file(config: &QueryFileConfig) -> Result<()> {
+    let results = run_query(&config.input_path, &config.query).await?;
+    println!(
+        "{}",
+        sql::format_batches(&results, config.format).context(FormattingResults)?
+    );
+    Ok(())
+}
+
+async fn run_query(input_path: &str, query: &str) -> Result<Vec<RecordBatch>> {
+    let input_path = InputPath::new(input_path, |path| {
+        let name = path.to_string_lossy();
+        let name = name.trim_end_matches(".gz");
+        name.ends_with(".parquet") || name.ends_with(".lp")
+    })
+    .context(OpenInput)?;
+
+    let db = Db::new("local");
+    let mut parquet_tables: BTreeMap<String, Vec<RecordBatch>> = BTreeMap::new();
+    for input_reader in input_path.input_readers() {
+        let input_reader = input_reader.context(OpenInput)?;
+        let name = input_reader.path().to_string_lossy().to_string();
+        debug!("loading {}", name);
+
+        match input_reader.file_type() {
+            FileType::LineProtocol => load_line_protocol(&db, input_reader, &name).await?,
+            FileType::Parquet => {
+                let table = table_name(input_reader.path());
+                let batches = read_parquet(input_reader, &name)?;
+                parquet_tables.entry(table).or_default().extend(batches);
+            }
+            FileType::TSM => return UnsupportedTsm { name }.fail(),
+        }
+    }
+
+    db.query_with_tables(query, &parquet_tables)
+        .await
+        .context(Querying)
+}
+
+async fn load_line_protocol(db: &Db, mut input_reader: InputReader, name: &str) -> Result<()> {
+    let mut lp = String::new();
+    input_reader
+        .read_to_string(&mut lp)
+        .context(ReadingLineProtocol { name })?;
+
+    let lines = parse_lines(&lp)
+        .collect::<Result<Vec<_>, _>>()
+        .context(ParsingLineProtocol { name })?;
+    db.write_lines(&lines)
+        .await
+        .context(LoadingLineProtocol { name })
+}
+
+fn read_parquet(input_reader: InputReader, name: &str) -> Result<Vec<RecordBatch>> {
+    let file_reader = SerializedFileReader::new(input_reader).context(ReadingParquet { name })?;
+    let mut arrow_reader = ParquetFileArrowReader::new(Rc::new(file_reader));
+
+    arrow_reader
+        .get_record_reader(BATCH_SIZE)
+        .context(ReadingParquet { name })?
+        .collect::<Result<Vec<_>, _>>()
+        .context(ConvertingParquet { name })
+}
+
+/// The table the rows of the Parquet file at `path` belong to: `cpu` for `cpu.parquet.gz`
+fn table_name(path: &Path) -> String {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    file_name
+        .trim_end_matches(".gz")
+        .trim_end_matches(".parquet")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn table_names() {
+        assert_eq!(table_name(Path::new("export/db/p/0/cpu.parquet")), "cpu");
+        assert_eq!(table_name(Path::new("mem.parquet.gz")), "mem");
+    }
+
+    #[tokio::test]
+    async fn query_line_protocol() {
+        let dir = test_helpers::tmp_dir().unwrap();
+        fs::write(
+            dir.path().join("metrics.lp"),
+            "cpu,host=a usage=0.5 10\ncpu,host=b usage=0.75 20\nmem used=2 10\n",
+        )
+        .unwrap();
+        // files of other types are ignored
+        fs::write(dir.path().join("notes.txt"), "not line protocol").unwrap();
+
+        let results = run_query(
+            &dir.path().to_string_lossy(),
+            "select host, usage from cpu where usage > 0.6",
+        )
+        .await
+        .unwrap();
+        let formatted = sql::format_batches(&results, OutputFormat::Csv).unwrap();
+        assert_eq!(formatted, "host,usage\nb,0.75");
+
+        let err = run_query(&dir.path().to_string_lossy(), "select * from disk")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Querying { .. }), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn invalid_files() {
+        let dir = test_helpers::tmp_dir().unwrap();
+        let path = dir.path().join("bad.lp");
+        fs::write(&path, "cpu,host=a usage=").unwrap();
+
+        let err = run_query(&path.to_string_lossy(), "select * from cpu")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ParsingLineProtocol { .. }), "{}", err);
+
+        let path = dir.path().join("bad.parquet");
+        fs::write(&path, "not parquet").unwrap();
+
+        let err = run_query(&path.to_string_lossy(), "select * from bad")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ReadingParquet { .. }), "{}", err);
+    }
+}
@@ -0,0 +1,294 @@
+//! This module contains the `replay` command, which sends the requests of a capture file (see
+//! `server::capture`) to a running server, to reproduce the workload the file was captured
+//! from.
+//!
+//! Requests are sent at the intervals they were received at, divided by the speed-up factor,
+//! without waiting for the previous requests to complete, as the clients of the original
+//! server did. With `--as-fast-as-possible`, they are instead sent one at a time, in order.
+//!
+//! Writes and InfluxQL queries are sent to the 1.x `/write` and `/query` endpoints of the HTTP
+//! API, which take the database directly, and SQL queries to the query gRPC API.
+
+use std::{
+    fmt,
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use futures::future;
+use hyper::{client::HttpConnector, header::AUTHORIZATION, Body, Client, Method};
+use influxdb_iox_client::QueryClient;
+use snafu::{ensure, ResultExt, Snafu};
+use tracing::{debug, warn};
+
+use super::database::{self, Connection};
+use crate::server::capture::{self, Record, Request};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error opening {:?}: {}", path, source))]
+    OpeningFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("{}", source))]
+    ReadingRecords { source: capture::Error },
+
+    #[snafu(display("{}", source))]
+    Connecting { source: database::Error },
+
+    #[snafu(display("Invalid speed {}: expected a factor above 0", speed))]
+    InvalidSpeed { speed: f64 },
+
+    #[snafu(display("Error joining request task: {}", source))]
+    JoiningRequest { source: tokio::task::JoinError },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Which capture file to replay, against which server, and how fast
+#[derive(Debug)]
+pub struct ReplayConfig {
+    pub file: PathBuf,
+    /// The URL of the HTTP API, such as `http://127.0.0.1:8080`
+    pub http_host: String,
+    /// How to reach the gRPC API, which the token is also used for
+    pub connection: Connection,
+    /// How many times faster than captured the requests are sent
+    pub speed: f64,
+    /// Send the requests one at a time, ignoring when they were captured
+    pub as_fast_as_possible: bool,
+}
+
+/// Sends captured requests to a server
+#[derive(Debug, Clone)]
+struct Replayer {
+    http: Client<HttpConnector>,
+    http_host: String,
+    authorization: Option<String>,
+    query: QueryClient,
+}
+
+/// How a request fared
+#[derive(Debug, Clone, Copy)]
+struct Outcome {
+    kind: Kind,
+    succeeded: bool,
+    latency: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Write,
+    Sql,
+    InfluxQl,
+}
+
+pub async fn replay(config: &ReplayConfig) -> Result<()> {
+    ensure!(
+        config.speed > 0.0,
+        InvalidSpeed {
+            speed: config.speed
+        }
+    );
+
+    let file = File::open(&config.file).context(OpeningFile { path: &config.file })?;
+    let connection = database::connect(&config.connection)
+        .await
+        .context(Connecting)?;
+    let replayer = Replayer {
+        http: Client::new(),
+        http_host: config.http_host.trim_end_matches('/').to_string(),
+        authorization: config
+            .connection
+            .token
+            .as_ref()
+            .map(|token| format!("Token {}", token)),
+        query: QueryClient::new(connection),
+    };
+
+    let start = Instant::now();
+    let mut first_time = None;
+    let mut outcomes = vec![];
+    let mut in_flight = vec![];
+
+    for record in capture::read_records(BufReader::new(file)) {
+        let record = record.context(ReadingRecords)?;
+
+        if config.as_fast_as_possible {
+            outcomes.push(replayer.clone().send(record).await);
+            continue;
+        }
+
+        // Sleep until the time of the record relative to the first one, sped up
+        let first_time = *first_time.get_or_insert(record.time);
+        let offset = (record.time - first_time).max(0) as f64 / config.speed;
+        let due = start + Duration::from_nanos(offset as u64);
+        tokio::time::delay_until(due.into()).await;
+
+        in_flight.push(tokio::spawn(replayer.clone().send(record)));
+    }
+
+    for outcome in future::join_all(in_flight).await {
+        outcomes.push(outcome.context(JoiningRequest)?);
+    }
+
+    println!("{}", Summary::new(&outcomes, start.elapsed()));
+    Ok(())
+}
+
+impl Replayer {
+    async fn send(mut self, record: Record) -> Outcome {
+        let Record { db, request, .. } = record;
+        let kind = match &request {
+            Request::Write { .. } => Kind::Write,
+            Request::Sql { .. } => Kind::Sql,
+            Request::InfluxQl { .. } => Kind::InfluxQl,
+        };
+
+        let start = Instant::now();
+        let result = match request {
+            Request::Write { lines, precision } => {
+                let mut params = vec![("db", db)];
+                params.extend(precision.map(|precision| ("precision", precision)));
+                self.http_request(Method::POST, "write", &params, lines)
+                    .await
+            }
+            Request::InfluxQl { query } => {
+                let mut params = vec![("q", query)];
+                if !db.is_empty() {
+                    params.push(("db", db));
+                }
+                self.http_request(Method::GET, "query", &params, String::new())
+                    .await
+            }
+            Request::Sql { query } => self
+                .query
+                .query(db, query)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        };
+        let latency = start.elapsed();
+
+        if let Err(e) = &result {
+            warn!("{:?} request failed: {}", kind, e);
+        } else {
+            debug!("{:?} request took {:?}", kind, latency);
+        }
+
+        Outcome {
+            kind,
+            succeeded: result.is_ok(),
+            latency,
+        }
+    }
+
+    async fn http_request(
+        &self,
+        method: Method,
+        endpoint: &str,
+        params: &[(&str, String)],
+        body: String,
+    ) -> Result<(), String> {
+        let query = serde_urlencoded::to_string(params).map_err(|e| e.to_string())?;
+        let mut request = hyper::Request::builder()
+            .method(method)
+            .uri(format!("{}/{}?{}", self.http_host, endpoint, query));
+        if let Some(authorization) = &self.authorization {
+            request = request.header(AUTHORIZATION, authorization.as_str());
+        }
+        let request = request.body(Body::from(body)).map_err(|e| e.to_string())?;
+
+        let response = self
+            .http
+            .request(request)
+            .await
+            .map_err(|e| e.to_string())?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("server responded {}", response.status()))
+        }
+    }
+}
+
+/// The number of requests of each kind that were replayed, and how long they took
+#[derive(Debug)]
+struct Summary {
+    elapsed: Duration,
+    kinds: Vec<(Kind, usize, usize, Duration)>,
+}
+
+impl Summary {
+    fn new(outcomes: &[Outcome], elapsed: Duration) -> Self {
+        let kinds = [Kind::Write, Kind::Sql, Kind::InfluxQl]
+            .iter()
+            .filter_map(|&kind| {
+                let outcomes: Vec<_> = outcomes.iter().filter(|o| o.kind == kind).collect();
+                if outcomes.is_empty() {
+                    return None;
+                }
+                let failed = outcomes.iter().filter(|o| !o.succeeded).count();
+                let total: Duration = outcomes.iter().map(|o| o.latency).sum();
+                Some((kind, outcomes.len(), failed, total / outcomes.len() as u32))
+            })
+            .collect();
+
+        Self { elapsed, kinds }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let requests: usize = self.kinds.iter().map(|(_, count, _, _)| count).sum();
+        write!(
+            f,
+            "Replayed {} requests in {:.3}s",
+            requests,
+            self.elapsed.as_secs_f64()
+        )?;
+        for (kind, count, failed, mean) in &self.kinds {
+            let name = match kind {
+                Kind::Write => "writes",
+                Kind::Sql => "SQL queries",
+                Kind::InfluxQl => "InfluxQL queries",
+            };
+            write!(
+                f,
+                "\n  {}: {} ({} failed), mean latency {:?}",
+                name, count, failed, mean
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let outcome = |kind, succeeded, ms| Outcome {
+            kind,
+            succeeded,
+            latency: Duration::from_millis(ms),
+        };
+        let outcomes = vec![
+            outcome(Kind::Write, true, 10),
+            outcome(Kind::Write, false, 30),
+            outcome(Kind::InfluxQl, true, 5),
+        ];
+
+        assert_eq!(
+            Summary::new(&outcomes, Duration::from_millis(1500)).to_string(),
+            "Replayed 3 requests in 1.500s\n  \
+             writes: 2 (1 failed), mean latency 20ms\n  \
+             InfluxQL queries: 1 (0 failed), mean latency 5ms"
+        );
+    }
+}
@@ -0,0 +1,459 @@
+//! This module contains the `sql` command, an interactive SQL shell for the databases of a
+//! running server. Queries are sent to the query gRPC API, which returns their results as
+//! Arrow IPC streams.
+//!
+//! Statements can span several lines and end with a `;`. Lines starting with a `\` are
+//! commands of the shell, such as `\d` to list the tables of the current database, which are
+//! answered by querying the tables of the `system` schema.
+
+use std::{path::PathBuf, str::FromStr};
+
+use arrow_deps::arrow::{
+    self,
+    array::{
+        ArrayRef, BooleanArray, Float64Array, Int64Array, StringArray, UInt32Array, UInt64Array,
+    },
+    datatypes::DataType,
+    record_batch::RecordBatch,
+};
+use influxdb_iox_client::{ManagementClient, QueryClient};
+use rustyline::{error::ReadlineError, Editor};
+use serde_json::{Map, Value};
+use snafu::{ResultExt, Snafu};
+
+use super::database::{self, Connection};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    Connecting { source: database::Error },
+
+    #[snafu(display("Error reading input: {}", source))]
+    ReadingInput { source: ReadlineError },
+
+    #[snafu(display("Error running query: {}", source))]
+    Querying { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Error listing databases: {}", source))]
+    ListingDatabases { source: influxdb_iox_client::Error },
+
+    #[snafu(display("Error formatting the results: {}", source))]
+    FormattingResults { source: arrow::error::ArrowError },
+
+    #[snafu(display("Unknown output format '{}': expected table, csv or json", format))]
+    UnknownFormat { format: String },
+
+    #[snafu(display("No database selected: use \\c <database>"))]
+    NoDatabase,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// How the results of queries are printed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// An ASCII table
+    Table,
+    /// CSV with a header row
+    Csv,
+    /// A JSON array with an object per row
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format.to_lowercase().as_str() {
+            "table" => Ok(Self::Table),
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            _ => UnknownFormat { format }.fail(),
+        }
+    }
+}
+
+/// Describes how the shell starts
+#[derive(Debug)]
+pub struct SqlConfig {
+    /// The database to run queries against, until another one is selected with `\c`
+    pub db_name: Option<String>,
+    pub format: OutputFormat,
+}
+
+const HELP: &str = r#"Statements can span several lines and end with a ';'.
+
+Commands:
+  \l               List the databases of the server
+  \c <database>    Run queries against <database>
+  \d               List the tables of the current database
+  \d <table>       Describe the columns of <table>
+  \chunks          List the chunks of the current database
+  \format <format> Print results as a table, csv or json
+  \?               Show this help
+  \q               Quit"#;
+
+/// A line of input of the shell
+#[derive(Debug, PartialEq)]
+enum Command {
+    Quit,
+    Help,
+    ListDatabases,
+    UseDatabase(String),
+    ListTables,
+    DescribeTable(String),
+    ListChunks,
+    SetFormat(String),
+    Unknown(String),
+    /// A complete SQL statement
+    Sql(String),
+}
+
+/// Runs the shell until the user quits, printing the results or errors of each command
+pub async fn repl(connection: &Connection, config: SqlConfig) -> Result<()> {
+    let server = database::connect(connection).await.context(Connecting)?;
+    let mut shell = Shell {
+        management_client: ManagementClient::new(server.clone()),
+        query_client: QueryClient::new(server),
+        db_name: config.db_name,
+        format: config.format,
+    };
+
+    let history = history_path();
+    let mut editor = Editor::<()>::new();
+    if let Some(history) = &history {
+        // there is no history the first time the shell runs
+        let _ = editor.load_history(history);
+    }
+
+    println!("Connected to {}. Type \\? for help.", connection.host);
+    let mut statement = String::new();
+    loop {
+        let prompt = match (&shell.db_name, statement.is_empty()) {
+            (_, false) => "...> ".to_string(),
+            (Some(db_name), true) => format!("{}> ", db_name),
+            (None, true) => "> ".to_string(),
+        };
+
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                statement.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e).context(ReadingInput),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str());
+        }
+
+        let command = match parse_line(&mut statement, &line) {
+            Some(command) => command,
+            None => continue,
+        };
+        if command == Command::Quit {
+            break;
+        }
+        if let Err(e) = shell.run(command).await {
+            eprintln!("{}", e);
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
+
+/// The state of the shell
+#[derive(Debug)]
+struct Shell {
+    management_client: ManagementClient,
+    query_client: QueryClient,
+    db_name: Option<String>,
+    format: OutputFormat,
+}
+
+impl Shell {
+    async fn run(&mut self, command: Command) -> Result<()> {
+        match command {
+            Command::Quit => {}
+            Command::Help => println!("{}", HELP),
+            Command::ListDatabases => {
+                let names = self
+                    .management_client
+                    .list_databases()
+                    .await
+                    .context(ListingDatabases)?;
+                for name in names {
+                    println!("{}", name);
+                }
+            }
+            Command::UseDatabase(db_name) => {
+                println!("Running queries against {}", db_name);
+                self.db_name = Some(db_name);
+            }
+            Command::ListTables => self.query(&list_tables_sql()).await?,
+            Command::DescribeTable(table) => self.query(&describe_table_sql(&table)).await?,
+            Command::ListChunks => self.query(&list_chunks_sql()).await?,
+            Command::SetFormat(format) => {
+                self.format = format.parse()?;
+                println!("Printing results as {}", format.to_lowercase());
+            }
+            Command::Unknown(command) => {
+                eprintln!("Unknown command {}. Type \\? for help.", command)
+            }
+            Command::Sql(sql) => self.query(&sql).await?,
+        }
+        Ok(())
+    }
+
+    async fn query(&mut self, sql: &str) -> Result<()> {
+        let db_name = self.db_name.clone().ok_or(Error::NoDatabase)?;
+        let batches = self
+            .query_client
+            .query(db_name, sql)
+            .await
+            .context(Querying)?;
+
+        println!("{}", format_batches(&batches, self.format)?);
+        Ok(())
+    }
+}
+
+/// Adds `line` to the statement being entered, returning the command to run if it is complete
+fn parse_line(statement: &mut String, line: &str) -> Option<Command> {
+    let trimmed = line.trim();
+    if statement.is_empty() {
+        if trimmed.is_empty() {
+            return None;
+        }
+        if trimmed.starts_with('\\') {
+            return Some(parse_command(trimmed));
+        }
+    } else {
+        statement.push('\n');
+    }
+    statement.push_str(line);
+
+    if trimmed.ends_with(';') {
+        let sql = statement.trim().trim_end_matches(';').trim().to_string();
+        statement.clear();
+        Some(Command::Sql(sql))
+    } else {
+        None
+    }
+}
+
+fn parse_command(line: &str) -> Command {
+    let mut parts = line.split_whitespace();
+    let command = parts.next().unwrap_or_default();
+    let argument = parts.next().map(ToString::to_string);
+
+    match (command, argument) {
+        ("\\q", None) => Command::Quit,
+        ("\\?", None) | ("\\h", None) => Command::Help,
+        ("\\l", None) => Command::ListDatabases,
+        ("\\c", Some(db_name)) => Command::UseDatabase(db_name),
+        ("\\d", None) => Command::ListTables,
+        ("\\d", Some(table)) => Command::DescribeTable(table),
+        ("\\chunks", None) => Command::ListChunks,
+        ("\\format", Some(format)) => Command::SetFormat(format),
+        _ => Command::Unknown(line.to_string()),
+    }
+}
+
+fn list_tables_sql() -> String {
+    // every table has a time column, whose count is the number of rows of the table
+    "SELECT table_name, SUM(count) AS row_count FROM system.columns \
+     WHERE column_name = 'time' GROUP BY table_name ORDER BY table_name"
+        .to_string()
+}
+
+fn describe_table_sql(table: &str) -> String {
+    format!(
+        "SELECT column_name, column_type, SUM(count) AS value_count FROM system.columns \
+         WHERE table_name = '{}' GROUP BY column_name, column_type ORDER BY column_name",
+        table.replace('\'', "''")
+    )
+}
+
+fn list_chunks_sql() -> String {
+    "SELECT * FROM system.chunks ORDER BY partition_key, id".to_string()
+}
+
+fn history_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".influxdb_iox_history"))
+}
+
+/// Decodes the Arrow IPC stream returned by the server
+pub(crate) fn format_batches(batches: &[RecordBatch], format: OutputFormat) -> Result<String> {
+    let rows: usize = batches.iter().map(|batch| batch.num_rows()).sum();
+
+    let formatted = match format {
+        OutputFormat::Table => {
+            let table =
+                arrow::util::pretty::pretty_format_batches(batches).context(FormattingResults)?;
+            format!("{}({} rows)", table, rows)
+        }
+        OutputFormat::Csv => {
+            let mut data = vec![];
+            {
+                let mut writer = arrow::csv::Writer::new(&mut data);
+                for batch in batches {
+                    writer.write(batch).context(FormattingResults)?;
+                }
+            }
+            String::from_utf8_lossy(&data).trim_end().to_string()
+        }
+        OutputFormat::Json => {
+            let mut objects = Vec::with_capacity(rows);
+            for batch in batches {
+                let schema = batch.schema();
+                for row in 0..batch.num_rows() {
+                    let object: Map<String, Value> = schema
+                        .fields()
+                        .iter()
+                        .zip(batch.columns())
+                        .map(|(field, column)| (field.name().clone(), json_value(column, row)))
+                        .collect();
+                    objects.push(Value::Object(object));
+                }
+            }
+            serde_json::to_string_pretty(&objects).expect("JSON values are serializable")
+        }
+    };
+    Ok(formatted)
+}
+
+/// Returns the value in `row` of `column`
+fn json_value(column: &ArrayRef, row: usize) -> Value {
+    if column.is_null(row) {
+        return Value::Null;
+    }
+
+    let any = column.as_any();
+    match column.data_type() {
+        DataType::Int64 => any.downcast_ref::<Int64Array>().unwrap().value(row).into(),
+        DataType::UInt32 => any.downcast_ref::<UInt32Array>().unwrap().value(row).into(),
+        DataType::UInt64 => any.downcast_ref::<UInt64Array>().unwrap().value(row).into(),
+        DataType::Float64 => any
+            .downcast_ref::<Float64Array>()
+            .unwrap()
+            .value(row)
+            .into(),
+        DataType::Boolean => any
+            .downcast_ref::<BooleanArray>()
+            .unwrap()
+            .value(row)
+            .into(),
+        DataType::Utf8 => any.downcast_ref::<StringArray>().unwrap().value(row).into(),
+        // IOx tables and the system tables only have the types above
+        _ => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_deps::arrow::datatypes::{Field, Schema};
+    use std::sync::Arc;
+
+    fn batch() -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("host", DataType::Utf8, true),
+            Field::new("usage", DataType::Float64, true),
+            Field::new("time", DataType::Int64, false),
+        ]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(StringArray::from(vec![Some("a"), None])),
+                Arc::new(Float64Array::from(vec![0.5, 0.25])),
+                Arc::new(Int64Array::from(vec![10, 20])),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_lines() {
+        let mut statement = String::new();
+        assert_eq!(parse_line(&mut statement, "  "), None);
+        assert_eq!(parse_line(&mut statement, "\\q"), Some(Command::Quit));
+        assert_eq!(
+            parse_line(&mut statement, "\\c telegraf"),
+            Some(Command::UseDatabase("telegraf".to_string()))
+        );
+        assert_eq!(
+            parse_line(&mut statement, "\\d cpu"),
+            Some(Command::DescribeTable("cpu".to_string()))
+        );
+        assert_eq!(
+            parse_line(&mut statement, "\\x"),
+            Some(Command::Unknown("\\x".to_string()))
+        );
+
+        assert_eq!(parse_line(&mut statement, "select *"), None);
+        assert_eq!(parse_line(&mut statement, "\\q"), None);
+        assert_eq!(
+            parse_line(&mut statement, "from cpu ;"),
+            Some(Command::Sql("select *\n\\q\nfrom cpu".to_string()))
+        );
+        assert!(statement.is_empty());
+
+        assert_eq!(
+            parse_line(&mut statement, "select 1;"),
+            Some(Command::Sql("select 1".to_string()))
+        );
+    }
+
+    #[test]
+    fn metadata_queries() {
+        assert_eq!(
+            describe_table_sql("it's"),
+            "SELECT column_name, column_type, SUM(count) AS value_count FROM system.columns \
+             WHERE table_name = 'it''s' GROUP BY column_name, column_type ORDER BY column_name"
+        );
+        assert!(list_tables_sql().contains("FROM system.columns"));
+        assert!(list_chunks_sql().contains("FROM system.chunks"));
+    }
+
+    #[test]
+    fn output_formats() {
+        let batches = vec![batch()];
+
+        let expected = vec![
+            "+------+-------+------+",
+            "| host | usage | time |",
+            "+------+-------+------+",
+            "| a    | 0.5   | 10   |",
+            "|      | 0.25  | 20   |",
+            "+------+-------+------+",
+            "(2 rows)",
+        ];
+        assert_eq!(
+            format_batches(&batches, OutputFormat::Table).unwrap(),
+            expected.join("\n")
+        );
+
+        assert_eq!(
+            format_batches(&batches, OutputFormat::Csv).unwrap(),
+            "host,usage,time\na,0.5,10\n,0.25,20"
+        );
+
+        let json: Value =
+            serde_json::from_str(&format_batches(&batches, OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"host": "a", "usage": 0.5, "time": 10},
+                {"host": null, "usage": 0.25, "time": 20},
+            ])
+        );
+
+        assert_eq!("JSON".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+}
@@ -1,21 +1,250 @@
 #![deny(rust_2018_idioms)]
 
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 
 use std::env::VarError;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::server::http_routes;
-use crate::server::rpc::storage;
+use crate::server::rpc;
+use crate::server::ConnectionManagerImpl;
+use crate::server::{
+    auth::Authorizer,
+    bucket_mapping::BucketMapping,
+    capture::Capture,
+    governor::{self, Governor, Limits},
+    http_routes,
+    log_filter::LogFilter,
+    mode::Mode,
+    mqtt::{self, MqttConfig},
+    notify::Notifier,
+    pgwire,
+    reload::{Reloader, Settings},
+    rpc::{
+        cache::{self, QueryCache},
+        ingest_limits::{self, IngestLimiter, IngestLimits},
+    },
+    socket_listener,
+    tls::{self, TlsConfig},
+};
 
-use ::storage::exec::Executor as StorageExecutor;
+use chrono::Utc;
+use cluster::{audit::AuditLog, database_store::ServerDatabases, Server as AppServer};
+use data_types::database_rules::DatabaseRules;
+use futures::{future::Either, Future, FutureExt};
+use hyper::server::{accept, accept::Accept, Builder};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
-use write_buffer::{Db, WriteBufferDatabases};
+use object_store::{InMemory, ObjectStore};
+use storage::exec::Executor as StorageExecutor;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+use write_buffer::WriteLimits;
+
+/// How often chunks past the retention period of their database are looked for
+const RETENTION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the mutable buffers are checked for chunks their lifecycle rules say to persist
+const PERSIST_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the continuous queries are checked for windows they haven't covered yet
+const TASK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the ownership leases of the databases last, unless configured otherwise
+pub const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(60);
+
+/// How often a read-only replica loads the catalogs of the writer it follows again, unless
+/// configured otherwise
+pub const DEFAULT_REPLICA_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the signing keys of the OpenID Connect provider are fetched again
+const OIDC_KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// The database the UDP and Unix socket listeners write to, unless configured otherwise
+pub const DEFAULT_SOCKET_DATABASE: &str = "udp";
+
+/// How long the server waits for in-flight work to complete once asked to shut down, unless
+/// configured otherwise
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The options of the server given on the command line
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Whether the server routes the writes of clients, stores data and answers queries, or
+    /// both
+    pub mode: Mode,
+    /// Serve the APIs over TLS with these files
+    pub tls: Option<TlsConfig>,
+    /// A JSON file of authentication tokens to load
+    pub auth_tokens: Option<PathBuf>,
+    /// Allow requests without an authentication token
+    pub allow_anonymous: bool,
+    /// A JSON file of the identity providers that authenticate users, and the roles of their
+    /// groups
+    pub auth_providers: Option<PathBuf>,
+    /// A JSON file mapping InfluxDB 2.0 buckets to databases
+    pub bucket_mappings: Option<PathBuf>,
+    /// Create the database of a bucket when it is first written to
+    pub auto_create_databases: bool,
+    /// The number of chunks a query scans at the same time
+    pub query_parallelism: Option<usize>,
+    /// The number of row groups of Parquet files a query fetches at the same time
+    pub row_group_fetches: Option<usize>,
+    /// The number of threads that execute queries, apart from those that handle requests
+    pub query_threads: Option<usize>,
+    /// Load the functions exported by the WebAssembly modules of this directory, which
+    /// queries can call
+    pub wasm_udf_dir: Option<PathBuf>,
+    /// How long the calls of a WebAssembly function on a batch of rows may run
+    pub wasm_udf_timeout: Option<Duration>,
+    /// The most bytes of memory each WebAssembly module may use
+    pub wasm_udf_memory: Option<usize>,
+    /// How long to wait for in-flight work to complete when shutting down
+    pub shutdown_timeout: Option<Duration>,
+    /// Persist the chunks buffered in memory to object storage when shutting down
+    pub persist_on_shutdown: bool,
+    /// Serve CPU profiles and heap statistics on the /debug/pprof routes
+    pub enable_profiling: bool,
+    /// Capture the writes and queries received to this file, to replay them later
+    pub capture_file: Option<PathBuf>,
+    /// How often to check the persisted Parquet files against their recorded checksums
+    pub verify_interval: Option<Duration>,
+    /// How many responses to metadata requests to cache. None disables the cache
+    pub query_cache_entries: Option<usize>,
+    /// How long responses to metadata requests are cached for at most
+    pub query_cache_max_age: Option<Duration>,
+    /// How many SQL and InfluxQL queries run at the same time at most
+    pub max_queries: Option<usize>,
+    /// How many of the queries running at the same time may be batch queries
+    pub max_batch_queries: Option<usize>,
+    /// Reject the batch queries received while this many of them wait to run
+    pub max_queued_batch_queries: Option<usize>,
+    /// Reject the gRPC write requests whose message is larger than this many bytes
+    pub grpc_max_write_size: Option<usize>,
+    /// How many gRPC write requests per second each token may send on average
+    pub grpc_write_rate: Option<u32>,
+    /// How many gRPC write requests each token may send at once
+    pub grpc_write_burst: Option<u32>,
+    /// How many gRPC write requests are handled at the same time at most
+    pub grpc_max_concurrent_writes: Option<usize>,
+    /// How many requests each gRPC connection has in flight at most
+    pub grpc_max_requests_per_connection: Option<usize>,
+    /// Throttle the writes to a partition once it buffers this many bytes
+    pub partition_write_limit: Option<usize>,
+    /// Throttle the writes to a database once it buffers this many bytes
+    pub buffer_write_limit: Option<usize>,
+    /// Write the messages published to the topics of an MQTT broker to a database
+    pub mqtt: Option<MqttConfig>,
+    /// A JSON file mapping the JSON messages published over MQTT to lines. The messages are
+    /// line protocol if not set
+    pub mqtt_json_mapping: Option<PathBuf>,
+    /// Accept line protocol datagrams on this UDP address
+    pub udp_bind_addr: Option<SocketAddr>,
+    /// Accept line protocol over connections to a Unix domain socket at this path
+    pub unix_socket: Option<PathBuf>,
+    /// The database the lines received by the UDP and Unix socket listeners are written to
+    pub socket_database: Option<String>,
+    /// Serve read-only SQL queries over the PostgreSQL wire protocol on this address
+    pub pg_bind_addr: Option<SocketAddr>,
+    /// Record the administrative and destructive operations requested to this file, or to
+    /// the objects under this `s3://` or `gs://` URL
+    pub audit_log: Option<String>,
+    /// Persist the data and configuration of the databases to this directory, or `s3://` or
+    /// `gs://` bucket, instead of keeping them in memory
+    pub object_store: Option<String>,
+    /// How long the ownership leases claimed on the databases last
+    pub lease_duration: Option<Duration>,
+    /// Serve read-only queries over the databases of the writer with this id, from the
+    /// object store
+    pub replica_of: Option<u32>,
+    /// How often to load the catalogs of the writer a replica follows again
+    pub replica_refresh_interval: Option<Duration>,
+    /// The secret of the token to authenticate with to the servers of the host groups writes
+    /// are replicated to
+    pub replication_token: Option<String>,
+    /// A JSON file of settings that override the options above, read again on SIGHUP
+    pub settings_file: Option<PathBuf>,
+}
+
+pub async fn main(
+    log_filter: LogFilter,
+    config: Config,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Config {
+        mode,
+        tls,
+        auth_tokens,
+        allow_anonymous,
+        auth_providers,
+        bucket_mappings,
+        auto_create_databases,
+        query_parallelism,
+        row_group_fetches,
+        query_threads,
+        wasm_udf_dir,
+        wasm_udf_timeout,
+        wasm_udf_memory,
+        shutdown_timeout,
+        persist_on_shutdown,
+        enable_profiling,
+        capture_file,
+        verify_interval,
+        query_cache_entries,
+        query_cache_max_age,
+        max_queries,
+        max_batch_queries,
+        max_queued_batch_queries,
+        grpc_max_write_size,
+        grpc_write_rate,
+        grpc_write_burst,
+        grpc_max_concurrent_writes,
+        grpc_max_requests_per_connection,
+        partition_write_limit,
+        buffer_write_limit,
+        mqtt,
+        mqtt_json_mapping,
+        udp_bind_addr,
+        unix_socket,
+        socket_database,
+        pg_bind_addr,
+        audit_log,
+        object_store,
+        lease_duration,
+        replica_of,
+        replica_refresh_interval,
+        replication_token,
+        settings_file,
+    } = config;
+
+    // The listeners of a mode the server doesn't run in would only fail their requests
+    if !mode.routes() {
+        for (option, set) in &[
+            ("--mqtt-broker", mqtt.is_some()),
+            ("--udp-bind-addr", udp_bind_addr.is_some()),
+            ("--unix-socket", unix_socket.is_some()),
+        ] {
+            if *set {
+                return Err(format!("{} accepts writes, which a {} doesn't", option, mode).into());
+            }
+        }
+    }
+    if !mode.stores() {
+        for (option, set) in &[
+            ("--pg-bind-addr", pg_bind_addr.is_some()),
+            ("--replica-of", replica_of.is_some()),
+        ] {
+            if *set {
+                return Err(format!("{} serves queries, which a {} doesn't", option, mode).into());
+            }
+        }
+    }
+    info!("Running as {}", mode);
 
-pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     dotenv::dotenv().ok();
 
     let db_dir = match std::env::var("INFLUXDB_IOX_DB_DIR") {
@@ -31,18 +260,498 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     debug!("InfluxDB IOx Server using database directory: {:?}", db_dir);
 
-    let storage = Arc::new(WriteBufferDatabases::new(&db_dir));
-    let dirs = storage.wal_dirs()?;
+    // Queries are executed on their own threads, so that they can't delay the handling of
+    // writes on the threads of the main runtime
+    storage::exec::pool::init(query_threads)?;
+
+    if let Some(dir) = &wasm_udf_dir {
+        let limits = storage::wasm::Limits {
+            batch_timeout: wasm_udf_timeout.unwrap_or(storage::wasm::DEFAULT_BATCH_TIMEOUT),
+            max_memory_bytes: wasm_udf_memory.unwrap_or(storage::wasm::DEFAULT_MAX_MEMORY_BYTES),
+        };
+        let count = storage::wasm::init(dir, limits)?;
+        info!("Loaded {} WebAssembly functions from {:?}", count, dir);
+    }
 
-    // TODO: make recovery of multiple databases multi-threaded
-    for dir in dirs {
-        let db = Db::restore_from_wal(dir).await?;
-        storage.add_db(db).await;
+    let store = match &object_store {
+        Some(url) => {
+            let (store, prefix) = ObjectStore::from_url(url);
+            if !prefix.is_empty() {
+                return Err(
+                    format!("object store {} must be a bucket, without a path", url).into(),
+                );
+            }
+            info!("Persisting to object storage at {}", url);
+            store
+        }
+        None => ObjectStore::new_in_memory(InMemory::new()),
+    };
+
+    // The database configuration, managed through the management gRPC API
+    let mut app_server = AppServer::new(ConnectionManagerImpl::new(replication_token), store);
+    app_server.set_wal_dir(&db_dir);
+    app_server.set_write_limits(WriteLimits {
+        partition_size: partition_write_limit,
+        buffer_size: buffer_write_limit,
+    });
+    if let Some(parallelism) = query_parallelism {
+        app_server.set_query_parallelism(parallelism);
+    }
+    if let Some(fetches) = row_group_fetches {
+        app_server.set_row_group_fetches(fetches);
+    }
+    let lease_duration = lease_duration.unwrap_or(DEFAULT_LEASE_DURATION);
+    // rejects a zero duration, which the renewals below would divide
+    app_server.enable_ownership_leases(cluster::ownership::holder_name(), lease_duration)?;
+    if let Some(location) = audit_log {
+        let audit_log = if location.starts_with("s3://") || location.starts_with("gs://") {
+            let (store, prefix) = ObjectStore::from_url(&location);
+            AuditLog::open_object_store(Arc::new(store), prefix).await?
+        } else {
+            AuditLog::open_file(&location)?
+        };
+        info!(
+            "Recording administrative operations to the audit log in {}",
+            audit_log.location()
+        );
+        app_server.set_audit_log(audit_log);
     }
 
+    match std::env::var("INFLUXDB_IOX_ID") {
+        Ok(id) => {
+            let id = id
+                .parse()
+                .expect("INFLUXDB_IOX_ID environment variable not a valid u32");
+            app_server.set_id(id);
+        }
+        Err(VarError::NotPresent) => {
+            info!("INFLUXDB_IOX_ID not set, set the writer id via the management API");
+        }
+        Err(VarError::NotUnicode(_)) => {
+            panic!("INFLUXDB_IOX_ID environment variable not a valid unicode string")
+        }
+    }
+    let mut authorizer = Authorizer::new(allow_anonymous);
+    if let Some(path) = auth_tokens {
+        let count = authorizer.load_tokens(&path)?;
+        info!("Loaded {} authentication tokens from {:?}", count, path);
+    }
+    if let Some(path) = auth_providers {
+        authorizer.load_providers(&path)?;
+        if let Some(oidc) = authorizer.oidc() {
+            info!(
+                "Accepting the tokens OpenID Connect provider {} issues for {}",
+                oidc.config().issuer,
+                oidc.config().audience
+            );
+        }
+        if let Some(ldap) = authorizer.ldap() {
+            info!(
+                "Authenticating users with LDAP directory {}",
+                ldap.config().url
+            );
+        }
+    }
+    if allow_anonymous {
+        warn!("Anonymous access is allowed, requests without a token can do anything");
+    }
+    let authorizer = Arc::new(authorizer);
+
+    // Keep up with the rotation of the OpenID Connect provider's signing keys. A provider
+    // that can't be reached yet only delays the acceptance of its tokens
+    if authorizer.oidc().is_some() {
+        let oidc_authorizer = Arc::clone(&authorizer);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(OIDC_KEY_REFRESH_INTERVAL);
+            loop {
+                interval.tick().await;
+                match oidc_authorizer.refresh_oidc_keys().await {
+                    Ok(count) => debug!("fetched {} OpenID Connect signing keys", count),
+                    Err(e) => warn!("error fetching OpenID Connect signing keys: {}", e),
+                }
+            }
+        });
+    }
+
+    // A router only routes the writes of the databases configured with rules saying where to,
+    // so it doesn't create the databases written to
+    let buckets = BucketMapping::new(auto_create_databases && mode.stores());
+    if let Some(path) = bucket_mappings {
+        let count = buckets.load(&path)?;
+        info!("Loaded {} bucket mappings from {:?}", count, path);
+    }
+    let buckets = Arc::new(buckets);
+
+    let capture = match capture_file {
+        Some(path) => {
+            let capture = Capture::open(path)?;
+            warn!(
+                "Capturing the writes and queries received to {:?}",
+                capture.path()
+            );
+            Some(Arc::new(capture))
+        }
+        None => None,
+    };
+
+    let cache = query_cache_entries.map(|entries| {
+        let max_age = query_cache_max_age.unwrap_or(cache::DEFAULT_MAX_AGE);
+        info!(
+            "Caching up to {} metadata responses for up to {:?}",
+            entries, max_age
+        );
+        Arc::new(QueryCache::new(entries, max_age))
+    });
+
+    let governor = Arc::new(Governor::new(Limits {
+        max_queries: max_queries.unwrap_or(governor::DEFAULT_MAX_QUERIES),
+        max_batch_queries: max_batch_queries.unwrap_or(governor::DEFAULT_MAX_BATCH_QUERIES),
+        max_queued_batch_queries: max_queued_batch_queries
+            .unwrap_or(governor::DEFAULT_MAX_QUEUED_BATCH_QUERIES),
+    }));
+    let limits = governor.limits();
+    info!(
+        "Running up to {} queries at a time, up to {} of them batch queries, with up to {} \
+         batch queries waiting",
+        limits.max_queries, limits.max_batch_queries, limits.max_queued_batch_queries
+    );
+
+    let limiter = Arc::new(IngestLimiter::new(IngestLimits {
+        max_message_size: grpc_max_write_size.unwrap_or(ingest_limits::DEFAULT_MAX_MESSAGE_SIZE),
+        requests_per_second: grpc_write_rate,
+        burst: grpc_write_burst,
+        max_concurrent_requests: grpc_max_concurrent_writes
+            .unwrap_or(ingest_limits::DEFAULT_MAX_CONCURRENT_REQUESTS),
+        max_requests_per_connection: grpc_max_requests_per_connection
+            .unwrap_or(ingest_limits::DEFAULT_MAX_REQUESTS_PER_CONNECTION),
+    }));
+
     // Fire up the query executor
     let executor = Arc::new(StorageExecutor::default());
 
+    let app_server = Arc::new(RwLock::new(app_server));
+    // Every protocol reads and writes the databases of the server. Those written to before
+    // they are created are created storing locally, unless the server is a router.
+    let default_rules = DatabaseRules {
+        store_locally: true,
+        ..Default::default()
+    };
+    let storage = Arc::new(ServerDatabases::new(
+        Arc::clone(&app_server),
+        Some(default_rules.clone()).filter(|_| mode.stores()),
+    ));
+
+    // Apply the settings file over the command line options, and reload it and the stored
+    // rules of the databases on SIGHUP
+    let defaults = Settings {
+        log_filter: log_filter.current().ok(),
+        query_parallelism,
+        partition_write_limit,
+        buffer_write_limit,
+    };
+    let reloader = Arc::new(Reloader::new(
+        settings_file.clone(),
+        defaults,
+        log_filter.clone(),
+        Arc::clone(&app_server),
+    ));
+    if let Some(path) = &settings_file {
+        reloader.reload().await?;
+        info!("Applied the settings of {:?}", path);
+    }
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(Arc::clone(&reloader)));
+
+    // Resolves once the server is asked to shut down, for both servers to stop accepting
+    // connections
+    let shutdown = shutdown_signal().boxed().shared();
+
+    // Renew the ownership leases of the databases well before they expire
+    let lease_server = Arc::clone(&app_server);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(lease_duration / 3);
+        loop {
+            interval.tick().await;
+            for (db_name, e) in lease_server.read().await.renew_leases().await {
+                warn!("error renewing the lease of database {}: {}", db_name, e);
+            }
+        }
+    });
+
+    // Only a server storing data maintains its chunks and runs its tasks and checks
+    if mode.stores() {
+        // Periodically drop the chunks that are past their database's retention period or their
+        // TTL in the read buffer, purge the rows deleted from persisted chunks, compact the
+        // overlapping and small persisted chunks, and merge the chunks of the partitions with
+        // many in the read buffer
+        let retention_server = Arc::clone(&app_server);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RETENTION_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let dropped = retention_server.read().await.drop_expired_chunks().await;
+                for (db_name, chunk) in dropped {
+                    debug!(
+                        "dropped expired chunk {} of partition {} in database {}",
+                        chunk.id, chunk.partition_key, db_name
+                    );
+                }
+                match retention_server
+                    .read()
+                    .await
+                    .drop_expired_persisted_chunks(Utc::now())
+                    .await
+                {
+                    Ok(dropped) => {
+                        for (db_name, chunk) in dropped {
+                            debug!(
+                                "dropped expired persisted chunk {} of partition {} in database {}",
+                                chunk.id, chunk.partition_key, db_name
+                            );
+                        }
+                    }
+                    Err(e) => warn!("error dropping expired persisted chunks: {}", e),
+                }
+
+                // Rewrite the persisted chunks with deleted rows without them
+                match retention_server.read().await.purge_deleted_rows().await {
+                    Ok(purged) => {
+                        for (db_name, chunk, replacement) in purged {
+                            info!(
+                                "purged deleted rows of chunk {} of partition {} in database {}, \
+                             {} rows left",
+                                chunk.id,
+                                chunk.partition_key,
+                                db_name,
+                                replacement.map_or(0, |chunk| chunk.row_count)
+                            );
+                        }
+                    }
+                    Err(e) => warn!("error purging deleted rows: {}", e),
+                }
+
+                // Fold the chunks persisted from late writes into the chunks they overlap
+                match retention_server
+                    .read()
+                    .await
+                    .compact_overlapping_chunks()
+                    .await
+                {
+                    Ok(compacted) => {
+                        for (db_name, chunks, replacement) in compacted {
+                            info!(
+                                "compacted {} overlapping chunks of partition {} in database {}, \
+                             {} rows left",
+                                chunks.len(),
+                                chunks[0].partition_key,
+                                db_name,
+                                replacement.map_or(0, |chunk| chunk.row_count)
+                            );
+                        }
+                    }
+                    Err(e) => warn!("error compacting overlapping chunks: {}", e),
+                }
+
+                // Merge the small files persisted for each partition into larger ones
+                match retention_server.read().await.compact_small_chunks().await {
+                    Ok(compacted) => {
+                        for (db_name, chunks, replacement) in compacted {
+                            info!(
+                                "compacted {} small chunks of partition {} in database {}, \
+                             {} rows left",
+                                chunks.len(),
+                                chunks[0].partition_key,
+                                db_name,
+                                replacement.map_or(0, |chunk| chunk.row_count)
+                            );
+                        }
+                    }
+                    Err(e) => warn!("error compacting small chunks: {}", e),
+                }
+
+                match retention_server
+                    .read()
+                    .await
+                    .merge_read_buffer_chunks()
+                    .await
+                {
+                    Ok(merged) => {
+                        for (db_name, chunks, chunk) in merged {
+                            debug!(
+                                "merged {} read buffer chunks of partition {} in database {} into \
+                             chunk {}, {} rows left",
+                                chunks.len(),
+                                chunk.partition_key,
+                                db_name,
+                                chunk.id,
+                                chunk.row_count
+                            );
+                        }
+                    }
+                    Err(e) => warn!("error merging read buffer chunks: {}", e),
+                }
+            }
+        });
+
+        // Persist the chunks whose data is old enough, or once the buffers hold too much
+        // unpersisted data, according to the lifecycle rules of their database, and hibernate
+        // the databases idle for long enough
+        let persist_server = Arc::clone(&app_server);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PERSIST_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                match persist_server.read().await.persist_due(Utc::now()).await {
+                    Ok(persisted) => {
+                        for (db_name, chunk) in persisted {
+                            debug!(
+                                "persisted {} rows of table {} of partition {} in database {}",
+                                chunk.row_count, chunk.table_name, chunk.partition_key, db_name
+                            );
+                        }
+                    }
+                    Err(e) => warn!("error persisting chunks: {}", e),
+                }
+
+                match persist_server
+                    .read()
+                    .await
+                    .hibernate_idle_databases(Utc::now())
+                    .await
+                {
+                    Ok(hibernated) => {
+                        for (db_name, chunks) in hibernated {
+                            debug!(
+                                "hibernated database {}, persisting {} chunks",
+                                db_name,
+                                chunks.len()
+                            );
+                        }
+                    }
+                    Err(e) => warn!("error hibernating idle databases: {}", e),
+                }
+            }
+        });
+
+        // Follow the databases of the writer this server is a replica of, as it persists chunks
+        if let Some(owner_id) = replica_of {
+            info!(
+                "Serving read-only replicas of the databases of writer {}",
+                owner_id
+            );
+            let replica_server = Arc::clone(&app_server);
+            let refresh_interval =
+                replica_refresh_interval.unwrap_or(DEFAULT_REPLICA_REFRESH_INTERVAL);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(refresh_interval);
+                loop {
+                    interval.tick().await;
+                    match replica_server
+                        .write()
+                        .await
+                        .refresh_replicas(owner_id)
+                        .await
+                    {
+                        Ok(refresh) => {
+                            for db_name in refresh.added {
+                                info!("replicating database {} of writer {}", db_name, owner_id);
+                            }
+                            for db_name in refresh.removed {
+                                info!("writer {} released database {}", owner_id, db_name);
+                            }
+                            debug!("replicas serve {} persisted chunks", refresh.chunks);
+                        }
+                        Err(e) => warn!("error refreshing replicas of writer {}: {}", owner_id, e),
+                    }
+                }
+            });
+        }
+
+        // Run the continuous queries once the windows they cover have passed
+        let task_server = Arc::clone(&app_server);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TASK_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let runs = task_server.read().await.run_due_tasks(Utc::now()).await;
+                for run in runs {
+                    match run.error {
+                        Some(e) => warn!("error running task {}: {}", run.task, e),
+                        None => debug!("task {} wrote {} rows", run.task, run.rows),
+                    }
+                }
+            }
+        });
+
+        // Evaluate the threshold checks once the windows they cover have passed, and notify their
+        // endpoints of the changes of level
+        let check_server = Arc::clone(&app_server);
+        tokio::spawn(async move {
+            let notifier = Notifier::default();
+            let mut interval = tokio::time::interval(TASK_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let notifications = check_server.read().await.run_due_checks(Utc::now()).await;
+                for notification in notifications {
+                    for e in notifier.send(&notification).await {
+                        warn!("error notifying check {}: {}", notification.check, e);
+                    }
+                }
+            }
+        });
+
+        // Periodically check the persisted files against the catalog, if asked to
+        if let Some(verify_interval) = verify_interval {
+            let verify_server = Arc::clone(&app_server);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(verify_interval);
+                loop {
+                    interval.tick().await;
+                    match verify_server
+                        .read()
+                        .await
+                        .verify_persisted_chunks(None)
+                        .await
+                    {
+                        Ok(checked) => {
+                            for (db_name, chunk, status) in checked {
+                                if status.is_problem() {
+                                    error!(
+                                        "file {} of chunk {} of partition {} in database {} is {}",
+                                        chunk.location,
+                                        chunk.id,
+                                        chunk.partition_key,
+                                        db_name,
+                                        status
+                                    );
+                                }
+                            }
+                        }
+                        Err(e) => warn!("error verifying persisted chunks: {}", e),
+                    }
+                }
+            });
+        }
+    }
+
+    // Write the messages published to the MQTT topics, if asked to
+    if let Some(mqtt) = mqtt {
+        let payload = match mqtt_json_mapping {
+            Some(path) => mqtt::Payload::Json(ingest::json::JsonMapping::load(path)?),
+            None => mqtt::Payload::LineProtocol,
+        };
+        info!(
+            "Writing the messages published to {:?} on MQTT broker {} to database {}",
+            mqtt.topics, mqtt.broker, mqtt.database
+        );
+        tokio::spawn(mqtt::run(
+            mqtt,
+            payload,
+            Arc::clone(&app_server),
+            shutdown.clone(),
+        ));
+    }
+
     // Construct and start up gRPC server
 
     let grpc_bind_addr: SocketAddr = match std::env::var("INFLUXDB_IOX_GRPC_BIND_ADDR") {
@@ -55,9 +764,46 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
     };
 
-    let grpc_server = storage::make_server(grpc_bind_addr, storage.clone(), executor);
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let grpc_tls = tls.as_ref().map(TlsConfig::grpc_config).transpose()?;
+    // The gRPC server is started right away so that the progress of the replays can be
+    // followed through the operations API
+    let grpc_server = tokio::spawn(rpc::make_server(
+        grpc_bind_addr,
+        grpc_tls,
+        mode,
+        authorizer.clone(),
+        buckets.clone(),
+        storage.clone(),
+        executor.clone(),
+        Arc::clone(&app_server),
+        capture.clone(),
+        cache,
+        Arc::clone(&governor),
+        limiter,
+        shutdown.clone(),
+    ));
 
-    info!("gRPC server listening on http://{}", grpc_bind_addr);
+    info!("gRPC server listening on {}://{}", scheme, grpc_bind_addr);
+
+    // Load the databases and replay their WAL, each replay as a job of the operations API.
+    // The other APIs wait for the lock to be released, so they start with every database.
+    {
+        let mut app_server = app_server.write().await;
+        if let Some(id) = app_server.id() {
+            app_server.load_configuration(id).await?;
+            let created = if mode.stores() {
+                app_server.replay_wals(&default_rules).await?
+            } else {
+                vec![]
+            };
+            if !created.is_empty() {
+                info!("Created databases {:?} from their WAL", created);
+                app_server.store_configuration().await?;
+            }
+            info!("Loaded {} databases", app_server.db_names().len());
+        }
+    }
 
     // Construct and start up HTTP server
 
@@ -71,24 +817,199 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
     };
 
-    let make_svc = make_service_fn(move |_conn| {
-        let storage = storage.clone();
+    // Accept line protocol on the UDP and Unix socket listeners, once the databases they
+    // write to have been replayed
+    let socket_database = socket_database.unwrap_or_else(|| DEFAULT_SOCKET_DATABASE.to_string());
+    if let Some(addr) = udp_bind_addr {
+        let socket = UdpSocket::bind(addr).await?;
+        info!(
+            "Writing the line protocol received on udp://{} to database {}",
+            addr, socket_database
+        );
+        tokio::spawn(socket_listener::serve_udp(
+            socket,
+            socket_database.clone(),
+            Arc::clone(&storage),
+            shutdown.clone(),
+        ));
+    }
+    if let Some(path) = unix_socket {
+        let listener = socket_listener::bind_unix(&path)?;
+        info!(
+            "Writing the line protocol received on unix://{} to database {}",
+            path.display(),
+            socket_database
+        );
+        tokio::spawn(socket_listener::serve_unix(
+            listener,
+            socket_database,
+            Arc::clone(&storage),
+            shutdown.clone(),
+        ));
+    }
+
+    // Serve queries to PostgreSQL clients, if asked to
+    if let Some(addr) = pg_bind_addr {
+        let listener = TcpListener::bind(addr).await?;
+        info!("PostgreSQL wire protocol listening on {}", addr);
+        tokio::spawn(pgwire::serve(
+            listener,
+            Arc::clone(&app_server),
+            authorizer.clone(),
+            capture.clone(),
+            Arc::clone(&governor),
+            shutdown.clone(),
+        ));
+    }
+
+    let state = Arc::new(http_routes::State {
+        mode,
+        storage: Arc::clone(&storage),
+        executor,
+        log_filter,
+        authorizer,
+        buckets,
+        capture,
+        governor,
+        profiling: enable_profiling,
+        reloader: Some(reloader),
+    });
+    let server = match tls {
+        Some(tls) => {
+            let acceptor = tls.http_acceptor()?;
+            let listener = TcpListener::bind(bind_addr).await?;
+            let incoming = accept::from_stream(tls::incoming(listener, acceptor));
+            let builder = Server::builder(incoming);
+            serve_http(builder, state, shutdown.clone()).boxed()
+        }
+        None => {
+            let builder = Server::bind(&bind_addr);
+            serve_http(builder, state, shutdown.clone()).boxed()
+        }
+    };
+    info!("Listening on {}://{}", scheme, bind_addr);
+
+    // Serve until asked to shut down, and then give the requests in flight until the deadline
+    // to complete
+    let servers = futures::future::join(grpc_server, server);
+    futures::pin_mut!(servers);
+    let deadline = match futures::future::select(shutdown, servers).await {
+        Either::Left(((), servers)) => {
+            let timeout = shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+            info!(
+                "Shutting down, waiting up to {:?} for in-flight work",
+                timeout
+            );
+            let deadline = Instant::now() + timeout;
+            match tokio::time::timeout_at(deadline, servers).await {
+                Ok((grpc_server, server)) => {
+                    grpc_server??;
+                    server?;
+                }
+                Err(_) => warn!("Requests still in flight at the shutdown deadline were dropped"),
+            }
+            deadline
+        }
+        Either::Right(((grpc_server, server), _)) => {
+            grpc_server??;
+            server?;
+            Instant::now() + shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT)
+        }
+    };
+
+    drain(app_server, persist_on_shutdown, deadline).await
+}
+
+/// Resolves when the process receives SIGTERM, as sent by Kubernetes to stop a pod, or SIGINT
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("installing SIGTERM handler");
+        tokio::select! {
+            _ = terminate.recv() => info!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Received Ctrl-C");
+    }
+}
+
+/// Reloads the settings file and the stored rules of the databases each time the process
+/// receives SIGHUP
+#[cfg(unix)]
+async fn reload_on_hangup(reloader: Arc<Reloader>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup()).expect("installing SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading");
+        if let Err(e) = reloader.reload().await {
+            error!("error reloading: {}", e);
+        }
+    }
+}
+
+/// Makes the data written so far durable once the servers no longer accept requests: syncs
+/// the WAL of every database, persists the chunks of the mutable buffers if
+/// `persist_on_shutdown` is set and waits for the background jobs still running, all before
+/// `deadline`.
+async fn drain(
+    app_server: Arc<RwLock<AppServer<ConnectionManagerImpl>>>,
+    persist_on_shutdown: bool,
+    deadline: Instant,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let app_server = app_server.read().await;
+    app_server.sync_wals().await?;
+    info!("Synced the WAL of every database");
+
+    if persist_on_shutdown {
+        match tokio::time::timeout_at(deadline, app_server.persist_buffers()).await {
+            Ok(persisted) => info!("Persisted {} chunks to object storage", persisted?.len()),
+            Err(_) => warn!("Persisting the buffered chunks did not complete before the deadline"),
+        }
+    }
+
+    for job in app_server.jobs().list() {
+        if job.status().is_complete() {
+            continue;
+        }
+        info!("Waiting for {} to complete", job.description());
+        if tokio::time::timeout_at(deadline, job.join()).await.is_err() {
+            warn!("Cancelling {} at the shutdown deadline", job.description());
+            job.cancel();
+        }
+    }
+
+    Ok(())
+}
+
+/// Serves the HTTP API on the connections accepted by `builder`, until `shutdown` resolves and
+/// the requests in flight have completed
+async fn serve_http<I>(
+    builder: Builder<I>,
+    state: Arc<http_routes::State<ServerDatabases<ConnectionManagerImpl>>>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), hyper::Error>
+where
+    I: Accept + Send,
+    I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let make_svc = make_service_fn(move |_conn: &I::Conn| {
+        let state = state.clone();
         async move {
             Ok::<_, http::Error>(service_fn(move |req| {
-                let state = storage.clone();
+                let state = state.clone();
                 http_routes::service(req, state)
             }))
         }
     });
 
-    let server = Server::bind(&bind_addr).serve(make_svc);
-    info!("Listening on http://{}", bind_addr);
-
-    // Wait for both the servers to complete
-    let (grpc_server, server) = futures::future::join(grpc_server, server).await;
-
-    grpc_server?;
-    server?;
-
-    Ok(())
+    builder
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown)
+        .await
 }
@@ -7,22 +7,38 @@
     clippy::use_self
 )]
 
+use std::path::Path;
+use std::time::Duration;
+
 use clap::{crate_authors, crate_version, value_t, App, Arg, ArgMatches, SubCommand};
 use ingest::parquet::writer::CompressionLevel;
+use server::{log_filter::LogFilter, mode::Mode, mqtt::MqttConfig, tls::TlsConfig};
 use tokio::runtime::Runtime;
 use tracing::{debug, error, info, warn};
+use tracing_subscriber::{prelude::*, reload, EnvFilter};
 
 mod panic;
 pub mod server;
 
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
 mod commands {
     pub mod convert;
+    pub mod database;
+    pub mod debug;
     pub mod file_meta;
+    pub mod import_tsm;
     mod input;
+    pub mod query_file;
+    pub mod replay;
+    pub mod sql;
     pub mod stats;
     pub mod write_buffer_server;
 }
 
+use commands::write_buffer_server;
 use panic::SendPanicsToTracing;
 
 enum ReturnCode {
@@ -30,14 +46,28 @@ enum ReturnCode {
     MetadataDumpFailed = 2,
     StatsFailed = 3,
     ServerExitedAbnormally = 4,
+    ImportFailed = 5,
+    DatabaseCommandFailed = 6,
+    QueryFailed = 7,
+    ReplayFailed = 8,
+    DebugCommandFailed = 9,
 }
 
 fn main() -> Result<(), std::io::Error> {
     let help = r#"InfluxDB IOx server and command line tools
 
 Examples:
-    # Run the InfluxDB IOx server:
-    influxdb_iox
+    # Run the InfluxDB IOx server, accepting the tokens in tokens.json:
+    influxdb_iox --auth-tokens tokens.json
+
+    # Run the InfluxDB IOx server, also accepting the users of the identity providers in providers.json:
+    influxdb_iox --auth-tokens tokens.json --auth-providers providers.json
+
+    # Run the InfluxDB IOx server, recording database, rules, delete and token changes to audit.jsonl:
+    influxdb_iox --auth-tokens tokens.json --audit-log audit.jsonl
+
+    # Run the InfluxDB IOx server for development, without authentication:
+    influxdb_iox --allow-anonymous
 
     # Run the InfluxDB IOx server with extra verbose logging
     influxdb_iox -v
@@ -45,6 +75,28 @@ Examples:
     # Run InfluxDB IOx with full debug logging specified with RUST_LOG
     RUST_LOG=debug influxdb_iox
 
+    # Run the InfluxDB IOx server, serving its APIs over TLS
+    influxdb_iox --tls-cert server.pem --tls-key server.key
+
+    # Run the InfluxDB IOx server, exporting traces to a local Jaeger agent
+    influxdb_iox -v --traces-exporter jaeger
+
+    # Run the InfluxDB IOx server, capturing the writes and queries it receives to workload.jsonl
+    influxdb_iox --capture-file workload.jsonl
+
+    # Run the InfluxDB IOx server, writing the JSON readings published to sensors/# to database iot
+    influxdb_iox --mqtt-broker localhost:1883 --mqtt-topic 'sensors/#' --mqtt-database iot \
+        --mqtt-json-mapping sensors.json
+
+    # Run the InfluxDB IOx server, writing the line protocol datagrams received on port 8089 to database agents
+    influxdb_iox --udp-bind-addr 127.0.0.1:8089 --socket-database agents
+
+    # Run the InfluxDB IOx server, serving SQL queries to PostgreSQL clients such as psql on port 5432
+    influxdb_iox --pg-bind-addr 127.0.0.1:5432
+
+    # Run the InfluxDB IOx server as a router, which only accepts and routes the writes of clients
+    influxdb_iox --mode router
+
     # converts line protocol formatted data in temperature.lp to out.parquet
     influxdb_iox convert temperature.lp out.parquet
 
@@ -53,6 +105,41 @@ Examples:
 
     # Dumps storage statistics about out.parquet to stdout
     influxdb_iox stats out.parquet
+
+    # Runs a SQL query over the Parquet files of an export, without a server
+    influxdb_iox query-file lake/mydb "SELECT host, usage FROM cpu WHERE usage > 0.9"
+
+    # Imports the shards of the InfluxDB 1.x data directory /var/lib/influxdb/data into the
+    # databases of the stopped server with writer id 1, which persists to S3 bucket "bucket"
+    influxdb_iox import-tsm /var/lib/influxdb/data s3://bucket --writer-id 1
+
+    # Exports the cpu table of database mydb to S3 as Parquet files, from a running server
+    influxdb_iox database export mydb --table cpu --start 2020-11-01T00:00:00Z --output s3://bucket/lake
+
+    # Backs database mydb up to S3, and restores it on another server as mydb_clone
+    influxdb_iox database snapshot mydb --output s3://backups/iox
+    influxdb_iox database restore mydb_clone --input s3://backups/iox/mydb --host http://other:8082
+
+    # Imports cpu.csv into the cpu table of database mydb, with tag host and float field usage
+    influxdb_iox database import mydb cpu.csv --table cpu --tag host --field usage:float --time-unit s
+
+    # Lists the chunks of database mydb as JSON, from a running server
+    influxdb_iox database chunk list mydb --json
+
+    # Runs SQL queries against database mydb of a running server in an interactive shell
+    influxdb_iox sql mydb
+
+    # Replays the writes and queries captured in workload.jsonl against a running server, 10 times faster
+    influxdb_iox replay workload.jsonl --speed 10
+
+    # Checks the Parquet files persisted by a running server for database mydb against its catalog
+    influxdb_iox debug verify-catalog mydb
+
+    # Rebuilds the catalog of database mydb from the Parquet files a running server persisted
+    influxdb_iox debug rebuild-catalog mydb
+
+    # Takes over database mydb from the server holding its ownership lease
+    influxdb_iox debug force-claim mydb
 "#;
 
     let matches = App::new(help)
@@ -116,6 +203,473 @@ Examples:
                         .help("Include detailed information per file")
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("query-file")
+                .about("Run a SQL query over local Parquet and line protocol files, without a \
+                        server. If a directory is specified, queries all files recursively")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("The input filename or directory to read from. Each measurement \
+                               of a .lp file is a table, and each .parquet file is a table \
+                               named after the file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("QUERY")
+                        .help("The SQL query to run")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["table", "csv", "json"])
+                        .default_value("table")
+                        .help("How to print the results of the query"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-tsm")
+                .about("Import the shards of an InfluxDB 1.x data directory into the databases of \
+                        a server, which must not be running. Shards imported by a previous run \
+                        are skipped")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("The InfluxDB 1.x data directory to import")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .help("The object store of the server: s3://bucket, gs://bucket or a \
+                               directory")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("writer-id")
+                        .long("writer-id")
+                        .takes_value(true)
+                        .required(true)
+                        .env("INFLUXDB_IOX_ID")
+                        .help("The writer id of the server"),
+                )
+                .arg(
+                    Arg::with_name("database")
+                        .long("database")
+                        .takes_value(true)
+                        .help("Only import the shards of this InfluxDB 1.x database"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("database")
+                .about("Manage the databases of a running server through its gRPC API")
+                .arg(host_arg().global(true))
+                .arg(token_arg().global(true))
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .global(true)
+                        .help("Print databases, partitions and chunks as JSON"),
+                )
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create a database")
+                        .arg(
+                            Arg::with_name("DATABASE")
+                                .help("The name of the database")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("rules")
+                                .long("rules")
+                                .takes_value(true)
+                                .help("A JSON file with the rules of the database. By default, \
+                                       the database stores writes locally and answers queries \
+                                       from them"),
+                        )
+                        .arg(
+                            Arg::with_name("template")
+                                .long("template")
+                                .takes_value(true)
+                                .help("Take the rules from the rules template of this name, \
+                                       apart from the fields the rules file lists as overrides. \
+                                       The database follows the changes of the template"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("List the databases"))
+                .subcommand(
+                    SubCommand::with_name("get")
+                        .about("Show the rules of a database")
+                        .arg(
+                            Arg::with_name("DATABASE")
+                                .help("The name of the database")
+                                .required(true)
+                                .index(1),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("partition")
+                        .about("Inspect the partitions of a database")
+                        .subcommand(
+                            SubCommand::with_name("list")
+                                .about("List the partitions of a database, with the number \
+                                        of chunks and rows of each")
+                                .arg(
+                                    Arg::with_name("DATABASE")
+                                        .help("The name of the database")
+                                        .required(true)
+                                        .index(1),
+                                ),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("chunk")
+                        .about("Inspect and move the chunks of a database")
+                        .subcommand(
+                            SubCommand::with_name("list")
+                                .about("List the chunks of a database")
+                                .arg(
+                                    Arg::with_name("DATABASE")
+                                        .help("The name of the database")
+                                        .required(true)
+                                        .index(1),
+                                )
+                                .arg(
+                                    Arg::with_name("partition")
+                                        .long("partition")
+                                        .takes_value(true)
+                                        .help("Only list the chunks of the partition with this \
+                                               key"),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("close")
+                                .about("Close the open chunk of a partition, so that writes \
+                                        go into a new chunk")
+                                .arg(
+                                    Arg::with_name("DATABASE")
+                                        .help("The name of the database")
+                                        .required(true)
+                                        .index(1),
+                                )
+                                .arg(
+                                    Arg::with_name("PARTITION")
+                                        .help("The key of the partition")
+                                        .required(true)
+                                        .index(2),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("persist")
+                                .about("Write a closed chunk out to object storage")
+                                .arg(
+                                    Arg::with_name("DATABASE")
+                                        .help("The name of the database")
+                                        .required(true)
+                                        .index(1),
+                                )
+                                .arg(
+                                    Arg::with_name("PARTITION")
+                                        .help("The key of the partition")
+                                        .required(true)
+                                        .index(2),
+                                )
+                                .arg(
+                                    Arg::with_name("CHUNK_ID")
+                                        .help("The id of the chunk")
+                                        .required(true)
+                                        .index(3),
+                                ),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("Export the data of a database as Parquet files, one per table \
+                                of each chunk, including the chunks not yet persisted")
+                        .arg(
+                            Arg::with_name("DATABASE")
+                                .help("The database to export")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("table")
+                                .long("table")
+                                .takes_value(true)
+                                .help("Only export this table"),
+                        )
+                        .arg(
+                            Arg::with_name("partition")
+                                .long("partition")
+                                .takes_value(true)
+                                .help("Only export the chunks of the partition with this key"),
+                        )
+                        .arg(
+                            Arg::with_name("start")
+                                .long("start")
+                                .takes_value(true)
+                                .help("Only export rows at or after this time, as an RFC 3339 \
+                                       timestamp or nanoseconds since the epoch"),
+                        )
+                        .arg(
+                            Arg::with_name("stop")
+                                .long("stop")
+                                .takes_value(true)
+                                .help("Only export rows before this time, as an RFC 3339 \
+                                       timestamp or nanoseconds since the epoch"),
+                        )
+                        .arg(
+                            Arg::with_name("output")
+                                .long("output")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Where the server writes the files to: s3://bucket/prefix, \
+                                       gs://bucket/prefix or a directory of the server"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("snapshot")
+                        .about("Take a snapshot of a database to another object store: its \
+                                rules, its catalog and the Parquet files of its persisted chunks")
+                        .arg(
+                            Arg::with_name("DATABASE")
+                                .help("The database to take a snapshot of")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("output")
+                                .long("output")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Where the server writes the snapshot to, under the name \
+                                       of the database: s3://bucket/prefix, gs://bucket/prefix \
+                                       or a directory of the server"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("restore")
+                        .about("Create a database from a snapshot, possibly taken by another \
+                                server")
+                        .arg(
+                            Arg::with_name("DATABASE")
+                                .help("The database to create")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("input")
+                                .long("input")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Where the snapshot is: the output it was taken to \
+                                       followed by the name of the database snapshotted"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Import CSV or Parquet files into a database, each one as a \
+                                persisted chunk")
+                        .arg(
+                            Arg::with_name("DATABASE")
+                                .help("The database to import into")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("FILES")
+                                .help("The files to import")
+                                .required(true)
+                                .multiple(true)
+                                .index(2),
+                        )
+                        .arg(
+                            Arg::with_name("format")
+                                .long("format")
+                                .takes_value(true)
+                                .possible_values(&["csv", "parquet"])
+                                .help("The format of the files, if not given by their extension"),
+                        )
+                        .arg(
+                            Arg::with_name("table")
+                                .long("table")
+                                .takes_value(true)
+                                .required(true)
+                                .help("The table to import the rows into"),
+                        )
+                        .arg(
+                            Arg::with_name("tag")
+                                .long("tag")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("A column to import as a tag"),
+                        )
+                        .arg(
+                            Arg::with_name("field")
+                                .long("field")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("A column to import as a field, as \
+                                       <name>:<float|integer|string|boolean>"),
+                        )
+                        .arg(
+                            Arg::with_name("time_column")
+                                .long("time-column")
+                                .takes_value(true)
+                                .default_value("time")
+                                .help("The column holding the timestamp of each row"),
+                        )
+                        .arg(
+                            Arg::with_name("time_unit")
+                                .long("time-unit")
+                                .takes_value(true)
+                                .possible_values(&["s", "ms", "us", "ns"])
+                                .default_value("ns")
+                                .help("The unit of integer timestamps"),
+                        )
+                        .arg(
+                            Arg::with_name("partition")
+                                .long("partition")
+                                .takes_value(true)
+                                .help(
+                                    "The key of the partition to add the chunks to. If not set, \
+                                     the rows are partitioned like the writes to the database",
+                                ),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("load-dimension")
+                        .about("Load a CSV file into a database as a dimension table, which \
+                                queries can join with its measurements")
+                        .arg(
+                            Arg::with_name("DATABASE")
+                                .help("The database to load the table into")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("TABLE")
+                                .help("The name of the table, which replaces the table of \
+                                       the same name if any")
+                                .required(true)
+                                .index(2),
+                        )
+                        .arg(
+                            Arg::with_name("FILE")
+                                .help("The CSV file, whose first line names the columns")
+                                .required(true)
+                                .index(3),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sql")
+                .about("Run SQL queries against the databases of a running server in an \
+                        interactive shell")
+                .arg(host_arg())
+                .arg(token_arg())
+                .arg(
+                    Arg::with_name("DATABASE")
+                        .help("The database to run queries against, which can be changed \
+                               with \\c <database>")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["table", "csv", "json"])
+                        .default_value("table")
+                        .help("How to print the results of queries"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Send the writes and queries captured with --capture-file to a running \
+                        server, as spaced in time as they were received")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("The capture file to replay")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("http-host")
+                        .long("http-host")
+                        .takes_value(true)
+                        .env("INFLUXDB_IOX_HTTP_HOST")
+                        .default_value("http://127.0.0.1:8080")
+                        .help("The URL of the HTTP API of the server, which writes and InfluxQL \
+                               queries are sent to"),
+                )
+                .arg(host_arg())
+                .arg(token_arg())
+                .arg(
+                    Arg::with_name("speed")
+                        .long("speed")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("How many times faster than they were captured to send the requests"),
+                )
+                .arg(
+                    Arg::with_name("as-fast-as-possible")
+                        .long("as-fast-as-possible")
+                        .help("Send the requests one after the other, each once the previous one \
+                               completed, ignoring when they were captured"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Diagnose problems with a running server")
+                .subcommand(
+                    SubCommand::with_name("verify-catalog")
+                        .about("Check the Parquet files of the persisted chunks against the sizes \
+                                and checksums recorded in the catalog, reporting the missing and \
+                                corrupt files")
+                        .arg(
+                            Arg::with_name("DATABASE")
+                                .help("The database whose catalog to verify. Defaults to all \
+                                       databases")
+                                .index(1),
+                        )
+                        .arg(host_arg())
+                        .arg(token_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("rebuild-catalog")
+                        .about("Rebuild the catalog of a database from the Parquet files \
+                                persisted under its prefix in object storage, for when the \
+                                catalog is lost or corrupt")
+                        .arg(
+                            Arg::with_name("DATABASE")
+                                .help("The database whose catalog to rebuild")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(host_arg())
+                        .arg(token_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("force-claim")
+                        .about("Take over the ownership lease of a database even if another \
+                                server holds it, for failovers from a server that is gone. The \
+                                other server stops writing to object storage once it notices")
+                        .arg(
+                            Arg::with_name("DATABASE")
+                                .help("The database to claim")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(host_arg())
+                        .arg(token_arg()),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("server")
                 .about("Runs in server mode (default)")
@@ -127,22 +681,447 @@ Examples:
         .arg(Arg::with_name("num-threads").long("num-threads").takes_value(true).help(
             "Set the maximum number of threads to use. Defaults to the number of cores on the system",
         ))
+        .arg(Arg::with_name("query-threads").long("query-threads").takes_value(true)
+            .env("INFLUXDB_IOX_QUERY_THREADS").help(
+            "The number of threads that execute queries, separate from the threads set by \
+                       --num-threads that handle requests and writes. Defaults to the number of cores on the system",
+        ))
+        .arg(Arg::with_name("wasm-udf-dir").long("wasm-udf-dir").takes_value(true)
+            .env("INFLUXDB_IOX_WASM_UDF_DIR").help(
+            "A directory of WebAssembly modules, whose exported functions of f64 and i64 values \
+                       can be called from SQL queries",
+        ))
+        .arg(Arg::with_name("wasm-udf-timeout").long("wasm-udf-timeout").takes_value(true)
+            .env("INFLUXDB_IOX_WASM_UDF_TIMEOUT").help(
+            "How many milliseconds the calls of a WebAssembly function on a batch of rows may run \
+                       before they are interrupted and the query fails. Defaults to 1000",
+        ))
+        .arg(Arg::with_name("wasm-udf-memory").long("wasm-udf-memory").takes_value(true)
+            .env("INFLUXDB_IOX_WASM_UDF_MEMORY").help(
+            "The most bytes of memory each WebAssembly module may use. Defaults to 16MiB",
+        ))
+        .arg(Arg::with_name("partition-write-limit").long("partition-write-limit").takes_value(true)
+            .env("INFLUXDB_IOX_PARTITION_WRITE_LIMIT").help(
+            "Reject writes to a partition with 429 Too Many Requests once it buffers this many bytes, \
+                       until it is persisted. Unlimited by default",
+        ))
+        .arg(Arg::with_name("buffer-write-limit").long("buffer-write-limit").takes_value(true)
+            .env("INFLUXDB_IOX_BUFFER_WRITE_LIMIT").help(
+            "Reject writes to a database with 429 Too Many Requests once it buffers this many bytes, \
+                       until it is persisted. Unlimited by default",
+        ))
+        .arg(Arg::with_name("mode").long("mode").takes_value(true).env("INFLUXDB_IOX_MODE")
+            .possible_values(&["router", "database", "all"]).default_value("all").help(
+            "What the server runs as. A router accepts the writes of clients and routes them, a \
+                       database stores data and answers queries, and all does both. The APIs of \
+                       the other modes respond with UNIMPLEMENTED",
+        ))
+        .arg(Arg::with_name("log-format").long("log-format").takes_value(true)
+            .possible_values(&["text", "json"]).default_value("text").help(
+            "How to format log lines. With json, each line is an object including the fields of \
+                       the spans (such as the request id) the log was emitted in",
+        ))
+        .arg(Arg::with_name("tls-cert").long("tls-cert").takes_value(true).env("INFLUXDB_IOX_TLS_CERT")
+            .requires("tls-key").help(
+            "Serve the HTTP and gRPC APIs over TLS, with the PEM encoded certificate chain in this file",
+        ))
+        .arg(Arg::with_name("tls-key").long("tls-key").takes_value(true).env("INFLUXDB_IOX_TLS_KEY")
+            .requires("tls-cert").help(
+            "The PEM encoded private key of the TLS certificate",
+        ))
+        .arg(Arg::with_name("tls-client-ca").long("tls-client-ca").takes_value(true)
+            .env("INFLUXDB_IOX_TLS_CLIENT_CA").requires("tls-cert").help(
+            "Only accept TLS clients with a certificate signed by one of the PEM encoded CA \
+                       certificates in this file",
+        ))
+        .arg(Arg::with_name("auth-tokens").long("auth-tokens").takes_value(true)
+            .env("INFLUXDB_IOX_AUTH_TOKENS").help(
+            "A JSON file of the tokens clients can authenticate with, and the scopes they grant",
+        ))
+        .arg(Arg::with_name("auth-providers").long("auth-providers").takes_value(true)
+            .env("INFLUXDB_IOX_AUTH_PROVIDERS").help(
+            "A JSON file of the OpenID Connect provider and LDAP directory that authenticate \
+                       users, and the roles granted to their groups",
+        ))
+        .arg(Arg::with_name("audit-log").long("audit-log").takes_value(true)
+            .env("INFLUXDB_IOX_AUDIT_LOG").help(
+            "Record who created and released databases, changed their rules, deleted data and \
+                       managed tokens to this file, or to the objects under this s3:// or gs:// URL. \
+                       The recent events are queryable in the system.audit_log table",
+        ))
+        .arg(Arg::with_name("object-store").long("object-store").takes_value(true)
+            .env("INFLUXDB_IOX_OBJECT_STORE").help(
+            "Persist the data and configuration of the databases to this directory, or this s3:// \
+                       or gs:// bucket. Kept in memory by default",
+        ))
+        .arg(Arg::with_name("lease-duration").long("lease-duration").takes_value(true)
+            .env("INFLUXDB_IOX_LEASE_DURATION").help(
+            "How many seconds the ownership leases this server claims on its databases in object \
+                       storage last, which keep another server with the same writer id from writing \
+                       to them. They are renewed three times per duration. Defaults to 60",
+        ))
+        .arg(Arg::with_name("replica-of").long("replica-of").takes_value(true)
+            .env("INFLUXDB_IOX_REPLICA_OF").requires("object-store").help(
+            "Serve read-only queries over the databases of the writer with this id, from the \
+                       chunks it persists to --object-store. Writes and deletes to them are rejected",
+        ))
+        .arg(Arg::with_name("replica-refresh-interval").long("replica-refresh-interval")
+            .takes_value(true).env("INFLUXDB_IOX_REPLICA_REFRESH_INTERVAL").requires("replica-of")
+            .help(
+            "Every this many seconds, load the catalogs of the writer followed by --replica-of \
+                       again, to serve the chunks it persisted since. Defaults to 30",
+        ))
+        .arg(Arg::with_name("replication-token").long("replication-token").takes_value(true)
+            .env("INFLUXDB_IOX_REPLICATION_TOKEN").help(
+            "The secret of the token this server authenticates with to the servers of the host \
+                       groups it replicates writes to, which needs the write permission on the \
+                       databases replicated",
+        ))
+        .arg(Arg::with_name("settings-file").long("settings-file").takes_value(true)
+            .env("INFLUXDB_IOX_SETTINGS_FILE").help(
+            "A JSON file of settings that override --query-parallelism, --partition-write-limit, \
+                       --buffer-write-limit and the log filter, read again on SIGHUP or POST \
+                       /api/v1/reload without restarting the server",
+        ))
+        .arg(Arg::with_name("allow-anonymous").long("allow-anonymous").help(
+            "Allow requests without an authentication token to do anything. Only meant for development",
+        ))
+        .arg(Arg::with_name("bucket-mappings").long("bucket-mappings").takes_value(true)
+            .env("INFLUXDB_IOX_BUCKET_MAPPINGS").help(
+            "A JSON file of the databases that store the buckets of InfluxDB 2.0 API requests. \
+                       Unmapped buckets are stored in the database named <org>_<bucket>",
+        ))
+        .arg(Arg::with_name("auto-create-databases").long("auto-create-databases").takes_value(true)
+            .env("INFLUXDB_IOX_AUTO_CREATE_DATABASES").possible_values(&["true", "false"])
+            .default_value("true").help(
+            "Whether writing to a bucket whose database does not exist creates the database",
+        ))
+        .arg(Arg::with_name("query-parallelism").long("query-parallelism").takes_value(true)
+            .env("INFLUXDB_IOX_QUERY_PARALLELISM").help(
+            "The number of chunks a query scans at the same time, each on its own worker. Defaults to 4",
+        ))
+        .arg(Arg::with_name("row-group-fetches").long("row-group-fetches").takes_value(true)
+            .env("INFLUXDB_IOX_ROW_GROUP_FETCHES").help(
+            "The number of row groups of Parquet files a query fetches from object storage at the \
+                       same time, from all the files it scans. Defaults to 16",
+        ))
+        .arg(Arg::with_name("shutdown-timeout").long("shutdown-timeout").takes_value(true)
+            .env("INFLUXDB_IOX_SHUTDOWN_TIMEOUT").help(
+            "How many seconds to wait on SIGTERM for the requests in flight, persistence and \
+                       background jobs to complete before exiting. Defaults to 30",
+        ))
+        .arg(Arg::with_name("enable-profiling").long("enable-profiling").help(
+            "Serve CPU profiles on /debug/pprof/profile and heap statistics on /debug/pprof/heap. \
+                       They require building with the pprof and jemalloc features",
+        ))
+        .arg(Arg::with_name("persist-on-shutdown").long("persist-on-shutdown").help(
+            "Persist the chunks buffered in memory to object storage when shutting down",
+        ))
+        .arg(Arg::with_name("capture-file").long("capture-file").takes_value(true)
+            .env("INFLUXDB_IOX_CAPTURE_FILE").help(
+            "Capture the writes and queries the server receives to this file, to reproduce them \
+                       with the replay command",
+        ))
+        .arg(Arg::with_name("verify-interval").long("verify-interval").takes_value(true)
+            .env("INFLUXDB_IOX_VERIFY_INTERVAL").help(
+            "Every this many seconds, check the persisted Parquet files against the checksums \
+                       recorded in the catalog, logging the missing and corrupt files",
+        ))
+        .arg(Arg::with_name("query-cache-entries").long("query-cache-entries").takes_value(true)
+            .env("INFLUXDB_IOX_QUERY_CACHE_ENTRIES").help(
+            "Cache up to this many responses to metadata requests (tag keys, tag values and \
+                       measurement names) until the data of their database changes. Disabled by default",
+        ))
+        .arg(Arg::with_name("query-cache-max-age").long("query-cache-max-age").takes_value(true)
+            .env("INFLUXDB_IOX_QUERY_CACHE_MAX_AGE").help(
+            "How many seconds responses to metadata requests stay cached for at most. Defaults to 60",
+        ))
+        .arg(Arg::with_name("max-queries").long("max-queries").takes_value(true)
+            .env("INFLUXDB_IOX_MAX_QUERIES").help(
+            "Run up to this many SQL and InfluxQL queries at the same time, the others wait for \
+                       a slot. Defaults to 32",
+        ))
+        .arg(Arg::with_name("max-batch-queries").long("max-batch-queries").takes_value(true)
+            .env("INFLUXDB_IOX_MAX_BATCH_QUERIES").help(
+            "Run up to this many batch queries at the same time, leaving the other slots to \
+                       interactive queries. Defaults to 8",
+        ))
+        .arg(Arg::with_name("max-queued-batch-queries").long("max-queued-batch-queries").takes_value(true)
+            .env("INFLUXDB_IOX_MAX_QUEUED_BATCH_QUERIES").help(
+            "Reject the batch queries received while this many of them wait to run. Defaults to 32",
+        ))
+        .arg(Arg::with_name("grpc-max-write-size").long("grpc-max-write-size").takes_value(true)
+            .env("INFLUXDB_IOX_GRPC_MAX_WRITE_SIZE").help(
+            "Reject the requests to the gRPC write and metrics services larger than this many \
+                       bytes. Defaults to 16777216",
+        ))
+        .arg(Arg::with_name("grpc-write-rate").long("grpc-write-rate").takes_value(true)
+            .env("INFLUXDB_IOX_GRPC_WRITE_RATE").help(
+            "The requests per second each token may send to the gRPC write and metrics services \
+                       on average. Unlimited by default",
+        ))
+        .arg(Arg::with_name("grpc-write-burst").long("grpc-write-burst").takes_value(true)
+            .env("INFLUXDB_IOX_GRPC_WRITE_BURST").requires("grpc-write-rate").help(
+            "The requests each token may send to the gRPC write and metrics services at once. \
+                       Defaults to --grpc-write-rate",
+        ))
+        .arg(Arg::with_name("grpc-max-concurrent-writes").long("grpc-max-concurrent-writes")
+            .takes_value(true).env("INFLUXDB_IOX_GRPC_MAX_CONCURRENT_WRITES").help(
+            "Reject the requests to the gRPC write and metrics services received while this many \
+                       of them are handled. Defaults to 256",
+        ))
+        .arg(Arg::with_name("grpc-max-requests-per-connection")
+            .long("grpc-max-requests-per-connection").takes_value(true)
+            .env("INFLUXDB_IOX_GRPC_MAX_REQUESTS_PER_CONNECTION").help(
+            "Handle up to this many requests of each gRPC connection at the same time, the others \
+                       wait. Defaults to 32",
+        ))
+        .arg(Arg::with_name("mqtt-broker").long("mqtt-broker").takes_value(true)
+            .env("INFLUXDB_IOX_MQTT_BROKER").requires_all(&["mqtt-topic", "mqtt-database"]).help(
+            "Subscribe to the --mqtt-topic topics of the MQTT broker at this host:port and write \
+                       the messages published to them to --mqtt-database",
+        ))
+        .arg(Arg::with_name("mqtt-topic").long("mqtt-topic").takes_value(true).multiple(true)
+            .number_of_values(1).env("INFLUXDB_IOX_MQTT_TOPIC").requires("mqtt-broker").help(
+            "A topic filter to subscribe to, which may contain the + and # wildcards. Can be repeated",
+        ))
+        .arg(Arg::with_name("mqtt-database").long("mqtt-database").takes_value(true)
+            .env("INFLUXDB_IOX_MQTT_DATABASE").requires("mqtt-broker").help(
+            "The database the messages published over MQTT are written to",
+        ))
+        .arg(Arg::with_name("mqtt-json-mapping").long("mqtt-json-mapping").takes_value(true)
+            .env("INFLUXDB_IOX_MQTT_JSON_MAPPING").requires("mqtt-broker").help(
+            "A JSON file naming the measurement, tags and fields of the JSON messages published \
+                       over MQTT. Messages are line protocol if not set",
+        ))
+        .arg(Arg::with_name("mqtt-client-id").long("mqtt-client-id").takes_value(true)
+            .env("INFLUXDB_IOX_MQTT_CLIENT_ID").default_value("influxdb_iox").help(
+            "The client identifier to connect to the MQTT broker with",
+        ))
+        .arg(Arg::with_name("mqtt-username").long("mqtt-username").takes_value(true)
+            .env("INFLUXDB_IOX_MQTT_USERNAME").help(
+            "The user name to connect to the MQTT broker with",
+        ))
+        .arg(Arg::with_name("mqtt-password").long("mqtt-password").takes_value(true)
+            .env("INFLUXDB_IOX_MQTT_PASSWORD").hide_env_values(true).help(
+            "The password to connect to the MQTT broker with",
+        ))
+        .arg(Arg::with_name("udp-bind-addr").long("udp-bind-addr").takes_value(true)
+            .env("INFLUXDB_IOX_UDP_BIND_ADDR").help(
+            "Accept line protocol datagrams on this UDP address, such as 127.0.0.1:8089, writing \
+                       them to --socket-database without acknowledging them",
+        ))
+        .arg(Arg::with_name("unix-socket").long("unix-socket").takes_value(true)
+            .env("INFLUXDB_IOX_UNIX_SOCKET").help(
+            "Accept line protocol over connections to a Unix domain socket created at this path, \
+                       writing it to --socket-database without acknowledging it",
+        ))
+        .arg(Arg::with_name("socket-database").long("socket-database").takes_value(true)
+            .env("INFLUXDB_IOX_SOCKET_DATABASE").help(
+            "The database the lines received on --udp-bind-addr and --unix-socket are written \
+                       to, created if needed. Defaults to udp",
+        ))
+        .arg(Arg::with_name("pg-bind-addr").long("pg-bind-addr").takes_value(true)
+            .env("INFLUXDB_IOX_PG_BIND_ADDR").help(
+            "Experimental: serve read-only SQL queries over the PostgreSQL wire protocol on this \
+                       address, such as 127.0.0.1:5432. Clients connect to a database by name and \
+                       give their token as password",
+        ))
+        .arg(Arg::with_name("traces-exporter").long("traces-exporter").takes_value(true)
+            .possible_values(&["none", "jaeger"]).default_value("none").help(
+            "Where to export the spans of traces to. Only spans enabled by the log level are exported",
+        ))
+        .arg(Arg::with_name("traces-exporter-jaeger-agent").long("traces-exporter-jaeger-agent")
+            .takes_value(true).default_value("localhost:6831").help(
+            "The host:port of the Jaeger agent to send spans to, over UDP",
+        ))
         .get_matches();
 
-    setup_logging(matches.occurrences_of("verbose"));
+    let traces_exporter = match matches.value_of("traces-exporter") {
+        Some("jaeger") => TracesExporter::Jaeger {
+            agent_endpoint: matches
+                .value_of("traces-exporter-jaeger-agent")
+                .unwrap()
+                .to_string(),
+        },
+        _ => TracesExporter::None,
+    };
+
+    let server_config = write_buffer_server::Config {
+        mode: value_t!(matches, "mode", Mode).unwrap_or_else(|e| e.exit()),
+        tls: matches.value_of("tls-cert").map(|cert| TlsConfig {
+            cert: cert.into(),
+            key: matches.value_of("tls-key").unwrap().into(),
+            client_ca: matches.value_of("tls-client-ca").map(Into::into),
+        }),
+        auth_tokens: matches.value_of("auth-tokens").map(Into::into),
+        allow_anonymous: matches.is_present("allow-anonymous"),
+        auth_providers: matches.value_of("auth-providers").map(Into::into),
+        audit_log: matches.value_of("audit-log").map(ToString::to_string),
+        object_store: matches.value_of("object-store").map(ToString::to_string),
+        lease_duration: matches.value_of("lease-duration").map(|secs| {
+            Duration::from_secs(
+                secs.parse()
+                    .expect("--lease-duration is not a valid number of seconds"),
+            )
+        }),
+        replica_of: matches
+            .value_of("replica-of")
+            .map(|id| id.parse().expect("--replica-of is not a valid writer id")),
+        replica_refresh_interval: matches.value_of("replica-refresh-interval").map(|secs| {
+            Duration::from_secs(
+                secs.parse()
+                    .expect("--replica-refresh-interval is not a valid number of seconds"),
+            )
+        }),
+        replication_token: matches.value_of("replication-token").map(ToString::to_string),
+        settings_file: matches.value_of("settings-file").map(Into::into),
+        bucket_mappings: matches.value_of("bucket-mappings").map(Into::into),
+        auto_create_databases: matches.value_of("auto-create-databases") == Some("true"),
+        query_parallelism: matches.value_of("query-parallelism").map(|n| {
+            n.parse()
+                .expect("--query-parallelism is not a valid number of chunks")
+        }),
+        row_group_fetches: matches.value_of("row-group-fetches").map(|n| {
+            n.parse()
+                .expect("--row-group-fetches is not a valid number of row groups")
+        }),
+        query_threads: matches.value_of("query-threads").map(|n| {
+            n.parse()
+                .expect("--query-threads is not a valid number of threads")
+        }),
+        shutdown_timeout: matches.value_of("shutdown-timeout").map(|secs| {
+            Duration::from_secs(
+                secs.parse()
+                    .expect("--shutdown-timeout is not a valid number of seconds"),
+            )
+        }),
+        persist_on_shutdown: matches.is_present("persist-on-shutdown"),
+        enable_profiling: matches.is_present("enable-profiling"),
+        capture_file: matches.value_of("capture-file").map(Into::into),
+        verify_interval: matches.value_of("verify-interval").map(|secs| {
+            Duration::from_secs(
+                secs.parse()
+                    .expect("--verify-interval is not a valid number of seconds"),
+            )
+        }),
+        query_cache_entries: matches.value_of("query-cache-entries").map(|n| {
+            n.parse()
+                .expect("--query-cache-entries is not a valid number of responses")
+        }),
+        query_cache_max_age: matches.value_of("query-cache-max-age").map(|secs| {
+            Duration::from_secs(
+                secs.parse()
+                    .expect("--query-cache-max-age is not a valid number of seconds"),
+            )
+        }),
+        max_queries: matches.value_of("max-queries").map(|n| {
+            n.parse()
+                .expect("--max-queries is not a valid number of queries")
+        }),
+        max_batch_queries: matches.value_of("max-batch-queries").map(|n| {
+            n.parse()
+                .expect("--max-batch-queries is not a valid number of queries")
+        }),
+        max_queued_batch_queries: matches.value_of("max-queued-batch-queries").map(|n| {
+            n.parse()
+                .expect("--max-queued-batch-queries is not a valid number of queries")
+        }),
+        grpc_max_write_size: matches.value_of("grpc-max-write-size").map(|n| {
+            n.parse()
+                .expect("--grpc-max-write-size is not a valid number of bytes")
+        }),
+        grpc_write_rate: matches.value_of("grpc-write-rate").map(|n| {
+            n.parse()
+                .expect("--grpc-write-rate is not a valid number of requests")
+        }),
+        grpc_write_burst: matches.value_of("grpc-write-burst").map(|n| {
+            n.parse()
+                .expect("--grpc-write-burst is not a valid number of requests")
+        }),
+        grpc_max_concurrent_writes: matches.value_of("grpc-max-concurrent-writes").map(|n| {
+            n.parse()
+                .expect("--grpc-max-concurrent-writes is not a valid number of requests")
+        }),
+        grpc_max_requests_per_connection: matches
+            .value_of("grpc-max-requests-per-connection")
+            .map(|n| {
+                n.parse()
+                    .expect("--grpc-max-requests-per-connection is not a valid number of requests")
+            }),
+        wasm_udf_dir: matches.value_of("wasm-udf-dir").map(Into::into),
+        wasm_udf_timeout: matches.value_of("wasm-udf-timeout").map(|millis| {
+            Duration::from_millis(
+                millis
+                    .parse()
+                    .expect("--wasm-udf-timeout is not a valid number of milliseconds"),
+            )
+        }),
+        wasm_udf_memory: matches.value_of("wasm-udf-memory").map(|n| {
+            n.parse()
+                .expect("--wasm-udf-memory is not a valid number of bytes")
+        }),
+        partition_write_limit: matches.value_of("partition-write-limit").map(|n| {
+            n.parse()
+                .expect("--partition-write-limit is not a valid number of bytes")
+        }),
+        buffer_write_limit: matches.value_of("buffer-write-limit").map(|n| {
+            n.parse()
+                .expect("--buffer-write-limit is not a valid number of bytes")
+        }),
+        mqtt: matches.value_of("mqtt-broker").map(|broker| MqttConfig {
+            broker: broker.to_string(),
+            topics: matches
+                .values_of("mqtt-topic")
+                .unwrap()
+                .map(ToString::to_string)
+                .collect(),
+            database: matches.value_of("mqtt-database").unwrap().to_string(),
+            client_id: matches.value_of("mqtt-client-id").unwrap().to_string(),
+            username: matches.value_of("mqtt-username").map(ToString::to_string),
+            password: matches.value_of("mqtt-password").map(ToString::to_string),
+        }),
+        mqtt_json_mapping: matches.value_of("mqtt-json-mapping").map(Into::into),
+        udp_bind_addr: matches.value_of("udp-bind-addr").map(|addr| {
+            addr.parse()
+                .expect("--udp-bind-addr is not a valid socket address")
+        }),
+        unix_socket: matches.value_of("unix-socket").map(Into::into),
+        socket_database: matches.value_of("socket-database").map(ToString::to_string),
+        pg_bind_addr: matches.value_of("pg-bind-addr").map(|addr| {
+            addr.parse()
+                .expect("--pg-bind-addr is not a valid socket address")
+        }),
+    };
+
+    let log_format = match matches.value_of("log-format") {
+        Some("json") => LogFormat::Json,
+        _ => LogFormat::Text,
+    };
+
+    // Note the exporter has to be kept alive for spans to be sent
+    let (log_filter, _uninstall) = setup_logging(
+        matches.occurrences_of("verbose"),
+        log_format,
+        traces_exporter,
+    );
 
     // Install custom panic handler (note can not use `_` otherwise
     // drop will be called immediately).
     let _f = SendPanicsToTracing::new();
 
     let mut tokio_runtime = get_runtime(matches.value_of("num-threads"))?;
-    tokio_runtime.block_on(dispatch_args(matches));
+    tokio_runtime.block_on(dispatch_args(matches, log_filter, server_config));
 
     info!("InfluxDB IOx server shutting down");
     Ok(())
 }
 
-async fn dispatch_args(matches: ArgMatches<'_>) {
+async fn dispatch_args(
+    matches: ArgMatches<'_>,
+    log_filter: LogFilter,
+    server_config: write_buffer_server::Config,
+) {
     match matches.subcommand() {
         ("convert", Some(sub_matches)) => {
             let input_path = sub_matches.value_of("INPUT").unwrap();
@@ -182,9 +1161,273 @@ async fn dispatch_args(matches: ArgMatches<'_>) {
                 }
             }
         }
+        ("query-file", Some(sub_matches)) => {
+            let config = commands::query_file::QueryFileConfig {
+                input_path: sub_matches.value_of("INPUT").unwrap().into(),
+                query: sub_matches.value_of("QUERY").unwrap().into(),
+                format: value_t!(sub_matches, "format", commands::sql::OutputFormat).unwrap(),
+            };
+
+            match commands::query_file::query_file(&config).await {
+                Ok(()) => debug!("Query completed successfully"),
+                Err(e) => {
+                    eprintln!("Query failed: {}", e);
+                    std::process::exit(ReturnCode::QueryFailed as _)
+                }
+            }
+        }
+        ("import-tsm", Some(sub_matches)) => {
+            let config = commands::import_tsm::ImportConfig {
+                data_dir: sub_matches.value_of("INPUT").unwrap().into(),
+                output: sub_matches.value_of("OUTPUT").unwrap().into(),
+                writer_id: value_t!(sub_matches, "writer-id", u32).unwrap_or_else(|e| e.exit()),
+                database: sub_matches.value_of("database").map(Into::into),
+            };
+
+            match commands::import_tsm::import(&config).await {
+                Ok(()) => debug!("Import completed successfully"),
+                Err(e) => {
+                    eprintln!("Import failed: {}", e);
+                    std::process::exit(ReturnCode::ImportFailed as _)
+                }
+            }
+        }
+        ("database", Some(sub_matches)) => {
+            let connection = commands::database::Connection {
+                host: sub_matches.value_of("host").unwrap().into(),
+                token: sub_matches.value_of("token").map(Into::into),
+            };
+
+            let output = |matches: &ArgMatches<'_>| {
+                if matches.is_present("json") {
+                    commands::database::Output::Json
+                } else {
+                    commands::database::Output::Text
+                }
+            };
+
+            let result = match sub_matches.subcommand() {
+                ("create", Some(create_matches)) => {
+                    commands::database::create(
+                        &connection,
+                        create_matches.value_of("DATABASE").unwrap(),
+                        create_matches.value_of("rules").map(Path::new),
+                        create_matches.value_of("template"),
+                    )
+                    .await
+                }
+                ("list", Some(list_matches)) => {
+                    commands::database::list(&connection, output(list_matches)).await
+                }
+                ("get", Some(get_matches)) => {
+                    commands::database::get(
+                        &connection,
+                        get_matches.value_of("DATABASE").unwrap(),
+                        output(get_matches),
+                    )
+                    .await
+                }
+                ("partition", Some(partition_matches)) => match partition_matches.subcommand() {
+                    ("list", Some(list_matches)) => {
+                        commands::database::list_partitions(
+                            &connection,
+                            list_matches.value_of("DATABASE").unwrap(),
+                            output(list_matches),
+                        )
+                        .await
+                    }
+                    _ => {
+                        eprintln!("{}", partition_matches.usage());
+                        std::process::exit(ReturnCode::DatabaseCommandFailed as _)
+                    }
+                },
+                ("chunk", Some(chunk_matches)) => match chunk_matches.subcommand() {
+                    ("list", Some(list_matches)) => {
+                        commands::database::list_chunks(
+                            &connection,
+                            list_matches.value_of("DATABASE").unwrap(),
+                            list_matches.value_of("partition"),
+                            output(list_matches),
+                        )
+                        .await
+                    }
+                    ("close", Some(close_matches)) => {
+                        commands::database::close_chunk(
+                            &connection,
+                            close_matches.value_of("DATABASE").unwrap(),
+                            close_matches.value_of("PARTITION").unwrap(),
+                            output(close_matches),
+                        )
+                        .await
+                    }
+                    ("persist", Some(persist_matches)) => {
+                        let chunk_id =
+                            value_t!(persist_matches, "CHUNK_ID", u32).unwrap_or_else(|e| e.exit());
+                        commands::database::persist_chunk(
+                            &connection,
+                            persist_matches.value_of("DATABASE").unwrap(),
+                            persist_matches.value_of("PARTITION").unwrap(),
+                            chunk_id,
+                        )
+                        .await
+                    }
+                    _ => {
+                        eprintln!("{}", chunk_matches.usage());
+                        std::process::exit(ReturnCode::DatabaseCommandFailed as _)
+                    }
+                },
+                ("export", Some(export_matches)) => {
+                    let config = commands::database::ExportConfig {
+                        db_name: export_matches.value_of("DATABASE").unwrap().into(),
+                        table: export_matches.value_of("table").map(Into::into),
+                        partition_key: export_matches.value_of("partition").map(Into::into),
+                        start: export_matches.value_of("start").map(Into::into),
+                        stop: export_matches.value_of("stop").map(Into::into),
+                        output: export_matches.value_of("output").unwrap().into(),
+                    };
+                    commands::database::export(&connection, &config).await
+                }
+                ("snapshot", Some(snapshot_matches)) => {
+                    commands::database::snapshot(
+                        &connection,
+                        snapshot_matches.value_of("DATABASE").unwrap(),
+                        snapshot_matches.value_of("output").unwrap(),
+                    )
+                    .await
+                }
+                ("restore", Some(restore_matches)) => {
+                    commands::database::restore(
+                        &connection,
+                        restore_matches.value_of("DATABASE").unwrap(),
+                        restore_matches.value_of("input").unwrap(),
+                    )
+                    .await
+                }
+                ("import", Some(import_matches)) => {
+                    let values = |name: &str| -> Vec<String> {
+                        import_matches
+                            .values_of(name)
+                            .map(|values| values.map(Into::into).collect())
+                            .unwrap_or_default()
+                    };
+                    let config = commands::database::ImportConfig {
+                        db_name: import_matches.value_of("DATABASE").unwrap().into(),
+                        files: import_matches
+                            .values_of("FILES")
+                            .unwrap()
+                            .map(Into::into)
+                            .collect(),
+                        format: import_matches.value_of("format").map(Into::into),
+                        table: import_matches.value_of("table").unwrap().into(),
+                        tags: values("tag"),
+                        fields: values("field"),
+                        time_column: import_matches.value_of("time_column").unwrap().into(),
+                        time_unit: import_matches.value_of("time_unit").unwrap().into(),
+                        partition_key: import_matches.value_of("partition").map(Into::into),
+                    };
+                    commands::database::import(&connection, &config).await
+                }
+                ("load-dimension", Some(load_matches)) => {
+                    commands::database::load_dimension_table(
+                        &connection,
+                        load_matches.value_of("DATABASE").unwrap(),
+                        load_matches.value_of("TABLE").unwrap(),
+                        Path::new(load_matches.value_of("FILE").unwrap()),
+                    )
+                    .await
+                }
+                _ => {
+                    eprintln!("{}", sub_matches.usage());
+                    std::process::exit(ReturnCode::DatabaseCommandFailed as _)
+                }
+            };
+
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                std::process::exit(ReturnCode::DatabaseCommandFailed as _)
+            }
+        }
+        ("sql", Some(sub_matches)) => {
+            let connection = commands::database::Connection {
+                host: sub_matches.value_of("host").unwrap().into(),
+                token: sub_matches.value_of("token").map(Into::into),
+            };
+            let config = commands::sql::SqlConfig {
+                db_name: sub_matches.value_of("DATABASE").map(Into::into),
+                format: value_t!(sub_matches, "format", commands::sql::OutputFormat).unwrap(),
+            };
+
+            if let Err(e) = commands::sql::repl(&connection, config).await {
+                eprintln!("{}", e);
+                std::process::exit(ReturnCode::DatabaseCommandFailed as _)
+            }
+        }
+        ("replay", Some(sub_matches)) => {
+            let config = commands::replay::ReplayConfig {
+                file: sub_matches.value_of("FILE").unwrap().into(),
+                http_host: sub_matches.value_of("http-host").unwrap().into(),
+                connection: commands::database::Connection {
+                    host: sub_matches.value_of("host").unwrap().into(),
+                    token: sub_matches.value_of("token").map(Into::into),
+                },
+                speed: value_t!(sub_matches, "speed", f64).unwrap_or_else(|e| e.exit()),
+                as_fast_as_possible: sub_matches.is_present("as-fast-as-possible"),
+            };
+
+            if let Err(e) = commands::replay::replay(&config).await {
+                eprintln!("Replay failed: {}", e);
+                std::process::exit(ReturnCode::ReplayFailed as _)
+            }
+        }
+        ("debug", Some(sub_matches)) => {
+            let result = match sub_matches.subcommand() {
+                ("verify-catalog", Some(verify_matches)) => {
+                    let connection = commands::database::Connection {
+                        host: verify_matches.value_of("host").unwrap().into(),
+                        token: verify_matches.value_of("token").map(Into::into),
+                    };
+                    commands::debug::verify_catalog(
+                        &connection,
+                        verify_matches.value_of("DATABASE"),
+                    )
+                    .await
+                }
+                ("rebuild-catalog", Some(rebuild_matches)) => {
+                    let connection = commands::database::Connection {
+                        host: rebuild_matches.value_of("host").unwrap().into(),
+                        token: rebuild_matches.value_of("token").map(Into::into),
+                    };
+                    commands::debug::rebuild_catalog(
+                        &connection,
+                        rebuild_matches.value_of("DATABASE").unwrap(),
+                    )
+                    .await
+                }
+                ("force-claim", Some(claim_matches)) => {
+                    let connection = commands::database::Connection {
+                        host: claim_matches.value_of("host").unwrap().into(),
+                        token: claim_matches.value_of("token").map(Into::into),
+                    };
+                    commands::debug::force_claim(
+                        &connection,
+                        claim_matches.value_of("DATABASE").unwrap(),
+                    )
+                    .await
+                }
+                _ => {
+                    eprintln!("{}", sub_matches.usage());
+                    std::process::exit(ReturnCode::DebugCommandFailed as _)
+                }
+            };
+
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                std::process::exit(ReturnCode::DebugCommandFailed as _)
+            }
+        }
         ("server", Some(_)) | (_, _) => {
             println!("Starting InfluxDB IOx server");
-            match commands::write_buffer_server::main().await {
+            match write_buffer_server::main(log_filter, server_config).await {
                 Ok(()) => eprintln!("Shutdown OK"),
                 Err(e) => {
                     error!("Server shutdown with error: {:?}", e);
@@ -195,6 +1438,25 @@ async fn dispatch_args(matches: ArgMatches<'_>) {
     }
 }
 
+/// The URL of the gRPC API of the server a command connects to
+fn host_arg() -> Arg<'static, 'static> {
+    Arg::with_name("host")
+        .long("host")
+        .takes_value(true)
+        .env("INFLUXDB_IOX_HOST")
+        .default_value("http://127.0.0.1:8082")
+        .help("The URL of the gRPC API of the server")
+}
+
+/// The token a command authenticates to the server with
+fn token_arg() -> Arg<'static, 'static> {
+    Arg::with_name("token")
+        .long("token")
+        .takes_value(true)
+        .env("INFLUXDB_IOX_TOKEN")
+        .help("The secret of the token to authenticate with")
+}
+
 /// Default debug level is debug for everything except
 /// some especially noisy low level libraries
 const DEFAULT_DEBUG_LOG_LEVEL: &str = "debug,hyper::proto::h1=info,h2=info";
@@ -205,13 +1467,40 @@ const DEFAULT_VERBOSE_LOG_LEVEL: &str = "info";
 // Default log level is warn level for all components
 const DEFAULT_LOG_LEVEL: &str = "warn";
 
+/// How log lines are formatted
+#[derive(Debug, Clone, Copy)]
+enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, including the fields of the spans the log was emitted in
+    Json,
+}
+
+/// Where the spans of traces are sent
+#[derive(Debug)]
+enum TracesExporter {
+    None,
+    /// Send spans to the Jaeger agent listening for UDP packets at `agent_endpoint`
+    Jaeger {
+        agent_endpoint: String,
+    },
+}
+
 /// Configures logging in the following precedence:
 ///
 /// 1. If RUST_LOG environment variable is set, use that value
 /// 2. if `-vv` (multiple instances of verbose), use DEFAULT_DEBUG_LOG_LEVEL
 /// 2. if `-v` (single instances of verbose), use DEFAULT_VERBOSE_LOG_LEVEL
 /// 3. Otherwise use DEFAULT_LOG_LEVEL
-fn setup_logging(num_verbose: u64) {
+///
+/// The same levels decide which spans are sent to `traces_exporter`. The returned `LogFilter`
+/// can be used to change the levels while the server runs, and the returned exporter handle
+/// flushes the exporter when dropped.
+fn setup_logging(
+    num_verbose: u64,
+    log_format: LogFormat,
+    traces_exporter: TracesExporter,
+) -> (LogFilter, Option<opentelemetry_jaeger::Uninstall>) {
     let rust_log_env = std::env::var("RUST_LOG");
 
     match rust_log_env {
@@ -230,7 +1519,36 @@ fn setup_logging(num_verbose: u64) {
         },
     }
 
-    env_logger::init();
+    let (filter, filter_handle) = reload::Layer::new(EnvFilter::from_default_env());
+
+    let (text_logs, json_logs) = match log_format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer()), None),
+        LogFormat::Json => (None, Some(tracing_subscriber::fmt::layer().json())),
+    };
+
+    let (traces, uninstall) = match traces_exporter {
+        TracesExporter::None => (None, None),
+        TracesExporter::Jaeger { agent_endpoint } => {
+            let (tracer, uninstall) = opentelemetry_jaeger::new_pipeline()
+                .with_service_name("influxdb_iox")
+                .with_agent_endpoint(agent_endpoint)
+                .install()
+                .expect("Error initializing Jaeger exporter");
+            (
+                Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+                Some(uninstall),
+            )
+        }
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text_logs)
+        .with(json_logs)
+        .with(traces)
+        .init();
+
+    (LogFilter::new(filter_handle), uninstall)
 }
 
 /// Creates the tokio runtime for executing IOx
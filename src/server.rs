@@ -1,4 +1,199 @@
 #![deny(rust_2018_idioms)]
 
+pub mod auth;
+pub mod bucket_mapping;
+pub mod capture;
+pub mod governor;
 pub mod http_routes;
+pub mod log_filter;
+pub mod mode;
+pub mod mqtt;
+pub mod notify;
+pub mod pgwire;
+pub mod profiling;
+pub mod reload;
 pub mod rpc;
+pub mod socket_listener;
+pub mod tls;
+pub mod trace;
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use cluster::{ConnectionManager, RemoteServer};
+use data_types::entry::Entry;
+use influxdb_iox_client::{Builder, WriteClient};
+use snafu::{ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error connecting to remote server {}: {}", connect, source))]
+    ConnectingToRemoteServer {
+        connect: String,
+        source: influxdb_iox_client::Error,
+    },
+
+    #[snafu(display(
+        "Error writing to database {} of remote server {}: {}",
+        db,
+        connect,
+        source
+    ))]
+    WritingToRemoteServer {
+        db: String,
+        connect: String,
+        source: influxdb_iox_client::Error,
+    },
+}
+
+/// The `ConnectionManager` used by the IOx server. The hosts of host groups are the URLs of
+/// the gRPC API of other IOx servers, such as `http://10.0.0.2:8082`, which entries are
+/// written to with the write API, authenticated with `token` if set. One connection is
+/// opened per host, on its first use, and shared by the writes to it.
+#[derive(Debug, Default, Clone)]
+pub struct ConnectionManagerImpl {
+    token: Option<String>,
+    connections: Arc<Mutex<BTreeMap<String, Arc<RemoteServerImpl>>>>,
+}
+
+impl ConnectionManagerImpl {
+    /// Creates a connection manager authenticating to the remote servers with the secret of
+    /// a token, if set
+    pub fn new(token: Option<String>) -> Self {
+        Self {
+            token,
+            ..Default::default()
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ConnectionManager for ConnectionManagerImpl {
+    type Error = Error;
+    type RemoteServer = RemoteServerImpl;
+
+    async fn remote_server(&self, connect: &str) -> Result<Arc<Self::RemoteServer>, Self::Error> {
+        let mut connections = self.connections.lock().expect("mutex poisoned");
+        if let Some(remote) = connections.get(connect) {
+            return Ok(Arc::clone(remote));
+        }
+
+        let mut builder = Builder::default();
+        if let Some(token) = &self.token {
+            builder = builder.token(token);
+        }
+        let connection = builder
+            .build_lazy(connect)
+            .context(ConnectingToRemoteServer { connect })?;
+        let remote = Arc::new(RemoteServerImpl {
+            connect: connect.to_string(),
+            client: WriteClient::new(connection),
+        });
+        connections.insert(connect.to_string(), Arc::clone(&remote));
+        Ok(remote)
+    }
+}
+
+/// A connection to the write API of another IOx server
+#[derive(Debug, Clone)]
+pub struct RemoteServerImpl {
+    connect: String,
+    client: WriteClient,
+}
+
+#[tonic::async_trait]
+impl RemoteServer for RemoteServerImpl {
+    type Error = Error;
+
+    async fn replicate(&self, db: &str, entry: &Entry) -> Result<(), Self::Error> {
+        // the clients of a connection share it, so each write can have its own
+        self.client
+            .clone()
+            .write_entry(db, entry.data())
+            .await
+            .context(WritingToRemoteServer {
+                db,
+                connect: &self.connect,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{
+        auth::Authorizer,
+        rpc::{ingest_limits::IngestLimiter, write::WriteService},
+    };
+    use cluster::Server as AppServer;
+    use data_types::database_rules::DatabaseRules;
+    use generated_types::write::write_service_server::WriteServiceServer;
+    use object_store::{InMemory, ObjectStore};
+    use tokio::{net::TcpListener, sync::RwLock};
+
+    #[tokio::test]
+    async fn replicate_to_remote_server() {
+        // the database server the router replicates to
+        let mut remote = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        remote.set_id(2);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        remote.create_database("foo", rules).await.unwrap();
+        let remote = Arc::new(RwLock::new(remote));
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = WriteService::new(
+            Arc::clone(&remote),
+            Arc::new(Authorizer::new(true)),
+            Arc::new(IngestLimiter::default()),
+        );
+        tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(WriteServiceServer::new(service))
+                .serve_with_incoming(listener.incoming())
+                .await
+        });
+
+        let mut router = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        router.set_id(1);
+        router
+            .create_host_group("databases".to_string(), vec![format!("http://{}", addr)])
+            .await
+            .unwrap();
+        let rules = DatabaseRules {
+            store_locally: false,
+            replication: vec!["databases".to_string()],
+            ..Default::default()
+        };
+        router.create_database("foo", rules).await.unwrap();
+
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines("cpu bar=1 10\ncpu bar=2 20")
+            .map(|l| l.unwrap())
+            .collect();
+        router.write_lines("foo", &lines).await.unwrap();
+
+        let summaries = remote.read().await.chunk_summaries("foo").await.unwrap();
+        assert_eq!(summaries[0].row_count, 2);
+
+        // the database doesn't exist on the remote server
+        let rules = DatabaseRules {
+            store_locally: false,
+            replication: vec!["databases".to_string()],
+            ..Default::default()
+        };
+        router.create_database("bar", rules).await.unwrap();
+        let err = router.write_lines("bar", &lines).await.unwrap_err();
+        assert!(err.to_string().contains("bar"), "{}", err);
+    }
+}
@@ -0,0 +1,1170 @@
+//! This module contains the authentication and authorization of requests to the HTTP and gRPC
+//! APIs of the server.
+//!
+//! Clients authenticate by sending a token in the `Authorization` header, as either
+//! `Token <secret>` (like InfluxDB 2.0 clients do) or `Bearer <secret>`. InfluxDB 1.x clients
+//! can instead use basic authentication, with the secret as the password and any user name.
+//! Each token grants a set of scopes, such as writing to one database or reading from all of
+//! them.
+//!
+//! Tokens are either loaded from a JSON file when the server starts:
+//!
+//! ```json
+//! {"tokens": [
+//!     {"id": "telegraf", "secret": "...", "scopes": [{"permission": "write", "database": "metrics"}]},
+//!     {"id": "admin", "secret": "...", "scopes": [{"permission": "manage", "database": "*"}]}
+//! ]}
+//! ```
+//!
+//! or created with the management gRPC API, in which case they only last until the server
+//! restarts.
+//!
+//! Read and write scopes can also be restricted to some measurements, and to the rows with some
+//! tag values, so that tenants can share a database:
+//!
+//! ```json
+//! {"permission": "write", "database": "shared", "measurements": ["cpu"], "tags": {"tenant": "acme"}}
+//! ```
+//!
+//! Users can also be authenticated by identity providers, configured in a JSON file that maps
+//! the groups of users to roles, which grant scopes like tokens do:
+//!
+//! ```json
+//! {
+//!     "oidc": {"issuer": "https://idp.example.com", "audience": "iox",
+//!              "jwks_url": "https://idp.example.com/.well-known/jwks.json"},
+//!     "ldap": {"url": "ldap://ldap.example.com", "user_dn": "uid={user},ou=people,dc=example,dc=com"},
+//!     "roles": [
+//!         {"group": "iox-admins", "scopes": [{"permission": "manage", "database": "*"}]},
+//!         {"group": "cn=iox-writers,ou=groups,dc=example,dc=com",
+//!          "scopes": [{"permission": "write", "database": "*"}]}
+//!     ]
+//! }
+//! ```
+//!
+//! With OpenID Connect, clients send the JSON Web Tokens of the provider as bearer tokens. With
+//! LDAP, InfluxDB 1.x clients send the user name and password of the directory with basic
+//! authentication, which `Authorizer::authenticate` checks before requests are authorized. The
+//! roles of LDAP groups name them by DN, unless the directory sets `match_group_names`.
+
+pub mod ldap;
+pub mod oidc;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use generated_types::management;
+use influxdb_line_protocol::ParsedLine;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use storage::access::RowAccess;
+use tonic::{metadata::MetadataMap, Status};
+use tracing::{debug, warn};
+
+pub const AUTHORIZATION_HEADER: &str = "authorization";
+
+/// The database name of scopes that apply to every database
+pub const ALL_DATABASES: &str = "*";
+
+/// How long the users authenticated by the LDAP directory are authorized without asking it
+/// again
+const LDAP_SESSION_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error reading tokens from {}: {}", path.display(), source))]
+    ReadingTokens {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error parsing tokens from {}: {}", path.display(), source))]
+    ParsingTokens {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Token {} already exists", id))]
+    TokenAlreadyExists { id: String },
+
+    #[snafu(display("Token {} has the same secret as another token", id))]
+    DuplicateSecret { id: String },
+
+    #[snafu(display("Error reading identity providers from {}: {}", path.display(), source))]
+    ReadingProviders {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error parsing identity providers from {}: {}", path.display(), source))]
+    ParsingProviders {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Error configuring the LDAP directory: {}", source))]
+    ConfiguringLdap { source: ldap::Error },
+
+    #[snafu(display("Token not found: {}", id))]
+    TokenNotFound { id: String },
+
+    #[snafu(display("Invalid scope for {}: {}", id, reason))]
+    InvalidScope {
+        /// The token or role with the scope, such as `token telegraf`
+        id: String,
+        reason: String,
+    },
+
+    #[snafu(display("Authentication token is required"))]
+    MissingToken,
+
+    #[snafu(display("Invalid authentication token"))]
+    InvalidToken,
+
+    #[snafu(display("Invalid bearer token: {}", source))]
+    InvalidBearerToken { source: oidc::Error },
+
+    #[snafu(display(
+        "{} is not allowed to {} {}",
+        principal,
+        permission,
+        database.as_deref().map_or("the server".to_string(), |db| format!("database {}", db))
+    ))]
+    PermissionDenied {
+        /// The token or user the request was sent with, such as `Token telegraf`
+        principal: String,
+        permission: Permission,
+        database: Option<String>,
+    },
+
+    #[snafu(display(
+        "Line {} is outside the measurements and tag values the request is allowed to write",
+        line
+    ))]
+    LineNotAllowed {
+        /// The number of the line, starting from 1
+        line: usize,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Converts an authorization failure into the appropriate tonic status
+    pub fn to_status(&self) -> Status {
+        match self {
+            Self::MissingToken | Self::InvalidToken | Self::InvalidBearerToken { .. } => {
+                Status::unauthenticated(self.to_string())
+            }
+            Self::PermissionDenied { .. } | Self::LineNotAllowed { .. } => {
+                Status::permission_denied(self.to_string())
+            }
+            Self::TokenAlreadyExists { .. } => Status::already_exists(self.to_string()),
+            Self::TokenNotFound { .. } => Status::not_found(self.to_string()),
+            Self::InvalidScope { .. } => Status::invalid_argument(self.to_string()),
+            Self::ReadingTokens { .. }
+            | Self::ParsingTokens { .. }
+            | Self::ReadingProviders { .. }
+            | Self::ParsingProviders { .. }
+            | Self::ConfiguringLdap { .. }
+            | Self::DuplicateSecret { .. } => Status::internal(self.to_string()),
+        }
+    }
+}
+
+/// What a scope allows its token to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Query data
+    Read,
+    /// Write data
+    Write,
+    /// Configure databases, or the server itself when the scope covers all databases
+    Manage,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read => write!(f, "read"),
+            Self::Write => write!(f, "write"),
+            Self::Manage => write!(f, "manage"),
+        }
+    }
+}
+
+/// A permission on one database, or on all of them
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Scope {
+    pub permission: Permission,
+    /// The name of the database, or `*` for all databases
+    pub database: String,
+    /// The measurements a read or write scope is restricted to, all of them if empty
+    #[serde(default)]
+    pub measurements: Vec<String>,
+    /// The tag values the rows of a read or write scope must have, such as `tenant=acme`
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Scope {
+    /// Whether this scope allows `permission` on `database`. Actions that concern the whole
+    /// server, rather than a database, have no database and are only allowed by scopes that
+    /// cover all databases.
+    fn allows(&self, permission: Permission, database: Option<&str>) -> bool {
+        self.permission == permission
+            && (self.database == ALL_DATABASES || database == Some(self.database.as_str()))
+    }
+
+    /// The rows of the databases of this scope it allows access to
+    fn row_access(&self) -> RowAccess {
+        RowAccess::new(&self.measurements, &self.tags)
+    }
+}
+
+/// A token, without its secret
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Token {
+    pub id: String,
+    #[serde(default)]
+    pub description: String,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Deserialize)]
+struct TokenFile {
+    tokens: Vec<TokenEntry>,
+}
+
+#[derive(Deserialize)]
+struct TokenEntry {
+    secret: String,
+    #[serde(flatten)]
+    token: Token,
+}
+
+/// The scopes granted to the users of the identity providers that are members of a group
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Role {
+    /// The name of the group, as listed in the groups claim of OpenID Connect tokens, or the
+    /// DN of the LDAP group, as listed in the group attribute of LDAP users. LDAP group names
+    /// match if the directory is configured with `match_group_names`
+    pub group: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// The identity providers that authenticate users, and the roles of their groups
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct IdentityProviders {
+    #[serde(default)]
+    pub oidc: Option<oidc::OidcConfig>,
+    #[serde(default)]
+    pub ldap: Option<ldap::LdapConfig>,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+}
+
+/// A user recently authenticated by the LDAP directory
+#[derive(Debug)]
+struct LdapSession {
+    scopes: Vec<Scope>,
+    expires: Instant,
+}
+
+/// The credentials of an `Authorization` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Credentials {
+    /// The secret of a token, or a JSON Web Token
+    Secret(String),
+    /// The user name and password of basic authentication
+    Basic { user: String, password: String },
+}
+
+impl Credentials {
+    /// The secret of a token these credentials may be. 1.x clients send it as the password,
+    /// with any user name
+    fn secret(&self) -> &str {
+        match self {
+            Self::Secret(secret) => secret,
+            Self::Basic { password, .. } => password,
+        }
+    }
+}
+
+/// Decides which requests are allowed, based on the tokens they carry
+pub struct Authorizer {
+    /// Whether requests without a token are allowed to do anything
+    allow_anonymous: bool,
+    /// Tokens keyed by their secret
+    tokens: RwLock<HashMap<String, Token>>,
+    /// Validates the JSON Web Tokens of the OpenID Connect provider, if there is one
+    oidc: Option<oidc::Validator>,
+    /// The LDAP directory basic authentication is checked against, if there is one
+    ldap: Option<ldap::Directory>,
+    roles: Vec<Role>,
+    /// The users authenticated by the LDAP directory, keyed by the digest of their user name
+    /// and password
+    ldap_sessions: RwLock<HashMap<Vec<u8>, LdapSession>>,
+}
+
+impl fmt::Debug for Authorizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the secrets are left out on purpose
+        f.debug_struct("Authorizer")
+            .field("allow_anonymous", &self.allow_anonymous)
+            .field("tokens", &self.tokens())
+            .field("oidc", &self.oidc)
+            .field("ldap", &self.ldap)
+            .field("roles", &self.roles)
+            .finish()
+    }
+}
+
+impl Authorizer {
+    /// Creates an authorizer without tokens. If `allow_anonymous` is set, requests without a
+    /// token are allowed, which is only meant for development.
+    pub fn new(allow_anonymous: bool) -> Self {
+        Self {
+            allow_anonymous,
+            tokens: Default::default(),
+            oidc: None,
+            ldap: None,
+            roles: vec![],
+            ldap_sessions: Default::default(),
+        }
+    }
+
+    /// Authenticates users with the identity providers of the JSON file at `path`, replacing
+    /// the previous ones. The signing keys of the OpenID Connect provider are not fetched
+    /// until `refresh_oidc_keys` is called
+    pub fn load_providers(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path).context(ReadingProviders { path })?;
+        let providers = serde_json::from_str(&json).context(ParsingProviders { path })?;
+        self.set_providers(providers)
+    }
+
+    /// Authenticates users with `providers`, replacing the previous ones
+    pub fn set_providers(&mut self, providers: IdentityProviders) -> Result<()> {
+        for role in &providers.roles {
+            check_scopes(&format!("role {}", role.group), &role.scopes)?;
+        }
+
+        self.ldap = providers
+            .ldap
+            .map(ldap::Directory::new)
+            .transpose()
+            .context(ConfiguringLdap)?;
+        self.oidc = providers.oidc.map(oidc::Validator::new);
+        self.roles = providers.roles;
+        self.ldap_sessions.write().expect("mutex poisoned").clear();
+        Ok(())
+    }
+
+    /// The OpenID Connect provider, if there is one
+    pub fn oidc(&self) -> Option<&oidc::Validator> {
+        self.oidc.as_ref()
+    }
+
+    /// The LDAP directory, if there is one
+    pub fn ldap(&self) -> Option<&ldap::Directory> {
+        self.ldap.as_ref()
+    }
+
+    /// Fetches the signing keys of the OpenID Connect provider, if there is one, returning
+    /// how many there are
+    pub async fn refresh_oidc_keys(&self) -> Result<usize, oidc::Error> {
+        match &self.oidc {
+            Some(oidc) => oidc.refresh_keys().await,
+            None => Ok(0),
+        }
+    }
+
+    /// Adds the tokens of the JSON file at `path`, returning how many there were
+    pub fn load_tokens(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path).context(ReadingTokens { path })?;
+        let file: TokenFile = serde_json::from_str(&json).context(ParsingTokens { path })?;
+
+        let count = file.tokens.len();
+        for entry in file.tokens {
+            self.add_token(entry.secret, entry.token)?;
+        }
+        Ok(count)
+    }
+
+    /// Creates a token with a new random secret, which is returned
+    pub fn create_token(&self, token: Token) -> Result<String> {
+        let secret = format!(
+            "{:032x}{:032x}",
+            rand::random::<u128>(),
+            rand::random::<u128>()
+        );
+        self.add_token(secret.clone(), token)?;
+        Ok(secret)
+    }
+
+    fn add_token(&self, secret: String, token: Token) -> Result<()> {
+        check_scopes(&format!("token {}", token.id), &token.scopes)?;
+
+        let mut tokens = self.tokens.write().expect("mutex poisoned");
+        ensure!(
+            !tokens.values().any(|t| t.id == token.id),
+            TokenAlreadyExists { id: token.id }
+        );
+        ensure!(
+            !tokens.contains_key(&secret),
+            DuplicateSecret { id: token.id }
+        );
+        tokens.insert(secret, token);
+        Ok(())
+    }
+
+    /// Removes the token `id`, so that its secret is no longer accepted
+    pub fn delete_token(&self, id: &str) -> Result<()> {
+        let mut tokens = self.tokens.write().expect("mutex poisoned");
+        let secret = tokens
+            .iter()
+            .find(|(_, token)| token.id == id)
+            .map(|(secret, _)| secret.clone())
+            .context(TokenNotFound { id })?;
+        tokens.remove(&secret);
+        Ok(())
+    }
+
+    /// Returns all the tokens, sorted by id
+    pub fn tokens(&self) -> Vec<Token> {
+        let mut tokens: Vec<_> = self
+            .tokens
+            .read()
+            .expect("mutex poisoned")
+            .values()
+            .cloned()
+            .collect();
+        tokens.sort_by(|a, b| a.id.cmp(&b.id));
+        tokens
+    }
+
+    /// Authenticates the user name and password of the basic authentication in the
+    /// `Authorization` header `authorization` with the LDAP directory, if there is one, so
+    /// that `authorize` grants the user the scopes of their groups for a while. Other
+    /// credentials are left for `authorize` to check
+    pub async fn authenticate(&self, authorization: Option<&str>) {
+        let directory = match &self.ldap {
+            Some(directory) => directory,
+            None => return,
+        };
+        let (user, password) = match authorization.and_then(parse_authorization) {
+            Some(Credentials::Basic { user, password }) => (user, password),
+            _ => return,
+        };
+        if self
+            .tokens
+            .read()
+            .expect("mutex poisoned")
+            .contains_key(&password)
+        {
+            return;
+        }
+
+        let key = session_key(&user, &password);
+        let now = Instant::now();
+        if let Some(session) = self.ldap_sessions.read().expect("mutex poisoned").get(&key) {
+            if session.expires > now {
+                return;
+            }
+        }
+
+        match directory.authenticate(&user, &password).await {
+            Ok(Some(identity)) => {
+                let session = LdapSession {
+                    scopes: self.role_scopes(&identity.groups),
+                    expires: now + LDAP_SESSION_TTL,
+                };
+                let mut sessions = self.ldap_sessions.write().expect("mutex poisoned");
+                sessions.retain(|_, session| session.expires > now);
+                sessions.insert(key, session);
+            }
+            Ok(None) => debug!("LDAP directory rejected the password of user {}", user),
+            Err(e) => warn!("error authenticating user {} with LDAP: {}", user, e),
+        }
+    }
+
+    /// Checks that a request sent with the `Authorization` header `authorization` is allowed
+    /// `permission` on `database`, or on the server if there is no database
+    pub fn authorize(
+        &self,
+        authorization: Option<&str>,
+        permission: Permission,
+        database: Option<&str>,
+    ) -> Result<()> {
+        self.allowed_scopes(authorization, permission, database)
+            .map(|_| ())
+    }
+
+    /// Like `authorize`, returning the rows of `database` the request may access, which are
+    /// those allowed by any of the scopes that allow it `permission`
+    pub fn access(
+        &self,
+        authorization: Option<&str>,
+        permission: Permission,
+        database: &str,
+    ) -> Result<RowAccess> {
+        let scopes = match self.allowed_scopes(authorization, permission, Some(database))? {
+            Some(scopes) => scopes,
+            None => return Ok(RowAccess::default()),
+        };
+        Ok(scopes
+            .iter()
+            .map(Scope::row_access)
+            .fold(None, |access: Option<RowAccess>, scope| {
+                Some(match access {
+                    Some(access) => access.union(scope),
+                    None => scope,
+                })
+            })
+            .unwrap_or_default())
+    }
+
+    /// Returns the scopes that allow a request sent with the `Authorization` header
+    /// `authorization` `permission` on `database`, or `None` if it is an anonymous request
+    /// that is allowed everything
+    fn allowed_scopes(
+        &self,
+        authorization: Option<&str>,
+        permission: Permission,
+        database: Option<&str>,
+    ) -> Result<Option<Vec<Scope>>> {
+        let credentials = match authorization {
+            Some(authorization) => parse_authorization(authorization).context(InvalidToken)?,
+            None if self.allow_anonymous => return Ok(None),
+            None => return MissingToken.fail(),
+        };
+
+        let (principal, scopes) = self.identify(&credentials)?;
+        let scopes: Vec<_> = scopes
+            .into_iter()
+            .filter(|scope| scope.allows(permission, database))
+            .collect();
+        ensure!(
+            !scopes.is_empty(),
+            PermissionDenied {
+                principal,
+                permission,
+                database: database.map(ToString::to_string),
+            }
+        );
+        Ok(Some(scopes))
+    }
+
+    /// Returns who sent a request with the `Authorization` header `authorization`, for the
+    /// audit log. Requests are authorized before they get here, so credentials that can't be
+    /// identified any more, such as an expired LDAP session, are reported as `unknown`
+    pub fn principal(&self, authorization: Option<&str>) -> String {
+        match authorization.map(parse_authorization) {
+            Some(Some(credentials)) => self
+                .identify(&credentials)
+                .map(|(principal, _)| principal)
+                .unwrap_or_else(|_| "unknown".to_string()),
+            Some(None) => "unknown".to_string(),
+            None => "anonymous".to_string(),
+        }
+    }
+
+    /// Returns who sent a gRPC request with `metadata`, for the audit log
+    pub fn principal_grpc(&self, metadata: &MetadataMap) -> String {
+        match metadata
+            .get(AUTHORIZATION_HEADER)
+            .map(|value| value.to_str())
+        {
+            Some(Ok(authorization)) => self.principal(Some(authorization)),
+            Some(Err(_)) => "unknown".to_string(),
+            None => self.principal(None),
+        }
+    }
+
+    /// Returns who `credentials` belong to, and the scopes they are granted
+    fn identify(&self, credentials: &Credentials) -> Result<(String, Vec<Scope>)> {
+        if let Credentials::Basic { user, password } = credentials {
+            let sessions = self.ldap_sessions.read().expect("mutex poisoned");
+            if let Some(session) = sessions.get(&session_key(user, password)) {
+                if session.expires > Instant::now() {
+                    return Ok((format!("User {}", user), session.scopes.clone()));
+                }
+            }
+        }
+
+        let secret = credentials.secret();
+        if let Some(token) = self.tokens.read().expect("mutex poisoned").get(secret) {
+            return Ok((format!("Token {}", token.id), token.scopes.clone()));
+        }
+
+        match &self.oidc {
+            Some(oidc) if oidc::is_jwt(secret) => {
+                let identity = oidc.validate(secret).context(InvalidBearerToken)?;
+                let scopes = self.role_scopes(&identity.groups);
+                Ok((format!("User {}", identity.name), scopes))
+            }
+            _ => InvalidToken.fail(),
+        }
+    }
+
+    /// Returns the scopes of the roles of `groups`
+    fn role_scopes(&self, groups: &[String]) -> Vec<Scope> {
+        self.roles
+            .iter()
+            .filter(|role| groups.contains(&role.group))
+            .flat_map(|role| role.scopes.iter().cloned())
+            .collect()
+    }
+
+    /// Checks that a gRPC request is allowed `permission` on `database`, or on the server if
+    /// there is no database
+    pub fn authorize_grpc(
+        &self,
+        metadata: &MetadataMap,
+        permission: Permission,
+        database: Option<&str>,
+    ) -> Result<(), Status> {
+        let authorization = match metadata.get(AUTHORIZATION_HEADER) {
+            Some(value) => Some(
+                value
+                    .to_str()
+                    .map_err(|_| Error::InvalidToken.to_status())?,
+            ),
+            None => None,
+        };
+
+        self.authorize(authorization, permission, database)
+            .map_err(|e| e.to_status())
+    }
+
+    /// Like `authorize_grpc`, returning the rows of `database` the request may access
+    pub fn access_grpc(
+        &self,
+        metadata: &MetadataMap,
+        permission: Permission,
+        database: &str,
+    ) -> Result<RowAccess, Status> {
+        let authorization = match metadata.get(AUTHORIZATION_HEADER) {
+            Some(value) => Some(
+                value
+                    .to_str()
+                    .map_err(|_| Error::InvalidToken.to_status())?,
+            ),
+            None => None,
+        };
+
+        self.access(authorization, permission, database)
+            .map_err(|e| e.to_status())
+    }
+}
+
+/// Checks that each of `lines` is allowed by `access`
+pub fn check_lines(access: &RowAccess, lines: &[ParsedLine<'_>]) -> Result<()> {
+    match lines.iter().position(|line| !access.allows_line(line)) {
+        Some(index) => LineNotAllowed { line: index + 1 }.fail(),
+        None => Ok(()),
+    }
+}
+
+/// Checks that each of `scopes`, granted to `id`, has a database, and is only restricted to
+/// measurements and tags if it reads or writes
+fn check_scopes(id: &str, scopes: &[Scope]) -> Result<()> {
+    for scope in scopes {
+        ensure!(
+            !scope.database.is_empty(),
+            InvalidScope {
+                id,
+                reason: "the database is required, use * for all databases",
+            }
+        );
+        ensure!(
+            scope.permission != Permission::Manage
+                || (scope.measurements.is_empty() && scope.tags.is_empty()),
+            InvalidScope {
+                id,
+                reason: "only read and write scopes can be restricted to measurements and tags",
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Returns the key of the LDAP session of `user`, which doesn't keep the password around
+fn session_key(user: &str, password: &str) -> Vec<u8> {
+    let mut digest = Sha256::new();
+    digest.input((user.len() as u64).to_be_bytes());
+    digest.input(user);
+    digest.input(password);
+    digest.result().to_vec()
+}
+
+/// Extracts the credentials from an `Authorization` header value
+fn parse_authorization(value: &str) -> Option<Credentials> {
+    let mut parts = value.trim().splitn(2, ' ');
+    let scheme = parts.next()?;
+    let credentials = parts.next()?.trim();
+
+    if scheme.eq_ignore_ascii_case("token") || scheme.eq_ignore_ascii_case("bearer") {
+        Some(credentials.to_string())
+            .filter(|secret| !secret.is_empty())
+            .map(Credentials::Secret)
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        let credentials = String::from_utf8(base64::decode(credentials).ok()?).ok()?;
+        let mut parts = credentials.splitn(2, ':');
+        let user = parts.next()?.to_string();
+        let password = parts.next()?.to_string();
+        if password.is_empty() {
+            return None;
+        }
+        Some(Credentials::Basic { user, password })
+    } else {
+        None
+    }
+}
+
+impl From<Permission> for management::Permission {
+    fn from(permission: Permission) -> Self {
+        match permission {
+            Permission::Read => Self::Read,
+            Permission::Write => Self::Write,
+            Permission::Manage => Self::Manage,
+        }
+    }
+}
+
+impl From<Token> for management::Token {
+    fn from(token: Token) -> Self {
+        Self {
+            id: token.id,
+            description: token.description,
+            scopes: token
+                .scopes
+                .into_iter()
+                .map(|scope| management::Scope {
+                    permission: management::Permission::from(scope.permission) as _,
+                    database: scope.database,
+                    measurements: scope.measurements,
+                    tags: scope.tags.into_iter().collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl std::convert::TryFrom<management::Token> for Token {
+    type Error = Error;
+
+    fn try_from(proto: management::Token) -> Result<Self, Self::Error> {
+        let id = proto.id;
+        let scopes = proto
+            .scopes
+            .into_iter()
+            .map(|scope| {
+                let permission = match management::Permission::from_i32(scope.permission) {
+                    Some(management::Permission::Read) => Permission::Read,
+                    Some(management::Permission::Write) => Permission::Write,
+                    Some(management::Permission::Manage) => Permission::Manage,
+                    _ => {
+                        return InvalidScope {
+                            id: format!("token {}", id),
+                            reason: "the permission is required",
+                        }
+                        .fail()
+                    }
+                };
+                Ok(Scope {
+                    permission,
+                    database: scope.database,
+                    measurements: scope.measurements,
+                    tags: scope.tags.into_iter().collect(),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            id,
+            description: proto.description,
+            scopes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn scope(permission: Permission, database: &str) -> Scope {
+        Scope {
+            permission,
+            database: database.to_string(),
+            measurements: vec![],
+            tags: BTreeMap::new(),
+        }
+    }
+
+    fn token(id: &str, scopes: Vec<Scope>) -> Token {
+        Token {
+            id: id.to_string(),
+            description: String::new(),
+            scopes,
+        }
+    }
+
+    #[test]
+    fn authorize() {
+        let authorizer = Authorizer::new(false);
+        let writer = authorizer
+            .create_token(token("writer", vec![scope(Permission::Write, "mydb")]))
+            .unwrap();
+        let admin = authorizer
+            .create_token(token(
+                "admin",
+                vec![scope(Permission::Manage, ALL_DATABASES)],
+            ))
+            .unwrap();
+        let writer_secret = writer.clone();
+        let writer = format!("Token {}", writer);
+        let admin = format!("Bearer {}", admin);
+
+        authorizer
+            .authorize(Some(&writer), Permission::Write, Some("mydb"))
+            .unwrap();
+        authorizer
+            .authorize(Some(&admin), Permission::Manage, Some("mydb"))
+            .unwrap();
+        authorizer
+            .authorize(Some(&admin), Permission::Manage, None)
+            .unwrap();
+
+        let denied = |authorization: Option<&str>, permission, database: Option<&str>| {
+            authorizer
+                .authorize(authorization, permission, database)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            denied(Some(&writer), Permission::Write, Some("otherdb")),
+            "Token writer is not allowed to write database otherdb"
+        );
+        assert_eq!(
+            denied(Some(&writer), Permission::Read, Some("mydb")),
+            "Token writer is not allowed to read database mydb"
+        );
+        assert_eq!(
+            denied(Some(&writer), Permission::Manage, None),
+            "Token writer is not allowed to manage the server"
+        );
+        assert_eq!(
+            denied(Some(&admin), Permission::Read, Some("mydb")),
+            "Token admin is not allowed to read database mydb"
+        );
+        assert_eq!(
+            denied(None, Permission::Read, Some("mydb")),
+            "Authentication token is required"
+        );
+        assert_eq!(
+            denied(Some("Token nope"), Permission::Read, Some("mydb")),
+            "Invalid authentication token"
+        );
+        assert_eq!(
+            denied(Some("Basic abc"), Permission::Read, Some("mydb")),
+            "Invalid authentication token"
+        );
+
+        // 1.x clients send the secret as the password
+        let basic = format!(
+            "Basic {}",
+            base64::encode(format!("telegraf:{}", writer_secret))
+        );
+        authorizer
+            .authorize(Some(&basic), Permission::Write, Some("mydb"))
+            .unwrap();
+        let no_password = format!("Basic {}", base64::encode("telegraf"));
+        assert_eq!(
+            denied(Some(&no_password), Permission::Write, Some("mydb")),
+            "Invalid authentication token"
+        );
+
+        assert_eq!(authorizer.principal(Some(&admin)), "Token admin");
+        assert_eq!(authorizer.principal(Some(&basic)), "Token writer");
+        assert_eq!(authorizer.principal(Some("Token nope")), "unknown");
+        assert_eq!(authorizer.principal(None), "anonymous");
+
+        authorizer.delete_token("writer").unwrap();
+        assert_eq!(
+            denied(Some(&writer), Permission::Write, Some("mydb")),
+            "Invalid authentication token"
+        );
+        assert!(matches!(
+            authorizer.delete_token("writer"),
+            Err(Error::TokenNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn anonymous() {
+        let authorizer = Authorizer::new(true);
+        authorizer
+            .authorize(None, Permission::Manage, None)
+            .unwrap();
+
+        // tokens that are sent are still checked
+        assert!(matches!(
+            authorizer.authorize(Some("Token nope"), Permission::Read, Some("mydb")),
+            Err(Error::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn restricted_access() {
+        let authorizer = Authorizer::new(false);
+        let mut acme = scope(Permission::Write, "shared");
+        acme.measurements = vec!["cpu".to_string()];
+        acme.tags.insert("tenant".to_string(), "acme".to_string());
+        let secret = authorizer
+            .create_token(token("acme", vec![acme, scope(Permission::Read, "other")]))
+            .unwrap();
+        let acme = format!("Token {}", secret);
+
+        let access = authorizer
+            .access(Some(&acme), Permission::Write, "shared")
+            .unwrap();
+        assert!(!access.is_unrestricted());
+        assert!(access.allows_measurement("cpu"));
+        assert!(!access.allows_measurement("mem"));
+        assert!(authorizer
+            .access(Some(&acme), Permission::Read, "other")
+            .unwrap()
+            .is_unrestricted());
+        assert!(matches!(
+            authorizer.access(Some(&acme), Permission::Read, "shared"),
+            Err(Error::PermissionDenied { .. })
+        ));
+
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines(
+            "cpu,tenant=acme usage=1 10\ncpu,tenant=beta usage=1 10",
+        )
+        .collect::<Result<_, _>>()
+        .unwrap();
+        check_lines(&access, &lines[..1]).unwrap();
+        assert_eq!(
+            check_lines(&access, &lines).unwrap_err().to_string(),
+            "Line 2 is outside the measurements and tag values the request is allowed to write"
+        );
+
+        let mut manage = scope(Permission::Manage, ALL_DATABASES);
+        manage.measurements = vec!["cpu".to_string()];
+        assert!(matches!(
+            authorizer.create_token(token("admin", vec![manage])),
+            Err(Error::InvalidScope { .. })
+        ));
+    }
+
+    #[test]
+    fn create_token() {
+        let authorizer = Authorizer::new(false);
+        let secret = authorizer
+            .create_token(token("reader", vec![scope(Permission::Read, "mydb")]))
+            .unwrap();
+        assert_eq!(secret.len(), 64);
+
+        let err = authorizer
+            .create_token(token("reader", vec![]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Token reader already exists");
+
+        let err = authorizer
+            .create_token(token("other", vec![scope(Permission::Read, "")]))
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidScope { .. }));
+
+        assert_eq!(
+            authorizer.tokens(),
+            vec![token("reader", vec![scope(Permission::Read, "mydb")])]
+        );
+        assert!(!format!("{:?}", authorizer).contains(&secret));
+    }
+
+    #[test]
+    fn identity_providers() {
+        use jsonwebtoken::{Algorithm, EncodingKey, Header};
+
+        let fixture = |name: &str| -> PathBuf {
+            [
+                env!("CARGO_MANIFEST_DIR"),
+                "tests",
+                "fixtures",
+                "oidc",
+                name,
+            ]
+            .iter()
+            .collect()
+        };
+        let sign = |sub: &str, groups: &[&str]| {
+            let claims = serde_json::json!({
+                "iss": "https://idp.example.com",
+                "aud": "iox",
+                "exp": chrono::Utc::now().timestamp() + 600,
+                "sub": sub,
+                "groups": groups,
+            });
+            let pem = fs::read(fixture("signing.key")).unwrap();
+            let mut header = Header::new(Algorithm::RS256);
+            header.kid = Some("test-key".to_string());
+            let key = EncodingKey::from_rsa_pem(&pem).unwrap();
+            format!(
+                "Bearer {}",
+                jsonwebtoken::encode(&header, &claims, &key).unwrap()
+            )
+        };
+
+        let mut authorizer = Authorizer::new(false);
+        let providers: IdentityProviders = serde_json::from_str(
+            r#"{
+                "oidc": {"issuer": "https://idp.example.com", "audience": "iox",
+                         "jwks_url": "https://idp.example.com/jwks"},
+                "roles": [
+                    {"group": "iox-readers", "scopes": [{"permission": "read", "database": "mydb"}]},
+                    {"group": "iox-writers", "scopes": [{"permission": "write", "database": "*"}]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        authorizer.set_providers(providers).unwrap();
+        let jwks = fs::read(fixture("jwks.json")).unwrap();
+        authorizer.oidc().unwrap().load_keys(&jwks).unwrap();
+
+        let alice = sign("alice", &["iox-readers", "staff"]);
+        authorizer
+            .authorize(Some(&alice), Permission::Read, Some("mydb"))
+            .unwrap();
+        assert_eq!(
+            authorizer
+                .authorize(Some(&alice), Permission::Write, Some("mydb"))
+                .unwrap_err()
+                .to_string(),
+            "User alice is not allowed to write database mydb"
+        );
+
+        let bob = sign("bob", &["iox-readers", "iox-writers"]);
+        authorizer
+            .authorize(Some(&bob), Permission::Write, Some("otherdb"))
+            .unwrap();
+
+        // users without roles are authenticated, but not allowed anything
+        let carol = sign("carol", &[]);
+        assert!(matches!(
+            authorizer.authorize(Some(&carol), Permission::Read, Some("mydb")),
+            Err(Error::PermissionDenied { .. })
+        ));
+
+        // tokens still work, and their secrets are not mistaken for JSON Web Tokens
+        let secret = authorizer
+            .create_token(token("reader", vec![scope(Permission::Read, "mydb")]))
+            .unwrap();
+        authorizer
+            .authorize(
+                Some(&format!("Token {}", secret)),
+                Permission::Read,
+                Some("mydb"),
+            )
+            .unwrap();
+        assert!(matches!(
+            authorizer.authorize(Some("Bearer a.b.c"), Permission::Read, Some("mydb")),
+            Err(Error::InvalidBearerToken { .. })
+        ));
+        assert!(matches!(
+            authorizer.authorize(Some("Bearer nope"), Permission::Read, Some("mydb")),
+            Err(Error::InvalidToken)
+        ));
+
+        let invalid = IdentityProviders {
+            roles: vec![Role {
+                group: "iox-readers".to_string(),
+                scopes: vec![scope(Permission::Read, "")],
+            }],
+            ..Default::default()
+        };
+        let err = authorizer.set_providers(invalid).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid scope for role iox-readers: the database is required, use * for all databases"
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_ldap_directory() {
+        let mut authorizer = Authorizer::new(false);
+        authorizer
+            .set_providers(IdentityProviders {
+                ldap: Some(ldap::LdapConfig {
+                    url: "ldap://127.0.0.1:1".to_string(),
+                    user_dn: "uid={user},dc=example,dc=com".to_string(),
+                    group_attribute: "memberOf".to_string(),
+                    ca_cert: None,
+                    match_group_names: false,
+                }),
+                ..Default::default()
+            })
+            .unwrap();
+        let secret = authorizer
+            .create_token(token("writer", vec![scope(Permission::Write, "mydb")]))
+            .unwrap();
+
+        // the secrets of tokens are still accepted as passwords
+        let basic = format!("Basic {}", base64::encode(format!("telegraf:{}", secret)));
+        authorizer.authenticate(Some(&basic)).await;
+        authorizer
+            .authorize(Some(&basic), Permission::Write, Some("mydb"))
+            .unwrap();
+
+        let basic = format!("Basic {}", base64::encode("alice:password"));
+        authorizer.authenticate(Some(&basic)).await;
+        assert!(matches!(
+            authorizer.authorize(Some(&basic), Permission::Write, Some("mydb")),
+            Err(Error::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn load_tokens() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"{{"tokens": [
+                {{"id": "telegraf", "secret": "s3cr3t", "description": "metrics agent",
+                  "scopes": [{{"permission": "write", "database": "metrics"}}]}},
+                {{"id": "admin", "secret": "adm1n",
+                  "scopes": [{{"permission": "manage", "database": "*"}}]}}
+            ]}}"#
+        )
+        .unwrap();
+
+        let authorizer = Authorizer::new(false);
+        assert_eq!(authorizer.load_tokens(file.path()).unwrap(), 2);
+        authorizer
+            .authorize(Some("Token s3cr3t"), Permission::Write, Some("metrics"))
+            .unwrap();
+
+        let tokens = authorizer.tokens();
+        assert_eq!(tokens[0].id, "admin");
+        assert_eq!(tokens[1].description, "metrics agent");
+
+        // loading the same secrets again fails
+        let err = authorizer.load_tokens(file.path()).unwrap_err();
+        assert!(matches!(err, Error::TokenAlreadyExists { .. }));
+
+        let err = authorizer.load_tokens("/does/not/exist").unwrap_err();
+        assert!(matches!(err, Error::ReadingTokens { .. }));
+    }
+}
@@ -0,0 +1,713 @@
+//! This module contains the authentication of users against an LDAP directory, for the
+//! InfluxDB 1.x clients that send a user name and password with basic authentication.
+//!
+//! The user is authenticated by binding to the directory as the DN made from the configured
+//! template and their user name. The groups of the user, which are mapped to roles, are the
+//! DNs listed by the group attribute (`memberOf` by default) of their entry, read over the same
+//! connection, and their names too if the configuration matches group names. Only simple binds are supported, over `ldap://` or, with the CA certificate of
+//! the directory, `ldaps://`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{internal::pemfile, ClientConfig},
+    webpki::DNSNameRef,
+    TlsConnector,
+};
+
+/// How long authenticating a user may take
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest message accepted from the directory
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// BER tags of the LDAP messages and their parts
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const ENUMERATED: u8 = 0x0a;
+const BOOLEAN: u8 = 0x01;
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const UNBIND_REQUEST: u8 = 0x42;
+const SEARCH_REQUEST: u8 = 0x63;
+const SEARCH_RESULT_ENTRY: u8 = 0x64;
+const SEARCH_RESULT_DONE: u8 = 0x65;
+const SEARCH_RESULT_REFERENCE: u8 = 0x73;
+const SIMPLE_AUTHENTICATION: u8 = 0x80;
+const PRESENT_FILTER: u8 = 0x87;
+
+/// The result code of a bind with a wrong user name or password
+const INVALID_CREDENTIALS: i64 = 49;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Invalid LDAP URL {}, expected ldap://host[:port] or ldaps://host[:port]",
+        url
+    ))]
+    InvalidUrl { url: String },
+
+    #[snafu(display("The CA certificate of the directory is required for ldaps:// URLs"))]
+    MissingCaCert,
+
+    #[snafu(display("Error reading CA certificate {}: {}", path.display(), source))]
+    ReadingCaCert {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("No PEM encoded certificate found in {}", path.display()))]
+    InvalidCaCert { path: PathBuf },
+
+    #[snafu(display("Error communicating with LDAP directory {}: {}", url, source))]
+    Communicating { url: String, source: std::io::Error },
+
+    #[snafu(display("LDAP directory {} did not answer in time", url))]
+    TimedOut { url: String },
+
+    #[snafu(display("Invalid message from LDAP directory: {}", reason))]
+    InvalidMessage { reason: String },
+
+    #[snafu(display("LDAP {} failed with result code {}: {}", operation, code, message))]
+    OperationFailed {
+        operation: &'static str,
+        code: i64,
+        message: String,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The directory users are authenticated against
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LdapConfig {
+    /// `ldap://host[:port]` or `ldaps://host[:port]`
+    pub url: String,
+    /// The DN users bind as, where `{user}` is replaced by their user name, such as
+    /// `uid={user},ou=people,dc=example,dc=com`
+    pub user_dn: String,
+    /// The attribute of user entries that lists the DNs of their groups
+    #[serde(default = "default_group_attribute")]
+    pub group_attribute: String,
+    /// A PEM file with the certificate of the CA that signed the directory's certificate,
+    /// required for `ldaps://` URLs
+    #[serde(default)]
+    pub ca_cert: Option<PathBuf>,
+    /// Whether roles also match the name of groups, the value of the first RDN of their DN,
+    /// such as `iox-admins` for `cn=iox-admins,ou=groups,dc=example,dc=com`. Groups in
+    /// different parts of the directory can have the same name, so roles only match the DNs
+    /// of groups by default
+    #[serde(default)]
+    pub match_group_names: bool,
+}
+
+fn default_group_attribute() -> String {
+    "memberOf".to_string()
+}
+
+/// A user the directory knows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    /// The DNs of the user's groups, along with their names if the directory matches group
+    /// names
+    pub groups: Vec<String>,
+}
+
+/// Authenticates users against an LDAP directory
+#[derive(Clone)]
+pub struct Directory {
+    config: LdapConfig,
+    host: String,
+    port: u16,
+    tls: Option<TlsConnector>,
+}
+
+impl std::fmt::Debug for Directory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Directory")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl Directory {
+    pub fn new(config: LdapConfig) -> Result<Self> {
+        let (tls, address) = if config.url.starts_with("ldaps://") {
+            let path = config.ca_cert.as_deref().context(MissingCaCert)?;
+            (Some(tls_connector(path)?), &config.url["ldaps://".len()..])
+        } else if config.url.starts_with("ldap://") {
+            (None, &config.url["ldap://".len()..])
+        } else {
+            return InvalidUrl { url: &config.url }.fail();
+        };
+        let address = address.trim_end_matches('/');
+
+        let (host, port) = match address.rfind(':') {
+            Some(colon) => (
+                &address[..colon],
+                address[colon + 1..]
+                    .parse()
+                    .ok()
+                    .context(InvalidUrl { url: &config.url })?,
+            ),
+            None if tls.is_some() => (address, 636),
+            None => (address, 389),
+        };
+        ensure!(!host.is_empty(), InvalidUrl { url: &config.url });
+        if tls.is_some() {
+            DNSNameRef::try_from_ascii_str(host)
+                .ok()
+                .context(InvalidUrl { url: &config.url })?;
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            tls,
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &LdapConfig {
+        &self.config
+    }
+
+    /// Binds to the directory as `user`, returning their identity, or None if the directory
+    /// rejected the user name or password
+    pub async fn authenticate(&self, user: &str, password: &str) -> Result<Option<Identity>> {
+        // an empty password would be an unauthenticated bind, which succeeds without checking
+        // anything
+        if user.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+
+        let url = &self.config.url;
+        let authenticating = async {
+            let stream = TcpStream::connect((self.host.as_str(), self.port))
+                .await
+                .context(Communicating { url })?;
+            match &self.tls {
+                Some(tls) => {
+                    let name = DNSNameRef::try_from_ascii_str(&self.host)
+                        .expect("host name was checked when the directory was created");
+                    let stream = tls
+                        .connect(name, stream)
+                        .await
+                        .context(Communicating { url })?;
+                    self.bind(stream, user, password).await
+                }
+                None => self.bind(stream, user, password).await,
+            }
+        };
+
+        tokio::time::timeout(TIMEOUT, authenticating)
+            .await
+            .map_err(|_| Error::TimedOut {
+                url: url.to_string(),
+            })?
+    }
+
+    async fn bind<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: S,
+        user: &str,
+        password: &str,
+    ) -> Result<Option<Identity>> {
+        let url = &self.config.url;
+        let dn = self
+            .config
+            .user_dn
+            .replace("{user}", &escape_dn_value(user));
+
+        let mut bind = vec![];
+        put(&mut bind, INTEGER, &[3]);
+        put(&mut bind, OCTET_STRING, dn.as_bytes());
+        put(&mut bind, SIMPLE_AUTHENTICATION, password.as_bytes());
+        send(&mut stream, 1, BIND_REQUEST, &bind)
+            .await
+            .context(Communicating { url })?;
+
+        let (tag, body) = receive(&mut stream, 1, url).await?;
+        ensure!(
+            tag == BIND_RESPONSE,
+            InvalidMessage {
+                reason: format!("expected a bind response, got tag {:#x}", tag),
+            }
+        );
+        match result(&body, "bind") {
+            Ok(()) => {}
+            Err(Error::OperationFailed {
+                code: INVALID_CREDENTIALS,
+                ..
+            }) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        // Read the group attribute of the user's entry
+        let mut search = vec![];
+        put(&mut search, OCTET_STRING, dn.as_bytes());
+        put(&mut search, ENUMERATED, &[0]); // the base object only
+        put(&mut search, ENUMERATED, &[0]); // never dereference aliases
+        put(&mut search, INTEGER, &[0]); // no size limit
+        put(&mut search, INTEGER, &[TIMEOUT.as_secs() as u8]);
+        put(&mut search, BOOLEAN, &[0]); // with the values of the attributes
+        put(&mut search, PRESENT_FILTER, b"objectClass");
+        let mut attributes = vec![];
+        put(
+            &mut attributes,
+            OCTET_STRING,
+            self.config.group_attribute.as_bytes(),
+        );
+        put(&mut search, SEQUENCE, &attributes);
+        send(&mut stream, 2, SEARCH_REQUEST, &search)
+            .await
+            .context(Communicating { url })?;
+
+        let mut groups = vec![];
+        loop {
+            let (tag, body) = receive(&mut stream, 2, url).await?;
+            match tag {
+                SEARCH_RESULT_ENTRY => {
+                    for dn in attribute_values(&body, &self.config.group_attribute)? {
+                        if self.config.match_group_names {
+                            groups.extend(first_rdn_value(&dn));
+                        }
+                        groups.push(dn);
+                    }
+                }
+                SEARCH_RESULT_REFERENCE => {}
+                SEARCH_RESULT_DONE => {
+                    result(&body, "search")?;
+                    break;
+                }
+                _ => {
+                    return InvalidMessage {
+                        reason: format!("expected a search result, got tag {:#x}", tag),
+                    }
+                    .fail()
+                }
+            }
+        }
+
+        // the directory closes the connection once it receives the unbind
+        send(&mut stream, 3, UNBIND_REQUEST, &[]).await.ok();
+
+        Ok(Some(Identity {
+            name: user.to_string(),
+            groups,
+        }))
+    }
+}
+
+fn tls_connector(path: &Path) -> Result<TlsConnector> {
+    let pem = fs::read(path).context(ReadingCaCert { path })?;
+    let certs = pemfile::certs(&mut pem.as_slice()).map_err(|_| Error::InvalidCaCert {
+        path: path.to_path_buf(),
+    })?;
+    ensure!(!certs.is_empty(), InvalidCaCert { path });
+
+    let mut config = ClientConfig::new();
+    for cert in &certs {
+        config
+            .root_store
+            .add(cert)
+            .map_err(|_| Error::InvalidCaCert {
+                path: path.to_path_buf(),
+            })?;
+    }
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Escapes the characters of `value` that are special in the values of DNs (RFC 4514)
+fn escape_dn_value(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=')
+            || (i == 0 && (c == '#' || c == ' '))
+            || (i == last && c == ' ');
+        if c == '\0' {
+            escaped.push_str("\\00");
+            continue;
+        }
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Returns the value of the first RDN of `dn`, such as `admins` for
+/// `cn=admins,ou=groups,dc=example,dc=com`
+fn first_rdn_value(dn: &str) -> Option<String> {
+    let mut value = String::new();
+    let mut chars = dn.splitn(2, '=').nth(1)?.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.push(chars.next()?),
+            ',' | '+' => break,
+            c => value.push(c),
+        }
+    }
+    Some(value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+/// Appends the BER encoding of a value with `tag` and the contents `contents`
+fn put(buf: &mut Vec<u8>, tag: u8, contents: &[u8]) {
+    buf.push(tag);
+    let len = contents.len();
+    if len < 0x80 {
+        buf.push(len as u8);
+    } else {
+        let bytes = (len as u32).to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        buf.push(0x80 | (4 - skip) as u8);
+        buf.extend_from_slice(&bytes[skip..]);
+    }
+    buf.extend_from_slice(contents);
+}
+
+/// Sends the LDAP message `id` holding the operation `op` with the contents `contents`
+async fn send<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    id: u8,
+    op: u8,
+    contents: &[u8],
+) -> std::io::Result<()> {
+    let mut message = vec![];
+    put(&mut message, INTEGER, &[id]);
+    put(&mut message, op, contents);
+    let mut buf = vec![];
+    put(&mut buf, SEQUENCE, &message);
+    writer.write_all(&buf).await?;
+    writer.flush().await
+}
+
+/// Receives the next LDAP message, which must answer the message `id`, returning the tag and
+/// contents of its operation
+async fn receive<R: AsyncRead + Unpin>(reader: &mut R, id: u8, url: &str) -> Result<(u8, Vec<u8>)> {
+    let tag = reader.read_u8().await.context(Communicating { url })?;
+    ensure!(
+        tag == SEQUENCE,
+        InvalidMessage {
+            reason: format!("expected a sequence, got tag {:#x}", tag),
+        }
+    );
+    let first = reader.read_u8().await.context(Communicating { url })?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        ensure!(
+            (1..=4).contains(&count),
+            InvalidMessage {
+                reason: format!("length of {} bytes", count),
+            }
+        );
+        let mut len = 0;
+        for _ in 0..count {
+            len = len << 8 | reader.read_u8().await.context(Communicating { url })? as usize;
+        }
+        len
+    };
+    ensure!(
+        len <= MAX_MESSAGE_SIZE,
+        InvalidMessage {
+            reason: format!("message of {} bytes", len),
+        }
+    );
+    let mut message = vec![0; len];
+    reader
+        .read_exact(&mut message)
+        .await
+        .context(Communicating { url })?;
+
+    let mut message = Ber(&message);
+    let (_, message_id) = message.read()?;
+    ensure!(
+        message_id == [id],
+        InvalidMessage {
+            reason: format!("unexpected message id {:?}", message_id),
+        }
+    );
+    let (tag, contents) = message.read()?;
+    Ok((tag, contents.to_vec()))
+}
+
+/// Checks the LDAPResult that `contents` start with
+fn result(contents: &[u8], operation: &'static str) -> Result<()> {
+    let mut contents = Ber(contents);
+    let (_, code) = contents.read()?;
+    let code = code.iter().fold(0i64, |code, &b| code << 8 | i64::from(b));
+    let _matched_dn = contents.read()?;
+    let (_, message) = contents.read()?;
+    ensure!(
+        code == 0,
+        OperationFailed {
+            operation,
+            code,
+            message: String::from_utf8_lossy(message),
+        }
+    );
+    Ok(())
+}
+
+/// Returns the values of `attribute` in the contents of a search result entry
+fn attribute_values(contents: &[u8], attribute: &str) -> Result<Vec<String>> {
+    let mut contents = Ber(contents);
+    let _object_name = contents.read()?;
+    let (_, attributes) = contents.read()?;
+
+    let mut attributes = Ber(attributes);
+    let mut values = vec![];
+    while !attributes.0.is_empty() {
+        let (_, partial_attribute) = attributes.read()?;
+        let mut partial_attribute = Ber(partial_attribute);
+        let (_, name) = partial_attribute.read()?;
+        let (tag, vals) = partial_attribute.read()?;
+        if tag != SET || !String::from_utf8_lossy(name).eq_ignore_ascii_case(attribute) {
+            continue;
+        }
+        let mut vals = Ber(vals);
+        while !vals.0.is_empty() {
+            let (_, value) = vals.read()?;
+            values.push(String::from_utf8_lossy(value).into_owned());
+        }
+    }
+    Ok(values)
+}
+
+/// Reads BER encoded values from the front of a buffer
+struct Ber<'a>(&'a [u8]);
+
+impl<'a> Ber<'a> {
+    /// Returns the tag and contents of the next value
+    fn read(&mut self) -> Result<(u8, &'a [u8])> {
+        let truncated = || Error::InvalidMessage {
+            reason: "truncated value".to_string(),
+        };
+        let buf = self.0;
+        let tag = *buf.get(0).ok_or_else(truncated)?;
+        let first = *buf.get(1).ok_or_else(truncated)?;
+        let (len, start) = if first < 0x80 {
+            (first as usize, 2)
+        } else {
+            let count = (first & 0x7f) as usize;
+            ensure!(
+                (1..=4).contains(&count),
+                InvalidMessage {
+                    reason: format!("length of {} bytes", count),
+                }
+            );
+            let bytes = buf.get(2..2 + count).ok_or_else(truncated)?;
+            let len = bytes.iter().fold(0, |len, &b| len << 8 | b as usize);
+            (len, 2 + count)
+        };
+        let contents = buf.get(start..start + len).ok_or_else(truncated)?;
+        self.0 = &buf[start + len..];
+        Ok((tag, contents))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn config(url: &str) -> LdapConfig {
+        LdapConfig {
+            url: url.to_string(),
+            user_dn: "uid={user},ou=people,dc=example,dc=com".to_string(),
+            group_attribute: default_group_attribute(),
+            ca_cert: None,
+            match_group_names: false,
+        }
+    }
+
+    fn ldap_result(code: u8, message: &str) -> Vec<u8> {
+        let mut contents = vec![];
+        put(&mut contents, ENUMERATED, &[code]);
+        put(&mut contents, OCTET_STRING, b"");
+        put(&mut contents, OCTET_STRING, message.as_bytes());
+        contents
+    }
+
+    /// Answers one connection like a directory where only `alice` with the password `secret`
+    /// can bind, and is a member of two groups
+    async fn fake_directory(mut listener: TcpListener) -> Vec<Vec<u8>> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut requests = vec![];
+
+        let (tag, bind) = receive(&mut stream, 1, "fake").await.unwrap();
+        assert_eq!(tag, BIND_REQUEST);
+        let mut fields = Ber(&bind);
+        fields.read().unwrap();
+        let (_, dn) = fields.read().unwrap();
+        let (_, password) = fields.read().unwrap();
+        requests.push(bind.clone());
+        if dn != b"uid=alice,ou=people,dc=example,dc=com" || password != b"secret" {
+            let response = ldap_result(INVALID_CREDENTIALS as u8, "invalid credentials");
+            send(&mut stream, 1, BIND_RESPONSE, &response)
+                .await
+                .unwrap();
+            return requests;
+        }
+        send(&mut stream, 1, BIND_RESPONSE, &ldap_result(0, ""))
+            .await
+            .unwrap();
+
+        let (tag, search) = receive(&mut stream, 2, "fake").await.unwrap();
+        assert_eq!(tag, SEARCH_REQUEST);
+        requests.push(search);
+
+        let mut values = vec![];
+        put(
+            &mut values,
+            OCTET_STRING,
+            b"cn=iox-admins,ou=groups,dc=example,dc=com",
+        );
+        put(
+            &mut values,
+            OCTET_STRING,
+            b"cn=staff\\, all,ou=groups,dc=example,dc=com",
+        );
+        let mut attribute = vec![];
+        put(&mut attribute, OCTET_STRING, b"memberOf");
+        put(&mut attribute, SET, &values);
+        let mut attributes = vec![];
+        put(&mut attributes, SEQUENCE, &attribute);
+        let mut entry = vec![];
+        put(&mut entry, OCTET_STRING, dn);
+        put(&mut entry, SEQUENCE, &attributes);
+        send(&mut stream, 2, SEARCH_RESULT_ENTRY, &entry)
+            .await
+            .unwrap();
+        send(&mut stream, 2, SEARCH_RESULT_DONE, &ldap_result(0, ""))
+            .await
+            .unwrap();
+
+        requests
+    }
+
+    #[tokio::test]
+    async fn authenticate() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let directory_task = tokio::spawn(fake_directory(listener));
+        let directory = Directory::new(config(&url)).unwrap();
+
+        let identity = directory
+            .authenticate("alice", "secret")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(identity.name, "alice");
+        assert_eq!(
+            identity.groups,
+            vec![
+                "cn=iox-admins,ou=groups,dc=example,dc=com",
+                "cn=staff\\, all,ou=groups,dc=example,dc=com",
+            ]
+        );
+        let requests = directory_task.await.unwrap();
+        assert_eq!(requests.len(), 2);
+
+        // the names of the groups only match when asked to
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let directory_task = tokio::spawn(fake_directory(listener));
+        let directory = Directory::new(LdapConfig {
+            match_group_names: true,
+            ..config(&url)
+        })
+        .unwrap();
+        let identity = directory
+            .authenticate("alice", "secret")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            identity.groups,
+            vec![
+                "iox-admins",
+                "cn=iox-admins,ou=groups,dc=example,dc=com",
+                "staff, all",
+                "cn=staff\\, all,ou=groups,dc=example,dc=com",
+            ]
+        );
+        directory_task.await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let directory_task = tokio::spawn(fake_directory(listener));
+        let directory = Directory::new(config(&url)).unwrap();
+        assert_eq!(
+            directory.authenticate("alice", "wrong").await.unwrap(),
+            None
+        );
+        directory_task.await.unwrap();
+
+        // empty passwords are rejected without asking the directory
+        assert_eq!(directory.authenticate("alice", "").await.unwrap(), None);
+    }
+
+    #[test]
+    fn urls() {
+        let directory = Directory::new(config("ldap://ldap.example.com")).unwrap();
+        assert_eq!(
+            (directory.host.as_str(), directory.port),
+            ("ldap.example.com", 389)
+        );
+        let directory = Directory::new(config("ldap://10.0.0.1:1389/")).unwrap();
+        assert_eq!(
+            (directory.host.as_str(), directory.port),
+            ("10.0.0.1", 1389)
+        );
+
+        let err = Directory::new(config("ldaps://ldap.example.com")).unwrap_err();
+        assert!(matches!(err, Error::MissingCaCert), "{}", err);
+        let err = Directory::new(config("http://ldap.example.com")).unwrap_err();
+        assert!(matches!(err, Error::InvalidUrl { .. }), "{}", err);
+        let err = Directory::new(config("ldap://ldap.example.com:port")).unwrap_err();
+        assert!(matches!(err, Error::InvalidUrl { .. }), "{}", err);
+    }
+
+    #[test]
+    fn dn_values() {
+        assert_eq!(escape_dn_value("alice"), "alice");
+        assert_eq!(escape_dn_value("a,b=c"), "a\\,b\\=c");
+        assert_eq!(escape_dn_value(" #x "), "\\ #x\\ ");
+        assert_eq!(
+            first_rdn_value("cn=iox-admins,ou=groups,dc=example,dc=com"),
+            Some("iox-admins".to_string())
+        );
+        assert_eq!(first_rdn_value("cn=a\\,b,dc=com"), Some("a,b".to_string()));
+        assert_eq!(first_rdn_value("nothing"), None);
+    }
+
+    #[test]
+    fn ber_lengths() {
+        let mut buf = vec![];
+        put(&mut buf, OCTET_STRING, &[7; 300]);
+        assert_eq!(&buf[..4], &[OCTET_STRING, 0x82, 0x01, 0x2c]);
+        let (tag, contents) = Ber(&buf).read().unwrap();
+        assert_eq!((tag, contents.len()), (OCTET_STRING, 300));
+
+        assert!(Ber(&buf[..100]).read().is_err());
+    }
+}
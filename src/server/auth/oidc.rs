@@ -0,0 +1,386 @@
+//! This module contains the validation of the JSON Web Tokens issued by an OpenID Connect
+//! identity provider, which clients can send as bearer tokens instead of IOx tokens.
+//!
+//! A token is accepted if it is signed by one of the RSA keys the provider publishes at its
+//! JWKS URL, was issued by the configured issuer for the configured audience and has not
+//! expired. The keys are fetched when the server starts and refreshed periodically.
+
+use std::{collections::HashMap, sync::RwLock};
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+/// The algorithms tokens may be signed with
+const ALGORITHMS: &[Algorithm] = &[Algorithm::RS256, Algorithm::RS384, Algorithm::RS512];
+
+/// How far the clocks of the server and the provider may drift apart, in seconds
+const LEEWAY_SECONDS: u64 = 60;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error fetching signing keys from {}: {}", url, source))]
+    FetchingKeys { url: String, source: reqwest::Error },
+
+    #[snafu(display("Error parsing signing keys from {}: {}", url, source))]
+    ParsingKeys {
+        url: String,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("No RSA signing keys found at {}", url))]
+    NoKeys { url: String },
+
+    #[snafu(display("{}", source))]
+    InvalidToken { source: jsonwebtoken::errors::Error },
+
+    #[snafu(display("Token is signed with unsupported algorithm {:?}", algorithm))]
+    UnsupportedAlgorithm { algorithm: Algorithm },
+
+    #[snafu(display("Token is signed with unknown key {}", kid.as_deref().unwrap_or("")))]
+    UnknownKey { kid: Option<String> },
+
+    #[snafu(display("Token has no {} claim", claim))]
+    MissingClaim { claim: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The OpenID Connect provider whose tokens are accepted
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct OidcConfig {
+    /// The `iss` claim of the tokens
+    pub issuer: String,
+    /// The `aud` claim of the tokens
+    pub audience: String,
+    /// Where the provider publishes the keys it signs tokens with
+    pub jwks_url: String,
+    /// The claim that names the user
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    /// The claim that lists the groups of the user, which are mapped to roles
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+}
+
+fn default_username_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_groups_claim() -> String {
+    "groups".to_string()
+}
+
+/// The user a valid token was issued to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+}
+
+/// Validates the tokens of an OpenID Connect provider
+pub struct Validator {
+    config: OidcConfig,
+    /// The signing keys of the provider, keyed by their id. Keys without an id have an
+    /// empty one
+    keys: RwLock<HashMap<String, DecodingKey<'static>>>,
+}
+
+impl std::fmt::Debug for Validator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut kids: Vec<_> = self
+            .keys
+            .read()
+            .expect("mutex poisoned")
+            .keys()
+            .cloned()
+            .collect();
+        kids.sort();
+        f.debug_struct("Validator")
+            .field("config", &self.config)
+            .field("keys", &kids)
+            .finish()
+    }
+}
+
+impl Validator {
+    /// Creates a validator without keys, which rejects all tokens until they are fetched
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            keys: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &OidcConfig {
+        &self.config
+    }
+
+    /// Fetches the signing keys of the provider, replacing the previous ones, and returns how
+    /// many there are
+    pub async fn refresh_keys(&self) -> Result<usize> {
+        let url = &self.config.jwks_url;
+        let body = async { reqwest::get(url).await?.error_for_status()?.bytes().await }
+            .await
+            .context(FetchingKeys { url })?;
+        self.load_keys(&body)
+    }
+
+    /// Replaces the signing keys with the RSA keys of the JWKS document `jwks`, ignoring the
+    /// others, and returns how many there are
+    pub fn load_keys(&self, jwks: &[u8]) -> Result<usize> {
+        let url = &self.config.jwks_url;
+        let jwks: JwkSet = serde_json::from_slice(jwks).context(ParsingKeys { url })?;
+        let keys: HashMap<_, _> = jwks
+            .keys
+            .into_iter()
+            .filter(|jwk| jwk.kty == "RSA")
+            .filter_map(|jwk| {
+                let key = DecodingKey::from_rsa_components(jwk.n.as_ref()?, jwk.e.as_ref()?);
+                Some((jwk.kid.unwrap_or_default(), key.into_static()))
+            })
+            .collect();
+        ensure!(!keys.is_empty(), NoKeys { url });
+
+        let count = keys.len();
+        *self.keys.write().expect("mutex poisoned") = keys;
+        Ok(count)
+    }
+
+    /// Checks the signature and claims of `token`, returning the user it was issued to
+    pub fn validate(&self, token: &str) -> Result<Identity> {
+        let header = jsonwebtoken::decode_header(token).context(InvalidToken)?;
+        ensure!(
+            ALGORITHMS.contains(&header.alg),
+            UnsupportedAlgorithm {
+                algorithm: header.alg
+            }
+        );
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = LEEWAY_SECONDS;
+        validation.iss = Some(self.config.issuer.clone());
+        validation.set_audience(&[&self.config.audience]);
+
+        let claims = {
+            let keys = self.keys.read().expect("mutex poisoned");
+            let key = match &header.kid {
+                Some(kid) => keys.get(kid),
+                // a token without a key id can only be checked if there is one key
+                None if keys.len() == 1 => keys.values().next(),
+                None => None,
+            }
+            .context(UnknownKey { kid: header.kid })?;
+            jsonwebtoken::decode::<HashMap<String, Value>>(token, key, &validation)
+                .context(InvalidToken)?
+                .claims
+        };
+
+        let name = match claims.get(&self.config.username_claim) {
+            Some(Value::String(name)) => name.clone(),
+            Some(Value::Number(name)) => name.to_string(),
+            _ => {
+                return MissingClaim {
+                    claim: &self.config.username_claim,
+                }
+                .fail()
+            }
+        };
+        let groups = match claims.get(&self.config.groups_claim) {
+            Some(Value::Array(groups)) => groups
+                .iter()
+                .filter_map(|group| group.as_str().map(ToString::to_string))
+                .collect(),
+            Some(Value::String(group)) => vec![group.clone()],
+            _ => vec![],
+        };
+
+        Ok(Identity { name, groups })
+    }
+}
+
+/// Whether `credentials` look like a JSON Web Token, three base64 encoded parts separated by
+/// dots, rather than the secret of an IOx token
+pub fn is_jwt(credentials: &str) -> bool {
+    let parts: Vec<_> = credentials.split('.').collect();
+    parts.len() == 3
+        && parts.iter().all(|part| {
+            !part.is_empty()
+                && part
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        [
+            env!("CARGO_MANIFEST_DIR"),
+            "tests",
+            "fixtures",
+            "oidc",
+            name,
+        ]
+        .iter()
+        .collect()
+    }
+
+    fn validator() -> Validator {
+        let validator = Validator::new(OidcConfig {
+            issuer: "https://idp.example.com".to_string(),
+            audience: "iox".to_string(),
+            jwks_url: "https://idp.example.com/jwks".to_string(),
+            username_claim: default_username_claim(),
+            groups_claim: default_groups_claim(),
+        });
+        let jwks = std::fs::read(fixture("jwks.json")).unwrap();
+        // the EC key of the fixture is skipped
+        assert_eq!(validator.load_keys(&jwks).unwrap(), 1);
+        validator
+    }
+
+    fn sign(kid: Option<&str>, claims: Value) -> String {
+        let pem = std::fs::read(fixture("signing.key")).unwrap();
+        let key = EncodingKey::from_rsa_pem(&pem).unwrap();
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = kid.map(ToString::to_string);
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+
+    fn expires() -> i64 {
+        chrono::Utc::now().timestamp() + 600
+    }
+
+    #[test]
+    fn valid_tokens() {
+        let validator = validator();
+
+        let token = sign(
+            Some("test-key"),
+            json!({
+                "iss": "https://idp.example.com",
+                "aud": "iox",
+                "exp": expires(),
+                "sub": "alice",
+                "groups": ["iox-readers", "staff"],
+            }),
+        );
+        assert!(is_jwt(&token));
+        assert_eq!(
+            validator.validate(&token).unwrap(),
+            Identity {
+                name: "alice".to_string(),
+                groups: vec!["iox-readers".to_string(), "staff".to_string()],
+            }
+        );
+
+        // the only key is used for tokens without a key id
+        let token = sign(
+            None,
+            json!({
+                "iss": "https://idp.example.com",
+                "aud": ["iox", "other"],
+                "exp": expires(),
+                "sub": "bob",
+                "groups": "iox-admins",
+            }),
+        );
+        assert_eq!(
+            validator.validate(&token).unwrap().groups,
+            vec!["iox-admins".to_string()]
+        );
+    }
+
+    #[test]
+    fn invalid_tokens() {
+        let validator = validator();
+        let claims = |iss: &str, aud: &str, exp: i64| json!({"iss": iss, "aud": aud, "exp": exp, "sub": "alice"});
+        let invalid = |token: String| validator.validate(&token).unwrap_err();
+
+        let err = invalid(sign(
+            Some("test-key"),
+            claims("https://evil.example.com", "iox", expires()),
+        ));
+        assert!(matches!(err, Error::InvalidToken { .. }), "{}", err);
+
+        let err = invalid(sign(
+            Some("test-key"),
+            claims("https://idp.example.com", "other", expires()),
+        ));
+        assert!(matches!(err, Error::InvalidToken { .. }), "{}", err);
+
+        let err = invalid(sign(
+            Some("test-key"),
+            claims("https://idp.example.com", "iox", expires() - 3600),
+        ));
+        assert!(matches!(err, Error::InvalidToken { .. }), "{}", err);
+
+        let err = invalid(sign(
+            Some("rotated"),
+            claims("https://idp.example.com", "iox", expires()),
+        ));
+        assert_eq!(err.to_string(), "Token is signed with unknown key rotated");
+
+        let err = invalid(sign(
+            Some("test-key"),
+            json!({"iss": "https://idp.example.com", "aud": "iox", "exp": expires()}),
+        ));
+        assert_eq!(err.to_string(), "Token has no sub claim");
+
+        // a signature by another key
+        let token = sign(
+            Some("test-key"),
+            claims("https://idp.example.com", "iox", expires()),
+        );
+        let mut parts: Vec<_> = token.split('.').collect();
+        let other = sign(
+            Some("test-key"),
+            claims("https://idp.example.com", "iox", expires() + 1),
+        );
+        parts[2] = other.rsplit('.').next().unwrap();
+        let err = invalid(parts.join("."));
+        assert!(matches!(err, Error::InvalidToken { .. }), "{}", err);
+
+        // tokens signed with a shared secret are not accepted
+        let token = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims("https://idp.example.com", "iox", expires()),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let err = invalid(token);
+        assert!(matches!(err, Error::UnsupportedAlgorithm { .. }), "{}", err);
+    }
+
+    #[test]
+    fn jwt_detection() {
+        assert!(is_jwt("eyJhbGciOiJSUzI1NiJ9.eyJzdWIiOiJhIn0.c2ln"));
+        assert!(!is_jwt("0123456789abcdef"));
+        assert!(!is_jwt("a..b"));
+        assert!(!is_jwt("a.b.c.d"));
+        assert!(!is_jwt("a.b.c d"));
+    }
+}
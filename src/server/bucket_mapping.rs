@@ -0,0 +1,255 @@
+//! This module contains the translation of the organization and bucket that InfluxDB 2.0 API
+//! requests address to the IOx database that stores them.
+//!
+//! By default bucket `sensors` of organization `company` is stored in database
+//! `company_sensors`. Buckets can instead be mapped to any database with a JSON file:
+//!
+//! ```json
+//! {"mappings": [
+//!     {"org": "company", "bucket": "sensors", "database": "sensors"},
+//!     {"org": "company", "bucket": "sensors_autogen", "database": "sensors"}
+//! ]}
+//! ```
+//!
+//! Organizations and buckets can be given by name or, as the storage gRPC API does, by id.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use serde::Deserialize;
+use snafu::{ensure, ResultExt, Snafu};
+use storage::org_and_bucket_to_database;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error reading bucket mappings from {}: {}", path.display(), source))]
+    ReadingMappings {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error parsing bucket mappings from {}: {}", path.display(), source))]
+    ParsingMappings {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display(
+        "Invalid mapping of bucket '{}' of org '{}': org, bucket and database are required",
+        bucket,
+        org
+    ))]
+    InvalidMapping { org: String, bucket: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The database a bucket is stored in
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Mapping {
+    pub org: String,
+    pub bucket: String,
+    pub database: String,
+}
+
+#[derive(Deserialize)]
+struct MappingFile {
+    mappings: Vec<Mapping>,
+}
+
+/// Decides which database stores each bucket
+#[derive(Debug)]
+pub struct BucketMapping {
+    /// Whether writing to a bucket whose database does not exist creates the database
+    auto_create: bool,
+    /// Databases keyed by org and bucket
+    mappings: RwLock<BTreeMap<(String, String), String>>,
+}
+
+impl BucketMapping {
+    /// Creates a mapping that stores each bucket in the database named after its org and
+    /// bucket, until other mappings are added
+    pub fn new(auto_create: bool) -> Self {
+        Self {
+            auto_create,
+            mappings: Default::default(),
+        }
+    }
+
+    /// Whether writes to a bucket should create its database if it does not exist
+    pub fn auto_create(&self) -> bool {
+        self.auto_create
+    }
+
+    /// Adds the mappings of the JSON file at `path`, returning how many there were
+    pub fn load(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path).context(ReadingMappings { path })?;
+        let file: MappingFile = serde_json::from_str(&json).context(ParsingMappings { path })?;
+
+        let count = file.mappings.len();
+        for mapping in file.mappings {
+            self.set(mapping)?;
+        }
+        Ok(count)
+    }
+
+    /// Stores the bucket of `mapping` in its database, replacing any previous mapping
+    pub fn set(&self, mapping: Mapping) -> Result<()> {
+        let Mapping {
+            org,
+            bucket,
+            database,
+        } = mapping;
+        ensure!(
+            !org.is_empty() && !bucket.is_empty() && !database.is_empty(),
+            InvalidMapping { org, bucket }
+        );
+
+        self.mappings
+            .write()
+            .expect("mutex poisoned")
+            .insert((org, bucket), database);
+        Ok(())
+    }
+
+    /// Returns the name of the database that stores `bucket` of `org`
+    pub fn database_name(&self, org: &str, bucket: &str) -> String {
+        self.mappings
+            .read()
+            .expect("mutex poisoned")
+            .get(&(org.to_string(), bucket.to_string()))
+            .cloned()
+            .unwrap_or_else(|| org_and_bucket_to_database(org, bucket))
+    }
+
+    /// Returns all the mappings, sorted by org and bucket
+    pub fn mappings(&self) -> Vec<Mapping> {
+        self.mappings
+            .read()
+            .expect("mutex poisoned")
+            .iter()
+            .map(|((org, bucket), database)| Mapping {
+                org: org.clone(),
+                bucket: bucket.clone(),
+                database: database.clone(),
+            })
+            .collect()
+    }
+
+    /// Returns the buckets stored in the databases `db_names`, sorted by org and bucket: the
+    /// mapped buckets, and a bucket for each other database named after an org and a bucket,
+    /// split at its first underscore
+    pub fn buckets(&self, db_names: &[String]) -> Vec<Mapping> {
+        let mut buckets: Vec<_> = self
+            .mappings()
+            .into_iter()
+            .filter(|mapping| db_names.contains(&mapping.database))
+            .collect();
+        for database in db_names {
+            if buckets.iter().any(|mapping| &mapping.database == database) {
+                continue;
+            }
+            if let Some(index) = database.find('_') {
+                let (org, bucket) = (&database[..index], &database[index + 1..]);
+                if !org.is_empty() && !bucket.is_empty() {
+                    buckets.push(Mapping {
+                        org: org.to_string(),
+                        bucket: bucket.to_string(),
+                        database: database.clone(),
+                    });
+                }
+            }
+        }
+
+        buckets.sort_by(|a, b| (&a.org, &a.bucket).cmp(&(&b.org, &b.bucket)));
+        buckets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn mapping(org: &str, bucket: &str, database: &str) -> Mapping {
+        Mapping {
+            org: org.to_string(),
+            bucket: bucket.to_string(),
+            database: database.to_string(),
+        }
+    }
+
+    #[test]
+    fn database_name() {
+        let buckets = BucketMapping::new(true);
+        assert_eq!(
+            buckets.database_name("company", "sensors"),
+            "company_sensors"
+        );
+
+        buckets
+            .set(mapping("company", "sensors", "sensors"))
+            .unwrap();
+        assert_eq!(buckets.database_name("company", "sensors"), "sensors");
+        assert_eq!(buckets.database_name("company", "other"), "company_other");
+        assert_eq!(buckets.database_name("other", "sensors"), "other_sensors");
+
+        let err = buckets.set(mapping("company", "", "sensors")).unwrap_err();
+        assert!(matches!(err, Error::InvalidMapping { .. }));
+    }
+
+    #[test]
+    fn load() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"{{"mappings": [
+                {{"org": "company", "bucket": "sensors_autogen", "database": "sensors"}},
+                {{"org": "company", "bucket": "sensors", "database": "sensors"}}
+            ]}}"#
+        )
+        .unwrap();
+
+        let buckets = BucketMapping::new(false);
+        assert_eq!(buckets.load(file.path()).unwrap(), 2);
+        assert_eq!(
+            buckets.mappings(),
+            vec![
+                mapping("company", "sensors", "sensors"),
+                mapping("company", "sensors_autogen", "sensors"),
+            ]
+        );
+
+        let err = buckets.load("/does/not/exist").unwrap_err();
+        assert!(matches!(err, Error::ReadingMappings { .. }));
+    }
+
+    #[test]
+    fn buckets() {
+        let buckets = BucketMapping::new(true);
+        buckets
+            .set(mapping("company", "sensors", "sensors"))
+            .unwrap();
+        buckets
+            .set(mapping("company", "missing", "missing"))
+            .unwrap();
+
+        let db_names: Vec<_> = vec!["company_hosts", "sensors", "system", "other_app_logs"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            buckets.buckets(&db_names),
+            vec![
+                mapping("company", "hosts", "company_hosts"),
+                mapping("company", "sensors", "sensors"),
+                mapping("other", "app_logs", "other_app_logs"),
+            ]
+        );
+    }
+}
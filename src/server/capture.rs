@@ -0,0 +1,218 @@
+//! This module captures the writes and queries the server receives to a file, so that the
+//! workload can be reproduced against another server with the `replay` command.
+//!
+//! Capturing is opt-in, with `--capture-file`. Each request is appended to the file as a line
+//! of JSON holding the time it was received, the database it is for and the request itself:
+//! the line protocol of writes or the text of queries. Requests are captured before they are
+//! executed, so the ones that fail are captured too.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use tracing::warn;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error opening capture file {:?}: {}", path, source))]
+    OpeningFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error reading capture file: {}", source))]
+    ReadingFile { source: std::io::Error },
+
+    #[snafu(display("Invalid record on line {} of capture file: {}", line, source))]
+    InvalidRecord {
+        line: usize,
+        source: serde_json::Error,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A request received by the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// When the request was received, in nanoseconds since the epoch
+    pub time: i64,
+    /// The database the request is for, empty for 1.x queries without a database
+    pub db: String,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+/// The part of a request needed to send it again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Request {
+    /// Line protocol, whose timestamps are in `precision` (`ns` if not set)
+    Write {
+        lines: String,
+        precision: Option<String>,
+    },
+    /// A SQL query, sent to the query gRPC API or to /api/v2/read
+    Sql { query: String },
+    /// InfluxQL statements sent to the 1.x /query endpoint
+    #[serde(rename = "influxql")]
+    InfluxQl { query: String },
+}
+
+/// Appends the requests the server receives to a capture file
+#[derive(Debug)]
+pub struct Capture {
+    path: PathBuf,
+    file: Mutex<BufWriter<File>>,
+}
+
+impl Capture {
+    /// Captures to the file at `path`, appending to it if it exists
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(OpeningFile { path: &path })?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// The path of the capture file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `request` for database `db`. Errors are logged rather than returned, so that
+    /// capturing never fails the request.
+    pub fn record(&self, db: &str, request: Request) {
+        let record = Record {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("time is after the epoch")
+                .as_nanos() as i64,
+            db: db.to_string(),
+            request,
+        };
+        let mut line = serde_json::to_vec(&record).expect("records can be serialized to JSON");
+        line.push(b'\n');
+
+        // The whole line is written and flushed at once so that the records of concurrent
+        // requests don't interleave, and a crash loses at most the record being written
+        let mut file = self.file.lock().expect("mutex poisoned");
+        if let Err(e) = file.write_all(&line).and_then(|_| file.flush()) {
+            warn!("error writing to capture file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Reads the records of a capture file, in the order they were captured
+pub fn read_records(reader: impl BufRead) -> impl Iterator<Item = Result<Record>> {
+    reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|(index, line)| {
+            let line = line.context(ReadingFile)?;
+            serde_json::from_str(&line).context(InvalidRecord { line: index + 1 })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+
+    #[test]
+    fn capture_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.jsonl");
+
+        let capture = Capture::open(&path).unwrap();
+        capture.record(
+            "telegraf",
+            Request::Write {
+                lines: "cpu usage=1 10\ncpu usage=2 20".to_string(),
+                precision: Some("s".to_string()),
+            },
+        );
+        capture.record(
+            "telegraf",
+            Request::Sql {
+                query: "select * from cpu".to_string(),
+            },
+        );
+        drop(capture);
+
+        // captures append to the file
+        let capture = Capture::open(&path).unwrap();
+        capture.record(
+            "",
+            Request::InfluxQl {
+                query: "SHOW DATABASES".to_string(),
+            },
+        );
+
+        let file = BufReader::new(File::open(&path).unwrap());
+        let records: Vec<_> = read_records(file).collect::<Result<_>>().unwrap();
+        let requests: Vec<_> = records
+            .iter()
+            .map(|record| (record.db.as_str(), &record.request))
+            .collect();
+        assert_eq!(
+            requests,
+            vec![
+                (
+                    "telegraf",
+                    &Request::Write {
+                        lines: "cpu usage=1 10\ncpu usage=2 20".to_string(),
+                        precision: Some("s".to_string()),
+                    }
+                ),
+                (
+                    "telegraf",
+                    &Request::Sql {
+                        query: "select * from cpu".to_string()
+                    }
+                ),
+                (
+                    "",
+                    &Request::InfluxQl {
+                        query: "SHOW DATABASES".to_string()
+                    }
+                ),
+            ]
+        );
+        assert!(records.windows(2).all(|w| w[0].time <= w[1].time));
+    }
+
+    #[test]
+    fn read_invalid_record() {
+        let data =
+            "\n{\"time\":1,\"db\":\"foo\",\"kind\":\"sql\",\"query\":\"select 1\"}\nnot json\n";
+        let mut records = read_records(Cursor::new(data));
+
+        assert_eq!(
+            records.next().unwrap().unwrap(),
+            Record {
+                time: 1,
+                db: "foo".to_string(),
+                request: Request::Sql {
+                    query: "select 1".to_string()
+                }
+            }
+        );
+        let err = records.next().unwrap().unwrap_err();
+        assert!(matches!(err, Error::InvalidRecord { line: 3, .. }));
+    }
+}
@@ -0,0 +1,408 @@
+//! This module contains the admission of SQL queries, so that dashboards keep answering
+//! quickly while exports and other batch queries run.
+//!
+//! Each query is either interactive, the default, or batch. Clients mark a query as batch with
+//! the `X-IOx-Query-Priority: batch` header (or gRPC metadata), or a `/*+ batch */` hint
+//! at the start of its SQL, which takes precedence over the header.
+//!
+//! At most `max_queries` queries run at the same time, and at most `max_batch_queries` of them
+//! are batch queries, so that some slots are always left for interactive queries. A query
+//! waits for a slot when none is free, and the waiting interactive queries are admitted
+//! before the waiting batch queries. Interactive queries always wait, but batch queries are
+//! rejected, to be retried later, once `max_queued_batch_queries` of them are waiting.
+
+use std::{collections::VecDeque, fmt, str::FromStr, sync::Mutex};
+
+use snafu::Snafu;
+use tokio::sync::oneshot;
+use tonic::metadata::MetadataMap;
+
+/// The header, or gRPC metadata key, giving the priority of the queries of a request
+pub const PRIORITY_HEADER: &str = "x-iox-query-priority";
+
+pub const DEFAULT_MAX_QUERIES: usize = 32;
+pub const DEFAULT_MAX_BATCH_QUERIES: usize = 8;
+pub const DEFAULT_MAX_QUEUED_BATCH_QUERIES: usize = 32;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unknown query priority {}, expected interactive or batch", priority))]
+    UnknownPriority { priority: String },
+
+    #[snafu(display("Too many batch queries are waiting to run ({}), retry later", queued))]
+    Rejected { queued: usize },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The priority class of a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Queries a user waits for, such as those of dashboards
+    Interactive,
+    /// Queries that may wait, such as exports
+    Batch,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self::Interactive
+    }
+}
+
+impl Priority {
+    /// Returns the priority of `query`, given by its hint or else by the `header` of its
+    /// request, and the query without the hint
+    pub fn of<'a>(header: Option<&str>, query: &'a str) -> Result<(Self, &'a str)> {
+        let (hint, query) = strip_hint(query);
+        let priority = match (hint, header) {
+            (Some(priority), _) => priority,
+            (None, Some(header)) => header.parse()?,
+            (None, None) => Self::default(),
+        };
+        Ok((priority, query))
+    }
+}
+
+impl FromStr for Priority {
+    type Err = Error;
+
+    fn from_str(priority: &str) -> Result<Self> {
+        match priority.trim().to_lowercase().as_str() {
+            "interactive" => Ok(Self::Interactive),
+            "batch" => Ok(Self::Batch),
+            _ => UnknownPriority { priority }.fail(),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Interactive => write!(f, "interactive"),
+            Self::Batch => write!(f, "batch"),
+        }
+    }
+}
+
+/// Returns the priority of the queries of a gRPC request given by its `metadata`, if it has
+/// one
+pub fn metadata_priority(metadata: &MetadataMap) -> Option<&str> {
+    metadata
+        .get(PRIORITY_HEADER)
+        .map(|value| value.to_str().unwrap_or_default())
+}
+
+/// Returns the priority of the `/*+ batch */` or `/*+ interactive */` hint at the start of
+/// `query`, if it has one, and the query without it. Other comments are left in the query.
+fn strip_hint(query: &str) -> (Option<Priority>, &str) {
+    let trimmed = query.trim_start();
+    if !trimmed.starts_with("/*+") {
+        return (None, query);
+    }
+    let end = match trimmed.find("*/") {
+        Some(end) => end,
+        None => return (None, query),
+    };
+    match trimmed[3..end].parse() {
+        Ok(priority) => (Some(priority), trimmed[end + 2..].trim_start()),
+        Err(_) => (None, query),
+    }
+}
+
+/// How many queries run and wait at the same time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_queries: usize,
+    pub max_batch_queries: usize,
+    pub max_queued_batch_queries: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_queries: DEFAULT_MAX_QUERIES,
+            max_batch_queries: DEFAULT_MAX_BATCH_QUERIES,
+            max_queued_batch_queries: DEFAULT_MAX_QUEUED_BATCH_QUERIES,
+        }
+    }
+}
+
+/// Admits the queries of the server as described in the module documentation
+#[derive(Debug)]
+pub struct Governor {
+    limits: Limits,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    running: usize,
+    running_batch: usize,
+    /// The queries waiting for a slot, in the order they arrived
+    waiting_interactive: VecDeque<oneshot::Sender<()>>,
+    waiting_batch: VecDeque<oneshot::Sender<()>>,
+}
+
+impl Default for Governor {
+    fn default() -> Self {
+        Self::new(Limits::default())
+    }
+}
+
+impl Governor {
+    /// Admits queries up to `limits`. At least one query runs at a time, and the batch
+    /// queries never take more slots than there are.
+    pub fn new(limits: Limits) -> Self {
+        let max_queries = limits.max_queries.max(1);
+        let limits = Limits {
+            max_queries,
+            max_batch_queries: limits.max_batch_queries.min(max_queries),
+            ..limits
+        };
+        Self {
+            limits,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Waits until a query of `priority` may run, returning the permit it runs under, or an
+    /// error if it is a batch query and too many of them are waiting already
+    pub async fn admit(&self, priority: Priority) -> Result<Permit<'_>> {
+        let rx = {
+            let mut state = self.state.lock().expect("mutex poisoned");
+            if state.can_start(priority, &self.limits) {
+                state.start(priority);
+                record(priority, "admitted");
+                return Ok(Permit {
+                    governor: self,
+                    priority,
+                });
+            }
+
+            let queued = match priority {
+                Priority::Interactive => &mut state.waiting_interactive,
+                Priority::Batch => &mut state.waiting_batch,
+            };
+            if priority == Priority::Batch && queued.len() >= self.limits.max_queued_batch_queries {
+                record(priority, "rejected");
+                return Rejected {
+                    queued: queued.len(),
+                }
+                .fail();
+            }
+            let (tx, rx) = oneshot::channel();
+            queued.push_back(tx);
+            rx
+        };
+        record(priority, "queued");
+
+        let mut waiter = Waiter {
+            governor: self,
+            priority,
+            rx,
+            admitted: false,
+        };
+        (&mut waiter.rx)
+            .await
+            .expect("the governor admits the queries waiting for it");
+        waiter.admitted = true;
+
+        Ok(Permit {
+            governor: self,
+            priority,
+        })
+    }
+
+    /// Frees the slot of a query of `priority` that completed, admitting the next waiting
+    /// queries
+    fn release(&self, priority: Priority) {
+        let mut state = self.state.lock().expect("mutex poisoned");
+        state.running -= 1;
+        if priority == Priority::Batch {
+            state.running_batch -= 1;
+        }
+        state.admit_waiting(&self.limits);
+    }
+}
+
+impl State {
+    fn can_start(&self, priority: Priority, limits: &Limits) -> bool {
+        if self.running >= limits.max_queries {
+            return false;
+        }
+        match priority {
+            Priority::Interactive => self.waiting_interactive.is_empty(),
+            Priority::Batch => {
+                self.running_batch < limits.max_batch_queries
+                    && self.waiting_interactive.is_empty()
+                    && self.waiting_batch.is_empty()
+            }
+        }
+    }
+
+    fn start(&mut self, priority: Priority) {
+        self.running += 1;
+        if priority == Priority::Batch {
+            self.running_batch += 1;
+        }
+    }
+
+    /// Admits the waiting queries while there are free slots, the interactive ones first
+    fn admit_waiting(&mut self, limits: &Limits) {
+        while self.running < limits.max_queries {
+            let (tx, priority) = match self.waiting_interactive.pop_front() {
+                Some(tx) => (tx, Priority::Interactive),
+                None if self.running_batch < limits.max_batch_queries => {
+                    match self.waiting_batch.pop_front() {
+                        Some(tx) => (tx, Priority::Batch),
+                        None => break,
+                    }
+                }
+                None => break,
+            };
+
+            // the query may have stopped waiting, if its request was cancelled
+            if tx.send(()).is_ok() {
+                self.start(priority);
+            }
+        }
+    }
+}
+
+/// A query waiting for a slot. If the query stops waiting after it was admitted, its slot
+/// is freed.
+struct Waiter<'a> {
+    governor: &'a Governor,
+    priority: Priority,
+    rx: oneshot::Receiver<()>,
+    admitted: bool,
+}
+
+impl<'a> Drop for Waiter<'a> {
+    fn drop(&mut self) {
+        if !self.admitted && self.rx.try_recv().is_ok() {
+            self.governor.release(self.priority);
+        }
+    }
+}
+
+/// The slot of a running query, freed when the permit is dropped
+#[derive(Debug)]
+pub struct Permit<'a> {
+    governor: &'a Governor,
+    priority: Priority,
+}
+
+impl<'a> Permit<'a> {
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        self.governor.release(self.priority);
+    }
+}
+
+fn record(priority: Priority, outcome: &str) {
+    metrics::registry()
+        .counter(
+            "query_admissions_total",
+            "SQL queries admitted, queued or rejected by the query governor",
+            &[("priority", &priority.to_string()), ("outcome", outcome)],
+        )
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn parse_priorities() {
+        assert_eq!(
+            Priority::of(None, "SELECT 1").unwrap(),
+            (Priority::Interactive, "SELECT 1")
+        );
+        assert_eq!(
+            Priority::of(Some("Batch"), "SELECT 1").unwrap(),
+            (Priority::Batch, "SELECT 1")
+        );
+        assert_eq!(
+            Priority::of(Some("interactive"), " /*+ batch */ SELECT 1").unwrap(),
+            (Priority::Batch, "SELECT 1")
+        );
+        assert_eq!(
+            Priority::of(Some("batch"), "/*+INTERACTIVE*/SELECT 1").unwrap(),
+            (Priority::Interactive, "SELECT 1")
+        );
+
+        // other comments are left alone
+        let query = "/*+ parallel */ SELECT 1";
+        assert_eq!(
+            Priority::of(None, query).unwrap(),
+            (Priority::Interactive, query)
+        );
+        assert!(Priority::of(Some("urgent"), "SELECT 1").is_err());
+    }
+
+    #[tokio::test]
+    async fn admits_interactive_queries_first() {
+        let governor = Governor::new(Limits {
+            max_queries: 2,
+            max_batch_queries: 1,
+            max_queued_batch_queries: 1,
+        });
+
+        // the second batch query waits, as one slot is kept for interactive queries
+        let batch = governor.admit(Priority::Batch).await.unwrap();
+        let mut queued_batch = governor.admit(Priority::Batch).boxed();
+        assert!((&mut queued_batch).now_or_never().is_none());
+        let rejected = governor.admit(Priority::Batch).await.unwrap_err();
+        assert!(matches!(rejected, Error::Rejected { queued: 1 }));
+
+        let interactive = governor.admit(Priority::Interactive).await.unwrap();
+        let mut queued_interactive = governor.admit(Priority::Interactive).boxed();
+        assert!((&mut queued_interactive).now_or_never().is_none());
+
+        // the slot freed by the batch query goes to the waiting interactive query
+        drop(batch);
+        assert!((&mut queued_batch).now_or_never().is_none());
+        let second_interactive = queued_interactive.await.unwrap();
+
+        drop(interactive);
+        let second_batch = queued_batch.await.unwrap();
+        assert_eq!(second_batch.priority(), Priority::Batch);
+
+        drop(second_interactive);
+        drop(second_batch);
+        let state = governor.state.lock().unwrap();
+        assert_eq!((state.running, state.running_batch), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn cancelled_queries_free_their_slots() {
+        let governor = Governor::new(Limits {
+            max_queries: 1,
+            ..Limits::default()
+        });
+
+        let first = governor.admit(Priority::Interactive).await.unwrap();
+        let mut cancelled = governor.admit(Priority::Interactive).boxed();
+        assert!((&mut cancelled).now_or_never().is_none());
+
+        // the slot is handed to the waiting query, which is cancelled before it runs
+        drop(first);
+        drop(cancelled);
+
+        let permit = governor.admit(Priority::Interactive).now_or_never();
+        assert!(permit.unwrap().is_ok());
+    }
+}
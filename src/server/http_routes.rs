@@ -14,20 +14,47 @@
 
 #![deny(rust_2018_idioms)]
 
-use http::header::CONTENT_ENCODING;
+use http::header::{HeaderValue, CONTENT_ENCODING, RETRY_AFTER};
 use tracing::{debug, error, info};
 
 use arrow_deps::arrow;
+use data_types::histogram::HistogramLayout;
 use influxdb_line_protocol::parse_lines;
-use storage::{org_and_bucket_to_database, Database, DatabaseStore};
+use ingest::prometheus;
+use storage::{
+    access::RowAccess,
+    autocomplete::{self, TagValues, TagValuesQuery},
+    predicate::TimestampRange,
+    validate::{self, LineDiagnostic, ParsedWrite},
+    Database, DatabaseStore,
+};
 
 use bytes::{Bytes, BytesMut};
 use futures::{self, StreamExt};
 use hyper::{Body, Method, StatusCode};
 use serde::Deserialize;
-use snafu::{OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use std::str;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing_futures::Instrument;
+
+use storage::exec::Executor as StorageExecutor;
+
+pub mod v1;
+
+use super::{
+    auth::{self, Authorizer, Permission},
+    bucket_mapping::{BucketMapping, Mapping},
+    capture::{self, Capture},
+    governor::{self, Governor, Priority},
+    log_filter,
+    log_filter::LogFilter,
+    mode::Mode,
+    profiling,
+    reload::{self, Reloader},
+    trace,
+};
 
 #[derive(Debug, Snafu)]
 pub enum ApplicationError {
@@ -66,13 +93,77 @@ pub enum ApplicationError {
         source: Box<dyn std::error::Error + Send + Sync>,
     },
 
+    #[snafu(display("Internal error writing lines into database {}:  {}", database, source))]
+    WritingLines {
+        database: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[snafu(display(
+        "Database {} is throttling writes, retry after {}s: {}",
+        database,
+        retry_after.as_secs(),
+        source
+    ))]
+    WriteThrottled {
+        database: String,
+        retry_after: Duration,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[snafu(display("Database {} rejected the write: {}", database, source))]
+    WriteRejected {
+        database: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[snafu(display(
+        "Internal error validating lines for database {}:  {}",
+        database,
+        source
+    ))]
+    ValidatingLines {
+        database: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[snafu(display("Internal error creating database {}:  {}", database, source))]
+    CreatingDatabase {
+        database: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
     // Application level errors
     #[snafu(display("Bucket {} not found in org {}", bucket, org))]
     BucketNotFound { org: String, bucket: String },
 
+    #[snafu(display("database not found: {}", database))]
+    DatabaseNotFound { database: String },
+
+    #[snafu(display("database name required"))]
+    MissingDatabase,
+
+    #[snafu(display("missing required parameter \"q\""))]
+    MissingQuery,
+
+    #[snafu(display("Unsupported statement '{}': only SHOW DATABASES, SHOW MEASUREMENTS and SELECT are supported", statement))]
+    UnsupportedStatement { statement: String },
+
+    #[snafu(display(
+        "Invalid precision '{}': expected one of ns, u, ms, s, m or h",
+        precision
+    ))]
+    InvalidPrecision { precision: String },
+
+    #[snafu(display("Timestamp {} is out of range at the requested precision", timestamp))]
+    TimestampOutOfRange { timestamp: i64 },
+
     #[snafu(display("Body exceeds limit of {} bytes", max_body_size))]
     RequestSizeExceeded { max_body_size: usize },
 
+    #[snafu(display("Either org or orgID is required"))]
+    MissingOrg,
+
     #[snafu(display("Expected query string in request, but none was provided"))]
     ExpectedQueryString {},
 
@@ -113,6 +204,17 @@ pub enum ApplicationError {
         source: influxdb_line_protocol::Error,
     },
 
+    #[snafu(display("Unknown write format '{}', expected lp or prometheus", format))]
+    UnknownWriteFormat { format: String },
+
+    #[snafu(display("Invalid histogram layout: {}", source))]
+    InvalidHistogramLayout {
+        source: data_types::histogram::Error,
+    },
+
+    #[snafu(display("Error translating Prometheus metrics: {}", source))]
+    TranslatingPrometheus { source: ingest::prometheus::Error },
+
     #[snafu(display("Error decompressing body as gzip: {}", source))]
     ReadingBodyAsGzip { source: std::io::Error },
 
@@ -121,6 +223,40 @@ pub enum ApplicationError {
 
     #[snafu(display("Internal error creating gzip decoder: {:?}", source))]
     CreatingGzipDecoder { source: std::io::Error },
+
+    #[snafu(display("Error changing the log filter: {}", source))]
+    SettingLogFilter { source: log_filter::Error },
+
+    #[snafu(display("{}", source))]
+    Unauthorized { source: auth::Error },
+
+    #[snafu(display("{}", source))]
+    AdmittingQuery { source: governor::Error },
+
+    #[snafu(display("Profiling is disabled, start the server with --enable-profiling"))]
+    ProfilingDisabled,
+
+    #[snafu(display("{}", source))]
+    Profiling { source: profiling::Error },
+
+    #[snafu(display("Reloading is disabled"))]
+    ReloadingDisabled,
+
+    #[snafu(display("{} is not served by a {}", route, mode))]
+    NotServedInMode { route: String, mode: Mode },
+
+    #[snafu(display("Error reloading: {}", source))]
+    Reloading { source: reload::Error },
+
+    #[snafu(display(
+        "Error filling the gaps of the query results of {}: {}",
+        database,
+        source
+    ))]
+    FillingGaps {
+        database: String,
+        source: storage::gapfill::Error,
+    },
 }
 
 impl ApplicationError {
@@ -129,9 +265,21 @@ impl ApplicationError {
             Self::BucketByName { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             Self::WritingPoints { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Query { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::CreatingDatabase { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::ValidatingLines { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::WritingLines { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::WriteThrottled { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::WriteRejected { .. } => StatusCode::BAD_REQUEST,
             Self::QueryError { .. } => StatusCode::BAD_REQUEST,
             Self::BucketNotFound { .. } => StatusCode::NOT_FOUND,
+            Self::DatabaseNotFound { .. } => StatusCode::NOT_FOUND,
+            Self::MissingDatabase => StatusCode::BAD_REQUEST,
+            Self::MissingQuery => StatusCode::BAD_REQUEST,
+            Self::UnsupportedStatement { .. } => StatusCode::BAD_REQUEST,
+            Self::InvalidPrecision { .. } => StatusCode::BAD_REQUEST,
+            Self::TimestampOutOfRange { .. } => StatusCode::BAD_REQUEST,
             Self::RequestSizeExceeded { .. } => StatusCode::BAD_REQUEST,
+            Self::MissingOrg => StatusCode::BAD_REQUEST,
             Self::ExpectedQueryString { .. } => StatusCode::BAD_REQUEST,
             Self::InvalidQueryString { .. } => StatusCode::BAD_REQUEST,
             Self::InvalidRequestBody { .. } => StatusCode::BAD_REQUEST,
@@ -140,20 +288,94 @@ impl ApplicationError {
             Self::ReadingBody { .. } => StatusCode::BAD_REQUEST,
             Self::ReadingBodyAsUtf8 { .. } => StatusCode::BAD_REQUEST,
             Self::ParsingLineProtocol { .. } => StatusCode::BAD_REQUEST,
+            Self::UnknownWriteFormat { .. } => StatusCode::BAD_REQUEST,
+            Self::InvalidHistogramLayout { .. } => StatusCode::BAD_REQUEST,
+            Self::TranslatingPrometheus { .. } => StatusCode::BAD_REQUEST,
             Self::ReadingBodyAsGzip { .. } => StatusCode::BAD_REQUEST,
             Self::RouteNotFound { .. } => StatusCode::NOT_FOUND,
             Self::CreatingGzipDecoder { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Unauthorized { source } => match source {
+                auth::Error::MissingToken
+                | auth::Error::InvalidToken
+                | auth::Error::InvalidBearerToken { .. } => StatusCode::UNAUTHORIZED,
+                auth::Error::PermissionDenied { .. } | auth::Error::LineNotAllowed { .. } => {
+                    StatusCode::FORBIDDEN
+                }
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            Self::SettingLogFilter { source } => match source {
+                log_filter::Error::InvalidFilter { .. } => StatusCode::BAD_REQUEST,
+                log_filter::Error::ReloadingFilter { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            Self::AdmittingQuery { source } => match source {
+                governor::Error::UnknownPriority { .. } => StatusCode::BAD_REQUEST,
+                governor::Error::Rejected { .. } => StatusCode::TOO_MANY_REQUESTS,
+            },
+            Self::ProfilingDisabled => StatusCode::NOT_FOUND,
+            Self::Profiling { source } => match source {
+                profiling::Error::NotCompiled { .. } => StatusCode::NOT_IMPLEMENTED,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            Self::ReloadingDisabled => StatusCode::NOT_FOUND,
+            Self::NotServedInMode { .. } => StatusCode::NOT_IMPLEMENTED,
+            Self::Reloading { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::FillingGaps { source, .. } => match source {
+                storage::gapfill::Error::TooManyRows => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
         }
     }
 }
 
 const MAX_SIZE: usize = 10_485_760; // max write request size of 10MB
 
+/// The state shared by the handlers of the HTTP API
+#[derive(Debug)]
+pub struct State<T> {
+    /// The routes of the other modes respond with 501 Not Implemented
+    pub mode: Mode,
+    pub storage: Arc<T>,
+    pub executor: Arc<StorageExecutor>,
+    pub log_filter: LogFilter,
+    pub authorizer: Arc<Authorizer>,
+    pub buckets: Arc<BucketMapping>,
+    /// Where the writes and queries received are captured, if they are
+    pub capture: Option<Arc<Capture>>,
+    /// Admits the SQL and InfluxQL queries by their priority
+    pub governor: Arc<Governor>,
+    /// Whether the /debug/pprof routes serve profiles of the server
+    pub profiling: bool,
+    /// Reloads the settings and database rules of the server, if it can be reloaded
+    pub reloader: Option<Arc<Reloader>>,
+}
+
 #[derive(Debug, Deserialize)]
 /// Body of the request to the /write endpoint
 struct WriteInfo {
-    org: String,
+    org: Option<String>,
+    /// InfluxDB 2.0 clients may identify the org by id instead of by name
+    #[serde(rename = "orgID")]
+    org_id: Option<String>,
     bucket: String,
+    /// The format of the body, `lp` (line protocol, the default) or `prometheus` (the
+    /// Prometheus text exposition format)
+    format: Option<String>,
+    /// The layout Prometheus histograms are written in, `dense` (the default) or `sparse`
+    histograms: Option<String>,
+    /// Validates the write against the database without storing it, responding with the
+    /// problems found with each line
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Returns the org of a request, given by name or by id
+fn org_param<'a>(
+    org: &'a Option<String>,
+    org_id: &'a Option<String>,
+) -> Result<&'a str, ApplicationError> {
+    org.as_deref()
+        .or_else(|| org_id.as_deref())
+        .context(MissingOrg)
 }
 
 /// Parse the request's body into raw bytes, applying size limits and
@@ -208,59 +430,184 @@ async fn parse_body(req: hyper::Request<Body>) -> Result<Bytes, ApplicationError
     }
 }
 
-#[tracing::instrument(level = "debug")]
+#[tracing::instrument(level = "debug", skip(state))]
 async fn write<T: DatabaseStore>(
     req: hyper::Request<Body>,
-    storage: Arc<T>,
+    state: &State<T>,
 ) -> Result<Option<Body>, ApplicationError> {
+    let State {
+        storage,
+        authorizer,
+        buckets,
+        capture,
+        ..
+    } = state;
+
     let query = req.uri().query().context(ExpectedQueryString)?;
 
     let write_info: WriteInfo = serde_urlencoded::from_str(query).context(InvalidQueryString {
         query_string: String::from(query),
     })?;
+    let org = org_param(&write_info.org, &write_info.org_id)?;
 
-    let db_name = org_and_bucket_to_database(&write_info.org, &write_info.bucket);
-
-    let db = storage
-        .db_or_create(&db_name)
-        .await
-        .map_err(|e| Box::new(e) as _)
-        .context(BucketByName {
-            org: write_info.org.clone(),
-            bucket_name: write_info.bucket.clone(),
-        })?;
+    let db_name = buckets.database_name(org, &write_info.bucket);
+    let access = authorizer
+        .access(authorization_header(&req)?, Permission::Write, &db_name)
+        .context(Unauthorized)?;
 
     let body = parse_body(req).await?;
 
     let body = str::from_utf8(&body).context(ReadingBodyAsUtf8)?;
 
+    let translated;
+    let body = match write_info.format.as_deref() {
+        None | Some("lp") => body,
+        Some("prometheus") => {
+            let layout = match &write_info.histograms {
+                Some(layout) => layout.parse().context(InvalidHistogramLayout)?,
+                None => HistogramLayout::default(),
+            };
+            translated =
+                prometheus::to_line_protocol(body, layout).context(TranslatingPrometheus)?;
+            translated.as_str()
+        }
+        Some(format) => return UnknownWriteFormat { format }.fail(),
+    };
+
+    if write_info.dry_run {
+        // databases aren't created by writes that are only validated
+        let db = storage.db(&db_name).await;
+        ensure!(
+            db.is_some() || buckets.auto_create(),
+            BucketNotFound {
+                org,
+                bucket: &write_info.bucket,
+            }
+        );
+        return validate_write(&db_name, db.as_deref(), &access, body).await;
+    }
+
+    let db = if buckets.auto_create() {
+        storage
+            .db_or_create(&db_name)
+            .await
+            .map_err(|e| Box::new(e) as _)
+            .context(BucketByName {
+                org,
+                bucket_name: &write_info.bucket,
+            })?
+    } else {
+        storage.db(&db_name).await.context(BucketNotFound {
+            org,
+            bucket: &write_info.bucket,
+        })?
+    };
+
+    if let Some(capture) = capture {
+        capture.record(
+            &db_name,
+            capture::Request::Write {
+                lines: body.to_string(),
+                precision: None,
+            },
+        );
+    }
+
     let lines = parse_lines(body)
         .collect::<Result<Vec<_>, influxdb_line_protocol::Error>>()
         .context(ParsingLineProtocol)?;
+    auth::check_lines(&access, &lines).context(Unauthorized)?;
 
     debug!(
         "Inserting {} lines into database {} (org {} bucket {})",
         lines.len(),
         db_name,
-        write_info.org,
+        org,
         write_info.bucket
     );
 
     db.write_lines(&lines)
         .await
-        .map_err(|e| Box::new(e) as _)
-        .context(WritingPoints {
-            org: write_info.org.clone(),
-            bucket_name: write_info.bucket.clone(),
+        .map_err(|e| match db.write_retry_after(&e) {
+            Some(retry_after) => ApplicationError::WriteThrottled {
+                database: db_name.clone(),
+                retry_after,
+                source: Box::new(e),
+            },
+            None if db.write_rejected(&e) => ApplicationError::WriteRejected {
+                database: db_name.clone(),
+                source: Box::new(e),
+            },
+            None => ApplicationError::WritingPoints {
+                org: org.to_string(),
+                bucket_name: write_info.bucket.clone(),
+                source: Box::new(e),
+            },
         })?;
 
+    metrics::registry()
+        .counter(
+            "http_write_lines_total",
+            "Lines of line protocol written through the HTTP API",
+            &[],
+        )
+        .add(lines.len() as u64);
+
     Ok(None)
 }
 
+/// Responds with the problems found with each line of `body`, which is parsed and checked
+/// against `access` and the columns of `db`, if the database exists, without being written
+async fn validate_write<D: Database>(
+    db_name: &str,
+    db: Option<&D>,
+    access: &RowAccess,
+    body: &str,
+) -> Result<Option<Body>, ApplicationError> {
+    let mut write = ParsedWrite::parse(body);
+
+    let not_allowed: Vec<_> = write
+        .lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !access.allows_line(line))
+        .map(|(index, _)| LineDiagnostic {
+            line_number: index + 1,
+            message: "the token may not write this line".to_string(),
+        })
+        .collect();
+    write.add(not_allowed);
+
+    let found = match db {
+        Some(db) => db
+            .validate_lines(&write.lines)
+            .await
+            .map_err(|e| Box::new(e) as _)
+            .context(ValidatingLines { database: db_name })?,
+        None => validate::check_column_types(Default::default(), &write.lines),
+    };
+    write.add(found);
+
+    let lines = write.line_count();
+    let diagnostics: Vec<_> = write
+        .diagnostics()
+        .into_iter()
+        .map(|d| serde_json::json!({ "line": d.line_number, "message": d.message }))
+        .collect();
+    let json = serde_json::json!({
+        "valid": diagnostics.is_empty(),
+        "lines": lines,
+        "diagnostics": diagnostics,
+    });
+    Ok(Some(json.to_string().into()))
+}
+
 #[derive(Deserialize, Debug)]
 /// Body of the request to the /read endpoint
 struct ReadInfo {
-    org: String,
+    org: Option<String>,
+    #[serde(rename = "orgID")]
+    org_id: Option<String>,
     bucket: String,
     // TODL This is currently a "SQL" request -- should be updated to conform
     // to the V2 API for reading (using timestamps, etc).
@@ -268,26 +615,51 @@ struct ReadInfo {
 }
 
 // TODO: figure out how to stream read results out rather than rendering the whole thing in mem
-#[tracing::instrument(level = "debug")]
+#[tracing::instrument(level = "debug", skip(state))]
 async fn read<T: DatabaseStore>(
     req: hyper::Request<Body>,
-    storage: Arc<T>,
+    state: &State<T>,
 ) -> Result<Option<Body>, ApplicationError> {
+    let State {
+        storage,
+        authorizer,
+        buckets,
+        capture,
+        governor,
+        ..
+    } = state;
+
     let query = req.uri().query().context(ExpectedQueryString {})?;
 
     let read_info: ReadInfo = serde_urlencoded::from_str(query).context(InvalidQueryString {
         query_string: query,
     })?;
+    let org = org_param(&read_info.org, &read_info.org_id)?;
 
-    let db_name = org_and_bucket_to_database(&read_info.org, &read_info.bucket);
+    let db_name = buckets.database_name(org, &read_info.bucket);
+    let access = authorizer
+        .access(authorization_header(&req)?, Permission::Read, &db_name)
+        .context(Unauthorized)?;
 
     let db = storage.db(&db_name).await.context(BucketNotFound {
-        org: read_info.org.clone(),
-        bucket: read_info.bucket.clone(),
+        org,
+        bucket: &read_info.bucket,
     })?;
 
+    if let Some(capture) = capture {
+        capture.record(
+            &db_name,
+            capture::Request::Sql {
+                query: read_info.sql_query.clone(),
+            },
+        );
+    }
+
+    let (priority, sql_query) =
+        Priority::of(priority_header(&req), &read_info.sql_query).context(AdmittingQuery)?;
+    let _permit = governor.admit(priority).await.context(AdmittingQuery)?;
     let results = db
-        .query(&read_info.sql_query)
+        .query_with_access(sql_query, &access)
         .await
         .map_err(|e| Box::new(e) as _)
         .context(QueryError {})?;
@@ -296,6 +668,82 @@ async fn read<T: DatabaseStore>(
     Ok(Some(results.into_bytes().into()))
 }
 
+#[derive(Deserialize, Debug)]
+/// Parameters of the requests to the /api/v1/tag_values endpoint
+struct TagValuesInfo {
+    org: Option<String>,
+    #[serde(rename = "orgID")]
+    org_id: Option<String>,
+    bucket: String,
+    tag: String,
+    /// Only the values starting with the prefix are listed
+    #[serde(default)]
+    prefix: String,
+    /// Only the values of the measurement are listed, if set
+    measurement: Option<String>,
+    /// Only the values of the rows from `start`, inclusive, to `end`, exclusive, in
+    /// nanoseconds since the epoch are listed, if either is set
+    start: Option<i64>,
+    end: Option<i64>,
+    /// The largest number of values listed, the default if 0 or not set. Larger limits than
+    /// the maximum are lowered to it.
+    #[serde(default)]
+    limit: usize,
+}
+
+// Route to list the first values of a tag starting with a prefix, for the autocompletion of
+// tag values, without listing all the values of the tag
+#[tracing::instrument(level = "debug", skip(state))]
+async fn tag_values<T: DatabaseStore>(
+    req: hyper::Request<Body>,
+    state: &State<T>,
+) -> Result<Option<Body>, ApplicationError> {
+    let State {
+        storage,
+        executor,
+        authorizer,
+        buckets,
+        ..
+    } = state;
+
+    let query = req.uri().query().context(ExpectedQueryString {})?;
+
+    let info: TagValuesInfo = serde_urlencoded::from_str(query).context(InvalidQueryString {
+        query_string: query,
+    })?;
+    let org = org_param(&info.org, &info.org_id)?;
+
+    let db_name = buckets.database_name(org, &info.bucket);
+    let access = authorizer
+        .access(authorization_header(&req)?, Permission::Read, &db_name)
+        .context(Unauthorized)?;
+
+    let db = storage.db(&db_name).await.context(BucketNotFound {
+        org,
+        bucket: &info.bucket,
+    })?;
+
+    let range = match (info.start, info.end) {
+        (None, None) => None,
+        (start, end) => Some(TimestampRange::new(
+            start.unwrap_or(i64::MIN),
+            end.unwrap_or(i64::MAX),
+        )),
+    };
+    let query = TagValuesQuery::new(info.tag, info.prefix)
+        .table_option(info.measurement)
+        .range_option(range)
+        .limit(info.limit);
+
+    let TagValues { values, truncated } =
+        autocomplete::tag_values(db.as_ref(), executor, &query, &access)
+            .await
+            .context(QueryError {})?;
+
+    let json = serde_json::json!({ "values": values, "truncated": truncated }).to_string();
+    Ok(Some(json.into()))
+}
+
 // Route to test that the server is alive
 #[tracing::instrument(level = "debug")]
 async fn ping(req: hyper::Request<Body>) -> Result<Option<Body>, ApplicationError> {
@@ -303,30 +751,326 @@ async fn ping(req: hyper::Request<Body>) -> Result<Option<Body>, ApplicationErro
     Ok(Some(response_body.into()))
 }
 
+// Route that InfluxDB 2.0 clients such as Telegraf probe before they write
+#[tracing::instrument(level = "debug")]
+async fn ready() -> Result<Option<Body>, ApplicationError> {
+    let json = serde_json::json!({ "status": "ready" }).to_string();
+    Ok(Some(json.into()))
+}
+
+#[derive(Debug, Deserialize)]
+/// Parameters of the requests to the /api/v2/buckets and /api/v2/orgs endpoints
+struct BucketsParams {
+    org: Option<String>,
+    #[serde(rename = "orgID")]
+    org_id: Option<String>,
+    /// The name of the bucket to list
+    name: Option<String>,
+    /// The id of the bucket to list
+    id: Option<String>,
+}
+
+impl BucketsParams {
+    fn parse(req: &hyper::Request<Body>) -> Result<Self, ApplicationError> {
+        let query = req.uri().query().unwrap_or("");
+        serde_urlencoded::from_str(query).context(InvalidQueryString {
+            query_string: String::from(query),
+        })
+    }
+}
+
+/// Returns the buckets of the org named in `params`, or of all orgs, that the request may read
+/// or write. The ids of orgs and buckets are their names.
+async fn visible_buckets<T: DatabaseStore>(
+    req: &hyper::Request<Body>,
+    state: &State<T>,
+    params: &BucketsParams,
+) -> Result<Vec<Mapping>, ApplicationError> {
+    let authorization = authorization_header(req)?;
+    let org = params.org.as_ref().or_else(|| params.org_id.as_ref());
+    let bucket = params.name.as_ref().or_else(|| params.id.as_ref());
+
+    let db_names = state.storage.db_names_sorted().await;
+    Ok(state
+        .buckets
+        .buckets(&db_names)
+        .into_iter()
+        .filter(|mapping| org.map_or(true, |org| &mapping.org == org))
+        .filter(|mapping| bucket.map_or(true, |bucket| &mapping.bucket == bucket))
+        .filter(|mapping| {
+            [Permission::Read, Permission::Write]
+                .iter()
+                .any(|&permission| {
+                    state
+                        .authorizer
+                        .authorize(authorization, permission, Some(&mapping.database))
+                        .is_ok()
+                })
+        })
+        .collect())
+}
+
+// Route to list the buckets in the format of the InfluxDB 2.0 API, so that its clients can
+// find the buckets they write to
+#[tracing::instrument(level = "debug", skip(state))]
+async fn list_buckets<T: DatabaseStore>(
+    req: hyper::Request<Body>,
+    state: &State<T>,
+) -> Result<Option<Body>, ApplicationError> {
+    let params = BucketsParams::parse(&req)?;
+    let buckets: Vec<_> = visible_buckets(&req, state, &params)
+        .await?
+        .into_iter()
+        .map(|mapping| {
+            serde_json::json!({
+                "id": mapping.bucket,
+                "name": mapping.bucket,
+                "orgID": mapping.org,
+                "type": "user",
+                "retentionRules": [],
+            })
+        })
+        .collect();
+
+    let json = serde_json::json!({ "buckets": buckets }).to_string();
+    Ok(Some(json.into()))
+}
+
+// Route to look up orgs in the format of the InfluxDB 2.0 API. The orgs are those of the
+// buckets, and any org named when writes create the databases of their buckets.
+#[tracing::instrument(level = "debug", skip(state))]
+async fn list_orgs<T: DatabaseStore>(
+    req: hyper::Request<Body>,
+    state: &State<T>,
+) -> Result<Option<Body>, ApplicationError> {
+    let params = BucketsParams::parse(&req)?;
+    let mut orgs: Vec<_> = visible_buckets(&req, state, &params)
+        .await?
+        .into_iter()
+        .map(|mapping| mapping.org)
+        .collect();
+    if let (Some(org), true) = (
+        params.org.as_ref().or_else(|| params.org_id.as_ref()),
+        state.buckets.auto_create(),
+    ) {
+        orgs.push(org.clone());
+    }
+    orgs.sort_unstable();
+    orgs.dedup();
+
+    let orgs: Vec<_> = orgs
+        .into_iter()
+        .map(|org| serde_json::json!({ "id": org, "name": org }))
+        .collect();
+    let json = serde_json::json!({ "orgs": orgs }).to_string();
+    Ok(Some(json.into()))
+}
+
+// Route to expose the metrics of the server in the Prometheus text format
+#[tracing::instrument(level = "debug")]
+async fn prometheus_metrics() -> Result<Option<Body>, ApplicationError> {
+    Ok(Some(metrics::registry().encode_text().into()))
+}
+
+// Route to show the directives that currently decide which logs are emitted
+#[tracing::instrument(level = "debug")]
+async fn get_log_filter(log_filter: &LogFilter) -> Result<Option<Body>, ApplicationError> {
+    let directives = log_filter.current().context(SettingLogFilter)?;
+    Ok(Some(directives.into()))
+}
+
+// Route to replace the log directives with the ones in the body of the request, using the
+// syntax of `RUST_LOG`
+#[tracing::instrument(level = "debug")]
+async fn set_log_filter(
+    req: hyper::Request<Body>,
+    log_filter: &LogFilter,
+) -> Result<Option<Body>, ApplicationError> {
+    let body = parse_body(req).await?;
+    let directives = str::from_utf8(&body).context(ReadingBodyAsUtf8)?;
+
+    log_filter
+        .set(directives.trim())
+        .context(SettingLogFilter)?;
+    info!(directives = directives.trim(), "Changed log filter");
+    Ok(None)
+}
+
+#[derive(Debug, Deserialize)]
+/// Parameters of the request to the /debug/pprof/profile endpoint
+struct ProfileParams {
+    /// How long to sample for, in seconds
+    #[serde(default = "default_profile_seconds")]
+    seconds: u64,
+    /// How many times to sample per second
+    #[serde(default = "default_profile_frequency")]
+    frequency: i32,
+}
+
+fn default_profile_seconds() -> u64 {
+    30
+}
+
+fn default_profile_frequency() -> i32 {
+    99
+}
+
+// Route to sample a CPU profile of the server, in the format read by `go tool pprof`
+#[tracing::instrument(level = "debug")]
+async fn cpu_profile(req: hyper::Request<Body>) -> Result<Option<Body>, ApplicationError> {
+    let query = req.uri().query().unwrap_or("");
+    let params: ProfileParams = serde_urlencoded::from_str(query).context(InvalidQueryString {
+        query_string: String::from(query),
+    })?;
+
+    info!(
+        seconds = params.seconds,
+        frequency = params.frequency,
+        "Sampling CPU profile"
+    );
+    let profile = profiling::cpu_profile(Duration::from_secs(params.seconds), params.frequency)
+        .await
+        .context(Profiling)?;
+    Ok(Some(profile.into()))
+}
+
+// Route to reload the settings file and the stored rules of the databases, as on SIGHUP
+#[tracing::instrument(level = "debug")]
+async fn reload_server(reloader: &Reloader) -> Result<Option<Body>, ApplicationError> {
+    let reload = reloader.reload().await.context(Reloading)?;
+    let json = serde_json::to_string(&reload).expect("reload serializes to JSON");
+    Ok(Some(json.into()))
+}
+
+// Route to show the memory use of the heap
+#[tracing::instrument(level = "debug")]
+async fn heap_stats() -> Result<Option<Body>, ApplicationError> {
+    let stats = profiling::heap_stats().context(Profiling)?;
+    let json = serde_json::to_string(&stats).expect("heap stats serialize to JSON");
+    Ok(Some(json.into()))
+}
+
+/// Checks that `req` is allowed `permission` on `database`, or on the server if there is no
+/// database
+fn authorize(
+    req: &hyper::Request<Body>,
+    authorizer: &Authorizer,
+    permission: Permission,
+    database: Option<&str>,
+) -> Result<(), ApplicationError> {
+    authorizer
+        .authorize(authorization_header(req)?, permission, database)
+        .context(Unauthorized)
+}
+
+/// Returns the `Authorization` header of `req`, if it has one
+fn authorization_header(req: &hyper::Request<Body>) -> Result<Option<&str>, ApplicationError> {
+    match req.headers().get(auth::AUTHORIZATION_HEADER) {
+        Some(value) => Ok(Some(
+            value
+                .to_str()
+                .map_err(|_| auth::Error::InvalidToken)
+                .context(Unauthorized)?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Returns the priority of the queries of `req` given by its header, if it has one
+fn priority_header(req: &hyper::Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(governor::PRIORITY_HEADER)
+        .map(|value| value.to_str().unwrap_or_default())
+}
+
 fn no_op(name: &str) -> Result<Option<Body>, ApplicationError> {
     info!("NOOP: {}", name);
     Ok(None)
 }
 
-pub async fn service<T: DatabaseStore>(
+async fn route<T: DatabaseStore>(
     req: hyper::Request<Body>,
-    storage: Arc<T>,
-) -> http::Result<hyper::Response<Body>> {
-    let method = req.method().clone();
-    let uri = req.uri().clone();
+    state: &State<T>,
+) -> Result<Option<Body>, ApplicationError> {
+    let authorizer = &state.authorizer;
 
-    let response = match (req.method(), req.uri().path()) {
-        (&Method::POST, "/api/v2/write") => write(req, storage).await,
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/api/v2/write") | (&Method::POST, "/write") if !state.mode.routes() => {
+            not_served(&req, state.mode)
+        }
+        (&Method::GET, "/api/v2/read")
+        | (&Method::GET, "/api/v1/tag_values")
+        | (&Method::GET, "/query")
+        | (&Method::POST, "/query")
+            if !state.mode.stores() =>
+        {
+            not_served(&req, state.mode)
+        }
+        (&Method::POST, "/api/v2/write") => write(req, state).await,
         (&Method::POST, "/api/v2/buckets") => no_op("create bucket"),
+        (&Method::GET, "/api/v2/buckets") => list_buckets(req, state).await,
+        (&Method::GET, "/api/v2/orgs") => list_orgs(req, state).await,
+        (&Method::GET, "/api/v2/ready") => ready().await,
         (&Method::GET, "/ping") => ping(req).await,
-        (&Method::GET, "/api/v2/read") => read(req, storage).await,
+        (&Method::GET, "/api/v2/read") => read(req, state).await,
+        (&Method::GET, "/api/v1/tag_values") => tag_values(req, state).await,
+        (&Method::POST, "/write") => v1::write(req, state).await,
+        (&Method::GET, "/query") | (&Method::POST, "/query") => v1::query(req, state).await,
+        (&Method::GET, "/metrics") => {
+            authorize(&req, authorizer, Permission::Manage, None)?;
+            prometheus_metrics().await
+        }
+        (&Method::GET, "/api/v1/log_filter") => {
+            authorize(&req, authorizer, Permission::Manage, None)?;
+            get_log_filter(&state.log_filter).await
+        }
+        (&Method::PUT, "/api/v1/log_filter") => {
+            authorize(&req, authorizer, Permission::Manage, None)?;
+            set_log_filter(req, &state.log_filter).await
+        }
+        (&Method::POST, "/api/v1/reload") => {
+            authorize(&req, authorizer, Permission::Manage, None)?;
+            let reloader = state.reloader.as_ref().context(ReloadingDisabled)?;
+            reload_server(reloader).await
+        }
+        (&Method::GET, "/debug/pprof/profile") => {
+            authorize(&req, authorizer, Permission::Manage, None)?;
+            ensure!(state.profiling, ProfilingDisabled);
+            cpu_profile(req).await
+        }
+        (&Method::GET, "/debug/pprof/heap") => {
+            authorize(&req, authorizer, Permission::Manage, None)?;
+            ensure!(state.profiling, ProfilingDisabled);
+            heap_stats().await
+        }
         _ => Err(ApplicationError::RouteNotFound {
-            method: method.clone(),
-            path: uri.to_string(),
+            method: req.method().clone(),
+            path: req.uri().to_string(),
         }),
-    };
+    }
+}
+
+fn not_served(req: &hyper::Request<Body>, mode: Mode) -> Result<Option<Body>, ApplicationError> {
+    NotServedInMode {
+        route: req.uri().path(),
+        mode,
+    }
+    .fail()
+}
+
+pub async fn service<T: DatabaseStore>(
+    req: hyper::Request<Body>,
+    state: Arc<State<T>>,
+) -> http::Result<hyper::Response<Body>> {
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let start = Instant::now();
 
-    let result = match response {
+    let request_id = trace::request_id(req.headers());
+    let span = trace::request_span("http", &request_id, req.headers());
+    let response = route(req, &state).instrument(span.clone()).await;
+
+    let mut result = match response {
         Ok(Some(body)) => hyper::Response::builder()
             .body(body)
             .expect("Should have been able to construct a response"),
@@ -337,16 +1081,74 @@ pub async fn service<T: DatabaseStore>(
         Err(e) => {
             error!(error = ?e, method = ?method, uri = ?uri, "Error while handing request");
             let json = serde_json::json!({"error": e.to_string()}).to_string();
-            hyper::Response::builder()
-                .status(e.status_code())
+            let mut builder = hyper::Response::builder().status(e.status_code());
+            if let ApplicationError::WriteThrottled { retry_after, .. } = &e {
+                // whole seconds, as clients may not accept fractions
+                let seconds = retry_after.as_secs().max(1);
+                builder = builder.header(RETRY_AFTER, seconds);
+            }
+            builder
                 .body(json.into())
                 .expect("Should have been able to construct a response")
         }
     };
-    info!(method = ?method, uri = ?uri, status = ?result.status(), "Handled request");
+    span.in_scope(|| {
+        info!(method = ?method, uri = ?uri, status = ?result.status(), "Handled request");
+    });
+
+    // the id is either valid header text sent by the client, or hex digits
+    result.headers_mut().insert(
+        trace::REQUEST_ID_HEADER,
+        HeaderValue::from_str(&request_id).expect("request id is a valid header value"),
+    );
+
+    let route = route_label(uri.path());
+    let registry = metrics::registry();
+    registry
+        .counter(
+            "http_requests_total",
+            "HTTP requests handled",
+            &[
+                ("method", method.as_str()),
+                ("path", route),
+                ("status", result.status().as_str()),
+            ],
+        )
+        .inc();
+    registry
+        .histogram(
+            "http_request_duration_seconds",
+            "Time taken to handle HTTP requests",
+            metrics::DURATION_BUCKETS,
+            &[("method", method.as_str()), ("path", route)],
+        )
+        .observe(start.elapsed().as_secs_f64());
+
     Ok(result)
 }
 
+/// Returns the path label to record the metrics of a request under. Unknown paths share a
+/// label so that arbitrary requests can't create new metrics.
+fn route_label(path: &str) -> &'static str {
+    match path {
+        "/api/v2/write" => "/api/v2/write",
+        "/api/v2/buckets" => "/api/v2/buckets",
+        "/api/v2/orgs" => "/api/v2/orgs",
+        "/api/v2/ready" => "/api/v2/ready",
+        "/ping" => "/ping",
+        "/api/v2/read" => "/api/v2/read",
+        "/api/v1/tag_values" => "/api/v1/tag_values",
+        "/write" => "/write",
+        "/query" => "/query",
+        "/metrics" => "/metrics",
+        "/api/v1/log_filter" => "/api/v1/log_filter",
+        "/api/v1/reload" => "/api/v1/reload",
+        "/debug/pprof/profile" => "/debug/pprof/profile",
+        "/debug/pprof/heap" => "/debug/pprof/heap",
+        _ => "unknown",
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -359,6 +1161,9 @@ mod tests {
     use hyper::Server;
 
     use storage::{test::TestDatabaseStore, DatabaseStore};
+    use tracing_subscriber::{layer::SubscriberExt, reload, EnvFilter, Registry};
+
+    use crate::server::bucket_mapping::Mapping;
 
     type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
     type Result<T, E = Error> = std::result::Result<T, E>;
@@ -377,60 +1182,925 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_write() -> Result<()> {
+    async fn test_metrics() -> Result<()> {
         let test_storage = Arc::new(TestDatabaseStore::new());
         let server_url = test_server(test_storage.clone());
 
         let client = Client::new();
+        client.get(&format!("{}/ping", server_url)).send().await?;
 
-        let lp_data = "h2o_temperature,location=santa_monica,state=CA surface_degrees=65.2,bottom_degrees=50.4 1568756160";
-
-        // send write data
-        let bucket_name = "MyBucket";
-        let org_name = "MyOrg";
         let response = client
-            .post(&format!(
-                "{}/api/v2/write?bucket={}&org={}",
-                server_url, bucket_name, org_name
-            ))
-            .body(lp_data)
+            .get(&format!("{}/metrics", server_url))
             .send()
-            .await;
-
-        check_response("write", response, StatusCode::NO_CONTENT, "").await;
-
-        // Check that the data got into the right bucket
-        let test_db = test_storage
-            .db("MyOrg_MyBucket")
-            .await
-            .expect("Database exists");
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
 
-        // Ensure the same line protocol data gets through
-        assert_eq!(test_db.get_lines().await, vec![lp_data]);
+        let body = response.text().await?;
+        assert!(body.contains("# TYPE http_requests_total counter\n"));
+        assert!(body.contains(r#"http_requests_total{method="GET",path="/ping",status="200"}"#));
+        assert!(body.contains(r#"http_request_duration_seconds_count{method="GET",path="/ping"}"#));
         Ok(())
     }
 
-    fn gzip_str(s: &str) -> Vec<u8> {
-        use libflate::gzip::Encoder;
-        use std::io::Write;
+    #[tokio::test]
+    async fn test_request_id() -> Result<()> {
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        let server_url = test_server(test_storage.clone());
 
-        let mut encoder = Encoder::new(Vec::new()).expect("creating gzip encoder");
-        write!(encoder, "{}", s).expect("writing into encoder");
-        encoder
-            .finish()
-            .into_result()
-            .expect("successfully encoding gzip data")
+        let client = Client::new();
+        let response = client
+            .get(&format!("{}/ping", server_url))
+            .header(trace::REQUEST_ID_HEADER, "my-request")
+            .send()
+            .await?;
+        assert_eq!(
+            response.headers().get(trace::REQUEST_ID_HEADER).unwrap(),
+            "my-request"
+        );
+
+        // errors get an id too
+        let response = client
+            .get(&format!("{}/not_a_route", server_url))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let request_id = response.headers().get(trace::REQUEST_ID_HEADER).unwrap();
+        assert_eq!(request_id.len(), 32);
+
+        Ok(())
     }
 
     #[tokio::test]
-    async fn test_gzip_write() -> Result<()> {
+    async fn test_log_filter() -> Result<()> {
         let test_storage = Arc::new(TestDatabaseStore::new());
         let server_url = test_server(test_storage.clone());
+        let url = format!("{}/api/v1/log_filter", server_url);
 
         let client = Client::new();
-        let lp_data = "h2o_temperature,location=santa_monica,state=CA surface_degrees=65.2,bottom_degrees=50.4 1568756160";
+        let response = client.get(&url).send().await;
+        check_response("log_filter", response, StatusCode::OK, "info").await;
 
-        // send write data encoded with gzip
+        let response = client
+            .put(&url)
+            .body("warn,write_buffer=debug")
+            .send()
+            .await;
+        check_response("log_filter", response, StatusCode::NO_CONTENT, "").await;
+
+        let response = client.put(&url).body("write_buffer=loud").send().await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // the order in which directives are shown is up to the filter
+        let directives = client.get(&url).send().await?.text().await?;
+        let mut directives: Vec<_> = directives.split(',').collect();
+        directives.sort_unstable();
+        assert_eq!(directives, vec!["warn", "write_buffer=debug"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reload() -> Result<()> {
+        let client = Client::new();
+
+        let server_url = test_server(Arc::new(TestDatabaseStore::new()));
+        let response = client
+            .post(&format!("{}/api/v1/reload", server_url))
+            .send()
+            .await;
+        check_response(
+            "reload",
+            response,
+            StatusCode::NOT_FOUND,
+            r#"{"error":"Reloading is disabled"}"#,
+        )
+        .await;
+
+        let reloader = Reloader::new(
+            None,
+            Default::default(),
+            test_log_filter(),
+            Arc::new(tokio::sync::RwLock::new(cluster::Server::new(
+                crate::server::ConnectionManagerImpl::default(),
+                object_store::ObjectStore::new_in_memory(object_store::InMemory::new()),
+            ))),
+        );
+        let server_url = serve(Arc::new(State {
+            mode: Mode::All,
+            storage: Arc::new(TestDatabaseStore::new()),
+            executor: Arc::new(StorageExecutor::default()),
+            log_filter: test_log_filter(),
+            authorizer: Arc::new(Authorizer::new(true)),
+            buckets: Arc::new(BucketMapping::new(true)),
+            capture: None,
+            governor: Arc::new(Governor::default()),
+            profiling: false,
+            reloader: Some(Arc::new(reloader)),
+        }));
+        let response = client
+            .post(&format!("{}/api/v1/reload", server_url))
+            .send()
+            .await;
+        check_response(
+            "reload",
+            response,
+            StatusCode::OK,
+            r#"{"settings":null,"databases":[]}"#,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_modes() -> Result<()> {
+        let client = Client::new();
+
+        for (mode, writes, reads) in &[
+            (
+                Mode::Router,
+                StatusCode::NO_CONTENT,
+                StatusCode::NOT_IMPLEMENTED,
+            ),
+            (Mode::Database, StatusCode::NOT_IMPLEMENTED, StatusCode::OK),
+        ] {
+            let server_url = serve(Arc::new(State {
+                mode: *mode,
+                storage: Arc::new(TestDatabaseStore::new()),
+                executor: Arc::new(StorageExecutor::default()),
+                log_filter: test_log_filter(),
+                authorizer: Arc::new(Authorizer::new(true)),
+                buckets: Arc::new(BucketMapping::new(true)),
+                capture: None,
+                governor: Arc::new(Governor::default()),
+                profiling: false,
+                reloader: None,
+            }));
+
+            let response = client
+                .post(&format!("{}/write?db=telegraf", server_url))
+                .body("cpu,host=a usage=0.5 10")
+                .send()
+                .await?;
+            assert_eq!(response.status(), *writes, "write to a {}", mode);
+
+            let response = client
+                .get(&format!("{}/query", server_url))
+                .query(&[("db", "telegraf"), ("q", "SHOW DATABASES")])
+                .send()
+                .await?;
+            assert_eq!(response.status(), *reads, "query of a {}", mode);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_v2_discovery() -> Result<()> {
+        let authorizer = Arc::new(Authorizer::new(false));
+        let secret = authorizer.create_token(auth::Token {
+            id: "telegraf".to_string(),
+            description: String::new(),
+            scopes: vec![auth::Scope {
+                permission: Permission::Write,
+                database: "MyOrg_MyBucket".to_string(),
+                measurements: vec![],
+                tags: Default::default(),
+            }],
+        })?;
+        let token = format!("Token {}", secret);
+
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        for db_name in &["MyOrg_MyBucket", "MyOrg_Other", "system"] {
+            test_storage.db_or_create(db_name).await?;
+        }
+        let server_url = test_server_with(
+            test_storage.clone(),
+            authorizer,
+            Arc::new(BucketMapping::new(true)),
+        );
+
+        let client = Client::new();
+        let response = client
+            .get(&format!("{}/api/v2/ready", server_url))
+            .send()
+            .await;
+        check_response("ready", response, StatusCode::OK, r#"{"status":"ready"}"#).await;
+
+        // only the buckets the token may access are listed
+        let buckets_url = format!("{}/api/v2/buckets", server_url);
+        let response = client
+            .get(&buckets_url)
+            .header(header::AUTHORIZATION, &token)
+            .send()
+            .await;
+        check_response(
+            "buckets",
+            response,
+            StatusCode::OK,
+            r#"{"buckets":[{"id":"MyBucket","name":"MyBucket","orgID":"MyOrg","retentionRules":[],"type":"user"}]}"#,
+        )
+        .await;
+        let response = client
+            .get(&format!("{}?org=Other&name=MyBucket", buckets_url))
+            .header(header::AUTHORIZATION, &token)
+            .send()
+            .await;
+        check_response("buckets", response, StatusCode::OK, r#"{"buckets":[]}"#).await;
+
+        // writes create the buckets of any org
+        let response = client
+            .get(&format!("{}/api/v2/orgs?org=NewOrg", server_url))
+            .header(header::AUTHORIZATION, &token)
+            .send()
+            .await;
+        check_response(
+            "orgs",
+            response,
+            StatusCode::OK,
+            r#"{"orgs":[{"id":"NewOrg","name":"NewOrg"}]}"#,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auth() -> Result<()> {
+        let authorizer = Arc::new(Authorizer::new(false));
+        let secret = authorizer.create_token(auth::Token {
+            id: "writer".to_string(),
+            description: String::new(),
+            scopes: vec![auth::Scope {
+                permission: Permission::Write,
+                database: "MyOrg_MyBucket".to_string(),
+                measurements: vec![],
+                tags: Default::default(),
+            }],
+        })?;
+        let token = format!("Token {}", secret);
+
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        let server_url = test_server_with(
+            test_storage.clone(),
+            Arc::clone(&authorizer),
+            Arc::new(BucketMapping::new(true)),
+        );
+        let write_url = format!("{}/api/v2/write?bucket=MyBucket&org=MyOrg", server_url);
+        let lp_data = "h2o_temperature,location=santa_monica surface_degrees=65.2 1568756160";
+
+        let client = Client::new();
+        let response = client.post(&write_url).body(lp_data).send().await;
+        check_response(
+            "write",
+            response,
+            StatusCode::UNAUTHORIZED,
+            r#"{"error":"Authentication token is required"}"#,
+        )
+        .await;
+
+        let response = client
+            .post(&write_url)
+            .header(header::AUTHORIZATION, token.as_str())
+            .body(lp_data)
+            .send()
+            .await;
+        check_response("write", response, StatusCode::NO_CONTENT, "").await;
+
+        let response = client
+            .post(&format!(
+                "{}/api/v2/write?bucket=OtherBucket&org=MyOrg",
+                server_url
+            ))
+            .header(header::AUTHORIZATION, token.as_str())
+            .body(lp_data)
+            .send()
+            .await;
+        check_response(
+            "write",
+            response,
+            StatusCode::FORBIDDEN,
+            r#"{"error":"Token writer is not allowed to write database MyOrg_OtherBucket"}"#,
+        )
+        .await;
+
+        let response = client
+            .get(&format!("{}/metrics", server_url))
+            .header(header::AUTHORIZATION, token.as_str())
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // tokens restricted to some tag values can only write those
+        let mut tags = std::collections::BTreeMap::new();
+        tags.insert("location".to_string(), "santa_monica".to_string());
+        let secret = authorizer.create_token(auth::Token {
+            id: "santa_monica".to_string(),
+            description: String::new(),
+            scopes: vec![auth::Scope {
+                permission: Permission::Write,
+                database: "MyOrg_MyBucket".to_string(),
+                measurements: vec![],
+                tags,
+            }],
+        })?;
+        let restricted = format!("Token {}", secret);
+        let response = client
+            .post(&write_url)
+            .header(header::AUTHORIZATION, restricted.as_str())
+            .body(lp_data)
+            .send()
+            .await;
+        check_response("write", response, StatusCode::NO_CONTENT, "").await;
+
+        let response = client
+            .post(&write_url)
+            .header(header::AUTHORIZATION, restricted.as_str())
+            .body(format!(
+                "{}\nh2o_temperature,location=coyote_creek surface_degrees=50 1568756160",
+                lp_data
+            ))
+            .send()
+            .await;
+        check_response(
+            "write",
+            response,
+            StatusCode::FORBIDDEN,
+            r#"{"error":"Line 2 is outside the measurements and tag values the request is allowed to write"}"#,
+        )
+        .await;
+
+        // ping stays open for health checks
+        let response = client.get(&format!("{}/ping", server_url)).send().await;
+        check_response("ping", response, StatusCode::OK, "PONG").await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write() -> Result<()> {
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        let server_url = test_server(test_storage.clone());
+
+        let client = Client::new();
+
+        let lp_data = "h2o_temperature,location=santa_monica,state=CA surface_degrees=65.2,bottom_degrees=50.4 1568756160";
+
+        // send write data
+        let bucket_name = "MyBucket";
+        let org_name = "MyOrg";
+        let response = client
+            .post(&format!(
+                "{}/api/v2/write?bucket={}&org={}",
+                server_url, bucket_name, org_name
+            ))
+            .body(lp_data)
+            .send()
+            .await;
+
+        check_response("write", response, StatusCode::NO_CONTENT, "").await;
+
+        // Check that the data got into the right bucket
+        let test_db = test_storage
+            .db("MyOrg_MyBucket")
+            .await
+            .expect("Database exists");
+
+        // Ensure the same line protocol data gets through
+        assert_eq!(test_db.get_lines().await, vec![lp_data]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_dry_run() -> Result<()> {
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        let server_url = test_server(test_storage.clone());
+        let client = Client::new();
+        let write_url = format!(
+            "{}/api/v2/write?bucket=MyBucket&org=MyOrg&dry_run=true",
+            server_url
+        );
+
+        let response = client
+            .post(&write_url)
+            .body("cpu,host=a usage=0.5 10\ncpu,host=b usage=1i 20\nnot line protocol")
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&response.bytes().await?)?;
+        assert_eq!(body["valid"], false);
+        assert_eq!(body["lines"], 3);
+        let diagnostics = body["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0]["line"], 2);
+        assert_eq!(
+            diagnostics[0]["message"],
+            "column usage of measurement cpu has type f64, not i64"
+        );
+        assert_eq!(diagnostics[1]["line"], 3);
+
+        let response = client
+            .post(&write_url)
+            .body("cpu,host=a usage=0.5 10")
+            .send()
+            .await;
+        check_response(
+            "dry run",
+            response,
+            StatusCode::OK,
+            r#"{"diagnostics":[],"lines":1,"valid":true}"#,
+        )
+        .await;
+
+        // nothing is written, and the database isn't created
+        assert!(test_storage.db("MyOrg_MyBucket").await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_prometheus() -> Result<()> {
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        let server_url = test_server(test_storage.clone());
+        let client = Client::new();
+
+        let metrics = "# TYPE latency_seconds histogram\n\
+                       latency_seconds_bucket{path=\"/\",le=\"0.1\"} 2 1568756160000\n\
+                       latency_seconds_bucket{path=\"/\",le=\"+Inf\"} 3 1568756160000\n\
+                       latency_seconds_count{path=\"/\"} 3 1568756160000\n\
+                       up 1 1568756160000\n";
+
+        let write_url = format!(
+            "{}/api/v2/write?bucket=MyBucket&org=MyOrg&format=prometheus",
+            server_url
+        );
+        let response = client.post(&write_url).body(metrics).send().await;
+        check_response("write", response, StatusCode::NO_CONTENT, "").await;
+
+        let test_db = test_storage
+            .db("MyOrg_MyBucket")
+            .await
+            .expect("Database exists");
+        let lines = test_db.get_lines().await;
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("latency_seconds,path=/ count=3,buckets="));
+        assert!(lines[0].ends_with(" 1568756160000000000"));
+        assert_eq!(lines[1], "up value=1 1568756160000000000");
+
+        let response = client
+            .post(&format!("{}&histograms=sideways", write_url))
+            .body(metrics)
+            .send()
+            .await
+            .expect("sent write");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = client
+            .post(&write_url.replace("prometheus", "graphite"))
+            .body(metrics)
+            .send()
+            .await
+            .expect("sent write");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_throttled() -> Result<()> {
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        let test_db = test_storage.db_or_create("MyOrg_MyBucket").await?;
+        test_db
+            .set_write_retry_after(Some(Duration::from_secs(3)))
+            .await;
+        let server_url = test_server(test_storage.clone());
+
+        let client = Client::new();
+        let lp_data = "h2o_temperature,location=santa_monica surface_degrees=65.2 1568756160";
+
+        let response = client
+            .post(&format!(
+                "{}/api/v2/write?bucket=MyBucket&org=MyOrg",
+                server_url
+            ))
+            .body(lp_data)
+            .send()
+            .await
+            .expect("sent write");
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response
+                .headers()
+                .get(RETRY_AFTER)
+                .map(HeaderValue::as_bytes),
+            Some(&b"3"[..])
+        );
+        assert!(test_db.get_lines().await.is_empty());
+
+        // writes are accepted again once the database catches up
+        test_db.set_write_retry_after(None).await;
+        let response = client
+            .post(&format!(
+                "{}/api/v2/write?bucket=MyBucket&org=MyOrg",
+                server_url
+            ))
+            .body(lp_data)
+            .send()
+            .await;
+        check_response("write", response, StatusCode::NO_CONTENT, "").await;
+        assert_eq!(test_db.get_lines().await, vec![lp_data]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_profiling() -> Result<()> {
+        let client = Client::new();
+
+        let server_url = test_server(Arc::new(TestDatabaseStore::new()));
+        let response = client
+            .get(&format!("{}/debug/pprof/heap", server_url))
+            .send()
+            .await;
+        check_response(
+            "heap",
+            response,
+            StatusCode::NOT_FOUND,
+            r#"{"error":"Profiling is disabled, start the server with --enable-profiling"}"#,
+        )
+        .await;
+
+        let server_url = serve(Arc::new(State {
+            mode: Mode::All,
+            storage: Arc::new(TestDatabaseStore::new()),
+            executor: Arc::new(StorageExecutor::default()),
+            log_filter: test_log_filter(),
+            authorizer: Arc::new(Authorizer::new(true)),
+            buckets: Arc::new(BucketMapping::new(true)),
+            capture: None,
+            governor: Arc::new(Governor::default()),
+            profiling: true,
+            reloader: None,
+        }));
+        let response = client
+            .get(&format!("{}/debug/pprof/heap", server_url))
+            .send()
+            .await
+            .expect("sent request");
+        if cfg!(feature = "jemalloc") {
+            assert_eq!(response.status(), StatusCode::OK);
+            let stats: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+            assert!(stats["allocated"].as_u64().unwrap() > 0, "{}", stats);
+        } else {
+            assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+        }
+
+        let response = client
+            .get(&format!(
+                "{}/debug/pprof/profile?seconds=forever",
+                server_url
+            ))
+            .send()
+            .await
+            .expect("sent request");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_mapped_bucket() -> Result<()> {
+        let buckets = BucketMapping::new(false);
+        buckets.set(Mapping {
+            org: "MyOrg".to_string(),
+            bucket: "MyBucket".to_string(),
+            database: "mydb".to_string(),
+        })?;
+
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        test_storage.db_or_create("mydb").await?;
+        let server_url = test_server_with(
+            test_storage.clone(),
+            Arc::new(Authorizer::new(true)),
+            Arc::new(buckets),
+        );
+
+        let client = Client::new();
+        let lp_data = "h2o_temperature,location=santa_monica surface_degrees=65.2 1568756160";
+
+        // clients may give the org by id
+        let response = client
+            .post(&format!(
+                "{}/api/v2/write?bucket=MyBucket&orgID=MyOrg",
+                server_url
+            ))
+            .body(lp_data)
+            .send()
+            .await;
+        check_response("write", response, StatusCode::NO_CONTENT, "").await;
+
+        let test_db = test_storage.db("mydb").await.expect("Database exists");
+        assert_eq!(test_db.get_lines().await, vec![lp_data]);
+
+        // databases are not created for unmapped buckets
+        let response = client
+            .post(&format!(
+                "{}/api/v2/write?bucket=OtherBucket&org=MyOrg",
+                server_url
+            ))
+            .body(lp_data)
+            .send()
+            .await;
+        check_response(
+            "write",
+            response,
+            StatusCode::NOT_FOUND,
+            r#"{"error":"Bucket OtherBucket not found in org MyOrg"}"#,
+        )
+        .await;
+        assert!(test_storage.db("MyOrg_OtherBucket").await.is_none());
+
+        let response = client
+            .post(&format!("{}/api/v2/write?bucket=MyBucket", server_url))
+            .body(lp_data)
+            .send()
+            .await;
+        check_response(
+            "write",
+            response,
+            StatusCode::BAD_REQUEST,
+            r#"{"error":"Either org or orgID is required"}"#,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_v1_write() -> Result<()> {
+        let authorizer = Arc::new(Authorizer::new(false));
+        let secret = authorizer.create_token(auth::Token {
+            id: "telegraf".to_string(),
+            description: String::new(),
+            scopes: vec![auth::Scope {
+                permission: Permission::Write,
+                database: "telegraf_weekly".to_string(),
+                measurements: vec![],
+                tags: Default::default(),
+            }],
+        })?;
+
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        let server_url = test_server_with(
+            test_storage.clone(),
+            authorizer,
+            Arc::new(BucketMapping::new(true)),
+        );
+
+        let client = Client::new();
+        let response = client
+            .post(&format!(
+                "{}/write?db=telegraf&rp=weekly&precision=s&u=telegraf&p={}",
+                server_url, secret
+            ))
+            .body("cpu,host=a usage=0.5 1600000000")
+            .send()
+            .await;
+        check_response("write", response, StatusCode::NO_CONTENT, "").await;
+
+        let test_db = test_storage
+            .db("telegraf_weekly")
+            .await
+            .expect("Database exists");
+        assert_eq!(
+            test_db.get_lines().await,
+            vec!["cpu,host=a usage=0.5 1600000000000000000"]
+        );
+
+        let response = client
+            .post(&format!(
+                "{}/write?db=telegraf&precision=d&p={}",
+                server_url, secret
+            ))
+            .body("cpu,host=a usage=0.5 1600000000")
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = client
+            .post(&format!("{}/write?db=telegraf&p={}", server_url, secret))
+            .body("cpu,host=a usage=0.5 1600000000")
+            .send()
+            .await;
+        check_response(
+            "write",
+            response,
+            StatusCode::FORBIDDEN,
+            r#"{"error":"Token telegraf is not allowed to write database telegraf"}"#,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_v1_query() -> Result<()> {
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        test_storage
+            .add_lp_string("telegraf", "cpu,host=a usage=0.5 1\nmem,host=a used=1 1")
+            .await;
+        let server_url = test_server(test_storage.clone());
+        let url = format!("{}/query", server_url);
+
+        let client = Client::new();
+        let response = client
+            .get(&url)
+            .query(&[("q", "SHOW DATABASES")])
+            .send()
+            .await;
+        check_response(
+            "query",
+            response,
+            StatusCode::OK,
+            r#"{"results":[{"statement_id":0,"series":[{"name":"databases","columns":["name"],"values":[["telegraf"]]}]}]}"#,
+        )
+        .await;
+
+        // the parameters of POST requests may be sent as a form
+        let response = client
+            .post(&url)
+            .form(&[
+                ("q", "SHOW MEASUREMENTS; SHOW MEASUREMENTS ON other"),
+                ("db", "telegraf"),
+            ])
+            .send()
+            .await;
+        check_response(
+            "query",
+            response,
+            StatusCode::OK,
+            r#"{"results":[{"statement_id":0,"series":[{"name":"measurements","columns":["name"],"values":[["cpu"],["mem"]]}]},{"statement_id":1,"error":"database not found: other"}]}"#,
+        )
+        .await;
+
+        let response = client
+            .get(&url)
+            .query(&[("q", "DROP DATABASE telegraf")])
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_priorities() -> Result<()> {
+        let storage = Arc::new(TestDatabaseStore::new());
+        storage
+            .add_lp_string("MyOrg_MyBucket", "cpu,host=a usage=0.5 1")
+            .await;
+        storage
+            .add_lp_string("telegraf", "cpu,host=a usage=0.5 1")
+            .await;
+        // no batch query may run or wait
+        let server_url = serve(Arc::new(State {
+            mode: Mode::All,
+            storage,
+            executor: Arc::new(StorageExecutor::default()),
+            log_filter: test_log_filter(),
+            authorizer: Arc::new(Authorizer::new(true)),
+            buckets: Arc::new(BucketMapping::new(true)),
+            capture: None,
+            governor: Arc::new(Governor::new(governor::Limits {
+                max_queries: 1,
+                max_batch_queries: 0,
+                max_queued_batch_queries: 0,
+            })),
+            profiling: false,
+            reloader: None,
+        }));
+
+        let client = Client::new();
+        let read = |sql: &str, priority: &str| {
+            client
+                .get(&format!("{}/api/v2/read", server_url))
+                .query(&[("org", "MyOrg"), ("bucket", "MyBucket"), ("sql_query", sql)])
+                .header(governor::PRIORITY_HEADER, priority)
+                .send()
+        };
+
+        let response = read("select * from cpu", "batch").await?;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = read("/*+ batch */ select * from cpu", "interactive").await?;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = read("select * from cpu", "urgent").await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // the rejection of a statement fails the whole 1.x query
+        let response = client
+            .get(&format!("{}/query", server_url))
+            .query(&[
+                ("db", "telegraf"),
+                ("q", "SHOW MEASUREMENTS; SELECT * FROM cpu"),
+            ])
+            .header(governor::PRIORITY_HEADER, "batch")
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tag_values() -> Result<()> {
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        test_storage
+            .add_lp_string(
+                "MyOrg_MyBucket",
+                "h2o,state=MA temp=70.4 100\n\
+                 h2o,state=MN temp=72.4 250\n\
+                 o2,state=MI temp=50.4 200\n\
+                 o2,state=CA temp=79.0 300",
+            )
+            .await;
+        let server_url = test_server(test_storage.clone());
+        let url = format!("{}/api/v1/tag_values", server_url);
+
+        let client = Client::new();
+        let params = [
+            ("org", "MyOrg"),
+            ("bucket", "MyBucket"),
+            ("tag", "state"),
+            ("prefix", "M"),
+        ];
+        let response = client.get(&url).query(&params).send().await;
+        check_response(
+            "tag_values",
+            response,
+            StatusCode::OK,
+            r#"{"truncated":false,"values":["MA","MI","MN"]}"#,
+        )
+        .await;
+
+        let response = client
+            .get(&url)
+            .query(&params)
+            .query(&[("limit", "2")])
+            .send()
+            .await;
+        check_response(
+            "tag_values",
+            response,
+            StatusCode::OK,
+            r#"{"truncated":true,"values":["MA","MI"]}"#,
+        )
+        .await;
+
+        let response = client
+            .get(&url)
+            .query(&params)
+            .query(&[("measurement", "h2o"), ("start", "150")])
+            .send()
+            .await;
+        check_response(
+            "tag_values",
+            response,
+            StatusCode::OK,
+            r#"{"truncated":false,"values":["MN"]}"#,
+        )
+        .await;
+
+        let response = client
+            .get(&url)
+            .query(&[("org", "MyOrg"), ("bucket", "Other"), ("tag", "state")])
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    fn gzip_str(s: &str) -> Vec<u8> {
+        use libflate::gzip::Encoder;
+        use std::io::Write;
+
+        let mut encoder = Encoder::new(Vec::new()).expect("creating gzip encoder");
+        write!(encoder, "{}", s).expect("writing into encoder");
+        encoder
+            .finish()
+            .into_result()
+            .expect("successfully encoding gzip data")
+    }
+
+    #[tokio::test]
+    async fn test_gzip_write() -> Result<()> {
+        let test_storage = Arc::new(TestDatabaseStore::new());
+        let server_url = test_server(test_storage.clone());
+
+        let client = Client::new();
+        let lp_data = "h2o_temperature,location=santa_monica,state=CA surface_degrees=65.2,bottom_degrees=50.4 1568756160";
+
+        // send write data encoded with gzip
         let bucket_name = "MyBucket";
         let org_name = "MyOrg";
         let response = client
@@ -484,11 +2154,40 @@ mod tests {
     /// creates an instance of the http service backed by a in-memory
     /// testable database.  Returns the url of the server
     fn test_server(storage: Arc<TestDatabaseStore>) -> String {
+        test_server_with(
+            storage,
+            Arc::new(Authorizer::new(true)),
+            Arc::new(BucketMapping::new(true)),
+        )
+    }
+
+    fn test_server_with(
+        storage: Arc<TestDatabaseStore>,
+        authorizer: Arc<Authorizer>,
+        buckets: Arc<BucketMapping>,
+    ) -> String {
+        let state = Arc::new(State {
+            mode: Mode::All,
+            storage,
+            executor: Arc::new(StorageExecutor::default()),
+            log_filter: test_log_filter(),
+            authorizer,
+            buckets,
+            capture: None,
+            governor: Arc::new(Governor::default()),
+            profiling: false,
+            reloader: None,
+        });
+        serve(state)
+    }
+
+    /// Starts the http service with `state`. Returns the url of the server
+    fn serve(state: Arc<State<TestDatabaseStore>>) -> String {
         let make_svc = make_service_fn(move |_conn| {
-            let storage = storage.clone();
+            let state = state.clone();
             async move {
                 Ok::<_, http::Error>(service_fn(move |req| {
-                    let state = storage.clone();
+                    let state = state.clone();
                     super::service(req, state)
                 }))
             }
@@ -502,4 +2201,12 @@ mod tests {
         println!("Started server at {}", server_url);
         server_url
     }
+
+    /// Creates a log filter for a subscriber that is never installed; the subscriber is leaked
+    /// as the filter can only be changed while it is alive
+    fn test_log_filter() -> LogFilter {
+        let (layer, handle) = reload::Layer::new(EnvFilter::new("info"));
+        Box::leak(Box::new(Registry::default().with(layer)));
+        LogFilter::new(handle)
+    }
 }
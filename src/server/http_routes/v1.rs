@@ -0,0 +1,1101 @@
+//! This module contains the InfluxDB 1.x compatible `/write` and `/query` endpoints, so that
+//! existing 1.x integrations (such as Telegraf or Grafana) can be pointed at IOx.
+//!
+//! A 1.x database and retention policy are stored in an IOx database: database `telegraf`
+//! with the default `autogen` retention policy is stored in database `telegraf`, and with
+//! retention policy `weekly` in database `telegraf_weekly`.
+//!
+//! `/query` is read only, and understands these statements:
+//!
+//! * `SHOW DATABASES`, listing the databases the client is allowed to read
+//! * `SHOW MEASUREMENTS [ON <database>]`
+//! * `SELECT ... FROM [<database>.[<retention policy>].]<measurement> ...`, which is run as
+//!   SQL against the table of the measurement. With `GROUP BY time(...)`, the windows missing
+//!   between the first and last of the results are filled as `fill(...)` says, with nulls by
+//!   default.
+//!
+//! Clients authenticate with an `Authorization` header, or with the user name and password
+//! given in the `u` and `p` parameters. The password is the secret of a token, in which case
+//! the user name is ignored, or the password of the user in the LDAP directory, if the server
+//! has one.
+
+use std::str;
+
+use arrow_deps::arrow::{
+    array::{Array, ArrayRef, BooleanArray, Float64Array, Int64Array, StringArray, UInt64Array},
+    datatypes::DataType,
+    record_batch::RecordBatch,
+};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use hyper::{Body, Method};
+use influxdb_line_protocol::parse_lines;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use snafu::{OptionExt, ResultExt};
+use std::sync::Arc;
+use storage::{
+    access::RowAccess,
+    gapfill::{self, Fill},
+    predicate::Predicate,
+    window::Window,
+    Database, DatabaseStore,
+};
+use tracing::debug;
+
+use super::{
+    authorization_header, parse_body, priority_header, AdmittingQuery, ApplicationError,
+    CreatingDatabase, DatabaseNotFound, ExpectedQueryString, FillingGaps, InvalidPrecision,
+    InvalidQueryString, MissingDatabase, MissingQuery, ParsingLineProtocol, Query,
+    ReadingBodyAsUtf8, State, TimestampOutOfRange, Unauthorized, UnsupportedStatement,
+    WritingLines,
+};
+use crate::server::{
+    auth::{self, Permission},
+    capture,
+    governor::Priority,
+};
+
+/// The retention policy that 1.x databases are created with
+const DEFAULT_RETENTION_POLICY: &str = "autogen";
+
+/// Returns the name of the IOx database that stores retention policy `rp` of 1.x database `db`
+pub fn database_name(db: &str, rp: Option<&str>) -> String {
+    match rp {
+        None | Some("") | Some(DEFAULT_RETENTION_POLICY) => db.to_string(),
+        Some(rp) => format!("{}_{}", db, rp),
+    }
+}
+
+/// The unit of the timestamps of a write, or of the times in query results
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+impl Precision {
+    fn parse(precision: &str) -> Result<Self, ApplicationError> {
+        Ok(match precision {
+            "n" | "ns" => Self::Nanoseconds,
+            "u" | "us" | "µ" => Self::Microseconds,
+            "ms" => Self::Milliseconds,
+            "s" => Self::Seconds,
+            "m" => Self::Minutes,
+            "h" => Self::Hours,
+            _ => return InvalidPrecision { precision }.fail(),
+        })
+    }
+
+    /// The number of nanoseconds in one unit
+    fn nanos(self) -> i64 {
+        match self {
+            Self::Nanoseconds => 1,
+            Self::Microseconds => 1_000,
+            Self::Milliseconds => 1_000_000,
+            Self::Seconds => 1_000_000_000,
+            Self::Minutes => 60 * 1_000_000_000,
+            Self::Hours => 60 * 60 * 1_000_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+/// Parameters of a request to the /write endpoint
+struct WriteParams {
+    db: String,
+    rp: Option<String>,
+    precision: Option<String>,
+    u: Option<String>,
+    p: Option<String>,
+}
+
+#[tracing::instrument(level = "debug", skip(state))]
+pub(super) async fn write<T: DatabaseStore>(
+    req: hyper::Request<Body>,
+    state: &State<T>,
+) -> Result<Option<Body>, ApplicationError> {
+    let query = req.uri().query().context(ExpectedQueryString {})?;
+    let params: WriteParams = serde_urlencoded::from_str(query).context(InvalidQueryString {
+        query_string: query,
+    })?;
+    let precision = Precision::parse(params.precision.as_deref().unwrap_or("ns"))?;
+
+    let database = database_name(&params.db, params.rp.as_deref());
+    let authorization = authorization(&req, params.u.as_deref(), params.p.as_deref())?;
+    state
+        .authorizer
+        .authenticate(authorization.as_deref())
+        .await;
+    let access = state
+        .authorizer
+        .access(authorization.as_deref(), Permission::Write, &database)
+        .context(Unauthorized)?;
+
+    let db = if state.buckets.auto_create() {
+        state
+            .storage
+            .db_or_create(&database)
+            .await
+            .map_err(|e| Box::new(e) as _)
+            .context(CreatingDatabase {
+                database: &database,
+            })?
+    } else {
+        state
+            .storage
+            .db(&database)
+            .await
+            .context(DatabaseNotFound {
+                database: &database,
+            })?
+    };
+
+    let body = parse_body(req).await?;
+    let body = str::from_utf8(&body).context(ReadingBodyAsUtf8)?;
+
+    if let Some(capture) = &state.capture {
+        capture.record(
+            &database,
+            capture::Request::Write {
+                lines: body.to_string(),
+                precision: params.precision.clone(),
+            },
+        );
+    }
+
+    let mut lines = parse_lines(body)
+        .collect::<Result<Vec<_>, influxdb_line_protocol::Error>>()
+        .context(ParsingLineProtocol)?;
+    auth::check_lines(&access, &lines).context(Unauthorized)?;
+
+    if precision != Precision::Nanoseconds {
+        for line in &mut lines {
+            if let Some(timestamp) = line.timestamp {
+                let nanos = timestamp
+                    .checked_mul(precision.nanos())
+                    .context(TimestampOutOfRange { timestamp })?;
+                line.timestamp = Some(nanos);
+            }
+        }
+    }
+
+    debug!(
+        "Inserting {} lines into database {} (1.x database {})",
+        lines.len(),
+        database,
+        params.db
+    );
+
+    db.write_lines(&lines)
+        .await
+        .map_err(|e| match db.write_retry_after(&e) {
+            Some(retry_after) => ApplicationError::WriteThrottled {
+                database: database.clone(),
+                retry_after,
+                source: Box::new(e),
+            },
+            None if db.write_rejected(&e) => ApplicationError::WriteRejected {
+                database: database.clone(),
+                source: Box::new(e),
+            },
+            None => ApplicationError::WritingLines {
+                database: database.clone(),
+                source: Box::new(e),
+            },
+        })?;
+
+    metrics::registry()
+        .counter(
+            "http_write_lines_total",
+            "Lines of line protocol written through the HTTP API",
+            &[],
+        )
+        .add(lines.len() as u64);
+
+    Ok(None)
+}
+
+#[derive(Debug, Default, Deserialize)]
+/// Parameters of a request to the /query endpoint, which can be sent in the query string or,
+/// for POST requests, as a form in the body
+struct QueryParams {
+    q: Option<String>,
+    db: Option<String>,
+    rp: Option<String>,
+    epoch: Option<String>,
+    u: Option<String>,
+    p: Option<String>,
+}
+
+impl QueryParams {
+    /// Returns these parameters, with the ones that are missing taken from `other`
+    fn or(self, other: Self) -> Self {
+        Self {
+            q: self.q.or(other.q),
+            db: self.db.or(other.db),
+            rp: self.rp.or(other.rp),
+            epoch: self.epoch.or(other.epoch),
+            u: self.u.or(other.u),
+            p: self.p.or(other.p),
+        }
+    }
+}
+
+/// A statement of a query
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    ShowDatabases,
+    ShowMeasurements {
+        db: Option<String>,
+    },
+    Select {
+        db: Option<String>,
+        rp: Option<String>,
+        measurement: String,
+        /// The statement, with the measurement as the table to select from
+        sql: String,
+        /// How the missing windows of a `GROUP BY time` are filled
+        gap_fill: Option<GapFill>,
+    },
+}
+
+/// The gap filling of the results of a `GROUP BY time`
+#[derive(Debug, Clone, PartialEq)]
+struct GapFill {
+    window: Window,
+    /// The tags the results are also grouped by
+    group_columns: Vec<String>,
+    fill: Fill,
+}
+
+impl Statement {
+    fn parse(statement: &str) -> Result<Self, ApplicationError> {
+        let words = words(statement);
+        let keyword = |i: usize, keyword: &str| {
+            words
+                .get(i)
+                .map_or(false, |(_, w)| w.eq_ignore_ascii_case(keyword))
+        };
+        let unsupported = || UnsupportedStatement { statement }.fail();
+
+        if keyword(0, "SHOW") && keyword(1, "DATABASES") && words.len() == 2 {
+            Ok(Self::ShowDatabases)
+        } else if keyword(0, "SHOW") && keyword(1, "MEASUREMENTS") {
+            match words.len() {
+                2 => Ok(Self::ShowMeasurements { db: None }),
+                4 if keyword(2, "ON") => Ok(Self::ShowMeasurements {
+                    db: Some(unquote(words[3].1).to_string()),
+                }),
+                _ => unsupported(),
+            }
+        } else if keyword(0, "SELECT") {
+            let from = match words
+                .iter()
+                .position(|(_, w)| w.eq_ignore_ascii_case("FROM"))
+            {
+                Some(i) if i + 1 < words.len() => words[i + 1],
+                _ => return unsupported(),
+            };
+
+            let (db, rp, measurement) = match split_identifier(from.1)[..] {
+                [measurement] => (None, None, measurement),
+                [rp, measurement] => (None, Some(rp), measurement),
+                [db, rp, measurement] => (Some(db), Some(rp), measurement),
+                _ => return unsupported(),
+            };
+            if measurement.is_empty() {
+                return unsupported();
+            }
+
+            let (offset, target) = from;
+            let sql = format!(
+                "{}{}{}",
+                &statement[..offset],
+                measurement,
+                &statement[offset + target.len()..]
+            );
+            let (sql, gap_fill) = match group_by_time(&sql) {
+                Some(rewritten) => rewritten,
+                None => return unsupported(),
+            };
+
+            Ok(Self::Select {
+                db: db.map(ToString::to_string),
+                rp: rp.map(ToString::to_string),
+                measurement: measurement.to_string(),
+                sql,
+                gap_fill,
+            })
+        } else {
+            unsupported()
+        }
+    }
+}
+
+/// Splits a query into its statements, which are separated by semicolons outside of quotes
+fn split_statements(query: &str) -> Vec<&str> {
+    let mut statements = vec![];
+    let mut quote = None;
+    let mut start = 0;
+
+    for (i, c) in query.char_indices() {
+        match (quote, c) {
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, ';') => {
+                statements.push(&query[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    statements.push(&query[start..]);
+
+    statements
+        .into_iter()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Returns the words of `statement` that are separated by whitespace, with their byte offsets
+fn words(statement: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut start = None;
+
+    for (i, c) in statement.char_indices() {
+        match (start, c.is_whitespace()) {
+            (Some(s), true) => {
+                words.push((s, &statement[s..i]));
+                start = None;
+            }
+            (None, false) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        words.push((s, &statement[s..]));
+    }
+
+    words
+}
+
+/// Rewrites the InfluxQL `GROUP BY time(every[, offset])` clause of a SELECT, and its
+/// `fill(...)` and `tz('zone')` clauses, into SQL: the rows are grouped by the start of their
+/// window, computed by `date_bin`, which is selected as the time column, along with the tags
+/// grouped by, and ordered by. The gap filling is returned with the SQL, as it is applied to
+/// the results of the query. Statements without a `GROUP BY time` are returned as is, and
+/// `None` if the clauses are malformed.
+fn group_by_time(sql: &str) -> Option<(String, Option<GapFill>)> {
+    let words = words(sql);
+    let keyword = |i: usize, keyword: &str| {
+        words
+            .get(i)
+            .map_or(false, |(_, w)| w.eq_ignore_ascii_case(keyword))
+    };
+
+    let group_by = match (0..words.len()).find(|&i| keyword(i, "GROUP") && keyword(i + 1, "BY")) {
+        Some(i) => i,
+        None => return Some((sql.to_string(), None)),
+    };
+    let time = match words[group_by + 2..]
+        .iter()
+        .find(|(_, w)| w.len() >= 5 && w[..5].eq_ignore_ascii_case("time("))
+    {
+        Some((offset, _)) => *offset,
+        None => return Some((sql.to_string(), None)),
+    };
+    let close = time + sql[time..].find(')')?;
+    let mut args = sql[time + 5..close].split(',').map(str::trim);
+    let every = args.next().filter(|every| !every.is_empty())?;
+    let window_offset = args.next().unwrap_or("");
+    if args.next().is_some() {
+        return None;
+    }
+
+    // the time zone clause is the last one of the statement
+    let mut end = sql.trim_end().len();
+    let mut tz = "";
+    if let Some((offset, word)) = words.last() {
+        if *offset > close && word.len() >= 3 && word[..3].eq_ignore_ascii_case("tz(") {
+            let clause = sql[*offset..end].trim_end_matches(')');
+            tz = clause[3..].trim().trim_matches('\'');
+            end = *offset;
+        }
+    }
+
+    let starts_with = |word: &str, prefix: &str| {
+        word.get(..prefix.len())
+            .map_or(false, |start| start.eq_ignore_ascii_case(prefix))
+    };
+
+    // the fill clause follows the GROUP BY clause, and filling with nulls is the default
+    let mut fill = Some(Fill::Null);
+    let mut tail = sql[close + 1..end].to_string();
+    if let Some((offset, _)) = words
+        .iter()
+        .find(|(offset, w)| *offset > close && *offset < end && starts_with(w, "fill("))
+    {
+        let fill_close = offset + sql[*offset..end].find(')')?;
+        fill = match sql[offset + 5..fill_close].trim() {
+            none if none.eq_ignore_ascii_case("none") => None,
+            fill => Some(fill.parse().ok()?),
+        };
+        tail = format!(
+            "{} {}",
+            sql[close + 1..*offset].trim_end(),
+            sql[fill_close + 1..end].trim_start()
+        );
+    }
+
+    // the tags grouped by, before and after the time
+    let group_end = words
+        .iter()
+        .find(|(offset, w)| {
+            *offset > close
+                && (starts_with(w, "fill(")
+                    || ["ORDER", "LIMIT", "OFFSET", "SLIMIT", "SOFFSET"]
+                        .iter()
+                        .any(|k| w.eq_ignore_ascii_case(k)))
+        })
+        .map_or(end, |(offset, _)| *offset);
+    let (by_offset, by) = words[group_by + 1];
+    let tags = format!(
+        "{},{}",
+        &sql[by_offset + by.len()..time],
+        &sql[close + 1..group_end]
+    );
+    let tags: Vec<_> = tags
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    if tags
+        .iter()
+        .any(|tag| tag.contains(|c| c == '*' || c == '('))
+    {
+        return None;
+    }
+
+    let window = Window::parse(every, window_offset, tz).ok()?;
+    let gap_fill = fill.map(|fill| GapFill {
+        window,
+        group_columns: tags.iter().map(|tag| unquote(tag).to_string()).collect(),
+        fill,
+    });
+
+    let date_bin = format!("date_bin(time, '{}', '{}', '{}')", every, window_offset, tz);
+    let select = words.get(1)?.0;
+    let mut rewritten = format!(
+        "{}{} AS time, {}{}{}{}",
+        &sql[..select],
+        date_bin,
+        tags.iter()
+            .map(|tag| format!("{}, ", tag))
+            .collect::<String>(),
+        &sql[select..time],
+        date_bin,
+        tail.trim_end()
+    );
+
+    // windows are ordered by time, before any LIMIT or OFFSET
+    if !(0..words.len()).any(|i| keyword(i, "ORDER") && keyword(i + 1, "BY")) {
+        let words = self::words(&rewritten);
+        let limit = words
+            .iter()
+            .find(|(_, w)| {
+                ["LIMIT", "OFFSET", "SLIMIT", "SOFFSET"]
+                    .iter()
+                    .any(|k| w.eq_ignore_ascii_case(k))
+            })
+            .map(|(offset, _)| *offset);
+        match limit {
+            Some(limit) => rewritten.insert_str(limit, "ORDER BY time "),
+            None => rewritten.push_str(" ORDER BY time"),
+        }
+    }
+
+    Some((rewritten, gap_fill))
+}
+
+/// Splits an identifier such as `"telegraf"."autogen"."cpu"` into its unquoted parts
+fn split_identifier(identifier: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+
+    for (i, c) in identifier.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '.' if !quoted => {
+                parts.push(unquote(&identifier[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(unquote(&identifier[start..]));
+
+    parts
+}
+
+fn unquote(identifier: &str) -> &str {
+    identifier.trim_matches('"')
+}
+
+/// How the times of query results are rendered
+#[derive(Debug, Clone, Copy)]
+enum TimeFormat {
+    /// RFC3339 strings, such as `2020-10-01T12:00:00Z`
+    Rfc3339,
+    /// Integers, counting units of this precision since the epoch
+    Epoch(Precision),
+}
+
+impl TimeFormat {
+    fn format(self, nanos: i64) -> Value {
+        match self {
+            Self::Rfc3339 => Utc
+                .timestamp_nanos(nanos)
+                .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+                .into(),
+            Self::Epoch(precision) => (nanos / precision.nanos()).into(),
+        }
+    }
+}
+
+/// The body of a /query response
+#[derive(Debug, Serialize)]
+struct QueryResponse {
+    results: Vec<StatementResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct StatementResult {
+    statement_id: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    series: Vec<Series>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Series {
+    name: String,
+    columns: Vec<String>,
+    values: Vec<Vec<Value>>,
+}
+
+impl Series {
+    /// A series with a single `name` column, as returned by the SHOW statements
+    fn names(name: &str, names: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            name: name.to_string(),
+            columns: vec!["name".to_string()],
+            values: names.into_iter().map(|n| vec![n.into()]).collect(),
+        }
+    }
+
+    /// Converts the results of selecting from `measurement` into a series, if there are any
+    fn from_batches(
+        measurement: &str,
+        batches: &[RecordBatch],
+        time_format: TimeFormat,
+    ) -> Option<Self> {
+        let schema = batches.first()?.schema();
+        let columns = schema
+            .fields()
+            .iter()
+            .map(|field| field.name().to_string())
+            .collect::<Vec<_>>();
+        let is_time = columns
+            .iter()
+            .map(|name| name == "time")
+            .collect::<Vec<_>>();
+
+        let mut values = vec![];
+        for batch in batches {
+            for row in 0..batch.num_rows() {
+                values.push(
+                    batch
+                        .columns()
+                        .iter()
+                        .zip(&is_time)
+                        .map(|(column, &is_time)| json_value(column, row, is_time, time_format))
+                        .collect(),
+                );
+            }
+        }
+
+        Some(Self {
+            name: measurement.to_string(),
+            columns,
+            values,
+        })
+    }
+}
+
+/// Returns the value in `row` of `column`. Times are integer nanoseconds, rendered in
+/// `time_format`.
+fn json_value(column: &ArrayRef, row: usize, is_time: bool, time_format: TimeFormat) -> Value {
+    if column.is_null(row) {
+        return Value::Null;
+    }
+
+    let any = column.as_any();
+    match column.data_type() {
+        DataType::Int64 => {
+            let value = any.downcast_ref::<Int64Array>().unwrap().value(row);
+            if is_time {
+                time_format.format(value)
+            } else {
+                value.into()
+            }
+        }
+        DataType::UInt64 => any.downcast_ref::<UInt64Array>().unwrap().value(row).into(),
+        DataType::Float64 => any
+            .downcast_ref::<Float64Array>()
+            .unwrap()
+            .value(row)
+            .into(),
+        DataType::Boolean => any
+            .downcast_ref::<BooleanArray>()
+            .unwrap()
+            .value(row)
+            .into(),
+        DataType::Utf8 => any.downcast_ref::<StringArray>().unwrap().value(row).into(),
+        // IOx tables only have the types above
+        _ => Value::Null,
+    }
+}
+
+#[tracing::instrument(level = "debug", skip(state))]
+pub(super) async fn query<T: DatabaseStore>(
+    req: hyper::Request<Body>,
+    state: &State<T>,
+) -> Result<Option<Body>, ApplicationError> {
+    let header = authorization_header(&req)?.map(ToString::to_string);
+    // InfluxQL has no hints, the priority of its queries is only given by the header
+    let priority = match priority_header(&req) {
+        Some(priority) => priority.parse().context(AdmittingQuery)?,
+        None => Priority::default(),
+    };
+
+    let mut params: QueryParams = match req.uri().query() {
+        Some(query) => serde_urlencoded::from_str(query).context(InvalidQueryString {
+            query_string: query,
+        })?,
+        None => QueryParams::default(),
+    };
+    if *req.method() == Method::POST {
+        let body = parse_body(req).await?;
+        let form: QueryParams =
+            serde_urlencoded::from_bytes(&body).context(InvalidQueryString {
+                query_string: String::from_utf8_lossy(&body),
+            })?;
+        params = params.or(form);
+    }
+
+    let time_format = match params.epoch.as_deref() {
+        Some(epoch) => TimeFormat::Epoch(Precision::parse(epoch)?),
+        None => TimeFormat::Rfc3339,
+    };
+    let authorization = header.or_else(|| credentials(params.u.as_deref(), params.p.as_deref()));
+    state
+        .authorizer
+        .authenticate(authorization.as_deref())
+        .await;
+
+    let q = params.q.as_deref().context(MissingQuery)?;
+    if let Some(capture) = &state.capture {
+        let database = params
+            .db
+            .as_deref()
+            .map(|db| database_name(db, params.rp.as_deref()))
+            .unwrap_or_default();
+        capture.record(
+            &database,
+            capture::Request::InfluxQl {
+                query: q.to_string(),
+            },
+        );
+    }
+    let statements = split_statements(q)
+        .into_iter()
+        .map(Statement::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut results = Vec::with_capacity(statements.len());
+    for (statement_id, statement) in statements.into_iter().enumerate() {
+        let result = execute(
+            statement,
+            &params,
+            authorization.as_deref(),
+            time_format,
+            priority,
+            state,
+        )
+        .await;
+
+        // 1.x reports errors of each statement in its result, but authorization and
+        // admission errors fail the whole request
+        let (series, error) = match result {
+            Ok(series) => (series, None),
+            Err(e @ ApplicationError::Unauthorized { .. })
+            | Err(e @ ApplicationError::AdmittingQuery { .. }) => return Err(e),
+            Err(e) => (vec![], Some(e.to_string())),
+        };
+        results.push(StatementResult {
+            statement_id,
+            series,
+            error,
+        });
+    }
+
+    let json = serde_json::to_string(&QueryResponse { results })
+        .expect("query results can be serialized to JSON");
+    Ok(Some(json.into()))
+}
+
+async fn execute<T: DatabaseStore>(
+    statement: Statement,
+    params: &QueryParams,
+    authorization: Option<&str>,
+    time_format: TimeFormat,
+    priority: Priority,
+    state: &State<T>,
+) -> Result<Vec<Series>, ApplicationError> {
+    match statement {
+        Statement::ShowDatabases => {
+            let names = state
+                .storage
+                .db_names_sorted()
+                .await
+                .into_iter()
+                .filter(|name| {
+                    state
+                        .authorizer
+                        .authorize(authorization, Permission::Read, Some(name))
+                        .is_ok()
+                });
+            Ok(vec![Series::names("databases", names)])
+        }
+        Statement::ShowMeasurements { db } => {
+            let (database, db, access) =
+                open_database(db, None, params, authorization, state).await?;
+
+            let plan = db
+                .table_names(access.restrict(Predicate::default()))
+                .await
+                .map_err(|e| Box::new(e) as _)
+                .context(Query {
+                    database: &database,
+                })?;
+            let names = state
+                .executor
+                .to_string_set(plan)
+                .await
+                .map_err(|e| Box::new(e) as _)
+                .context(Query {
+                    database: &database,
+                })?;
+
+            Ok(vec![Series::names("measurements", names.iter().cloned())])
+        }
+        Statement::Select {
+            db,
+            rp,
+            measurement,
+            sql,
+            gap_fill,
+        } => {
+            let (database, db, access) =
+                open_database(db, rp, params, authorization, state).await?;
+
+            let _permit = state
+                .governor
+                .admit(priority)
+                .await
+                .context(AdmittingQuery)?;
+            let batches = db
+                .query_with_access(&sql, &access)
+                .await
+                .map_err(|e| Box::new(e) as _)
+                .context(Query {
+                    database: &database,
+                })?;
+            let batches = match gap_fill {
+                Some(GapFill {
+                    window,
+                    group_columns,
+                    fill,
+                }) => gapfill::fill_gaps(&batches, "time", &group_columns, &window, None, fill)
+                    .context(FillingGaps {
+                        database: &database,
+                    })?,
+                None => batches,
+            };
+
+            Ok(Series::from_batches(&measurement, &batches, time_format)
+                .into_iter()
+                .collect())
+        }
+    }
+}
+
+/// Returns the database a statement reads from, which is the one given by the statement or
+/// else by the parameters of the request
+async fn open_database<T: DatabaseStore>(
+    db: Option<String>,
+    rp: Option<String>,
+    params: &QueryParams,
+    authorization: Option<&str>,
+    state: &State<T>,
+) -> Result<(String, Arc<T::Database>, RowAccess), ApplicationError> {
+    let db = db.or_else(|| params.db.clone()).context(MissingDatabase)?;
+    let rp = rp.or_else(|| params.rp.clone());
+    let database = database_name(&db, rp.as_deref());
+
+    let access = state
+        .authorizer
+        .access(authorization, Permission::Read, &database)
+        .context(Unauthorized)?;
+
+    let db = state
+        .storage
+        .db(&database)
+        .await
+        .context(DatabaseNotFound {
+            database: &database,
+        })?;
+    Ok((database, db, access))
+}
+
+/// Returns the authorization of a request: its `Authorization` header, or else the user name
+/// and password it was sent with
+fn authorization(
+    req: &hyper::Request<Body>,
+    user: Option<&str>,
+    password: Option<&str>,
+) -> Result<Option<String>, ApplicationError> {
+    Ok(match authorization_header(req)? {
+        Some(header) => Some(header.to_string()),
+        None => credentials(user, password),
+    })
+}
+
+/// Returns the `Authorization` header equivalent to the user name and password parameters of
+/// a request
+fn credentials(user: Option<&str>, password: Option<&str>) -> Option<String> {
+    let password = password?;
+    Some(match user {
+        Some(user) => format!("Basic {}", base64::encode(format!("{}:{}", user, password))),
+        None => format!("Token {}", password),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_deps::arrow::datatypes::{Field, Schema};
+
+    #[test]
+    fn database_names() {
+        assert_eq!(database_name("telegraf", None), "telegraf");
+        assert_eq!(database_name("telegraf", Some("")), "telegraf");
+        assert_eq!(database_name("telegraf", Some("autogen")), "telegraf");
+        assert_eq!(database_name("telegraf", Some("weekly")), "telegraf_weekly");
+    }
+
+    #[test]
+    fn precision() {
+        assert_eq!(Precision::parse("ns").unwrap(), Precision::Nanoseconds);
+        assert_eq!(Precision::parse("u").unwrap().nanos(), 1_000);
+        assert_eq!(Precision::parse("ms").unwrap().nanos(), 1_000_000);
+        assert_eq!(Precision::parse("h").unwrap().nanos(), 3_600_000_000_000);
+        assert!(matches!(
+            Precision::parse("d"),
+            Err(ApplicationError::InvalidPrecision { .. })
+        ));
+    }
+
+    #[test]
+    fn statements() {
+        assert_eq!(
+            split_statements("SHOW DATABASES; SELECT * FROM \"a;b\";;"),
+            vec!["SHOW DATABASES", "SELECT * FROM \"a;b\""]
+        );
+
+        assert_eq!(
+            Statement::parse("show databases").unwrap(),
+            Statement::ShowDatabases
+        );
+        assert_eq!(
+            Statement::parse("SHOW MEASUREMENTS").unwrap(),
+            Statement::ShowMeasurements { db: None }
+        );
+        assert_eq!(
+            Statement::parse("SHOW MEASUREMENTS ON \"telegraf\"").unwrap(),
+            Statement::ShowMeasurements {
+                db: Some("telegraf".to_string())
+            }
+        );
+        assert_eq!(
+            Statement::parse("SELECT usage FROM cpu WHERE host = 'a'").unwrap(),
+            Statement::Select {
+                db: None,
+                rp: None,
+                measurement: "cpu".to_string(),
+                sql: "SELECT usage FROM cpu WHERE host = 'a'".to_string(),
+                gap_fill: None,
+            }
+        );
+        assert_eq!(
+            Statement::parse("SELECT cpu FROM \"telegraf\".\"weekly\".\"cpu\" LIMIT 1").unwrap(),
+            Statement::Select {
+                db: Some("telegraf".to_string()),
+                rp: Some("weekly".to_string()),
+                measurement: "cpu".to_string(),
+                sql: "SELECT cpu FROM cpu LIMIT 1".to_string(),
+                gap_fill: None,
+            }
+        );
+        assert_eq!(
+            Statement::parse("SELECT * FROM telegraf..cpu").unwrap(),
+            Statement::Select {
+                db: Some("telegraf".to_string()),
+                rp: Some("".to_string()),
+                measurement: "cpu".to_string(),
+                sql: "SELECT * FROM cpu".to_string(),
+                gap_fill: None,
+            }
+        );
+
+        assert_eq!(
+            Statement::parse(
+                "SELECT avg(usage) FROM cpu WHERE host = 'a' GROUP BY time(1d, 8h) tz('America/New_York')"
+            )
+            .unwrap(),
+            Statement::Select {
+                db: None,
+                rp: None,
+                measurement: "cpu".to_string(),
+                sql: "SELECT date_bin(time, '1d', '8h', 'America/New_York') AS time, avg(usage) \
+                      FROM cpu WHERE host = 'a' \
+                      GROUP BY date_bin(time, '1d', '8h', 'America/New_York') ORDER BY time"
+                    .to_string(),
+                gap_fill: Some(GapFill {
+                    window: Window::parse("1d", "8h", "America/New_York").unwrap(),
+                    group_columns: vec![],
+                    fill: Fill::Null,
+                }),
+            }
+        );
+        assert_eq!(
+            Statement::parse(
+                "SELECT max(usage) FROM cpu GROUP BY host, time(1h) fill(previous) LIMIT 2"
+            )
+            .unwrap(),
+            Statement::Select {
+                db: None,
+                rp: None,
+                measurement: "cpu".to_string(),
+                sql: "SELECT date_bin(time, '1h', '', '') AS time, host, max(usage) FROM cpu \
+                      GROUP BY host, date_bin(time, '1h', '', '') ORDER BY time LIMIT 2"
+                    .to_string(),
+                gap_fill: Some(GapFill {
+                    window: Window::parse("1h", "", "").unwrap(),
+                    group_columns: vec!["host".to_string()],
+                    fill: Fill::Previous,
+                }),
+            }
+        );
+        assert_eq!(
+            Statement::parse(
+                "SELECT mean(usage) FROM cpu GROUP BY time(1m), \"region\" fill(none)"
+            )
+            .unwrap(),
+            Statement::Select {
+                db: None,
+                rp: None,
+                measurement: "cpu".to_string(),
+                sql: "SELECT date_bin(time, '1m', '', '') AS time, \"region\", mean(usage) \
+                      FROM cpu GROUP BY date_bin(time, '1m', '', ''), \"region\" ORDER BY time"
+                    .to_string(),
+                gap_fill: None,
+            }
+        );
+
+        for unsupported in &[
+            "SELECT max(usage) FROM cpu GROUP BY time()",
+            "SELECT max(usage) FROM cpu GROUP BY time(1h",
+            "SELECT max(usage) FROM cpu GROUP BY time(1h, 1m, 1s)",
+            "SELECT max(usage) FROM cpu GROUP BY time(0s)",
+            "SELECT max(usage) FROM cpu GROUP BY time(1h) tz('Mars/Olympus_Mons')",
+            "SELECT max(usage) FROM cpu GROUP BY time(1h) fill(sideways)",
+            "SELECT max(usage) FROM cpu GROUP BY *, time(1h)",
+            "DROP DATABASE telegraf",
+            "SHOW DATABASES extra",
+            "SHOW MEASUREMENTS telegraf",
+            "SELECT 1",
+            "SELECT * FROM a.b.c.d",
+        ] {
+            assert!(
+                matches!(
+                    Statement::parse(unsupported),
+                    Err(ApplicationError::UnsupportedStatement { .. })
+                ),
+                "{}",
+                unsupported
+            );
+        }
+    }
+
+    #[test]
+    fn series_from_batches() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("host", DataType::Utf8, true),
+            Field::new("usage", DataType::Float64, true),
+            Field::new("time", DataType::Int64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec![Some("a"), None])),
+                Arc::new(Float64Array::from(vec![0.5, 1.0])),
+                Arc::new(Int64Array::from(vec![
+                    1_600_000_000_000_000_000,
+                    1_600_000_000_500_000_000,
+                ])),
+            ],
+        )
+        .unwrap();
+
+        let series = Series::from_batches("cpu", &[batch.clone()], TimeFormat::Rfc3339).unwrap();
+        assert_eq!(
+            serde_json::to_value(&series).unwrap(),
+            serde_json::json!({
+                "name": "cpu",
+                "columns": ["host", "usage", "time"],
+                "values": [
+                    ["a", 0.5, "2020-09-13T12:26:40Z"],
+                    [null, 1.0, "2020-09-13T12:26:40.500Z"],
+                ]
+            })
+        );
+
+        let series =
+            Series::from_batches("cpu", &[batch], TimeFormat::Epoch(Precision::Milliseconds))
+                .unwrap();
+        assert_eq!(
+            series.values[1],
+            vec![Value::Null, 1.0.into(), 1_600_000_000_500i64.into()]
+        );
+
+        assert_eq!(Series::from_batches("cpu", &[], TimeFormat::Rfc3339), None);
+    }
+}
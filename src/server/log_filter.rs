@@ -0,0 +1,72 @@
+//! This module contains the handle used to change which logs (and spans) the server emits
+//! while it is running, using the same directives as the `RUST_LOG` environment variable.
+
+use snafu::{ResultExt, Snafu};
+use tracing_subscriber::{filter::ParseError, reload, EnvFilter, Registry};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid log filter '{}': {}", directives, source))]
+    InvalidFilter {
+        directives: String,
+        source: ParseError,
+    },
+
+    #[snafu(display("Unable to access the log filter: {}", source))]
+    ReloadingFilter { source: reload::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A handle to the filter of the installed log subscriber
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl std::fmt::Debug for LogFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LogFilter").finish()
+    }
+}
+
+impl LogFilter {
+    pub fn new(handle: reload::Handle<EnvFilter, Registry>) -> Self {
+        Self { handle }
+    }
+
+    /// Returns the directives of the filter currently in use
+    pub fn current(&self) -> Result<String> {
+        self.handle
+            .with_current(ToString::to_string)
+            .context(ReloadingFilter)
+    }
+
+    /// Replaces the filter with one using `directives`, such as `info,write_buffer=debug`
+    pub fn set(&self, directives: &str) -> Result<()> {
+        let filter = EnvFilter::try_new(directives).context(InvalidFilter { directives })?;
+        self.handle.reload(filter).context(ReloadingFilter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn set_filter() {
+        let (layer, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let _subscriber = Registry::default().with(layer);
+        let log_filter = LogFilter::new(handle);
+
+        assert_eq!(log_filter.current().unwrap(), "info");
+
+        log_filter.set("debug").unwrap();
+        assert_eq!(log_filter.current().unwrap(), "debug");
+
+        let err = log_filter.set("write_buffer=loud").unwrap_err();
+        assert!(matches!(err, Error::InvalidFilter { .. }));
+        assert_eq!(log_filter.current().unwrap(), "debug");
+    }
+}
@@ -0,0 +1,92 @@
+//! This module contains the modes a server runs in, so that the routing of writes and the
+//! storage of the databases can be scaled apart:
+//!
+//! * `router`: accepts writes from clients and routes them according to the rules of their
+//!   database, without storing data or answering queries.
+//! * `database`: stores the data of the databases, takes the entries routers replicate to it
+//!   and answers queries, without accepting writes from clients.
+//! * `all`: both, the default.
+//!
+//! The APIs a mode doesn't serve respond with UNIMPLEMENTED, or 501 Not Implemented over HTTP.
+//! The management, operations, health and reflection APIs are served in every mode.
+
+use std::{fmt, str::FromStr};
+
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unknown mode {}, expected router, database or all", mode))]
+    UnknownMode { mode: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What a server runs as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Router,
+    Database,
+    All,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Self::All
+    }
+}
+
+impl Mode {
+    /// Whether the server accepts writes from clients, over the HTTP write endpoints, the
+    /// OpenTelemetry metrics service and the MQTT, UDP and Unix socket listeners
+    pub fn routes(self) -> bool {
+        matches!(self, Self::Router | Self::All)
+    }
+
+    /// Whether the server stores data and answers queries, over the HTTP read and query
+    /// endpoints, the storage, query and Flight services and the PostgreSQL wire protocol
+    pub fn stores(self) -> bool {
+        matches!(self, Self::Database | Self::All)
+    }
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode.to_lowercase().as_str() {
+            "router" => Ok(Self::Router),
+            "database" => Ok(Self::Database),
+            "all" => Ok(Self::All),
+            _ => UnknownMode { mode }.fail(),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Router => write!(f, "router"),
+            Self::Database => write!(f, "database"),
+            Self::All => write!(f, "all"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_modes() {
+        for mode in &[Mode::Router, Mode::Database, Mode::All] {
+            assert_eq!(mode.to_string().parse::<Mode>().unwrap(), *mode);
+        }
+        assert_eq!("Router".parse::<Mode>().unwrap(), Mode::Router);
+        assert!("ingester".parse::<Mode>().is_err());
+
+        assert!(Mode::Router.routes() && !Mode::Router.stores());
+        assert!(!Mode::Database.routes() && Mode::Database.stores());
+        assert!(Mode::All.routes() && Mode::All.stores());
+    }
+}
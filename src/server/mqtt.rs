@@ -0,0 +1,600 @@
+//! This module subscribes to topics of an MQTT broker and writes the messages published to
+//! them to a database, for IoT gateways and devices that publish their readings over MQTT
+//! rather than writing to the HTTP API.
+//!
+//! The listener is opt-in, with `--mqtt-broker`. It speaks enough of MQTT 3.1.1 to receive
+//! messages: it subscribes to its topics with QoS 1, acknowledges each message once it has
+//! been written and reconnects when the connection to the broker drops. Messages are either
+//! line protocol or, with `--mqtt-json-mapping`, JSON documents translated into line protocol
+//! by an `ingest::json::JsonMapping`. Messages that can't be written are logged and dropped.
+
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+
+use cluster::{ConnectionManager, Server as AppServer};
+use futures::future::{abortable, AbortHandle};
+use influxdb_line_protocol::parse_lines;
+use ingest::json::JsonMapping;
+use snafu::{ensure, ResultExt, Snafu};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, RwLock},
+    time::Instant,
+};
+use tracing::{debug, info, warn};
+
+/// How long to wait before reconnecting to the broker after the connection dropped
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How often the broker expects to hear from the listener
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error connecting to MQTT broker {}: {}", broker, source))]
+    Connecting {
+        broker: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error communicating with MQTT broker: {}", source))]
+    Communicating { source: std::io::Error },
+
+    #[snafu(display("Malformed MQTT packet: {}", reason))]
+    MalformedPacket { reason: String },
+
+    #[snafu(display("MQTT broker refused the connection with return code {}", code))]
+    ConnectionRefused { code: u8 },
+
+    #[snafu(display("MQTT broker refused the subscription to {}", topic))]
+    SubscriptionRefused { topic: String },
+
+    #[snafu(display("MQTT broker closed the connection"))]
+    ConnectionClosed,
+
+    #[snafu(display("Message is not valid UTF-8 line protocol: {}", source))]
+    InvalidUtf8 { source: std::string::FromUtf8Error },
+
+    #[snafu(display("Error translating JSON message: {}", source))]
+    TranslatingJson { source: ingest::json::Error },
+
+    #[snafu(display("Error parsing line protocol: {}", source))]
+    ParsingLines {
+        source: influxdb_line_protocol::Error,
+    },
+
+    #[snafu(display("Error writing message: {}", source))]
+    WritingMessage { source: cluster::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The options of the MQTT listener given on the command line
+#[derive(Clone, Default)]
+pub struct MqttConfig {
+    /// The host:port of the broker
+    pub broker: String,
+    /// The topic filters to subscribe to, which may contain the + and # wildcards
+    pub topics: Vec<String>,
+    /// The database the messages are written to
+    pub database: String,
+    /// The client identifier the listener connects with
+    pub client_id: String,
+    /// The user name to connect with, if the broker requires one
+    pub username: Option<String>,
+    /// The password to connect with
+    pub password: Option<String>,
+}
+
+impl fmt::Debug for MqttConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttConfig")
+            .field("broker", &self.broker)
+            .field("topics", &self.topics)
+            .field("database", &self.database)
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// What the messages published to the topics contain
+#[derive(Debug, Clone)]
+pub enum Payload {
+    LineProtocol,
+    /// JSON documents, translated into line protocol by the mapping. The topic of each
+    /// message is its source
+    Json(JsonMapping),
+}
+
+/// A packet received from the broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Packet {
+    ConnAck {
+        return_code: u8,
+    },
+    SubAck {
+        packet_id: u16,
+        return_codes: Vec<u8>,
+    },
+    Publish {
+        topic: String,
+        /// Set for messages published with QoS 1 or 2, which have to be acknowledged
+        packet_id: Option<u16>,
+        payload: Vec<u8>,
+    },
+    PingResp,
+    /// A packet the listener does not act on, by its type
+    Other(u8),
+}
+
+/// Subscribes to the topics of `config` and writes the messages published to them to its
+/// database, reconnecting whenever the connection drops, until `shutdown` resolves
+pub async fn run<M>(
+    config: MqttConfig,
+    payload: Payload,
+    app_server: Arc<RwLock<AppServer<M>>>,
+    shutdown: impl Future<Output = ()>,
+) where
+    M: ConnectionManager + fmt::Debug + Send + Sync + 'static,
+{
+    let listening = async {
+        loop {
+            if let Err(e) = listen(&config, &payload, &app_server).await {
+                warn!("MQTT listener disconnected from {}: {}", config.broker, e);
+            }
+            tokio::time::delay_for(RECONNECT_DELAY).await;
+        }
+    };
+
+    tokio::select! {
+        _ = listening => {},
+        _ = shutdown => info!("MQTT listener stopped"),
+    }
+}
+
+/// Connects to the broker and handles the messages it sends, until the connection drops
+async fn listen<M>(
+    config: &MqttConfig,
+    payload: &Payload,
+    app_server: &RwLock<AppServer<M>>,
+) -> Result<()>
+where
+    M: ConnectionManager + fmt::Debug + Send + Sync + 'static,
+{
+    let stream = TcpStream::connect(&config.broker)
+        .await
+        .context(Connecting {
+            broker: &config.broker,
+        })?;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    writer
+        .write_all(&connect_packet(config))
+        .await
+        .context(Communicating)?;
+    match read_packet(&mut reader).await? {
+        Packet::ConnAck { return_code: 0 } => {}
+        Packet::ConnAck { return_code } => return ConnectionRefused { code: return_code }.fail(),
+        packet => {
+            return MalformedPacket {
+                reason: format!("expected CONNACK, got {:?}", packet),
+            }
+            .fail()
+        }
+    }
+
+    writer
+        .write_all(&subscribe_packet(1, &config.topics))
+        .await
+        .context(Communicating)?;
+
+    // Packets are read on their own task, for the listener to send pings while it waits. The
+    // task is aborted when the listener returns, closing the connection
+    let (mut sender, mut packets) = mpsc::channel(16);
+    let (reading, abort) = abortable(async move {
+        loop {
+            let packet = read_packet(&mut reader).await;
+            let failed = packet.is_err();
+            if sender.send(packet).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    tokio::spawn(reading);
+    let _abort = AbortOnDrop(abort);
+
+    let mut ping = tokio::time::interval_at(Instant::now() + KEEP_ALIVE / 2, KEEP_ALIVE / 2);
+    loop {
+        let packet = tokio::select! {
+            packet = packets.recv() => match packet {
+                Some(packet) => packet?,
+                None => return ConnectionClosed.fail(),
+            },
+            _ = ping.tick() => {
+                writer.write_all(&[PINGREQ, 0]).await.context(Communicating)?;
+                continue;
+            }
+        };
+
+        match packet {
+            Packet::SubAck { return_codes, .. } => {
+                for (topic, code) in config.topics.iter().zip(return_codes) {
+                    ensure!(code != 0x80, SubscriptionRefused { topic });
+                }
+                info!(
+                    "MQTT listener subscribed to {:?} on {}",
+                    config.topics, config.broker
+                );
+            }
+            Packet::Publish {
+                topic,
+                packet_id,
+                payload: message,
+            } => {
+                match write_message(config, payload, app_server, &topic, message).await {
+                    Ok(lines) => debug!("wrote {} lines published to {}", lines, topic),
+                    Err(e) => warn!("dropping message published to {}: {}", topic, e),
+                }
+                if let Some(packet_id) = packet_id {
+                    writer
+                        .write_all(&puback_packet(packet_id))
+                        .await
+                        .context(Communicating)?;
+                }
+            }
+            Packet::ConnAck { .. } | Packet::PingResp | Packet::Other(_) => {}
+        }
+    }
+}
+
+/// Aborts the task reading packets from the broker when dropped
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Writes the lines of a message published to `topic` to the database, returning how many
+/// there were
+async fn write_message<M>(
+    config: &MqttConfig,
+    payload: &Payload,
+    app_server: &RwLock<AppServer<M>>,
+    topic: &str,
+    message: Vec<u8>,
+) -> Result<usize>
+where
+    M: ConnectionManager + fmt::Debug + Send + Sync + 'static,
+{
+    let lp = match payload {
+        Payload::LineProtocol => String::from_utf8(message).context(InvalidUtf8)?,
+        Payload::Json(mapping) => mapping
+            .to_line_protocol(topic, &message)
+            .context(TranslatingJson)?,
+    };
+    let lines = parse_lines(&lp)
+        .collect::<Result<Vec<_>, _>>()
+        .context(ParsingLines)?;
+    if lines.is_empty() {
+        return Ok(0);
+    }
+
+    app_server
+        .read()
+        .await
+        .write_lines(&config.database, &lines)
+        .await
+        .context(WritingMessage)?;
+    Ok(lines.len())
+}
+
+/// Reads the next packet sent by the broker
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Packet> {
+    let header = match reader.read_u8().await {
+        Ok(header) => header,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return ConnectionClosed.fail(),
+        Err(e) => return Err(e).context(Communicating),
+    };
+
+    // The remaining length is encoded in up to 4 bytes, 7 bits at a time
+    let mut length = 0usize;
+    for shift in (0..4).map(|i| i * 7) {
+        let byte = reader.read_u8().await.context(Communicating)?;
+        length |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        ensure!(
+            shift < 21,
+            MalformedPacket {
+                reason: "remaining length longer than 4 bytes"
+            }
+        );
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await.context(Communicating)?;
+    decode_packet(header, &body)
+}
+
+fn decode_packet(header: u8, body: &[u8]) -> Result<Packet> {
+    let mut body = Body(body);
+    let packet = match header & 0xf0 {
+        CONNACK => {
+            body.u8()?;
+            Packet::ConnAck {
+                return_code: body.u8()?,
+            }
+        }
+        SUBACK => Packet::SubAck {
+            packet_id: body.u16()?,
+            return_codes: body.0.to_vec(),
+        },
+        PUBLISH => {
+            let topic = body.string()?;
+            let qos = (header >> 1) & 0x03;
+            let packet_id = if qos > 0 { Some(body.u16()?) } else { None };
+            Packet::Publish {
+                topic,
+                packet_id,
+                payload: body.0.to_vec(),
+            }
+        }
+        PINGRESP => Packet::PingResp,
+        kind => Packet::Other(kind),
+    };
+    Ok(packet)
+}
+
+/// The bytes of a packet left to decode
+struct Body<'a>(&'a [u8]);
+
+impl<'a> Body<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        ensure!(
+            self.0.len() >= n,
+            MalformedPacket {
+                reason: "packet shorter than its contents"
+            }
+        );
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let length = self.u16()?;
+        let bytes = self.take(length.into())?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| Error::MalformedPacket {
+            reason: "string is not valid UTF-8".to_string(),
+        })
+    }
+}
+
+/// Returns a packet with the fixed header `header` and `body`
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            packet.push(byte | 0x80);
+        } else {
+            packet.push(byte);
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn put_string(body: &mut Vec<u8>, s: &str) {
+    body.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    body.extend_from_slice(s.as_bytes());
+}
+
+/// Returns the CONNECT packet of a clean session of `config`
+fn connect_packet(config: &MqttConfig) -> Vec<u8> {
+    let mut flags = 0x02;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+
+    let mut body = vec![];
+    put_string(&mut body, "MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    put_string(&mut body, &config.client_id);
+    if let Some(username) = &config.username {
+        put_string(&mut body, username);
+    }
+    if let Some(password) = &config.password {
+        put_string(&mut body, password);
+    }
+    packet(CONNECT, &body)
+}
+
+/// Returns the SUBSCRIBE packet subscribing to `topics` with QoS 1
+fn subscribe_packet(packet_id: u16, topics: &[String]) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    for topic in topics {
+        put_string(&mut body, topic);
+        body.push(1);
+    }
+    packet(SUBSCRIBE, &body)
+}
+
+fn puback_packet(packet_id: u16) -> Vec<u8> {
+    packet(PUBACK, &packet_id.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ConnectionManagerImpl;
+    use data_types::database_rules::DatabaseRules;
+    use object_store::{InMemory, ObjectStore};
+    use tokio::net::TcpListener;
+
+    fn publish_packet(topic: &str, packet_id: u16, payload: &[u8]) -> Vec<u8> {
+        let mut body = vec![];
+        put_string(&mut body, topic);
+        body.extend_from_slice(&packet_id.to_be_bytes());
+        body.extend_from_slice(payload);
+        packet(PUBLISH | 0x02, &body)
+    }
+
+    #[tokio::test]
+    async fn packets() {
+        let payload = vec![b'x'; 200];
+        let mut bytes = &publish_packet("sensors/1", 7, &payload)[..];
+        // 200 bytes of payload need two bytes of remaining length
+        assert_eq!(bytes[1..3], [0xd5, 0x01]);
+        assert_eq!(
+            read_packet(&mut bytes).await.unwrap(),
+            Packet::Publish {
+                topic: "sensors/1".to_string(),
+                packet_id: Some(7),
+                payload,
+            }
+        );
+
+        let mut bytes = &[0x20, 2, 0, 5][..];
+        assert_eq!(
+            read_packet(&mut bytes).await.unwrap(),
+            Packet::ConnAck { return_code: 5 }
+        );
+
+        let mut bytes = &[0x30, 5, 0, 9, b'a'][..];
+        let err = read_packet(&mut bytes).await.unwrap_err();
+        assert!(matches!(err, Error::Communicating { .. }));
+        let mut bytes = &[0x30, 3, 0, 9, b'a'][..];
+        let err = read_packet(&mut bytes).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Malformed MQTT packet: packet shorter than its contents"
+        );
+        let mut bytes = &[][..];
+        let err = read_packet(&mut bytes).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectionClosed));
+
+        let config = MqttConfig {
+            client_id: "iox".to_string(),
+            username: Some("u".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            connect_packet(&config),
+            [
+                0x10, 18, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x82, 0, 30, 0, 3, b'i', b'o', b'x', 0,
+                1, b'u'
+            ]
+        );
+        assert_eq!(
+            subscribe_packet(1, &["a/#".to_string()]),
+            [0x82, 8, 0, 1, 0, 3, b'a', b'/', b'#', 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn listen_to_broker() {
+        let mut app_server = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        app_server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        app_server.create_database("iot", rules).await.unwrap();
+        let app_server = Arc::new(RwLock::new(app_server));
+
+        let mut broker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = MqttConfig {
+            broker: broker.local_addr().unwrap().to_string(),
+            topics: vec!["sensors/#".to_string()],
+            database: "iot".to_string(),
+            client_id: "iox".to_string(),
+            ..Default::default()
+        };
+        let mapping: JsonMapping = serde_json::from_str(
+            r#"{"measurement": "readings", "fields": {"temp": "t"}, "source_tag": "topic"}"#,
+        )
+        .unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let listener = tokio::spawn(run(
+            config,
+            Payload::Json(mapping),
+            Arc::clone(&app_server),
+            async {
+                stopped.await.ok();
+            },
+        ));
+
+        let (mut socket, _) = broker.accept().await.unwrap();
+        assert_eq!(
+            read_packet(&mut socket).await.unwrap(),
+            Packet::Other(CONNECT)
+        );
+        socket.write_all(&[CONNACK, 2, 0, 0]).await.unwrap();
+        assert_eq!(read_packet(&mut socket).await.unwrap(), Packet::Other(0x80));
+        socket.write_all(&[SUBACK, 3, 0, 1, 1]).await.unwrap();
+
+        socket
+            .write_all(&publish_packet("sensors/1", 9, br#"{"t": 21.5}"#))
+            .await
+            .unwrap();
+        socket
+            .write_all(&publish_packet("sensors/2", 10, b"not json"))
+            .await
+            .unwrap();
+        // messages are acknowledged once written, or dropped
+        assert_eq!(
+            read_packet(&mut socket).await.unwrap(),
+            Packet::Other(PUBACK)
+        );
+        assert_eq!(
+            read_packet(&mut socket).await.unwrap(),
+            Packet::Other(PUBACK)
+        );
+
+        let batches = app_server
+            .read()
+            .await
+            .query_local("iot", "select topic, temp from readings")
+            .await
+            .unwrap();
+        assert_eq!(batches[0].num_rows(), 1);
+
+        stop.send(()).unwrap();
+        listener.await.unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+//! This module contains the delivery of the notifications of threshold checks to their
+//! endpoints: generic webhooks, Slack incoming webhooks and the PagerDuty Events API.
+//!
+//! A notification is sent once to each endpoint of its check. Deliveries that fail are logged
+//! rather than retried, as the next change of level of the check supersedes them.
+
+use std::time::Duration;
+
+use cluster::checks::{Endpoint, Level, Notification};
+use serde_json::{json, Value};
+use snafu::{ResultExt, Snafu};
+
+/// Where the PagerDuty Events API v2 accepts events
+const PAGERDUTY_EVENTS_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+
+/// How long an endpoint may take to accept a notification
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error sending notification to {}: {}", url, source))]
+    Sending { url: String, source: reqwest::Error },
+
+    #[snafu(display("Error serializing notification: {}", source))]
+    Serializing { source: serde_json::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Sends notifications to the endpoints of checks
+#[derive(Debug, Default, Clone)]
+pub struct Notifier {
+    client: reqwest::Client,
+}
+
+impl Notifier {
+    /// Sends `notification` to each of its endpoints, returning the errors of the deliveries
+    /// that failed
+    pub async fn send(&self, notification: &Notification) -> Vec<Error> {
+        let mut errors = vec![];
+        for endpoint in &notification.endpoints {
+            let (url, body) = request(endpoint, notification);
+            if let Err(e) = self.post(url, &body).await {
+                errors.push(e);
+            }
+        }
+        errors
+    }
+
+    async fn post(&self, url: &str, body: &Value) -> Result<()> {
+        let body = serde_json::to_vec(body).context(Serializing)?;
+        self.client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .timeout(TIMEOUT)
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context(Sending { url })?;
+        Ok(())
+    }
+}
+
+/// Returns the URL and body of the request that notifies `endpoint` of `notification`
+fn request<'a>(endpoint: &'a Endpoint, notification: &Notification) -> (&'a str, Value) {
+    match endpoint {
+        Endpoint::Webhook { url } => (url, webhook_body(notification)),
+        Endpoint::Slack { url } => (url, json!({ "text": summary(notification) })),
+        Endpoint::PagerDuty { routing_key } => (
+            PAGERDUTY_EVENTS_URL,
+            pagerduty_body(routing_key, notification),
+        ),
+    }
+}
+
+fn summary(notification: &Notification) -> String {
+    format!(
+        "[{}] check {} of database {} changed from {} to {} with value {}",
+        notification.level.to_string().to_uppercase(),
+        notification.check,
+        notification.db_name,
+        notification.previous,
+        notification.level,
+        notification.value
+    )
+}
+
+fn webhook_body(notification: &Notification) -> Value {
+    json!({
+        "check": notification.check,
+        "db_name": notification.db_name,
+        "previous": notification.previous,
+        "level": notification.level,
+        "value": notification.value,
+        "time": notification.time.to_rfc3339(),
+        "summary": summary(notification),
+    })
+}
+
+/// Triggers an incident when a check becomes warning or critical, and resolves it once the
+/// check is ok again. The incidents of a check are deduplicated by its database and name.
+fn pagerduty_body(routing_key: &str, notification: &Notification) -> Value {
+    let dedup_key = format!("{}/{}", notification.db_name, notification.check);
+    if notification.level == Level::Ok {
+        return json!({
+            "routing_key": routing_key,
+            "event_action": "resolve",
+            "dedup_key": dedup_key,
+        });
+    }
+
+    json!({
+        "routing_key": routing_key,
+        "event_action": "trigger",
+        "dedup_key": dedup_key,
+        "payload": {
+            "summary": summary(notification),
+            "source": notification.db_name,
+            "severity": match notification.level {
+                Level::Crit => "critical",
+                _ => "warning",
+            },
+            "timestamp": notification.time.to_rfc3339(),
+            "custom_details": {
+                "check": notification.check,
+                "value": notification.value,
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn notification(previous: Level, level: Level) -> Notification {
+        Notification {
+            check: "cpu_high".to_string(),
+            db_name: "foo".to_string(),
+            previous,
+            level,
+            value: 95.0,
+            time: Utc.timestamp(1_604_232_330, 0),
+            endpoints: vec![],
+        }
+    }
+
+    #[test]
+    fn webhook_and_slack_bodies() {
+        let notification = notification(Level::Ok, Level::Crit);
+        let webhook = Endpoint::Webhook {
+            url: "http://example.com/hook".to_string(),
+        };
+        let (url, body) = request(&webhook, &notification);
+        assert_eq!(url, "http://example.com/hook");
+        assert_eq!(body["check"], "cpu_high");
+        assert_eq!(body["previous"], "ok");
+        assert_eq!(body["level"], "crit");
+        assert_eq!(body["value"], 95.0);
+        assert_eq!(body["time"], "2020-11-01T12:05:30+00:00");
+
+        let slack = Endpoint::Slack {
+            url: "https://hooks.slack.com/services/T0/B0/x".to_string(),
+        };
+        let (_, body) = request(&slack, &notification);
+        assert_eq!(
+            body,
+            json!({
+                "text": "[CRIT] check cpu_high of database foo changed from ok to crit with value 95"
+            })
+        );
+    }
+
+    #[test]
+    fn pagerduty_triggers_and_resolves() {
+        let pagerduty = Endpoint::PagerDuty {
+            routing_key: "key".to_string(),
+        };
+
+        let (url, body) = request(&pagerduty, &notification(Level::Ok, Level::Warn));
+        assert_eq!(url, PAGERDUTY_EVENTS_URL);
+        assert_eq!(body["routing_key"], "key");
+        assert_eq!(body["event_action"], "trigger");
+        assert_eq!(body["dedup_key"], "foo/cpu_high");
+        assert_eq!(body["payload"]["severity"], "warning");
+        assert_eq!(body["payload"]["source"], "foo");
+
+        let (_, body) = request(&pagerduty, &notification(Level::Warn, Level::Ok));
+        assert_eq!(
+            body,
+            json!({
+                "routing_key": "key",
+                "event_action": "resolve",
+                "dedup_key": "foo/cpu_high",
+            })
+        );
+    }
+}
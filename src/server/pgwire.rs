@@ -0,0 +1,717 @@
+//! This module contains an experimental PostgreSQL wire protocol frontend, which lets the
+//! tools that speak the protocol, such as psql and the PostgreSQL drivers, run read-only SQL
+//! queries against the databases of a `cluster::Server`.
+//!
+//! The frontend is opt-in, with `--pg-bind-addr`. It speaks version 3 of the protocol without
+//! TLS, and only its simple query flow: each query message holds one statement, whose results
+//! are sent back in the text format. The database of a connection is the `database` of its
+//! startup message. Unless the server allows anonymous access, clients are asked for a
+//! password, which is the secret of a token or the password of the user in the server's LDAP
+//! directory. SELECT queries run through the SQL frontend, the statements tools send when
+//! connecting (SET, BEGIN, COMMIT, ...) are accepted and ignored, and anything else is
+//! rejected. The protocol has no headers, so only a `/*+ batch */` hint marks a query as a
+//! batch query, see the `governor` module.
+
+use std::{future::Future, sync::Arc};
+
+use arrow_deps::arrow::{
+    array::{
+        ArrayRef, BooleanArray, Float64Array, Int32Array, Int64Array, StringArray, UInt32Array,
+        UInt64Array,
+    },
+    datatypes::DataType,
+    record_batch::RecordBatch,
+};
+use cluster::{ConnectionManager, Server as AppServer};
+use snafu::{ResultExt, Snafu};
+use storage::access::RowAccess;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::RwLock,
+};
+use tracing::{debug, info, warn};
+
+use crate::server::{
+    auth::{Authorizer, Permission},
+    capture::{self, Capture},
+    governor::{Governor, Priority},
+};
+
+/// The version of PostgreSQL reported to clients, which some of them check
+const SERVER_VERSION: &str = "13.0";
+
+const PROTOCOL_VERSION_3: i32 = 196_608;
+const SSL_REQUEST: i32 = 80_877_103;
+const CANCEL_REQUEST: i32 = 80_877_102;
+
+/// The largest message accepted from clients
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+// The object ids of the PostgreSQL types the columns of results are described as
+const BOOL_OID: i32 = 16;
+const INT8_OID: i32 = 20;
+const INT4_OID: i32 = 23;
+const TEXT_OID: i32 = 25;
+const FLOAT8_OID: i32 = 701;
+const NUMERIC_OID: i32 = 1700;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error communicating with PostgreSQL client: {}", source))]
+    Communicating { source: std::io::Error },
+
+    #[snafu(display("Invalid PostgreSQL message: {}", reason))]
+    InvalidMessage { reason: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An error reported to the client, with its SQLSTATE code
+#[derive(Debug)]
+struct QueryError {
+    code: &'static str,
+    message: String,
+}
+
+impl QueryError {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Serves the PostgreSQL clients connecting to `listener`, until `shutdown` resolves
+pub async fn serve<M>(
+    mut listener: TcpListener,
+    app_server: Arc<RwLock<AppServer<M>>>,
+    authorizer: Arc<Authorizer>,
+    capture: Option<Arc<Capture>>,
+    governor: Arc<Governor>,
+    shutdown: impl Future<Output = ()>,
+) where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    let accepting = async {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let session = Session {
+                        app_server: Arc::clone(&app_server),
+                        authorizer: Arc::clone(&authorizer),
+                        capture: capture.clone(),
+                        governor: Arc::clone(&governor),
+                    };
+                    tokio::spawn(async move {
+                        if let Err(e) = session.run(stream).await {
+                            debug!("PostgreSQL connection from {} ended: {}", addr, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("error accepting PostgreSQL connection: {}", e),
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = accepting => {},
+        _ = shutdown => info!("Stopped accepting PostgreSQL connections"),
+    }
+}
+
+/// A connection of a PostgreSQL client
+#[derive(Debug)]
+struct Session<M: ConnectionManager> {
+    app_server: Arc<RwLock<AppServer<M>>>,
+    authorizer: Arc<Authorizer>,
+    capture: Option<Arc<Capture>>,
+    governor: Arc<Governor>,
+}
+
+impl<M> Session<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    async fn run(self, stream: TcpStream) -> Result<()> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+
+        let params = loop {
+            let body = read_startup(&mut reader).await?;
+            let code = i32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+            match code {
+                // TLS is not supported, and clients carry on without it
+                SSL_REQUEST => writer.write_all(b"N").await.context(Communicating)?,
+                CANCEL_REQUEST => return Ok(()),
+                PROTOCOL_VERSION_3 => break startup_params(&body[4..])?,
+                _ => {
+                    let error =
+                        QueryError::new("0A000", format!("unsupported protocol version {}", code));
+                    return send(&mut writer, &[error_response(&error)]).await;
+                }
+            }
+        };
+
+        let db_name = match params.iter().find(|(key, _)| key == "database") {
+            Some((_, db_name)) => db_name.clone(),
+            None => {
+                let error = QueryError::new("3D000", "a database is required");
+                return send(&mut writer, &[error_response(&error)]).await;
+            }
+        };
+
+        // Only ask for a password if connecting without one is not allowed
+        let (authorization, access) = match self.authorizer.access(None, Permission::Read, &db_name)
+        {
+            Ok(access) => (None, access),
+            Err(_) => {
+                send(&mut writer, &[message(b'R', &3i32.to_be_bytes())]).await?;
+                let password = match read_message(&mut reader).await? {
+                    Some((b'p', body)) => cstring(&mut &body[..])?,
+                    _ => return Ok(()),
+                };
+                // the password is the secret of a token, or the password of the user in the LDAP
+                // directory
+                let user = params
+                    .iter()
+                    .find(|(key, _)| key == "user")
+                    .map_or("", |(_, user)| user.as_str());
+                let authorization =
+                    format!("Basic {}", base64::encode(format!("{}:{}", user, password)));
+                self.authorizer.authenticate(Some(&authorization)).await;
+                match self
+                    .authorizer
+                    .access(Some(&authorization), Permission::Read, &db_name)
+                {
+                    Ok(access) => (Some(authorization), access),
+                    Err(e) => {
+                        let error = QueryError::new("28000", e.to_string());
+                        return send(&mut writer, &[error_response(&error)]).await;
+                    }
+                }
+            }
+        };
+
+        let mut messages = vec![message(b'R', &0i32.to_be_bytes())];
+        for (name, value) in &[
+            ("server_version", SERVER_VERSION),
+            ("server_encoding", "UTF8"),
+            ("client_encoding", "UTF8"),
+            ("DateStyle", "ISO, MDY"),
+            ("integer_datetimes", "on"),
+            ("standard_conforming_strings", "on"),
+        ] {
+            let mut body = vec![];
+            put_cstring(&mut body, name);
+            put_cstring(&mut body, value);
+            messages.push(message(b'S', &body));
+        }
+        let mut key_data = std::process::id().to_be_bytes().to_vec();
+        key_data.extend_from_slice(&rand::random::<u32>().to_be_bytes());
+        messages.push(message(b'K', &key_data));
+        messages.push(ready_for_query());
+        send(&mut writer, &messages).await?;
+
+        // After an error in the extended query flow, which is not supported, the messages up
+        // to the next Sync are ignored
+        let mut skipping = false;
+        while let Some((tag, body)) = read_message(&mut reader).await? {
+            match tag {
+                b'Q' => {
+                    let query = cstring(&mut &body[..])?;
+                    let messages = match self
+                        .query(&db_name, authorization.as_deref(), &access, &query)
+                        .await
+                    {
+                        Ok(messages) => messages,
+                        Err(error) => vec![error_response(&error)],
+                    };
+                    send(&mut writer, &messages).await?;
+                    send(&mut writer, &[ready_for_query()]).await?;
+                }
+                b'S' => {
+                    skipping = false;
+                    send(&mut writer, &[ready_for_query()]).await?;
+                }
+                b'X' => break,
+                b'H' => {}
+                _ if skipping => {}
+                _ => {
+                    skipping = true;
+                    let error =
+                        QueryError::new("0A000", "only the simple query protocol is supported");
+                    send(&mut writer, &[error_response(&error)]).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the statement of a query message, returning the messages of its results. The
+    /// statement may read the rows of the database `access` allows, and the tables of the
+    /// other databases the `authorization` of the connection may read.
+    async fn query(
+        &self,
+        db_name: &str,
+        authorization: Option<&str>,
+        access: &RowAccess,
+        query: &str,
+    ) -> Result<Vec<Vec<u8>>, QueryError> {
+        let statement = query.trim().trim_end_matches(';').trim();
+        let (priority, statement) =
+            Priority::of(None, statement).map_err(|e| QueryError::new("22023", e.to_string()))?;
+        if statement.is_empty() {
+            return Ok(vec![message(b'I', &[])]);
+        }
+
+        let keyword = statement
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        match keyword.as_str() {
+            "SELECT" | "WITH" => {}
+            "SET" | "BEGIN" | "START" | "COMMIT" | "END" | "ROLLBACK" | "DISCARD"
+            | "DEALLOCATE" => {
+                let tag = if keyword == "START" {
+                    "BEGIN"
+                } else {
+                    &keyword
+                };
+                return Ok(vec![command_complete(tag)]);
+            }
+            _ => {
+                return Err(QueryError::new(
+                    "25006",
+                    format!("{} is not supported by the read-only frontend", keyword),
+                ))
+            }
+        }
+
+        debug!(
+            "running PostgreSQL query against {}: {}",
+            db_name, statement
+        );
+        if let Some(capture) = &self.capture {
+            capture.record(
+                db_name,
+                capture::Request::Sql {
+                    query: statement.to_string(),
+                },
+            );
+        }
+        let _permit = self
+            .governor
+            .admit(priority)
+            .await
+            .map_err(|e| QueryError::new("53000", e.to_string()))?;
+        let batches = self
+            .app_server
+            .read()
+            .await
+            .query_local_across(db_name, statement, |name| {
+                if name == db_name {
+                    Some(access.clone())
+                } else {
+                    self.authorizer
+                        .access(authorization, Permission::Read, name)
+                        .ok()
+                }
+            })
+            .await
+            .map_err(|e| match e {
+                cluster::Error::DatabaseNotFound { .. } => QueryError::new("3D000", e.to_string()),
+                cluster::Error::TableNotAllowed { .. }
+                | cluster::Error::DatabaseNotAllowed { .. } => {
+                    QueryError::new("42501", e.to_string())
+                }
+                _ => QueryError::new("42000", e.to_string()),
+            })?;
+
+        Ok(results(&batches))
+    }
+}
+
+/// Returns the messages describing and holding the rows of `batches`
+fn results(batches: &[RecordBatch]) -> Vec<Vec<u8>> {
+    let mut messages = vec![];
+    let mut rows = 0;
+    if let Some(first) = batches.first() {
+        let schema = first.schema();
+        let mut body = (schema.fields().len() as i16).to_be_bytes().to_vec();
+        for field in schema.fields() {
+            let (type_oid, type_size) = pg_type(field.data_type());
+            put_cstring(&mut body, field.name());
+            body.extend_from_slice(&0i32.to_be_bytes()); // table
+            body.extend_from_slice(&0i16.to_be_bytes()); // column of the table
+            body.extend_from_slice(&type_oid.to_be_bytes());
+            body.extend_from_slice(&type_size.to_be_bytes());
+            body.extend_from_slice(&(-1i32).to_be_bytes()); // type modifier
+            body.extend_from_slice(&0i16.to_be_bytes()); // text format
+        }
+        messages.push(message(b'T', &body));
+    }
+
+    for batch in batches {
+        for row in 0..batch.num_rows() {
+            let mut body = (batch.num_columns() as i16).to_be_bytes().to_vec();
+            for column in batch.columns() {
+                match text_value(column, row) {
+                    Some(value) => {
+                        body.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                        body.extend_from_slice(value.as_bytes());
+                    }
+                    None => body.extend_from_slice(&(-1i32).to_be_bytes()),
+                }
+            }
+            messages.push(message(b'D', &body));
+            rows += 1;
+        }
+    }
+
+    messages.push(command_complete(&format!("SELECT {}", rows)));
+    messages
+}
+
+/// Returns the object id and size of the PostgreSQL type a column of `data_type` is
+/// described as
+fn pg_type(data_type: &DataType) -> (i32, i16) {
+    match data_type {
+        DataType::Boolean => (BOOL_OID, 1),
+        DataType::Int32 => (INT4_OID, 4),
+        DataType::Int64 | DataType::UInt32 => (INT8_OID, 8),
+        // PostgreSQL has no unsigned 64 bit integers
+        DataType::UInt64 => (NUMERIC_OID, -1),
+        DataType::Float64 => (FLOAT8_OID, 8),
+        _ => (TEXT_OID, -1),
+    }
+}
+
+/// Returns the value in `row` of `column` in the text format, None if it is null
+fn text_value(column: &ArrayRef, row: usize) -> Option<String> {
+    if column.is_null(row) {
+        return None;
+    }
+
+    let any = column.as_any();
+    let value = match column.data_type() {
+        DataType::Boolean => {
+            let value = any.downcast_ref::<BooleanArray>().unwrap().value(row);
+            if value { "t" } else { "f" }.to_string()
+        }
+        DataType::Int32 => any
+            .downcast_ref::<Int32Array>()
+            .unwrap()
+            .value(row)
+            .to_string(),
+        DataType::Int64 => any
+            .downcast_ref::<Int64Array>()
+            .unwrap()
+            .value(row)
+            .to_string(),
+        DataType::UInt32 => any
+            .downcast_ref::<UInt32Array>()
+            .unwrap()
+            .value(row)
+            .to_string(),
+        DataType::UInt64 => any
+            .downcast_ref::<UInt64Array>()
+            .unwrap()
+            .value(row)
+            .to_string(),
+        DataType::Float64 => {
+            let value = any.downcast_ref::<Float64Array>().unwrap().value(row);
+            if value.is_nan() {
+                "NaN".to_string()
+            } else if value.is_infinite() {
+                if value > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+            } else {
+                value.to_string()
+            }
+        }
+        DataType::Utf8 => any
+            .downcast_ref::<StringArray>()
+            .unwrap()
+            .value(row)
+            .to_string(),
+        // IOx tables and the functions over them only have the types above
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Reads the body of a startup message, which has no tag
+async fn read_startup<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let length = reader.read_i32().await.context(Communicating)?;
+    if length < 8 || length as usize > MAX_MESSAGE_SIZE {
+        return InvalidMessage {
+            reason: format!("startup message of {} bytes", length),
+        }
+        .fail();
+    }
+    let mut body = vec![0; length as usize - 4];
+    reader.read_exact(&mut body).await.context(Communicating)?;
+    Ok(body)
+}
+
+/// Reads the next message of the client, as its tag and body, or None once it disconnected
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<(u8, Vec<u8>)>> {
+    let tag = match reader.read_u8().await {
+        Ok(tag) => tag,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e).context(Communicating),
+    };
+    let length = reader.read_i32().await.context(Communicating)?;
+    if length < 4 || length as usize > MAX_MESSAGE_SIZE {
+        return InvalidMessage {
+            reason: format!("message of {} bytes", length),
+        }
+        .fail();
+    }
+    let mut body = vec![0; length as usize - 4];
+    reader.read_exact(&mut body).await.context(Communicating)?;
+    Ok(Some((tag, body)))
+}
+
+/// Decodes the parameters of a startup message, pairs of null terminated strings ending with
+/// an empty one
+fn startup_params(mut body: &[u8]) -> Result<Vec<(String, String)>> {
+    let mut params = vec![];
+    loop {
+        let key = cstring(&mut body)?;
+        if key.is_empty() {
+            return Ok(params);
+        }
+        let value = cstring(&mut body)?;
+        params.push((key, value));
+    }
+}
+
+/// Takes a null terminated string from the front of `body`
+fn cstring(body: &mut &[u8]) -> Result<String> {
+    let end = body
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| Error::InvalidMessage {
+            reason: "unterminated string".to_string(),
+        })?;
+    let s = String::from_utf8(body[..end].to_vec()).map_err(|_| Error::InvalidMessage {
+        reason: "string is not valid UTF-8".to_string(),
+    })?;
+    *body = &body[end + 1..];
+    Ok(s)
+}
+
+fn put_cstring(body: &mut Vec<u8>, s: &str) {
+    body.extend_from_slice(s.as_bytes());
+    body.push(0);
+}
+
+/// Returns a message with the tag `tag` and `body`
+fn message(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = vec![tag];
+    message.extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+fn ready_for_query() -> Vec<u8> {
+    message(b'Z', b"I")
+}
+
+fn command_complete(tag: &str) -> Vec<u8> {
+    let mut body = vec![];
+    put_cstring(&mut body, tag);
+    message(b'C', &body)
+}
+
+fn error_response(error: &QueryError) -> Vec<u8> {
+    let mut body = vec![];
+    for (field, value) in &[
+        (b'S', "ERROR"),
+        (b'V', "ERROR"),
+        (b'C', error.code),
+        (b'M', error.message.as_str()),
+    ] {
+        body.push(*field);
+        put_cstring(&mut body, value);
+    }
+    body.push(0);
+    message(b'E', &body)
+}
+
+async fn send<W: AsyncWrite + Unpin>(writer: &mut W, messages: &[Vec<u8>]) -> Result<()> {
+    for message in messages {
+        writer.write_all(message).await.context(Communicating)?;
+    }
+    writer.flush().await.context(Communicating)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ConnectionManagerImpl;
+    use data_types::database_rules::DatabaseRules;
+    use object_store::{InMemory, ObjectStore};
+
+    /// Connects to the frontend at `addr` as a client of `database`
+    async fn connect(addr: std::net::SocketAddr, database: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let mut body = PROTOCOL_VERSION_3.to_be_bytes().to_vec();
+        put_cstring(&mut body, "user");
+        put_cstring(&mut body, "iox");
+        put_cstring(&mut body, "database");
+        put_cstring(&mut body, database);
+        body.push(0);
+        stream
+            .write_all(&(body.len() as i32 + 4).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(&body).await.unwrap();
+        stream
+    }
+
+    /// Reads the messages of the server up to the next ReadyForQuery, as their tags and the
+    /// strings they hold
+    async fn read_until_ready(stream: &mut TcpStream) -> Vec<(char, Vec<String>)> {
+        let mut messages = vec![];
+        loop {
+            let (tag, body) = read_message(stream).await.unwrap().unwrap();
+            let strings = match tag {
+                b'C' => vec![cstring(&mut &body[..]).unwrap()],
+                b'E' => {
+                    let mut body = &body[..];
+                    let mut fields = vec![];
+                    while body[0] != 0 {
+                        let field = body[0] as char;
+                        body = &body[1..];
+                        fields.push(format!("{}:{}", field, cstring(&mut body).unwrap()));
+                    }
+                    fields
+                }
+                b'D' => {
+                    let mut body = &body[2..];
+                    let mut values = vec![];
+                    while !body.is_empty() {
+                        let length = i32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                        body = &body[4..];
+                        if length < 0 {
+                            values.push("NULL".to_string());
+                        } else {
+                            let (value, rest) = body.split_at(length as usize);
+                            values.push(String::from_utf8(value.to_vec()).unwrap());
+                            body = rest;
+                        }
+                    }
+                    values
+                }
+                b'T' => {
+                    let mut body = &body[2..];
+                    let mut names = vec![];
+                    while !body.is_empty() {
+                        names.push(cstring(&mut body).unwrap());
+                        body = &body[18..];
+                    }
+                    names
+                }
+                _ => vec![],
+            };
+            messages.push((tag as char, strings));
+            if tag == b'Z' {
+                return messages;
+            }
+        }
+    }
+
+    async fn simple_query(stream: &mut TcpStream, query: &str) -> Vec<(char, Vec<String>)> {
+        let mut body = vec![];
+        put_cstring(&mut body, query);
+        stream.write_all(&message(b'Q', &body)).await.unwrap();
+        read_until_ready(stream).await
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(ToString::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn test_simple_queries() {
+        let mut app_server = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        app_server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        app_server.create_database("foo", rules).await.unwrap();
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines(
+            "cpu,host=a usage=0.5,up=true 10\ncpu usage=1.5 20",
+        )
+        .map(|l| l.unwrap())
+        .collect();
+        app_server.write_lines("foo", &lines).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            Arc::new(RwLock::new(app_server)),
+            Arc::new(Authorizer::new(true)),
+            None,
+            Arc::new(Governor::default()),
+            async {
+                stopped.await.ok();
+            },
+        ));
+
+        let mut stream = connect(addr, "foo").await;
+        let startup = read_until_ready(&mut stream).await;
+        assert_eq!(startup.first().unwrap().0, 'R');
+        assert_eq!(startup.last().unwrap().0, 'Z');
+
+        let results = simple_query(
+            &mut stream,
+            "select host, usage, up, time from cpu order by time;",
+        )
+        .await;
+        assert_eq!(
+            results,
+            vec![
+                ('T', strings(&["host", "usage", "up", "time"])),
+                ('D', strings(&["a", "0.5", "t", "10"])),
+                ('D', strings(&["NULL", "1.5", "NULL", "20"])),
+                ('C', strings(&["SELECT 2"])),
+                ('Z', vec![]),
+            ]
+        );
+
+        let results = simple_query(&mut stream, "SET extra_float_digits = 3").await;
+        assert_eq!(results[0], ('C', strings(&["SET"])));
+
+        let results = simple_query(&mut stream, "delete from cpu").await;
+        assert_eq!(results[0].0, 'E');
+        assert!(results[0].1.contains(&"C:25006".to_string()));
+
+        let results = simple_query(&mut stream, "select * from missing").await;
+        assert_eq!(results[0].0, 'E');
+
+        // the connection is still usable after errors
+        let results = simple_query(&mut stream, "select count(*) from cpu").await;
+        assert_eq!(results[1], ('D', strings(&["2"])));
+
+        let mut stream = connect(addr, "bar").await;
+        read_until_ready(&mut stream).await;
+        let results = simple_query(&mut stream, "select * from cpu").await;
+        assert_eq!(results[0].0, 'E');
+        assert!(results[0].1.contains(&"C:3D000".to_string()));
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+//! This module contains the profiles served on the `/debug/pprof` routes, so that the
+//! performance of a running server can be investigated without attaching external profilers.
+//!
+//! CPU profiles are sampled with pprof-rs and require the `pprof` feature. Heap statistics are
+//! read from jemalloc, which is only the global allocator with the `jemalloc` feature.
+
+use std::time::Duration;
+
+use serde::Serialize;
+use snafu::Snafu;
+
+// which variants are constructed depends on the enabled features
+#[allow(dead_code)]
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "{} profiles are not available: the server was built without the {} feature",
+        profile,
+        feature
+    ))]
+    NotCompiled {
+        profile: &'static str,
+        feature: &'static str,
+    },
+
+    #[snafu(display("Error sampling the CPU profile: {}", source))]
+    SamplingCpu {
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[snafu(display("Error reading the heap statistics: {}", source))]
+    ReadingHeapStats {
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The longest CPU profile that can be requested, so that a forgotten request doesn't keep
+/// the profiler running
+pub const MAX_CPU_PROFILE_DURATION: Duration = Duration::from_secs(300);
+
+/// Samples the stacks of all the threads `frequency` times a second for `duration`, returning
+/// the profile in the protobuf format read by `go tool pprof`
+#[cfg(feature = "pprof")]
+pub async fn cpu_profile(duration: Duration, frequency: i32) -> Result<Vec<u8>> {
+    use prost::Message;
+
+    let duration = duration.min(MAX_CPU_PROFILE_DURATION);
+
+    // the profiler guard stays on the blocking thread, as it can't be held across an await
+    let profile = tokio::task::spawn_blocking(move || {
+        let boxed = |e: pprof::Error| Error::SamplingCpu {
+            source: Box::new(e),
+        };
+        let guard = pprof::ProfilerGuard::new(frequency).map_err(boxed)?;
+        std::thread::sleep(duration);
+        let report = guard.report().build().map_err(boxed)?;
+        report.pprof().map_err(boxed)
+    })
+    .await
+    .map_err(|e| Error::SamplingCpu {
+        source: Box::new(e),
+    })??;
+
+    let mut body = Vec::with_capacity(profile.encoded_len());
+    profile.encode(&mut body).map_err(|e| Error::SamplingCpu {
+        source: Box::new(e),
+    })?;
+    Ok(body)
+}
+
+#[cfg(not(feature = "pprof"))]
+pub async fn cpu_profile(_duration: Duration, _frequency: i32) -> Result<Vec<u8>> {
+    NotCompiled {
+        profile: "CPU",
+        feature: "pprof",
+    }
+    .fail()
+}
+
+/// Memory use of the jemalloc heap, in bytes. See the `stats.*` entries of `man jemalloc`
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Allocated by the application
+    pub allocated: usize,
+    /// In active pages, a multiple of the page size larger than `allocated`
+    pub active: usize,
+    /// Dedicated to jemalloc's own metadata
+    pub metadata: usize,
+    /// In physically resident data pages mapped by jemalloc
+    pub resident: usize,
+    /// In active extents mapped by jemalloc
+    pub mapped: usize,
+    /// In virtual memory mappings retained rather than returned to the operating system
+    pub retained: usize,
+}
+
+/// Returns the current memory use of the jemalloc heap
+#[cfg(feature = "jemalloc")]
+pub fn heap_stats() -> Result<HeapStats> {
+    use jemalloc_ctl::{epoch, stats};
+
+    let read = |result: Result<usize, jemalloc_ctl::Error>| {
+        result.map_err(|e| Error::ReadingHeapStats {
+            source: Box::new(e),
+        })
+    };
+
+    // the statistics are cached until the epoch advances
+    epoch::advance().map_err(|e| Error::ReadingHeapStats {
+        source: Box::new(e),
+    })?;
+
+    Ok(HeapStats {
+        allocated: read(stats::allocated::read())?,
+        active: read(stats::active::read())?,
+        metadata: read(stats::metadata::read())?,
+        resident: read(stats::resident::read())?,
+        mapped: read(stats::mapped::read())?,
+        retained: read(stats::retained::read())?,
+    })
+}
+
+#[cfg(not(feature = "jemalloc"))]
+pub fn heap_stats() -> Result<HeapStats> {
+    NotCompiled {
+        profile: "Heap",
+        feature: "jemalloc",
+    }
+    .fail()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "jemalloc")]
+    #[test]
+    fn heap_stats_track_allocations() {
+        let before = heap_stats().unwrap();
+        let data = vec![0_u8; 16 * 1024 * 1024];
+        let after = heap_stats().unwrap();
+        assert!(after.allocated >= before.allocated + data.len());
+    }
+
+    #[cfg(not(feature = "jemalloc"))]
+    #[test]
+    fn heap_stats_not_compiled() {
+        let err = heap_stats().unwrap_err();
+        assert!(matches!(err, Error::NotCompiled { feature: "jemalloc", .. }));
+    }
+}
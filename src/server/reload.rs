@@ -0,0 +1,281 @@
+//! This module contains the reloading of the configuration of a running server, on SIGHUP or
+//! on a request to the `/api/v1/reload` endpoint, as a restart replays the WAL and starts with
+//! a cold read buffer. A reload:
+//!
+//! * reads the settings file given with `--settings-file` again, and applies its log filter,
+//!   query parallelism and write limits. The settings the file leaves out take the values given
+//!   on the command line.
+//! * applies the rules of the databases stored since they were loaded, such as their lifecycle
+//!   rules, see `cluster::Server::reload_database_rules`.
+//!
+//! The background workers read the rules and settings each time they run, so the changes apply
+//! from their next run, and the writes and queries in progress finish with the earlier ones.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use cluster::{Server as AppServer, DEFAULT_QUERY_PARALLELISM};
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use tokio::sync::{Mutex, RwLock};
+use tracing::info;
+use write_buffer::WriteLimits;
+
+use super::{log_filter::LogFilter, ConnectionManagerImpl};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error reading settings file {:?}: {}", path, source))]
+    ReadingSettings {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Invalid settings file {:?}: {}", path, source))]
+    ParsingSettings {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Error changing the log filter: {}", source))]
+    SettingLogFilter { source: super::log_filter::Error },
+
+    #[snafu(display("Error reloading the rules of the databases: {}", source))]
+    ReloadingRules { source: cluster::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The settings of the server that can change while it runs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The directives deciding which logs are emitted, using the syntax of `RUST_LOG`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_filter: Option<String>,
+    /// The number of chunks a query scans at the same time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_parallelism: Option<usize>,
+    /// Throttle the writes to a partition once it buffers this many bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition_write_limit: Option<usize>,
+    /// Throttle the writes to a database once it buffers this many bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffer_write_limit: Option<usize>,
+}
+
+impl Settings {
+    /// Reads the settings from the JSON file at `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).context(ReadingSettings { path })?;
+        serde_json::from_slice(&data).context(ParsingSettings { path })
+    }
+
+    /// Returns the settings of `self`, and those of `defaults` that `self` leaves out
+    fn or(self, defaults: &Self) -> Self {
+        Self {
+            log_filter: self.log_filter.or_else(|| defaults.log_filter.clone()),
+            query_parallelism: self.query_parallelism.or(defaults.query_parallelism),
+            partition_write_limit: self
+                .partition_write_limit
+                .or(defaults.partition_write_limit),
+            buffer_write_limit: self.buffer_write_limit.or(defaults.buffer_write_limit),
+        }
+    }
+}
+
+/// A database whose stored rules a reload applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReloadedRules {
+    pub db_name: String,
+    /// The generation of the rules applied
+    pub generation: u64,
+}
+
+/// What a reload applied
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Reload {
+    /// The settings in use, if the server has a settings file
+    pub settings: Option<Settings>,
+    pub databases: Vec<ReloadedRules>,
+}
+
+/// Reloads the settings file and the stored rules of the databases into the running server
+#[derive(Debug)]
+pub struct Reloader {
+    settings_file: Option<PathBuf>,
+    /// The settings given on the command line
+    defaults: Settings,
+    log_filter: LogFilter,
+    app_server: Arc<RwLock<AppServer<ConnectionManagerImpl>>>,
+    /// Held during a reload, so that concurrent reloads apply one after the other
+    reloading: Mutex<()>,
+}
+
+impl Reloader {
+    pub fn new(
+        settings_file: Option<PathBuf>,
+        defaults: Settings,
+        log_filter: LogFilter,
+        app_server: Arc<RwLock<AppServer<ConnectionManagerImpl>>>,
+    ) -> Self {
+        Self {
+            settings_file,
+            defaults,
+            log_filter,
+            app_server,
+            reloading: Mutex::new(()),
+        }
+    }
+
+    /// Applies the settings file, if there is one, and then the rules of the databases stored
+    /// since they were loaded. An invalid settings file changes nothing.
+    pub async fn reload(&self) -> Result<Reload> {
+        let _reloading = self.reloading.lock().await;
+
+        let settings = match &self.settings_file {
+            Some(path) => {
+                let settings = Settings::load(path)?.or(&self.defaults);
+                self.apply(&settings).await?;
+                info!(?settings, "Applied the settings file");
+                Some(settings)
+            }
+            None => None,
+        };
+
+        let reloaded = match self.app_server.write().await.reload_database_rules().await {
+            Ok(reloaded) => reloaded,
+            // the rules are only stored once the server has an id
+            Err(cluster::Error::IdNotSet) => vec![],
+            Err(e) => return Err(e).context(ReloadingRules),
+        };
+        let databases = reloaded
+            .into_iter()
+            .map(|(db_name, generation)| {
+                info!(db = db_name.as_str(), generation, "Applied stored rules");
+                ReloadedRules {
+                    db_name,
+                    generation,
+                }
+            })
+            .collect();
+
+        Ok(Reload {
+            settings,
+            databases,
+        })
+    }
+
+    async fn apply(&self, settings: &Settings) -> Result<()> {
+        // first, as the directives may be invalid
+        if let Some(directives) = &settings.log_filter {
+            self.log_filter.set(directives).context(SettingLogFilter)?;
+        }
+
+        let mut app_server = self.app_server.write().await;
+        app_server.set_query_parallelism(
+            settings
+                .query_parallelism
+                .unwrap_or(DEFAULT_QUERY_PARALLELISM),
+        );
+        app_server.set_write_limits(WriteLimits {
+            partition_size: settings.partition_write_limit,
+            buffer_size: settings.buffer_write_limit,
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cluster::database_store::ServerDatabases;
+    use data_types::database_rules::DatabaseRules;
+    use object_store::{InMemory, ObjectStore};
+    use storage::{Database, DatabaseStore};
+    use tracing_subscriber::{layer::SubscriberExt, reload, EnvFilter, Registry};
+
+    #[tokio::test]
+    async fn reload_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings_file = dir.path().join("settings.json");
+        let mut app_server = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        app_server.set_id(1);
+        let app_server = Arc::new(RwLock::new(app_server));
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        let storage = ServerDatabases::new(Arc::clone(&app_server), Some(rules));
+
+        // the subscriber is leaked as the filter can only be changed while it is alive
+        let (layer, handle) = reload::Layer::new(EnvFilter::new("info"));
+        Box::leak(Box::new(Registry::default().with(layer)));
+        let log_filter = LogFilter::new(handle);
+
+        let defaults = Settings {
+            log_filter: Some("info".to_string()),
+            buffer_write_limit: Some(1_000_000),
+            ..Default::default()
+        };
+        let reloader = Reloader::new(
+            Some(settings_file.clone()),
+            defaults,
+            log_filter.clone(),
+            Arc::clone(&app_server),
+        );
+
+        fs::write(
+            &settings_file,
+            r#"{"log_filter": "debug", "query_parallelism": 2, "partition_write_limit": 1}"#,
+        )
+        .unwrap();
+        let reload = reloader.reload().await.unwrap();
+        assert_eq!(
+            reload.settings,
+            Some(Settings {
+                log_filter: Some("debug".to_string()),
+                query_parallelism: Some(2),
+                partition_write_limit: Some(1),
+                buffer_write_limit: Some(1_000_000),
+            })
+        );
+        assert!(reload.databases.is_empty());
+        assert_eq!(log_filter.current().unwrap(), "debug");
+        assert_eq!(app_server.read().await.query_parallelism(), 2);
+
+        // the new partition limit throttles the writes to existing databases
+        let db = storage.db_or_create("foo").await.unwrap();
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines("cpu bar=1 10")
+            .map(|l| l.unwrap())
+            .collect();
+        db.write_lines(&lines).await.unwrap();
+        assert!(db.write_lines(&lines).await.is_err());
+
+        // an invalid file changes nothing
+        fs::write(&settings_file, r#"{"log_filter": "debug", "threads": 2}"#).unwrap();
+        let err = reloader.reload().await.unwrap_err();
+        assert!(matches!(err, Error::ParsingSettings { .. }), "{}", err);
+        fs::write(&settings_file, r#"{"log_filter": "write_buffer=loud"}"#).unwrap();
+        let err = reloader.reload().await.unwrap_err();
+        assert!(matches!(err, Error::SettingLogFilter { .. }), "{}", err);
+        assert_eq!(app_server.read().await.query_parallelism(), 2);
+
+        // the settings left out take their values from the command line
+        fs::write(&settings_file, "{}").unwrap();
+        reloader.reload().await.unwrap();
+        assert_eq!(log_filter.current().unwrap(), "info");
+        assert_eq!(
+            app_server.read().await.query_parallelism(),
+            DEFAULT_QUERY_PARALLELISM
+        );
+        db.write_lines(&lines).await.unwrap();
+    }
+}
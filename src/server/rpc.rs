@@ -1,6 +1,305 @@
 //! This module contains gRPC service implementatations
 
+pub mod cache;
 pub mod data;
 pub mod expr;
+pub mod flight;
+pub mod health;
+pub mod ingest_limits;
 pub mod input;
+pub mod management;
+pub mod operations;
+pub mod otlp;
+pub mod query;
+pub mod reflection;
 pub mod storage;
+pub mod write;
+
+use std::{future::Future, net::SocketAddr, sync::Arc};
+
+use ::storage::{exec::Executor as StorageExecutor, DatabaseStore};
+use cluster::{ConnectionManager, Server as AppServer};
+use generated_types::{
+    arrow_flight::protocol::flight_service_server::FlightServiceServer,
+    grpc::{
+        health::v1::health_server::HealthServer,
+        reflection::v1alpha::server_reflection_server::ServerReflectionServer,
+    },
+    i_ox_server::IOxServer,
+    management::{
+        management_service_server::ManagementServiceServer,
+        operations_service_server::OperationsServiceServer,
+    },
+    opentelemetry::proto::collector::metrics::v1::metrics_service_server::MetricsServiceServer,
+    query::query_service_server::QueryServiceServer,
+    storage_server::StorageServer,
+    write::write_service_server::WriteServiceServer,
+    FILE_DESCRIPTOR_SET,
+};
+use snafu::{ResultExt, Snafu};
+use tokio::sync::{watch, RwLock};
+use tonic::transport::ServerTlsConfig;
+
+use super::{
+    auth::{Authorizer, Permission},
+    bucket_mapping::BucketMapping,
+    capture::Capture,
+    governor::Governor,
+    mode::Mode,
+};
+
+use self::{
+    cache::QueryCache,
+    flight::FlightSqlService,
+    health::HealthService,
+    ingest_limits::IngestLimiter,
+    management::ManagementService,
+    operations::OperationsService,
+    otlp::OtlpMetricsService,
+    query::QueryService,
+    reflection::{Descriptors, ReflectionService},
+    storage::GrpcService,
+    write::WriteService,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("gRPC server error:  {}", source))]
+    ServerError { source: tonic::transport::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Instantiate a server listening on the specified address
+/// implementing the IOx, Storage, Write, Query, Flight SQL, Management, Operations and
+/// OpenTelemetry metrics gRPC interfaces, as well as the standard health checking and server
+/// reflection services, the underlying hyper server instance, served over
+/// TLS if `tls` is set. Requests are checked by `authorizer`: the management requests require
+/// the manage permission on the database they target, or on the whole server for the others,
+/// the operations service the manage permission on the whole server, writes and metrics exports the
+/// write permission on their database and queries the read permission on their database. Health
+/// checks and reflection don't need a token.
+/// The services of the other modes than `mode` respond with UNIMPLEMENTED, and health checks
+/// don't know them.
+/// Queries are captured to `capture`, if set, and the SQL queries of the query and Flight SQL
+/// services run once `governor` admits them. The requests to the write and metrics services
+/// are limited by `limiter`, and each connection by its number of requests in flight. The
+/// responses to metadata requests of the storage service are cached in `cache`, if set. Once
+/// `shutdown` resolves, the server stops accepting connections, health checks report that it
+/// no longer serves, and it resolves when the requests in flight have completed.
+pub async fn make_server<T, M>(
+    bind_addr: SocketAddr,
+    tls: Option<ServerTlsConfig>,
+    mode: Mode,
+    authorizer: Arc<Authorizer>,
+    buckets: Arc<BucketMapping>,
+    storage: Arc<T>,
+    executor: Arc<StorageExecutor>,
+    app_server: Arc<RwLock<AppServer<M>>>,
+    capture: Option<Arc<Capture>>,
+    cache: Option<Arc<QueryCache>>,
+    governor: Arc<Governor>,
+    limiter: Arc<IngestLimiter>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()>
+where
+    T: DatabaseStore + 'static,
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    let mut builder = tonic::transport::Server::builder()
+        .concurrency_limit_per_connection(limiter.limits().max_requests_per_connection);
+    if let Some(tls) = tls {
+        builder = builder.tls_config(tls);
+    }
+
+    let descriptors = Arc::new(
+        Descriptors::decode(FILE_DESCRIPTOR_SET)
+            .expect("the file descriptor set built into the server is valid"),
+    );
+    let (serving_tx, serving) = watch::channel(true);
+    let services = descriptors
+        .services()
+        .iter()
+        .filter(|name| serves(mode, name))
+        .cloned();
+    let health = HealthService::new(services, serving);
+    let jobs = Arc::clone(app_server.read().await.jobs());
+    let shutdown = async move {
+        shutdown.await;
+        // the receivers may all be gone already
+        let _ = serving_tx.broadcast(false);
+    };
+
+    builder
+        .trace_fn(|headers| {
+            super::trace::request_span("grpc", &super::trace::request_id(headers), headers)
+        })
+        .add_service(IOxServer::with_interceptor(
+            GrpcService::new(
+                storage.clone(),
+                executor.clone(),
+                authorizer.clone(),
+                buckets.clone(),
+                cache.clone(),
+            ),
+            require_mode(mode, IOX_SERVICE),
+        ))
+        .add_service(StorageServer::with_interceptor(
+            GrpcService::new(
+                storage.clone(),
+                executor.clone(),
+                authorizer.clone(),
+                buckets,
+                cache,
+            ),
+            require_mode(mode, STORAGE_SERVICE),
+        ))
+        .add_service(WriteServiceServer::with_interceptor(
+            WriteService::new(app_server.clone(), authorizer.clone(), limiter.clone()),
+            ingest_limits::rate_limit(limiter.clone()),
+        ))
+        .add_service(MetricsServiceServer::with_interceptor(
+            OtlpMetricsService::new(app_server.clone(), authorizer.clone(), limiter.clone()),
+            {
+                let require_mode = require_mode(mode, METRICS_SERVICE);
+                let rate_limit = ingest_limits::rate_limit(limiter);
+                move |req| require_mode(req).and_then(&rate_limit)
+            },
+        ))
+        .add_service(QueryServiceServer::with_interceptor(
+            QueryService::new(
+                app_server.clone(),
+                authorizer.clone(),
+                capture.clone(),
+                governor.clone(),
+            ),
+            require_mode(mode, QUERY_SERVICE),
+        ))
+        .add_service(FlightServiceServer::with_interceptor(
+            FlightSqlService::new(app_server.clone(), authorizer.clone(), capture, governor),
+            require_mode(mode, FLIGHT_SERVICE),
+        ))
+        .add_service(ManagementServiceServer::new(ManagementService::new(
+            app_server.clone(),
+            authorizer.clone(),
+        )))
+        .add_service(OperationsServiceServer::with_interceptor(
+            OperationsService::new(jobs),
+            require_manage(authorizer),
+        ))
+        .add_service(HealthServer::new(health))
+        .add_service(ServerReflectionServer::new(ReflectionService::new(
+            descriptors,
+        )))
+        .serve_with_shutdown(bind_addr, shutdown)
+        .await
+        .context(ServerError {})
+}
+
+/// The services that store data and answer queries
+const IOX_SERVICE: &str = "influxdata.platform.storage.IOx";
+const STORAGE_SERVICE: &str = "influxdata.platform.storage.Storage";
+const QUERY_SERVICE: &str = "influxdata.iox.query.v1.QueryService";
+const FLIGHT_SERVICE: &str = "arrow.flight.protocol.FlightService";
+
+/// The service that accepts the metrics exported by clients, as writes of their database
+const METRICS_SERVICE: &str = "opentelemetry.proto.collector.metrics.v1.MetricsService";
+
+/// Whether a server running in `mode` serves the service `name`
+fn serves(mode: Mode, name: &str) -> bool {
+    match name {
+        IOX_SERVICE | STORAGE_SERVICE | QUERY_SERVICE | FLIGHT_SERVICE => mode.stores(),
+        METRICS_SERVICE => mode.routes(),
+        _ => true,
+    }
+}
+
+/// Returns an interceptor that rejects the requests to the service `name` as unimplemented if
+/// a server running in `mode` doesn't serve it
+fn require_mode(
+    mode: Mode,
+    name: &'static str,
+) -> impl Fn(tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> + Send + Sync + 'static
+{
+    move |req| {
+        if serves(mode, name) {
+            Ok(req)
+        } else {
+            Err(tonic::Status::unimplemented(format!(
+                "{} is not served by a {}",
+                name, mode
+            )))
+        }
+    }
+}
+
+/// Returns an interceptor that only lets through requests allowed to manage the server
+fn require_manage(
+    authorizer: Arc<Authorizer>,
+) -> impl Fn(tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> + Send + Sync + 'static
+{
+    move |req| {
+        authorizer.authorize_grpc(req.metadata(), Permission::Manage, None)?;
+        Ok(req)
+    }
+}
+
+/// Returns the status of a request that failed with `error` of the server, with `message` as
+/// its message. The errors the request itself causes map to their status, such as NOT_FOUND
+/// for a missing database or RESOURCE_EXHAUSTED for a throttled write, and the other errors to
+/// `other`, which services pass as INTERNAL unless the request is more likely at fault.
+pub fn cluster_status(
+    error: &cluster::Error,
+    message: String,
+    other: tonic::Code,
+) -> tonic::Status {
+    use cluster::Error::*;
+    use tonic::Code;
+
+    let code = match error {
+        IdNotSet
+        | NoLocalBuffer { .. }
+        | EntryWithStrictSchema { .. }
+        | ReadOnlyReplica { .. }
+        | NotOwner { .. }
+        | OwnershipLost { .. }
+        | LeasesDisabled
+        | TemplateInUse { .. }
+        | WalReplayFailed { .. }
+        | WalReplayNotFailed { .. } => Code::FailedPrecondition,
+        DatabaseNotFound { .. }
+        | OpenChunkNotFound { .. }
+        | ClosedChunkNotFound { .. }
+        | RulesGenerationNotFound { .. }
+        | TaskNotFound { .. }
+        | CheckNotFound { .. }
+        | DimensionTableNotFound { .. }
+        | ChunkPolicyNotFound { .. }
+        | TemplateNotFound { .. } => Code::NotFound,
+        DatabaseAlreadyExists { .. }
+        | TaskAlreadyExists { .. }
+        | CheckAlreadyExists { .. }
+        | TemplateAlreadyExists { .. } => Code::AlreadyExists,
+        SchemaViolations { .. }
+        | TimestampViolations { .. }
+        | InvalidEntry { .. }
+        | InvalidTask { .. }
+        | InvalidCheck { .. }
+        | InvalidDimensionTableName { .. }
+        | InvalidDimensionTable { .. }
+        | DimensionTableEmpty { .. }
+        | DimensionTableTooLarge { .. }
+        | InvalidChunkPolicy { .. }
+        | InvalidTemplate { .. }
+        | ApplyingTemplate { .. }
+        | InvalidWalSkip { .. } => Code::InvalidArgument,
+        BufferFull { .. } | WriteThrottled { .. } => Code::ResourceExhausted,
+        IngestPaused { .. } => Code::Unavailable,
+        TableNotAllowed { .. } | DatabaseNotAllowed { .. } => Code::PermissionDenied,
+        DatabaseOwned { .. } => Code::Aborted,
+        CorruptFile { .. } => Code::DataLoss,
+        SystemTablesError { .. } | ScanningChunks { .. } => Code::Internal,
+        _ => other,
+    };
+    tonic::Status::new(code, message)
+}
@@ -0,0 +1,202 @@
+//! This module contains the cache of the responses to metadata requests of the storage gRPC
+//! service, such as tag keys and measurement names, which dashboards issue many times a
+//! minute with the same parameters.
+//!
+//! Responses are cached along with the data version of their database, and are only served
+//! while the database reports the same version, so any write or dropped chunk invalidates
+//! them. They also expire after a maximum age, as the results of a database with a retention
+//! period change as time passes.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use generated_types::StringValuesResponse;
+
+/// The default maximum age of the cached responses
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Identifies a cached response: the database it was computed from and a description of the
+/// request, including all of its parameters
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    db_name: String,
+    request: String,
+}
+
+impl CacheKey {
+    pub fn new(db_name: impl Into<String>, request: impl Into<String>) -> Self {
+        Self {
+            db_name: db_name.into(),
+            request: request.into(),
+        }
+    }
+
+    pub fn db_name(&self) -> &str {
+        &self.db_name
+    }
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+    data_version: u64,
+    cached_at: Instant,
+    response: StringValuesResponse,
+}
+
+/// A cache of at most `max_entries` metadata responses
+#[derive(Debug)]
+pub struct QueryCache {
+    max_entries: usize,
+    max_age: Duration,
+    entries: Mutex<HashMap<CacheKey, CachedResponse>>,
+}
+
+impl QueryCache {
+    pub fn new(max_entries: usize, max_age: Duration) -> Self {
+        Self {
+            max_entries,
+            max_age,
+            entries: Default::default(),
+        }
+    }
+
+    /// Returns the response cached for `key`, if it was computed from data of `data_version`
+    /// and hasn't expired
+    pub fn get(&self, key: &CacheKey, data_version: u64) -> Option<StringValuesResponse> {
+        let mut entries = self.entries.lock().expect("mutex poisoned");
+
+        let response = match entries.get(key) {
+            Some(cached)
+                if cached.data_version == data_version
+                    && cached.cached_at.elapsed() < self.max_age =>
+            {
+                Some(cached.response.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+
+        let result = if response.is_some() { "hit" } else { "miss" };
+        metrics::registry()
+            .counter(
+                "query_cache_requests_total",
+                "Metadata requests looked up in the query cache",
+                &[("result", result)],
+            )
+            .inc();
+
+        response
+    }
+
+    /// Caches `response` for `key`, as computed from data of `data_version`. Once the cache is
+    /// full, the entries computed from other data versions are evicted first, then the oldest.
+    pub fn insert(&self, key: CacheKey, data_version: u64, response: StringValuesResponse) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().expect("mutex poisoned");
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let max_age = self.max_age;
+            entries.retain(|k, cached| {
+                k.db_name != key.db_name
+                    || (cached.data_version == data_version && cached.cached_at.elapsed() < max_age)
+            });
+        }
+        while entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, cached)| cached.cached_at)
+                .map(|(k, _)| k.clone())
+                .expect("cache is not empty");
+            entries.remove(&oldest);
+        }
+
+        entries.insert(
+            key,
+            CachedResponse {
+                data_version,
+                cached_at: Instant::now(),
+                response,
+            },
+        );
+    }
+
+    /// The number of responses currently cached
+    pub fn len(&self) -> usize {
+        self.entries.lock().expect("mutex poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(values: &[&str]) -> StringValuesResponse {
+        StringValuesResponse {
+            values: values.iter().map(|v| v.as_bytes().to_vec()).collect(),
+        }
+    }
+
+    #[test]
+    fn invalidated_by_data_version() {
+        let cache = QueryCache::new(10, DEFAULT_MAX_AGE);
+        let key = CacheKey::new("db", "tag_keys");
+        assert_eq!(cache.get(&key, 1), None);
+
+        cache.insert(key.clone(), 1, response(&["host"]));
+        assert_eq!(cache.get(&key, 1), Some(response(&["host"])));
+        assert_eq!(cache.get(&CacheKey::new("other", "tag_keys"), 1), None);
+
+        // the data changed since
+        assert_eq!(cache.get(&key, 2), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn expires() {
+        let cache = QueryCache::new(10, Duration::from_millis(0));
+        let key = CacheKey::new("db", "tag_keys");
+        cache.insert(key.clone(), 1, response(&["host"]));
+        assert_eq!(cache.get(&key, 1), None);
+    }
+
+    #[test]
+    fn evicts_stale_then_oldest() {
+        let cache = QueryCache::new(2, DEFAULT_MAX_AGE);
+        cache.insert(CacheKey::new("db", "a"), 1, response(&["a"]));
+        cache.insert(CacheKey::new("db", "b"), 2, response(&["b"]));
+
+        // "a" was computed from an older version of the data of db
+        std::thread::sleep(Duration::from_millis(1));
+        cache.insert(CacheKey::new("db", "c"), 2, response(&["c"]));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(
+            cache.get(&CacheKey::new("db", "b"), 2),
+            Some(response(&["b"]))
+        );
+
+        // all current, so the oldest goes
+        cache.insert(CacheKey::new("db", "d"), 2, response(&["d"]));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&CacheKey::new("db", "b"), 2), None);
+        assert_eq!(
+            cache.get(&CacheKey::new("db", "d"), 2),
+            Some(response(&["d"]))
+        );
+
+        let cache = QueryCache::new(0, DEFAULT_MAX_AGE);
+        cache.insert(CacheKey::new("db", "a"), 1, response(&["a"]));
+        assert!(cache.is_empty());
+    }
+}
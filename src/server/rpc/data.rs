@@ -8,9 +8,12 @@ use arrow_deps::arrow::{
     datatypes::DataType as ArrowDataType,
 };
 
-use storage::exec::{
-    fieldlist::FieldList,
-    seriesset::{GroupDescription, GroupedSeriesSetItem, SeriesSet},
+use storage::{
+    exec::{
+        fieldlist::FieldList,
+        seriesset::{GroupDescription, GroupedSeriesSetItem, SeriesSet},
+    },
+    quantile::TDigest,
 };
 
 use generated_types::{
@@ -47,9 +50,10 @@ pub fn tag_keys_to_byte_vecs(tag_keys: Arc<BTreeSet<String>>) -> Vec<Vec<u8>> {
     specials_iter.chain(tag_keys_iter).collect()
 }
 
-/// Convert `SeriesSet` into a form suitable for gRPC transport
+/// Convert the `SeriesSet`s of a single series (e.g. from several
+/// chunks) into a form suitable for gRPC transport
 ///
-/// Each `SeriesSet` gets converted into this pattern:
+/// The `SeriesSet`s get converted into this pattern:
 ///
 /// ```
 /// (SeriesFrame for field1)
@@ -59,16 +63,102 @@ pub fn tag_keys_to_byte_vecs(tag_keys: Arc<BTreeSet<String>>) -> Vec<Vec<u8>> {
 /// (....)
 /// ```
 ///
-/// The specific type of (*Points) depends on the type of field column.
-pub fn series_set_to_read_response(series_set: SeriesSet) -> Result<ReadResponse> {
-    let frames = series_set_to_frames(series_set)?;
+/// The points of each field are merged in timestamp order, so Flux sees
+/// each field of the series exactly once. The specific type of (*Points)
+/// depends on the type of field column.
+pub fn series_sets_to_read_response(series_sets: Vec<SeriesSet>) -> Result<ReadResponse> {
+    let frames = series_sets_to_frames(&series_sets)?;
     Ok(ReadResponse { frames })
 }
 
+/// Replaces the points of each numeric field of `response` with a single point: the
+/// approximate `percentile` of their values, at the time of the last of them. The other fields
+/// are left out, as their values have no percentile.
+pub fn approx_percentile_response(response: ReadResponse, percentile: f64) -> ReadResponse {
+    let mut frames = Vec::with_capacity(response.frames.len());
+    let mut series = None;
+    for frame in response.frames {
+        let (timestamps, values) = match frame.data {
+            Some(Data::Series(series_frame)) => {
+                series = Some(series_frame);
+                continue;
+            }
+            Some(Data::FloatPoints(points)) => (points.timestamps, points.values),
+            Some(Data::IntegerPoints(points)) => (
+                points.timestamps,
+                points.values.into_iter().map(|v| v as f64).collect(),
+            ),
+            Some(Data::StringPoints(_)) | Some(Data::BooleanPoints(_)) => {
+                series = None;
+                continue;
+            }
+            data => {
+                frames.push(Frame { data });
+                continue;
+            }
+        };
+        let series = match series.take() {
+            Some(series) => series,
+            None => continue,
+        };
+
+        let mut digest = TDigest::default();
+        for value in values {
+            digest.add(value);
+        }
+        let (timestamps, values) = match (timestamps.last(), digest.quantile(percentile)) {
+            (Some(&time), Some(value)) => (vec![time], vec![value]),
+            _ => (vec![], vec![]),
+        };
+        frames.push(Frame {
+            data: Some(Data::Series(SeriesFrame {
+                data_type: DataType::Float as i32,
+                ..series
+            })),
+        });
+        frames.push(Frame {
+            data: Some(Data::FloatPoints(FloatPointsFrame { timestamps, values })),
+        });
+    }
+    ReadResponse { frames }
+}
+
 fn series_set_to_frames(series_set: SeriesSet) -> Result<Vec<Frame>> {
+    series_sets_to_frames(&[series_set])
+}
+
+fn series_sets_to_frames(series_sets: &[SeriesSet]) -> Result<Vec<Frame>> {
+    let first = match series_sets.first() {
+        Some(first) => first,
+        None => return Ok(vec![]),
+    };
+
+    // the points of each field (name and type), in order of first appearance
+    let mut fields: Vec<(String, DataType, Vec<Data>)> = Vec::new();
+    for series_set in series_sets {
+        let schema = series_set.batch.schema();
+        for &field_index in series_set.field_indices.iter() {
+            let name = schema.field(field_index).name();
+            let data_type = data_type(series_set.batch.column(field_index))?;
+            let points = field_points(series_set, field_index)?;
+
+            match fields
+                .iter_mut()
+                .find(|(n, t, _)| n == name && *t == data_type)
+            {
+                Some((_, _, field_points)) => field_points.push(points),
+                None => fields.push((name.clone(), data_type, vec![points])),
+            }
+        }
+    }
+
     let mut data_records = Vec::new();
-    for field_index in series_set.field_indices.iter() {
-        field_to_data(&mut data_records, &series_set, *field_index)?
+    for (name, data_type, points) in fields {
+        data_records.push(Data::Series(SeriesFrame {
+            tags: convert_tags(first.table_name.as_ref(), &name, &first.tags),
+            data_type: data_type as i32,
+        }));
+        data_records.push(merge_points(points));
     }
 
     let frames = data_records
@@ -147,25 +237,15 @@ fn data_type(array: &ArrayRef) -> Result<DataType> {
 }
 
 // Convert and append a single field to a sequence of frames
-fn field_to_data(frames: &mut Vec<Data>, series_set: &SeriesSet, field_index: usize) -> Result<()> {
+/// Returns the points of the field at `field_index` of `series_set`
+fn field_points(series_set: &SeriesSet, field_index: usize) -> Result<Data> {
     let batch = &series_set.batch;
-    let schema = batch.schema();
 
     let array = batch.column(field_index);
 
     let start_row = series_set.start_row;
     let num_rows = series_set.num_rows;
 
-    let series_frame = SeriesFrame {
-        tags: convert_tags(
-            series_set.table_name.as_ref(),
-            schema.field(field_index).name(),
-            &series_set.tags,
-        ),
-        data_type: data_type(array)? as i32,
-    };
-    frames.push(Data::Series(series_frame));
-
     let timestamps = batch
         .column(series_set.timestamp_index)
         .as_any()
@@ -173,7 +253,7 @@ fn field_to_data(frames: &mut Vec<Data>, series_set: &SeriesSet, field_index: us
         .unwrap()
         .extract_values(start_row, num_rows);
 
-    frames.push(match array.data_type() {
+    Ok(match array.data_type() {
         ArrowDataType::Utf8 => {
             let values = array
                 .as_any()
@@ -212,8 +292,61 @@ fn field_to_data(frames: &mut Vec<Data>, series_set: &SeriesSet, field_index: us
             }
             .fail();
         }
-    });
-    Ok(())
+    })
+}
+
+/// Merges points frames of the same type, each ordered by timestamp, into a
+/// single frame ordered by timestamp
+fn merge_points(points: Vec<Data>) -> Data {
+    let mut points = points.into_iter();
+    let first = points.next().expect("at least one points frame");
+
+    points.fold(first, |merged, next| match (merged, next) {
+        (Data::StringPoints(a), Data::StringPoints(b)) => {
+            let (timestamps, values) = merge_sorted(a.timestamps, a.values, b.timestamps, b.values);
+            Data::StringPoints(StringPointsFrame { timestamps, values })
+        }
+        (Data::FloatPoints(a), Data::FloatPoints(b)) => {
+            let (timestamps, values) = merge_sorted(a.timestamps, a.values, b.timestamps, b.values);
+            Data::FloatPoints(FloatPointsFrame { timestamps, values })
+        }
+        (Data::IntegerPoints(a), Data::IntegerPoints(b)) => {
+            let (timestamps, values) = merge_sorted(a.timestamps, a.values, b.timestamps, b.values);
+            Data::IntegerPoints(IntegerPointsFrame { timestamps, values })
+        }
+        (Data::BooleanPoints(a), Data::BooleanPoints(b)) => {
+            let (timestamps, values) = merge_sorted(a.timestamps, a.values, b.timestamps, b.values);
+            Data::BooleanPoints(BooleanPointsFrame { timestamps, values })
+        }
+        (merged, next) => unreachable!("merging points {:?} and {:?}", merged, next),
+    })
+}
+
+/// Merges two sequences of (timestamp, value) ordered by timestamp
+fn merge_sorted<T>(
+    a_timestamps: Vec<i64>,
+    a_values: Vec<T>,
+    b_timestamps: Vec<i64>,
+    b_values: Vec<T>,
+) -> (Vec<i64>, Vec<T>) {
+    let mut timestamps = Vec::with_capacity(a_timestamps.len() + b_timestamps.len());
+    let mut values = Vec::with_capacity(timestamps.capacity());
+
+    let mut a = a_timestamps.into_iter().zip(a_values).peekable();
+    let mut b = b_timestamps.into_iter().zip(b_values).peekable();
+    loop {
+        let from_a = match (a.peek(), b.peek()) {
+            (Some((a_time, _)), Some((b_time, _))) => a_time <= b_time,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => break,
+        };
+        let (timestamp, value) = if from_a { a.next() } else { b.next() }.unwrap();
+        timestamps.push(timestamp);
+        values.push(value);
+    }
+
+    (timestamps, values)
 }
 
 // Convert the tag=value pairs from the series set to the correct gRPC
@@ -338,7 +471,7 @@ mod tests {
         };
 
         let response =
-            series_set_to_read_response(series_set).expect("Correctly converted series set");
+            series_sets_to_read_response(vec![series_set]).expect("Correctly converted series set");
         println!("Response is: {:#?}", response);
 
         assert_eq!(response.frames.len(), 8); // 2 per field x 4 fields = 8
@@ -367,6 +500,80 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_approx_percentile_conversion() {
+        let series_set = SeriesSet {
+            table_name: Arc::new("the_table".into()),
+            tags: vec![(Arc::new("tag1".into()), Arc::new("val1".into()))],
+            timestamp_index: 4,
+            field_indices: Arc::new(vec![0, 1, 2, 3]),
+            start_row: 0,
+            num_rows: 4,
+            batch: make_record_batch(),
+        };
+
+        let response =
+            series_sets_to_read_response(vec![series_set]).expect("Correctly converted series set");
+        let response = approx_percentile_response(response, 0.5);
+
+        let dumped_frames = response
+            .frames
+            .iter()
+            .map(|f| dump_frame(f))
+            .collect::<Vec<_>>();
+
+        // the string and boolean fields have no percentile
+        let expected_frames = vec![
+            "SeriesFrame, tags: _field=int_field,_measurement=the_table,tag1=val1, type: 0",
+            "FloatPointsFrame, timestamps: [4000], values: \"2.5\"",
+            "SeriesFrame, tags: _field=float_field,_measurement=the_table,tag1=val1, type: 0",
+            "FloatPointsFrame, timestamps: [4000], values: \"25.1\"",
+        ];
+
+        assert_eq!(
+            dumped_frames, expected_frames,
+            "Expected:\n{:#?}\nActual:\n{:#?}",
+            expected_frames, dumped_frames
+        );
+    }
+
+    #[test]
+    fn test_series_sets_merge() {
+        // the same series from two chunks, with overlapping times
+        let make_series_set = |start_row, num_rows| SeriesSet {
+            table_name: Arc::new("the_table".into()),
+            tags: vec![(Arc::new("tag1".into()), Arc::new("val1".into()))],
+            timestamp_index: 4,
+            field_indices: Arc::new(vec![1, 2]),
+            start_row,
+            num_rows,
+            batch: make_record_batch(),
+        };
+
+        let response =
+            series_sets_to_read_response(vec![make_series_set(0, 2), make_series_set(1, 3)])
+                .expect("Correctly converted series sets");
+
+        let dumped_frames = response
+            .frames
+            .iter()
+            .map(|f| dump_frame(f))
+            .collect::<Vec<_>>();
+
+        let expected_frames = vec![
+            "SeriesFrame, tags: _field=int_field,_measurement=the_table,tag1=val1, type: 1",
+            "IntegerPointsFrame, timestamps: [1000, 2000, 2000, 3000, 4000], values: \"1,2,2,3,4\"",
+            "SeriesFrame, tags: _field=float_field,_measurement=the_table,tag1=val1, type: 0",
+            "FloatPointsFrame, timestamps: [1000, 2000, 2000, 3000, 4000], values: \"10.1,20.1,20.1,30.1,40.1\"",
+        ];
+
+        assert_eq!(
+            dumped_frames, expected_frames,
+            "Expected:\n{:#?}\nActual:\n{:#?}",
+            expected_frames, dumped_frames
+        );
+    }
+
     #[test]
     fn test_group_group_conversion() {
         let group_description = GroupDescription {
@@ -12,7 +12,7 @@ use generated_types::{
     Node as RPCNode, Predicate as RPCPredicate,
 };
 use snafu::{ResultExt, Snafu};
-use storage::predicate::PredicateBuilder;
+use storage::predicate::{regex_match_expr, PredicateBuilder};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -34,13 +34,20 @@ pub enum Error {
     ))]
     RegExpLiteralNotSupported { regexp: String },
 
-    #[snafu(display("Error creating predicate: Regular expression predicates are not supported"))]
-    RegExpNotSupported {},
+    #[snafu(display(
+        "Error creating predicate: Regular expression comparisons must compare an expression with a regular expression"
+    ))]
+    UnsupportedRegExpComparison {},
 
     #[snafu(display(
-        "Error creating predicate: Not Regular expression predicates are not supported"
+        "Error creating predicate: Invalid regular expression '{}': {}",
+        pattern,
+        source
     ))]
-    NotRegExpNotSupported {},
+    InvalidRegExp {
+        pattern: String,
+        source: regex::Error,
+    },
 
     #[snafu(display("Error creating predicate: StartsWith comparisons not supported"))]
     StartsWithNotSupported {},
@@ -334,15 +341,46 @@ impl SpecialTagKeys for String {
 // converts a Node from the RPC layer into a datafusion logical expr
 fn convert_node_to_expr(node: RPCNode) -> Result<Expr> {
     let RPCNode { children, value } = node;
+    let value = value.expect("Normalization removed all None values");
+
+    // regular expressions only appear as the right hand side of a regex comparison, so these
+    // are converted before their children
+    if let RPCValue::Comparison(comparison) = value {
+        if comparison == RPCComparison::Regex as i32 {
+            return build_regex_node(children, true);
+        } else if comparison == RPCComparison::NotRegex as i32 {
+            return build_regex_node(children, false);
+        }
+    }
+
     let inputs = children
         .into_iter()
         .map(convert_node_to_expr)
         .collect::<Result<Vec<_>>>()?;
 
-    let value = value.expect("Normalization removed all None values");
     build_node(value, inputs)
 }
 
+/// Creates an expr from a regex comparison node, `lhs =~ /pattern/`, or `lhs !~ /pattern/` if
+/// `matches` is false
+fn build_regex_node(children: Vec<RPCNode>, matches: bool) -> Result<Expr> {
+    let mut children = children.into_iter();
+    match (children.next(), children.next(), children.next()) {
+        (
+            Some(lhs),
+            Some(RPCNode {
+                value: Some(RPCValue::RegexValue(pattern)),
+                ..
+            }),
+            None,
+        ) => {
+            let lhs = convert_node_to_expr(lhs)?;
+            regex_match_expr(lhs, &pattern, matches).context(InvalidRegExp { pattern: &pattern })
+        }
+        _ => UnsupportedRegExpComparison {}.fail(),
+    }
+}
+
 fn make_tag_name(tag_name: Vec<u8>) -> Result<String> {
     // These should have been handled at a higher level -- if we get
     // here it is too late
@@ -406,10 +444,6 @@ fn build_comparison_node(comparison: i32, inputs: Vec<Expr>) -> Result<Expr> {
         build_binary_expr(Operator::NotEq, inputs)
     } else if comparison == RPCComparison::StartsWith as i32 {
         StartsWithNotSupported {}.fail()
-    } else if comparison == RPCComparison::Regex as i32 {
-        RegExpNotSupported {}.fail()
-    } else if comparison == RPCComparison::NotRegex as i32 {
-        NotRegExpNotSupported {}.fail()
     } else if comparison == RPCComparison::Lt as i32 {
         build_binary_expr(Operator::Lt, inputs)
     } else if comparison == RPCComparison::Lte as i32 {
@@ -739,6 +773,85 @@ mod tests {
         make_tag_ref_node(&[0], field_name)
     }
 
+    #[test]
+    fn test_convert_predicate_regex() {
+        // host =~ /^server/ OR region !~ /west/
+        let regex = |tag_name: &str, comparison: RPCComparison, pattern: &str| RPCNode {
+            children: vec![
+                RPCNode {
+                    children: vec![],
+                    value: Some(RPCValue::TagRefValue(tag_name.into())),
+                },
+                RPCNode {
+                    children: vec![],
+                    value: Some(RPCValue::RegexValue(pattern.into())),
+                },
+            ],
+            value: Some(RPCValue::Comparison(comparison as i32)),
+        };
+        let rpc_predicate = RPCPredicate {
+            root: Some(make_or_node(
+                regex("host", RPCComparison::Regex, "^server"),
+                regex("region", RPCComparison::NotRegex, "west"),
+            )),
+        };
+
+        let predicate = PredicateBuilder::default()
+            .rpc_predicate(Some(rpc_predicate))
+            .expect("successfully converting predicate")
+            .build();
+
+        assert_eq!(predicate.exprs.len(), 1);
+        match &predicate.exprs[0] {
+            Expr::BinaryExpr {
+                left,
+                op: Operator::Or,
+                right,
+            } => {
+                for (expr, name, column) in &[
+                    (left, "regex_match", "host"),
+                    (right, "regex_not_match", "region"),
+                ] {
+                    match expr.as_ref() {
+                        Expr::ScalarUDF { fun, args } => {
+                            assert_eq!(fun.name, *name);
+                            assert!(matches!(&args[..], [Expr::Column(c)] if c == column));
+                        }
+                        other => panic!("unexpected expression: {:?}", other),
+                    }
+                }
+            }
+            other => panic!("unexpected expression: {:?}", other),
+        }
+
+        // an invalid regular expression
+        let rpc_predicate = RPCPredicate {
+            root: Some(regex("host", RPCComparison::Regex, "(")),
+        };
+        let res = PredicateBuilder::default().rpc_predicate(Some(rpc_predicate));
+        let actual_error = error_result_to_string(res);
+        assert!(
+            actual_error.contains("Invalid regular expression '('"),
+            "{}",
+            actual_error
+        );
+
+        // a regular expression compared to something else than a regular expression
+        let rpc_predicate = RPCPredicate {
+            root: Some(RPCNode {
+                children: vec![make_host_comparison().0],
+                value: Some(RPCValue::Comparison(RPCComparison::Regex as i32)),
+            }),
+        };
+        let res = PredicateBuilder::default().rpc_predicate(Some(rpc_predicate));
+        let actual_error = error_result_to_string(res);
+        assert!(
+            actual_error.contains("must compare an expression with a regular expression"),
+            "{}",
+            actual_error
+        );
+    }
+
     /// returns (RPCNode, and expected_expr for the "host > 5.0")
     fn make_host_comparison() -> (RPCNode, Expr) {
         // host > 5.0
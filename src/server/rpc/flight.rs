@@ -0,0 +1,862 @@
+//! This module contains the implementation of the Arrow Flight SQL service, which lets the
+//! JDBC and ODBC Flight SQL drivers, and the BI tools built on them such as Tableau and
+//! DBeaver, run SQL queries against the databases of a `cluster::Server` and list their
+//! tables.
+//!
+//! Clients name the database in the `x-iox-database` header, or as the catalog of the
+//! metadata commands. GetFlightInfo answers with a single endpoint whose ticket is the
+//! command itself, which DoGet runs. The schema of the results of a query is only known once
+//! it has run, so the FlightInfo of a statement has an empty schema: the schema is the first
+//! message of the stream returned by DoGet.
+
+use std::sync::Arc;
+
+use arrow_deps::arrow::{
+    array::{ArrayRef, BinaryArray, StringArray},
+    datatypes::{DataType, Field, Schema, SchemaRef},
+    error::ArrowError,
+    ipc::{self, writer::StreamWriter},
+    record_batch::RecordBatch,
+};
+use cluster::{system_tables, ConnectionManager, Server as AppServer};
+use generated_types::arrow_flight::protocol::{
+    self as flight, flight_service_server,
+    sql::{
+        CommandGetCatalogs, CommandGetDbSchemas, CommandGetTableTypes, CommandGetTables,
+        CommandStatementQuery,
+    },
+    Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightEndpoint, FlightInfo,
+    HandshakeRequest, HandshakeResponse, PutResult, SchemaResult, Ticket,
+};
+use prost::Message;
+use snafu::{OptionExt, ResultExt, Snafu};
+use storage::access::RowAccess;
+use tokio::sync::RwLock;
+use tonic::{metadata::MetadataMap, Code, Request, Response, Status, Streaming};
+use tracing::debug;
+
+use super::{cluster_status, otlp::DATABASE_HEADER, query::encode};
+use crate::server::{
+    auth::{Authorizer, Permission},
+    capture::{self, Capture},
+    governor::{self, Governor, Priority},
+};
+
+/// The prefix of the type URLs of the Flight SQL commands packed in a `google.protobuf.Any`
+const TYPE_URL_PREFIX: &str = "type.googleapis.com/arrow.flight.protocol.sql.";
+
+/// The schema the tables of a database are listed in
+const DEFAULT_SCHEMA: &str = "public";
+
+/// The schema the system tables are listed in
+const SYSTEM_SCHEMA: &str = "system";
+
+const TABLE: &str = "TABLE";
+const SYSTEM_TABLE: &str = "SYSTEM TABLE";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("The {} header or a catalog is required", DATABASE_HEADER))]
+    MissingDatabaseName,
+
+    #[snafu(display("Invalid Flight SQL command: {}", reason))]
+    InvalidCommand { reason: String },
+
+    #[snafu(display("Unsupported Flight SQL command {}", type_url))]
+    UnsupportedCommand { type_url: String },
+
+    #[snafu(display("The schema of the results of a query is only known once it runs"))]
+    UnknownSchema,
+
+    #[snafu(display("Error admitting query: {}", source))]
+    Admitting { source: governor::Error },
+
+    #[snafu(display("Error running query: {}", source))]
+    Querying { source: cluster::Error },
+
+    #[snafu(display("Error encoding the results: {}", source))]
+    EncodingResults { source: ArrowError },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Converts the error into the status of the failed Flight SQL request, UNIMPLEMENTED for
+    /// the commands of Flight SQL the service doesn't support
+    fn to_status(&self) -> tonic::Status {
+        match &self {
+            Self::MissingDatabaseName | Self::InvalidCommand { .. } => {
+                Status::invalid_argument(self.to_string())
+            }
+            Self::UnsupportedCommand { .. } | Self::UnknownSchema => {
+                Status::unimplemented(self.to_string())
+            }
+            Self::Admitting { source } => match source {
+                governor::Error::UnknownPriority { .. } => {
+                    Status::invalid_argument(self.to_string())
+                }
+                governor::Error::Rejected { .. } => Status::resource_exhausted(self.to_string()),
+            },
+            // planning errors are caused by the query
+            Self::Querying { source } => {
+                cluster_status(source, self.to_string(), Code::InvalidArgument)
+            }
+            Self::EncodingResults { .. } => Status::internal(self.to_string()),
+        }
+    }
+}
+
+/// The Flight SQL commands supported, decoded from the `google.protobuf.Any` they are
+/// packed in
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    GetCatalogs,
+    GetDbSchemas(CommandGetDbSchemas),
+    GetTables(CommandGetTables),
+    GetTableTypes,
+    StatementQuery(CommandStatementQuery),
+}
+
+impl Command {
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let any = prost_types::Any::decode(bytes).map_err(|e| Error::InvalidCommand {
+            reason: e.to_string(),
+        })?;
+        if !any.type_url.starts_with(TYPE_URL_PREFIX) {
+            return UnsupportedCommand {
+                type_url: any.type_url,
+            }
+            .fail();
+        }
+        let name = &any.type_url[TYPE_URL_PREFIX.len()..];
+        let value = &any.value[..];
+        let invalid = |e: prost::DecodeError| Error::InvalidCommand {
+            reason: e.to_string(),
+        };
+
+        let command = match name {
+            "CommandGetCatalogs" => {
+                CommandGetCatalogs::decode(value).map_err(invalid)?;
+                Self::GetCatalogs
+            }
+            "CommandGetDbSchemas" => {
+                Self::GetDbSchemas(CommandGetDbSchemas::decode(value).map_err(invalid)?)
+            }
+            "CommandGetTables" => {
+                Self::GetTables(CommandGetTables::decode(value).map_err(invalid)?)
+            }
+            "CommandGetTableTypes" => {
+                CommandGetTableTypes::decode(value).map_err(invalid)?;
+                Self::GetTableTypes
+            }
+            "CommandStatementQuery" => {
+                Self::StatementQuery(CommandStatementQuery::decode(value).map_err(invalid)?)
+            }
+            _ => {
+                return UnsupportedCommand {
+                    type_url: &any.type_url,
+                }
+                .fail()
+            }
+        };
+        Ok(command)
+    }
+
+    /// The catalog the command names, empty if none
+    fn catalog(&self) -> &str {
+        match self {
+            Self::GetDbSchemas(command) => &command.catalog,
+            Self::GetTables(command) => &command.catalog,
+            _ => "",
+        }
+    }
+
+    /// The schema of the results of the command, if known before running it
+    fn schema(&self) -> Option<SchemaRef> {
+        let utf8 = |name, nullable| Field::new(name, DataType::Utf8, nullable);
+        let fields = match self {
+            Self::GetCatalogs => vec![utf8("catalog_name", false)],
+            Self::GetDbSchemas(_) => {
+                vec![utf8("catalog_name", true), utf8("db_schema_name", false)]
+            }
+            Self::GetTables(command) => {
+                let mut fields = vec![
+                    utf8("catalog_name", true),
+                    utf8("db_schema_name", true),
+                    utf8("table_name", false),
+                    utf8("table_type", false),
+                ];
+                if command.include_schema {
+                    fields.push(Field::new("table_schema", DataType::Binary, false));
+                }
+                fields
+            }
+            Self::GetTableTypes => vec![utf8("table_type", false)],
+            Self::StatementQuery(_) => return None,
+        };
+        Some(Arc::new(Schema::new(fields)))
+    }
+}
+
+/// A table listed by GetTables
+#[derive(Debug)]
+struct TableInfo {
+    schema: &'static str,
+    name: String,
+    table_type: &'static str,
+}
+
+/// The responses of the streaming methods, which are all computed up front
+type FlightStream<T> = futures::stream::Iter<std::vec::IntoIter<Result<T, Status>>>;
+
+/// Implements the Arrow Flight SQL service on top of the local databases of a
+/// `cluster::Server`. Commands require the read permission on the database they run
+/// against; GetCatalogs only lists the databases the client can read.
+#[derive(Debug)]
+pub struct FlightSqlService<M: ConnectionManager> {
+    app_server: Arc<RwLock<AppServer<M>>>,
+    authorizer: Arc<Authorizer>,
+    capture: Option<Arc<Capture>>,
+    governor: Arc<Governor>,
+}
+
+impl<M> FlightSqlService<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    /// Create a new FlightSqlService for the databases of `app_server`, which captures the
+    /// queries it receives to `capture`, if set, and runs them once `governor` admits them
+    pub fn new(
+        app_server: Arc<RwLock<AppServer<M>>>,
+        authorizer: Arc<Authorizer>,
+        capture: Option<Arc<Capture>>,
+        governor: Arc<Governor>,
+    ) -> Self {
+        Self {
+            app_server,
+            authorizer,
+            capture,
+            governor,
+        }
+    }
+
+    /// Decodes the command of a request and checks the client may run it, returning it with
+    /// the database it runs against, if any, and the rows of the database it may read
+    fn authorize(
+        &self,
+        metadata: &MetadataMap,
+        cmd: &[u8],
+    ) -> Result<(Command, Option<String>, RowAccess), Status> {
+        let command = Command::decode(cmd).map_err(|e| e.to_status())?;
+        match command {
+            Command::GetCatalogs | Command::GetTableTypes => {
+                Ok((command, None, RowAccess::default()))
+            }
+            _ => {
+                let db_name =
+                    database_name(metadata, command.catalog()).map_err(|e| e.to_status())?;
+                let access = self
+                    .authorizer
+                    .access_grpc(metadata, Permission::Read, &db_name)?;
+                Ok((command, Some(db_name), access))
+            }
+        }
+    }
+
+    /// Runs `command`, returning its results as the Flight data of an Arrow IPC stream
+    async fn run(
+        &self,
+        metadata: &MetadataMap,
+        command: Command,
+        db_name: Option<String>,
+        access: &RowAccess,
+    ) -> Result<Vec<FlightData>> {
+        let db_name = db_name.as_deref().unwrap_or_default();
+        let schema = command.schema();
+        let columns: Vec<ArrayRef> = match command {
+            Command::GetCatalogs => {
+                let app_server = self.app_server.read().await;
+                let names: Vec<_> = app_server
+                    .db_names()
+                    .into_iter()
+                    .filter(|name| {
+                        self.authorizer
+                            .authorize_grpc(metadata, Permission::Read, Some(name))
+                            .is_ok()
+                    })
+                    .collect();
+                vec![strings(names.iter().map(String::as_str))]
+            }
+            Command::GetDbSchemas(command) => {
+                let schemas: Vec<_> = [DEFAULT_SCHEMA, SYSTEM_SCHEMA]
+                    .iter()
+                    .copied()
+                    .filter(|schema| matches_pattern(&command.db_schema_filter_pattern, schema))
+                    .collect();
+                vec![
+                    strings(schemas.iter().map(|_| db_name)),
+                    strings(schemas.iter().copied()),
+                ]
+            }
+            Command::GetTables(command) => {
+                let tables: Vec<_> = self
+                    .tables(db_name, access)
+                    .await?
+                    .into_iter()
+                    .filter(|table| {
+                        matches_pattern(&command.db_schema_filter_pattern, table.schema)
+                            && matches_pattern(&command.table_name_filter_pattern, &table.name)
+                            && (command.table_types.is_empty()
+                                || command.table_types.iter().any(|t| t == table.table_type))
+                    })
+                    .collect();
+
+                let mut columns = vec![
+                    strings(tables.iter().map(|_| db_name)),
+                    strings(tables.iter().map(|table| table.schema)),
+                    strings(tables.iter().map(|table| table.name.as_str())),
+                    strings(tables.iter().map(|table| table.table_type)),
+                ];
+                if command.include_schema {
+                    let mut schemas = vec![];
+                    for table in &tables {
+                        schemas.push(self.table_schema(db_name, access, table).await?);
+                    }
+                    let schemas: Vec<_> = schemas.iter().map(Vec::as_slice).collect();
+                    columns.push(Arc::new(BinaryArray::from(schemas)));
+                }
+                columns
+            }
+            Command::GetTableTypes => vec![strings([TABLE, SYSTEM_TABLE].iter().copied())],
+            Command::StatementQuery(command) => {
+                debug!(
+                    "running Flight SQL query against {}: {}",
+                    db_name, command.query
+                );
+                if let Some(capture) = &self.capture {
+                    capture.record(
+                        db_name,
+                        capture::Request::Sql {
+                            query: command.query.clone(),
+                        },
+                    );
+                }
+                let (priority, query) =
+                    Priority::of(governor::metadata_priority(metadata), &command.query)
+                        .context(Admitting)?;
+                let _permit = self.governor.admit(priority).await.context(Admitting)?;
+                let results = self
+                    .app_server
+                    .read()
+                    .await
+                    .query_local_across(db_name, query, |name| {
+                        if name == db_name {
+                            Some(access.clone())
+                        } else {
+                            self.authorizer
+                                .access_grpc(metadata, Permission::Read, name)
+                                .ok()
+                        }
+                    })
+                    .await
+                    .context(Querying)?;
+                return flight_data(&results).context(EncodingResults);
+            }
+        };
+
+        let schema = schema.expect("metadata commands have a schema");
+        let batch = RecordBatch::try_new(schema, columns).context(EncodingResults)?;
+        flight_data(&[batch]).context(EncodingResults)
+    }
+
+    /// Lists the tables of the database that have data, then the system tables, leaving out
+    /// those `access` doesn't allow
+    async fn tables(&self, db_name: &str, access: &RowAccess) -> Result<Vec<TableInfo>> {
+        let query = format!("select distinct table_name from {}", system_tables::COLUMNS);
+        let batches = self
+            .app_server
+            .read()
+            .await
+            .query_local(db_name, &query)
+            .await
+            .context(Querying)?;
+
+        let mut names = vec![];
+        for batch in &batches {
+            if let Some(column) = batch.column(0).as_any().downcast_ref::<StringArray>() {
+                names.extend((0..column.len()).map(|i| column.value(i).to_string()));
+            }
+        }
+        names.sort();
+        names.dedup();
+
+        let mut tables: Vec<_> = names
+            .into_iter()
+            .filter(|name| access.allows_measurement(name))
+            .map(|name| TableInfo {
+                schema: DEFAULT_SCHEMA,
+                name,
+                table_type: TABLE,
+            })
+            .collect();
+        let system = [
+            system_tables::CHUNKS,
+            system_tables::COLUMNS,
+            system_tables::OPERATIONS,
+            system_tables::TASKS,
+            system_tables::TASK_RUNS,
+            system_tables::AUDIT_LOG,
+            system_tables::TABLES,
+            system_tables::PARTITIONS,
+        ];
+        tables.extend(
+            system
+                .iter()
+                .filter(|name| access.allows_measurement(name))
+                .map(|name| TableInfo {
+                    schema: SYSTEM_SCHEMA,
+                    name: name.trim_start_matches("system.").to_string(),
+                    table_type: SYSTEM_TABLE,
+                }),
+        );
+        Ok(tables)
+    }
+
+    /// Returns the schema of `table` as an encapsulated Arrow IPC schema message. Tables
+    /// don't have a schema of their own, so it is the schema of their rows
+    async fn table_schema(
+        &self,
+        db_name: &str,
+        access: &RowAccess,
+        table: &TableInfo,
+    ) -> Result<Vec<u8>> {
+        let query = if table.schema == SYSTEM_SCHEMA {
+            format!("select * from {}.{} limit 1", SYSTEM_SCHEMA, table.name)
+        } else {
+            format!(
+                "select * from \"{}\" limit 1",
+                table.name.replace('"', "\"\"")
+            )
+        };
+        let batches = self
+            .app_server
+            .read()
+            .await
+            .query_local_with_access(db_name, &query, access)
+            .await
+            .context(Querying)?;
+        let schema = match batches.first() {
+            Some(batch) => batch.schema(),
+            None => Arc::new(Schema::empty()),
+        };
+        encode_schema(&schema).context(EncodingResults)
+    }
+}
+
+/// Returns the database a command runs against: its catalog, or the one named by the
+/// `x-iox-database` header of the request
+fn database_name(metadata: &MetadataMap, catalog: &str) -> Result<String> {
+    if !catalog.is_empty() {
+        return Ok(catalog.to_string());
+    }
+    metadata
+        .get(DATABASE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|name| !name.is_empty())
+        .map(ToString::to_string)
+        .context(MissingDatabaseName)
+}
+
+fn strings<'a>(values: impl Iterator<Item = &'a str>) -> ArrayRef {
+    Arc::new(StringArray::from(values.collect::<Vec<_>>()))
+}
+
+/// Returns true if `value` matches the LIKE `pattern`, in which % matches any sequence of
+/// characters and _ any single character. An empty pattern matches everything
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    fn like(pattern: &[char], value: &[char]) -> bool {
+        match pattern.split_first() {
+            None => value.is_empty(),
+            Some(('%', rest)) => (0..=value.len()).any(|i| like(rest, &value[i..])),
+            Some(('_', rest)) => !value.is_empty() && like(rest, &value[1..]),
+            Some((c, rest)) => value.first() == Some(c) && like(rest, &value[1..]),
+        }
+    }
+
+    if pattern.is_empty() {
+        return true;
+    }
+    let pattern: Vec<_> = pattern.chars().collect();
+    let value: Vec<_> = value.chars().collect();
+    like(&pattern, &value)
+}
+
+/// Encodes `schema` as an encapsulated Arrow IPC schema message
+fn encode_schema(schema: &Schema) -> Result<Vec<u8>, ArrowError> {
+    let mut data = vec![];
+    // the writer starts the stream with the schema
+    StreamWriter::try_new(&mut data, schema)?;
+    Ok(data)
+}
+
+/// Encodes `batches` as the Flight data of an Arrow IPC stream: its schema followed by its
+/// record batches, one IPC message each. There is no data if there are no batches
+fn flight_data(batches: &[RecordBatch]) -> Result<Vec<FlightData>, ArrowError> {
+    let stream = encode(batches)?;
+    let malformed = || ArrowError::IoError("truncated Arrow IPC stream".to_string());
+
+    // Each message is an optional continuation marker, the length of its flatbuffer header,
+    // the header and its body. A length of 0 marks the end of the stream
+    let mut data = vec![];
+    let mut rest = &stream[..];
+    while rest.len() >= 4 {
+        let mut length = i32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        rest = &rest[4..];
+        if length == -1 {
+            let bytes = rest.get(..4).ok_or_else(malformed)?;
+            length = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            rest = &rest[4..];
+        }
+        if length <= 0 {
+            break;
+        }
+
+        let header = rest.get(..length as usize).ok_or_else(malformed)?;
+        rest = &rest[length as usize..];
+        let body_length = ipc::get_root_as_message(header).bodyLength() as usize;
+        let body = rest.get(..body_length).ok_or_else(malformed)?;
+        rest = &rest[body_length..];
+
+        data.push(FlightData {
+            flight_descriptor: None,
+            data_header: header.to_vec(),
+            app_metadata: vec![],
+            data_body: body.to_vec(),
+        });
+    }
+    Ok(data)
+}
+
+#[tonic::async_trait]
+impl<M> flight_service_server::FlightService for FlightSqlService<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    type HandshakeStream = FlightStream<HandshakeResponse>;
+    type ListFlightsStream = FlightStream<FlightInfo>;
+    type DoGetStream = FlightStream<FlightData>;
+    type DoPutStream = FlightStream<PutResult>;
+    type DoExchangeStream = FlightStream<FlightData>;
+    type DoActionStream = FlightStream<flight::Result>;
+    type ListActionsStream = FlightStream<ActionType>;
+
+    /// Clients authenticate with the authorization header of each request, so the handshake
+    /// has nothing to exchange
+    async fn handshake(
+        &self,
+        _req: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        let response = HandshakeResponse {
+            protocol_version: 0,
+            payload: vec![],
+        };
+        Ok(Response::new(futures::stream::iter(vec![Ok(response)])))
+    }
+
+    async fn list_flights(
+        &self,
+        _req: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        Err(Status::unimplemented("Flights are Flight SQL commands"))
+    }
+
+    async fn get_flight_info(
+        &self,
+        req: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let (command, ..) = self.authorize(req.metadata(), &req.get_ref().cmd)?;
+        let schema = match command.schema() {
+            Some(schema) => encode_schema(&schema)
+                .context(EncodingResults)
+                .map_err(|e| e.to_status())?,
+            None => vec![],
+        };
+
+        let descriptor = req.into_inner();
+        let ticket = Ticket {
+            ticket: descriptor.cmd.clone(),
+        };
+        Ok(Response::new(FlightInfo {
+            schema,
+            flight_descriptor: Some(descriptor),
+            endpoint: vec![FlightEndpoint {
+                ticket: Some(ticket),
+                location: vec![],
+            }],
+            total_records: -1,
+            total_bytes: -1,
+        }))
+    }
+
+    async fn get_schema(
+        &self,
+        req: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        let (command, ..) = self.authorize(req.metadata(), &req.get_ref().cmd)?;
+        let schema = command
+            .schema()
+            .context(UnknownSchema)
+            .and_then(|schema| encode_schema(&schema).context(EncodingResults))
+            .map_err(|e| e.to_status())?;
+        Ok(Response::new(SchemaResult { schema }))
+    }
+
+    async fn do_get(&self, req: Request<Ticket>) -> Result<Response<Self::DoGetStream>, Status> {
+        let (command, db_name, access) = self.authorize(req.metadata(), &req.get_ref().ticket)?;
+        let data = self
+            .run(req.metadata(), command, db_name, &access)
+            .await
+            .map_err(|e| e.to_status())?;
+        let data: Vec<_> = data.into_iter().map(Ok).collect();
+        Ok(Response::new(futures::stream::iter(data)))
+    }
+
+    async fn do_put(
+        &self,
+        _req: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented(
+            "Flight SQL updates are not supported",
+        ))
+    }
+
+    async fn do_exchange(
+        &self,
+        _req: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented("DoExchange is not supported"))
+    }
+
+    async fn do_action(
+        &self,
+        _req: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented(
+            "Flight SQL prepared statements are not supported",
+        ))
+    }
+
+    async fn list_actions(
+        &self,
+        _req: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Ok(Response::new(futures::stream::iter(vec![])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ConnectionManagerImpl;
+    use arrow_deps::arrow::{ipc::reader::StreamReader, util::pretty::pretty_format_batches};
+    use data_types::database_rules::DatabaseRules;
+    use flight_service_server::FlightService as _;
+    use futures::StreamExt;
+    use object_store::{InMemory, ObjectStore};
+    use std::io::Cursor;
+    use tonic::Code;
+
+    async fn make_service() -> FlightSqlService<ConnectionManagerImpl> {
+        let mut app_server = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        app_server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        app_server.create_database("foo", rules).await.unwrap();
+
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines("cpu,host=a usage=0.5 10")
+            .map(|l| l.unwrap())
+            .collect();
+        app_server.write_lines("foo", &lines).await.unwrap();
+
+        FlightSqlService::new(
+            Arc::new(RwLock::new(app_server)),
+            Arc::new(Authorizer::new(true)),
+            None,
+            Arc::new(Governor::default()),
+        )
+    }
+
+    fn to_bytes(message: &impl Message) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(message.encoded_len());
+        message.encode(&mut buf).unwrap();
+        buf
+    }
+
+    /// Packs a Flight SQL command in a `google.protobuf.Any`
+    fn pack(name: &str, command: impl Message) -> Vec<u8> {
+        to_bytes(&prost_types::Any {
+            type_url: format!("{}{}", TYPE_URL_PREFIX, name),
+            value: to_bytes(&command),
+        })
+    }
+
+    fn descriptor(cmd: Vec<u8>, db_name: Option<&str>) -> Request<FlightDescriptor> {
+        let mut request = Request::new(FlightDescriptor {
+            r#type: flight::flight_descriptor::DescriptorType::Cmd as i32,
+            cmd,
+            path: vec![],
+        });
+        if let Some(db_name) = db_name {
+            request
+                .metadata_mut()
+                .insert(DATABASE_HEADER, db_name.parse().unwrap());
+        }
+        request
+    }
+
+    /// Redeems the ticket of the flight of `cmd`, returning the results as a table
+    async fn run(service: &FlightSqlService<ConnectionManagerImpl>, cmd: Vec<u8>) -> String {
+        let info = service
+            .get_flight_info(descriptor(cmd, Some("foo")))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut request = Request::new(info.endpoint[0].ticket.clone().unwrap());
+        request
+            .metadata_mut()
+            .insert(DATABASE_HEADER, "foo".parse().unwrap());
+        let data: Vec<_> = service
+            .do_get(request)
+            .await
+            .unwrap()
+            .into_inner()
+            .collect()
+            .await;
+
+        let mut stream = vec![];
+        for data in data {
+            let data = data.unwrap();
+            stream.extend_from_slice(&(-1i32).to_le_bytes());
+            stream.extend_from_slice(&(data.data_header.len() as i32).to_le_bytes());
+            stream.extend_from_slice(&data.data_header);
+            stream.extend_from_slice(&data.data_body);
+        }
+        stream.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        let batches = StreamReader::try_new(Cursor::new(stream))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        pretty_format_batches(&batches).unwrap().trim().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_statement_query() {
+        let service = make_service().await;
+
+        let query = CommandStatementQuery {
+            query: "select host, usage, time from cpu".to_string(),
+        };
+        let info = service
+            .get_flight_info(descriptor(
+                pack("CommandStatementQuery", query.clone()),
+                Some("foo"),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        // the schema of the results is only known once the query runs
+        assert!(info.schema.is_empty());
+
+        let expected = vec![
+            "+------+-------+------+",
+            "| host | usage | time |",
+            "+------+-------+------+",
+            "| a    | 0.5   | 10   |",
+            "+------+-------+------+",
+        ];
+        assert_eq!(
+            run(&service, pack("CommandStatementQuery", query.clone())).await,
+            expected.join("\n")
+        );
+
+        let status = service
+            .get_flight_info(descriptor(pack("CommandStatementQuery", query), None))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .get_flight_info(descriptor(
+                pack("CommandPreparedStatementQuery", CommandGetCatalogs {}),
+                Some("foo"),
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unimplemented);
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let service = make_service().await;
+
+        let expected = vec![
+            "+--------------+",
+            "| catalog_name |",
+            "+--------------+",
+            "| foo          |",
+            "+--------------+",
+        ];
+        assert_eq!(
+            run(&service, pack("CommandGetCatalogs", CommandGetCatalogs {})).await,
+            expected.join("\n")
+        );
+
+        let command = CommandGetTables {
+            db_schema_filter_pattern: "pub%".to_string(),
+            ..Default::default()
+        };
+        let expected = vec![
+            "+--------------+----------------+------------+------------+",
+            "| catalog_name | db_schema_name | table_name | table_type |",
+            "+--------------+----------------+------------+------------+",
+            "| foo          | public         | cpu        | TABLE      |",
+            "+--------------+----------------+------------+------------+",
+        ];
+        assert_eq!(
+            run(&service, pack("CommandGetTables", command)).await,
+            expected.join("\n")
+        );
+
+        let command = CommandGetTables {
+            table_name_filter_pattern: "%runs".to_string(),
+            table_types: vec![SYSTEM_TABLE.to_string()],
+            ..Default::default()
+        };
+        let expected = vec![
+            "+--------------+----------------+------------+--------------+",
+            "| catalog_name | db_schema_name | table_name | table_type   |",
+            "+--------------+----------------+------------+--------------+",
+            "| foo          | system         | task_runs  | SYSTEM TABLE |",
+            "+--------------+----------------+------------+--------------+",
+        ];
+        assert_eq!(
+            run(&service, pack("CommandGetTables", command)).await,
+            expected.join("\n")
+        );
+    }
+
+    #[test]
+    fn like_patterns() {
+        assert!(matches_pattern("", "cpu"));
+        assert!(matches_pattern("c%", "cpu"));
+        assert!(matches_pattern("%p%", "cpu"));
+        assert!(matches_pattern("c_u", "cpu"));
+        assert!(!matches_pattern("c_", "cpu"));
+        assert!(!matches_pattern("mem%", "cpu"));
+    }
+}
@@ -0,0 +1,151 @@
+//! This module contains the implementation of the standard gRPC health
+//! checking service, which load balancers and orchestrators call to find out
+//! whether the server, named by an empty service name, or one of its gRPC
+//! services can take requests. They report serving until the server starts
+//! shutting down, when they report not serving and the watches end, so that
+//! they don't hold up the shutdown.
+
+use std::collections::BTreeSet;
+
+use generated_types::grpc::health::v1::{
+    health_check_response::ServingStatus, health_server, HealthCheckRequest, HealthCheckResponse,
+};
+use tokio::sync::{mpsc, watch};
+use tonic::{Request, Response, Status};
+
+/// Implements the protobuf defined health service for the gRPC services of the
+/// server
+#[derive(Debug)]
+pub struct HealthService {
+    /// The fully qualified names of the services
+    services: BTreeSet<String>,
+    /// Whether the server takes requests, false once it is shutting down
+    serving: watch::Receiver<bool>,
+}
+
+impl HealthService {
+    /// Create a new HealthService for the services named `services`
+    pub fn new(services: impl IntoIterator<Item = String>, serving: watch::Receiver<bool>) -> Self {
+        Self {
+            services: services.into_iter().collect(),
+            serving,
+        }
+    }
+
+    fn is_known(&self, service: &str) -> bool {
+        service.is_empty() || self.services.contains(service)
+    }
+}
+
+fn serving_status(known: bool, serving: bool) -> ServingStatus {
+    match (known, serving) {
+        (false, _) => ServingStatus::ServiceUnknown,
+        (true, true) => ServingStatus::Serving,
+        (true, false) => ServingStatus::NotServing,
+    }
+}
+
+fn response(status: ServingStatus) -> HealthCheckResponse {
+    HealthCheckResponse {
+        status: status as i32,
+    }
+}
+
+#[tonic::async_trait]
+impl health_server::Health for HealthService {
+    async fn check(
+        &self,
+        req: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = req.into_inner().service;
+        if !self.is_known(&service) {
+            return Err(Status::not_found(format!("Unknown service {}", service)));
+        }
+
+        let serving = *self.serving.borrow();
+        Ok(Response::new(response(serving_status(true, serving))))
+    }
+
+    type WatchStream = mpsc::Receiver<Result<HealthCheckResponse, Status>>;
+
+    async fn watch(
+        &self,
+        req: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let known = self.is_known(&req.into_inner().service);
+        let mut serving = self.serving.clone();
+        let (mut tx, rx) = mpsc::channel(1);
+
+        tokio::spawn(async move {
+            let mut sent = None;
+            loop {
+                // the first call returns the current value, and the server no longer serves
+                // once the sender is dropped
+                let is_serving = serving.recv().await.unwrap_or(false);
+                let status = serving_status(known, is_serving);
+                if sent != Some(status) {
+                    if tx.send(Ok(response(status))).await.is_err() {
+                        return;
+                    }
+                    sent = Some(status);
+                }
+                if !is_serving {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use health_server::Health as _;
+    use tonic::Code;
+
+    fn request(service: &str) -> Request<HealthCheckRequest> {
+        Request::new(HealthCheckRequest {
+            service: service.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let (serving_tx, serving) = watch::channel(true);
+        let service = HealthService::new(
+            vec!["influxdata.iox.write.v1.WriteService".to_string()],
+            serving,
+        );
+
+        for name in &["", "influxdata.iox.write.v1.WriteService"] {
+            let status = service.check(request(name)).await.unwrap().into_inner();
+            assert_eq!(status, response(ServingStatus::Serving));
+        }
+        let status = service.check(request("foo.Bar")).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let mut unknown = service
+            .watch(request("foo.Bar"))
+            .await
+            .unwrap()
+            .into_inner();
+        let status = unknown.recv().await.unwrap().unwrap();
+        assert_eq!(status, response(ServingStatus::ServiceUnknown));
+
+        let mut watching = service.watch(request("")).await.unwrap().into_inner();
+        let status = watching.recv().await.unwrap().unwrap();
+        assert_eq!(status, response(ServingStatus::Serving));
+
+        // the watches end once the server shuts down
+        serving_tx.broadcast(false).unwrap();
+        let status = watching.recv().await.unwrap().unwrap();
+        assert_eq!(status, response(ServingStatus::NotServing));
+        assert!(watching.recv().await.is_none());
+        assert!(unknown.recv().await.is_none());
+
+        let status = service.check(request("")).await.unwrap().into_inner();
+        assert_eq!(status, response(ServingStatus::NotServing));
+    }
+}
@@ -0,0 +1,302 @@
+//! This module contains the limits of the gRPC services that ingest data, the write and
+//! OpenTelemetry metrics services, so that a misbehaving producer can't monopolize them:
+//!
+//! * Requests whose message is larger than `max_message_size` bytes are rejected. Messages
+//!   are checked once decoded, so the limit bounds what a request writes rather than the
+//!   memory taken to decode it.
+//! * Each token sends up to `requests_per_second` requests on average, in bursts of up to
+//!   `burst` requests, when a rate is set. Anonymous requests share one budget.
+//! * At most `max_concurrent_requests` requests are handled at the same time, and each
+//!   connection to the gRPC server has at most `max_requests_per_connection` requests in
+//!   flight, whichever services they are to, the others waiting for a slot.
+//!
+//! Requests over a limit fail with RESOURCE_EXHAUSTED, naming the limit they hit in their
+//! `x-iox-limit` metadata and, for the rate, how many milliseconds to wait before retrying in
+//! their `x-iox-retry-after-ms` metadata.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use snafu::{ensure, OptionExt, Snafu};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tonic::{
+    metadata::{MetadataMap, MetadataValue},
+    Code, Request, Status,
+};
+
+use crate::server::auth::AUTHORIZATION_HEADER;
+
+/// The metadata key naming the limit a request hit
+pub const LIMIT_METADATA: &str = "x-iox-limit";
+
+/// The metadata key giving how many milliseconds to wait before retrying a request over the
+/// rate of its token
+pub const RETRY_AFTER_METADATA: &str = "x-iox-retry-after-ms";
+
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 256;
+pub const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 32;
+
+/// The number of tokens whose rates are tracked before those with their whole burst available
+/// are forgotten
+const MAX_TRACKED_TOKENS: usize = 10_000;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Request of {} bytes is larger than the limit of {} bytes",
+        size,
+        limit
+    ))]
+    MessageTooLarge { size: usize, limit: usize },
+
+    #[snafu(display(
+        "Token is over its rate of {} requests per second, retry in {} ms",
+        limit,
+        retry_after.as_millis()
+    ))]
+    RateLimited { limit: u32, retry_after: Duration },
+
+    #[snafu(display("Too many requests in flight ({}), retry later", limit))]
+    Overloaded { limit: usize },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// The name of the limit hit, as given in the `x-iox-limit` metadata
+    pub fn limit(&self) -> &'static str {
+        match self {
+            Self::MessageTooLarge { .. } => "message-size",
+            Self::RateLimited { .. } => "rate",
+            Self::Overloaded { .. } => "concurrency",
+        }
+    }
+
+    /// Converts the error into a RESOURCE_EXHAUSTED status, with the limit hit in its metadata
+    pub fn to_status(&self) -> Status {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(LIMIT_METADATA, MetadataValue::from_static(self.limit()));
+        if let Self::RateLimited { retry_after, .. } = self {
+            if let Ok(value) = retry_after.as_millis().to_string().parse() {
+                metadata.insert(RETRY_AFTER_METADATA, value);
+            }
+        }
+        Status::with_metadata(Code::ResourceExhausted, self.to_string(), metadata)
+    }
+}
+
+/// The limits of the requests to the ingest services
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IngestLimits {
+    pub max_message_size: usize,
+    /// The requests per second each token may send on average, unlimited if not set
+    pub requests_per_second: Option<u32>,
+    /// The most requests a token may send at once, `requests_per_second` if not set
+    pub burst: Option<u32>,
+    pub max_concurrent_requests: usize,
+    pub max_requests_per_connection: usize,
+}
+
+impl Default for IngestLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            requests_per_second: None,
+            burst: None,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+            max_requests_per_connection: DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+        }
+    }
+}
+
+/// Enforces the limits of the ingest services as described in the module documentation
+#[derive(Debug)]
+pub struct IngestLimiter {
+    limits: IngestLimits,
+    requests: Semaphore,
+    /// The requests each token may still send, by token
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+/// The requests a token may still send, refilled at the rate of the limits up to their burst
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    available: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Refills the bucket for the time elapsed until `now`, returning the requests available
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * rate).min(burst);
+        self.updated = now;
+        self.available
+    }
+}
+
+impl Default for IngestLimiter {
+    fn default() -> Self {
+        Self::new(IngestLimits::default())
+    }
+}
+
+impl IngestLimiter {
+    /// Enforces `limits`, of which at least one request is handled at a time and at least
+    /// one request is sent at once
+    pub fn new(limits: IngestLimits) -> Self {
+        let limits = IngestLimits {
+            max_concurrent_requests: limits.max_concurrent_requests.max(1),
+            max_requests_per_connection: limits.max_requests_per_connection.max(1),
+            burst: limits.burst.map(|burst| burst.max(1)),
+            ..limits
+        };
+        Self {
+            limits,
+            requests: Semaphore::new(limits.max_concurrent_requests),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn limits(&self) -> IngestLimits {
+        self.limits
+    }
+
+    /// Checks that a request whose message is `size` bytes may be handled now, returning the
+    /// permit it is handled under
+    pub fn admit(&self, size: usize) -> Result<SemaphorePermit<'_>> {
+        let limit = self.limits.max_message_size;
+        ensure!(size <= limit, MessageTooLarge { size, limit });
+        self.requests.try_acquire().ok().context(Overloaded {
+            limit: self.limits.max_concurrent_requests,
+        })
+    }
+
+    /// Checks that the token of a request with `metadata` is within its rate at `now`,
+    /// counting the request against it
+    pub fn check_rate(&self, metadata: &MetadataMap, now: Instant) -> Result<()> {
+        let limit = match self.limits.requests_per_second {
+            Some(limit) if limit > 0 => limit,
+            _ => return Ok(()),
+        };
+        let rate = f64::from(limit);
+        let burst = f64::from(self.limits.burst.unwrap_or(limit));
+        let token = metadata
+            .get(AUTHORIZATION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        let mut buckets = self.buckets.lock().expect("mutex poisoned");
+        if buckets.len() >= MAX_TRACKED_TOKENS && !buckets.contains_key(token) {
+            buckets.retain(|_, bucket| bucket.refill(now, rate, burst) < burst);
+        }
+        let bucket = buckets.entry(token.to_string()).or_insert(Bucket {
+            available: burst,
+            updated: now,
+        });
+        if bucket.refill(now, rate, burst) < 1.0 {
+            let retry_after = Duration::from_secs_f64((1.0 - bucket.available) / rate);
+            return RateLimited { limit, retry_after }.fail();
+        }
+        bucket.available -= 1.0;
+        Ok(())
+    }
+}
+
+/// Returns an interceptor that rejects the requests of the tokens over their rate
+pub fn rate_limit(
+    limiter: Arc<IngestLimiter>,
+) -> impl Fn(Request<()>) -> Result<Request<()>, Status> + Send + Sync + 'static {
+    move |req| {
+        limiter
+            .check_rate(req.metadata(), Instant::now())
+            .map_err(|e| e.to_status())?;
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(token: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(AUTHORIZATION_HEADER, token.parse().unwrap());
+        metadata
+    }
+
+    #[test]
+    fn limits_message_sizes_and_concurrency() {
+        let limiter = IngestLimiter::new(IngestLimits {
+            max_message_size: 100,
+            max_concurrent_requests: 1,
+            ..Default::default()
+        });
+
+        let err = limiter.admit(101).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::MessageTooLarge {
+                size: 101,
+                limit: 100
+            }
+        ));
+        let status = err.to_status();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(
+            status.metadata().get(LIMIT_METADATA).unwrap(),
+            "message-size"
+        );
+
+        let permit = limiter.admit(100).unwrap();
+        let err = limiter.admit(10).unwrap_err();
+        assert!(matches!(err, Error::Overloaded { limit: 1 }));
+        drop(permit);
+        limiter.admit(10).unwrap();
+    }
+
+    #[test]
+    fn limits_the_rate_of_each_token() {
+        let limiter = IngestLimiter::new(IngestLimits {
+            requests_per_second: Some(10),
+            burst: Some(2),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let (a, b) = (metadata("Token a"), metadata("Token b"));
+
+        limiter.check_rate(&a, start).unwrap();
+        limiter.check_rate(&a, start).unwrap();
+        let err = limiter.check_rate(&a, start).unwrap_err();
+        match &err {
+            Error::RateLimited { limit, retry_after } => {
+                assert_eq!(*limit, 10);
+                assert_eq!(*retry_after, Duration::from_millis(100));
+            }
+            _ => panic!("unexpected error {}", err),
+        }
+        let status = err.to_status();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(status.metadata().get(LIMIT_METADATA).unwrap(), "rate");
+        assert_eq!(status.metadata().get(RETRY_AFTER_METADATA).unwrap(), "100");
+
+        // the other tokens have their own budget, and the budget refills over time
+        limiter.check_rate(&b, start).unwrap();
+        limiter
+            .check_rate(&a, start + Duration::from_millis(100))
+            .unwrap();
+        assert!(limiter
+            .check_rate(&a, start + Duration::from_millis(150))
+            .is_err());
+
+        // without a rate, requests are not counted
+        let limiter = IngestLimiter::default();
+        for _ in 0..100 {
+            limiter.check_rate(&a, start).unwrap();
+        }
+    }
+}
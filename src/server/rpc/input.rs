@@ -3,7 +3,7 @@ use tonic::Status;
 use generated_types::{
     MeasurementFieldsRequest, MeasurementNamesRequest, MeasurementTagKeysRequest,
     MeasurementTagValuesRequest, ReadFilterRequest, ReadGroupRequest, ReadSource, TagKeysRequest,
-    TagValuesRequest,
+    TagValuesRequest, TagValuesWithPrefixRequest,
 };
 use storage::id::Id;
 
@@ -71,6 +71,12 @@ impl GrpcInputs for TagValuesRequest {
     }
 }
 
+impl GrpcInputs for TagValuesWithPrefixRequest {
+    fn read_source_field(&self) -> Option<&prost_types::Any> {
+        self.tags_source.as_ref()
+    }
+}
+
 impl GrpcInputs for MeasurementNamesRequest {
     fn read_source_field(&self) -> Option<&prost_types::Any> {
         self.source.as_ref()
@@ -0,0 +1,3196 @@
+//! This module contains the implementation of the management gRPC
+//! service, used to configure the databases served by a
+//! `cluster::Server`
+
+use std::{
+    convert::{Infallible, TryInto},
+    sync::Arc,
+    time::Duration,
+};
+
+use cluster::{
+    audit::AuditEvent,
+    checks::{Check, CheckState, Direction, Endpoint, Level},
+    chunk_policy::ChunkPolicy,
+    integrity::FileStatus,
+    tasks::Task,
+    tombstone::DeletePredicate,
+    wal_replay::WalReplay,
+    ConnectionManager, Server as AppServer,
+};
+use data_types::{database_rules::DatabaseRules, table_schema::DataType};
+use generated_types::management::{
+    self, check_endpoint, management_service_server, CloseChunkRequest, CloseChunkResponse,
+    CreateCheckRequest, CreateCheckResponse, CreateDatabaseRequest, CreateDatabaseResponse,
+    CreateDummyJobRequest, CreateDummyJobResponse, CreateRulesTemplateRequest,
+    CreateRulesTemplateResponse, CreateTaskRequest, CreateTaskResponse, CreateTokenRequest,
+    CreateTokenResponse, DatabaseRulesUpdate, DeleteCheckRequest, DeleteCheckResponse,
+    DeleteChunkPolicyRequest, DeleteChunkPolicyResponse, DeleteRequest, DeleteResponse,
+    DeleteRulesTemplateRequest, DeleteRulesTemplateResponse, DeleteTaskRequest, DeleteTaskResponse,
+    DeleteTokenRequest, DeleteTokenResponse, DropDimensionTableRequest, DropDimensionTableResponse,
+    ExportDatabaseRequest, ExportDatabaseResponse, ForceClaimDatabaseRequest,
+    ForceClaimDatabaseResponse, GetDatabaseRequest, GetDatabaseResponse, GetWriterIdRequest,
+    GetWriterIdResponse, ImportDataRequest, ImportDataResponse, ListChecksRequest,
+    ListChecksResponse, ListChunkPoliciesRequest, ListChunkPoliciesResponse, ListChunksRequest,
+    ListChunksResponse, ListDatabaseRulesVersionsRequest, ListDatabaseRulesVersionsResponse,
+    ListDatabasesRequest, ListDatabasesResponse, ListRulesTemplatesRequest,
+    ListRulesTemplatesResponse, ListTasksRequest, ListTasksResponse, ListTokensRequest,
+    ListTokensResponse, LoadDimensionTableRequest, LoadDimensionTableResponse, MoveChunkRequest,
+    MoveChunkResponse, PauseIngestRequest, PauseIngestResponse, PauseTaskRequest,
+    PauseTaskResponse, PersistChunkRequest, PersistChunkResponse, RebuildCatalogRequest,
+    RebuildCatalogResponse, ReleaseDatabaseRequest, ReleaseDatabaseResponse,
+    RestoreDatabaseRequest, RestoreDatabaseResponse, RollbackDatabaseRulesRequest,
+    RollbackDatabaseRulesResponse, SetChunkPolicyRequest, SetChunkPolicyResponse,
+    SkipWalReplayRequest, SkipWalReplayResponse, SnapshotDatabaseRequest, SnapshotDatabaseResponse,
+    UpdateDatabaseRulesRequest, UpdateDatabaseRulesResponse, UpdateRulesTemplateRequest,
+    UpdateRulesTemplateResponse, UpdateWriterIdRequest, UpdateWriterIdResponse, VerifiedFile,
+    VerifyCatalogRequest, VerifyCatalogResponse,
+};
+
+use ingest::import::{FileFormat, SchemaMapping, TimeUnit};
+use object_store::ObjectStore;
+use prost::Message;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use storage::predicate::TimestampRange;
+use tokio::sync::RwLock;
+use tonic::{Code, Request, Response, Status};
+use tracing::{info, warn};
+
+use super::{cluster_status, operations::to_operation};
+use crate::server::auth::{self, Authorizer, Permission};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Database rules are required"))]
+    MissingRules,
+
+    #[snafu(display("Database name is required"))]
+    MissingDatabaseName,
+
+    #[snafu(display("Token is required"))]
+    MissingToken,
+
+    #[snafu(display("Table name is required"))]
+    MissingTableName,
+
+    #[snafu(display("Export output is required"))]
+    MissingOutput,
+
+    #[snafu(display("Snapshot input is required"))]
+    MissingInput,
+
+    #[snafu(display("Schema mapping is required"))]
+    MissingMapping,
+
+    #[snafu(display("Task is required"))]
+    MissingTask,
+
+    #[snafu(display("Check is required"))]
+    MissingCheck,
+
+    #[snafu(display("Check endpoint is required"))]
+    MissingCheckEndpoint,
+
+    #[snafu(display("Chunk policy is required"))]
+    MissingChunkPolicy,
+
+    #[snafu(display("Invalid schema mapping: {}", description))]
+    InvalidMapping { description: String },
+
+    #[snafu(display("Error importing data: {}", source))]
+    ImportingData { source: ingest::import::Error },
+
+    #[snafu(display("Error importing data: conversion panicked: {}", source))]
+    ImportPanicked { source: tokio::task::JoinError },
+
+    #[snafu(display("Error managing tokens: {}", source))]
+    TokenError { source: auth::Error },
+
+    #[snafu(display("Invalid database rules: {}", source))]
+    InvalidRules {
+        source: data_types::database_rules::Error,
+    },
+
+    #[snafu(display("Database not found: {}", db_name))]
+    DatabaseNotFound { db_name: String },
+
+    #[snafu(display("Error managing databases: {}", source))]
+    ServerError { source: cluster::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Converts the error into the status of the failed management request: the request is
+    /// at fault for a missing or invalid field, and the errors of the server keep the status
+    /// `cluster_status` gives them
+    fn to_status(&self) -> tonic::Status {
+        match &self {
+            Self::MissingRules => Status::invalid_argument(self.to_string()),
+            Self::MissingDatabaseName => Status::invalid_argument(self.to_string()),
+            Self::MissingToken => Status::invalid_argument(self.to_string()),
+            Self::MissingTableName => Status::invalid_argument(self.to_string()),
+            Self::MissingOutput => Status::invalid_argument(self.to_string()),
+            Self::MissingInput => Status::invalid_argument(self.to_string()),
+            Self::MissingMapping => Status::invalid_argument(self.to_string()),
+            Self::MissingTask => Status::invalid_argument(self.to_string()),
+            Self::MissingCheck => Status::invalid_argument(self.to_string()),
+            Self::MissingCheckEndpoint => Status::invalid_argument(self.to_string()),
+            Self::MissingChunkPolicy => Status::invalid_argument(self.to_string()),
+            Self::InvalidMapping { .. } => Status::invalid_argument(self.to_string()),
+            Self::ImportingData { .. } => Status::invalid_argument(self.to_string()),
+            Self::ImportPanicked { .. } => Status::internal(self.to_string()),
+            Self::TokenError { source } => source.to_status(),
+            Self::InvalidRules { .. } => Status::invalid_argument(self.to_string()),
+            Self::DatabaseNotFound { .. } => Status::not_found(self.to_string()),
+            Self::ServerError { source } => {
+                cluster_status(source, self.to_string(), Code::Internal)
+            }
+        }
+    }
+}
+
+/// Implements the protobuf defined management service on top of a
+/// `cluster::Server`, and of the `Authorizer` of the server for tokens
+#[derive(Debug)]
+pub struct ManagementService<M: ConnectionManager> {
+    app_server: Arc<RwLock<AppServer<M>>>,
+    authorizer: Arc<Authorizer>,
+}
+
+impl<M> ManagementService<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    /// Create a new ManagementService that configures `app_server` and
+    /// the tokens of `authorizer`
+    pub fn new(app_server: Arc<RwLock<AppServer<M>>>, authorizer: Arc<Authorizer>) -> Self {
+        Self {
+            app_server,
+            authorizer,
+        }
+    }
+
+    /// Checks that `req` is allowed to manage the database `db_name`, or the whole server if
+    /// it doesn't target a database
+    fn authorize<T>(&self, req: &Request<T>, db_name: Option<&str>) -> Result<(), Status> {
+        self.authorizer
+            .authorize_grpc(req.metadata(), Permission::Manage, db_name)
+    }
+
+    /// Returns who sent `req` and its encoded payload, which are recorded to the audit log
+    /// once the operation is done
+    fn audit_request<T: Message>(&self, req: &Request<T>) -> (String, Vec<u8>) {
+        let actor = self.authorizer.principal_grpc(req.metadata());
+        let mut payload = Vec::with_capacity(req.get_ref().encoded_len());
+        req.get_ref()
+            .encode(&mut payload)
+            .expect("the capacity of the buffer is the length of the message");
+        (actor, payload)
+    }
+
+    /// Records the outcome of `operation` on the database `db_name`, or on the server if
+    /// there is none, to the audit log of the server if it has one
+    async fn audit<T, E: std::fmt::Display>(
+        &self,
+        (actor, payload): (String, Vec<u8>),
+        operation: &str,
+        db_name: Option<String>,
+        result: &Result<T, E>,
+    ) {
+        let audit_log = match self.app_server.read().await.audit_log() {
+            Some(audit_log) => Arc::clone(audit_log),
+            None => return,
+        };
+
+        let db_name = db_name.filter(|name| !name.is_empty());
+        let error = result.as_ref().err().map(ToString::to_string);
+        let event = AuditEvent::new(actor, operation, db_name, &payload, error);
+        if let Err(e) = audit_log.record(event).await {
+            warn!("error recording {} to the audit log: {}", operation, e);
+        }
+    }
+
+    async fn get_database_rules_impl(&self, db_name: String) -> Result<management::DatabaseRules> {
+        ensure_db_name(&db_name)?;
+
+        let app_server = self.app_server.read().await;
+        let rules = app_server
+            .db_rules(&db_name)
+            .context(DatabaseNotFound { db_name: &db_name })?
+            .clone();
+
+        let mut rules: management::DatabaseRules = rules.into();
+        rules.name = db_name;
+
+        Ok(rules)
+    }
+
+    async fn create_database_impl(&self, rules: Option<management::DatabaseRules>) -> Result<()> {
+        let (db_name, rules) = convert_rules(rules)?;
+
+        let mut app_server = self.app_server.write().await;
+        app_server
+            .create_database(&db_name, rules)
+            .await
+            .context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!("created database {}", db_name);
+        Ok(())
+    }
+
+    async fn update_database_rules_impl(
+        &self,
+        rules: Option<management::DatabaseRules>,
+    ) -> Result<u64> {
+        let (db_name, rules) = convert_rules(rules)?;
+
+        let mut app_server = self.app_server.write().await;
+        let generation = app_server
+            .update_database_rules(&db_name, rules)
+            .await
+            .context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "updated rules for database {} to generation {}",
+            db_name, generation
+        );
+        Ok(generation)
+    }
+
+    async fn list_database_rules_versions_impl(
+        &self,
+        db_name: String,
+    ) -> Result<Vec<management::DatabaseRulesVersion>> {
+        ensure_db_name(&db_name)?;
+
+        let app_server = self.app_server.read().await;
+        let versions = app_server
+            .database_rules_versions(&db_name)
+            .await
+            .context(ServerError)?;
+
+        Ok(versions
+            .into_iter()
+            .map(|version| {
+                let mut rules: management::DatabaseRules = version.rules.into();
+                rules.name = db_name.clone();
+                management::DatabaseRulesVersion {
+                    generation: version.generation,
+                    rules: Some(rules),
+                }
+            })
+            .collect())
+    }
+
+    async fn rollback_database_rules_impl(&self, db_name: String, generation: u64) -> Result<u64> {
+        ensure_db_name(&db_name)?;
+
+        let mut app_server = self.app_server.write().await;
+        let new_generation = app_server
+            .rollback_database_rules(&db_name, generation)
+            .await
+            .context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "rolled back rules for database {} to generation {} as generation {}",
+            db_name, generation, new_generation
+        );
+        Ok(new_generation)
+    }
+
+    async fn release_database_impl(&self, db_name: String) -> Result<()> {
+        ensure_db_name(&db_name)?;
+
+        let mut app_server = self.app_server.write().await;
+        app_server
+            .release_database(&db_name)
+            .await
+            .context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!("released database {}", db_name);
+        Ok(())
+    }
+
+    async fn pause_ingest_impl(&self, db_name: String, paused: bool) -> Result<()> {
+        ensure_db_name(&db_name)?;
+
+        let mut app_server = self.app_server.write().await;
+        app_server
+            .set_ingest_paused(&db_name, paused)
+            .context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "{} writes to database {}",
+            if paused { "paused" } else { "resumed" },
+            db_name
+        );
+        Ok(())
+    }
+
+    async fn skip_wal_replay_impl(
+        &self,
+        db_name: String,
+        sequence_number: u64,
+    ) -> Result<WalReplay> {
+        ensure_db_name(&db_name)?;
+
+        let mut app_server = self.app_server.write().await;
+        let replay = app_server
+            .skip_wal_replay(&db_name, sequence_number)
+            .await
+            .context(ServerError)?;
+        // the entries skipped are left out of the replays after a restart too
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "skipped the replay of the WAL of database {} to entry {}",
+            db_name, sequence_number
+        );
+        Ok(replay)
+    }
+
+    async fn create_rules_template_impl(
+        &self,
+        rules: Option<management::DatabaseRules>,
+    ) -> Result<()> {
+        let (name, rules) = convert_rules(rules)?;
+
+        let mut app_server = self.app_server.write().await;
+        app_server
+            .create_rules_template(&name, rules)
+            .context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!("created rules template {}", name);
+        Ok(())
+    }
+
+    async fn update_rules_template_impl(
+        &self,
+        rules: Option<management::DatabaseRules>,
+    ) -> Result<Vec<(String, u64)>> {
+        let (name, rules) = convert_rules(rules)?;
+
+        let mut app_server = self.app_server.write().await;
+        let updated = app_server
+            .update_rules_template(&name, rules)
+            .await
+            .context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "updated rules template {} and the rules of {} databases",
+            name,
+            updated.len()
+        );
+        Ok(updated)
+    }
+
+    async fn delete_rules_template_impl(&self, name: String) -> Result<()> {
+        let mut app_server = self.app_server.write().await;
+        app_server
+            .delete_rules_template(&name)
+            .context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!("deleted rules template {}", name);
+        Ok(())
+    }
+
+    async fn create_task_impl(&self, task: Option<management::Task>) -> Result<()> {
+        let task = convert_task(task.context(MissingTask)?);
+        let name = task.name.clone();
+
+        let mut app_server = self.app_server.write().await;
+        app_server.create_task(task).context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!("created task {}", name);
+        Ok(())
+    }
+
+    async fn pause_task_impl(&self, name: String, paused: bool) -> Result<()> {
+        let mut app_server = self.app_server.write().await;
+        app_server
+            .set_task_paused(&name, paused)
+            .context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "{} task {}",
+            if paused { "paused" } else { "resumed" },
+            name
+        );
+        Ok(())
+    }
+
+    async fn delete_task_impl(&self, name: String) -> Result<()> {
+        let mut app_server = self.app_server.write().await;
+        app_server.delete_task(&name).context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!("deleted task {}", name);
+        Ok(())
+    }
+
+    async fn create_check_impl(&self, check: Option<management::Check>) -> Result<()> {
+        let check = convert_check(check.context(MissingCheck)?)?;
+        let name = check.name.clone();
+
+        let mut app_server = self.app_server.write().await;
+        app_server.create_check(check).context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!("created check {}", name);
+        Ok(())
+    }
+
+    async fn delete_check_impl(&self, name: String) -> Result<()> {
+        let mut app_server = self.app_server.write().await;
+        app_server.delete_check(&name).context(ServerError)?;
+        app_server
+            .store_configuration()
+            .await
+            .context(ServerError)?;
+
+        info!("deleted check {}", name);
+        Ok(())
+    }
+
+    async fn list_chunks_impl(&self, db_name: String) -> Result<Vec<management::Chunk>> {
+        ensure_db_name(&db_name)?;
+
+        let chunks = self
+            .app_server
+            .read()
+            .await
+            .chunk_summaries(&db_name)
+            .await
+            .context(ServerError)?;
+
+        Ok(chunks.into_iter().map(Into::into).collect())
+    }
+
+    async fn close_chunk_impl(
+        &self,
+        db_name: String,
+        partition_key: String,
+    ) -> Result<management::Chunk> {
+        ensure_db_name(&db_name)?;
+
+        let chunk = self
+            .app_server
+            .read()
+            .await
+            .close_chunk(&db_name, &partition_key)
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "closed chunk {} of partition {} in database {}",
+            chunk.id, partition_key, db_name
+        );
+        Ok(chunk.into())
+    }
+
+    async fn move_chunk_impl(
+        &self,
+        db_name: String,
+        partition_key: String,
+        chunk_id: u32,
+    ) -> Result<management::Chunk> {
+        ensure_db_name(&db_name)?;
+
+        let chunk = self
+            .app_server
+            .read()
+            .await
+            .move_chunk(&db_name, &partition_key, chunk_id)
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "moved chunk {} of partition {} in database {} to the read buffer",
+            chunk.id, partition_key, db_name
+        );
+        Ok(chunk.into())
+    }
+
+    async fn persist_chunk_impl(
+        &self,
+        db_name: String,
+        partition_key: String,
+        chunk_id: u32,
+    ) -> Result<()> {
+        ensure_db_name(&db_name)?;
+
+        let persisted = self
+            .app_server
+            .read()
+            .await
+            .persist_chunk(&db_name, &partition_key, chunk_id)
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "persisted chunk {} of partition {} in database {} as {} files",
+            chunk_id,
+            partition_key,
+            db_name,
+            persisted.len()
+        );
+        Ok(())
+    }
+
+    async fn set_chunk_policy_impl(&self, request: SetChunkPolicyRequest) -> Result<()> {
+        let SetChunkPolicyRequest { db_name, policy } = request;
+        ensure_db_name(&db_name)?;
+        let policy = convert_chunk_policy(policy.context(MissingChunkPolicy)?);
+
+        let description = format!("{:?}", policy);
+        self.app_server
+            .read()
+            .await
+            .set_chunk_policy(&db_name, policy)
+            .await
+            .context(ServerError)?;
+
+        info!("set chunk policy {} of database {}", description, db_name);
+        Ok(())
+    }
+
+    async fn delete_chunk_policy_impl(&self, request: DeleteChunkPolicyRequest) -> Result<()> {
+        let DeleteChunkPolicyRequest {
+            db_name,
+            partition_key,
+            chunk_id,
+        } = request;
+        ensure_db_name(&db_name)?;
+        let chunk_id = chunk_id.map(|chunk_id| chunk_id.id);
+
+        self.app_server
+            .read()
+            .await
+            .remove_chunk_policy(&db_name, &partition_key, chunk_id)
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "removed policy of chunk {:?} of partition {} of database {}",
+            chunk_id, partition_key, db_name
+        );
+        Ok(())
+    }
+
+    async fn export_database_impl(
+        &self,
+        request: ExportDatabaseRequest,
+    ) -> Result<management::Operation> {
+        let ExportDatabaseRequest {
+            db_name,
+            table,
+            partition_key,
+            range,
+            output,
+        } = request;
+        ensure_db_name(&db_name)?;
+        ensure!(!output.is_empty(), MissingOutput);
+
+        let (store, prefix) = ObjectStore::from_url(&output);
+        let non_empty = |s: &str| if s.is_empty() { None } else { Some(s) };
+        let range = range.map(|range| TimestampRange::new(range.start, range.end));
+
+        let tracker = self
+            .app_server
+            .read()
+            .await
+            .export_database(
+                &db_name,
+                non_empty(&table),
+                non_empty(&partition_key),
+                range,
+                Arc::new(store),
+                &prefix,
+            )
+            .await
+            .context(ServerError)?;
+
+        info!("exporting database {} to {}", db_name, output);
+        Ok(to_operation(&tracker))
+    }
+
+    async fn snapshot_database_impl(
+        &self,
+        request: SnapshotDatabaseRequest,
+    ) -> Result<management::Operation> {
+        let SnapshotDatabaseRequest { db_name, output } = request;
+        ensure_db_name(&db_name)?;
+        ensure!(!output.is_empty(), MissingOutput);
+
+        let (store, prefix) = ObjectStore::from_url(&output);
+        let tracker = self
+            .app_server
+            .read()
+            .await
+            .snapshot_database(&db_name, Arc::new(store), &prefix)
+            .await
+            .context(ServerError)?;
+
+        info!("taking a snapshot of database {} to {}", db_name, output);
+        Ok(to_operation(&tracker))
+    }
+
+    async fn restore_database_impl(
+        &self,
+        request: RestoreDatabaseRequest,
+    ) -> Result<Vec<management::PersistedChunk>> {
+        let RestoreDatabaseRequest { db_name, input } = request;
+        ensure_db_name(&db_name)?;
+        ensure!(!input.is_empty(), MissingInput);
+
+        // the files are copied without holding up the requests to the server, which is only
+        // locked to create the database once they are all copied
+        let (store, prefix) = ObjectStore::from_url(&input);
+        let snapshot = self
+            .app_server
+            .read()
+            .await
+            .copy_snapshot(&db_name, &store, &prefix)
+            .await
+            .context(ServerError)?;
+        let chunks = snapshot.catalog.chunks();
+
+        self.app_server
+            .write()
+            .await
+            .restore_database(&db_name, snapshot)
+            .await
+            .context(ServerError)?;
+
+        info!("restored database {} from {}", db_name, input);
+        Ok(chunks.into_iter().map(Into::into).collect())
+    }
+
+    async fn import_data_impl(
+        &self,
+        request: ImportDataRequest,
+    ) -> Result<Vec<management::PersistedChunk>> {
+        let ImportDataRequest {
+            db_name,
+            partition_key,
+            format,
+            mapping,
+            data,
+        } = request;
+        ensure_db_name(&db_name)?;
+
+        let format = match management::FileFormat::from_i32(format) {
+            Some(management::FileFormat::Csv) => FileFormat::Csv,
+            Some(management::FileFormat::Parquet) => FileFormat::Parquet,
+            _ => {
+                return InvalidMapping {
+                    description: "the file format is required",
+                }
+                .fail()
+            }
+        };
+        let mapping = convert_mapping(mapping.context(MissingMapping)?)?;
+
+        let table =
+            tokio::task::spawn_blocking(move || ingest::import::convert(format, data, &mapping))
+                .await
+                .context(ImportPanicked)?
+                .context(ImportingData)?;
+
+        let app_server = self.app_server.read().await;
+        let chunks = if partition_key.is_empty() {
+            app_server.import_partitioned_table(&db_name, table).await
+        } else {
+            app_server
+                .import_table(&db_name, &partition_key, table)
+                .await
+                .map(|chunk| vec![chunk])
+        }
+        .context(ServerError)?;
+
+        for chunk in &chunks {
+            info!(
+                "imported {} rows into chunk {} of partition {} in database {}",
+                chunk.row_count, chunk.id, chunk.partition_key, db_name
+            );
+        }
+        Ok(chunks.into_iter().map(Into::into).collect())
+    }
+
+    async fn delete_impl(&self, request: DeleteRequest) -> Result<()> {
+        let DeleteRequest {
+            db_name,
+            table_name,
+            range,
+            tags,
+        } = request;
+        ensure_db_name(&db_name)?;
+        ensure!(!table_name.is_empty(), MissingTableName);
+
+        // without a range, the rows of every time are deleted
+        let (start, end) = range.map_or((i64::MIN, i64::MAX), |range| (range.start, range.end));
+        let predicate = DeletePredicate {
+            table_name,
+            start,
+            end,
+            tags: tags.into_iter().collect(),
+        };
+
+        self.app_server
+            .read()
+            .await
+            .delete(&db_name, predicate.clone())
+            .await
+            .context(ServerError)?;
+
+        info!("deleted {:?} from database {}", predicate, db_name);
+        Ok(())
+    }
+
+    async fn load_dimension_table_impl(
+        &self,
+        request: LoadDimensionTableRequest,
+    ) -> Result<LoadDimensionTableResponse> {
+        let LoadDimensionTableRequest {
+            db_name,
+            table_name,
+            data,
+        } = request;
+        ensure_db_name(&db_name)?;
+        ensure!(!table_name.is_empty(), MissingTableName);
+
+        let row_count = self
+            .app_server
+            .read()
+            .await
+            .load_dimension_table(&db_name, &table_name, data)
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "loaded dimension table {} of database {} with {} rows",
+            table_name, db_name, row_count
+        );
+        Ok(LoadDimensionTableResponse {
+            row_count: row_count as u64,
+        })
+    }
+
+    async fn drop_dimension_table_impl(&self, request: DropDimensionTableRequest) -> Result<()> {
+        let DropDimensionTableRequest {
+            db_name,
+            table_name,
+        } = request;
+        ensure_db_name(&db_name)?;
+        ensure!(!table_name.is_empty(), MissingTableName);
+
+        self.app_server
+            .read()
+            .await
+            .drop_dimension_table(&db_name, &table_name)
+            .await
+            .context(ServerError)?;
+
+        info!(
+            "dropped dimension table {} of database {}",
+            table_name, db_name
+        );
+        Ok(())
+    }
+
+    async fn rebuild_catalog_impl(
+        &self,
+        request: RebuildCatalogRequest,
+    ) -> Result<RebuildCatalogResponse> {
+        let RebuildCatalogRequest { db_name } = request;
+        ensure_db_name(&db_name)?;
+
+        let rebuilt = self
+            .app_server
+            .read()
+            .await
+            .rebuild_catalog(&db_name)
+            .await
+            .context(ServerError)?;
+
+        Ok(RebuildCatalogResponse {
+            chunks: rebuilt.chunks.into_iter().map(Into::into).collect(),
+            skipped: rebuilt
+                .skipped
+                .into_iter()
+                .map(|(location, reason)| management::SkippedFile {
+                    location,
+                    reason: reason.to_string(),
+                })
+                .collect(),
+        })
+    }
+
+    async fn force_claim_database_impl(&self, db_name: String) -> Result<u64> {
+        ensure_db_name(&db_name)?;
+
+        let token = self
+            .app_server
+            .read()
+            .await
+            .force_claim_database(&db_name)
+            .await
+            .context(ServerError)?;
+
+        warn!(
+            "force-claimed database {}, with lease token {}",
+            db_name, token
+        );
+        Ok(token)
+    }
+
+    async fn verify_catalog_impl(
+        &self,
+        request: VerifyCatalogRequest,
+    ) -> Result<Vec<VerifiedFile>> {
+        let VerifyCatalogRequest { db_name } = request;
+        let db_name = Some(db_name.as_str()).filter(|db_name| !db_name.is_empty());
+
+        let checked = self
+            .app_server
+            .read()
+            .await
+            .verify_persisted_chunks(db_name)
+            .await
+            .context(ServerError)?;
+
+        Ok(checked
+            .into_iter()
+            .map(|(db_name, chunk, status)| {
+                let code = match status {
+                    FileStatus::Ok => management::verified_file::Status::Ok,
+                    FileStatus::Unverified => management::verified_file::Status::Unverified,
+                    FileStatus::Missing { .. } => management::verified_file::Status::Missing,
+                    FileStatus::SizeMismatch { .. } | FileStatus::ChecksumMismatch { .. } => {
+                        management::verified_file::Status::Corrupt
+                    }
+                };
+                VerifiedFile {
+                    db_name,
+                    chunk: Some(chunk.into()),
+                    status: code as i32,
+                    description: status.to_string(),
+                }
+            })
+            .collect())
+    }
+}
+
+#[tonic::async_trait]
+impl<M> management_service_server::ManagementService for ManagementService<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    async fn get_writer_id(
+        &self,
+        req: Request<GetWriterIdRequest>,
+    ) -> Result<Response<GetWriterIdResponse>, Status> {
+        self.authorize(&req, None)?;
+        match self.app_server.read().await.id() {
+            Some(id) => Ok(Response::new(GetWriterIdResponse { id })),
+            None => Err(Error::ServerError {
+                source: cluster::Error::IdNotSet,
+            }
+            .to_status()),
+        }
+    }
+
+    async fn update_writer_id(
+        &self,
+        req: Request<UpdateWriterIdRequest>,
+    ) -> Result<Response<UpdateWriterIdResponse>, Status> {
+        self.authorize(&req, None)?;
+        let id = req.into_inner().id;
+        self.app_server.write().await.set_id(id);
+
+        info!("updated writer id to {}", id);
+        Ok(Response::new(UpdateWriterIdResponse {}))
+    }
+
+    async fn list_databases(
+        &self,
+        req: Request<ListDatabasesRequest>,
+    ) -> Result<Response<ListDatabasesResponse>, Status> {
+        let names = self.app_server.read().await.db_names();
+
+        // without the manage permission on the whole server, only the databases it may
+        // manage are listed
+        let names = match self.authorize(&req, None) {
+            Ok(()) => names,
+            Err(status) => {
+                let names: Vec<_> = names
+                    .into_iter()
+                    .filter(|name| self.authorize(&req, Some(name)).is_ok())
+                    .collect();
+                if names.is_empty() {
+                    return Err(status);
+                }
+                names
+            }
+        };
+
+        Ok(Response::new(ListDatabasesResponse { names }))
+    }
+
+    async fn get_database(
+        &self,
+        req: Request<GetDatabaseRequest>,
+    ) -> Result<Response<GetDatabaseResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().name))?;
+        let GetDatabaseRequest { name } = req.into_inner();
+
+        let rules = self
+            .get_database_rules_impl(name.clone())
+            .await
+            .map_err(|e| e.to_status())?;
+        let app_server = self.app_server.read().await;
+        let ingest_paused = app_server
+            .is_ingest_paused(&name)
+            .context(ServerError)
+            .map_err(|e| e.to_status())?;
+        let wal_replay = app_server
+            .wal_replay(&name)
+            .context(ServerError)
+            .map_err(|e| e.to_status())?;
+
+        Ok(Response::new(GetDatabaseResponse {
+            rules: Some(rules),
+            ingest_paused,
+            wal_replay: wal_replay.as_ref().map(to_wal_replay),
+        }))
+    }
+
+    async fn create_database(
+        &self,
+        req: Request<CreateDatabaseRequest>,
+    ) -> Result<Response<CreateDatabaseResponse>, Status> {
+        self.authorize(
+            &req,
+            req.get_ref()
+                .rules
+                .as_ref()
+                .map(|rules| rules.name.as_str()),
+        )?;
+        let audit = self.audit_request(&req);
+        let CreateDatabaseRequest { rules } = req.into_inner();
+        let db_name = rules.as_ref().map(|rules| rules.name.clone());
+
+        let result = self.create_database_impl(rules).await;
+        self.audit(audit, "CreateDatabase", db_name, &result).await;
+        result
+            .map(|_| Response::new(CreateDatabaseResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn update_database_rules(
+        &self,
+        req: Request<UpdateDatabaseRulesRequest>,
+    ) -> Result<Response<UpdateDatabaseRulesResponse>, Status> {
+        self.authorize(
+            &req,
+            req.get_ref()
+                .rules
+                .as_ref()
+                .map(|rules| rules.name.as_str()),
+        )?;
+        let audit = self.audit_request(&req);
+        let UpdateDatabaseRulesRequest { rules } = req.into_inner();
+        let db_name = rules.as_ref().map(|rules| rules.name.clone());
+
+        let result = self.update_database_rules_impl(rules).await;
+        self.audit(audit, "UpdateDatabaseRules", db_name, &result)
+            .await;
+        result
+            .map(|generation| Response::new(UpdateDatabaseRulesResponse { generation }))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn list_database_rules_versions(
+        &self,
+        req: Request<ListDatabaseRulesVersionsRequest>,
+    ) -> Result<Response<ListDatabaseRulesVersionsResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let ListDatabaseRulesVersionsRequest { db_name } = req.into_inner();
+
+        self.list_database_rules_versions_impl(db_name)
+            .await
+            .map(|versions| Response::new(ListDatabaseRulesVersionsResponse { versions }))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn rollback_database_rules(
+        &self,
+        req: Request<RollbackDatabaseRulesRequest>,
+    ) -> Result<Response<RollbackDatabaseRulesResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let audit = self.audit_request(&req);
+        let RollbackDatabaseRulesRequest {
+            db_name,
+            generation,
+        } = req.into_inner();
+
+        let result = self
+            .rollback_database_rules_impl(db_name.clone(), generation)
+            .await;
+        self.audit(audit, "RollbackDatabaseRules", Some(db_name), &result)
+            .await;
+        result
+            .map(|generation| Response::new(RollbackDatabaseRulesResponse { generation }))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn release_database(
+        &self,
+        req: Request<ReleaseDatabaseRequest>,
+    ) -> Result<Response<ReleaseDatabaseResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().name))?;
+        let audit = self.audit_request(&req);
+        let ReleaseDatabaseRequest { name } = req.into_inner();
+
+        let result = self.release_database_impl(name.clone()).await;
+        self.audit(audit, "ReleaseDatabase", Some(name), &result)
+            .await;
+        result
+            .map(|_| Response::new(ReleaseDatabaseResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn pause_ingest(
+        &self,
+        req: Request<PauseIngestRequest>,
+    ) -> Result<Response<PauseIngestResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let audit = self.audit_request(&req);
+        let PauseIngestRequest { db_name, paused } = req.into_inner();
+
+        let result = self.pause_ingest_impl(db_name.clone(), paused).await;
+        self.audit(audit, "PauseIngest", Some(db_name), &result)
+            .await;
+        result
+            .map(|_| Response::new(PauseIngestResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn skip_wal_replay(
+        &self,
+        req: Request<SkipWalReplayRequest>,
+    ) -> Result<Response<SkipWalReplayResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let audit = self.audit_request(&req);
+        let SkipWalReplayRequest {
+            db_name,
+            sequence_number,
+        } = req.into_inner();
+
+        let result = self
+            .skip_wal_replay_impl(db_name.clone(), sequence_number)
+            .await;
+        self.audit(audit, "SkipWalReplay", Some(db_name), &result)
+            .await;
+        result
+            .map(|replay| {
+                Response::new(SkipWalReplayResponse {
+                    wal_replay: Some(to_wal_replay(&replay)),
+                })
+            })
+            .map_err(|e| e.to_status())
+    }
+
+    async fn list_chunks(
+        &self,
+        req: Request<ListChunksRequest>,
+    ) -> Result<Response<ListChunksResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let ListChunksRequest { db_name } = req.into_inner();
+
+        self.list_chunks_impl(db_name)
+            .await
+            .map(|chunks| Response::new(ListChunksResponse { chunks }))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn close_chunk(
+        &self,
+        req: Request<CloseChunkRequest>,
+    ) -> Result<Response<CloseChunkResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let CloseChunkRequest {
+            db_name,
+            partition_key,
+        } = req.into_inner();
+
+        self.close_chunk_impl(db_name, partition_key)
+            .await
+            .map(|chunk| Response::new(CloseChunkResponse { chunk: Some(chunk) }))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn move_chunk(
+        &self,
+        req: Request<MoveChunkRequest>,
+    ) -> Result<Response<MoveChunkResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let MoveChunkRequest {
+            db_name,
+            partition_key,
+            chunk_id,
+        } = req.into_inner();
+
+        self.move_chunk_impl(db_name, partition_key, chunk_id)
+            .await
+            .map(|chunk| Response::new(MoveChunkResponse { chunk: Some(chunk) }))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn persist_chunk(
+        &self,
+        req: Request<PersistChunkRequest>,
+    ) -> Result<Response<PersistChunkResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let PersistChunkRequest {
+            db_name,
+            partition_key,
+            chunk_id,
+        } = req.into_inner();
+
+        self.persist_chunk_impl(db_name, partition_key, chunk_id)
+            .await
+            .map(|()| Response::new(PersistChunkResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn set_chunk_policy(
+        &self,
+        req: Request<SetChunkPolicyRequest>,
+    ) -> Result<Response<SetChunkPolicyResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let audit = self.audit_request(&req);
+        let request = req.into_inner();
+        let db_name = request.db_name.clone();
+
+        let result = self.set_chunk_policy_impl(request).await;
+        self.audit(audit, "SetChunkPolicy", Some(db_name), &result)
+            .await;
+        result
+            .map(|()| Response::new(SetChunkPolicyResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn list_chunk_policies(
+        &self,
+        req: Request<ListChunkPoliciesRequest>,
+    ) -> Result<Response<ListChunkPoliciesResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let ListChunkPoliciesRequest { db_name } = req.into_inner();
+        ensure_db_name(&db_name).map_err(|e| e.to_status())?;
+
+        self.app_server
+            .read()
+            .await
+            .chunk_policies(&db_name)
+            .context(ServerError)
+            .map(|policies| {
+                Response::new(ListChunkPoliciesResponse {
+                    policies: policies.into_iter().map(to_chunk_policy).collect(),
+                })
+            })
+            .map_err(|e| e.to_status())
+    }
+
+    async fn delete_chunk_policy(
+        &self,
+        req: Request<DeleteChunkPolicyRequest>,
+    ) -> Result<Response<DeleteChunkPolicyResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let audit = self.audit_request(&req);
+        let request = req.into_inner();
+        let db_name = request.db_name.clone();
+
+        let result = self.delete_chunk_policy_impl(request).await;
+        self.audit(audit, "DeleteChunkPolicy", Some(db_name), &result)
+            .await;
+        result
+            .map(|()| Response::new(DeleteChunkPolicyResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn export_database(
+        &self,
+        req: Request<ExportDatabaseRequest>,
+    ) -> Result<Response<ExportDatabaseResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        self.export_database_impl(req.into_inner())
+            .await
+            .map(|operation| {
+                Response::new(ExportDatabaseResponse {
+                    operation: Some(operation),
+                })
+            })
+            .map_err(|e| e.to_status())
+    }
+
+    async fn snapshot_database(
+        &self,
+        req: Request<SnapshotDatabaseRequest>,
+    ) -> Result<Response<SnapshotDatabaseResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        self.snapshot_database_impl(req.into_inner())
+            .await
+            .map(|operation| {
+                Response::new(SnapshotDatabaseResponse {
+                    operation: Some(operation),
+                })
+            })
+            .map_err(|e| e.to_status())
+    }
+
+    async fn restore_database(
+        &self,
+        req: Request<RestoreDatabaseRequest>,
+    ) -> Result<Response<RestoreDatabaseResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let audit = self.audit_request(&req);
+        let request = req.into_inner();
+        let db_name = request.db_name.clone();
+
+        let result = self.restore_database_impl(request).await;
+        self.audit(audit, "RestoreDatabase", Some(db_name), &result)
+            .await;
+        result
+            .map(|chunks| Response::new(RestoreDatabaseResponse { chunks }))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn import_data(
+        &self,
+        req: Request<ImportDataRequest>,
+    ) -> Result<Response<ImportDataResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        self.import_data_impl(req.into_inner())
+            .await
+            .map(|chunks| Response::new(ImportDataResponse { chunks }))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn delete(
+        &self,
+        req: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let audit = self.audit_request(&req);
+        let request = req.into_inner();
+        let db_name = request.db_name.clone();
+
+        let result = self.delete_impl(request).await;
+        self.audit(audit, "Delete", Some(db_name), &result).await;
+        result
+            .map(|()| Response::new(DeleteResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn load_dimension_table(
+        &self,
+        req: Request<LoadDimensionTableRequest>,
+    ) -> Result<Response<LoadDimensionTableResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let audit = self.audit_request(&req);
+        let request = req.into_inner();
+        let db_name = request.db_name.clone();
+
+        let result = self.load_dimension_table_impl(request).await;
+        self.audit(audit, "LoadDimensionTable", Some(db_name), &result)
+            .await;
+        result.map(Response::new).map_err(|e| e.to_status())
+    }
+
+    async fn drop_dimension_table(
+        &self,
+        req: Request<DropDimensionTableRequest>,
+    ) -> Result<Response<DropDimensionTableResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let audit = self.audit_request(&req);
+        let request = req.into_inner();
+        let db_name = request.db_name.clone();
+
+        let result = self.drop_dimension_table_impl(request).await;
+        self.audit(audit, "DropDimensionTable", Some(db_name), &result)
+            .await;
+        result
+            .map(|()| Response::new(DropDimensionTableResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn rebuild_catalog(
+        &self,
+        req: Request<RebuildCatalogRequest>,
+    ) -> Result<Response<RebuildCatalogResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let response = self
+            .rebuild_catalog_impl(req.into_inner())
+            .await
+            .map_err(|e| e.to_status())?;
+
+        info!(
+            "rebuilt catalog with {} chunks, skipping {} files",
+            response.chunks.len(),
+            response.skipped.len()
+        );
+        Ok(Response::new(response))
+    }
+
+    async fn force_claim_database(
+        &self,
+        req: Request<ForceClaimDatabaseRequest>,
+    ) -> Result<Response<ForceClaimDatabaseResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        let audit = self.audit_request(&req);
+        let ForceClaimDatabaseRequest { db_name } = req.into_inner();
+
+        let result = self.force_claim_database_impl(db_name.clone()).await;
+        self.audit(audit, "ForceClaimDatabase", Some(db_name), &result)
+            .await;
+        result
+            .map(|token| Response::new(ForceClaimDatabaseResponse { token }))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn verify_catalog(
+        &self,
+        req: Request<VerifyCatalogRequest>,
+    ) -> Result<Response<VerifyCatalogResponse>, Status> {
+        self.authorize(&req, Some(&req.get_ref().db_name))?;
+        self.verify_catalog_impl(req.into_inner())
+            .await
+            .map(|files| Response::new(VerifyCatalogResponse { files }))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn create_dummy_job(
+        &self,
+        req: Request<CreateDummyJobRequest>,
+    ) -> Result<Response<CreateDummyJobResponse>, Status> {
+        self.authorize(&req, None)?;
+        let CreateDummyJobRequest { nanos } = req.into_inner();
+
+        let tracker =
+            self.app_server
+                .read()
+                .await
+                .jobs()
+                .spawn("dummy job", |progress| async move {
+                    progress.set_total(nanos.len());
+                    for duration in nanos {
+                        tokio::time::delay_for(Duration::from_nanos(duration)).await;
+                        progress.inc_completed(1);
+                    }
+                    Ok::<_, Infallible>(())
+                });
+
+        Ok(Response::new(CreateDummyJobResponse {
+            operation: Some(to_operation(&tracker)),
+        }))
+    }
+
+    async fn create_token(
+        &self,
+        req: Request<CreateTokenRequest>,
+    ) -> Result<Response<CreateTokenResponse>, Status> {
+        self.authorize(&req, None)?;
+        let audit = self.audit_request(&req);
+        let CreateTokenRequest { token } = req.into_inner();
+
+        let result = token
+            .context(MissingToken)
+            .and_then(|token| token.try_into().context(TokenError))
+            .and_then(|token: auth::Token| {
+                let id = token.id.clone();
+                let secret = self.authorizer.create_token(token).context(TokenError)?;
+                Ok((id, secret))
+            });
+        self.audit(audit, "CreateToken", None, &result).await;
+        let (id, secret) = result.map_err(|e| e.to_status())?;
+
+        info!("created token {}", id);
+        Ok(Response::new(CreateTokenResponse { secret }))
+    }
+
+    async fn list_tokens(
+        &self,
+        req: Request<ListTokensRequest>,
+    ) -> Result<Response<ListTokensResponse>, Status> {
+        self.authorize(&req, None)?;
+        let tokens = self
+            .authorizer
+            .tokens()
+            .into_iter()
+            .map(Into::into)
+            .collect();
+
+        Ok(Response::new(ListTokensResponse { tokens }))
+    }
+
+    async fn delete_token(
+        &self,
+        req: Request<DeleteTokenRequest>,
+    ) -> Result<Response<DeleteTokenResponse>, Status> {
+        self.authorize(&req, None)?;
+        let audit = self.audit_request(&req);
+        let DeleteTokenRequest { id } = req.into_inner();
+
+        let result = self.authorizer.delete_token(&id);
+        self.audit(audit, "DeleteToken", None, &result).await;
+        result.map_err(|e| e.to_status())?;
+
+        info!("deleted token {}", id);
+        Ok(Response::new(DeleteTokenResponse {}))
+    }
+
+    async fn create_task(
+        &self,
+        req: Request<CreateTaskRequest>,
+    ) -> Result<Response<CreateTaskResponse>, Status> {
+        self.authorize(&req, None)?;
+        let CreateTaskRequest { task } = req.into_inner();
+
+        self.create_task_impl(task)
+            .await
+            .map(|_| Response::new(CreateTaskResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn list_tasks(
+        &self,
+        req: Request<ListTasksRequest>,
+    ) -> Result<Response<ListTasksResponse>, Status> {
+        self.authorize(&req, None)?;
+        let app_server = self.app_server.read().await;
+        let tasks = app_server
+            .tasks()
+            .into_iter()
+            .map(|task| management::Task {
+                name: task.name,
+                db_name: task.db_name,
+                query: task.query,
+                every_nanos: task.every.as_nanos() as u64,
+                destination_db: task.destination_db,
+                destination_table: task.destination_table,
+                paused: task.paused,
+            })
+            .collect();
+        let runs = app_server
+            .task_runs()
+            .into_iter()
+            .map(|run| management::TaskRun {
+                task: run.task,
+                window_start: run.window.0,
+                window_end: run.window.1,
+                rows: run.rows as u64,
+                error: run.error.unwrap_or_default(),
+                duration_nanos: run.duration.as_nanos() as u64,
+            })
+            .collect();
+
+        Ok(Response::new(ListTasksResponse { tasks, runs }))
+    }
+
+    async fn pause_task(
+        &self,
+        req: Request<PauseTaskRequest>,
+    ) -> Result<Response<PauseTaskResponse>, Status> {
+        self.authorize(&req, None)?;
+        let PauseTaskRequest { name, paused } = req.into_inner();
+
+        self.pause_task_impl(name, paused)
+            .await
+            .map(|_| Response::new(PauseTaskResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn delete_task(
+        &self,
+        req: Request<DeleteTaskRequest>,
+    ) -> Result<Response<DeleteTaskResponse>, Status> {
+        self.authorize(&req, None)?;
+        let DeleteTaskRequest { name } = req.into_inner();
+
+        self.delete_task_impl(name)
+            .await
+            .map(|_| Response::new(DeleteTaskResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn create_check(
+        &self,
+        req: Request<CreateCheckRequest>,
+    ) -> Result<Response<CreateCheckResponse>, Status> {
+        self.authorize(&req, None)?;
+        let CreateCheckRequest { check } = req.into_inner();
+
+        self.create_check_impl(check)
+            .await
+            .map(|_| Response::new(CreateCheckResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn list_checks(
+        &self,
+        req: Request<ListChecksRequest>,
+    ) -> Result<Response<ListChecksResponse>, Status> {
+        self.authorize(&req, None)?;
+        let (checks, states) = self
+            .app_server
+            .read()
+            .await
+            .checks()
+            .into_iter()
+            .map(|(check, state)| {
+                let state = to_check_state(&check.name, state);
+                (to_check(check), state)
+            })
+            .unzip();
+
+        Ok(Response::new(ListChecksResponse { checks, states }))
+    }
+
+    async fn delete_check(
+        &self,
+        req: Request<DeleteCheckRequest>,
+    ) -> Result<Response<DeleteCheckResponse>, Status> {
+        self.authorize(&req, None)?;
+        let DeleteCheckRequest { name } = req.into_inner();
+
+        self.delete_check_impl(name)
+            .await
+            .map(|_| Response::new(DeleteCheckResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn create_rules_template(
+        &self,
+        req: Request<CreateRulesTemplateRequest>,
+    ) -> Result<Response<CreateRulesTemplateResponse>, Status> {
+        self.authorize(&req, None)?;
+        let audit = self.audit_request(&req);
+        let CreateRulesTemplateRequest { rules } = req.into_inner();
+
+        let result = self.create_rules_template_impl(rules).await;
+        self.audit(audit, "CreateRulesTemplate", None, &result)
+            .await;
+        result
+            .map(|_| Response::new(CreateRulesTemplateResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn update_rules_template(
+        &self,
+        req: Request<UpdateRulesTemplateRequest>,
+    ) -> Result<Response<UpdateRulesTemplateResponse>, Status> {
+        self.authorize(&req, None)?;
+        let audit = self.audit_request(&req);
+        let UpdateRulesTemplateRequest { rules } = req.into_inner();
+
+        let result = self.update_rules_template_impl(rules).await;
+        self.audit(audit, "UpdateRulesTemplate", None, &result)
+            .await;
+        result
+            .map(|updated| {
+                let updated = updated
+                    .into_iter()
+                    .map(|(db_name, generation)| DatabaseRulesUpdate {
+                        db_name,
+                        generation,
+                    })
+                    .collect();
+                Response::new(UpdateRulesTemplateResponse { updated })
+            })
+            .map_err(|e| e.to_status())
+    }
+
+    async fn list_rules_templates(
+        &self,
+        req: Request<ListRulesTemplatesRequest>,
+    ) -> Result<Response<ListRulesTemplatesResponse>, Status> {
+        self.authorize(&req, None)?;
+        let templates = self
+            .app_server
+            .read()
+            .await
+            .rules_templates()
+            .into_iter()
+            .map(|(name, rules)| management::DatabaseRules {
+                name,
+                ..rules.into()
+            })
+            .collect();
+
+        Ok(Response::new(ListRulesTemplatesResponse { templates }))
+    }
+
+    async fn delete_rules_template(
+        &self,
+        req: Request<DeleteRulesTemplateRequest>,
+    ) -> Result<Response<DeleteRulesTemplateResponse>, Status> {
+        self.authorize(&req, None)?;
+        let audit = self.audit_request(&req);
+        let DeleteRulesTemplateRequest { name } = req.into_inner();
+
+        let result = self.delete_rules_template_impl(name).await;
+        self.audit(audit, "DeleteRulesTemplate", None, &result)
+            .await;
+        result
+            .map(|_| Response::new(DeleteRulesTemplateResponse {}))
+            .map_err(|e| e.to_status())
+    }
+}
+
+/// Converts the protobuf definition of a task, which is validated when it is created
+fn convert_task(task: management::Task) -> Task {
+    let management::Task {
+        name,
+        db_name,
+        query,
+        every_nanos,
+        destination_db,
+        destination_table,
+        paused,
+    } = task;
+
+    Task {
+        name,
+        db_name,
+        query,
+        every: Duration::from_nanos(every_nanos),
+        destination_db,
+        destination_table,
+        paused,
+    }
+}
+
+/// Converts the protobuf definition of a check, which is validated when it is created
+fn convert_check(check: management::Check) -> Result<Check> {
+    let management::Check {
+        name,
+        db_name,
+        query,
+        every_nanos,
+        direction,
+        warn,
+        crit,
+        hysteresis,
+        endpoints,
+    } = check;
+
+    let endpoints = endpoints
+        .into_iter()
+        .map(|endpoint| {
+            Ok(match endpoint.endpoint.context(MissingCheckEndpoint)? {
+                check_endpoint::Endpoint::WebhookUrl(url) => Endpoint::Webhook { url },
+                check_endpoint::Endpoint::SlackUrl(url) => Endpoint::Slack { url },
+                check_endpoint::Endpoint::PagerdutyRoutingKey(routing_key) => {
+                    Endpoint::PagerDuty { routing_key }
+                }
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(Check {
+        name,
+        db_name,
+        query,
+        every: Duration::from_nanos(every_nanos),
+        direction: match management::check::Direction::from_i32(direction) {
+            Some(management::check::Direction::Below) => Direction::Below,
+            _ => Direction::Above,
+        },
+        warn: warn.map(|threshold| threshold.value),
+        crit: crit.map(|threshold| threshold.value),
+        hysteresis,
+        endpoints,
+    })
+}
+
+fn to_check(check: Check) -> management::Check {
+    let threshold = |value| management::check::Threshold { value };
+    management::Check {
+        name: check.name,
+        db_name: check.db_name,
+        query: check.query,
+        every_nanos: check.every.as_nanos() as u64,
+        direction: match check.direction {
+            Direction::Above => management::check::Direction::Above,
+            Direction::Below => management::check::Direction::Below,
+        } as i32,
+        warn: check.warn.map(threshold),
+        crit: check.crit.map(threshold),
+        hysteresis: check.hysteresis,
+        endpoints: check
+            .endpoints
+            .into_iter()
+            .map(|endpoint| management::CheckEndpoint {
+                endpoint: Some(match endpoint {
+                    Endpoint::Webhook { url } => check_endpoint::Endpoint::WebhookUrl(url),
+                    Endpoint::Slack { url } => check_endpoint::Endpoint::SlackUrl(url),
+                    Endpoint::PagerDuty { routing_key } => {
+                        check_endpoint::Endpoint::PagerdutyRoutingKey(routing_key)
+                    }
+                }),
+            })
+            .collect(),
+    }
+}
+
+fn to_check_state(name: &str, state: CheckState) -> management::CheckState {
+    management::CheckState {
+        name: name.to_string(),
+        level: match state.level {
+            Level::Ok => management::CheckLevel::Ok,
+            Level::Warn => management::CheckLevel::Warn,
+            Level::Crit => management::CheckLevel::Crit,
+        } as i32,
+        value: state.value.unwrap_or(f64::NAN),
+        evaluated_at: state
+            .evaluated_at
+            .map_or(0, |evaluated_at| evaluated_at.timestamp_nanos()),
+        error: state.error.unwrap_or_default(),
+    }
+}
+
+/// Converts the protobuf definition of a chunk policy, which is validated when it is set
+fn convert_chunk_policy(policy: management::ChunkPolicy) -> ChunkPolicy {
+    let management::ChunkPolicy {
+        partition_key,
+        chunk_id,
+        pinned,
+        ttl_seconds,
+    } = policy;
+
+    ChunkPolicy {
+        partition_key,
+        chunk_id: chunk_id.map(|chunk_id| chunk_id.id),
+        pinned,
+        ttl: match ttl_seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        },
+    }
+}
+
+fn to_chunk_policy(policy: ChunkPolicy) -> management::ChunkPolicy {
+    management::ChunkPolicy {
+        partition_key: policy.partition_key,
+        chunk_id: policy
+            .chunk_id
+            .map(|id| management::chunk_policy::ChunkId { id }),
+        pinned: policy.pinned,
+        ttl_seconds: policy.ttl.map_or(0, |ttl| ttl.as_secs()),
+    }
+}
+
+fn to_wal_replay(replay: &WalReplay) -> management::WalReplay {
+    management::WalReplay {
+        replayed: replay.progress.replayed as u64,
+        skipped: replay.progress.skipped as u64,
+        next_sequence_number: replay.progress.next_sequence_number,
+        end_sequence_number: replay.progress.end_sequence_number,
+        lag: replay.progress.lag(),
+        duration_nanos: replay.duration.as_nanos() as u64,
+        error: replay.error.clone().unwrap_or_default(),
+    }
+}
+
+fn ensure_db_name(db_name: &str) -> Result<()> {
+    if db_name.is_empty() {
+        MissingDatabaseName.fail()
+    } else {
+        Ok(())
+    }
+}
+
+/// Converts the protobuf schema mapping of an import
+fn convert_mapping(mapping: management::SchemaMapping) -> Result<SchemaMapping> {
+    let management::SchemaMapping {
+        table,
+        tags,
+        fields,
+        time_column,
+        time_unit,
+    } = mapping;
+    ensure!(
+        !table.is_empty(),
+        InvalidMapping {
+            description: "the table name is required"
+        }
+    );
+    ensure!(
+        !time_column.is_empty(),
+        InvalidMapping {
+            description: "the time column is required"
+        }
+    );
+
+    let fields = fields
+        .into_iter()
+        .map(|field| {
+            let data_type = match management::FieldType::from_i32(field.r#type) {
+                Some(management::FieldType::Float) => DataType::Float,
+                Some(management::FieldType::Integer) => DataType::Integer,
+                Some(management::FieldType::String) => DataType::String,
+                Some(management::FieldType::Boolean) => DataType::Boolean,
+                _ => {
+                    return InvalidMapping {
+                        description: format!("field {} has no type", field.name),
+                    }
+                    .fail()
+                }
+            };
+            Ok((field.name, data_type))
+        })
+        .collect::<Result<_>>()?;
+
+    let time_unit = match management::TimeUnit::from_i32(time_unit) {
+        Some(management::TimeUnit::Microseconds) => TimeUnit::Microseconds,
+        Some(management::TimeUnit::Milliseconds) => TimeUnit::Milliseconds,
+        Some(management::TimeUnit::Seconds) => TimeUnit::Seconds,
+        _ => TimeUnit::Nanoseconds,
+    };
+
+    Ok(SchemaMapping {
+        table,
+        tags,
+        fields,
+        time_column,
+        time_unit,
+    })
+}
+
+/// Splits the protobuf rules into the database name and the
+/// validated `DatabaseRules`
+fn convert_rules(rules: Option<management::DatabaseRules>) -> Result<(String, DatabaseRules)> {
+    let rules = rules.context(MissingRules)?;
+    let db_name = rules.name.clone();
+    ensure_db_name(&db_name)?;
+
+    let rules = rules.try_into().context(InvalidRules)?;
+
+    Ok((db_name, rules))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ConnectionManagerImpl;
+    use management_service_server::ManagementService as _;
+    use object_store::{InMemory, ObjectStore};
+    use tonic::Code;
+
+    fn make_service() -> ManagementService<ConnectionManagerImpl> {
+        let app_server = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        ManagementService::new(
+            Arc::new(RwLock::new(app_server)),
+            Arc::new(Authorizer::new(true)),
+        )
+    }
+
+    fn create_request(name: &str, store_locally: bool) -> Request<CreateDatabaseRequest> {
+        Request::new(CreateDatabaseRequest {
+            rules: Some(management::DatabaseRules {
+                name: name.to_string(),
+                store_locally,
+                ..Default::default()
+            }),
+        })
+    }
+
+    fn with_token<T>(mut request: Request<T>, secret: &str) -> Request<T> {
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Token {}", secret).parse().unwrap(),
+        );
+        request
+    }
+
+    #[tokio::test]
+    async fn test_requires_writer_id() {
+        let service = make_service();
+
+        let status = service
+            .get_writer_id(Request::new(GetWriterIdRequest {}))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        let status = service
+            .create_database(create_request("foo", false))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 42 }))
+            .await
+            .unwrap();
+        let response = service
+            .get_writer_id(Request::new(GetWriterIdRequest {}))
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().id, 42);
+    }
+
+    #[tokio::test]
+    async fn test_database_crud() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+
+        service
+            .create_database(create_request("foo", false))
+            .await
+            .unwrap();
+        service
+            .create_database(create_request("bar", true))
+            .await
+            .unwrap();
+
+        let status = service
+            .create_database(create_request("foo", false))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+
+        let status = service
+            .create_database(create_request("", false))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let names = service
+            .list_databases(Request::new(ListDatabasesRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .names;
+        assert_eq!(names, vec!["bar", "foo"]);
+
+        let rules = service
+            .get_database(Request::new(GetDatabaseRequest {
+                name: "bar".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .rules
+            .unwrap();
+        assert_eq!(rules.name, "bar");
+        assert!(rules.store_locally);
+
+        service
+            .update_database_rules(Request::new(UpdateDatabaseRulesRequest {
+                rules: Some(management::DatabaseRules {
+                    name: "bar".to_string(),
+                    store_locally: false,
+                    query_local: true,
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap();
+
+        let rules = service
+            .get_database(Request::new(GetDatabaseRequest {
+                name: "bar".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .rules
+            .unwrap();
+        assert!(!rules.store_locally);
+        assert!(rules.query_local);
+
+        let versions = service
+            .list_database_rules_versions(Request::new(ListDatabaseRulesVersionsRequest {
+                db_name: "bar".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .versions;
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].generation, 1);
+        assert!(versions[0].rules.as_ref().unwrap().store_locally);
+        assert_eq!(versions[1].generation, 2);
+
+        let generation = service
+            .rollback_database_rules(Request::new(RollbackDatabaseRulesRequest {
+                db_name: "bar".to_string(),
+                generation: 1,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .generation;
+        assert_eq!(generation, 3);
+
+        let rules = service
+            .get_database(Request::new(GetDatabaseRequest {
+                name: "bar".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .rules
+            .unwrap();
+        assert!(rules.store_locally);
+        assert!(!rules.query_local);
+
+        let status = service
+            .rollback_database_rules(Request::new(RollbackDatabaseRulesRequest {
+                db_name: "bar".to_string(),
+                generation: 10,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let status = service
+            .update_database_rules(Request::new(UpdateDatabaseRulesRequest {
+                rules: Some(management::DatabaseRules {
+                    name: "baz".to_string(),
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        service
+            .release_database(Request::new(ReleaseDatabaseRequest {
+                name: "foo".to_string(),
+            }))
+            .await
+            .unwrap();
+
+        let status = service
+            .get_database(Request::new(GetDatabaseRequest {
+                name: "foo".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let names = service
+            .list_databases(Request::new(ListDatabasesRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .names;
+        assert_eq!(names, vec!["bar"]);
+    }
+
+    #[tokio::test]
+    async fn test_chunks() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+        service
+            .create_database(create_request("foo", true))
+            .await
+            .unwrap();
+
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines("cpu bar=1 10")
+            .map(|l| l.unwrap())
+            .collect();
+        service
+            .app_server
+            .read()
+            .await
+            .write_lines("foo", &lines)
+            .await
+            .unwrap();
+
+        let chunks = service
+            .list_chunks(Request::new(ListChunksRequest {
+                db_name: "foo".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .chunks;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(
+            chunks[0].storage,
+            management::ChunkStorage::OpenMutableBuffer as i32
+        );
+        assert_eq!(chunks[0].row_count, 1);
+
+        let chunk = service
+            .close_chunk(Request::new(CloseChunkRequest {
+                db_name: "foo".to_string(),
+                partition_key: chunks[0].partition_key.clone(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .chunk
+            .unwrap();
+        assert_eq!(chunk.id, chunks[0].id);
+        assert_eq!(
+            chunk.storage,
+            management::ChunkStorage::ClosedMutableBuffer as i32
+        );
+
+        let status = service
+            .close_chunk(Request::new(CloseChunkRequest {
+                db_name: "foo".to_string(),
+                partition_key: "unknown".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let chunk = service
+            .move_chunk(Request::new(MoveChunkRequest {
+                db_name: "foo".to_string(),
+                partition_key: chunks[0].partition_key.clone(),
+                chunk_id: chunks[0].id,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .chunk
+            .unwrap();
+        assert_eq!(chunk.id, chunks[0].id);
+        assert_eq!(chunk.storage, management::ChunkStorage::ReadBuffer as i32);
+
+        // the chunk is no longer in the mutable buffer
+        let status = service
+            .move_chunk(Request::new(MoveChunkRequest {
+                db_name: "foo".to_string(),
+                partition_key: chunks[0].partition_key.clone(),
+                chunk_id: chunks[0].id,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_policies() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+        service
+            .create_database(create_request("foo", true))
+            .await
+            .unwrap();
+
+        let pinned = management::ChunkPolicy {
+            partition_key: "2020-11-01".to_string(),
+            chunk_id: None,
+            pinned: true,
+            ttl_seconds: 0,
+        };
+        let expiring = management::ChunkPolicy {
+            partition_key: "2020-11-01".to_string(),
+            chunk_id: Some(management::chunk_policy::ChunkId { id: 3 }),
+            pinned: false,
+            ttl_seconds: 3600,
+        };
+        for policy in &[&expiring, &pinned] {
+            service
+                .set_chunk_policy(Request::new(SetChunkPolicyRequest {
+                    db_name: "foo".to_string(),
+                    policy: Some((*policy).clone()),
+                }))
+                .await
+                .unwrap();
+        }
+
+        let list = || async {
+            service
+                .list_chunk_policies(Request::new(ListChunkPoliciesRequest {
+                    db_name: "foo".to_string(),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+                .policies
+        };
+        assert_eq!(list().await, vec![pinned.clone(), expiring.clone()]);
+
+        let status = service
+            .set_chunk_policy(Request::new(SetChunkPolicyRequest {
+                db_name: "foo".to_string(),
+                policy: Some(management::ChunkPolicy {
+                    ttl_seconds: 60,
+                    ..pinned.clone()
+                }),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        service
+            .delete_chunk_policy(Request::new(DeleteChunkPolicyRequest {
+                db_name: "foo".to_string(),
+                partition_key: "2020-11-01".to_string(),
+                chunk_id: None,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(list().await, vec![expiring]);
+
+        let status = service
+            .delete_chunk_policy(Request::new(DeleteChunkPolicyRequest {
+                db_name: "foo".to_string(),
+                partition_key: "2020-11-01".to_string(),
+                chunk_id: None,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_export_database() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+        service
+            .create_database(Request::new(CreateDatabaseRequest {
+                rules: Some(management::DatabaseRules {
+                    name: "foo".to_string(),
+                    store_locally: true,
+                    partition_template: Some(management::PartitionTemplate {
+                        parts: vec![management::partition_template::Part {
+                            part: Some(management::partition_template::part::Part::Time(
+                                "%Y-%m-%dT%H".to_string(),
+                            )),
+                        }],
+                    }),
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap();
+
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines("cpu bar=1 10\nmem used=2 20")
+            .map(|l| l.unwrap())
+            .collect();
+        service
+            .app_server
+            .read()
+            .await
+            .write_lines("foo", &lines)
+            .await
+            .unwrap();
+
+        let status = service
+            .export_database(Request::new(ExportDatabaseRequest {
+                db_name: "foo".to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let output = test_helpers::tmp_dir().unwrap();
+        let operation = service
+            .export_database(Request::new(ExportDatabaseRequest {
+                db_name: "foo".to_string(),
+                table: "cpu".to_string(),
+                output: output.path().to_string_lossy().to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .operation
+            .unwrap();
+
+        let tracker = service
+            .app_server
+            .read()
+            .await
+            .jobs()
+            .get(operation.id)
+            .unwrap();
+        tracker.join().await;
+        assert_eq!(tracker.error(), None);
+
+        let exported = output.path().join("foo").join("1970-01-01T00").join("0");
+        assert!(exported.join("cpu.parquet").exists());
+        assert!(!exported.join("mem.parquet").exists());
+
+        let status = service
+            .export_database(Request::new(ExportDatabaseRequest {
+                db_name: "bar".to_string(),
+                output: output.path().to_string_lossy().to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_and_restore_database() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+        service
+            .create_database(create_request("foo", true))
+            .await
+            .unwrap();
+        let chunk = service
+            .import_data(Request::new(ImportDataRequest {
+                db_name: "foo".to_string(),
+                partition_key: "import".to_string(),
+                format: management::FileFormat::Csv as i32,
+                mapping: Some(management::SchemaMapping {
+                    table: "cpu".to_string(),
+                    tags: vec!["host".to_string()],
+                    fields: vec![management::FieldSchema {
+                        name: "usage".to_string(),
+                        r#type: management::FieldType::Float as i32,
+                    }],
+                    time_column: "time".to_string(),
+                    time_unit: management::TimeUnit::Seconds as i32,
+                }),
+                data: b"host,usage,time\na,0.5,10\n".to_vec(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .chunks
+            .remove(0);
+
+        let output = test_helpers::tmp_dir().unwrap();
+        let operation = service
+            .snapshot_database(Request::new(SnapshotDatabaseRequest {
+                db_name: "foo".to_string(),
+                output: output.path().to_string_lossy().to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .operation
+            .unwrap();
+        let tracker = service
+            .app_server
+            .read()
+            .await
+            .jobs()
+            .get(operation.id)
+            .unwrap();
+        tracker.join().await;
+        assert_eq!(tracker.error(), None);
+        assert!(output.path().join("foo").join("snapshot.json").exists());
+
+        let input = output.path().join("foo").to_string_lossy().to_string();
+        let restore = RestoreDatabaseRequest {
+            db_name: "restored".to_string(),
+            input,
+        };
+        let chunks = service
+            .restore_database(Request::new(restore.clone()))
+            .await
+            .unwrap()
+            .into_inner()
+            .chunks;
+        assert_eq!(
+            chunks,
+            vec![management::PersistedChunk {
+                location: "1/restored/data/import/0/cpu.parquet".to_string(),
+                ..chunk
+            }]
+        );
+
+        let status = service
+            .restore_database(Request::new(restore.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+
+        let status = service
+            .restore_database(Request::new(RestoreDatabaseRequest {
+                input: "".to_string(),
+                ..restore
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_import_data() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+        service
+            .create_database(create_request("foo", true))
+            .await
+            .unwrap();
+
+        let request = ImportDataRequest {
+            db_name: "foo".to_string(),
+            partition_key: "import".to_string(),
+            format: management::FileFormat::Csv as i32,
+            mapping: Some(management::SchemaMapping {
+                table: "cpu".to_string(),
+                tags: vec!["host".to_string()],
+                fields: vec![management::FieldSchema {
+                    name: "usage".to_string(),
+                    r#type: management::FieldType::Float as i32,
+                }],
+                time_column: "time".to_string(),
+                time_unit: management::TimeUnit::Seconds as i32,
+            }),
+            data: b"host,usage,time\na,0.5,10\nb,0.25,20\n".to_vec(),
+        };
+        let chunk = service
+            .import_data(Request::new(request.clone()))
+            .await
+            .unwrap()
+            .into_inner()
+            .chunks
+            .remove(0);
+        assert_eq!(chunk.table_name, "cpu");
+        assert_eq!(chunk.partition_key, "import");
+        assert_eq!(chunk.row_count, 2);
+        assert_eq!(chunk.location, "1/foo/data/import/0/cpu.parquet");
+
+        // without a partition key, the rows are partitioned by the template of the database
+        let chunks = service
+            .import_data(Request::new(ImportDataRequest {
+                partition_key: "".to_string(),
+                ..request.clone()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .chunks;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].partition_key, "");
+        assert_eq!(chunks[0].row_count, 2);
+
+        let status = service
+            .import_data(Request::new(ImportDataRequest {
+                format: management::FileFormat::Unspecified as i32,
+                ..request.clone()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .import_data(Request::new(ImportDataRequest {
+                data: b"host,usage,time\na,high,10\n".to_vec(),
+                ..request.clone()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .import_data(Request::new(ImportDataRequest {
+                db_name: "bar".to_string(),
+                ..request
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let delete = DeleteRequest {
+            db_name: "foo".to_string(),
+            table_name: "cpu".to_string(),
+            range: Some(management::TimeRange { start: 0, end: 15 }),
+            tags: vec![("host".to_string(), "a".to_string())]
+                .into_iter()
+                .collect(),
+        };
+        service.delete(Request::new(delete.clone())).await.unwrap();
+        let tombstones = service.app_server.read().await.tombstones("foo").unwrap();
+        assert_eq!(tombstones.len(), 1);
+        assert_eq!(tombstones[0].predicate.tags["host"], "a");
+
+        let files = service
+            .verify_catalog(Request::new(VerifyCatalogRequest {
+                db_name: "".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .files;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].db_name, "foo");
+        assert_eq!(
+            files[0].status,
+            management::verified_file::Status::Ok as i32
+        );
+        assert_eq!(files[0].chunk.as_ref().unwrap().checksum.len(), 64);
+
+        let rebuilt = service
+            .rebuild_catalog(Request::new(RebuildCatalogRequest {
+                db_name: "foo".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(rebuilt.chunks, vec![files[0].chunk.clone().unwrap()]);
+        assert!(rebuilt.skipped.is_empty());
+
+        let status = service
+            .verify_catalog(Request::new(VerifyCatalogRequest {
+                db_name: "bar".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let status = service
+            .delete(Request::new(DeleteRequest {
+                table_name: "".to_string(),
+                ..delete.clone()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .delete(Request::new(DeleteRequest {
+                db_name: "bar".to_string(),
+                ..delete
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_force_claim_database() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+        service
+            .create_database(create_request("foo", true))
+            .await
+            .unwrap();
+        let request = ForceClaimDatabaseRequest {
+            db_name: "foo".to_string(),
+        };
+
+        let status = service
+            .force_claim_database(Request::new(request.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        service
+            .app_server
+            .write()
+            .await
+            .enable_ownership_leases("test", Duration::from_secs(60))
+            .unwrap();
+        let response = service
+            .force_claim_database(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.token, 1);
+
+        let status = service
+            .force_claim_database(Request::new(ForceClaimDatabaseRequest {
+                db_name: "bar".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_rules() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+
+        let status = service
+            .create_database(Request::new(CreateDatabaseRequest { rules: None }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .create_database(Request::new(CreateDatabaseRequest {
+                rules: Some(management::DatabaseRules {
+                    name: "foo".to_string(),
+                    replication_count: 1000,
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_tokens() {
+        let service = make_service();
+
+        let token = management::Token {
+            id: "telegraf".to_string(),
+            description: "metrics agent".to_string(),
+            scopes: vec![management::Scope {
+                permission: management::Permission::Write as _,
+                database: "metrics".to_string(),
+                ..Default::default()
+            }],
+        };
+        let secret = service
+            .create_token(Request::new(CreateTokenRequest {
+                token: Some(token.clone()),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .secret;
+        assert!(!secret.is_empty());
+
+        let status = service
+            .create_token(Request::new(CreateTokenRequest {
+                token: Some(token.clone()),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+
+        let status = service
+            .create_token(Request::new(CreateTokenRequest {
+                token: Some(management::Token {
+                    id: "other".to_string(),
+                    scopes: vec![management::Scope {
+                        permission: management::Permission::Unspecified as _,
+                        database: "metrics".to_string(),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let tokens = service
+            .list_tokens(Request::new(ListTokensRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .tokens;
+        assert_eq!(tokens, vec![token]);
+
+        service
+            .delete_token(Request::new(DeleteTokenRequest {
+                id: "telegraf".to_string(),
+            }))
+            .await
+            .unwrap();
+        let status = service
+            .delete_token(Request::new(DeleteTokenRequest {
+                id: "telegraf".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_database_manage_scope() {
+        let mut app_server = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        app_server.set_id(1);
+        let authorizer = Authorizer::new(false);
+        let secret = authorizer
+            .create_token(auth::Token {
+                id: "foo_admin".to_string(),
+                description: String::new(),
+                scopes: vec![auth::Scope {
+                    permission: auth::Permission::Manage,
+                    database: "foo".to_string(),
+                    measurements: vec![],
+                    tags: Default::default(),
+                }],
+            })
+            .unwrap();
+        let service =
+            ManagementService::new(Arc::new(RwLock::new(app_server)), Arc::new(authorizer));
+
+        // the token manages its database
+        service
+            .create_database(with_token(create_request("foo", true), &secret))
+            .await
+            .unwrap();
+        service
+            .list_chunks(with_token(
+                Request::new(ListChunksRequest {
+                    db_name: "foo".to_string(),
+                }),
+                &secret,
+            ))
+            .await
+            .unwrap();
+        let names = service
+            .list_databases(with_token(Request::new(ListDatabasesRequest {}), &secret))
+            .await
+            .unwrap()
+            .into_inner()
+            .names;
+        assert_eq!(names, vec!["foo"]);
+
+        // but not the other databases, nor the server
+        let status = service
+            .create_database(with_token(create_request("bar", true), &secret))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        let status = service
+            .update_writer_id(with_token(
+                Request::new(UpdateWriterIdRequest { id: 2 }),
+                &secret,
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+
+        let status = service
+            .list_chunks(Request::new(ListChunksRequest {
+                db_name: "foo".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app_server = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        app_server.set_id(1);
+        app_server.set_audit_log(
+            cluster::audit::AuditLog::open_file(dir.path().join("audit.jsonl")).unwrap(),
+        );
+        let authorizer = Authorizer::new(true);
+        let secret = authorizer
+            .create_token(auth::Token {
+                id: "admin".to_string(),
+                description: String::new(),
+                scopes: vec![auth::Scope {
+                    permission: auth::Permission::Manage,
+                    database: auth::ALL_DATABASES.to_string(),
+                    measurements: vec![],
+                    tags: Default::default(),
+                }],
+            })
+            .unwrap();
+        let app_server = Arc::new(RwLock::new(app_server));
+        let service = ManagementService::new(Arc::clone(&app_server), Arc::new(authorizer));
+
+        let mut request = create_request("mydb", true);
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Token {}", secret).parse().unwrap(),
+        );
+        let mut payload = vec![];
+        request.get_ref().encode(&mut payload).unwrap();
+        service.create_database(request).await.unwrap();
+        service
+            .release_database(Request::new(ReleaseDatabaseRequest {
+                name: "nope".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        service
+            .delete_token(Request::new(DeleteTokenRequest {
+                id: "admin".to_string(),
+            }))
+            .await
+            .unwrap();
+
+        let events = app_server.read().await.audit_log().unwrap().events();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| {
+                (
+                    e.actor.as_str(),
+                    e.operation.as_str(),
+                    e.db_name.as_deref(),
+                    e.error.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("Token admin", "CreateDatabase", Some("mydb"), false),
+                ("anonymous", "ReleaseDatabase", Some("nope"), true),
+                ("anonymous", "DeleteToken", None, false),
+            ]
+        );
+        assert_eq!(
+            events[0].payload_sha256,
+            AuditEvent::new("", "", None, &payload, None).payload_sha256
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pause_ingest() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+        service
+            .create_database(create_request("foo", true))
+            .await
+            .unwrap();
+
+        let service = &service;
+        let ingest_paused = move || async move {
+            service
+                .get_database(Request::new(GetDatabaseRequest {
+                    name: "foo".to_string(),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+                .ingest_paused
+        };
+        assert!(!ingest_paused().await);
+
+        service
+            .pause_ingest(Request::new(PauseIngestRequest {
+                db_name: "foo".to_string(),
+                paused: true,
+            }))
+            .await
+            .unwrap();
+        assert!(ingest_paused().await);
+
+        service
+            .pause_ingest(Request::new(PauseIngestRequest {
+                db_name: "foo".to_string(),
+                paused: false,
+            }))
+            .await
+            .unwrap();
+        assert!(!ingest_paused().await);
+
+        let status = service
+            .pause_ingest(Request::new(PauseIngestRequest {
+                db_name: "nope".to_string(),
+                paused: true,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_skip_wal_replay() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+        service
+            .create_database(create_request("foo", true))
+            .await
+            .unwrap();
+
+        // the database wasn't restored from a WAL
+        let database = service
+            .get_database(Request::new(GetDatabaseRequest {
+                name: "foo".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(database.wal_replay, None);
+
+        let skip = |db_name: &str, sequence_number| {
+            Request::new(SkipWalReplayRequest {
+                db_name: db_name.to_string(),
+                sequence_number,
+            })
+        };
+        let status = service.skip_wal_replay(skip("foo", 1)).await.unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        let status = service.skip_wal_replay(skip("nope", 1)).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_tasks() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+        service
+            .create_database(create_request("foo", true))
+            .await
+            .unwrap();
+
+        let task = management::Task {
+            name: "rollup".to_string(),
+            db_name: "foo".to_string(),
+            query: "SELECT host, avg(usage) AS usage FROM cpu GROUP BY host".to_string(),
+            every_nanos: 300_000_000_000,
+            destination_db: "foo".to_string(),
+            destination_table: "cpu_5m".to_string(),
+            paused: false,
+        };
+        service
+            .create_task(Request::new(CreateTaskRequest {
+                task: Some(task.clone()),
+            }))
+            .await
+            .unwrap();
+
+        let status = service
+            .create_task(Request::new(CreateTaskRequest {
+                task: Some(task.clone()),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+
+        let status = service
+            .create_task(Request::new(CreateTaskRequest {
+                task: Some(management::Task {
+                    name: "other".to_string(),
+                    every_nanos: 0,
+                    ..task.clone()
+                }),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .create_task(Request::new(CreateTaskRequest { task: None }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        service
+            .pause_task(Request::new(PauseTaskRequest {
+                name: "rollup".to_string(),
+                paused: true,
+            }))
+            .await
+            .unwrap();
+
+        let response = service
+            .list_tasks(Request::new(ListTasksRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            response.tasks,
+            vec![management::Task {
+                paused: true,
+                ..task
+            }]
+        );
+        assert!(response.runs.is_empty());
+
+        service
+            .delete_task(Request::new(DeleteTaskRequest {
+                name: "rollup".to_string(),
+            }))
+            .await
+            .unwrap();
+        let status = service
+            .delete_task(Request::new(DeleteTaskRequest {
+                name: "rollup".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_rules_templates() {
+        let service = make_service();
+        service
+            .update_writer_id(Request::new(UpdateWriterIdRequest { id: 1 }))
+            .await
+            .unwrap();
+
+        let template = management::DatabaseRules {
+            name: "tenant".to_string(),
+            store_locally: true,
+            retention_period_seconds: 3600,
+            ..Default::default()
+        };
+        service
+            .create_rules_template(Request::new(CreateRulesTemplateRequest {
+                rules: Some(template.clone()),
+            }))
+            .await
+            .unwrap();
+
+        service
+            .create_database(Request::new(CreateDatabaseRequest {
+                rules: Some(management::DatabaseRules {
+                    name: "foo".to_string(),
+                    template: Some(management::TemplateRef {
+                        name: "tenant".to_string(),
+                        overrides: vec![],
+                    }),
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap();
+        let status = service
+            .create_database(Request::new(CreateDatabaseRequest {
+                rules: Some(management::DatabaseRules {
+                    name: "bar".to_string(),
+                    template: Some(management::TemplateRef {
+                        name: "tenant".to_string(),
+                        overrides: vec!["retention".to_string()],
+                    }),
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let response = service
+            .update_rules_template(Request::new(UpdateRulesTemplateRequest {
+                rules: Some(management::DatabaseRules {
+                    retention_period_seconds: 60,
+                    ..template.clone()
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            response.updated,
+            vec![DatabaseRulesUpdate {
+                db_name: "foo".to_string(),
+                generation: 2,
+            }]
+        );
+
+        let rules = service
+            .get_database(Request::new(GetDatabaseRequest {
+                name: "foo".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .rules
+            .unwrap();
+        assert!(rules.store_locally);
+        assert_eq!(rules.retention_period_seconds, 60);
+
+        let response = service
+            .list_rules_templates(Request::new(ListRulesTemplatesRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        let names: Vec<_> = response.templates.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["tenant"]);
+
+        let status = service
+            .delete_rules_template(Request::new(DeleteRulesTemplateRequest {
+                name: "tenant".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+    }
+}
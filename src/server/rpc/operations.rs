@@ -0,0 +1,223 @@
+//! This module contains the implementation of the operations gRPC
+//! service, used to observe and control the background jobs tracked
+//! by a `cluster::Server`
+
+use std::{sync::Arc, time::Duration};
+
+use cluster::tracker::{Tracker, TrackerId, TrackerRegistry, TrackerStatus};
+use generated_types::management::{
+    self, operations_service_server, CancelOperationRequest, CancelOperationResponse,
+    GetOperationRequest, GetOperationResponse, ListOperationsRequest, ListOperationsResponse,
+    WaitOperationRequest, WaitOperationResponse,
+};
+
+use snafu::{OptionExt, Snafu};
+use tonic::{Request, Response, Status};
+use tracing::info;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Operation not found: {}", id))]
+    OperationNotFound { id: TrackerId },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Converts the error into the status of the failed operations request
+    fn to_status(&self) -> tonic::Status {
+        match &self {
+            Self::OperationNotFound { .. } => Status::not_found(self.to_string()),
+        }
+    }
+}
+
+/// Implements the protobuf defined operations service on top of the
+/// job registry of a `cluster::Server`. The registry is shared rather
+/// than reached through the server, so that the jobs can be followed
+/// while the server is locked, such as during the replay of the WALs
+/// at startup.
+#[derive(Debug)]
+pub struct OperationsService {
+    jobs: Arc<TrackerRegistry>,
+}
+
+impl OperationsService {
+    /// Create a new OperationsService for the jobs of `jobs`
+    pub fn new(jobs: Arc<TrackerRegistry>) -> Self {
+        Self { jobs }
+    }
+
+    fn get_tracker(&self, id: TrackerId) -> Result<Tracker> {
+        self.jobs.get(id).context(OperationNotFound { id })
+    }
+}
+
+#[tonic::async_trait]
+impl operations_service_server::OperationsService for OperationsService {
+    async fn list_operations(
+        &self,
+        _req: Request<ListOperationsRequest>,
+    ) -> Result<Response<ListOperationsResponse>, Status> {
+        let operations = self.jobs.list().iter().map(to_operation).collect();
+
+        Ok(Response::new(ListOperationsResponse { operations }))
+    }
+
+    async fn get_operation(
+        &self,
+        req: Request<GetOperationRequest>,
+    ) -> Result<Response<GetOperationResponse>, Status> {
+        let GetOperationRequest { id } = req.into_inner();
+
+        self.get_tracker(id)
+            .map(|tracker| {
+                Response::new(GetOperationResponse {
+                    operation: Some(to_operation(&tracker)),
+                })
+            })
+            .map_err(|e| e.to_status())
+    }
+
+    async fn cancel_operation(
+        &self,
+        req: Request<CancelOperationRequest>,
+    ) -> Result<Response<CancelOperationResponse>, Status> {
+        let CancelOperationRequest { id } = req.into_inner();
+
+        let tracker = self.get_tracker(id).map_err(|e| e.to_status())?;
+        tracker.cancel();
+
+        info!("requested cancellation of operation {}", id);
+        Ok(Response::new(CancelOperationResponse {}))
+    }
+
+    async fn wait_operation(
+        &self,
+        req: Request<WaitOperationRequest>,
+    ) -> Result<Response<WaitOperationResponse>, Status> {
+        let WaitOperationRequest { id, timeout_nanos } = req.into_inner();
+
+        let tracker = self.get_tracker(id).map_err(|e| e.to_status())?;
+
+        if timeout_nanos == 0 {
+            tracker.join().await;
+        } else {
+            // the latest state is returned whether or not the wait timed out
+            let _ = tokio::time::timeout(Duration::from_nanos(timeout_nanos), tracker.join()).await;
+        }
+
+        Ok(Response::new(WaitOperationResponse {
+            operation: Some(to_operation(&tracker)),
+        }))
+    }
+}
+
+/// Converts the current state of `tracker` into its protobuf representation
+pub fn to_operation(tracker: &Tracker) -> management::Operation {
+    let status = match tracker.status() {
+        TrackerStatus::Running => management::OperationStatus::Running,
+        TrackerStatus::Success => management::OperationStatus::Success,
+        TrackerStatus::Failure => management::OperationStatus::Failure,
+        TrackerStatus::Cancelled => management::OperationStatus::Cancelled,
+    };
+
+    management::Operation {
+        id: tracker.id(),
+        description: tracker.description().to_string(),
+        status: status as i32,
+        error: tracker.error().unwrap_or_default(),
+        completed: tracker.progress().completed() as u64,
+        total: tracker.progress().total() as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use operations_service_server::OperationsService as _;
+    use tonic::Code;
+
+    #[tokio::test]
+    async fn test_operations() {
+        let jobs = Arc::new(TrackerRegistry::new());
+        let service = OperationsService::new(Arc::clone(&jobs));
+
+        let quick = jobs.spawn("quick", |progress| async move {
+            progress.set_total(1);
+            progress.inc_completed(1);
+            Ok::<_, String>(())
+        });
+        let slow = jobs.spawn("slow", |_| async {
+            tokio::time::delay_for(Duration::from_secs(60)).await;
+            Ok::<_, String>(())
+        });
+
+        let operation = service
+            .wait_operation(Request::new(WaitOperationRequest {
+                id: quick.id(),
+                timeout_nanos: 0,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .operation
+            .unwrap();
+        assert_eq!(operation.description, "quick");
+        assert_eq!(
+            operation.status,
+            management::OperationStatus::Success as i32
+        );
+        assert_eq!(operation.completed, 1);
+        assert_eq!(operation.total, 1);
+
+        // times out while the job is still running
+        let operation = service
+            .wait_operation(Request::new(WaitOperationRequest {
+                id: slow.id(),
+                timeout_nanos: 1_000,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .operation
+            .unwrap();
+        assert_eq!(
+            operation.status,
+            management::OperationStatus::Running as i32
+        );
+
+        let operations = service
+            .list_operations(Request::new(ListOperationsRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .operations;
+        assert_eq!(operations.len(), 2);
+
+        service
+            .cancel_operation(Request::new(CancelOperationRequest { id: slow.id() }))
+            .await
+            .unwrap();
+        let operation = service
+            .wait_operation(Request::new(WaitOperationRequest {
+                id: slow.id(),
+                timeout_nanos: 0,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .operation
+            .unwrap();
+        assert_eq!(
+            operation.status,
+            management::OperationStatus::Cancelled as i32
+        );
+
+        let status = service
+            .get_operation(Request::new(GetOperationRequest { id: 42 }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+}
@@ -0,0 +1,241 @@
+//! This module contains the implementation of the OpenTelemetry metrics gRPC service, which
+//! receives the metrics exported over OTLP by OpenTelemetry SDKs and collectors and writes
+//! them to the database named by the `x-iox-database` header, as configured by its
+//! `OtlpRules`
+
+use std::sync::Arc;
+
+use cluster::{ConnectionManager, Server as AppServer};
+use generated_types::opentelemetry::proto::collector::metrics::v1::{
+    metrics_service_server, ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+};
+use influxdb_line_protocol::parse_lines;
+use prost::Message;
+use snafu::{OptionExt, ResultExt, Snafu};
+use tokio::sync::RwLock;
+use tonic::{metadata::MetadataMap, Code, Request, Response, Status};
+
+use storage::access::RowAccess;
+
+use super::{cluster_status, ingest_limits::IngestLimiter};
+use crate::server::auth::{self, Authorizer, Permission};
+
+/// The header naming the database the metrics are written to
+pub const DATABASE_HEADER: &str = "x-iox-database";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("The {} header is required", DATABASE_HEADER))]
+    MissingDatabaseName,
+
+    #[snafu(display("Database {} not found", db_name))]
+    DatabaseNotFound { db_name: String },
+
+    #[snafu(display("Error translating metrics into line protocol: {}", source))]
+    TranslatingMetrics {
+        source: influxdb_line_protocol::Error,
+    },
+
+    #[snafu(display("Error writing metrics: {}", source))]
+    WritingMetrics { source: cluster::Error },
+
+    #[snafu(display("{}", source))]
+    LineNotAllowed { source: auth::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Converts the error into the status of the failed export, which OpenTelemetry exporters
+    /// retry when it is RESOURCE_EXHAUSTED and drop otherwise
+    fn to_status(&self) -> tonic::Status {
+        match &self {
+            Self::MissingDatabaseName => Status::invalid_argument(self.to_string()),
+            Self::DatabaseNotFound { .. } => Status::not_found(self.to_string()),
+            Self::TranslatingMetrics { .. } => Status::invalid_argument(self.to_string()),
+            Self::WritingMetrics { source } => {
+                cluster_status(source, self.to_string(), Code::Internal)
+            }
+            Self::LineNotAllowed { .. } => Status::permission_denied(self.to_string()),
+        }
+    }
+}
+
+/// Implements the OpenTelemetry metrics service on top of a `cluster::Server`. Exports
+/// require the write permission on the database they write to, and are handled within the
+/// limits of `limiter`.
+#[derive(Debug)]
+pub struct OtlpMetricsService<M: ConnectionManager> {
+    app_server: Arc<RwLock<AppServer<M>>>,
+    authorizer: Arc<Authorizer>,
+    limiter: Arc<IngestLimiter>,
+}
+
+impl<M> OtlpMetricsService<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    /// Create a new OtlpMetricsService for the databases of `app_server`
+    pub fn new(
+        app_server: Arc<RwLock<AppServer<M>>>,
+        authorizer: Arc<Authorizer>,
+        limiter: Arc<IngestLimiter>,
+    ) -> Self {
+        Self {
+            app_server,
+            authorizer,
+            limiter,
+        }
+    }
+
+    async fn export_impl(
+        &self,
+        db_name: &str,
+        access: &RowAccess,
+        request: ExportMetricsServiceRequest,
+    ) -> Result<()> {
+        let app_server = self.app_server.read().await;
+        let rules = app_server
+            .db_rules(db_name)
+            .context(DatabaseNotFound { db_name })?;
+
+        let lp = ingest::otlp::to_line_protocol(&request, &rules.otlp);
+        let lines = parse_lines(&lp)
+            .collect::<Result<Vec<_>, _>>()
+            .context(TranslatingMetrics)?;
+        auth::check_lines(access, &lines).context(LineNotAllowed)?;
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        app_server
+            .write_lines(db_name, &lines)
+            .await
+            .context(WritingMetrics)
+    }
+}
+
+/// Returns the database named by the `x-iox-database` header of a request
+fn database_name(metadata: &MetadataMap) -> Result<String> {
+    metadata
+        .get(DATABASE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|name| !name.is_empty())
+        .map(ToString::to_string)
+        .context(MissingDatabaseName)
+}
+
+#[tonic::async_trait]
+impl<M> metrics_service_server::MetricsService for OtlpMetricsService<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    async fn export(
+        &self,
+        req: Request<ExportMetricsServiceRequest>,
+    ) -> Result<Response<ExportMetricsServiceResponse>, Status> {
+        let db_name = database_name(req.metadata()).map_err(|e| e.to_status())?;
+        let access = self
+            .authorizer
+            .access_grpc(req.metadata(), Permission::Write, &db_name)?;
+        let _permit = self
+            .limiter
+            .admit(req.get_ref().encoded_len())
+            .map_err(|e| e.to_status())?;
+
+        self.export_impl(&db_name, &access, req.into_inner())
+            .await
+            .map(|()| {
+                Response::new(ExportMetricsServiceResponse {
+                    partial_success: None,
+                })
+            })
+            .map_err(|e| e.to_status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ConnectionManagerImpl;
+    use data_types::database_rules::{DatabaseRules, OtlpRules};
+    use generated_types::opentelemetry::proto::metrics::v1::{
+        metric, number_data_point, Gauge, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+    };
+    use metrics_service_server::MetricsService as _;
+    use object_store::{InMemory, ObjectStore};
+    use tonic::Code;
+
+    fn export_request(db_name: Option<&str>) -> Request<ExportMetricsServiceRequest> {
+        let mut request = Request::new(ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: None,
+                scope_metrics: vec![ScopeMetrics {
+                    scope: None,
+                    metrics: vec![Metric {
+                        name: "queue_size".to_string(),
+                        description: String::new(),
+                        unit: String::new(),
+                        data: Some(metric::Data::Gauge(Gauge {
+                            data_points: vec![NumberDataPoint {
+                                time_unix_nano: 10,
+                                value: Some(number_data_point::Value::AsDouble(1.5)),
+                                ..Default::default()
+                            }],
+                        })),
+                    }],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        });
+        if let Some(db_name) = db_name {
+            request
+                .metadata_mut()
+                .insert(DATABASE_HEADER, db_name.parse().unwrap());
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let mut app_server = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        app_server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            otlp: OtlpRules {
+                table_prefix: "otel_".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        app_server.create_database("foo", rules).await.unwrap();
+        let app_server = Arc::new(RwLock::new(app_server));
+        let service = OtlpMetricsService::new(
+            Arc::clone(&app_server),
+            Arc::new(Authorizer::new(true)),
+            Arc::new(IngestLimiter::default()),
+        );
+
+        service.export(export_request(Some("foo"))).await.unwrap();
+        let batches = app_server
+            .read()
+            .await
+            .query_local("foo", "select * from otel_queue_size")
+            .await
+            .unwrap();
+        assert_eq!(batches[0].num_rows(), 1);
+
+        let status = service.export(export_request(None)).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .export(export_request(Some("bar")))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+}
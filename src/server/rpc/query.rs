@@ -0,0 +1,417 @@
+//! This module contains the implementation of the query gRPC service,
+//! which runs SQL queries against the databases of a `cluster::Server`
+//! and returns their results as Arrow IPC streams, and streams the rows
+//! written to them
+
+use std::{
+    pin::Pin,
+    sync::{atomic::Ordering, Arc},
+};
+
+use arrow_deps::arrow::{error::ArrowError, ipc::writer::StreamWriter, record_batch::RecordBatch};
+use cluster::{
+    tail::{self, Subscription},
+    ConnectionManager, Server as AppServer,
+};
+use futures::{Stream, StreamExt};
+use generated_types::query::{
+    field_value, query_service_server, FieldValue, Point, QueryRequest, QueryResponse, TailRequest,
+    TailResponse,
+};
+use snafu::{ensure, ResultExt, Snafu};
+use storage::access::RowAccess;
+use tokio::sync::RwLock;
+use tonic::{metadata::MetadataMap, Code, Request, Response, Status};
+use tracing::debug;
+
+use super::cluster_status;
+use crate::server::{
+    auth::{Authorizer, Permission},
+    capture::{self, Capture},
+    governor::{self, Governor, Priority},
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Database name is required"))]
+    MissingDatabaseName,
+
+    #[snafu(display("Table name is required"))]
+    MissingTableName,
+
+    #[snafu(display("Error admitting query: {}", source))]
+    Admitting { source: governor::Error },
+
+    #[snafu(display("Error running query: {}", source))]
+    Querying { source: cluster::Error },
+
+    #[snafu(display("Error subscribing to new rows: {}", source))]
+    Subscribing { source: cluster::Error },
+
+    #[snafu(display("Error encoding the results: {}", source))]
+    EncodingResults { source: ArrowError },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Converts the error into the status of the failed query or subscription. A query fails
+    /// with INVALID_ARGUMENT unless the server is at fault, as most errors come from planning it.
+    fn to_status(&self) -> tonic::Status {
+        match &self {
+            Self::MissingDatabaseName => Status::invalid_argument(self.to_string()),
+            Self::MissingTableName => Status::invalid_argument(self.to_string()),
+            Self::Admitting { source } => match source {
+                governor::Error::UnknownPriority { .. } => {
+                    Status::invalid_argument(self.to_string())
+                }
+                governor::Error::Rejected { .. } => Status::resource_exhausted(self.to_string()),
+            },
+            // planning errors are caused by the query
+            Self::Querying { source } => {
+                cluster_status(source, self.to_string(), Code::InvalidArgument)
+            }
+            Self::Subscribing { source } => {
+                cluster_status(source, self.to_string(), Code::Internal)
+            }
+            Self::EncodingResults { .. } => Status::internal(self.to_string()),
+        }
+    }
+}
+
+/// Implements the protobuf defined query service on top of the local
+/// databases of a `cluster::Server`. Queries require the read permission
+/// on the database they run against.
+#[derive(Debug)]
+pub struct QueryService<M: ConnectionManager> {
+    app_server: Arc<RwLock<AppServer<M>>>,
+    authorizer: Arc<Authorizer>,
+    capture: Option<Arc<Capture>>,
+    governor: Arc<Governor>,
+}
+
+impl<M> QueryService<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    /// Create a new QueryService for the databases of `app_server`, which captures the
+    /// queries it receives to `capture`, if set, and runs them once `governor` admits them
+    pub fn new(
+        app_server: Arc<RwLock<AppServer<M>>>,
+        authorizer: Arc<Authorizer>,
+        capture: Option<Arc<Capture>>,
+        governor: Arc<Governor>,
+    ) -> Self {
+        Self {
+            app_server,
+            authorizer,
+            capture,
+            governor,
+        }
+    }
+
+    /// Runs the query of `request`, with the priority given by its hint or else by the
+    /// `metadata` of the request. The query may read the rows of its database `access`
+    /// allows, and the tables of the other databases the request may read.
+    async fn query_impl(
+        &self,
+        request: &QueryRequest,
+        metadata: &MetadataMap,
+        access: RowAccess,
+    ) -> Result<Vec<u8>> {
+        let QueryRequest { db_name, sql } = request;
+        ensure!(!db_name.is_empty(), MissingDatabaseName);
+
+        debug!("running query against {}: {}", db_name, sql);
+        if let Some(capture) = &self.capture {
+            capture.record(db_name, capture::Request::Sql { query: sql.clone() });
+        }
+        let (priority, sql) =
+            Priority::of(governor::metadata_priority(metadata), sql).context(Admitting)?;
+        let _permit = self.governor.admit(priority).await.context(Admitting)?;
+        let results = self
+            .app_server
+            .read()
+            .await
+            .query_local_across(db_name, sql, |name| {
+                if name == db_name.as_str() {
+                    Some(access.clone())
+                } else {
+                    self.authorizer
+                        .access_grpc(metadata, Permission::Read, name)
+                        .ok()
+                }
+            })
+            .await
+            .context(Querying)?;
+
+        encode(&results).context(EncodingResults)
+    }
+
+    async fn tail_impl(&self, request: TailRequest, access: RowAccess) -> Result<Subscription> {
+        let TailRequest {
+            db_name,
+            table_name,
+            tags,
+            buffer_size,
+        } = request;
+        ensure!(!db_name.is_empty(), MissingDatabaseName);
+        ensure!(!table_name.is_empty(), MissingTableName);
+
+        let filter = tail::Filter {
+            table_name,
+            tags: tags.into_iter().collect(),
+            access,
+        };
+        let buffer_size = match buffer_size {
+            0 => tail::DEFAULT_BUFFER_SIZE,
+            n => n as usize,
+        };
+        debug!("subscribing to {:?} of {}", filter, db_name);
+        self.app_server
+            .read()
+            .await
+            .tail(&db_name, filter, buffer_size)
+            .context(Subscribing)
+    }
+}
+
+type PointStream = Pin<Box<dyn Stream<Item = Result<TailResponse, Status>> + Send + Sync>>;
+
+#[tonic::async_trait]
+impl<M> query_service_server::QueryService for QueryService<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    async fn query(&self, req: Request<QueryRequest>) -> Result<Response<QueryResponse>, Status> {
+        let access = self.authorizer.access_grpc(
+            req.metadata(),
+            Permission::Read,
+            &req.get_ref().db_name,
+        )?;
+
+        self.query_impl(req.get_ref(), req.metadata(), access)
+            .await
+            .map(|arrow_ipc| Response::new(QueryResponse { arrow_ipc }))
+            .map_err(|e| e.to_status())
+    }
+
+    type TailStream = PointStream;
+
+    async fn tail(&self, req: Request<TailRequest>) -> Result<Response<Self::TailStream>, Status> {
+        let access = self.authorizer.access_grpc(
+            req.metadata(),
+            Permission::Read,
+            &req.get_ref().db_name,
+        )?;
+
+        let Subscription { points, evicted } = self
+            .tail_impl(req.into_inner(), access)
+            .await
+            .map_err(|e| e.to_status())?;
+
+        // the stream of an evicted subscriber ends with an error, once it has received the
+        // rows buffered for it
+        let end = futures::stream::once(async move {
+            if evicted.load(Ordering::SeqCst) {
+                Some(Err(Status::resource_exhausted(
+                    "evicted for not keeping up with the new rows",
+                )))
+            } else {
+                None
+            }
+        })
+        .filter_map(futures::future::ready);
+        let stream = points
+            .map(|points| {
+                Ok(TailResponse {
+                    points: points.into_iter().map(to_point).collect(),
+                })
+            })
+            .chain(end);
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+fn to_point(point: tail::Point) -> Point {
+    Point {
+        table_name: point.table_name,
+        time: point.time,
+        tags: point.tags.into_iter().collect(),
+        fields: point
+            .fields
+            .into_iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    tail::FieldValue::I64(v) => field_value::Value::I64Value(v),
+                    tail::FieldValue::F64(v) => field_value::Value::F64Value(v),
+                    tail::FieldValue::Bool(v) => field_value::Value::BoolValue(v),
+                    tail::FieldValue::String(v) => field_value::Value::StringValue(v),
+                };
+                (name, FieldValue { value: Some(value) })
+            })
+            .collect(),
+    }
+}
+
+/// Encodes `batches` as an Arrow IPC stream, which is empty if there are no batches
+pub(super) fn encode(batches: &[RecordBatch]) -> Result<Vec<u8>, ArrowError> {
+    let mut data = vec![];
+    if let Some(first) = batches.first() {
+        let schema = first.schema();
+        let mut writer = StreamWriter::try_new(&mut data, &schema)?;
+        for batch in batches {
+            writer.write(batch)?;
+        }
+        writer.finish()?;
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ConnectionManagerImpl;
+    use arrow_deps::arrow::{ipc::reader::StreamReader, util::pretty::pretty_format_batches};
+    use data_types::database_rules::DatabaseRules;
+    use object_store::{InMemory, ObjectStore};
+    use query_service_server::QueryService as _;
+    use std::io::Cursor;
+    use tonic::Code;
+
+    async fn make_service() -> QueryService<ConnectionManagerImpl> {
+        let mut app_server = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        app_server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        app_server.create_database("foo", rules).await.unwrap();
+
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines("cpu,host=a usage=0.5 10")
+            .map(|l| l.unwrap())
+            .collect();
+        app_server.write_lines("foo", &lines).await.unwrap();
+
+        QueryService::new(
+            Arc::new(RwLock::new(app_server)),
+            Arc::new(Authorizer::new(true)),
+            None,
+            Arc::new(Governor::default()),
+        )
+    }
+
+    fn query(db_name: &str, sql: &str) -> Request<QueryRequest> {
+        Request::new(QueryRequest {
+            db_name: db_name.to_string(),
+            sql: sql.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let service = make_service().await;
+
+        let arrow_ipc = service
+            .query(query("foo", "select host, usage, time from cpu"))
+            .await
+            .unwrap()
+            .into_inner()
+            .arrow_ipc;
+        let batches = StreamReader::try_new(Cursor::new(arrow_ipc))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let expected = vec![
+            "+------+-------+------+",
+            "| host | usage | time |",
+            "+------+-------+------+",
+            "| a    | 0.5   | 10   |",
+            "+------+-------+------+",
+        ];
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().trim(),
+            expected.join("\n")
+        );
+
+        let arrow_ipc = service
+            .query(query("foo", "select id, storage from system.chunks"))
+            .await
+            .unwrap()
+            .into_inner()
+            .arrow_ipc;
+        assert!(!arrow_ipc.is_empty());
+
+        let status = service
+            .query(query("foo", "select * from nope"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .query(query("bar", "select * from cpu"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let status = service.query(query("", "select 1")).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    fn tail_request(buffer_size: u32) -> Request<TailRequest> {
+        Request::new(TailRequest {
+            db_name: "foo".to_string(),
+            table_name: "cpu".to_string(),
+            tags: vec![("host".to_string(), "a".to_string())]
+                .into_iter()
+                .collect(),
+            buffer_size,
+        })
+    }
+
+    async fn write(service: &QueryService<ConnectionManagerImpl>, lines: &str) {
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines(lines)
+            .map(|l| l.unwrap())
+            .collect();
+        service
+            .app_server
+            .read()
+            .await
+            .write_lines("foo", &lines)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tail() {
+        let service = make_service().await;
+
+        let mut stream = service.tail(tail_request(0)).await.unwrap().into_inner();
+        write(&service, "cpu,host=b usage=0.1 20\ncpu,host=a usage=0.7 30").await;
+        let response = stream.next().await.unwrap().unwrap();
+        assert_eq!(response.points.len(), 1);
+        let point = &response.points[0];
+        assert_eq!(point.time, 30);
+        assert_eq!(
+            point.fields["usage"].value,
+            Some(field_value::Value::F64Value(0.7))
+        );
+
+        // a client that doesn't keep up is evicted
+        let mut stream = service.tail(tail_request(1)).await.unwrap().into_inner();
+        write(&service, "cpu,host=a usage=0.8 40").await;
+        write(&service, "cpu,host=a usage=0.9 50").await;
+        assert_eq!(stream.next().await.unwrap().unwrap().points[0].time, 40);
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(stream.next().await.is_none());
+
+        let mut request = tail_request(0);
+        request.get_mut().table_name = String::new();
+        let status = service.tail(request).await.err().unwrap();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
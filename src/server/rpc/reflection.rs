@@ -0,0 +1,343 @@
+//! This module contains the implementation of the standard gRPC server
+//! reflection service, which lets tools such as grpcurl list the services of
+//! the server and call them without compiled protobuf definitions. It serves
+//! the descriptors of the protobuf files built into the server.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use generated_types::grpc::reflection::v1alpha::{
+    server_reflection_request::MessageRequest, server_reflection_response::MessageResponse,
+    server_reflection_server, ErrorResponse, FileDescriptorResponse, ListServiceResponse,
+    ServerReflectionRequest, ServerReflectionResponse, ServiceResponse,
+};
+use prost::Message;
+use prost_types::{DescriptorProto, FileDescriptorSet};
+use snafu::{OptionExt, Snafu};
+use tokio::sync::mpsc;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("File not found: {}", name))]
+    FileNotFound { name: String },
+
+    #[snafu(display("Symbol not found: {}", name))]
+    SymbolNotFound { name: String },
+
+    #[snafu(display("Extensions are not supported"))]
+    ExtensionsUnsupported,
+
+    #[snafu(display("Missing message request"))]
+    MissingRequest,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Converts a result from the business logic into the error response of the protocol,
+    /// which is sent without ending the stream
+    fn to_error_response(&self) -> ErrorResponse {
+        let code = match self {
+            Self::FileNotFound { .. } | Self::SymbolNotFound { .. } => Code::NotFound,
+            Self::ExtensionsUnsupported => Code::Unimplemented,
+            Self::MissingRequest => Code::InvalidArgument,
+        };
+        ErrorResponse {
+            error_code: code as i32,
+            error_message: self.to_string(),
+        }
+    }
+}
+
+/// A protobuf file of the server
+#[derive(Debug)]
+struct File {
+    /// The encoded `FileDescriptorProto`
+    descriptor: Vec<u8>,
+    /// The names of the files it imports
+    dependencies: Vec<String>,
+}
+
+/// The descriptors of the protobuf files of the server, indexed by the symbols
+/// they define
+#[derive(Debug, Default)]
+pub struct Descriptors {
+    /// Keyed by file name
+    files: BTreeMap<String, File>,
+    /// The name of the file defining each fully qualified service, method,
+    /// message and enum
+    symbols: BTreeMap<String, String>,
+    /// The fully qualified names of the services
+    services: Vec<String>,
+}
+
+impl Descriptors {
+    /// Indexes the files of the encoded `FileDescriptorSet`
+    pub fn decode(encoded: &[u8]) -> Result<Self, prost::DecodeError> {
+        let set = FileDescriptorSet::decode(encoded)?;
+        let mut descriptors = Self::default();
+
+        for file in set.file {
+            let name = file.name().to_string();
+            let prefix = match file.package() {
+                "" => String::new(),
+                package => format!("{}.", package),
+            };
+
+            for service in &file.service {
+                let service_name = format!("{}{}", prefix, service.name());
+                for method in &service.method {
+                    descriptors
+                        .symbols
+                        .insert(format!("{}.{}", service_name, method.name()), name.clone());
+                }
+                descriptors
+                    .symbols
+                    .insert(service_name.clone(), name.clone());
+                descriptors.services.push(service_name);
+            }
+            for message in &file.message_type {
+                descriptors.add_message(&prefix, message, &name);
+            }
+            for enum_type in &file.enum_type {
+                descriptors
+                    .symbols
+                    .insert(format!("{}{}", prefix, enum_type.name()), name.clone());
+            }
+
+            let mut descriptor = Vec::with_capacity(file.encoded_len());
+            file.encode(&mut descriptor)
+                .expect("the capacity of the buffer is the length of the message");
+            descriptors.files.insert(
+                name,
+                File {
+                    descriptor,
+                    dependencies: file.dependency,
+                },
+            );
+        }
+
+        descriptors.services.sort();
+        Ok(descriptors)
+    }
+
+    /// Indexes `message` and the messages and enums nested in it
+    fn add_message(&mut self, prefix: &str, message: &DescriptorProto, file_name: &str) {
+        let message_name = format!("{}{}", prefix, message.name());
+        let nested_prefix = format!("{}.", message_name);
+        for nested in &message.nested_type {
+            self.add_message(&nested_prefix, nested, file_name);
+        }
+        for enum_type in &message.enum_type {
+            self.symbols.insert(
+                format!("{}{}", nested_prefix, enum_type.name()),
+                file_name.to_string(),
+            );
+        }
+        self.symbols.insert(message_name, file_name.to_string());
+    }
+
+    /// The fully qualified names of the services, in order
+    pub fn services(&self) -> &[String] {
+        &self.services
+    }
+
+    /// Returns the encoded descriptors of the file `name` and then of the files
+    /// it imports, directly or not
+    fn file_with_dependencies(&self, name: &str) -> Result<Vec<Vec<u8>>> {
+        let mut names = vec![name.to_string()];
+        let mut descriptors = vec![];
+        let mut i = 0;
+        while i < names.len() {
+            let file = self.files.get(&names[i]).context(FileNotFound {
+                name: names[i].clone(),
+            })?;
+            descriptors.push(file.descriptor.clone());
+            for dependency in &file.dependencies {
+                if !names.contains(dependency) {
+                    names.push(dependency.clone());
+                }
+            }
+            i += 1;
+        }
+        Ok(descriptors)
+    }
+
+    fn message_response(&self, request: &MessageRequest) -> Result<MessageResponse> {
+        let file_name = match request {
+            MessageRequest::FileByFilename(name) => name,
+            MessageRequest::FileContainingSymbol(symbol) => self
+                .symbols
+                .get(symbol)
+                .context(SymbolNotFound { name: symbol })?,
+            MessageRequest::FileContainingExtension(_)
+            | MessageRequest::AllExtensionNumbersOfType(_) => return ExtensionsUnsupported.fail(),
+            MessageRequest::ListServices(_) => {
+                return Ok(MessageResponse::ListServicesResponse(ListServiceResponse {
+                    service: self
+                        .services
+                        .iter()
+                        .map(|name| ServiceResponse { name: name.clone() })
+                        .collect(),
+                }))
+            }
+        };
+
+        Ok(MessageResponse::FileDescriptorResponse(
+            FileDescriptorResponse {
+                file_descriptor_proto: self.file_with_dependencies(file_name)?,
+            },
+        ))
+    }
+
+    /// Answers `request`, with an error response if it can't be answered
+    pub fn respond(&self, request: ServerReflectionRequest) -> ServerReflectionResponse {
+        let response = request
+            .message_request
+            .as_ref()
+            .context(MissingRequest)
+            .and_then(|message_request| self.message_response(message_request))
+            .unwrap_or_else(|e| MessageResponse::ErrorResponse(e.to_error_response()));
+
+        ServerReflectionResponse {
+            valid_host: request.host.clone(),
+            original_request: Some(request),
+            message_response: Some(response),
+        }
+    }
+}
+
+/// Implements the protobuf defined server reflection service from the
+/// descriptors of the protobuf files of the server
+#[derive(Debug)]
+pub struct ReflectionService {
+    descriptors: Arc<Descriptors>,
+}
+
+impl ReflectionService {
+    /// Create a new ReflectionService serving `descriptors`
+    pub fn new(descriptors: Arc<Descriptors>) -> Self {
+        Self { descriptors }
+    }
+}
+
+#[tonic::async_trait]
+impl server_reflection_server::ServerReflection for ReflectionService {
+    type ServerReflectionInfoStream = mpsc::Receiver<Result<ServerReflectionResponse, Status>>;
+
+    async fn server_reflection_info(
+        &self,
+        req: Request<Streaming<ServerReflectionRequest>>,
+    ) -> Result<Response<Self::ServerReflectionInfoStream>, Status> {
+        let descriptors = Arc::clone(&self.descriptors);
+        let mut requests = req.into_inner();
+        let (mut tx, rx) = mpsc::channel(4);
+
+        tokio::spawn(async move {
+            loop {
+                let response = match requests.message().await {
+                    Ok(Some(request)) => Ok(descriptors.respond(request)),
+                    Ok(None) => return,
+                    Err(status) => Err(status),
+                };
+                let failed = response.is_err();
+                if tx.send(response).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generated_types::FILE_DESCRIPTOR_SET;
+    use prost_types::FileDescriptorProto;
+
+    fn request(message_request: MessageRequest) -> ServerReflectionRequest {
+        ServerReflectionRequest {
+            host: "localhost".to_string(),
+            message_request: Some(message_request),
+        }
+    }
+
+    fn file_names(response: ServerReflectionResponse) -> Vec<String> {
+        match response.message_response {
+            Some(MessageResponse::FileDescriptorResponse(response)) => response
+                .file_descriptor_proto
+                .iter()
+                .map(|encoded| {
+                    FileDescriptorProto::decode(encoded.as_slice())
+                        .unwrap()
+                        .name()
+                        .to_string()
+                })
+                .collect(),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    fn error_code(response: ServerReflectionResponse) -> i32 {
+        match response.message_response {
+            Some(MessageResponse::ErrorResponse(response)) => response.error_code,
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reflection() {
+        let descriptors = Descriptors::decode(FILE_DESCRIPTOR_SET).unwrap();
+
+        let response = descriptors.respond(request(MessageRequest::ListServices(String::new())));
+        assert_eq!(response.valid_host, "localhost");
+        let services = match response.message_response {
+            Some(MessageResponse::ListServicesResponse(response)) => response.service,
+            other => panic!("unexpected response {:?}", other),
+        };
+        let services: Vec<_> = services.into_iter().map(|s| s.name).collect();
+        for name in &[
+            "grpc.health.v1.Health",
+            "grpc.reflection.v1alpha.ServerReflection",
+            "influxdata.iox.management.v1.ManagementService",
+            "influxdata.platform.storage.Storage",
+        ] {
+            assert!(services.contains(&name.to_string()), "{:?}", services);
+        }
+
+        // the files a file imports follow it
+        for symbol in &[
+            "influxdata.iox.management.v1.ManagementService",
+            "influxdata.iox.management.v1.ManagementService.CreateDatabase",
+            "influxdata.iox.management.v1.CreateDatabaseRequest",
+        ] {
+            let response = descriptors.respond(request(MessageRequest::FileContainingSymbol(
+                symbol.to_string(),
+            )));
+            let names = file_names(response);
+            assert_eq!(names[0], "management.proto");
+            assert!(names.contains(&"google/protobuf/empty.proto".to_string()));
+        }
+        let response = descriptors.respond(request(MessageRequest::FileByFilename(
+            "grpc/health/v1/health.proto".to_string(),
+        )));
+        assert_eq!(file_names(response), vec!["grpc/health/v1/health.proto"]);
+        let response = descriptors.respond(request(MessageRequest::FileContainingSymbol(
+            "grpc.health.v1.HealthCheckResponse.ServingStatus".to_string(),
+        )));
+        assert_eq!(file_names(response), vec!["grpc/health/v1/health.proto"]);
+
+        let response = descriptors.respond(request(MessageRequest::FileContainingSymbol(
+            "foo.Bar".to_string(),
+        )));
+        assert_eq!(error_code(response), Code::NotFound as i32);
+        let response = descriptors.respond(request(MessageRequest::AllExtensionNumbersOfType(
+            "foo.Bar".to_string(),
+        )));
+        assert_eq!(error_code(response), Code::Unimplemented as i32);
+        let response = descriptors.respond(ServerReflectionRequest::default());
+        assert_eq!(error_code(response), Code::InvalidArgument as i32);
+    }
+}
@@ -2,16 +2,16 @@
 //! implemented in terms of the `storage::Database` and
 //! `storage::DatabaseStore`
 
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{cmp::Ordering, collections::HashMap, future::Future, sync::Arc};
 
 use generated_types::{
-    i_ox_server::{IOx, IOxServer},
-    storage_server::{Storage, StorageServer},
+    aggregate::AggregateType, i_ox_server::IOx, storage_server::Storage, Aggregate,
     CapabilitiesResponse, CreateBucketRequest, CreateBucketResponse, DeleteBucketRequest,
     DeleteBucketResponse, GetBucketsResponse, MeasurementFieldsRequest, MeasurementFieldsResponse,
     MeasurementNamesRequest, MeasurementTagKeysRequest, MeasurementTagValuesRequest, Organization,
     Predicate, ReadFilterRequest, ReadGroupRequest, ReadResponse, StringValuesResponse,
-    TagKeysRequest, TagValuesRequest, TestErrorRequest, TestErrorResponse, TimestampRange,
+    TagKeysRequest, TagValuesRequest, TagValuesWithPrefixRequest, TagValuesWithPrefixResponse,
+    TestErrorRequest, TestErrorResponse, TimestampRange,
 };
 
 // For some reason rust thinks these imports are unused, but then
@@ -19,35 +19,36 @@ use generated_types::{
 #[allow(unused_imports)]
 use generated_types::{node, Node};
 
+use crate::server::auth::{Authorizer, Permission};
+use crate::server::bucket_mapping::BucketMapping;
 use crate::server::rpc::expr::{AddRPCNode, SpecialTagKeys};
 use crate::server::rpc::input::GrpcInputs;
 
 use storage::{
+    access::RowAccess,
+    autocomplete::{self, TagValues, TagValuesQuery},
     exec::{
         seriesset::{Error as SeriesSetError, GroupedSeriesSetItem, SeriesSet},
         Executor as StorageExecutor,
     },
-    org_and_bucket_to_database,
     predicate::PredicateBuilder,
     Database, DatabaseStore,
 };
 
-use snafu::{OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
 
 use tokio::sync::mpsc;
 use tonic::Status;
 use tracing::{info, warn};
 
+use super::cache::{CacheKey, QueryCache};
 use super::data::{
-    fieldlist_to_measurement_fields_response, grouped_series_set_item_to_read_response,
-    series_set_to_read_response, tag_keys_to_byte_vecs,
+    approx_percentile_response, fieldlist_to_measurement_fields_response,
+    grouped_series_set_item_to_read_response, series_sets_to_read_response, tag_keys_to_byte_vecs,
 };
 
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("gRPC server error:  {}", source))]
-    ServerError { source: tonic::transport::Error },
-
     #[snafu(display("Database not found: {}", db_name))]
     DatabaseNotFound { db_name: String },
 
@@ -129,6 +130,9 @@ pub enum Error {
 
     #[snafu(display("Operation not yet implemented:  {}", operation))]
     NotYetImplemented { operation: String },
+
+    #[snafu(display("Percentile {} is not between 0 and 1", percentile))]
+    InvalidPercentile { percentile: f64 },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -137,7 +141,6 @@ impl Error {
     /// Converts a result from the business logic into the appropriate tonic status
     fn to_status(&self) -> tonic::Status {
         match &self {
-            Self::ServerError { .. } => Status::internal(self.to_string()),
             Self::DatabaseNotFound { .. } => Status::not_found(self.to_string()),
             Self::ListingTables { .. } => Status::internal(self.to_string()),
             Self::ListingColumns { .. } => {
@@ -159,6 +162,7 @@ impl Error {
             Self::ConvertingSeriesSet { .. } => Status::invalid_argument(self.to_string()),
             Self::ConvertingFieldList { .. } => Status::invalid_argument(self.to_string()),
             Self::NotYetImplemented { .. } => Status::internal(self.to_string()),
+            Self::InvalidPercentile { .. } => Status::invalid_argument(self.to_string()),
         }
     }
 }
@@ -167,15 +171,75 @@ impl Error {
 pub struct GrpcService<T: DatabaseStore> {
     db_store: Arc<T>,
     executor: Arc<StorageExecutor>,
+    authorizer: Arc<Authorizer>,
+    buckets: Arc<BucketMapping>,
+    cache: Option<Arc<QueryCache>>,
 }
 
 impl<T> GrpcService<T>
 where
     T: DatabaseStore + 'static,
 {
-    /// Create a new GrpcService connected to `db_store`
-    pub fn new(db_store: Arc<T>, executor: Arc<StorageExecutor>) -> Self {
-        Self { db_store, executor }
+    /// Create a new GrpcService connected to `db_store`, that serves the requests allowed
+    /// by `authorizer` from the databases `buckets` maps them to. The responses to metadata
+    /// requests are cached in `cache`, if set.
+    pub fn new(
+        db_store: Arc<T>,
+        executor: Arc<StorageExecutor>,
+        authorizer: Arc<Authorizer>,
+        buckets: Arc<BucketMapping>,
+        cache: Option<Arc<QueryCache>>,
+    ) -> Self {
+        Self {
+            db_store,
+            executor,
+            authorizer,
+            buckets,
+            cache,
+        }
+    }
+
+    /// Returns the response cached for the metadata request `key`, if the data of its
+    /// database hasn't changed since, or computes it with `response` and caches it. The
+    /// responses to requests that may only read some rows, per `access`, aren't cached.
+    async fn cached(
+        &self,
+        key: CacheKey,
+        access: &RowAccess,
+        response: impl Future<Output = Result<StringValuesResponse>>,
+    ) -> Result<StringValuesResponse> {
+        let cache = match &self.cache {
+            Some(cache) if access.is_unrestricted() => cache,
+            _ => return response.await,
+        };
+        let data_version = match self.db_store.db(key.db_name()).await {
+            Some(db) => db.data_version(),
+            None => None,
+        };
+        let data_version = match data_version {
+            Some(data_version) => data_version,
+            None => return response.await,
+        };
+
+        if let Some(response) = cache.get(&key, data_version) {
+            return Ok(response);
+        }
+        let response = response.await?;
+        cache.insert(key, data_version, response.clone());
+        Ok(response)
+    }
+
+    /// Returns the database a request reads from, and the rows it may read, if the request
+    /// is allowed to read it
+    fn authorize_read<R: GrpcInputs>(
+        &self,
+        req: &tonic::Request<R>,
+    ) -> Result<(String, RowAccess), Status> {
+        let db_name = get_database_name(req.get_ref(), &self.buckets)?;
+        let access = self
+            .authorizer
+            .access_grpc(req.metadata(), Permission::Read, &db_name)?;
+        Ok((db_name, access))
     }
 }
 
@@ -214,6 +278,46 @@ where
         warn!("Got a test_error request. About to panic");
         panic!("This is a test panic");
     }
+
+    async fn tag_values_with_prefix(
+        &self,
+        req: tonic::Request<TagValuesWithPrefixRequest>,
+    ) -> Result<tonic::Response<TagValuesWithPrefixResponse>, Status> {
+        let (db_name, access) = self.authorize_read(&req)?;
+
+        let TagValuesWithPrefixRequest {
+            tags_source: _tag_source,
+            range,
+            tag_key,
+            prefix,
+            measurement,
+            limit,
+        } = req.into_inner();
+
+        info!(
+            "tag_values_with_prefix for database {}, range: {:?}, tag_key: {}, prefix: {}",
+            db_name, range, tag_key, prefix
+        );
+
+        let measurement = Some(measurement).filter(|measurement| !measurement.is_empty());
+        let range =
+            range.map(|range| storage::predicate::TimestampRange::new(range.start, range.end));
+        let query = TagValuesQuery::new(tag_key, prefix)
+            .table_option(measurement)
+            .range_option(range)
+            .limit(limit as usize);
+
+        tag_values_with_prefix_impl(
+            self.db_store.clone(),
+            self.executor.clone(),
+            db_name,
+            access,
+            query,
+        )
+        .await
+        .map(tonic::Response::new)
+        .map_err(|e| e.to_status())
+    }
 }
 
 /// Implementes the protobuf defined Storage service for a DatabaseStore
@@ -230,10 +334,9 @@ where
     ) -> Result<tonic::Response<Self::ReadFilterStream>, Status> {
         let (tx, rx) = mpsc::channel(4);
 
+        let (db_name, access) = self.authorize_read(&req)?;
         let read_filter_request = req.into_inner();
 
-        let db_name = get_database_name(&read_filter_request)?;
-
         let ReadFilterRequest {
             read_source: _read_source,
             range,
@@ -247,6 +350,7 @@ where
             self.db_store.clone(),
             self.executor.clone(),
             db_name,
+            access,
             range,
             predicate,
         )
@@ -264,10 +368,9 @@ where
     ) -> Result<tonic::Response<Self::ReadGroupStream>, Status> {
         let (tx, rx) = mpsc::channel(4);
 
+        let (db_name, access) = self.authorize_read(&req)?;
         let read_group_request = req.into_inner();
 
-        let db_name = get_database_name(&read_group_request)?;
-
         let ReadGroupRequest {
             read_source: _read_source,
             range,
@@ -275,10 +378,11 @@ where
             group_keys,
             // TODO: handle Group::None
             group: _group,
-            // TODO: handle aggregate values, especially whether None is the same as
-            // Some(AggregateType::None) or not
-            aggregate: _aggregate,
+            // TODO: handle the aggregates other than the approximate percentiles, especially
+            // whether None is the same as Some(AggregateType::None) or not
+            aggregate,
         } = read_group_request;
+        let percentile = approx_percentile(aggregate).map_err(|e| e.to_status())?;
 
         info!(
             "read_group for database {}, range: {:?}, group_keys: {:?}",
@@ -290,9 +394,11 @@ where
             self.db_store.clone(),
             self.executor.clone(),
             db_name,
+            access,
             range,
             predicate,
             group_keys,
+            percentile,
         )
         .await
         .map_err(|e| e.to_status())?;
@@ -308,10 +414,9 @@ where
     ) -> Result<tonic::Response<Self::TagKeysStream>, Status> {
         let (mut tx, rx) = mpsc::channel(4);
 
+        let (db_name, access) = self.authorize_read(&req)?;
         let tag_keys_request = req.into_inner();
 
-        let db_name = get_database_name(&tag_keys_request)?;
-
         let TagKeysRequest {
             tags_source: _tag_source,
             range,
@@ -322,16 +427,23 @@ where
 
         let measurement = None;
 
-        let response = tag_keys_impl(
-            self.db_store.clone(),
-            self.executor.clone(),
-            db_name,
-            measurement,
-            range,
-            predicate,
-        )
-        .await
-        .map_err(|e| e.to_status());
+        let key = CacheKey::new(&db_name, format!("tag_keys {:?} {:?}", range, predicate));
+        let response = self
+            .cached(
+                key,
+                &access,
+                tag_keys_impl(
+                    self.db_store.clone(),
+                    self.executor.clone(),
+                    db_name,
+                    access.clone(),
+                    measurement,
+                    range,
+                    predicate,
+                ),
+            )
+            .await
+            .map_err(|e| e.to_status());
 
         tx.send(response)
             .await
@@ -348,10 +460,9 @@ where
     ) -> Result<tonic::Response<Self::TagValuesStream>, Status> {
         let (mut tx, rx) = mpsc::channel(4);
 
+        let (db_name, access) = self.authorize_read(&req)?;
         let tag_values_request = req.into_inner();
 
-        let db_name = get_database_name(&tag_values_request)?;
-
         let TagValuesRequest {
             tags_source: _tag_source,
             range,
@@ -372,22 +483,42 @@ where
                 unimplemented!("tag_value for a measurement, with general predicate");
             }
 
-            measurement_name_impl(self.db_store.clone(), self.executor.clone(), db_name, range)
-                .await
+            let key = CacheKey::new(&db_name, format!("measurement_names {:?}", range));
+            self.cached(
+                key,
+                &access,
+                measurement_name_impl(
+                    self.db_store.clone(),
+                    self.executor.clone(),
+                    db_name,
+                    access.clone(),
+                    range,
+                ),
+            )
+            .await
         } else {
             info!(
                 "tag_values for database {}, range: {:?}, tag_key: {}",
                 db_name, range, tag_key
             );
 
-            tag_values_impl(
-                self.db_store.clone(),
-                self.executor.clone(),
-                db_name,
-                tag_key,
-                measurement,
-                range,
-                predicate,
+            let key = CacheKey::new(
+                &db_name,
+                format!("tag_values {:?} {:?} {:?}", tag_key, range, predicate),
+            );
+            self.cached(
+                key,
+                &access,
+                tag_values_impl(
+                    self.db_store.clone(),
+                    self.executor.clone(),
+                    db_name,
+                    access.clone(),
+                    tag_key,
+                    measurement,
+                    range,
+                    predicate,
+                ),
             )
             .await
         };
@@ -424,10 +555,9 @@ where
     ) -> Result<tonic::Response<Self::MeasurementNamesStream>, Status> {
         let (mut tx, rx) = mpsc::channel(4);
 
+        let (db_name, access) = self.authorize_read(&req)?;
         let measurement_names_request = req.into_inner();
 
-        let db_name = get_database_name(&measurement_names_request)?;
-
         let MeasurementNamesRequest {
             source: _source,
             range,
@@ -438,10 +568,21 @@ where
             db_name, range
         );
 
-        let response =
-            measurement_name_impl(self.db_store.clone(), self.executor.clone(), db_name, range)
-                .await
-                .map_err(|e| e.to_status());
+        let key = CacheKey::new(&db_name, format!("measurement_names {:?}", range));
+        let response = self
+            .cached(
+                key,
+                &access,
+                measurement_name_impl(
+                    self.db_store.clone(),
+                    self.executor.clone(),
+                    db_name,
+                    access.clone(),
+                    range,
+                ),
+            )
+            .await
+            .map_err(|e| e.to_status());
 
         tx.send(response)
             .await
@@ -458,10 +599,9 @@ where
     ) -> Result<tonic::Response<Self::MeasurementTagKeysStream>, Status> {
         let (mut tx, rx) = mpsc::channel(4);
 
+        let (db_name, access) = self.authorize_read(&req)?;
         let measurement_tag_keys_request = req.into_inner();
 
-        let db_name = get_database_name(&measurement_tag_keys_request)?;
-
         let MeasurementTagKeysRequest {
             source: _source,
             measurement,
@@ -474,18 +614,31 @@ where
             db_name, range, measurement
         );
 
+        let key = CacheKey::new(
+            &db_name,
+            format!(
+                "measurement_tag_keys {:?} {:?} {:?}",
+                measurement, range, predicate
+            ),
+        );
         let measurement = Some(measurement);
 
-        let response = tag_keys_impl(
-            self.db_store.clone(),
-            self.executor.clone(),
-            db_name,
-            measurement,
-            range,
-            predicate,
-        )
-        .await
-        .map_err(|e| e.to_status());
+        let response = self
+            .cached(
+                key,
+                &access,
+                tag_keys_impl(
+                    self.db_store.clone(),
+                    self.executor.clone(),
+                    db_name,
+                    access.clone(),
+                    measurement,
+                    range,
+                    predicate,
+                ),
+            )
+            .await
+            .map_err(|e| e.to_status());
 
         tx.send(response)
             .await
@@ -502,10 +655,9 @@ where
     ) -> Result<tonic::Response<Self::MeasurementTagValuesStream>, Status> {
         let (mut tx, rx) = mpsc::channel(4);
 
+        let (db_name, access) = self.authorize_read(&req)?;
         let measurement_tag_values_request = req.into_inner();
 
-        let db_name = get_database_name(&measurement_tag_values_request)?;
-
         let MeasurementTagValuesRequest {
             source: _source,
             measurement,
@@ -519,19 +671,32 @@ where
             db_name, range, measurement, tag_key
         );
 
+        let key = CacheKey::new(
+            &db_name,
+            format!(
+                "measurement_tag_values {:?} {:?} {:?} {:?}",
+                tag_key, measurement, range, predicate
+            ),
+        );
         let measurement = Some(measurement);
 
-        let response = tag_values_impl(
-            self.db_store.clone(),
-            self.executor.clone(),
-            db_name,
-            tag_key,
-            measurement,
-            range,
-            predicate,
-        )
-        .await
-        .map_err(|e| e.to_status());
+        let response = self
+            .cached(
+                key,
+                &access,
+                tag_values_impl(
+                    self.db_store.clone(),
+                    self.executor.clone(),
+                    db_name,
+                    access.clone(),
+                    tag_key,
+                    measurement,
+                    range,
+                    predicate,
+                ),
+            )
+            .await
+            .map_err(|e| e.to_status());
 
         tx.send(response)
             .await
@@ -548,10 +713,9 @@ where
     ) -> Result<tonic::Response<Self::MeasurementFieldsStream>, Status> {
         let (mut tx, rx) = mpsc::channel(4);
 
+        let (db_name, access) = self.authorize_read(&req)?;
         let measurement_fields_request = req.into_inner();
 
-        let db_name = get_database_name(&measurement_fields_request)?;
-
         let MeasurementFieldsRequest {
             source: _source,
             measurement,
@@ -570,6 +734,7 @@ where
             self.db_store.clone(),
             self.executor.clone(),
             db_name,
+            access,
             measurement,
             range,
             predicate,
@@ -599,11 +764,8 @@ impl SetRange for PredicateBuilder {
     }
 }
 
-fn get_database_name(input: &impl GrpcInputs) -> Result<String, Status> {
-    Ok(org_and_bucket_to_database(
-        input.org_id()?,
-        &input.bucket_name()?,
-    ))
+fn get_database_name(input: &impl GrpcInputs, buckets: &BucketMapping) -> Result<String, Status> {
+    Ok(buckets.database_name(&input.org_id()?.to_string(), &input.bucket_name()?))
 }
 
 // The following code implements the business logic of the requests as
@@ -617,12 +779,13 @@ async fn measurement_name_impl<T>(
     db_store: Arc<T>,
     executor: Arc<StorageExecutor>,
     db_name: String,
+    access: RowAccess,
     range: Option<TimestampRange>,
 ) -> Result<StringValuesResponse>
 where
     T: DatabaseStore,
 {
-    let predicate = PredicateBuilder::default().set_range(range).build();
+    let predicate = access.restrict(PredicateBuilder::default().set_range(range).build());
 
     let plan = db_store
         .db(&db_name)
@@ -657,6 +820,7 @@ async fn tag_keys_impl<T>(
     db_store: Arc<T>,
     executor: Arc<StorageExecutor>,
     db_name: String,
+    access: RowAccess,
     measurement: Option<String>,
     range: Option<TimestampRange>,
     rpc_predicate: Option<Predicate>,
@@ -674,6 +838,7 @@ where
             rpc_predicate_string,
         })?
         .build();
+    let predicate = access.restrict(predicate);
 
     let db = db_store
         .db(&db_name)
@@ -708,6 +873,7 @@ async fn tag_values_impl<T>(
     db_store: Arc<T>,
     executor: Arc<StorageExecutor>,
     db_name: String,
+    access: RowAccess,
     tag_name: String,
     measurement: Option<String>,
     range: Option<TimestampRange>,
@@ -726,6 +892,7 @@ where
             rpc_predicate_string,
         })?
         .build();
+    let predicate = access.restrict(predicate);
 
     let db = db_store
         .db(&db_name)
@@ -760,12 +927,40 @@ where
     Ok(StringValuesResponse { values })
 }
 
+/// Return the first values of a tag starting with a prefix, without listing all its values
+async fn tag_values_with_prefix_impl<T>(
+    db_store: Arc<T>,
+    executor: Arc<StorageExecutor>,
+    db_name: String,
+    access: RowAccess,
+    query: TagValuesQuery,
+) -> Result<TagValuesWithPrefixResponse>
+where
+    T: DatabaseStore,
+{
+    let db = db_store
+        .db(&db_name)
+        .await
+        .context(DatabaseNotFound { db_name: &db_name })?;
+
+    let TagValues { values, truncated } =
+        autocomplete::tag_values(db.as_ref(), &executor, &query, &access)
+            .await
+            .context(ListingTagValues {
+                db_name: &db_name,
+                tag_name: &query.tag_name,
+            })?;
+
+    Ok(TagValuesWithPrefixResponse { values, truncated })
+}
+
 /// Launch async tasks that send the result of executing read_filter to `tx`
 async fn read_filter_impl<T>(
     tx: mpsc::Sender<Result<ReadResponse, Status>>,
     db_store: Arc<T>,
     executor: Arc<StorageExecutor>,
     db_name: String,
+    access: RowAccess,
     range: Option<TimestampRange>,
     rpc_predicate: Option<Predicate>,
 ) -> Result<()>
@@ -781,6 +976,7 @@ where
             rpc_predicate_string,
         })?
         .build();
+    let predicate = access.restrict(predicate);
 
     let db = db_store
         .db(&db_name)
@@ -816,35 +1012,90 @@ where
 }
 
 /// Receives SeriesSets from rx, converts them to ReadResponse and
-/// and sends them to tx
+/// and sends them to tx, merging consecutive sets of the same series
 async fn convert_series_set(
     mut rx: mpsc::Receiver<Result<SeriesSet, SeriesSetError>>,
     mut tx: mpsc::Sender<Result<ReadResponse, Status>>,
 ) {
+    let mut merger = SeriesSetMerger::default();
+
     while let Some(series_set) = rx.recv().await {
-        let response = series_set
-            .context(ComputingSeriesSet)
-            .and_then(|series_set| {
-                series_set_to_read_response(series_set).context(ConvertingSeriesSet)
-            })
-            .map_err(|e| Status::internal(e.to_string()));
+        let responses: Vec<_> = match series_set.context(ComputingSeriesSet) {
+            Ok(series_set) => merger.push(series_set).into_iter().collect(),
+            Err(e) => merger
+                .flush()
+                .into_iter()
+                .chain(std::iter::once(Err(Status::internal(e.to_string()))))
+                .collect(),
+        };
 
-        // ignore errors sending results there is no one to notice them, return early
-        if tx.send(response).await.is_err() {
-            return;
+        for response in responses {
+            // ignore errors sending results there is no one to notice them, return early
+            if tx.send(response).await.is_err() {
+                return;
+            }
         }
     }
+
+    if let Some(response) = merger.flush() {
+        tx.send(response).await.ok();
+    }
+}
+
+/// Buffers the consecutive `SeriesSet`s of the same series (e.g. from
+/// several chunks) so they are sent as a single series, reduced to the
+/// approximate `percentile` of each of its fields if set
+#[derive(Debug, Default)]
+struct SeriesSetMerger {
+    series_sets: Vec<SeriesSet>,
+    percentile: Option<f64>,
+}
+
+impl SeriesSetMerger {
+    /// Adds `series_set`, returning the response for the previous series
+    /// if this one starts a new series
+    fn push(&mut self, series_set: SeriesSet) -> Option<Result<ReadResponse, Status>> {
+        let new_series = self.series_sets.first().map_or(false, |first| {
+            first.cmp_series_key(&series_set) != Ordering::Equal
+        });
+
+        let response = if new_series { self.flush() } else { None };
+        self.series_sets.push(series_set);
+        response
+    }
+
+    /// Returns the response for the buffered series, if any
+    fn flush(&mut self) -> Option<Result<ReadResponse, Status>> {
+        if self.series_sets.is_empty() {
+            return None;
+        }
+
+        let series_sets = std::mem::replace(&mut self.series_sets, vec![]);
+        let percentile = self.percentile;
+        Some(
+            series_sets_to_read_response(series_sets)
+                .map(|response| match percentile {
+                    Some(percentile) => approx_percentile_response(response, percentile),
+                    None => response,
+                })
+                .context(ConvertingSeriesSet)
+                .map_err(|e| Status::internal(e.to_string())),
+        )
+    }
 }
 
 /// Launch async tasks that send the result of executing read_group to `tx`
+#[allow(clippy::too_many_arguments)]
 async fn read_group_impl<T>(
     tx: mpsc::Sender<Result<ReadResponse, Status>>,
     db_store: Arc<T>,
     executor: Arc<StorageExecutor>,
     db_name: String,
+    access: RowAccess,
     range: Option<TimestampRange>,
     rpc_predicate: Option<Predicate>,
     group_keys: Vec<String>,
+    percentile: Option<f64>,
 ) -> Result<()>
 where
     T: DatabaseStore,
@@ -858,6 +1109,7 @@ where
             rpc_predicate_string,
         })?
         .build();
+    let predicate = access.restrict(predicate);
 
     let db = db_store
         .db(&db_name)
@@ -875,7 +1127,7 @@ where
     // and to run the actual plans (so we can return a result to the
     // client before we start sending result)
     let (tx_series, rx_series) = mpsc::channel(4);
-    tokio::spawn(async move { convert_grouped_series_set(rx_series, tx).await });
+    tokio::spawn(async move { convert_grouped_series_set(rx_series, tx, percentile).await });
 
     // fire up the plans and start the pipeline flowing
     tokio::spawn(async move {
@@ -892,24 +1144,71 @@ where
 }
 
 /// Receives SeriesSets from rx, converts them to ReadResponse and
-/// and sends them to tx
+/// and sends them to tx, merging consecutive sets of the same series
+/// and reducing them to the approximate `percentile` of each field if set
 async fn convert_grouped_series_set(
     mut rx: mpsc::Receiver<Result<GroupedSeriesSetItem, SeriesSetError>>,
     mut tx: mpsc::Sender<Result<ReadResponse, Status>>,
+    percentile: Option<f64>,
 ) {
+    let mut merger = SeriesSetMerger {
+        percentile,
+        ..Default::default()
+    };
+
     while let Some(grouped_series_set_item) = rx.recv().await {
-        let response = grouped_series_set_item
-            .context(ComputingGroupedSeriesSet)
-            .and_then(|grouped_series_set_item| {
-                grouped_series_set_item_to_read_response(grouped_series_set_item)
-                    .context(ConvertingSeriesSet)
-            })
-            .map_err(|e| Status::internal(e.to_string()));
+        let responses: Vec<_> = match grouped_series_set_item.context(ComputingGroupedSeriesSet) {
+            Ok(GroupedSeriesSetItem::GroupData(series_set)) => {
+                merger.push(series_set).into_iter().collect()
+            }
+            item => {
+                let response = item
+                    .and_then(|grouped_series_set_item| {
+                        grouped_series_set_item_to_read_response(grouped_series_set_item)
+                            .context(ConvertingSeriesSet)
+                    })
+                    .map_err(|e| Status::internal(e.to_string()));
+
+                merger
+                    .flush()
+                    .into_iter()
+                    .chain(std::iter::once(response))
+                    .collect()
+            }
+        };
+
+        for response in responses {
+            // ignore errors sending results there is no one to notice them, return early
+            if tx.send(response).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    if let Some(response) = merger.flush() {
+        tx.send(response).await.ok();
+    }
+}
+
+/// Returns the percentile each series of a read_group request is reduced to, if its
+/// aggregate is an approximate percentile
+fn approx_percentile(aggregate: Option<Aggregate>) -> Result<Option<f64>> {
+    let aggregate = match aggregate {
+        Some(aggregate) => aggregate,
+        None => return Ok(None),
+    };
 
-        // ignore errors sending results there is no one to notice them, return early
-        if tx.send(response).await.is_err() {
-            return;
+    match AggregateType::from_i32(aggregate.r#type) {
+        Some(AggregateType::ApproxMedian) => Ok(Some(0.5)),
+        Some(AggregateType::ApproxPercentile) => {
+            let percentile = aggregate.percentile;
+            ensure!(
+                (0.0..=1.0).contains(&percentile),
+                InvalidPercentile { percentile }
+            );
+            Ok(Some(percentile))
         }
+        _ => Ok(None),
     }
 }
 
@@ -918,6 +1217,7 @@ async fn measurement_fields_impl<T>(
     db_store: Arc<T>,
     executor: Arc<StorageExecutor>,
     db_name: String,
+    access: RowAccess,
     measurement: String,
     range: Option<TimestampRange>,
     rpc_predicate: Option<Predicate>,
@@ -935,6 +1235,7 @@ where
             rpc_predicate_string,
         })?
         .build();
+    let predicate = access.restrict(predicate);
 
     let db = db_store
         .db(&db_name)
@@ -962,37 +1263,19 @@ where
     fieldlist_to_measurement_fields_response(fieldlist).context(ConvertingFieldList)
 }
 
-/// Instantiate a server listening on the specified address
-/// implementing the IOx and Storage gRPC interfaces, the
-/// underlying hyper server instance. Resolves when the server has
-/// shutdown.
-pub async fn make_server<T>(
-    bind_addr: SocketAddr,
-    storage: Arc<T>,
-    executor: Arc<StorageExecutor>,
-) -> Result<()>
-where
-    T: DatabaseStore + 'static,
-{
-    tonic::transport::Server::builder()
-        .add_service(IOxServer::new(GrpcService::new(
-            storage.clone(),
-            executor.clone(),
-        )))
-        .add_service(StorageServer::new(GrpcService::new(
-            storage.clone(),
-            executor.clone(),
-        )))
-        .serve(bind_addr)
-        .await
-        .context(ServerError {})
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::panic::SendPanicsToTracing;
+    use crate::server::{
+        governor::Governor,
+        mode::Mode,
+        rpc::{cache::DEFAULT_MAX_AGE, ingest_limits::IngestLimiter, make_server},
+        ConnectionManagerImpl,
+    };
     use arrow_deps::arrow::datatypes::DataType;
+    use cluster::Server as AppServer;
+    use object_store::{InMemory, ObjectStore};
     use std::{
         convert::TryFrom,
         net::{IpAddr, Ipv4Addr, SocketAddr},
@@ -1004,6 +1287,7 @@ mod tests {
         exec::GroupedSeriesSetPlans,
         exec::SeriesSetPlans,
         id::Id,
+        org_and_bucket_to_database,
         test::ColumnNamesRequest,
         test::FieldColumnsRequest,
         test::QueryGroupsRequest,
@@ -1011,6 +1295,7 @@ mod tests {
         test::{ColumnValuesRequest, QuerySeriesRequest},
     };
     use test_helpers::tracing::TracingCapture;
+    use tokio::sync::RwLock;
     use tonic::Code;
 
     use futures::prelude::*;
@@ -1049,6 +1334,69 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_influxdb_iox_rpc_tag_values_with_prefix() -> Result<(), tonic::Status> {
+        let mut fixture = Fixture::new(11904)
+            .await
+            .expect("Connecting to test server");
+
+        let db_info = OrgAndBucket::new(123, 456);
+        let lp_data = "h2o,state=MA,city=Boston temp=70.4 100\n\
+                       h2o,state=MN,city=Minneapolis temp=72.4 250\n\
+                       o2,state=MI temp=50.4 200\n\
+                       o2,state=CA temp=79.0 300";
+        fixture
+            .test_storage
+            .add_lp_string(&db_info.db_name, lp_data)
+            .await;
+
+        let request = TagValuesWithPrefixRequest {
+            tags_source: Some(StorageClientWrapper::read_source(
+                db_info.org_id,
+                db_info.bucket_id,
+                1,
+            )),
+            range: None,
+            tag_key: "state".into(),
+            prefix: "M".into(),
+            measurement: "".into(),
+            limit: 0,
+        };
+
+        let response = fixture
+            .iox_client
+            .tag_values_with_prefix(request.clone())
+            .await?
+            .into_inner();
+        assert_eq!(response.values, vec!["MA", "MI", "MN"]);
+        assert!(!response.truncated);
+
+        let response = fixture
+            .iox_client
+            .tag_values_with_prefix(TagValuesWithPrefixRequest {
+                limit: 2,
+                ..request.clone()
+            })
+            .await?
+            .into_inner();
+        assert_eq!(response.values, vec!["MA", "MI"]);
+        assert!(response.truncated);
+
+        let response = fixture
+            .iox_client
+            .tag_values_with_prefix(TagValuesWithPrefixRequest {
+                range: make_timestamp_range(150, 300),
+                measurement: "h2o".into(),
+                ..request
+            })
+            .await?
+            .into_inner();
+        assert_eq!(response.values, vec!["MN"]);
+        assert!(!response.truncated);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_storage_rpc_capabilities() -> Result<(), tonic::Status> {
         // Note we use a unique port. TODO: let the OS pick the port
@@ -1112,6 +1460,53 @@ mod tests {
         Ok(())
     }
 
+    /// test that tag_keys responses are cached until the data version of the database changes
+    #[tokio::test]
+    async fn test_storage_rpc_tag_keys_cached() -> Result<(), tonic::Status> {
+        // Note we use a unique port. TODO: let the OS pick the port
+        let mut fixture = Fixture::new(11814)
+            .await
+            .expect("Connecting to test server");
+
+        let db_info = OrgAndBucket::new(123, 456);
+        let test_db = fixture
+            .test_storage
+            .db_or_create(&db_info.db_name)
+            .await
+            .expect("creating test database");
+
+        let request = TagKeysRequest {
+            tags_source: Some(StorageClientWrapper::read_source(
+                db_info.org_id,
+                db_info.bucket_id,
+                1,
+            )),
+            range: make_timestamp_range(150, 200),
+            predicate: None,
+        };
+
+        // the test database doesn't report a data version, so nothing is cached
+        test_db.set_column_names(to_string_vec(&["k1"])).await;
+        let actual_tag_keys = fixture.storage_client.tag_keys(request.clone()).await?;
+        assert_eq!(actual_tag_keys, vec!["_field", "_measurement", "k1"]);
+
+        test_db.set_data_version(Some(1));
+        test_db.set_column_names(to_string_vec(&["k2"])).await;
+        let actual_tag_keys = fixture.storage_client.tag_keys(request.clone()).await?;
+        assert_eq!(actual_tag_keys, vec!["_field", "_measurement", "k2"]);
+
+        // served from the cache: the database isn't asked again
+        test_db.set_column_names(to_string_vec(&["k3"])).await;
+        let actual_tag_keys = fixture.storage_client.tag_keys(request.clone()).await?;
+        assert_eq!(actual_tag_keys, vec!["_field", "_measurement", "k2"]);
+
+        test_db.set_data_version(Some(2));
+        let actual_tag_keys = fixture.storage_client.tag_keys(request).await?;
+        assert_eq!(actual_tag_keys, vec!["_field", "_measurement", "k3"]);
+
+        Ok(())
+    }
+
     /// test the plumbing of the RPC layer for tag_keys -- specifically that
     /// the right parameters are passed into the Database interface
     /// and that the returned values are sent back via gRPC.
@@ -1717,6 +2112,31 @@ mod tests {
         });
         assert_eq!(test_db.get_query_groups_request().await, expected_request);
 
+        // ---
+        // test invalid percentile
+        // ---
+        let request = ReadGroupRequest {
+            read_source: source.clone(),
+            range: None,
+            predicate: None,
+            group_keys: vec![],
+            group,
+            aggregate: Some(Aggregate {
+                r#type: AggregateType::ApproxPercentile as i32,
+                percentile: 2.0,
+            }),
+        };
+
+        let response = fixture.storage_client.read_group(request).await;
+        let response_string = format!("{:?}", response);
+        let expected_error = "Percentile 2 is not between 0 and 1";
+        assert!(
+            response_string.contains(expected_error),
+            "'{}' did not contain expected content '{}'",
+            response_string,
+            expected_error
+        );
+
         Ok(())
     }
 
@@ -2134,7 +2554,26 @@ mod tests {
 
             println!("Starting InfluxDB IOx rpc test server on {:?}", bind_addr);
 
-            let server = make_server(bind_addr, test_storage.clone(), test_executor.clone());
+            let app_server = Arc::new(RwLock::new(AppServer::new(
+                ConnectionManagerImpl::default(),
+                ObjectStore::new_in_memory(InMemory::new()),
+            )));
+
+            let server = make_server(
+                bind_addr,
+                None,
+                Mode::default(),
+                Arc::new(Authorizer::new(true)),
+                Arc::new(BucketMapping::new(true)),
+                test_storage.clone(),
+                test_executor.clone(),
+                app_server,
+                None,
+                Some(Arc::new(QueryCache::new(100, DEFAULT_MAX_AGE))),
+                Arc::new(Governor::default()),
+                Arc::new(IngestLimiter::default()),
+                futures::future::pending(),
+            );
             tokio::task::spawn(server);
 
             let iox_client = connect_to_server::<IOxClient>(bind_addr).await?;
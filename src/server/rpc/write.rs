@@ -0,0 +1,318 @@
+//! This module contains the implementation of the write gRPC service,
+//! which accepts entries that were already converted from line protocol,
+//! for example by a router, and hands them to a `cluster::Server`
+
+use std::sync::Arc;
+
+use cluster::{ConnectionManager, Server as AppServer};
+use generated_types::write::{
+    write_service_server, LineDiagnostic, ValidateLinesRequest, ValidateLinesResponse,
+    WriteEntryRequest, WriteEntryResponse,
+};
+use prost::Message;
+use snafu::{ensure, ResultExt, Snafu};
+use storage::{access::RowAccess, validate::ParsedWrite};
+use tokio::sync::RwLock;
+use tonic::{Code, Request, Response, Status};
+
+use super::{cluster_status, ingest_limits::IngestLimiter};
+use crate::server::auth::{Authorizer, Permission};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Database name is required"))]
+    MissingDatabaseName,
+
+    #[snafu(display("Entry is required"))]
+    MissingEntry,
+
+    #[snafu(display("Error writing entry: {}", source))]
+    WritingEntry { source: cluster::Error },
+
+    #[snafu(display("Error validating lines: {}", source))]
+    ValidatingLines { source: cluster::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Converts the error into the status of the failed write: INVALID_ARGUMENT for an entry
+    /// the database can't take as is, and RESOURCE_EXHAUSTED for a write the database
+    /// throttles, which clients retry later
+    fn to_status(&self) -> tonic::Status {
+        match &self {
+            Self::MissingDatabaseName => Status::invalid_argument(self.to_string()),
+            Self::MissingEntry => Status::invalid_argument(self.to_string()),
+            Self::WritingEntry { source } | Self::ValidatingLines { source } => {
+                cluster_status(source, self.to_string(), Code::Internal)
+            }
+        }
+    }
+}
+
+/// Implements the protobuf defined write service on top of a
+/// `cluster::Server`. Writes require the write permission on the database
+/// they write to, not restricted to some of its measurements or tag values, and are handled
+/// within the limits of `limiter`.
+#[derive(Debug)]
+pub struct WriteService<M: ConnectionManager> {
+    app_server: Arc<RwLock<AppServer<M>>>,
+    authorizer: Arc<Authorizer>,
+    limiter: Arc<IngestLimiter>,
+}
+
+impl<M> WriteService<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    /// Create a new WriteService for the databases of `app_server`
+    pub fn new(
+        app_server: Arc<RwLock<AppServer<M>>>,
+        authorizer: Arc<Authorizer>,
+        limiter: Arc<IngestLimiter>,
+    ) -> Self {
+        Self {
+            app_server,
+            authorizer,
+            limiter,
+        }
+    }
+
+    async fn write_entry_impl(&self, request: WriteEntryRequest) -> Result<()> {
+        let WriteEntryRequest { db_name, entry } = request;
+        ensure!(!db_name.is_empty(), MissingDatabaseName);
+        ensure!(!entry.is_empty(), MissingEntry);
+
+        self.app_server
+            .read()
+            .await
+            .write_entry(&db_name, entry)
+            .await
+            .context(WritingEntry)
+    }
+
+    async fn validate_lines_impl(
+        &self,
+        request: ValidateLinesRequest,
+        access: &RowAccess,
+    ) -> Result<ValidateLinesResponse> {
+        let ValidateLinesRequest { db_name, lp_data } = request;
+        ensure!(!db_name.is_empty(), MissingDatabaseName);
+
+        let mut write = ParsedWrite::parse(&lp_data);
+        let not_allowed: Vec<_> = write
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| !access.allows_line(line))
+            .map(|(index, _)| storage::validate::LineDiagnostic {
+                line_number: index + 1,
+                message: "the token may not write this line".to_string(),
+            })
+            .collect();
+        write.add(not_allowed);
+
+        let found = self
+            .app_server
+            .read()
+            .await
+            .validate_lines(&db_name, &write.lines)
+            .await
+            .context(ValidatingLines)?;
+        write.add(found);
+
+        Ok(ValidateLinesResponse {
+            line_count: write.line_count() as u64,
+            diagnostics: write
+                .diagnostics()
+                .into_iter()
+                .map(|d| LineDiagnostic {
+                    line_number: d.line_number as u64,
+                    message: d.message,
+                })
+                .collect(),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl<M> write_service_server::WriteService for WriteService<M>
+where
+    M: ConnectionManager + std::fmt::Debug + Send + Sync + 'static,
+{
+    async fn write_entry(
+        &self,
+        req: Request<WriteEntryRequest>,
+    ) -> Result<Response<WriteEntryResponse>, Status> {
+        let access = self.authorizer.access_grpc(
+            req.metadata(),
+            Permission::Write,
+            &req.get_ref().db_name,
+        )?;
+        // the rows of entries aren't checked, so they can't be written with restricted access
+        if !access.is_unrestricted() {
+            return Err(Status::permission_denied(
+                "Entries can only be written with access to all the rows of the database",
+            ));
+        }
+        let _permit = self
+            .limiter
+            .admit(req.get_ref().encoded_len())
+            .map_err(|e| e.to_status())?;
+
+        self.write_entry_impl(req.into_inner())
+            .await
+            .map(|()| Response::new(WriteEntryResponse {}))
+            .map_err(|e| e.to_status())
+    }
+
+    async fn validate_lines(
+        &self,
+        req: Request<ValidateLinesRequest>,
+    ) -> Result<Response<ValidateLinesResponse>, Status> {
+        let access = self.authorizer.access_grpc(
+            req.metadata(),
+            Permission::Write,
+            &req.get_ref().db_name,
+        )?;
+        let _permit = self
+            .limiter
+            .admit(req.get_ref().encoded_len())
+            .map_err(|e| e.to_status())?;
+
+        self.validate_lines_impl(req.into_inner(), &access)
+            .await
+            .map(Response::new)
+            .map_err(|e| e.to_status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ConnectionManagerImpl;
+    use data_types::{database_rules::DatabaseRules, entry::lines_to_entry};
+    use object_store::{InMemory, ObjectStore};
+    use tonic::Code;
+    use write_service_server::WriteService as _;
+
+    fn write_request(db_name: &str, entry: Vec<u8>) -> Request<WriteEntryRequest> {
+        Request::new(WriteEntryRequest {
+            db_name: db_name.to_string(),
+            entry,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_write_entry() {
+        let mut app_server = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        app_server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        app_server
+            .create_database("foo", rules.clone())
+            .await
+            .unwrap();
+        let app_server = Arc::new(RwLock::new(app_server));
+        let service = WriteService::new(
+            Arc::clone(&app_server),
+            Arc::new(Authorizer::new(true)),
+            Arc::new(IngestLimiter::default()),
+        );
+
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines("cpu bar=1 10")
+            .map(|l| l.unwrap())
+            .collect();
+        let entry = lines_to_entry(2, 1, &lines, &rules).unwrap().into_data();
+
+        service
+            .write_entry(write_request("foo", entry.clone()))
+            .await
+            .unwrap();
+        let summaries = app_server
+            .read()
+            .await
+            .chunk_summaries("foo")
+            .await
+            .unwrap();
+        assert_eq!(summaries[0].row_count, 1);
+
+        let status = service
+            .write_entry(write_request("bar", entry))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let status = service
+            .write_entry(write_request("foo", vec![]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .write_entry(write_request("foo", b"not an entry".to_vec()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_validate_lines() {
+        let mut app_server = AppServer::new(
+            ConnectionManagerImpl::default(),
+            ObjectStore::new_in_memory(InMemory::new()),
+        );
+        app_server.set_id(1);
+        let rules = DatabaseRules {
+            store_locally: true,
+            ..Default::default()
+        };
+        app_server.create_database("foo", rules).await.unwrap();
+        let lines: Vec<_> = influxdb_line_protocol::parse_lines("cpu bar=1 10")
+            .map(|l| l.unwrap())
+            .collect();
+        app_server.write_lines("foo", &lines).await.unwrap();
+        let app_server = Arc::new(RwLock::new(app_server));
+        let service = WriteService::new(
+            Arc::clone(&app_server),
+            Arc::new(Authorizer::new(true)),
+            Arc::new(IngestLimiter::default()),
+        );
+
+        let request = |lp_data: &str| {
+            Request::new(ValidateLinesRequest {
+                db_name: "foo".to_string(),
+                lp_data: lp_data.to_string(),
+            })
+        };
+        let response = service
+            .validate_lines(request("cpu bar=2 20\ncpu bar=true 30\nnot line protocol"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.line_count, 3);
+        assert_eq!(
+            response.diagnostics[0],
+            LineDiagnostic {
+                line_number: 2,
+                message: "column bar of measurement cpu has type f64, not bool".to_string(),
+            }
+        );
+        assert_eq!(response.diagnostics[1].line_number, 3);
+        assert_eq!(response.diagnostics.len(), 2);
+
+        // nothing was written
+        let summaries = app_server
+            .read()
+            .await
+            .chunk_summaries("foo")
+            .await
+            .unwrap();
+        assert_eq!(summaries[0].row_count, 1);
+    }
+}
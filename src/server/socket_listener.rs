@@ -0,0 +1,346 @@
+//! This module contains the UDP and Unix domain socket listeners, which accept line protocol
+//! from local agents that fire and forget their points, such as statsd-style collectors,
+//! without the overhead of an HTTP request per write.
+//!
+//! The listeners are opt-in, with `--udp-bind-addr` and `--unix-socket`, and write to the
+//! database named by `--socket-database`, creating it if needed. Each UDP datagram is a batch
+//! of lines; a Unix socket connection is a stream of lines, written in batches of the lines
+//! received together. Nothing is sent back: the lines that are not valid line protocol and
+//! those that can't be written are dropped and counted, per listener, in the
+//! `socket_listener_parse_errors_total` and `socket_listener_dropped_lines_total` metrics.
+
+use std::{
+    future::Future,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use influxdb_line_protocol::parse_lines;
+use snafu::{ResultExt, Snafu};
+use storage::{Database, DatabaseStore};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::{UdpSocket, UnixListener, UnixStream},
+};
+use tracing::{debug, info, warn};
+
+/// The largest UDP datagram received. Larger ones are truncated
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// The most lines of a Unix socket connection written at once
+const MAX_BATCH_LINES: usize = 1000;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error binding Unix socket {:?}: {}", path, source))]
+    BindingUnixSocket {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Binds a Unix domain socket at `path`, replacing the socket left there by a previous run
+pub fn bind_unix(path: &Path) -> Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path).context(BindingUnixSocket { path })?;
+        }
+    }
+    UnixListener::bind(path).context(BindingUnixSocket { path })
+}
+
+/// Writes the lines of the datagrams received on `socket` to the database `db_name` of
+/// `storage`, until `shutdown` resolves
+pub async fn serve_udp<T: DatabaseStore>(
+    mut socket: UdpSocket,
+    db_name: String,
+    storage: Arc<T>,
+    shutdown: impl Future<Output = ()>,
+) {
+    let name = match socket.local_addr() {
+        Ok(addr) => format!("udp://{}", addr),
+        Err(_) => "udp".to_string(),
+    };
+    let writer = LineWriter {
+        name,
+        db_name,
+        storage,
+    };
+
+    let receiving = async {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((len, _)) => writer.write(&buf[..len]).await,
+                Err(e) => warn!("error receiving on {}: {}", writer.name, e),
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = receiving => {},
+        _ = shutdown => info!("Stopped listening on {}", writer.name),
+    }
+}
+
+/// Writes the lines sent over the connections accepted by `listener` to the database
+/// `db_name` of `storage`, until `shutdown` resolves
+pub async fn serve_unix<T: DatabaseStore + 'static>(
+    mut listener: UnixListener,
+    db_name: String,
+    storage: Arc<T>,
+    shutdown: impl Future<Output = ()>,
+) {
+    let path = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(Path::to_path_buf));
+    let name = match &path {
+        Some(path) => format!("unix://{}", path.display()),
+        None => "unix".to_string(),
+    };
+    let writer = Arc::new(LineWriter {
+        name,
+        db_name,
+        storage,
+    });
+
+    let accepting = async {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(read_stream(stream, Arc::clone(&writer)));
+                }
+                Err(e) => warn!("error accepting connection on {}: {}", writer.name, e),
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = accepting => {},
+        _ = shutdown => info!("Stopped listening on {}", writer.name),
+    }
+
+    if let Some(path) = path {
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("error removing Unix socket {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Writes the lines of `stream` until it is closed, in batches of the lines already received
+async fn read_stream<T: DatabaseStore>(stream: UnixStream, writer: Arc<LineWriter<T>>) {
+    let mut reader = BufReader::new(stream);
+    let mut batch = Vec::new();
+    let mut lines = 0;
+    loop {
+        match reader.read_until(b'\n', &mut batch).await {
+            Ok(0) => break,
+            Ok(_) => lines += 1,
+            Err(e) => {
+                warn!("error reading from {}: {}", writer.name, e);
+                break;
+            }
+        }
+        if reader.buffer().is_empty() || lines >= MAX_BATCH_LINES {
+            writer.write(&batch).await;
+            batch.clear();
+            lines = 0;
+        }
+    }
+    if !batch.is_empty() {
+        writer.write(&batch).await;
+    }
+}
+
+/// Writes the lines received by a listener to its database
+#[derive(Debug)]
+struct LineWriter<T> {
+    /// The listener, as the address it listens on
+    name: String,
+    db_name: String,
+    storage: Arc<T>,
+}
+
+impl<T: DatabaseStore> LineWriter<T> {
+    /// Writes the valid lines of `data`, dropping the others
+    async fn write(&self, data: &[u8]) {
+        let labels = [("listener", self.name.as_str())];
+        let registry = metrics::registry();
+
+        let body = match std::str::from_utf8(data) {
+            Ok(body) => body,
+            Err(_) => {
+                let lines = data.split(|&b| b == b'\n').filter(|l| !l.is_empty());
+                registry
+                    .counter(
+                        "socket_listener_parse_errors_total",
+                        "Lines received by the socket listeners that are not valid line protocol",
+                        &labels,
+                    )
+                    .add(lines.count() as u64);
+                return;
+            }
+        };
+
+        let mut lines = vec![];
+        let mut errors = 0;
+        for line in parse_lines(body) {
+            match line {
+                Ok(line) => lines.push(line),
+                Err(e) => {
+                    debug!("invalid line received on {}: {}", self.name, e);
+                    errors += 1;
+                }
+            }
+        }
+        if errors > 0 {
+            registry
+                .counter(
+                    "socket_listener_parse_errors_total",
+                    "Lines received by the socket listeners that are not valid line protocol",
+                    &labels,
+                )
+                .add(errors);
+        }
+        if lines.is_empty() {
+            return;
+        }
+
+        let written = match self.storage.db_or_create(&self.db_name).await {
+            Ok(db) => db.write_lines(&lines).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match written {
+            Ok(()) => registry
+                .counter(
+                    "socket_listener_lines_total",
+                    "Lines of line protocol written through the socket listeners",
+                    &labels,
+                )
+                .add(lines.len() as u64),
+            Err(e) => {
+                warn!(
+                    "dropping {} lines received on {}: {}",
+                    lines.len(),
+                    self.name,
+                    e
+                );
+                registry
+                    .counter(
+                        "socket_listener_dropped_lines_total",
+                        "Lines received by the socket listeners that could not be written",
+                        &labels,
+                    )
+                    .add(lines.len() as u64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use storage::test::TestDatabaseStore;
+    use tokio::io::AsyncWriteExt;
+
+    /// Returns the lines written to `db_name` once there are `count` of them
+    async fn wait_for_lines(
+        storage: &TestDatabaseStore,
+        db_name: &str,
+        count: usize,
+    ) -> Vec<String> {
+        for _ in 0..100 {
+            if let Some(db) = storage.db(db_name).await {
+                let lines = db.get_lines().await;
+                if lines.len() >= count {
+                    return lines;
+                }
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("{} lines were not written to {}", count, db_name);
+    }
+
+    fn counter(name: &'static str, listener: &str) -> u64 {
+        metrics::registry()
+            .counter(name, "", &[("listener", listener)])
+            .get()
+    }
+
+    #[tokio::test]
+    async fn udp() {
+        let storage = Arc::new(TestDatabaseStore::new());
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let listener = tokio::spawn(serve_udp(
+            socket,
+            "agents".to_string(),
+            Arc::clone(&storage),
+            async {
+                stopped.await.ok();
+            },
+        ));
+
+        let mut client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(
+                b"cpu usage=1.5 10\nnot line protocol\ncpu usage=2.5 20\n",
+                addr,
+            )
+            .await
+            .unwrap();
+
+        let lines = wait_for_lines(&storage, "agents", 2).await;
+        assert_eq!(lines, vec!["cpu usage=1.5 10", "cpu usage=2.5 20"]);
+        let name = format!("udp://{}", addr);
+        assert_eq!(counter("socket_listener_lines_total", &name), 2);
+        assert_eq!(counter("socket_listener_parse_errors_total", &name), 1);
+
+        stop.send(()).unwrap();
+        listener.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("iox.sock");
+        let storage = Arc::new(TestDatabaseStore::new());
+
+        // a socket left by a previous run is replaced
+        drop(bind_unix(&path).unwrap());
+        let listener = bind_unix(&path).unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let listener = tokio::spawn(serve_unix(
+            listener,
+            "agents".to_string(),
+            Arc::clone(&storage),
+            async {
+                stopped.await.ok();
+            },
+        ));
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        client.write_all(b"mem used=1.5 10\nmem ").await.unwrap();
+        client
+            .write_all(b"used=2.5 20\nmem used=3.5 30")
+            .await
+            .unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let lines = wait_for_lines(&storage, "agents", 3).await;
+        assert_eq!(
+            lines,
+            vec!["mem used=1.5 10", "mem used=2.5 20", "mem used=3.5 30"]
+        );
+
+        stop.send(()).unwrap();
+        listener.await.unwrap();
+        assert!(!path.exists());
+    }
+}
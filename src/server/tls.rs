@@ -0,0 +1,196 @@
+//! This module contains the TLS configuration of the HTTP and gRPC servers, loaded from PEM
+//! files. When a client CA is given, clients must present a certificate signed by it.
+
+use std::{
+    fs,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures::{Stream, StreamExt};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile, AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore,
+        ServerConfig, TLSError,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tracing::warn;
+
+/// The number of TLS handshakes of new HTTP connections that can be in progress at once
+const MAX_CONCURRENT_HANDSHAKES: usize = 64;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error reading {}: {}", path.display(), source))]
+    ReadingFile { path: PathBuf, source: io::Error },
+
+    #[snafu(display("No PEM encoded certificate found in {}", path.display()))]
+    InvalidCertificate { path: PathBuf },
+
+    #[snafu(display("No PEM encoded PKCS8 or RSA private key found in {}", path.display()))]
+    InvalidKey { path: PathBuf },
+
+    #[snafu(display("Error configuring TLS: {}", source))]
+    ConfiguringTls { source: TLSError },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The files to serve TLS with
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// The certificate chain of the server
+    pub cert: PathBuf,
+    /// The private key of the server's certificate
+    pub key: PathBuf,
+    /// If set, only clients with a certificate signed by one of the certificates in this
+    /// file are accepted
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Returns the configuration of the gRPC server
+    pub fn grpc_config(&self) -> Result<ServerTlsConfig> {
+        // check the files up front, as tonic only parses them once serving
+        self.rustls_config()?;
+
+        let identity = Identity::from_pem(read(&self.cert)?, read(&self.key)?);
+        let mut config = ServerTlsConfig::new().identity(identity);
+        if let Some(client_ca) = &self.client_ca {
+            config = config.client_ca_root(Certificate::from_pem(read(client_ca)?));
+        }
+        Ok(config)
+    }
+
+    /// Returns the acceptor to secure the connections of the HTTP server with
+    pub fn http_acceptor(&self) -> Result<TlsAcceptor> {
+        let mut config = self.rustls_config()?;
+        config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    fn rustls_config(&self) -> Result<ServerConfig> {
+        let verifier = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                let (valid, _) = roots
+                    .add_pem_file(&mut BufReader::new(&read(client_ca)?[..]))
+                    .ok()
+                    .context(InvalidCertificate { path: client_ca })?;
+                ensure!(valid > 0, InvalidCertificate { path: client_ca });
+                AllowAnyAuthenticatedClient::new(roots)
+            }
+            None => NoClientAuth::new(),
+        };
+
+        let certs = pemfile::certs(&mut BufReader::new(&read(&self.cert)?[..]))
+            .ok()
+            .filter(|certs| !certs.is_empty())
+            .context(InvalidCertificate { path: &self.cert })?;
+        let key = private_key(&self.key)?;
+
+        let mut config = ServerConfig::new(verifier);
+        config.set_single_cert(certs, key).context(ConfiguringTls)?;
+        Ok(config)
+    }
+}
+
+/// Returns the first PKCS8 or RSA private key in the file at `path`
+fn private_key(path: &Path) -> Result<PrivateKey> {
+    let pem = read(path)?;
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut BufReader::new(&pem[..])).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut BufReader::new(&pem[..])).unwrap_or_default();
+
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .next()
+        .context(InvalidKey { path })
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).context(ReadingFile { path })
+}
+
+/// Returns the TLS connections made to `listener`. Connections whose handshake fails are
+/// logged and dropped, as they only concern the client that made them.
+pub fn incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> impl Stream<Item = io::Result<TlsStream<TcpStream>>> {
+    listener
+        .map(move |stream| {
+            let acceptor = acceptor.clone();
+            async move {
+                match stream {
+                    Ok(stream) => match acceptor.accept(stream).await {
+                        Ok(stream) => Some(Ok(stream)),
+                        Err(e) => {
+                            warn!(error = %e, "TLS handshake failed");
+                            None
+                        }
+                    },
+                    Err(e) => Some(Err(e)),
+                }
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_HANDSHAKES)
+        .filter_map(futures::future::ready)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "tls", name]
+            .iter()
+            .collect()
+    }
+
+    fn config(client_ca: Option<&str>) -> TlsConfig {
+        TlsConfig {
+            cert: fixture("server.pem"),
+            key: fixture("server.key"),
+            client_ca: client_ca.map(fixture),
+        }
+    }
+
+    #[test]
+    fn loads_pem_files() {
+        config(None).http_acceptor().unwrap();
+        config(None).grpc_config().unwrap();
+
+        // the self signed certificate is its own CA
+        config(Some("server.pem")).http_acceptor().unwrap();
+        config(Some("server.pem")).grpc_config().unwrap();
+    }
+
+    #[test]
+    fn invalid_files() {
+        let mut missing = config(None);
+        missing.key = fixture("missing.key");
+        let err = missing.http_acceptor().unwrap_err();
+        assert!(matches!(err, Error::ReadingFile { .. }), "{}", err);
+
+        // the files are swapped
+        let mut swapped = config(None);
+        swapped.cert = fixture("server.key");
+        let err = swapped.http_acceptor().unwrap_err();
+        assert!(matches!(err, Error::InvalidCertificate { .. }), "{}", err);
+
+        swapped.cert = fixture("server.pem");
+        swapped.key = fixture("server.pem");
+        let err = swapped.grpc_config().unwrap_err();
+        assert!(matches!(err, Error::InvalidKey { .. }), "{}", err);
+
+        let err = config(Some("server.key")).http_acceptor().unwrap_err();
+        assert!(matches!(err, Error::InvalidCertificate { .. }), "{}", err);
+    }
+}
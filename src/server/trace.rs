@@ -0,0 +1,254 @@
+//! This module contains the extraction of trace context from incoming HTTP and gRPC requests,
+//! so that the spans created while handling a request continue the trace of the client that
+//! sent it.
+//!
+//! Both the [W3C `traceparent`](https://www.w3.org/TR/trace-context/#traceparent-header) and the
+//! [Jaeger `uber-trace-id`](https://www.jaegertracing.io/docs/1.21/client-libraries/#propagation-format)
+//! headers are understood. If a request carries both, `traceparent` wins.
+//!
+//! Each request is also given an id, which is recorded on its span so that all the logs of the
+//! request can be found. Clients can choose the id by sending an `X-Request-Id` header.
+
+use http::HeaderMap;
+use opentelemetry::trace::{
+    SpanContext, SpanId, TraceContextExt, TraceId, TraceState, TRACE_FLAG_SAMPLED,
+};
+use tracing::{field, info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+const TRACEPARENT_HEADER: &str = "traceparent";
+const JAEGER_HEADER: &str = "uber-trace-id";
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Request ids given by clients that are longer than this are replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Identifies the span of a remote caller that a request is part of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    /// The id of the caller's span, which becomes the parent of the request span
+    pub span_id: u64,
+    /// Whether the caller sampled the trace
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Extracts the trace context from the headers of a request, if it carries one
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+        header(TRACEPARENT_HEADER)
+            .and_then(Self::from_traceparent)
+            .or_else(|| header(JAEGER_HEADER).and_then(Self::from_jaeger))
+    }
+
+    /// Parses a W3C `traceparent` header value:
+    /// `{version}-{trace-id}-{parent-id}-{trace-flags}`
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let parts: Vec<_> = value.trim().split('-').collect();
+
+        // later versions may append fields, but version ff is invalid
+        let version = *parts.get(0)?;
+        if version.len() != 2 || version == "ff" || u8::from_str_radix(version, 16).is_err() {
+            return None;
+        }
+        if parts.len() < 4 || (version == "00" && parts.len() != 4) {
+            return None;
+        }
+
+        let (trace_id, span_id, flags) = (parts[1], parts[2], parts[3]);
+        if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Self::new(
+            u128::from_str_radix(trace_id, 16).ok()?,
+            u64::from_str_radix(span_id, 16).ok()?,
+            flags & 1 == 1,
+        )
+    }
+
+    /// Parses a Jaeger `uber-trace-id` header value:
+    /// `{trace-id}:{span-id}:{parent-span-id}:{flags}`, which may be URL encoded
+    pub fn from_jaeger(value: &str) -> Option<Self> {
+        let value = value.trim().replace("%3A", ":").replace("%3a", ":");
+        let parts: Vec<_> = value.split(':').collect();
+        if parts.len() != 4 || parts[0].len() > 32 || parts[1].len() > 16 {
+            return None;
+        }
+
+        let flags = u8::from_str_radix(parts[3], 16).ok()?;
+        Self::new(
+            u128::from_str_radix(parts[0], 16).ok()?,
+            u64::from_str_radix(parts[1], 16).ok()?,
+            flags & 1 == 1,
+        )
+    }
+
+    /// All zero ids are invalid in both formats
+    fn new(trace_id: u128, span_id: u64, sampled: bool) -> Option<Self> {
+        if trace_id == 0 || span_id == 0 {
+            return None;
+        }
+
+        Some(Self {
+            trace_id,
+            span_id,
+            sampled,
+        })
+    }
+}
+
+/// Returns the id of a request: the one sent by the client in `X-Request-Id` if it is made of
+/// at most 128 printable ASCII characters, otherwise a new random id
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map(ToString::to_string)
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()))
+}
+
+/// Creates the span to handle a request with the given id and headers under. If the request
+/// carries a trace context, the span continues that trace, and the trace id is recorded on the
+/// span so that it shows up in the logs.
+pub fn request_span(protocol: &'static str, request_id: &str, headers: &HeaderMap) -> Span {
+    let span = info_span!("request", protocol, request_id, trace_id = field::Empty);
+
+    if let Some(context) = TraceContext::from_headers(headers) {
+        span.record(
+            "trace_id",
+            &field::display(format!("{:032x}", context.trace_id)),
+        );
+
+        let flags = if context.sampled {
+            TRACE_FLAG_SAMPLED
+        } else {
+            0
+        };
+        let remote = SpanContext::new(
+            TraceId::from_u128(context.trace_id),
+            SpanId::from_u64(context.span_id),
+            flags,
+            true,
+            TraceState::default(),
+        );
+        span.set_parent(&opentelemetry::Context::new().with_remote_span_context(remote));
+    }
+
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn traceparent() {
+        assert_eq!(
+            TraceContext::from_traceparent(
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+            ),
+            Some(TraceContext {
+                trace_id: 0x0af7651916cd43dd8448eb211c80319c,
+                span_id: 0xb7ad6b7169203331,
+                sampled: true,
+            })
+        );
+
+        let unsampled = TraceContext::from_traceparent(
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00",
+        )
+        .unwrap();
+        assert!(!unsampled.sampled);
+
+        // future versions may add fields
+        assert!(TraceContext::from_traceparent(
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra"
+        )
+        .is_some());
+
+        for invalid in &[
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0af7651916cd43dd8448eb211c8031-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319z-b7ad6b7169203331-01",
+        ] {
+            assert_eq!(TraceContext::from_traceparent(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn jaeger() {
+        assert_eq!(
+            TraceContext::from_jaeger("abc:def:0:1"),
+            Some(TraceContext {
+                trace_id: 0xabc,
+                span_id: 0xdef,
+                sampled: true,
+            })
+        );
+        assert_eq!(
+            TraceContext::from_jaeger(
+                "0af7651916cd43dd8448eb211c80319c%3Ab7ad6b7169203331%3A0%3A0"
+            ),
+            Some(TraceContext {
+                trace_id: 0x0af7651916cd43dd8448eb211c80319c,
+                span_id: 0xb7ad6b7169203331,
+                sampled: false,
+            })
+        );
+
+        for invalid in &["", "abc:def:0", "0:def:0:1", "abc:0:0:1", "xyz:def:0:1"] {
+            assert_eq!(TraceContext::from_jaeger(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn request_ids() {
+        let mut headers = HeaderMap::new();
+
+        let generated = request_id(&headers);
+        assert_eq!(generated.len(), 32);
+        assert_ne!(generated, request_id(&headers));
+
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("my-request-1"));
+        assert_eq!(request_id(&headers), "my-request-1");
+
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("two words"));
+        assert_ne!(request_id(&headers), "two words");
+    }
+
+    #[test]
+    fn from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(TraceContext::from_headers(&headers), None);
+
+        headers.insert(JAEGER_HEADER, HeaderValue::from_static("abc:def:0:1"));
+        assert_eq!(
+            TraceContext::from_headers(&headers).map(|c| c.trace_id),
+            Some(0xabc)
+        );
+
+        headers.insert(
+            TRACEPARENT_HEADER,
+            HeaderValue::from_static("00-0000000000000000000000000000000a-000000000000000b-01"),
+        );
+        assert_eq!(
+            TraceContext::from_headers(&headers).map(|c| c.trace_id),
+            Some(0xa)
+        );
+    }
+}
@@ -0,0 +1,513 @@
+//! This module contains the restrictions on the rows of a database a request may access,
+//! which let several tenants share a database. The rows can be restricted to some
+//! measurements, and to the rows with some tag values, such as `tenant=acme`.
+//!
+//! Restrictions are enforced on both sides: the lines written must belong to the allowed
+//! measurements and carry the allowed tag values, and queries only see the allowed rows, as
+//! the restrictions are added to their predicates when they are planned.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use arrow_deps::{
+    arrow::{
+        array::{Array, BooleanArray, StringArray},
+        compute::kernels::filter::filter_record_batch,
+        error::ArrowError,
+        record_batch::RecordBatch,
+    },
+    datafusion::{
+        logical_plan::{col, Expr},
+        scalar::ScalarValue,
+    },
+};
+use influxdb_line_protocol::ParsedLine;
+
+use crate::predicate::Predicate;
+
+/// The rows of a database a request may access. The default allows all of them.
+///
+/// Rows are allowed by grants, each of which allows the rows of some measurements that have
+/// all of its tag values. The grants of several scopes are kept apart rather than merged, so
+/// that a row is only allowed when a single grant allows both its measurement and its tag
+/// values.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RowAccess {
+    /// The grants allowing the rows, all rows if `None`
+    grants: Option<Vec<Grant>>,
+}
+
+/// The rows of the measurements `measurements` that have the values of `tags`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Grant {
+    /// The allowed measurements, all of them if `None`
+    measurements: Option<BTreeSet<String>>,
+    /// The tag values of the allowed rows, any tag values if empty
+    tags: BTreeMap<String, String>,
+}
+
+impl Grant {
+    fn allows_measurement(&self, name: &str) -> bool {
+        self.measurements
+            .as_ref()
+            .map_or(true, |measurements| measurements.contains(name))
+    }
+
+    /// Whether a row that has the tag values for which `has_tag` returns true is allowed
+    fn allows_tags(&self, has_tag: impl Fn(&str, &str) -> bool) -> bool {
+        self.tags.iter().all(|(key, value)| has_tag(key, value))
+    }
+
+    /// An expression that is true for the rows with the tag values, if they are restricted
+    fn tags_expr(&self) -> Option<Expr> {
+        let mut tags_expr: Option<Expr> = None;
+        for (key, value) in &self.tags {
+            let term = col(key).eq(Expr::Literal(ScalarValue::Utf8(Some(value.clone()))));
+            tags_expr = Some(match tags_expr {
+                Some(expr) => expr.and(term),
+                None => term,
+            });
+        }
+        tags_expr
+    }
+}
+
+/// An expression that is true for the rows with the tag values of one of `grants`, `None` if
+/// one of them allows any tag values, and false for all rows if there are no grants
+fn grants_expr<'a>(grants: impl Iterator<Item = &'a Grant>) -> Option<Expr> {
+    let mut filter: Option<Expr> = None;
+    for grant in grants {
+        let tags_expr = grant.tags_expr()?;
+        filter = Some(match filter {
+            Some(expr) => expr.or(tags_expr),
+            None => tags_expr,
+        });
+    }
+    Some(filter.unwrap_or(Expr::Literal(ScalarValue::Boolean(Some(false)))))
+}
+
+impl RowAccess {
+    /// Allows the rows of `measurements`, or of all measurements if empty, that have the
+    /// values of `tags`, or any tag values if empty
+    pub fn new(measurements: &[String], tags: &BTreeMap<String, String>) -> Self {
+        if measurements.is_empty() && tags.is_empty() {
+            return Self::default();
+        }
+
+        let grant = Grant {
+            measurements: if measurements.is_empty() {
+                None
+            } else {
+                Some(measurements.iter().cloned().collect())
+            },
+            tags: tags.clone(),
+        };
+        Self {
+            grants: Some(vec![grant]),
+        }
+    }
+
+    /// Also allows the rows `other` allows, by adding its grants to those of `self`
+    pub fn union(self, other: Self) -> Self {
+        let grants = match (self.grants, other.grants) {
+            (Some(mut grants), Some(other)) => {
+                for grant in other {
+                    if !grants.contains(&grant) {
+                        grants.push(grant);
+                    }
+                }
+                Some(grants)
+            }
+            _ => None,
+        };
+
+        Self { grants }
+    }
+
+    /// Whether all rows are allowed
+    pub fn is_unrestricted(&self) -> bool {
+        self.grants.is_none()
+    }
+
+    /// Whether rows of the measurement, or table, `name` may be allowed
+    pub fn allows_measurement(&self, name: &str) -> bool {
+        self.grants.as_ref().map_or(true, |grants| {
+            grants.iter().any(|grant| grant.allows_measurement(name))
+        })
+    }
+
+    /// Whether the row of `line` is allowed
+    pub fn allows_line(&self, line: &ParsedLine<'_>) -> bool {
+        let grants = match &self.grants {
+            Some(grants) => grants,
+            None => return true,
+        };
+
+        let measurement = line.series.measurement.as_str();
+        let line_tags = line.series.tag_set.as_ref();
+        let has_tag = |key: &str, value: &str| {
+            line_tags.map_or(false, |line_tags| {
+                line_tags
+                    .iter()
+                    .any(|(k, v)| k.as_str() == key && v.as_str() == value)
+            })
+        };
+        grants
+            .iter()
+            .any(|grant| grant.allows_measurement(measurement) && grant.allows_tags(&has_tag))
+    }
+
+    /// Whether the row of measurement `name` with the tag values `tags` is allowed
+    pub fn allows_point(&self, name: &str, tags: &BTreeMap<String, String>) -> bool {
+        self.grants.as_ref().map_or(true, |grants| {
+            grants.iter().any(|grant| {
+                grant.allows_measurement(name)
+                    && grant
+                        .allows_tags(|key, value| tags.get(key).map(String::as_str) == Some(value))
+            })
+        })
+    }
+
+    /// The tag columns the allowed rows are told apart by, which `filter_batch` reads
+    pub fn tag_columns(&self) -> BTreeSet<&str> {
+        self.grants
+            .iter()
+            .flatten()
+            .flat_map(|grant| grant.tags.keys().map(String::as_str))
+            .collect()
+    }
+
+    /// An expression that is true for the allowed rows of the table `table_name`, if they are
+    /// restricted by their tag values
+    pub fn filter_expr(&self, table_name: &str) -> Option<Expr> {
+        let grants = self.grants.as_ref()?;
+        grants_expr(
+            grants
+                .iter()
+                .filter(|grant| grant.allows_measurement(table_name)),
+        )
+    }
+
+    /// Restricts `predicate` to the allowed rows, so that the plans built from it only read
+    /// those. When the grants allow different tag values in different measurements, the rows
+    /// are restricted by an expression for each measurement named by a grant, and one for the
+    /// other tables.
+    pub fn restrict(&self, mut predicate: Predicate) -> Predicate {
+        let grants = match &self.grants {
+            Some(grants) => grants,
+            None => return predicate,
+        };
+
+        let measurements = grants.iter().try_fold(BTreeSet::new(), |mut all, grant| {
+            all.extend(grant.measurements.as_ref()?.iter().cloned());
+            Some(all)
+        });
+        if let Some(measurements) = measurements {
+            predicate.table_names = Some(match predicate.table_names {
+                Some(table_names) => table_names.intersection(&measurements).cloned().collect(),
+                None => measurements,
+            });
+        }
+
+        if grants.iter().all(|grant| grant.measurements.is_none()) {
+            if let Some(expr) = grants_expr(grants.iter()) {
+                predicate.exprs.push(expr);
+            }
+        } else {
+            for name in grants
+                .iter()
+                .flat_map(|grant| grant.measurements.iter().flatten())
+            {
+                let expr = self
+                    .filter_expr(name)
+                    .unwrap_or(Expr::Literal(ScalarValue::Boolean(Some(true))));
+                predicate.table_exprs.insert(name.clone(), expr);
+            }
+            predicate.other_tables_expr =
+                grants_expr(grants.iter().filter(|grant| grant.measurements.is_none()));
+        }
+        predicate
+    }
+
+    /// Leaves out of `batch`, rows of the table `table_name`, the rows that aren't allowed.
+    /// Rows without one of the tags of a grant don't have its values, so a table without the
+    /// tag columns has no allowed rows.
+    pub fn filter_batch(
+        &self,
+        table_name: &str,
+        batch: &RecordBatch,
+    ) -> Result<RecordBatch, ArrowError> {
+        let grants = match &self.grants {
+            Some(grants) => grants,
+            None => return Ok(batch.clone()),
+        };
+
+        let schema = batch.schema();
+        let mut keep = vec![false; batch.num_rows()];
+        for grant in grants
+            .iter()
+            .filter(|grant| grant.allows_measurement(table_name))
+        {
+            let columns: Option<Vec<_>> = grant
+                .tags
+                .iter()
+                .map(|(key, value)| {
+                    let index = schema.index_of(key).ok()?;
+                    let column = batch.column(index).as_any().downcast_ref::<StringArray>()?;
+                    Some((column, value.as_str()))
+                })
+                .collect();
+            let columns = match columns {
+                Some(columns) => columns,
+                None => continue,
+            };
+
+            for (row, keep) in keep.iter_mut().enumerate() {
+                *keep |= columns
+                    .iter()
+                    .all(|(column, value)| !column.is_null(row) && column.value(row) == *value);
+            }
+        }
+
+        if keep.iter().all(|&keep| keep) {
+            Ok(batch.clone())
+        } else {
+            filter_record_batch(batch, &BooleanArray::from(keep))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_deps::arrow::{
+        array::Int64Array,
+        datatypes::{DataType, Field, Schema},
+    };
+    use influxdb_line_protocol::parse_lines;
+    use std::sync::Arc;
+
+    fn access(measurements: &[&str], tags: &[(&str, &str)]) -> RowAccess {
+        let measurements: Vec<_> = measurements.iter().map(ToString::to_string).collect();
+        let tags = tags
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        RowAccess::new(&measurements, &tags)
+    }
+
+    fn tag_expr(key: &str, value: &str) -> Expr {
+        col(key).eq(Expr::Literal(ScalarValue::Utf8(Some(value.to_string()))))
+    }
+
+    fn bool_expr(value: bool) -> Expr {
+        Expr::Literal(ScalarValue::Boolean(Some(value)))
+    }
+
+    fn allowed_lines(access: &RowAccess, lp: &str) -> Vec<bool> {
+        parse_lines(lp)
+            .map(|line| access.allows_line(&line.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn lines() {
+        let lp = "cpu,tenant=acme usage=1 10\n\
+                  cpu,tenant=beta usage=1 10\n\
+                  cpu usage=1 10\n\
+                  mem,tenant=acme,host=a free=1 10";
+
+        assert_eq!(
+            allowed_lines(&RowAccess::default(), lp),
+            vec![true, true, true, true]
+        );
+        assert_eq!(
+            allowed_lines(&access(&["cpu"], &[]), lp),
+            vec![true, true, true, false]
+        );
+        assert_eq!(
+            allowed_lines(&access(&[], &[("tenant", "acme")]), lp),
+            vec![true, false, false, true]
+        );
+
+        let combined =
+            access(&["cpu"], &[("tenant", "acme")]).union(access(&["mem"], &[("tenant", "beta")]));
+        assert_eq!(
+            allowed_lines(&combined, lp),
+            vec![true, false, false, false]
+        );
+
+        // an unrestricted grant allows everything
+        let combined = access(&["cpu"], &[("tenant", "acme")]).union(RowAccess::default());
+        assert!(combined.is_unrestricted());
+    }
+
+    #[test]
+    fn predicate() {
+        let access = access(&["cpu", "mem"], &[("tenant", "acme")]);
+        let expected = tag_expr("tenant", "acme");
+        assert_eq!(
+            format!("{:?}", access.filter_expr("cpu").unwrap()),
+            format!("{:?}", expected)
+        );
+        assert_eq!(
+            format!("{:?}", access.filter_expr("disk").unwrap()),
+            format!("{:?}", bool_expr(false))
+        );
+
+        let predicate = access.restrict(Predicate::default());
+        assert_eq!(
+            predicate.table_names,
+            Some(
+                vec!["cpu".to_string(), "mem".to_string()]
+                    .into_iter()
+                    .collect()
+            )
+        );
+        assert!(predicate.exprs.is_empty());
+        assert_eq!(
+            format!("{:?}", predicate.table_exprs["mem"]),
+            format!("{:?}", expected)
+        );
+
+        let mut predicate = Predicate::default();
+        predicate.table_names = Some(
+            vec!["cpu".to_string(), "disk".to_string()]
+                .into_iter()
+                .collect(),
+        );
+        let predicate = access.restrict(predicate);
+        assert_eq!(
+            predicate.table_names,
+            Some(vec!["cpu".to_string()].into_iter().collect())
+        );
+
+        assert!(RowAccess::default().filter_expr("cpu").is_none());
+
+        // the same tag values in all measurements are restricted by a single expression
+        let predicate = self::access(&[], &[("tenant", "acme")]).restrict(Predicate::default());
+        assert!(predicate.table_names.is_none());
+        assert_eq!(
+            format!("{:?}", predicate.exprs),
+            format!("{:?}", vec![expected])
+        );
+        assert!(predicate.table_exprs.is_empty());
+
+        let combined = access.union(self::access(&[], &[("region", "us"), ("tenant", "beta")]));
+        assert_eq!(
+            combined.tag_columns().into_iter().collect::<Vec<_>>(),
+            vec!["region", "tenant"]
+        );
+        assert!(RowAccess::default().tag_columns().is_empty());
+    }
+
+    #[test]
+    fn batches() {
+        let schema = Schema::new(vec![
+            Field::new("tenant", DataType::Utf8, true),
+            Field::new("time", DataType::Int64, true),
+        ]);
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(StringArray::from(vec![Some("acme"), Some("beta"), None])),
+                Arc::new(Int64Array::from(vec![1, 2, 3])),
+            ],
+        )
+        .unwrap();
+
+        let filtered = access(&[], &[("tenant", "acme")])
+            .filter_batch("cpu", &batch)
+            .unwrap();
+        assert_eq!(filtered.num_rows(), 1);
+
+        let filtered = access(&[], &[("region", "us")])
+            .filter_batch("cpu", &batch)
+            .unwrap();
+        assert_eq!(filtered.num_rows(), 0);
+
+        let filtered = RowAccess::default().filter_batch("cpu", &batch).unwrap();
+        assert_eq!(filtered.num_rows(), 3);
+
+        let filtered = access(&["mem"], &[]).filter_batch("cpu", &batch).unwrap();
+        assert_eq!(filtered.num_rows(), 0);
+    }
+
+    #[test]
+    fn mixed_scopes() {
+        // a scope restricting the tag values doesn't lift the restriction on the measurements
+        // of another scope, or the other way round
+        let combined = access(&[], &[("tenant", "acme")]).union(access(&["cpu"], &[]));
+        assert!(!combined.is_unrestricted());
+        let lp = "cpu,tenant=globex usage=1 10\n\
+                  mem,tenant=globex free=1 10\n\
+                  mem,tenant=acme free=1 10";
+        assert_eq!(allowed_lines(&combined, lp), vec![true, false, true]);
+
+        let predicate = combined.restrict(Predicate::default());
+        assert!(predicate.table_names.is_none());
+        assert!(predicate.exprs.is_empty());
+        assert_eq!(
+            format!("{:?}", predicate.table_exprs["cpu"]),
+            format!("{:?}", bool_expr(true))
+        );
+        assert_eq!(
+            format!("{:?}", predicate.other_tables_expr.unwrap()),
+            format!("{:?}", tag_expr("tenant", "acme"))
+        );
+
+        // each measurement only has the tag values of the scope that allows it
+        let combined = access(&["cpu"], &[("tenant", "acme")])
+            .union(access(&["mem"], &[("tenant", "globex")]));
+        let lp = "cpu,tenant=acme usage=1 10\n\
+                  cpu,tenant=globex usage=1 10\n\
+                  mem,tenant=globex free=1 10\n\
+                  mem,tenant=acme free=1 10";
+        assert_eq!(allowed_lines(&combined, lp), vec![true, false, true, false]);
+        let globex = vec![("tenant".to_string(), "globex".to_string())]
+            .into_iter()
+            .collect();
+        assert!(!combined.allows_point("cpu", &globex));
+        assert!(combined.allows_point("mem", &globex));
+
+        let predicate = combined.restrict(Predicate::default());
+        assert_eq!(
+            predicate.table_names,
+            Some(
+                vec!["cpu".to_string(), "mem".to_string()]
+                    .into_iter()
+                    .collect()
+            )
+        );
+        assert_eq!(
+            format!("{:?}", predicate.table_exprs["cpu"]),
+            format!("{:?}", tag_expr("tenant", "acme"))
+        );
+        assert_eq!(
+            format!("{:?}", predicate.table_exprs["mem"]),
+            format!("{:?}", tag_expr("tenant", "globex"))
+        );
+
+        let schema = Schema::new(vec![Field::new("tenant", DataType::Utf8, true)]);
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            vec![Arc::new(StringArray::from(vec![
+                Some("acme"),
+                Some("globex"),
+                None,
+            ]))],
+        )
+        .unwrap();
+        let filtered = combined.filter_batch("cpu", &batch).unwrap();
+        let tenants = filtered
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(tenants.len(), 1);
+        assert_eq!(tenants.value(0), "acme");
+        let filtered = combined.filter_batch("mem", &batch).unwrap();
+        assert_eq!(filtered.num_rows(), 1);
+        let filtered = combined.filter_batch("disk", &batch).unwrap();
+        assert_eq!(filtered.num_rows(), 0);
+    }
+}
@@ -0,0 +1,232 @@
+//! This module contains the InfluxQL transformation functions that compute each value of a
+//! series from the values before it, exposed to SQL as `derivative`, `non_negative_derivative`,
+//! `difference`, `moving_average` and `cumulative_sum`, which make counters such as the bytes
+//! sent by a network interface useful as rates.
+//!
+//! The functions are computed over the rows in the order they are read, before any `ORDER BY`,
+//! which must be the time order of a single series, such as with
+//! `SELECT time, non_negative_derivative(bytes, time, '1s') FROM net WHERE interface = 'eth0'`
+//! for points written in time order. Null values are skipped: their rows are null, and the
+//! next values are computed from the last value that isn't.
+
+use std::sync::Arc;
+
+use arrow_deps::{
+    arrow::{
+        array::{Array, ArrayRef, Float64Array, Int64Array, StringArray},
+        datatypes::DataType,
+    },
+    datafusion::{error::DataFusionError, logical_plan::create_udf, physical_plan::udf::ScalarUDF},
+};
+
+use crate::window::parse_duration;
+
+/// Returns all the transformation functions, to register with a query context
+pub fn udfs() -> Vec<ScalarUDF> {
+    vec![
+        derivative_udf("derivative", false),
+        derivative_udf("non_negative_derivative", true),
+        create_udf(
+            "difference",
+            vec![DataType::Float64],
+            Arc::new(DataType::Float64),
+            Arc::new(|args: &[ArrayRef]| Ok(to_array(differences(&floats(&args[0]))))),
+        ),
+        create_udf(
+            "moving_average",
+            vec![DataType::Float64, DataType::Int64],
+            Arc::new(DataType::Float64),
+            Arc::new(|args: &[ArrayRef]| {
+                let values = floats(&args[0]);
+                if values.is_empty() {
+                    return Ok(to_array(values));
+                }
+                let n = integers(&args[1])[0].filter(|&n| n > 0).ok_or_else(|| {
+                    DataFusionError::Execution(
+                        "moving_average needs a window of at least one value".to_string(),
+                    )
+                })?;
+                Ok(to_array(moving_averages(&values, n as usize)))
+            }),
+        ),
+        create_udf(
+            "cumulative_sum",
+            vec![DataType::Float64],
+            Arc::new(DataType::Float64),
+            Arc::new(|args: &[ArrayRef]| Ok(to_array(cumulative_sums(&floats(&args[0]))))),
+        ),
+    ]
+}
+
+/// Returns `derivative(value, time, unit)`, the rate of change of the value per `unit`, a
+/// duration such as `'1s'`, or `non_negative_derivative`, which is null where the value
+/// decreased, as when a counter is reset
+fn derivative_udf(name: &str, non_negative: bool) -> ScalarUDF {
+    create_udf(
+        name,
+        vec![DataType::Float64, DataType::Int64, DataType::Utf8],
+        Arc::new(DataType::Float64),
+        Arc::new(move |args: &[ArrayRef]| {
+            let values = floats(&args[0]);
+            if values.is_empty() {
+                return Ok(to_array(values));
+            }
+            let unit = args[2]
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .filter(|units| !units.is_null(0))
+                .map(|units| units.value(0))
+                .unwrap_or("1s");
+            let unit =
+                parse_duration(unit).map_err(|e| DataFusionError::Execution(e.to_string()))?;
+            Ok(to_array(derivatives(
+                &values,
+                &integers(&args[1]),
+                unit,
+                non_negative,
+            )))
+        }),
+    )
+}
+
+fn floats(array: &ArrayRef) -> Vec<Option<f64>> {
+    let array = array
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .expect("values are cast to floats");
+    (0..array.len())
+        .map(|i| Some(array.value(i)).filter(|_| !array.is_null(i)))
+        .collect()
+}
+
+fn integers(array: &ArrayRef) -> Vec<Option<i64>> {
+    let array = array
+        .as_any()
+        .downcast_ref::<Int64Array>()
+        .expect("times are cast to integers");
+    (0..array.len())
+        .map(|i| Some(array.value(i)).filter(|_| !array.is_null(i)))
+        .collect()
+}
+
+fn to_array(values: Vec<Option<f64>>) -> ArrayRef {
+    Arc::new(Float64Array::from(values))
+}
+
+fn derivatives(
+    values: &[Option<f64>],
+    times: &[Option<i64>],
+    unit: i64,
+    non_negative: bool,
+) -> Vec<Option<f64>> {
+    let mut previous: Option<(f64, i64)> = None;
+    values
+        .iter()
+        .zip(times)
+        .map(|(&value, &time)| {
+            let (value, time) = (value?, time?);
+            let derivative = previous.and_then(|(previous_value, previous_time)| {
+                let elapsed = time - previous_time;
+                if elapsed == 0 {
+                    return None;
+                }
+                Some((value - previous_value) * unit as f64 / elapsed as f64)
+            });
+            previous = Some((value, time));
+            derivative.filter(|&d| !non_negative || d >= 0.0)
+        })
+        .collect()
+}
+
+fn differences(values: &[Option<f64>]) -> Vec<Option<f64>> {
+    let mut previous = None;
+    values
+        .iter()
+        .map(|&value| {
+            let value = value?;
+            let difference = previous.map(|previous| value - previous);
+            previous = Some(value);
+            difference
+        })
+        .collect()
+}
+
+/// The average of each value and the `n - 1` values before it, or null for the first values
+fn moving_averages(values: &[Option<f64>], n: usize) -> Vec<Option<f64>> {
+    let mut window = std::collections::VecDeque::with_capacity(n);
+    let mut sum = 0.0;
+    values
+        .iter()
+        .map(|&value| {
+            let value = value?;
+            window.push_back(value);
+            sum += value;
+            if window.len() > n {
+                sum -= window.pop_front().expect("window is not empty");
+            }
+            Some(sum / n as f64).filter(|_| window.len() == n)
+        })
+        .collect()
+}
+
+fn cumulative_sums(values: &[Option<f64>]) -> Vec<Option<f64>> {
+    let mut sum = 0.0;
+    values
+        .iter()
+        .map(|&value| {
+            sum += value?;
+            Some(sum)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: i64 = 1_000_000_000;
+
+    #[test]
+    fn derivative() {
+        let values = [Some(10.0), Some(40.0), None, Some(20.0), Some(50.0)];
+        let times = [
+            Some(0),
+            Some(10 * SECOND),
+            Some(15 * SECOND),
+            Some(20 * SECOND),
+            Some(20 * SECOND),
+        ];
+
+        assert_eq!(
+            derivatives(&values, &times, SECOND, false),
+            vec![None, Some(3.0), None, Some(-2.0), None]
+        );
+        assert_eq!(
+            derivatives(&values, &times, 60 * SECOND, true),
+            vec![None, Some(180.0), None, None, None]
+        );
+    }
+
+    #[test]
+    fn difference_and_sums() {
+        let values = [Some(1.0), Some(4.0), None, Some(2.0)];
+        assert_eq!(
+            differences(&values),
+            vec![None, Some(3.0), None, Some(-2.0)]
+        );
+        assert_eq!(
+            cumulative_sums(&values),
+            vec![Some(1.0), Some(5.0), None, Some(7.0)]
+        );
+    }
+
+    #[test]
+    fn moving_average() {
+        let values = [Some(1.0), Some(3.0), None, Some(5.0), Some(10.0)];
+        assert_eq!(
+            moving_averages(&values, 2),
+            vec![None, Some(2.0), None, Some(4.0), Some(7.5)]
+        );
+        assert_eq!(moving_averages(&values, 1), values.to_vec());
+    }
+}
@@ -0,0 +1,184 @@
+//! This module contains the listing of the values of a tag that start with a prefix, for the
+//! autocompletion of tag values in UIs. Unlike `Database::column_values`, a listing is capped
+//! to a number of values, so that a short or empty prefix doesn't list every value of a tag
+//! with many of them, and databases can find the values by scanning their dictionaries
+//! instead of planning a query.
+
+use std::collections::BTreeSet;
+
+use crate::{
+    access::RowAccess, exec::Executor, predicate::PredicateBuilder, predicate::TimestampRange,
+    Database,
+};
+
+/// The number of values listed when a request doesn't ask for a number
+pub const DEFAULT_LIMIT: usize = 100;
+
+/// The largest number of values listed, whatever a request asks for
+pub const MAX_LIMIT: usize = 1000;
+
+/// The values of a tag to list
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagValuesQuery {
+    pub tag_name: String,
+    /// Only the values starting with `prefix` are listed
+    pub prefix: String,
+    /// Only the values of this table are listed, if set
+    pub table_name: Option<String>,
+    /// Only the values of the rows in this range are listed, if set
+    pub range: Option<TimestampRange>,
+    /// The largest number of values listed
+    pub limit: usize,
+}
+
+impl TagValuesQuery {
+    /// Lists up to `DEFAULT_LIMIT` of the values of `tag_name` starting with `prefix`, in all
+    /// tables and times
+    pub fn new(tag_name: impl Into<String>, prefix: impl Into<String>) -> Self {
+        Self {
+            tag_name: tag_name.into(),
+            prefix: prefix.into(),
+            table_name: None,
+            range: None,
+            limit: DEFAULT_LIMIT,
+        }
+    }
+
+    pub fn table_option(mut self, table_name: Option<String>) -> Self {
+        self.table_name = table_name;
+        self
+    }
+
+    pub fn range_option(mut self, range: Option<TimestampRange>) -> Self {
+        self.range = range;
+        self
+    }
+
+    /// Lists up to `limit` values, `DEFAULT_LIMIT` if 0, and at most `MAX_LIMIT`
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = match limit {
+            0 => DEFAULT_LIMIT,
+            limit => limit.min(MAX_LIMIT),
+        };
+        self
+    }
+
+    /// Returns a collector of the values this query lists
+    pub fn collector(&self) -> TagValuesCollector<'_> {
+        TagValuesCollector {
+            query: self,
+            values: BTreeSet::new(),
+        }
+    }
+}
+
+/// The values of a tag listed for a `TagValuesQuery`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagValues {
+    /// The first values starting with the prefix, in sorted order
+    pub values: Vec<String>,
+    /// Whether more values than the listed ones start with the prefix
+    pub truncated: bool,
+}
+
+/// Collects the first values of a `TagValuesQuery`, in sorted order, from values found in any
+/// order. Only one more value than the limit is kept, to tell whether the listing is
+/// truncated.
+#[derive(Debug)]
+pub struct TagValuesCollector<'a> {
+    query: &'a TagValuesQuery,
+    values: BTreeSet<String>,
+}
+
+impl<'a> TagValuesCollector<'a> {
+    /// Collects `value`, if it starts with the prefix of the query
+    pub fn insert(&mut self, value: &str) {
+        if !value.starts_with(&self.query.prefix) || self.values.contains(value) {
+            return;
+        }
+
+        if self.values.len() > self.query.limit {
+            let last = match self.values.iter().next_back() {
+                Some(last) if last.as_str() > value => last.clone(),
+                _ => return,
+            };
+            self.values.remove(&last);
+        }
+        self.values.insert(value.to_string());
+    }
+
+    pub fn finish(self) -> TagValues {
+        let truncated = self.values.len() > self.query.limit;
+        let values = self.values.into_iter().take(self.query.limit).collect();
+        TagValues { values, truncated }
+    }
+}
+
+/// Lists the values of `query` in `db`, in the rows `access` allows. Databases list the values
+/// of unrestricted requests on their own. The values of restricted requests are filtered out
+/// of the values the rows they may read have, with a plan run by `executor`.
+pub async fn tag_values<D: Database>(
+    db: &D,
+    executor: &Executor,
+    query: &TagValuesQuery,
+    access: &RowAccess,
+) -> Result<TagValues, Box<dyn std::error::Error + Send + Sync>> {
+    if access.is_unrestricted() {
+        return Ok(db.tag_values_with_prefix(query).await?);
+    }
+
+    let predicate = PredicateBuilder::default()
+        .table_option(query.table_name.clone())
+        .timestamp_range_option(query.range)
+        .build();
+    let plan = db
+        .column_values(&query.tag_name, access.restrict(predicate))
+        .await?;
+    let values = executor.to_string_set(plan).await?;
+
+    let mut collector = query.collector();
+    for value in values.iter() {
+        collector.insert(value);
+    }
+    Ok(collector.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_the_first_values() {
+        let query = TagValuesQuery::new("host", "web").limit(2);
+        let mut collector = query.collector();
+        for value in &["web3", "db1", "web2", "web3", "web10", "web1"] {
+            collector.insert(value);
+        }
+        let values = collector.finish();
+        assert_eq!(values.values, vec!["web1", "web10"]);
+        assert!(values.truncated);
+
+        let query = TagValuesQuery::new("host", "web").limit(3);
+        let mut collector = query.collector();
+        for value in &["web2", "web1", "web3", "db1"] {
+            collector.insert(value);
+        }
+        let values = collector.finish();
+        assert_eq!(values.values, vec!["web1", "web2", "web3"]);
+        assert!(!values.truncated);
+    }
+
+    #[test]
+    fn limits_are_capped() {
+        assert_eq!(TagValuesQuery::new("host", "").limit, DEFAULT_LIMIT);
+        assert_eq!(
+            TagValuesQuery::new("host", "").limit(0).limit,
+            DEFAULT_LIMIT
+        );
+        assert_eq!(TagValuesQuery::new("host", "").limit(10).limit, 10);
+        assert_eq!(
+            TagValuesQuery::new("host", "").limit(1_000_000).limit,
+            MAX_LIMIT
+        );
+    }
+}
@@ -4,11 +4,12 @@
 mod counters;
 pub mod fieldlist;
 mod planning;
+pub mod pool;
 mod schema_pivot;
 pub mod seriesset;
 pub mod stringset;
 
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
 use arrow_deps::{
     arrow::record_batch::RecordBatch,
@@ -21,8 +22,8 @@ use schema_pivot::SchemaPivotNode;
 
 use fieldlist::{FieldList, IntoFieldList};
 use seriesset::{
-    Error as SeriesSetError, GroupedSeriesSetConverter, GroupedSeriesSetItem, SeriesSet,
-    SeriesSetConverter,
+    Error as SeriesSetError, GroupDescription, GroupedSeriesSetConverter, GroupedSeriesSetItem,
+    SeriesSet, SeriesSetConverter,
 };
 use stringset::{IntoStringSet, StringSet, StringSetRef};
 use tokio::sync::mpsc::{self, error::SendError};
@@ -83,6 +84,11 @@ pub enum Error {
         source: Box<SendError<Result<SeriesSet, SeriesSetError>>>,
     },
 
+    #[snafu(display("Sending grouped series set results during conversion: {:?}", source))]
+    SendingDuringGroupedConversion {
+        source: Box<SendError<Result<GroupedSeriesSetItem, SeriesSetError>>>,
+    },
+
     #[snafu(display("Joining execution task: {}", source))]
     JoinError { source: tokio::task::JoinError },
 }
@@ -248,7 +254,10 @@ impl Executor {
     /// Executes the embedded plans, each as separate tasks, sending
     /// the resulting `SeriesSet`s one by one to the `tx` chanel.
     ///
-    /// The SeriesSets are guaranteed to come back ordered by table_name
+    /// The SeriesSets are guaranteed to come back ordered by series key
+    /// (table_name, then tag values), which is the order Flux expects,
+    /// even when several plans (e.g. of different chunks) produce series
+    /// of the same table
     ///
     /// Note that the returned future resolves (e.g. "returns") once
     /// all plans have been sent to `tx`. This means that the future
@@ -267,9 +276,9 @@ impl Executor {
         }
 
         // sort by table name and send the results to separate
-        // channels
+        // channels, grouped by table
         plans.sort_by(|a, b| a.table_name.cmp(&b.table_name));
-        let mut rx_channels = Vec::new(); // sorted by table names
+        let mut rx_channels: Vec<(Arc<String>, Vec<_>)> = Vec::new(); // sorted by table names
 
         // Run the plans in parallel
         let handles = plans
@@ -278,9 +287,14 @@ impl Executor {
                 // Clone Arc's for transmission to threads
                 let counters = self.counters.clone();
                 let (plan_tx, plan_rx) = mpsc::channel(1);
-                rx_channels.push(plan_rx);
+                match rx_channels.last_mut() {
+                    Some((table_name, table_rx)) if *table_name == plan.table_name => {
+                        table_rx.push(plan_rx)
+                    }
+                    _ => rx_channels.push((plan.table_name.clone(), vec![plan_rx])),
+                }
 
-                tokio::task::spawn(async move {
+                pool::spawn(async move {
                     let SeriesSetPlan {
                         table_name,
                         plan,
@@ -291,7 +305,6 @@ impl Executor {
                     let tag_columns = Arc::new(tag_columns);
                     let field_columns = Arc::new(field_columns);
 
-                    // TODO run these on some executor other than the main tokio pool (maybe?)
                     let ctx = IOxExecutionContext::new(counters);
                     let physical_plan = ctx
                         .make_plan(&plan)
@@ -313,14 +326,22 @@ impl Executor {
             })
             .collect::<Vec<_>>();
 
-        // transfer data from the rx steams in order
-        for mut rx in rx_channels {
-            while let Some(r) = rx.recv().await {
-                tx.send(r)
-                    .await
-                    .map_err(|e| Error::SendingDuringConversion {
-                        source: Box::new(e),
-                    })?
+        // transfer data from the rx steams in order, sorting the series
+        // of each table, which each plan only sorts by its own tag columns
+        for (_, table_rx) in rx_channels {
+            let mut series_sets = Vec::new();
+            for mut rx in table_rx {
+                while let Some(r) = rx.recv().await {
+                    match r {
+                        Ok(series_set) => series_sets.push(series_set),
+                        Err(e) => send_series_set(&mut tx, Err(e)).await?,
+                    }
+                }
+            }
+
+            series_sets.sort_by(|a, b| a.cmp_series_key(b));
+            for series_set in series_sets {
+                send_series_set(&mut tx, Ok(series_set)).await?
             }
         }
 
@@ -335,6 +356,11 @@ impl Executor {
     /// Executes the the Grouped plans, sending the
     /// results one by one to the `tx` chanel.
     ///
+    /// The groups are guaranteed to come back ordered by their tag
+    /// values, each with a single GroupStart followed by its series
+    /// ordered by series key, even when several plans (e.g. of different
+    /// tables or chunks) produce series of the same group
+    ///
     /// Note that the returned future resolves (e.g. "returns") once
     /// all plans have been sent to `tx`. This means that the future
     /// will not resolve if there is nothing hooked up receiving
@@ -343,9 +369,10 @@ impl Executor {
     pub async fn to_grouped_series_set(
         &self,
         grouped_series_set_plans: GroupedSeriesSetPlans,
-        tx: mpsc::Sender<Result<GroupedSeriesSetItem, SeriesSetError>>,
+        mut tx: mpsc::Sender<Result<GroupedSeriesSetItem, SeriesSetError>>,
     ) -> Result<()> {
         let GroupedSeriesSetPlans { grouped_plans } = grouped_series_set_plans;
+        let mut rx_channels = Vec::new();
 
         // Run the plans in parallel
         let handles = grouped_plans
@@ -353,8 +380,10 @@ impl Executor {
             .map(|plan| {
                 // Clone Arc's for transmission to threads
                 let counters = self.counters.clone();
-                let tx = tx.clone();
-                tokio::task::spawn(async move {
+                let (tx, rx) = mpsc::channel(1);
+                rx_channels.push(rx);
+
+                pool::spawn(async move {
                     let GroupedSeriesSetPlan {
                         series_set_plan,
                         num_prefix_tag_group_columns,
@@ -370,7 +399,6 @@ impl Executor {
                     let tag_columns = Arc::new(tag_columns);
                     let field_columns = Arc::new(field_columns);
 
-                    // TODO run these on some executor other than the main tokio pool (maybe?)
                     let ctx = IOxExecutionContext::new(counters);
                     let physical_plan = ctx
                         .make_plan(&plan)
@@ -398,6 +426,34 @@ impl Executor {
             })
             .collect::<Vec<_>>();
 
+        // collect the series of each group from all the plans, and send
+        // the groups in order
+        let mut groups = BTreeMap::new();
+        for mut rx in rx_channels {
+            let mut group_tags = None;
+            while let Some(r) = rx.recv().await {
+                match r {
+                    Ok(GroupedSeriesSetItem::GroupStart(group)) => group_tags = Some(group.tags),
+                    Ok(GroupedSeriesSetItem::GroupData(series_set)) => groups
+                        .entry(group_tags.clone().expect("GroupStart before GroupData"))
+                        .or_insert_with(Vec::new)
+                        .push(series_set),
+                    Err(e) => send_grouped_series_set_item(&mut tx, Err(e)).await?,
+                }
+            }
+        }
+
+        for (tags, mut series_sets) in groups {
+            let group_start = GroupedSeriesSetItem::GroupStart(GroupDescription { tags });
+            send_grouped_series_set_item(&mut tx, Ok(group_start)).await?;
+
+            series_sets.sort_by(|a, b| a.cmp_series_key(b));
+            for series_set in series_sets {
+                let group_data = GroupedSeriesSetItem::GroupData(series_set);
+                send_grouped_series_set_item(&mut tx, Ok(group_data)).await?
+            }
+        }
+
         // now, wait for all the values to resolve and reprot any errors
         for join_handle in handles.into_iter() {
             join_handle.await.context(JoinError)??;
@@ -416,7 +472,7 @@ impl Executor {
                     .map(|plan| {
                         let counters = self.counters.clone();
 
-                        tokio::task::spawn(async move {
+                        pool::spawn(async move {
                             let ctx = IOxExecutionContext::new(counters);
                             let physical_plan = ctx
                                 .make_plan(&plan)
@@ -455,6 +511,28 @@ impl Executor {
         run_logical_plans(counters, vec![plan]).await
     }
 }
+async fn send_series_set(
+    tx: &mut mpsc::Sender<Result<SeriesSet, SeriesSetError>>,
+    series_set: Result<SeriesSet, SeriesSetError>,
+) -> Result<()> {
+    tx.send(series_set)
+        .await
+        .map_err(|e| Error::SendingDuringConversion {
+            source: Box::new(e),
+        })
+}
+
+async fn send_grouped_series_set_item(
+    tx: &mut mpsc::Sender<Result<GroupedSeriesSetItem, SeriesSetError>>,
+    item: Result<GroupedSeriesSetItem, SeriesSetError>,
+) -> Result<()> {
+    tx.send(item)
+        .await
+        .map_err(|e| Error::SendingDuringGroupedConversion {
+            source: Box::new(e),
+        })
+}
+
 /// Create a SchemaPivot node which  an arbitrary input like
 ///  ColA | ColB | ColC
 /// ------+------+------
@@ -485,8 +563,7 @@ async fn run_logical_plans(
         .into_iter()
         .map(|plan| {
             let counters = counters.clone();
-            // TODO run these on some executor other than the main tokio pool
-            tokio::task::spawn(async move {
+            pool::spawn(async move {
                 let ctx = IOxExecutionContext::new(counters);
                 let physical_plan = ctx.make_plan(&plan).await.expect("making logical plan");
 
@@ -510,6 +587,7 @@ async fn run_logical_plans(
 #[cfg(test)]
 mod tests {
     use arrow_deps::arrow::{
+        array::ArrayRef,
         array::Int64Array,
         array::StringArray,
         array::StringBuilder,
@@ -721,6 +799,70 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn executor_series_set_plans_ordered_by_series_key() -> Result<()> {
+        // two plans (e.g. chunks) of the same table, each only ordered by itself
+        let plans = SeriesSetPlans::from(vec![
+            make_series_set_plan("t", &["b", "b"], &[1, 2]),
+            make_series_set_plan("t", &["a", "c"], &[3, 4]),
+        ]);
+
+        let (tx, mut rx) = mpsc::channel(10);
+        Executor::new().to_series_set(plans, tx).await?;
+
+        let mut series = vec![];
+        while let Some(series_set) = rx.recv().await {
+            let series_set = series_set.expect("series set");
+            series.push(format!(
+                "{} {}={}",
+                series_set.table_name, series_set.tags[0].0, series_set.tags[0].1
+            ));
+        }
+        assert_eq!(series, vec!["t tag=a", "t tag=b", "t tag=c"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn executor_grouped_series_set_plans_ordered_by_group() -> Result<()> {
+        // plans of two tables, both with series in group "a"
+        let plans = GroupedSeriesSetPlans::from(vec![
+            GroupedSeriesSetPlan {
+                series_set_plan: make_series_set_plan("t2", &["a"], &[1]),
+                num_prefix_tag_group_columns: 1,
+            },
+            GroupedSeriesSetPlan {
+                series_set_plan: make_series_set_plan("t1", &["a", "b"], &[2, 3]),
+                num_prefix_tag_group_columns: 1,
+            },
+        ]);
+
+        let (tx, mut rx) = mpsc::channel(10);
+        Executor::new().to_grouped_series_set(plans, tx).await?;
+
+        let mut items = vec![];
+        while let Some(item) = rx.recv().await {
+            items.push(match item.expect("grouped series set item") {
+                GroupedSeriesSetItem::GroupStart(group) => format!("group {}", group.tags[0].1),
+                GroupedSeriesSetItem::GroupData(series_set) => {
+                    format!("series {} {}", series_set.table_name, series_set.tags[0].1)
+                }
+            });
+        }
+        assert_eq!(
+            items,
+            vec![
+                "group a",
+                "series t1 a",
+                "series t2 a",
+                "group b",
+                "series t1 b"
+            ]
+        );
+
+        Ok(())
+    }
+
     /// return a set for testing
     fn to_set(strs: &[&str]) -> StringSetRef {
         StringSetRef::new(strs.iter().map(|s| s.to_string()).collect::<StringSet>())
@@ -734,6 +876,29 @@ mod tests {
         Arc::new(builder.finish())
     }
 
+    // creates a plan for a table with a single tag, and a row per tag value
+    fn make_series_set_plan(table_name: &str, tags: &[&str], times: &[i64]) -> SeriesSetPlan {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("tag", DataType::Utf8, true),
+            Field::new("time", DataType::Int64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                to_string_array(tags) as ArrayRef,
+                Arc::new(Int64Array::from(times.to_vec())),
+            ],
+        )
+        .expect("created record batch");
+
+        SeriesSetPlan {
+            table_name: Arc::new(table_name.into()),
+            plan: make_plan(schema, vec![batch]),
+            tag_columns: vec![Arc::new("tag".into())],
+            field_columns: vec![],
+        }
+    }
+
     // creates a DataFusion plan that reads the RecordBatches into memory
     fn make_plan(schema: SchemaRef, data: Vec<RecordBatch>) -> LogicalPlan {
         let projected_schema = schema.clone();
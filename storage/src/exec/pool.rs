@@ -0,0 +1,185 @@
+//! A dedicated pool of threads for executing queries.
+//!
+//! Executing a query plan is CPU heavy, and a large query run on the main
+//! Tokio runtime can keep its worker threads busy for long enough to delay
+//! the handling of writes. Once `init` has been called, the tasks spawned with
+//! `spawn` run on a separate multi-threaded runtime instead, so queries only
+//! compete with each other for its threads. Until then, tasks are spawned on
+//! the current runtime, which is what tests rely on.
+use std::{future::Future, sync::Arc, time::Instant};
+
+use metrics::{Gauge, Histogram, DURATION_BUCKETS};
+use once_cell::sync::OnceCell;
+use tokio::{
+    runtime::{Builder, Handle, Runtime},
+    task::JoinHandle,
+};
+
+static QUERY_POOL: OnceCell<QueryPool> = OnceCell::new();
+
+/// Creates the pool that executes queries from now on, with `threads` threads
+/// or one per CPU core. Returns an error if the threads can't be started.
+/// Calling it again has no effect.
+pub fn init(threads: Option<usize>) -> std::io::Result<()> {
+    QUERY_POOL
+        .get_or_try_init(|| QueryPool::new("query", threads.unwrap_or_else(num_cpus::get)))?;
+    Ok(())
+}
+
+/// Spawns the task `future` on the query pool, or on the current runtime if
+/// the query pool has not been created.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match QUERY_POOL.get() {
+        Some(pool) => pool.spawn(future),
+        None => tokio::task::spawn(future),
+    }
+}
+
+/// A multi-threaded runtime whose threads only run the tasks spawned on it,
+/// with metrics of how busy it is.
+#[derive(Debug)]
+pub struct QueryPool {
+    runtime: Runtime,
+    metrics: Arc<PoolMetrics>,
+}
+
+#[derive(Debug)]
+struct PoolMetrics {
+    queued: Arc<Gauge>,
+    active: Arc<Gauge>,
+    task_duration: Arc<Histogram>,
+}
+
+impl QueryPool {
+    /// Starts a pool of `threads` threads, whose metrics are labelled with
+    /// `name`.
+    pub fn new(name: &str, threads: usize) -> std::io::Result<Self> {
+        let threads = threads.max(1);
+        let runtime = Builder::new()
+            .threaded_scheduler()
+            .core_threads(threads)
+            .thread_name(format!("{}-pool", name))
+            .enable_all()
+            .build()?;
+
+        let registry = metrics::registry();
+        let labels = &[("pool", name)];
+        registry
+            .gauge("query_pool_threads", "Threads of the pool", labels)
+            .set(threads as i64);
+        let metrics = Arc::new(PoolMetrics {
+            queued: registry.gauge(
+                "query_pool_queued_tasks",
+                "Tasks spawned on the pool that have not started yet",
+                labels,
+            ),
+            active: registry.gauge(
+                "query_pool_active_tasks",
+                "Tasks running on the pool",
+                labels,
+            ),
+            task_duration: registry.histogram(
+                "query_pool_task_duration_seconds",
+                "Time from the start to the end of the tasks run on the pool",
+                DURATION_BUCKETS,
+                labels,
+            ),
+        });
+
+        Ok(Self { runtime, metrics })
+    }
+
+    /// Returns a handle to spawn tasks on the pool with
+    pub fn handle(&self) -> &Handle {
+        self.runtime.handle()
+    }
+
+    /// Spawns the task `future` on the threads of the pool. The returned
+    /// handle can be awaited from any runtime.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let metrics = Arc::clone(&self.metrics);
+        metrics.queued.add(1);
+
+        self.handle().spawn(async move {
+            metrics.queued.add(-1);
+            let _running = Running::new(&metrics);
+            future.await
+        })
+    }
+}
+
+/// Counts a task as active from its start until it completes or is dropped
+#[derive(Debug)]
+struct Running<'a> {
+    metrics: &'a PoolMetrics,
+    start: Instant,
+}
+
+impl<'a> Running<'a> {
+    fn new(metrics: &'a PoolMetrics) -> Self {
+        metrics.active.add(1);
+        Self {
+            metrics,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl<'a> Drop for Running<'a> {
+    fn drop(&mut self) {
+        self.metrics.active.add(-1);
+        self.metrics
+            .task_duration
+            .observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_tasks_on_pool_threads() {
+        let pool = QueryPool::new("test", 2).unwrap();
+
+        let thread_name = pool
+            .spawn(async { std::thread::current().name().map(str::to_string) })
+            .await
+            .unwrap();
+        assert_eq!(thread_name.as_deref(), Some("test-pool"));
+
+        let registry = metrics::registry();
+        let labels = &[("pool", "test")];
+        assert_eq!(registry.gauge("query_pool_threads", "", labels).get(), 2);
+        assert_eq!(
+            registry.gauge("query_pool_active_tasks", "", labels).get(),
+            0
+        );
+        assert_eq!(
+            registry.gauge("query_pool_queued_tasks", "", labels).get(),
+            0
+        );
+        assert_eq!(
+            registry
+                .histogram(
+                    "query_pool_task_duration_seconds",
+                    "",
+                    DURATION_BUCKETS,
+                    labels
+                )
+                .count(),
+            1
+        );
+
+        // the pool's runtime must not be dropped from within the test's runtime
+        std::thread::spawn(move || drop(pool)).join().unwrap();
+    }
+}
@@ -17,7 +17,7 @@
 //! the columns would be ordered `host`, `region`, and `service` as
 //! well.
 
-use std::sync::Arc;
+use std::{cmp::Ordering, sync::Arc};
 
 use arrow::{
     array::StringArray, datatypes::DataType, datatypes::SchemaRef, record_batch::RecordBatch,
@@ -99,6 +99,15 @@ pub struct SeriesSet {
     pub batch: RecordBatch,
 }
 
+impl SeriesSet {
+    /// Compares the series keys of this set and `other`: the table name, then the tag values.
+    /// This is the order in which Flux expects the series, including across the plans of
+    /// several chunks.
+    pub fn cmp_series_key(&self, other: &Self) -> Ordering {
+        (&self.table_name, &self.tags).cmp(&(&other.table_name, &other.tags))
+    }
+}
+
 /// Describes a group of series "group of series" series. Namely,
 /// several logical timeseries that share the same timestamps and
 /// name=value tag keys, grouped by some subset of the tag keys
@@ -0,0 +1,584 @@
+//! This module contains gap filling, which adds a row for each window missing from the
+//! results of a window aggregate so that downsampled series are continuous.
+//!
+//! The values of the added rows are filled as with InfluxQL's `fill()`: with nulls, the
+//! previous value of the series, a linear interpolation between the values around the gap, or
+//! a constant.
+
+use std::{cmp::Ordering, str::FromStr, sync::Arc};
+
+use arrow_deps::arrow::{
+    array::{
+        Array, ArrayRef, BooleanArray, BooleanBuilder, Float64Array, Float64Builder, Int64Array,
+        Int64Builder, PrimitiveBuilder, StringArray, StringBuilder, UInt64Array, UInt64Builder,
+    },
+    datatypes::{ArrowPrimitiveType, DataType},
+    error::ArrowError,
+    record_batch::RecordBatch,
+};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+use crate::window::Window;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Invalid fill '{}': expected null, previous, linear, none or a number",
+        fill
+    ))]
+    InvalidFill { fill: String },
+
+    #[snafu(display("Can't fill gaps without an integer time column named '{}'", name))]
+    MissingTimeColumn { name: String },
+
+    #[snafu(display("Can't fill gaps in column '{}' of type {:?}", column, data_type))]
+    UnsupportedType { column: String, data_type: DataType },
+
+    #[snafu(display("Filling the gaps would add more than {} rows", MAX_FILLED_ROWS))]
+    TooManyRows,
+
+    #[snafu(display("Error building the filled batch: {}", source))]
+    BuildingBatch { source: ArrowError },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The most rows gap filling adds, so that a small window over a wide range fails rather
+/// than exhausting memory
+pub const MAX_FILLED_ROWS: usize = 1_000_000;
+
+/// How the values of the missing windows are filled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fill {
+    /// With nulls
+    Null,
+    /// With the values of the previous window of the series
+    Previous,
+    /// With a linear interpolation between the windows of the series around the gap. Only
+    /// numeric columns are interpolated, integers being truncated, and the others are null.
+    Linear,
+    /// With a constant. Only numeric columns are set, and the others are null.
+    Value(f64),
+}
+
+impl FromStr for Fill {
+    type Err = Error;
+
+    /// Parses the argument of InfluxQL's `fill()`, except `none`, which disables gap filling
+    fn from_str(fill: &str) -> Result<Self> {
+        match fill.trim().to_ascii_lowercase().as_str() {
+            "null" => Ok(Self::Null),
+            "previous" => Ok(Self::Previous),
+            "linear" => Ok(Self::Linear),
+            value => value
+                .parse()
+                .ok()
+                .map(Self::Value)
+                .context(InvalidFill { fill }),
+        }
+    }
+}
+
+/// A row of the input batches, as the index of its batch and its index in the batch
+type RowRef = (usize, usize);
+
+/// A row of the filled batch
+#[derive(Debug)]
+enum OutputRow {
+    Input(RowRef),
+    Filled {
+        time: i64,
+        /// A row of the same series, to copy the group columns from
+        series: RowRef,
+        previous: Option<RowRef>,
+        next: Option<RowRef>,
+    },
+}
+
+/// A value of the filled batch
+#[derive(Debug, Clone, Copy)]
+enum Cell {
+    Row(RowRef),
+    Number(f64),
+    Null,
+}
+
+/// Adds a row for each window of `window` missing from the series of `batches`, the results
+/// of a window aggregate with the start of their windows in `time_column`.
+///
+/// The series are identified by the values of `group_columns`, which are copied to the added
+/// rows, and the other columns are filled as `fill` says. Every series is filled from the
+/// window of `range.0` to the window of `range.1` inclusive, or by default from the earliest
+/// to the latest window of all the series. The rows are returned ordered by series and time.
+pub fn fill_gaps(
+    batches: &[RecordBatch],
+    time_column: &str,
+    group_columns: &[String],
+    window: &Window,
+    range: Option<(i64, i64)>,
+    fill: Fill,
+) -> Result<Vec<RecordBatch>> {
+    let schema = match batches.first() {
+        Some(batch) => batch.schema(),
+        None => return Ok(vec![]),
+    };
+    let time_index = schema
+        .index_of(time_column)
+        .ok()
+        .filter(|&i| schema.field(i).data_type() == &DataType::Int64)
+        .context(MissingTimeColumn { name: time_column })?;
+    let group_indexes: Vec<_> = group_columns
+        .iter()
+        .filter_map(|name| schema.index_of(name).ok())
+        .collect();
+    for field in schema.fields() {
+        ensure!(
+            matches!(
+                field.data_type(),
+                DataType::Int64
+                    | DataType::UInt64
+                    | DataType::Float64
+                    | DataType::Utf8
+                    | DataType::Boolean
+            ),
+            UnsupportedType {
+                column: field.name(),
+                data_type: field.data_type().clone(),
+            }
+        );
+    }
+
+    let column = |i: usize, (b, _): RowRef| batches[b].column(i);
+    let row_time = |row: RowRef| int_value(column(time_index, row), row.1);
+    let series_key = |row: RowRef| {
+        group_indexes
+            .iter()
+            .map(|&i| string_value(column(i, row), row.1))
+            .collect::<Vec<_>>()
+    };
+
+    let mut rows: Vec<RowRef> = batches
+        .iter()
+        .enumerate()
+        .flat_map(|(b, batch)| (0..batch.num_rows()).map(move |r| (b, r)))
+        .collect();
+    rows.sort_by(|&a, &b| match series_key(a).cmp(&series_key(b)) {
+        Ordering::Equal => row_time(a).cmp(&row_time(b)),
+        ordering => ordering,
+    });
+
+    let (first, last) = match range {
+        Some(range) => range,
+        None => {
+            let mut times = rows.iter().filter_map(|&row| row_time(row));
+            let first = match times.next() {
+                Some(first) => first,
+                None => return Ok(batches.to_vec()),
+            };
+            times.fold((first, first), |(min, max), t| (min.min(t), max.max(t)))
+        }
+    };
+    let (first, last) = (window.start(first), window.start(last));
+    // the start of the next window, unless the end of time was reached
+    let advance = |start: i64| Some(window.next(start)).filter(|&next| next > start);
+
+    let mut output = Vec::with_capacity(rows.len());
+    let mut filled = 0;
+    let mut start = 0;
+    while start < rows.len() {
+        let key = series_key(rows[start]);
+        let end = rows[start..]
+            .iter()
+            .position(|&row| series_key(row) != key)
+            .map_or(rows.len(), |len| start + len);
+        let series = &rows[start..end];
+
+        let mut next = 0;
+        let mut previous = None;
+        let mut window_start = Some(first);
+        while let Some(window_start_time) = window_start.filter(|&start| start <= last) {
+            match series.get(next).map(|&row| (row, row_time(row))) {
+                // rows without a time are passed through
+                Some((row, None)) => {
+                    output.push(OutputRow::Input(row));
+                    next += 1;
+                }
+                Some((row, Some(t))) if t <= window_start_time => {
+                    output.push(OutputRow::Input(row));
+                    previous = Some(row);
+                    next += 1;
+                    if t == window_start_time {
+                        window_start = advance(window_start_time);
+                    }
+                }
+                row => {
+                    filled += 1;
+                    ensure!(filled <= MAX_FILLED_ROWS, TooManyRows);
+                    output.push(OutputRow::Filled {
+                        time: window_start_time,
+                        series: series[0],
+                        previous,
+                        next: row.map(|(row, _)| row),
+                    });
+                    window_start = advance(window_start_time);
+                }
+            }
+        }
+        output.extend(series[next..].iter().map(|&row| OutputRow::Input(row)));
+
+        start = end;
+    }
+
+    let columns = schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            // times are copied exactly rather than through a float
+            if i == time_index {
+                let mut builder = Int64Builder::new(output.len());
+                for row in &output {
+                    let value = match *row {
+                        OutputRow::Input(row) => row_time(row),
+                        OutputRow::Filled { time, .. } => Some(time),
+                    };
+                    append(&mut builder, value)?;
+                }
+                return Ok(Arc::new(builder.finish()) as ArrayRef);
+            }
+
+            let data_type = field.data_type();
+            let numeric = matches!(
+                data_type,
+                DataType::Int64 | DataType::UInt64 | DataType::Float64
+            );
+            let number = |row: RowRef| numeric_value(column(i, row), row.1);
+
+            let cells = output.iter().map(|row| match *row {
+                OutputRow::Input(row) => Cell::Row(row),
+                OutputRow::Filled { series, .. } if group_indexes.contains(&i) => Cell::Row(series),
+                OutputRow::Filled {
+                    time,
+                    previous,
+                    next,
+                    ..
+                } => match fill {
+                    Fill::Null => Cell::Null,
+                    Fill::Previous => previous.map_or(Cell::Null, Cell::Row),
+                    Fill::Value(value) if numeric => Cell::Number(value),
+                    Fill::Linear if numeric => {
+                        interpolate(previous, next, time, &row_time, &number)
+                            .map_or(Cell::Null, Cell::Number)
+                    }
+                    Fill::Value(_) | Fill::Linear => Cell::Null,
+                },
+            });
+
+            build_column(data_type, cells, |row| column(i, row))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let batch = RecordBatch::try_new(schema, columns).context(BuildingBatch)?;
+    Ok(vec![batch])
+}
+
+/// Interpolates the value at `time` between the values of the rows `previous` and `next`
+fn interpolate(
+    previous: Option<RowRef>,
+    next: Option<RowRef>,
+    time: i64,
+    row_time: &impl Fn(RowRef) -> Option<i64>,
+    number: &impl Fn(RowRef) -> Option<f64>,
+) -> Option<f64> {
+    let (previous, next) = (previous?, next?);
+    let (t0, t1) = (row_time(previous)? as f64, row_time(next)? as f64);
+    let (v0, v1) = (number(previous)?, number(next)?);
+    if t1 <= t0 {
+        return None;
+    }
+    Some(v0 + (v1 - v0) * (time as f64 - t0) / (t1 - t0))
+}
+
+/// Builds a column of `data_type` from `cells`, copying the values of rows from the arrays
+/// `column` returns for them
+fn build_column<'a>(
+    data_type: &DataType,
+    cells: impl Iterator<Item = Cell>,
+    column: impl Fn(RowRef) -> &'a ArrayRef,
+) -> Result<ArrayRef> {
+    let (capacity, _) = cells.size_hint();
+    let array: ArrayRef = match data_type {
+        DataType::Float64 => {
+            let mut builder = Float64Builder::new(capacity);
+            for cell in cells {
+                let value = match cell {
+                    Cell::Row(row) => numeric_value(column(row), row.1),
+                    Cell::Number(n) => Some(n),
+                    Cell::Null => None,
+                };
+                append(&mut builder, value)?;
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::Int64 => {
+            let mut builder = Int64Builder::new(capacity);
+            for cell in cells {
+                let value = match cell {
+                    Cell::Row(row) => int_value(column(row), row.1),
+                    Cell::Number(n) => Some(n as i64),
+                    Cell::Null => None,
+                };
+                append(&mut builder, value)?;
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::UInt64 => {
+            let mut builder = UInt64Builder::new(capacity);
+            for cell in cells {
+                let value = match cell {
+                    Cell::Row(row) => column(row)
+                        .as_any()
+                        .downcast_ref::<UInt64Array>()
+                        .filter(|a| !a.is_null(row.1))
+                        .map(|a| a.value(row.1)),
+                    Cell::Number(n) => Some(n.max(0.0) as u64),
+                    Cell::Null => None,
+                };
+                append(&mut builder, value)?;
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::Boolean => {
+            let mut builder = BooleanBuilder::new(capacity);
+            for cell in cells {
+                let value = match cell {
+                    Cell::Row(row) => column(row)
+                        .as_any()
+                        .downcast_ref::<BooleanArray>()
+                        .filter(|a| !a.is_null(row.1))
+                        .map(|a| a.value(row.1)),
+                    Cell::Number(_) | Cell::Null => None,
+                };
+                match value {
+                    Some(value) => builder.append_value(value),
+                    None => builder.append_null(),
+                }
+                .context(BuildingBatch)?;
+            }
+            Arc::new(builder.finish())
+        }
+        _ => {
+            let mut builder = StringBuilder::new(capacity);
+            for cell in cells {
+                match cell {
+                    Cell::Row(row) => match string_value(column(row), row.1) {
+                        Some(value) => builder.append_value(&value),
+                        None => builder.append_null(),
+                    },
+                    Cell::Number(_) | Cell::Null => builder.append_null(),
+                }
+                .context(BuildingBatch)?;
+            }
+            Arc::new(builder.finish())
+        }
+    };
+    Ok(array)
+}
+
+/// Appends `value`, or a null, to a primitive builder
+fn append<T: ArrowPrimitiveType>(
+    builder: &mut PrimitiveBuilder<T>,
+    value: Option<T::Native>,
+) -> Result<()> {
+    match value {
+        Some(value) => builder.append_value(value),
+        None => builder.append_null(),
+    }
+    .context(BuildingBatch)
+}
+
+fn int_value(column: &ArrayRef, row: usize) -> Option<i64> {
+    column
+        .as_any()
+        .downcast_ref::<Int64Array>()
+        .filter(|a| !a.is_null(row))
+        .map(|a| a.value(row))
+}
+
+/// Returns the value of `row` of a numeric column as a float
+fn numeric_value(column: &ArrayRef, row: usize) -> Option<f64> {
+    if column.is_null(row) {
+        return None;
+    }
+    let any = column.as_any();
+    match column.data_type() {
+        DataType::Float64 => any.downcast_ref::<Float64Array>().map(|a| a.value(row)),
+        DataType::Int64 => any
+            .downcast_ref::<Int64Array>()
+            .map(|a| a.value(row) as f64),
+        DataType::UInt64 => any
+            .downcast_ref::<UInt64Array>()
+            .map(|a| a.value(row) as f64),
+        _ => None,
+    }
+}
+
+/// Returns the value of `row` of a column as a string, to compare the groups of series
+fn string_value(column: &ArrayRef, row: usize) -> Option<String> {
+    if column.is_null(row) {
+        return None;
+    }
+    let any = column.as_any();
+    match column.data_type() {
+        DataType::Utf8 => any
+            .downcast_ref::<StringArray>()
+            .map(|a| a.value(row).to_string()),
+        DataType::Boolean => any
+            .downcast_ref::<BooleanArray>()
+            .map(|a| a.value(row).to_string()),
+        _ => numeric_value(column, row).map(|n| n.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_deps::arrow::datatypes::{Field, Schema};
+
+    const HOUR: i64 = 3_600_000_000_000;
+
+    fn batch(rows: &[(&str, i64, Option<f64>, Option<&str>)]) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("host", DataType::Utf8, true),
+            Field::new("time", DataType::Int64, false),
+            Field::new("mean", DataType::Float64, true),
+            Field::new("last", DataType::Utf8, true),
+        ]));
+        let hosts = StringArray::from(rows.iter().map(|r| r.0).collect::<Vec<_>>());
+        let times = Int64Array::from(rows.iter().map(|r| r.1).collect::<Vec<_>>());
+        let means = Float64Array::from(rows.iter().map(|r| r.2).collect::<Vec<_>>());
+        let lasts = StringArray::from(rows.iter().map(|r| r.3).collect::<Vec<_>>());
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(hosts),
+                Arc::new(times),
+                Arc::new(means),
+                Arc::new(lasts),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn rows(batches: &[RecordBatch]) -> Vec<String> {
+        let mut rows = vec![];
+        for batch in batches {
+            for row in 0..batch.num_rows() {
+                let values: Vec<_> = batch
+                    .columns()
+                    .iter()
+                    .map(|column| string_value(column, row).unwrap_or_else(|| "-".into()))
+                    .collect();
+                rows.push(values.join(","));
+            }
+        }
+        rows
+    }
+
+    fn fill(fill: Fill, range: Option<(i64, i64)>) -> Vec<String> {
+        let input = [
+            batch(&[
+                ("b", 2 * HOUR, Some(4.0), Some("x")),
+                ("a", 0, Some(1.0), Some("x")),
+            ]),
+            batch(&[("a", 3 * HOUR, Some(4.0), Some("y"))]),
+        ];
+        let window = Window::new(HOUR, 0, None);
+        let output =
+            fill_gaps(&input, "time", &["host".to_string()], &window, range, fill).unwrap();
+        rows(&output)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("NULL".parse::<Fill>().unwrap(), Fill::Null);
+        assert_eq!("previous".parse::<Fill>().unwrap(), Fill::Previous);
+        assert_eq!(" linear".parse::<Fill>().unwrap(), Fill::Linear);
+        assert_eq!("-1.5".parse::<Fill>().unwrap(), Fill::Value(-1.5));
+        assert!(matches!(
+            "none".parse::<Fill>(),
+            Err(Error::InvalidFill { .. })
+        ));
+    }
+
+    #[test]
+    fn fills() {
+        let h = |n: i64| (n * HOUR).to_string();
+
+        assert_eq!(
+            fill(Fill::Null, None),
+            vec![
+                format!("a,0,1,x"),
+                format!("a,{},-,-", h(1)),
+                format!("a,{},-,-", h(2)),
+                format!("a,{},4,y", h(3)),
+                format!("b,0,-,-"),
+                format!("b,{},-,-", h(1)),
+                format!("b,{},4,x", h(2)),
+                format!("b,{},-,-", h(3)),
+            ]
+        );
+
+        assert_eq!(
+            fill(Fill::Previous, None)[..4].to_vec(),
+            vec![
+                format!("a,0,1,x"),
+                format!("a,{},1,x", h(1)),
+                format!("a,{},1,x", h(2)),
+                format!("a,{},4,y", h(3)),
+            ]
+        );
+
+        // strings aren't interpolated, and nothing is extrapolated
+        assert_eq!(
+            fill(Fill::Linear, None),
+            vec![
+                format!("a,0,1,x"),
+                format!("a,{},2,-", h(1)),
+                format!("a,{},3,-", h(2)),
+                format!("a,{},4,y", h(3)),
+                format!("b,0,-,-"),
+                format!("b,{},-,-", h(1)),
+                format!("b,{},4,x", h(2)),
+                format!("b,{},-,-", h(3)),
+            ]
+        );
+
+        assert_eq!(
+            fill(Fill::Value(0.0), Some((HOUR / 2, 4 * HOUR)))[..5].to_vec(),
+            vec![
+                format!("a,0,1,x"),
+                format!("a,{},0,-", h(1)),
+                format!("a,{},0,-", h(2)),
+                format!("a,{},4,y", h(3)),
+                format!("a,{},0,-", h(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let window = Window::new(1, 0, None);
+        let input = [batch(&[("a", 0, None, None), ("a", i64::MAX, None, None)])];
+
+        let err = fill_gaps(&input, "timestamp", &[], &window, None, Fill::Null).unwrap_err();
+        assert!(matches!(err, Error::MissingTimeColumn { .. }));
+
+        let err = fill_gaps(&input, "time", &[], &window, None, Fill::Null).unwrap_err();
+        assert!(matches!(err, Error::TooManyRows));
+
+        assert!(fill_gaps(&[], "time", &[], &window, None, Fill::Null)
+            .unwrap()
+            .is_empty());
+    }
+}
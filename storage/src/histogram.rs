@@ -0,0 +1,64 @@
+//! This module contains the `histogram_quantile(q, buckets)` SQL function, which estimates
+//! the `q` quantile of the histogram stored in the string field `buckets` of each row, such as
+//! the 99th percentile latency of the requests of an SLO dashboard with
+//! `SELECT time, histogram_quantile(0.99, buckets) FROM http_request_duration_seconds`.
+//!
+//! See `data_types::histogram` for how histograms are stored. The quantile is null where the
+//! buckets can't be parsed, or the histogram has no observations.
+
+use std::sync::Arc;
+
+use arrow_deps::{
+    arrow::{
+        array::{Array, ArrayRef, Float64Array, StringArray},
+        datatypes::DataType,
+    },
+    datafusion::{logical_plan::create_udf, physical_plan::udf::ScalarUDF},
+};
+use data_types::histogram::Histogram;
+
+/// Returns the `histogram_quantile` function, to register with a query context
+pub fn histogram_quantile_udf() -> ScalarUDF {
+    create_udf(
+        "histogram_quantile",
+        vec![DataType::Float64, DataType::Utf8],
+        Arc::new(DataType::Float64),
+        Arc::new(|args: &[ArrayRef]| {
+            let quantiles = args[0]
+                .as_any()
+                .downcast_ref::<Float64Array>()
+                .expect("quantiles are cast to floats");
+            let buckets = args[1]
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .expect("buckets are cast to strings");
+
+            let values: Vec<_> = (0..buckets.len())
+                .map(|i| {
+                    if quantiles.is_null(i) || buckets.is_null(i) {
+                        return None;
+                    }
+                    quantile(quantiles.value(i), buckets.value(i))
+                })
+                .collect();
+            Ok(Arc::new(Float64Array::from(values)) as ArrayRef)
+        }),
+    )
+}
+
+fn quantile(q: f64, buckets: &str) -> Option<f64> {
+    buckets.parse::<Histogram>().ok()?.quantile(q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantiles() {
+        assert_eq!(quantile(0.5, "1:2,2:4,+Inf:4"), Some(1.0));
+        assert_eq!(quantile(0.75, "1:2,2:4,+Inf:4"), Some(1.5));
+        assert_eq!(quantile(0.5, "0.1:0,+Inf:0"), None);
+        assert_eq!(quantile(0.5, "garbage"), None);
+    }
+}
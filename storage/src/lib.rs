@@ -12,12 +12,21 @@ use data_types::data::ReplicatedWrite;
 use exec::{FieldListPlan, GroupedSeriesSetPlans, SeriesSetPlans, StringSetPlan};
 use influxdb_line_protocol::ParsedLine;
 
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, sync::Arc, time::Duration};
 
+pub mod access;
+pub mod analytic;
+pub mod autocomplete;
 pub mod exec;
+pub mod gapfill;
+pub mod histogram;
 pub mod id;
 pub mod predicate;
+pub mod quantile;
 pub mod util;
+pub mod validate;
+pub mod wasm;
+pub mod window;
 
 use self::predicate::{Predicate, TimestampRange};
 
@@ -41,12 +50,49 @@ pub trait Database: Debug + Send + Sync {
     /// writes parsed lines into this database
     async fn write_lines(&self, lines: &[ParsedLine<'_>]) -> Result<(), Self::Error>;
 
+    /// Checks `lines` against the columns of the database without writing them, returning the
+    /// problems found, numbered by the position of their line in `lines`
+    async fn validate_lines(
+        &self,
+        lines: &[ParsedLine<'_>],
+    ) -> Result<Vec<validate::LineDiagnostic>, Self::Error> {
+        Ok(validate::check_column_types(Default::default(), lines))
+    }
+
     /// Stores the replicated write in the write buffer and, if enabled, the write ahead log.
     async fn store_replicated_write(&self, write: &ReplicatedWrite) -> Result<(), Self::Error>;
 
+    /// Returns a version of the data of the database, which changes whenever data is written
+    /// or removed, so that results computed from the data can be cached until it changes.
+    /// `None` if the database doesn't track the changes of its data.
+    fn data_version(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns how long to wait before retrying a write that failed with `error`, if the
+    /// database rejected it because it can't keep up with writes for now
+    fn write_retry_after(&self, _error: &Self::Error) -> Option<Duration> {
+        None
+    }
+
+    /// Returns whether a write that failed with `error` was rejected because of what it
+    /// writes, such as points that break the schema of the database, so that retrying it
+    /// would fail the same way
+    fn write_rejected(&self, _error: &Self::Error) -> bool {
+        false
+    }
+
     /// Execute the specified query and return arrow record batches with the result
     async fn query(&self, query: &str) -> Result<Vec<RecordBatch>, Self::Error>;
 
+    /// Execute the specified query over only the rows `access` allows, and return arrow
+    /// record batches with the result
+    async fn query_with_access(
+        &self,
+        query: &str,
+        access: &access::RowAccess,
+    ) -> Result<Vec<RecordBatch>, Self::Error>;
+
     /// Returns a plan that lists the names of tables in this
     /// database that have at least one row that matches the
     /// conditions listed on `predicate`
@@ -96,6 +142,13 @@ pub trait Database: Debug + Send + Sync {
         group_columns: Vec<String>,
     ) -> Result<GroupedSeriesSetPlans, Self::Error>;
 
+    /// Returns the first values of the tag `query.tag_name` starting with `query.prefix`, in
+    /// the rows that match `query`, without listing all the values of the tag
+    async fn tag_values_with_prefix(
+        &self,
+        query: &autocomplete::TagValuesQuery,
+    ) -> Result<autocomplete::TagValues, Self::Error>;
+
     /// Fetch the specified table names and columns as Arrow
     /// RecordBatches. Columns are returned in the order specified.
     async fn table_to_arrow(
@@ -114,6 +167,9 @@ pub trait DatabaseStore: Debug + Send + Sync {
     /// The type of error this DataBase store generates
     type Error: std::error::Error + Send + Sync + 'static;
 
+    /// List the database names, in sorted order
+    async fn db_names_sorted(&self) -> Vec<String>;
+
     /// Retrieve the database specified by `name` returning None if no
     /// such database exists
     async fn db(&self, name: &str) -> Option<Arc<Self::Database>>;
@@ -1,6 +1,16 @@
-use std::collections::BTreeSet;
-
-use arrow_deps::datafusion::logical_plan::Expr;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use arrow_deps::{
+    arrow::{
+        array::{Array, ArrayRef, BooleanArray, StringArray},
+        datatypes::DataType,
+    },
+    datafusion::logical_plan::{create_udf, Expr},
+};
+use regex::Regex;
 
 /// Specifies a continuous range of nanosecond timestamps. Timestamp
 /// predicates are so common and critical to performance of timeseries
@@ -55,6 +65,14 @@ pub struct Predicate {
     /// from the results.
     pub exprs: Vec<Expr>,
 
+    /// Optional predicates that differ between tables: the rows of the
+    /// tables named here must also evaluate to TRUE for their expression,
+    /// and the rows of the other tables for `other_tables_expr`, if present
+    pub table_exprs: BTreeMap<String, Expr>,
+
+    /// Optional predicate for the rows of the tables not in `table_exprs`
+    pub other_tables_expr: Option<Expr>,
+
     /// Optional timestamp range: only rows within this range are included in
     /// results. Other rows are excluded
     pub range: Option<TimestampRange>,
@@ -63,10 +81,51 @@ pub struct Predicate {
 impl Predicate {
     /// Return true if this predicate has any general purpose predicates
     pub fn has_exprs(&self) -> bool {
-        !self.exprs.is_empty()
+        !self.exprs.is_empty() || !self.table_exprs.is_empty() || self.other_tables_expr.is_some()
     }
 }
 
+/// Creates an expression that is true for the rows where `input`, a string column, matches the
+/// regular expression `pattern`, or doesn't match it if `matches` is false. A null value is
+/// matched as an empty string, the way InfluxDB treats the rows without a tag.
+pub fn regex_match_expr(input: Expr, pattern: &str, matches: bool) -> Result<Expr, regex::Error> {
+    let regex = Regex::new(pattern)?;
+    let name = if matches {
+        "regex_match"
+    } else {
+        "regex_not_match"
+    };
+
+    let udf = create_udf(
+        name,
+        vec![DataType::Utf8],
+        Arc::new(DataType::Boolean),
+        Arc::new(move |args: &[ArrayRef]| {
+            let input = args[0]
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .expect("regex input is cast to a string");
+            Ok(Arc::new(regex_match(input, &regex, matches)) as ArrayRef)
+        }),
+    );
+
+    Ok(Expr::ScalarUDF {
+        fun: Arc::new(udf),
+        args: vec![input],
+    })
+}
+
+fn regex_match(input: &StringArray, regex: &Regex, matches: bool) -> BooleanArray {
+    BooleanArray::from(
+        (0..input.len())
+            .map(|i| {
+                let value = if input.is_null(i) { "" } else { input.value(i) };
+                regex.is_match(value) == matches
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
 #[derive(Debug, Default)]
 /// Structure for building `Predicate`s
 pub struct PredicateBuilder {
@@ -145,3 +204,25 @@ impl PredicateBuilder {
         self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regex_matches_nulls_as_empty_strings() {
+        let input = StringArray::from(vec![Some("us-west"), Some("eu-west"), None]);
+        let matching = |pattern: &str, matches: bool| {
+            let matched = regex_match(&input, &Regex::new(pattern).unwrap(), matches);
+            (0..matched.len())
+                .map(|i| matched.value(i))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(matching("^us-", true), vec![true, false, false]);
+        assert_eq!(matching("^us-", false), vec![false, true, true]);
+        assert_eq!(matching("^$", true), vec![false, false, true]);
+
+        assert!(regex_match_expr(Expr::Column("host".into()), "(", true).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+//! This module contains the `approx_percentile(value, p)` and `approx_median(value)` SQL
+//! aggregates, which estimate the `p` quantile (between 0 and 1) of the values of each group,
+//! such as the 99th percentile latency of each host with
+//! `SELECT host, approx_percentile(latency, 0.99) FROM requests GROUP BY host`.
+//!
+//! Exact percentiles need every value of a group in memory, which is infeasible over billions
+//! of points. The values are instead summarised by a t-digest: a few dozen centroids, each
+//! the mean and the number of nearby values, kept small near the extremes so that the tail
+//! percentiles stay accurate. Digests merge, so each chunk is summarised on its own and the
+//! summaries are combined, and are exchanged as strings of the form
+//! `min:max:mean*weight,mean*weight,...`.
+
+use std::{fmt, str::FromStr, sync::Arc};
+
+use arrow_deps::{
+    arrow::datatypes::DataType,
+    datafusion::{
+        error::{DataFusionError, Result as DataFusionResult},
+        logical_plan::create_udaf,
+        physical_plan::{
+            aggregates::{AccumulatorFunctionImplementation, StateTypeFunction},
+            functions::{ReturnTypeFunction, Signature},
+            udaf::AggregateUDF,
+            Accumulator,
+        },
+        scalar::ScalarValue,
+    },
+};
+
+/// Bounds the number of centroids of a digest, which is about half of it: the larger, the
+/// more accurate the quantiles
+const COMPRESSION: f64 = 100.0;
+
+/// How many values are buffered before they are merged into the centroids
+const BUFFER_SIZE: usize = 1000;
+
+/// A summary of a set of values, to estimate their quantiles
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TDigest {
+    /// The centroids, as their mean and weight, ordered by mean
+    centroids: Vec<(f64, f64)>,
+    /// The values and centroids added since the centroids were last merged
+    unmerged: Vec<(f64, f64)>,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    /// Adds `value`, ignoring values that aren't numbers
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        if self.is_empty() {
+            self.min = value;
+            self.max = value;
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.unmerged.push((value, 1.0));
+        if self.unmerged.len() >= BUFFER_SIZE {
+            self.compress();
+        }
+    }
+
+    /// Adds the values summarised by `other`
+    pub fn merge(&mut self, other: &Self) {
+        if other.is_empty() {
+            return;
+        }
+        if self.is_empty() {
+            self.min = other.min;
+            self.max = other.max;
+        }
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.unmerged.extend_from_slice(&other.centroids);
+        self.unmerged.extend_from_slice(&other.unmerged);
+        if self.unmerged.len() >= BUFFER_SIZE {
+            self.compress();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.centroids.is_empty() && self.unmerged.is_empty()
+    }
+
+    /// Returns the estimated `q` quantile of the values, or `None` if there are none or `q`
+    /// isn't between 0 and 1
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.is_empty() || !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+        let mut digest = self.clone();
+        digest.compress();
+        let centroids = &digest.centroids;
+
+        // the values of a centroid are assumed to be spread around its mean, which sits
+        // halfway through its weight, and the values between the means are interpolated
+        let total: f64 = centroids.iter().map(|(_, weight)| weight).sum();
+        let target = q * total;
+        let mut previous = (0.0, self.min);
+        let mut cumulative = 0.0;
+        for &(mean, weight) in centroids {
+            let center = cumulative + weight / 2.0;
+            if target < center {
+                return Some(interpolate(previous, (center, mean), target));
+            }
+            previous = (center, mean);
+            cumulative += weight;
+        }
+        Some(interpolate(previous, (total, self.max), target))
+    }
+
+    /// Merges the buffered values and centroids into the centroids, combining neighbouring
+    /// centroids while they stay within the size the scale function allows at their quantile
+    fn compress(&mut self) {
+        if self.unmerged.is_empty() {
+            return;
+        }
+        let mut all = std::mem::replace(&mut self.centroids, vec![]);
+        all.append(&mut self.unmerged);
+        all.sort_by(|a, b| a.0.partial_cmp(&b.0).expect("values are numbers"));
+
+        let total: f64 = all.iter().map(|(_, weight)| weight).sum();
+        let mut all = all.into_iter();
+        let mut current = all.next().expect("at least one centroid");
+        let mut q0 = 0.0;
+        let mut q_limit = max_quantile(q0);
+        for next in all {
+            if q0 + (current.1 + next.1) / total <= q_limit {
+                let weight = current.1 + next.1;
+                current = (current.0 + (next.0 - current.0) * next.1 / weight, weight);
+            } else {
+                q0 += current.1 / total;
+                q_limit = max_quantile(q0);
+                self.centroids.push(current);
+                current = next;
+            }
+        }
+        self.centroids.push(current);
+    }
+}
+
+/// Returns the quantile up to which a centroid starting at quantile `q0` may extend, using
+/// the scale function `k(q) = δ / 2π * asin(2q - 1)` with a step of 1
+fn max_quantile(q0: f64) -> f64 {
+    let scale = COMPRESSION / (2.0 * std::f64::consts::PI);
+    let k = scale * (2.0 * q0 - 1.0).asin() + 1.0;
+    if k >= scale * std::f64::consts::FRAC_PI_2 {
+        return 1.0;
+    }
+    ((k / scale).sin() + 1.0) / 2.0
+}
+
+fn interpolate((x0, y0): (f64, f64), (x1, y1): (f64, f64), x: f64) -> f64 {
+    if x1 <= x0 {
+        return y1;
+    }
+    y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+}
+
+impl fmt::Display for TDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut digest = self.clone();
+        digest.compress();
+        write!(f, "{}:{}:", digest.min, digest.max)?;
+        for (i, (mean, weight)) in digest.centroids.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}*{}", mean, weight)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TDigest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |n: &str| {
+            n.parse::<f64>()
+                .map_err(|e| format!("invalid t-digest {}: {}", s, e))
+        };
+
+        let mut parts = s.splitn(3, ':');
+        let (min, max, centroids) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(min), Some(max), Some(centroids)) => (min, max, centroids),
+            _ => return Err(format!("invalid t-digest {}", s)),
+        };
+        let centroids = centroids
+            .split(',')
+            .filter(|c| !c.is_empty())
+            .map(|centroid| {
+                let mut parts = centroid.splitn(2, '*');
+                match (parts.next(), parts.next()) {
+                    (Some(mean), Some(weight)) => Ok((parse(mean)?, parse(weight)?)),
+                    _ => Err(format!("invalid t-digest centroid {}", centroid)),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            centroids,
+            unmerged: vec![],
+            min: parse(min)?,
+            max: parse(max)?,
+        })
+    }
+}
+
+/// Returns the approximate quantile aggregates, to register with a query context
+pub fn udafs() -> Vec<AggregateUDF> {
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Float64)));
+    let state_type: StateTypeFunction =
+        Arc::new(|_| Ok(Arc::new(vec![DataType::Utf8, DataType::Float64])));
+    let accumulator: AccumulatorFunctionImplementation =
+        Arc::new(|| Ok(Box::new(QuantileAccumulator::default())));
+
+    vec![
+        AggregateUDF::new(
+            "approx_percentile",
+            &Signature::Exact(vec![DataType::Float64, DataType::Float64]),
+            &return_type,
+            &accumulator,
+            &state_type,
+        ),
+        create_udaf(
+            "approx_median",
+            DataType::Float64,
+            Arc::new(DataType::Float64),
+            Arc::new(|| {
+                Ok(Box::new(QuantileAccumulator {
+                    quantile: Some(0.5),
+                    ..Default::default()
+                }))
+            }),
+            Arc::new(vec![DataType::Utf8, DataType::Float64]),
+        ),
+    ]
+}
+
+/// Summarises the values of a group, and the quantile to estimate once it is known
+#[derive(Debug, Default)]
+struct QuantileAccumulator {
+    digest: TDigest,
+    quantile: Option<f64>,
+}
+
+impl QuantileAccumulator {
+    fn set_quantile(&mut self, quantile: &ScalarValue) -> DataFusionResult<()> {
+        if let (None, ScalarValue::Float64(Some(q))) = (self.quantile, quantile) {
+            if !(0.0..=1.0).contains(q) {
+                return Err(DataFusionError::Execution(format!(
+                    "approx_percentile needs a percentile between 0 and 1, not {}",
+                    q
+                )));
+            }
+            self.quantile = Some(*q);
+        }
+        Ok(())
+    }
+}
+
+impl Accumulator for QuantileAccumulator {
+    fn state(&self) -> DataFusionResult<Vec<ScalarValue>> {
+        Ok(vec![
+            ScalarValue::Utf8(Some(self.digest.to_string())),
+            ScalarValue::Float64(self.quantile),
+        ])
+    }
+
+    fn update(&mut self, values: &Vec<ScalarValue>) -> DataFusionResult<()> {
+        if let Some(quantile) = values.get(1) {
+            self.set_quantile(quantile)?;
+        }
+        if let ScalarValue::Float64(Some(value)) = values[0] {
+            self.digest.add(value);
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, states: &Vec<ScalarValue>) -> DataFusionResult<()> {
+        self.set_quantile(&states[1])?;
+        if let ScalarValue::Utf8(Some(digest)) = &states[0] {
+            let digest = digest
+                .parse::<TDigest>()
+                .map_err(DataFusionError::Execution)?;
+            self.digest.merge(&digest);
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self) -> DataFusionResult<ScalarValue> {
+        let quantile = self.quantile.and_then(|q| self.digest.quantile(q));
+        Ok(ScalarValue::Float64(quantile))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(values: impl IntoIterator<Item = u32>) -> TDigest {
+        let mut digest = TDigest::default();
+        for value in values {
+            digest.add(f64::from(value));
+        }
+        digest
+    }
+
+    fn assert_near(actual: Option<f64>, expected: f64, tolerance: f64) {
+        let actual = actual.expect("a quantile");
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn estimates_quantiles() {
+        let small = digest(1..=5);
+        assert_eq!(small.quantile(0.5), Some(3.0));
+        assert_eq!(small.quantile(0.0), Some(1.0));
+        assert_eq!(small.quantile(1.0), Some(5.0));
+        assert_eq!(small.quantile(1.5), None);
+        assert_eq!(TDigest::default().quantile(0.5), None);
+
+        let large = digest(1..=100_000);
+        assert_near(large.quantile(0.5), 50_000.0, 500.0);
+        assert_near(large.quantile(0.99), 99_000.0, 100.0);
+        assert_near(large.quantile(0.999), 99_900.0, 20.0);
+    }
+
+    #[test]
+    fn merges_digests() {
+        let mut odd = digest((1..=100_000).filter(|v| v % 2 == 1));
+        let even = digest((1..=100_000).filter(|v| v % 2 == 0));
+        odd.merge(&even);
+        assert_near(odd.quantile(0.5), 50_000.0, 500.0);
+        assert_near(odd.quantile(0.99), 99_000.0, 100.0);
+
+        // digests merge the same once exchanged as strings
+        let parsed: TDigest = even.to_string().parse().unwrap();
+        assert_eq!(parsed.quantile(0.9), even.quantile(0.9));
+        assert!("1:2".parse::<TDigest>().is_err());
+        assert!("1:2:3".parse::<TDigest>().is_err());
+        assert_eq!("1:1:".parse::<TDigest>().unwrap().quantile(0.5), None);
+    }
+}
@@ -4,6 +4,8 @@
 use arrow_deps::arrow::record_batch::RecordBatch;
 
 use crate::{
+    access::RowAccess,
+    autocomplete::{TagValues, TagValuesQuery},
     exec::FieldListPlan,
     exec::{
         stringset::{StringSet, StringSetRef},
@@ -17,7 +19,7 @@ use influxdb_line_protocol::{parse_lines, ParsedLine};
 
 use async_trait::async_trait;
 use snafu::{OptionExt, Snafu};
-use std::{collections::BTreeMap, collections::BTreeSet, sync::Arc};
+use std::{collections::BTreeMap, collections::BTreeSet, sync::Arc, time::Duration};
 
 use std::fmt::Write;
 
@@ -31,6 +33,12 @@ pub struct TestDatabase {
     /// Replicated writes which have been written to this database, in order
     replicated_writes: Mutex<Vec<ReplicatedWrite>>,
 
+    /// If set, writes are rejected as throttled, to be retried after this long
+    write_retry_after: Mutex<Option<Duration>>,
+
+    /// The data version reported, if set
+    data_version: std::sync::Mutex<Option<u64>>,
+
     /// `column_names` to return upon next request
     column_names: Arc<Mutex<Option<StringSetRef>>>,
 
@@ -108,6 +116,9 @@ pub enum TestError {
 
     #[snafu(display("Test database execution:  {:?}", source))]
     Execution { source: crate::exec::Error },
+
+    #[snafu(display("Test database is throttling writes"))]
+    WriteThrottled { retry_after: Duration },
 }
 
 impl TestDatabase {
@@ -115,6 +126,17 @@ impl TestDatabase {
         Self::default()
     }
 
+    /// Rejects the writes from now on as throttled, to be retried after `retry_after`, or
+    /// accepts them again if `None`
+    pub async fn set_write_retry_after(&self, retry_after: Option<Duration>) {
+        *self.write_retry_after.lock().await = retry_after;
+    }
+
+    /// Reports `data_version` as the version of the data of this database from now on
+    pub fn set_data_version(&self, data_version: Option<u64>) {
+        *self.data_version.lock().expect("mutex poisoned") = data_version;
+    }
+
     /// Get all lines written to this database
     pub async fn get_lines(&self) -> Vec<String> {
         self.saved_lines.lock().await.clone()
@@ -216,6 +238,8 @@ fn predicate_to_test_string(predicate: &Predicate) -> String {
         table_names,
         field_columns,
         exprs,
+        table_exprs,
+        other_tables_expr,
         range,
     } = predicate;
 
@@ -234,6 +258,14 @@ fn predicate_to_test_string(predicate: &Predicate) -> String {
         write!(result, " exprs: {:?}", exprs).unwrap();
     }
 
+    if !table_exprs.is_empty() {
+        write!(result, " table_exprs: {:?}", table_exprs).unwrap();
+    }
+
+    if let Some(other_tables_expr) = other_tables_expr {
+        write!(result, " other_tables_expr: {:?}", other_tables_expr).unwrap();
+    }
+
     if let Some(range) = range {
         write!(result, " range: {:?}", range).unwrap();
     }
@@ -248,6 +280,10 @@ impl Database for TestDatabase {
 
     /// Writes parsed lines into this database
     async fn write_lines(&self, lines: &[ParsedLine<'_>]) -> Result<(), Self::Error> {
+        if let Some(retry_after) = *self.write_retry_after.lock().await {
+            return WriteThrottled { retry_after }.fail();
+        }
+
         let mut saved_lines = self.saved_lines.lock().await;
         for line in lines {
             saved_lines.push(line.to_string())
@@ -261,11 +297,30 @@ impl Database for TestDatabase {
         Ok(())
     }
 
+    fn data_version(&self) -> Option<u64> {
+        *self.data_version.lock().expect("mutex poisoned")
+    }
+
+    fn write_retry_after(&self, error: &Self::Error) -> Option<Duration> {
+        match error {
+            TestError::WriteThrottled { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
     /// Execute the specified query and return arrow record batches with the result
     async fn query(&self, _query: &str) -> Result<Vec<RecordBatch>, Self::Error> {
         unimplemented!("query Not yet implemented");
     }
 
+    async fn query_with_access(
+        &self,
+        _query: &str,
+        _access: &RowAccess,
+    ) -> Result<Vec<RecordBatch>, Self::Error> {
+        unimplemented!("query_with_access Not yet implemented");
+    }
+
     /// Return all table names that are saved in this database
     async fn table_names(&self, predicate: Predicate) -> Result<StringSetPlan, Self::Error> {
         let saved_lines = self.saved_lines.lock().await;
@@ -402,6 +457,34 @@ impl Database for TestDatabase {
             })
     }
 
+    /// Return the values of the tag in the saved lines that match the query
+    async fn tag_values_with_prefix(
+        &self,
+        query: &TagValuesQuery,
+    ) -> Result<TagValues, Self::Error> {
+        let saved_lines = self.saved_lines.lock().await;
+
+        let mut collector = query.collector();
+        for line in parse_lines(&saved_lines.join("\n")) {
+            let line = line.expect("Correctly parsed saved line");
+            let in_table = query.table_name.as_ref().map_or(true, |name| {
+                line.series.measurement.as_str() == name.as_str()
+            });
+            if !in_table || !line_in_range(&line, query.range.as_ref()) {
+                continue;
+            }
+
+            let tags = line.series.tag_set.iter().flatten();
+            for (key, value) in tags {
+                if key.as_str() == query.tag_name {
+                    collector.insert(value.as_str());
+                }
+            }
+        }
+
+        Ok(collector.finish())
+    }
+
     /// Fetch the specified table names and columns as Arrow RecordBatches
     async fn table_to_arrow(
         &self,
@@ -444,6 +527,14 @@ impl Default for TestDatabaseStore {
 impl DatabaseStore for TestDatabaseStore {
     type Database = TestDatabase;
     type Error = TestError;
+
+    /// List the database names
+    async fn db_names_sorted(&self) -> Vec<String> {
+        let databases = self.databases.lock().await;
+
+        databases.keys().cloned().collect()
+    }
+
     /// Retrieve the database specified name
     async fn db(&self, name: &str) -> Option<Arc<Self::Database>> {
         let databases = self.databases.lock().await;
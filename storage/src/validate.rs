@@ -0,0 +1,183 @@
+//! This module contains the validation of writes of line protocol without storing them, so
+//! that generated line protocol can be checked before it is sent for real. Each line is
+//! parsed, and the lines that parse are checked against the types of the columns the database
+//! already has and against the other lines of the write, as a column can only hold values of
+//! one type.
+
+use std::{collections::BTreeMap, fmt};
+
+use data_types::TIME_COLUMN_NAME;
+use influxdb_line_protocol::{parse_lines, FieldValue, ParsedLine};
+
+/// A problem with a line of a write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineDiagnostic {
+    /// The position of the line in the write, starting at 1 and not counting empty lines
+    pub line_number: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LineDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line_number, self.message)
+    }
+}
+
+/// The lines of a write that parsed, and the problems found with its lines so far
+#[derive(Debug)]
+pub struct ParsedWrite<'a> {
+    pub lines: Vec<ParsedLine<'a>>,
+    /// The line number of each of `lines`
+    line_numbers: Vec<usize>,
+    diagnostics: Vec<LineDiagnostic>,
+}
+
+impl<'a> ParsedWrite<'a> {
+    /// Parses each line of `body`, recording the lines that fail to parse
+    pub fn parse(body: &'a str) -> Self {
+        let mut write = Self {
+            lines: vec![],
+            line_numbers: vec![],
+            diagnostics: vec![],
+        };
+        for (index, line) in parse_lines(body).enumerate() {
+            match line {
+                Ok(line) => {
+                    write.lines.push(line);
+                    write.line_numbers.push(index + 1);
+                }
+                Err(e) => write.diagnostics.push(LineDiagnostic {
+                    line_number: index + 1,
+                    message: e.to_string(),
+                }),
+            }
+        }
+        write
+    }
+
+    /// The number of lines of the write, parsed or not
+    pub fn line_count(&self) -> usize {
+        self.lines.len() + self.diagnostics.len()
+    }
+
+    /// Records problems found with `lines`, whose line numbers are positions in `lines`
+    /// rather than in the write
+    pub fn add(&mut self, diagnostics: impl IntoIterator<Item = LineDiagnostic>) {
+        let line_numbers = &self.line_numbers;
+        self.diagnostics
+            .extend(diagnostics.into_iter().map(|diagnostic| LineDiagnostic {
+                line_number: line_numbers[diagnostic.line_number - 1],
+                ..diagnostic
+            }));
+    }
+
+    /// Returns the problems found with the write, in the order of its lines
+    pub fn diagnostics(mut self) -> Vec<LineDiagnostic> {
+        // stable, so the problems of a line stay in the order they were found
+        self.diagnostics.sort_by_key(|d| d.line_number);
+        self.diagnostics
+    }
+}
+
+/// Returns the type of the column that stores `value`, as described by `ColumnSummary`
+pub fn field_type(value: &FieldValue<'_>) -> &'static str {
+    match value {
+        FieldValue::I64(_) => "i64",
+        FieldValue::F64(_) => "f64",
+        FieldValue::String(_) => "String",
+        FieldValue::Boolean(_) => "bool",
+    }
+}
+
+/// Checks the values of `lines` against the types of the columns of the database, keyed by
+/// table and column name, and against the types the earlier lines gave new columns
+pub fn check_column_types(
+    mut column_types: BTreeMap<(String, String), String>,
+    lines: &[ParsedLine<'_>],
+) -> Vec<LineDiagnostic> {
+    let mut diagnostics = vec![];
+    for (index, line) in lines.iter().enumerate() {
+        let table = line.series.measurement.as_str();
+        let tags = line
+            .series
+            .tag_set
+            .iter()
+            .flatten()
+            .map(|(key, _)| (key.as_str(), "tag"));
+        let fields = line
+            .field_set
+            .iter()
+            .map(|(key, value)| (key.as_str(), field_type(value)));
+
+        for (column, value_type) in tags.chain(fields) {
+            if column == TIME_COLUMN_NAME {
+                diagnostics.push(LineDiagnostic {
+                    line_number: index + 1,
+                    message: format!(
+                        "column {} of measurement {} is reserved for timestamps",
+                        column, table
+                    ),
+                });
+                continue;
+            }
+
+            let existing = column_types
+                .entry((table.to_string(), column.to_string()))
+                .or_insert_with(|| value_type.to_string());
+            if existing.as_str() != value_type {
+                diagnostics.push(LineDiagnostic {
+                    line_number: index + 1,
+                    message: format!(
+                        "column {} of measurement {} has type {}, not {}",
+                        column, table, existing, value_type
+                    ),
+                });
+            }
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnoses_lines() {
+        let body = "cpu,host=a usage=0.5 10\n\
+                    \n\
+                    cpu,host=a usage=1i 20\n\
+                    not line protocol\n\
+                    mem,host=a free=1i,time=3i 30\n\
+                    cpu,usage=a host=\"b\" 40";
+        let mut write = ParsedWrite::parse(body);
+        assert_eq!(write.line_count(), 5);
+        assert_eq!(write.lines.len(), 4);
+
+        let mut column_types = BTreeMap::new();
+        column_types.insert(("mem".to_string(), "free".to_string()), "f64".to_string());
+        let found = check_column_types(column_types, &write.lines);
+        write.add(found);
+
+        let diagnostics: Vec<_> = write
+            .diagnostics()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(diagnostics.len(), 6, "{:#?}", diagnostics);
+        assert_eq!(
+            diagnostics[0],
+            "line 2: column usage of measurement cpu has type f64, not i64"
+        );
+        assert!(diagnostics[1].starts_with("line 3: "));
+        assert_eq!(
+            &diagnostics[2..],
+            &[
+                "line 4: column free of measurement mem has type f64, not i64",
+                "line 4: column time of measurement mem is reserved for timestamps",
+                "line 5: column usage of measurement cpu has type f64, not tag",
+                "line 5: column host of measurement cpu has type tag, not String",
+            ]
+        );
+    }
+}
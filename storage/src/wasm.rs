@@ -0,0 +1,628 @@
+//! This module contains the scalar SQL functions operators load from WebAssembly modules, for
+//! unit conversions and business logic that the built-in functions don't cover, without
+//! recompiling the server.
+//!
+//! Every `.wasm` file of the directory given to `init` is compiled when the server starts, and
+//! each function it exports becomes a SQL function of the same name, such as
+//! `SELECT fahrenheit(temp) FROM weather`. The parameters and result of an exported function
+//! must be `f64` or `i64`, which are the `Float64` and `Int64` columns of queries, and it must
+//! take at least one argument. The function is null where any of its arguments is.
+//!
+//! The functions are sandboxed: modules can't import anything, so they have no access to the
+//! host, their memories must declare a maximum size within `Limits::max_memory_bytes`, which
+//! they can't grow beyond, and the calls of a function on a batch of rows are interrupted once
+//! they have run for `Limits::batch_timeout`, failing the query. A single watchdog thread
+//! interrupts the calls that run past their deadline.
+//!
+//! Each thread running queries compiles and instantiates a module the first time it calls one
+//! of its functions, and keeps the instance for the calls that follow. The instances of a
+//! thread are dropped once one of its calls fails, so functions shouldn't rely on state kept
+//! between calls.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use arrow_deps::{
+    arrow::{
+        array::{Array, ArrayRef, Float64Array, Int64Array},
+        datatypes::DataType,
+    },
+    datafusion::{error::DataFusionError, logical_plan::create_udf, physical_plan::udf::ScalarUDF},
+};
+use once_cell::sync::OnceCell;
+use snafu::{ensure, ResultExt, Snafu};
+use tracing::{info, warn};
+use wasmparser::{ModuleReader, SectionCode};
+use wasmtime::{
+    Config, Engine, ExternType, Instance, InterruptHandle, Module, Store, Val, ValType,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Error reading the WebAssembly functions in {:?}: {}", dir, source))]
+    ReadingDir {
+        dir: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Error reading WebAssembly module {}: {}", module, source))]
+    ReadingModule {
+        module: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Invalid WebAssembly module {}: {}", module, message))]
+    InvalidModule { module: String, message: String },
+
+    #[snafu(display(
+        "WebAssembly module {} imports {}.{}: functions can't import anything",
+        module,
+        import_module,
+        name
+    ))]
+    ImportNotAllowed {
+        module: String,
+        import_module: String,
+        name: String,
+    },
+
+    #[snafu(display(
+        "WebAssembly function {} of module {} is already exported by another module",
+        name,
+        module
+    ))]
+    DuplicateFunction { module: String, name: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The size of a page of WebAssembly memory
+const PAGE_SIZE: usize = 64 * 1024;
+
+/// How long the calls of a function on a batch of rows may run, unless configured otherwise
+pub const DEFAULT_BATCH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The largest memory a module may declare, unless configured otherwise
+pub const DEFAULT_MAX_MEMORY_BYTES: usize = 16 * 1024 * 1024;
+
+/// The resources the functions of the modules may use
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// How long the calls of a function on a batch of rows may run before they are
+    /// interrupted
+    pub batch_timeout: Duration,
+    /// The largest memory a module may declare, in bytes
+    pub max_memory_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            batch_timeout: DEFAULT_BATCH_TIMEOUT,
+            max_memory_bytes: DEFAULT_MAX_MEMORY_BYTES,
+        }
+    }
+}
+
+static UDFS: OnceCell<Vec<ScalarUDF>> = OnceCell::new();
+
+/// Compiles the `.wasm` files of `dir`, whose functions queries may call from now on. Returns
+/// the number of functions loaded, or an error if a module is invalid or breaks the sandbox
+/// rules. Calling it again has no effect.
+pub fn init(dir: &Path, limits: Limits) -> Result<usize> {
+    let udfs = UDFS.get_or_try_init(|| load_dir(dir, limits))?;
+    Ok(udfs.len())
+}
+
+/// Returns the functions loaded by `init`, to register with a query context
+pub fn udfs() -> Vec<ScalarUDF> {
+    UDFS.get().cloned().unwrap_or_default()
+}
+
+fn load_dir(dir: &Path, limits: Limits) -> Result<Vec<ScalarUDF>> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir).context(ReadingDir { dir })? {
+        let path = entry.context(ReadingDir { dir })?.path();
+        if path
+            .extension()
+            .map_or(false, |extension| extension == "wasm")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut names = BTreeSet::new();
+    let mut udfs = vec![];
+    for path in paths {
+        let module = path.display().to_string();
+        let wasm = std::fs::read(&path).context(ReadingModule { module: &module })?;
+        for udf in compile(&module, &wasm, limits)? {
+            ensure!(
+                names.insert(udf.name.clone()),
+                DuplicateFunction {
+                    module: &module,
+                    name: &udf.name,
+                }
+            );
+            info!("loaded WebAssembly function {} from {}", udf.name, module);
+            udfs.push(udf);
+        }
+    }
+    Ok(udfs)
+}
+
+/// Creates a store for instances whose code can be interrupted
+fn store() -> Store {
+    let mut config = Config::new();
+    config.interruptable(true);
+    Store::new(&Engine::new(&config))
+}
+
+/// Compiles the WebAssembly module named `module`, in the binary or text format, returning its
+/// exported functions
+fn compile(module: &str, wasm: &[u8], limits: Limits) -> Result<Vec<ScalarUDF>> {
+    let invalid = |message: String| Error::InvalidModule {
+        module: module.to_string(),
+        message,
+    };
+    let binary = wat::parse_bytes(wasm)
+        .map_err(|e| invalid(e.to_string()))?
+        .into_owned();
+    check_memories(&binary, limits).map_err(invalid)?;
+    let store = store();
+    let compiled = Module::new(&store, &binary[..]).map_err(|e| invalid(e.to_string()))?;
+
+    if let Some(import) = compiled.imports().iter().next() {
+        return ImportNotAllowed {
+            module,
+            import_module: import.module(),
+            name: import.name(),
+        }
+        .fail();
+    }
+    // instantiating the module runs its start function, if any
+    let (instantiated, _) = with_timeout(&store, limits.batch_timeout, || {
+        Instance::new(&compiled, &[]).map_err(|e| e.to_string())
+    });
+    instantiated.map_err(invalid)?;
+
+    let binary = Arc::new(binary);
+    let mut udfs = vec![];
+    for export in compiled.exports() {
+        let func = match export.ty() {
+            ExternType::Func(func) => func,
+            _ => continue,
+        };
+        let params = func.params().to_vec();
+        let results = func.results().to_vec();
+        let supported = |ty: &ValType| matches!(ty, ValType::F64 | ValType::I64);
+        if params.is_empty() || results.len() != 1 || !params.iter().chain(&results).all(supported)
+        {
+            warn!(
+                "skipping WebAssembly function {} of {}: only functions of f64 and i64 values \
+                 returning one value, with at least one argument, can be called from queries",
+                export.name(),
+                module
+            );
+            continue;
+        }
+
+        let function = Arc::new(Function {
+            module: module.to_string(),
+            binary: Arc::clone(&binary),
+            name: export.name().to_string(),
+            params: params.clone(),
+            result: results[0].clone(),
+            limits,
+        });
+        udfs.push(create_udf(
+            export.name(),
+            params.iter().map(data_type).collect(),
+            Arc::new(data_type(&results[0])),
+            Arc::new(move |args: &[ArrayRef]| {
+                function.call(args).map_err(|message| {
+                    DataFusionError::Execution(format!(
+                        "WebAssembly function {} failed: {}",
+                        function.name, message
+                    ))
+                })
+            }),
+        ));
+    }
+    Ok(udfs)
+}
+
+/// Checks that every memory of the module `binary` declares a maximum size within
+/// `limits.max_memory_bytes`, as a memory can grow up to its maximum
+fn check_memories(binary: &[u8], limits: Limits) -> Result<(), String> {
+    let max_pages = (limits.max_memory_bytes / PAGE_SIZE) as u32;
+    let mut reader = ModuleReader::new(binary).map_err(|e| e.to_string())?;
+    while !reader.eof() {
+        let section = reader.read().map_err(|e| e.to_string())?;
+        if let SectionCode::Memory = section.code {
+            let memories = section
+                .get_memory_section_reader()
+                .map_err(|e| e.to_string())?;
+            for memory in memories {
+                let memory = memory.map_err(|e| e.to_string())?;
+                match memory.limits.maximum {
+                    Some(maximum) if maximum <= max_pages => {}
+                    _ => {
+                        return Err(format!(
+                            "memories must declare a maximum size of at most {} pages of 64KiB",
+                            max_pages
+                        ))
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs `f`, which runs the code of instances of `store`, interrupting that code once `f` has
+/// run for `timeout`. Also returns whether the code was interrupted: an interrupt that comes as
+/// `f` returns stays pending, and would interrupt the next code the store runs.
+fn with_timeout<T>(
+    store: &Store,
+    timeout: Duration,
+    f: impl FnOnce() -> Result<T, String>,
+) -> (Result<T, String>, bool) {
+    let handle = match store.interrupt_handle() {
+        Ok(handle) => handle,
+        Err(e) => return (Err(e.to_string()), false),
+    };
+    let watchdog = watchdog();
+    let watch = watchdog.watch(handle, timeout);
+    let result = f();
+    let interrupted = watchdog.unwatch(watch);
+    (result, interrupted)
+}
+
+static WATCHDOG: OnceCell<Arc<Watchdog>> = OnceCell::new();
+
+/// Returns the watchdog of the calls, starting its thread the first time
+fn watchdog() -> &'static Watchdog {
+    WATCHDOG.get_or_init(|| {
+        let watchdog = Arc::new(Watchdog::default());
+        let running = Arc::clone(&watchdog);
+        thread::Builder::new()
+            .name("wasm-watchdog".to_string())
+            .spawn(move || running.run())
+            .expect("failed to spawn the WebAssembly watchdog thread");
+        watchdog
+    })
+}
+
+/// Interrupts the code that runs past its deadline, from a single thread shared by every call
+#[derive(Default)]
+struct Watchdog {
+    watches: Mutex<Watches>,
+    /// Notified when a watch is added, which may come before the next deadline
+    added: Condvar,
+}
+
+#[derive(Default)]
+struct Watches {
+    next_id: u64,
+    /// The interrupt handles of the code running, by deadline
+    pending: BTreeMap<(Instant, u64), InterruptHandle>,
+    /// The watches whose code was interrupted, until they are removed
+    interrupted: HashSet<u64>,
+}
+
+/// The watch of the code of a call, see `Watchdog::watch`
+struct Watch {
+    deadline: Instant,
+    id: u64,
+}
+
+impl Watchdog {
+    /// Interrupts the code of `handle` if it still runs once `timeout` has passed
+    fn watch(&self, handle: InterruptHandle, timeout: Duration) -> Watch {
+        let mut watches = self.watches.lock().expect("mutex poisoned");
+        let watch = Watch {
+            deadline: Instant::now() + timeout,
+            id: watches.next_id,
+        };
+        watches.next_id += 1;
+        watches.pending.insert((watch.deadline, watch.id), handle);
+        self.added.notify_one();
+        watch
+    }
+
+    /// Stops watching the code of `watch`, returning whether it was interrupted
+    fn unwatch(&self, watch: Watch) -> bool {
+        let mut watches = self.watches.lock().expect("mutex poisoned");
+        watches
+            .pending
+            .remove(&(watch.deadline, watch.id))
+            .is_none()
+            && watches.interrupted.remove(&watch.id)
+    }
+
+    /// Interrupts the code of the watches as their deadline passes, forever
+    fn run(&self) {
+        let mut watches = self.watches.lock().expect("mutex poisoned");
+        loop {
+            let now = Instant::now();
+            loop {
+                let key = match watches.pending.keys().next() {
+                    Some(&key) if key.0 <= now => key,
+                    _ => break,
+                };
+                if let Some(handle) = watches.pending.remove(&key) {
+                    handle.interrupt();
+                    watches.interrupted.insert(key.1);
+                }
+            }
+
+            let next_deadline = watches.pending.keys().next().map(|&(deadline, _)| deadline);
+            watches = match next_deadline {
+                Some(deadline) => {
+                    self.added
+                        .wait_timeout(watches, deadline - now)
+                        .expect("mutex poisoned")
+                        .0
+                }
+                None => self.added.wait(watches).expect("mutex poisoned"),
+            };
+        }
+    }
+}
+
+fn data_type(ty: &ValType) -> DataType {
+    match ty {
+        ValType::I64 => DataType::Int64,
+        _ => DataType::Float64,
+    }
+}
+
+/// An exported function of a module
+struct Function {
+    /// The name of the module
+    module: String,
+    /// The module, in the binary format
+    binary: Arc<Vec<u8>>,
+    name: String,
+    params: Vec<ValType>,
+    result: ValType,
+    limits: Limits,
+}
+
+thread_local! {
+    /// The instances of the modules whose functions the thread called
+    static INSTANCES: RefCell<Option<ThreadInstances>> = RefCell::new(None);
+}
+
+/// The instances of the modules of a thread, in a store of their own
+struct ThreadInstances {
+    store: Store,
+    /// The instances by module name
+    instances: HashMap<String, Instance>,
+}
+
+impl ThreadInstances {
+    fn new() -> Self {
+        Self {
+            store: store(),
+            instances: HashMap::new(),
+        }
+    }
+
+    /// Returns the instance of the module `module` of the binary `binary`, which is compiled
+    /// and instantiated the first time
+    fn instance(&mut self, module: &str, binary: &[u8]) -> Result<Instance, String> {
+        if let Some(instance) = self.instances.get(module) {
+            return Ok(instance.clone());
+        }
+
+        let compiled = Module::new(&self.store, binary).map_err(|e| e.to_string())?;
+        let instance = Instance::new(&compiled, &[]).map_err(|e| e.to_string())?;
+        self.instances.insert(module.to_string(), instance.clone());
+        Ok(instance)
+    }
+}
+
+impl Function {
+    /// Calls the function with the values of each row of `args`, in the instance of its module
+    /// of the thread
+    fn call(&self, args: &[ArrayRef]) -> Result<ArrayRef, String> {
+        INSTANCES.with(|instances| {
+            let mut instances = instances.borrow_mut();
+            let thread = instances.get_or_insert_with(ThreadInstances::new);
+            let store = thread.store.clone();
+            let (result, interrupted) = with_timeout(&store, self.limits.batch_timeout, || {
+                let instance = thread.instance(&self.module, &self.binary)?;
+                self.call_rows(&instance, args)
+            });
+            // a failed call may leave its instance half way through, and the store of an
+            // interrupted call may still have the interrupt pending
+            if result.is_err() || interrupted {
+                *instances = None;
+            }
+            result
+        })
+    }
+
+    fn call_rows(&self, instance: &Instance, args: &[ArrayRef]) -> Result<ArrayRef, String> {
+        let func = instance
+            .get_func(&self.name)
+            .expect("exported functions are in the instance");
+
+        let rows = args.first().map_or(0, |array| array.len());
+        let mut floats = vec![];
+        let mut integers = vec![];
+        let mut values = Vec::with_capacity(self.params.len());
+        for row in 0..rows {
+            values.clear();
+            for array in args {
+                if array.is_null(row) {
+                    break;
+                }
+                values.push(match array.data_type() {
+                    DataType::Int64 => Val::I64(integer_array(array).value(row)),
+                    _ => Val::F64(float_array(array).value(row).to_bits()),
+                });
+            }
+
+            let result = if values.len() == args.len() {
+                let results = func.call(&values).map_err(|e| e.to_string())?;
+                Some(results[0].clone())
+            } else {
+                None
+            };
+            match result {
+                Some(Val::I64(value)) => integers.push(Some(value)),
+                Some(Val::F64(bits)) => floats.push(Some(f64::from_bits(bits))),
+                _ => {
+                    integers.push(None);
+                    floats.push(None);
+                }
+            }
+        }
+
+        Ok(match self.result {
+            ValType::I64 => Arc::new(Int64Array::from(integers)) as ArrayRef,
+            _ => Arc::new(Float64Array::from(floats)) as ArrayRef,
+        })
+    }
+}
+
+fn float_array(array: &ArrayRef) -> &Float64Array {
+    array
+        .as_any()
+        .downcast_ref()
+        .expect("arguments are cast to the parameter types")
+}
+
+fn integer_array(array: &ArrayRef) -> &Int64Array {
+    array
+        .as_any()
+        .downcast_ref()
+        .expect("arguments are cast to the parameter types")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONVERSIONS: &str = r#"
+        (module
+          (memory (export "memory") 1 1)
+          (func (export "fahrenheit") (param f64) (result f64)
+            local.get 0
+            f64.const 1.8
+            f64.mul
+            f64.const 32
+            f64.add)
+          (func (export "kilobytes") (param i64) (result i64)
+            local.get 0
+            i64.const 1024
+            i64.div_s)
+          (func (export "spin") (param i64) (result i64)
+            (loop $forever (br $forever))
+            local.get 0)
+          (func (export "not_a_udf") (param i32)))
+    "#;
+
+    fn udf<'a>(udfs: &'a [ScalarUDF], name: &str) -> &'a ScalarUDF {
+        udfs.iter().find(|udf| udf.name == name).unwrap()
+    }
+
+    #[test]
+    fn calls_functions() -> Result<(), Box<dyn std::error::Error>> {
+        let udfs = compile("conversions", CONVERSIONS.as_bytes(), Limits::default())?;
+        let names: Vec<_> = udfs.iter().map(|udf| udf.name.as_str()).collect();
+        assert_eq!(names, vec!["fahrenheit", "kilobytes", "spin"]);
+
+        let temps: ArrayRef = Arc::new(Float64Array::from(vec![Some(100.0), None, Some(-40.0)]));
+        let results = (udf(&udfs, "fahrenheit").fun)(&[temps])?;
+        let results = float_array(&results);
+        assert_eq!(results.value(0), 212.0);
+        assert!(results.is_null(1));
+        assert_eq!(results.value(2), -40.0);
+
+        let sizes: ArrayRef = Arc::new(Int64Array::from(vec![2048, 4096]));
+        let results = (udf(&udfs, "kilobytes").fun)(&[sizes.clone()])?;
+        assert_eq!(integer_array(&results).value(1), 4);
+
+        // a function that runs for too long is interrupted
+        let err = (udf(&udfs, "spin").fun)(&[sizes]).unwrap_err();
+        assert!(err.to_string().contains("spin"), "{}", err);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_instances_until_a_call_fails() -> Result<(), Box<dyn std::error::Error>> {
+        let counter = r#"
+            (module
+              (global $calls (mut i64) (i64.const 0))
+              (func (export "count") (param i64) (result i64)
+                global.get $calls
+                i64.const 1
+                i64.add
+                global.set $calls
+                global.get $calls)
+              (func (export "spin") (param i64) (result i64)
+                (loop $forever (br $forever))
+                local.get 0))
+        "#;
+        let limits = Limits {
+            batch_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let udfs = compile("counter", counter.as_bytes(), limits)?;
+        let rows: ArrayRef = Arc::new(Int64Array::from(vec![0, 0]));
+        let count = |udfs: &[ScalarUDF]| -> Result<Vec<i64>, DataFusionError> {
+            let results = (udf(udfs, "count").fun)(&[rows.clone()])?;
+            let results = integer_array(&results);
+            Ok((0..results.len()).map(|i| results.value(i)).collect())
+        };
+
+        // the instance of the thread is kept from one batch to the next
+        assert_eq!(count(&udfs)?, vec![1, 2]);
+        assert_eq!(count(&udfs)?, vec![3, 4]);
+
+        // and replaced once a call fails, without the interrupt of the failed call
+        // interrupting the next one
+        (udf(&udfs, "spin").fun)(&[rows.clone()]).unwrap_err();
+        assert_eq!(count(&udfs)?, vec![1, 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sandboxes_modules() {
+        let limits = Limits::default();
+
+        let imports = r#"
+            (module
+              (import "env" "now" (func $now (result i64)))
+              (func (export "now") (result i64) call $now))
+        "#;
+        let err = compile("imports", imports.as_bytes(), limits).unwrap_err();
+        assert!(matches!(err, Error::ImportNotAllowed { .. }), "{}", err);
+
+        // 1024 pages of 64KiB are 64MiB, more than the default limit
+        let memory = r#"(module (memory 1 1024))"#;
+        let err = compile("memory", memory.as_bytes(), limits).unwrap_err();
+        assert!(matches!(err, Error::InvalidModule { .. }), "{}", err);
+
+        // memories without a maximum could grow without limit
+        let memory = r#"(module (memory 1))"#;
+        let err = compile("memory", memory.as_bytes(), limits).unwrap_err();
+        assert!(matches!(err, Error::InvalidModule { .. }), "{}", err);
+
+        let err = compile("garbage", b"not wasm", limits).unwrap_err();
+        assert!(matches!(err, Error::InvalidModule { .. }), "{}", err);
+    }
+}
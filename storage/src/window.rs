@@ -0,0 +1,394 @@
+//! This module contains the alignment of timestamps to the windows of `GROUP BY time(every,
+//! offset)` queries, exposed to SQL as the `date_bin(time, every, offset, tz)` function.
+//!
+//! Without a time zone, windows are a fixed number of nanoseconds wide and aligned to the
+//! epoch shifted by the offset. In a time zone, windows are aligned to its wall clock time
+//! instead: day windows start at local midnight (plus the offset), and are 23 or 25 hours long
+//! across daylight saving time changes, which fixed-width windows get wrong.
+
+use std::sync::Arc;
+
+use arrow_deps::{
+    arrow::{
+        array::{Array, ArrayRef, Int64Array, StringArray},
+        datatypes::DataType,
+    },
+    datafusion::{error::DataFusionError, logical_plan::create_udf, physical_plan::udf::ScalarUDF},
+};
+use chrono::{LocalResult, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use snafu::{ensure, OptionExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Invalid duration '{}': expected integers with units of ns, us, ms, s, m, h, d or w, such as 1h30m",
+        duration
+    ))]
+    InvalidDuration { duration: String },
+
+    #[snafu(display("Windows must be longer than zero, got '{}'", every))]
+    EmptyWindow { every: String },
+
+    #[snafu(display("Unknown time zone '{}'", tz))]
+    UnknownTimeZone { tz: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// Parses an InfluxQL duration literal such as `1d`, `-15m` or `1h30m` into nanoseconds
+pub fn parse_duration(duration: &str) -> Result<i64> {
+    let invalid = || InvalidDuration { duration };
+    let (negative, mut rest) = match duration.trim() {
+        d if d.starts_with('-') => (true, &d[1..]),
+        d => (false, d),
+    };
+    ensure!(!rest.is_empty(), invalid());
+
+    let mut nanos: i64 = 0;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .context(invalid())?;
+        let value: i64 = rest[..digits].parse().ok().context(invalid())?;
+        rest = &rest[digits..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or_else(|| rest.len());
+        let unit = match &rest[..unit_len] {
+            "ns" => 1,
+            "u" | "µ" | "us" => 1_000,
+            "ms" => 1_000_000,
+            "s" => NANOS_PER_SEC,
+            "m" => 60 * NANOS_PER_SEC,
+            "h" => 60 * 60 * NANOS_PER_SEC,
+            "d" => 24 * 60 * 60 * NANOS_PER_SEC,
+            "w" => 7 * 24 * 60 * 60 * NANOS_PER_SEC,
+            _ => return invalid().fail(),
+        };
+        rest = &rest[unit_len..];
+
+        nanos = value
+            .checked_mul(unit)
+            .and_then(|n| nanos.checked_add(n))
+            .context(invalid())?;
+    }
+
+    Ok(if negative { -nanos } else { nanos })
+}
+
+/// The windows of `every` nanoseconds, shifted by `offset` nanoseconds from the epoch, in the
+/// wall clock time of `tz` if set
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    every: i64,
+    offset: i64,
+    tz: Option<Tz>,
+}
+
+impl Window {
+    pub fn new(every: i64, offset: i64, tz: Option<Tz>) -> Self {
+        assert!(every > 0, "windows must be longer than zero");
+        Self { every, offset, tz }
+    }
+
+    /// Parses the arguments of `date_bin`, where an empty offset or time zone means none
+    pub fn parse(every: &str, offset: &str, tz: &str) -> Result<Self> {
+        let every_nanos = parse_duration(every)?;
+        ensure!(every_nanos > 0, EmptyWindow { every });
+
+        let offset = match offset.trim() {
+            "" => 0,
+            offset => parse_duration(offset)?,
+        };
+        let tz = match tz.trim() {
+            "" => None,
+            tz => Some(tz.parse().ok().context(UnknownTimeZone { tz })?),
+        };
+
+        Ok(Self::new(every_nanos, offset, tz))
+    }
+
+    /// Returns the start of the window `timestamp` falls in, both in nanoseconds since the
+    /// epoch
+    pub fn start(&self, timestamp: i64) -> i64 {
+        match self.tz {
+            None => align(timestamp, self.every, self.offset),
+            Some(tz) => self.start_in(tz, timestamp),
+        }
+    }
+
+    /// Returns the start of the window after the one starting at `start`. In a time zone, the
+    /// window may be shorter or longer than `every` across daylight saving time changes.
+    pub fn next(&self, start: i64) -> i64 {
+        match self.tz {
+            None => start.saturating_add(self.every),
+            Some(_) => self.start(start.saturating_add(self.every + self.every / 2)),
+        }
+    }
+
+    fn start_in(&self, tz: Tz, timestamp: i64) -> i64 {
+        let local = Utc
+            .timestamp_nanos(timestamp)
+            .with_timezone(&tz)
+            .naive_local()
+            .timestamp_nanos();
+        let mut start = align(local, self.every, self.offset);
+
+        loop {
+            let naive = NaiveDateTime::from_timestamp(
+                start.div_euclid(NANOS_PER_SEC),
+                start.rem_euclid(NANOS_PER_SEC) as u32,
+            );
+            match tz.from_local_datetime(&naive) {
+                LocalResult::Single(start) => return start.timestamp_nanos(),
+                // the start is in the hour repeated when the clocks go back: it is the later
+                // instant if the timestamp is past it
+                LocalResult::Ambiguous(earliest, latest) => {
+                    let latest = latest.timestamp_nanos();
+                    return if latest <= timestamp {
+                        latest
+                    } else {
+                        earliest.timestamp_nanos()
+                    };
+                }
+                // the start was skipped when the clocks went forward, so the window starts
+                // once they did
+                LocalResult::None => start += 60 * NANOS_PER_SEC,
+            }
+        }
+    }
+}
+
+/// Returns the largest time no later than `timestamp` that is a whole number of `every` away
+/// from `offset`
+fn align(timestamp: i64, every: i64, offset: i64) -> i64 {
+    let since_offset = i128::from(timestamp) - i128::from(offset);
+    let start = i128::from(timestamp) - since_offset.rem_euclid(i128::from(every));
+    start.max(i128::from(i64::MIN)) as i64
+}
+
+/// Returns the value of an argument that is a constant string, expanded to an array as long
+/// as the other arguments
+fn constant(arg: &ArrayRef) -> &str {
+    let strings = arg
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .expect("date_bin arguments are cast to strings");
+    if strings.is_null(0) {
+        ""
+    } else {
+        strings.value(0)
+    }
+}
+
+/// Returns the `date_bin(time, every, offset, tz)` SQL function, which returns the start of
+/// the window each time falls in, such as `date_bin(time, '1d', '8h', 'Europe/Paris')`. The
+/// offset and time zone may be empty strings.
+pub fn date_bin_udf() -> ScalarUDF {
+    create_udf(
+        "date_bin",
+        vec![
+            DataType::Int64,
+            DataType::Utf8,
+            DataType::Utf8,
+            DataType::Utf8,
+        ],
+        Arc::new(DataType::Int64),
+        Arc::new(|args: &[ArrayRef]| {
+            let times = args[0]
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .expect("times are cast to integers");
+            if times.is_empty() {
+                return Ok(Arc::new(Int64Array::from(Vec::<i64>::new())) as ArrayRef);
+            }
+
+            let window = Window::parse(constant(&args[1]), constant(&args[2]), constant(&args[3]))
+                .map_err(|e| DataFusionError::Execution(e.to_string()))?;
+
+            let starts = (0..times.len())
+                .map(|i| {
+                    if times.is_null(i) {
+                        None
+                    } else {
+                        Some(window.start(times.value(i)))
+                    }
+                })
+                .collect::<Vec<_>>();
+            Ok(Arc::new(Int64Array::from(starts)) as ArrayRef)
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    fn nanos(rfc3339: &str) -> i64 {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .timestamp_nanos()
+    }
+
+    fn start(window: &Window, rfc3339: &str) -> String {
+        Utc.timestamp_nanos(window.start(nanos(rfc3339)))
+            .to_rfc3339()
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("10ns").unwrap(), 10);
+        assert_eq!(parse_duration("1h30m").unwrap(), 90 * 60 * NANOS_PER_SEC);
+        assert_eq!(parse_duration("-15m").unwrap(), -15 * 60 * NANOS_PER_SEC);
+        assert_eq!(
+            parse_duration("2w").unwrap(),
+            14 * 24 * 60 * 60 * NANOS_PER_SEC
+        );
+        assert_eq!(parse_duration("5us").unwrap(), 5_000);
+
+        for invalid in &["", "-", "10", "h", "1y", "1.5h", "99999999999999999999d"] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn fixed_windows() {
+        let window = Window::parse("1h", "", "").unwrap();
+        assert_eq!(
+            start(&window, "2020-11-01T05:45:00Z"),
+            "2020-11-01T05:00:00+00:00"
+        );
+
+        // InfluxQL's GROUP BY time(1d, 8h)
+        let window = Window::parse("1d", "8h", "").unwrap();
+        assert_eq!(
+            start(&window, "2020-11-01T05:45:00Z"),
+            "2020-10-31T08:00:00+00:00"
+        );
+        assert_eq!(
+            start(&window, "2020-11-01T08:00:00Z"),
+            "2020-11-01T08:00:00+00:00"
+        );
+
+        // before the epoch
+        let window = Window::parse("1d", "", "").unwrap();
+        assert_eq!(
+            start(&window, "1969-12-31T23:00:00Z"),
+            "1969-12-31T00:00:00+00:00"
+        );
+
+        assert!(matches!(
+            Window::parse("0s", "", ""),
+            Err(Error::EmptyWindow { .. })
+        ));
+        assert!(matches!(
+            Window::parse("1d", "", "Mars/Olympus_Mons"),
+            Err(Error::UnknownTimeZone { .. })
+        ));
+    }
+
+    #[test]
+    fn day_windows_across_dst() {
+        let window = Window::parse("1d", "", "America/New_York").unwrap();
+
+        // local midnight is 04:00 UTC in daylight saving time
+        assert_eq!(
+            start(&window, "2020-10-31T12:00:00Z"),
+            "2020-10-31T04:00:00+00:00"
+        );
+        // the clocks go back on 2020-11-01, which is 25 hours long
+        assert_eq!(
+            start(&window, "2020-11-02T04:30:00Z"),
+            "2020-11-01T04:00:00+00:00"
+        );
+        assert_eq!(
+            start(&window, "2020-11-02T05:00:00Z"),
+            "2020-11-02T05:00:00+00:00"
+        );
+        // and forward on 2021-03-14, which is 23 hours long
+        assert_eq!(
+            start(&window, "2021-03-15T03:30:00Z"),
+            "2021-03-14T05:00:00+00:00"
+        );
+        assert_eq!(
+            start(&window, "2021-03-15T04:00:00Z"),
+            "2021-03-15T04:00:00+00:00"
+        );
+
+        // offsets are in wall clock time too
+        let window = Window::parse("1d", "8h", "America/New_York").unwrap();
+        assert_eq!(
+            start(&window, "2020-11-02T12:00:00Z"),
+            "2020-11-01T13:00:00+00:00"
+        );
+        assert_eq!(
+            start(&window, "2020-11-02T13:00:00Z"),
+            "2020-11-02T13:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn hour_windows_across_dst() {
+        let window = Window::parse("1h", "", "America/New_York").unwrap();
+
+        // 01:00 happens twice on 2020-11-01, at 05:00 and 06:00 UTC
+        assert_eq!(
+            start(&window, "2020-11-01T05:30:00Z"),
+            "2020-11-01T05:00:00+00:00"
+        );
+        assert_eq!(
+            start(&window, "2020-11-01T06:30:00Z"),
+            "2020-11-01T06:00:00+00:00"
+        );
+
+        // 02:00 is skipped on 2021-03-14, 03:00 EDT being 07:00 UTC
+        assert_eq!(
+            start(&window, "2021-03-14T07:30:00Z"),
+            "2021-03-14T07:00:00+00:00"
+        );
+
+        // zones with half hour offsets
+        let window = Window::parse("1h", "", "Asia/Kolkata").unwrap();
+        assert_eq!(
+            start(&window, "2020-11-01T05:45:00Z"),
+            "2020-11-01T05:30:00+00:00"
+        );
+    }
+
+    #[test]
+    fn next_windows() {
+        let window = Window::parse("1d", "", "America/New_York").unwrap();
+        let days: Vec<_> = std::iter::successors(Some(nanos("2020-10-31T04:00:00Z")), |&start| {
+            Some(window.next(start))
+        })
+        .take(3)
+        .map(|start| Utc.timestamp_nanos(start).to_rfc3339())
+        .collect();
+        assert_eq!(
+            days,
+            vec![
+                "2020-10-31T04:00:00+00:00",
+                "2020-11-01T04:00:00+00:00",
+                "2020-11-02T05:00:00+00:00"
+            ]
+        );
+
+        let window = Window::parse("1h", "", "").unwrap();
+        assert_eq!(window.next(0), 3_600 * NANOS_PER_SEC);
+    }
+
+    #[test]
+    fn gap_at_midnight() {
+        // Sao Paulo skipped from 00:00 to 01:00 on 2018-11-04, so the day starts at 01:00
+        let window = Window::parse("1d", "", "America/Sao_Paulo").unwrap();
+        assert_eq!(
+            start(&window, "2018-11-04T12:00:00Z"),
+            "2018-11-04T03:00:00+00:00"
+        );
+    }
+}
@@ -9,6 +9,7 @@
 use std::{env, f64, sync::Arc};
 pub use tempfile;
 
+pub mod server_fixture;
 pub mod tracing;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
@@ -49,5 +50,7 @@ pub fn str_pair_vec_to_vec(str_vec: &[(&str, &str)]) -> Vec<(Arc<String>, Arc<St
 
 pub fn enable_logging() {
     std::env::set_var("RUST_LOG", "debug");
-    env_logger::init();
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
 }
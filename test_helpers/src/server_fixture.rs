@@ -0,0 +1,275 @@
+//! A fixture that runs the `influxdb_iox` server in a separate process for end-to-end tests.
+//!
+//! Each fixture listens on its own random ports and stores its data in its own temporary
+//! directory, so tests using fixtures can run in parallel. The server binary must have been
+//! built, which `cargo test` only does for the tests of the `influxdb_iox` crate itself: run
+//! `cargo build` first when using the fixture from the tests of other crates.
+//!
+//! ```no_run
+//! # async fn example() -> test_helpers::Result {
+//! use test_helpers::server_fixture::{Scenario, ServerFixture};
+//!
+//! let fixture = ServerFixture::create().await?;
+//! Scenario::new("mydb")
+//!     .lines(vec!["cpu,host=a usage=0.5 10", "cpu,host=b usage=0.75 20"])
+//!     .provision(&fixture)
+//!     .await?;
+//!
+//! let batches = fixture
+//!     .query_client()
+//!     .await?
+//!     .query("mydb", "select * from cpu")
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    convert::TryFrom,
+    net::{SocketAddr, TcpListener},
+    process::{Child, Command},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use assert_cmd::prelude::*;
+use data_types::{database_rules::DatabaseRules, entry::lines_to_entry};
+use influxdb_iox_client::{
+    generated_types::management, Builder, Connection, ManagementClient, OperationsClient,
+    QueryClient, WriteClient,
+};
+use influxdb_line_protocol::parse_lines;
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+
+use crate::Result;
+
+/// The writer id the server of a fixture is started with
+pub const WRITER_ID: u32 = 1;
+
+/// How long to wait for a server to start listening before giving up
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check whether a starting server listens yet
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The `influxdb_iox` server, running in a separate process. The process is killed when the
+/// fixture is dropped.
+#[derive(Debug)]
+pub struct ServerFixture {
+    process: Child,
+    http_addr: SocketAddr,
+    grpc_addr: SocketAddr,
+    /// The sequence number of the next entry `Scenario`s write
+    sequence: AtomicU64,
+
+    // The temporary directory **must** be last so that it is
+    // dropped after the server process is killed.
+    dir: TempDir,
+}
+
+impl ServerFixture {
+    /// Starts a server that allows anonymous requests, and waits until it is ready
+    pub async fn create() -> Result<Self> {
+        let dir = crate::tmp_dir()?;
+        let http_addr = unused_addr()?;
+        let grpc_addr = unused_addr()?;
+
+        let process = spawn(&dir, http_addr, grpc_addr)?;
+        let fixture = Self {
+            process,
+            http_addr,
+            grpc_addr,
+            sequence: AtomicU64::new(1),
+            dir,
+        };
+        fixture.wait_until_ready().await?;
+        Ok(fixture)
+    }
+
+    /// Kills the server and starts it again on the same ports and data directory, for example
+    /// to test that data is recovered from the WAL
+    pub async fn restart(&mut self) -> Result<()> {
+        self.process.kill()?;
+        self.process.wait()?;
+        self.process = spawn(&self.dir, self.http_addr, self.grpc_addr)?;
+        self.wait_until_ready().await
+    }
+
+    /// The base URL of the HTTP API, such as `http://127.0.0.1:34567`
+    pub fn http_base(&self) -> String {
+        format!("http://{}", self.http_addr)
+    }
+
+    /// The base URL of the gRPC API
+    pub fn grpc_base(&self) -> String {
+        format!("http://{}", self.grpc_addr)
+    }
+
+    /// The directory the server stores its data in
+    pub fn dir(&self) -> &TempDir {
+        &self.dir
+    }
+
+    /// Opens a connection to the gRPC API, which the typed clients share
+    pub async fn connection(&self) -> Result<Connection> {
+        Ok(Builder::default().build(self.grpc_base()).await?)
+    }
+
+    /// A client for the management API
+    pub async fn management_client(&self) -> Result<ManagementClient> {
+        Ok(ManagementClient::new(self.connection().await?))
+    }
+
+    /// A client for the operations API
+    pub async fn operations_client(&self) -> Result<OperationsClient> {
+        Ok(OperationsClient::new(self.connection().await?))
+    }
+
+    /// A client for the query API
+    pub async fn query_client(&self) -> Result<QueryClient> {
+        Ok(QueryClient::new(self.connection().await?))
+    }
+
+    /// A client for the write API
+    pub async fn write_client(&self) -> Result<WriteClient> {
+        Ok(WriteClient::new(self.connection().await?))
+    }
+
+    /// Waits until both the HTTP and the gRPC API accept connections
+    async fn wait_until_ready(&self) -> Result<()> {
+        let listening = async {
+            for addr in &[self.http_addr, self.grpc_addr] {
+                while TcpStream::connect(*addr).await.is_err() {
+                    tokio::time::delay_for(POLL_INTERVAL).await;
+                }
+            }
+        };
+
+        tokio::time::timeout(STARTUP_TIMEOUT, listening)
+            .await
+            .map_err(|_| format!("server did not start within {:?}", STARTUP_TIMEOUT).into())
+    }
+}
+
+impl Drop for ServerFixture {
+    fn drop(&mut self) {
+        // the process may have exited already
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+fn spawn(dir: &TempDir, http_addr: SocketAddr, grpc_addr: SocketAddr) -> Result<Child> {
+    Ok(Command::cargo_bin("influxdb_iox")?
+        // Can enable for debugging
+        //.arg("-vv")
+        .arg("--allow-anonymous")
+        .env("INFLUXDB_IOX_DB_DIR", dir.path())
+        .env("INFLUXDB_IOX_BIND_ADDR", http_addr.to_string())
+        .env("INFLUXDB_IOX_GRPC_BIND_ADDR", grpc_addr.to_string())
+        .env("INFLUXDB_IOX_ID", WRITER_ID.to_string())
+        .spawn()?)
+}
+
+/// Returns a local address no other process listens on right now
+fn unused_addr() -> Result<SocketAddr> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?)
+}
+
+/// A database with data, to provision a `ServerFixture` with
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    rules: management::DatabaseRules,
+    lines: Vec<String>,
+}
+
+impl Scenario {
+    /// A database named `db_name` that stores writes locally and answers queries from them
+    pub fn new(db_name: impl Into<String>) -> Self {
+        Self {
+            rules: management::DatabaseRules {
+                name: db_name.into(),
+                store_locally: true,
+                query_local: true,
+                ..Default::default()
+            },
+            lines: vec![],
+        }
+    }
+
+    /// Creates the database with `rules` instead. The name of the database is kept.
+    pub fn rules(self, rules: management::DatabaseRules) -> Self {
+        let name = self.rules.name;
+        Self {
+            rules: management::DatabaseRules { name, ..rules },
+            ..self
+        }
+    }
+
+    /// Writes `lines` of line protocol to the database once it is created
+    pub fn lines<I, S>(mut self, lines: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.lines.extend(lines.into_iter().map(Into::into));
+        self
+    }
+
+    /// The name of the database
+    pub fn db_name(&self) -> &str {
+        &self.rules.name
+    }
+
+    /// Creates the database on the server of `fixture` and writes the lines to it, as one
+    /// entry through the write API
+    pub async fn provision(&self, fixture: &ServerFixture) -> Result<()> {
+        let connection = fixture.connection().await?;
+        ManagementClient::new(connection.clone())
+            .create_database(self.rules.clone())
+            .await?;
+
+        if self.lines.is_empty() {
+            return Ok(());
+        }
+        let lp = self.lines.join("\n");
+        let lines = parse_lines(&lp).collect::<Result<Vec<_>, _>>()?;
+        let rules = DatabaseRules::try_from(self.rules.clone())?;
+        let sequence = fixture.sequence.fetch_add(1, Ordering::SeqCst);
+        let entry = lines_to_entry(WRITER_ID, sequence, &lines, &rules)?;
+
+        WriteClient::new(connection)
+            .write_entry(self.db_name(), entry.into_data())
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scenarios() {
+        let scenario = Scenario::new("mydb")
+            .rules(management::DatabaseRules {
+                name: "ignored".to_string(),
+                store_locally: true,
+                ..Default::default()
+            })
+            .lines(vec!["cpu usage=1 10"])
+            .lines(vec!["mem used=2 10".to_string()]);
+
+        assert_eq!(scenario.db_name(), "mydb");
+        assert!(!scenario.rules.query_local);
+        assert_eq!(scenario.lines, vec!["cpu usage=1 10", "mem used=2 10"]);
+    }
+
+    #[test]
+    fn unused_addrs() {
+        let addr = unused_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_ne!(addr.port(), 0);
+    }
+}
@@ -1,21 +1,10 @@
-// The test in this file runs the server in a separate thread and makes HTTP requests as a smoke
-// test for the integration of the whole system.
+// The tests in this file run the server in a separate process and make HTTP and gRPC requests
+// as smoke tests for the integration of the whole system.
 //
-// As written, only one test of this style can run at a time. Add more data to the existing test to
-// test more scenarios rather than adding more tests in the same style.
-//
-// Or, change the way this test behaves to create isolated instances by:
-//
-// - Finding an unused port for the server to run on and using that port in the URL
-// - Creating a temporary directory for an isolated database path
-//
-// Or, change the tests to use one server and isolate through `org_id` by:
-//
-// - Starting one server before all the relevant tests are run
-// - Creating a unique org_id per test
-// - Stopping the server after all relevant tests are run
+// Each test starts its own `ServerFixture`, which listens on random ports and stores its data in
+// a temporary directory, so the tests are isolated from each other and can run in parallel.
 
-use assert_cmd::prelude::*;
+use arrow_deps::arrow::util::pretty::pretty_format_batches;
 use futures::prelude::*;
 use generated_types::{
     node::{Comparison, Value},
@@ -29,16 +18,11 @@ use generated_types::{
 use prost::Message;
 use std::convert::TryInto;
 use std::fs;
-use std::process::{Child, Command};
 use std::str;
-use std::time::{Duration, SystemTime};
+use std::time::SystemTime;
 use std::u32;
-use tempfile::TempDir;
-use test_helpers::*;
+use test_helpers::server_fixture::{Scenario, ServerFixture};
 
-const HTTP_BASE: &str = "http://localhost:8080";
-const API_BASE: &str = "http://localhost:8080/api/v2";
-const GRPC_URL_BASE: &str = "http://localhost:8082/";
 const TOKEN: &str = "InfluxDB IOx doesn't have authentication yet";
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
@@ -46,12 +30,13 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 
 async fn read_data_as_sql(
     client: &reqwest::Client,
+    server: &ServerFixture,
     path: &str,
     org_id: &str,
     bucket_id: &str,
     sql_query: &str,
 ) -> Result<Vec<String>> {
-    let url = format!("{}{}", API_BASE, path);
+    let url = format!("{}/api/v2{}", server.http_base(), path);
     let lines = client
         .get(&url)
         .query(&[
@@ -85,8 +70,7 @@ async fn write_data(
 
 #[tokio::test]
 async fn read_and_write_data() -> Result<()> {
-    let mut server = TestServer::new()?;
-    server.wait_until_ready().await;
+    let mut server = ServerFixture::create().await?;
 
     let org_id_str = "0000111100001111";
     let org_id = u64::from_str_radix(org_id_str, 16).unwrap();
@@ -94,7 +78,7 @@ async fn read_and_write_data() -> Result<()> {
     let bucket_id = u64::from_str_radix(bucket_id_str, 16).unwrap();
 
     let client = reqwest::Client::new();
-    let client2 = influxdb2_client::Client::new(HTTP_BASE, TOKEN);
+    let client2 = influxdb2_client::Client::new(server.http_base(), TOKEN);
 
     let start_time = SystemTime::now();
     let ns_since_epoch: i64 = start_time
@@ -176,6 +160,7 @@ async fn read_and_write_data() -> Result<()> {
 
     let text = read_data_as_sql(
         &client,
+        &server,
         "/read",
         org_id_str,
         bucket_id_str,
@@ -189,17 +174,17 @@ async fn read_and_write_data() -> Result<()> {
     );
 
     // Make an invalid organization WAL dir to test that the server ignores it instead of crashing
-    let invalid_org_dir = server.dir.path().join("not-an-org-id");
+    let invalid_org_dir = server.dir().path().join("not-an-org-id");
     fs::create_dir(invalid_org_dir)?;
 
     // Test the WAL by restarting the server
-    server.restart()?;
-    server.wait_until_ready().await;
+    server.restart().await?;
 
     // Then check the entries are restored from the WAL
 
     let text = read_data_as_sql(
         &client,
+        &server,
         "/read",
         org_id_str,
         bucket_id_str,
@@ -208,7 +193,7 @@ async fn read_and_write_data() -> Result<()> {
     .await?;
     assert_eq!(text, expected_read_data);
 
-    let mut storage_client = StorageClient::connect(GRPC_URL_BASE).await?;
+    let mut storage_client = StorageClient::connect(server.grpc_base()).await?;
 
     // Validate that capabilities rpc endpoint is hooked up
     let capabilities_response = storage_client.capabilities(()).await?;
@@ -453,6 +438,58 @@ async fn read_and_write_data() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn management_and_query_apis() -> Result<()> {
+    let server = ServerFixture::create().await?;
+    let scenario = Scenario::new("company_sensors").lines(vec![
+        "cpu,host=a usage=0.5 10",
+        "cpu,host=b usage=0.75 20",
+        "mem,host=a used=2 10",
+    ]);
+    scenario.provision(&server).await?;
+
+    let mut management = server.management_client().await?;
+    assert_eq!(management.list_databases().await?, vec!["company_sensors"]);
+    let rules = management.get_database("company_sensors").await?;
+    assert!(rules.store_locally);
+
+    let chunks = management.list_chunks("company_sensors").await?;
+    let rows: u64 = chunks.iter().map(|chunk| chunk.row_count).sum();
+    assert_eq!(rows, 3);
+
+    let batches = server
+        .query_client()
+        .await?
+        .query(
+            "company_sensors",
+            "select host, usage from cpu order by host",
+        )
+        .await?;
+    let expected = vec![
+        "+------+-------+",
+        "| host | usage |",
+        "+------+-------+",
+        "| a    | 0.5   |",
+        "| b    | 0.75  |",
+        "+------+-------+",
+    ];
+    assert_eq!(pretty_format_batches(&batches)?.trim(), expected.join("\n"));
+
+    let err = server
+        .query_client()
+        .await?
+        .query("unknown", "select * from cpu")
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, influxdb_iox_client::Error::NotFound { .. }),
+        "{}",
+        err
+    );
+
+    Ok(())
+}
+
 // Don't make a separate #test function so that we can reuse the same
 // server process
 async fn test_http_error_messages(client: &influxdb2_client::Client) -> Result<()> {
@@ -492,103 +529,6 @@ fn substitute_nanos(ns_since_epoch: i64, lines: &[&str]) -> Vec<String> {
         .collect()
 }
 
-struct TestServer {
-    server_process: Child,
-
-    // The temporary directory **must** be last so that it is
-    // dropped after the database closes.
-    #[allow(dead_code)]
-    dir: TempDir,
-}
-
-impl TestServer {
-    fn new() -> Result<Self> {
-        let _ = dotenv::dotenv(); // load .env file if present
-
-        let dir = test_helpers::tmp_dir()?;
-
-        let server_process = Command::cargo_bin("influxdb_iox")?
-            // Can enable for debbugging
-            //.arg("-vv")
-            .env("INFLUXDB_IOX_DB_DIR", dir.path())
-            .spawn()?;
-
-        Ok(Self {
-            dir,
-            server_process,
-        })
-    }
-
-    fn restart(&mut self) -> Result<()> {
-        self.server_process.kill()?;
-        self.server_process.wait()?;
-        self.server_process = Command::cargo_bin("influxdb_iox")?
-            // Can enable for debbugging
-            //.arg("-vv")
-            .env("INFLUXDB_IOX_DB_DIR", self.dir.path())
-            .spawn()?;
-        Ok(())
-    }
-
-    async fn wait_until_ready(&self) {
-        // Poll the RPC and HTTP servers separately as they listen on
-        // different ports but both need to be up for the test to run
-        let try_grpc_connect = async {
-            let mut interval = tokio::time::interval(Duration::from_millis(500));
-            loop {
-                match StorageClient::connect(GRPC_URL_BASE).await {
-                    Ok(storage_client) => {
-                        println!(
-                            "Successfully connected storage_client: {:?}",
-                            storage_client
-                        );
-                        return;
-                    }
-                    Err(e) => {
-                        println!("Waiting for gRPC server to be up: {}", e);
-                    }
-                }
-                interval.tick().await;
-            }
-        };
-
-        let try_http_connect = async {
-            let client = reqwest::Client::new();
-            let url = format!("{}/ping", HTTP_BASE);
-            let mut interval = tokio::time::interval(Duration::from_millis(500));
-            loop {
-                match client.get(&url).send().await {
-                    Ok(resp) => {
-                        println!("Successfully got a response from HTTP: {:?}", resp);
-                        return;
-                    }
-                    Err(e) => {
-                        println!("Waiting for HTTP server to be up: {}", e);
-                    }
-                }
-                interval.tick().await;
-            }
-        };
-
-        let pair = future::join(try_http_connect, try_grpc_connect);
-
-        let capped_check = tokio::time::timeout(Duration::from_secs(3), pair);
-
-        match capped_check.await {
-            Ok(_) => println!("Server is up correctly"),
-            Err(e) => println!("WARNING: server was not ready: {}", e),
-        }
-    }
-}
-
-impl Drop for TestServer {
-    fn drop(&mut self) {
-        self.server_process
-            .kill()
-            .expect("Should have been able to kill the test server");
-    }
-}
-
 fn dump_data_frames(frames: &[Data]) -> Vec<String> {
     frames.iter().map(|f| dump_data(f)).collect()
 }
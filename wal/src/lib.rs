@@ -17,6 +17,7 @@
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crc32fast::Hasher;
+use metrics::Counter;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use snafu::{ensure, ResultExt, Snafu};
@@ -27,6 +28,7 @@ use std::{
     io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
     iter, mem, num,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 /// WAL Writer and related utilties
@@ -39,6 +41,14 @@ pub struct Error(InternalError);
 /// SequenceNumber is a u64 monotonically increasing number for each WAL entry
 pub type SequenceNumber = u64;
 
+static WAL_BYTES_WRITTEN: Lazy<Arc<Counter>> = Lazy::new(|| {
+    metrics::registry().counter(
+        "wal_bytes_written_total",
+        "Bytes appended to WAL segment files, including entry headers",
+        &[],
+    )
+});
+
 #[derive(Debug, Snafu)]
 enum InternalError {
     UnableToReadFileMetadata {
@@ -192,6 +202,27 @@ impl WalBuilder {
         Loader::load(self.file_locator())
     }
 
+    /// Get the sequence numbers of the first and the last entry in this WAL that have been
+    /// persisted to disk, or `None` if it has no entries. Only the headers of the entries are
+    /// read.
+    ///
+    /// # Asynchronous considerations
+    ///
+    /// This method performs blocking IO and care should be taken when using
+    /// it in an asynchronous context.
+    pub fn sequence_number_range(&self) -> Result<Option<(u64, u64)>> {
+        let mut headers = Loader::headers(&self.clone().file_locator())?;
+        let first = match headers.next().transpose()? {
+            Some(first) => first.sequence_number,
+            None => return Ok(None),
+        };
+        let last = headers
+            .last()
+            .transpose()?
+            .map_or(first, |h| h.sequence_number);
+        Ok(Some((first, last)))
+    }
+
     fn file_locator(self) -> FileLocator {
         FileLocator {
             root: self.root,
@@ -282,7 +313,9 @@ impl Wal {
         h.write(&mut f)?;
         f.write_all(&payload.data).context(UnableToWriteData)?;
 
-        self.total_size += Header::LEN + payload.len as u64;
+        let bytes_written = Header::LEN + payload.len as u64;
+        self.total_size += bytes_written;
+        WAL_BYTES_WRITTEN.add(bytes_written);
         self.active_file = Some(f);
         self.sequence_number += 1;
 
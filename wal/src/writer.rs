@@ -43,10 +43,11 @@ pub struct WalDetails {
     pub write_tx: mpsc::Sender<WalWrite>,
 }
 
+/// A request to the WAL task: a write to append and sync, or only a sync if there is no payload
 #[derive(Debug)]
 pub struct WalWrite {
-    payload: WritePayload,
-    notify_tx: mpsc::Sender<Result<SequenceNumber, WalError>>,
+    payload: Option<WritePayload>,
+    notify_tx: mpsc::Sender<Result<Option<SequenceNumber>, WalError>>,
 }
 
 impl WalDetails {
@@ -63,7 +64,16 @@ impl WalDetails {
 
     pub async fn write_and_sync(&self, data: Vec<u8>) -> Result<()> {
         let payload = WritePayload::new(data).context(UnderlyingWalError {})?;
+        self.send(Some(payload)).await
+    }
+
+    /// Waits for the writes sent to the WAL before this call to be appended, and syncs the
+    /// WAL's files to disk
+    pub async fn sync(&self) -> Result<()> {
+        self.send(None).await
+    }
 
+    async fn send(&self, payload: Option<WritePayload>) -> Result<()> {
         let (notify_tx, mut notify_rx) = mpsc::channel(1);
 
         let write = WalWrite { payload, notify_tx };
@@ -126,7 +136,11 @@ pub async fn start_wal_sync_task(wal_builder: WalBuilder) -> Result<WalDetails>
                         let payload = write.payload;
                         let mut tx = write.notify_tx;
 
-                        let result = wal.append(payload).and_then(|seq| {
+                        let result = match payload {
+                            Some(payload) => wal.append(payload).map(Some),
+                            None => Ok(None),
+                        }
+                        .and_then(|seq| {
                             wal.sync_all()?;
                             Ok(seq)
                         });
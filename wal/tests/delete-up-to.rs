@@ -12,6 +12,7 @@ fn delete_up_to() -> Result {
     // Set the file rollover size limit low to test interaction with file rollover
     let builder = WalBuilder::new(dir.as_ref()).file_rollover_size(100);
     let mut wal = builder.clone().wal()?;
+    assert_eq!(builder.sequence_number_range()?, None);
 
     create_and_sync_batch!(
         wal,
@@ -62,6 +63,7 @@ fn delete_up_to() -> Result {
         b"one entry that puts the existing file over the limit"
     );
     assert_entry!(wal_entries[4], 4, b"another entry");
+    assert_eq!(builder.sequence_number_range()?, Some((0, 4)));
 
     // Not including 3!
     wal.delete_up_to_entry(3)?;
@@ -90,6 +92,7 @@ fn delete_up_to() -> Result {
     );
     assert_entry!(wal_entries[2], 4, b"another entry");
     assert_entry!(wal_entries[3], 5, b"entry after deletion");
+    assert_eq!(builder.sequence_number_range()?, Some((2, 5)));
 
     Ok(())
 }
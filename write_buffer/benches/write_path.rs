@@ -0,0 +1,183 @@
+//! Benchmarks of the stages of the write path, for workloads of different shapes, so that a
+//! regression in any stage shows up on its own:
+//!
+//! * `parse`: parsing the line protocol
+//! * `entry_build`: splitting the lines into the write entries of their partitions
+//! * `buffer_insert`: inserting the entries into the mutable buffer of a new database
+//! * `wal_append`: compressing an entry, appending it to a new WAL and syncing it
+//! * `end_to_end`: all of the above, as done by a write to a database with a WAL
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use data_types::{
+    data::{lines_to_replicated_write, split_lines_into_write_entry_partitions},
+    database_rules::DatabaseRules,
+};
+use influxdb_line_protocol::{parse_lines, ParsedLine};
+use storage::Database;
+use tokio::runtime::Runtime;
+use wal::{WalBuilder, WritePayload};
+use write_buffer::{partition_key, Db};
+
+const LINES: usize = 1000;
+
+const DAY_NS: i64 = 1_600_000_000_000_000_000;
+
+/// A workload: the line protocol of a write
+struct Workload {
+    name: &'static str,
+    lp: String,
+}
+
+/// Writes of `LINES` lines of the shapes the write path sees
+fn workloads() -> Vec<Workload> {
+    vec![
+        Workload {
+            name: "many_measurements",
+            lp: lines(|i| format!("m{},host=server{} usage=0.5,count={}i", i % 200, i % 10, i)),
+        },
+        Workload {
+            name: "wide_schema",
+            lp: lines(|i| {
+                let tags: Vec<_> = (0..10).map(|t| format!("tag{}=value{}", t, t)).collect();
+                let fields: Vec<_> = (0..50).map(|f| format!("field{}={}.5", f, i)).collect();
+                format!("wide,{} {}", tags.join(","), fields.join(","))
+            }),
+        },
+        Workload {
+            name: "high_cardinality",
+            lp: lines(|i| {
+                format!(
+                    "requests,host=host{},path=/api/v2/item/{},status=200 latency={}i",
+                    i % 100,
+                    i,
+                    i % 250
+                )
+            }),
+        },
+    ]
+}
+
+/// Returns `LINES` lines of line protocol, each made by `line` without its timestamp, all in
+/// the same partition
+fn lines(line: impl Fn(usize) -> String) -> String {
+    (0..LINES)
+        .map(|i| format!("{} {}\n", line(i), DAY_NS + i as i64))
+        .collect()
+}
+
+fn parse(lp: &str) -> Vec<ParsedLine<'_>> {
+    parse_lines(lp).collect::<Result<_, _>>().unwrap()
+}
+
+fn benchmark_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_path_parse");
+    group.throughput(Throughput::Elements(LINES as u64));
+    for workload in workloads() {
+        group.bench_function(workload.name, |b| {
+            b.iter(|| {
+                let lines = parse(&workload.lp);
+                assert_eq!(lines.len(), LINES);
+            })
+        });
+    }
+    group.finish();
+}
+
+fn benchmark_entry_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_path_entry_build");
+    group.throughput(Throughput::Elements(LINES as u64));
+    for workload in workloads() {
+        let lines = parse(&workload.lp);
+        group.bench_function(workload.name, |b| {
+            b.iter(|| split_lines_into_write_entry_partitions(partition_key, &lines))
+        });
+    }
+    group.finish();
+}
+
+fn benchmark_buffer_insert(c: &mut Criterion) {
+    let mut rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("write_path_buffer_insert");
+    group.throughput(Throughput::Elements(LINES as u64));
+    for workload in workloads() {
+        let lines = parse(&workload.lp);
+        let write = lines_to_replicated_write(1, 1, &lines, &DatabaseRules::default());
+        group.bench_function(workload.name, |b| {
+            // the buffer is returned to be dropped outside of the measurement
+            b.iter_batched(
+                || Db::new("mydb"),
+                |db| {
+                    rt.block_on(db.store_replicated_write(&write)).unwrap();
+                    db
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn benchmark_wal_append(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_path_wal_append");
+    group.throughput(Throughput::Elements(LINES as u64));
+    for workload in workloads() {
+        let lines = parse(&workload.lp);
+        let entry = split_lines_into_write_entry_partitions(partition_key, &lines);
+        group.bench_function(workload.name, |b| {
+            // each iteration appends to a new WAL, so that the WALs don't fill the disk
+            b.iter_batched(
+                || {
+                    let dir = test_helpers::tmp_dir().unwrap();
+                    let wal = WalBuilder::new(dir.path()).wal().unwrap();
+                    (dir, wal, entry.clone())
+                },
+                |(dir, mut wal, entry)| {
+                    wal.append(WritePayload::new(entry).unwrap()).unwrap();
+                    wal.sync_all().unwrap();
+                    (dir, wal)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn benchmark_end_to_end(c: &mut Criterion) {
+    let mut rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("write_path_end_to_end");
+    group.throughput(Throughput::Elements(LINES as u64));
+    for workload in workloads() {
+        group.bench_function(workload.name, |b| {
+            // the database and its WAL are created outside of the measurement
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::default();
+                for _ in 0..iters {
+                    let dir = test_helpers::tmp_dir().unwrap();
+                    let db = rt
+                        .block_on(Db::try_with_wal("mydb", &mut dir.path().to_owned()))
+                        .unwrap();
+
+                    let start = Instant::now();
+                    rt.block_on(db.write_lines(&parse(&workload.lp))).unwrap();
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    benchmark_parse,
+    benchmark_entry_build,
+    benchmark_buffer_insert,
+    benchmark_wal_append,
+    benchmark_end_to_end,
+);
+
+criterion_main!(benches);
@@ -0,0 +1,660 @@
+//! This module contains ASOF joins, which match each row of a table with the row of another
+//! table that has the same values of some columns and the nearest timestamp, to align series
+//! sampled at different rates.
+//!
+//! `a ASOF JOIN b ON a.host = b.host` matches each row of `a` with the latest row of `b` of the
+//! same host at or before its time, and `a ASOF NEAREST JOIN b ON ...` with the row of `b` of
+//! the nearest time, before or after, preferring the earlier row on ties. Rows of `a` without
+//! a match are dropped, unless the join is an `ASOF LEFT JOIN`. The columns of `b` keep their
+//! values, including `time`, and `asof_time` holds the time of the row of `a` each row of `b`
+//! was matched with.
+//!
+//! DataFusion only joins rows with equal values, so an ASOF join is planned as an equi-join
+//! with a table holding, for each distinct key and time of the rows of `a`, the row of `b` it
+//! is matched with.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use arrow_deps::arrow::{
+    array::{
+        Array, ArrayRef, BooleanArray, Float64Array, Int64Array, StringArray, UInt32Array,
+        UInt64Array,
+    },
+    compute::kernels::{cast::cast, take::take},
+    datatypes::{DataType, Field, Schema},
+    error::ArrowError,
+    record_batch::RecordBatch,
+};
+use data_types::TIME_COLUMN_NAME;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use sqlparser::{
+    ast::{
+        BinaryOperator, Expr, Ident, JoinConstraint, JoinOperator, ObjectName, SetExpr, Statement,
+        TableAlias, TableFactor,
+    },
+    dialect::GenericDialect,
+    tokenizer::{Token, Tokenizer},
+};
+
+/// The column of the matched rows holding the time of the row they were matched with
+pub const ASOF_TIME_COLUMN: &str = "asof_time";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("ASOF joins are only supported in a single SELECT of tables"))]
+    UnsupportedQuery,
+
+    #[snafu(display("ASOF joins are only supported as inner or left joins"))]
+    UnsupportedJoin,
+
+    #[snafu(display(
+        "ASOF join condition '{}' is not an equality of columns of the joined tables",
+        condition
+    ))]
+    UnsupportedCondition { condition: String },
+
+    #[snafu(display("table {} has no column {} to join on", table, column))]
+    MissingColumn { table: String, column: String },
+
+    #[snafu(display("can't join on column {} of type {:?}", column, data_type))]
+    UnsupportedKeyType { column: String, data_type: DataType },
+
+    #[snafu(display("error aligning the rows of table {}: {}", table, source))]
+    Aligning { table: String, source: ArrowError },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Which row of the right table a row of the left table is matched with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The latest row at or before the time of the row
+    Backward,
+    /// The row of the nearest time, the earlier one on ties
+    Nearest,
+}
+
+/// An ASOF join of a query, planned as a join with the table `aligned_table`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsofJoin {
+    pub direction: Direction,
+    pub left_table: String,
+    pub right_table: String,
+    /// The name of the table of the rows of `right_table` matched with the rows of
+    /// `left_table`
+    pub aligned_table: String,
+    /// The columns of the left and right tables whose values must be equal
+    pub keys: Vec<(String, String)>,
+}
+
+/// Removes the `ASOF` and `NEAREST` keywords from `query`, so that it is plain SQL, and returns
+/// the direction of each of its joins that was an ASOF join, in the order of their `JOIN`
+/// keywords. The query is returned unchanged if it has no ASOF join.
+pub fn strip_keywords(query: &str) -> (String, Vec<Option<Direction>>) {
+    let dialect = GenericDialect {};
+    let tokens = match Tokenizer::new(&dialect, query).tokenize() {
+        Ok(tokens) => tokens,
+        // the parser reports the error
+        Err(_) => return (query.to_string(), vec![]),
+    };
+    let is_word = |token: &Token, word: &str| match token {
+        Token::Word(w) => w.quote_style.is_none() && w.value.eq_ignore_ascii_case(word),
+        _ => false,
+    };
+    let next_word = |from: usize| {
+        tokens[from..]
+            .iter()
+            .position(|token| !matches!(token, Token::Whitespace(_)))
+            .map(|offset| from + offset)
+    };
+
+    let mut stripped = String::with_capacity(query.len());
+    let mut directions = vec![];
+    let mut pending = None;
+    let mut index = 0;
+    while index < tokens.len() {
+        let token = &tokens[index];
+        if is_word(token, "asof") {
+            let mut direction = Direction::Backward;
+            let mut next = next_word(index + 1);
+            if let Some(n) = next.filter(|&n| is_word(&tokens[n], "nearest")) {
+                direction = Direction::Nearest;
+                next = next_word(n + 1);
+            }
+            // `asof` is otherwise an identifier
+            if let Some(n) = next.filter(|&n| {
+                ["join", "inner", "left", "right", "full", "cross"]
+                    .iter()
+                    .any(|word| is_word(&tokens[n], word))
+            }) {
+                pending = Some(direction);
+                index = n;
+                continue;
+            }
+        } else if is_word(token, "join") {
+            directions.push(pending.take());
+        }
+        stripped.push_str(&token.to_string());
+        index += 1;
+    }
+
+    if directions.iter().all(Option::is_none) {
+        (query.to_string(), directions)
+    } else {
+        (stripped, directions)
+    }
+}
+
+/// Rewrites the ASOF joins of the parsed query `statements`, whose joins have `directions` as
+/// returned by `strip_keywords`, into joins with the tables of aligned rows, which are to be
+/// built by `align` for each of the returned joins
+pub fn rewrite(
+    statements: &mut [Statement],
+    directions: &[Option<Direction>],
+) -> Result<Vec<AsofJoin>> {
+    if directions.iter().all(Option::is_none) {
+        return Ok(vec![]);
+    }
+
+    let select = match statements {
+        [Statement::Query(query)] => match &mut query.body {
+            SetExpr::Select(select) => select,
+            _ => return UnsupportedQuery.fail(),
+        },
+        _ => return UnsupportedQuery.fail(),
+    };
+    // the joins of subqueries would shift the joins the directions belong to
+    let joins: usize = select.from.iter().map(|from| from.joins.len()).sum();
+    ensure!(joins == directions.len(), UnsupportedQuery);
+
+    let mut directions = directions.iter();
+    let mut asof_joins = vec![];
+    for from in &mut select.from {
+        let left = relation_names(&from.relation);
+        for join in &mut from.joins {
+            let direction = match directions.next() {
+                Some(Some(direction)) => *direction,
+                _ => continue,
+            };
+            let (left_table, left_name) = left.clone().context(UnsupportedQuery)?;
+            let (right_table, right_name) =
+                relation_names(&join.relation).context(UnsupportedQuery)?;
+
+            let (constraint, outer) = match &join.join_operator {
+                JoinOperator::Inner(constraint) => (constraint, false),
+                JoinOperator::LeftOuter(constraint) => (constraint, true),
+                _ => return UnsupportedJoin.fail(),
+            };
+            let keys = match constraint {
+                JoinConstraint::On(expr) => join_keys(expr, &left_name, &right_name)?,
+                JoinConstraint::Using(columns) => columns
+                    .iter()
+                    .map(|column| (column.value.clone(), column.value.clone()))
+                    .collect(),
+                JoinConstraint::Natural => return UnsupportedJoin.fail(),
+            };
+
+            let condition = keys.iter().fold(
+                equal(&left_name, TIME_COLUMN_NAME, &right_name, ASOF_TIME_COLUMN),
+                |condition, (left_column, right_column)| Expr::BinaryOp {
+                    left: Box::new(condition),
+                    op: BinaryOperator::And,
+                    right: Box::new(equal(&left_name, left_column, &right_name, right_column)),
+                },
+            );
+            join.join_operator = if outer {
+                JoinOperator::LeftOuter(JoinConstraint::On(condition))
+            } else {
+                JoinOperator::Inner(JoinConstraint::On(condition))
+            };
+
+            let aligned_table = format!("{}_asof_{}", right_table, asof_joins.len());
+            if let TableFactor::Table { name, alias, .. } = &mut join.relation {
+                *name = ObjectName(vec![Ident::new(aligned_table.as_str())]);
+                // the columns of the right table are still referred to by its name
+                if alias.is_none() {
+                    *alias = Some(TableAlias {
+                        name: Ident::new(right_name.as_str()),
+                        columns: vec![],
+                    });
+                }
+            }
+
+            asof_joins.push(AsofJoin {
+                direction,
+                left_table,
+                right_table,
+                aligned_table,
+                keys,
+            });
+        }
+    }
+
+    Ok(asof_joins)
+}
+
+/// The name of the table of `relation`, and the name its columns are qualified by
+fn relation_names(relation: &TableFactor) -> Option<(String, String)> {
+    match relation {
+        TableFactor::Table { name, alias, .. } => {
+            let table = name.to_string();
+            let qualifier = alias
+                .as_ref()
+                .map_or_else(|| table.clone(), |alias| alias.name.value.clone());
+            Some((table, qualifier))
+        }
+        _ => None,
+    }
+}
+
+/// `<left>.<left_column> = <right>.<right_column>`
+fn equal(left: &str, left_column: &str, right: &str, right_column: &str) -> Expr {
+    let column = |table: &str, column: &str| {
+        Expr::CompoundIdentifier(vec![Ident::new(table), Ident::new(column)])
+    };
+    Expr::BinaryOp {
+        left: Box::new(column(left, left_column)),
+        op: BinaryOperator::Eq,
+        right: Box::new(column(right, right_column)),
+    }
+}
+
+/// Returns the pairs of columns of the tables qualified by `left` and `right` that the
+/// conjunction of equalities `expr` compares
+fn join_keys(expr: &Expr, left: &str, right: &str) -> Result<Vec<(String, String)>> {
+    let unsupported = || UnsupportedCondition {
+        condition: expr.to_string(),
+    };
+    match expr {
+        Expr::Nested(expr) => join_keys(expr, left, right),
+        Expr::BinaryOp {
+            left: a,
+            op: BinaryOperator::And,
+            right: b,
+        } => {
+            let mut keys = join_keys(a, left, right)?;
+            keys.extend(join_keys(b, left, right)?);
+            Ok(keys)
+        }
+        Expr::BinaryOp {
+            left: a,
+            op: BinaryOperator::Eq,
+            right: b,
+        } => {
+            let a = column_ref(a).context(unsupported())?;
+            let b = column_ref(b).context(unsupported())?;
+            let (a, b) = if a.0 == Some(right) || b.0 == Some(left) {
+                (b, a)
+            } else {
+                (a, b)
+            };
+            ensure!(
+                a.0.map_or(true, |table| table == left) && b.0.map_or(true, |table| table == right),
+                unsupported()
+            );
+            Ok(vec![(a.1.to_string(), b.1.to_string())])
+        }
+        _ => unsupported().fail(),
+    }
+}
+
+/// The table qualifying the column `expr` refers to, if any, and the name of the column
+fn column_ref(expr: &Expr) -> Option<(Option<&str>, &str)> {
+    match expr {
+        Expr::Identifier(column) => Some((None, column.value.as_str())),
+        Expr::CompoundIdentifier(idents) => match idents.as_slice() {
+            [table, column] => Some((Some(table.value.as_str()), column.value.as_str())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A row of a batch of the right table, with its time
+type RowRef = (i64, usize, usize);
+
+/// Builds the table `join.aligned_table` from the rows of the left and right tables: the rows
+/// of `right` matched with the distinct keys and times of the rows of `left`, with the time
+/// they were matched with. Rows with nulls in their keys or time match no row.
+pub fn align(
+    join: &AsofJoin,
+    left: &[RecordBatch],
+    right: &[RecordBatch],
+) -> Result<Vec<RecordBatch>> {
+    let right_schema = match right.first() {
+        Some(batch) => batch.schema(),
+        None => return Ok(vec![]),
+    };
+    let time_type = left
+        .first()
+        .and_then(|batch| {
+            let schema = batch.schema();
+            let index = schema.index_of(TIME_COLUMN_NAME).ok()?;
+            Some(schema.field(index).data_type().clone())
+        })
+        .unwrap_or(DataType::Int64);
+
+    let left_columns: Vec<_> = join
+        .keys
+        .iter()
+        .map(|(column, _)| column.as_str())
+        .collect();
+    let mut points = BTreeSet::new();
+    for batch in left {
+        for_each_row(&join.left_table, batch, &left_columns, |key, time, _| {
+            points.insert((key, time));
+        })?;
+    }
+
+    let right_columns: Vec<_> = join
+        .keys
+        .iter()
+        .map(|(_, column)| column.as_str())
+        .collect();
+    let mut series: HashMap<Vec<String>, Vec<RowRef>> = HashMap::new();
+    for (index, batch) in right.iter().enumerate() {
+        for_each_row(
+            &join.right_table,
+            batch,
+            &right_columns,
+            |key, time, row| {
+                series.entry(key).or_default().push((time, index, row));
+            },
+        )?;
+    }
+    // the sort is stable, so that the last row written of a time is matched
+    for rows in series.values_mut() {
+        rows.sort_by_key(|(time, _, _)| *time);
+    }
+
+    // the rows matched in each batch of the right table, with the time they were matched with
+    let mut matched = vec![(vec![], vec![]); right.len()];
+    for (key, time) in points {
+        let rows = match series.get(&key) {
+            Some(rows) => rows,
+            None => continue,
+        };
+        if let Some((_, batch, row)) = match_row(rows, time, join.direction) {
+            matched[batch].0.push(row as u32);
+            matched[batch].1.push(time);
+        }
+    }
+
+    let mut fields = right_schema.fields().clone();
+    fields.push(Field::new(ASOF_TIME_COLUMN, time_type.clone(), true));
+    let schema = Arc::new(Schema::new(fields));
+    let context = || Aligning {
+        table: &join.right_table,
+    };
+
+    let mut batches = vec![];
+    for (batch, (rows, times)) in right.iter().zip(matched) {
+        // an empty batch is kept if none is matched, for the schema of the table
+        if rows.is_empty() && !batches.is_empty() {
+            continue;
+        }
+        let rows = UInt32Array::from(rows);
+        let mut columns = batch
+            .columns()
+            .iter()
+            .map(|column| take(column, &rows, None))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(context)?;
+        let times: ArrayRef = Arc::new(Int64Array::from(times));
+        columns.push(cast(&times, &time_type).with_context(context)?);
+        batches.push(RecordBatch::try_new(Arc::clone(&schema), columns).with_context(context)?);
+    }
+    Ok(batches)
+}
+
+/// Calls `f` with the values of the columns `key_columns` and the time of each row of `batch`
+/// of the table `table`, and the index of the row, skipping the rows with nulls in them
+fn for_each_row(
+    table: &str,
+    batch: &RecordBatch,
+    key_columns: &[&str],
+    mut f: impl FnMut(Vec<String>, i64, usize),
+) -> Result<()> {
+    let column = |name: &str| {
+        batch
+            .schema()
+            .index_of(name)
+            .map(|index| Arc::clone(batch.column(index)))
+            .ok()
+            .context(MissingColumn {
+                table,
+                column: name,
+            })
+    };
+    let times = cast(&column(TIME_COLUMN_NAME)?, &DataType::Int64).context(Aligning { table })?;
+    let times = times
+        .as_any()
+        .downcast_ref::<Int64Array>()
+        .expect("cast to Int64");
+    let keys = key_columns
+        .iter()
+        .map(|name| column(name).map(|column| (*name, column)))
+        .collect::<Result<Vec<_>>>()?;
+
+    'rows: for row in 0..batch.num_rows() {
+        if times.is_null(row) {
+            continue;
+        }
+        let mut key = Vec::with_capacity(keys.len());
+        for (name, column) in &keys {
+            match key_value(name, column, row)? {
+                Some(value) => key.push(value),
+                None => continue 'rows,
+            }
+        }
+        f(key, times.value(row), row);
+    }
+    Ok(())
+}
+
+/// The value of `row` of the key column `name`, as a string to compare the keys of both
+/// tables
+fn key_value(name: &str, column: &ArrayRef, row: usize) -> Result<Option<String>> {
+    if column.is_null(row) {
+        return Ok(None);
+    }
+    let any = column.as_any();
+    let value = match column.data_type() {
+        DataType::Utf8 => any
+            .downcast_ref::<StringArray>()
+            .map(|a| a.value(row).to_string()),
+        DataType::Int64 => any
+            .downcast_ref::<Int64Array>()
+            .map(|a| a.value(row).to_string()),
+        DataType::UInt64 => any
+            .downcast_ref::<UInt64Array>()
+            .map(|a| a.value(row).to_string()),
+        DataType::Float64 => any
+            .downcast_ref::<Float64Array>()
+            .map(|a| a.value(row).to_string()),
+        DataType::Boolean => any
+            .downcast_ref::<BooleanArray>()
+            .map(|a| a.value(row).to_string()),
+        _ => None,
+    };
+    value.map(Some).context(UnsupportedKeyType {
+        column: name,
+        data_type: column.data_type().clone(),
+    })
+}
+
+/// The row of `rows`, sorted by time, matched with a row at `time`
+fn match_row(rows: &[RowRef], time: i64, direction: Direction) -> Option<RowRef> {
+    // the index of the first row after `time`
+    let after = upper_bound(rows, time);
+    let before = after.checked_sub(1).map(|index| rows[index]);
+    match direction {
+        Direction::Backward => before,
+        Direction::Nearest => {
+            let next = rows
+                .get(after)
+                .map(|next| rows[upper_bound(rows, next.0) - 1]);
+            match (before, next) {
+                (Some(before), Some(next)) if next.0 - time < time - before.0 => Some(next),
+                (None, next) => next,
+                (before, _) => before,
+            }
+        }
+    }
+}
+
+/// The index of the first of `rows`, sorted by time, after `time`
+fn upper_bound(rows: &[RowRef], time: i64) -> usize {
+    let (mut low, mut high) = (0, rows.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        if rows[mid].0 <= time {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::parser::Parser;
+
+    fn batch(hosts: Vec<&str>, values: Vec<i64>, times: Vec<i64>) -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("host", DataType::Utf8, true),
+            Field::new("value", DataType::Int64, true),
+            Field::new(TIME_COLUMN_NAME, DataType::Int64, true),
+        ]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(StringArray::from(hosts)),
+                Arc::new(Int64Array::from(values)),
+                Arc::new(Int64Array::from(times)),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn strips_keywords() {
+        let (query, directions) = strip_keywords(
+            "select * from cpu asof join mem on cpu.host = mem.host \
+             ASOF NEAREST LEFT JOIN disk using (host) join net on cpu.host = net.host",
+        );
+        assert_eq!(
+            query,
+            "select * from cpu join mem on cpu.host = mem.host \
+             LEFT JOIN disk using (host) join net on cpu.host = net.host"
+        );
+        assert_eq!(
+            directions,
+            vec![Some(Direction::Backward), Some(Direction::Nearest), None]
+        );
+
+        // asof is otherwise an identifier, and queries without ASOF joins are kept as is
+        let query = "select asof from cpu asof where 'asof join' = asof.host";
+        assert_eq!(strip_keywords(query), (query.to_string(), vec![]));
+    }
+
+    #[test]
+    fn rewrites_joins() {
+        let (query, directions) = strip_keywords(
+            "select * from cpu c asof left join mem on mem.host = c.host and region = region",
+        );
+        let mut statements = Parser::parse_sql(&GenericDialect {}, &query).unwrap();
+        let joins = rewrite(&mut statements, &directions).unwrap();
+        assert_eq!(
+            joins,
+            vec![AsofJoin {
+                direction: Direction::Backward,
+                left_table: "cpu".to_string(),
+                right_table: "mem".to_string(),
+                aligned_table: "mem_asof_0".to_string(),
+                keys: vec![
+                    ("host".to_string(), "host".to_string()),
+                    ("region".to_string(), "region".to_string())
+                ],
+            }]
+        );
+        assert_eq!(
+            statements[0].to_string(),
+            "SELECT * FROM cpu AS c LEFT JOIN mem_asof_0 AS mem ON \
+             c.time = mem.asof_time AND c.host = mem.host AND c.region = mem.region"
+        );
+
+        for query in &[
+            "select * from cpu asof join mem on cpu.host > mem.host",
+            "select * from cpu asof join mem on cpu.host = disk.host",
+            "select * from cpu asof right join mem on cpu.host = mem.host",
+            "select * from cpu asof join (select * from mem) m on cpu.host = m.host",
+        ] {
+            let (query, directions) = strip_keywords(query);
+            let mut statements = Parser::parse_sql(&GenericDialect {}, &query).unwrap();
+            assert!(rewrite(&mut statements, &directions).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn aligns_rows() {
+        let left = vec![batch(
+            vec!["a", "a", "a", "b", "c"],
+            vec![1, 2, 3, 4, 5],
+            vec![10, 20, 20, 35, 10],
+        )];
+        let right = vec![
+            batch(vec!["a", "a", "b"], vec![100, 200, 300], vec![5, 18, 40]),
+            batch(vec!["a"], vec![400], vec![18]),
+        ];
+        let mut join = AsofJoin {
+            direction: Direction::Backward,
+            left_table: "cpu".to_string(),
+            right_table: "mem".to_string(),
+            aligned_table: "mem_asof_0".to_string(),
+            keys: vec![("host".to_string(), "host".to_string())],
+        };
+
+        let values = |batches: Vec<RecordBatch>| {
+            let mut values = vec![];
+            for batch in &batches {
+                let value = batch
+                    .column(1)
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .unwrap();
+                let asof = batch
+                    .column(3)
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .unwrap();
+                for row in 0..batch.num_rows() {
+                    values.push((asof.value(row), value.value(row)));
+                }
+            }
+            values.sort_unstable();
+            values
+        };
+
+        // the last row written of a time is matched, and rows after the time never are
+        let aligned = align(&join, &left, &right).unwrap();
+        assert_eq!(aligned[0].schema().field(3).name(), ASOF_TIME_COLUMN);
+        assert_eq!(values(aligned), vec![(10, 100), (20, 400)]);
+
+        join.direction = Direction::Nearest;
+        assert_eq!(
+            values(align(&join, &left, &right).unwrap()),
+            vec![(10, 100), (20, 400), (35, 300)]
+        );
+
+        // a missing key column is an error
+        join.keys = vec![("region".to_string(), "region".to_string())];
+        assert!(matches!(
+            align(&join, &left, &right),
+            Err(Error::MissingColumn { .. })
+        ));
+    }
+}
@@ -1,8 +1,14 @@
 use generated_types::wal as wb;
 use snafu::Snafu;
+use std::{collections::HashSet, mem};
 
 use crate::dictionary::Dictionary;
-use data_types::{data::type_description, partition_metadata::Statistics};
+use data_types::{
+    data::type_description,
+    entry::{ColumnValues, LogicalColumnType},
+    partition_metadata::Statistics,
+};
+use std::fmt::{Debug, Display};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -100,6 +106,71 @@ impl Column {
         })
     }
 
+    /// Creates an empty column, padded with `capacity` nulls, for the values of an `Entry`
+    /// column, which can then be appended with `append_entry_values`. Returns `None` if every
+    /// value is null, as the statistics of the column start from its first value.
+    pub fn for_entry_values(
+        capacity: usize,
+        logical_type: LogicalColumnType,
+        values: &ColumnValues<'_>,
+    ) -> Option<Self> {
+        Some(match (logical_type, values) {
+            (LogicalColumnType::Tag, ColumnValues::String(v)) => {
+                Self::Tag(vec![None; capacity], initial_string_stats(v)?)
+            }
+            (_, ColumnValues::String(v)) => {
+                Self::String(vec![None; capacity], initial_string_stats(v)?)
+            }
+            (_, ColumnValues::I64(v)) => Self::I64(vec![None; capacity], initial_stats(v)?),
+            (_, ColumnValues::F64(v)) => Self::F64(vec![None; capacity], initial_stats(v)?),
+            (_, ColumnValues::Bool(v)) => Self::Bool(vec![None; capacity], initial_stats(v)?),
+        })
+    }
+
+    /// Appends the values of an `Entry` column, which must have the type of this column
+    pub fn append_entry_values(
+        &mut self,
+        dictionary: &mut Dictionary,
+        logical_type: LogicalColumnType,
+        values: &ColumnValues<'_>,
+    ) -> Result<()> {
+        match (self, logical_type, values) {
+            (Self::Tag(vals, stats), LogicalColumnType::Tag, ColumnValues::String(values)) => vals
+                .extend(values.iter().map(|v| {
+                    v.map(|v| {
+                        Statistics::update_string(stats, v);
+                        dictionary.lookup_value_or_insert(v)
+                    })
+                })),
+            (Self::String(vals, stats), LogicalColumnType::Field, ColumnValues::String(values)) => {
+                vals.extend(values.iter().map(|v| {
+                    v.map(|v| {
+                        Statistics::update_string(stats, v);
+                        v.to_string()
+                    })
+                }))
+            }
+            (Self::I64(vals, stats), _, ColumnValues::I64(values)) => {
+                append_with_stats(vals, stats, values)
+            }
+            (Self::F64(vals, stats), _, ColumnValues::F64(values)) => {
+                append_with_stats(vals, stats, values)
+            }
+            (Self::Bool(vals, stats), _, ColumnValues::Bool(values)) => {
+                append_with_stats(vals, stats, values)
+            }
+            (column, logical_type, values) => {
+                return TypeMismatch {
+                    existing_column_type: column.type_description(),
+                    inserted_value_type: entry_type_description(logical_type, values),
+                }
+                .fail()
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn len(&self) -> usize {
         match self {
             Self::F64(v, _) => v.len(),
@@ -114,6 +185,48 @@ impl Column {
         self.len() == 0
     }
 
+    /// Returns an estimate of the memory used by the values of this column, in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Self::F64(v, _) => mem::size_of_val(v.as_slice()),
+            Self::I64(v, _) => mem::size_of_val(v.as_slice()),
+            Self::String(v, _) => {
+                mem::size_of_val(v.as_slice()) + v.iter().flatten().map(String::len).sum::<usize>()
+            }
+            Self::Bool(v, _) => mem::size_of_val(v.as_slice()),
+            Self::Tag(v, _) => mem::size_of_val(v.as_slice()),
+        }
+    }
+
+    /// Returns the number of non-null values in this column, along with its minimum and
+    /// maximum values formatted as strings
+    pub fn stats_strings(&self) -> (u32, String, String) {
+        match self {
+            Self::F64(_, s) => (s.count, s.min.to_string(), s.max.to_string()),
+            Self::I64(_, s) => (s.count, s.min.to_string(), s.max.to_string()),
+            Self::String(_, s) => (s.count, s.min.clone(), s.max.clone()),
+            Self::Bool(_, s) => (s.count, s.min.to_string(), s.max.to_string()),
+            Self::Tag(_, s) => (s.count, s.min.clone(), s.max.clone()),
+        }
+    }
+
+    /// Returns the number of distinct non-null values in this column
+    pub fn distinct_count(&self) -> u32 {
+        let distinct = match self {
+            Self::F64(v, _) => v
+                .iter()
+                .flatten()
+                .map(|v| v.to_bits())
+                .collect::<HashSet<_>>()
+                .len(),
+            Self::I64(v, _) => v.iter().flatten().collect::<HashSet<_>>().len(),
+            Self::String(v, _) => v.iter().flatten().collect::<HashSet<_>>().len(),
+            Self::Bool(v, _) => v.iter().flatten().collect::<HashSet<_>>().len(),
+            Self::Tag(v, _) => v.iter().flatten().collect::<HashSet<_>>().len(),
+        };
+        distinct as u32
+    }
+
     pub fn type_description(&self) -> &'static str {
         match self {
             Self::F64(_, _) => "f64",
@@ -124,6 +237,15 @@ impl Column {
         }
     }
 
+    /// How the values of the column are held: tag values are ids into the partition's
+    /// dictionary and all other values are stored as they are
+    pub fn encoding_description(&self) -> &'static str {
+        match self {
+            Self::Tag(_, _) => "dictionary",
+            _ => "plain",
+        }
+    }
+
     pub fn push(&mut self, dictionary: &mut Dictionary, value: &wb::Value<'_>) -> Result<()> {
         let inserted = match self {
             Self::Tag(vals, stats) => match value.value_as_tag_value() {
@@ -217,6 +339,17 @@ impl Column {
         }
     }
 
+    /// Adds nulls to the end of the column until it has `len` values
+    pub fn pad_to_len(&mut self, len: usize) {
+        match self {
+            Self::F64(v, _) => v.resize(len.max(v.len()), None),
+            Self::I64(v, _) => v.resize(len.max(v.len()), None),
+            Self::String(v, _) => v.resize(len.max(v.len()), None),
+            Self::Bool(v, _) => v.resize(len.max(v.len()), None),
+            Self::Tag(v, _) => v.resize(len.max(v.len()), None),
+        }
+    }
+
     /// Returns true if any rows are within the range [min_value,
     /// max_value). Inclusive of `start`, exclusive of `end`
     pub fn has_i64_range(&self, start: i64, end: i64) -> Result<bool> {
@@ -257,6 +390,49 @@ impl Column {
     }
 }
 
+fn initial_stats<T>(values: &[Option<T>]) -> Option<Statistics<T>>
+where
+    T: PartialEq + PartialOrd + Debug + Display + Copy,
+{
+    let first = values.iter().flatten().next()?;
+    let mut stats = Statistics::new(*first);
+    // the first value is counted again when it is appended
+    stats.count = 0;
+    Some(stats)
+}
+
+fn initial_string_stats(values: &[Option<&str>]) -> Option<Statistics<String>> {
+    let first = values.iter().flatten().next()?;
+    let mut stats = Statistics::new(first.to_string());
+    stats.count = 0;
+    Some(stats)
+}
+
+fn append_with_stats<T>(vals: &mut Vec<Option<T>>, stats: &mut Statistics<T>, values: &[Option<T>])
+where
+    T: PartialEq + PartialOrd + Debug + Display + Copy,
+{
+    for value in values {
+        if let Some(value) = value {
+            stats.update(*value);
+        }
+        vals.push(*value);
+    }
+}
+
+fn entry_type_description(
+    logical_type: LogicalColumnType,
+    values: &ColumnValues<'_>,
+) -> &'static str {
+    match (logical_type, values) {
+        (LogicalColumnType::Tag, ColumnValues::String(_)) => "tag",
+        (_, ColumnValues::String(_)) => "String",
+        (_, ColumnValues::I64(_)) => "i64",
+        (_, ColumnValues::F64(_)) => "f64",
+        (_, ColumnValues::Bool(_)) => "bool",
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -264,6 +440,17 @@ mod tests {
     type TestError = Box<dyn std::error::Error + Send + Sync + 'static>;
     type Result<T = (), E = TestError> = std::result::Result<T, E>;
 
+    #[test]
+    fn test_distinct_count() {
+        let mut stats = Statistics::new(1.0);
+        stats.update(2.0);
+        let col = Column::F64(vec![Some(1.0), None, Some(2.0), Some(1.0)], stats);
+        assert_eq!(col.distinct_count(), 2);
+
+        let col = Column::Tag(vec![None, None], Statistics::new(String::new()));
+        assert_eq!(col.distinct_count(), 0);
+    }
+
     #[test]
     fn test_has_i64_range() -> Result {
         let mut stats = Statistics::new(1);
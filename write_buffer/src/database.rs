@@ -1,26 +1,40 @@
 use generated_types::wal as wb;
 use influxdb_line_protocol::ParsedLine;
 use storage::{
+    access::RowAccess,
+    analytic,
+    autocomplete::{TagValues, TagValuesCollector, TagValuesQuery},
     exec::{
-        stringset::StringSet, FieldListPlan, GroupedSeriesSetPlan, GroupedSeriesSetPlans,
+        pool, stringset::StringSet, FieldListPlan, GroupedSeriesSetPlan, GroupedSeriesSetPlans,
         SeriesSetPlan, SeriesSetPlans, StringSetPlan,
     },
-    predicate::Predicate,
-    Database,
+    histogram,
+    predicate::{Predicate, PredicateBuilder, TimestampRange},
+    quantile,
+    validate::{self, LineDiagnostic},
+    wasm, window, Database,
 };
 use wal::{
     writer::{start_wal_sync_task, Error as WalWriterError, WalDetails},
     WalBuilder,
 };
 
+use crate::asof;
 use crate::column::Column;
 use crate::partition::Partition;
+use crate::rollup::Rollups;
+use crate::sequence::Sequences;
 use crate::{partition::PartitionPredicate, table::Table};
 
-use std::collections::{BTreeSet, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::io::ErrorKind;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
 
 use arrow_deps::{
     arrow,
@@ -31,27 +45,53 @@ use arrow_deps::{
         datasource::MemTable, error::DataFusionError, execution::context::ExecutionContext,
     },
 };
-use data_types::data::{split_lines_into_write_entry_partitions, ReplicatedWrite};
+use data_types::{
+    chunk::{ChunkSummary, ColumnPredicate, ColumnSummary, Comparison, Literal},
+    data::{split_lines_into_write_entry_partitions, ReplicatedWrite},
+    database_rules::RollupRule,
+    entry::Entry,
+    table_schema::Schema,
+    TIME_COLUMN_NAME,
+};
+use packers::Packers;
 
 use crate::dictionary::Error as DictionaryError;
-use crate::partition::restore_partitions_from_wal;
+use crate::partition::restore_partitions_from_wal_with_progress;
 
 use async_trait::async_trait;
 use chrono::{offset::TimeZone, Utc};
-use snafu::{OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use sqlparser::{
-    ast::{SetExpr, Statement, TableFactor},
+    ast::{
+        BinaryOperator, Expr, Query, SelectItem, SetExpr, Statement, TableFactor, TableWithJoins,
+        Value,
+    },
     dialect::GenericDialect,
     parser::Parser,
 };
 use tokio::sync::RwLock;
-use tracing::info;
+use tracing::{debug, debug_span, info, info_span};
+use tracing_futures::Instrument;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Dir {:?} invalid for DB", dir))]
     OpenDb { dir: PathBuf },
 
+    #[snafu(display(
+        "Database {} is throttling writes to partition {}: {} bytes are buffered, over the limit of {} bytes",
+        database,
+        partition_key,
+        used,
+        limit
+    ))]
+    WriteThrottled {
+        database: String,
+        partition_key: String,
+        used: usize,
+        limit: usize,
+    },
+
     #[snafu(display("Error opening WAL for database {}: {}", database, source))]
     OpeningWal {
         database: String,
@@ -94,6 +134,25 @@ pub enum Error {
     #[snafu(display("Partition {} is full", partition))]
     PartitionFull { partition: String },
 
+    #[snafu(display("No open chunk for partition {}", partition_key))]
+    OpenChunkNotFound { partition_key: String },
+
+    #[snafu(display("Chunk {} of partition {} not found", chunk_id, partition_key))]
+    ChunkNotFound {
+        partition_key: String,
+        chunk_id: u32,
+    },
+
+    #[snafu(display(
+        "Chunk {} of partition {} is still open for writes",
+        chunk_id,
+        partition_key
+    ))]
+    ChunkIsOpen {
+        partition_key: String,
+        chunk_id: u32,
+    },
+
     #[snafu(display("Error in {}: {}", source_module, source))]
     PassThrough {
         source_module: &'static str,
@@ -182,12 +241,21 @@ pub enum Error {
         source: sqlparser::parser::ParserError,
     },
 
+    #[snafu(display("error planning the ASOF joins of query {}: {}", query, source))]
+    AsofJoinError { query: String, source: asof::Error },
+
     #[snafu(display("error executing query {}: {}", query, source))]
     QueryError {
         query: String,
         source: DataFusionError,
     },
 
+    #[snafu(display("error waiting for query {} to execute: {}", query, source))]
+    QueryJoinError {
+        query: String,
+        source: tokio::task::JoinError,
+    },
+
     #[snafu(display("Unsupported SQL statement in query {}: {}", query, statement))]
     UnsupportedStatement {
         query: String,
@@ -197,6 +265,18 @@ pub enum Error {
     #[snafu(display("query error {} on query {}", message, query))]
     GenericQueryError { message: String, query: String },
 
+    #[snafu(display("Table {} of query {} is not allowed", table_name, query))]
+    TableNotAllowed { query: String, table_name: String },
+
+    #[snafu(display("Table {} of query {} not found", table_name, query))]
+    QueryTableNotFound { query: String, table_name: String },
+
+    #[snafu(display("Error filtering the allowed rows of table {}: {}", table_name, source))]
+    FilteringRows {
+        table_name: String,
+        source: arrow_deps::arrow::error::ArrowError,
+    },
+
     #[snafu(display("replicated write from writer {} missing payload", writer))]
     MissingPayload { writer: u32 },
 }
@@ -221,12 +301,92 @@ impl From<crate::partition::Error> for Error {
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// The rows of a table of an open chunk that weren't persisted yet, packed to be persisted as
+/// an increment of the chunk
+#[derive(Debug)]
+pub struct ChunkIncrement {
+    pub table: ExportedTable,
+    /// The number of rows of the table persisted once the increment is
+    pub persisted_rows: usize,
+}
+
+/// The rows of one table of one chunk, packed to be written to Parquet
+#[derive(Debug)]
+pub struct ExportedTable {
+    pub partition_key: String,
+    pub chunk_id: u32,
+    /// The schema of the table, named after it
+    pub schema: Schema,
+    /// The values of each column of `schema`, in order
+    pub columns: Vec<Packers>,
+}
+
+/// How far a replay of the WAL of a database got, see `Db::restore_from_wal_skipping`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ReplayProgress {
+    /// The entries of the WAL restored so far
+    pub replayed: usize,
+    /// The entries of the WAL left out so far
+    pub skipped: usize,
+    /// The WAL sequence number of the entry the replay is at: the entry after the last one
+    /// restored or left out, or the first entry of the WAL
+    pub next_sequence_number: u64,
+    /// The WAL sequence number after the last entry of the WAL, which the replay is done at
+    pub end_sequence_number: u64,
+}
+
+impl ReplayProgress {
+    /// The number of entries of the WAL the replay is behind by
+    pub fn lag(&self) -> u64 {
+        self.end_sequence_number
+            .saturating_sub(self.next_sequence_number)
+    }
+}
+
+impl Error {
+    /// Returns how long to wait before retrying the write, if it was rejected because the
+    /// database can't keep up with writes for now
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::WriteThrottled { .. } => Some(WRITE_RETRY_AFTER),
+            _ => None,
+        }
+    }
+}
+
+/// How long writers are asked to wait before retrying a throttled write
+pub const WRITE_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// `WriteLimits` bound the memory used by the partitions of a database. Once a limit is
+/// reached, writes are rejected with a retryable error until the partitions are closed and
+/// moved out of the write buffer, rather than accepted until the process runs out of memory.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct WriteLimits {
+    /// Writes to a partition are rejected while its open chunk uses more than this, in bytes
+    pub partition_size: Option<usize>,
+    /// Writes are rejected while all the chunks of the database use more than this, in bytes
+    pub buffer_size: Option<usize>,
+}
+
 #[derive(Debug, Default)]
 pub struct Db {
     pub name: String,
     // TODO: partitions need to be wrapped in an Arc if they're going to be used without this lock
     partitions: RwLock<Vec<Partition>>,
     wal_details: Option<WalDetails>,
+    /// How long data is kept for, based on its timestamps
+    retention_period: Mutex<Option<Duration>>,
+    /// The limits over which writes are throttled
+    write_limits: Mutex<WriteLimits>,
+    /// For each partition key with dropped chunks, the lowest id new chunks can get, so that
+    /// the ids of dropped chunks are never reused
+    next_chunk_ids: Mutex<HashMap<String, u32>>,
+    /// The high water marks of the entries applied to each partition
+    sequences: RwLock<Sequences>,
+    /// Incremented whenever data is written or dropped, while the partitions are locked
+    data_version: AtomicU64,
+    /// The aggregates maintained for the rollup rules of the database
+    rollups: Mutex<Rollups>,
 }
 
 impl Db {
@@ -274,6 +434,32 @@ impl Db {
     /// Create a new DB and initially restore pre-existing data in the
     /// Write Ahead Log (WAL) directory `wal_dir`
     pub async fn restore_from_wal(wal_dir: PathBuf) -> Result<Self> {
+        Self::restore_from_wal_with_progress(wal_dir, |_| {}).await
+    }
+
+    /// Restores a DB from the WAL directory `wal_dir` as `restore_from_wal` does, calling
+    /// `on_replayed` with the number of WAL entries replayed so far after each entry
+    pub async fn restore_from_wal_with_progress(
+        wal_dir: PathBuf,
+        mut on_replayed: impl FnMut(usize) + Send,
+    ) -> Result<Self> {
+        Self::restore_from_wal_skipping(
+            wal_dir,
+            |_| false,
+            |progress| on_replayed(progress.replayed),
+        )
+        .await
+    }
+
+    /// Restores a DB from the WAL directory `wal_dir` as `restore_from_wal` does, leaving out
+    /// the entries whose WAL sequence number `skip` returns true for, such as entries that
+    /// can't be restored. `on_progress` is called with how far the replay got once the WAL is
+    /// opened, and again after each entry restored or left out.
+    pub async fn restore_from_wal_skipping(
+        wal_dir: PathBuf,
+        skip: impl Fn(u64) -> bool + Send,
+        mut on_progress: impl FnMut(&ReplayProgress) + Send,
+    ) -> Result<Self> {
         let now = std::time::Instant::now();
         let name = wal_dir
             .iter()
@@ -288,13 +474,32 @@ impl Db {
             .await
             .context(OpeningWal { database: &name })?;
 
+        let mut progress = ReplayProgress::default();
+        if let Some((first, last)) = wal_builder
+            .sequence_number_range()
+            .context(LoadingWal { database: &name })?
+        {
+            progress.next_sequence_number = first;
+            progress.end_sequence_number = last + 1;
+        }
+        on_progress(&progress);
+
         // TODO: check wal metadata format
         let entries = wal_builder
             .entries()
             .context(LoadingWal { database: &name })?;
 
-        let (partitions, stats) =
-            restore_partitions_from_wal(entries).context(WalRecoverError { database: &name })?;
+        let (partitions, mut stats) =
+            restore_partitions_from_wal_with_progress(entries, skip, |sequence_number, skipped| {
+                if skipped {
+                    progress.skipped += 1;
+                } else {
+                    progress.replayed += 1;
+                }
+                progress.next_sequence_number = sequence_number + 1;
+                on_progress(&progress);
+            })
+            .context(WalRecoverError { database: &name })?;
 
         let elapsed = now.elapsed();
         info!(
@@ -306,18 +511,123 @@ impl Db {
         );
 
         info!("{} database partition count: {}", &name, partitions.len(),);
+        if stats.duplicate_writes > 0 {
+            info!(
+                "{} database skipped {} duplicate partition writes",
+                &name, stats.duplicate_writes
+            );
+        }
 
         Ok(Self {
             name,
             partitions: RwLock::new(partitions),
             wal_details: Some(wal_details),
+            sequences: RwLock::new(std::mem::take(&mut stats.sequences)),
+            ..Default::default()
         })
     }
 
+    /// Applies the rows of `entry` to the partitions of the database and appends it to the
+    /// WAL. The writes of the entry to partitions that already applied it, or a later entry
+    /// of its producer, are skipped, so that delivering an entry again has no effect.
+    pub async fn store_entry(&self, entry: &Entry) -> Result<()> {
+        let (producer_id, sequence_number) = (entry.producer_id(), entry.sequence_number());
+        {
+            let mut partitions = self.partitions.write().await;
+            let mut sequences = self.sequences.write().await;
+            let mut applied = 0;
+
+            // reject the whole entry before applying any of it
+            for write in entry.partition_writes() {
+                let key = write.key();
+                if !sequences.is_applied(key, producer_id, sequence_number) {
+                    self.check_write_limits(&partitions, key)?;
+                }
+            }
+
+            self.data_changed();
+            let mut skipped = HashSet::new();
+            for write in entry.partition_writes() {
+                let key = write.key();
+                if sequences.is_applied(key, producer_id, sequence_number) {
+                    skipped.insert(key);
+                    debug!(
+                        "skipping write of entry {} of producer {} to partition {} of {}: already applied",
+                        sequence_number, producer_id, key, self.name
+                    );
+                    continue;
+                }
+
+                match partitions.iter_mut().find(|p| p.should_write(key)) {
+                    Some(p) => p.write_partition_write(&write)?,
+                    None => {
+                        let id = partitions
+                            .iter()
+                            .filter(|p| p.key == key)
+                            .map(|p| p.id + 1)
+                            .max()
+                            .unwrap_or(0)
+                            .max(self.next_chunk_id(key));
+                        let mut p = Partition::with_id(key, id);
+                        p.write_partition_write(&write)?;
+                        partitions.push(p)
+                    }
+                }
+                sequences.record(key, producer_id, sequence_number);
+                applied += 1;
+            }
+
+            self.rollups
+                .lock()
+                .expect("mutex poisoned")
+                .update(entry, |key| skipped.contains(key));
+
+            if applied == 0 {
+                return Ok(());
+            }
+        }
+
+        if let Some(wal) = &self.wal_details {
+            wal.write_and_sync(entry.data().to_vec())
+                .instrument(info_span!("write_wal"))
+                .await
+                .context(WritingWal {
+                    database: &self.name,
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the writes made so far to be appended to the WAL, if the database has one, and
+    /// syncs its files to disk
+    pub async fn sync_wal(&self) -> Result<()> {
+        if let Some(wal) = &self.wal_details {
+            wal.sync().await.context(WritingWal {
+                database: &self.name,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Returns the high water marks of the entries applied to the partitions of the database
+    pub async fn sequences(&self) -> Sequences {
+        self.sequences.read().await.clone()
+    }
+
     async fn write_entries_to_partitions(&self, batch: &wb::WriteBufferBatch<'_>) -> Result<()> {
         if let Some(entries) = batch.entries() {
             let mut partitions = self.partitions.write().await;
 
+            // reject the whole batch before applying any of it
+            for entry in entries.iter() {
+                let key = entry
+                    .partition_key()
+                    .expect("partition key should have been inserted");
+                self.check_write_limits(&partitions, key)?;
+            }
+
+            self.data_changed();
             for entry in entries {
                 let key = entry
                     .partition_key()
@@ -326,7 +636,14 @@ impl Db {
                 match partitions.iter_mut().find(|p| p.should_write(key)) {
                     Some(p) => p.write_entry(&entry)?,
                     None => {
-                        let mut p = Partition::new(key);
+                        let id = partitions
+                            .iter()
+                            .filter(|p| p.key == key)
+                            .map(|p| p.id + 1)
+                            .max()
+                            .unwrap_or(0)
+                            .max(self.next_chunk_id(key));
+                        let mut p = Partition::with_id(key, id);
                         p.write_entry(&entry)?;
                         partitions.push(p)
                     }
@@ -348,17 +665,40 @@ impl Database for Db {
         let data = split_lines_into_write_entry_partitions(partition_key, lines);
         let batch = flatbuffers::get_root::<wb::WriteBufferBatch<'_>>(&data);
 
-        self.write_entries_to_partitions(&batch).await?;
+        self.write_entries_to_partitions(&batch)
+            .instrument(info_span!("write_partitions", lines = lines.len()))
+            .await?;
 
         if let Some(wal) = &self.wal_details {
-            wal.write_and_sync(data).await.context(WritingWal {
-                database: &self.name,
-            })?;
+            wal.write_and_sync(data)
+                .instrument(info_span!("write_wal"))
+                .await
+                .context(WritingWal {
+                    database: &self.name,
+                })?;
         }
 
         Ok(())
     }
 
+    async fn validate_lines(
+        &self,
+        lines: &[ParsedLine<'_>],
+    ) -> Result<Vec<LineDiagnostic>, Self::Error> {
+        Ok(validate::check_column_types(
+            self.column_types().await,
+            lines,
+        ))
+    }
+
+    fn write_retry_after(&self, error: &Self::Error) -> Option<Duration> {
+        error.retry_after()
+    }
+
+    fn data_version(&self) -> Option<u64> {
+        Some(self.data_version.load(Ordering::SeqCst))
+    }
+
     async fn store_replicated_write(&self, write: &ReplicatedWrite) -> Result<(), Self::Error> {
         match write.write_buffer_batch() {
             Some(b) => self.write_entries_to_partitions(&b).await?,
@@ -384,6 +724,7 @@ impl Database for Db {
     }
 
     async fn table_names(&self, predicate: Predicate) -> Result<StringSetPlan, Self::Error> {
+        let predicate = self.apply_retention(predicate);
         // TODO: Cache this information to avoid creating this each time
         let partitions = self.partitions.read().await;
 
@@ -419,6 +760,7 @@ impl Database for Db {
 
     // return all column names in this database, while applying optional predicates
     async fn tag_column_names(&self, predicate: Predicate) -> Result<StringSetPlan, Self::Error> {
+        let predicate = self.apply_retention(predicate);
         let has_exprs = predicate.has_exprs();
         let mut filter = PartitionTableFilter::new(predicate);
 
@@ -435,6 +777,7 @@ impl Database for Db {
 
     /// return all field names in this database, while applying optional predicates
     async fn field_columns(&self, predicate: Predicate) -> Result<FieldListPlan, Self::Error> {
+        let predicate = self.apply_retention(predicate);
         let mut filter = PartitionTableFilter::new(predicate);
         let mut visitor = TableFieldPredVisitor::new();
         self.visit_tables(&mut filter, &mut visitor).await?;
@@ -447,6 +790,7 @@ impl Database for Db {
         column_name: &str,
         predicate: Predicate,
     ) -> Result<StringSetPlan, Self::Error> {
+        let predicate = self.apply_retention(predicate);
         let has_exprs = predicate.has_exprs();
         let mut filter = PartitionTableFilter::new(predicate);
 
@@ -461,7 +805,22 @@ impl Database for Db {
         }
     }
 
+    async fn tag_values_with_prefix(
+        &self,
+        query: &TagValuesQuery,
+    ) -> Result<TagValues, Self::Error> {
+        let predicate = PredicateBuilder::default()
+            .table_option(query.table_name.clone())
+            .timestamp_range_option(query.range)
+            .build();
+        let mut filter = PartitionTableFilter::new(self.apply_retention(predicate));
+        let mut visitor = PrefixValueVisitor::new(query);
+        self.visit_tables(&mut filter, &mut visitor).await?;
+        Ok(visitor.values.finish())
+    }
+
     async fn query_series(&self, predicate: Predicate) -> Result<SeriesSetPlans, Self::Error> {
+        let predicate = self.apply_retention(predicate);
         let mut filter = PartitionTableFilter::new(predicate);
         let mut visitor = SeriesVisitor::new();
         self.visit_tables(&mut filter, &mut visitor).await?;
@@ -473,6 +832,7 @@ impl Database for Db {
         predicate: Predicate,
         group_columns: Vec<String>,
     ) -> Result<GroupedSeriesSetPlans, Self::Error> {
+        let predicate = self.apply_retention(predicate);
         let mut filter = PartitionTableFilter::new(predicate)
             // Add any specified groups as predicate columns (so we can skip tables without those tags)
             .add_required_columns(&group_columns);
@@ -488,66 +848,40 @@ impl Database for Db {
         columns: &[&str],
     ) -> Result<Vec<RecordBatch>, Self::Error> {
         let partitions = self.partitions.read().await;
+        let boundary = self.retention_boundary();
 
+        // Partitions that are entirely expired are skipped, but they may still contain expired
+        // rows if they also contain newer data
         let batches = partitions
             .iter()
-            .map(|p| p.table_to_arrow(table_name, columns))
+            .filter(|p| !p.is_expired(boundary))
+            .map(|p| {
+                debug_span!("scan_chunk", partition_key = %p.key, chunk_id = p.id)
+                    .in_scope(|| p.table_to_arrow(table_name, columns))
+            })
             .collect::<Result<Vec<_>, crate::partition::Error>>()?;
 
         Ok(batches)
     }
 
     async fn query(&self, query: &str) -> Result<Vec<RecordBatch>, Self::Error> {
-        let mut tables = vec![];
-
-        let dialect = GenericDialect {};
-        let ast = Parser::parse_sql(&dialect, query).context(InvalidSqlQuery { query })?;
-
-        for statement in ast {
-            match statement {
-                Statement::Query(q) => {
-                    if let SetExpr::Select(q) = q.body {
-                        for item in q.from {
-                            if let TableFactor::Table { name, .. } = item.relation {
-                                let name = name.to_string();
-                                let data = self.table_to_arrow(&name, &[]).await?;
-                                tables.push(ArrowTable {
-                                    name,
-                                    schema: data[0].schema().clone(),
-                                    data,
-                                });
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    return UnsupportedStatement {
-                        query: query.to_string(),
-                        statement,
-                    }
-                    .fail()
-                }
-            }
-        }
-
-        let config = ExecutionConfig::new().with_batch_size(1024 * 1024);
-        let mut ctx = ExecutionContext::with_config(config);
-
-        for table in tables {
-            let provider =
-                MemTable::new(table.schema, vec![table.data]).context(QueryError { query })?;
-            ctx.register_table(&table.name, Box::new(provider));
-        }
-
-        let plan = ctx
-            .create_logical_plan(&query)
-            .context(QueryError { query })?;
-        let plan = ctx.optimize(&plan).context(QueryError { query })?;
-        let plan = ctx
-            .create_physical_plan(&plan)
-            .context(QueryError { query })?;
+        self.query_with_tables(query, &BTreeMap::new()).await
+    }
 
-        ctx.collect(plan).await.context(QueryError { query })
+    async fn query_with_access(
+        &self,
+        query: &str,
+        access: &RowAccess,
+    ) -> Result<Vec<RecordBatch>, Self::Error> {
+        self.query_allowed(
+            query,
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            1,
+            access,
+        )
+        .await
     }
 }
 
@@ -626,127 +960,1074 @@ impl Db {
         self.partitions.read().await.is_empty()
     }
 
-    /// Traverse this database's tables, calling the relevant
-    /// functions, in order, of `visitor`, as described on the Visitor
-    /// trait.
-    ///
-    /// Skips visiting any table or columns of `filter.should_visit_table` returns false
-    async fn visit_tables<V: Visitor>(
-        &self,
-        filter: &mut PartitionTableFilter,
-        visitor: &mut V,
-    ) -> Result<()> {
-        let partitions = self.partitions.read().await;
-
-        for partition in partitions.iter() {
-            visitor.pre_visit_partition(partition)?;
-            filter.pre_visit_partition(partition)?;
+    /// Sets how long data is kept for, based on its timestamps. Queries exclude rows older than
+    /// the retention period, and `drop_expired_chunks` removes the chunks that only contain
+    /// such rows. If `None`, data is kept forever.
+    pub fn set_retention_period(&self, retention_period: Option<Duration>) {
+        *self.retention_period.lock().expect("mutex poisoned") = retention_period;
+    }
 
-            for table in partition.tables.values() {
-                if filter.should_visit_table(table)? {
-                    visitor.pre_visit_table(table, partition, filter)?;
+    /// Sets the limits over which writes are throttled
+    pub fn set_write_limits(&self, write_limits: WriteLimits) {
+        *self.write_limits.lock().expect("mutex poisoned") = write_limits;
+    }
 
-                    for (column_id, column_index) in &table.column_id_to_index {
-                        visitor.visit_column(
-                            table,
-                            *column_id,
-                            &table.columns[*column_index],
-                            filter,
-                        )?
-                    }
+    /// Sets the rollup rules of the database. The aggregates of unchanged rules are kept, the
+    /// aggregates of new or changed rules cover the entries stored from then on.
+    pub fn set_rollup_rules(&self, rules: &[RollupRule]) {
+        self.rollups
+            .lock()
+            .expect("mutex poisoned")
+            .set_rules(rules);
+    }
 
-                    visitor.post_visit_table(table, partition)?;
+    /// Returns `WriteThrottled` if writing to partition `key` would use memory over the write
+    /// limits of the database
+    fn check_write_limits(&self, partitions: &[Partition], key: &str) -> Result<()> {
+        let limits = *self.write_limits.lock().expect("mutex poisoned");
+
+        let partition_usage = limits.partition_size.map(|limit| {
+            let used = partitions
+                .iter()
+                .filter(|p| p.should_write(key))
+                .map(Partition::size)
+                .sum();
+            (used, limit)
+        });
+        let buffer_usage = limits
+            .buffer_size
+            .map(|limit| (partitions.iter().map(Partition::size).sum(), limit));
+
+        for (used, limit) in partition_usage.into_iter().chain(buffer_usage) {
+            if used > limit {
+                metrics::registry()
+                    .counter(
+                        "write_buffer_writes_throttled_total",
+                        "Writes rejected because the write buffer was over its limits",
+                        &[("db_name", &self.name)],
+                    )
+                    .inc();
+
+                return WriteThrottled {
+                    database: &self.name,
+                    partition_key: key,
+                    used,
+                    limit,
                 }
+                .fail();
             }
-            visitor.post_visit_partition(partition)?;
-        } // next partition
+        }
 
         Ok(())
     }
-}
 
-/// Common logic for processing and filtering tables in the write buffer
-///
-/// Note that since each partition has its own dictionary, mappings
-/// between Strings --> we cache the String->id mappings per partition
-///
-/// b) the table doesn't have a column range that overlaps the
-/// predicate values, e.g., if you have env = "us-west" and a
-/// table's env column has the range ["eu-south", "us-north"].
-#[derive(Debug)]
-struct PartitionTableFilter {
-    predicate: Predicate,
-
-    /// If specififed, only tables with all specified columns will be
-    /// visited. Note that just becuase a table has all these columns,
-    /// it might not be visited for other reasons (e.g. it is filted
-    /// out by a table_name predicate)
-    additional_required_columns: Option<HashSet<String>>,
+    /// Drops the chunks whose data is all older than the retention period, returning their
+    /// summaries
+    pub async fn drop_expired_chunks(&self) -> Vec<ChunkSummary> {
+        let boundary = match self.retention_boundary() {
+            Some(boundary) => boundary,
+            None => return vec![],
+        };
 
-    /// A 'compiled' version of the predicate to evaluate on tables /
-    /// columns in a particular partition during the walk
-    partition_predicate: Option<PartitionPredicate>,
-}
+        let mut partitions = self.partitions.write().await;
+        let dropped_windows = self
+            .rollups
+            .lock()
+            .expect("mutex poisoned")
+            .drop_before(boundary);
+        let (expired, retained) = partitions
+            .drain(..)
+            .partition(|p| p.is_expired(Some(boundary)));
+        *partitions = retained;
+        if !expired.is_empty() || dropped_windows > 0 {
+            self.data_changed();
+        }
 
-impl PartitionTableFilter {
-    fn new(predicate: Predicate) -> Self {
-        Self {
-            predicate,
-            additional_required_columns: None,
-            partition_predicate: None,
+        for partition in &expired {
+            self.chunk_dropped(&partition.key, partition.id);
+        }
+        let expired: Vec<_> = expired.iter().map(Partition::chunk_summary).collect();
+        if !expired.is_empty() {
+            info!(
+                "{} database dropped {} chunks past the retention period",
+                self.name,
+                expired.len()
+            );
         }
+
+        expired
     }
 
-    /// adds the specified columns to a list of columns that must be
-    /// present in a table.
-    fn add_required_columns(mut self, column_names: &[String]) -> Self {
-        let mut required_columns = self
-            .additional_required_columns
-            .take()
-            .unwrap_or_else(HashSet::new);
+    /// Records that the data of the database changed, invalidating the results computed from
+    /// earlier versions. Called while the partitions are locked for writing, so that a reader
+    /// that got the version before reading the partitions never sees older data than it.
+    fn data_changed(&self) {
+        self.data_version.fetch_add(1, Ordering::SeqCst);
+    }
 
-        for c in column_names {
-            if !required_columns.contains(c) {
-                required_columns.insert(c.clone());
-            }
-        }
+    /// The lowest id a new chunk of partition `key` can get, given the chunks dropped so far
+    fn next_chunk_id(&self, key: &str) -> u32 {
+        let next_chunk_ids = self.next_chunk_ids.lock().expect("mutex poisoned");
+        next_chunk_ids.get(key).copied().unwrap_or(0)
+    }
 
-        self.additional_required_columns = Some(required_columns);
-        self
+    /// Records that chunk `id` of partition `key` was dropped
+    fn chunk_dropped(&self, key: &str, id: u32) {
+        let mut next_chunk_ids = self.next_chunk_ids.lock().expect("mutex poisoned");
+        let next = next_chunk_ids.entry(key.to_string()).or_default();
+        *next = (*next).max(id + 1);
     }
 
-    /// Called when each partition gets visited. Since ids are
-    /// specific to each partitition, the predicates much get
-    /// translated each time.
-    fn pre_visit_partition(&mut self, partition: &Partition) -> Result<()> {
-        let mut partition_predicate = partition.compile_predicate(&self.predicate)?;
+    /// Returns the timestamp, in nanoseconds, before which data is past the retention period
+    fn retention_boundary(&self) -> Option<i64> {
+        let retention_period = (*self.retention_period.lock().expect("mutex poisoned"))?;
+        let retention_nanos = i64::try_from(retention_period.as_nanos()).unwrap_or(i64::MAX);
 
-        // add any additional column needs
-        if let Some(additional_required_columns) = &self.additional_required_columns {
-            partition.add_required_columns_to_predicate(
-                additional_required_columns,
-                &mut partition_predicate,
-            );
+        Some(Utc::now().timestamp_nanos().saturating_sub(retention_nanos))
+    }
+
+    /// Restricts the time range of `predicate` to data within the retention period
+    fn apply_retention(&self, mut predicate: Predicate) -> Predicate {
+        if let Some(boundary) = self.retention_boundary() {
+            let range = match predicate.range {
+                Some(range) => TimestampRange::new(range.start.max(boundary), range.end),
+                None => TimestampRange::new(boundary, i64::MAX),
+            };
+            predicate.range = Some(range);
         }
 
-        self.partition_predicate = Some(partition_predicate);
+        predicate
+    }
 
-        Ok(())
+    /// Returns a summary of each chunk of data in this database. Each partition of the write
+    /// buffer is a chunk.
+    pub async fn chunk_summaries(&self) -> Vec<ChunkSummary> {
+        self.partitions
+            .read()
+            .await
+            .iter()
+            .map(Partition::chunk_summary)
+            .collect()
     }
 
-    /// If returns false, skips visiting _table and all its columns
-    fn should_visit_table(&mut self, table: &Table) -> Result<bool> {
-        Ok(table.could_match_predicate(self.partition_predicate())?)
+    /// Returns a summary of each chunk whose rows all have a time before `boundary`, a
+    /// timestamp in nanoseconds. Chunks without a time column are left out.
+    pub async fn chunks_older_than(&self, boundary: i64) -> Vec<ChunkSummary> {
+        self.partitions
+            .read()
+            .await
+            .iter()
+            .filter(|p| p.is_expired(Some(boundary)))
+            .map(Partition::chunk_summary)
+            .collect()
     }
 
-    pub fn partition_predicate(&self) -> &PartitionPredicate {
-        self.partition_predicate
-            .as_ref()
-            .expect("Visited partition to compile predicate")
+    /// Runs the SQL `query` against the database. Tables named in `extra_tables` are read
+    /// from the given record batches instead of from the database, which is how virtual
+    /// tables such as the `system` tables are queried.
+    pub async fn query_with_tables(
+        &self,
+        query: &str,
+        extra_tables: &BTreeMap<String, Vec<RecordBatch>>,
+    ) -> Result<Vec<RecordBatch>> {
+        self.query_with_sorted_tables(query, extra_tables, &BTreeMap::new())
+            .await
     }
-}
 
-/// return all column names in this database, while applying only the
+    /// Runs the SQL `query` like `query_with_tables`, where `sort_keys` holds the columns the
+    /// batches of some of the extra tables are sorted by. The `ORDER BY` clause of a query the
+    /// batches already satisfy is dropped, so that the rows are not sorted again.
+    pub async fn query_with_sorted_tables(
+        &self,
+        query: &str,
+        extra_tables: &BTreeMap<String, Vec<RecordBatch>>,
+        sort_keys: &BTreeMap<String, Vec<String>>,
+    ) -> Result<Vec<RecordBatch>> {
+        let extra_tables = extra_tables
+            .iter()
+            .map(|(name, batches)| (name.clone(), vec![batches.clone()]))
+            .collect();
+        self.query_with_partitioned_tables(query, &extra_tables, sort_keys, 1)
+            .await
+    }
+
+    /// Runs the SQL `query` like `query_with_sorted_tables`, where the batches of each extra
+    /// table are split into partitions, such as the chunks the rows were read from. Up to
+    /// `concurrency` partitions are scanned at the same time, on separate workers, and their
+    /// results are merged by the query plan. The partitions of a table in `sort_keys` are
+    /// scanned as one, as the rows are only sorted across partitions in their given order.
+    pub async fn query_with_partitioned_tables(
+        &self,
+        query: &str,
+        extra_tables: &BTreeMap<String, Vec<Vec<RecordBatch>>>,
+        sort_keys: &BTreeMap<String, Vec<String>>,
+        concurrency: usize,
+    ) -> Result<Vec<RecordBatch>> {
+        self.query_allowed(
+            query,
+            extra_tables,
+            &BTreeMap::new(),
+            sort_keys,
+            concurrency,
+            &RowAccess::default(),
+        )
+        .await
+    }
+
+    /// Runs the SQL `query` like `query_with_partitioned_tables`, over only the rows `access`
+    /// allows. Naming a table outside of the allowed measurements is an error, and the rows
+    /// of the other tables are filtered before they are planned, so no part of the query can
+    /// see the rows that aren't allowed. The `other_tables` are the tables of other
+    /// databases, named `<database>.<table>`, whose rows are already restricted to those the
+    /// query may access there, which `access` doesn't apply to.
+    pub async fn query_allowed(
+        &self,
+        query: &str,
+        extra_tables: &BTreeMap<String, Vec<Vec<RecordBatch>>>,
+        other_tables: &BTreeMap<String, Vec<Vec<RecordBatch>>>,
+        sort_keys: &BTreeMap<String, Vec<String>>,
+        concurrency: usize,
+        access: &RowAccess,
+    ) -> Result<Vec<RecordBatch>> {
+        let query = &elide_sort(query, sort_keys)?;
+        let mut tables = vec![];
+
+        for name in query_table_names(query)? {
+            let other_table = other_tables.get(&name);
+            ensure!(
+                other_table.is_some() || access.allows_measurement(&name),
+                TableNotAllowed {
+                    query,
+                    table_name: &name
+                }
+            );
+            let partitions = match extra_tables.get(&name).or(other_table) {
+                Some(partitions) if sort_keys.contains_key(&name) => {
+                    vec![partitions.iter().flatten().cloned().collect()]
+                }
+                Some(partitions) => partitions.clone(),
+                None => match self.rollup_to_arrow(&name)? {
+                    Some(batch) => vec![vec![batch]],
+                    None => vec![self.table_to_arrow(&name, &[]).await?],
+                },
+            };
+            let partitions = if other_table.is_some() || access.is_unrestricted() {
+                partitions
+            } else {
+                partitions
+                    .iter()
+                    .map(|batches| {
+                        batches
+                            .iter()
+                            .map(|batch| access.filter_batch(&name, batch))
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .context(FilteringRows { table_name: &name })?
+            };
+            // a table without any batch has no schema to plan the query with
+            let schema = partitions
+                .iter()
+                .flatten()
+                .next()
+                .map(|batch| batch.schema())
+                .context(QueryTableNotFound {
+                    query,
+                    table_name: &name,
+                })?;
+            tables.push(ArrowTable {
+                name,
+                schema,
+                partitions,
+            });
+        }
+
+        // ASOF joins are planned as joins with the rows of the right table they match
+        let (plain, directions) = asof::strip_keywords(query);
+        let query = &if directions.iter().any(Option::is_some) {
+            let mut statements = parse_sql(&plain)?;
+            let joins =
+                asof::rewrite(&mut statements, &directions).context(AsofJoinError { query })?;
+            for join in &joins {
+                let rows = |name: &str| -> Vec<RecordBatch> {
+                    tables
+                        .iter()
+                        .filter(|table| table.name == name)
+                        .flat_map(|table| table.partitions.iter().flatten().cloned())
+                        .collect()
+                };
+                let aligned = asof::align(join, &rows(&join.left_table), &rows(&join.right_table))
+                    .context(AsofJoinError { query })?;
+                if let Some(batch) = aligned.first() {
+                    tables.push(ArrowTable {
+                        name: join.aligned_table.clone(),
+                        schema: batch.schema(),
+                        partitions: vec![aligned],
+                    });
+                }
+            }
+            statements[0].to_string()
+        } else {
+            query.clone()
+        };
+
+        let config = ExecutionConfig::new()
+            .with_batch_size(1024 * 1024)
+            .with_concurrency(concurrency.max(1));
+        let mut ctx = ExecutionContext::with_config(config);
+
+        for table in tables {
+            let provider =
+                MemTable::new(table.schema, table.partitions).context(QueryError { query })?;
+            ctx.register_table(&table.name, Box::new(provider));
+        }
+        ctx.register_udf(window::date_bin_udf());
+        for udf in analytic::udfs() {
+            ctx.register_udf(udf);
+        }
+        ctx.register_udf(histogram::histogram_quantile_udf());
+        for udaf in quantile::udafs() {
+            ctx.register_udaf(udaf);
+        }
+        for udf in wasm::udfs() {
+            ctx.register_udf(udf);
+        }
+
+        let plan = info_span!("plan").in_scope(|| {
+            let plan = ctx
+                .create_logical_plan(&query)
+                .context(QueryError { query })?;
+            let plan = ctx.optimize(&plan).context(QueryError { query })?;
+            ctx.create_physical_plan(&plan)
+                .context(QueryError { query })
+        })?;
+
+        // executing the plan is CPU heavy, so it runs on the query pool rather than alongside
+        // the handling of writes
+        pool::spawn(async move { ctx.collect(plan).await }.instrument(info_span!("execute")))
+            .await
+            .context(QueryJoinError { query })?
+            .context(QueryError { query })
+    }
+
+    /// Returns the aggregates of the rollup rule whose destination is `table_name`, if any
+    fn rollup_to_arrow(&self, table_name: &str) -> Result<Option<RecordBatch>> {
+        self.rollups
+            .lock()
+            .expect("mutex poisoned")
+            .to_arrow(table_name)
+            .transpose()
+            .context(ArrowError)
+    }
+
+    /// Returns the names of the tables with rows in chunk `chunk_id` of partition
+    /// `partition_key`, or an empty list if there is no such chunk
+    pub async fn chunk_table_names(&self, partition_key: &str, chunk_id: u32) -> Vec<String> {
+        let partitions = self.partitions.read().await;
+        let partition = match partitions
+            .iter()
+            .find(|p| p.key == partition_key && p.id == chunk_id)
+        {
+            Some(partition) => partition,
+            None => return vec![],
+        };
+
+        let mut names: Vec<_> = partition
+            .tables
+            .keys()
+            .filter_map(|id| partition.dictionary.lookup_id(*id).ok())
+            .map(str::to_string)
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Converts the rows of the table `table_name` in chunk `chunk_id` of partition
+    /// `partition_key` to Arrow. Only those of `columns` the table has are converted, or all of
+    /// them if `columns` is empty or the table has none of them. The rows persisted as
+    /// increments of the chunk are left out, as they are read from object storage. Returns
+    /// `None` if the chunk has no other rows of the table.
+    pub async fn chunk_table_to_arrow(
+        &self,
+        partition_key: &str,
+        chunk_id: u32,
+        table_name: &str,
+        columns: &[&str],
+    ) -> Result<Option<RecordBatch>> {
+        let partitions = self.partitions.read().await;
+        let partition = partitions
+            .iter()
+            .find(|p| p.key == partition_key && p.id == chunk_id)
+            .context(ChunkNotFound {
+                partition_key,
+                chunk_id,
+            })?;
+
+        let table = match partition
+            .dictionary
+            .id(table_name)
+            .and_then(|id| partition.tables.get(&id))
+        {
+            Some(table) if table.row_count() > table.persisted_rows => table,
+            _ => return Ok(None),
+        };
+
+        let columns = partition.table_columns(table_name, columns);
+        let batch = debug_span!("scan_chunk", partition_key, chunk_id)
+            .in_scope(|| partition.table_to_arrow(table_name, &columns))?;
+        if table.persisted_rows == 0 {
+            return Ok(Some(batch));
+        }
+
+        let len = batch.num_rows() - table.persisted_rows;
+        let unpersisted = batch
+            .columns()
+            .iter()
+            .map(|column| column.slice(table.persisted_rows, len))
+            .collect();
+        let batch = RecordBatch::try_new(batch.schema(), unpersisted).context(ArrowError)?;
+        Ok(Some(batch))
+    }
+
+    /// Drops the closed chunk `chunk_id` of partition `partition_key`, for example once its
+    /// data was moved elsewhere, returning its summary
+    pub async fn drop_chunk(&self, partition_key: &str, chunk_id: u32) -> Result<ChunkSummary> {
+        let mut partitions = self.partitions.write().await;
+        let index = partitions
+            .iter()
+            .position(|p| p.key == partition_key && p.id == chunk_id)
+            .context(ChunkNotFound {
+                partition_key,
+                chunk_id,
+            })?;
+        ensure!(
+            !partitions[index].is_open,
+            ChunkIsOpen {
+                partition_key,
+                chunk_id
+            }
+        );
+
+        self.chunk_dropped(partition_key, chunk_id);
+        self.data_changed();
+        Ok(partitions.remove(index).chunk_summary())
+    }
+
+    /// Returns the statistics and size of every column in the database, by chunk
+    /// Returns the type of each column of each table, keyed by table and column name, as
+    /// described by `ColumnSummary`
+    pub async fn column_types(&self) -> BTreeMap<(String, String), String> {
+        self.column_summaries()
+            .await
+            .into_iter()
+            .map(|c| ((c.table_name, c.column_name), c.column_type))
+            .collect()
+    }
+
+    pub async fn column_summaries(&self) -> Vec<ColumnSummary> {
+        self.partitions
+            .read()
+            .await
+            .iter()
+            .flat_map(Partition::column_summaries)
+            .collect()
+    }
+
+    /// Packs the rows with a time in `range` of each table of each chunk, or all the rows if
+    /// there is no range. When set, only the chunks of the partition `partition_key` and only
+    /// the table `table_name` are exported. Tables without any matching rows are left out.
+    pub async fn export(
+        &self,
+        table_name: Option<&str>,
+        partition_key: Option<&str>,
+        range: Option<TimestampRange>,
+    ) -> Result<Vec<ExportedTable>> {
+        let partitions = self.partitions.read().await;
+        let boundary = self.retention_boundary();
+        let mut exported = vec![];
+
+        for partition in partitions.iter() {
+            if partition.is_expired(boundary)
+                || partition_key.map_or(false, |key| key != partition.key)
+            {
+                continue;
+            }
+
+            for table in partition.tables.values() {
+                if let Some(table_name) = table_name {
+                    match partition.dictionary.id(table_name) {
+                        Some(table_id) if table_id == table.id => {}
+                        _ => continue,
+                    }
+                }
+
+                let (schema, columns) = table.to_packers(partition, range.as_ref())?;
+                if columns.first().map_or(0, Packers::num_rows) == 0 {
+                    continue;
+                }
+
+                exported.push(ExportedTable {
+                    partition_key: partition.key.clone(),
+                    chunk_id: partition.id,
+                    schema,
+                    columns,
+                });
+            }
+        }
+
+        exported.sort_by(|a, b| {
+            (&a.partition_key, a.chunk_id, a.schema.measurement()).cmp(&(
+                &b.partition_key,
+                b.chunk_id,
+                b.schema.measurement(),
+            ))
+        });
+        Ok(exported)
+    }
+
+    /// Packs the rows of each table of the open chunk of partition `partition_key` that weren't
+    /// persisted yet, if the chunk has at least `min_rows` of them, to persist them as an
+    /// increment of the chunk. Once an increment is persisted, `mark_persisted` leaves its rows
+    /// out of the rows packed and scanned from then on.
+    pub async fn export_increment(
+        &self,
+        partition_key: &str,
+        min_rows: usize,
+    ) -> Result<Vec<ChunkIncrement>> {
+        let partitions = self.partitions.read().await;
+        let partition = partitions
+            .iter()
+            .find(|p| p.key == partition_key && p.is_open)
+            .context(OpenChunkNotFound { partition_key })?;
+
+        let unpersisted: usize = partition
+            .tables
+            .values()
+            .map(|table| table.row_count() - table.persisted_rows)
+            .sum();
+        if unpersisted == 0 || unpersisted < min_rows {
+            return Ok(vec![]);
+        }
+
+        let mut increments = vec![];
+        for table in partition.tables.values() {
+            let (schema, columns) = table.to_packers(partition, None)?;
+            if columns.first().map_or(0, Packers::num_rows) == 0 {
+                continue;
+            }
+
+            increments.push(ChunkIncrement {
+                table: ExportedTable {
+                    partition_key: partition.key.clone(),
+                    chunk_id: partition.id,
+                    schema,
+                    columns,
+                },
+                persisted_rows: table.row_count(),
+            });
+        }
+        increments.sort_by(|a, b| {
+            a.table
+                .schema
+                .measurement()
+                .cmp(b.table.schema.measurement())
+        });
+        Ok(increments)
+    }
+
+    /// Records that the rows of `increment` were persisted
+    pub async fn mark_persisted(&self, increment: &ChunkIncrement) -> Result<()> {
+        let ExportedTable {
+            partition_key,
+            chunk_id,
+            schema,
+            ..
+        } = &increment.table;
+
+        let mut partitions = self.partitions.write().await;
+        let partition = partitions
+            .iter_mut()
+            .find(|p| &p.key == partition_key && p.id == *chunk_id)
+            .context(ChunkNotFound {
+                partition_key,
+                chunk_id: *chunk_id,
+            })?;
+        let table_id = partition.dictionary.id(schema.measurement());
+        if let Some(table) = table_id.and_then(|id| partition.tables.get_mut(&id)) {
+            table.persisted_rows = table.persisted_rows.max(increment.persisted_rows);
+        }
+
+        Ok(())
+    }
+
+    /// Closes the open chunk for `partition_key` so that it no longer accepts writes, returning
+    /// its summary. Later writes for the partition key go into a new chunk.
+    pub async fn close_chunk(&self, partition_key: &str) -> Result<ChunkSummary> {
+        let mut partitions = self.partitions.write().await;
+
+        let partition = partitions
+            .iter_mut()
+            .find(|p| p.key == partition_key && p.is_open)
+            .context(OpenChunkNotFound { partition_key })?;
+        partition.is_open = false;
+
+        let summary = partition.chunk_summary();
+        metrics::registry()
+            .histogram(
+                "write_buffer_closed_chunk_bytes",
+                "Estimated size of write buffer chunks when they are closed",
+                metrics::SIZE_BUCKETS,
+                &[],
+            )
+            .observe(summary.estimated_bytes as f64);
+
+        Ok(summary)
+    }
+
+    /// Traverse this database's tables, calling the relevant
+    /// functions, in order, of `visitor`, as described on the Visitor
+    /// trait.
+    ///
+    /// Skips visiting any table or columns of `filter.should_visit_table` returns false
+    async fn visit_tables<V: Visitor>(
+        &self,
+        filter: &mut PartitionTableFilter,
+        visitor: &mut V,
+    ) -> Result<()> {
+        let partitions = self.partitions.read().await;
+
+        for partition in partitions.iter() {
+            visitor.pre_visit_partition(partition)?;
+            filter.pre_visit_partition(partition)?;
+
+            for table in partition.tables.values() {
+                if filter.should_visit_table(table)? {
+                    visitor.pre_visit_table(table, partition, filter)?;
+
+                    for (column_id, column_index) in &table.column_id_to_index {
+                        visitor.visit_column(
+                            table,
+                            *column_id,
+                            &table.columns[*column_index],
+                            filter,
+                        )?
+                    }
+
+                    visitor.post_visit_table(table, partition)?;
+                }
+            }
+            visitor.post_visit_partition(partition)?;
+        } // next partition
+
+        Ok(())
+    }
+}
+
+/// Parses the SQL `query`, which may have ASOF joins
+fn parse_sql(query: &str) -> Result<Vec<Statement>> {
+    let dialect = GenericDialect {};
+    let (plain, _) = asof::strip_keywords(query);
+    Parser::parse_sql(&dialect, &plain).context(InvalidSqlQuery { query })
+}
+
+/// Returns the names of the tables `query` selects from, including the tables it joins, in
+/// the order they first appear. Only `SELECT` queries are supported.
+pub fn query_table_names(query: &str) -> Result<Vec<String>> {
+    let ast = parse_sql(query)?;
+
+    let mut names: Vec<String> = vec![];
+    for statement in ast {
+        match statement {
+            Statement::Query(q) => {
+                if let SetExpr::Select(q) = q.body {
+                    let relations = q.from.into_iter().flat_map(|item| {
+                        std::iter::once(item.relation)
+                            .chain(item.joins.into_iter().map(|join| join.relation))
+                    });
+                    for relation in relations {
+                        if let TableFactor::Table { name, .. } = relation {
+                            let name = name.to_string();
+                            if !names.contains(&name) {
+                                names.push(name);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {
+                return UnsupportedStatement {
+                    query: query.to_string(),
+                    statement,
+                }
+                .fail()
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+/// Returns the comparisons of a column with a constant that the rows selected by `query` must
+/// satisfy, keyed by the table they select from, to rule out the chunks that can't have any
+/// matching rows. Only the comparisons `AND`ed together in the `WHERE` clause of a query of a
+/// single table are returned, as the others don't restrict the rows scanned.
+pub fn query_column_predicates(query: &str) -> Result<BTreeMap<String, Vec<ColumnPredicate>>> {
+    let ast = parse_sql(query)?;
+
+    let mut predicates = BTreeMap::new();
+    for statement in ast {
+        let select = match statement {
+            Statement::Query(q) => match q.body {
+                SetExpr::Select(select) => select,
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let table_name = match select.from.as_slice() {
+            [TableWithJoins {
+                relation: TableFactor::Table { name, .. },
+                joins,
+            }] if joins.is_empty() => name.to_string(),
+            _ => continue,
+        };
+
+        let mut comparisons = vec![];
+        if let Some(selection) = &select.selection {
+            conjunct_comparisons(selection, &mut comparisons);
+        }
+        if !comparisons.is_empty() {
+            predicates.insert(table_name, comparisons);
+        }
+    }
+
+    Ok(predicates)
+}
+
+/// Returns the columns of each table that `query` reads, so that the chunks only convert
+/// those, keyed by the table they select from. The time column is always read. Only the
+/// queries of a single table are considered, and a table whose columns are all selected with
+/// `*`, or referenced in expressions that aren't understood, is left out, as all of its
+/// columns are needed.
+pub fn query_columns(query: &str) -> Result<BTreeMap<String, BTreeSet<String>>> {
+    let ast = parse_sql(query)?;
+
+    let mut columns = BTreeMap::new();
+    'statements: for statement in ast {
+        let query = match statement {
+            Statement::Query(q) => q,
+            _ => continue,
+        };
+        let select = match &query.body {
+            SetExpr::Select(select) if query.ctes.is_empty() => select,
+            _ => continue,
+        };
+        let table_name = match select.from.as_slice() {
+            [TableWithJoins {
+                relation: TableFactor::Table { name, .. },
+                joins,
+            }] if joins.is_empty() => name.to_string(),
+            _ => continue,
+        };
+
+        let mut table_columns = BTreeSet::new();
+        table_columns.insert(TIME_COLUMN_NAME.to_string());
+        let mut exprs: Vec<&Expr> = vec![];
+        for item in &select.projection {
+            match item {
+                SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                    exprs.push(expr)
+                }
+                SelectItem::Wildcard | SelectItem::QualifiedWildcard(_) => continue 'statements,
+            }
+        }
+        exprs.extend(&select.selection);
+        exprs.extend(&select.group_by);
+        exprs.extend(&select.having);
+        exprs.extend(query.order_by.iter().map(|order_by| &order_by.expr));
+
+        if exprs
+            .into_iter()
+            .all(|expr| expr_columns(expr, &mut table_columns))
+        {
+            columns.insert(table_name, table_columns);
+        }
+    }
+
+    Ok(columns)
+}
+
+/// Adds to `columns` the columns `expr` references, returning false if some of them can't be
+/// told, when `expr` is `*` or isn't understood
+fn expr_columns(expr: &Expr, columns: &mut BTreeSet<String>) -> bool {
+    match expr {
+        Expr::Identifier(ident) => {
+            columns.insert(ident.value.clone());
+            true
+        }
+        Expr::CompoundIdentifier(idents) => match idents.last() {
+            Some(ident) => {
+                columns.insert(ident.value.clone());
+                true
+            }
+            None => false,
+        },
+        Expr::Value(_) => true,
+        Expr::Nested(expr)
+        | Expr::IsNull(expr)
+        | Expr::IsNotNull(expr)
+        | Expr::UnaryOp { expr, .. }
+        | Expr::Cast { expr, .. } => expr_columns(expr, columns),
+        Expr::BinaryOp { left, right, .. } => {
+            expr_columns(left, columns) && expr_columns(right, columns)
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            expr_columns(expr, columns) && expr_columns(low, columns) && expr_columns(high, columns)
+        }
+        Expr::InList { expr, list, .. } => {
+            expr_columns(expr, columns) && list.iter().all(|expr| expr_columns(expr, columns))
+        }
+        Expr::Function(function) if function.over.is_none() => {
+            function.args.iter().all(|arg| match arg {
+                // as in `count(*)`, which counts the rows without reading any column
+                Expr::Wildcard => true,
+                arg => expr_columns(arg, columns),
+            })
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            operand
+                .iter()
+                .chain(else_result)
+                .all(|expr| expr_columns(expr, columns))
+                && conditions
+                    .iter()
+                    .chain(results)
+                    .all(|expr| expr_columns(expr, columns))
+        }
+        _ => false,
+    }
+}
+
+/// Adds to `comparisons` the comparisons of a column with a constant among the conjuncts of
+/// `expr`
+fn conjunct_comparisons(expr: &Expr, comparisons: &mut Vec<ColumnPredicate>) {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            conjunct_comparisons(left, comparisons);
+            conjunct_comparisons(right, comparisons);
+        }
+        Expr::Nested(expr) => conjunct_comparisons(expr, comparisons),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Like,
+            right,
+        } => {
+            if let (Expr::Identifier(column), Expr::Value(Value::SingleQuotedString(pattern))) =
+                (left.as_ref(), right.as_ref())
+            {
+                comparisons.push(ColumnPredicate {
+                    column_name: column.value.clone(),
+                    op: Comparison::Like,
+                    value: Literal::String(pattern.clone()),
+                });
+            }
+        }
+        Expr::BinaryOp { left, op, right } => {
+            let op = match op {
+                BinaryOperator::Eq => Comparison::Eq,
+                BinaryOperator::Lt => Comparison::Lt,
+                BinaryOperator::LtEq => Comparison::LtEq,
+                BinaryOperator::Gt => Comparison::Gt,
+                BinaryOperator::GtEq => Comparison::GtEq,
+                _ => return,
+            };
+            let predicate = match (left.as_ref(), right.as_ref()) {
+                (Expr::Identifier(column), Expr::Value(value)) => {
+                    literal(value).map(|value| (column, op, value))
+                }
+                (Expr::Value(value), Expr::Identifier(column)) => {
+                    literal(value).map(|value| (column, op.flip(), value))
+                }
+                _ => None,
+            };
+            if let Some((column, op, value)) = predicate {
+                comparisons.push(ColumnPredicate {
+                    column_name: column.value.clone(),
+                    op,
+                    value,
+                });
+            }
+        }
+        _ => {}
+    }
+}
+
+fn literal(value: &Value) -> Option<Literal> {
+    match value {
+        Value::Number(n) => Some(Literal::Number(n.to_string())),
+        Value::SingleQuotedString(s) => Some(Literal::String(s.clone())),
+        _ => None,
+    }
+}
+
+/// Returns `query` without its `ORDER BY` clause if the rows it selects are already in that
+/// order, and `query` itself otherwise. The rows are in order when the query selects plain
+/// columns of a single table whose rows are sorted by `sort_keys`, and orders them ascending by
+/// a prefix of the sort key: filtering and projecting the rows of a table keeps their order.
+pub fn elide_sort(query: &str, sort_keys: &BTreeMap<String, Vec<String>>) -> Result<String> {
+    if sort_keys.is_empty() {
+        return Ok(query.to_string());
+    }
+
+    let mut ast = parse_sql(query)?;
+    match ast.as_mut_slice() {
+        [Statement::Query(q)] if is_sorted_by_order_by(q, sort_keys) => {
+            debug!(
+                "rows are already sorted, dropping the ORDER BY clause of {}",
+                query
+            );
+            q.order_by.clear();
+            Ok(ast[0].to_string())
+        }
+        _ => Ok(query.to_string()),
+    }
+}
+
+fn is_sorted_by_order_by(query: &Query, sort_keys: &BTreeMap<String, Vec<String>>) -> bool {
+    if query.order_by.is_empty() || !query.ctes.is_empty() {
+        return false;
+    }
+
+    let select = match &query.body {
+        SetExpr::Select(select) => select,
+        _ => return false,
+    };
+    if select.distinct || !select.group_by.is_empty() || select.having.is_some() {
+        return false;
+    }
+
+    let sort_key = match select.from.as_slice() {
+        [TableWithJoins {
+            relation: TableFactor::Table { name, .. },
+            joins,
+        }] if joins.is_empty() => match sort_keys.get(&name.to_string()) {
+            Some(sort_key) => sort_key,
+            None => return false,
+        },
+        _ => return false,
+    };
+
+    let plain_columns = select.projection.iter().all(|item| {
+        matches!(
+            item,
+            SelectItem::Wildcard | SelectItem::UnnamedExpr(Expr::Identifier(_))
+        )
+    });
+
+    plain_columns
+        && query.order_by.len() <= sort_key.len()
+        && query
+            .order_by
+            .iter()
+            .zip(sort_key)
+            .all(|(order_by, column)| {
+                order_by.asc != Some(false)
+                    && matches!(&order_by.expr, Expr::Identifier(ident) if ident.to_string() == *column)
+            })
+}
+
+/// Common logic for processing and filtering tables in the write buffer
+///
+/// Note that since each partition has its own dictionary, mappings
+/// between Strings --> we cache the String->id mappings per partition
+///
+/// b) the table doesn't have a column range that overlaps the
+/// predicate values, e.g., if you have env = "us-west" and a
+/// table's env column has the range ["eu-south", "us-north"].
+#[derive(Debug)]
+struct PartitionTableFilter {
+    predicate: Predicate,
+
+    /// If specififed, only tables with all specified columns will be
+    /// visited. Note that just becuase a table has all these columns,
+    /// it might not be visited for other reasons (e.g. it is filted
+    /// out by a table_name predicate)
+    additional_required_columns: Option<HashSet<String>>,
+
+    /// A 'compiled' version of the predicate to evaluate on tables /
+    /// columns in a particular partition during the walk
+    partition_predicate: Option<PartitionPredicate>,
+}
+
+impl PartitionTableFilter {
+    fn new(predicate: Predicate) -> Self {
+        Self {
+            predicate,
+            additional_required_columns: None,
+            partition_predicate: None,
+        }
+    }
+
+    /// adds the specified columns to a list of columns that must be
+    /// present in a table.
+    fn add_required_columns(mut self, column_names: &[String]) -> Self {
+        let mut required_columns = self
+            .additional_required_columns
+            .take()
+            .unwrap_or_else(HashSet::new);
+
+        for c in column_names {
+            if !required_columns.contains(c) {
+                required_columns.insert(c.clone());
+            }
+        }
+
+        self.additional_required_columns = Some(required_columns);
+        self
+    }
+
+    /// Called when each partition gets visited. Since ids are
+    /// specific to each partitition, the predicates much get
+    /// translated each time.
+    fn pre_visit_partition(&mut self, partition: &Partition) -> Result<()> {
+        let mut partition_predicate = partition.compile_predicate(&self.predicate)?;
+
+        // add any additional column needs
+        if let Some(additional_required_columns) = &self.additional_required_columns {
+            partition.add_required_columns_to_predicate(
+                additional_required_columns,
+                &mut partition_predicate,
+            );
+        }
+
+        self.partition_predicate = Some(partition_predicate);
+
+        Ok(())
+    }
+
+    /// If returns false, skips visiting _table and all its columns
+    fn should_visit_table(&mut self, table: &Table) -> Result<bool> {
+        Ok(table.could_match_predicate(self.partition_predicate())?)
+    }
+
+    pub fn partition_predicate(&self) -> &PartitionPredicate {
+        self.partition_predicate
+            .as_ref()
+            .expect("Visited partition to compile predicate")
+    }
+}
+
+/// return all column names in this database, while applying only the
 /// timestamp range (has no general purpose predicates)
 struct NameVisitor {
     column_names: StringSet,
@@ -971,6 +2252,112 @@ impl<'a> Visitor for ValueVisitor<'a> {
     }
 }
 
+/// return the first values of a tag column that start with a prefix,
+/// while applying the timestamp range. Only the values in the
+/// dictionary of each partition are compared with the prefix, rather
+/// than the value of each row.
+struct PrefixValueVisitor<'a> {
+    tag_name: &'a str,
+    prefix: &'a str,
+    // what column id we are looking for
+    column_id: Option<u32>,
+    // the ids of the partition's values that start with the prefix
+    prefix_value_ids: HashSet<u32>,
+    partition_value_ids: BTreeSet<u32>,
+    values: TagValuesCollector<'a>,
+}
+
+impl<'a> PrefixValueVisitor<'a> {
+    fn new(query: &'a TagValuesQuery) -> Self {
+        Self {
+            tag_name: &query.tag_name,
+            prefix: &query.prefix,
+            column_id: None,
+            prefix_value_ids: HashSet::new(),
+            partition_value_ids: BTreeSet::new(),
+            values: query.collector(),
+        }
+    }
+}
+
+impl<'a> Visitor for PrefixValueVisitor<'a> {
+    fn pre_visit_partition(&mut self, partition: &Partition) -> Result<()> {
+        self.partition_value_ids.clear();
+
+        // partitions without the column have none of its values
+        self.column_id = partition.dictionary.id(self.tag_name);
+        self.prefix_value_ids = match self.column_id {
+            Some(_) => partition.dictionary.ids_with_prefix(self.prefix),
+            None => HashSet::new(),
+        };
+
+        Ok(())
+    }
+
+    fn visit_column(
+        &mut self,
+        table: &Table,
+        column_id: u32,
+        column: &Column,
+        filter: &mut PartitionTableFilter,
+    ) -> Result<()> {
+        if Some(column_id) != self.column_id || self.prefix_value_ids.is_empty() {
+            return Ok(());
+        }
+
+        // fields of the same name in other tables have no tag values
+        let column = match column {
+            Column::Tag(column, _) => column,
+            _ => return Ok(()),
+        };
+
+        let partition_predicate = filter.partition_predicate();
+        let prefix_value_ids = &self.prefix_value_ids;
+        let partition_value_ids = &mut self.partition_value_ids;
+        match partition_predicate.range {
+            None => column
+                .iter()
+                .filter_map(|&s| s)
+                .filter(|value_id| prefix_value_ids.contains(value_id))
+                .for_each(|value_id| {
+                    partition_value_ids.insert(value_id);
+                }),
+            Some(range) => {
+                let time_column = table.column_i64(partition_predicate.time_column_id)?;
+
+                column
+                    .iter()
+                    .zip(time_column.iter())
+                    .filter_map(|(&column_value_id, &timestamp_value)| {
+                        if range.contains_opt(timestamp_value) {
+                            column_value_id
+                        } else {
+                            None
+                        }
+                    })
+                    .filter(|value_id| prefix_value_ids.contains(value_id))
+                    .for_each(|value_id| {
+                        partition_value_ids.insert(value_id);
+                    });
+            }
+        }
+        Ok(())
+    }
+
+    fn post_visit_partition(&mut self, partition: &Partition) -> Result<()> {
+        for &value_id in &self.partition_value_ids {
+            let value = partition.dictionary.lookup_id(value_id).context(
+                ColumnValueIdNotFoundInDictionary {
+                    value_id,
+                    partition: &partition.key,
+                },
+            )?;
+            self.values.insert(value);
+        }
+        Ok(())
+    }
+}
+
 /// return all column values for the specified column in this
 /// database, while applying the timestamp range and predicate
 struct ValuePredVisitor<'a> {
@@ -1080,12 +2467,13 @@ pub fn partition_key(line: &ParsedLine<'_>) -> String {
 struct ArrowTable {
     name: String,
     schema: Arc<ArrowSchema>,
-    data: Vec<RecordBatch>,
+    partitions: Vec<Vec<RecordBatch>>,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::partition::restore_partitions_from_wal;
     use arrow_deps::datafusion::{
         logical_plan::{self, Literal},
         scalar::ScalarValue,
@@ -1106,60 +2494,493 @@ mod tests {
         datatypes::DataType,
         util::pretty::pretty_format_batches,
     };
+    use data_types::{chunk::ChunkStorage, database_rules::DatabaseRules, entry::lines_to_entry};
     use influxdb_line_protocol::parse_lines;
     use test_helpers::str_pair_vec_to_vec;
     use tokio::sync::mpsc;
 
-    type TestError = Box<dyn std::error::Error + Send + Sync + 'static>;
-    type Result<T = (), E = TestError> = std::result::Result<T, E>;
+    type TestError = Box<dyn std::error::Error + Send + Sync + 'static>;
+    type Result<T = (), E = TestError> = std::result::Result<T, E>;
+
+    fn to_set(v: &[&str]) -> BTreeSet<String> {
+        v.iter().map(|s| s.to_string()).collect::<BTreeSet<_>>()
+    }
+
+    // Abstract a bit of boilerplate around table assertions and improve the failure output.
+    // The default failure message uses Debug formatting, which prints newlines as `\n`.
+    // This prints the pretty_format_batches using Display so it's easier to read the tables.
+    fn assert_table_eq(table: &str, partitions: &[arrow::record_batch::RecordBatch]) {
+        let res = pretty_format_batches(partitions).unwrap();
+        assert_eq!(table, res, "\n\nleft:\n\n{}\nright:\n\n{}", table, res);
+    }
+
+    // query the table names, with optional range predicate
+    async fn table_names(db: &Db, predicate: Predicate) -> Result<StringSet> {
+        let plan = db.table_names(predicate).await?;
+        let executor = Executor::default();
+        let s = executor.to_string_set(plan).await?;
+
+        // unwrap it (for easy test comparisons)
+        let s = Arc::try_unwrap(s).expect("only one reference to the set in the test");
+        Ok(s)
+    }
+
+    #[tokio::test]
+    async fn list_table_names() -> Result {
+        let mut dir = test_helpers::tmp_dir()?.into_path();
+
+        let db = Db::try_with_wal("mydb", &mut dir).await?;
+
+        // no tables initially
+        assert_eq!(
+            table_names(&db, Predicate::default()).await?,
+            BTreeSet::new()
+        );
+
+        // write two different tables
+        let lines: Vec<_> =
+            parse_lines("cpu,region=west user=23.2 10\ndisk,region=east bytes=99i 11")
+                .map(|l| l.unwrap())
+                .collect();
+        db.write_lines(&lines).await?;
+
+        // Now, we should see the two tables
+        assert_eq!(
+            table_names(&db, Predicate::default()).await?,
+            to_set(&["cpu", "disk"])
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn close_chunk() -> Result {
+        let db = Db::new("mydb");
+
+        let lines: Vec<_> = parse_lines("cpu,region=west user=23.2 10\ndisk bytes=99i 11")
+            .map(|l| l.unwrap())
+            .collect();
+        db.write_lines(&lines).await?;
+
+        let summaries = db.chunk_summaries().await;
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].partition_key, "1970-01-01T00");
+        assert_eq!(summaries[0].id, 0);
+        assert_eq!(summaries[0].storage, ChunkStorage::OpenMutableBuffer);
+        assert_eq!(summaries[0].row_count, 2);
+        assert!(summaries[0].estimated_bytes > 0);
+
+        let closed = db.close_chunk("1970-01-01T00").await?;
+        assert_eq!(closed.id, 0);
+        assert_eq!(closed.storage, ChunkStorage::ClosedMutableBuffer);
+
+        let err = db.close_chunk("1970-01-01T00").await.unwrap_err();
+        assert!(matches!(err, Error::OpenChunkNotFound { .. }));
+
+        // new writes go into a new chunk, but are still queryable with the old ones
+        db.write_lines(&lines).await?;
+        let summaries = db.chunk_summaries().await;
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[1].id, 1);
+        assert_eq!(summaries[1].storage, ChunkStorage::OpenMutableBuffer);
+
+        assert_eq!(
+            table_names(&db, Predicate::default()).await?,
+            to_set(&["cpu", "disk"])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn finds_column_predicates() {
+        let predicates = query_column_predicates(
+            "select * from cpu where host = 'a' and (5 < time and usage >= 0.5) and \
+             (region = 'west' or region = 'east') and usage + 1 > 2 and \
+             message like '%timeout%' and 'x' like message",
+        )
+        .unwrap();
+        let predicate = |column_name: &str, op, value| ColumnPredicate {
+            column_name: column_name.to_string(),
+            op,
+            value,
+        };
+        assert_eq!(
+            predicates["cpu"],
+            vec![
+                predicate("host", Comparison::Eq, Literal::String("a".to_string())),
+                predicate("time", Comparison::Gt, Literal::Number("5".to_string())),
+                predicate(
+                    "usage",
+                    Comparison::GtEq,
+                    Literal::Number("0.5".to_string())
+                ),
+                predicate(
+                    "message",
+                    Comparison::Like,
+                    Literal::String("%timeout%".to_string())
+                ),
+            ]
+        );
+
+        // disjunctions and joins don't restrict the rows scanned
+        for query in &[
+            "select * from cpu",
+            "select * from cpu where host = 'a' or host = 'b'",
+            "select * from cpu join mem on cpu.host = mem.host where cpu.host = 'a'",
+        ] {
+            assert!(
+                query_column_predicates(query).unwrap().is_empty(),
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn finds_query_columns() {
+        let columns = query_columns(
+            "select host, avg(usage) as usage, count(*) from cpu \
+             where region = 'west' and cpu.core in (1, 2) group by host order by host",
+        )
+        .unwrap();
+        assert_eq!(
+            columns["cpu"],
+            to_set(&["core", "host", "region", "time", "usage"])
+        );
+        assert_eq!(
+            query_columns("select count(*) from cpu").unwrap()["cpu"],
+            to_set(&["time"])
+        );
+
+        // all the columns are read when they can't be told
+        for query in &[
+            "select * from cpu",
+            "select host, * from cpu where usage > 1",
+            "select host from cpu join mem on cpu.host = mem.host",
+            "select host from (select * from cpu)",
+            "select host from cpu where exists (select * from mem)",
+        ] {
+            assert!(query_columns(query).unwrap().is_empty(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn elides_sorts() {
+        let sort_keys: BTreeMap<_, _> = vec![(
+            "cpu".to_string(),
+            vec!["host".to_string(), "time".to_string()],
+        )]
+        .into_iter()
+        .collect();
+        let unsorted = |query: &str| {
+            let dialect = GenericDialect {};
+            Parser::parse_sql(&dialect, query).unwrap()[0].to_string()
+        };
+
+        for (query, expected) in &[
+            (
+                "select host, usage from cpu where usage > 1 order by host",
+                "select host, usage from cpu where usage > 1",
+            ),
+            (
+                "select * from cpu order by host, time asc limit 2",
+                "select * from cpu limit 2",
+            ),
+        ] {
+            assert_eq!(elide_sort(query, &sort_keys).unwrap(), unsorted(expected));
+        }
+
+        // queries whose rows need sorting are kept
+        for query in &[
+            "select * from cpu",
+            "select * from cpu order by time",
+            "select * from cpu order by host desc",
+            "select host, count(usage) from cpu group by host order by host",
+            "select distinct host from cpu order by host",
+            "select * from mem order by host",
+        ] {
+            assert_eq!(elide_sort(query, &sort_keys).unwrap(), *query);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunk_access() -> Result {
+        let db = Db::new("mydb");
+
+        let lines: Vec<_> = parse_lines("cpu,region=west user=23.2 10\ndisk bytes=99i 11")
+            .map(|l| l.unwrap())
+            .collect();
+        db.write_lines(&lines).await?;
+
+        assert_eq!(
+            db.chunk_table_names("1970-01-01T00", 0).await,
+            vec!["cpu", "disk"]
+        );
+        assert!(db.chunk_table_names("1970-01-01T00", 1).await.is_empty());
+
+        let batch = db
+            .chunk_table_to_arrow("1970-01-01T00", 0, "cpu", &[])
+            .await?
+            .unwrap();
+        assert_eq!(batch.num_rows(), 1);
+        // only the columns asked for are converted, in the order of the columns of the table,
+        // leaving out those the table doesn't have
+        let batch = db
+            .chunk_table_to_arrow("1970-01-01T00", 0, "cpu", &["time", "region", "missing"])
+            .await?
+            .unwrap();
+        let names: Vec<_> = batch
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect();
+        assert_eq!(names, vec!["region", "time"]);
+        assert!(db
+            .chunk_table_to_arrow("1970-01-01T00", 0, "mem", &[])
+            .await?
+            .is_none());
+        let err = db
+            .chunk_table_to_arrow("1970-01-01T00", 1, "cpu", &[])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ChunkNotFound { .. }));
+
+        // only closed chunks can be dropped
+        let err = db.drop_chunk("1970-01-01T00", 0).await.unwrap_err();
+        assert!(matches!(err, Error::ChunkIsOpen { .. }));
+        db.close_chunk("1970-01-01T00").await?;
+        let dropped = db.drop_chunk("1970-01-01T00", 0).await?;
+        assert_eq!(dropped.row_count, 2);
+        assert!(db.chunk_summaries().await.is_empty());
+
+        // the ids of dropped chunks are not reused
+        db.write_lines(&lines).await?;
+        assert_eq!(db.chunk_summaries().await[0].id, 1);
 
-    fn to_set(v: &[&str]) -> BTreeSet<String> {
-        v.iter().map(|s| s.to_string()).collect::<BTreeSet<_>>()
-    }
+        assert_eq!(
+            query_table_names("select * from cpu, disk where cpu.time = disk.time")?,
+            vec!["cpu", "disk"]
+        );
+        assert_eq!(
+            query_table_names(
+                "select * from cpu c asof join mem on c.host = mem.host \
+                 left join cpu p on c.host = p.host join hosts using (host)"
+            )?,
+            vec!["cpu", "mem", "hosts"]
+        );
+        assert!(matches!(
+            query_table_names("drop table cpu"),
+            Err(Error::UnsupportedStatement { .. })
+        ));
 
-    // Abstract a bit of boilerplate around table assertions and improve the failure output.
-    // The default failure message uses Debug formatting, which prints newlines as `\n`.
-    // This prints the pretty_format_batches using Display so it's easier to read the tables.
-    fn assert_table_eq(table: &str, partitions: &[arrow::record_batch::RecordBatch]) {
-        let res = pretty_format_batches(partitions).unwrap();
-        assert_eq!(table, res, "\n\nleft:\n\n{}\nright:\n\n{}", table, res);
+        Ok(())
     }
 
-    // query the table names, with optional range predicate
-    async fn table_names(db: &Db, predicate: Predicate) -> Result<StringSet> {
-        let plan = db.table_names(predicate).await?;
-        let executor = Executor::default();
-        let s = executor.to_string_set(plan).await?;
+    #[tokio::test]
+    async fn export() -> Result {
+        let db = Db::new("mydb");
 
-        // unwrap it (for easy test comparisons)
-        let s = Arc::try_unwrap(s).expect("only one reference to the set in the test");
-        Ok(s)
+        let lines: Vec<_> = parse_lines(
+            "cpu,region=west user=23.2 10
+disk bytes=99i 11",
+        )
+        .map(|l| l.unwrap())
+        .collect();
+        db.write_lines(&lines).await?;
+        db.close_chunk("1970-01-01T00").await?;
+        let lines: Vec<_> = parse_lines("cpu,region=east user=21.0 20")
+            .map(|l| l.unwrap())
+            .collect();
+        db.write_lines(&lines).await?;
+
+        let exported = db.export(None, None, None).await?;
+        let names: Vec<_> = exported
+            .iter()
+            .map(|t| (t.chunk_id, t.schema.measurement(), t.columns[0].num_rows()))
+            .collect();
+        assert_eq!(names, vec![(0, "cpu", 1), (0, "disk", 1), (1, "cpu", 1)]);
+
+        let exported = db
+            .export(
+                Some("cpu"),
+                Some("1970-01-01T00"),
+                Some(TimestampRange::new(15, 25)),
+            )
+            .await?;
+        assert_eq!(exported.len(), 1);
+        assert_eq!(exported[0].chunk_id, 1);
+
+        assert!(db
+            .export(None, Some("2020-01-01T00"), None)
+            .await?
+            .is_empty());
+        assert!(db.export(Some("mem"), None, None).await?.is_empty());
+
+        Ok(())
     }
 
     #[tokio::test]
-    async fn list_table_names() -> Result {
-        let mut dir = test_helpers::tmp_dir()?.into_path();
+    async fn chunk_increments() -> Result {
+        let db = Db::new("mydb");
+        let write = |lp: &'static str| {
+            let lines: Vec<_> = parse_lines(lp).map(|l| l.unwrap()).collect();
+            let db = &db;
+            async move { db.write_lines(&lines).await }
+        };
 
-        let db = Db::try_with_wal("mydb", &mut dir).await?;
+        write("cpu,region=west user=23.2 10\ndisk bytes=99i 11").await?;
+        assert!(db.export_increment("1970-01-01T00", 3).await?.is_empty());
+        let increments = db.export_increment("1970-01-01T00", 2).await?;
+        let names: Vec<_> = increments
+            .iter()
+            .map(|i| (i.table.schema.measurement(), i.persisted_rows))
+            .collect();
+        assert_eq!(names, vec![("cpu", 1), ("disk", 1)]);
 
-        // no tables initially
+        // rows written while the increment is persisted are left for the next one
+        write("cpu,region=east user=21.0 20").await?;
+        for increment in &increments {
+            db.mark_persisted(increment).await?;
+        }
+        let increments = db.export_increment("1970-01-01T00", 1).await?;
+        assert_eq!(increments.len(), 1);
+        assert_eq!(increments[0].table.columns[0].num_rows(), 1);
+        assert_eq!(increments[0].persisted_rows, 2);
+        db.mark_persisted(&increments[0]).await?;
+        assert!(db.export_increment("1970-01-01T00", 0).await?.is_empty());
+
+        // the persisted rows are left out of the rows scanned and exported, but not queried
+        write("cpu,region=north user=25.0 30").await?;
+        let batch = db
+            .chunk_table_to_arrow("1970-01-01T00", 0, "cpu", &[])
+            .await?
+            .unwrap();
+        assert_eq!(batch.num_rows(), 1);
+        assert!(db
+            .chunk_table_to_arrow("1970-01-01T00", 0, "disk", &[])
+            .await?
+            .is_none());
+        let exported = db.export(None, None, None).await?;
+        assert_eq!(exported.len(), 1);
+        assert_eq!(exported[0].columns[0].num_rows(), 1);
+        assert_eq!(db.chunk_summaries().await[0].row_count, 4);
+
+        db.close_chunk("1970-01-01T00").await?;
+        let err = db.export_increment("1970-01-01T00", 0).await.unwrap_err();
+        assert!(matches!(err, Error::OpenChunkNotFound { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retention_period() -> Result {
+        let db = Db::new("mydb");
+
+        let now = Utc::now().timestamp_nanos();
+        let lp = format!(
+            "cpu,region=west user=23.2 10\ndisk bytes=99i {}\nmem used=1i {}",
+            now - 10,
+            now
+        );
+        let lines: Vec<_> = parse_lines(&lp).map(|l| l.unwrap()).collect();
+        db.write_lines(&lines).await?;
+        let chunks = db.chunk_summaries().await;
+        assert_eq!(chunks.len(), 2);
+
+        // no retention period, nothing is dropped or filtered
+        assert!(db.drop_expired_chunks().await.is_empty());
         assert_eq!(
             table_names(&db, Predicate::default()).await?,
-            BTreeSet::new()
+            to_set(&["cpu", "disk", "mem"])
         );
 
-        // write two different tables
-        let lines: Vec<_> =
-            parse_lines("cpu,region=west user=23.2 10\ndisk,region=east bytes=99i 11")
-                .map(|l| l.unwrap())
-                .collect();
-        db.write_lines(&lines).await?;
+        db.set_retention_period(Some(Duration::from_secs(3600)));
 
-        // Now, we should see the two tables
+        // the old data is excluded from queries even before it is dropped
         assert_eq!(
             table_names(&db, Predicate::default()).await?,
-            to_set(&["cpu", "disk"])
+            to_set(&["disk", "mem"])
         );
+        let predicate = PredicateBuilder::default().timestamp_range(0, 100).build();
+        assert_eq!(table_names(&db, predicate).await?, BTreeSet::new());
+
+        let dropped = db.drop_expired_chunks().await;
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].partition_key, "1970-01-01T00");
+        assert_eq!(db.chunk_summaries().await.len(), 1);
+
+        // a table whose partitions are all expired is no longer found by queries
+        let db = Db::new("mydb");
+        let lines: Vec<_> = parse_lines("cpu user=23.2 10")
+            .map(|l| l.unwrap())
+            .collect();
+        db.write_lines(&lines).await?;
+        db.set_retention_period(Some(Duration::from_secs(3600)));
+        let err = db.query("select * from cpu").await.unwrap_err();
+        assert!(matches!(err, Error::QueryTableNotFound { .. }), "{}", err);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_limits() -> Result {
+        let db = Db::new("mydb");
+        db.set_write_limits(WriteLimits {
+            partition_size: Some(1),
+            buffer_size: None,
+        });
+
+        let write = |lp: &'static str| {
+            let db = &db;
+            async move {
+                let lines: Vec<_> = parse_lines(lp).map(|l| l.unwrap()).collect();
+                db.write_lines(&lines).await
+            }
+        };
+
+        // the partition is over its limit once written to
+        write("cpu bar=1 10").await?;
+        let err = write("cpu bar=2 20").await.unwrap_err();
+        assert!(matches!(err, Error::WriteThrottled { .. }), "{}", err);
+        assert_eq!(err.retry_after(), Some(WRITE_RETRY_AFTER));
+
+        // other partitions aren't throttled
+        write("cpu bar=3 3600000000000").await?;
+
+        // closing the chunk makes room for writes to the partition
+        db.close_chunk("1970-01-01T00").await?;
+        write("cpu bar=2 20").await?;
+
+        db.set_write_limits(WriteLimits {
+            partition_size: None,
+            buffer_size: Some(1),
+        });
+        let err = write("mem bar=1 7200000000000").await.unwrap_err();
+        assert!(matches!(err, Error::WriteThrottled { .. }), "{}", err);
+        assert_eq!(db.chunk_summaries().await.len(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn data_version() -> Result {
+        let db = Db::new("mydb");
+        let version = || db.data_version().unwrap();
+        let initial = version();
+
+        let lines: Vec<_> = parse_lines("cpu bar=1 10").map(|l| l.unwrap()).collect();
+        db.write_lines(&lines).await?;
+        let written = version();
+        assert!(written > initial);
+
+        // closing a chunk doesn't change the data
+        db.close_chunk("1970-01-01T00").await?;
+        assert_eq!(version(), written);
+
+        db.drop_chunk("1970-01-01T00", 0).await?;
+        assert!(version() > written);
 
         Ok(())
     }
@@ -1309,6 +3130,7 @@ mod tests {
                 .map(|l| l.unwrap())
                 .collect();
             db.write_lines(&lines).await?;
+            db.sync_wal().await?;
 
             let partitions = db.table_to_arrow("cpu", cpu_columns).await?;
             assert_table_eq(expected_cpu_table, &partitions);
@@ -1360,6 +3182,434 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn query_date_bin() -> Result {
+        let db = Db::new("foo");
+
+        // 2020-11-01T03:00:00Z, 2020-11-02T04:30:00Z and 2020-11-02T05:00:00Z
+        let lines: Vec<_> = parse_lines(
+            "cpu user=1 1604199600000000000\n\
+             cpu user=2 1604291400000000000\n\
+             cpu user=4 1604293200000000000",
+        )
+        .map(|l| l.unwrap())
+        .collect();
+        db.write_lines(&lines).await?;
+
+        // New York days, which start at 04:00 UTC until the clocks go back on 2020-11-01
+        let results = db
+            .query(
+                "select date_bin(time, '1d', '', 'America/New_York') as time, sum(user) \
+                 from cpu group by date_bin(time, '1d', '', 'America/New_York') order by time",
+            )
+            .await?;
+
+        let expected = r#"+---------------------+-----------+
+| time                | SUM(user) |
++---------------------+-----------+
+| 1604116800000000000 | 1         |
+| 1604203200000000000 | 2         |
+| 1604293200000000000 | 4         |
++---------------------+-----------+
+"#;
+        assert_table_eq(expected, &results);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_derivative() -> Result {
+        let db = Db::new("foo");
+
+        // a counter, reset at 30s
+        let lines: Vec<_> = parse_lines(
+            "net bytes=100 0\n\
+             net bytes=400 10000000000\n\
+             net bytes=1000 20000000000\n\
+             net bytes=50 30000000000",
+        )
+        .map(|l| l.unwrap())
+        .collect();
+        db.write_lines(&lines).await?;
+
+        let results = db
+            .query(
+                "select time, non_negative_derivative(bytes, time, '1s') as rate, \
+                 difference(bytes) as diff, cumulative_sum(bytes) as total from net",
+            )
+            .await?;
+
+        let expected = r#"+-------------+------+------+-------+
+| time        | rate | diff | total |
++-------------+------+------+-------+
+| 0           |      |      | 100   |
+| 10000000000 | 30   | 300  | 500   |
+| 20000000000 | 60   | 600  | 1500  |
+| 30000000000 |      | -950 | 1550  |
++-------------+------+------+-------+
+"#;
+        assert_table_eq(expected, &results);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_histogram_quantile() -> Result {
+        let db = Db::new("foo");
+
+        let lines: Vec<_> = parse_lines(
+            "latency,path=/a count=4,buckets=\"1:2,2:4,+Inf:4\" 10\n\
+             latency,path=/b count=0,buckets=\"1:0,2:0,+Inf:0\" 10",
+        )
+        .map(|l| l.unwrap())
+        .collect();
+        db.write_lines(&lines).await?;
+
+        let results = db
+            .query(
+                "select path, histogram_quantile(0.75, buckets) as p75 from latency \
+                 order by path",
+            )
+            .await?;
+
+        let expected = r#"+------+-----+
+| path | p75 |
++------+-----+
+| /a   | 1.5 |
+| /b   |     |
++------+-----+
+"#;
+        assert_table_eq(expected, &results);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_approx_percentiles() -> Result {
+        let db = Db::new("foo");
+
+        let lp: Vec<_> = (1..=100)
+            .map(|i| format!("latency,path=/{} value={}i {}", i % 2, i, i))
+            .collect();
+        let lp = lp.join("\n");
+        let lines: Vec<_> = parse_lines(&lp).map(|l| l.unwrap()).collect();
+        db.write_lines(&lines).await?;
+
+        let results = db
+            .query(
+                "select path, approx_median(value) as p50, \
+                 approx_percentile(value, 0.9) as p90 from latency \
+                 group by path order by path",
+            )
+            .await?;
+
+        let expected = r#"+------+-----+-----+
+| path | p50 | p90 |
++------+-----+-----+
+| /0   | 51  | 91  |
+| /1   | 50  | 90  |
++------+-----+-----+
+"#;
+        assert_table_eq(expected, &results);
+
+        let err = db
+            .query("select approx_percentile(value, 2) from latency")
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("percentile between 0 and 1"),
+            "{}",
+            err
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_rollups() -> Result {
+        let db = Db::new("foo");
+        db.set_rollup_rules(&[RollupRule {
+            table: "cpu".to_string(),
+            destination_table: "cpu_1m".to_string(),
+            every: Duration::from_secs(60),
+            fields: vec!["usage".to_string()],
+        }]);
+
+        let lines: Vec<_> = parse_lines(
+            "cpu,host=a usage=1,idle=9 0\n\
+             cpu,host=a usage=3 30000000000\n\
+             cpu,host=b usage=2 90000000000",
+        )
+        .map(|l| l.unwrap())
+        .collect();
+        let entry = lines_to_entry(1, 1, &lines, &DatabaseRules::default())?;
+        db.store_entry(&entry).await?;
+        // a replayed entry isn't aggregated twice
+        db.store_entry(&entry).await?;
+
+        let results = db
+            .query(
+                "select host, time, usage_count, usage_sum, usage_max from cpu_1m \
+                 order by host",
+            )
+            .await?;
+
+        let expected = r#"+------+-------------+-------------+-----------+-----------+
+| host | time        | usage_count | usage_sum | usage_max |
++------+-------------+-------------+-----------+-----------+
+| a    | 0           | 2           | 4         | 3         |
+| b    | 60000000000 | 1           | 2         | 2         |
++------+-------------+-------------+-----------+-----------+
+"#;
+        assert_table_eq(expected, &results);
+
+        // the rows are still written to their own table
+        let results = db.query("select count(usage) as n from cpu").await?;
+        let expected = r#"+---+
+| n |
++---+
+| 3 |
++---+
+"#;
+        assert_table_eq(expected, &results);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn joins_tables() -> Result {
+        let db = Db::new("mydb");
+        let lines: Vec<_> = parse_lines(
+            "cpu,host=a usage=1 10\n\
+             cpu,host=a usage=2 20\n\
+             cpu,host=b usage=3 20\n\
+             mem,host=a free=100 5\n\
+             mem,host=a free=200 18\n\
+             mem,host=b free=300 40",
+        )
+        .map(|l| l.unwrap())
+        .collect();
+        db.write_lines(&lines).await?;
+
+        let results = db
+            .query(
+                "select usage, free from cpu join mem on cpu.host = mem.host order by usage, free",
+            )
+            .await?;
+        let expected = r#"+-------+------+
+| usage | free |
++-------+------+
+| 1     | 100  |
+| 1     | 200  |
+| 2     | 100  |
+| 2     | 200  |
+| 3     | 300  |
++-------+------+
+"#;
+        assert_table_eq(expected, &results);
+
+        // each row of cpu is matched with the latest row of mem of its host at or before it
+        let results = db
+            .query(
+                "select usage, free, mem.time, asof_time from cpu \
+                 asof join mem on cpu.host = mem.host order by usage",
+            )
+            .await?;
+        let expected = r#"+-------+------+------+-----------+
+| usage | free | time | asof_time |
++-------+------+------+-----------+
+| 1     | 100  | 5    | 10        |
+| 2     | 200  | 18   | 20        |
++-------+------+------+-----------+
+"#;
+        assert_table_eq(expected, &results);
+
+        // or with the nearest one, keeping the rows without a match
+        let results = db
+            .query(
+                "select usage, free from cpu \
+                 asof nearest left join mem using (host) order by usage",
+            )
+            .await?;
+        let expected = r#"+-------+------+
+| usage | free |
++-------+------+
+| 1     | 100  |
+| 2     | 200  |
+| 3     | 300  |
++-------+------+
+"#;
+        assert_table_eq(expected, &results);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn store_entries_and_recover() -> Result {
+        let mut dir = test_helpers::tmp_dir()?.into_path();
+        let rules = DatabaseRules::default();
+
+        let expected_cpu_table = r#"+------+------+-------+------+---------+
+| host | user | other | time | new_tag |
++------+------+-------+------+---------+
+| A    | 23.2 | 1     | 10   |         |
+| B    | 23.1 |       | 15   |         |
+| A    | 15.1 |       | 20   | foo     |
++------+------+-------+------+---------+
+"#;
+        let cpu_columns = &["host", "user", "other", "time", "new_tag"];
+
+        {
+            let db = Db::try_with_wal("mydb", &mut dir).await?;
+            let lines: Vec<_> =
+                parse_lines("cpu,host=A user=23.2,other=1i 10\ncpu,host=B user=23.1 15")
+                    .map(|l| l.unwrap())
+                    .collect();
+            db.store_entry(&lines_to_entry(1, 1, &lines, &rules)?)
+                .await?;
+            let lines: Vec<_> = parse_lines("cpu,host=A,new_tag=foo user=15.1 20")
+                .map(|l| l.unwrap())
+                .collect();
+            db.store_entry(&lines_to_entry(1, 2, &lines, &rules)?)
+                .await?;
+
+            let partitions = db.table_to_arrow("cpu", cpu_columns).await?;
+            assert_table_eq(expected_cpu_table, &partitions);
+        }
+
+        // check that it recovers from the wal
+        {
+            let db = Db::restore_from_wal(dir).await?;
+
+            let partitions = db.table_to_arrow("cpu", cpu_columns).await?;
+            assert_table_eq(expected_cpu_table, &partitions);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_applied_entries() -> Result {
+        let mut dir = test_helpers::tmp_dir()?.into_path();
+        let rules = DatabaseRules::default();
+
+        let expected_cpu_table = r#"+-----+------+
+| bar | time |
++-----+------+
+| 1   | 10   |
+| 2   | 20   |
+| 3   | 30   |
++-----+------+
+"#;
+        let entry = |producer_id, sequence_number, lp| {
+            let lines: Vec<_> = parse_lines(lp).map(|l| l.unwrap()).collect();
+            lines_to_entry(producer_id, sequence_number, &lines, &rules).unwrap()
+        };
+
+        {
+            let db = Db::try_with_wal("mydb", &mut dir).await?;
+            let first = entry(1, 5, "cpu bar=1 10");
+            db.store_entry(&first).await?;
+            // delivered again, and an older entry of the same producer
+            db.store_entry(&first).await?;
+            db.store_entry(&entry(1, 4, "cpu bar=4 40")).await?;
+            // the sequence numbers of other producers are independent
+            db.store_entry(&entry(2, 1, "cpu bar=2 20")).await?;
+            db.store_entry(&entry(1, 6, "cpu bar=3 30")).await?;
+
+            let partitions = db.table_to_arrow("cpu", &["bar", "time"]).await?;
+            assert_table_eq(expected_cpu_table, &partitions);
+
+            // a WAL holding the same entry twice, as it would after a crash between writing
+            // an entry to the WAL and acknowledging it
+            db.wal_details
+                .as_ref()
+                .unwrap()
+                .write_and_sync(first.into_data())
+                .await?;
+        }
+
+        let mut replayed = 0;
+        let db = Db::restore_from_wal_with_progress(dir, |count| replayed = count).await?;
+        assert_eq!(replayed, 4);
+
+        let partitions = db.table_to_arrow("cpu", &["bar", "time"]).await?;
+        assert_table_eq(expected_cpu_table, &partitions);
+
+        let sequences = db.sequences().await;
+        assert_eq!(sequences.high_water_mark("", 1), Some(6));
+        assert_eq!(sequences.high_water_mark("", 2), Some(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_entries_that_cant_be_restored() -> Result {
+        let mut dir = test_helpers::tmp_dir()?.into_path();
+        let rules = DatabaseRules::default();
+
+        let expected_cpu_table = r#"+-----+------+
+| bar | time |
++-----+------+
+| 1   | 10   |
+| 3   | 30   |
++-----+------+
+"#;
+        let entry = |sequence_number, lp| {
+            let lines: Vec<_> = parse_lines(lp).map(|l| l.unwrap()).collect();
+            lines_to_entry(1, sequence_number, &lines, &rules).unwrap()
+        };
+
+        {
+            let db = Db::try_with_wal("mydb", &mut dir).await?;
+            db.store_entry(&entry(1, "cpu bar=1 10")).await?;
+            // an entry cut short, which is still marked as one
+            let mut poisoned = entry(2, "cpu bar=2 20").into_data();
+            poisoned.truncate(8);
+            db.wal_details
+                .as_ref()
+                .unwrap()
+                .write_and_sync(poisoned)
+                .await?;
+            db.store_entry(&entry(3, "cpu bar=3 30")).await?;
+        }
+
+        // the replay stops at the second entry of the WAL
+        let mut progress = ReplayProgress::default();
+        let err = Db::restore_from_wal_skipping(dir.clone(), |_| false, |p| progress = *p)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::WalRecoverError { .. }), "{}", err);
+        let expected = ReplayProgress {
+            replayed: 1,
+            skipped: 0,
+            next_sequence_number: 1,
+            end_sequence_number: 3,
+        };
+        assert_eq!(progress, expected);
+        assert_eq!(progress.lag(), 2);
+
+        let db = Db::restore_from_wal_skipping(
+            dir,
+            |sequence_number| sequence_number == 1,
+            |p| progress = *p,
+        )
+        .await?;
+        let expected = ReplayProgress {
+            replayed: 2,
+            skipped: 1,
+            next_sequence_number: 3,
+            end_sequence_number: 3,
+        };
+        assert_eq!(progress, expected);
+        assert_eq!(progress.lag(), 0);
+
+        let partitions = db.table_to_arrow("cpu", &["bar", "time"]).await?;
+        assert_table_eq(expected_cpu_table, &partitions);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn recover_partial_entries() -> Result {
         let mut dir = test_helpers::tmp_dir()?.into_path();
@@ -1445,6 +3695,7 @@ mod tests {
                 name,
                 partitions: RwLock::new(partitions),
                 wal_details: None,
+                ..Default::default()
             };
 
             // some cpu
@@ -1838,6 +4089,52 @@ disk bytes=23432323i 1600136510000000000",
         Ok(())
     }
 
+    #[tokio::test]
+    async fn list_tag_values_with_prefix() -> Result {
+        let db = Db::new("column_namedb");
+
+        // the cities start with the same letters as the states
+        let lp_data = "h2o,state=CA,city=LA temp=70.4 100\n\
+                       h2o,state=MA,city=Boston temp=72.4 250\n\
+                       h2o,state=MN,city=Minneapolis temp=60.0 300\n\
+                       o2,state=MA,city=Boston temp=50.4 200\n\
+                       o2,state=MI temp=79.0 400\n";
+
+        let lines: Vec<_> = parse_lines(lp_data).map(|l| l.unwrap()).collect();
+        db.write_lines(&lines).await?;
+
+        let query = TagValuesQuery::new("state", "M");
+        let values = db.tag_values_with_prefix(&query).await?;
+        assert_eq!(values.values, vec!["MA", "MI", "MN"]);
+        assert!(!values.truncated);
+
+        let query = TagValuesQuery::new("state", "M").limit(2);
+        let values = db.tag_values_with_prefix(&query).await?;
+        assert_eq!(values.values, vec!["MA", "MI"]);
+        assert!(values.truncated);
+
+        let query = TagValuesQuery::new("state", "M").table_option(Some("o2".into()));
+        let values = db.tag_values_with_prefix(&query).await?;
+        assert_eq!(values.values, vec!["MA", "MI"]);
+
+        let query =
+            TagValuesQuery::new("state", "M").range_option(Some(TimestampRange::new(50, 301)));
+        let values = db.tag_values_with_prefix(&query).await?;
+        assert_eq!(values.values, vec!["MA", "MN"]);
+
+        for query in &[
+            TagValuesQuery::new("state", "X"),
+            TagValuesQuery::new("country", ""),
+        ] {
+            assert_eq!(
+                db.tag_values_with_prefix(query).await?,
+                TagValues::default()
+            );
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_query_series() -> Result {
         // This test checks that everything is wired together
@@ -1,5 +1,7 @@
 //! Contains a structure to map from strings to u32 symbols based on
 //! string interning.
+use std::collections::HashSet;
+
 use snafu::{OptionExt, Snafu};
 use string_interner::{
     backend::StringBackend, DefaultHashBuilder, DefaultSymbol, StringInterner, Symbol,
@@ -59,6 +61,17 @@ impl Dictionary {
             .resolve(symbol)
             .context(DictionaryIdLookupError { id })
     }
+
+    /// Returns the ids of the values in self.dictionary that start with `prefix`
+    pub fn ids_with_prefix(&self, prefix: &str) -> HashSet<u32> {
+        let mut ids = HashSet::new();
+        for (symbol, value) in &self.0 {
+            if value.starts_with(prefix) {
+                ids.insert(symbol_to_u32(symbol));
+            }
+        }
+        ids
+    }
 }
 
 fn symbol_to_u32(sym: DefaultSymbol) -> u32 {
@@ -8,15 +8,20 @@
     clippy::use_self
 )]
 
+mod asof;
 mod column;
 mod database;
 mod dictionary;
 mod partition;
-mod store;
+mod rollup;
+mod sequence;
 mod table;
 
-// Allow restore partitions to be used outside of this crate (for
-// benchmarking)
-pub use crate::database::Db;
-pub use crate::partition::restore_partitions_from_wal;
-pub use crate::store::WriteBufferDatabases;
+// Allow restore partitions and the partitioning of writes to be used outside
+// of this crate (for benchmarking)
+pub use crate::database::{
+    partition_key, query_column_predicates, query_columns, query_table_names, ChunkIncrement, Db,
+    Error, ExportedTable, ReplayProgress, WriteLimits, WRITE_RETRY_AFTER,
+};
+pub use crate::partition::{restore_partitions_from_wal, RestorationStats};
+pub use crate::sequence::Sequences;
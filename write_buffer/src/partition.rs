@@ -4,16 +4,25 @@ use arrow_deps::{
     datafusion::scalar::ScalarValue,
 };
 use generated_types::wal as wb;
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
+};
 use wal::{Entry as WalEntry, Result as WalResult};
 
-use data_types::TIME_COLUMN_NAME;
+use data_types::{
+    chunk::{ChunkStorage, ChunkSummary, ColumnSummary},
+    entry::{self, Entry},
+    TIME_COLUMN_NAME,
+};
 use storage::{
     predicate::{Predicate, TimestampRange},
     util::{visit_expression, AndExprBuilder, ExpressionVisitor},
 };
 
+use crate::column::Column;
 use crate::dictionary::Dictionary;
+use crate::sequence::Sequences;
 use crate::table::Table;
 
 use snafu::{OptionExt, ResultExt, Snafu};
@@ -26,6 +35,9 @@ pub enum Error {
     #[snafu(display("Partition {} not found", partition))]
     PartitionNotFound { partition: String },
 
+    #[snafu(display("Invalid entry in WAL: {}", source))]
+    InvalidWalEntry { source: entry::Error },
+
     #[snafu(display(
         "Column name {} not found in dictionary of partition {}",
         column,
@@ -76,6 +88,10 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub struct Partition {
     pub key: String,
 
+    /// Identifies this partition among the chunks of data written for `key`. Each time a
+    /// partition is closed, new writes for its key go into a new partition with the next id.
+    pub id: u32,
+
     /// `dictionary` maps &str -> u32. The u32s are used in place of String or str to avoid slow
     /// string operations. The same dictionary is used for table names, tag names, tag values, and
     /// column names.
@@ -124,6 +140,14 @@ pub struct PartitionPredicate {
     /// expressions should be returned.
     pub partition_exprs: Vec<Expr>,
 
+    /// Expressions the rows of some tables, keyed by their id, must also
+    /// evaluate to TRUE for
+    pub table_exprs: BTreeMap<u32, Expr>,
+
+    /// Expression the rows of the tables not in `table_exprs` must also
+    /// evaluate to TRUE for
+    pub other_tables_expr: Option<Expr>,
+
     /// If Some, then the table must contain all columns specified
     /// to pass the predicate
     pub required_columns: Option<PartitionIdSet>,
@@ -150,6 +174,27 @@ impl PartitionPredicate {
         builder.build()
     }
 
+    /// Like `filter_expr`, for the table `table_id` with only the columns for which
+    /// `has_column` returns true, including the expression for that table. A comparison
+    /// involving a column the table doesn't have is never true, except in regular expression
+    /// matches, which match a missing column as an empty string. Rewriting the comparisons
+    /// rather than ruling out the table keeps the rows matching the other branches of an `OR`.
+    pub fn filter_expr_for_table(
+        &self,
+        table_id: u32,
+        has_column: impl Fn(&str) -> bool,
+    ) -> Option<Expr> {
+        let table_expr = self
+            .table_exprs
+            .get(&table_id)
+            .or_else(|| self.other_tables_expr.as_ref());
+        AndExprBuilder::default()
+            .append_opt(self.filter_expr())
+            .append_opt_ref(table_expr)
+            .build()
+            .map(|expr| replace_missing_columns(&expr, &has_column))
+    }
+
     /// Return true if there is a non empty field restriction
     pub fn has_field_restriction(&self) -> bool {
         match &self.field_restriction {
@@ -180,6 +225,75 @@ impl PartitionPredicate {
     }
 }
 
+/// Rewrites `expr` for a table with only the columns for which `has_column` returns true, as
+/// described in `PartitionPredicate::filter_expr_for_table`
+fn replace_missing_columns(expr: &Expr, has_column: &dyn Fn(&str) -> bool) -> Expr {
+    match expr {
+        Expr::BinaryExpr {
+            left,
+            op: op @ Operator::And,
+            right,
+        }
+        | Expr::BinaryExpr {
+            left,
+            op: op @ Operator::Or,
+            right,
+        } => Expr::BinaryExpr {
+            left: Box::new(replace_missing_columns(left, has_column)),
+            op: op.clone(),
+            right: Box::new(replace_missing_columns(right, has_column)),
+        },
+        Expr::Nested(expr) => Expr::Nested(Box::new(replace_missing_columns(expr, has_column))),
+        Expr::ScalarUDF { fun, args } => Expr::ScalarUDF {
+            fun: fun.clone(),
+            args: args
+                .iter()
+                .map(|arg| match arg {
+                    Expr::Column(name) if !has_column(name) => {
+                        Expr::Literal(ScalarValue::Utf8(None))
+                    }
+                    arg => arg.clone(),
+                })
+                .collect(),
+        },
+        expr => {
+            let mut columns = HashSet::new();
+            expr_to_column_names(expr, &mut columns).unwrap();
+            if columns.iter().all(|name| has_column(name)) {
+                expr.clone()
+            } else {
+                Expr::Literal(ScalarValue::Boolean(Some(false)))
+            }
+        }
+    }
+}
+
+/// Returns true if the rows matching `expr` must have a value for all the columns `expr`
+/// refers to, which is not the case of `OR` trees and regular expression matches
+fn requires_columns(expr: &Expr) -> bool {
+    struct Visitor {
+        requires_columns: bool,
+    }
+
+    impl ExpressionVisitor for Visitor {
+        fn pre_visit(&mut self, expr: &Expr) {
+            match expr {
+                Expr::BinaryExpr {
+                    op: Operator::Or, ..
+                }
+                | Expr::ScalarUDF { .. } => self.requires_columns = false,
+                _ => {}
+            }
+        }
+    }
+
+    let mut visitor = Visitor {
+        requires_columns: true,
+    };
+    visit_expression(expr, &mut visitor);
+    visitor.requires_columns
+}
+
 /// Creates expression like:
 /// range.low <= time && time < range.high
 fn make_range_expr(range: &TimestampRange) -> Expr {
@@ -203,8 +317,14 @@ fn make_range_expr(range: &TimestampRange) -> Expr {
 
 impl Partition {
     pub fn new(key: impl Into<String>) -> Self {
+        Self::with_id(key, 0)
+    }
+
+    /// Creates a new open partition for `key` with the chunk id `id`
+    pub fn with_id(key: impl Into<String>, id: u32) -> Self {
         Self {
             key: key.into(),
+            id,
             dictionary: Dictionary::new(),
             tables: HashMap::new(),
             is_open: true,
@@ -221,6 +341,25 @@ impl Partition {
         Ok(())
     }
 
+    /// Writes the rows of a partition write of an `Entry`
+    pub fn write_partition_write(&mut self, write: &entry::PartitionWrite<'_>) -> Result<()> {
+        for batch in write.table_batches() {
+            let table_name = batch.name();
+            let table_id = self.dictionary.lookup_value_or_insert(table_name);
+
+            let table = self
+                .tables
+                .entry(table_id)
+                .or_insert_with(|| Table::new(table_id));
+
+            table
+                .append_columns(&mut self.dictionary, &batch)
+                .context(TableWrite { table_name })?;
+        }
+
+        Ok(())
+    }
+
     fn write_table_batch(&mut self, batch: &wb::TableWriteBatch<'_>) -> Result<()> {
         let table_name = batch.name().context(TableWriteWithoutName)?;
         let table_id = self.dictionary.lookup_value_or_insert(table_name);
@@ -255,15 +394,28 @@ impl Partition {
 
         // it would be nice to avoid cloning all the exprs here.
         let partition_exprs = predicate.exprs.clone();
-
-        // In order to evaluate expressions in the table, all columns
-        // referenced in the expression must appear (I think, not sure
-        // about NOT, etc so panic if we see one of those);
+        let table_exprs: BTreeMap<_, _> = predicate
+            .table_exprs
+            .iter()
+            .filter_map(|(name, expr)| Some((self.dictionary.id(name)?, expr.clone())))
+            .collect();
+        let other_tables_expr = predicate.other_tables_expr.clone();
+
+        // The rows matching an expression have all the columns it refers to, unless the
+        // expression is an OR tree or a regular expression match (not sure about NOT, etc so
+        // panic if we see one of those). The tables without those columns have no matching
+        // rows.
         let mut visitor = SupportVisitor {};
         let mut predicate_columns: HashSet<String> = HashSet::new();
         for expr in &partition_exprs {
             visit_expression(expr, &mut visitor);
-            expr_to_column_names(&expr, &mut predicate_columns).unwrap();
+            if requires_columns(expr) {
+                expr_to_column_names(&expr, &mut predicate_columns).unwrap();
+            }
+        }
+        // the expressions for some of the tables don't tell which columns every table needs
+        for expr in table_exprs.values().chain(&other_tables_expr) {
+            visit_expression(expr, &mut visitor);
         }
 
         // if there are any column references in the expression, ensure they appear in any table
@@ -277,6 +429,8 @@ impl Partition {
             table_name_predicate,
             field_restriction,
             partition_exprs,
+            table_exprs,
+            other_tables_expr,
             required_columns,
             time_column_id,
             range,
@@ -339,10 +493,123 @@ impl Partition {
 
     /// returns true if data with partition key `key` should be
     /// written to this partition,
+    /// Returns the location, size and row count of this partition as a chunk
+    pub fn chunk_summary(&self) -> ChunkSummary {
+        let storage = if self.is_open {
+            ChunkStorage::OpenMutableBuffer
+        } else {
+            ChunkStorage::ClosedMutableBuffer
+        };
+
+        ChunkSummary {
+            partition_key: self.key.clone(),
+            id: self.id,
+            storage,
+            estimated_bytes: self.size(),
+            row_count: self.tables.values().map(Table::row_count).sum(),
+            index_bytes: 0,
+            index_build_micros: 0,
+        }
+    }
+
+    /// Returns the estimated size of the data of this partition, in bytes
+    pub fn size(&self) -> usize {
+        self.tables.values().map(Table::size).sum()
+    }
+
+    /// Returns the statistics and size of every column of every table in this partition
+    pub fn column_summaries(&self) -> Vec<ColumnSummary> {
+        let mut summaries = vec![];
+
+        for table in self.tables.values() {
+            let table_name = self
+                .dictionary
+                .lookup_id(table.id)
+                .expect("table id wasn't inserted into dictionary");
+
+            for (&column_id, &column_index) in &table.column_id_to_index {
+                let column_name = self
+                    .dictionary
+                    .lookup_id(column_id)
+                    .expect("column id wasn't inserted into dictionary");
+                let column = &table.columns[column_index];
+                let (count, min_value, max_value) = column.stats_strings();
+
+                summaries.push(ColumnSummary {
+                    partition_key: self.key.clone(),
+                    chunk_id: self.id,
+                    table_name: table_name.to_string(),
+                    column_name: column_name.to_string(),
+                    column_type: column.type_description().to_string(),
+                    encoding: column.encoding_description().to_string(),
+                    count,
+                    null_count: column.len() as u32 - count,
+                    distinct_count: column.distinct_count(),
+                    min_value,
+                    max_value,
+                    estimated_bytes: column.size(),
+                });
+            }
+        }
+
+        summaries
+            .sort_by(|a, b| (&a.table_name, &a.column_name).cmp(&(&b.table_name, &b.column_name)));
+        summaries
+    }
+
+    /// Returns the largest timestamp written to any table in this partition
+    pub fn max_time(&self) -> Option<i64> {
+        let time_column_id = self.dictionary.id(TIME_COLUMN_NAME)?;
+
+        self.tables
+            .values()
+            .filter_map(|table| {
+                let column_index = table.column_id_to_index.get(&time_column_id)?;
+                match &table.columns[*column_index] {
+                    Column::I64(_, stats) => Some(stats.max),
+                    _ => None,
+                }
+            })
+            .max()
+    }
+
+    /// Returns true if all the data in this partition is older than `boundary`, a timestamp
+    /// in nanoseconds. Nothing is expired if there is no boundary.
+    pub fn is_expired(&self, boundary: Option<i64>) -> bool {
+        match (boundary, self.max_time()) {
+            (Some(boundary), Some(max_time)) => max_time < boundary,
+            _ => false,
+        }
+    }
+
     pub fn should_write(&self, key: &str) -> bool {
         self.key.starts_with(key) && self.is_open
     }
 
+    /// Returns those of `columns` that the table `table_name` has, in the order of its
+    /// columns, or none if it has none of them or there is no such table
+    pub fn table_columns<'a>(&self, table_name: &str, columns: &[&'a str]) -> Vec<&'a str> {
+        let table = match self
+            .dictionary
+            .id(table_name)
+            .and_then(|id| self.tables.get(&id))
+        {
+            Some(table) => table,
+            None => return vec![],
+        };
+
+        let mut found: Vec<_> = columns
+            .iter()
+            .filter_map(|&column| {
+                let id = self.dictionary.id(column)?;
+                let index = table.column_id_to_index.get(&id)?;
+                Some((*index, column))
+            })
+            .collect();
+        found.sort_unstable();
+        found.into_iter().map(|(_, column)| column).collect()
+    }
+
     /// Convert the table specified in this partition into an arrow record batch
     pub fn table_to_arrow(&self, table_name: &str, columns: &[&str]) -> Result<RecordBatch> {
         let table_id =
@@ -392,6 +659,8 @@ impl ExpressionVisitor for SupportVisitor {
         match expr {
             Expr::Literal(..) => {}
             Expr::Column(..) => {}
+            // regular expression matches
+            Expr::ScalarUDF { .. } => {}
             Expr::BinaryExpr { op, .. } => {
                 match op {
                     Operator::Eq
@@ -423,11 +692,28 @@ impl ExpressionVisitor for SupportVisitor {
 pub struct RestorationStats {
     pub row_count: usize,
     pub tables: BTreeSet<String>,
+    /// The high water marks of the entries in the WAL
+    pub sequences: Sequences,
+    /// The number of partition writes of entries that were skipped because the WAL held
+    /// them more than once
+    pub duplicate_writes: usize,
 }
 
 /// Given a set of WAL entries, restore them into a set of Partitions.
 pub fn restore_partitions_from_wal(
     wal_entries: impl Iterator<Item = WalResult<WalEntry>>,
+) -> Result<(Vec<Partition>, RestorationStats)> {
+    restore_partitions_from_wal_with_progress(wal_entries, |_| false, |_, _| {})
+}
+
+/// Restores a set of WAL entries into a set of Partitions as `restore_partitions_from_wal`
+/// does, leaving out the entries whose WAL sequence number `skip` returns true for. Once an
+/// entry is restored or left out, `on_entry` is called with its sequence number and whether
+/// it was left out.
+pub fn restore_partitions_from_wal_with_progress(
+    wal_entries: impl Iterator<Item = WalResult<WalEntry>>,
+    skip: impl Fn(u64) -> bool,
+    mut on_entry: impl FnMut(u64, bool),
 ) -> Result<(Vec<Partition>, RestorationStats)> {
     let mut stats = RestorationStats::default();
 
@@ -435,30 +721,12 @@ pub fn restore_partitions_from_wal(
 
     for wal_entry in wal_entries {
         let wal_entry = wal_entry.context(WalEntryRead)?;
-        let bytes = wal_entry.as_data();
-
-        let batch = flatbuffers::get_root::<wb::WriteBufferBatch<'_>>(&bytes);
-
-        if let Some(entries) = batch.entries() {
-            for entry in entries {
-                let partition_key = entry.partition_key().context(MissingPartitionKey)?;
-
-                if !partitions.contains_key(partition_key) {
-                    partitions.insert(
-                        partition_key.to_string(),
-                        Partition::new(partition_key.to_string()),
-                    );
-                }
-
-                let partition = partitions
-                    .get_mut(partition_key)
-                    .context(PartitionNotFound {
-                        partition: partition_key,
-                    })?;
-
-                partition.write_entry(&entry)?;
-            }
+        if skip(wal_entry.sequence_number()) {
+            on_entry(wal_entry.sequence_number(), true);
+            continue;
         }
+        restore_wal_entry(&wal_entry, &mut partitions, &mut stats)?;
+        on_entry(wal_entry.sequence_number(), false);
     }
     let partitions = partitions
         .into_iter()
@@ -483,6 +751,64 @@ pub fn restore_partitions_from_wal(
     Ok((partitions, stats))
 }
 
+/// Restores a WAL entry into `partitions`, recording its sequence in `stats`
+fn restore_wal_entry(
+    wal_entry: &WalEntry,
+    partitions: &mut BTreeMap<String, Partition>,
+    stats: &mut RestorationStats,
+) -> Result<()> {
+    let bytes = wal_entry.as_data();
+
+    // the WAL holds entries, or write buffer batches if it was written before entries
+    // were introduced
+    if Entry::is_entry(&bytes) {
+        let entry = Entry::try_from(bytes.to_vec()).context(InvalidWalEntry)?;
+        let (producer_id, sequence_number) = (entry.producer_id(), entry.sequence_number());
+        for write in entry.partition_writes() {
+            let key = write.key();
+            if stats
+                .sequences
+                .is_applied(key, producer_id, sequence_number)
+            {
+                stats.duplicate_writes += 1;
+                continue;
+            }
+
+            partitions
+                .entry(key.to_string())
+                .or_insert_with(|| Partition::new(key.to_string()))
+                .write_partition_write(&write)?;
+            stats.sequences.record(key, producer_id, sequence_number);
+        }
+        return Ok(());
+    }
+
+    let batch = flatbuffers::get_root::<wb::WriteBufferBatch<'_>>(&bytes);
+
+    if let Some(entries) = batch.entries() {
+        for entry in entries {
+            let partition_key = entry.partition_key().context(MissingPartitionKey)?;
+
+            if !partitions.contains_key(partition_key) {
+                partitions.insert(
+                    partition_key.to_string(),
+                    Partition::new(partition_key.to_string()),
+                );
+            }
+
+            let partition = partitions
+                .get_mut(partition_key)
+                .context(PartitionNotFound {
+                    partition: partition_key,
+                })?;
+
+            partition.write_entry(&entry)?;
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
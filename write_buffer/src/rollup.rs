@@ -0,0 +1,355 @@
+//! This module contains the aggregates a write buffer maintains for the rollup rules of its
+//! database: the count, sum, min and max of the numeric fields of a table for each window of
+//! time and series, updated as the entries are stored, so that they are queried without
+//! scanning the rows they were computed from.
+//!
+//! The aggregates only cover the rows stored since the rule was set.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use arrow_deps::arrow::{
+    array::{ArrayRef, Float64Array, Int64Array, StringArray},
+    datatypes::{DataType, Field, Schema},
+    error::Result as ArrowResult,
+    record_batch::RecordBatch,
+};
+use data_types::{
+    database_rules::RollupRule,
+    entry::{ColumnValues, Entry, LogicalColumnType},
+    TIME_COLUMN_NAME,
+};
+
+/// The tag values of a series, sorted by tag name
+type SeriesKey = Vec<(String, String)>;
+
+/// The aggregates of the rollup rules of a database
+#[derive(Debug, Default)]
+pub struct Rollups {
+    tables: Vec<RollupTable>,
+}
+
+impl Rollups {
+    /// Replaces the rules. The aggregates of the rules that are unchanged are kept, the
+    /// others start from the next stored entry.
+    pub fn set_rules(&mut self, rules: &[RollupRule]) {
+        let mut tables = std::mem::take(&mut self.tables);
+        self.tables = rules
+            .iter()
+            .map(
+                |rule| match tables.iter().position(|table| &table.rule == rule) {
+                    Some(i) => tables.swap_remove(i),
+                    None => RollupTable::new(rule.clone()),
+                },
+            )
+            .collect();
+    }
+
+    /// Adds the rows of `entry` to the aggregates of the tables they are written to, skipping
+    /// the writes to partitions for which `skip` returns true
+    pub fn update(&mut self, entry: &Entry, skip: impl Fn(&str) -> bool) {
+        if self.tables.is_empty() {
+            return;
+        }
+
+        for write in entry.partition_writes() {
+            if skip(write.key()) {
+                continue;
+            }
+            for batch in write.table_batches() {
+                for table in self
+                    .tables
+                    .iter_mut()
+                    .filter(|table| table.rule.table == batch.name())
+                {
+                    table.update(&batch);
+                }
+            }
+        }
+    }
+
+    /// Drops the windows that end before `boundary`, in nanoseconds since the epoch,
+    /// returning how many were dropped
+    pub fn drop_before(&mut self, boundary: i64) -> usize {
+        let mut dropped = 0;
+        for table in &mut self.tables {
+            let every = table.rule.every.as_nanos() as i128;
+            let before = table.windows.len();
+            table
+                .windows
+                .retain(|(_, start), _| *start as i128 + every > boundary as i128);
+            dropped += before - table.windows.len();
+        }
+        dropped
+    }
+
+    /// Returns the aggregates queried as `table_name`, if it is the destination of a rule
+    /// and there are any
+    pub fn to_arrow(&self, table_name: &str) -> Option<ArrowResult<RecordBatch>> {
+        self.tables
+            .iter()
+            .find(|table| table.rule.destination_table == table_name)
+            .filter(|table| !table.windows.is_empty())
+            .map(RollupTable::to_arrow)
+    }
+}
+
+/// The count, sum, min and max of the values of a field within a window
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Aggregate {
+    count: i64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Aggregate {
+    fn new(value: f64) -> Self {
+        Self {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+}
+
+#[derive(Debug)]
+struct RollupTable {
+    rule: RollupRule,
+    tags: BTreeSet<String>,
+    fields: BTreeSet<String>,
+    /// The aggregates of each field, per series and start of window
+    windows: BTreeMap<(SeriesKey, i64), BTreeMap<String, Aggregate>>,
+}
+
+impl RollupTable {
+    fn new(rule: RollupRule) -> Self {
+        Self {
+            rule,
+            tags: BTreeSet::new(),
+            fields: BTreeSet::new(),
+            windows: BTreeMap::new(),
+        }
+    }
+
+    fn update(&mut self, batch: &data_types::entry::TableBatch<'_>) {
+        let mut tags = vec![];
+        let mut fields = vec![];
+        let mut times = None;
+
+        for column in batch.columns() {
+            match (column.logical_type(), column.values()) {
+                (LogicalColumnType::Tag, ColumnValues::String(values)) => {
+                    tags.push((column.name(), values))
+                }
+                (LogicalColumnType::Time, ColumnValues::I64(values)) => times = Some(values),
+                (LogicalColumnType::Field, ColumnValues::F64(values))
+                    if self.rule.aggregates(column.name()) =>
+                {
+                    fields.push((column.name(), values))
+                }
+                (LogicalColumnType::Field, ColumnValues::I64(values))
+                    if self.rule.aggregates(column.name()) =>
+                {
+                    let values = values.into_iter().map(|v| v.map(|v| v as f64)).collect();
+                    fields.push((column.name(), values))
+                }
+                _ => {}
+            }
+        }
+        let times = match times {
+            Some(times) => times,
+            None => return,
+        };
+        tags.sort_by_key(|(name, _)| *name);
+
+        for (row, time) in times.into_iter().enumerate() {
+            let time = match time {
+                Some(time) => time,
+                None => continue,
+            };
+            let series: SeriesKey = tags
+                .iter()
+                .filter_map(|(name, values)| {
+                    values[row].map(|value| (name.to_string(), value.to_string()))
+                })
+                .collect();
+
+            let mut values = fields
+                .iter()
+                .filter_map(|(name, values)| values[row].map(|value| (*name, value)))
+                .peekable();
+            if values.peek().is_none() {
+                continue;
+            }
+
+            for (name, _) in &series {
+                if !self.tags.contains(name) {
+                    self.tags.insert(name.clone());
+                }
+            }
+            let aggregates = self
+                .windows
+                .entry((series, self.rule.window_start(time)))
+                .or_default();
+            for (name, value) in values {
+                if !self.fields.contains(name) {
+                    self.fields.insert(name.to_string());
+                }
+                match aggregates.get_mut(name) {
+                    Some(aggregate) => aggregate.add(value),
+                    None => {
+                        aggregates.insert(name.to_string(), Aggregate::new(value));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the aggregates as a row per window of each series, ordered by series and time
+    fn to_arrow(&self) -> ArrowResult<RecordBatch> {
+        let mut schema = vec![];
+        let mut columns: Vec<ArrayRef> = vec![];
+
+        for tag in &self.tags {
+            let values: StringArray = self
+                .windows
+                .keys()
+                .map(|(series, _)| {
+                    series
+                        .iter()
+                        .find(|(name, _)| name == tag)
+                        .map(|(_, value)| value.as_str())
+                })
+                .collect::<Vec<_>>()
+                .into();
+            schema.push(Field::new(tag, DataType::Utf8, true));
+            columns.push(Arc::new(values));
+        }
+
+        let times: Vec<_> = self.windows.keys().map(|(_, start)| *start).collect();
+        schema.push(Field::new(TIME_COLUMN_NAME, DataType::Int64, false));
+        columns.push(Arc::new(Int64Array::from(times)));
+
+        for field in &self.fields {
+            let aggregates: Vec<_> = self
+                .windows
+                .values()
+                .map(|aggregates| aggregates.get(field))
+                .collect();
+            let floats = |f: fn(&Aggregate) -> f64| -> ArrayRef {
+                Arc::new(Float64Array::from(
+                    aggregates
+                        .iter()
+                        .map(|a| a.map(f))
+                        .collect::<Vec<Option<f64>>>(),
+                ))
+            };
+
+            schema.push(Field::new(
+                &format!("{}_count", field),
+                DataType::Int64,
+                false,
+            ));
+            columns.push(Arc::new(Int64Array::from(
+                aggregates
+                    .iter()
+                    .map(|a| a.map_or(0, |a| a.count))
+                    .collect::<Vec<_>>(),
+            )));
+            for (suffix, f) in &[
+                ("sum", (|a: &Aggregate| a.sum) as fn(&Aggregate) -> f64),
+                ("min", |a: &Aggregate| a.min),
+                ("max", |a: &Aggregate| a.max),
+            ] {
+                schema.push(Field::new(
+                    &format!("{}_{}", field, suffix),
+                    DataType::Float64,
+                    true,
+                ));
+                columns.push(floats(*f));
+            }
+        }
+
+        RecordBatch::try_new(Arc::new(Schema::new(schema)), columns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_deps::arrow::util::pretty::pretty_format_batches;
+    use data_types::{database_rules::DatabaseRules, entry::lines_to_entry};
+    use influxdb_line_protocol::parse_lines;
+    use std::time::Duration;
+
+    const MINUTE: i64 = 60_000_000_000;
+
+    fn entry(sequence_number: u64, lp: &str) -> Entry {
+        let lines: Vec<_> = parse_lines(lp).map(|l| l.unwrap()).collect();
+        lines_to_entry(1, sequence_number, &lines, &DatabaseRules::default()).unwrap()
+    }
+
+    #[test]
+    fn aggregates() {
+        let rule = RollupRule {
+            table: "cpu".to_string(),
+            destination_table: "cpu_1m".to_string(),
+            every: Duration::from_secs(60),
+            fields: vec![],
+        };
+        let mut rollups = Rollups::default();
+        rollups.set_rules(&[rule.clone()]);
+
+        rollups.update(
+            &entry(
+                1,
+                &format!(
+                    "cpu,host=a usage=1,count=2i 0\n\
+                     cpu,host=a usage=3,status=\"ok\" {}\n\
+                     cpu,host=b usage=5 {}\n\
+                     mem,host=a free=1 0",
+                    MINUTE / 2,
+                    MINUTE
+                ),
+            ),
+            |_| false,
+        );
+        rollups.update(&entry(2, "cpu,host=a usage=2 10"), |_| false);
+        rollups.update(&entry(3, "cpu,host=a usage=100 20"), |_| true);
+
+        let batch = rollups.to_arrow("cpu_1m").unwrap().unwrap();
+        let expected = "\
++------+-------------+-------------+-----------+-----------+-----------+-------------+-----------+-----------+-----------+
+| host | time        | count_count | count_sum | count_min | count_max | usage_count | usage_sum | usage_min | usage_max |
++------+-------------+-------------+-----------+-----------+-----------+-------------+-----------+-----------+-----------+
+| a    | 0           | 1           | 2         | 2         | 2         | 3           | 6         | 1         | 3         |
+| b    | 60000000000 | 0           |           |           |           | 1           | 5         | 5         | 5         |
++------+-------------+-------------+-----------+-----------+-----------+-------------+-----------+-----------+-----------+
+";
+        assert_eq!(pretty_format_batches(&[batch]).unwrap(), expected);
+        assert!(rollups.to_arrow("cpu").is_none());
+
+        // unchanged rules keep their aggregates
+        rollups.set_rules(&[rule.clone()]);
+        assert_eq!(rollups.drop_before(MINUTE), 1);
+        let batch = rollups.to_arrow("cpu_1m").unwrap().unwrap();
+        assert_eq!(batch.num_rows(), 1);
+
+        rollups.set_rules(&[RollupRule {
+            fields: vec!["usage".to_string()],
+            ..rule
+        }]);
+        assert!(rollups.to_arrow("cpu_1m").is_none());
+    }
+}
@@ -0,0 +1,82 @@
+//! This module contains the tracking of the sequence numbers of the entries applied to a
+//! write buffer, so that an entry that is replayed from the WAL or delivered more than once
+//! is not applied twice.
+
+use std::collections::BTreeMap;
+
+/// The highest sequence number applied from each producer, per partition key. The entries
+/// of a producer are applied in sequence order, so an entry at or below the high water mark
+/// of its producer has been applied already.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Sequences {
+    high_water_marks: BTreeMap<String, BTreeMap<u32, u64>>,
+}
+
+impl Sequences {
+    /// Returns true if the entry `sequence_number` of `producer_id`, or a later one, has
+    /// been applied to `partition_key`
+    pub fn is_applied(&self, partition_key: &str, producer_id: u32, sequence_number: u64) -> bool {
+        self.high_water_mark(partition_key, producer_id)
+            .map_or(false, |mark| sequence_number <= mark)
+    }
+
+    /// Records that the entry `sequence_number` of `producer_id` has been applied to
+    /// `partition_key`
+    pub fn record(&mut self, partition_key: &str, producer_id: u32, sequence_number: u64) {
+        let mark = self
+            .high_water_marks
+            .entry(partition_key.to_string())
+            .or_default()
+            .entry(producer_id)
+            .or_default();
+        *mark = sequence_number.max(*mark);
+    }
+
+    /// The highest sequence number of `producer_id` applied to `partition_key`
+    pub fn high_water_mark(&self, partition_key: &str, producer_id: u32) -> Option<u64> {
+        self.high_water_marks
+            .get(partition_key)
+            .and_then(|marks| marks.get(&producer_id))
+            .copied()
+    }
+
+    /// Returns the partition key, producer id and high water mark of every producer that
+    /// has written to a partition
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u32, u64)> + '_ {
+        self.high_water_marks.iter().flat_map(|(key, marks)| {
+            marks
+                .iter()
+                .map(move |(producer_id, mark)| (key.as_str(), *producer_id, *mark))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_high_water_marks() {
+        let mut sequences = Sequences::default();
+        assert!(!sequences.is_applied("p1", 1, 1));
+
+        sequences.record("p1", 1, 5);
+        sequences.record("p1", 2, 3);
+        sequences.record("p2", 1, 7);
+        // an older entry doesn't lower the mark
+        sequences.record("p1", 1, 4);
+
+        assert!(sequences.is_applied("p1", 1, 5));
+        assert!(sequences.is_applied("p1", 1, 1));
+        assert!(!sequences.is_applied("p1", 1, 6));
+        assert!(!sequences.is_applied("p1", 3, 1));
+        assert!(!sequences.is_applied("p3", 1, 1));
+
+        assert_eq!(sequences.high_water_mark("p2", 1), Some(7));
+        assert_eq!(sequences.high_water_mark("p2", 2), None);
+        assert_eq!(
+            sequences.iter().collect::<Vec<_>>(),
+            vec![("p1", 1, 5), ("p1", 2, 3), ("p2", 1, 7)]
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use generated_types::wal as wb;
 use storage::{
     exec::{make_schema_pivot, GroupedSeriesSetPlan, SeriesSetPlan},
+    predicate::TimestampRange,
     util::dump_plan,
 };
 use tracing::debug;
@@ -14,7 +15,12 @@ use crate::{
     partition::PartitionIdSet,
     partition::{Partition, PartitionPredicate},
 };
-use data_types::TIME_COLUMN_NAME;
+use data_types::{
+    entry,
+    table_schema::{DataType, Schema, SchemaBuilder},
+    TIME_COLUMN_NAME,
+};
+use packers::{ByteArray, Packers};
 use snafu::{OptionExt, ResultExt, Snafu};
 
 use arrow_deps::{
@@ -105,6 +111,17 @@ pub enum Error {
         source: DictionaryError,
     },
 
+    #[snafu(display(
+        "Internal: Table id '{}' not found in dictionary of partition {}",
+        table,
+        partition
+    ))]
+    TableIdNotFoundInDictionary {
+        table: u32,
+        partition: String,
+        source: DictionaryError,
+    },
+
     #[snafu(display(
         "Schema mismatch: for column {}: can't insert {} into column with type {}",
         column,
@@ -178,6 +195,11 @@ pub struct Table {
 
     /// Actual column storage
     pub columns: Vec<Column>,
+
+    /// The number of rows, from the first, persisted to object storage as increments of the
+    /// chunk while it was open. They stay in memory, but are left out of the rows packed to be
+    /// persisted.
+    pub persisted_rows: usize,
 }
 
 type ArcStringVec = Vec<Arc<String>>;
@@ -188,6 +210,7 @@ impl Table {
             id,
             column_id_to_index: HashMap::new(),
             columns: Vec::new(),
+            persisted_rows: 0,
         }
     }
 
@@ -237,6 +260,11 @@ impl Table {
         self.columns.first().map_or(0, |v| v.len())
     }
 
+    /// Returns an estimate of the memory used by the columns of this table, in bytes
+    pub fn size(&self) -> usize {
+        self.columns.iter().map(Column::size).sum()
+    }
+
     /// Returns a reference to the specified column
     fn column(&self, column_id: u32) -> Result<&Column> {
         Ok(self
@@ -275,13 +303,67 @@ impl Table {
         Ok(())
     }
 
+    /// Appends the rows of a table batch of an `Entry`, whose values are stored by column
+    pub fn append_columns(
+        &mut self,
+        dictionary: &mut Dictionary,
+        batch: &entry::TableBatch<'_>,
+    ) -> Result<()> {
+        let row_count = self.row_count();
+
+        for column in batch.columns() {
+            let column_name = column.name();
+            let column_id = dictionary.lookup_value_or_insert(column_name);
+            let logical_type = column.logical_type();
+            let values = column.values();
+
+            let column = match self.column_id_to_index.get(&column_id) {
+                Some(idx) => &mut self.columns[*idx],
+                None => match Column::for_entry_values(row_count, logical_type, &values) {
+                    Some(column) => {
+                        let idx = self.columns.len();
+                        self.column_id_to_index.insert(column_id, idx);
+                        self.columns.push(column);
+                        &mut self.columns[idx]
+                    }
+                    // a column of nulls is only added once it has a value
+                    None => continue,
+                },
+            };
+
+            column
+                .append_entry_values(dictionary, logical_type, &values)
+                .context(ColumnError {
+                    column: column_name,
+                })?;
+        }
+
+        // make sure all the columns are of the same length
+        let row_count = row_count + batch.row_count();
+        for col in &mut self.columns {
+            col.pad_to_len(row_count);
+        }
+
+        Ok(())
+    }
+
     /// Creates and adds a datafuson filtering expression, if any out of the
-    /// combination of predicate and timestamp. Returns the builder
+    /// combination of predicate and timestamp, for the columns of this table. Returns the
+    /// builder
     fn add_datafusion_predicate(
+        &self,
         plan_builder: LogicalPlanBuilder,
         partition_predicate: &PartitionPredicate,
+        partition: &Partition,
     ) -> Result<LogicalPlanBuilder> {
-        match partition_predicate.filter_expr() {
+        let has_column = |name: &str| {
+            partition
+                .dictionary
+                .id(name)
+                .map_or(false, |id| self.column_id_to_index.contains_key(&id))
+        };
+
+        match partition_predicate.filter_expr_for_table(self.id, has_column) {
             Some(df_predicate) => plan_builder.filter(df_predicate).context(BuildingPlan),
             None => Ok(plan_builder),
         }
@@ -345,7 +427,8 @@ impl Table {
         // Shouldn't have field selections here (as we are getting the tags...)
         assert!(!partition_predicate.has_field_restriction());
 
-        let plan_builder = Self::add_datafusion_predicate(plan_builder, partition_predicate)?;
+        let plan_builder =
+            self.add_datafusion_predicate(plan_builder, partition_predicate, partition)?;
 
         // add optional selection to remove time column
         let plan_builder = if !need_time_column {
@@ -415,7 +498,8 @@ impl Table {
         // shouldn't have columns selection (as this is getting tag values...)
         assert!(!partition_predicate.has_field_restriction());
 
-        let plan_builder = Self::add_datafusion_predicate(plan_builder, partition_predicate)?;
+        let plan_builder =
+            self.add_datafusion_predicate(plan_builder, partition_predicate, partition)?;
 
         plan_builder
             .project(select_exprs)
@@ -489,7 +573,8 @@ impl Table {
         });
 
         // Filtering
-        let plan_builder = Self::add_datafusion_predicate(plan_builder, partition_predicate)?;
+        let plan_builder =
+            self.add_datafusion_predicate(plan_builder, partition_predicate, partition)?;
 
         let mut sort_exprs = Vec::new();
         sort_exprs.extend(tag_columns.iter().map(|c| c.into_sort_expr()));
@@ -585,7 +670,8 @@ impl Table {
         });
 
         // Filtering
-        let plan_builder = Self::add_datafusion_predicate(plan_builder, partition_predicate)?;
+        let plan_builder =
+            self.add_datafusion_predicate(plan_builder, partition_predicate, partition)?;
 
         // Selection
         let select_exprs = self
@@ -834,6 +920,122 @@ impl Table {
         RecordBatch::try_new(Arc::new(schema), columns).context(ArrowError {})
     }
 
+    /// Packs the rows of this table with a time in `range`, or all its rows if there is no
+    /// range, into a column per tag, field and the time, as they are written to Parquet.
+    /// Tags come first, then fields, each sorted by name. The rows persisted already are left
+    /// out.
+    pub fn to_packers(
+        &self,
+        partition: &Partition,
+        range: Option<&TimestampRange>,
+    ) -> Result<(Schema, Vec<Packers>)> {
+        let table_name =
+            partition
+                .dictionary
+                .lookup_id(self.id)
+                .context(TableIdNotFoundInDictionary {
+                    table: self.id,
+                    partition: &partition.key,
+                })?;
+
+        let mut columns = self
+            .column_id_to_index
+            .iter()
+            .map(|(&column_id, &column_index)| {
+                let column_name = partition.dictionary.lookup_id(column_id).context(
+                    ColumnIdNotFoundInDictionary {
+                        column_id,
+                        partition: &partition.key,
+                    },
+                )?;
+                Ok((column_name, &self.columns[column_index]))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        columns.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut builder = SchemaBuilder::new(table_name);
+        for &(column_name, column) in &columns {
+            builder = match column {
+                Column::Tag(..) => builder.tag(column_name),
+                _ if column_name == TIME_COLUMN_NAME => builder,
+                Column::F64(..) => builder.field(column_name, DataType::Float),
+                Column::I64(..) => builder.field(column_name, DataType::Integer),
+                Column::String(..) => builder.field(column_name, DataType::String),
+                Column::Bool(..) => builder.field(column_name, DataType::Boolean),
+            };
+        }
+        let schema = builder.build();
+
+        let times = columns
+            .iter()
+            .find(|(column_name, _)| *column_name == TIME_COLUMN_NAME)
+            .map(|(_, column)| *column);
+        let rows: Vec<usize> = match (range, times) {
+            (None, _) => (self.persisted_rows..self.row_count()).collect(),
+            (Some(range), Some(Column::I64(times, _))) => times
+                .iter()
+                .enumerate()
+                .skip(self.persisted_rows)
+                .filter(|&(_, &time)| range.contains_opt(time))
+                .map(|(row, _)| row)
+                .collect(),
+            (Some(_), _) => vec![],
+        };
+
+        let mut packers = Vec::with_capacity(columns.len());
+        for col_def in schema.get_col_defs() {
+            let mut packer = Packers::from(col_def.data_type);
+            let column = columns
+                .iter()
+                .find(|(column_name, _)| *column_name == col_def.name)
+                .map(|(_, column)| *column);
+
+            match column {
+                Some(Column::F64(vals, _)) => {
+                    let packer = packer.f64_packer_mut();
+                    rows.iter().for_each(|&row| packer.push_option(vals[row]));
+                }
+                Some(Column::I64(vals, _)) => {
+                    let packer = packer.i64_packer_mut();
+                    rows.iter().for_each(|&row| packer.push_option(vals[row]));
+                }
+                Some(Column::Bool(vals, _)) => {
+                    let packer = packer.bool_packer_mut();
+                    rows.iter().for_each(|&row| packer.push_option(vals[row]));
+                }
+                Some(Column::String(vals, _)) => {
+                    let packer = packer.str_packer_mut();
+                    for &row in &rows {
+                        packer.push_option(vals[row].as_deref().map(ByteArray::from));
+                    }
+                }
+                Some(Column::Tag(vals, _)) => {
+                    let packer = packer.str_packer_mut();
+                    for &row in &rows {
+                        let value = match vals[row] {
+                            Some(value_id) => {
+                                let tag_value = partition.dictionary.lookup_id(value_id).context(
+                                    TagValueIdNotFoundInDictionary {
+                                        value: value_id,
+                                        partition: &partition.key,
+                                    },
+                                )?;
+                                Some(ByteArray::from(tag_value))
+                            }
+                            None => None,
+                        };
+                        packer.push_option(value);
+                    }
+                }
+                None => rows.iter().for_each(|_| packer.push_none()),
+            }
+
+            packers.push(packer);
+        }
+
+        Ok((schema, packers))
+    }
+
     /// returns true if any row in this table could possible match the
     /// predicate. true does not mean any rows will *actually* match,
     /// just that the entire table can not be ruled out.
@@ -1025,7 +1227,10 @@ mod tests {
     use data_types::data::split_lines_into_write_entry_partitions;
     use datafusion::{logical_plan::Operator, scalar::ScalarValue};
     use influxdb_line_protocol::{parse_lines, ParsedLine};
-    use storage::{exec::Executor, predicate::PredicateBuilder};
+    use storage::{
+        exec::Executor,
+        predicate::{regex_match_expr, PredicateBuilder},
+    };
     use test_helpers::str_vec_to_arc_vec;
 
     use super::*;
@@ -1262,6 +1467,83 @@ mod tests {
         assert_eq!(expected, results, "expected output");
     }
 
+    #[tokio::test]
+    async fn test_series_set_plan_random_predicates() {
+        // compare the rows selected by randomly generated trees of equality, regular
+        // expression, AND and OR predicates with a naive row by row evaluation
+        let mut rng = Lcg(42);
+
+        for _ in 0..20 {
+            let mut partition = Partition::new("dummy_partition_key");
+            let dictionary = &mut partition.dictionary;
+            let mut table = Table::new(dictionary.lookup_value_or_insert("table_name"));
+
+            // the tags of each row, which may be missing
+            let rows: Vec<Vec<(&str, Option<&str>)>> = (0..10)
+                .map(|_| {
+                    TAGS.iter()
+                        .map(|(name, values)| (*name, rng.choose_optional(values)))
+                        .collect()
+                })
+                .collect();
+
+            let lp_lines: Vec<_> = rows
+                .iter()
+                .enumerate()
+                .map(|(time, tags)| {
+                    let mut line = String::from("h2o");
+                    for (name, value) in tags {
+                        if let Some(value) = value {
+                            line.push_str(&format!(",{}={}", name, value));
+                        }
+                    }
+                    format!("{} temp={} {}", line, time, time)
+                })
+                .collect();
+            write_lines_to_table(
+                &mut table,
+                dictionary,
+                lp_lines.iter().map(String::as_str).collect(),
+            );
+
+            for _ in 0..10 {
+                let tree = TestPredicate::random(&mut rng, 3);
+
+                let expected: Vec<usize> = rows
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, tags)| tree.evaluate(tags))
+                    .map(|(time, _)| time)
+                    .collect();
+
+                let predicate = PredicateBuilder::default().add_expr(tree.expr()).build();
+                let partition_predicate = partition.compile_predicate(&predicate).unwrap();
+
+                let mut actual = vec![];
+                if table.could_match_predicate(&partition_predicate).unwrap() {
+                    let series_set_plan = table
+                        .series_set_plan(&partition_predicate, &partition)
+                        .expect("creating the series set plan");
+
+                    // the time is the last column, and all the rows but the first are data
+                    actual = run_plan(series_set_plan.plan)
+                        .await
+                        .iter()
+                        .filter(|line| line.starts_with('|'))
+                        .skip(1)
+                        .map(|line| {
+                            let cells: Vec<_> = line.split('|').map(str::trim).collect();
+                            cells[cells.len() - 2].parse().unwrap()
+                        })
+                        .collect();
+                    actual.sort();
+                }
+
+                assert_eq!(expected, actual, "predicate {:?} on {:?}", tree, rows);
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_grouped_series_set_plan() {
         // test that filters are applied reasonably
@@ -1354,6 +1636,56 @@ mod tests {
         assert_eq!(expected, results, "expected output");
     }
 
+    #[test]
+    fn test_to_packers() {
+        let mut partition = Partition::new("dummy_partition_key");
+        let dictionary = &mut partition.dictionary;
+        let mut table = Table::new(dictionary.lookup_value_or_insert("h2o"));
+
+        let lp_lines = vec![
+            "h2o,state=MA,city=Boston temp=70.4 100",
+            "h2o,state=MA temp=72.4,reading=\"high\" 250",
+            "h2o,state=CA,city=LA temp=90.0 350",
+        ];
+        write_lines_to_table(&mut table, dictionary, lp_lines);
+
+        let range = TimestampRange::new(100, 300);
+        let (schema, packers) = table.to_packers(&partition, Some(&range)).unwrap();
+
+        assert_eq!(schema.measurement(), "h2o");
+        let column_names: Vec<_> = schema
+            .get_col_defs()
+            .into_iter()
+            .map(|col_def| col_def.name)
+            .collect();
+        assert_eq!(
+            column_names,
+            vec!["city", "state", "reading", "temp", "time"]
+        );
+
+        assert_eq!(
+            packers[0].str_packer().to_vec(),
+            &[Some(ByteArray::from("Boston")), None]
+        );
+        assert_eq!(
+            packers[1].str_packer().to_vec(),
+            &[Some(ByteArray::from("MA")), Some(ByteArray::from("MA"))]
+        );
+        assert_eq!(
+            packers[2].str_packer().to_vec(),
+            &[None, Some(ByteArray::from("high"))]
+        );
+        assert_eq!(packers[3].f64_packer().to_vec(), &[Some(70.4), Some(72.4)]);
+        assert_eq!(packers[4].i64_packer().to_vec(), &[Some(100), Some(250)]);
+
+        let (_, packers) = table.to_packers(&partition, None).unwrap();
+        assert!(packers.iter().all(|packer| packer.num_rows() == 3));
+
+        let range = TimestampRange::new(1000, 2000);
+        let (_, packers) = table.to_packers(&partition, Some(&range)).unwrap();
+        assert!(packers.iter().all(|packer| packer.num_rows() == 0));
+    }
+
     #[test]
     fn test_reorder_prefix() {
         assert_eq!(reorder_prefix_ok(&[], &[]), &[] as &[&str]);
@@ -1481,4 +1813,97 @@ mod tests {
     fn partition_key_func(_: &ParsedLine<'_>) -> String {
         String::from("the_partition_key")
     }
+
+    /// The tag names of the rows of `test_series_set_plan_random_predicates` with their
+    /// possible values
+    const TAGS: &[(&str, &[&str])] = &[
+        ("city", &["Boston", "LA", "Kingston"]),
+        ("state", &["MA", "CA"]),
+    ];
+
+    /// The regular expressions of `test_series_set_plan_random_predicates`
+    const PATTERNS: &[&str] = &["^B", "o", "^(LA|MA)$", "^$"];
+
+    /// A predicate which can be evaluated against the tags of a row, as well as converted to
+    /// an expression
+    #[derive(Debug)]
+    enum TestPredicate {
+        Eq(&'static str, &'static str),
+        Regex(&'static str, &'static str, bool),
+        And(Box<TestPredicate>, Box<TestPredicate>),
+        Or(Box<TestPredicate>, Box<TestPredicate>),
+    }
+
+    impl TestPredicate {
+        fn random(rng: &mut Lcg, depth: usize) -> Self {
+            let (name, values) = TAGS[rng.below(TAGS.len())];
+            match rng.below(if depth == 0 { 2 } else { 4 }) {
+                0 => Self::Eq(name, values[rng.below(values.len())]),
+                1 => Self::Regex(name, PATTERNS[rng.below(PATTERNS.len())], rng.below(2) == 0),
+                2 => Self::And(
+                    Box::new(Self::random(rng, depth - 1)),
+                    Box::new(Self::random(rng, depth - 1)),
+                ),
+                _ => Self::Or(
+                    Box::new(Self::random(rng, depth - 1)),
+                    Box::new(Self::random(rng, depth - 1)),
+                ),
+            }
+        }
+
+        /// A missing tag is never equal to a value, and matches regular expressions as an
+        /// empty string
+        fn evaluate(&self, tags: &[(&str, Option<&str>)]) -> bool {
+            let tag = |name: &str| tags.iter().find(|(n, _)| *n == name).unwrap().1;
+            match self {
+                Self::Eq(name, value) => tag(name) == Some(*value),
+                Self::Regex(name, pattern, matches) => {
+                    regex::Regex::new(pattern)
+                        .unwrap()
+                        .is_match(tag(name).unwrap_or(""))
+                        == *matches
+                }
+                Self::And(left, right) => left.evaluate(tags) && right.evaluate(tags),
+                Self::Or(left, right) => left.evaluate(tags) || right.evaluate(tags),
+            }
+        }
+
+        fn expr(&self) -> Expr {
+            let binary = |left, op, right| Expr::BinaryExpr {
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+            };
+            match self {
+                Self::Eq(name, value) => binary(
+                    Expr::Column(name.to_string()),
+                    Operator::Eq,
+                    Expr::Literal(ScalarValue::Utf8(Some(value.to_string()))),
+                ),
+                Self::Regex(name, pattern, matches) => {
+                    regex_match_expr(Expr::Column(name.to_string()), pattern, *matches).unwrap()
+                }
+                Self::And(left, right) => binary(left.expr(), Operator::And, right.expr()),
+                Self::Or(left, right) => binary(left.expr(), Operator::Or, right.expr()),
+            }
+        }
+    }
+
+    /// A deterministic linear congruential generator, good enough to generate test cases
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((self.0 >> 33) % n as u64) as usize
+        }
+
+        fn choose_optional<'a>(&mut self, values: &[&'a str]) -> Option<&'a str> {
+            let i = self.below(values.len() + 1);
+            values.get(i).copied()
+        }
+    }
 }